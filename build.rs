@@ -0,0 +1,47 @@
+//! Fails the build with a single message when the compiler is older than the Rust version the
+//! enabled features need, see src/msrv.rs.
+
+use std::{env, process::Command};
+
+#[allow(dead_code)]
+#[path = "src/msrv.rs"]
+mod msrv;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/msrv.rs");
+    println!("cargo:rerun-if-env-changed=RUSTC");
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let output = Command::new(&rustc).arg("--version").output();
+    // left to the compiler if it can't tell its version
+    let Some(current) = output
+        .ok()
+        .and_then(|output| msrv::Version::of_rustc(&String::from_utf8_lossy(&output.stdout)))
+    else {
+        return;
+    };
+    let (required, feature) = msrv::required(|feature| {
+        let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+        env::var_os(var).is_some()
+    });
+    if current >= required {
+        return;
+    }
+    let reason = match feature {
+        Some(feature) => {
+            let (_, _, why) = msrv::FEATURES
+                .iter()
+                .find(|(f, _, _)| *f == feature)
+                .unwrap();
+            format!(" with the `{feature}` feature, for {why}")
+        }
+        None => String::new(),
+    };
+    eprintln!(
+        "error: threeqlite requires Rust {required} or newer{reason}, but `{rustc}` is Rust {current}. \
+         Update the toolchain, e.g. with `rustup update`, or see src/msrv.rs for the version each \
+         feature needs."
+    );
+    std::process::exit(1);
+}
@@ -0,0 +1,31 @@
+#!/bin/sh
+# Check that every combination of features compiles on its own.
+set -eu
+
+cd "$(dirname "$0")/.."
+
+# everything at once, with the features of sqlite-vfs, e.g. `syscall` and `loadext`
+echo "checking all features of the workspace"
+cargo test --workspace --all-features --no-run
+
+if cargo hack --version >/dev/null 2>&1; then
+    exec cargo hack check --package threeqlite --feature-powerset --no-dev-deps "$@"
+fi
+
+features="s3 http-readonly lite-s3 redis-bus chaos rusqlite asyncdb metrics-export cli auto-register auto-register-static"
+set -- $features
+n=$#
+i=0
+while [ "$i" -lt $((1 << n)) ]; do
+    combo=""
+    j=0
+    for feature in $features; do
+        if [ $((i >> j & 1)) -eq 1 ]; then
+            combo="$combo,$feature"
+        fi
+        j=$((j + 1))
+    done
+    echo "checking features: [${combo#,}]"
+    cargo check --package threeqlite --all-targets --no-default-features --features "${combo#,}"
+    i=$((i + 1))
+done
@@ -0,0 +1,16 @@
+#!/bin/sh
+# Check the crate with the toolchain of a Rust version, e.g. the one src/msrv.rs documents for a
+# combination of features.
+#
+#   scripts/check-msrv.sh <version> [features]
+set -eu
+
+cd "$(dirname "$0")/.."
+
+version=$1
+features=${2-}
+
+rustup toolchain install "$version" --profile minimal --no-self-update
+# a target directory of its own, not to wait for the lock of the one running the tests
+exec cargo "+$version" check --package threeqlite --lib --locked \
+    --no-default-features --features "$features" --target-dir target/msrv
@@ -0,0 +1,144 @@
+//! The busy handler of the connection a file belongs to.
+//!
+//! Shortly after opening a database file, and before each statement, SQLite passes the busy
+//! handler of the connection with `SQLITE_FCNTL_BUSYHANDLER`, so that a VFS waiting for a lock can
+//! leave the decision to keep waiting to the application (`sqlite3_busy_timeout`,
+//! `sqlite3_busy_handler`) instead of retrying on a policy of its own. The handle receives it as a
+//! [BusyHandlerRef] through [DatabaseHandle::set_busy_handler], and `None` once it is closed.
+//!
+//! The handler belongs to the connection and may only run on its behalf: [BusyHandlerRef::invoke]
+//! refuses, without calling it, unless SQLite is inside a callback of the file it was passed to on
+//! the calling thread.
+//!
+//! [DatabaseHandle::set_busy_handler]: crate::DatabaseHandle::set_busy_handler
+
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::state::FileExt;
+use crate::{DatabaseHandle, Vfs};
+
+/// `int (*)(void*)`, the signature of the busy handler SQLite passes.
+pub type BusyCallback = unsafe extern "C" fn(*mut c_void) -> c_int;
+
+static NEXT_SERIAL: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The serial of the handler of the file whose callback runs on this thread, `0` if none.
+    static CURRENT: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The busy handler of a connection, see the [module documentation](self).
+#[derive(Clone)]
+pub struct BusyHandlerRef {
+    callback: BusyCallback,
+    arg: *mut c_void,
+    serial: u64,
+}
+
+// SAFETY: the pointers are only used by `invoke`, which checks that it runs within a callback of
+// the connection on the calling thread.
+unsafe impl Send for BusyHandlerRef {}
+// SAFETY: as for `Send`
+unsafe impl Sync for BusyHandlerRef {}
+
+impl BusyHandlerRef {
+    /// # Safety
+    ///
+    /// `callback` must be safe to call with `arg` within any callback of the file the handler is
+    /// [entered](BusyHandlerRef::enter) for, as is the pair SQLite passes.
+    pub unsafe fn new(callback: BusyCallback, arg: *mut c_void) -> Self {
+        Self {
+            callback,
+            arg,
+            serial: NEXT_SERIAL.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Ask the handler whether to keep waiting for a lock, after another attempt failed. SQLite
+    /// counts the invocations itself until the lock is acquired, and once the handler returned
+    /// `false`, it keeps returning `false` for the statement. Off the callback thread, it returns
+    /// `false` without calling the handler.
+    pub fn invoke(&self) -> bool {
+        if CURRENT.get() != self.serial {
+            tracing::warn!(target: "sqlite_vfs::lock", "busy handler invoked outside of its callback");
+            return false;
+        }
+        // SAFETY: within a callback of the file the handler was passed to, per the contract of
+        // `BusyHandlerRef::new`
+        unsafe { (self.callback)(self.arg) != 0 }
+    }
+
+    /// Allow [BusyHandlerRef::invoke] on this thread until the scope is dropped. Done for every
+    /// callback on a file that may wait for a lock; backends call it to test themselves without
+    /// SQLite.
+    pub fn enter(&self) -> BusyScope {
+        BusyScope(CURRENT.replace(self.serial))
+    }
+}
+
+impl std::fmt::Debug for BusyHandlerRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BusyHandlerRef")
+            .field("serial", &self.serial)
+            .finish()
+    }
+}
+
+/// See [BusyHandlerRef::enter].
+pub struct BusyScope(u64);
+
+impl BusyScope {
+    /// Enter the scope of the busy handler of the file `ext`, if it has one.
+    pub(crate) fn file<V: Vfs, F: DatabaseHandle>(ext: Option<&FileExt<V, F>>) -> Option<Self> {
+        ext?.busy_handler.as_ref().map(BusyHandlerRef::enter)
+    }
+}
+
+impl Drop for BusyScope {
+    fn drop(&mut self) {
+        CURRENT.set(self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicI32;
+
+    use super::*;
+
+    /// Counts its invocations in `arg`, allowing three.
+    unsafe extern "C" fn three(arg: *mut c_void) -> c_int {
+        // SAFETY: the tests pass an `AtomicI32` that outlives the handler
+        let count = unsafe { &*(arg as *const AtomicI32) };
+        (count.fetch_add(1, Ordering::Relaxed) < 3) as c_int
+    }
+
+    #[test]
+    fn test_invoke_within_scope() {
+        let count = AtomicI32::new(0);
+        // SAFETY: `three` takes an `AtomicI32`, which outlives `handler`
+        let handler = unsafe { BusyHandlerRef::new(three, &count as *const _ as *mut c_void) };
+        assert!(!handler.invoke());
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+
+        {
+            let _scope = handler.enter();
+            assert_eq!([(); 4].map(|_| handler.invoke()), [true, true, true, false]);
+            // not on another thread
+            let other = handler.clone();
+            assert!(!std::thread::spawn(move || other.invoke()).join().unwrap());
+            // nor for another file
+            // SAFETY: never invoked within its scope
+            let nested = unsafe { BusyHandlerRef::new(three, std::ptr::null_mut()) };
+            let inner = nested.enter();
+            assert!(!handler.invoke());
+            drop(inner);
+            assert!(!handler.invoke());
+        }
+        assert!(!handler.invoke());
+        assert_eq!(count.load(Ordering::Relaxed), 5);
+    }
+}
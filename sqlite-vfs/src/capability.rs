@@ -0,0 +1,397 @@
+//! What the SQLite library a VFS is registered with can do.
+//!
+//! Compile options change which callbacks SQLite invokes and which device characteristics it
+//! acts on, so a bug may only reproduce against a library built with unusual options. [register]
+//! probes the library through [SqliteLibrary] before registering, refuses libraries older than
+//! [MIN_VERSION_NUMBER], logs the findings at `INFO` and a warning for each [Capabilities::warnings],
+//! and keeps them for [vfs_stats](crate::vfs_stats). Files only advertise the device
+//! characteristics the library can use, see [Capabilities::device_characteristics].
+//!
+//! | Option | Effect on the VFS |
+//! |---|---|
+//! | `SQLITE_ENABLE_ATOMIC_WRITE` | required for `SQLITE_IOCAP_ATOMIC*` to matter |
+//! | `SQLITE_ENABLE_BATCH_ATOMIC_WRITE` | required for `SQLITE_IOCAP_BATCH_ATOMIC` to matter |
+//! | `SQLITE_DIRECT_OVERFLOW_READ` | overflow pages are read past the page cache, i.e. more reads |
+//! | `SQLITE_MAX_MMAP_SIZE=0` | `xFetch` is never called (this crate doesn't provide it anyway) |
+//! | `SQLITE_THREADSAFE=0` | unsupported: callbacks may run on several threads at once |
+//!
+//! What the backend can do is declared by [Vfs::capabilities] as [VfsCapabilities]. It is called
+//! once, when registering, and the value kept decides the methods registered and which kinds every
+//! open refuses.
+//!
+//! [register]: crate::register
+//! [Vfs::capabilities]: crate::Vfs::capabilities
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+
+use crate::{wip::WalIndex, DatabaseHandle, OpenKind, RegisterError};
+
+/// The oldest SQLite supported, [MIN_VERSION], which renamed master journals to super-journals.
+pub const MIN_VERSION_NUMBER: c_int = 3_033_000;
+pub const MIN_VERSION: &str = "3.33.0";
+
+/// The sector size of files that assume nothing, and that SQLite uses for files advertising
+/// `SQLITE_IOCAP_POWERSAFE_OVERWRITE` whatever their answer.
+pub const DEFAULT_SECTOR_SIZE: u32 = 512;
+/// The largest sector size SQLite accepts, `SQLITE_MAX_SECTOR_SIZE`.
+pub const MAX_SECTOR_SIZE: u32 = 65536;
+
+/// The queries of the library probed, see [Linked].
+pub trait SqliteLibrary {
+    /// `sqlite3_libversion_number`, e.g. `3046000`.
+    fn version_number(&self) -> c_int;
+    /// `sqlite3_libversion`, e.g. `3.46.0`.
+    fn version(&self) -> String;
+    /// `sqlite3_threadsafe`, i.e. `SQLITE_THREADSAFE`.
+    fn threadsafe(&self) -> c_int;
+    /// `sqlite3_compileoption_used`, with or without the `SQLITE_` prefix.
+    fn compile_option_used(&self, option: &str) -> bool;
+}
+
+/// The SQLite library linked into this crate, or the one that loaded it as an extension.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Linked;
+
+impl SqliteLibrary for Linked {
+    fn version_number(&self) -> c_int {
+        // SAFETY: takes no arguments and may be called before initialization
+        unsafe { libsqlite3_sys::sqlite3_libversion_number() }
+    }
+
+    fn version(&self) -> String {
+        // SAFETY: returns a static nul-terminated string
+        unsafe { CStr::from_ptr(libsqlite3_sys::sqlite3_libversion()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn threadsafe(&self) -> c_int {
+        // SAFETY: takes no arguments and may be called before initialization
+        unsafe { libsqlite3_sys::sqlite3_threadsafe() }
+    }
+
+    fn compile_option_used(&self, option: &str) -> bool {
+        let Ok(option) = CString::new(option) else {
+            return false;
+        };
+        // SAFETY: `option` is a nul-terminated string that outlives the call
+        unsafe { libsqlite3_sys::sqlite3_compileoption_used(option.as_ptr()) == 1 }
+    }
+}
+
+/// What a probe found out about a library, see the [module documentation](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: String,
+    pub version_number: c_int,
+    /// `0` single-threaded, `1` serialized, `2` multi-thread.
+    pub threadsafe: c_int,
+    pub atomic_write: bool,
+    pub batch_atomic_write: bool,
+    pub direct_overflow_read: bool,
+    /// Built with `SQLITE_MAX_MMAP_SIZE=0`.
+    pub mmap_disabled: bool,
+}
+
+impl Capabilities {
+    pub fn probe(library: &dyn SqliteLibrary) -> Self {
+        Self {
+            version: library.version(),
+            version_number: library.version_number(),
+            threadsafe: library.threadsafe(),
+            atomic_write: library.compile_option_used("ENABLE_ATOMIC_WRITE"),
+            batch_atomic_write: library.compile_option_used("ENABLE_BATCH_ATOMIC_WRITE"),
+            direct_overflow_read: library.compile_option_used("DIRECT_OVERFLOW_READ"),
+            mmap_disabled: library.compile_option_used("MAX_MMAP_SIZE=0"),
+        }
+    }
+
+    pub fn supported(&self) -> bool {
+        self.version_number >= MIN_VERSION_NUMBER
+    }
+
+    /// Findings that don't prevent registering, but may cause trouble.
+    pub fn warnings(&self) -> Vec<&'static str> {
+        let mut warnings = Vec::new();
+        if self.threadsafe == 0 {
+            warnings.push(
+                "SQLite is built single-threaded (SQLITE_THREADSAFE=0), while callbacks must be \
+                 serialized or multi-thread",
+            );
+        }
+        warnings
+    }
+
+    /// `flags` without the device characteristics the library can't use.
+    pub fn device_characteristics(&self, mut flags: c_int) -> c_int {
+        if !self.batch_atomic_write {
+            flags &= !libsqlite3_sys::SQLITE_IOCAP_BATCH_ATOMIC;
+        }
+        if !self.atomic_write {
+            flags &= !(libsqlite3_sys::SQLITE_IOCAP_ATOMIC
+                | libsqlite3_sys::SQLITE_IOCAP_ATOMIC512
+                | libsqlite3_sys::SQLITE_IOCAP_ATOMIC1K
+                | libsqlite3_sys::SQLITE_IOCAP_ATOMIC2K
+                | libsqlite3_sys::SQLITE_IOCAP_ATOMIC4K
+                | libsqlite3_sys::SQLITE_IOCAP_ATOMIC8K
+                | libsqlite3_sys::SQLITE_IOCAP_ATOMIC16K
+                | libsqlite3_sys::SQLITE_IOCAP_ATOMIC32K
+                | libsqlite3_sys::SQLITE_IOCAP_ATOMIC64K);
+        }
+        flags
+    }
+}
+
+/// What a backend implements, as declared by [Vfs::capabilities](crate::Vfs::capabilities).
+///
+/// [register](crate::register) advertises the shared-memory methods only with
+/// [supports_shm](Self::supports_shm): without them, SQLite keeps the journal mode of a database
+/// when asked for `PRAGMA journal_mode=WAL`, outside of exclusive locking mode. Opens of the kinds
+/// the backend doesn't support fail with `SQLITE_CANTOPEN` and [Error::UnsupportedKind] before
+/// they reach it, e.g. the statement that needed a temporary file, see [VfsCapabilities::supports].
+///
+/// [Error::UnsupportedKind]: crate::error::Error::UnsupportedKind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VfsCapabilities {
+    /// Opening [OpenKind::Wal] files.
+    pub supports_wal: bool,
+    /// Opening temporary databases and journals: [OpenKind::TempDb], [OpenKind::TempJournal],
+    /// [OpenKind::TransientDb] and [OpenKind::SubJournal].
+    pub supports_temp_files: bool,
+    /// Opening [OpenKind::MainJournal] and [OpenKind::SuperJournal] files.
+    pub supports_journals: bool,
+    /// The WAL index in shared memory, see [crate::wip::WalIndex].
+    pub supports_shm: bool,
+    /// Writes between `SQLITE_FCNTL_BEGIN_ATOMIC_WRITE` and `SQLITE_FCNTL_COMMIT_ATOMIC_WRITE` land
+    /// all or none. Reported only: files don't advertise `SQLITE_IOCAP_BATCH_ATOMIC`, as those
+    /// file controls aren't passed on to handles yet.
+    pub atomic_batch: bool,
+}
+
+impl VfsCapabilities {
+    /// Everything but [atomic_batch](Self::atomic_batch).
+    pub const ALL: Self = Self {
+        supports_wal: true,
+        supports_temp_files: true,
+        supports_journals: true,
+        supports_shm: true,
+        atomic_batch: false,
+    };
+
+    /// What a backend with handles of type `H` supports, as far as its types tell: the WAL and its
+    /// index unless its [WalIndex] is disabled, and every other kind of file.
+    pub fn of<H: DatabaseHandle>() -> Self {
+        let wal = H::WalIndex::enabled();
+        Self {
+            supports_wal: wal,
+            supports_shm: wal,
+            ..Self::ALL
+        }
+    }
+
+    /// Whether files of `kind` can be opened.
+    pub fn supports(&self, kind: OpenKind) -> bool {
+        match kind {
+            OpenKind::MainDb => true,
+            OpenKind::MainJournal | OpenKind::SuperJournal => self.supports_journals,
+            OpenKind::TempDb
+            | OpenKind::TempJournal
+            | OpenKind::TransientDb
+            | OpenKind::SubJournal => self.supports_temp_files,
+            OpenKind::Wal => self.supports_wal,
+        }
+    }
+
+    /// The version of the I/O methods to register: 2 adds the shared-memory methods.
+    pub fn io_methods_version(&self) -> c_int {
+        if self.supports_shm {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+impl std::fmt::Display for VfsCapabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "wal={} temp_files={} journals={} shm={} atomic_batch={}",
+            self.supports_wal,
+            self.supports_temp_files,
+            self.supports_journals,
+            self.supports_shm,
+            self.atomic_batch,
+        )
+    }
+}
+
+/// Check that a file answering `sector_size` to `xSectorSize` is coherent with the device
+/// characteristics `flags` it advertises. SQLite would otherwise silently use another value: it
+/// clamps the sector size to 512..=[MAX_SECTOR_SIZE] and ignores it for files advertising
+/// `SQLITE_IOCAP_POWERSAFE_OVERWRITE`, so a larger sector is only honored with `psow=0`.
+pub fn check_sector_size(sector_size: u32, flags: c_int) -> Result<(), &'static str> {
+    if !sector_size.is_power_of_two()
+        || !(DEFAULT_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&sector_size)
+    {
+        return Err("the sector size must be a power of two from 512 to 65536 bytes");
+    }
+    if flags & libsqlite3_sys::SQLITE_IOCAP_POWERSAFE_OVERWRITE != 0
+        && sector_size != DEFAULT_SECTOR_SIZE
+    {
+        return Err("SQLite uses 512-byte sectors for files advertising powersafe overwrites");
+    }
+    Ok(())
+}
+
+/// Probe `library` before registering the VFS `name` with it, warning about each of
+/// [Capabilities::warnings].
+pub(crate) fn check(
+    name: &str,
+    library: &dyn SqliteLibrary,
+) -> Result<Capabilities, RegisterError> {
+    let capabilities = Capabilities::probe(library);
+    if !capabilities.supported() {
+        return Err(RegisterError::Unsupported(capabilities.version));
+    }
+    for warning in capabilities.warnings() {
+        tracing::warn!(target: "sqlite_vfs::vfs", name, "{warning}");
+    }
+    Ok(capabilities)
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sqlite={} threadsafe={} atomic_write={} batch_atomic_write={} direct_overflow_read={} mmap_disabled={}",
+            self.version,
+            self.threadsafe,
+            self.atomic_write,
+            self.batch_atomic_write,
+            self.direct_overflow_read,
+            self.mmap_disabled,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A library answering the probe with fixed values.
+    #[derive(Default)]
+    struct Mock {
+        version_number: c_int,
+        threadsafe: c_int,
+        options: Vec<&'static str>,
+    }
+
+    impl SqliteLibrary for Mock {
+        fn version_number(&self) -> c_int {
+            self.version_number
+        }
+
+        fn version(&self) -> String {
+            let n = self.version_number;
+            format!("{}.{}.{}", n / 1_000_000, n / 1000 % 1000, n % 1000)
+        }
+
+        fn threadsafe(&self) -> c_int {
+            self.threadsafe
+        }
+
+        fn compile_option_used(&self, option: &str) -> bool {
+            self.options.contains(&option)
+        }
+    }
+
+    #[test]
+    fn test_probe_linked() {
+        let linked = Capabilities::probe(&Linked);
+        assert!(linked.supported(), "{linked}");
+        assert!(linked.version.starts_with("3."), "{linked}");
+        assert_ne!(linked.threadsafe, 0);
+        assert!(linked.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_adjustments() {
+        let batch = libsqlite3_sys::SQLITE_IOCAP_BATCH_ATOMIC;
+        let atomic = libsqlite3_sys::SQLITE_IOCAP_ATOMIC4K;
+        let psow = libsqlite3_sys::SQLITE_IOCAP_POWERSAFE_OVERWRITE;
+
+        let plain = Capabilities::probe(&Mock {
+            version_number: 3_046_001,
+            threadsafe: 1,
+            options: vec![],
+        });
+        assert_eq!(plain.device_characteristics(batch | atomic | psow), psow);
+        assert!(plain.warnings().is_empty());
+
+        let batched = Capabilities::probe(&Mock {
+            version_number: 3_046_001,
+            threadsafe: 2,
+            options: vec![
+                "ENABLE_BATCH_ATOMIC_WRITE",
+                "DIRECT_OVERFLOW_READ",
+                "MAX_MMAP_SIZE=0",
+            ],
+        });
+        assert_eq!(
+            batched.device_characteristics(batch | atomic | psow),
+            batch | psow
+        );
+        assert_eq!(
+            batched.to_string(),
+            "sqlite=3.46.1 threadsafe=2 atomic_write=false batch_atomic_write=true \
+             direct_overflow_read=true mmap_disabled=true"
+        );
+
+        let single = Capabilities::probe(&Mock {
+            version_number: 3_046_001,
+            threadsafe: 0,
+            options: vec!["ENABLE_ATOMIC_WRITE"],
+        });
+        assert_eq!(single.device_characteristics(batch | atomic), atomic);
+        assert_eq!(single.warnings().len(), 1);
+    }
+
+    #[test]
+    fn test_refuse_old_library() {
+        let old = Mock {
+            version_number: 3_031_001,
+            threadsafe: 1,
+            ..Mock::default()
+        };
+        let err = check("old", &old).unwrap_err();
+        assert!(matches!(&err, RegisterError::Unsupported(version) if version == "3.31.1"));
+        assert_eq!(
+            err.to_string(),
+            "sqlite 3.31.1 is not supported, the minimum version is 3.33.0"
+        );
+
+        let oldest = Mock {
+            version_number: MIN_VERSION_NUMBER,
+            ..old
+        };
+        assert_eq!(check("oldest", &oldest).unwrap().version, MIN_VERSION);
+    }
+
+    #[test]
+    fn test_sector_size_coherence() {
+        let psow = libsqlite3_sys::SQLITE_IOCAP_POWERSAFE_OVERWRITE;
+        assert_eq!(check_sector_size(DEFAULT_SECTOR_SIZE, psow), Ok(()));
+        assert_eq!(check_sector_size(DEFAULT_SECTOR_SIZE, 0), Ok(()));
+        assert_eq!(check_sector_size(4096, 0), Ok(()));
+        assert_eq!(check_sector_size(MAX_SECTOR_SIZE, 0), Ok(()));
+
+        // ignored by SQLite, which would pad journals to 512 bytes regardless
+        assert!(check_sector_size(4096, psow).is_err());
+        // clamped by SQLite
+        for size in [0, 256, 1000, 2 * MAX_SECTOR_SIZE] {
+            assert!(check_sector_size(size, 0).is_err(), "{size}");
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! A clock for tests, to be returned by [Vfs::current_time](crate::Vfs::current_time).
+//!
+//! Backends that expire leases or back off between retries read the same clock, and usually
+//! advance it from [Vfs::sleep](crate::Vfs::sleep) instead of sleeping, so that waiting on a busy
+//! lock takes no wall-clock time and always plays out the same way.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl MockClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
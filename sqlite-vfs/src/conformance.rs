@@ -0,0 +1,853 @@
+//! A conformance kit for [Vfs] implementations.
+//!
+//! [run_all] runs the catalog of [SCENARIOS] against fresh instances of a backend and reports
+//! each as passed, failed (with what went wrong) or skipped. Scenarios of the
+//! [Category::Files] and [Category::Locking] categories call the backend directly; the others
+//! register it with SQLite and run statements through real connections, so they cover what
+//! SQLite relies on rather than what the traits spell out: short reads zero-filling the rest of
+//! the buffer, locks excluding other connections, journals being deleted and hot journals rolled
+//! back, temporary files being deleted on close.
+//!
+//! ```ignore
+//! let report = sqlite_vfs::conformance::run_all(
+//!     || SyncVfsAdapter::new(MyVfs::in_temp_dir()),
+//!     &ConformanceOptions {
+//!         skip: vec![Category::Wal],
+//!     },
+//! );
+//! assert!(report.passed(), "{report}");
+//! ```
+//!
+//! Every call of the factory must return a backend without any files, independent of the
+//! backends returned before. Scenarios name their files with relative names like `main.db`.
+//!
+//! A panic in a scenario calling the backend directly fails that scenario. A panic in a callback
+//! invoked by SQLite can't be unwound across it and aborts the process.
+
+use std::borrow::Cow;
+use std::ffi::{c_int, CStr, CString};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::capability::VfsCapabilities;
+use crate::error::Error;
+use crate::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// Creating, deleting, reading, writing and resizing files.
+    Files,
+    /// The lock state machine, and locks excluding other connections.
+    Locking,
+    /// Transactions in the default rollback journal mode.
+    Transactions,
+    /// Temporary files, e.g. for temporary tables.
+    TempFiles,
+    /// Transactions in WAL mode.
+    Wal,
+}
+
+impl std::fmt::Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Category::Files => "files",
+            Category::Locking => "locking",
+            Category::Transactions => "transactions",
+            Category::TempFiles => "temp files",
+            Category::Wal => "wal",
+        })
+    }
+}
+
+/// A requirement of SQLite on a VFS, and how [run_all] checks it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scenario {
+    pub id: &'static str,
+    pub category: Category,
+    pub description: &'static str,
+}
+
+/// The catalog of scenarios, in the order [run_all] runs them.
+pub const SCENARIOS: &[Scenario] = &[
+    Scenario {
+        id: "files.open-create",
+        category: Category::Files,
+        description: "Opening a missing file with OpenAccess::Create creates an empty file.",
+    },
+    Scenario {
+        id: "files.delete",
+        category: Category::Files,
+        description: "A deleted file no longer exists.",
+    },
+    Scenario {
+        id: "files.write-past-end",
+        category: Category::Files,
+        description: "Writing past the end of a file grows it, reading zeros in the gap.",
+    },
+    Scenario {
+        id: "files.short-read",
+        category: Category::Files,
+        description: "Reading past the end of a file fails with Error::UnexpectedEof and fills \
+                      the rest of the buffer with zeros.",
+    },
+    Scenario {
+        id: "files.set-len",
+        category: Category::Files,
+        description: "set_len truncates a file, or grows it with zeros.",
+    },
+    Scenario {
+        id: "locking.transitions",
+        category: Category::Locking,
+        description: "current_lock follows each lock and unlock of a handle, from none up to \
+                      exclusive and back.",
+    },
+    Scenario {
+        id: "locking.exclusion",
+        category: Category::Locking,
+        description: "A connection writing keeps other connections from writing, and one \
+                      holding an exclusive lock keeps them from reading.",
+    },
+    Scenario {
+        id: "transactions.roundtrip",
+        category: Category::Transactions,
+        description: "Rows committed through one connection are read back intact by the next.",
+    },
+    Scenario {
+        id: "transactions.journal-deleted",
+        category: Category::Transactions,
+        description: "The rollback journal no longer exists once a transaction commits.",
+    },
+    Scenario {
+        id: "transactions.hot-journal",
+        category: Category::Transactions,
+        description: "A database left with a hot journal by an interrupted transaction is \
+                      rolled back when next opened.",
+    },
+    Scenario {
+        id: "temp-files.delete-on-close",
+        category: Category::TempFiles,
+        description: "Temporary tables and sorts spill to temporary files, which are deleted \
+                      when closed.",
+    },
+    Scenario {
+        id: "wal.roundtrip",
+        category: Category::Wal,
+        description: "A database switches to WAL mode, and rows committed in it are read back \
+                      by the next connection.",
+    },
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct ConformanceOptions {
+    /// Categories the backend doesn't support. Their scenarios are reported as skipped.
+    pub skip: Vec<Category>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// Failed with what went wrong.
+    Failed(String),
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScenarioResult {
+    pub scenario: Scenario,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grade {
+    /// Every scenario passed.
+    Conformant,
+    /// Every scenario that ran passed, some were skipped.
+    Partial,
+    /// Some scenario failed.
+    NonConformant,
+}
+
+impl std::fmt::Display for Grade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Grade::Conformant => "conformant",
+            Grade::Partial => "conformant in the categories run",
+            Grade::NonConformant => "not conformant",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConformanceReport {
+    pub results: Vec<ScenarioResult>,
+}
+
+impl ConformanceReport {
+    /// Whether no scenario failed.
+    pub fn passed(&self) -> bool {
+        self.grade() != Grade::NonConformant
+    }
+
+    pub fn grade(&self) -> Grade {
+        if self.count(|outcome| matches!(outcome, Outcome::Failed(_))) > 0 {
+            Grade::NonConformant
+        } else if self.count(|outcome| *outcome == Outcome::Skipped) > 0 {
+            Grade::Partial
+        } else {
+            Grade::Conformant
+        }
+    }
+
+    /// The IDs of the scenarios that failed.
+    pub fn failed(&self) -> Vec<&'static str> {
+        self.results
+            .iter()
+            .filter(|result| matches!(result.outcome, Outcome::Failed(_)))
+            .map(|result| result.scenario.id)
+            .collect()
+    }
+
+    fn count(&self, f: impl Fn(&Outcome) -> bool) -> usize {
+        self.results
+            .iter()
+            .filter(|result| f(&result.outcome))
+            .count()
+    }
+}
+
+impl std::fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "VFS conformance: {} passed, {} failed, {} skipped: {}",
+            self.count(|outcome| *outcome == Outcome::Passed),
+            self.count(|outcome| matches!(outcome, Outcome::Failed(_))),
+            self.count(|outcome| *outcome == Outcome::Skipped),
+            self.grade()
+        )?;
+        let width = self
+            .results
+            .iter()
+            .map(|result| result.scenario.id.len())
+            .max()
+            .unwrap_or_default();
+        for result in &self.results {
+            let status = match result.outcome {
+                Outcome::Passed => "pass",
+                Outcome::Failed(_) => "FAIL",
+                Outcome::Skipped => "skip",
+            };
+            writeln!(
+                f,
+                "  {status}  {:width$}  {}",
+                result.scenario.id, result.scenario.description
+            )?;
+            if let Outcome::Failed(details) = &result.outcome {
+                for line in details.lines() {
+                    writeln!(f, "  {:width$}        {line}", "")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run every scenario of the catalog against backends returned by `factory`, see the
+/// [module documentation](self).
+pub fn run_all<V, F>(factory: impl Fn() -> V, options: &ConformanceOptions) -> ConformanceReport
+where
+    V: Vfs<Handle = F> + Send,
+    F: DatabaseHandle<Error = V::Error>,
+{
+    let results = SCENARIOS
+        .iter()
+        .map(|scenario| {
+            let outcome = if options.skip.contains(&scenario.category) {
+                Outcome::Skipped
+            } else {
+                let run = std::panic::catch_unwind(AssertUnwindSafe(|| run(scenario, &factory)));
+                match run {
+                    Ok(Ok(())) => Outcome::Passed,
+                    Ok(Err(details)) => Outcome::Failed(details),
+                    Err(panic) => Outcome::Failed(format!("panicked: {}", panic_message(&panic))),
+                }
+            };
+            tracing::debug!(target: "sqlite_vfs::vfs", id = scenario.id, ?outcome, "conformance");
+            ScenarioResult {
+                scenario: *scenario,
+                outcome,
+            }
+        })
+        .collect();
+    ConformanceReport { results }
+}
+
+fn run<V, F>(scenario: &Scenario, factory: &impl Fn() -> V) -> Result<(), String>
+where
+    V: Vfs<Handle = F> + Send,
+    F: DatabaseHandle<Error = V::Error>,
+{
+    match scenario.id {
+        "files.open-create" => block_on(open_create(factory())),
+        "files.delete" => block_on(delete(factory())),
+        "files.write-past-end" => block_on(write_past_end(factory())),
+        "files.short-read" => block_on(short_read(factory())),
+        "files.set-len" => block_on(set_len(factory())),
+        "locking.transitions" => block_on(lock_transitions(factory())),
+        "locking.exclusion" => lock_exclusion(&Backend::register(factory())?),
+        "transactions.roundtrip" => roundtrip(&Backend::register(factory())?, "DELETE"),
+        "transactions.journal-deleted" => journal_deleted(&Backend::register(factory())?),
+        "transactions.hot-journal" => hot_journal(factory),
+        "temp-files.delete-on-close" => temp_files(&Backend::register(factory())?),
+        "wal.roundtrip" => roundtrip(&Backend::register(factory())?, "WAL"),
+        id => Err(format!("unknown scenario {id}")),
+    }
+}
+
+fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_owned(),
+    }
+}
+
+/// Run `future` to completion, with the I/O and time drivers a backend talking to a remote store
+/// needs. SQLite callbacks start a runtime of their own, so the scenarios going through SQLite must
+/// not run within this one.
+fn block_on<T>(future: impl Future<Output = T>) -> T {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start a runtime")
+        .block_on(future)
+}
+
+/// Describe a failed call of the backend.
+fn call<T, E: std::error::Error>(what: &str, res: Result<T, Error<E>>) -> Result<T, String> {
+    res.map_err(|err| format!("{what} failed: {}", err.describe()))
+}
+
+fn ensure(condition: bool, details: impl FnOnce() -> String) -> Result<(), String> {
+    match condition {
+        true => Ok(()),
+        false => Err(details()),
+    }
+}
+
+fn create() -> OpenOptions {
+    OpenOptions::new(OpenKind::MainDb, OpenAccess::Create)
+}
+
+async fn read_all<F: DatabaseHandle>(file: &mut F) -> Result<Vec<u8>, String> {
+    let size = call("size", file.size().await)?;
+    let mut buf = vec![0; size as usize];
+    call("read_exact_at", file.read_exact_at(&mut buf, 0).await)?;
+    Ok(buf)
+}
+
+async fn open_create<V: Vfs>(vfs: V) -> Result<(), String> {
+    let exists = call("exists", vfs.exists("main.db").await)?;
+    ensure(!exists, || {
+        "main.db exists before it was created".to_owned()
+    })?;
+    let mut file = call("open", vfs.open("main.db", create()).await)?;
+    let exists = call("exists", vfs.exists("main.db").await)?;
+    ensure(exists, || "main.db doesn't exist once opened".to_owned())?;
+    let size = call("size", file.size().await)?;
+    ensure(size == 0, || format!("a new file has {size} bytes"))
+}
+
+async fn delete<V: Vfs>(vfs: V) -> Result<(), String> {
+    let mut file = call("open", vfs.open("main.db", create()).await)?;
+    call("write_all_at", file.write_all_at(&[1; 4], 0).await)?;
+    drop(file);
+    call("delete", vfs.delete("main.db").await)?;
+    let exists = call("exists", vfs.exists("main.db").await)?;
+    ensure(!exists, || "main.db still exists once deleted".to_owned())
+}
+
+async fn write_past_end<V: Vfs>(vfs: V) -> Result<(), String> {
+    let mut file = call("open", vfs.open("main.db", create()).await)?;
+    call("write_all_at", file.write_all_at(&[1; 4], 8192).await)?;
+    let size = call("size", file.size().await)?;
+    ensure(size == 8196, || {
+        format!("size is {size} after writing 4 bytes at 8192, not 8196")
+    })?;
+    let data = read_all(&mut file).await?;
+    if let Some(at) = data[..8192].iter().position(|b| *b != 0) {
+        return Err(format!("byte {at} of the gap is {:#04x}, not 0", data[at]));
+    }
+    ensure(data[8192..] == [1; 4], || {
+        format!("read {:?} back instead of the bytes written", &data[8192..])
+    })
+}
+
+async fn short_read<V: Vfs>(vfs: V) -> Result<(), String> {
+    let mut file = call("open", vfs.open("main.db", create()).await)?;
+    call("write_all_at", file.write_all_at(&[7; 100], 0).await)?;
+    for (offset, available) in [(50, 50), (1000, 0)] {
+        let mut buf = [0xaa; 200];
+        match file.read_exact_at(&mut buf, offset).await {
+            Err(Error::UnexpectedEof) => {}
+            Ok(()) => {
+                return Err(format!(
+                    "reading 200 bytes at {offset} of a 100 byte file succeeded"
+                ))
+            }
+            Err(err) => {
+                return Err(format!(
+                    "reading 200 bytes at {offset} of a 100 byte file failed with {:?} instead \
+                     of Error::UnexpectedEof",
+                    err.describe()
+                ))
+            }
+        }
+        ensure(buf[..available].iter().all(|b| *b == 7), || {
+            format!("the {available} bytes available at {offset} weren't read")
+        })?;
+        if let Some(at) = buf[available..].iter().position(|b| *b != 0) {
+            return Err(format!(
+                "byte {} of a short read at {offset} is {:#04x}, not 0",
+                available + at,
+                buf[available + at]
+            ));
+        }
+    }
+    Ok(())
+}
+
+async fn set_len<V: Vfs>(vfs: V) -> Result<(), String> {
+    let mut file = call("open", vfs.open("main.db", create()).await)?;
+    call("write_all_at", file.write_all_at(&[7; 10], 0).await)?;
+    call("set_len", file.set_len(4096).await)?;
+    let data = read_all(&mut file).await?;
+    ensure(data.len() == 4096, || {
+        format!("size is {} after growing to 4096", data.len())
+    })?;
+    ensure(data[..10] == [7; 10], || {
+        "growing changed the existing bytes".to_owned()
+    })?;
+    if let Some(at) = data[10..].iter().position(|b| *b != 0) {
+        return Err(format!(
+            "byte {} is {:#04x} after growing, not 0",
+            10 + at,
+            data[10 + at]
+        ));
+    }
+    call("set_len", file.set_len(5).await)?;
+    let data = read_all(&mut file).await?;
+    ensure(data == [7; 5], || {
+        format!("read {data:?} after truncating to 5 bytes")
+    })
+}
+
+async fn lock_transitions<V: Vfs>(vfs: V) -> Result<(), String> {
+    let mut file = call("open", vfs.open("main.db", create()).await)?;
+    let lock = call("current_lock", file.current_lock().await)?;
+    ensure(lock == LockKind::None, || {
+        format!("a new handle holds a {lock:?} lock")
+    })?;
+    let steps = [
+        (true, LockKind::Shared),
+        (true, LockKind::Reserved),
+        (true, LockKind::Exclusive),
+        (false, LockKind::Shared),
+        (false, LockKind::None),
+    ];
+    for (acquire, lock) in steps {
+        let granted = match acquire {
+            true => call("lock", file.lock(lock).await)?,
+            false => call("unlock", file.unlock(lock).await)?,
+        };
+        ensure(granted, || {
+            format!("a {lock:?} lock wasn't granted without contention")
+        })?;
+        let current = call("current_lock", file.current_lock().await)?;
+        ensure(current == lock, || {
+            format!("current_lock is {current:?} after moving to {lock:?}")
+        })?;
+    }
+    Ok(())
+}
+
+fn lock_exclusion<V: Vfs + Send>(backend: &Backend<V>) -> Result<(), String> {
+    let a = backend.connect("main.db")?;
+    let b = backend.connect("main.db")?;
+    a.execute("CREATE TABLE t (n INTEGER)")?;
+    a.execute("BEGIN IMMEDIATE; INSERT INTO t VALUES (1)")?;
+    match b.execute("BEGIN IMMEDIATE") {
+        Err(err) if err.contains("SQLITE_BUSY") || err.contains("locked") => {}
+        Err(err) => {
+            return Err(format!(
+                "a second writer failed with {err}, not SQLITE_BUSY"
+            ))
+        }
+        Ok(()) => return Err("a second connection started writing alongside the first".into()),
+    }
+    a.execute("COMMIT")?;
+    b.execute("BEGIN IMMEDIATE; INSERT INTO t VALUES (2); COMMIT")?;
+
+    a.execute("BEGIN EXCLUSIVE")?;
+    if b.query("SELECT count(*) FROM t").is_ok() {
+        return Err("a connection read while another held an exclusive lock".into());
+    }
+    a.execute("COMMIT")?;
+    let count = b.query("SELECT count(*) FROM t")?;
+    ensure(count == "2", || format!("read {count} rows instead of 2"))
+}
+
+fn roundtrip<V: Vfs + Send>(backend: &Backend<V>, journal_mode: &str) -> Result<(), String> {
+    {
+        let conn = backend.connect("main.db")?;
+        let mode = conn.query(&format!("PRAGMA journal_mode = {journal_mode}"))?;
+        ensure(mode.eq_ignore_ascii_case(journal_mode), || {
+            format!("journal_mode is {mode} instead of {journal_mode}")
+        })?;
+        conn.execute(
+            "CREATE TABLE t (n INTEGER, b BLOB);
+             BEGIN;
+             WITH RECURSIVE c(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM c WHERE n < 500)
+             INSERT INTO t SELECT n, zeroblob(300) FROM c;
+             COMMIT;",
+        )?;
+    }
+    let conn = backend.connect("main.db")?;
+    let count = conn.query("SELECT count(*) || ',' || sum(n) FROM t")?;
+    ensure(count == "500,125250", || {
+        format!("read back {count} as the count and sum of 500 rows")
+    })?;
+    let integrity = conn.query("PRAGMA integrity_check")?;
+    ensure(integrity == "ok", || {
+        format!("integrity check: {integrity}")
+    })
+}
+
+fn journal_deleted<V: Vfs + Send>(backend: &Backend<V>) -> Result<(), String> {
+    let conn = backend.connect("main.db")?;
+    conn.execute("CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1)")?;
+    let journals = backend.opened(&[OpenKind::MainJournal]);
+    ensure(!journals.is_empty(), || "no journal was opened".to_owned())?;
+    for journal in journals {
+        let exists = call("exists", block_on(backend.vfs.exists(&journal)))?;
+        ensure(!exists, || format!("{journal} exists after the commit"))?;
+    }
+    Ok(())
+}
+
+fn hot_journal<V, F>(factory: &impl Fn() -> V) -> Result<(), String>
+where
+    V: Vfs<Handle = F> + Send,
+    F: DatabaseHandle<Error = V::Error>,
+{
+    let crashed = Backend::register(factory())?;
+    let conn = crashed.connect("main.db")?;
+    conn.execute(
+        "CREATE TABLE t (n INTEGER, b BLOB);
+         INSERT INTO t VALUES (1, zeroblob(100));",
+    )?;
+    // with a tiny page cache, the transaction spills to the database before committing
+    conn.execute(
+        "PRAGMA cache_size = 2;
+         BEGIN;
+         WITH RECURSIVE c(n) AS (SELECT 2 UNION ALL SELECT n + 1 FROM c WHERE n < 300)
+         INSERT INTO t SELECT n, zeroblob(1000) FROM c;",
+    )?;
+    // copy the files as they are now, as if the process crashed
+    let (db, journal) = match (
+        crashed.opened(&[OpenKind::MainDb]).first(),
+        crashed.opened(&[OpenKind::MainJournal]).first(),
+    ) {
+        (Some(db), Some(journal)) => (db.clone(), journal.clone()),
+        _ => return Err("the transaction opened no journal".to_owned()),
+    };
+    let files = block_on(async {
+        let mut files = Vec::new();
+        for (name, kind) in [(&db, OpenKind::MainDb), (&journal, OpenKind::MainJournal)] {
+            let opts = OpenOptions::new(kind, OpenAccess::Read);
+            let mut file = call("open", crashed.vfs.open(name, opts).await)?;
+            files.push(read_all(&mut file).await?);
+        }
+        Ok::<_, String>(files)
+    })?;
+    drop(conn);
+
+    // written the way SQLite writes them: the database under an exclusive lock, and both synced
+    let recovered = Backend::register(factory())?;
+    block_on(async {
+        let mut file = call("open", recovered.vfs.open("main.db", create()).await)?;
+        call("lock", file.lock(LockKind::Exclusive).await)?;
+        call("write_all_at", file.write_all_at(&files[0], 0).await)?;
+        call("sync", file.sync(false).await)?;
+        call("unlock", file.unlock(LockKind::None).await)?;
+        let opts = OpenOptions::new(OpenKind::MainJournal, OpenAccess::Create);
+        let mut file = call("open", recovered.vfs.open("main.db-journal", opts).await)?;
+        call("write_all_at", file.write_all_at(&files[1], 0).await)?;
+        call("sync", file.sync(false).await)?;
+        Ok::<_, String>(())
+    })?;
+    let conn = recovered.connect("main.db")?;
+    let count = conn.query("SELECT count(*) FROM t")?;
+    ensure(count == "1", || {
+        format!("read {count} rows instead of the 1 committed before the interrupted transaction")
+    })?;
+    let integrity = conn.query("PRAGMA integrity_check")?;
+    ensure(integrity == "ok", || {
+        format!("integrity check: {integrity}")
+    })?;
+    let journals = recovered.opened(&[OpenKind::MainJournal]);
+    for journal in journals {
+        let exists = call("exists", block_on(recovered.vfs.exists(&journal)))?;
+        ensure(!exists, || format!("{journal} exists after the rollback"))?;
+    }
+    Ok(())
+}
+
+fn temp_files<V: Vfs + Send>(backend: &Backend<V>) -> Result<(), String> {
+    {
+        let conn = backend.connect("main.db")?;
+        conn.execute(
+            "PRAGMA temp_store = FILE;
+             PRAGMA temp.cache_size = 2;
+             CREATE TEMP TABLE t AS
+             WITH RECURSIVE c(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM c WHERE n < 2000)
+             SELECT n, randomblob(200) AS b FROM c;",
+        )?;
+        let count = conn.query("SELECT count(*) FROM (SELECT n FROM t ORDER BY b)")?;
+        ensure(count == "2000", || {
+            format!("sorted {count} rows instead of 2000")
+        })?;
+    }
+    let temp = backend.opened(&[
+        OpenKind::TempDb,
+        OpenKind::TempJournal,
+        OpenKind::TransientDb,
+        OpenKind::SubJournal,
+    ]);
+    ensure(!temp.is_empty(), || {
+        "no temporary file was opened".to_owned()
+    })?;
+    for name in temp {
+        let exists = call("exists", block_on(backend.vfs.exists(&name)))?;
+        ensure(!exists, || format!("{name} exists after it was closed"))?;
+    }
+    Ok(())
+}
+
+static NEXT_BACKEND: AtomicUsize = AtomicUsize::new(0);
+
+/// A backend registered with SQLite, recording the files SQLite opens, until dropped.
+struct Backend<V> {
+    name: String,
+    vfs: Arc<V>,
+    opened: Arc<Mutex<Vec<(String, OpenKind)>>>,
+    _registration: crate::VfsHandle,
+}
+
+impl<V: Vfs + Send> Backend<V> {
+    fn register<F>(vfs: V) -> Result<Self, String>
+    where
+        V: Vfs<Handle = F> + Send,
+        F: DatabaseHandle<Error = V::Error>,
+    {
+        let name = format!(
+            "conformance-{}",
+            NEXT_BACKEND.fetch_add(1, Ordering::Relaxed)
+        );
+        let vfs = Arc::new(vfs);
+        let opened = Arc::default();
+        let recorder = Recorder {
+            vfs: vfs.clone(),
+            opened: Arc::clone(&opened),
+        };
+        let registration = crate::register(&name, recorder, false)
+            .map_err(|err| format!("registering the backend failed: {err}"))?;
+        Ok(Self {
+            name,
+            vfs,
+            opened,
+            _registration: registration,
+        })
+    }
+
+    fn connect(&self, db: &str) -> Result<Connection, String> {
+        Connection::open(&self.name, db)
+    }
+
+    /// The names of the files of `kinds` SQLite opened so far.
+    fn opened(&self, kinds: &[OpenKind]) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .opened
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, kind)| kinds.contains(kind))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.dedup();
+        names
+    }
+}
+
+struct Recorder<V> {
+    vfs: Arc<V>,
+    opened: Arc<Mutex<Vec<(String, OpenKind)>>>,
+}
+
+impl<V: Vfs + Send> Vfs for Recorder<V> {
+    type Handle = V::Handle;
+    type Error = V::Error;
+
+    fn open(
+        &self,
+        db: &str,
+        opts: OpenOptions,
+    ) -> impl Future<Output = Result<Self::Handle, Error<Self::Error>>> {
+        self.opened.lock().unwrap().push((db.to_owned(), opts.kind));
+        self.vfs.open(db, opts)
+    }
+
+    fn delete(&self, db: &str) -> impl Future<Output = Result<(), Error<Self::Error>>> {
+        self.vfs.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> impl Future<Output = Result<bool, Error<Self::Error>>> + Send {
+        self.vfs.exists(db)
+    }
+
+    fn temporary_name(&self) -> impl Future<Output = String> {
+        self.vfs.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [u8]) -> impl Future<Output = ()> {
+        self.vfs.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> impl Future<Output = Duration> {
+        self.vfs.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.vfs.current_time()
+    }
+
+    fn capabilities(&self) -> VfsCapabilities {
+        self.vfs.capabilities()
+    }
+
+    fn access(
+        &self,
+        db: &str,
+        write: bool,
+    ) -> impl Future<Output = Result<bool, Error<Self::Error>>> {
+        self.vfs.access(db, write)
+    }
+
+    fn full_pathname<'a>(
+        &self,
+        db: &'a str,
+    ) -> impl Future<Output = Result<Cow<'a, str>, Error<Self::Error>>> {
+        self.vfs.full_pathname(db)
+    }
+}
+
+/// A connection through the SQLite library linked into this crate.
+struct Connection(*mut libsqlite3_sys::sqlite3);
+
+impl Connection {
+    fn open(vfs: &str, db: &str) -> Result<Self, String> {
+        let (vfs, db) = (CString::new(vfs).unwrap(), CString::new(db).unwrap());
+        let mut conn = std::ptr::null_mut();
+        let flags = libsqlite3_sys::SQLITE_OPEN_READWRITE | libsqlite3_sys::SQLITE_OPEN_CREATE;
+        // SAFETY: the names are nul-terminated strings, and `conn` where to write the connection
+        let rc =
+            unsafe { libsqlite3_sys::sqlite3_open_v2(db.as_ptr(), &mut conn, flags, vfs.as_ptr()) };
+        let conn = Self(conn);
+        match rc {
+            libsqlite3_sys::SQLITE_OK => Ok(conn),
+            rc => Err(format!("opening the database failed: {}", conn.error(rc))),
+        }
+    }
+
+    fn error(&self, rc: c_int) -> String {
+        // SAFETY: both return nul-terminated strings valid until the next call on the connection,
+        // which is open unless null
+        let (name, message) = unsafe {
+            let name = CStr::from_ptr(libsqlite3_sys::sqlite3_errstr(rc));
+            let message = match self.0.is_null() {
+                true => name,
+                false => CStr::from_ptr(libsqlite3_sys::sqlite3_errmsg(self.0)),
+            };
+            (name.to_string_lossy(), message.to_string_lossy())
+        };
+        let code = match rc & 0xff {
+            libsqlite3_sys::SQLITE_BUSY => "SQLITE_BUSY".to_owned(),
+            _ => format!("code {rc}"),
+        };
+        format!("{message} ({name}, {code})")
+    }
+
+    fn execute(&self, sql: &str) -> Result<(), String> {
+        let c_sql = CString::new(sql).unwrap();
+        // SAFETY: the connection is open, and `c_sql` a nul-terminated string
+        let rc = unsafe {
+            libsqlite3_sys::sqlite3_exec(
+                self.0,
+                c_sql.as_ptr(),
+                None,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        match rc {
+            libsqlite3_sys::SQLITE_OK => Ok(()),
+            rc => Err(format!("{sql:?} failed: {}", self.error(rc))),
+        }
+    }
+
+    /// The first column of the first row of `sql`, as text.
+    fn query(&self, sql: &str) -> Result<String, String> {
+        let c_sql = CString::new(sql).unwrap();
+        let mut stmt = std::ptr::null_mut();
+        // SAFETY: the connection is open, `c_sql` a nul-terminated string, and `stmt` is only
+        // stepped and read once prepared, then finalized
+        unsafe {
+            let rc = libsqlite3_sys::sqlite3_prepare_v2(
+                self.0,
+                c_sql.as_ptr(),
+                -1,
+                &mut stmt,
+                std::ptr::null_mut(),
+            );
+            if rc != libsqlite3_sys::SQLITE_OK {
+                return Err(format!("{sql:?} failed: {}", self.error(rc)));
+            }
+            let rc = libsqlite3_sys::sqlite3_step(stmt);
+            let res = match rc {
+                libsqlite3_sys::SQLITE_ROW => {
+                    let text = libsqlite3_sys::sqlite3_column_text(stmt, 0);
+                    match text.is_null() {
+                        true => Ok(String::new()),
+                        false => Ok(CStr::from_ptr(text as _).to_string_lossy().into_owned()),
+                    }
+                }
+                libsqlite3_sys::SQLITE_DONE => Err(format!("{sql:?} returned no rows")),
+                rc => Err(format!("{sql:?} failed: {}", self.error(rc))),
+            };
+            libsqlite3_sys::sqlite3_finalize(stmt);
+            res
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // SAFETY: the connection was opened, or is null, and isn't used anymore
+        unsafe {
+            libsqlite3_sys::sqlite3_close(self.0);
+        }
+    }
+}
@@ -19,8 +19,11 @@ pub enum Error<External = Box<dyn std::error::Error>> {
     #[snafu(display("path too long"))]
     PathTooLong,
 
-    #[snafu(display("invalid open flags"))]
-    InvalidOpenFlags,
+    #[snafu(display("invalid open flags {flags:#x}: {reason}"))]
+    InvalidOpenFlags {
+        flags: i32,
+        reason: &'static str,
+    },
 
     #[snafu(display("invalid file pointer"))]
     InvalidFilePtr,
@@ -31,6 +34,11 @@ pub enum Error<External = Box<dyn std::error::Error>> {
     #[snafu(display("received null pointer"))]
     NullPtr,
 
+    #[snafu(display("invalid buffer length {len}"))]
+    InvalidLength {
+        len: i32,
+    },
+
     #[snafu(display("write zero (???)"))]
     WriteZero,
 
@@ -47,9 +55,50 @@ pub enum Error<External = Box<dyn std::error::Error>> {
     #[snafu(display("wal is disabled"))]
     WalDisabled,
 
+    /// The backend doesn't open files of `kind`, see [crate::capability::VfsCapabilities].
+    #[snafu(display("the backend can't open {kind:?} files"))]
+    UnsupportedKind {
+        kind: crate::OpenKind,
+    },
+
     #[snafu(display("trying to lock wal index, which isn't created yet"))]
     WalIndexLock,
 
+    /// The wal index kept changing while being read, see [crate::wip::WalIndex::refresh].
+    /// Reported to SQLite as `SQLITE_BUSY`.
+    #[snafu(display("wal index kept changing while being read ({attempts} attempts)"))]
+    WalIndexUnstable {
+        attempts: usize,
+    },
+
+    /// The backend is temporarily unable to serve the request. Reported to SQLite as
+    /// `SQLITE_BUSY` instead of an I/O error.
+    #[snafu(display("database is busy"))]
+    Busy {
+        cause: External,
+    },
+
+    /// The operation would exceed a size limit of the backend. Reported to SQLite as
+    /// `SQLITE_FULL` instead of an I/O error.
+    #[snafu(display("database or disk is full"))]
+    Full {
+        cause: External,
+    },
+
+    /// The backend is out of the memory it may use. Reported to SQLite as `SQLITE_NOMEM`
+    /// instead of an I/O error.
+    #[snafu(display("out of memory"))]
+    NoMem {
+        cause: External,
+    },
+
+    /// The backend was denied access to its storage. Reported to SQLite as `SQLITE_AUTH`
+    /// instead of an I/O error.
+    #[snafu(display("access denied"))]
+    Auth {
+        cause: External,
+    },
+
     External {
         cause: External,
     },
@@ -60,3 +109,19 @@ impl<T> From<T> for Error<T> {
         Self::External { cause: value }
     }
 }
+
+impl<E: std::fmt::Display> Error<E> {
+    /// The error message, including the message of an external cause.
+    pub fn describe(&self) -> String {
+        match self {
+            Error::Busy { cause }
+            | Error::Full { cause }
+            | Error::NoMem { cause }
+            | Error::Auth { cause } => {
+                format!("{self}: {cause}")
+            }
+            Error::External { cause } => cause.to_string(),
+            err => err.to_string(),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Names of the `SQLITE_FCNTL_*` file control opcodes, and a coverage report of the ones a
+//! workload sends.
+//!
+//! Most opcodes are answered with `SQLITE_NOTFOUND` or acknowledged with `SQLITE_OK` without
+//! doing anything (see [crate::io::file_control_inner]), which is what SQLite expects, but hides
+//! which of them a workload actually exercises. Registering a [FileControlCoverage] as the
+//! [Instrumentation] of a VFS (see [crate::register_instrumented]) counts every file control by
+//! opcode, logs opcodes missing from [OPCODES] at `DEBUG` with their numeric value, and warns
+//! once per opcode about the [stubs](is_stub) the VFS acknowledges without handling.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::raw::c_int;
+use std::sync::Mutex;
+
+use crate::instrument::{CallbackDetails, CallbackKind, Instrumentation, Phase};
+
+/// Every file control opcode known to this crate, with its name without the `SQLITE_FCNTL_`
+/// prefix.
+pub const OPCODES: &[(c_int, &str)] = &[
+    (libsqlite3_sys::SQLITE_FCNTL_LOCKSTATE, "LOCKSTATE"),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_GET_LOCKPROXYFILE,
+        "GET_LOCKPROXYFILE",
+    ),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_SET_LOCKPROXYFILE,
+        "SET_LOCKPROXYFILE",
+    ),
+    (libsqlite3_sys::SQLITE_FCNTL_LAST_ERRNO, "LAST_ERRNO"),
+    (libsqlite3_sys::SQLITE_FCNTL_SIZE_HINT, "SIZE_HINT"),
+    (libsqlite3_sys::SQLITE_FCNTL_CHUNK_SIZE, "CHUNK_SIZE"),
+    (libsqlite3_sys::SQLITE_FCNTL_FILE_POINTER, "FILE_POINTER"),
+    (libsqlite3_sys::SQLITE_FCNTL_SYNC_OMITTED, "SYNC_OMITTED"),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_WIN32_AV_RETRY,
+        "WIN32_AV_RETRY",
+    ),
+    (libsqlite3_sys::SQLITE_FCNTL_PERSIST_WAL, "PERSIST_WAL"),
+    (libsqlite3_sys::SQLITE_FCNTL_OVERWRITE, "OVERWRITE"),
+    (libsqlite3_sys::SQLITE_FCNTL_VFSNAME, "VFSNAME"),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_POWERSAFE_OVERWRITE,
+        "POWERSAFE_OVERWRITE",
+    ),
+    (libsqlite3_sys::SQLITE_FCNTL_PRAGMA, "PRAGMA"),
+    (libsqlite3_sys::SQLITE_FCNTL_BUSYHANDLER, "BUSYHANDLER"),
+    (libsqlite3_sys::SQLITE_FCNTL_TEMPFILENAME, "TEMPFILENAME"),
+    (libsqlite3_sys::SQLITE_FCNTL_MMAP_SIZE, "MMAP_SIZE"),
+    (libsqlite3_sys::SQLITE_FCNTL_TRACE, "TRACE"),
+    (libsqlite3_sys::SQLITE_FCNTL_HAS_MOVED, "HAS_MOVED"),
+    (libsqlite3_sys::SQLITE_FCNTL_SYNC, "SYNC"),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_COMMIT_PHASETWO,
+        "COMMIT_PHASETWO",
+    ),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_WIN32_SET_HANDLE,
+        "WIN32_SET_HANDLE",
+    ),
+    (libsqlite3_sys::SQLITE_FCNTL_WAL_BLOCK, "WAL_BLOCK"),
+    (libsqlite3_sys::SQLITE_FCNTL_ZIPVFS, "ZIPVFS"),
+    (libsqlite3_sys::SQLITE_FCNTL_RBU, "RBU"),
+    (libsqlite3_sys::SQLITE_FCNTL_VFS_POINTER, "VFS_POINTER"),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_JOURNAL_POINTER,
+        "JOURNAL_POINTER",
+    ),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_WIN32_GET_HANDLE,
+        "WIN32_GET_HANDLE",
+    ),
+    (libsqlite3_sys::SQLITE_FCNTL_PDB, "PDB"),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE,
+        "BEGIN_ATOMIC_WRITE",
+    ),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE,
+        "COMMIT_ATOMIC_WRITE",
+    ),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE,
+        "ROLLBACK_ATOMIC_WRITE",
+    ),
+    (libsqlite3_sys::SQLITE_FCNTL_LOCK_TIMEOUT, "LOCK_TIMEOUT"),
+    (libsqlite3_sys::SQLITE_FCNTL_DATA_VERSION, "DATA_VERSION"),
+    (libsqlite3_sys::SQLITE_FCNTL_SIZE_LIMIT, "SIZE_LIMIT"),
+    (libsqlite3_sys::SQLITE_FCNTL_CKPT_DONE, "CKPT_DONE"),
+    (libsqlite3_sys::SQLITE_FCNTL_RESERVE_BYTES, "RESERVE_BYTES"),
+    (libsqlite3_sys::SQLITE_FCNTL_CKPT_START, "CKPT_START"),
+    (
+        libsqlite3_sys::SQLITE_FCNTL_EXTERNAL_READER,
+        "EXTERNAL_READER",
+    ),
+    (libsqlite3_sys::SQLITE_FCNTL_CKSM_FILE, "CKSM_FILE"),
+    (libsqlite3_sys::SQLITE_FCNTL_RESET_CACHE, "RESET_CACHE"),
+];
+
+/// The name of `op` without the `SQLITE_FCNTL_` prefix, e.g. `BUSYHANDLER`.
+pub fn name(op: c_int) -> Option<&'static str> {
+    OPCODES
+        .iter()
+        .find(|(known, _)| *known == op)
+        .map(|(_, name)| *name)
+}
+
+/// Whether the VFS acknowledges `op` with `SQLITE_OK` without doing anything.
+pub fn is_stub(op: c_int) -> bool {
+    matches!(
+        op,
+        libsqlite3_sys::SQLITE_FCNTL_SYNC
+            | libsqlite3_sys::SQLITE_FCNTL_COMMIT_PHASETWO
+            | libsqlite3_sys::SQLITE_FCNTL_CKPT_DONE
+            | libsqlite3_sys::SQLITE_FCNTL_CKPT_START
+    )
+}
+
+/// Counts file controls by opcode, see the [module documentation](self).
+#[derive(Debug, Default)]
+pub struct FileControlCoverage {
+    counts: Mutex<BTreeMap<c_int, u64>>,
+    warned: Mutex<BTreeSet<c_int>>,
+}
+
+impl FileControlCoverage {
+    pub fn record(&self, op: c_int) {
+        *self.counts.lock().unwrap().entry(op).or_default() += 1;
+        if name(op).is_none() {
+            tracing::debug!(target: "sqlite_vfs::io", op, "unknown file control");
+        }
+        if is_stub(op) && self.warned.lock().unwrap().insert(op) {
+            tracing::warn!(
+                target: "sqlite_vfs::io",
+                op = name(op),
+                "file control acknowledged without being handled"
+            );
+        }
+    }
+
+    /// The number of file controls sent so far by opcode, in the order of their values. Unknown
+    /// opcodes are named `unknown(<op>)`.
+    pub fn report(&self) -> Vec<(String, u64)> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(op, count)| match name(*op) {
+                Some(name) => (name.to_owned(), *count),
+                None => (format!("unknown({op})"), *count),
+            })
+            .collect()
+    }
+}
+
+impl Instrumentation for FileControlCoverage {
+    fn on_callback(&self, kind: CallbackKind, _db: &str, details: CallbackDetails) {
+        if let (CallbackKind::FileControl, Phase::Enter, Some(op)) =
+            (kind, details.phase, details.arg)
+        {
+            self.record(op);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_cover_file_control() {
+        let io = include_str!("io.rs");
+        let mut referenced = io
+            .split("libsqlite3_sys::SQLITE_FCNTL_")
+            .skip(1)
+            .map(|rest| {
+                rest.split(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+                    .next()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        referenced.sort_unstable();
+        referenced.dedup();
+        assert!(referenced.len() > 30, "{referenced:?}");
+        for op in referenced {
+            assert!(
+                OPCODES.iter().any(|(_, name)| *name == op),
+                "SQLITE_FCNTL_{op} is missing from OPCODES"
+            );
+        }
+
+        assert_eq!(
+            name(libsqlite3_sys::SQLITE_FCNTL_BUSYHANDLER),
+            Some("BUSYHANDLER")
+        );
+        assert_eq!(name(17), None);
+    }
+
+    #[test]
+    fn test_report() {
+        let coverage = FileControlCoverage::default();
+        for op in [
+            libsqlite3_sys::SQLITE_FCNTL_SYNC,
+            libsqlite3_sys::SQLITE_FCNTL_PRAGMA,
+            libsqlite3_sys::SQLITE_FCNTL_SYNC,
+            1000,
+        ] {
+            coverage.record(op);
+        }
+        assert_eq!(
+            coverage.report(),
+            vec![
+                ("PRAGMA".to_owned(), 1),
+                ("SYNC".to_owned(), 2),
+                ("unknown(1000)".to_owned(), 1)
+            ]
+        );
+        // warned about once
+        assert_eq!(
+            *coverage.warned.lock().unwrap(),
+            BTreeSet::from([libsqlite3_sys::SQLITE_FCNTL_SYNC])
+        );
+    }
+}
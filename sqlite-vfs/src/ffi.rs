@@ -0,0 +1,763 @@
+//! Safe views of the raw pointers SQLite passes to the callbacks of a VFS.
+//!
+//! The callbacks in [crate::io] and [crate::vfs] wrap their raw arguments on entry, in one
+//! `unsafe` block stating what SQLite guarantees about them, and only use the wrappers from then
+//! on. The dereferences, casts and `MaybeUninit` accesses are thus all in this module, each block
+//! naming the invariant it relies on and who upholds it:
+//!
+//! | Wrapper | Wraps | Checked once |
+//! |---|---|---|
+//! | [VfsRef] | `sqlite3_vfs*` | not null, not retired |
+//! | [FileSlot] | the `sqlite3_file*` passed to `xOpen` | not null |
+//! | [FileRef] | any other `sqlite3_file*` | not null, opened and not closed yet |
+//! | [OutParam] | `int*`, `sqlite3_int64*`, `double*`, ... | not null, on each access |
+//! | [SqliteBuffer], [SqliteBufferMut] | `zBuf`/`iAmt` pairs | non-negative length, not null |
+//! | [FileControlArg] | `op`/`pArg` of `xFileControl` | `pArg` is read as the type of `op` only |
+//! | [OpenName], [c_str] | `const char*` | not null |
+//!
+//! Whether the [FileExt] of a file is initialized is tracked by `pMethods`, like SQLite does:
+//! [FileSlot::open] sets it once the [FileExt] is written, and [FileRef::close] clears it when
+//! taking the [FileExt] out, so a [FileRef] can't be created for a file that was closed.
+//!
+//! A [VfsRef] only hands out a shared reference to the [State], since SQLite calls into the VFS
+//! from several connections at once; its mutable parts are atomics and mutexes. A [FileRef] hands
+//! out the [FileExt] borrowed from itself, and SQLite never calls two methods of one file at once.
+//!
+//! The tests of this module don't call into SQLite and also run under Miri (`make miri`).
+
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, addr_of_mut};
+use std::slice;
+use std::sync::Arc;
+
+use crate::busy::{BusyCallback, BusyHandlerRef};
+use crate::error::Error;
+use crate::state::{FileExt, FileState, Registration, State};
+use crate::{DatabaseHandle, Vfs};
+
+/// The [State] of a registered VFS, shared for the duration of a callback.
+pub(crate) struct VfsRef<V: Vfs> {
+    state: Arc<State<V>>,
+}
+
+impl<V: Vfs> VfsRef<V> {
+    /// `None` if `p_vfs` is null or the VFS was retired, see [crate::VfsHandle].
+    ///
+    /// # Safety
+    ///
+    /// `p_vfs` is null or the `sqlite3_vfs` [crate::register] allocated for `V`, whose `pAppData`
+    /// is a [Registration] that is never freed nor mutated through anything but shared
+    /// references. SQLite passes it to each method of the VFS.
+    pub(crate) unsafe fn from_raw(p_vfs: *mut libsqlite3_sys::sqlite3_vfs) -> Option<Self> {
+        // SAFETY: per the contract of the function, a non-null `p_vfs` is an `sqlite3_vfs` whose
+        // `pAppData` is a live `Registration<V>` only ever borrowed shared
+        let registration =
+            unsafe { (p_vfs.as_ref()?.pAppData as *const Registration<V>).as_ref() }?;
+        let state = registration.state.read().unwrap().clone()?;
+        Some(Self { state })
+    }
+
+    /// The state, for a file to keep it allocated, see [FileExt::state].
+    pub(crate) fn shared(&self) -> &Arc<State<V>> {
+        &self.state
+    }
+}
+
+impl<V: Vfs> Deref for VfsRef<V> {
+    type Target = State<V>;
+
+    fn deref(&self) -> &State<V> {
+        &self.state
+    }
+}
+
+/// The memory SQLite allocated for a file it is about to open, see [FileSlot::open].
+pub(crate) struct FileSlot<'a, V: Vfs, F: DatabaseHandle> {
+    file: &'a mut FileState<V, F>,
+}
+
+impl<'a, V: Vfs, F: DatabaseHandle> FileSlot<'a, V, F> {
+    /// Mark the file as not open, until [FileSlot::open], as SQLite expects of `xOpen`.
+    ///
+    /// # Safety
+    ///
+    /// `p_file` is null or points to `szOsFile` bytes, i.e. room for a `FileState<V, F>`, not
+    /// used by any open file during `'a`. SQLite passes such a pointer to `xOpen`.
+    pub(crate) unsafe fn from_raw(p_file: *mut libsqlite3_sys::sqlite3_file) -> Option<Self> {
+        let p_file = p_file as *mut FileState<V, F>;
+        if p_file.is_null() {
+            return None;
+        }
+        // SAFETY: `p_file` is valid for writes of a `FileState<V, F>` during `'a` and not used by
+        // anyone else. Writing `pMethods`, the only field of `sqlite3_file`, initializes `base`;
+        // `ext` may stay uninitialized in a `MaybeUninit`, so the reference is to a valid value.
+        let file = unsafe {
+            addr_of_mut!((*p_file).base.pMethods).write(ptr::null());
+            &mut *p_file
+        };
+        Some(Self { file })
+    }
+
+    /// Initialize the file with `ext`, making it open with the `io_methods` of the [State] it
+    /// keeps allocated.
+    pub(crate) fn open(self, ext: FileExt<V, F>) -> FileRef<'a, V, F> {
+        let ext = self.file.ext.write(ext);
+        self.file.base.pMethods = &ext.state.io_methods;
+        FileRef { file: self.file }
+    }
+}
+
+/// An open file, see the [module documentation](self).
+pub(crate) struct FileRef<'a, V: Vfs, F: DatabaseHandle> {
+    file: &'a mut FileState<V, F>,
+}
+
+impl<'a, V: Vfs, F: DatabaseHandle> FileRef<'a, V, F> {
+    /// `None` if `p_file` is null or the file isn't open.
+    ///
+    /// # Safety
+    ///
+    /// `p_file` is null or points to a `FileState<V, F>` that was passed to [FileSlot::from_raw],
+    /// and nothing else accesses it during `'a`. SQLite passes such a pointer to each method of a
+    /// file, and doesn't call two methods of a file at once; a callback must not create a second
+    /// [FileRef] to a file while it holds one.
+    pub(crate) unsafe fn from_raw(p_file: *mut libsqlite3_sys::sqlite3_file) -> Option<Self> {
+        // SAFETY: `base` was initialized by `FileSlot::from_raw`, and `ext` is a `MaybeUninit`,
+        // so `p_file` points to a valid `FileState<V, F>`, exclusively ours during `'a`
+        let file = unsafe { (p_file as *mut FileState<V, F>).as_mut() }?;
+        if file.base.pMethods.is_null() {
+            return None;
+        }
+        Some(Self { file })
+    }
+
+    pub(crate) fn ext(&self) -> &FileExt<V, F> {
+        // SAFETY: `pMethods` is only set by `FileSlot::open` after writing `ext`, and cleared by
+        // `FileRef::close` before taking it out again
+        unsafe { self.file.ext.assume_init_ref() }
+    }
+
+    pub(crate) fn ext_mut(&mut self) -> &mut FileExt<V, F> {
+        // SAFETY: as in `FileRef::ext`
+        unsafe { self.file.ext.assume_init_mut() }
+    }
+
+    /// Take the [FileExt] out of the file, leaving it closed.
+    pub(crate) fn close(self) -> FileExt<V, F> {
+        self.file.base.pMethods = ptr::null();
+        let ext = std::mem::replace(&mut self.file.ext, MaybeUninit::uninit());
+        // SAFETY: `ext` was initialized, as in `FileRef::ext`, and no `FileRef` can read the
+        // uninitialized value left behind, since `pMethods` is null
+        unsafe { ext.assume_init() }
+    }
+}
+
+impl<V: Vfs, F: DatabaseHandle> Deref for FileRef<'_, V, F> {
+    type Target = FileExt<V, F>;
+
+    fn deref(&self) -> &FileExt<V, F> {
+        self.ext()
+    }
+}
+
+impl<V: Vfs, F: DatabaseHandle> DerefMut for FileRef<'_, V, F> {
+    fn deref_mut(&mut self) -> &mut FileExt<V, F> {
+        self.ext_mut()
+    }
+}
+
+/// A pointer SQLite passes for the callback to write a result into, e.g. `pResOut`.
+pub(crate) struct OutParam<'a, T> {
+    ptr: *mut T,
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> OutParam<'a, T> {
+    /// # Safety
+    ///
+    /// `ptr` is null, or valid for writes of a `T` during `'a`, and for reads if it is read with
+    /// [OutParam::read]. SQLite passes such pointers, for the duration of the callback.
+    pub(crate) unsafe fn from_raw(ptr: *mut T) -> Self {
+        Self {
+            ptr,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Write `value`, failing if the pointer is null.
+    pub(crate) fn write<E>(&mut self, value: T) -> Result<(), Error<E>> {
+        match self.set(value) {
+            true => Ok(()),
+            false => Err(Error::NullPtr),
+        }
+    }
+
+    /// Write `value` unless the pointer is null, for parameters SQLite documents as optional.
+    /// Returns whether it was written.
+    pub(crate) fn set(&mut self, value: T) -> bool {
+        if self.ptr.is_null() {
+            return false;
+        }
+        // SAFETY: not null, and valid for writes per the contract of `OutParam::from_raw`; `write`
+        // doesn't drop the previous value, which may be uninitialized
+        unsafe { self.ptr.write(value) };
+        true
+    }
+
+    /// The value SQLite passed in, for parameters that are read before they are written.
+    pub(crate) fn read(&self) -> Option<T>
+    where
+        T: Copy,
+    {
+        // SAFETY: not null, and valid for reads per the contract of `OutParam::from_raw`
+        (!self.ptr.is_null()).then(|| unsafe { self.ptr.read() })
+    }
+}
+
+impl OutParam<'_, *mut c_char> {
+    /// Write a copy of `value` allocated with `sqlite3_malloc`, which SQLite frees, unless the
+    /// pointer is null. Returns whether it was written.
+    pub(crate) fn set_sqlite_string(&mut self, value: &CStr) -> bool {
+        if self.ptr.is_null() {
+            return false;
+        }
+        // SAFETY: `%s` takes one nul-terminated string, which `value` is
+        let copy = unsafe { libsqlite3_sys::sqlite3_mprintf(c"%s".as_ptr(), value.as_ptr()) };
+        self.set(copy)
+    }
+}
+
+/// A buffer SQLite passes to be read from, `zBuf` and `iAmt` of `xWrite`.
+pub(crate) struct SqliteBuffer<'a>(&'a [u8]);
+
+impl<'a> SqliteBuffer<'a> {
+    /// Fails if `len` is negative, or `ptr` is null while `len` isn't 0.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` is null or valid for reads of `len` bytes during `'a`, and these bytes aren't
+    /// written to during `'a`. SQLite passes such buffers, for the duration of the callback.
+    pub(crate) unsafe fn from_raw<E>(ptr: *const c_void, len: c_int) -> Result<Self, Error<E>> {
+        let len = buffer_len(ptr, len)?;
+        if len == 0 {
+            return Ok(Self(&[]));
+        }
+        // SAFETY: `ptr` is not null and valid for reads of `len` bytes during `'a`, and any bit
+        // pattern is a valid `u8`
+        Ok(Self(unsafe {
+            slice::from_raw_parts(ptr as *const u8, len)
+        }))
+    }
+}
+
+impl Deref for SqliteBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+/// A buffer SQLite passes to be written to, e.g. `zBuf` and `iAmt` of `xRead`.
+pub(crate) struct SqliteBufferMut<'a>(&'a mut [u8]);
+
+impl<'a> SqliteBufferMut<'a> {
+    /// Fails if `len` is negative, or `ptr` is null while `len` isn't 0.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` is null or valid for reads and writes of `len` bytes during `'a`, which nothing else
+    /// accesses during `'a`. SQLite passes such buffers, for the duration of the callback.
+    pub(crate) unsafe fn from_raw<E>(ptr: *mut c_void, len: c_int) -> Result<Self, Error<E>> {
+        let len = buffer_len(ptr, len)?;
+        if len == 0 {
+            return Ok(Self(&mut []));
+        }
+        // SAFETY: `ptr` is not null, valid for reads and writes of `len` bytes during `'a` and
+        // exclusively ours; SQLite allocates its buffers as plain bytes
+        Ok(Self(unsafe {
+            slice::from_raw_parts_mut(ptr as *mut u8, len)
+        }))
+    }
+
+    /// Write `msg` as a nul-terminated string, truncated to fit like `sqlite3_snprintf`, but
+    /// never within a character. Nul bytes of `msg` are written as spaces, so that the string
+    /// doesn't end early.
+    pub(crate) fn write_str(&mut self, msg: &str) {
+        let Some(max) = self.0.len().checked_sub(1) else {
+            return;
+        };
+        let mut len = msg.len().min(max);
+        while !msg.is_char_boundary(len) {
+            len -= 1;
+        }
+        for (out, byte) in self.0.iter_mut().zip(&msg.as_bytes()[..len]) {
+            *out = match byte {
+                0 => b' ',
+                byte => *byte,
+            };
+        }
+        self.0[len] = 0;
+    }
+}
+
+impl Deref for SqliteBufferMut<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl DerefMut for SqliteBufferMut<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.0
+    }
+}
+
+fn buffer_len<E>(ptr: *const c_void, len: c_int) -> Result<usize, Error<E>> {
+    let len = usize::try_from(len).map_err(|_| Error::InvalidLength { len })?;
+    if ptr.is_null() && len > 0 {
+        return Err(Error::NullPtr);
+    }
+    Ok(len)
+}
+
+/// What the `pArg` of a file control points to, by opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArgType {
+    /// `int*`, read and/or written.
+    Int,
+    /// `sqlite3_int64*`, read.
+    Int64,
+    /// `char**`, written with a string allocated by `sqlite3_malloc`.
+    StringOut,
+    /// `const char*`.
+    Text,
+    /// `char*[3]`: the error message written, the pragma name and its argument or null.
+    Pragma,
+    /// `void*[2]`: the busy handler and its argument.
+    BusyHandler,
+}
+
+impl ArgType {
+    /// See <https://www.sqlite.org/c3ref/c_fcntl_begin_atomic_write.html>.
+    fn of(op: c_int) -> Option<Self> {
+        use libsqlite3_sys::*;
+        match op {
+            SQLITE_FCNTL_LOCKSTATE
+            | SQLITE_FCNTL_LAST_ERRNO
+            | SQLITE_FCNTL_CHUNK_SIZE
+            | SQLITE_FCNTL_PERSIST_WAL
+            | SQLITE_FCNTL_POWERSAFE_OVERWRITE
+            | SQLITE_FCNTL_HAS_MOVED => Some(Self::Int),
+            SQLITE_FCNTL_SIZE_HINT => Some(Self::Int64),
+            SQLITE_FCNTL_VFSNAME | SQLITE_FCNTL_TEMPFILENAME => Some(Self::StringOut),
+            SQLITE_FCNTL_TRACE => Some(Self::Text),
+            SQLITE_FCNTL_PRAGMA => Some(Self::Pragma),
+            SQLITE_FCNTL_BUSYHANDLER => Some(Self::BusyHandler),
+            _ => None,
+        }
+    }
+}
+
+/// The `op` and `pArg` of `xFileControl`. `pArg` is only accessed as the type SQLite passes with
+/// `op`; accessing it as another type panics.
+pub(crate) struct FileControlArg<'a> {
+    op: c_int,
+    ptr: *mut c_void,
+    _marker: PhantomData<&'a mut c_void>,
+}
+
+impl<'a> FileControlArg<'a> {
+    /// # Safety
+    ///
+    /// `p_arg` is null or points to what SQLite documents for `op`, valid for reads and writes
+    /// during `'a`. SQLite passes such an argument, for the duration of the callback.
+    pub(crate) unsafe fn from_raw(op: c_int, p_arg: *mut c_void) -> Self {
+        Self {
+            op,
+            ptr: p_arg,
+            _marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn op(&self) -> c_int {
+        self.op
+    }
+
+    fn expect(&self, ty: ArgType) {
+        assert_eq!(
+            ArgType::of(self.op),
+            Some(ty),
+            "file control {} doesn't take {ty:?}",
+            self.op
+        );
+    }
+
+    pub(crate) fn int(&mut self) -> OutParam<'_, c_int> {
+        self.expect(ArgType::Int);
+        // SAFETY: `pArg` of `op` is null or an `int*`, valid for the lifetime of `self`
+        unsafe { OutParam::from_raw(self.ptr as *mut c_int) }
+    }
+
+    pub(crate) fn int64(&mut self) -> OutParam<'_, i64> {
+        self.expect(ArgType::Int64);
+        // SAFETY: `pArg` of `op` is null or an `sqlite3_int64*`, valid for the lifetime of `self`
+        unsafe { OutParam::from_raw(self.ptr as *mut i64) }
+    }
+
+    pub(crate) fn string_out(&mut self) -> OutParam<'_, *mut c_char> {
+        self.expect(ArgType::StringOut);
+        // SAFETY: `pArg` of `op` is null or a `char**`, valid for the lifetime of `self`
+        unsafe { OutParam::from_raw(self.ptr as *mut *mut c_char) }
+    }
+
+    pub(crate) fn text(&self) -> Option<&CStr> {
+        self.expect(ArgType::Text);
+        // SAFETY: `pArg` of `op` is null or a nul-terminated string, valid for the lifetime of
+        // `self`
+        unsafe { c_str(self.ptr as *const c_char) }
+    }
+
+    /// The name and argument of the pragma, and where to write its result or error message.
+    pub(crate) fn pragma(&mut self) -> Option<Pragma<'_>> {
+        self.expect(ArgType::Pragma);
+        let args = self.ptr as *mut *mut c_char;
+        if args.is_null() {
+            return None;
+        }
+        // SAFETY: `pArg` of `op` is a `char*[3]`, valid for the lifetime of `self`, whose second
+        // and third entries are null or nul-terminated strings
+        let (name, value, message) = unsafe {
+            (
+                c_str(*args.add(1))?.to_str().ok()?,
+                c_str(*args.add(2)).and_then(|value| value.to_str().ok()),
+                OutParam::from_raw(args),
+            )
+        };
+        Some(Pragma {
+            name,
+            value,
+            message,
+        })
+    }
+
+    /// The busy handler passed with `SQLITE_FCNTL_BUSYHANDLER`, `None` if `pArg` is null.
+    pub(crate) fn busy_handler(&self) -> Option<Option<BusyHandlerRef>> {
+        self.expect(ArgType::BusyHandler);
+        // SAFETY: `pArg` of `op` is null or a `void*[2]`, valid for the lifetime of `self`
+        let args = unsafe { (self.ptr as *const [*mut c_void; 2]).as_ref() }?;
+        Some((!args[0].is_null()).then(|| {
+            // SAFETY: SQLite passes `int (*)(void*)` as the first entry, which can be called with
+            // the second one during any callback of the file
+            unsafe {
+                let callback: BusyCallback = std::mem::transmute(args[0]);
+                BusyHandlerRef::new(callback, args[1])
+            }
+        }))
+    }
+}
+
+/// See [FileControlArg::pragma].
+pub(crate) struct Pragma<'a> {
+    pub name: &'a str,
+    pub value: Option<&'a str>,
+    /// Written with a string allocated by `sqlite3_malloc`, which SQLite frees.
+    pub message: OutParam<'a, *mut c_char>,
+}
+
+/// The name passed to `xOpen`, which may be followed by URI parameters.
+pub(crate) struct OpenName<'a>(&'a CStr);
+
+impl<'a> OpenName<'a> {
+    /// `None` if `z_name` is null.
+    ///
+    /// # Safety
+    ///
+    /// `z_name` is null or the name SQLite passed to `xOpen`, valid during `'a`.
+    pub(crate) unsafe fn from_raw(z_name: *const c_char) -> Option<Self> {
+        // SAFETY: a name passed to `xOpen` is a nul-terminated string
+        unsafe { c_str(z_name) }.map(Self)
+    }
+
+    pub(crate) fn name(&self) -> &'a CStr {
+        self.0
+    }
+
+    /// The boolean URI parameter `param`, `default` if the name has none.
+    pub(crate) fn uri_boolean(&self, param: &CStr, default: bool) -> bool {
+        // SAFETY: `sqlite3_uri_boolean` reads the parameters SQLite stores after the name it
+        // passes to `xOpen`, which this is
+        unsafe {
+            libsqlite3_sys::sqlite3_uri_boolean(self.0.as_ptr(), param.as_ptr(), default as c_int)
+                != 0
+        }
+    }
+}
+
+/// `None` if `ptr` is null.
+///
+/// # Safety
+///
+/// `ptr` is null or a nul-terminated string, valid and not written to during `'a`.
+pub(crate) unsafe fn c_str<'a>(ptr: *const c_char) -> Option<&'a CStr> {
+    // SAFETY: per the contract of the function
+    (!ptr.is_null()).then(|| unsafe { CStr::from_ptr(ptr) })
+}
+
+// the tests only pass pointers to locals that outlive the wrappers
+#[allow(clippy::undocumented_unsafe_blocks)]
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::capability::{Capabilities, VfsCapabilities};
+    use crate::sync_compat::{SyncDatabaseHandle, SyncHandleAdapter, SyncVfs, SyncVfsAdapter};
+    use crate::{LockKind, OpenOptions};
+
+    #[test]
+    fn test_out_param() {
+        let mut null = unsafe { OutParam::<c_int>::from_raw(ptr::null_mut()) };
+        assert!(matches!(null.write::<()>(1), Err(Error::NullPtr)));
+        assert_eq!(null.read(), None);
+        assert!(!null.set(1));
+
+        let mut value = MaybeUninit::<c_int>::uninit();
+        let mut out = unsafe { OutParam::from_raw(value.as_mut_ptr()) };
+        out.write::<()>(7).unwrap();
+        assert_eq!(out.read(), Some(7));
+        assert_eq!(unsafe { value.assume_init() }, 7);
+    }
+
+    #[test]
+    fn test_buffers() {
+        let data = [1u8, 2, 3];
+        let buf = unsafe { SqliteBuffer::from_raw::<()>(data.as_ptr() as _, 2) }.unwrap();
+        assert_eq!(&*buf, &[1, 2]);
+        assert!(unsafe { SqliteBuffer::from_raw::<()>(ptr::null(), 0) }
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            unsafe { SqliteBuffer::from_raw::<()>(ptr::null(), 1) },
+            Err(Error::NullPtr)
+        ));
+        assert!(matches!(
+            unsafe { SqliteBuffer::from_raw::<()>(data.as_ptr() as _, -1) },
+            Err(Error::InvalidLength { len: -1 })
+        ));
+
+        let mut data = [0u8; 4];
+        let mut buf =
+            unsafe { SqliteBufferMut::from_raw::<()>(data.as_mut_ptr() as _, 3) }.unwrap();
+        buf.copy_from_slice(&[1, 2, 255]);
+        assert_eq!(data, [1, 2, 255, 0]);
+
+        let mut data = [0xffu8; 4];
+        let mut buf =
+            unsafe { SqliteBufferMut::from_raw::<()>(data.as_mut_ptr() as _, 4) }.unwrap();
+        buf.write_str("not supported");
+        assert_eq!(&data, b"not\0");
+        let mut buf =
+            unsafe { SqliteBufferMut::from_raw::<()>(data.as_mut_ptr() as _, 0) }.unwrap();
+        buf.write_str("not supported");
+        assert_eq!(&data, b"not\0");
+        // `é` takes 2 bytes, and doesn't fit after `a`
+        let mut buf =
+            unsafe { SqliteBufferMut::from_raw::<()>(data.as_mut_ptr() as _, 3) }.unwrap();
+        buf.write_str("aé");
+        assert_eq!(&data, b"a\0t\0");
+        let mut buf =
+            unsafe { SqliteBufferMut::from_raw::<()>(data.as_mut_ptr() as _, 4) }.unwrap();
+        buf.write_str("a\0b");
+        assert_eq!(&data, b"a b\0");
+    }
+
+    #[test]
+    fn test_file_control_arg() {
+        let mut errno: c_int = 0;
+        let mut arg = unsafe {
+            FileControlArg::from_raw(
+                libsqlite3_sys::SQLITE_FCNTL_LAST_ERRNO,
+                &mut errno as *mut c_int as _,
+            )
+        };
+        arg.int().write::<()>(5).unwrap();
+        assert_eq!(errno, 5);
+
+        let mut null = unsafe {
+            FileControlArg::from_raw(libsqlite3_sys::SQLITE_FCNTL_PRAGMA, ptr::null_mut())
+        };
+        assert!(null.pragma().is_none());
+
+        let name = c"threeqlite_stats";
+        let mut args = [
+            ptr::null_mut(),
+            name.as_ptr() as *mut c_char,
+            ptr::null_mut(),
+        ];
+        let mut arg = unsafe {
+            FileControlArg::from_raw(libsqlite3_sys::SQLITE_FCNTL_PRAGMA, args.as_mut_ptr() as _)
+        };
+        let pragma = arg.pragma().unwrap();
+        assert_eq!((pragma.name, pragma.value), ("threeqlite_stats", None));
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't take Int64")]
+    fn test_file_control_arg_type() {
+        let mut chunk_size: c_int = 4096;
+        let mut arg = unsafe {
+            FileControlArg::from_raw(
+                libsqlite3_sys::SQLITE_FCNTL_CHUNK_SIZE,
+                &mut chunk_size as *mut c_int as _,
+            )
+        };
+        // an `int*`, which must not be read as an `sqlite3_int64*`
+        arg.int64();
+    }
+
+    struct Nothing;
+
+    impl SyncDatabaseHandle for Nothing {
+        fn size(&self) -> Result<u64, std::io::Error> {
+            Ok(0)
+        }
+
+        fn read_exact_at(&mut self, _buf: &mut [u8], _offset: u64) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn write_all_at(&mut self, _buf: &[u8], _offset: u64) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn sync(&mut self, _data_only: bool) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn set_len(&mut self, _size: u64) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn lock(&mut self, _lock: LockKind) -> Result<bool, std::io::Error> {
+            Ok(true)
+        }
+
+        fn reserved(&mut self) -> Result<bool, std::io::Error> {
+            Ok(false)
+        }
+
+        fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+            Ok(LockKind::None)
+        }
+    }
+
+    impl SyncVfs for Nothing {
+        type Handle = Nothing;
+
+        fn open(&self, _db: &str, _opts: OpenOptions) -> Result<Nothing, std::io::Error> {
+            Ok(Nothing)
+        }
+
+        fn delete(&self, _db: &str) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn exists(&self, _db: &str) -> Result<bool, std::io::Error> {
+            Ok(false)
+        }
+
+        fn temporary_name(&self) -> String {
+            String::new()
+        }
+
+        fn random(&self, _buffer: &mut [u8]) {}
+
+        fn sleep(&self, duration: Duration) -> Duration {
+            duration
+        }
+    }
+
+    type V = SyncVfsAdapter<Nothing>;
+    type F = SyncHandleAdapter<Nothing>;
+
+    fn ext(db_name: &str) -> FileExt<V, F> {
+        let vfs = Arc::new(SyncVfsAdapter::new(Nothing));
+        let state = State {
+            name: CString::new("nothing").unwrap(),
+            capabilities: Arc::new(Capabilities::default()),
+            backend: VfsCapabilities::ALL,
+            vfs: vfs.clone(),
+            #[cfg(any(feature = "syscall", feature = "loadext"))]
+            parent_vfs: ptr::null_mut(),
+            io_methods: unsafe { std::mem::zeroed() },
+            last_error: Arc::new(Mutex::new(None)),
+            next_id: Default::default(),
+            instrumentation: None,
+        };
+        FileExt {
+            vfs,
+            vfs_name: CString::new("nothing").unwrap(),
+            db_name: db_name.to_owned(),
+            file: SyncHandleAdapter(Nothing),
+            delete_on_close: false,
+            last_error: None,
+            vfs_last_error: Arc::new(Mutex::new(None)),
+            wal_index: None,
+            wal_index_regions: HashMap::new(),
+            wal_index_locks: HashMap::new(),
+            has_exclusive_lock: false,
+            id: 0,
+            growth_quantum: None,
+            persist_wal: false,
+            powersafe_overwrite: true,
+            busy_handler: None,
+            capabilities: Arc::new(Capabilities::default()),
+            instrumentation: None,
+            state: Arc::new(state),
+        }
+    }
+
+    #[test]
+    fn test_file_lifecycle() {
+        // what SQLite allocates for `szOsFile`, not initialized
+        let mut memory = Box::new(MaybeUninit::<FileState<V, F>>::uninit());
+        let p_file = memory.as_mut_ptr() as *mut libsqlite3_sys::sqlite3_file;
+
+        // not open until `FileSlot::open`
+        assert!(unsafe { FileSlot::<V, F>::from_raw(p_file) }.is_some());
+        assert!(unsafe { FileRef::<V, F>::from_raw(p_file) }.is_none());
+
+        let slot = unsafe { FileSlot::<V, F>::from_raw(p_file) }.unwrap();
+        slot.open(ext("test.db")).id = 7;
+
+        let file = unsafe { FileRef::<V, F>::from_raw(p_file) }.unwrap();
+        assert_eq!((file.db_name.as_str(), file.id), ("test.db", 7));
+        // open with the methods of the state it keeps allocated
+        assert!(ptr::eq(
+            unsafe { (*p_file).pMethods },
+            &file.state.io_methods
+        ));
+        let closed = file.close();
+        assert_eq!(closed.db_name, "test.db");
+        drop(closed);
+
+        // a callback after xClose doesn't see the dropped state
+        assert!(unsafe { FileRef::<V, F>::from_raw(p_file) }.is_none());
+        assert!(unsafe { FileRef::<V, F>::from_raw(ptr::null_mut()) }.is_none());
+
+        // reused for another file
+        let slot = unsafe { FileSlot::<V, F>::from_raw(p_file) }.unwrap();
+        slot.open(ext("other.db"));
+        let file = unsafe { FileRef::<V, F>::from_raw(p_file) }.unwrap();
+        assert_eq!(file.close().db_name, "other.db");
+    }
+}
@@ -0,0 +1,312 @@
+//! Observe the callbacks SQLite invokes on a [Vfs](crate::Vfs) and its files.
+//!
+//! Register an [Instrumentation] together with the VFS using [crate::register_instrumented]. It
+//! is called on entry to and on exit from every callback, with the result code and the time spent
+//! on exit, and may look at the bytes read and written with [Instrumentation::on_data]. Without
+//! one, each callback pays a single `Option` check.
+//!
+//! [RecordingInstrumentation] keeps an ordered log of the callbacks, to assert the sequence a
+//! workload produces in tests:
+//!
+//! ```ignore
+//! let rec = Arc::new(RecordingInstrumentation::default());
+//! let _vfs = sqlite_vfs::register_instrumented("myvfs", vfs, false, rec.clone())?;
+//! // ... commit a transaction ...
+//! rec.assert_sequence(&[
+//!     (CallbackKind::Write, "main.db"),
+//!     (CallbackKind::Sync, "main.db"),
+//!     (CallbackKind::Delete, "main.db-journal"),
+//! ]);
+//! ```
+
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::state::FileExt;
+use crate::{DatabaseHandle, Vfs};
+
+/// Observes the callbacks of a VFS. See the [module documentation](self).
+pub trait Instrumentation: Send + Sync {
+    /// Called on entry to and on exit from a callback. `db` is the file the callback operates
+    /// on, or the empty string for callbacks on the VFS itself, such as [CallbackKind::Sleep].
+    fn on_callback(&self, kind: CallbackKind, db: &str, details: CallbackDetails);
+
+    /// Called between entry to and exit from a [CallbackKind::Write] with the bytes to write, and
+    /// of a [CallbackKind::Read] with the bytes read, if it read them in full. Does nothing by
+    /// default.
+    fn on_data(&self, kind: CallbackKind, db: &str, offset: u64, data: &[u8]) {
+        let _ = (kind, db, offset, data);
+    }
+}
+
+/// A callback of `sqlite3_vfs` or `sqlite3_io_methods`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CallbackKind {
+    Open,
+    Delete,
+    Access,
+    FullPathname,
+    Randomness,
+    Sleep,
+    CurrentTime,
+    Close,
+    Read,
+    Write,
+    Truncate,
+    Sync,
+    FileSize,
+    Lock,
+    Unlock,
+    CheckReservedLock,
+    FileControl,
+    SectorSize,
+    DeviceCharacteristics,
+    ShmMap,
+    ShmLock,
+    ShmBarrier,
+    ShmUnmap,
+}
+
+impl CallbackKind {
+    /// The name of the callback in SQLite's documentation, e.g. `xWrite`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Open => "xOpen",
+            Self::Delete => "xDelete",
+            Self::Access => "xAccess",
+            Self::FullPathname => "xFullPathname",
+            Self::Randomness => "xRandomness",
+            Self::Sleep => "xSleep",
+            Self::CurrentTime => "xCurrentTimeInt64",
+            Self::Close => "xClose",
+            Self::Read => "xRead",
+            Self::Write => "xWrite",
+            Self::Truncate => "xTruncate",
+            Self::Sync => "xSync",
+            Self::FileSize => "xFileSize",
+            Self::Lock => "xLock",
+            Self::Unlock => "xUnlock",
+            Self::CheckReservedLock => "xCheckReservedLock",
+            Self::FileControl => "xFileControl",
+            Self::SectorSize => "xSectorSize",
+            Self::DeviceCharacteristics => "xDeviceCharacteristics",
+            Self::ShmMap => "xShmMap",
+            Self::ShmLock => "xShmLock",
+            Self::ShmBarrier => "xShmBarrier",
+            Self::ShmUnmap => "xShmUnmap",
+        }
+    }
+}
+
+impl std::fmt::Display for CallbackKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Enter,
+    Exit {
+        /// The result code returned to SQLite.
+        rc: c_int,
+        elapsed: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallbackDetails {
+    pub phase: Phase,
+    /// The offset of a read or write, or the size a file is truncated to.
+    pub offset: Option<u64>,
+    /// The length of a read or write.
+    pub len: Option<usize>,
+    /// The lock level of a lock or unlock, the flags of an open, sync or shared memory lock, the
+    /// op of a file control, or the duration in microseconds of a sleep.
+    pub arg: Option<c_int>,
+}
+
+impl CallbackDetails {
+    pub(crate) const NONE: Self = Self {
+        phase: Phase::Enter,
+        offset: None,
+        len: None,
+        arg: None,
+    };
+
+    pub(crate) fn range(offset: i64, len: c_int) -> Self {
+        Self {
+            offset: Some(offset as u64),
+            len: Some(len as usize),
+            ..Self::NONE
+        }
+    }
+
+    pub(crate) fn arg(arg: c_int) -> Self {
+        Self {
+            arg: Some(arg),
+            ..Self::NONE
+        }
+    }
+}
+
+/// A running callback, reported on [Probe::exit].
+pub(crate) struct Probe {
+    running: Option<Running>,
+}
+
+struct Running {
+    instrumentation: Arc<dyn Instrumentation>,
+    db: String,
+    kind: CallbackKind,
+    details: CallbackDetails,
+    start: Instant,
+}
+
+impl Probe {
+    pub(crate) fn enter(
+        instrumentation: Option<&Arc<dyn Instrumentation>>,
+        kind: CallbackKind,
+        db: impl FnOnce() -> String,
+        details: CallbackDetails,
+    ) -> Self {
+        let running = instrumentation.map(|instrumentation| {
+            let db = db();
+            instrumentation.on_callback(kind, &db, details);
+            Running {
+                instrumentation: Arc::clone(instrumentation),
+                db,
+                kind,
+                details,
+                start: Instant::now(),
+            }
+        });
+        Self { running }
+    }
+
+    /// Enter a callback on the file `p_file`.
+    pub(crate) fn file<V: Vfs, F: DatabaseHandle>(
+        ext: Option<&FileExt<V, F>>,
+        kind: CallbackKind,
+        details: CallbackDetails,
+    ) -> Self {
+        match ext {
+            Some(ext) => Self::enter(
+                ext.instrumentation.as_ref(),
+                kind,
+                || ext.db_name.clone(),
+                details,
+            ),
+            None => Self { running: None },
+        }
+    }
+
+    /// Whether an [Instrumentation] observes the callback, i.e. [Probe::data] is worth calling.
+    pub(crate) fn observed(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Report the bytes of a read or write, see [Instrumentation::on_data].
+    pub(crate) fn data(&self, data: &[u8]) {
+        if let Some(running) = &self.running {
+            let offset = running.details.offset.unwrap_or(0);
+            running
+                .instrumentation
+                .on_data(running.kind, &running.db, offset, data);
+        }
+    }
+
+    pub(crate) fn exit(self, rc: c_int) -> c_int {
+        if let Some(running) = self.running {
+            let phase = Phase::Exit {
+                rc,
+                elapsed: running.start.elapsed(),
+            };
+            let details = CallbackDetails {
+                phase,
+                ..running.details
+            };
+            running
+                .instrumentation
+                .on_callback(running.kind, &running.db, details);
+        }
+        rc
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub kind: CallbackKind,
+    pub db: String,
+    pub details: CallbackDetails,
+}
+
+/// An [Instrumentation] keeping an ordered log of all callbacks.
+#[derive(Debug, Default)]
+pub struct RecordingInstrumentation {
+    events: Mutex<Vec<Event>>,
+}
+
+impl RecordingInstrumentation {
+    /// All events, in the order they happened.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// The events of the file `db`.
+    pub fn events_for(&self, db: &str) -> Vec<Event> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.db == db)
+            .cloned()
+            .collect()
+    }
+
+    /// The callbacks entered, in order.
+    pub fn calls(&self) -> Vec<(CallbackKind, String)> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.details.phase == Phase::Enter)
+            .map(|event| (event.kind, event.db.clone()))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+
+    /// Assert that the callbacks in `expected` were entered in this order. Other callbacks may
+    /// happen in between, e.g. the reads and locks around a commit.
+    #[track_caller]
+    pub fn assert_sequence(&self, expected: &[(CallbackKind, &str)]) {
+        let calls = self.calls();
+        let mut remaining = calls.iter();
+        for (i, (kind, db)) in expected.iter().enumerate() {
+            if !remaining.any(|(k, d)| k == kind && d == db) {
+                let log = calls
+                    .iter()
+                    .map(|(kind, db)| format!("  {kind}({db})"))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                panic!(
+                    "expected {kind}({db}) (#{i} of the sequence) did not follow, calls:\n{log}"
+                );
+            }
+        }
+    }
+}
+
+impl Instrumentation for RecordingInstrumentation {
+    fn on_callback(&self, kind: CallbackKind, db: &str, details: CallbackDetails) {
+        self.events.lock().unwrap().push(Event {
+            kind,
+            db: db.to_owned(),
+            details,
+        });
+    }
+}
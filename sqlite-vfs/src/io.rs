@@ -1,24 +1,28 @@
 use std::collections::hash_map::Entry;
-use std::mem;
 
 use super::*;
+use busy::BusyScope;
 use error::Error;
-use state::{file_state, null_ptr_error, FileState};
+use ffi::{FileControlArg, FileRef, OutParam, SqliteBuffer, SqliteBufferMut};
+use instrument::{CallbackDetails, CallbackKind, Probe};
 use wip::WalIndex;
 
 #[tokio::main]
-async fn close_inner<V: Vfs, F: DatabaseHandle>(file: *mut libsqlite3_sys::sqlite3_file) -> c_int {
-    if let Some(f) = unsafe { (file as *mut FileState<V, F>).as_mut() } {
-        let ext = unsafe { f.ext.assume_init_mut() };
+async fn close_inner<V: Vfs, F: DatabaseHandle>(file: Option<FileRef<'_, V, F>>) -> c_int {
+    if let Some(file) = file {
+        // dropped even if deleting it fails, as SQLite considers it closed either way
+        let mut ext = file.close();
+        tracing::debug!(target: "sqlite_vfs::io", id = ext.id, db = %ext.db_name, "close");
+
+        // the connection's busy handler is about to become invalid
+        ext.busy_handler = None;
+        ext.file.set_busy_handler(None);
+
         if ext.delete_on_close {
             if let Err(err) = Vfs::delete(&*ext.vfs, &*ext.db_name).await {
                 return ext.set_last_error(libsqlite3_sys::SQLITE_DELETE, err);
             }
         }
-
-        let ext = mem::replace(&mut f.ext, MaybeUninit::uninit());
-        let ext = unsafe { ext.assume_init() }; // extract the value to drop it
-        log::trace!("[{}] close ({})", ext.id, ext.db_name);
     }
 
     // #[cfg(feature = "sqlite_test")]
@@ -29,28 +33,27 @@ async fn close_inner<V: Vfs, F: DatabaseHandle>(file: *mut libsqlite3_sys::sqlit
 
 /// Read data from a file.
 #[tokio::main]
-pub async fn read_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    p_file: *mut libsqlite3_sys::sqlite3_file,
-    z_buf: *mut c_void,
-    i_amt: c_int,
+async fn read_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
+    buf: Result<SqliteBufferMut<'_>, Error<V::Error>>,
     i_ofst: libsqlite3_sys::sqlite3_int64,
 ) -> c_int {
-    let state = unsafe {
-        match file_state::<V, F>(p_file) {
-            Ok(f) => f,
-            Err(_) => return libsqlite3_sys::SQLITE_IOERR_CLOSE,
-        }
+    let Some(mut state) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_CLOSE;
+    };
+    let mut out = match buf {
+        Ok(buf) => buf,
+        Err(err) => return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_READ, err),
     };
-    // log::trace!(
-    //     "[{}] read offset={} len={} ({})",
-    //     state.id,
-    //     i_ofst,
-    //     i_amt,
-    //     state.db_name
-    // );
+    tracing::trace!(
+        target: "sqlite_vfs::io::read",
+        id = state.id,
+        offset = i_ofst,
+        len = out.len(),
+        "read"
+    );
 
-    let out = unsafe { slice::from_raw_parts_mut(z_buf as *mut u8, i_amt as usize) };
-    if let Err(err) = state.file.read_exact_at(out, i_ofst as u64).await {
+    if let Err(err) = state.file.read_exact_at(&mut out, i_ofst as u64).await {
         if let crate::error::Error::UnexpectedEof = err {
             return libsqlite3_sys::SQLITE_IOERR_SHORT_READ;
         }
@@ -62,26 +65,27 @@ pub async fn read_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
 
 /// Write data to a file.
 #[tokio::main]
-pub async unsafe fn write_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    p_file: *mut libsqlite3_sys::sqlite3_file,
-    z: *const c_void,
-    i_amt: c_int,
+async fn write_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
+    buf: Result<SqliteBuffer<'_>, Error<V::Error>>,
     i_ofst: libsqlite3_sys::sqlite3_int64,
 ) -> c_int {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_WRITE,
-    };
-    log::trace!(
-        "[{}] write offset={} len={} ({})",
-        state.id,
-        i_ofst,
-        i_amt,
-        state.db_name
+    let Some(mut state) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_WRITE;
+    };
+    let data = match buf {
+        Ok(buf) => buf,
+        Err(err) => return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_WRITE, err),
+    };
+    tracing::trace!(
+        target: "sqlite_vfs::io::write",
+        id = state.id,
+        offset = i_ofst,
+        len = data.len(),
+        "write"
     );
 
-    let data = slice::from_raw_parts(z as *mut u8, i_amt as usize);
-    let result = state.file.write_all_at(data, i_ofst as u64).await;
+    let result = state.file.write_all_at(&data, i_ofst as u64).await;
 
     match result {
         Ok(_) => {}
@@ -96,22 +100,24 @@ pub async unsafe fn write_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
 
 /// Truncate a file.
 #[tokio::main]
-pub async unsafe fn truncate_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    p_file: *mut libsqlite3_sys::sqlite3_file,
+async fn truncate_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
     size: libsqlite3_sys::sqlite3_int64,
 ) -> c_int {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_FSYNC,
+    let Some(mut state) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_FSYNC;
     };
 
-    let size: u64 = if let Some(chunk_size) = state.chunk_size {
-        (((size as usize + chunk_size - 1) / chunk_size) * chunk_size) as u64
-    } else {
-        size as u64
+    // the logical length, unlike SQLite's unix VFS, which rounds it up to the growth quantum: a
+    // backend may allocate in larger units, but must not report the rounding as data
+    let Ok(size) = u64::try_from(size) else {
+        return state.set_last_error(
+            libsqlite3_sys::SQLITE_IOERR_TRUNCATE,
+            Error::ExpectedArg { name: "size" },
+        );
     };
 
-    log::trace!("[{}] truncate size={} ({})", state.id, size, state.db_name);
+    tracing::trace!(target: "sqlite_vfs::io", id = state.id, size, "truncate");
 
     // #[cfg(feature = "sqlite_test")]
     // if simulate_io_error() {
@@ -125,17 +131,25 @@ pub async unsafe fn truncate_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     libsqlite3_sys::SQLITE_OK
 }
 
+/// `size` rounded up to a multiple of the growth quantum, if any, see
+/// [DatabaseHandle::set_chunk_size].
+fn allocation(size: u64, growth_quantum: Option<u64>) -> u64 {
+    match growth_quantum {
+        Some(quantum) => size.div_ceil(quantum).saturating_mul(quantum),
+        None => size,
+    }
+}
+
 /// Persist changes to a file.
 #[tokio::main]
-pub async unsafe fn sync_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    p_file: *mut libsqlite3_sys::sqlite3_file,
+async fn sync_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
     flags: c_int,
 ) -> c_int {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_FSYNC,
+    let Some(mut state) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_FSYNC;
     };
-    log::trace!("[{}] sync ({})", state.id, state.db_name);
+    tracing::trace!(target: "sqlite_vfs::io", id = state.id, "sync");
 
     if let Err(err) = state
         .file
@@ -150,22 +164,21 @@ pub async unsafe fn sync_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
 
 /// Return the current file-size of a file.
 #[tokio::main]
-pub async unsafe fn file_size_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    p_file: *mut libsqlite3_sys::sqlite3_file,
-    p_size: *mut libsqlite3_sys::sqlite3_int64,
+async fn file_size_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
+    mut p_size: OutParam<'_, libsqlite3_sys::sqlite3_int64>,
 ) -> c_int {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_FSTAT,
-    };
-    log::trace!("[{}] file_size ({})", state.id, state.db_name);
-
-    if let Err(err) = state.file.size().await.and_then(|n| {
-        let p_size: &mut libsqlite3_sys::sqlite3_int64 =
-            p_size.as_mut().ok_or_else(null_ptr_error::<V::Error>)?;
-        *p_size = n as libsqlite3_sys::sqlite3_int64;
-        Ok(())
-    }) {
+    let Some(mut state) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_FSTAT;
+    };
+    tracing::trace!(target: "sqlite_vfs::io", id = state.id, "file_size");
+
+    if let Err(err) = state
+        .file
+        .size()
+        .await
+        .and_then(|n| p_size.write(n as libsqlite3_sys::sqlite3_int64))
+    {
         return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_FSTAT, err);
     }
 
@@ -179,15 +192,15 @@ pub async unsafe fn file_size_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>
 
 /// Lock a file.
 #[tokio::main]
-pub async unsafe fn lock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    p_file: *mut libsqlite3_sys::sqlite3_file,
+async fn lock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
     e_lock: c_int,
 ) -> c_int {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_LOCK,
+    let Some(mut file) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_LOCK;
     };
-    log::trace!("[{}] lock ({})", state.id, state.db_name);
+    let state = &mut *file;
+    tracing::trace!(target: "sqlite_vfs::lock", id = state.id, "lock");
 
     let lock = match LockKind::from_i32(e_lock) {
         Some(lock) => lock,
@@ -196,7 +209,7 @@ pub async unsafe fn lock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     match state.file.lock(lock).await {
         Ok(true) => {
             state.has_exclusive_lock = lock == LockKind::Exclusive;
-            log::trace!("[{}] lock={:?} ({})", state.id, lock, state.db_name);
+            tracing::debug!(target: "sqlite_vfs::lock", id = state.id, ?lock, db = %state.db_name, "locked");
 
             // If just acquired a exclusive database lock while not having any exclusive lock
             // on the wal index, make sure the wal index is up to date.
@@ -207,21 +220,19 @@ pub async unsafe fn lock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
                     .any(|(_, lock)| *lock == wip::WalIndexLock::Exclusive);
 
                 if !has_exclusive_wal_index {
-                    log::trace!(
-                        "[{}] acquired exclusive db lock, pulling wal index changes",
-                        state.id,
+                    tracing::trace!(
+                        target: "sqlite_vfs::lock",
+                        id = state.id,
+                        "acquired exclusive db lock, pulling wal index changes"
                     );
 
-                    if let Some((wal_index, _)) = state.wal_index.as_mut() {
-                        for (region, data) in &mut state.wal_index_regions {
-                            if let Err(err) = wal_index.pull::<F>(*region as u32, data) {
-                                log::error!(
-                                    "[{}] pulling wal index changes failed: {}",
-                                    state.id,
-                                    err
-                                )
-                            }
-                        }
+                    if let Err(err) = refresh_wal_index(state) {
+                        tracing::error!(
+                            target: "sqlite_vfs::lock",
+                            id = state.id,
+                            %err,
+                            "pulling wal index changes failed"
+                        )
                     }
                 }
             }
@@ -229,11 +240,12 @@ pub async unsafe fn lock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
             libsqlite3_sys::SQLITE_OK
         }
         Ok(false) => {
-            log::trace!(
-                "[{}] busy (denied {:?}) ({})",
-                state.id,
-                lock,
-                state.db_name
+            tracing::debug!(
+                target: "sqlite_vfs::lock",
+                id = state.id,
+                ?lock,
+                db = %state.db_name,
+                "busy"
             );
             libsqlite3_sys::SQLITE_BUSY
         }
@@ -243,15 +255,14 @@ pub async unsafe fn lock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
 
 /// Unlock a file.
 #[tokio::main]
-pub async unsafe fn unlock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    p_file: *mut libsqlite3_sys::sqlite3_file,
+async fn unlock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
     e_lock: c_int,
 ) -> c_int {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_UNLOCK,
+    let Some(mut state) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_UNLOCK;
     };
-    log::trace!("[{}] unlock ({})", state.id, state.db_name);
+    tracing::trace!(target: "sqlite_vfs::lock", id = state.id, "unlock");
 
     let lock = match LockKind::from_i32(e_lock) {
         Some(lock) => lock,
@@ -260,7 +271,7 @@ pub async unsafe fn unlock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     match state.file.unlock(lock).await {
         Ok(true) => {
             state.has_exclusive_lock = lock == LockKind::Exclusive;
-            log::trace!("[{}] unlock={:?} ({})", state.id, lock, state.db_name);
+            tracing::debug!(target: "sqlite_vfs::lock", id = state.id, ?lock, db = %state.db_name, "unlocked");
             libsqlite3_sys::SQLITE_OK
         }
         Ok(false) => libsqlite3_sys::SQLITE_BUSY,
@@ -270,26 +281,26 @@ pub async unsafe fn unlock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
 
 /// Check if another file-handle holds a [LockKind::Reserved] lock on a file.
 #[tokio::main]
-pub async unsafe fn check_reserved_lock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    p_file: *mut libsqlite3_sys::sqlite3_file,
-    p_res_out: *mut c_int,
+async fn check_reserved_lock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
+    mut p_res_out: OutParam<'_, c_int>,
 ) -> c_int {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_CHECKRESERVEDLOCK,
+    let Some(mut state) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_CHECKRESERVEDLOCK;
     };
-    log::trace!("[{}] check_reserved_lock ({})", state.id, state.db_name);
+    tracing::trace!(target: "sqlite_vfs::lock", id = state.id, "check_reserved_lock");
 
     // #[cfg(feature = "sqlite_test")]
     // if simulate_io_error() {
     //     return libsqlite3_sys::SQLITE_IOERR_CHECKRESERVEDLOCK;
     // }
 
-    if let Err(err) = state.file.reserved().await.and_then(|is_reserved| {
-        let p_res_out: &mut c_int = p_res_out.as_mut().ok_or_else(null_ptr_error)?;
-        *p_res_out = is_reserved as c_int;
-        Ok(())
-    }) {
+    if let Err(err) = state
+        .file
+        .reserved()
+        .await
+        .and_then(|is_reserved| p_res_out.write(is_reserved as c_int))
+    {
         return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_UNLOCK, err);
     }
 
@@ -298,16 +309,15 @@ pub async unsafe fn check_reserved_lock_inner<V: Vfs, F: DatabaseHandle<Error =
 
 /// File control method. For custom operations on a mem-file.
 #[tokio::main]
-pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    p_file: *mut libsqlite3_sys::sqlite3_file,
-    op: c_int,
-    p_arg: *mut c_void,
+async fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
+    mut p_arg: FileControlArg<'_>,
 ) -> c_int {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_NOTFOUND,
+    let Some(mut state) = file else {
+        return libsqlite3_sys::SQLITE_NOTFOUND;
     };
-    log::trace!("[{}] file_control op={} ({})", state.id, op, state.db_name);
+    let op = p_arg.op();
+    tracing::trace!(target: "sqlite_vfs::io", id = state.id, op, "file_control");
 
     // Docs: https://www.sqlite.org/c3ref/c_fcntl_begin_atomic_write.html
     match op {
@@ -325,9 +335,7 @@ pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Erro
         // Used for debugging. Write current state of the lock into (int)pArg.
         libsqlite3_sys::SQLITE_FCNTL_LOCKSTATE => match state.file.current_lock().await {
             Ok(lock) => {
-                if let Some(p_arg) = (p_arg as *mut i32).as_mut() {
-                    *p_arg = lock as i32;
-                }
+                p_arg.int().set(lock as i32);
                 libsqlite3_sys::SQLITE_OK
             }
             Err(err) => state.set_last_error(libsqlite3_sys::SQLITE_ERROR, err),
@@ -339,21 +347,15 @@ pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Erro
 
         // Write last error number into (int)pArg.
         libsqlite3_sys::SQLITE_FCNTL_LAST_ERRNO => {
-            if let Some(p_arg) = (p_arg as *mut i32).as_mut() {
-                *p_arg = state.last_errno;
-            }
+            p_arg.int().set(state.last_errno());
             libsqlite3_sys::SQLITE_OK
         }
 
         // Give the VFS layer a hint of how large the database file will grow to be during the
         // current transaction.
         libsqlite3_sys::SQLITE_FCNTL_SIZE_HINT => {
-            let size_hint = match (p_arg as *mut i64)
-                .as_ref()
-                .cloned()
-                .and_then(|s| u64::try_from(s).ok())
-            {
-                Some(chunk_size) => chunk_size,
+            let size_hint = match p_arg.int64().read().and_then(|s| u64::try_from(s).ok()) {
+                Some(size_hint) => size_hint,
                 None => {
                     return state.set_last_error(
                         libsqlite3_sys::SQLITE_NOTFOUND,
@@ -374,13 +376,9 @@ pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Erro
                 return libsqlite3_sys::SQLITE_OK;
             }
 
-            if let Some(chunk_size) = state.chunk_size {
-                let chunk_size = chunk_size as u64;
-                let size = ((size_hint + chunk_size - 1) / chunk_size) * chunk_size;
-                if let Err(err) = state.file.set_len(size).await {
-                    return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_TRUNCATE, err);
-                }
-            } else if let Err(err) = state.file.set_len(size_hint).await {
+            // room to grow into, not data: the length is left to the writes that follow
+            let size = allocation(size_hint, state.growth_quantum);
+            if let Err(err) = state.file.allocate(size).await {
                 return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_TRUNCATE, err);
             }
 
@@ -392,16 +390,11 @@ pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Erro
             libsqlite3_sys::SQLITE_OK
         }
 
-        // Request that the VFS extends and truncates the database file in chunks of a size
-        // specified by the user. Return an error as this is not forwarded to the [Vfs] trait
-        // right now.
+        // Request that the VFS extends the database file in chunks of a size specified by the
+        // user. A size of zero or less turns it off, as in SQLite's own VFSes.
         libsqlite3_sys::SQLITE_FCNTL_CHUNK_SIZE => {
-            let chunk_size = match (p_arg as *mut i32)
-                .as_ref()
-                .cloned()
-                .and_then(|s| usize::try_from(s).ok())
-            {
-                Some(chunk_size) => chunk_size,
+            let chunk_size = match p_arg.int().read() {
+                Some(chunk_size) => usize::try_from(chunk_size).unwrap_or(0),
                 None => {
                     return state.set_last_error(
                         libsqlite3_sys::SQLITE_NOTFOUND,
@@ -414,7 +407,7 @@ pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Erro
                 return state.set_last_error(libsqlite3_sys::SQLITE_ERROR, err);
             }
 
-            state.chunk_size = Some(chunk_size);
+            state.growth_quantum = (chunk_size > 0).then_some(chunk_size as u64);
 
             libsqlite3_sys::SQLITE_OK
         }
@@ -424,14 +417,17 @@ pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Erro
         // programs. Not implemented.
         libsqlite3_sys::SQLITE_FCNTL_WIN32_AV_RETRY => libsqlite3_sys::SQLITE_NOTFOUND,
 
-        // Enable or disable the persistent WAL setting.
+        // Enable or disable the persistent WAL setting, see [DatabaseHandle::set_persist_wal].
         libsqlite3_sys::SQLITE_FCNTL_PERSIST_WAL => {
-            if let Some(p_arg) = (p_arg as *mut i32).as_mut() {
-                if *p_arg < 0 {
+            let mut p_arg = p_arg.int();
+            if let Some(value) = p_arg.read() {
+                if value < 0 {
                     // query current setting
-                    *p_arg = state.persist_wal as i32;
+                    p_arg.set(state.persist_wal as i32);
                 } else {
-                    state.persist_wal = *p_arg == 1;
+                    let persist = value == 1;
+                    state.persist_wal = persist;
+                    state.file.set_persist_wal(persist);
                 }
             };
 
@@ -444,45 +440,70 @@ pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Erro
 
         // Used to obtain the names of all VFSes in the VFS stack.
         libsqlite3_sys::SQLITE_FCNTL_VFSNAME => {
-            if let Some(p_arg) = (p_arg as *mut *const c_char).as_mut() {
-                let name = ManuallyDrop::new(state.vfs_name.clone());
-                *p_arg = name.as_ptr();
-            };
+            // freed by SQLite
+            p_arg.string_out().set_sqlite_string(&state.vfs_name);
 
             libsqlite3_sys::SQLITE_OK
         }
 
         // Set or query the persistent "powersafe-overwrite" or "PSOW" setting.
         libsqlite3_sys::SQLITE_FCNTL_POWERSAFE_OVERWRITE => {
-            if let Some(p_arg) = (p_arg as *mut i32).as_mut() {
-                if *p_arg < 0 {
+            let mut p_arg = p_arg.int();
+            if let Some(value) = p_arg.read() {
+                if value < 0 {
                     // query current setting
-                    *p_arg = state.powersafe_overwrite as i32;
+                    p_arg.set(state.powersafe_overwrite as i32);
                 } else {
-                    state.powersafe_overwrite = *p_arg == 1;
+                    state.powersafe_overwrite = value == 1;
                 }
             };
 
             libsqlite3_sys::SQLITE_OK
         }
 
-        // Optionally intercept PRAGMA statements. Always fall back to normal pragma processing.
-        libsqlite3_sys::SQLITE_FCNTL_PRAGMA => libsqlite3_sys::SQLITE_NOTFOUND,
+        // Optionally intercept PRAGMA statements. Falls back to normal pragma processing unless
+        // the handle answers the pragma.
+        libsqlite3_sys::SQLITE_FCNTL_PRAGMA => {
+            let Some(mut pragma) = p_arg.pragma() else {
+                return libsqlite3_sys::SQLITE_NOTFOUND;
+            };
+
+            match state.file.pragma(pragma.name, pragma.value).await {
+                Ok(Some(result)) => {
+                    if let Ok(result) = CString::new(result) {
+                        pragma.message.set_sqlite_string(&result);
+                    }
+                    libsqlite3_sys::SQLITE_OK
+                }
+                Ok(None) => libsqlite3_sys::SQLITE_NOTFOUND,
+                Err(err) => {
+                    if let Ok(msg) = CString::new(err.to_string()) {
+                        pragma.message.set_sqlite_string(&msg);
+                    }
+                    state.set_last_error(libsqlite3_sys::SQLITE_ERROR, err)
+                }
+            }
+        }
 
         // May be invoked by SQLite on the database file handle shortly after it is opened in
         // order to provide a custom VFS with access to the connection's busy-handler callback.
-        // Not implemented.
-        libsqlite3_sys::SQLITE_FCNTL_BUSYHANDLER => libsqlite3_sys::SQLITE_NOTFOUND,
+        // pArg points to the callback, followed by its argument. See [crate::busy].
+        libsqlite3_sys::SQLITE_FCNTL_BUSYHANDLER => {
+            let Some(handler) = p_arg.busy_handler() else {
+                return libsqlite3_sys::SQLITE_NOTFOUND;
+            };
+            state.busy_handler = handler.clone();
+            state.file.set_busy_handler(handler);
+            libsqlite3_sys::SQLITE_OK
+        }
 
         // Generate a temporary filename. Not implemented.
         libsqlite3_sys::SQLITE_FCNTL_TEMPFILENAME => {
-            if let Some(p_arg) = (p_arg as *mut *const c_char).as_mut() {
-                let name = state.vfs.temporary_name().await;
-                // unwrap() is fine as os strings are an arbitrary sequences of non-zero bytes
-                let name = CString::new(name.as_bytes()).unwrap();
-                let name = ManuallyDrop::new(name);
-                *p_arg = name.as_ptr();
-            };
+            let name = state.vfs.temporary_name().await;
+            // unwrap() is fine as os strings are an arbitrary sequences of non-zero bytes
+            let name = CString::new(name.as_bytes()).unwrap();
+            // freed by SQLite
+            p_arg.string_out().set_sqlite_string(&name);
 
             libsqlite3_sys::SQLITE_OK
         }
@@ -494,8 +515,9 @@ pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Erro
         // Advisory information to the VFS about what the higher layers of the SQLite stack are
         // doing.
         libsqlite3_sys::SQLITE_FCNTL_TRACE => {
-            let trace = CStr::from_ptr(p_arg as *const c_char);
-            log::trace!("{}", trace.to_string_lossy());
+            if let Some(trace) = p_arg.text() {
+                tracing::trace!(target: "sqlite_vfs::io", trace = %trace.to_string_lossy());
+            }
             libsqlite3_sys::SQLITE_OK
         }
 
@@ -503,9 +525,7 @@ pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Erro
         // opened.
         libsqlite3_sys::SQLITE_FCNTL_HAS_MOVED => match state.file.moved().await {
             Ok(moved) => {
-                if let Some(p_arg) = (p_arg as *mut i32).as_mut() {
-                    *p_arg = moved as i32;
-                }
+                p_arg.int().set(moved as i32);
                 libsqlite3_sys::SQLITE_OK
             }
             Err(err) => state.set_last_error(libsqlite3_sys::SQLITE_ERROR, err),
@@ -580,7 +600,10 @@ pub async unsafe fn file_control_inner<V: Vfs, F: DatabaseHandle<Error = V::Erro
 pub unsafe extern "C" fn close<V: Vfs, F: DatabaseHandle>(
     p_file: *mut libsqlite3_sys::sqlite3_file,
 ) -> c_int {
-    close_inner::<V, F>(p_file)
+    // SAFETY: SQLite passes a file of this VFS, and doesn't use it during or after the call
+    let file = unsafe { FileRef::<V, F>::from_raw(p_file) };
+    let probe = Probe::file(file.as_deref(), CallbackKind::Close, CallbackDetails::NONE);
+    probe.exit(close_inner(file))
 }
 
 /// Read data from a file.
@@ -590,7 +613,28 @@ pub unsafe extern "C" fn read<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     i_amt: c_int,
     i_ofst: libsqlite3_sys::sqlite3_int64,
 ) -> c_int {
-    read_inner::<V, F>(p_file, z_buf, i_amt, i_ofst)
+    // SAFETY: SQLite passes a file of this VFS, and a buffer of `i_amt` bytes to read into, neither
+    // used elsewhere during the call
+    let (file, buf) = unsafe {
+        (
+            FileRef::<V, F>::from_raw(p_file),
+            SqliteBufferMut::from_raw(z_buf, i_amt),
+        )
+    };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::Read,
+        CallbackDetails::range(i_ofst, i_amt),
+    );
+    let _busy = BusyScope::file(file.as_deref());
+    let rc = read_inner(file, buf, i_ofst);
+    if rc == libsqlite3_sys::SQLITE_OK && probe.observed() {
+        // SAFETY: as above, the buffer now holding the bytes read
+        if let Ok(data) = unsafe { SqliteBuffer::from_raw::<V::Error>(z_buf, i_amt) } {
+            probe.data(&data);
+        }
+    }
+    probe.exit(rc)
 }
 
 /// Write data to a file.
@@ -600,7 +644,24 @@ pub unsafe extern "C" fn write<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     i_amt: c_int,
     i_ofst: libsqlite3_sys::sqlite3_int64,
 ) -> c_int {
-    write_inner::<V, F>(p_file, z, i_amt, i_ofst)
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call, and the
+    // `i_amt` bytes to write
+    let (file, buf) = unsafe {
+        (
+            FileRef::<V, F>::from_raw(p_file),
+            SqliteBuffer::from_raw(z, i_amt),
+        )
+    };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::Write,
+        CallbackDetails::range(i_ofst, i_amt),
+    );
+    let _busy = BusyScope::file(file.as_deref());
+    if let Ok(data) = &buf {
+        probe.data(data);
+    }
+    probe.exit(write_inner(file, buf, i_ofst))
 }
 
 /// Truncate a file.
@@ -608,7 +669,15 @@ pub unsafe extern "C" fn truncate<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     p_file: *mut libsqlite3_sys::sqlite3_file,
     size: libsqlite3_sys::sqlite3_int64,
 ) -> c_int {
-    truncate_inner::<V, F>(p_file, size)
+    let details = CallbackDetails {
+        offset: Some(size as u64),
+        ..CallbackDetails::NONE
+    };
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call
+    let file = unsafe { FileRef::<V, F>::from_raw(p_file) };
+    let probe = Probe::file(file.as_deref(), CallbackKind::Truncate, details);
+    let _busy = BusyScope::file(file.as_deref());
+    probe.exit(truncate_inner(file, size))
 }
 
 /// Persist changes to a file.
@@ -616,7 +685,15 @@ pub unsafe extern "C" fn sync<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     p_file: *mut libsqlite3_sys::sqlite3_file,
     flags: c_int,
 ) -> c_int {
-    sync_inner::<V, F>(p_file, flags)
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call
+    let file = unsafe { FileRef::<V, F>::from_raw(p_file) };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::Sync,
+        CallbackDetails::arg(flags),
+    );
+    let _busy = BusyScope::file(file.as_deref());
+    probe.exit(sync_inner(file, flags))
 }
 
 /// Return the current file-size of a file.
@@ -624,7 +701,21 @@ pub unsafe extern "C" fn file_size<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     p_file: *mut libsqlite3_sys::sqlite3_file,
     p_size: *mut libsqlite3_sys::sqlite3_int64,
 ) -> c_int {
-    file_size_inner::<V, F>(p_file, p_size)
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call, and where to
+    // write its size
+    let (file, p_size) = unsafe {
+        (
+            FileRef::<V, F>::from_raw(p_file),
+            OutParam::from_raw(p_size),
+        )
+    };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::FileSize,
+        CallbackDetails::NONE,
+    );
+    let _busy = BusyScope::file(file.as_deref());
+    probe.exit(file_size_inner(file, p_size))
 }
 
 /// Lock a file.
@@ -632,7 +723,15 @@ pub unsafe extern "C" fn lock<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     p_file: *mut libsqlite3_sys::sqlite3_file,
     e_lock: c_int,
 ) -> c_int {
-    lock_inner::<V, F>(p_file, e_lock)
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call
+    let file = unsafe { FileRef::<V, F>::from_raw(p_file) };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::Lock,
+        CallbackDetails::arg(e_lock),
+    );
+    let _busy = BusyScope::file(file.as_deref());
+    probe.exit(lock_inner(file, e_lock))
 }
 
 /// Unlock a file.
@@ -640,7 +739,15 @@ pub unsafe extern "C" fn unlock<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     p_file: *mut libsqlite3_sys::sqlite3_file,
     e_lock: c_int,
 ) -> c_int {
-    unlock_inner::<V, F>(p_file, e_lock)
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call
+    let file = unsafe { FileRef::<V, F>::from_raw(p_file) };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::Unlock,
+        CallbackDetails::arg(e_lock),
+    );
+    let _busy = BusyScope::file(file.as_deref());
+    probe.exit(unlock_inner(file, e_lock))
 }
 
 /// Check if another file-handle holds a [LockKind::Reserved] lock on a file.
@@ -648,7 +755,21 @@ pub unsafe extern "C" fn check_reserved_lock<V: Vfs, F: DatabaseHandle<Error = V
     p_file: *mut libsqlite3_sys::sqlite3_file,
     p_res_out: *mut c_int,
 ) -> c_int {
-    check_reserved_lock_inner::<V, F>(p_file, p_res_out)
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call, and where to
+    // write the result
+    let (file, p_res_out) = unsafe {
+        (
+            FileRef::<V, F>::from_raw(p_file),
+            OutParam::from_raw(p_res_out),
+        )
+    };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::CheckReservedLock,
+        CallbackDetails::NONE,
+    );
+    let _busy = BusyScope::file(file.as_deref());
+    probe.exit(check_reserved_lock_inner(file, p_res_out))
 }
 
 /// File control method. For custom operations on a mem-file.
@@ -657,59 +778,87 @@ pub unsafe extern "C" fn file_control<V: Vfs, F: DatabaseHandle<Error = V::Error
     op: c_int,
     p_arg: *mut c_void,
 ) -> c_int {
-    file_control_inner::<V, F>(p_file, op, p_arg)
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call, and the
+    // argument of `op`
+    let (file, p_arg) = unsafe {
+        (
+            FileRef::<V, F>::from_raw(p_file),
+            FileControlArg::from_raw(op, p_arg),
+        )
+    };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::FileControl,
+        CallbackDetails::arg(op),
+    );
+    probe.exit(file_control_inner(file, p_arg))
 }
 
 /// Return the sector-size in bytes for a file.
-pub unsafe extern "C" fn sector_size<F>(_p_file: *mut libsqlite3_sys::sqlite3_file) -> c_int {
-    log::trace!("sector_size");
+pub unsafe extern "C" fn sector_size<V: Vfs, F: DatabaseHandle>(
+    p_file: *mut libsqlite3_sys::sqlite3_file,
+) -> c_int {
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call
+    let file = unsafe { FileRef::<V, F>::from_raw(p_file) };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::SectorSize,
+        CallbackDetails::NONE,
+    );
+    let sector_size = file.map_or(capability::DEFAULT_SECTOR_SIZE, |state| {
+        state.file.sector_size()
+    });
+    tracing::trace!(target: "sqlite_vfs::io", sector_size, "sector_size");
 
-    1024
+    probe.exit(sector_size as c_int)
 }
 
 /// Return the device characteristic flags supported by a file.
 pub unsafe extern "C" fn device_characteristics<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     p_file: *mut libsqlite3_sys::sqlite3_file,
 ) -> c_int {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_SHMMAP,
-    };
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call
+    let file = unsafe { FileRef::<V, F>::from_raw(p_file) };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::DeviceCharacteristics,
+        CallbackDetails::NONE,
+    );
+    probe.exit(device_characteristics_inner(file))
+}
 
-    log::trace!("[{}] device_characteristics", state.id,);
+fn device_characteristics_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
+) -> c_int {
+    let Some(state) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_SHMMAP;
+    };
 
-    // The following characteristics are needed to match the expected behavior of the tests.
+    tracing::trace!(target: "sqlite_vfs::io", id = state.id, "device_characteristics");
 
-    // after reboot following a crash or power loss, the only bytes in a file that were written
-    // at the application level might have changed and that adjacent bytes, even bytes within
-    // the same sector are guaranteed to be unchanged
-    if state.powersafe_overwrite {
-        libsqlite3_sys::SQLITE_IOCAP_POWERSAFE_OVERWRITE
-    } else {
-        0
-    }
+    state.device_characteristics()
 }
 
 /// Create a shared memory file mapping.
 #[tokio::main]
-pub async unsafe fn shm_map_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    p_file: *mut libsqlite3_sys::sqlite3_file,
+async fn shm_map_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
     region_ix: i32,
     region_size: i32,
     b_extend: i32,
-    pp: *mut *mut c_void,
+    mut pp: OutParam<'_, *mut c_void>,
 ) -> i32 {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_SHMMAP,
+    let Some(mut file) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_SHMMAP;
     };
-    log::trace!(
-        "[{}] shm_map pg={} sz={} extend={} ({})",
-        state.id,
+    let state = &mut *file;
+    tracing::trace!(
+        target: "sqlite_vfs::io",
+        id = state.id,
         region_ix,
         region_size,
         b_extend,
-        state.db_name
+        "shm_map"
     );
 
     if !F::WalIndex::enabled() {
@@ -763,7 +912,7 @@ pub async unsafe fn shm_map_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     let entry = state.wal_index_regions.entry(region_ix as u32);
     match entry {
         Entry::Occupied(mut entry) => {
-            *pp = entry.get_mut().as_mut_ptr() as *mut c_void;
+            pp.set(entry.get_mut().as_mut_ptr() as *mut c_void);
         }
         Entry::Vacant(entry) => {
             let mut m = match wal_index.map::<F>(region_ix as u32) {
@@ -772,7 +921,7 @@ pub async unsafe fn shm_map_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
                     return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_SHMMAP, err);
                 }
             };
-            *pp = m.as_mut_ptr() as *mut c_void;
+            pp.set(m.as_mut_ptr() as *mut c_void);
             entry.insert(m);
         }
     }
@@ -792,7 +941,15 @@ pub unsafe extern "C" fn shm_map<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     b_extend: i32,
     pp: *mut *mut c_void,
 ) -> i32 {
-    shm_map_inner::<V, F>(p_file, region_ix, region_size, b_extend, pp)
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call, and where to
+    // write the address of the region
+    let (file, pp) = unsafe { (FileRef::<V, F>::from_raw(p_file), OutParam::from_raw(pp)) };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::ShmMap,
+        CallbackDetails::arg(region_ix),
+    );
+    probe.exit(shm_map_inner(file, region_ix, region_size, b_extend, pp))
 }
 
 /// Perform locking on a shared-memory segment.
@@ -802,21 +959,42 @@ pub unsafe extern "C" fn shm_lock<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     n: i32,
     flags: i32,
 ) -> i32 {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_SHMMAP,
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call
+    let file = unsafe { FileRef::<V, F>::from_raw(p_file) };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::ShmLock,
+        CallbackDetails {
+            offset: Some(offset as u64),
+            len: Some(n as usize),
+            arg: Some(flags),
+            ..CallbackDetails::NONE
+        },
+    );
+    probe.exit(shm_lock_inner(file, offset, n, flags))
+}
+
+fn shm_lock_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
+    offset: i32,
+    n: i32,
+    flags: i32,
+) -> i32 {
+    let Some(mut file) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_SHMMAP;
     };
+    let state = &mut *file;
     let locking = flags & libsqlite3_sys::SQLITE_SHM_LOCK > 0;
     let exclusive = flags & libsqlite3_sys::SQLITE_SHM_EXCLUSIVE > 0;
-    log::trace!(
-        "[{}] shm_lock offset={} n={} lock={} exclusive={} (flags={}) ({})",
-        state.id,
+    tracing::trace!(
+        target: "sqlite_vfs::lock",
+        id = state.id,
         offset,
         n,
         locking,
         exclusive,
         flags,
-        state.db_name
+        "shm_lock"
     );
 
     let range = offset as u8..(offset + n) as u8;
@@ -826,8 +1004,8 @@ pub unsafe extern "C" fn shm_lock<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
         (false, _) => wip::WalIndexLock::None,
     };
 
-    let (wal_index, readonly) = match state.wal_index.as_mut() {
-        Some((wal_index, readonly)) => (wal_index, *readonly),
+    let readonly = match state.wal_index.as_ref() {
+        Some((_, readonly)) => *readonly,
         None => {
             return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_SHMLOCK, Error::WalIndexLock)
         }
@@ -840,14 +1018,13 @@ pub unsafe extern "C" fn shm_lock<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
             .any(|(_, lock)| *lock == wip::WalIndexLock::Exclusive);
 
         if !has_exclusive {
-            log::trace!(
-                "[{}] does not have wal index write lock, pulling changes",
-                state.id
+            tracing::trace!(
+                target: "sqlite_vfs::lock",
+                id = state.id,
+                "does not have wal index write lock, pulling changes"
             );
-            for (region, data) in &mut state.wal_index_regions {
-                if let Err(err) = wal_index.pull::<F>(*region as u32, data) {
-                    return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_SHMLOCK, err);
-                }
+            if let Err(err) = refresh_wal_index(state) {
+                return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_SHMLOCK, err);
             }
         }
     } else {
@@ -858,18 +1035,20 @@ pub unsafe extern "C" fn shm_lock<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
 
         // push index changes when moving from any exclusive lock to no exclusive locks
         if releases_any_exclusive && !readonly {
-            log::trace!(
-                "[{}] releasing an exclusive lock, pushing wal index changes",
-                state.id,
+            tracing::trace!(
+                target: "sqlite_vfs::lock",
+                id = state.id,
+                "releasing an exclusive lock, pushing wal index changes"
             );
-            for (region, data) in &mut state.wal_index_regions {
-                if let Err(err) = wal_index.push::<F>(*region as u32, data) {
-                    return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_SHMLOCK, err);
-                }
+            if let Err(err) = publish_wal_index(state) {
+                return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_SHMLOCK, err);
             }
         }
     }
 
+    let Some((wal_index, _)) = state.wal_index.as_mut() else {
+        return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_SHMLOCK, Error::WalIndexLock);
+    };
     match wal_index.lock::<F>(range.clone(), lock) {
         Ok(true) => {
             for region in range {
@@ -886,27 +1065,43 @@ pub unsafe extern "C" fn shm_lock<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
 pub unsafe extern "C" fn shm_barrier<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     p_file: *mut libsqlite3_sys::sqlite3_file,
 ) {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return,
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call
+    let file = unsafe { FileRef::<V, F>::from_raw(p_file) };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::ShmBarrier,
+        CallbackDetails::NONE,
+    );
+    shm_barrier_inner(file);
+    probe.exit(libsqlite3_sys::SQLITE_OK);
+}
+
+fn shm_barrier_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(file: Option<FileRef<'_, V, F>>) {
+    let Some(mut file) = file else {
+        return;
     };
-    log::trace!("[{}] shm_barrier ({})", state.id, state.db_name);
+    let state = &mut *file;
+    tracing::trace!(target: "sqlite_vfs::io", id = state.id, "shm_barrier");
 
-    let (wal_index, readonly) = if let Some((wal_index, readonly)) = state.wal_index.as_mut() {
-        (wal_index, *readonly)
+    let readonly = if let Some((_, readonly)) = state.wal_index.as_ref() {
+        *readonly
     } else {
         return;
     };
 
     if state.has_exclusive_lock && !readonly {
-        log::trace!(
-            "[{}] has exclusive db lock, pushing wal index changes",
-            state.id,
+        tracing::trace!(
+            target: "sqlite_vfs::lock",
+            id = state.id,
+            "has exclusive db lock, pushing wal index changes"
         );
-        for (region, data) in &mut state.wal_index_regions {
-            if let Err(err) = wal_index.push::<F>(*region as u32, data) {
-                log::error!("[{}] pushing wal index changes failed: {}", state.id, err)
-            }
+        if let Err(err) = publish_wal_index(state) {
+            tracing::error!(
+                target: "sqlite_vfs::lock",
+                id = state.id,
+                %err,
+                "pushing wal index changes failed"
+            )
         }
 
         return;
@@ -918,32 +1113,84 @@ pub unsafe extern "C" fn shm_barrier<V: Vfs, F: DatabaseHandle<Error = V::Error>
         .any(|(_, lock)| *lock == wip::WalIndexLock::Exclusive);
 
     if !has_exclusive {
-        log::trace!(
-            "[{}] does not have wal index write lock, pulling changes",
-            state.id
+        tracing::trace!(
+            target: "sqlite_vfs::lock",
+            id = state.id,
+            "does not have wal index write lock, pulling changes"
         );
-        for (region, data) in &mut state.wal_index_regions {
-            if let Err(err) = wal_index.pull::<F>(*region as u32, data) {
-                log::error!("[{}] pulling wal index changes failed: {}", state.id, err)
-            }
+        if let Err(err) = refresh_wal_index(state) {
+            tracing::error!(
+                target: "sqlite_vfs::lock",
+                id = state.id,
+                %err,
+                "pulling wal index changes failed"
+            )
         }
     }
 }
 
+/// Push the mapped wal index regions of `state` as one publication, see [WalIndex::publish].
+fn publish_wal_index<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    state: &mut state::FileExt<V, F>,
+) -> Result<(), Error<V::Error>> {
+    let Some((wal_index, _)) = state.wal_index.as_mut() else {
+        return Ok(());
+    };
+    let regions = state
+        .wal_index_regions
+        .iter()
+        .map(|(region, data)| (*region, &**data));
+    let sequence = wal_index.publish::<F>(regions)?;
+    tracing::trace!(target: "sqlite_vfs::lock", id = state.id, sequence, "published wal index");
+    Ok(())
+}
+
+/// Pull the mapped wal index regions of `state` from a single publication, see
+/// [WalIndex::refresh].
+fn refresh_wal_index<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    state: &mut state::FileExt<V, F>,
+) -> Result<(), Error<V::Error>> {
+    let Some((wal_index, _)) = state.wal_index.as_mut() else {
+        return Ok(());
+    };
+    let mut regions: Vec<(u32, &mut [u8; 32768])> = state
+        .wal_index_regions
+        .iter_mut()
+        .map(|(region, data)| (*region, &mut **data))
+        .collect();
+    let sequence = wal_index.refresh::<F>(&mut regions)?;
+    tracing::trace!(target: "sqlite_vfs::lock", id = state.id, sequence, "refreshed wal index");
+    Ok(())
+}
+
 /// Unmap a shared memory segment.
 pub unsafe extern "C" fn shm_unmap<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
     p_file: *mut libsqlite3_sys::sqlite3_file,
     delete_flags: i32,
 ) -> i32 {
-    let state = match file_state::<V, F>(p_file) {
-        Ok(f) => f,
-        Err(_) => return libsqlite3_sys::SQLITE_IOERR_SHMMAP,
-    };
-    log::trace!(
-        "[{}] shm_unmap delete={} ({})",
-        state.id,
-        delete_flags == 1,
-        state.db_name
+    // SAFETY: SQLite passes a file of this VFS, not used elsewhere during the call
+    let file = unsafe { FileRef::<V, F>::from_raw(p_file) };
+    let probe = Probe::file(
+        file.as_deref(),
+        CallbackKind::ShmUnmap,
+        CallbackDetails::arg(delete_flags),
+    );
+    probe.exit(shm_unmap_inner(file, delete_flags))
+}
+
+fn shm_unmap_inner<V: Vfs, F: DatabaseHandle<Error = V::Error>>(
+    file: Option<FileRef<'_, V, F>>,
+    delete_flags: i32,
+) -> i32 {
+    let Some(mut file) = file else {
+        return libsqlite3_sys::SQLITE_IOERR_SHMMAP;
+    };
+    let state = &mut *file;
+    tracing::trace!(
+        target: "sqlite_vfs::io",
+        id = state.id,
+        delete = delete_flags == 1,
+        "shm_unmap"
     );
 
     state.wal_index_regions.clear();
@@ -0,0 +1,34 @@
+//! Deprecated trait names kept for one release to ease the migration to the async traits. See
+//! `MIGRATION.md`.
+#![allow(deprecated)]
+
+use crate::sync_compat::{SyncDatabaseHandle, SyncVfs};
+
+/// The blocking `Vfs` trait from before the async consolidation.
+#[deprecated(
+    note = "`sqlite_vfs::Vfs` is async now; implement `sync_compat::SyncVfs` and register it \
+            wrapped in `sync_compat::SyncVfsAdapter`, or implement `sqlite_vfs::Vfs` directly"
+)]
+pub trait Vfs: SyncVfs {}
+
+impl<T: SyncVfs> Vfs for T {}
+
+/// The blocking `DatabaseHandle` trait from before the async consolidation.
+#[deprecated(note = "`sqlite_vfs::DatabaseHandle` is async now; implement \
+            `sync_compat::SyncDatabaseHandle` (adapted by `sync_compat::SyncHandleAdapter`), or \
+            implement `sqlite_vfs::DatabaseHandle` directly")]
+pub trait DatabaseHandle: SyncDatabaseHandle {}
+
+impl<T: SyncDatabaseHandle> DatabaseHandle for T {}
+
+/// The buffer [Vfs::random](crate::Vfs::random) fills, as the `i8` it used to be passed as, for
+/// implementations that fill it through an `i8` API.
+#[deprecated(
+    note = "`Vfs::random` and `SyncVfs::random` take `&mut [u8]` now; fill the bytes \
+            directly"
+)]
+pub fn random_as_i8(buffer: &mut [u8]) -> &mut [i8] {
+    // SAFETY: `i8` has the size, alignment and valid bit patterns of `u8`, and the returned slice
+    // borrows `buffer` mutably for as long as it lives
+    unsafe { std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut i8, buffer.len()) }
+}
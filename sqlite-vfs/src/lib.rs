@@ -1,28 +1,77 @@
 #![allow(clippy::question_mark)]
+#![deny(unsafe_op_in_unsafe_fn, clippy::undocumented_unsafe_blocks)]
 //! Create a custom SQLite virtual file system by implementing the [Vfs] trait and registering it
 //! using [register].
-
+//!
+//! # Tracing
+//!
+//! Events are emitted with [tracing] (and forwarded to [log] if no subscriber is installed) under
+//! the following targets:
+//!
+//! - `sqlite_vfs::io::read`, `sqlite_vfs::io::write`: every read and write
+//! - `sqlite_vfs::io`: every other file operation
+//! - `sqlite_vfs::lock`: lock requests, and lock transitions of the database and WAL index
+//! - `sqlite_vfs::vfs`: operations on the VFS itself, like opening and deleting files
+//!
+//! Per-operation events are emitted at `TRACE`, lock transitions and opening or closing files at
+//! `DEBUG`, lifecycle events at `INFO`, and degradations at `WARN` and `ERROR`. Fields are only
+//! evaluated for enabled events. To compile the per-operation events out of release builds, enable
+//! `tracing`'s `release_max_level_debug` feature (or `log`'s) in the final binary.
+//!
+//! # Testing
+//!
+//! [instrument] reports every callback SQLite invokes, to assert the order of operations a
+//! workload produces, and [fcntl::FileControlCoverage] which file controls it sends.
+//! [Vfs::current_time] is where SQLite reads the time; return a
+//! [clock::MockClock] from it to control time in tests. [conformance] checks a backend against
+//! the requirements of SQLite on a VFS.
+//!
+//! # Safety
+//!
+//! The callbacks SQLite invokes wrap the raw pointers they receive in the views of `ffi` right
+//! away, which hold all dereferences of them; every `unsafe` block states what it relies on in a
+//! `// SAFETY:` comment, and `unsafe fn`s still use `unsafe` blocks inside.
+//!
+//! [log]: https://docs.rs/log
+
+pub mod busy;
+pub mod capability;
+pub mod clock;
+pub mod conformance;
 pub mod error;
+pub mod fcntl;
+mod ffi;
+pub mod instrument;
 pub mod io;
+pub mod legacy;
 pub mod state;
+pub mod sync_compat;
 pub mod vfs;
 
 use std::borrow::Cow;
-use std::ffi::{c_void, CStr, CString};
+use std::collections::BTreeMap;
+use std::ffi::{c_void, CString};
 use std::future::Future;
 use std::io::ErrorKind;
-use std::mem::{size_of, ManuallyDrop, MaybeUninit};
+use std::mem::size_of;
 use std::ops::Range;
-use std::os::raw::{c_char, c_int};
+use std::os::raw::c_int;
 use std::ptr::null_mut;
-use std::slice;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 
-use state::{FileState, State};
+use capability::{Capabilities, SqliteLibrary, VfsCapabilities};
+use instrument::Instrumentation;
+use state::{FileState, Registration, State};
 use tokio::runtime::Handle;
 
 /// A file opened by [Vfs].
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` does not implement the async `sqlite_vfs::DatabaseHandle` trait",
+    note = "blocking handles should implement `sqlite_vfs::sync_compat::SyncDatabaseHandle`; they \
+            are adapted by `sync_compat::SyncHandleAdapter` when their VFS is wrapped in \
+            `sync_compat::SyncVfsAdapter` (see MIGRATION.md)"
+)]
 pub trait DatabaseHandle: Sync {
     /// An optional trait used to store a WAL (write-ahead log).
     type WalIndex: wip::WalIndex;
@@ -53,6 +102,12 @@ pub trait DatabaseHandle: Sync {
     ) -> impl Future<Output = Result<(), crate::error::Error<Self::Error>>>;
 
     /// Set the database file to the specified `size`. Truncates or extends the underlying storage.
+    ///
+    /// `size` is the logical length SQLite asked for, never rounded up to the growth quantum of
+    /// [set_chunk_size](Self::set_chunk_size), and [size](Self::size) returns it afterwards. Bytes
+    /// between the previous length and a larger `size` read as zeros, and so do the bytes past a
+    /// smaller `size` once the file grows again: a backend storing the file in units of its own
+    /// discards or zeroes what lies past `size` in the last unit it keeps.
     fn set_len(
         &mut self,
         size: u64,
@@ -85,7 +140,11 @@ pub trait DatabaseHandle: Sync {
         &self,
     ) -> impl Future<Output = Result<LockKind, crate::error::Error<Self::Error>>>;
 
-    /// Change the chunk size of the database to `chunk_size`.
+    /// Set the growth quantum of the file to `chunk_size` bytes, as asked by
+    /// `SQLITE_FCNTL_CHUNK_SIZE`, or `0` when SQLite turns it off. SQLite would like the file to
+    /// be allocated in multiples of it, e.g. to reduce fragmentation. It is a hint for allocation
+    /// only, unrelated to any unit the backend stores the file in, and doesn't change the length
+    /// of the file, see [set_len](Self::set_len) and [allocate](Self::allocate).
     fn set_chunk_size(
         &self,
         _chunk_size: usize,
@@ -93,12 +152,51 @@ pub trait DatabaseHandle: Sync {
         async move { Ok(()) }
     }
 
+    /// Make room for the file to grow to `size` bytes without changing its length, as asked by
+    /// `SQLITE_FCNTL_SIZE_HINT` ahead of writes. `size` is rounded up to the growth quantum of
+    /// [set_chunk_size](Self::set_chunk_size). Does nothing by default.
+    fn allocate(
+        &mut self,
+        _size: u64,
+    ) -> impl Future<Output = Result<(), crate::error::Error<Self::Error>>> {
+        async move { Ok(()) }
+    }
+
     /// Check if the underlying data of the handle got moved or deleted. When moved, the handle can
     /// still be read from, but not written to anymore.
     fn moved(&self) -> impl Future<Output = Result<bool, crate::error::Error<Self::Error>>> {
         async move { Ok(false) }
     }
 
+    /// The sector size in bytes SQLite is told through `xSectorSize`, i.e. the unit a crash may
+    /// tear a write at. It must be coherent with the device characteristics of the file, see
+    /// [capability::check_sector_size]; the default of 512 assumes nothing.
+    fn sector_size(&self) -> u32 {
+        capability::DEFAULT_SECTOR_SIZE
+    }
+
+    /// Intercept a `PRAGMA name = value` statement. Return `Some` to answer the pragma with the
+    /// given value, or `None` to fall back to SQLite's normal pragma processing.
+    fn pragma(
+        &mut self,
+        _name: &str,
+        _value: Option<&str>,
+    ) -> impl Future<Output = Result<Option<String>, crate::error::Error<Self::Error>>> {
+        async move { Ok(None) }
+    }
+
+    /// Receive the busy handler of the connection, to consult while waiting for a lock, see
+    /// [busy]. `None` once the file is closed.
+    fn set_busy_handler(&mut self, _handler: Option<busy::BusyHandlerRef>) {}
+
+    /// Learn whether the WAL and its index are kept when the last connection to the database
+    /// closes, as set with `SQLITE_FCNTL_PERSIST_WAL`. SQLite decides whether to delete them
+    /// itself: only the connection that can lock the database exclusively on close, i.e. the last
+    /// one of every process, checkpoints and then deletes them through [Vfs::delete] and
+    /// [wip::WalIndex::delete], unless they persist. Backends that keep more than the WAL, e.g.
+    /// objects the WAL is split into, retain them accordingly.
+    fn set_persist_wal(&mut self, _persist: bool) {}
+
     fn wal_index(
         &self,
         readonly: bool,
@@ -106,6 +204,11 @@ pub trait DatabaseHandle: Sync {
 }
 
 /// A virtual file system for SQLite.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` does not implement the async `sqlite_vfs::Vfs` trait",
+    note = "blocking file systems should implement `sqlite_vfs::sync_compat::SyncVfs` and be \
+            registered as `sync_compat::SyncVfsAdapter::new(vfs)` (see MIGRATION.md)"
+)]
 pub trait Vfs: Sync {
     /// The file returned by [Vfs::open].
     type Handle: DatabaseHandle;
@@ -126,6 +229,10 @@ pub trait Vfs: Sync {
     ) -> impl Future<Output = Result<(), crate::error::Error<Self::Error>>>;
 
     /// Check if a database `db` already exists.
+    ///
+    /// `Ok(false)` must mean that `db` definitively doesn't exist. When the backend can't tell,
+    /// e.g. because its storage is unreachable, this must fail instead: callers decide whether to
+    /// create a file or recover a hot journal by the answer, and take a failure for neither.
     fn exists(
         &self,
         db: &str,
@@ -135,10 +242,23 @@ pub trait Vfs: Sync {
     fn temporary_name(&self) -> impl Future<Output = String>;
 
     /// Populate the `buffer` with random data.
-    fn random(&self, buffer: &mut [i8]) -> impl Future<Output = ()>;
+    fn random(&self, buffer: &mut [u8]) -> impl Future<Output = ()>;
 
     /// Sleep for `duration`. Return the duration actually slept.
-    fn sleep(&self, duration: Duration) -> Duration;
+    fn sleep(&self, duration: Duration) -> impl Future<Output = Duration>;
+
+    /// The current time, as reported to SQLite, e.g. for `datetime('now')`. The default
+    /// implementation returns the system time.
+    fn current_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// What the backend can do, see [VfsCapabilities]. Called once, when registering; the value
+    /// then decides the methods registered and which kinds every open refuses. The default
+    /// implementation derives it from the handles, see [VfsCapabilities::of].
+    fn capabilities(&self) -> VfsCapabilities {
+        VfsCapabilities::of::<Self::Handle>()
+    }
 
     /// Check access to `db`. The default implementation always returns `true`.
     fn access(
@@ -169,6 +289,28 @@ pub mod wip {
         Exclusive,
     }
 
+    /// How many times [WalIndex::refresh] reads the regions before giving up on a stable
+    /// snapshot, with [Error::WalIndexUnstable](crate::error::Error::WalIndexUnstable).
+    pub const REFRESH_ATTEMPTS: usize = 8;
+
+    /// The shared memory index of a WAL database, kept in sync across the connections sharing it.
+    ///
+    /// Connections hold a copy of each mapped region, and exchange changes through the backing
+    /// index: the holder of an exclusive lock pushes its regions when it releases the lock or
+    /// reaches a memory barrier, and the other connections pull them when they lock the index or
+    /// reach a barrier. Pushing several regions isn't atomic, so the exchange follows a sequence
+    /// lock, as implemented by [WalIndex::publish] and [WalIndex::refresh]:
+    ///
+    /// - the publisher moves the sequence to an odd number, pushes every mapped region, then moves
+    ///   it to the following even number, written last;
+    /// - a reader reads the sequence, pulls every region, then reads the sequence again, and only
+    ///   keeps what it pulled if the sequence was even and didn't change meanwhile. Otherwise it
+    ///   tries again, up to [REFRESH_ATTEMPTS] times.
+    ///
+    /// Only the holder of an exclusive lock publishes, so publications never overlap. A publisher
+    /// that fails halfway leaves an odd sequence, and readers busy until the next publication.
+    /// Indexes that keep no sequence, the default, behave as before: their sequence is always 0
+    /// and each pull is taken as is.
     pub trait WalIndex: Sync {
         fn enabled() -> bool {
             true
@@ -200,6 +342,71 @@ pub mod wip {
         ) -> Result<(), crate::error::Error<Handle::Error>> {
             Ok(())
         }
+
+        /// The current sequence of the backing index, odd while a publication is in progress.
+        fn sequence<Handle: DatabaseHandle>(
+            &mut self,
+        ) -> Result<u64, crate::error::Error<Handle::Error>> {
+            Ok(0)
+        }
+
+        /// Move the sequence of the backing index to `sequence`.
+        fn set_sequence<Handle: DatabaseHandle>(
+            &mut self,
+            _sequence: u64,
+        ) -> Result<(), crate::error::Error<Handle::Error>> {
+            Ok(())
+        }
+
+        /// Push `regions` to the backing index as one publication. Returns its sequence.
+        fn publish<'a, Handle: DatabaseHandle>(
+            &mut self,
+            regions: impl IntoIterator<Item = (u32, &'a [u8; 32768])>,
+        ) -> Result<u64, crate::error::Error<Handle::Error>> {
+            let current = self.sequence::<Handle>()?;
+            // past a publication left in progress as well
+            let begin = if current % 2 == 0 {
+                current + 1
+            } else {
+                current + 2
+            };
+            self.set_sequence::<Handle>(begin)?;
+            for (region, data) in regions {
+                self.push::<Handle>(region, data)?;
+            }
+            self.set_sequence::<Handle>(begin + 1)?;
+            Ok(begin + 1)
+        }
+
+        /// Pull `regions` from the backing index, all from the same publication. They are only
+        /// updated once a stable snapshot was read. Returns its sequence.
+        fn refresh<Handle: DatabaseHandle>(
+            &mut self,
+            regions: &mut [(u32, &mut [u8; 32768])],
+        ) -> Result<u64, crate::error::Error<Handle::Error>> {
+            let mut snapshot: Vec<Box<[u8; 32768]>> =
+                regions.iter().map(|(_, data)| Box::new(**data)).collect();
+            for _ in 0..REFRESH_ATTEMPTS {
+                let before = self.sequence::<Handle>()?;
+                if before % 2 == 1 {
+                    std::thread::yield_now();
+                    continue;
+                }
+                for ((region, _), data) in regions.iter().zip(&mut snapshot) {
+                    self.pull::<Handle>(*region, data)?;
+                }
+                if self.sequence::<Handle>()? == before {
+                    for ((_, data), pulled) in regions.iter_mut().zip(&snapshot) {
+                        **data = **pulled;
+                    }
+                    return Ok(before);
+                }
+                std::thread::yield_now();
+            }
+            Err(crate::error::Error::WalIndexUnstable {
+                attempts: REFRESH_ATTEMPTS,
+            })
+        }
     }
 }
 
@@ -281,14 +488,83 @@ pub enum LockKind {
     Exclusive,
 }
 
-/// Register a virtual file system ([Vfs]) to SQLite.
+/// Register a virtual file system ([Vfs]) to SQLite, until the returned [VfsHandle] is dropped
+/// or [VfsHandle::leak]ed.
 pub fn register<F: DatabaseHandle<Error = V::Error>, V: Vfs<Handle = F>>(
     name: &str,
     vfs: V,
     as_default: bool,
+) -> Result<VfsHandle, RegisterError> {
+    register_inner(
+        name,
+        vfs,
+        as_default,
+        None,
+        libsqlite3_sys::sqlite3_vfs_register,
+        &capability::Linked,
+    )
+}
+
+/// `sqlite3_vfs_register`, as linked into this crate or as provided by the
+/// `sqlite3_api_routines` a loadable extension is called with.
+pub type VfsRegister =
+    unsafe extern "C" fn(vfs: *mut libsqlite3_sys::sqlite3_vfs, make_default: c_int) -> c_int;
+
+/// Register a virtual file system ([Vfs]) through `vfs_register`, e.g. to register it with the
+/// SQLite library that loads an extension rather than the one linked into this crate. Its
+/// callbacks are reported to `instrumentation`, if any. It stays registered, and its state
+/// allocated, for the life of the process, since that library may not be the linked one
+/// [VfsHandle::unregister] goes through.
+pub fn register_with<F: DatabaseHandle<Error = V::Error>, V: Vfs<Handle = F>>(
+    name: &str,
+    vfs: V,
+    as_default: bool,
+    instrumentation: Option<Arc<dyn Instrumentation>>,
+    vfs_register: VfsRegister,
 ) -> Result<(), RegisterError> {
+    register_inner(
+        name,
+        vfs,
+        as_default,
+        instrumentation,
+        vfs_register,
+        &capability::Linked,
+    )
+    .map(VfsHandle::leak)
+}
+
+/// Register a virtual file system ([Vfs]) to SQLite, reporting its callbacks to
+/// `instrumentation`, until the returned [VfsHandle] is dropped or [VfsHandle::leak]ed.
+pub fn register_instrumented<F: DatabaseHandle<Error = V::Error>, V: Vfs<Handle = F>>(
+    name: &str,
+    vfs: V,
+    as_default: bool,
+    instrumentation: Arc<dyn Instrumentation>,
+) -> Result<VfsHandle, RegisterError> {
+    register_inner(
+        name,
+        vfs,
+        as_default,
+        Some(instrumentation),
+        libsqlite3_sys::sqlite3_vfs_register,
+        &capability::Linked,
+    )
+}
+
+fn register_inner<F: DatabaseHandle<Error = V::Error>, V: Vfs<Handle = F>>(
+    name: &str,
+    vfs: V,
+    as_default: bool,
+    instrumentation: Option<Arc<dyn Instrumentation>>,
+    vfs_register: VfsRegister,
+    library: &dyn SqliteLibrary,
+) -> Result<VfsHandle, RegisterError> {
+    sweep_retired();
+    let capabilities = capability::check(name, library)?;
+    let backend = vfs.capabilities();
+    let shm = backend.supports_shm;
     let io_methods = libsqlite3_sys::sqlite3_io_methods {
-        iVersion: 2,
+        iVersion: backend.io_methods_version(),
         xClose: Some(io::close::<V, F>),
         xRead: Some(io::read::<V, F>),
         xWrite: Some(io::write::<V, F>),
@@ -299,25 +575,35 @@ pub fn register<F: DatabaseHandle<Error = V::Error>, V: Vfs<Handle = F>>(
         xUnlock: Some(io::unlock::<V, F>),
         xCheckReservedLock: Some(io::check_reserved_lock::<V, F>),
         xFileControl: Some(io::file_control::<V, F>),
-        xSectorSize: Some(io::sector_size::<F>),
+        xSectorSize: Some(io::sector_size::<V, F>),
         xDeviceCharacteristics: Some(io::device_characteristics::<V, F>),
-        xShmMap: Some(io::shm_map::<V, F>),
-        xShmLock: Some(io::shm_lock::<V, F>),
-        xShmBarrier: Some(io::shm_barrier::<V, F>),
-        xShmUnmap: Some(io::shm_unmap::<V, F>),
+        xShmMap: shm.then_some(io::shm_map::<V, F>),
+        xShmLock: shm.then_some(io::shm_lock::<V, F>),
+        xShmBarrier: shm.then_some(io::shm_barrier::<V, F>),
+        xShmUnmap: shm.then_some(io::shm_unmap::<V, F>),
         xFetch: None,
         xUnfetch: None,
     };
-    let name = CString::new(name).map_err(|e| RegisterError::Nul(e))?;
-    let name_ptr = name.as_ptr();
-    let ptr = Box::into_raw(Box::new(State {
-        name,
+    let c_name = CString::new(name).map_err(|e| RegisterError::Nul(e))?;
+    let name_ptr = c_name.as_ptr();
+    let instrumented = instrumentation.is_some();
+    let capabilities = Arc::new(capabilities);
+    let state = Arc::new(State {
+        name: c_name.clone(),
+        capabilities: capabilities.clone(),
+        backend,
         vfs: Arc::new(vfs),
         #[cfg(any(feature = "syscall", feature = "loadext"))]
+        // SAFETY: finds the default VFS, taking no pointer
         parent_vfs: unsafe { libsqlite3_sys::sqlite3_vfs_find(std::ptr::null_mut()) },
         io_methods,
         last_error: Default::default(),
-        next_id: 0,
+        next_id: Default::default(),
+        instrumentation,
+    });
+    let ptr = Box::into_raw(Box::new(Registration {
+        name: c_name,
+        state: RwLock::new(Some(state)),
     }));
     let vfs = Box::into_raw(Box::new(libsqlite3_sys::sqlite3_vfs {
         #[cfg(not(feature = "syscall"))]
@@ -358,22 +644,274 @@ pub fn register<F: DatabaseHandle<Error = V::Error>, V: Vfs<Handle = F>>(
         xNextSystemCall: Some(vfs::next_system_call::<V>),
     }));
 
-    let result = unsafe { libsqlite3_sys::sqlite3_vfs_register(vfs, as_default as i32) };
+    let allocation = Allocation {
+        vfs,
+        retire: retire::<V>,
+        free: free::<V>,
+    };
+    // SAFETY: once registered, `vfs` and its registration are never freed, and only borrowed
+    // shared, see `ffi::VfsRef`
+    let result = unsafe { vfs_register(vfs, as_default as i32) };
     if result != libsqlite3_sys::SQLITE_OK {
+        // SAFETY: SQLite didn't take `vfs`
+        unsafe { allocation.free() };
         return Err(RegisterError::Register(result));
     }
+    tracing::info!(
+        target: "sqlite_vfs::vfs",
+        name,
+        as_default,
+        instrumented,
+        %capabilities,
+        %backend,
+        "registered"
+    );
+    REGISTERED.lock().unwrap().insert(
+        name.to_owned(),
+        VfsStats {
+            capabilities: (*capabilities).clone(),
+            backend,
+            instrumented,
+        },
+    );
+
+    Ok(VfsHandle {
+        name: name.to_owned(),
+        allocation: Some(allocation),
+    })
+}
+
+/// The `sqlite3_vfs` of a VFS registered by this crate and its [Registration].
+struct Allocation {
+    vfs: *mut libsqlite3_sys::sqlite3_vfs,
+    retire: unsafe fn(*mut libsqlite3_sys::sqlite3_vfs) -> bool,
+    free: unsafe fn(*mut libsqlite3_sys::sqlite3_vfs),
+}
+
+// SAFETY: the pointers are only dereferenced by `retire`, which locks the state, and by `free`,
+// once, and `State` is `Send`
+unsafe impl Send for Allocation {}
+
+impl Allocation {
+    /// Free the state unless a file opened through the VFS is open or one of its callbacks is
+    /// running, returning whether it did, see [retire].
+    ///
+    /// # Safety
+    ///
+    /// The VFS was unregistered, so no new connection can use it.
+    unsafe fn retire(&self) -> bool {
+        // SAFETY: per the contract of the function
+        unsafe { (self.retire)(self.vfs) }
+    }
 
-    // TODO: return object that allows to unregister (and cleanup the memory)?
+    /// # Safety
+    ///
+    /// SQLite never saw `vfs`: registering it failed.
+    unsafe fn free(self) {
+        // SAFETY: per the contract of the function
+        unsafe { (self.free)(self.vfs) }
+    }
+}
 
-    Ok(())
+/// Drop the [State] of `vfs` if nothing but its registration refers to it, leaving connections
+/// that still use the VFS without one, see [VfsHandle].
+///
+/// # Safety
+///
+/// `vfs` and its `pAppData` were allocated by [register_inner] for `V`.
+unsafe fn retire<V: Vfs>(vfs: *mut libsqlite3_sys::sqlite3_vfs) -> bool {
+    // SAFETY: allocated by [register_inner], and never freed once registered
+    let registration = unsafe { &*((*vfs).pAppData as *const Registration<V>) };
+    let mut state = registration.state.write().unwrap();
+    // open files and running callbacks hold the other references, and none can be taken while
+    // the state is locked
+    if state
+        .as_ref()
+        .is_some_and(|state| Arc::strong_count(state) > 1)
+    {
+        return false;
+    }
+    *state = None;
+    true
+}
+
+/// # Safety
+///
+/// `vfs` and its `pAppData` were allocated by [register_inner] for `V`, and never registered.
+unsafe fn free<V: Vfs>(vfs: *mut libsqlite3_sys::sqlite3_vfs) {
+    // SAFETY: both were allocated as boxes, see [register_inner], and aren't used anymore
+    unsafe {
+        let vfs = Box::from_raw(vfs);
+        drop(Box::from_raw(vfs.pAppData as *mut Registration<V>));
+    }
+}
+
+/// Unregistered VFSs whose files were still open, retired by [sweep_retired] once they are
+/// closed.
+static RETIRED: Mutex<Vec<Allocation>> = Mutex::new(Vec::new());
+
+/// Retire the VFSs whose files are all closed, on each registration and unregistration.
+fn sweep_retired() {
+    // SAFETY: unregistered before they were pushed
+    RETIRED
+        .lock()
+        .unwrap()
+        .retain(|allocation| !unsafe { allocation.retire() });
+}
+
+/// A VFS registered by [register] or [register_instrumented], unregistered when dropped.
+///
+/// Connections opened through it keep working once it is unregistered: its state is freed once
+/// their files are all closed, on a later registration or unregistration. Connections that still
+/// use it after, e.g. an in-memory database, can't open files anymore, and sleep, read the time
+/// and get random bytes through the operating system and the default VFS rather than the [Vfs].
+/// Its `sqlite3_vfs` and name stay allocated for the life of the process, since SQLite doesn't
+/// tell when such a connection is closed. Unregistering while SQLite opens a new connection
+/// through it is a race the caller must avoid, just like calling `sqlite3_vfs_unregister`
+/// directly is.
+#[must_use = "dropping the handle unregisters the VFS, see `VfsHandle::leak`"]
+pub struct VfsHandle {
+    name: String,
+    /// `None` once unregistered or leaked.
+    allocation: Option<Allocation>,
+}
+
+impl VfsHandle {
+    /// The name the VFS is registered as.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Unregister the VFS, and free its state unless files opened through it are still open. If
+    /// unregistering fails, it stays registered, and its state allocated, for the life of the
+    /// process.
+    pub fn unregister(mut self) -> Result<(), RegisterError> {
+        self.unregister_inner()
+    }
+
+    /// Keep the VFS registered, and its state allocated, for the life of the process, like
+    /// [register] did before it returned a handle. [unregister] still unregisters it by name.
+    pub fn leak(mut self) {
+        self.allocation = None;
+    }
+
+    fn unregister_inner(&mut self) -> Result<(), RegisterError> {
+        let Some(allocation) = self.allocation.take() else {
+            return Ok(());
+        };
+        // SAFETY: `vfs` stays allocated, and SQLite ignores it if [unregister] already
+        // unregistered it
+        let result = unsafe { libsqlite3_sys::sqlite3_vfs_unregister(allocation.vfs) };
+        if result != libsqlite3_sys::SQLITE_OK {
+            return Err(RegisterError::Register(result));
+        }
+        {
+            let mut registered = REGISTERED.lock().unwrap();
+            registered.remove(&self.name);
+        }
+        // SAFETY: unregistered
+        let deferred = !unsafe { allocation.retire() };
+        tracing::info!(target: "sqlite_vfs::vfs", name = self.name, deferred, "unregistered");
+        if deferred {
+            RETIRED.lock().unwrap().push(allocation);
+        }
+        sweep_retired();
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for VfsHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VfsHandle")
+            .field("name", &self.name)
+            .field("registered", &self.allocation.is_some())
+            .finish()
+    }
+}
+
+impl Drop for VfsHandle {
+    fn drop(&mut self) {
+        if let Err(err) = self.unregister_inner() {
+            tracing::error!(
+                target: "sqlite_vfs::vfs",
+                name = self.name,
+                %err,
+                "dropped the handle of a VFS that can't be unregistered, keeping it registered"
+            );
+        }
+    }
+}
+
+/// Unregister the VFS `name`, registered by this crate with the SQLite library linked into it.
+/// Returns whether it was registered. New connections can't find it anymore, while those opened
+/// through it keep working: its state is only freed by dropping its [VfsHandle], if it wasn't
+/// leaked.
+pub fn unregister(name: &str) -> Result<bool, RegisterError> {
+    let c_name = CString::new(name).map_err(RegisterError::Nul)?;
+    let mut registered = REGISTERED.lock().unwrap();
+    if !registered.contains_key(name) {
+        return Ok(false);
+    }
+    // SAFETY: `c_name` outlives the call
+    let vfs = unsafe { libsqlite3_sys::sqlite3_vfs_find(c_name.as_ptr()) };
+    if vfs.is_null() {
+        // registered with another library, see [register_with]
+        return Ok(false);
+    }
+    // SAFETY: `vfs` is registered, and stays allocated after
+    let result = unsafe { libsqlite3_sys::sqlite3_vfs_unregister(vfs) };
+    if result != libsqlite3_sys::SQLITE_OK {
+        return Err(RegisterError::Register(result));
+    }
+    registered.remove(name);
+    tracing::info!(target: "sqlite_vfs::vfs", name, "unregistered");
+    Ok(true)
 }
 
 // TODO: add to [Vfs]?
 const MAX_PATH_LENGTH: usize = 512;
 
+static REGISTERED: Mutex<BTreeMap<String, VfsStats>> = Mutex::new(BTreeMap::new());
+
+/// What is known about a VFS registered by this crate, see [vfs_stats].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VfsStats {
+    /// What the SQLite library it was registered with can do.
+    pub capabilities: Capabilities,
+    /// What the backend can do, see [Vfs::capabilities].
+    pub backend: VfsCapabilities,
+    pub instrumented: bool,
+}
+
+impl std::fmt::Display for VfsStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} instrumented={}",
+            self.capabilities, self.backend, self.instrumented
+        )
+    }
+}
+
+/// What is known about the VFS registered as `name`, if this crate registered it.
+pub fn vfs_stats(name: &str) -> Option<VfsStats> {
+    REGISTERED.lock().unwrap().get(name).cloned()
+}
+
 impl OpenOptions {
-    fn from_flags(flags: i32) -> Option<Self> {
-        Some(OpenOptions {
+    /// Options to open an object of `kind` with `access`, e.g. to call [Vfs::open] directly.
+    pub fn new(kind: OpenKind, access: OpenAccess) -> Self {
+        Self {
+            kind,
+            access,
+            delete_on_close: false,
+        }
+    }
+
+    /// Decode the flags SQLite passes to `xOpen`. Flags of anything but the kind, the access and
+    /// deleting on close are ignored.
+    fn from_flags(flags: i32) -> Result<Self, &'static str> {
+        Ok(OpenOptions {
             kind: OpenKind::from_flags(flags)?,
             access: OpenAccess::from_flags(flags)?,
             delete_on_close: flags & libsqlite3_sys::SQLITE_OPEN_DELETEONCLOSE > 0,
@@ -392,25 +930,26 @@ impl OpenOptions {
 }
 
 impl OpenKind {
-    fn from_flags(flags: i32) -> Option<Self> {
-        match flags {
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_MAIN_DB > 0 => Some(Self::MainDb),
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_MAIN_JOURNAL > 0 => {
-                Some(Self::MainJournal)
-            }
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_TEMP_DB > 0 => Some(Self::TempDb),
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_TEMP_JOURNAL > 0 => {
-                Some(Self::TempJournal)
-            }
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_TRANSIENT_DB > 0 => {
-                Some(Self::TransientDb)
-            }
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_SUBJOURNAL > 0 => Some(Self::SubJournal),
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_SUPER_JOURNAL > 0 => {
-                Some(Self::SuperJournal)
-            }
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_WAL > 0 => Some(Self::Wal),
-            _ => None,
+    pub const ALL: [OpenKind; 8] = [
+        OpenKind::MainDb,
+        OpenKind::MainJournal,
+        OpenKind::TempDb,
+        OpenKind::TempJournal,
+        OpenKind::TransientDb,
+        OpenKind::SubJournal,
+        OpenKind::SuperJournal,
+        OpenKind::Wal,
+    ];
+
+    /// The kind whose bit is set in `flags`. Exactly one must be.
+    fn from_flags(flags: i32) -> Result<Self, &'static str> {
+        let mut kinds = Self::ALL
+            .into_iter()
+            .filter(|kind| flags & kind.to_flags() != 0);
+        match (kinds.next(), kinds.next()) {
+            (Some(kind), None) => Ok(kind),
+            (None, _) => Err("no file type set"),
+            (Some(_), Some(_)) => Err("more than one file type set"),
         }
     }
 
@@ -429,18 +968,24 @@ impl OpenKind {
 }
 
 impl OpenAccess {
-    fn from_flags(flags: i32) -> Option<Self> {
-        match flags {
-            flags
-                if (flags & libsqlite3_sys::SQLITE_OPEN_CREATE > 0)
-                    && (flags & libsqlite3_sys::SQLITE_OPEN_EXCLUSIVE > 0) =>
-            {
-                Some(Self::CreateNew)
-            }
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_CREATE > 0 => Some(Self::Create),
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_READWRITE > 0 => Some(Self::Write),
-            flags if flags & libsqlite3_sys::SQLITE_OPEN_READONLY > 0 => Some(Self::Read),
-            _ => None,
+    /// Exactly one of `SQLITE_OPEN_READONLY` and `SQLITE_OPEN_READWRITE` must be set, and
+    /// `SQLITE_OPEN_CREATE` only together with the latter. `SQLITE_OPEN_EXCLUSIVE` only matters
+    /// together with `SQLITE_OPEN_CREATE`.
+    fn from_flags(flags: i32) -> Result<Self, &'static str> {
+        let set = |flag: i32| flags & flag != 0;
+        match (
+            set(libsqlite3_sys::SQLITE_OPEN_READONLY),
+            set(libsqlite3_sys::SQLITE_OPEN_READWRITE),
+            set(libsqlite3_sys::SQLITE_OPEN_CREATE),
+            set(libsqlite3_sys::SQLITE_OPEN_EXCLUSIVE),
+        ) {
+            (true, true, _, _) => Err("both read-only and read-write set"),
+            (false, false, _, _) => Err("neither read-only nor read-write set"),
+            (true, false, true, _) => Err("create set on a read-only open"),
+            (true, false, false, _) => Ok(Self::Read),
+            (false, true, false, _) => Ok(Self::Write),
+            (false, true, true, false) => Ok(Self::Create),
+            (false, true, true, true) => Ok(Self::CreateNew),
         }
     }
 
@@ -527,13 +1072,15 @@ impl wip::WalIndex for WalDisabled {
 pub enum RegisterError {
     Nul(std::ffi::NulError),
     Register(i32),
+    /// The SQLite library, of this version, is older than [capability::MIN_VERSION_NUMBER].
+    Unsupported(String),
 }
 
 impl std::error::Error for RegisterError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Self::Nul(err) => Some(err),
-            Self::Register(_) => None,
+            Self::Register(_) | Self::Unsupported(_) => None,
         }
     }
 }
@@ -545,6 +1092,11 @@ impl std::fmt::Display for RegisterError {
             Self::Register(code) => {
                 write!(f, "registering sqlite vfs failed with error code: {}", code)
             }
+            Self::Unsupported(version) => write!(
+                f,
+                "sqlite {version} is not supported, the minimum version is {}",
+                capability::MIN_VERSION
+            ),
         }
     }
 }
@@ -566,4 +1118,198 @@ mod tests {
         assert!(LockKind::Reserved < LockKind::Pending);
         assert!(LockKind::Pending < LockKind::Exclusive);
     }
+
+    /// The flags SQLite passes to `xOpen`, after `sqlite3OsOpen` masks them, as set by `main.c`,
+    /// `pager.c`, `wal.c` and `vdbesort.c`.
+    #[test]
+    fn test_open_flags_sqlite_emits() {
+        use libsqlite3_sys::*;
+        use OpenAccess::*;
+        use OpenKind::*;
+
+        const RO: i32 = SQLITE_OPEN_READONLY;
+        const RW: i32 = SQLITE_OPEN_READWRITE;
+        const RWC: i32 = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
+        const TEMP: i32 = RWC | SQLITE_OPEN_EXCLUSIVE | SQLITE_OPEN_DELETEONCLOSE;
+        // bits SQLite passes along that don't affect decoding
+        const OTHER: i32 = SQLITE_OPEN_URI | SQLITE_OPEN_NOFOLLOW;
+
+        let table = [
+            ("main db", RWC | SQLITE_OPEN_MAIN_DB, MainDb, Create, false),
+            (
+                "main db, existing",
+                RW | SQLITE_OPEN_MAIN_DB,
+                MainDb,
+                Write,
+                false,
+            ),
+            (
+                "main db, read-only",
+                RO | SQLITE_OPEN_MAIN_DB,
+                MainDb,
+                Read,
+                false,
+            ),
+            (
+                "main db, uri",
+                RWC | OTHER | SQLITE_OPEN_MAIN_DB,
+                MainDb,
+                Create,
+                false,
+            ),
+            (
+                "journal",
+                RWC | SQLITE_OPEN_MAIN_JOURNAL,
+                MainJournal,
+                Create,
+                false,
+            ),
+            (
+                "journal, hot",
+                RW | SQLITE_OPEN_MAIN_JOURNAL,
+                MainJournal,
+                Write,
+                false,
+            ),
+            (
+                "journal, recovery read-only",
+                RO | SQLITE_OPEN_MAIN_JOURNAL,
+                MainJournal,
+                Read,
+                false,
+            ),
+            (
+                "journal of a temp db",
+                RWC | SQLITE_OPEN_EXCLUSIVE | SQLITE_OPEN_DELETEONCLOSE | SQLITE_OPEN_MAIN_JOURNAL,
+                MainJournal,
+                CreateNew,
+                true,
+            ),
+            (
+                "temp db",
+                TEMP | SQLITE_OPEN_TEMP_DB,
+                TempDb,
+                CreateNew,
+                true,
+            ),
+            (
+                "temp journal",
+                TEMP | SQLITE_OPEN_TEMP_JOURNAL,
+                TempJournal,
+                CreateNew,
+                true,
+            ),
+            (
+                "transient db",
+                TEMP | SQLITE_OPEN_TRANSIENT_DB,
+                TransientDb,
+                CreateNew,
+                true,
+            ),
+            (
+                "subjournal",
+                TEMP | SQLITE_OPEN_SUBJOURNAL,
+                SubJournal,
+                CreateNew,
+                true,
+            ),
+            (
+                "super journal",
+                RWC | SQLITE_OPEN_EXCLUSIVE | SQLITE_OPEN_SUPER_JOURNAL,
+                SuperJournal,
+                CreateNew,
+                false,
+            ),
+            (
+                "super journal, recovery",
+                RO | SQLITE_OPEN_SUPER_JOURNAL,
+                SuperJournal,
+                Read,
+                false,
+            ),
+            ("wal", RWC | SQLITE_OPEN_WAL, Wal, Create, false),
+            ("wal, existing", RW | SQLITE_OPEN_WAL, Wal, Write, false),
+            ("wal, read-only", RO | SQLITE_OPEN_WAL, Wal, Read, false),
+        ];
+        for (case, flags, kind, access, delete_on_close) in table {
+            let opts = OpenOptions::from_flags(flags)
+                .unwrap_or_else(|err| panic!("{case} ({flags:#x}): {err}"));
+            assert_eq!(
+                opts,
+                OpenOptions {
+                    kind,
+                    access,
+                    delete_on_close
+                },
+                "{case} ({flags:#x})"
+            );
+            // the kind, access and deleting on close survive
+            let mask = OpenKind::ALL
+                .iter()
+                .fold(RWC | RO | SQLITE_OPEN_DELETEONCLOSE, |mask, kind| {
+                    mask | kind.to_flags()
+                });
+            let exclusive = match access {
+                CreateNew => SQLITE_OPEN_EXCLUSIVE,
+                _ => 0,
+            };
+            assert_eq!(opts.to_flags(), flags & mask | exclusive, "{case}");
+            assert_eq!(OpenOptions::from_flags(opts.to_flags()), Ok(opts), "{case}");
+        }
+    }
+
+    #[test]
+    fn test_open_flags_rejected() {
+        use libsqlite3_sys::*;
+
+        let table = [
+            (SQLITE_OPEN_READWRITE, "no file type set"),
+            (
+                SQLITE_OPEN_READWRITE | SQLITE_OPEN_MAIN_DB | SQLITE_OPEN_MAIN_JOURNAL,
+                "more than one file type set",
+            ),
+            (
+                SQLITE_OPEN_READWRITE | SQLITE_OPEN_TEMP_JOURNAL | SQLITE_OPEN_WAL,
+                "more than one file type set",
+            ),
+            (SQLITE_OPEN_MAIN_DB, "neither read-only nor read-write set"),
+            (
+                SQLITE_OPEN_CREATE | SQLITE_OPEN_MAIN_DB,
+                "neither read-only nor read-write set",
+            ),
+            (
+                SQLITE_OPEN_READONLY | SQLITE_OPEN_READWRITE | SQLITE_OPEN_MAIN_DB,
+                "both read-only and read-write set",
+            ),
+            (
+                SQLITE_OPEN_READONLY | SQLITE_OPEN_CREATE | SQLITE_OPEN_WAL,
+                "create set on a read-only open",
+            ),
+        ];
+        for (flags, reason) in table {
+            assert_eq!(OpenOptions::from_flags(flags), Err(reason), "{flags:#x}");
+        }
+    }
+
+    #[test]
+    fn test_open_options_round_trip() {
+        let accesses = [
+            OpenAccess::Read,
+            OpenAccess::Write,
+            OpenAccess::Create,
+            OpenAccess::CreateNew,
+        ];
+        for kind in OpenKind::ALL {
+            for access in accesses {
+                for delete_on_close in [false, true] {
+                    let opts = OpenOptions {
+                        kind,
+                        access,
+                        delete_on_close,
+                    };
+                    assert_eq!(OpenOptions::from_flags(opts.to_flags()), Ok(opts.clone()));
+                }
+            }
+        }
+    }
 }
@@ -4,19 +4,42 @@ use std::{
     io::ErrorKind,
     mem::MaybeUninit,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{atomic::AtomicUsize, Arc, Mutex, RwLock},
 };
 
-use crate::{wip, DatabaseHandle, Vfs};
+use crate::{
+    busy::BusyHandlerRef,
+    capability::{Capabilities, VfsCapabilities},
+    instrument::Instrumentation,
+    wip, DatabaseHandle, Vfs,
+};
 
 pub struct State<V: Vfs> {
     pub name: CString,
+    /// What the SQLite library the VFS is registered with can do.
+    pub capabilities: Arc<Capabilities>,
+    /// What the backend can do, see [Vfs::capabilities].
+    pub backend: VfsCapabilities,
     pub vfs: Arc<V>,
     #[cfg(any(feature = "syscall", feature = "loadext"))]
-    parent_vfs: *mut libsqlite3_sys::sqlite3_vfs,
+    pub parent_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     pub io_methods: libsqlite3_sys::sqlite3_io_methods,
-    pub last_error: Arc<Mutex<Option<(i32, crate::error::Error<V::Error>)>>>,
-    pub next_id: usize,
+    /// The last error reported by `xGetLastError`: the last error of a VFS-scope operation, or a
+    /// copy of the last error of any file, whichever happened last.
+    pub last_error: Arc<Mutex<Option<LastError>>>,
+    /// Shared by connections opening files concurrently.
+    pub next_id: AtomicUsize,
+    pub instrumentation: Option<Arc<dyn Instrumentation>>,
+}
+
+/// What the `pAppData` of a registered `sqlite3_vfs` points to. Both stay allocated for the life
+/// of the process, since SQLite doesn't tell when the last connection using them is closed; the
+/// [State] is freed once the VFS is unregistered and unused, see [crate::VfsHandle].
+pub struct Registration<V: Vfs> {
+    /// What `zName` points to.
+    pub name: CString,
+    /// `None` once retired, leaving the connections that still use the VFS without a state.
+    pub state: RwLock<Option<Arc<State<V>>>>,
 }
 
 #[repr(C)]
@@ -32,57 +55,84 @@ pub struct FileExt<V: Vfs, F: DatabaseHandle> {
     pub db_name: String,
     pub file: F,
     pub delete_on_close: bool,
-    /// The last error; shared with the VFS.
-    pub last_error: Arc<Mutex<Option<(i32, crate::error::Error<V::Error>)>>>,
-    /// The last error number of this file/connection (not shared with the VFS).
-    pub last_errno: i32,
+    /// The last error of this file, reported by `SQLITE_FCNTL_LAST_ERRNO`.
+    pub last_error: Option<(i32, crate::error::Error<V::Error>)>,
+    /// The last error of the VFS, see [State::last_error].
+    pub vfs_last_error: Arc<Mutex<Option<LastError>>>,
     pub wal_index: Option<(F::WalIndex, bool)>,
     pub wal_index_regions: HashMap<u32, Pin<Box<[u8; 32768]>>>,
     pub wal_index_locks: HashMap<u8, wip::WalIndexLock>,
     pub has_exclusive_lock: bool,
     pub id: usize,
-    pub chunk_size: Option<usize>,
+    /// The growth quantum set by `SQLITE_FCNTL_CHUNK_SIZE`, see
+    /// [DatabaseHandle::set_chunk_size]. Rounds up allocation hints only, never the length of the
+    /// file.
+    pub growth_quantum: Option<u64>,
     pub persist_wal: bool,
     pub powersafe_overwrite: bool,
+    /// Passed by `SQLITE_FCNTL_BUSYHANDLER`, see [crate::busy].
+    pub busy_handler: Option<BusyHandlerRef>,
+    pub capabilities: Arc<Capabilities>,
+    pub instrumentation: Option<Arc<dyn Instrumentation>>,
+    /// The state of the VFS, kept allocated with the `io_methods` of the file until it is closed.
+    pub state: Arc<State<V>>,
+}
+
+/// An error as reported by `xGetLastError`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastError {
+    pub code: i32,
+    pub message: String,
 }
 
 impl<V: Vfs> State<V> {
-    pub(crate) fn set_last_error(&mut self, no: i32, err: crate::error::Error<V::Error>) -> i32 {
-        // log::error!("{} ({})", err, no);
-        *(self.last_error.lock().unwrap()) = Some((no, err));
+    pub(crate) fn set_last_error(&self, no: i32, err: crate::error::Error<V::Error>) -> i32 {
+        *(self.last_error.lock().unwrap()) = Some(LastError {
+            code: no,
+            message: err.describe(),
+        });
         no
     }
 }
 
 impl<V: Vfs, F: DatabaseHandle> FileExt<V, F> {
     pub(crate) fn set_last_error(&mut self, no: i32, err: crate::error::Error<V::Error>) -> i32 {
-        // log::error!("{} ({})", err, no);
-        *(self.last_error.lock().unwrap()) = Some((no, err));
-        self.last_errno = no;
+        // A busy, full, exhausted or unauthorized backend is reported as such, regardless of the operation that failed.
+        let no = match err {
+            crate::error::Error::Busy { .. } | crate::error::Error::WalIndexUnstable { .. } => {
+                libsqlite3_sys::SQLITE_BUSY
+            }
+            crate::error::Error::Full { .. } => libsqlite3_sys::SQLITE_FULL,
+            crate::error::Error::NoMem { .. } => libsqlite3_sys::SQLITE_NOMEM,
+            crate::error::Error::Auth { .. } => libsqlite3_sys::SQLITE_AUTH,
+            _ => no,
+        };
+        // tagged with its origin, as SQLite asks the VFS rather than the file
+        *(self.vfs_last_error.lock().unwrap()) = Some(LastError {
+            code: no,
+            message: format!("{} (file {}): {}", self.db_name, self.id, err.describe()),
+        });
+        self.last_error = Some((no, err));
         no
     }
-}
 
-pub(crate) fn null_ptr_error<External>() -> crate::error::Error<External> {
-    crate::error::Error::NullPtr
-}
+    /// The error number of the last error of this file, `0` if there was none.
+    pub fn last_errno(&self) -> i32 {
+        self.last_error.as_ref().map_or(0, |(no, _)| *no)
+    }
 
-pub unsafe fn vfs_state<'a, V: Vfs>(
-    ptr: *mut libsqlite3_sys::sqlite3_vfs,
-) -> Result<&'a mut State<V>, crate::error::Error<V::Error>> {
-    let vfs: &mut libsqlite3_sys::sqlite3_vfs = ptr.as_mut().ok_or_else(null_ptr_error)?;
-    let state = (vfs.pAppData as *mut State<V>)
-        .as_mut()
-        .ok_or_else(null_ptr_error)?;
-    Ok(state)
-}
+    /// The flags answered to `xDeviceCharacteristics`.
+    pub fn device_characteristics(&self) -> std::os::raw::c_int {
+        // The following characteristics are needed to match the expected behavior of the tests.
 
-pub unsafe fn file_state<'a, V: Vfs, F: DatabaseHandle<Error = V::Error>>(
-    ptr: *mut libsqlite3_sys::sqlite3_file,
-) -> Result<&'a mut FileExt<V, F>, crate::error::Error<V::Error>> {
-    let f = (ptr as *mut FileState<V, F>)
-        .as_mut()
-        .ok_or_else(null_ptr_error)?;
-    let ext = f.ext.assume_init_mut();
-    Ok(ext)
+        // after reboot following a crash or power loss, the only bytes in a file that were written
+        // at the application level might have changed and that adjacent bytes, even bytes within
+        // the same sector are guaranteed to be unchanged
+        let flags = if self.powersafe_overwrite {
+            libsqlite3_sys::SQLITE_IOCAP_POWERSAFE_OVERWRITE
+        } else {
+            0
+        };
+        self.capabilities.device_characteristics(flags)
+    }
 }
@@ -0,0 +1,241 @@
+//! Adapters for implementing a [Vfs] with blocking, [std::io::Error]-based methods.
+//!
+//! Implement [SyncVfs] and [SyncDatabaseHandle] (the shape of the traits before they were made
+//! async) and register the VFS wrapped in a [SyncVfsAdapter]:
+//!
+//! ```ignore
+//! sqlite_vfs::register("myvfs", SyncVfsAdapter::new(MyVfs::default()), false)?.leak();
+//! ```
+
+use std::borrow::Cow;
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime};
+
+use crate::busy::BusyHandlerRef;
+use crate::capability::VfsCapabilities;
+use crate::error::Error;
+use crate::{DatabaseHandle, LockKind, OpenOptions, Vfs, WalDisabled};
+
+/// A file opened by [SyncVfs]. See [DatabaseHandle] for the semantics of each method.
+pub trait SyncDatabaseHandle: Sync {
+    fn size(&self) -> Result<u64, std::io::Error>;
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error>;
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error>;
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error>;
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error>;
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error>;
+
+    fn unlock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.lock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error>;
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error>;
+
+    fn set_chunk_size(&self, _chunk_size: usize) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn allocate(&mut self, _size: u64) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn moved(&self) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    fn sector_size(&self) -> u32 {
+        crate::capability::DEFAULT_SECTOR_SIZE
+    }
+
+    fn set_busy_handler(&mut self, _handler: Option<BusyHandlerRef>) {}
+
+    fn set_persist_wal(&mut self, _persist: bool) {}
+}
+
+/// A blocking virtual file system. See [Vfs] for the semantics of each method.
+pub trait SyncVfs: Sync {
+    type Handle: SyncDatabaseHandle;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error>;
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error>;
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error>;
+
+    fn temporary_name(&self) -> String;
+
+    fn random(&self, buffer: &mut [u8]);
+
+    fn sleep(&self, duration: Duration) -> Duration;
+
+    fn current_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn capabilities(&self) -> VfsCapabilities {
+        VfsCapabilities::of::<SyncHandleAdapter<Self::Handle>>()
+    }
+
+    fn access(&self, _db: &str, _write: bool) -> Result<bool, std::io::Error> {
+        Ok(true)
+    }
+
+    fn full_pathname<'a>(&self, db: &'a str) -> Result<Cow<'a, str>, std::io::Error> {
+        Ok(db.into())
+    }
+}
+
+/// Exposes a [SyncVfs] as a [Vfs]. WAL is not supported for adapted handles.
+pub struct SyncVfsAdapter<V>(pub V);
+
+/// Exposes a [SyncDatabaseHandle] as a [DatabaseHandle].
+pub struct SyncHandleAdapter<H>(pub H);
+
+impl<V> SyncVfsAdapter<V> {
+    pub fn new(vfs: V) -> Self {
+        Self(vfs)
+    }
+}
+
+/// Map an [std::io::Error] onto the variants the glue code handles specially.
+fn from_io(err: std::io::Error) -> Error<std::io::Error> {
+    match err.kind() {
+        ErrorKind::PermissionDenied => Error::PermissionDenied,
+        ErrorKind::UnexpectedEof => Error::UnexpectedEof,
+        ErrorKind::WriteZero => Error::WriteZero,
+        _ => Error::External { cause: err },
+    }
+}
+
+impl<H: SyncDatabaseHandle> DatabaseHandle for SyncHandleAdapter<H> {
+    type WalIndex = WalDisabled;
+    type Error = std::io::Error;
+
+    async fn size(&mut self) -> Result<u64, Error<Self::Error>> {
+        self.0.size().map_err(from_io)
+    }
+
+    async fn read_exact_at(
+        &mut self,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> Result<(), Error<Self::Error>> {
+        self.0.read_exact_at(buf, offset).map_err(from_io)
+    }
+
+    async fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error<Self::Error>> {
+        self.0.write_all_at(buf, offset).map_err(from_io)
+    }
+
+    async fn sync(&mut self, data_only: bool) -> Result<(), Error<Self::Error>> {
+        self.0.sync(data_only).map_err(from_io)
+    }
+
+    async fn set_len(&mut self, size: u64) -> Result<(), Error<Self::Error>> {
+        self.0.set_len(size).map_err(from_io)
+    }
+
+    async fn lock(&mut self, lock: LockKind) -> Result<bool, Error<Self::Error>> {
+        self.0.lock(lock).map_err(from_io)
+    }
+
+    async fn unlock(&mut self, lock: LockKind) -> Result<bool, Error<Self::Error>> {
+        self.0.unlock(lock).map_err(from_io)
+    }
+
+    async fn reserved(&mut self) -> Result<bool, Error<Self::Error>> {
+        self.0.reserved().map_err(from_io)
+    }
+
+    async fn current_lock(&self) -> Result<LockKind, Error<Self::Error>> {
+        self.0.current_lock().map_err(from_io)
+    }
+
+    async fn set_chunk_size(&self, chunk_size: usize) -> Result<(), Error<Self::Error>> {
+        self.0.set_chunk_size(chunk_size).map_err(from_io)
+    }
+
+    async fn allocate(&mut self, size: u64) -> Result<(), Error<Self::Error>> {
+        self.0.allocate(size).map_err(from_io)
+    }
+
+    async fn moved(&self) -> Result<bool, Error<Self::Error>> {
+        self.0.moved().map_err(from_io)
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.0.sector_size()
+    }
+
+    fn set_busy_handler(&mut self, handler: Option<BusyHandlerRef>) {
+        self.0.set_busy_handler(handler)
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        self.0.set_persist_wal(persist)
+    }
+
+    async fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, Error<Self::Error>> {
+        Ok(WalDisabled)
+    }
+}
+
+impl<V: SyncVfs> Vfs for SyncVfsAdapter<V> {
+    type Handle = SyncHandleAdapter<V::Handle>;
+    type Error = std::io::Error;
+
+    async fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, Error<Self::Error>> {
+        self.0
+            .open(db, opts)
+            .map(SyncHandleAdapter)
+            .map_err(from_io)
+    }
+
+    async fn delete(&self, db: &str) -> Result<(), Error<Self::Error>> {
+        self.0.delete(db).map_err(|err| match err.kind() {
+            ErrorKind::NotFound => Error::DbNotFound {
+                name: db.to_owned(),
+            },
+            _ => from_io(err),
+        })
+    }
+
+    async fn exists(&self, db: &str) -> Result<bool, Error<Self::Error>> {
+        self.0.exists(db).map_err(from_io)
+    }
+
+    async fn temporary_name(&self) -> String {
+        self.0.temporary_name()
+    }
+
+    async fn random(&self, buffer: &mut [u8]) {
+        self.0.random(buffer)
+    }
+
+    async fn sleep(&self, duration: Duration) -> Duration {
+        self.0.sleep(duration)
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.0.current_time()
+    }
+
+    fn capabilities(&self) -> VfsCapabilities {
+        self.0.capabilities()
+    }
+
+    async fn access(&self, db: &str, write: bool) -> Result<bool, Error<Self::Error>> {
+        self.0.access(db, write).map_err(from_io)
+    }
+
+    async fn full_pathname<'a>(&self, db: &'a str) -> Result<Cow<'a, str>, Error<Self::Error>> {
+        self.0.full_pathname(db).map_err(from_io)
+    }
+}
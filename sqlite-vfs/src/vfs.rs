@@ -4,72 +4,86 @@
 use std::{
     ffi::{c_char, c_int, c_void, CStr, CString},
     io::ErrorKind,
-    sync::Arc,
-    time::Duration,
+    sync::{atomic::Ordering, Arc, OnceLock},
+    time::{Duration, SystemTime},
 };
 
 use tokio::runtime;
 
 use crate::{
+    capability,
     error::Error,
-    state::{null_ptr_error, vfs_state, FileExt, FileState},
+    ffi::{c_str, FileSlot, OpenName, OutParam, SqliteBufferMut, VfsRef},
+    instrument::{CallbackDetails, CallbackKind, Probe},
+    state::{FileExt, LastError},
     DatabaseHandle, OpenAccess, OpenKind, OpenOptions, Vfs, MAX_PATH_LENGTH,
 };
 
 /// Open a new file handler.
-async unsafe fn open_inner<F: DatabaseHandle, V: Vfs<Handle = F>>(
-    p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
-    z_name: *const c_char,
-    p_file: *mut libsqlite3_sys::sqlite3_file,
+async fn open_inner<'a, F: DatabaseHandle, V: Vfs<Handle = F>>(
+    state: Option<VfsRef<V>>,
+    z_name: Option<OpenName<'_>>,
+    p_file: Option<FileSlot<'a, V, F>>,
     flags: c_int,
-    p_out_flags: *mut c_int,
+    mut p_out_flags: OutParam<'_, c_int>,
 ) -> c_int {
-    let state = match vfs_state::<V>(p_vfs) {
-        Ok(state) => state,
-        Err(_) => return libsqlite3_sys::SQLITE_ERROR,
+    let Some(state) = state else {
+        return libsqlite3_sys::SQLITE_ERROR;
     };
 
-    let name = if z_name.is_null() {
-        None
-    } else {
-        let raw = CStr::from_ptr(z_name);
-        match raw.to_str() {
+    let name = match &z_name {
+        None => None,
+        Some(raw) => match raw.name().to_str() {
             Ok(name) => Some(name),
             Err(_) => {
                 return state.set_last_error(
                     libsqlite3_sys::SQLITE_CANTOPEN,
                     Error::InvalidDbName {
-                        name: raw.to_owned(),
+                        name: raw.name().to_owned(),
                     },
                 )
             }
-        }
+        },
     };
-    log::trace!("open z_name={:?} flags={}", name, flags);
+    tracing::debug!(target: "sqlite_vfs::vfs", ?name, flags, "open");
 
     let mut opts = match OpenOptions::from_flags(flags) {
-        Some(opts) => opts,
-        None => {
-            return state.set_last_error(libsqlite3_sys::SQLITE_CANTOPEN, Error::InvalidOpenFlags);
+        Ok(opts) => opts,
+        Err(reason) => {
+            return state.set_last_error(
+                libsqlite3_sys::SQLITE_CANTOPEN,
+                Error::InvalidOpenFlags { flags, reason },
+            );
         }
     };
 
-    if z_name.is_null() && !opts.delete_on_close {
-        return state.set_last_error(libsqlite3_sys::SQLITE_CANTOPEN, Error::InvalidOpenFlags);
+    // before the backend, which may not handle it at all
+    if !state.backend.supports(opts.kind) {
+        tracing::debug!(target: "sqlite_vfs::vfs", ?name, kind = ?opts.kind, "unsupported kind");
+        return state.set_last_error(
+            libsqlite3_sys::SQLITE_CANTOPEN,
+            Error::UnsupportedKind { kind: opts.kind },
+        );
     }
 
-    let out_file = match (p_file as *mut FileState<V, F>).as_mut() {
-        Some(f) => f,
-        None => {
-            return state.set_last_error(libsqlite3_sys::SQLITE_CANTOPEN, Error::InvalidFilePtr);
-        }
+    if z_name.is_none() && !opts.delete_on_close {
+        return state.set_last_error(
+            libsqlite3_sys::SQLITE_CANTOPEN,
+            Error::InvalidOpenFlags {
+                flags,
+                reason: "a file without a name must be deleted on close",
+            },
+        );
+    }
+
+    let Some(out_file) = p_file else {
+        return state.set_last_error(libsqlite3_sys::SQLITE_CANTOPEN, Error::InvalidFilePtr);
     };
 
     let mut powersafe_overwrite = true;
-    if flags & libsqlite3_sys::SQLITE_OPEN_URI > 0 && name.is_some() {
-        let param = b"psow\0";
-        if libsqlite3_sys::sqlite3_uri_boolean(z_name, param.as_ptr() as *const c_char, 1) == 0 {
-            powersafe_overwrite = false;
+    if flags & libsqlite3_sys::SQLITE_OPEN_URI > 0 {
+        if let Some(z_name) = &z_name {
+            powersafe_overwrite = z_name.uri_boolean(c"psow", true);
         }
     }
 
@@ -78,22 +92,30 @@ async unsafe fn open_inner<F: DatabaseHandle, V: Vfs<Handle = F>>(
         None => state.vfs.temporary_name().await,
     };
     let result = state.vfs.open(&name, opts.clone()).await;
-    let result = match result {
-        Ok(f) => Ok(f),
-        // handle creation failure due to readonly directory
-        Err(Error::PermissionDenied)
-            if matches!(
-                opts.kind,
-                OpenKind::SuperJournal | OpenKind::MainJournal | OpenKind::Wal
-            ) && matches!(opts.access, OpenAccess::Create | OpenAccess::CreateNew)
-                && !state.vfs.exists(&name).await.unwrap_or(false) =>
-        {
-            return state.set_last_error(
-                libsqlite3_sys::SQLITE_READONLY_DIRECTORY,
-                Error::PermissionDenied,
-            );
+
+    // handle creation failure due to readonly directory, which only a file known to be missing
+    // is blamed on: one whose existence can't be checked fails the open with that error
+    if matches!(result, Err(Error::PermissionDenied))
+        && matches!(
+            opts.kind,
+            OpenKind::SuperJournal | OpenKind::MainJournal | OpenKind::Wal
+        )
+        && matches!(opts.access, OpenAccess::Create | OpenAccess::CreateNew)
+    {
+        match state.vfs.exists(&name).await {
+            Ok(false) => {
+                return state.set_last_error(
+                    libsqlite3_sys::SQLITE_READONLY_DIRECTORY,
+                    Error::PermissionDenied,
+                );
+            }
+            Ok(true) => {}
+            Err(err) => return state.set_last_error(libsqlite3_sys::SQLITE_CANTOPEN, err),
         }
+    }
 
+    let result = match result {
+        Ok(f) => Ok(f),
         // Try again as readonly
         Err(Error::PermissionDenied) if opts.access != OpenAccess::Read => {
             opts.access = OpenAccess::Read;
@@ -117,29 +139,44 @@ async unsafe fn open_inner<F: DatabaseHandle, V: Vfs<Handle = F>>(
         }
     };
 
-    if let Some(p_out_flags) = p_out_flags.as_mut() {
-        *p_out_flags = opts.to_flags();
-    }
+    p_out_flags.set(opts.to_flags());
 
-    out_file.base.pMethods = &state.io_methods;
-    out_file.ext.write(FileExt {
+    let ext = FileExt {
         vfs: state.vfs.clone(),
         vfs_name: state.name.clone(),
         db_name: name,
         file,
         delete_on_close: opts.delete_on_close,
-        last_error: Arc::clone(&state.last_error),
-        last_errno: 0,
+        last_error: None,
+        vfs_last_error: Arc::clone(&state.last_error),
         wal_index: None,
         wal_index_regions: Default::default(),
         wal_index_locks: Default::default(),
         has_exclusive_lock: false,
-        id: state.next_id,
-        chunk_size: None,
+        id: state.next_id.fetch_add(1, Ordering::Relaxed),
+        growth_quantum: None,
         persist_wal: false,
         powersafe_overwrite,
-    });
-    state.next_id = state.next_id.overflowing_add(1).0;
+        busy_handler: None,
+        capabilities: state.capabilities.clone(),
+        instrumentation: state.instrumentation.clone(),
+        state: Arc::clone(state.shared()),
+    };
+    // pagers only ask the database file, journals are padded to its sector size
+    if matches!(
+        opts.kind,
+        OpenKind::MainDb | OpenKind::TempDb | OpenKind::TransientDb
+    ) {
+        let sector_size = ext.file.sector_size();
+        debug_assert_eq!(
+            capability::check_sector_size(sector_size, ext.device_characteristics()),
+            Ok(()),
+            "sector size {sector_size} of {} opened with powersafe_overwrite={}",
+            ext.db_name,
+            ext.powersafe_overwrite,
+        );
+    }
+    out_file.open(ext);
 
     // #[cfg(feature = "sqlite_test")]
     // libsqlite3_sys::sqlite3_inc_open_file_count();
@@ -149,17 +186,18 @@ async unsafe fn open_inner<F: DatabaseHandle, V: Vfs<Handle = F>>(
 
 /// Delete the file located at `z_path`. If the `sync_dir` argument is true, ensure the
 /// file-system modifications are synced to disk before returning.
-async unsafe fn delete_inner<V: Vfs>(
-    p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
-    z_path: *const c_char,
+async fn delete_inner<V: Vfs>(
+    state: Option<VfsRef<V>>,
+    z_path: Option<&CStr>,
     _sync_dir: c_int,
 ) -> c_int {
-    let state = match vfs_state::<V>(p_vfs) {
-        Ok(state) => state,
-        Err(_) => return libsqlite3_sys::SQLITE_DELETE,
+    let Some(state) = state else {
+        return libsqlite3_sys::SQLITE_DELETE;
     };
 
-    let raw = CStr::from_ptr(z_path);
+    let Some(raw) = z_path else {
+        return state.set_last_error(libsqlite3_sys::SQLITE_ERROR, Error::NullPtr);
+    };
     let path = match raw.to_str() {
         Ok(name) => name,
         Err(_) => {
@@ -171,7 +209,7 @@ async unsafe fn delete_inner<V: Vfs>(
             )
         }
     };
-    log::trace!("delete name={}", path);
+    tracing::debug!(target: "sqlite_vfs::vfs", path, "delete");
 
     match state.vfs.delete(path).await {
         Ok(_) => libsqlite3_sys::SQLITE_OK,
@@ -186,38 +224,39 @@ async unsafe fn delete_inner<V: Vfs>(
 
 /// Test for access permissions. Return true if the requested permission is available, or false
 /// otherwise.
-async unsafe fn access_inner<V: Vfs>(
-    p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
-    z_path: *const c_char,
+async fn access_inner<V: Vfs>(
+    state: Option<VfsRef<V>>,
+    z_path: Option<&CStr>,
     flags: c_int,
-    p_res_out: *mut c_int,
+    mut p_res_out: OutParam<'_, c_int>,
 ) -> c_int {
     // #[cfg(feature = "sqlite_test")]
     // if simulate_io_error() {
     //     return libsqlite3_sys::SQLITE_IOERR_ACCESS;
     // }
 
-    let state = match vfs_state::<V>(p_vfs) {
-        Ok(state) => state,
-        Err(_) => return libsqlite3_sys::SQLITE_ERROR,
+    let Some(state) = state else {
+        return libsqlite3_sys::SQLITE_ERROR;
     };
 
-    let path = match CStr::from_ptr(z_path).to_str() {
+    let Some(raw) = z_path else {
+        return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_ACCESS, Error::NullPtr);
+    };
+    let path = match raw.to_str() {
         Ok(name) => name,
         Err(_) => {
-            log::warn!(
-                "access failed: database must be valid utf8 (received: {:?})",
-                CStr::from_ptr(z_path)
+            tracing::warn!(
+                target: "sqlite_vfs::vfs",
+                path = ?raw,
+                "access failed: database must be valid utf8"
             );
 
-            if let Ok(p_res_out) = p_res_out.as_mut().ok_or_else(null_ptr_error::<V::Error>) {
-                *p_res_out = false as i32;
-            }
+            p_res_out.set(false as i32);
 
             return libsqlite3_sys::SQLITE_OK;
         }
     };
-    log::trace!("access z_name={} flags={}", path, flags);
+    tracing::trace!(target: "sqlite_vfs::vfs", path, flags, "access");
 
     let result = match flags {
         libsqlite3_sys::SQLITE_ACCESS_EXISTS => state.vfs.exists(path).await,
@@ -226,11 +265,7 @@ async unsafe fn access_inner<V: Vfs>(
         _ => return libsqlite3_sys::SQLITE_IOERR_ACCESS,
     };
 
-    if let Err(err) = result.and_then(|ok| {
-        let p_res_out: &mut c_int = p_res_out.as_mut().ok_or_else(null_ptr_error)?;
-        *p_res_out = ok as i32;
-        Ok(())
-    }) {
+    if let Err(err) = result.and_then(|ok| p_res_out.write(ok as i32)) {
         return state.set_last_error(libsqlite3_sys::SQLITE_IOERR_ACCESS, err);
     }
 
@@ -240,23 +275,23 @@ async unsafe fn access_inner<V: Vfs>(
 /// Populate buffer `z_out` with the full canonical pathname corresponding to the pathname in
 /// `z_path`. `z_out` is guaranteed to point to a buffer of at least (INST_MAX_PATHNAME+1)
 /// bytes.
-async unsafe fn full_pathname_inner<V: Vfs>(
-    p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
-    z_path: *const c_char,
-    n_out: c_int,
-    z_out: *mut c_char,
+async fn full_pathname_inner<V: Vfs>(
+    state: Option<VfsRef<V>>,
+    z_path: Option<&CStr>,
+    z_out: Result<SqliteBufferMut<'_>, Error<V::Error>>,
 ) -> c_int {
     // #[cfg(feature = "sqlite_test")]
     // if simulate_io_error() {
     //     return libsqlite3_sys::SQLITE_ERROR;
     // }
 
-    let state = match vfs_state::<V>(p_vfs) {
-        Ok(state) => state,
-        Err(_) => return libsqlite3_sys::SQLITE_ERROR,
+    let Some(state) = state else {
+        return libsqlite3_sys::SQLITE_ERROR;
     };
 
-    let raw = CStr::from_ptr(z_path);
+    let Some(raw) = z_path else {
+        return state.set_last_error(libsqlite3_sys::SQLITE_ERROR, Error::NullPtr);
+    };
     let path = match raw.to_str() {
         Ok(name) => name,
         Err(_) => {
@@ -268,7 +303,11 @@ async unsafe fn full_pathname_inner<V: Vfs>(
             )
         }
     };
-    log::trace!("full_pathname name={}", path);
+    let mut out = match z_out {
+        Ok(out) => out,
+        Err(err) => return state.set_last_error(libsqlite3_sys::SQLITE_ERROR, err),
+    };
+    tracing::trace!(target: "sqlite_vfs::vfs", path, "full_pathname");
 
     let name = match state
         .vfs
@@ -281,15 +320,29 @@ async unsafe fn full_pathname_inner<V: Vfs>(
     };
 
     let name = name.to_bytes_with_nul();
-    if name.len() > n_out as usize || name.len() > MAX_PATH_LENGTH {
+    if name.len() > out.len() || name.len() > MAX_PATH_LENGTH {
         return state.set_last_error(libsqlite3_sys::SQLITE_CANTOPEN, Error::PathTooLong);
     }
-    let out = std::slice::from_raw_parts_mut(z_out as *mut u8, name.len());
-    out.copy_from_slice(name);
+    out[..name.len()].copy_from_slice(name);
 
     libsqlite3_sys::SQLITE_OK
 }
 
+/// Enter the callback `kind` on the VFS, on the file named `z_name` if any.
+fn probe<V: Vfs>(
+    state: Option<&VfsRef<V>>,
+    kind: CallbackKind,
+    z_name: Option<&CStr>,
+    details: CallbackDetails,
+) -> Probe {
+    Probe::enter(
+        state.and_then(|state| state.instrumentation.as_ref()),
+        kind,
+        || z_name.map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+        details,
+    )
+}
+
 /// Open a new file handler.
 pub unsafe extern "C" fn open<F: DatabaseHandle, V: Vfs<Handle = F>>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
@@ -298,15 +351,30 @@ pub unsafe extern "C" fn open<F: DatabaseHandle, V: Vfs<Handle = F>>(
     flags: c_int,
     p_out_flags: *mut c_int,
 ) -> c_int {
-    tokio::runtime::Runtime::new()
-        .unwrap()
-        .block_on(open_inner::<F, V>(
-            p_vfs,
-            z_name,
-            p_file,
-            flags,
-            p_out_flags,
-        ))
+    // SAFETY: SQLite passes this VFS, the name to open or null, room for the file, which isn't
+    // open, and where to write the flags it was opened with
+    let (state, z_name, p_file, p_out_flags) = unsafe {
+        (
+            VfsRef::<V>::from_raw(p_vfs),
+            OpenName::from_raw(z_name),
+            FileSlot::<V, F>::from_raw(p_file),
+            OutParam::from_raw(p_out_flags),
+        )
+    };
+    let probe = probe(
+        state.as_ref(),
+        CallbackKind::Open,
+        z_name.as_ref().map(OpenName::name),
+        CallbackDetails::arg(flags),
+    );
+    let rc = tokio::runtime::Runtime::new().unwrap().block_on(open_inner(
+        state,
+        z_name,
+        p_file,
+        flags,
+        p_out_flags,
+    ));
+    probe.exit(rc)
 }
 
 /// Delete the file located at `z_path`. If the `sync_dir` argument is true, ensure the
@@ -316,9 +384,18 @@ pub unsafe extern "C" fn delete<V: Vfs>(
     z_path: *const c_char,
     sync_dir: c_int,
 ) -> c_int {
-    tokio::runtime::Runtime::new()
+    // SAFETY: SQLite passes this VFS and the path to delete
+    let (state, z_path) = unsafe { (VfsRef::<V>::from_raw(p_vfs), c_str(z_path)) };
+    let probe = probe(
+        state.as_ref(),
+        CallbackKind::Delete,
+        z_path,
+        CallbackDetails::NONE,
+    );
+    let rc = tokio::runtime::Runtime::new()
         .unwrap()
-        .block_on(delete_inner::<V>(p_vfs, z_path, sync_dir))
+        .block_on(delete_inner(state, z_path, sync_dir));
+    probe.exit(rc)
 }
 
 /// Test for access permissions. Return true if the requested permission is available, or false
@@ -329,9 +406,24 @@ pub unsafe extern "C" fn access<V: Vfs>(
     flags: c_int,
     p_res_out: *mut c_int,
 ) -> c_int {
-    tokio::runtime::Runtime::new()
+    // SAFETY: SQLite passes this VFS, the path to check and where to write the result
+    let (state, z_path, p_res_out) = unsafe {
+        (
+            VfsRef::<V>::from_raw(p_vfs),
+            c_str(z_path),
+            OutParam::from_raw(p_res_out),
+        )
+    };
+    let probe = probe(
+        state.as_ref(),
+        CallbackKind::Access,
+        z_path,
+        CallbackDetails::arg(flags),
+    );
+    let rc = tokio::runtime::Runtime::new()
         .unwrap()
-        .block_on(access_inner::<V>(p_vfs, z_path, flags, p_res_out))
+        .block_on(access_inner(state, z_path, flags, p_res_out));
+    probe.exit(rc)
 }
 
 /// Populate buffer `z_out` with the full canonical pathname corresponding to the pathname in
@@ -343,28 +435,47 @@ pub unsafe extern "C" fn full_pathname<V: Vfs>(
     n_out: c_int,
     z_out: *mut c_char,
 ) -> c_int {
-    tokio::runtime::Runtime::new()
+    // SAFETY: SQLite passes this VFS, the path to resolve and a buffer of `n_out` bytes for the
+    // result
+    let (state, z_path, z_out) = unsafe {
+        (
+            VfsRef::<V>::from_raw(p_vfs),
+            c_str(z_path),
+            SqliteBufferMut::from_raw(z_out as *mut c_void, n_out),
+        )
+    };
+    let probe = probe(
+        state.as_ref(),
+        CallbackKind::FullPathname,
+        z_path,
+        CallbackDetails::NONE,
+    );
+    let rc = tokio::runtime::Runtime::new()
         .unwrap()
-        .block_on(full_pathname_inner::<V>(p_vfs, z_path, n_out, z_out))
+        .block_on(full_pathname_inner(state, z_path, z_out));
+    probe.exit(rc)
 }
 
 /// Open the dynamic library located at `z_path` and return a handle.
 #[allow(unused_variables)]
-pub unsafe extern "C" fn dlopen<V>(
+pub unsafe extern "C" fn dlopen<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     z_path: *const c_char,
 ) -> *mut c_void {
-    log::trace!("dlopen");
+    tracing::trace!(target: "sqlite_vfs::vfs", "dlopen");
 
     #[cfg(feature = "loadext")]
     {
-        let state = match vfs_state::<V>(p_vfs) {
-            Ok(state) => state,
-            Err(_) => return null_mut(),
+        // SAFETY: SQLite passes this VFS
+        let Some(state) = (unsafe { VfsRef::<V>::from_raw(p_vfs) }) else {
+            return std::ptr::null_mut();
         };
 
-        if let Some(dlopen) = state.parent_vfs.as_ref().and_then(|v| v.xDlOpen) {
-            return dlopen(state.parent_vfs, z_path);
+        // SAFETY: the parent VFS outlives this one
+        let parent = unsafe { state.parent_vfs.as_ref() };
+        if let Some(dlopen) = parent.and_then(|v| v.xDlOpen) {
+            // SAFETY: it takes the arguments SQLite passed
+            return unsafe { dlopen(state.parent_vfs, z_path) };
         }
     }
 
@@ -374,52 +485,59 @@ pub unsafe extern "C" fn dlopen<V>(
 /// Populate the buffer `z_err_msg` (size `n_byte` bytes) with a human readable utf-8 string
 /// describing the most recent error encountered associated with dynamic libraries.
 #[allow(unused_variables)]
-pub unsafe extern "C" fn dlerror<V>(
+pub unsafe extern "C" fn dlerror<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     n_byte: c_int,
     z_err_msg: *mut c_char,
 ) {
-    log::trace!("dlerror");
+    tracing::trace!(target: "sqlite_vfs::vfs", "dlerror");
 
     #[cfg(feature = "loadext")]
     {
-        let state = match vfs_state::<V>(p_vfs) {
-            Ok(state) => state,
-            Err(_) => return,
+        // SAFETY: SQLite passes this VFS
+        let Some(state) = (unsafe { VfsRef::<V>::from_raw(p_vfs) }) else {
+            return;
         };
 
-        if let Some(dlerror) = state.parent_vfs.as_ref().and_then(|v| v.xDlError) {
-            return dlerror(state.parent_vfs, n_byte, z_err_msg);
+        // SAFETY: the parent VFS outlives this one
+        let parent = unsafe { state.parent_vfs.as_ref() };
+        if let Some(dlerror) = parent.and_then(|v| v.xDlError) {
+            // SAFETY: it takes the arguments SQLite passed
+            unsafe { dlerror(state.parent_vfs, n_byte, z_err_msg) };
         }
-
-        return;
     }
 
     #[cfg(not(feature = "loadext"))]
     {
-        let msg = concat!("Loadable extensions are not supported", "\0");
-        libsqlite3_sys::sqlite3_snprintf(n_byte, z_err_msg, msg.as_ptr() as _);
+        // SAFETY: SQLite passes a buffer of `n_byte` bytes for the message
+        let out = unsafe { SqliteBufferMut::from_raw::<()>(z_err_msg as *mut c_void, n_byte) };
+        if let Ok(mut out) = out {
+            out.write_str("Loadable extensions are not supported");
+        }
     }
 }
 
 /// Return a pointer to the symbol `z_sym` in the dynamic library pHandle.
 #[allow(unused_variables)]
-pub unsafe extern "C" fn dlsym<V>(
+pub unsafe extern "C" fn dlsym<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     p: *mut c_void,
     z_sym: *const c_char,
 ) -> Option<unsafe extern "C" fn(*mut libsqlite3_sys::sqlite3_vfs, *mut c_void, *const c_char)> {
-    log::trace!("dlsym");
+    tracing::trace!(target: "sqlite_vfs::vfs", "dlsym");
 
     #[cfg(feature = "loadext")]
     {
-        let state = match vfs_state::<V>(p_vfs) {
-            Ok(state) => state,
-            Err(_) => return None,
+        // SAFETY: SQLite passes this VFS
+        let Some(state) = (unsafe { VfsRef::<V>::from_raw(p_vfs) }) else {
+            return None;
         };
 
-        if let Some(dlsym) = state.parent_vfs.as_ref().and_then(|v| v.xDlSym) {
-            return dlsym(state.parent_vfs, p, z_sym);
+        // SAFETY: the parent VFS outlives this one
+        let parent = unsafe { state.parent_vfs.as_ref() };
+        if let Some(dlsym) = parent.and_then(|v| v.xDlSym) {
+            // SAFETY: it takes the arguments SQLite passed
+            return unsafe { dlsym(state.parent_vfs, p, z_sym) };
         }
     }
 
@@ -428,56 +546,108 @@ pub unsafe extern "C" fn dlsym<V>(
 
 /// Close the dynamic library handle `p_handle`.
 #[allow(unused_variables)]
-pub unsafe extern "C" fn dlclose<V>(
+pub unsafe extern "C" fn dlclose<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     p_handle: *mut c_void,
 ) {
-    log::trace!("dlclose");
+    tracing::trace!(target: "sqlite_vfs::vfs", "dlclose");
 
     #[cfg(feature = "loadext")]
     {
-        let state = match vfs_state::<V>(p_vfs) {
-            Ok(state) => state,
-            Err(_) => return,
+        // SAFETY: SQLite passes this VFS
+        let Some(state) = (unsafe { VfsRef::<V>::from_raw(p_vfs) }) else {
+            return;
         };
 
-        if let Some(dlclose) = state.parent_vfs.as_ref().and_then(|v| v.xDlClose) {
-            return dlclose(state.parent_vfs, p_handle);
+        // SAFETY: the parent VFS outlives this one
+        let parent = unsafe { state.parent_vfs.as_ref() };
+        if let Some(dlclose) = parent.and_then(|v| v.xDlClose) {
+            // SAFETY: it takes the arguments SQLite passed
+            unsafe { dlclose(state.parent_vfs, p_handle) };
         }
     }
 }
 
-async unsafe fn randomness_inner<V: Vfs>(
-    p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
-    n_byte: c_int,
-    z_buf_out: *mut c_char,
+async fn randomness_inner<V: Vfs>(
+    state: Option<VfsRef<V>>,
+    z_buf_out: Result<SqliteBufferMut<'_>, Error<V::Error>>,
 ) -> c_int {
-    log::trace!("randomness");
+    tracing::trace!(target: "sqlite_vfs::vfs", "randomness");
 
-    let bytes = std::slice::from_raw_parts_mut(z_buf_out as *mut i8, n_byte as usize);
+    let Ok(mut bytes) = z_buf_out else {
+        return 0;
+    };
+    if bytes.is_empty() {
+        return 0;
+    }
     if cfg!(feature = "sqlite_test") {
         // During testing, the buffer is simply initialized to all zeroes for repeatability
         bytes.fill(0);
     } else {
-        let state = match vfs_state::<V>(p_vfs) {
-            Ok(state) => state,
-            Err(_) => return 0,
+        let Some(state) = state else {
+            return 0;
         };
 
-        state.vfs.random(bytes).await;
+        state.vfs.random(&mut bytes).await;
     }
     bytes.len() as c_int
 }
 
-/// Populate the buffer pointed to by `z_buf_out` with `n_byte` bytes of random data.
+/// Populate the buffer pointed to by `z_buf_out` with `n_byte` bytes of random data. Returns
+/// how many bytes were written: none for a negative `n_byte` or a null buffer.
 pub unsafe extern "C" fn randomness<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     n_byte: c_int,
     z_buf_out: *mut c_char,
 ) -> c_int {
-    tokio::runtime::Runtime::new()
+    // SAFETY: SQLite passes this VFS
+    let state = unsafe { VfsRef::<V>::from_raw(p_vfs) };
+    if state.is_none() && !p_vfs.is_null() {
+        // retired while a connection still uses it, so the default VFS fills the buffer
+        // SAFETY: finds the default VFS, taking no pointer
+        let default = unsafe { libsqlite3_sys::sqlite3_vfs_find(std::ptr::null_mut()) };
+        // SAFETY: a registered VFS stays allocated while it is registered
+        let randomness = unsafe { default.as_ref() }.and_then(|v| v.xRandomness);
+        return match randomness {
+            // SAFETY: it takes the arguments SQLite passed
+            Some(randomness) => unsafe { randomness(default, n_byte, z_buf_out) },
+            None => 0,
+        };
+    }
+    // SAFETY: SQLite passes a buffer of `n_byte` bytes to fill. `c_char` is `i8` or `u8`
+    // depending on the platform, both with the layout and valid bit patterns of `u8`, so the
+    // buffer is handed to [Vfs::random] as bytes
+    let z_buf_out = unsafe { SqliteBufferMut::from_raw(z_buf_out as *mut c_void, n_byte) };
+    let probe = probe(
+        state.as_ref(),
+        CallbackKind::Randomness,
+        None,
+        CallbackDetails::NONE,
+    );
+    let n = tokio::runtime::Runtime::new()
         .unwrap()
-        .block_on(randomness_inner::<V>(p_vfs, n_byte, z_buf_out))
+        .block_on(randomness_inner(state, z_buf_out));
+    probe.exit(n)
+}
+
+/// The runtime [sleep] waits on, shared by every VFS of the process so that the sleeps of several
+/// connections wait together rather than each on a runtime started for it. Built on first use.
+fn shared_runtime() -> Option<&'static runtime::Runtime> {
+    static RUNTIME: OnceLock<std::io::Result<runtime::Runtime>> = OnceLock::new();
+    let rt = RUNTIME.get_or_init(|| {
+        runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("sqlite-vfs")
+            .enable_all()
+            .build()
+    });
+    match rt {
+        Ok(rt) => Some(rt),
+        Err(err) => {
+            tracing::error!(target: "sqlite_vfs::vfs", %err, "starting the runtime failed");
+            None
+        }
+    }
 }
 
 /// Sleep for `n_micro` microseconds. Return the number of microseconds actually slept.
@@ -485,126 +655,157 @@ pub unsafe extern "C" fn sleep<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     n_micro: c_int,
 ) -> c_int {
-    log::trace!("sleep");
-
-    let state = match vfs_state::<V>(p_vfs) {
-        Ok(state) => state,
-        Err(_) => return libsqlite3_sys::SQLITE_ERROR,
+    tracing::trace!(target: "sqlite_vfs::vfs", "sleep");
+
+    let duration = Duration::from_micros(n_micro.max(0) as u64);
+    // SAFETY: SQLite passes this VFS
+    let Some(state) = (unsafe { VfsRef::<V>::from_raw(p_vfs) }) else {
+        // retired while a connection still uses it
+        std::thread::sleep(duration);
+        return n_micro;
     };
-    state
-        .vfs
-        .sleep(Duration::from_micros(n_micro as u64))
-        .as_micros() as c_int
+    let probe = Probe::enter(
+        state.instrumentation.as_ref(),
+        CallbackKind::Sleep,
+        String::new,
+        CallbackDetails::arg(n_micro),
+    );
+    let Some(rt) = shared_runtime() else {
+        return probe.exit(libsqlite3_sys::SQLITE_ERROR);
+    };
+    let slept = rt.block_on(state.vfs.sleep(duration)).as_micros() as c_int;
+    probe.exit(slept)
 }
 
 /// Return the current time as a Julian Day number in `p_time_out`.
-pub unsafe extern "C" fn current_time<V>(
+pub unsafe extern "C" fn current_time<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     p_time_out: *mut f64,
 ) -> c_int {
-    log::trace!("current_time");
+    tracing::trace!(target: "sqlite_vfs::vfs", "current_time");
 
     let mut i = 0i64;
-    current_time_int64::<V>(p_vfs, &mut i);
+    // SAFETY: `p_vfs` is passed on as SQLite passed it, and `i` is a valid `i64`
+    let rc = unsafe { current_time_int64::<V>(p_vfs, &mut i) };
 
-    *p_time_out = i as f64 / 86400000.0;
-    libsqlite3_sys::SQLITE_OK
+    // SAFETY: SQLite passes where to write the time
+    unsafe { OutParam::from_raw(p_time_out) }.set(i as f64 / 86400000.0);
+    rc
 }
 
-pub unsafe extern "C" fn current_time_int64<V>(
-    _p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
+/// Return the current time as milliseconds since the Julian epoch in `p`, as reported by
+/// [Vfs::current_time].
+pub unsafe extern "C" fn current_time_int64<V: Vfs>(
+    p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     p: *mut i64,
 ) -> i32 {
-    log::trace!("current_time_int64");
+    tracing::trace!(target: "sqlite_vfs::vfs", "current_time_int64");
 
+    /// The Unix epoch in milliseconds since the Julian epoch.
     const UNIX_EPOCH: i64 = 24405875 * 8640000;
-    let now = time::OffsetDateTime::now_utc().unix_timestamp() + UNIX_EPOCH;
-    // #[cfg(feature = "sqlite_test")]
-    // let now = if libsqlite3_sys::sqlite3_get_current_time() > 0 {
-    //     libsqlite3_sys::sqlite3_get_current_time() as i64 * 1000 + UNIX_EPOCH
-    // } else {
-    //     now
-    // };
 
-    *p = now;
-    libsqlite3_sys::SQLITE_OK
+    // SAFETY: SQLite passes this VFS and where to write the time
+    let (state, mut p) = unsafe { (VfsRef::<V>::from_raw(p_vfs), OutParam::from_raw(p)) };
+    let probe = probe(
+        state.as_ref(),
+        CallbackKind::CurrentTime,
+        None,
+        CallbackDetails::NONE,
+    );
+    // the system clock once retired while a connection still uses it
+    let now = state.map_or_else(SystemTime::now, |state| state.vfs.current_time());
+    let now = match now.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => UNIX_EPOCH + since.as_millis() as i64,
+        Err(err) => UNIX_EPOCH - err.duration().as_millis() as i64,
+    };
+
+    p.set(now);
+    probe.exit(libsqlite3_sys::SQLITE_OK)
 }
 
 #[cfg(feature = "syscall")]
-pub unsafe extern "C" fn set_system_call<V>(
+pub unsafe extern "C" fn set_system_call<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     z_name: *const ::std::os::raw::c_char,
-    p_new_func: libsqlite3_sys::libsqlite3_syscall_ptr,
+    p_new_func: libsqlite3_sys::sqlite3_syscall_ptr,
 ) -> ::std::os::raw::c_int {
-    let state = match vfs_state::<V>(p_vfs) {
-        Ok(state) => state,
-        Err(_) => return libsqlite3_sys::SQLITE_ERROR,
+    // SAFETY: SQLite passes this VFS
+    let Some(state) = (unsafe { VfsRef::<V>::from_raw(p_vfs) }) else {
+        return libsqlite3_sys::SQLITE_ERROR;
     };
 
-    if let Some(set_system_call) = state.parent_vfs.as_ref().and_then(|v| v.xSetSystemCall) {
-        return set_system_call(state.parent_vfs, z_name, p_new_func);
+    // SAFETY: the parent VFS outlives this one
+    let parent = unsafe { state.parent_vfs.as_ref() };
+    if let Some(set_system_call) = parent.and_then(|v| v.xSetSystemCall) {
+        // SAFETY: it takes the arguments SQLite passed
+        return unsafe { set_system_call(state.parent_vfs, z_name, p_new_func) };
     }
 
     libsqlite3_sys::SQLITE_ERROR
 }
 
 #[cfg(feature = "syscall")]
-pub unsafe extern "C" fn get_system_call<V>(
+pub unsafe extern "C" fn get_system_call<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     z_name: *const ::std::os::raw::c_char,
-) -> libsqlite3_sys::libsqlite3_syscall_ptr {
-    let state = match vfs_state::<V>(p_vfs) {
-        Ok(state) => state,
-        Err(_) => return None,
+) -> libsqlite3_sys::sqlite3_syscall_ptr {
+    // SAFETY: SQLite passes this VFS
+    let Some(state) = (unsafe { VfsRef::<V>::from_raw(p_vfs) }) else {
+        return None;
     };
 
-    if let Some(get_system_call) = state.parent_vfs.as_ref().and_then(|v| v.xGetSystemCall) {
-        return get_system_call(state.parent_vfs, z_name);
+    // SAFETY: the parent VFS outlives this one
+    let parent = unsafe { state.parent_vfs.as_ref() };
+    if let Some(get_system_call) = parent.and_then(|v| v.xGetSystemCall) {
+        // SAFETY: it takes the arguments SQLite passed
+        return unsafe { get_system_call(state.parent_vfs, z_name) };
     }
 
     None
 }
 
 #[cfg(feature = "syscall")]
-pub unsafe extern "C" fn next_system_call<V>(
+pub unsafe extern "C" fn next_system_call<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     z_name: *const ::std::os::raw::c_char,
 ) -> *const ::std::os::raw::c_char {
-    let state = match vfs_state::<V>(p_vfs) {
-        Ok(state) => state,
-        Err(_) => return std::ptr::null(),
+    // SAFETY: SQLite passes this VFS
+    let Some(state) = (unsafe { VfsRef::<V>::from_raw(p_vfs) }) else {
+        return std::ptr::null();
     };
 
-    if let Some(next_system_call) = state.parent_vfs.as_ref().and_then(|v| v.xNextSystemCall) {
-        return next_system_call(state.parent_vfs, z_name);
+    // SAFETY: the parent VFS outlives this one
+    let parent = unsafe { state.parent_vfs.as_ref() };
+    if let Some(next_system_call) = parent.and_then(|v| v.xNextSystemCall) {
+        // SAFETY: it takes the arguments SQLite passed
+        return unsafe { next_system_call(state.parent_vfs, z_name) };
     }
 
     std::ptr::null()
 }
 
+/// Write the last error of the VFS into `z_err_msg`, nul-terminated and truncated to `n_byte`
+/// bytes, and return its code.
 pub unsafe extern "C" fn get_last_error<V: Vfs>(
     p_vfs: *mut libsqlite3_sys::sqlite3_vfs,
     n_byte: c_int,
     z_err_msg: *mut c_char,
 ) -> c_int {
-    let state = match vfs_state::<V>(p_vfs) {
-        Ok(state) => state,
-        Err(_) => return libsqlite3_sys::SQLITE_ERROR,
-    };
-    if let Some((eno, err)) = state.last_error.lock().unwrap().as_ref() {
-        let msg = match CString::new(err.to_string()) {
-            Ok(msg) => msg,
-            Err(_) => return libsqlite3_sys::SQLITE_ERROR,
-        };
-
-        let msg = msg.to_bytes_with_nul();
-        if msg.len() > n_byte as usize {
-            return libsqlite3_sys::SQLITE_ERROR;
-        }
-        let out = std::slice::from_raw_parts_mut(z_err_msg as *mut u8, msg.len());
-        out.copy_from_slice(msg);
-
-        return *eno;
+    // SAFETY: SQLite passes this VFS and a buffer of `n_byte` bytes for the message
+    let (state, out) = unsafe {
+        (
+            VfsRef::<V>::from_raw(p_vfs),
+            SqliteBufferMut::from_raw::<V::Error>(z_err_msg as *mut c_void, n_byte),
+        )
+    };
+    let (Some(state), Ok(mut out)) = (state, out) else {
+        return libsqlite3_sys::SQLITE_ERROR;
+    };
+    if let Some(LastError { code, message }) = state.last_error.lock().unwrap().as_ref() {
+        // truncated rather than dropped when the buffer is too small: messages start with what
+        // identifies the failure, the file it happened on
+        out.write_str(message);
+        return *code;
     }
     libsqlite3_sys::SQLITE_OK
 }
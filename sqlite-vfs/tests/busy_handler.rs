@@ -0,0 +1,96 @@
+mod common;
+
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use common::MemVfs;
+use rusqlite::{Connection, ErrorCode, OpenFlags};
+use sqlite_vfs::instrument::{CallbackKind, RecordingInstrumentation};
+use sqlite_vfs::sync_compat::SyncVfsAdapter;
+
+/// Register a cooperative [MemVfs] as `name` and open `main.db` with a table `t` through it.
+/// Returns the connection, the lock of the other process and the recorded callbacks.
+fn setup(
+    name: &str,
+) -> (
+    Connection,
+    Arc<Mutex<Option<SystemTime>>>,
+    Arc<RecordingInstrumentation>,
+) {
+    let vfs = MemVfs {
+        cooperative: true,
+        ..MemVfs::default()
+    };
+    let locked_until = vfs.locked_until.clone();
+    let rec = Arc::new(RecordingInstrumentation::default());
+    sqlite_vfs::register_instrumented(name, SyncVfsAdapter::new(vfs), false, rec.clone())
+        .unwrap()
+        .leak();
+    let conn = Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        name,
+    )
+    .unwrap();
+    conn.execute_batch("CREATE TABLE t (n INTEGER)").unwrap();
+    (conn, locked_until, rec)
+}
+
+fn locks(rec: &RecordingInstrumentation) -> usize {
+    rec.calls()
+        .iter()
+        .filter(|(kind, _)| *kind == CallbackKind::Lock)
+        .count()
+}
+
+static REFUSING: AtomicI32 = AtomicI32::new(0);
+
+fn refuse_fourth(count: i32) -> bool {
+    REFUSING.fetch_add(1, Ordering::Relaxed);
+    count < 3
+}
+
+#[test]
+fn test_refusal_ends_the_wait() {
+    let (conn, locked_until, rec) = setup("busy-refusal");
+    conn.busy_handler(Some(refuse_fourth)).unwrap();
+    *locked_until.lock().unwrap() = Some(SystemTime::now() + Duration::from_secs(3600));
+    rec.clear();
+
+    let err = conn
+        .query_row("SELECT count(*) FROM t", [], |row| row.get::<_, i64>(0))
+        .unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::DatabaseBusy));
+    // asked within a single lock callback; once refused, SQLite doesn't retry
+    assert_eq!(REFUSING.load(Ordering::Relaxed), 4);
+    assert_eq!(locks(&rec), 1);
+}
+
+static RELEASING: AtomicI32 = AtomicI32::new(0);
+static LOCKED_UNTIL: OnceLock<Arc<Mutex<Option<SystemTime>>>> = OnceLock::new();
+
+/// The other process releases the database while the handler is asked the second time.
+fn release_second(count: i32) -> bool {
+    RELEASING.fetch_add(1, Ordering::Relaxed);
+    if count == 1 {
+        *LOCKED_UNTIL.get().unwrap().lock().unwrap() = None;
+    }
+    true
+}
+
+#[test]
+fn test_wait_until_released() {
+    let (conn, locked_until, rec) = setup("busy-release");
+    LOCKED_UNTIL.set(locked_until.clone()).unwrap();
+    conn.busy_handler(Some(release_second)).unwrap();
+    *locked_until.lock().unwrap() = Some(SystemTime::now() + Duration::from_secs(3600));
+    rec.clear();
+
+    let n: i64 = conn
+        .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(n, 0);
+    assert_eq!(RELEASING.load(Ordering::Relaxed), 2);
+    assert_eq!(locks(&rec), 1);
+}
@@ -0,0 +1,146 @@
+mod common;
+
+use common::{MemFile, MemVfs};
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::capability::{Capabilities, Linked, VfsCapabilities};
+use sqlite_vfs::sync_compat::{SyncHandleAdapter, SyncVfsAdapter};
+
+#[test]
+fn test_vfs_stats_report_linked_library() {
+    assert_eq!(sqlite_vfs::vfs_stats("capability-mem"), None);
+    sqlite_vfs::register(
+        "capability-mem",
+        SyncVfsAdapter::new(MemVfs::default()),
+        false,
+    )
+    .unwrap()
+    .leak();
+
+    let stats = sqlite_vfs::vfs_stats("capability-mem").unwrap();
+    assert_eq!(stats.capabilities, Capabilities::probe(&Linked));
+    assert!(!stats.instrumented);
+    assert_eq!(
+        stats.capabilities.version,
+        rusqlite::version(),
+        "registered with the library rusqlite links"
+    );
+    assert!(
+        stats
+            .to_string()
+            .starts_with(&format!("sqlite={} ", rusqlite::version())),
+        "{stats}"
+    );
+
+    let conn = Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "capability-mem",
+    )
+    .unwrap();
+    conn.execute_batch("CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1);")
+        .unwrap();
+}
+
+fn connect(vfs: &str) -> Connection {
+    Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        vfs,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_wal_degrades_without_shm() {
+    sqlite_vfs::register(
+        "capability-no-wal",
+        SyncVfsAdapter::new(MemVfs::default()),
+        false,
+    )
+    .unwrap()
+    .leak();
+    // derived from the handles, whose WAL index is disabled
+    let stats = sqlite_vfs::vfs_stats("capability-no-wal").unwrap();
+    assert_eq!(
+        stats.backend,
+        VfsCapabilities {
+            supports_wal: false,
+            supports_shm: false,
+            ..VfsCapabilities::ALL
+        }
+    );
+    assert!(
+        stats
+            .to_string()
+            .contains("wal=false temp_files=true journals=true shm=false"),
+        "{stats}"
+    );
+
+    let conn = connect("capability-no-wal");
+    conn.execute_batch("CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1);")
+        .unwrap();
+    let mode: String = conn
+        .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(mode, "delete");
+    conn.execute("INSERT INTO t VALUES (2)", []).unwrap();
+}
+
+#[test]
+fn test_temp_files_unsupported() {
+    let vfs = MemVfs {
+        capabilities: Some(VfsCapabilities {
+            supports_temp_files: false,
+            ..VfsCapabilities::of::<SyncHandleAdapter<MemFile>>()
+        }),
+        ..MemVfs::default()
+    };
+    let files = vfs.files.clone();
+    sqlite_vfs::register("capability-no-temp", SyncVfsAdapter::new(vfs), false)
+        .unwrap()
+        .leak();
+    assert!(
+        !sqlite_vfs::vfs_stats("capability-no-temp")
+            .unwrap()
+            .backend
+            .supports_temp_files
+    );
+
+    // the statement spilling the temporary database to a file fails, and only it
+    let fill = "WITH RECURSIVE r(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM r WHERE i < 200)
+                INSERT INTO scratch SELECT randomblob(1000) FROM r";
+    let conn = connect("capability-no-temp");
+    conn.execute_batch(
+        "PRAGMA temp_store = FILE;
+         PRAGMA temp.cache_size = 2;
+         CREATE TABLE t (n INTEGER);
+         CREATE TEMP TABLE scratch (b BLOB);",
+    )
+    .unwrap();
+    let err = conn.execute_batch(fill).unwrap_err();
+    assert_eq!(
+        err.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::CannotOpen),
+        "{err}"
+    );
+    conn.execute("INSERT INTO t VALUES (1)", []).unwrap();
+    assert!(files
+        .lock()
+        .unwrap()
+        .keys()
+        .all(|name| name.starts_with("main.db")));
+
+    // kept in memory, temporary tables work
+    let conn = connect("capability-no-temp");
+    conn.execute_batch(
+        "PRAGMA temp_store = MEMORY;
+         PRAGMA temp.cache_size = 2;
+         CREATE TEMP TABLE scratch (b BLOB);",
+    )
+    .unwrap();
+    conn.execute_batch(fill).unwrap();
+    let count: i64 = conn
+        .query_row("SELECT count(*) FROM scratch", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 200);
+}
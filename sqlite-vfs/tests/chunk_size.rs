@@ -0,0 +1,417 @@
+mod common;
+
+use std::collections::HashMap;
+use std::ffi::{c_int, CStr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::MemVfs;
+use sqlite_vfs::sync_compat::{SyncDatabaseHandle, SyncVfs, SyncVfsAdapter};
+use sqlite_vfs::{LockKind, OpenOptions};
+
+/// The unit [ChunkedVfs] stores files in, deliberately unlike any growth quantum SQLite asks for.
+const STORAGE_CHUNK_SIZE: usize = 1000;
+
+/// A file stored in chunks of [STORAGE_CHUNK_SIZE] bytes, as an object store layout would, with
+/// its logical length recorded apart from them, as in a manifest.
+#[derive(Default)]
+struct Stored {
+    chunks: Vec<Vec<u8>>,
+    len: u64,
+    /// The growth quantums the file was given.
+    growth_quantums: Vec<usize>,
+}
+
+impl Stored {
+    fn allocate(&mut self, size: u64) {
+        let chunks = (size as usize).div_ceil(STORAGE_CHUNK_SIZE);
+        if self.chunks.len() < chunks {
+            self.chunks.resize(chunks, vec![0; STORAGE_CHUNK_SIZE]);
+        }
+    }
+
+    /// Whether all bytes past the logical length read as zeros, should the file grow over them.
+    fn tail_is_zero(&self) -> bool {
+        let len = self.len as usize;
+        self.chunks.iter().enumerate().all(|(i, chunk)| {
+            let start = len
+                .saturating_sub(i * STORAGE_CHUNK_SIZE)
+                .min(STORAGE_CHUNK_SIZE);
+            chunk[start..].iter().all(|byte| *byte == 0)
+        })
+    }
+}
+
+#[derive(Clone, Default)]
+struct ChunkedVfs {
+    files: Arc<Mutex<HashMap<String, Arc<Mutex<Stored>>>>>,
+}
+
+struct ChunkedFile {
+    stored: Arc<Mutex<Stored>>,
+    lock: LockKind,
+}
+
+impl SyncDatabaseHandle for ChunkedFile {
+    fn size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.stored.lock().unwrap().len)
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let stored = self.stored.lock().unwrap();
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let pos = offset as usize + i;
+            *byte = match pos < stored.len as usize {
+                true => stored
+                    .chunks
+                    .get(pos / STORAGE_CHUNK_SIZE)
+                    .map_or(0, |chunk| chunk[pos % STORAGE_CHUNK_SIZE]),
+                false => 0,
+            };
+        }
+        if offset + buf.len() as u64 > stored.len {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let mut stored = self.stored.lock().unwrap();
+        let end = offset + buf.len() as u64;
+        stored.allocate(end);
+        for (i, byte) in buf.iter().enumerate() {
+            let pos = offset as usize + i;
+            stored.chunks[pos / STORAGE_CHUNK_SIZE][pos % STORAGE_CHUNK_SIZE] = *byte;
+        }
+        stored.len = stored.len.max(end);
+        Ok(())
+    }
+
+    fn sync(&mut self, _data_only: bool) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let mut stored = self.stored.lock().unwrap();
+        if size < stored.len {
+            // discard the chunks past the end, and zero the tail of the last one kept
+            let size = size as usize;
+            stored.chunks.truncate(size.div_ceil(STORAGE_CHUNK_SIZE));
+            if let Some(last) = stored.chunks.get_mut(size / STORAGE_CHUNK_SIZE) {
+                last[size % STORAGE_CHUNK_SIZE..].fill(0);
+            }
+        }
+        stored.len = size;
+        Ok(())
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.lock = lock;
+        Ok(true)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        Ok(self.lock)
+    }
+
+    fn set_chunk_size(&self, chunk_size: usize) -> Result<(), std::io::Error> {
+        self.stored.lock().unwrap().growth_quantums.push(chunk_size);
+        Ok(())
+    }
+
+    fn allocate(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.stored.lock().unwrap().allocate(size);
+        Ok(())
+    }
+}
+
+impl SyncVfs for ChunkedVfs {
+    type Handle = ChunkedFile;
+
+    fn open(&self, db: &str, _opts: OpenOptions) -> Result<ChunkedFile, std::io::Error> {
+        let mut files = self.files.lock().unwrap();
+        Ok(ChunkedFile {
+            stored: files.entry(db.to_owned()).or_default().clone(),
+            lock: LockKind::None,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.files.lock().unwrap().remove(db);
+        Ok(())
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        Ok(self.files.lock().unwrap().contains_key(db))
+    }
+
+    fn temporary_name(&self) -> String {
+        "temp".to_owned()
+    }
+
+    fn random(&self, buffer: &mut [u8]) {
+        buffer.fill(4);
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        duration
+    }
+}
+
+/// A file opened through the `sqlite3_io_methods` of a registered VFS, as SQLite would.
+struct RawFile {
+    /// Backs the `sqlite3_file` of the VFS, `szOsFile` bytes.
+    file: Vec<u64>,
+}
+
+impl RawFile {
+    fn open(vfs: &CStr, name: &'static CStr) -> Self {
+        unsafe {
+            let vfs = libsqlite3_sys::sqlite3_vfs_find(vfs.as_ptr());
+            assert!(!vfs.is_null());
+            let mut file = vec![0u64; ((*vfs).szOsFile as usize).div_ceil(8)];
+            let flags = libsqlite3_sys::SQLITE_OPEN_MAIN_DB
+                | libsqlite3_sys::SQLITE_OPEN_READWRITE
+                | libsqlite3_sys::SQLITE_OPEN_CREATE;
+            let mut out_flags = 0;
+            let rc = ((*vfs).xOpen.unwrap())(
+                vfs,
+                name.as_ptr(),
+                file.as_mut_ptr() as *mut _,
+                flags,
+                &mut out_flags,
+            );
+            assert_eq!(rc, libsqlite3_sys::SQLITE_OK);
+            Self { file }
+        }
+    }
+
+    fn ptr(&mut self) -> *mut libsqlite3_sys::sqlite3_file {
+        self.file.as_mut_ptr() as *mut _
+    }
+
+    fn methods(&mut self) -> &libsqlite3_sys::sqlite3_io_methods {
+        unsafe { &*(*self.ptr()).pMethods }
+    }
+
+    fn write(&mut self, buf: &[u8], offset: i64) -> c_int {
+        let write = self.methods().xWrite.unwrap();
+        unsafe {
+            write(
+                self.ptr(),
+                buf.as_ptr() as *const _,
+                buf.len() as c_int,
+                offset,
+            )
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8], offset: i64) -> c_int {
+        let read = self.methods().xRead.unwrap();
+        unsafe {
+            read(
+                self.ptr(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as c_int,
+                offset,
+            )
+        }
+    }
+
+    fn truncate(&mut self, size: i64) -> c_int {
+        let truncate = self.methods().xTruncate.unwrap();
+        unsafe { truncate(self.ptr(), size) }
+    }
+
+    fn size(&mut self) -> i64 {
+        let file_size = self.methods().xFileSize.unwrap();
+        let mut size = 0;
+        assert_eq!(
+            unsafe { file_size(self.ptr(), &mut size) },
+            libsqlite3_sys::SQLITE_OK
+        );
+        size
+    }
+
+    fn chunk_size(&mut self, mut chunk_size: c_int) -> c_int {
+        let file_control = self.methods().xFileControl.unwrap();
+        let arg = &mut chunk_size as *mut c_int as *mut _;
+        unsafe { file_control(self.ptr(), libsqlite3_sys::SQLITE_FCNTL_CHUNK_SIZE, arg) }
+    }
+
+    fn size_hint(&mut self, mut size: i64) -> c_int {
+        let file_control = self.methods().xFileControl.unwrap();
+        let arg = &mut size as *mut i64 as *mut _;
+        unsafe { file_control(self.ptr(), libsqlite3_sys::SQLITE_FCNTL_SIZE_HINT, arg) }
+    }
+}
+
+impl Drop for RawFile {
+    fn drop(&mut self) {
+        let close = self.methods().xClose.unwrap();
+        unsafe { close(self.ptr()) };
+    }
+}
+
+/// A small xorshift generator, so that failures reproduce from the seed alone.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+/// Run random sequences of growth quanta, size hints, grows, shrinks, writes and reads against the
+/// file `name` of the VFS registered as `vfs`, comparing each with a model of its content. After
+/// each step, `check` is called with the logical length of the model.
+fn check_against_model(vfs: &CStr, name: &'static CStr, check: impl Fn(u64)) {
+    const MAX_LEN: u64 = 20_000;
+    for seed in 1..=16u64 {
+        let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        let mut file = RawFile::open(vfs, name);
+        assert_eq!(file.truncate(0), libsqlite3_sys::SQLITE_OK);
+        let mut model: Vec<u8> = vec![];
+        let mut history = vec![];
+        for _ in 0..300 {
+            let step = match rng.below(5) {
+                0 => {
+                    let chunk_size = [0, -1, 512, 3000, 4096, 65536][rng.below(6) as usize];
+                    assert_eq!(file.chunk_size(chunk_size), libsqlite3_sys::SQLITE_OK);
+                    format!("chunk_size {chunk_size}")
+                }
+                1 => {
+                    let size = rng.below(MAX_LEN) as i64;
+                    assert_eq!(file.size_hint(size), libsqlite3_sys::SQLITE_OK);
+                    format!("size_hint {size}")
+                }
+                2 => {
+                    let size = rng.below(MAX_LEN);
+                    assert_eq!(file.truncate(size as i64), libsqlite3_sys::SQLITE_OK);
+                    model.resize(size as usize, 0);
+                    format!("truncate {size}")
+                }
+                3 => {
+                    let offset = rng.below(MAX_LEN);
+                    let buf = vec![1 + rng.below(255) as u8; 1 + rng.below(3000) as usize];
+                    assert_eq!(file.write(&buf, offset as i64), libsqlite3_sys::SQLITE_OK);
+                    let end = offset as usize + buf.len();
+                    if model.len() < end {
+                        model.resize(end, 0);
+                    }
+                    model[offset as usize..end].copy_from_slice(&buf);
+                    format!("write {} at {offset}", buf.len())
+                }
+                _ => {
+                    // mostly around the end, where rounding would show
+                    let offset = match rng.below(2) {
+                        0 => (model.len() as u64).saturating_sub(rng.below(5000)),
+                        _ => rng.below(MAX_LEN),
+                    };
+                    let mut buf = vec![0xaa; 1 + rng.below(5000) as usize];
+                    let rc = file.read(&mut buf, offset as i64);
+                    let start = (offset as usize).min(model.len());
+                    let end = (offset as usize + buf.len()).min(model.len());
+                    let mut expected = model[start..end].to_vec();
+                    expected.resize(buf.len(), 0);
+                    let short = (offset as usize + buf.len()) > model.len();
+                    let expected_rc = match short {
+                        true => libsqlite3_sys::SQLITE_IOERR_SHORT_READ,
+                        false => libsqlite3_sys::SQLITE_OK,
+                    };
+                    let step = format!("read {} at {offset}", buf.len());
+                    assert_eq!(rc, expected_rc, "seed {seed}: {history:?} {step}");
+                    assert!(buf == expected, "seed {seed}: {history:?} {step}");
+                    step
+                }
+            };
+            history.push(step);
+            assert_eq!(
+                file.size() as usize,
+                model.len(),
+                "seed {seed}: {history:?}"
+            );
+            check(model.len() as u64);
+        }
+    }
+}
+
+#[test]
+fn test_growth_quantum_against_model() {
+    let chunked = ChunkedVfs::default();
+    sqlite_vfs::register("chunked", SyncVfsAdapter::new(chunked.clone()), false)
+        .unwrap()
+        .leak();
+    check_against_model(c"chunked", c"main.db", |len| {
+        let files = chunked.files.lock().unwrap();
+        let stored = files["main.db"].lock().unwrap();
+        assert_eq!(stored.len, len);
+        assert!(stored.tail_is_zero());
+    });
+    let files = chunked.files.lock().unwrap();
+    let quantums = &files["main.db"].lock().unwrap().growth_quantums;
+    // turning it off reaches the handle as zero
+    assert!(quantums.contains(&0) && quantums.contains(&3000));
+
+    sqlite_vfs::register("chunked-mem", SyncVfsAdapter::new(MemVfs::default()), false)
+        .unwrap()
+        .leak();
+    check_against_model(c"chunked-mem", c"main.db", |_| {});
+}
+
+#[test]
+fn test_growth_quantum_through_sqlite() {
+    let chunked = ChunkedVfs::default();
+    sqlite_vfs::register(
+        "chunked-sqlite",
+        SyncVfsAdapter::new(chunked.clone()),
+        false,
+    )
+    .unwrap()
+    .leak();
+    let conn = rusqlite::Connection::open_with_flags_and_vfs(
+        "main.db",
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE,
+        "chunked-sqlite",
+    )
+    .unwrap();
+    let mut chunk_size: c_int = 65536;
+    let rc = unsafe {
+        libsqlite3_sys::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            libsqlite3_sys::SQLITE_FCNTL_CHUNK_SIZE,
+            &mut chunk_size as *mut c_int as *mut _,
+        )
+    };
+    assert_eq!(rc, libsqlite3_sys::SQLITE_OK);
+    conn.execute_batch(
+        "CREATE TABLE t (x BLOB);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+         INSERT INTO t SELECT randomblob(900) FROM n;
+         DELETE FROM t WHERE rowid > 10;
+         VACUUM;",
+    )
+    .unwrap();
+    let page_count: u64 = conn
+        .query_row("PRAGMA page_count", [], |row| row.get(0))
+        .unwrap();
+    let n: i64 = conn
+        .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(n, 10);
+
+    // the length is the one SQLite asked for, not rounded up to the growth quantum
+    let files = chunked.files.lock().unwrap();
+    let stored = files["main.db"].lock().unwrap();
+    assert_eq!(stored.len, page_count * 4096);
+    assert!(stored.tail_is_zero());
+    assert_eq!(stored.growth_quantums, [65536]);
+}
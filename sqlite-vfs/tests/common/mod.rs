@@ -0,0 +1,423 @@
+#![allow(dead_code)]
+
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use sqlite_vfs::busy::BusyHandlerRef;
+use sqlite_vfs::capability::VfsCapabilities;
+use sqlite_vfs::clock::MockClock;
+use sqlite_vfs::sync_compat::{SyncDatabaseHandle, SyncHandleAdapter, SyncVfs};
+use sqlite_vfs::{LockKind, OpenAccess, OpenOptions};
+
+pub type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The locks held on each file by the handles of a VFS, with the semantics of SQLite's own
+/// VFSes: any number of shared locks, at most one reserved lock alongside them, and an exclusive
+/// lock only once all other shared locks are released. A pending lock keeps new shared locks
+/// from being acquired meanwhile.
+#[derive(Clone, Default)]
+pub struct Locks(Arc<Mutex<HashMap<String, FileLocks>>>);
+
+#[derive(Default)]
+struct FileLocks {
+    shared: HashSet<usize>,
+    reserved: Option<usize>,
+    pending: Option<usize>,
+    exclusive: Option<usize>,
+}
+
+impl Locks {
+    /// Move the lock of handle `id` on `name` from `from` to `to`. Return whether it was granted.
+    fn transition(&self, name: &str, id: usize, from: LockKind, to: LockKind) -> bool {
+        let mut locks = self.0.lock().unwrap();
+        let file = locks.entry(name.to_owned()).or_default();
+        let other = |holder: Option<usize>| holder.is_some_and(|holder| holder != id);
+        if to < from {
+            for holder in [&mut file.reserved, &mut file.pending, &mut file.exclusive] {
+                if *holder == Some(id) {
+                    *holder = None;
+                }
+            }
+            if to == LockKind::None {
+                file.shared.remove(&id);
+            }
+            return true;
+        }
+        match to {
+            LockKind::None => true,
+            LockKind::Shared => {
+                if other(file.pending) || other(file.exclusive) {
+                    return false;
+                }
+                file.shared.insert(id);
+                true
+            }
+            LockKind::Reserved => {
+                if other(file.reserved) || other(file.pending) || other(file.exclusive) {
+                    return false;
+                }
+                file.reserved = Some(id);
+                true
+            }
+            LockKind::Pending | LockKind::Exclusive => {
+                if other(file.reserved) || other(file.pending) || other(file.exclusive) {
+                    return false;
+                }
+                file.pending = Some(id);
+                if to == LockKind::Pending || file.shared.iter().any(|holder| *holder != id) {
+                    return to == LockKind::Pending;
+                }
+                file.exclusive = Some(id);
+                true
+            }
+        }
+    }
+
+    /// Whether any handle holds a reserved, pending or exclusive lock on `name`.
+    fn reserved(&self, name: &str) -> bool {
+        self.0.lock().unwrap().get(name).is_some_and(|file| {
+            file.reserved.is_some() || file.pending.is_some() || file.exclusive.is_some()
+        })
+    }
+}
+
+/// An in-memory VFS. With `readonly` set, it refuses to open anything for writing.
+#[derive(Default)]
+pub struct MemVfs {
+    pub files: Files,
+    pub readonly: bool,
+    /// Names of the files whose reads fail, and whose existence can't be checked.
+    pub failing: Arc<Mutex<HashSet<String>>>,
+    /// Names of the files that can't be opened for writing, as in a read-only directory.
+    pub denied: Arc<Mutex<HashSet<String>>>,
+    /// Read the time from this clock and advance it instead of sleeping.
+    pub clock: Option<MockClock>,
+    /// Refuse all locks until then, as if another process held the database.
+    pub locked_until: Arc<Mutex<Option<SystemTime>>>,
+    /// Wait for [MemVfs::locked_until] while the connection's busy handler allows, rather than
+    /// refusing the lock right away.
+    pub cooperative: bool,
+    pub locks: Locks,
+    /// Answered to `xSectorSize` instead of the default.
+    pub sector_size: Option<u32>,
+    /// Declared instead of the capabilities derived from the handles.
+    pub capabilities: Option<VfsCapabilities>,
+}
+
+impl MemVfs {
+    fn now(&self) -> SystemTime {
+        self.clock
+            .as_ref()
+            .map_or_else(SystemTime::now, MockClock::now)
+    }
+}
+
+pub struct MemFile {
+    name: String,
+    files: Files,
+    failing: Arc<Mutex<HashSet<String>>>,
+    clock: Option<MockClock>,
+    locked_until: Arc<Mutex<Option<SystemTime>>>,
+    cooperative: bool,
+    busy_handler: Option<BusyHandlerRef>,
+    locks: Locks,
+    id: usize,
+    lock: LockKind,
+    sector_size: Option<u32>,
+}
+
+impl SyncDatabaseHandle for MemFile {
+    fn size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.files.lock().unwrap()[&self.name].len() as u64)
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        if self.failing.lock().unwrap().contains(&self.name) {
+            return Err(std::io::Error::other("injected read failure"));
+        }
+        let files = self.files.lock().unwrap();
+        let data = &files[&self.name];
+        let start = (offset as usize).min(data.len());
+        let end = (start + buf.len()).min(data.len());
+        buf[..end - start].copy_from_slice(&data[start..end]);
+        buf[end - start..].fill(0);
+        if end - start < buf.len() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        let mut files = self.files.lock().unwrap();
+        let data = files.get_mut(&self.name).unwrap();
+        let end = offset as usize + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset as usize..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&mut self, _data_only: bool) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let mut files = self.files.lock().unwrap();
+        files.get_mut(&self.name).unwrap().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        loop {
+            let now = self
+                .clock
+                .as_ref()
+                .map_or_else(SystemTime::now, MockClock::now);
+            if lock <= self.lock || !self.locked_until.lock().unwrap().is_some_and(|t| now < t) {
+                break;
+            }
+            match &self.busy_handler {
+                Some(handler) if self.cooperative && handler.invoke() => continue,
+                _ => return Ok(false),
+            }
+        }
+        if !self.locks.transition(&self.name, self.id, self.lock, lock) {
+            return Ok(false);
+        }
+        self.lock = lock;
+        Ok(true)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        Ok(self.locks.reserved(&self.name))
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        Ok(self.lock)
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.sector_size
+            .unwrap_or(sqlite_vfs::capability::DEFAULT_SECTOR_SIZE)
+    }
+
+    fn set_busy_handler(&mut self, handler: Option<BusyHandlerRef>) {
+        self.busy_handler = handler;
+    }
+}
+
+impl Drop for MemFile {
+    fn drop(&mut self) {
+        self.locks
+            .transition(&self.name, self.id, self.lock, LockKind::None);
+    }
+}
+
+impl SyncVfs for MemVfs {
+    type Handle = MemFile;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let denied = self.readonly || self.denied.lock().unwrap().contains(db);
+        if denied && opts.access != OpenAccess::Read {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        self.files.lock().unwrap().entry(db.to_owned()).or_default();
+        Ok(MemFile {
+            name: db.to_owned(),
+            files: self.files.clone(),
+            failing: self.failing.clone(),
+            clock: self.clock.clone(),
+            locked_until: self.locked_until.clone(),
+            cooperative: self.cooperative,
+            busy_handler: None,
+            locks: self.locks.clone(),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            lock: LockKind::None,
+            sector_size: self.sector_size,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        match self.files.lock().unwrap().remove(db) {
+            Some(_) => Ok(()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        if self.failing.lock().unwrap().contains(db) {
+            return Err(std::io::Error::other("storage unreachable"));
+        }
+        Ok(self.files.lock().unwrap().contains_key(db))
+    }
+
+    fn temporary_name(&self) -> String {
+        format!("temp-{:?}", Instant::now())
+    }
+
+    fn random(&self, buffer: &mut [u8]) {
+        buffer.fill(4);
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        match &self.clock {
+            Some(clock) => clock.advance(duration),
+            None => std::thread::sleep(duration),
+        }
+        duration
+    }
+
+    fn current_time(&self) -> SystemTime {
+        self.now()
+    }
+
+    fn capabilities(&self) -> VfsCapabilities {
+        self.capabilities
+            .unwrap_or_else(VfsCapabilities::of::<SyncHandleAdapter<MemFile>>)
+    }
+}
+
+/// A VFS over the files of a directory, removed again when the VFS is dropped.
+pub struct FsVfs {
+    pub dir: PathBuf,
+    locks: Locks,
+}
+
+impl FsVfs {
+    /// A VFS over a new, empty directory in the system's temporary directory.
+    pub fn temporary() -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "sqlite-vfs-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self {
+            dir,
+            locks: Locks::default(),
+        }
+    }
+}
+
+impl Drop for FsVfs {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub struct FsFile {
+    name: String,
+    file: std::fs::File,
+    locks: Locks,
+    id: usize,
+    lock: LockKind,
+}
+
+impl SyncDatabaseHandle for FsFile {
+    fn size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.file.read_at(&mut buf[read..], offset + read as u64)? {
+                0 => {
+                    buf[read..].fill(0);
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        match data_only {
+            true => self.file.sync_data(),
+            false => self.file.sync_all(),
+        }
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        self.file.set_len(size)
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        if !self.locks.transition(&self.name, self.id, self.lock, lock) {
+            return Ok(false);
+        }
+        self.lock = lock;
+        Ok(true)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        Ok(self.locks.reserved(&self.name))
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        Ok(self.lock)
+    }
+}
+
+impl Drop for FsFile {
+    fn drop(&mut self) {
+        self.locks
+            .transition(&self.name, self.id, self.lock, LockKind::None);
+    }
+}
+
+impl SyncVfs for FsVfs {
+    type Handle = FsFile;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        let mut options = std::fs::OpenOptions::new();
+        options.read(true);
+        match opts.access {
+            OpenAccess::Read => &mut options,
+            OpenAccess::Write => options.write(true),
+            OpenAccess::Create => options.write(true).create(true),
+            OpenAccess::CreateNew => options.write(true).create_new(true),
+        };
+        Ok(FsFile {
+            name: db.to_owned(),
+            file: options.open(self.dir.join(db))?,
+            locks: self.locks.clone(),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            lock: LockKind::None,
+        })
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        std::fs::remove_file(self.dir.join(db))
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        Ok(self.dir.join(db).exists())
+    }
+
+    fn temporary_name(&self) -> String {
+        format!("temp-{}", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn random(&self, buffer: &mut [u8]) {
+        buffer.fill(4);
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        std::thread::sleep(duration);
+        duration
+    }
+}
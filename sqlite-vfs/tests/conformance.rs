@@ -0,0 +1,132 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{FsVfs, MemFile, MemVfs};
+use sqlite_vfs::conformance::{run_all, Category, ConformanceOptions, Grade, Outcome, SCENARIOS};
+use sqlite_vfs::sync_compat::{SyncDatabaseHandle, SyncVfs, SyncVfsAdapter};
+use sqlite_vfs::{LockKind, OpenOptions};
+
+fn without_wal() -> ConformanceOptions {
+    ConformanceOptions {
+        skip: vec![Category::Wal],
+    }
+}
+
+#[test]
+fn test_mem_backend_conforms() {
+    let report = run_all(|| SyncVfsAdapter::new(MemVfs::default()), &without_wal());
+    assert_eq!(report.grade(), Grade::Partial, "{report}");
+    assert_eq!(report.results.len(), SCENARIOS.len());
+    assert_eq!(
+        report.results.last().unwrap().outcome,
+        Outcome::Skipped,
+        "{report}"
+    );
+}
+
+#[test]
+fn test_fs_backend_conforms() {
+    let report = run_all(|| SyncVfsAdapter::new(FsVfs::temporary()), &without_wal());
+    assert_eq!(report.grade(), Grade::Partial, "{report}");
+}
+
+/// Grows files with 0xff instead of zeros.
+#[derive(Default)]
+struct DirtyGrowth(MemVfs);
+
+struct DirtyFile(MemFile);
+
+impl SyncDatabaseHandle for DirtyFile {
+    fn size(&self) -> Result<u64, std::io::Error> {
+        self.0.size()
+    }
+
+    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+        self.0.read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), std::io::Error> {
+        self.0.write_all_at(buf, offset)
+    }
+
+    fn sync(&mut self, data_only: bool) -> Result<(), std::io::Error> {
+        self.0.sync(data_only)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), std::io::Error> {
+        let current = self.0.size()?;
+        self.0.set_len(size)?;
+        if size > current {
+            self.0
+                .write_all_at(&vec![0xff; (size - current) as usize], current)?;
+        }
+        Ok(())
+    }
+
+    fn lock(&mut self, lock: LockKind) -> Result<bool, std::io::Error> {
+        self.0.lock(lock)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        self.0.reserved()
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        self.0.current_lock()
+    }
+}
+
+impl SyncVfs for DirtyGrowth {
+    type Handle = DirtyFile;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, std::io::Error> {
+        self.0.open(db, opts).map(DirtyFile)
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.0.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        self.0.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.0.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [u8]) {
+        self.0.random(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.0.sleep(duration)
+    }
+}
+
+#[test]
+fn test_report_failures() {
+    let report = run_all(
+        || SyncVfsAdapter::new(DirtyGrowth::default()),
+        &ConformanceOptions {
+            skip: vec![Category::Locking, Category::Wal],
+        },
+    );
+    assert_eq!(report.grade(), Grade::NonConformant);
+    assert_eq!(report.failed(), vec!["files.set-len"]);
+
+    let report = report.to_string();
+    assert!(
+        report.starts_with("VFS conformance: 8 passed, 1 failed, 3 skipped: not conformant\n"),
+        "{report}"
+    );
+    assert!(
+        report.contains(
+            "  FAIL  files.set-len                 set_len truncates a file, or grows it with \
+             zeros.\n                                      byte 10 is 0xff after growing, not 0\n"
+        ),
+        "{report}"
+    );
+    assert!(report.contains("  skip  locking.exclusion"), "{report}");
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::MemVfs;
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::fcntl::{self, FileControlCoverage};
+use sqlite_vfs::sync_compat::SyncVfsAdapter;
+
+#[test]
+fn test_coverage_report() {
+    let coverage = Arc::new(FileControlCoverage::default());
+    sqlite_vfs::register_instrumented(
+        "file-control",
+        SyncVfsAdapter::new(MemVfs::default()),
+        false,
+        coverage.clone(),
+    )
+    .unwrap()
+    .leak();
+
+    let conn = Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "file-control",
+    )
+    .unwrap();
+    conn.execute_batch(
+        "PRAGMA busy_timeout = 1000;
+         CREATE TABLE t (n INTEGER);
+         BEGIN;
+         INSERT INTO t VALUES (1);
+         INSERT INTO t VALUES (2);
+         COMMIT;",
+    )
+    .unwrap();
+    for n in 3..10 {
+        conn.execute("INSERT INTO t VALUES (?1)", [n]).unwrap();
+    }
+    let n: i64 = conn
+        .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(n, 9);
+    let mut errno = 0;
+    unsafe {
+        libsqlite3_sys::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            libsqlite3_sys::SQLITE_FCNTL_LAST_ERRNO,
+            &mut errno as *mut i32 as *mut _,
+        );
+        libsqlite3_sys::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            1000,
+            std::ptr::null_mut(),
+        );
+    }
+
+    let report = coverage.report();
+    let count = |name: &str| {
+        report
+            .iter()
+            .find(|(op, _)| op == name)
+            .map_or(0, |(_, count)| *count)
+    };
+    // every commit announces its sync and its second phase, every statement the busy handler
+    for op in [
+        "PRAGMA",
+        "BUSYHANDLER",
+        "SYNC",
+        "COMMIT_PHASETWO",
+        "LAST_ERRNO",
+    ] {
+        assert!(count(op) > 0, "{op} missing from {report:?}");
+    }
+    assert!(count("SYNC") >= 8, "{report:?}");
+    assert_eq!(count("unknown(1000)"), 1);
+    assert!(report
+        .iter()
+        .all(|(op, _)| op.starts_with("unknown(")
+            || fcntl::OPCODES.iter().any(|(_, name)| name == op)));
+}
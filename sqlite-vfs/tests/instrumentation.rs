@@ -0,0 +1,194 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use common::MemVfs;
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::clock::MockClock;
+use sqlite_vfs::instrument::{
+    CallbackDetails, CallbackKind, Instrumentation, Phase, RecordingInstrumentation,
+};
+use sqlite_vfs::sync_compat::SyncVfsAdapter;
+
+fn open(vfs: &str) -> Connection {
+    Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        vfs,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_commit_sequence() {
+    let rec = Arc::new(RecordingInstrumentation::default());
+    sqlite_vfs::register_instrumented(
+        "instrumented",
+        SyncVfsAdapter::new(MemVfs::default()),
+        false,
+        rec.clone(),
+    )
+    .unwrap()
+    .leak();
+
+    let conn = open("instrumented");
+    conn.execute_batch("CREATE TABLE t (n INTEGER)").unwrap();
+    rec.clear();
+    conn.execute("INSERT INTO t VALUES (1)", []).unwrap();
+
+    // the documented order of a rollback journal commit
+    rec.assert_sequence(&[
+        (CallbackKind::Lock, "main.db"),
+        (CallbackKind::Open, "main.db-journal"),
+        (CallbackKind::Write, "main.db-journal"),
+        (CallbackKind::Sync, "main.db-journal"),
+        (CallbackKind::Write, "main.db"),
+        (CallbackKind::Sync, "main.db"),
+        (CallbackKind::Close, "main.db-journal"),
+        (CallbackKind::Delete, "main.db-journal"),
+        (CallbackKind::Unlock, "main.db"),
+    ]);
+
+    // every callback is entered and exited in turn, writes and syncs succeeded
+    let events = rec.events_for("main.db");
+    assert!(!events.is_empty());
+    for pair in events.chunks(2) {
+        assert_eq!(pair[0].kind, pair[1].kind);
+        assert_eq!(pair[0].details.phase, Phase::Enter);
+        let Phase::Exit { rc, .. } = pair[1].details.phase else {
+            panic!("{:?}", pair[1]);
+        };
+        if matches!(pair[1].kind, CallbackKind::Write | CallbackKind::Sync) {
+            assert_eq!(rc, libsqlite3_sys::SQLITE_OK);
+        }
+    }
+    let write = events
+        .iter()
+        .find(|event| event.kind == CallbackKind::Write)
+        .unwrap();
+    assert!(write.details.offset.is_some() && write.details.len.is_some());
+}
+
+#[test]
+#[should_panic(expected = "expected xDelete(main.db-journal) (#1 of the sequence) did not follow")]
+fn test_sequence_mismatch() {
+    let rec = Arc::new(RecordingInstrumentation::default());
+    sqlite_vfs::register_instrumented(
+        "instrumented-mismatch",
+        SyncVfsAdapter::new(MemVfs::default()),
+        false,
+        rec.clone(),
+    )
+    .unwrap()
+    .leak();
+
+    let conn = open("instrumented-mismatch");
+    conn.execute_batch("CREATE TABLE t (n INTEGER)").unwrap();
+    // the journal is deleted before the database is closed, not after
+    drop(conn);
+    rec.assert_sequence(&[
+        (CallbackKind::Close, "main.db"),
+        (CallbackKind::Delete, "main.db-journal"),
+    ]);
+}
+
+#[test]
+fn test_mock_clock() {
+    let clock = MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let vfs = MemVfs {
+        clock: Some(clock.clone()),
+        ..MemVfs::default()
+    };
+    let locked_until = vfs.locked_until.clone();
+    let rec = Arc::new(RecordingInstrumentation::default());
+    sqlite_vfs::register_instrumented("mock-clock", SyncVfsAdapter::new(vfs), false, rec.clone())
+        .unwrap()
+        .leak();
+
+    let conn = open("mock-clock");
+    let now = || -> String {
+        conn.query_row("SELECT datetime('now')", [], |row| row.get(0))
+            .unwrap()
+    };
+    assert_eq!(now(), "2023-11-14 22:13:20");
+    clock.advance(Duration::from_secs(90));
+    assert_eq!(now(), "2023-11-14 22:14:50");
+
+    // another process holds the database for three seconds: SQLite's busy handler sleeps, which
+    // advances the clock until the lock is released, without any wall-clock waiting
+    conn.execute_batch("CREATE TABLE t (n INTEGER); PRAGMA busy_timeout = 10000")
+        .unwrap();
+    let released = clock.now() + Duration::from_secs(3);
+    *locked_until.lock().unwrap() = Some(released);
+    rec.clear();
+    let n: i64 = conn
+        .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(n, 0);
+    assert!(clock.now() >= released);
+
+    let sleeps = rec
+        .calls()
+        .iter()
+        .filter(|(kind, _)| *kind == CallbackKind::Sleep)
+        .count();
+    assert!(sleeps > 1, "{sleeps}");
+    assert!(rec.events_for("main.db").iter().any(|event| event.kind == CallbackKind::Lock
+        && matches!(event.details.phase, Phase::Exit { rc, .. } if rc == libsqlite3_sys::SQLITE_BUSY)));
+}
+
+/// Keeps the bytes of the writes and reads of `main.db`.
+#[derive(Default)]
+struct DataRecorder {
+    data: Mutex<Vec<(CallbackKind, u64, Vec<u8>)>>,
+}
+
+impl Instrumentation for DataRecorder {
+    fn on_callback(&self, _kind: CallbackKind, _db: &str, _details: CallbackDetails) {}
+
+    fn on_data(&self, kind: CallbackKind, db: &str, offset: u64, data: &[u8]) {
+        if db == "main.db" {
+            self.data
+                .lock()
+                .unwrap()
+                .push((kind, offset, data.to_vec()));
+        }
+    }
+}
+
+#[test]
+fn test_data_of_reads_and_writes() {
+    let rec = Arc::new(DataRecorder::default());
+    sqlite_vfs::register_instrumented(
+        "instrumented-data",
+        SyncVfsAdapter::new(MemVfs::default()),
+        false,
+        rec.clone(),
+    )
+    .unwrap()
+    .leak();
+
+    let conn = open("instrumented-data");
+    conn.execute_batch("CREATE TABLE t (s TEXT); INSERT INTO t VALUES ('needle in the page')")
+        .unwrap();
+    let contains = |data: &[u8]| data.windows(6).any(|w| w == b"needle");
+    let written = rec.data.lock().unwrap().clone();
+    let (_, offset, page) = written
+        .iter()
+        .find(|(kind, _, data)| *kind == CallbackKind::Write && contains(data))
+        .unwrap()
+        .clone();
+
+    // read back from the database rather than SQLite's page cache
+    drop(conn);
+    let conn = open("instrumented-data");
+    let s: String = conn
+        .query_row("SELECT s FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(s, "needle in the page");
+    let data = rec.data.lock().unwrap();
+    assert!(data
+        .iter()
+        .any(|(kind, at, data)| *kind == CallbackKind::Read && *at == offset && *data == page));
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use std::ffi::{c_char, c_int, CStr};
+
+use common::MemVfs;
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::sync_compat::SyncVfsAdapter;
+
+fn open(db: &str) -> Connection {
+    Connection::open_with_flags_and_vfs(
+        db,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "last-error",
+    )
+    .unwrap()
+}
+
+fn last_errno(conn: &Connection) -> c_int {
+    let mut errno: c_int = 0;
+    let rc = unsafe {
+        libsqlite3_sys::sqlite3_file_control(
+            conn.handle(),
+            c"main".as_ptr(),
+            libsqlite3_sys::SQLITE_FCNTL_LAST_ERRNO,
+            &mut errno as *mut c_int as *mut _,
+        )
+    };
+    assert_eq!(rc, libsqlite3_sys::SQLITE_OK);
+    errno
+}
+
+/// What `xGetLastError` reports for the VFS.
+fn vfs_last_error() -> (c_int, String) {
+    let (code, buf) = vfs_last_error_into(c"last-error", 256);
+    let msg = CStr::from_bytes_until_nul(&buf).unwrap();
+    (code, msg.to_string_lossy().into_owned())
+}
+
+/// What `xGetLastError` of the VFS `name` writes into a buffer of `len` bytes, which it is told
+/// is 1 byte shorter than it is.
+fn vfs_last_error_into(name: &CStr, len: usize) -> (c_int, Vec<u8>) {
+    let mut buf = vec![0xffu8; len + 1];
+    let code = unsafe {
+        let vfs = libsqlite3_sys::sqlite3_vfs_find(name.as_ptr());
+        (*vfs).xGetLastError.unwrap()(vfs, len as c_int, buf.as_mut_ptr() as *mut c_char)
+    };
+    assert_eq!(buf[len], 0xff, "written past the buffer");
+    buf.truncate(len);
+    (code, buf)
+}
+
+#[test]
+fn test_last_error_per_file() {
+    let vfs = MemVfs::default();
+    let failing = vfs.failing.clone();
+    sqlite_vfs::register("last-error", SyncVfsAdapter::new(vfs), false)
+        .unwrap()
+        .leak();
+
+    let a = open("a.db");
+    let b = open("b.db");
+    for conn in [&a, &b] {
+        conn.execute_batch(
+            "PRAGMA cache_size = 0; CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1);",
+        )
+        .unwrap();
+    }
+
+    failing.lock().unwrap().insert("a.db".to_owned());
+    assert!(a
+        .query_row("SELECT n FROM t", [], |row| row.get::<_, i64>(0))
+        .is_err());
+    let n: i64 = b
+        .query_row("SELECT n FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(n, 1);
+
+    assert_eq!(last_errno(&a), libsqlite3_sys::SQLITE_IOERR_READ);
+    assert_eq!(last_errno(&b), 0);
+
+    let (code, msg) = vfs_last_error();
+    assert_eq!(code, libsqlite3_sys::SQLITE_IOERR_READ);
+    assert!(msg.starts_with("a.db (file "), "{msg}");
+    assert!(msg.contains("injected read failure"), "{msg}");
+}
+
+#[test]
+fn test_last_error_truncated() {
+    let vfs = MemVfs::default();
+    let failing = vfs.failing.clone();
+    sqlite_vfs::register("last-error-truncated", SyncVfsAdapter::new(vfs), false)
+        .unwrap()
+        .leak();
+    let conn = Connection::open_with_flags_and_vfs(
+        "ünï.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "last-error-truncated",
+    )
+    .unwrap();
+    conn.execute_batch("PRAGMA cache_size = 0; CREATE TABLE t (n INTEGER);")
+        .unwrap();
+    failing.lock().unwrap().insert("ünï.db".to_owned());
+    assert!(conn
+        .query_row("SELECT count(*) FROM t", [], |row| row.get::<_, i64>(0))
+        .is_err());
+
+    let (code, full) = vfs_last_error_into(c"last-error-truncated", 256);
+    assert_eq!(code, libsqlite3_sys::SQLITE_IOERR_READ);
+    let full = CStr::from_bytes_until_nul(&full).unwrap().to_str().unwrap();
+    assert!(full.starts_with("ünï.db (file "), "{full}");
+    assert!(full.ends_with("injected read failure"), "{full}");
+
+    // `ü` and `ï` take 2 bytes each: every length cuts between characters, or before them
+    for len in 1..full.len() + 1 {
+        let (code, buf) = vfs_last_error_into(c"last-error-truncated", len);
+        assert_eq!(code, libsqlite3_sys::SQLITE_IOERR_READ, "{len}");
+        let msg = CStr::from_bytes_until_nul(&buf).unwrap().to_str().unwrap();
+        assert!(full.starts_with(msg), "{len}: {msg}");
+        assert!(msg.len() < len && msg.len() + 2 > len - 1, "{len}: {msg}");
+    }
+    let (_, buf) = vfs_last_error_into(c"last-error-truncated", 9);
+    assert_eq!(buf, b"\xc3\xbcn\xc3\xaf.db\0");
+    let (_, buf) = vfs_last_error_into(c"last-error-truncated", 2);
+    assert_eq!(buf, b"\0\xff");
+
+    // nothing to write into, but the code is still reported
+    let (code, buf) = vfs_last_error_into(c"last-error-truncated", 0);
+    assert_eq!((code, buf), (libsqlite3_sys::SQLITE_IOERR_READ, vec![]));
+}
@@ -0,0 +1,371 @@
+mod common;
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{MemFile, MemVfs};
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::error::Error;
+use sqlite_vfs::sync_compat::{SyncHandleAdapter, SyncVfsAdapter};
+use sqlite_vfs::wip::{WalIndex, WalIndexLock};
+use sqlite_vfs::{DatabaseHandle, LockKind, OpenOptions, Vfs};
+
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// The wal index of a database, shared by its connections.
+#[derive(Default)]
+struct Shm {
+    regions: HashMap<u32, Box<[u8; 32768]>>,
+    sequence: u64,
+    /// The holders of each lock slot, with whether they hold it exclusively.
+    locks: HashMap<u8, HashMap<usize, bool>>,
+}
+
+/// What the connections of a [ShmVfs] share: the wal indexes by database, and the deletions and
+/// `SQLITE_FCNTL_PERSIST_WAL` settings they received.
+#[derive(Clone, Default)]
+struct Backend {
+    shm: Arc<Mutex<HashMap<String, Shm>>>,
+    deleted_shm: Arc<Mutex<Vec<String>>>,
+    persist: Arc<Mutex<Vec<(String, bool)>>>,
+}
+
+/// A [MemVfs] with a shared memory wal index, so that SQLite can run in WAL mode with several
+/// connections. VFSes sharing a [Backend] and the files stand in for separate processes.
+struct ShmVfs {
+    mem: SyncVfsAdapter<MemVfs>,
+    backend: Backend,
+}
+
+struct ShmFile {
+    file: SyncHandleAdapter<MemFile>,
+    name: String,
+    backend: Backend,
+}
+
+struct ShmIndex {
+    db: String,
+    id: usize,
+    backend: Backend,
+}
+
+impl ShmIndex {
+    fn with<T>(&self, f: impl FnOnce(&mut Shm) -> T) -> T {
+        f(self
+            .backend
+            .shm
+            .lock()
+            .unwrap()
+            .entry(self.db.clone())
+            .or_default())
+    }
+}
+
+impl WalIndex for ShmIndex {
+    fn map<H: DatabaseHandle>(&mut self, region: u32) -> Result<[u8; 32768], Error<H::Error>> {
+        Ok(self.with(|shm| **shm.regions.entry(region).or_insert(Box::new([0; 32768]))))
+    }
+
+    fn lock<H: DatabaseHandle>(
+        &mut self,
+        locks: Range<u8>,
+        lock: WalIndexLock,
+    ) -> Result<bool, Error<H::Error>> {
+        let id = self.id;
+        Ok(self.with(|shm| {
+            let others = |slot: u8, exclusive_only: bool| {
+                shm.locks.get(&slot).is_some_and(|holders| {
+                    holders
+                        .iter()
+                        .any(|(holder, exclusive)| *holder != id && (*exclusive || !exclusive_only))
+                })
+            };
+            let free = match lock {
+                WalIndexLock::None => true,
+                WalIndexLock::Shared => locks.clone().all(|slot| !others(slot, true)),
+                WalIndexLock::Exclusive => locks.clone().all(|slot| !others(slot, false)),
+            };
+            if free {
+                for slot in locks {
+                    let holders = shm.locks.entry(slot).or_default();
+                    match lock {
+                        WalIndexLock::None => holders.remove(&id),
+                        _ => holders.insert(id, lock == WalIndexLock::Exclusive),
+                    };
+                }
+            }
+            free
+        }))
+    }
+
+    fn delete<H: DatabaseHandle>(self) -> Result<(), Error<H::Error>> {
+        self.backend.shm.lock().unwrap().remove(&self.db);
+        self.backend
+            .deleted_shm
+            .lock()
+            .unwrap()
+            .push(self.db.clone());
+        Ok(())
+    }
+
+    fn pull<H: DatabaseHandle>(
+        &mut self,
+        region: u32,
+        data: &mut [u8; 32768],
+    ) -> Result<(), Error<H::Error>> {
+        self.with(|shm| {
+            if let Some(pushed) = shm.regions.get(&region) {
+                *data = **pushed;
+            }
+        });
+        Ok(())
+    }
+
+    fn push<H: DatabaseHandle>(
+        &mut self,
+        region: u32,
+        data: &[u8; 32768],
+    ) -> Result<(), Error<H::Error>> {
+        self.with(|shm| shm.regions.insert(region, Box::new(*data)));
+        Ok(())
+    }
+
+    fn sequence<H: DatabaseHandle>(&mut self) -> Result<u64, Error<H::Error>> {
+        Ok(self.with(|shm| shm.sequence))
+    }
+
+    fn set_sequence<H: DatabaseHandle>(&mut self, sequence: u64) -> Result<(), Error<H::Error>> {
+        self.with(|shm| shm.sequence = sequence);
+        Ok(())
+    }
+}
+
+impl Drop for ShmIndex {
+    fn drop(&mut self) {
+        if let Some(shm) = self.backend.shm.lock().unwrap().get_mut(&self.db) {
+            for holders in shm.locks.values_mut() {
+                holders.remove(&self.id);
+            }
+        }
+    }
+}
+
+impl DatabaseHandle for ShmFile {
+    type WalIndex = ShmIndex;
+    type Error = std::io::Error;
+
+    async fn size(&mut self) -> Result<u64, Error<Self::Error>> {
+        self.file.size().await
+    }
+
+    async fn read_exact_at(
+        &mut self,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> Result<(), Error<Self::Error>> {
+        self.file.read_exact_at(buf, offset).await
+    }
+
+    async fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error<Self::Error>> {
+        self.file.write_all_at(buf, offset).await
+    }
+
+    async fn sync(&mut self, data_only: bool) -> Result<(), Error<Self::Error>> {
+        self.file.sync(data_only).await
+    }
+
+    async fn set_len(&mut self, size: u64) -> Result<(), Error<Self::Error>> {
+        self.file.set_len(size).await
+    }
+
+    async fn lock(&mut self, lock: LockKind) -> Result<bool, Error<Self::Error>> {
+        self.file.lock(lock).await
+    }
+
+    async fn reserved(&mut self) -> Result<bool, Error<Self::Error>> {
+        self.file.reserved().await
+    }
+
+    async fn current_lock(&self) -> Result<LockKind, Error<Self::Error>> {
+        self.file.current_lock().await
+    }
+
+    fn set_persist_wal(&mut self, persist: bool) {
+        let setting = (self.name.clone(), persist);
+        self.backend.persist.lock().unwrap().push(setting);
+    }
+
+    async fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, Error<Self::Error>> {
+        Ok(ShmIndex {
+            db: self.name.clone(),
+            id: NEXT_INDEX.fetch_add(1, Ordering::Relaxed),
+            backend: self.backend.clone(),
+        })
+    }
+}
+
+impl Vfs for ShmVfs {
+    type Handle = ShmFile;
+    type Error = std::io::Error;
+
+    async fn open(&self, db: &str, opts: OpenOptions) -> Result<Self::Handle, Error<Self::Error>> {
+        Ok(ShmFile {
+            file: self.mem.open(db, opts).await?,
+            name: db.to_owned(),
+            backend: self.backend.clone(),
+        })
+    }
+
+    async fn delete(&self, db: &str) -> Result<(), Error<Self::Error>> {
+        self.mem.delete(db).await
+    }
+
+    async fn exists(&self, db: &str) -> Result<bool, Error<Self::Error>> {
+        self.mem.exists(db).await
+    }
+
+    async fn temporary_name(&self) -> String {
+        self.mem.temporary_name().await
+    }
+
+    async fn random(&self, buffer: &mut [u8]) {
+        self.mem.random(buffer).await
+    }
+
+    async fn sleep(&self, duration: Duration) -> Duration {
+        self.mem.sleep(duration).await
+    }
+
+    async fn full_pathname<'a>(&self, db: &'a str) -> Result<Cow<'a, str>, Error<Self::Error>> {
+        self.mem.full_pathname(db).await
+    }
+}
+
+/// Register a VFS as `name` on `mem`'s files and `backend`'s wal indexes.
+fn register(name: &str, mem: &MemVfs, backend: &Backend) {
+    let vfs = ShmVfs {
+        mem: SyncVfsAdapter::new(MemVfs {
+            files: mem.files.clone(),
+            locks: mem.locks.clone(),
+            ..MemVfs::default()
+        }),
+        backend: backend.clone(),
+    };
+    sqlite_vfs::register(name, vfs, false).unwrap().leak();
+}
+
+fn open(vfs: &str) -> Connection {
+    Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        vfs,
+    )
+    .unwrap()
+}
+
+/// Which other connection is open while the first one closes.
+#[derive(Clone, Copy, Debug)]
+enum Peer {
+    /// None: the first one is the last closer.
+    None,
+    /// One of the same VFS.
+    SameProcess,
+    /// One of another VFS on the same files, as another process.
+    OtherProcess,
+}
+
+/// The behavior matrix of `SQLITE_FCNTL_PERSIST_WAL`: the WAL and its index only go away when
+/// the last connection closes without persisting them, and another connection that is still open
+/// keeps reading the database either way.
+#[test]
+fn test_persist_wal_matrix() {
+    let mem = MemVfs::default();
+    let backend = Backend::default();
+    register("persist-wal", &mem, &backend);
+    register("persist-wal-other", &mem, &backend);
+
+    for persist in [false, true] {
+        for other in [Peer::None, Peer::SameProcess, Peer::OtherProcess] {
+            let case = format!("persist {persist}, other connection {other:?}");
+            mem.files.lock().unwrap().clear();
+            backend.shm.lock().unwrap().clear();
+            backend.deleted_shm.lock().unwrap().clear();
+            backend.persist.lock().unwrap().clear();
+
+            let conn = open("persist-wal");
+            let mode: String = conn
+                .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(mode, "wal", "{case}");
+            conn.execute_batch("CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1);")
+                .unwrap();
+            let mut value = persist as i32;
+            let rc = unsafe {
+                libsqlite3_sys::sqlite3_file_control(
+                    conn.handle(),
+                    c"main".as_ptr(),
+                    libsqlite3_sys::SQLITE_FCNTL_PERSIST_WAL,
+                    &mut value as *mut i32 as *mut _,
+                )
+            };
+            assert_eq!(rc, libsqlite3_sys::SQLITE_OK, "{case}");
+
+            let other = match other {
+                Peer::None => None,
+                Peer::SameProcess => Some(open("persist-wal")),
+                Peer::OtherProcess => Some(open("persist-wal-other")),
+            };
+            if let Some(other) = &other {
+                let n: i64 = other
+                    .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(n, 1, "{case}");
+            }
+            conn.close().unwrap();
+
+            assert!(
+                backend
+                    .persist
+                    .lock()
+                    .unwrap()
+                    .contains(&("main.db".to_owned(), persist)),
+                "{case}"
+            );
+            let wal_kept = mem.files.lock().unwrap().contains_key("main.db-wal");
+            let shm_deleted = !backend.deleted_shm.lock().unwrap().is_empty();
+            match &other {
+                // the last closer checkpoints, and only keeps the WAL and its index if asked to
+                None => {
+                    assert_eq!(wal_kept, persist, "{case}");
+                    assert_eq!(shm_deleted, !persist, "{case}");
+                }
+                // the other connection still needs them
+                Some(other) => {
+                    assert!(wal_kept && !shm_deleted, "{case}");
+                    other.execute("INSERT INTO t VALUES (2)", []).unwrap();
+                    let n: i64 = other
+                        .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                        .unwrap();
+                    assert_eq!(n, 2, "{case}");
+                }
+            }
+            if let Some(other) = other {
+                // without the setting, which is per connection, the last closer deletes them
+                other.close().unwrap();
+                assert!(
+                    !mem.files.lock().unwrap().contains_key("main.db-wal"),
+                    "{case}"
+                );
+                assert!(!backend.deleted_shm.lock().unwrap().is_empty(), "{case}");
+            }
+            let files = mem.files.lock().unwrap();
+            let mut names: HashSet<_> = files.keys().map(String::as_str).collect();
+            names.remove("main.db-wal");
+            assert_eq!(names, HashSet::from(["main.db"]), "{case}");
+        }
+    }
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use common::{MemFile, MemVfs};
+use sqlite_vfs::sync_compat::{SyncVfs, SyncVfsAdapter};
+use sqlite_vfs::OpenOptions;
+
+/// A [MemVfs] filling each buffer with the number of the call.
+#[derive(Default)]
+struct CountingVfs {
+    mem: MemVfs,
+    calls: AtomicU8,
+}
+
+impl SyncVfs for CountingVfs {
+    type Handle = MemFile;
+
+    fn open(&self, db: &str, opts: OpenOptions) -> Result<MemFile, std::io::Error> {
+        self.mem.open(db, opts)
+    }
+
+    fn delete(&self, db: &str) -> Result<(), std::io::Error> {
+        self.mem.delete(db)
+    }
+
+    fn exists(&self, db: &str) -> Result<bool, std::io::Error> {
+        self.mem.exists(db)
+    }
+
+    fn temporary_name(&self) -> String {
+        self.mem.temporary_name()
+    }
+
+    fn random(&self, buffer: &mut [u8]) {
+        buffer.fill(self.calls.fetch_add(1, Ordering::Relaxed) + 1);
+    }
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        self.mem.sleep(duration)
+    }
+}
+
+/// Call the `xRandomness` of the VFS registered as `name`, on a buffer of `len` bytes of which
+/// `n_byte` are asked for.
+fn randomness(name: &std::ffi::CStr, len: usize, n_byte: i32) -> (i32, Vec<u8>) {
+    let mut buf = vec![0u8; len];
+    let n = unsafe {
+        let vfs = libsqlite3_sys::sqlite3_vfs_find(name.as_ptr());
+        assert!(!vfs.is_null());
+        let randomness = (*vfs).xRandomness.unwrap();
+        randomness(vfs, n_byte, buf.as_mut_ptr() as *mut _)
+    };
+    (n, buf)
+}
+
+// the test build of SQLite is given zeroes instead, for repeatability
+#[cfg(not(feature = "sqlite_test"))]
+#[test]
+fn test_randomness_fills_bytes() {
+    let vfs = SyncVfsAdapter::new(CountingVfs::default());
+    sqlite_vfs::register("randomness", vfs, false)
+        .unwrap()
+        .leak();
+
+    let (n, first) = randomness(c"randomness", 64, 48);
+    assert_eq!(n, 48);
+    assert!(first[..48].iter().all(|byte| *byte != 0));
+    assert!(first[48..].iter().all(|byte| *byte == 0));
+    let (n, second) = randomness(c"randomness", 64, 64);
+    assert_eq!(n, 64);
+    assert_ne!(first[..48], second[..48]);
+
+    // nothing asked for, or a length SQLite never passes
+    assert_eq!(randomness(c"randomness", 8, 0), (0, vec![0; 8]));
+    assert_eq!(randomness(c"randomness", 8, -1), (0, vec![0; 8]));
+    let n = unsafe {
+        let vfs = libsqlite3_sys::sqlite3_vfs_find(c"randomness".as_ptr());
+        ((*vfs).xRandomness.unwrap())(vfs, 8, std::ptr::null_mut())
+    };
+    assert_eq!(n, 0);
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_random_as_i8() {
+    let mut bytes = [0u8; 2];
+    sqlite_vfs::legacy::random_as_i8(&mut bytes).copy_from_slice(&[-1, 1]);
+    assert_eq!(bytes, [255, 1]);
+}
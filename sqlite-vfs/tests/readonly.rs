@@ -0,0 +1,78 @@
+mod common;
+
+use common::MemVfs;
+use rusqlite::{Connection, DatabaseName, ErrorCode, OpenFlags};
+use sqlite_vfs::sync_compat::SyncVfsAdapter;
+
+#[test]
+fn test_open_readonly_without_write_permission() {
+    let writable = MemVfs::default();
+    let readonly = MemVfs {
+        files: writable.files.clone(),
+        readonly: true,
+        ..MemVfs::default()
+    };
+    sqlite_vfs::register("ro-writable", SyncVfsAdapter::new(writable), false)
+        .unwrap()
+        .leak();
+    sqlite_vfs::register("ro-readonly", SyncVfsAdapter::new(readonly), false)
+        .unwrap()
+        .leak();
+
+    let conn = Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "ro-writable",
+    )
+    .unwrap();
+    conn.execute_batch("CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1), (2);")
+        .unwrap();
+    drop(conn);
+
+    // asking for read-write access falls back to a read-only connection
+    let conn = Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE,
+        "ro-readonly",
+    )
+    .unwrap();
+    assert!(conn.is_readonly(DatabaseName::Main).unwrap());
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 2);
+
+    let err = conn.execute("INSERT INTO t VALUES (3)", []).unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::ReadOnly));
+}
+
+#[test]
+fn test_journal_existence_unknown() {
+    let vfs = MemVfs::default();
+    let (denied, failing) = (vfs.denied.clone(), vfs.failing.clone());
+    sqlite_vfs::register("ro-journal", SyncVfsAdapter::new(vfs), false)
+        .unwrap()
+        .leak();
+    let conn = Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "ro-journal",
+    )
+    .unwrap();
+    // keeps the lock, so that the existence of the journal is only checked on creating it
+    conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE; SELECT * FROM sqlite_master;")
+        .unwrap();
+    denied.lock().unwrap().insert("main.db-journal".to_owned());
+
+    // a journal known to be missing can't be created in the directory
+    let err = conn.execute("CREATE TABLE t (n INTEGER)", []).unwrap_err();
+    assert_eq!(
+        err.sqlite_error().map(|err| err.extended_code),
+        Some(libsqlite3_sys::SQLITE_READONLY_DIRECTORY)
+    );
+
+    // one whose existence can't be checked isn't taken for missing
+    failing.lock().unwrap().insert("main.db-journal".to_owned());
+    let err = conn.execute("CREATE TABLE t (n INTEGER)", []).unwrap_err();
+    assert_eq!(err.sqlite_error_code(), Some(ErrorCode::CannotOpen));
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use common::MemVfs;
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::sync_compat::SyncVfsAdapter;
+
+/// The size of the journal a transaction updating one page leaves behind, with `sector_size`
+/// answered to `xSectorSize` and powersafe overwrites advertised or not.
+fn journal_len(vfs_name: &str, sector_size: Option<u32>, psow: bool) -> usize {
+    let vfs = MemVfs {
+        sector_size,
+        ..MemVfs::default()
+    };
+    let files = vfs.files.clone();
+    sqlite_vfs::register(vfs_name, SyncVfsAdapter::new(vfs), false)
+        .unwrap()
+        .leak();
+
+    let conn = Connection::open_with_flags_and_vfs(
+        format!("file:main.db?psow={}", psow as u8),
+        OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_URI,
+        vfs_name,
+    )
+    .unwrap();
+    conn.execute_batch(
+        "PRAGMA page_size = 4096;
+         PRAGMA journal_mode = PERSIST;
+         CREATE TABLE t (n INTEGER);
+         INSERT INTO t VALUES (1);",
+    )
+    .unwrap();
+    // the journal of the last transaction alone, which rewrote page 1 for the change counter and
+    // page 2 for the row
+    files
+        .lock()
+        .unwrap()
+        .insert("main.db-journal".into(), Vec::new());
+    conn.execute("UPDATE t SET n = 2", []).unwrap();
+    let len = files.lock().unwrap()["main.db-journal"].len();
+    len
+}
+
+#[test]
+fn test_journal_header_padded_to_sector() {
+    // a header padded to a sector, then each page with its number and checksum
+    let records = 2 * (4 + 4096 + 4);
+    assert_eq!(journal_len("sector-default", None, true), 512 + records);
+    assert_eq!(journal_len("sector-512", Some(512), false), 512 + records);
+    assert_eq!(journal_len("sector-4k", Some(4096), false), 4096 + records);
+    assert_eq!(
+        journal_len("sector-64k", Some(65536), false),
+        65536 + records,
+        "the largest sector bloats every journal by 64 KiB"
+    );
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common::MemVfs;
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::sync_compat::SyncVfsAdapter;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::Layer;
+
+/// Records the target and level of every event.
+#[derive(Clone, Default)]
+struct Recorder {
+    events: Arc<Mutex<Vec<(String, Level)>>>,
+}
+
+impl Recorder {
+    fn saw(&self, target: &str, level: Level) -> bool {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(t, l)| t == target && *l == level)
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Recorder {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        self.events
+            .lock()
+            .unwrap()
+            .push((meta.target().to_owned(), *meta.level()));
+    }
+}
+
+fn workload(vfs: &str, rows: usize) -> Connection {
+    let conn = Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        vfs,
+    )
+    .unwrap();
+    conn.execute_batch("CREATE TABLE t (n INTEGER PRIMARY KEY, s TEXT)")
+        .unwrap();
+    let txn = conn.unchecked_transaction().unwrap();
+    for n in 0..rows {
+        txn.execute("INSERT INTO t VALUES (?1, ?2)", (n, "x".repeat(1024)))
+            .unwrap();
+    }
+    txn.commit().unwrap();
+    conn
+}
+
+#[test]
+fn test_targets_and_levels() {
+    let recorder = Recorder::default();
+    let subscriber = tracing_subscriber::registry().with(recorder.clone());
+
+    tracing::subscriber::with_default(subscriber, || {
+        sqlite_vfs::register("trace-mem", SyncVfsAdapter::new(MemVfs::default()), false)
+            .unwrap()
+            .leak();
+        let conn = workload("trace-mem", 10);
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 10);
+    });
+
+    let expected = [
+        ("sqlite_vfs::vfs", Level::INFO),
+        ("sqlite_vfs::vfs", Level::DEBUG),
+        ("sqlite_vfs::io::read", Level::TRACE),
+        ("sqlite_vfs::io::write", Level::TRACE),
+        ("sqlite_vfs::io", Level::TRACE),
+        ("sqlite_vfs::io", Level::DEBUG),
+        ("sqlite_vfs::lock", Level::TRACE),
+        ("sqlite_vfs::lock", Level::DEBUG),
+    ];
+    for (target, level) in expected {
+        assert!(recorder.saw(target, level), "no {level} event at {target}");
+    }
+
+    // reads and writes are only ever traced
+    for (target, level) in recorder.events.lock().unwrap().iter() {
+        if target.starts_with("sqlite_vfs::io::") {
+            assert_eq!(*level, Level::TRACE, "{target}");
+        }
+    }
+}
+
+/// Compares scanning a table with disabled `TRACE` events against running without any subscriber,
+/// in which case every event is rejected by a single level comparison, just like events compiled
+/// out by `tracing/max_level_*`. Timing-sensitive, so run it explicitly with
+/// `cargo test --release --test tracing -- --ignored`.
+#[test]
+#[ignore]
+fn bench_disabled_read_overhead() {
+    sqlite_vfs::register("bench-mem", SyncVfsAdapter::new(MemVfs::default()), false)
+        .unwrap()
+        .leak();
+    let conn = workload("bench-mem", 2000);
+    // keep the page cache small so that every scan reads through the VFS
+    conn.execute_batch("PRAGMA cache_size = 10").unwrap();
+
+    let scan = || {
+        let start = Instant::now();
+        for _ in 0..20 {
+            let len: i64 = conn
+                .query_row("SELECT SUM(LENGTH(s)) FROM t", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(len, 2000 * 1024);
+        }
+        start.elapsed()
+    };
+    let median = |f: &dyn Fn() -> Duration| {
+        let mut runs = (0..7).map(|_| f()).collect::<Vec<_>>();
+        runs.sort();
+        runs[runs.len() / 2]
+    };
+
+    let baseline = median(&scan);
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(Level::INFO)
+        .with_writer(std::io::sink)
+        .finish();
+    let disabled = tracing::subscriber::with_default(subscriber, || median(&scan));
+
+    let ratio = disabled.as_secs_f64() / baseline.as_secs_f64();
+    println!("baseline {baseline:?}, disabled tracing {disabled:?} ({ratio:.3}x)");
+    assert!(ratio < 1.1, "disabled tracing costs {ratio:.3}x");
+}
@@ -0,0 +1,5 @@
+#![deny(deprecated)]
+
+fn takes_old_vfs<V: sqlite_vfs::legacy::Vfs>(_vfs: V) {}
+
+fn main() {}
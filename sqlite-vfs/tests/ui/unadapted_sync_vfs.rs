@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use sqlite_vfs::sync_compat::{SyncDatabaseHandle, SyncVfs};
+use sqlite_vfs::{LockKind, OpenOptions};
+
+struct File;
+
+impl SyncDatabaseHandle for File {
+    fn size(&self) -> Result<u64, std::io::Error> {
+        Ok(0)
+    }
+
+    fn read_exact_at(&mut self, _buf: &mut [u8], _offset: u64) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, _buf: &[u8], _offset: u64) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn sync(&mut self, _data_only: bool) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, _size: u64) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn lock(&mut self, _lock: LockKind) -> Result<bool, std::io::Error> {
+        Ok(true)
+    }
+
+    fn reserved(&mut self) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    fn current_lock(&self) -> Result<LockKind, std::io::Error> {
+        Ok(LockKind::None)
+    }
+}
+
+struct OldVfs;
+
+impl SyncVfs for OldVfs {
+    type Handle = File;
+
+    fn open(&self, _db: &str, _opts: OpenOptions) -> Result<File, std::io::Error> {
+        Ok(File)
+    }
+
+    fn delete(&self, _db: &str) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn exists(&self, _db: &str) -> Result<bool, std::io::Error> {
+        Ok(false)
+    }
+
+    fn temporary_name(&self) -> String {
+        String::new()
+    }
+
+    fn random(&self, _buffer: &mut [u8]) {}
+
+    fn sleep(&self, duration: Duration) -> Duration {
+        duration
+    }
+}
+
+fn main() {
+    sqlite_vfs::register("old", OldVfs, false).unwrap();
+}
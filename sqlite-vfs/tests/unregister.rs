@@ -0,0 +1,149 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use common::MemVfs;
+use rusqlite::{Connection, OpenFlags};
+use sqlite_vfs::clock::MockClock;
+use sqlite_vfs::sync_compat::SyncVfsAdapter;
+
+fn open(vfs: &str) -> rusqlite::Result<Connection> {
+    Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        vfs,
+    )
+}
+
+#[test]
+fn test_open_connections_outlive_unregistering() {
+    let vfs = MemVfs::default();
+    let files = vfs.files.clone();
+    sqlite_vfs::register("unregister", SyncVfsAdapter::new(vfs), false)
+        .unwrap()
+        .leak();
+    let conn = open("unregister").unwrap();
+    conn.execute_batch("CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1);")
+        .unwrap();
+
+    assert!(sqlite_vfs::unregister("unregister").unwrap());
+    assert!(sqlite_vfs::vfs_stats("unregister").is_none());
+    assert!(open("unregister").is_err());
+    assert!(!sqlite_vfs::unregister("unregister").unwrap());
+
+    // the connection still reads and writes through it
+    conn.execute("INSERT INTO t VALUES (2)", []).unwrap();
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 2);
+    drop(conn);
+
+    // and the name can be registered again
+    let vfs = MemVfs {
+        files,
+        ..MemVfs::default()
+    };
+    sqlite_vfs::register("unregister", SyncVfsAdapter::new(vfs), false)
+        .unwrap()
+        .leak();
+    let conn = open("unregister").unwrap();
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn test_unregister_unknown() {
+    assert!(!sqlite_vfs::unregister("never-registered").unwrap());
+    // not registered by this crate
+    assert!(!sqlite_vfs::unregister("unix").unwrap());
+}
+
+#[test]
+fn test_register_unregister_loop_frees_state() {
+    let files = MemVfs::default().files;
+    for i in 0..100 {
+        let name = format!("loop-{i}");
+        let vfs = MemVfs {
+            files: files.clone(),
+            ..MemVfs::default()
+        };
+        let handle = sqlite_vfs::register(&name, SyncVfsAdapter::new(vfs), false).unwrap();
+        assert_eq!(handle.name(), name);
+        let conn = open(&name).unwrap();
+        conn.execute_batch("CREATE TABLE IF NOT EXISTS t (n INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+        drop(conn);
+
+        match i % 2 {
+            0 => handle.unregister().unwrap(),
+            _ => drop(handle),
+        }
+        assert!(sqlite_vfs::vfs_stats(&name).is_none());
+        assert!(open(&name).is_err());
+        // the VFS held the only other reference
+        assert_eq!(Arc::strong_count(&files), 1);
+    }
+}
+
+#[test]
+fn test_dropped_while_open_frees_once_closed() {
+    let vfs = MemVfs::default();
+    let files = vfs.files.clone();
+    let handle = sqlite_vfs::register("deferred", SyncVfsAdapter::new(vfs), false).unwrap();
+    let conn = open("deferred").unwrap();
+    conn.execute_batch("CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1);")
+        .unwrap();
+
+    drop(handle);
+    assert!(open("deferred").is_err());
+    // still allocated for the connection, which keeps working
+    conn.execute("INSERT INTO t VALUES (2)", []).unwrap();
+    assert!(Arc::strong_count(&files) > 1);
+
+    // freed by the next registration once closed
+    drop(conn);
+    sqlite_vfs::register(
+        "deferred-next",
+        SyncVfsAdapter::new(MemVfs::default()),
+        false,
+    )
+    .unwrap()
+    .unregister()
+    .unwrap();
+    assert_eq!(Arc::strong_count(&files), 1);
+}
+
+#[test]
+fn test_memory_connections_outlive_the_state() {
+    let clock = MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let vfs = MemVfs {
+        clock: Some(clock),
+        ..MemVfs::default()
+    };
+    let files = vfs.files.clone();
+    let handle = sqlite_vfs::register("in-memory", SyncVfsAdapter::new(vfs), false).unwrap();
+    let conn = Connection::open_with_flags_and_vfs(
+        ":memory:",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "in-memory",
+    )
+    .unwrap();
+    let now = || -> String {
+        conn.query_row("SELECT datetime('now')", [], |row| row.get(0))
+            .unwrap()
+    };
+    assert_eq!(now(), "2023-11-14 22:13:20");
+
+    // no file is open, so the state is freed right away
+    drop(handle);
+    assert_eq!(Arc::strong_count(&files), 1);
+
+    // while the connection still reads the time through the VFS, from the system clock now
+    assert!(now().as_str() > "2024", "{}", now());
+    conn.execute_batch("CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1);")
+        .unwrap();
+}
@@ -0,0 +1,270 @@
+mod common;
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use common::MemFile;
+use sqlite_vfs::error::Error;
+use sqlite_vfs::sync_compat::SyncHandleAdapter;
+use sqlite_vfs::wip::{WalIndex, WalIndexLock, REFRESH_ATTEMPTS};
+use sqlite_vfs::DatabaseHandle;
+
+type Handle = SyncHandleAdapter<MemFile>;
+type Region = Box<[u8; 32768]>;
+
+/// A write to the backing index.
+#[derive(Clone)]
+enum Op {
+    Sequence(u64),
+    Push(u32, Region),
+}
+
+/// The backing index shared by the connections, logging the writes it receives.
+#[derive(Clone, Default)]
+struct Remote {
+    sequence: u64,
+    regions: HashMap<u32, Region>,
+    log: Vec<Op>,
+}
+
+impl Remote {
+    fn apply(&mut self, op: Op) {
+        match &op {
+            Op::Sequence(sequence) => self.sequence = *sequence,
+            Op::Push(region, data) => {
+                self.regions.insert(*region, data.clone());
+            }
+        }
+        self.log.push(op);
+    }
+}
+
+/// A connection to a [Remote], which applies the writes of another connection in between its own
+/// reads: `schedule[n]` of them right before its `n`th read.
+#[derive(Default)]
+struct ScriptedIndex {
+    remote: Arc<Mutex<Remote>>,
+    writer: VecDeque<Op>,
+    schedule: Vec<usize>,
+    reads: usize,
+}
+
+impl ScriptedIndex {
+    fn new(remote: &Arc<Mutex<Remote>>) -> Self {
+        Self {
+            remote: remote.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn interleave(&mut self) {
+        let count = self.schedule.get(self.reads).copied().unwrap_or(0);
+        self.reads += 1;
+        let mut remote = self.remote.lock().unwrap();
+        for op in self.writer.drain(..count.min(self.writer.len())) {
+            remote.apply(op);
+        }
+    }
+}
+
+impl WalIndex for ScriptedIndex {
+    fn map<H: DatabaseHandle>(&mut self, _region: u32) -> Result<[u8; 32768], Error<H::Error>> {
+        Ok([0; 32768])
+    }
+
+    fn lock<H: DatabaseHandle>(
+        &mut self,
+        _locks: Range<u8>,
+        _lock: WalIndexLock,
+    ) -> Result<bool, Error<H::Error>> {
+        Ok(true)
+    }
+
+    fn delete<H: DatabaseHandle>(self) -> Result<(), Error<H::Error>> {
+        Ok(())
+    }
+
+    fn pull<H: DatabaseHandle>(
+        &mut self,
+        region: u32,
+        data: &mut [u8; 32768],
+    ) -> Result<(), Error<H::Error>> {
+        self.interleave();
+        if let Some(pushed) = self.remote.lock().unwrap().regions.get(&region) {
+            *data = **pushed;
+        }
+        Ok(())
+    }
+
+    fn push<H: DatabaseHandle>(
+        &mut self,
+        region: u32,
+        data: &[u8; 32768],
+    ) -> Result<(), Error<H::Error>> {
+        let op = Op::Push(region, Box::new(*data));
+        self.remote.lock().unwrap().apply(op);
+        Ok(())
+    }
+
+    fn sequence<H: DatabaseHandle>(&mut self) -> Result<u64, Error<H::Error>> {
+        self.interleave();
+        Ok(self.remote.lock().unwrap().sequence)
+    }
+
+    fn set_sequence<H: DatabaseHandle>(&mut self, sequence: u64) -> Result<(), Error<H::Error>> {
+        self.remote.lock().unwrap().apply(Op::Sequence(sequence));
+        Ok(())
+    }
+}
+
+/// Publish two regions filled with `fill` to `remote`.
+fn publish(remote: &Arc<Mutex<Remote>>, fill: u8) -> u64 {
+    let data = [fill; 32768];
+    ScriptedIndex::new(remote)
+        .publish::<Handle>([(0, &data), (1, &data)])
+        .unwrap()
+}
+
+/// Refresh two regions from `index`, returning the sequence and the fill of each region.
+fn refresh(index: &mut ScriptedIndex) -> Result<(u64, [u8; 2]), Error<std::io::Error>> {
+    let (mut first, mut second) = ([0xff; 32768], [0xff; 32768]);
+    let result = index.refresh::<Handle>(&mut [(0, &mut first), (1, &mut second)]);
+    assert!(first.iter().all(|byte| *byte == first[0]), "torn region");
+    assert!(second.iter().all(|byte| *byte == second[0]), "torn region");
+    result.map(|sequence| (sequence, [first[0], second[0]]))
+}
+
+/// The nondecreasing positions, out of `positions`, of `ops` writes.
+fn schedules(ops: usize, positions: usize) -> Vec<Vec<usize>> {
+    if ops == 0 {
+        return vec![vec![0; positions]];
+    }
+    let mut all = Vec::new();
+    for position in 0..positions {
+        for mut rest in schedules(ops - 1, positions - position) {
+            let mut schedule = vec![0; position];
+            rest[0] += 1;
+            schedule.append(&mut rest);
+            all.push(schedule);
+        }
+    }
+    all
+}
+
+#[test]
+fn test_publish_writes_sequence_last() {
+    let remote = Arc::new(Mutex::new(Remote::default()));
+    assert_eq!(publish(&remote, 1), 2);
+    assert_eq!(publish(&remote, 2), 4);
+    let remote = remote.lock().unwrap();
+    let log: Vec<_> = remote
+        .log
+        .iter()
+        .map(|op| match op {
+            Op::Sequence(sequence) => format!("sequence {sequence}"),
+            Op::Push(region, data) => format!("push {region}={}", data[0]),
+        })
+        .collect();
+    assert_eq!(
+        log,
+        [
+            "sequence 1",
+            "push 0=1",
+            "push 1=1",
+            "sequence 2",
+            "sequence 3",
+            "push 0=2",
+            "push 1=2",
+            "sequence 4",
+        ]
+    );
+}
+
+#[test]
+fn test_no_mixed_snapshot() {
+    let remote = Arc::new(Mutex::new(Remote::default()));
+    publish(&remote, 1);
+
+    // the writes of the next publication
+    let mut recorded = remote.lock().unwrap().clone();
+    recorded.log.clear();
+    let recorded = Arc::new(Mutex::new(recorded));
+    publish(&recorded, 2);
+    let ops = recorded.lock().unwrap().log.clone();
+    assert_eq!(ops.len(), 4);
+
+    // each way of interleaving them with the reads of two attempts and a half
+    let reads = 10;
+    let mut outcomes = HashMap::new();
+    for schedule in schedules(ops.len(), reads + 1) {
+        let remote = Arc::new(Mutex::new(Remote::default()));
+        publish(&remote, 1);
+        let mut reader = ScriptedIndex {
+            writer: ops.iter().cloned().collect(),
+            schedule: schedule.clone(),
+            ..ScriptedIndex::new(&remote)
+        };
+        let outcome = refresh(&mut reader);
+        let outcome = match outcome {
+            Ok((2, [1, 1])) => "before",
+            Ok((4, [2, 2])) => "after",
+            Ok((sequence, fills)) => {
+                panic!("mixed snapshot {fills:?} at sequence {sequence}, schedule {schedule:?}")
+            }
+            // the writer stalled halfway for as long as the reader tried
+            Err(Error::WalIndexUnstable { .. }) => "busy",
+            Err(err) => panic!("{err}, schedule {schedule:?}"),
+        };
+        *outcomes.entry(outcome).or_insert(0) += 1;
+    }
+    assert!(outcomes["before"] > 0);
+    assert!(outcomes["after"] > 0);
+    assert!(outcomes["busy"] > 0);
+}
+
+#[test]
+fn test_unstable_index() {
+    let remote = Arc::new(Mutex::new(Remote::default()));
+    publish(&remote, 1);
+
+    // a publisher that failed halfway
+    remote.lock().unwrap().apply(Op::Sequence(3));
+    remote
+        .lock()
+        .unwrap()
+        .apply(Op::Push(0, Box::new([2; 32768])));
+    let mut reader = ScriptedIndex::new(&remote);
+    let (mut first, mut second) = ([0; 32768], [0; 32768]);
+    let err = reader
+        .refresh::<Handle>(&mut [(0, &mut first), (1, &mut second)])
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::WalIndexUnstable { attempts } if attempts == REFRESH_ATTEMPTS),
+        "{err}"
+    );
+    assert_eq!(first, [0; 32768], "left untouched");
+
+    // the next publication moves past it
+    assert_eq!(publish(&remote, 3), 6);
+    assert_eq!(refresh(&mut reader).unwrap(), (6, [3, 3]));
+
+    // a writer publishing during each attempt
+    let mut ops = VecDeque::new();
+    for sequence in 0..REFRESH_ATTEMPTS as u64 {
+        ops.push_back(Op::Sequence(7 + 2 * sequence));
+        ops.push_back(Op::Sequence(8 + 2 * sequence));
+    }
+    let mut reader = ScriptedIndex {
+        writer: ops,
+        // between the reads of the sequence of each attempt
+        schedule: (0..4 * REFRESH_ATTEMPTS)
+            .map(|read| if read % 4 == 1 { 2 } else { 0 })
+            .collect(),
+        ..ScriptedIndex::new(&remote)
+    };
+    assert!(matches!(
+        refresh(&mut reader),
+        Err(Error::WalIndexUnstable { .. })
+    ));
+}
@@ -0,0 +1,459 @@
+//! An async front for rusqlite connections running through the VFS.
+//!
+//! SQLite is synchronous and every storage request made from a VFS callback blocks the calling
+//! thread, so each [AsyncConnection] owns a dedicated thread running the rusqlite connection.
+//! Statements are shipped to that thread over a bounded channel and their results are returned
+//! through futures, keeping the tokio workers free for storage I/O.
+//!
+//! A statement failing with `SQLITE_BUSY` is reported as [Error::Busy] with the diagnosis of
+//! [ThreeQLite::why_busy] while someone holds the lock, see [crate::busy]. After a writer changed
+//! the schema, the connection flushes its cached statements before running the next one, see
+//! [crate::schema].
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rusqlite::{Connection, OpenFlags, Params, Row, Transaction};
+use snafu::ResultExt;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    error::{Error, SqliteSnafu},
+    schema::Statements,
+    vfs::ThreeQLite,
+};
+
+/// Number of requests that can be queued for a connection before callers have to wait.
+const QUEUE_DEPTH: usize = 32;
+
+/// How long a statement waits on a locked database before failing with `SQLITE_BUSY`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Job = Box<dyn FnOnce(&mut Connection) + Send>;
+
+enum Message {
+    Run(Job),
+    Close,
+}
+
+struct Worker {
+    tx: mpsc::WeakSender<Message>,
+    done: oneshot::Receiver<()>,
+}
+
+/// The connection threads of a [ThreeQLite] instance, closed by [ThreeQLite::shutdown].
+#[derive(Default)]
+pub struct Workers {
+    workers: Mutex<Vec<Worker>>,
+    closed: AtomicBool,
+}
+
+impl Workers {
+    /// Close all connections, and refuse to open new ones. Requests queued before the call are
+    /// still executed. Returns once every connection thread closed its connection.
+    pub async fn shutdown(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        for worker in workers {
+            if let Some(tx) = worker.tx.upgrade() {
+                let _ = tx.send(Message::Close).await;
+            }
+            let _ = worker.done.await;
+        }
+    }
+}
+
+/// A rusqlite connection driven from async code. Cloning is cheap; clones share the connection.
+#[derive(Clone)]
+pub struct AsyncConnection {
+    tx: mpsc::Sender<Message>,
+    /// The instance and database the connection was opened on, to diagnose busy errors.
+    db: Option<(ThreeQLite, String)>,
+    /// Whether the cached statements predate a schema change seen by the instance.
+    statements: Option<Arc<Statements>>,
+}
+
+impl AsyncConnection {
+    /// Open `db` through the VFS `vfs` was registered as.
+    pub async fn open(vfs: &ThreeQLite, db: &str) -> Result<Self, Error> {
+        Self::open_with_flags(
+            vfs,
+            db,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        )
+        .await
+    }
+
+    /// Open `db` with `flags` through the VFS `vfs` was registered as. The connection is always
+    /// opened with `SQLITE_OPEN_NO_MUTEX`, since only its thread uses it.
+    pub async fn open_with_flags(
+        vfs: &ThreeQLite,
+        db: &str,
+        flags: OpenFlags,
+    ) -> Result<Self, Error> {
+        let Some(name) = vfs.name.get().cloned() else {
+            return Err(Error::NotRegistered);
+        };
+        let db = db.to_owned();
+
+        // passing `psow=0` for a sector size above 512, see [crate::sector]
+        let (path, flags) = match vfs.connection_defaults.uri(&db) {
+            Some(uri) => (uri, flags | OpenFlags::SQLITE_OPEN_URI),
+            None => (db.clone(), flags),
+        };
+        let defaults = vfs.connection_defaults.sql();
+        let mut conn = Self::spawn(&vfs.workers, move || {
+            let conn = Connection::open_with_flags_and_vfs(
+                path,
+                flags | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                &name,
+            )?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.execute_batch(&defaults)?;
+            Ok(conn)
+        })
+        .await?;
+        let changes = vfs.inner.read().await.schema.changes();
+        conn.db = Some((vfs.clone(), db));
+        Ok(conn.following_schema(changes))
+    }
+
+    pub(crate) async fn spawn(
+        workers: &Workers,
+        open: impl FnOnce() -> rusqlite::Result<Connection> + Send + 'static,
+    ) -> Result<Self, Error> {
+        if workers.closed.load(Ordering::Relaxed) {
+            return Err(Error::ConnectionClosed);
+        }
+        let (tx, mut rx) = mpsc::channel::<Message>(QUEUE_DEPTH);
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+
+        std::thread::Builder::new()
+            .name("threeqlite-conn".to_owned())
+            .spawn(move || {
+                let mut conn = match open() {
+                    Ok(conn) => {
+                        let _ = opened_tx.send(Ok(()));
+                        conn
+                    }
+                    Err(err) => {
+                        let _ = opened_tx.send(Err(err));
+                        let _ = done_tx.send(());
+                        return;
+                    }
+                };
+
+                while let Some(Message::Run(job)) = rx.blocking_recv() {
+                    job(&mut conn);
+                }
+                // closed once shutdown returns
+                drop(rx);
+
+                if let Err((_, err)) = conn.close() {
+                    tracing::error!(target: "threeqlite::asyncdb", %err, "closing connection failed");
+                }
+                let _ = done_tx.send(());
+            })
+            .map_err(|err| Error::Whatever {
+                message: format!("failed to spawn connection thread: {err}"),
+                source: None,
+            })?;
+
+        match opened_rx.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return Err(err).context(SqliteSnafu),
+            Err(_) => return Err(Error::ConnectionClosed),
+        }
+
+        workers.workers.lock().unwrap().push(Worker {
+            tx: tx.downgrade(),
+            done: done_rx,
+        });
+
+        Ok(Self {
+            tx,
+            db: None,
+            statements: None,
+        })
+    }
+
+    /// Whether the connection thread is gone, after [ThreeQLite::shutdown] or a panic.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Flush the cached statements whenever `changes`, the schema changes seen by an instance,
+    /// moved on, see [crate::schema].
+    pub(crate) fn following_schema(mut self, changes: Arc<AtomicU64>) -> Self {
+        self.statements = Some(Arc::new(Statements::new(changes)));
+        self
+    }
+
+    /// How often the cached statements were flushed after a schema change.
+    pub fn statement_flushes(&self) -> u64 {
+        self.statements
+            .as_ref()
+            .map_or(0, |statements| statements.flushes())
+    }
+
+    /// Report a busy database with the diagnosis of its lock.
+    async fn sqlite_result<T>(&self, res: rusqlite::Result<T>) -> Result<T, Error> {
+        if let (Some((vfs, db)), Err(err)) = (&self.db, &res) {
+            if err.sqlite_error_code() == Some(rusqlite::ErrorCode::DatabaseBusy) {
+                match vfs.why_busy(db).await {
+                    Ok(Some(diagnosis)) => {
+                        return Err(Error::Busy {
+                            diagnosis: Box::new(diagnosis),
+                        })
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        tracing::debug!(target: "threeqlite::asyncdb", %err, "diagnosing busy database failed")
+                    }
+                }
+            }
+        }
+        res.context(SqliteSnafu)
+    }
+
+    /// Run `f` on the connection thread. Waits if too many requests are queued already.
+    ///
+    /// Dropping the returned future does not cancel `f`; it still runs, but its result is
+    /// discarded.
+    pub async fn call<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> T + Send + 'static,
+    {
+        let (res_tx, res_rx) = oneshot::channel();
+        let stale = self
+            .statements
+            .as_ref()
+            .is_some_and(|statements| statements.stale());
+        let job: Job = Box::new(move |conn| {
+            if stale {
+                conn.flush_prepared_statement_cache();
+            }
+            if res_tx.send(f(conn)).is_err() {
+                tracing::trace!(
+                    target: "threeqlite::asyncdb",
+                    "caller went away, discarding statement result"
+                );
+            }
+        });
+
+        self.tx
+            .send(Message::Run(job))
+            .await
+            .map_err(|_| Error::ConnectionClosed)?;
+        res_rx.await.map_err(|_| Error::ConnectionClosed)
+    }
+
+    /// Execute a single statement, returning the number of changed rows.
+    pub async fn execute<P>(&self, sql: impl Into<String>, params: P) -> Result<usize, Error>
+    where
+        P: Params + Send + 'static,
+    {
+        let sql = sql.into();
+        let res = self.call(move |conn| conn.execute(&sql, params)).await?;
+        self.sqlite_result(res).await
+    }
+
+    /// Run a query, mapping each row with `f`.
+    pub async fn query_map<T, P, F>(
+        &self,
+        sql: impl Into<String>,
+        params: P,
+        f: F,
+    ) -> Result<Vec<T>, Error>
+    where
+        T: Send + 'static,
+        P: Params + Send + 'static,
+        F: FnMut(&Row<'_>) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let sql = sql.into();
+        let res = self
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&sql)?;
+                let rows = stmt.query_map(params, f)?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+        self.sqlite_result(res).await
+    }
+
+    /// Run `f` inside a transaction, committing if it returns `Ok`.
+    pub async fn transaction<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Transaction<'_>) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let res = self
+            .call(move |conn| {
+                let txn = conn.transaction()?;
+                let res = f(&txn)?;
+                txn.commit()?;
+                Ok(res)
+            })
+            .await?;
+        self.sqlite_result(res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    async fn memory(workers: &Workers) -> AsyncConnection {
+        AsyncConnection::spawn(workers, Connection::open_in_memory)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_queries() {
+        let workers = Workers::default();
+        let conn = memory(&workers).await;
+        conn.execute("CREATE TABLE t (n INTEGER)", [])
+            .await
+            .unwrap();
+
+        let tasks = (0..64)
+            .map(|n| {
+                let conn = conn.clone();
+                tokio::spawn(async move { conn.execute("INSERT INTO t VALUES (?1)", [n]).await })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap(), 1);
+        }
+
+        let sum = conn
+            .query_map("SELECT SUM(n) FROM t", [], |row| row.get::<_, i64>(0))
+            .await
+            .unwrap();
+        assert_eq!(sum, vec![(0..64).sum::<i64>()]);
+
+        workers.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_executor_not_blocked() {
+        let workers = Workers::default();
+        let conn = memory(&workers).await;
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+
+        // a slow statement must not starve the (single-threaded) executor
+        conn.call(|_| std::thread::sleep(Duration::from_millis(100)))
+            .await
+            .unwrap();
+        assert!(ticks.load(Ordering::Relaxed) >= 5);
+
+        ticker.abort();
+        workers.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rollback() {
+        let workers = Workers::default();
+        let conn = memory(&workers).await;
+        conn.execute("CREATE TABLE t (n INTEGER)", [])
+            .await
+            .unwrap();
+
+        let res = conn
+            .transaction(|txn| {
+                txn.execute("INSERT INTO t VALUES (1)", [])?;
+                txn.execute("INSERT INTO nope VALUES (1)", [])
+            })
+            .await;
+        assert!(matches!(res, Err(Error::Sqlite { .. })));
+
+        let count = conn
+            .query_map("SELECT COUNT(*) FROM t", [], |row| row.get::<_, i64>(0))
+            .await
+            .unwrap();
+        assert_eq!(count, vec![0]);
+
+        workers.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dropped_future_still_runs() {
+        let workers = Workers::default();
+        let conn = memory(&workers).await;
+        conn.execute("CREATE TABLE t (n INTEGER)", [])
+            .await
+            .unwrap();
+
+        let fut = conn.execute("INSERT INTO t VALUES (1)", []);
+        // poll once so the request is queued, then drop it
+        let _ = tokio::time::timeout(Duration::ZERO, fut).await;
+
+        let count = conn
+            .query_map("SELECT COUNT(*) FROM t", [], |row| row.get::<_, i64>(0))
+            .await
+            .unwrap();
+        assert_eq!(count, vec![1]);
+
+        workers.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_queue() {
+        let workers = Workers::default();
+        let conn = memory(&workers).await;
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        let pending = (0..8)
+            .map(|_| {
+                let conn = conn.clone();
+                let ran = ran.clone();
+                tokio::spawn(async move {
+                    conn.call(move |_| {
+                        std::thread::sleep(Duration::from_millis(10));
+                        ran.fetch_add(1, Ordering::Relaxed);
+                    })
+                    .await
+                })
+            })
+            .collect::<Vec<_>>();
+        tokio::task::yield_now().await;
+
+        workers.shutdown().await;
+        assert_eq!(ran.load(Ordering::Relaxed), 8);
+        for task in pending {
+            task.await.unwrap().unwrap();
+        }
+        assert!(matches!(
+            conn.call(|_| ()).await,
+            Err(Error::ConnectionClosed)
+        ));
+        assert!(conn.is_closed());
+        assert!(matches!(
+            AsyncConnection::spawn(&workers, Connection::open_in_memory).await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+}
@@ -0,0 +1,465 @@
+//! Registering the VFS as a side effect of loading or linking this crate, for hosts that can
+//! only name a VFS, e.g. language bindings passing `vfs=threeqlite` in a URI.
+//!
+//! Build a loadable extension with
+//!
+//! ```sh
+//! cargo rustc --release --lib --features auto-register --crate-type cdylib
+//! ```
+//!
+//! and load it with `sqlite3_load_extension` or `.load`. Its entry point
+//! [sqlite3_threeqlite_init] registers the VFS with the SQLite library loading it, through the
+//! `sqlite3_api_routines` it is passed, and keeps the extension loaded once it succeeded. Loading
+//! it again, e.g. for every connection, is a no-op. With `auto-register-static`, a static
+//! constructor registers the VFS with the SQLite linked into the program when it starts instead;
+//! the outcome is available from [static_registration].
+//!
+//! Both read their configuration from the environment. Variables that are set but empty count as
+//! unset:
+//!
+//! | Variable                      | Default      | Sets                                         |
+//! | ----------------------------- | ------------ | -------------------------------------------- |
+//! | `THREEQLITE_VFS`              | `threeqlite` | The name the VFS is registered as            |
+//! | `THREEQLITE_DEFAULT_VFS`      | `false`      | Register as SQLite's default VFS             |
+//! | `THREEQLITE_BUCKET`           | `threeqlite` | [Config::bucket]                             |
+//! | `THREEQLITE_DB`               | `test.db`    | [Config::db_filename]                        |
+//! | `THREEQLITE_LOCK_FILE`        | `lockfile`   | [Config::lock_file]                          |
+//! | `THREEQLITE_METADATA`         | `metadata`   | [Config::metadata_filename]                  |
+//! | `THREEQLITE_BUSY_TIMEOUT_MS`  | unset        | [LockConfig::busy_timeout]                   |
+//! | `THREEQLITE_LOCK_BACKEND`     | `legal-hold` | [LockConfig::backend], or `conditional`      |
+//! | `THREEQLITE_REGION`           | AWS chain    | The region, over `AWS_REGION`                |
+//! | `THREEQLITE_ENDPOINT`         | AWS chain    | The S3 endpoint, over `AWS_ENDPOINT_URL`     |
+//! | `THREEQLITE_FORCE_PATH_STYLE` | `false`      | Path-style requests, e.g. for MinIO          |
+//!
+//! Flags accept `1`, `true` and `yes`, or `0`, `false` and `no`. Credentials come from the usual
+//! AWS environment and profile chain. An invalid value fails the registration with an
+//! [Error::InvalidEnv] naming the variable, which the extension reports as its load error.
+//!
+//! [LockConfig::busy_timeout]: crate::config::LockConfig::busy_timeout
+//! [LockConfig::backend]: crate::config::LockConfig::backend
+
+use std::{
+    ffi::{c_char, c_int, c_void},
+    sync::Mutex,
+    time::Duration,
+};
+
+use aws_config::{BehaviorVersion, Region};
+use sqlite_vfs::VfsRegister;
+
+use crate::{
+    config::{Config, LockConfig},
+    error::Error,
+    key::{KeyLayout, ObjectKey},
+    vfs::ThreeQLite,
+};
+
+/// The VFS as configured by the environment, see the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct EnvConfig {
+    pub vfs_name: String,
+    pub as_default: bool,
+    pub config: Config,
+    pub region: Option<String>,
+    pub endpoint: Option<String>,
+    pub force_path_style: bool,
+}
+
+impl EnvConfig {
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Read the configuration from the variables `lookup` returns.
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let var = |name: &str| lookup(name).filter(|value| !value.is_empty());
+        let invalid = |var, reason: String| Error::InvalidEnv { var, reason };
+        let flag = |name: &'static str| match var(name).as_deref() {
+            None => Ok(false),
+            Some("1" | "true" | "yes") => Ok(true),
+            Some("0" | "false" | "no") => Ok(false),
+            Some(value) => Err(invalid(name, format!("{value:?} is not a flag"))),
+        };
+        let key = |name: &'static str, default: String| {
+            let value = var(name).unwrap_or(default);
+            ObjectKey::new(value.as_str())
+                .map(String::from)
+                .map_err(|err| invalid(name, err.to_string()))
+        };
+
+        let defaults = Config::default();
+        let vfs_name = var("THREEQLITE_VFS").unwrap_or_else(|| "threeqlite".to_owned());
+        if vfs_name.contains('\0') {
+            return Err(invalid(
+                "THREEQLITE_VFS",
+                "name contains a NUL byte".to_owned(),
+            ));
+        }
+        let bucket = var("THREEQLITE_BUCKET").unwrap_or(defaults.bucket);
+        let db_filename = var("THREEQLITE_DB").unwrap_or(defaults.db_filename);
+        KeyLayout::db(&db_filename).map_err(|err| invalid("THREEQLITE_DB", err.to_string()))?;
+        let busy_timeout = var("THREEQLITE_BUSY_TIMEOUT_MS")
+            .map(|ms| match ms.parse() {
+                Ok(ms) => Ok(Duration::from_millis(ms)),
+                Err(_) => Err(invalid(
+                    "THREEQLITE_BUSY_TIMEOUT_MS",
+                    format!("{ms:?} is not a number of milliseconds"),
+                )),
+            })
+            .transpose()?;
+        let backend = var("THREEQLITE_LOCK_BACKEND")
+            .map(|backend| backend.parse())
+            .transpose()
+            .map_err(|reason| invalid("THREEQLITE_LOCK_BACKEND", reason))?
+            .unwrap_or(defaults.lock.backend);
+
+        Ok(Self {
+            vfs_name,
+            as_default: flag("THREEQLITE_DEFAULT_VFS")?,
+            config: Config {
+                bucket,
+                db_filename,
+                lock_file: key("THREEQLITE_LOCK_FILE", defaults.lock_file)?,
+                metadata_filename: key("THREEQLITE_METADATA", defaults.metadata_filename)?,
+                lock: LockConfig {
+                    busy_timeout,
+                    backend,
+                    ..defaults.lock
+                },
+                ..Config::default()
+            },
+            region: var("THREEQLITE_REGION"),
+            endpoint: var("THREEQLITE_ENDPOINT"),
+            force_path_style: flag("THREEQLITE_FORCE_PATH_STYLE")?,
+        })
+    }
+
+    /// Create the instance this configuration describes.
+    pub async fn instance(self) -> Result<ThreeQLite, Error> {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = self.region {
+            loader = loader.region(Region::new(region));
+        }
+        if let Some(endpoint) = self.endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        let sdk_config = loader.load().await;
+        let s3_config = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .force_path_style(self.force_path_style)
+            .build();
+        ThreeQLite::with_client(self.config, aws_sdk_s3::Client::from_conf(s3_config))
+    }
+}
+
+/// The instance registered by this module, once a registration succeeded.
+static REGISTERED: Mutex<Option<ThreeQLite>> = Mutex::new(None);
+
+/// The instance registered by [sqlite3_threeqlite_init] or the static constructor.
+pub fn registered() -> Option<ThreeQLite> {
+    REGISTERED.lock().unwrap().clone()
+}
+
+/// Register the VFS configured by `env` through `vfs_register`, or through the
+/// `sqlite3_vfs_register` linked into this crate. Does nothing if a VFS was registered already.
+pub fn register(env: EnvConfig, vfs_register: Option<VfsRegister>) -> Result<ThreeQLite, Error> {
+    let mut registered = REGISTERED.lock().unwrap();
+    if let Some(tq) = &*registered {
+        return Ok(tq.clone());
+    }
+    let (name, as_default) = (env.vfs_name.clone(), env.as_default);
+    // the callbacks run on the runtimes of sqlite-vfs, this one only loads the configuration
+    let tq = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| Error::Whatever {
+            message: format!("failed to start runtime: {err}"),
+            source: None,
+        })?
+        .block_on(env.instance())?;
+    let vfs_register = vfs_register.unwrap_or(libsqlite3_sys::sqlite3_vfs_register);
+    tq.register_with(&name, as_default, vfs_register)
+        .map_err(|err| Error::Whatever {
+            message: format!("failed to register VFS {name:?}: {err}"),
+            source: None,
+        })?;
+    *registered = Some(tq.clone());
+    Ok(tq)
+}
+
+/// Indices into `sqlite3_api_routines`, which SQLite only ever appends to.
+const API_MALLOC: usize = 68;
+const API_VFS_REGISTER: usize = 142;
+
+/// The entry of `api` at `index`.
+///
+/// # Safety
+///
+/// `api` must point to SQLite's `sqlite3_api_routines`, whose entry at `index` must have type `F`.
+unsafe fn api_routine<F: Copy>(api: *const c_void, index: usize) -> Option<F> {
+    let routine = *(api as *const Option<unsafe extern "C" fn()>).add(index);
+    routine.map(|routine| std::mem::transmute_copy(&routine))
+}
+
+/// Hand `message` to SQLite as the error of loading the extension, allocated with the
+/// `sqlite3_malloc` of `api`, as SQLite frees it.
+unsafe fn report(errmsg: *mut *mut c_char, api: *const c_void, message: &str) {
+    if errmsg.is_null() {
+        return;
+    }
+    type Malloc = unsafe extern "C" fn(c_int) -> *mut c_void;
+    let malloc = match api.is_null() {
+        true => Some(libsqlite3_sys::sqlite3_malloc as Malloc),
+        false => api_routine::<Malloc>(api, API_MALLOC),
+    };
+    let Some(malloc) = malloc else {
+        return;
+    };
+    let message = message.replace('\0', " ");
+    let buf = malloc(message.len() as c_int + 1) as *mut c_char;
+    if buf.is_null() {
+        return;
+    }
+    std::ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, buf, message.len());
+    *buf.add(message.len()) = 0;
+    *errmsg = buf;
+}
+
+/// The entry point of the loadable extension, registering the VFS configured from the
+/// environment with the SQLite library behind `api`. A null `api` registers with the SQLite
+/// linked into this crate.
+///
+/// # Safety
+///
+/// Must be called by SQLite, or with the same arguments SQLite passes.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_threeqlite_init(
+    _db: *mut c_void,
+    errmsg: *mut *mut c_char,
+    api: *const c_void,
+) -> c_int {
+    let vfs_register = match api.is_null() {
+        true => None,
+        false => match api_routine::<VfsRegister>(api, API_VFS_REGISTER) {
+            Some(vfs_register) => Some(vfs_register),
+            None => {
+                report(
+                    errmsg,
+                    api,
+                    "threeqlite: sqlite3_vfs_register is unavailable",
+                );
+                return libsqlite3_sys::SQLITE_ERROR;
+            }
+        },
+    };
+    let res = std::panic::catch_unwind(|| register(EnvConfig::from_env()?, vfs_register));
+    let message = match res {
+        // the VFS must outlive the connection loading the extension
+        Ok(Ok(_)) => return libsqlite3_sys::SQLITE_OK_LOAD_PERMANENTLY,
+        Ok(Err(err)) => format!("threeqlite: {err}"),
+        Err(_) => "threeqlite: registering the VFS panicked".to_owned(),
+    };
+    report(errmsg, api, &message);
+    libsqlite3_sys::SQLITE_ERROR
+}
+
+#[cfg(feature = "auto-register-static")]
+static STATIC_REGISTRATION: std::sync::OnceLock<Result<String, String>> =
+    std::sync::OnceLock::new();
+
+/// The name of the VFS the static constructor registered, or why it failed.
+#[cfg(feature = "auto-register-static")]
+pub fn static_registration() -> Option<&'static Result<String, String>> {
+    STATIC_REGISTRATION.get()
+}
+
+#[cfg(feature = "auto-register-static")]
+extern "C" fn register_on_start() {
+    let res = std::panic::catch_unwind(|| {
+        let env = EnvConfig::from_env()?;
+        let name = env.vfs_name.clone();
+        register(env, None).map(|_| name)
+    });
+    let res = match res {
+        Ok(res) => res.map_err(|err| err.to_string()),
+        Err(_) => Err("registering the VFS panicked".to_owned()),
+    };
+    if let Err(err) = &res {
+        // runs before `main`, so no subscriber is installed yet
+        eprintln!("threeqlite: {err}");
+    }
+    let _ = STATIC_REGISTRATION.set(res);
+}
+
+#[cfg(feature = "auto-register-static")]
+#[used]
+#[cfg_attr(
+    any(target_os = "linux", target_os = "android", target_os = "freebsd"),
+    link_section = ".init_array"
+)]
+#[cfg_attr(target_vendor = "apple", link_section = "__DATA,__mod_init_func")]
+#[cfg_attr(windows, link_section = ".CRT$XCU")]
+static REGISTER_ON_START: extern "C" fn() = register_on_start;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::config::LockBackend;
+
+    fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<_, _> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_env_config() {
+        let env = EnvConfig::from_lookup(lookup(&[])).unwrap();
+        assert_eq!(
+            (env.vfs_name.as_str(), env.as_default),
+            ("threeqlite", false)
+        );
+        assert_eq!(env.config.bucket, "threeqlite");
+        assert_eq!(env.config.lock.busy_timeout, None);
+
+        let env = EnvConfig::from_lookup(lookup(&[
+            ("THREEQLITE_VFS", "s3"),
+            ("THREEQLITE_DEFAULT_VFS", "yes"),
+            ("THREEQLITE_BUCKET", "tenants"),
+            ("THREEQLITE_DB", "a/main.db"),
+            ("THREEQLITE_LOCK_FILE", ""),
+            ("THREEQLITE_BUSY_TIMEOUT_MS", "2500"),
+            ("THREEQLITE_LOCK_BACKEND", "conditional"),
+            ("THREEQLITE_FORCE_PATH_STYLE", "1"),
+        ]))
+        .unwrap();
+        assert_eq!((env.vfs_name.as_str(), env.as_default), ("s3", true));
+        assert_eq!(env.config.db_filename, "a/main.db");
+        // empty counts as unset
+        assert_eq!(env.config.lock_file, "lockfile");
+        assert_eq!(
+            env.config.lock.busy_timeout,
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(env.config.lock.backend, LockBackend::Conditional);
+        assert!(env.force_path_style);
+
+        for (var, value, reason) in [
+            ("THREEQLITE_DEFAULT_VFS", "maybe", "\"maybe\" is not a flag"),
+            ("THREEQLITE_DB", "/main.db", "key starts with a slash"),
+            ("THREEQLITE_METADATA", "a//b", "key has an empty segment"),
+            ("THREEQLITE_BUSY_TIMEOUT_MS", "5s", "\"5s\" is not a number"),
+            ("THREEQLITE_LOCK_BACKEND", "object-lock", "\"object-lock\""),
+        ] {
+            let err = EnvConfig::from_lookup(lookup(&[(var, value)])).unwrap_err();
+            let message = err.to_string();
+            assert!(
+                message.starts_with(&format!("invalid {var}: ")) && message.contains(reason),
+                "{message}"
+            );
+        }
+    }
+
+    #[cfg(feature = "auto-register-static")]
+    #[test]
+    fn test_static_registration() {
+        let name = static_registration().unwrap().as_ref().unwrap();
+        let c_name = std::ffi::CString::new(name.as_str()).unwrap();
+        assert!(!unsafe { libsqlite3_sys::sqlite3_vfs_find(c_name.as_ptr()) }.is_null());
+        assert_eq!(registered().unwrap().name.get(), Some(name));
+    }
+
+    // the static constructor registers a VFS before these tests could
+    #[cfg(not(feature = "auto-register-static"))]
+    mod extension {
+        use std::{
+            ffi::CStr,
+            sync::atomic::{AtomicUsize, Ordering},
+        };
+
+        use super::*;
+
+        static API_REGISTRATIONS: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe extern "C" fn counting_register(
+            vfs: *mut libsqlite3_sys::sqlite3_vfs,
+            make_default: c_int,
+        ) -> c_int {
+            API_REGISTRATIONS.fetch_add(1, Ordering::Relaxed);
+            libsqlite3_sys::sqlite3_vfs_register(vfs, make_default)
+        }
+
+        /// Stands in for the `sqlite3_api_routines` of the SQLite loading the extension.
+        fn api() -> Vec<Option<unsafe extern "C" fn()>> {
+            let mut api = vec![None; 256];
+            unsafe {
+                api[API_MALLOC] = Some(std::mem::transmute::<
+                    unsafe extern "C" fn(c_int) -> *mut c_void,
+                    unsafe extern "C" fn(),
+                >(libsqlite3_sys::sqlite3_malloc));
+                api[API_VFS_REGISTER] = Some(std::mem::transmute::<
+                    VfsRegister,
+                    unsafe extern "C" fn(),
+                >(counting_register));
+            }
+            api
+        }
+
+        #[test]
+        fn test_extension_entry_point() {
+            let api = api();
+            let init = |errmsg: &mut *mut c_char| unsafe {
+                sqlite3_threeqlite_init(std::ptr::null_mut(), errmsg, api.as_ptr() as *const c_void)
+            };
+
+            // a misconfiguration fails the load, naming the variable
+            std::env::set_var("THREEQLITE_VFS", "threeqlite-auto-test");
+            std::env::set_var("THREEQLITE_REGION", "us-east-1");
+            std::env::set_var("THREEQLITE_BUSY_TIMEOUT_MS", "soon");
+            let mut errmsg = std::ptr::null_mut();
+            assert_eq!(init(&mut errmsg), libsqlite3_sys::SQLITE_ERROR);
+            let message = unsafe { CStr::from_ptr(errmsg) }
+                .to_str()
+                .unwrap()
+                .to_owned();
+            unsafe { libsqlite3_sys::sqlite3_free(errmsg as *mut c_void) };
+            assert_eq!(
+                message,
+                "threeqlite: invalid THREEQLITE_BUSY_TIMEOUT_MS: \"soon\" is not a number of \
+                 milliseconds"
+            );
+            assert_eq!(API_REGISTRATIONS.load(Ordering::Relaxed), 0);
+
+            // registers with the SQLite behind the API routines, once
+            std::env::set_var("THREEQLITE_BUSY_TIMEOUT_MS", "1000");
+            std::env::set_var("THREEQLITE_BUCKET", "auto-test");
+            for _ in 0..2 {
+                let mut errmsg = std::ptr::null_mut();
+                assert_eq!(
+                    init(&mut errmsg),
+                    libsqlite3_sys::SQLITE_OK_LOAD_PERMANENTLY
+                );
+                assert!(errmsg.is_null());
+            }
+            for var in [
+                "THREEQLITE_VFS",
+                "THREEQLITE_REGION",
+                "THREEQLITE_BUSY_TIMEOUT_MS",
+                "THREEQLITE_BUCKET",
+            ] {
+                std::env::remove_var(var);
+            }
+            assert_eq!(API_REGISTRATIONS.load(Ordering::Relaxed), 1);
+            let name = c"threeqlite-auto-test";
+            assert!(!unsafe { libsqlite3_sys::sqlite3_vfs_find(name.as_ptr()) }.is_null());
+            let tq = registered().unwrap();
+            assert_eq!(tq.name.get().unwrap(), "threeqlite-auto-test");
+            let inner = tq.inner.try_read().unwrap();
+            assert_eq!(inner.bucket, "auto-test");
+            assert_eq!(inner.lock_config.busy_timeout, Some(Duration::from_secs(1)));
+        }
+    }
+}
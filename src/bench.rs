@@ -0,0 +1,269 @@
+//! Golden benchmarks of canonical workloads against the mock store.
+//!
+//! Each workload runs against a fresh [MockS3] answering every request after a fixed latency, and
+//! is measured by what the store saw: requests per method, bytes of request and response bodies,
+//! and round trips on the critical path, see [crate::replay::critical_path]. Those only change with the
+//! code, as pages are picked with a fixed seed, and are compared against `benches/baseline.json`:
+//! one growing by more than the tolerance, 10% by default, fails [tests::test_workloads] with a
+//! line per regression. The wall time is reported, and only compared with a tolerance of its own,
+//! as it depends on the machine. The test is ignored, keeping the thresholds of a benchmark out of
+//! the unit suite, and runs with `--ignored`.
+//!
+//! | Variable                          | Effect                                              |
+//! |-----------------------------------|-----------------------------------------------------|
+//! | `THREEQLITE_BENCH_TOLERANCE`      | growth allowed of counts and bytes, e.g. `0.25`     |
+//! | `THREEQLITE_BENCH_WALL_TOLERANCE` | growth allowed of the wall time, unchecked if unset |
+//! | `THREEQLITE_BENCH_UPDATE=1`       | write the reports as the new baseline               |
+//!
+//! ```sh
+//! cargo test --lib bench -- --ignored --nocapture
+//! ```
+//!
+//! The workloads replay the page accesses SQLite makes rather than running SQL, and read at a
+//! known generation as reads do once the lock protocol registered them. WAL mode isn't
+//! implemented, see [crate::wal], so the mixed load runs with a rollback journal.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use sqlite_vfs::{OpenAccess, OpenKind, OpenOptions, Vfs};
+
+use crate::{
+    cache::{CacheConfig, CacheUse},
+    config::Config,
+    flush::{CommitStep, PendingWrites},
+    journal::{self, Journal, JournalKind},
+    key::KeyLayout,
+    mock::{self, MockS3},
+    replay::Report,
+    vfs::ThreeQLite,
+};
+
+/// The latency of every request.
+const LATENCY: Duration = Duration::from_millis(5);
+const PAGE_SIZE: u32 = 4096;
+const PAGES: u32 = 256;
+/// The generation reads are made at, see [crate::vfs::Inner::read_at].
+const GENERATION: u64 = 1;
+const SEED: u64 = 0x5eed;
+const BASELINE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/baseline.json");
+
+/// An instance over a fresh store holding the database, answering after [LATENCY].
+struct Bench {
+    mock: MockS3,
+    tq: ThreeQLite,
+}
+
+impl Bench {
+    fn new() -> Self {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(PAGE_SIZE, PAGES, 1));
+        for method in ["GET", "HEAD", "PUT", "DELETE", "POST"] {
+            mock.delay(method, LATENCY);
+        }
+        let config = Config {
+            cache: CacheConfig {
+                capacity: 4 * 1024 * 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        Self { mock, tq }
+    }
+
+    /// Run `workload`, reporting the requests it sent.
+    async fn measure(&self, workload: impl std::future::Future<Output = ()>) -> Report {
+        let start = self.mock.exchanges().len();
+        let started = Instant::now();
+        Box::pin(workload).await;
+        let wall = started.elapsed();
+        Report::new(&self.mock.exchanges()[start..], wall)
+    }
+
+    /// Open the database and read its header and first page, as SQLite does on its first query.
+    async fn open(&self) {
+        // boxed, the future of an open is too large for the stack of a test
+        let handle = Box::pin(self.tq.open(
+            "test.db",
+            OpenOptions::new(OpenKind::MainDb, OpenAccess::Read),
+        ))
+        .await
+        .unwrap();
+        self.read(&[0]).await;
+        drop(handle);
+    }
+
+    async fn read(&self, pages: &[u32]) {
+        let inner = self.tq.inner.read().await;
+        for page in pages {
+            let offset = (page * PAGE_SIZE) as usize;
+            if *page == 0 {
+                inner
+                    .read_at(0, 100, Some(GENERATION), CacheUse::Admit)
+                    .await
+                    .unwrap();
+            }
+            inner
+                .read_at(
+                    offset,
+                    PAGE_SIZE as usize,
+                    Some(GENERATION),
+                    CacheUse::Admit,
+                )
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Commit a transaction writing `pages`, each filled with `fill`, in the order SQLite calls
+    /// in: journal the ones that exist, upload them all, delete the journal and record the
+    /// commit.
+    async fn commit(&self, pages: &[u32], fill: u8) {
+        let inner = self.tq.inner.read().await;
+        let key = KeyLayout::journal(&inner.db_filename);
+        let mut journal = Journal::open(&inner, key.clone(), JournalKind::Main, true)
+            .await
+            .unwrap();
+        let mut content = vec![0; 512];
+        for page in pages.iter().filter(|page| **page < PAGES) {
+            content.extend((page + 1).to_be_bytes());
+            content.extend(vec![0; PAGE_SIZE as usize]);
+            content.extend(0u32.to_be_bytes());
+        }
+        journal.write_at(&content, 0).unwrap();
+        journal.sync(&inner).await.unwrap();
+
+        let mut pending = PendingWrites::default();
+        for page in pages {
+            pending.write((page * PAGE_SIZE) as u64, &vec![fill; PAGE_SIZE as usize]);
+        }
+        // boxed as well
+        Box::pin(inner.page_flush(&inner.db_filename, &pending).run())
+            .await
+            .unwrap();
+        inner.commit_log.record(&key, CommitStep::PagesDurable);
+        journal::delete(&inner, &key, JournalKind::Main)
+            .await
+            .unwrap();
+        inner
+            .write_metadata_record(Default::default())
+            .await
+            .unwrap();
+    }
+}
+
+/// Pages of the database picked with the fixed seed.
+fn lookups(rng: &mut StdRng, count: usize) -> Vec<u32> {
+    let pages: Vec<u32> = (1..PAGES).collect();
+    pages.choose_multiple(rng, count).copied().collect()
+}
+
+/// Run every workload, each against a store of its own.
+pub async fn run() -> BTreeMap<String, Report> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut reports = BTreeMap::new();
+
+    let bench = Bench::new();
+    reports.insert("cold_open", bench.measure(bench.open()).await);
+    reports.insert("warm_open", bench.measure(bench.open()).await);
+
+    let bench = Bench::new();
+    Box::pin(bench.open()).await;
+    let pages = lookups(&mut rng, 32);
+    reports.insert(
+        "point_lookups_cold",
+        bench.measure(bench.read(&pages)).await,
+    );
+    reports.insert(
+        "point_lookups_warm",
+        bench.measure(bench.read(&pages)).await,
+    );
+
+    let bench = Bench::new();
+    Box::pin(bench.open()).await;
+    let pages: Vec<u32> = (1..PAGES).collect();
+    reports.insert("sequential_scan", bench.measure(bench.read(&pages)).await);
+
+    let bench = Bench::new();
+    Box::pin(bench.open()).await;
+    let pages = lookups(&mut rng, 2);
+    reports.insert(
+        "oltp_transaction",
+        bench.measure(bench.commit(&pages, 1)).await,
+    );
+
+    let bench = Bench::new();
+    Box::pin(bench.open()).await;
+    // past the end, each uploaded in parallel, as many as the mock accepts connections at once
+    let pages: Vec<u32> = (PAGES..PAGES + 64).collect();
+    reports.insert("bulk_insert", bench.measure(bench.commit(&pages, 2)).await);
+
+    let bench = Bench::new();
+    Box::pin(bench.open()).await;
+    let rounds: Vec<_> = (0..4)
+        .map(|_| (lookups(&mut rng, 8), lookups(&mut rng, 2)))
+        .collect();
+    let mixed = async {
+        for (round, (reads, writes)) in rounds.iter().enumerate() {
+            Box::pin(bench.read(reads)).await;
+            Box::pin(bench.commit(writes, round as u8)).await;
+        }
+    };
+    reports.insert("mixed_load", bench.measure(mixed).await);
+
+    reports
+        .into_iter()
+        .map(|(name, report)| (name.to_owned(), report))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env_f64(name: &str) -> Option<f64> {
+        std::env::var(name)
+            .ok()
+            .map(|value| value.parse().unwrap_or_else(|_| panic!("{name}={value}")))
+    }
+
+    #[tokio::test]
+    #[ignore = "a benchmark, run with --ignored"]
+    async fn test_workloads() {
+        let reports = Box::pin(run()).await;
+        for (name, report) in &reports {
+            println!("{name:<20} {report}");
+        }
+        if std::env::var("THREEQLITE_BENCH_UPDATE").is_ok_and(|update| update == "1") {
+            let json = serde_json::to_string_pretty(&reports).unwrap();
+            std::fs::write(BASELINE, json + "\n").unwrap();
+            return;
+        }
+
+        let baseline: BTreeMap<String, Report> =
+            serde_json::from_str(&std::fs::read_to_string(BASELINE).unwrap()).unwrap();
+        let tolerance = env_f64("THREEQLITE_BENCH_TOLERANCE").unwrap_or(0.1);
+        let wall_tolerance = env_f64("THREEQLITE_BENCH_WALL_TOLERANCE");
+        let mut regressions = vec![];
+        for (name, report) in &reports {
+            match baseline.get(name) {
+                Some(baseline) => regressions.extend(
+                    report
+                        .regressions(baseline, tolerance, wall_tolerance)
+                        .into_iter()
+                        .map(|regression| format!("{name}: {regression}")),
+                ),
+                None => regressions.push(format!("{name}: not in the baseline")),
+            }
+        }
+        assert!(
+            regressions.is_empty(),
+            "regressed past the baseline, or run with THREEQLITE_BENCH_UPDATE=1 to accept:\n{}",
+            regressions.join("\n")
+        );
+    }
+}
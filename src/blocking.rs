@@ -0,0 +1,199 @@
+//! A synchronous client for scripts and build tools that don't run an async runtime.
+//!
+//! [BlockingClient] owns a private current-thread tokio runtime and blocks on the async API of
+//! [ThreeQLite] for each call. It must not be used from within an async runtime: blocking there
+//! would stall a worker, or deadlock a current-thread runtime, so every call checks for one first
+//! and fails with [Error::InAsyncContext] instead. The same goes for the connections it opens,
+//! whose VFS callbacks block the calling thread.
+//!
+//! Background tasks of the instance, such as refreshing credentials, only make progress while a
+//! call is blocking on the runtime.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), threeqlite::error::Error> {
+//! use threeqlite::{blocking::BlockingClient, config::Config};
+//!
+//! let client = BlockingClient::new(Config::default())?;
+//! client.register("threeqlite", false).unwrap();
+//! let conn = client.connect("app.db")?;
+//! let users: i64 = conn
+//!     .query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))
+//!     .unwrap();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+
+use rusqlite::{Connection, OpenFlags};
+use snafu::ResultExt;
+use sqlite_vfs::RegisterError;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{
+    config::Config,
+    discover::{DatabaseInfo, ListOptions, QuickHeader},
+    error::{Error, SqliteSnafu},
+    stats::StatsSnapshot,
+    vfs::ThreeQLite,
+};
+
+/// A [ThreeQLite] instance driven from synchronous code, see the [module documentation](self).
+pub struct BlockingClient {
+    runtime: Runtime,
+    tq: ThreeQLite,
+}
+
+/// Fails if called from within an async runtime.
+fn check_context() -> Result<(), Error> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => Err(Error::InAsyncContext),
+        Err(_) => Ok(()),
+    }
+}
+
+fn runtime() -> Result<Runtime, Error> {
+    check_context()?;
+    Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| Error::Whatever {
+            message: format!("failed to start runtime: {err}"),
+            source: Some(Box::new(err)),
+        })
+}
+
+impl BlockingClient {
+    /// Create an instance with the credentials and region of the environment, like
+    /// [ThreeQLite::with_config].
+    pub fn new(config: Config) -> Result<Self, Error> {
+        let runtime = runtime()?;
+        let tq = runtime.block_on(ThreeQLite::with_config(config))?;
+        Ok(Self { runtime, tq })
+    }
+
+    /// Create an instance talking to the object store through `s3`, like
+    /// [ThreeQLite::with_client].
+    pub fn with_client(config: Config, s3: aws_sdk_s3::Client) -> Result<Self, Error> {
+        let runtime = runtime()?;
+        let tq = runtime.block_on(async { ThreeQLite::with_client(config, s3) })?;
+        Ok(Self { runtime, tq })
+    }
+
+    fn block_on<T>(&self, future: impl Future<Output = T>) -> Result<T, Error> {
+        check_context()?;
+        Ok(self.runtime.block_on(future))
+    }
+
+    /// The instance, e.g. for the parts of its API without a blocking counterpart here.
+    pub fn instance(&self) -> &ThreeQLite {
+        &self.tq
+    }
+
+    /// Register the instance with SQLite as the VFS `name`, see [ThreeQLite::register].
+    pub fn register(&self, name: &str, as_default: bool) -> Result<(), RegisterError> {
+        self.tq.register(name, as_default)
+    }
+
+    /// Open `db` through the VFS the instance was registered as.
+    pub fn connect(&self, db: &str) -> Result<Connection, Error> {
+        self.connect_with_flags(
+            db,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        )
+    }
+
+    /// Open `db` with `flags` through the VFS the instance was registered as.
+    pub fn connect_with_flags(&self, db: &str, flags: OpenFlags) -> Result<Connection, Error> {
+        check_context()?;
+        let Some(name) = self.tq.name.get() else {
+            return Err(Error::NotRegistered);
+        };
+        // passing `psow=0` for a sector size above 512, see [crate::sector]
+        let (path, flags) = match self.tq.connection_defaults.uri(db) {
+            Some(uri) => (uri, flags | OpenFlags::SQLITE_OPEN_URI),
+            None => (db.to_owned(), flags),
+        };
+        let conn = Connection::open_with_flags_and_vfs(path, flags, name).context(SqliteSnafu)?;
+        conn.execute_batch(&self.tq.connection_defaults.sql())
+            .context(SqliteSnafu)?;
+        Ok(conn)
+    }
+
+    /// See [ThreeQLite::snapshot_to].
+    pub fn snapshot_to(&self, db: &str, out: &mut impl std::io::Write) -> Result<u64, Error> {
+        self.block_on(self.tq.snapshot_to(db, out))?
+    }
+
+    /// See [ThreeQLite::list_databases].
+    pub fn list_databases(
+        &self,
+        prefix_filter: Option<&str>,
+        opts: ListOptions,
+    ) -> Result<Vec<DatabaseInfo>, Error> {
+        self.block_on(self.tq.list_databases(prefix_filter, opts))?
+    }
+
+    /// See [ThreeQLite::quick_header].
+    pub fn quick_header(&self, db: &str) -> Result<QuickHeader, Error> {
+        self.block_on(self.tq.quick_header(db))?
+    }
+
+    /// See [ThreeQLite::preflight].
+    pub fn preflight(&self) -> Result<Vec<String>, Error> {
+        self.block_on(self.tq.preflight())?
+    }
+
+    pub fn stats(&self) -> Result<StatsSnapshot, Error> {
+        self.block_on(self.tq.stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockS3};
+
+    #[test]
+    fn test_blocking_client() {
+        let mock = MockS3::start();
+        let db = mock::database(4096, 4, 1);
+        mock.put("test.db", db.clone());
+        let client = BlockingClient::with_client(Config::default(), mock.client()).unwrap();
+
+        let mut copy = vec![];
+        assert_eq!(client.snapshot_to("test.db", &mut copy).unwrap(), 4 * 4096);
+        assert_eq!(copy, db);
+        let dbs = client.list_databases(None, ListOptions::default()).unwrap();
+        assert_eq!(dbs.len(), 1);
+        assert_eq!(dbs[0].name, "test.db");
+        assert_eq!(client.preflight().unwrap(), Vec::<String>::new());
+        assert!(client.stats().unwrap().requests > 0);
+        assert!(matches!(
+            client.connect("test.db"),
+            Err(Error::NotRegistered)
+        ));
+        assert!(client.snapshot_to("missing.db", &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_in_async_context() {
+        let mock = MockS3::start();
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let err = runtime
+            .block_on(async { BlockingClient::with_client(Config::default(), mock.client()) })
+            .err();
+        assert!(matches!(err, Some(Error::InAsyncContext)), "{err:?}");
+
+        let client = BlockingClient::with_client(Config::default(), mock.client()).unwrap();
+        runtime.block_on(async {
+            assert!(matches!(client.stats(), Err(Error::InAsyncContext)));
+            assert!(matches!(
+                client.connect("test.db"),
+                Err(Error::InAsyncContext)
+            ));
+        });
+        // fine again outside of it
+        client.stats().unwrap();
+    }
+}
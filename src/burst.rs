@@ -0,0 +1,321 @@
+//! Bursts of opens.
+//!
+//! When a service scales up, hundreds of connections open at once, and each open checks the
+//! metadata object, the write permission and the database object: thousands of identical requests
+//! for a few keys within a second, which the store may throttle right during the deploy. The
+//! outcomes of these checks are shared between the opens of an instance, while the request is in
+//! flight and for [BurstConfig::ttl] after it came in, so that an open in a burst waits for the
+//! one request per key rather than sending its own. Failures aren't shared: the next open waiting
+//! checks again.
+//!
+//! Sharing them is sound because nothing an open checks decides a commit. Every transaction
+//! validates again once it runs: taking the write lock reads the metadata object, reads register
+//! at the generation it has, and a write without permission fails with the store's `403`. An open
+//! sharing an outcome sees the database as it was up to [BurstConfig::ttl] earlier, as if it had
+//! opened that much earlier. Opens and shared checks run in [scope], and [assert_fresh], called
+//! where commits are decided, panics in debug builds within it.
+//!
+//! Whether a journal exists isn't shared: it decides whether SQLite rolls the journal back, before
+//! any transaction validates.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::OnceCell;
+
+use crate::{
+    create::{self, DatabaseState},
+    error::Error,
+    heal::MetadataHealth,
+    key::ObjectKey,
+    stats::Stats,
+    vfs::Inner,
+};
+
+tokio::task_local! {
+    /// Set while the current task checks a database it opens, see [scope].
+    static OPENING: ();
+}
+
+/// Settings of sharing checks between opens, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BurstConfig {
+    /// How long the outcome of a check is shared with later opens. With zero, only opens
+    /// arriving while the request is in flight share it.
+    pub ttl: Duration,
+}
+
+impl Default for BurstConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_millis(250),
+        }
+    }
+}
+
+/// The outcome of a check and when it came in, once it did.
+type Slot<T> = Arc<OnceCell<(T, Instant)>>;
+
+/// Outcomes of one kind of check per key, in flight or at most `ttl` old.
+#[derive(Debug)]
+pub struct Shared<T> {
+    ttl: Duration,
+    slots: Mutex<HashMap<String, Slot<T>>>,
+}
+
+impl<T: Clone> Shared<T> {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The outcome of `check` for `key`, and whether it was shared rather than checked.
+    async fn get<F>(&self, key: &str, check: impl FnOnce() -> F) -> Result<(T, bool), Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let slot = {
+            let now = Instant::now();
+            let mut slots = self.slots.lock().unwrap();
+            slots.retain(|_, slot| {
+                slot.get()
+                    .is_none_or(|(_, at)| now.duration_since(*at) < self.ttl)
+            });
+            slots.entry(key.to_owned()).or_default().clone()
+        };
+        let mut checked = false;
+        let ran = &mut checked;
+        let (value, _) = slot
+            .get_or_try_init(|| async move {
+                *ran = true;
+                Ok::<_, Error>((check().await?, Instant::now()))
+            })
+            .await?;
+        Ok((value.clone(), !checked))
+    }
+
+    /// Stop sharing the outcome for `key`, after this instance changed what it depends on.
+    fn forget(&self, key: &str) {
+        self.slots.lock().unwrap().remove(key);
+    }
+}
+
+/// The checks shared between the opens of an instance.
+#[derive(Debug)]
+pub struct OpenBurst {
+    metadata: Shared<MetadataHealth>,
+    state: Shared<DatabaseState>,
+    writable: Shared<bool>,
+}
+
+impl OpenBurst {
+    pub fn new(config: BurstConfig) -> Self {
+        Self {
+            metadata: Shared::new(config.ttl),
+            state: Shared::new(config.ttl),
+            writable: Shared::new(config.ttl),
+        }
+    }
+}
+
+/// Run `fut`, the checks of an open, so that [assert_fresh] can tell. `fut` is boxed right away,
+/// keeping it out of the future of the open.
+pub fn scope<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    OPENING.scope((), Box::pin(fut))
+}
+
+/// Whether the current task runs the checks of an open.
+pub fn opening() -> bool {
+    OPENING.try_with(|_| ()).is_ok()
+}
+
+/// Check in debug builds that the current task doesn't run the checks of an open, whose outcomes
+/// may be shared, see the [module documentation](self).
+pub fn assert_fresh() {
+    debug_assert!(
+        !opening(),
+        "a commit decided within the checks of an open, which may see shared outcomes"
+    );
+}
+
+impl Inner {
+    async fn shared<T: Clone, F>(
+        &self,
+        checks: impl FnOnce(&OpenBurst) -> &Shared<T>,
+        key: &str,
+        check: impl FnOnce() -> F,
+    ) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let (value, shared) = scope(checks(&self.burst).get(key, check)).await?;
+        if shared {
+            Stats::incr(&self.stats.open_burst_hits);
+        }
+        Ok(value)
+    }
+
+    /// [Inner::check_metadata], shared between the opens of a burst.
+    pub async fn shared_metadata_health(&self, db: &ObjectKey) -> Result<MetadataHealth, Error> {
+        self.shared(
+            |burst| &burst.metadata,
+            db.as_str(),
+            || self.check_metadata(db),
+        )
+        .await
+    }
+
+    /// [create::state], shared between the opens of a burst.
+    pub async fn shared_state(&self, db: &ObjectKey) -> Result<DatabaseState, Error> {
+        self.shared(
+            |burst| &burst.state,
+            db.as_str(),
+            || create::state(self, db),
+        )
+        .await
+    }
+
+    /// Stop sharing the state of `db`, which this instance just created.
+    pub fn forget_state(&self, db: &ObjectKey) {
+        self.burst.state.forget(db.as_str());
+    }
+
+    /// [Inner::probe_write] for the probe object `probe` of `prefix`, shared between the opens of
+    /// a burst.
+    pub async fn shared_probe(&self, prefix: &str, probe: &ObjectKey) -> Result<bool, Error> {
+        self.shared(|burst| &burst.writable, prefix, || self.probe_write(probe))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlite_vfs::{OpenAccess, OpenKind, OpenOptions, Vfs};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        mock::{self, MockS3},
+        vfs::{MetadataRecord, ThreeQLite},
+    };
+
+    async fn instance(mock: &MockS3, ttl: Duration) -> ThreeQLite {
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let config = Config {
+            open_burst: BurstConfig { ttl },
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let inner = tq.inner.read().await;
+        inner
+            .write_metadata_record(MetadataRecord::default())
+            .await
+            .unwrap();
+        drop(inner);
+        tq
+    }
+
+    async fn burst(tq: &ThreeQLite, opens: usize) {
+        let tasks: Vec<_> = (0..opens)
+            .map(|_| {
+                let tq = tq.clone();
+                tokio::spawn(async move {
+                    let opts = OpenOptions::new(OpenKind::MainDb, OpenAccess::Write);
+                    Box::pin(tq.open("test.db", opts)).await.map(drop)
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+    }
+
+    fn count(mock: &MockS3, method: &str, key: &str) -> usize {
+        let requests = mock.requests().into_iter();
+        requests.filter(|(m, k)| m == method && k == key).count()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_burst_shares_checks() {
+        let mock = MockS3::start();
+        let tq = instance(&mock, Duration::from_secs(60)).await;
+        burst(&tq, 200).await;
+
+        assert_eq!(count(&mock, "HEAD", "metadata"), 1);
+        assert_eq!(count(&mock, "HEAD", "test.db"), 1);
+        assert_eq!(count(&mock, "PUT", ".write-probe"), 1);
+        let stats = tq.stats().await;
+        assert!(stats.open_burst_hits >= 2 * 199, "{stats}");
+        assert!(stats.to_string().contains(" open_burst_hits="), "{stats}");
+
+        // a transaction still reads the metadata object
+        let gets = count(&mock, "GET", "metadata");
+        tq.inner.write().await.request_write_lock().await.unwrap();
+        assert!(count(&mock, "GET", "metadata") > gets);
+    }
+
+    #[tokio::test]
+    async fn test_shared_per_window() {
+        let mock = MockS3::start();
+        let tq = instance(&mock, Duration::from_millis(100)).await;
+        burst(&tq, 20).await;
+        assert_eq!(count(&mock, "HEAD", "metadata"), 1);
+        burst(&tq, 20).await;
+        assert_eq!(count(&mock, "HEAD", "metadata"), 1);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        burst(&tq, 20).await;
+        assert_eq!(count(&mock, "HEAD", "metadata"), 2);
+        assert_eq!(count(&mock, "HEAD", "test.db"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failures_not_shared() {
+        let mock = MockS3::start();
+        let tq = instance(&mock, Duration::from_secs(60)).await;
+        mock.reject("HEAD", 503);
+        let inner = tq.inner.read().await;
+        let db = ObjectKey::new("test.db").unwrap();
+        assert!(inner.shared_state(&db).await.is_err());
+        mock.restore("HEAD");
+        assert_eq!(
+            inner.shared_state(&db).await.unwrap(),
+            DatabaseState::Initialized
+        );
+        assert_eq!(inner.stats().open_burst_hits, 0);
+    }
+
+    #[tokio::test]
+    async fn test_created_database_not_shared_as_missing() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let open = |access| {
+            let tq = tq.clone();
+            async move {
+                let opts = OpenOptions::new(OpenKind::MainDb, access);
+                Box::pin(tq.open("test.db", opts)).await.map(drop)
+            }
+        };
+        assert!(open(OpenAccess::Write).await.is_err());
+        open(OpenAccess::Create).await.unwrap();
+        open(OpenAccess::Write).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "within the checks of an open")]
+    async fn test_commit_within_open_checks() {
+        let mock = MockS3::start();
+        let tq = instance(&mock, Duration::from_secs(60)).await;
+        scope(async {
+            let _ = tq.inner.write().await.request_write_lock().await;
+        })
+        .await;
+    }
+}
@@ -0,0 +1,443 @@
+//! Telling other instances about a commit as it happens.
+//!
+//! Without events, a peer learns about a new generation only on its next poll, see
+//! [crate::watch], and then drops every cached page of the database. An [InvalidationBus] carries
+//! a [GenerationUpdate] from the writer to its peers right after the commit barrier, with the
+//! ranges of the database object the commit changed. A [GenerationWatcher](crate::watch) that
+//! subscribed handles an update like an event of its source: it confirms the generation by
+//! reading the metadata object before notifying anyone, so a forged or stale update costs one
+//! request and changes nothing. If the update is for the generation right after the one it had,
+//! the pages it cached that the commit didn't change move to the new generation, and the changed
+//! ones are fetched again from S3 right away.
+//!
+//! Delivery is best-effort: updates can be late, duplicated or lost, e.g. while a subscriber
+//! reconnects. Duplicates are dropped by their generation, and the watcher keeps polling every
+//! [WatchConfig::poll_interval](crate::watch::WatchConfig::poll_interval), which remains the
+//! bound on staleness.
+//!
+//! [LoopbackBus] connects the instances of a process. [RedisBus], behind the `redis-bus`
+//! feature, publishes to a Redis channel.
+
+use std::{future::Future, ops::Range, pin::pin, sync::Mutex, task::Poll};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// A commit, as published by the instance that made it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenerationUpdate {
+    pub bucket: String,
+    pub db: String,
+    /// The generation the commit reached.
+    pub generation: u64,
+    /// The byte ranges of the database object the commit wrote, `None` if unknown, e.g. after
+    /// truncating it.
+    pub changed: Option<Vec<Range<u64>>>,
+}
+
+/// Carries [GenerationUpdate]s between instances, see the [module documentation](self).
+pub trait InvalidationBus: Send + Sync {
+    /// Send `update` to the subscribers, without waiting for it to be delivered.
+    fn publish(&self, update: GenerationUpdate);
+
+    /// The updates published from now on, by any instance.
+    fn subscribe(&self) -> UnboundedReceiver<GenerationUpdate>;
+}
+
+/// An [InvalidationBus] between the instances of a process.
+#[derive(Default)]
+pub struct LoopbackBus {
+    subscribers: Mutex<Vec<UnboundedSender<GenerationUpdate>>>,
+}
+
+impl InvalidationBus for LoopbackBus {
+    fn publish(&self, update: GenerationUpdate) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(update.clone()).is_ok());
+    }
+
+    fn subscribe(&self) -> UnboundedReceiver<GenerationUpdate> {
+        let (tx, rx) = unbounded_channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+/// The output of whichever of `a` and `b` is ready first, `a` if both are.
+pub(crate) async fn first<T>(a: impl Future<Output = T>, b: impl Future<Output = T>) -> T {
+    let (mut a, mut b) = (pin!(a), pin!(b));
+    std::future::poll_fn(|cx| match a.as_mut().poll(cx) {
+        Poll::Ready(out) => Poll::Ready(out),
+        Poll::Pending => b.as_mut().poll(cx),
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, sync::Arc, time::Duration};
+
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::{
+        cache::{CacheConfig, CacheUse},
+        config::Config,
+        flush::PendingWrites,
+        key::KeyLayout,
+        mock::{self, MockS3},
+        vfs::{MetadataRecord, ThreeQLite},
+        watch::{NoEvents, WatchConfig},
+    };
+
+    const PAGE: usize = 4096;
+
+    fn config() -> Config {
+        Config {
+            cache: CacheConfig {
+                capacity: 1024 * 1024,
+                ..CacheConfig::default()
+            },
+            watch: WatchConfig {
+                poll_interval: Duration::from_secs(10),
+                ..WatchConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    async fn flush(tq: &ThreeQLite, pages: &[(u64, u8)]) {
+        let mut pending = PendingWrites::default();
+        for (page, byte) in pages {
+            pending.write(page * PAGE as u64, &[*byte; PAGE]);
+        }
+        let db = KeyLayout::db("test.db").unwrap();
+        tq.flush_pages(&db, &Arc::new(pending)).await.unwrap();
+    }
+
+    fn gets(mock: &MockS3) -> usize {
+        let requests = mock.requests().into_iter();
+        requests
+            .filter(|(method, key)| method == "GET" && key == "test.db")
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_loopback() {
+        let bus = LoopbackBus::default();
+        let mut a = bus.subscribe();
+        drop(bus.subscribe());
+        let update = GenerationUpdate {
+            bucket: "threeqlite".to_owned(),
+            db: "test.db".to_owned(),
+            generation: 2,
+            changed: None,
+        };
+        bus.publish(update.clone());
+        assert_eq!(a.recv().await, Some(update));
+        // the subscriber that went away is dropped
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_peer_refreshes_changed_pages() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(PAGE as u32, 4, 1));
+        let bus: Arc<dyn InvalidationBus> = Arc::new(LoopbackBus::default());
+        let writer = ThreeQLite::with_client(config(), mock.client()).unwrap();
+        let peer = ThreeQLite::with_client(config(), mock.client()).unwrap();
+        writer.publish_commits(bus.clone()).await;
+        peer.publish_commits(bus.clone()).await;
+        writer
+            .inner
+            .read()
+            .await
+            .write_metadata_record(MetadataRecord::default())
+            .await
+            .unwrap();
+        flush(&writer, &[(1, 1)]).await;
+
+        let mut watcher = peer.generation_watcher(None::<NoEvents>).await;
+        let generation = watcher.generation().unwrap();
+        let inner = peer.inner.read().await.clone();
+        for page in 0..4 {
+            let offset = page * PAGE;
+            inner
+                .read_at(offset, PAGE, Some(generation), CacheUse::Admit)
+                .await
+                .unwrap();
+        }
+
+        let mut published = bus.subscribe();
+        flush(&writer, &[(1, 7), (3, 7)]).await;
+        let update = published.recv().await.unwrap();
+        assert_eq!(update.generation, generation + 1);
+        assert_eq!(update.changed, Some(vec![4096..8192, 12288..16384]));
+
+        let invalidations = RefCell::new(vec![]);
+        let mut on_change = |generation| invalidations.borrow_mut().push(generation);
+        let start = Instant::now();
+        watcher.step(&mut on_change).await;
+        // well within the poll interval
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(*invalidations.borrow(), [generation + 1]);
+
+        // the unchanged pages moved to the new generation, the changed ones were fetched again
+        let before = gets(&mock);
+        for page in 0..4 {
+            let offset = page * PAGE;
+            let data = inner
+                .read_at(offset, PAGE, Some(generation + 1), CacheUse::Admit)
+                .await
+                .unwrap();
+            if page % 2 == 1 {
+                assert_eq!(data, [7; PAGE]);
+            }
+        }
+        assert_eq!(gets(&mock), before);
+
+        // delivered again: nothing is invalidated or counted twice
+        bus.publish(update.clone());
+        watcher.step(&mut on_change).await;
+        assert_eq!(*invalidations.borrow(), [generation + 1]);
+        let stats = peer.stats().await;
+        assert_eq!(
+            (
+                stats.notifications_received,
+                stats.notifications_confirmed,
+                stats.notifications_spurious
+            ),
+            (1, 1, 0)
+        );
+    }
+}
+
+#[cfg(feature = "redis-bus")]
+pub use redis::RedisBus;
+
+#[cfg(feature = "redis-bus")]
+mod redis {
+    use std::{io, sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+        sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    };
+
+    use super::{first, GenerationUpdate, InvalidationBus};
+
+    /// An [InvalidationBus] on a Redis channel, speaking RESP over plain TCP. Each update is
+    /// published as JSON; every subscription holds a connection of its own, reconnecting after
+    /// [RedisBus::reconnect] while Redis is unreachable.
+    pub struct RedisBus {
+        /// `host:port` of the Redis server.
+        pub authority: String,
+        pub channel: String,
+        pub reconnect: Duration,
+        /// The connection updates are published on, opened by the first.
+        publisher: Arc<tokio::sync::Mutex<Option<BufReader<TcpStream>>>>,
+    }
+
+    impl RedisBus {
+        pub fn new(authority: impl Into<String>, channel: impl Into<String>) -> Self {
+            Self {
+                authority: authority.into(),
+                channel: channel.into(),
+                reconnect: Duration::from_secs(1),
+                publisher: Arc::default(),
+            }
+        }
+    }
+
+    impl InvalidationBus for RedisBus {
+        fn publish(&self, update: GenerationUpdate) {
+            let payload = serde_json::to_string(&update).unwrap();
+            let (authority, channel) = (self.authority.clone(), self.channel.clone());
+            let publisher = self.publisher.clone();
+            tokio::spawn(async move {
+                let mut conn = publisher.lock().await;
+                let res = async {
+                    if conn.is_none() {
+                        *conn = Some(BufReader::new(TcpStream::connect(&authority).await?));
+                    }
+                    let conn = conn.as_mut().unwrap();
+                    send(conn, &["PUBLISH", &channel, &payload]).await?;
+                    read_reply(conn).await
+                }
+                .await;
+                if let Err(err) = res {
+                    *conn = None;
+                    tracing::debug!(target: "threeqlite::bus", %authority, %err, "publishing an update failed");
+                }
+            });
+        }
+
+        fn subscribe(&self) -> UnboundedReceiver<GenerationUpdate> {
+            let (tx, rx) = unbounded_channel();
+            let (authority, channel) = (self.authority.clone(), self.channel.clone());
+            let reconnect = self.reconnect;
+            tokio::spawn(async move {
+                while !tx.is_closed() {
+                    // the subscriber going away ends the subscription
+                    let closed = async {
+                        tx.closed().await;
+                        Ok(())
+                    };
+                    if let Err(err) = first(closed, listen(&authority, &channel, &tx)).await {
+                        tracing::debug!(target: "threeqlite::bus", %authority, %err, "subscription failed, reconnecting");
+                    }
+                    tokio::time::sleep(reconnect).await;
+                }
+            });
+            rx
+        }
+    }
+
+    /// Forward the messages of `channel` to `tx` until the connection fails.
+    async fn listen(
+        authority: &str,
+        channel: &str,
+        tx: &UnboundedSender<GenerationUpdate>,
+    ) -> io::Result<()> {
+        let mut conn = BufReader::new(TcpStream::connect(authority).await?);
+        send(&mut conn, &["SUBSCRIBE", channel]).await?;
+        loop {
+            let reply = read_reply(&mut conn).await?;
+            let [kind, _, payload] = reply.as_slice() else {
+                continue;
+            };
+            if kind.as_slice() != b"message" {
+                continue;
+            }
+            match serde_json::from_slice(payload) {
+                Ok(update) => {
+                    let _ = tx.send(update);
+                }
+                Err(err) => {
+                    tracing::debug!(target: "threeqlite::bus", %err, "ignoring an invalid update")
+                }
+            }
+        }
+    }
+
+    /// Send a command as an array of bulk strings.
+    async fn send(conn: &mut BufReader<TcpStream>, args: &[&str]) -> io::Result<()> {
+        let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            buf.extend(format!("${}\r\n", arg.len()).bytes());
+            buf.extend(arg.bytes());
+            buf.extend(b"\r\n");
+        }
+        conn.get_mut().write_all(&buf).await
+    }
+
+    /// Read a reply, a scalar or an array of scalars, as the values of its elements.
+    async fn read_reply(conn: &mut BufReader<TcpStream>) -> io::Result<Vec<Vec<u8>>> {
+        let line = read_line(conn).await?;
+        match line.split_first() {
+            Some((b'*', len)) => {
+                let len = parse_len(len)?;
+                let mut values = Vec::with_capacity(len.min(16));
+                for _ in 0..len {
+                    let line = read_line(conn).await?;
+                    values.push(read_scalar(conn, line).await?);
+                }
+                Ok(values)
+            }
+            _ => Ok(vec![read_scalar(conn, line).await?]),
+        }
+    }
+
+    async fn read_scalar(conn: &mut BufReader<TcpStream>, line: Vec<u8>) -> io::Result<Vec<u8>> {
+        match line.split_first() {
+            Some((b'+' | b':', value)) => Ok(value.to_vec()),
+            Some((b'-', message)) => Err(io::Error::other(String::from_utf8_lossy(message))),
+            Some((b'$', len)) if len == b"-1" => Ok(vec![]),
+            Some((b'$', len)) => {
+                let len = parse_len(len)?;
+                let mut value = vec![0; len + 2];
+                tokio::io::AsyncReadExt::read_exact(conn, &mut value).await?;
+                value.truncate(len);
+                Ok(value)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected reply from Redis",
+            )),
+        }
+    }
+
+    async fn read_line(conn: &mut BufReader<TcpStream>) -> io::Result<Vec<u8>> {
+        let mut line = vec![];
+        if conn.read_until(b'\n', &mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        while line.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+            line.pop();
+        }
+        Ok(line)
+    }
+
+    fn parse_len(len: &[u8]) -> io::Result<usize> {
+        std::str::from_utf8(len)
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid length"))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use tokio::net::TcpListener;
+
+        use super::*;
+
+        /// A Redis server that knows one channel: it answers `PUBLISH` and forwards what was
+        /// published to the one subscriber.
+        async fn serve(listener: TcpListener) {
+            let (subscriber, _) = listener.accept().await.unwrap();
+            let mut subscriber = BufReader::new(subscriber);
+            let subscribe = read_reply(&mut subscriber).await.unwrap();
+            assert_eq!(subscribe, [b"SUBSCRIBE".to_vec(), b"commits".to_vec()]);
+            subscriber
+                .get_mut()
+                .write_all(b"*3\r\n$9\r\nsubscribe\r\n$7\r\ncommits\r\n:1\r\n")
+                .await
+                .unwrap();
+            let (publisher, _) = listener.accept().await.unwrap();
+            let mut publisher = BufReader::new(publisher);
+            loop {
+                let Ok(command) = read_reply(&mut publisher).await else {
+                    return;
+                };
+                publisher.get_mut().write_all(b":1\r\n").await.unwrap();
+                let mut message = b"*3\r\n$7\r\nmessage\r\n$7\r\ncommits\r\n".to_vec();
+                message.extend(format!("${}\r\n", command[2].len()).bytes());
+                message.extend(&command[2]);
+                message.extend(b"\r\n");
+                subscriber.get_mut().write_all(&message).await.unwrap();
+            }
+        }
+
+        #[tokio::test]
+        async fn test_redis_bus() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let bus = RedisBus::new(listener.local_addr().unwrap().to_string(), "commits");
+            let server = tokio::spawn(serve(listener));
+            let mut updates = bus.subscribe();
+            // subscribed before publishing
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let update = GenerationUpdate {
+                bucket: "threeqlite".to_owned(),
+                db: "test.db".to_owned(),
+                generation: 7,
+                changed: Some(vec![0..4096, 8192..12288]),
+            };
+            bus.publish(update.clone());
+            assert_eq!(updates.recv().await, Some(update));
+            server.abort();
+        }
+    }
+}
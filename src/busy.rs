@@ -0,0 +1,466 @@
+//! Why a database is busy.
+//!
+//! Every writer describes itself with a [Holder] in the user metadata of the metadata object, next
+//! to its write request or lock. An instance that gives up waiting for the lock after
+//! [LockConfig::busy_timeout] reports an [Error::Busy] naming whoever it waited for, and
+//! [ThreeQLite::why_busy] reads the same on demand. Contention is local when the holder is this
+//! instance itself, e.g. another connection of the same process, and remote otherwise.
+//!
+//! A holder also names its process as a [LocalProcess]. A writer that finds the lock held by a
+//! process of its own machine that exited, e.g. one killed in the middle of a transaction, takes
+//! the lock over rather than wait for it forever, unless
+//! [LockConfig::take_over_dead_writers] is unset. Holders on other machines, and processes that
+//! can't be looked up, are always taken to be alive. Nor is the lock taken over while the
+//! journal of the database exists: the writer may have died with part of its pages uploaded, and
+//! readers let in would see them. It stays held until the journal is rolled back and deleted, see
+//! [crate::recovery].
+//!
+//! Between attempts, a handle also asks the busy handler of its connection, e.g. the one
+//! installed by `PRAGMA busy_timeout`, and gives up once it refuses, as SQLite does waiting for
+//! its own locks. Both limits apply, whichever is reached first. See [sqlite_vfs::busy].
+//!
+//! [LockConfig::busy_timeout]: crate::config::LockConfig::busy_timeout
+//! [LockConfig::take_over_dead_writers]: crate::config::LockConfig::take_over_dead_writers
+//! [Error::Busy]: crate::error::Error::Busy
+//! [ThreeQLite::why_busy]: crate::vfs::ThreeQLite::why_busy
+
+use std::{collections::HashMap, fmt, future::Future, io::ErrorKind, time::Duration};
+
+use sqlite_vfs::busy::BusyHandlerRef;
+
+use crate::{
+    discover::LockStatus,
+    registration,
+    vfs::{Metadata, MetadataRecord},
+};
+
+const HOLDER: &str = "threeqlite-holder";
+const HOLDER_HOST: &str = "threeqlite-holder-host";
+const HOLDER_SINCE: &str = "threeqlite-holder-since";
+const HOLDER_EPOCH: &str = "threeqlite-holder-epoch";
+const HOLDER_RELEASE: &str = "threeqlite-holder-release";
+const HOLDER_PROCESS: &str = "threeqlite-holder-process";
+
+tokio::task_local! {
+    /// The busy handler of the connection waiting for the lock, see [with_handler].
+    static HANDLER: Option<BusyHandlerRef>;
+}
+
+/// Run `fut`, asking `handler` whether to keep waiting for the lock, see [keep_waiting]. `fut` is
+/// boxed, since the storage futures it wraps are large enough to overflow the stack of a debug
+/// build once nested in a handle's.
+pub async fn with_handler<F: Future>(handler: Option<BusyHandlerRef>, fut: F) -> F::Output {
+    HANDLER.scope(handler, Box::pin(fut)).await
+}
+
+/// Whether to keep waiting for the lock after another failed attempt, according to the busy
+/// handler of the connection, if it passed one.
+pub fn keep_waiting() -> bool {
+    HANDLER
+        .try_with(|handler| handler.as_ref().is_none_or(BusyHandlerRef::invoke))
+        .unwrap_or(true)
+}
+
+/// The writer holding or requesting the lock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Holder {
+    /// [LockConfig::identity](crate::config::LockConfig::identity) of the writer.
+    pub identity: String,
+    pub hostname: String,
+    /// When the writer started waiting for the lock, in milliseconds since the Unix epoch.
+    pub since: u64,
+    /// The generation the writer found when it started.
+    pub epoch: u64,
+    /// When the writer expects to release the lock, in milliseconds since the Unix epoch, see
+    /// [crate::wait::expecting_hold].
+    pub expected_release: Option<u64>,
+    /// The process of the writer, `None` if it couldn't be named or the writer predates it.
+    pub process: Option<LocalProcess>,
+}
+
+impl Holder {
+    pub fn from_metadata(metadata: Option<&HashMap<String, String>>) -> Option<Self> {
+        let metadata = metadata?;
+        Some(Self {
+            identity: metadata.get(HOLDER)?.clone(),
+            hostname: metadata.get(HOLDER_HOST).cloned().unwrap_or_default(),
+            since: metadata.get(HOLDER_SINCE)?.parse().ok()?,
+            epoch: metadata.get(HOLDER_EPOCH)?.parse().ok()?,
+            expected_release: metadata
+                .get(HOLDER_RELEASE)
+                .and_then(|release| release.parse().ok()),
+            process: metadata
+                .get(HOLDER_PROCESS)
+                .and_then(|process| LocalProcess::parse(process)),
+        })
+    }
+
+    pub fn to_metadata(&self) -> HashMap<String, String> {
+        let mut metadata = HashMap::from([
+            (HOLDER.to_owned(), self.identity.clone()),
+            (HOLDER_HOST.to_owned(), self.hostname.clone()),
+            (HOLDER_SINCE.to_owned(), self.since.to_string()),
+            (HOLDER_EPOCH.to_owned(), self.epoch.to_string()),
+        ]);
+        if let Some(release) = self.expected_release {
+            metadata.insert(HOLDER_RELEASE.to_owned(), release.to_string());
+        }
+        if let Some(process) = &self.process {
+            metadata.insert(HOLDER_PROCESS.to_owned(), process.to_string());
+        }
+        metadata
+    }
+}
+
+/// A process, named so that other processes of the same machine can tell whether it still runs.
+/// PIDs are only meaningful within a boot of a machine and a PID namespace, so those are part of
+/// the name. Only known on Linux.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalProcess {
+    /// The boot ID of the machine and the PID namespace, e.g.
+    /// `9e0e8c4f-6a0e-4a5c-9d3e-2f1b7c0a8d11/pid:[4026531836]`.
+    pub namespace: String,
+    pub pid: u32,
+}
+
+impl LocalProcess {
+    /// This process, `None` where processes can't be told apart.
+    pub fn current() -> Option<Self> {
+        let boot = std::fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+        let namespace = std::fs::read_link("/proc/self/ns/pid").ok()?;
+        Some(Self {
+            namespace: format!("{}/{}", boot.trim(), namespace.display()),
+            pid: std::process::id(),
+        })
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        let (namespace, pid) = name.rsplit_once('#')?;
+        Some(Self {
+            namespace: namespace.to_owned(),
+            pid: pid.parse().ok()?,
+        })
+    }
+
+    /// Whether the process is known to have exited: it ran next to this one, and there is no
+    /// process of its PID left but a zombie. Processes are only visible if `/proc` isn't mounted
+    /// with `hidepid`, which is checked by looking up PID 1.
+    pub fn exited(&self) -> bool {
+        let Some(current) = Self::current() else {
+            return false;
+        };
+        if current.namespace != self.namespace || current.pid == self.pid {
+            return false;
+        }
+        let stat = |pid: u32| std::fs::read_to_string(format!("/proc/{pid}/stat"));
+        if stat(1).is_err() {
+            return false;
+        }
+        match stat(self.pid) {
+            Ok(stat) => stat
+                .rsplit_once(')')
+                .is_some_and(|(_, state)| state.trim_start().starts_with('Z')),
+            Err(err) => err.kind() == ErrorKind::NotFound,
+        }
+    }
+}
+
+impl fmt::Display for LocalProcess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#{}", self.namespace, self.pid)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Contention {
+    /// The lock is held by this instance.
+    Local,
+    /// The lock is held by another instance, or by one that doesn't describe itself.
+    Remote,
+}
+
+impl std::fmt::Display for Contention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Contention::Local => "local",
+            Contention::Remote => "remote",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BusyDiagnosis {
+    pub contention: Contention,
+    pub lock: LockStatus,
+    /// The writer holding or requesting the lock. `None` while only readers are active, or if
+    /// the writer predates holder descriptors.
+    pub holder: Option<Holder>,
+    /// How long the holder has been at it.
+    pub held_for: Option<Duration>,
+}
+
+impl std::fmt::Display for BusyDiagnosis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} contention, lock is {}", self.contention, self.lock)?;
+        if let Some(holder) = &self.holder {
+            write!(f, "; held by {}", holder.identity)?;
+            if !holder.hostname.is_empty() {
+                write!(f, " on {}", holder.hostname)?;
+            }
+            if let Some(held_for) = self.held_for {
+                write!(f, " for {:.1}s", held_for.as_secs_f64())?;
+            }
+            write!(f, " since generation {}", holder.epoch)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why the lock in `record` keeps this instance waiting, `None` if nothing holds it.
+/// `identity` and `own_lock` are the identity and the lock ID of this instance.
+pub fn diagnose(
+    record: &MetadataRecord,
+    identity: &str,
+    own_lock: Option<&[u8]>,
+    now: u64,
+) -> Option<BusyDiagnosis> {
+    let lock = LockStatus::of(record, now);
+    if lock == LockStatus::Idle {
+        return None;
+    }
+    let holder = match lock {
+        LockStatus::Writing | LockStatus::WriteRequested { .. } => record.holder.clone(),
+        LockStatus::Idle | LockStatus::Reading { .. } => None,
+    };
+    let own_writer = matches!(
+        (&record.metadata, own_lock),
+        (Metadata::Writer(id), Some(own)) if id == own
+    );
+    let own_holder = holder
+        .as_ref()
+        .is_some_and(|h| registration::is_own(&h.identity, identity));
+    let contention = match own_holder || own_writer {
+        true => Contention::Local,
+        false => Contention::Remote,
+    };
+    let held_for = holder
+        .as_ref()
+        .map(|holder| Duration::from_millis(now.saturating_sub(holder.since)));
+    Some(BusyDiagnosis {
+        contention,
+        lock,
+        holder,
+        held_for,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicI32, Ordering},
+        time::Instant,
+    };
+
+    use super::*;
+    use crate::{
+        config::{Config, LockConfig},
+        error::Error,
+        mock::MockS3,
+        protocol::{self, WriteRequest},
+        vfs::ThreeQLite,
+    };
+
+    fn holder(identity: &str) -> Holder {
+        Holder {
+            identity: identity.to_owned(),
+            hostname: "host-a".to_owned(),
+            since: 1_000,
+            epoch: 41,
+            expected_release: None,
+            process: None,
+        }
+    }
+
+    #[test]
+    fn test_holder_metadata_roundtrip() {
+        let mut holder = holder("billing-7");
+        assert_eq!(
+            Holder::from_metadata(Some(&holder.to_metadata())),
+            Some(holder.clone())
+        );
+        holder.expected_release = Some(61_000);
+        assert_eq!(
+            Holder::from_metadata(Some(&holder.to_metadata())),
+            Some(holder)
+        );
+        assert_eq!(Holder::from_metadata(Some(&HashMap::new())), None);
+        assert_eq!(Holder::from_metadata(None), None);
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let idle = MetadataRecord::default();
+        assert_eq!(diagnose(&idle, "me", None, 5_000), None);
+
+        let writing = MetadataRecord {
+            holder: Some(holder("billing-7")),
+            ..protocol::acquire(idle.clone(), &[1])
+        };
+        let remote = diagnose(&writing, "me", None, 13_500).unwrap();
+        assert_eq!(remote.contention, Contention::Remote);
+        assert_eq!(remote.lock, LockStatus::Writing);
+        assert_eq!(remote.held_for, Some(Duration::from_millis(12_500)));
+        assert_eq!(
+            remote.to_string(),
+            "remote contention, lock is writing; held by billing-7 on host-a for 12.5s since \
+             generation 41"
+        );
+
+        // held by this instance, whether it described itself or not
+        let local = diagnose(&writing, "billing-7", None, 13_500).unwrap();
+        assert_eq!(local.contention, Contention::Local);
+        let legacy = MetadataRecord {
+            holder: None,
+            ..writing.clone()
+        };
+        assert_eq!(
+            diagnose(&legacy, "me", Some(&[1]), 0).unwrap().contention,
+            Contention::Local
+        );
+        assert_eq!(
+            diagnose(&legacy, "me", Some(&[2]), 0).unwrap().contention,
+            Contention::Remote
+        );
+
+        // readers are not described
+        let reading = protocol::join(writing.clone(), &[2]);
+        let diagnosis = diagnose(&reading, "me", None, 0).unwrap();
+        assert_eq!(diagnosis.lock, LockStatus::Reading { readers: 1 });
+        assert_eq!(diagnosis.holder, None);
+
+        // a live request names the writer waiting for the readers
+        let request = WriteRequest {
+            id: vec![3],
+            expires: 60_000,
+        };
+        let requested = MetadataRecord {
+            holder: Some(holder("billing-7")),
+            ..protocol::request(reading, request)
+        };
+        let diagnosis = diagnose(&requested, "me", None, 2_000).unwrap();
+        assert_eq!(diagnosis.lock, LockStatus::WriteRequested { readers: 1 });
+        assert_eq!(diagnosis.holder.unwrap().identity, "billing-7");
+    }
+
+    fn instance(mock: &MockS3, lock: LockConfig) -> ThreeQLite {
+        let config = Config {
+            lock,
+            ..Config::default()
+        };
+        ThreeQLite::with_client(config, mock.client()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_busy_error_names_remote_holder() {
+        let mock = MockS3::start();
+        let other = instance(
+            &mock,
+            LockConfig {
+                identity: "billing-worker-7".to_owned(),
+                ..LockConfig::default()
+            },
+        );
+        let tq = instance(
+            &mock,
+            LockConfig {
+                busy_timeout: Some(Duration::ZERO),
+                ..LockConfig::default()
+            },
+        );
+
+        // the other instance holds the write lock
+        let holder = Holder {
+            identity: "billing-worker-7".to_owned(),
+            hostname: "host-b".to_owned(),
+            since: protocol::now_ms() - 5_000,
+            epoch: 3,
+            expected_release: None,
+            process: None,
+        };
+        let record = MetadataRecord {
+            holder: Some(holder.clone()),
+            ..protocol::acquire(MetadataRecord::default(), &[7])
+        };
+        other
+            .inner
+            .read()
+            .await
+            .write_metadata_record(record)
+            .await
+            .unwrap();
+
+        let diagnosis = tq.why_busy("test.db").await.unwrap().unwrap();
+        assert_eq!(diagnosis.contention, Contention::Remote);
+        assert_eq!(diagnosis.lock, LockStatus::Writing);
+        assert_eq!(diagnosis.holder, Some(holder));
+        assert!(diagnosis.held_for.unwrap() >= Duration::from_secs(5));
+        assert_eq!(tq.why_busy("other.db").await.unwrap(), None);
+        let own = other.why_busy("test.db").await.unwrap().unwrap();
+        assert_eq!(own.contention, Contention::Local);
+
+        // giving up on the lock reports the holder to SQLite
+        let err = {
+            let inner = tq.inner.read().await;
+            let record = inner.read_metadata_record().await.unwrap();
+            inner.check_busy(&record, Instant::now()).unwrap_err()
+        };
+        let message = sqlite_vfs::error::Error::Busy { cause: err }.describe();
+        assert!(message.contains("remote contention"), "{message}");
+        assert!(
+            message.contains("held by billing-worker-7 on host-b"),
+            "{message}"
+        );
+        assert!(message.contains("since generation 3"), "{message}");
+        let stats = tq.stats().await;
+        assert_eq!((stats.busy_local, stats.busy_remote), (0, 1));
+    }
+
+    /// Counts its invocations in `arg`, allowing two.
+    unsafe extern "C" fn two(arg: *mut std::ffi::c_void) -> std::os::raw::c_int {
+        let count = &*(arg as *const AtomicI32);
+        (count.fetch_add(1, Ordering::Relaxed) < 2) as std::os::raw::c_int
+    }
+
+    #[tokio::test]
+    async fn test_busy_handler_ends_the_wait() {
+        let mock = MockS3::start();
+        let tq = instance(&mock, LockConfig::default());
+        let record = protocol::acquire(MetadataRecord::default(), &[7]);
+
+        // without a busy timeout, only the handler ends the wait
+        let count = AtomicI32::new(0);
+        let handler = unsafe { BusyHandlerRef::new(two, &count as *const _ as *mut _) };
+        let _scope = handler.enter();
+        let (attempts, err) = {
+            let inner = tq.inner.read().await;
+            let start = Instant::now();
+            with_handler(Some(handler.clone()), async {
+                let mut attempts = 1;
+                loop {
+                    match inner.check_busy(&record, start) {
+                        Ok(()) => attempts += 1,
+                        Err(err) => return (attempts, err),
+                    }
+                }
+            })
+            .await
+        };
+        assert!(matches!(err, Error::Busy { .. }), "{err}");
+        assert_eq!((attempts, count.load(Ordering::Relaxed)), (3, 3));
+        assert_eq!(tq.stats().await.busy_remote, 1);
+
+        // outside of `with_handler`, the handler isn't asked
+        assert!(keep_waiting());
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+}
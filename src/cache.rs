@@ -0,0 +1,966 @@
+//! A cache of pages read from the database object, resistant to scans.
+//!
+//! Pages are cached by the generation they were read at (see [crate::heal]), which a reader
+//! learns when it registers in the metadata object, so that a commit by any instance retires
+//! them. Reads that don't register (read-only handles, degraded reads) bypass the cache, as do
+//! bulk transfers, which don't read pages but whole objects through [crate::fetch]. Writes of
+//! this instance drop the pages they overlap.
+//!
+//! With [CachePolicy::Lru], every page read enters the cache as most recently used, so a single
+//! full scan evicts the working set of transactional traffic. With [CachePolicy::Segmented],
+//! pages enter a probationary segment and only graduate to the protected segment, sized by
+//! [CacheConfig::protected_share], when read again; eviction takes pages from the least recently
+//! used end of the probationary segment first. Pages of reads a [ScanDetector] tags as
+//! sequential enter at that end, so a scan recycles its own pages and never touches the
+//! protected segment. [CacheConfig::sequential] decides whether they are admitted at all.
+//!
+//! Cached pages are charged to the [MemoryBudget] of the instance. Under memory pressure the
+//! cache gives up its probationary, then its protected pages, see [crate::memory]. A page that
+//! can't be charged isn't admitted.
+//!
+//! Pages fetched ahead of SQLite asking for them, see [crate::prefetch], enter the probationary
+//! segment like any other page. Their first read counts as a prefetch hit rather than a hit of
+//! its segment, so that [CacheStats::prefetch_hit_ratio] tells how many of them were needed.
+//!
+//! SQLite reads small slices of the database header again and again, e.g. the change counter
+//! whenever it takes a shared lock. Independently of the pages, the first [HEADER_LEN] bytes of
+//! each database are pinned as of the generation they were read at, and serve any read within
+//! them at that generation, even with the cache disabled. A flush of this instance patches the
+//! pin with what it wrote, and the commit moves it to the next generation, so that the writer
+//! never reads its own header back. Pins are neither evicted nor charged to the budget.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use crate::memory::{Charge, Component, MemoryBudget, Relief};
+
+/// The length of the pinned header of a database, that of the SQLite database header.
+pub const HEADER_LEN: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Plain least-recently-used replacement.
+    Lru,
+    /// Segmented LRU, see the [module documentation](self).
+    Segmented,
+}
+
+/// How the pages of sequential reads enter the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SequentialAdmission {
+    /// At the least recently used end of the probationary segment.
+    Probation,
+    /// Not at all.
+    Bypass,
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// Bytes of pages cached per instance. The cache is off if 0.
+    pub capacity: u64,
+    pub policy: CachePolicy,
+    /// Share of [CacheConfig::capacity] the protected segment may take.
+    pub protected_share: f64,
+    pub sequential: SequentialAdmission,
+    /// Consecutive reads, each starting where the previous one ended, after which reads are
+    /// sequential.
+    pub sequential_after: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 0,
+            policy: CachePolicy::Segmented,
+            protected_share: 0.8,
+            sequential: SequentialAdmission::Probation,
+            sequential_after: 4,
+        }
+    }
+}
+
+/// How a read of the database object uses the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheUse {
+    /// Served from the cache if possible, and cached otherwise.
+    Admit,
+    /// Part of a scan, see [SequentialAdmission].
+    Sequential,
+    /// Neither served from nor admitted to the cache.
+    Bypass,
+    /// Fetched ahead of being read, see [crate::prefetch].
+    Prefetch,
+}
+
+/// Why a page left the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionCause {
+    /// Evicted to make room.
+    Capacity,
+    /// Overwritten or truncated by this instance.
+    Written,
+    /// A newer generation of the database was read.
+    Superseded,
+    /// Evicted to relieve memory pressure, see [crate::memory].
+    Pressure,
+}
+
+impl EvictionCause {
+    pub const ALL: [EvictionCause; 4] = [
+        EvictionCause::Capacity,
+        EvictionCause::Written,
+        EvictionCause::Superseded,
+        EvictionCause::Pressure,
+    ];
+}
+
+impl std::fmt::Display for EvictionCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EvictionCause::Capacity => "capacity",
+            EvictionCause::Written => "written",
+            EvictionCause::Superseded => "superseded",
+            EvictionCause::Pressure => "pressure",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Segment {
+    Probation,
+    Protected,
+}
+
+/// Counters of a [PageCache].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub probation_hits: u64,
+    pub protected_hits: u64,
+    pub misses: u64,
+    /// Pages admitted to the probationary segment, or to the cache with [CachePolicy::Lru].
+    pub admissions: u64,
+    /// Pages of sequential reads admitted.
+    pub sequential_admissions: u64,
+    /// Reads that bypassed the cache.
+    pub bypassed: u64,
+    /// Pages moved to the protected segment on being read again.
+    pub promotions: u64,
+    /// Pages moved back to the probationary segment to make room in the protected one.
+    pub demotions: u64,
+    pub probation_bytes: u64,
+    pub protected_bytes: u64,
+    /// Pages evicted per [EvictionCause].
+    pub evictions: [u64; EvictionCause::ALL.len()],
+    /// Reads served by a pinned header.
+    pub header_hits: u64,
+    /// Pages admitted by a prefetch.
+    pub prefetched: u64,
+    /// First reads of prefetched pages, not counted as hits of their segment.
+    pub prefetch_hits: u64,
+}
+
+impl CacheStats {
+    pub fn hits(&self) -> u64 {
+        self.probation_hits + self.protected_hits + self.prefetch_hits
+    }
+
+    /// The share of prefetched pages read before leaving the cache, `None` before the first
+    /// prefetch.
+    pub fn prefetch_hit_ratio(&self) -> Option<f64> {
+        (self.prefetched > 0).then(|| self.prefetch_hits as f64 / self.prefetched as f64)
+    }
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "probation_hits={} protected_hits={} misses={} admissions={} sequential_admissions={} bypassed={} promotions={} demotions={} probation_bytes={} protected_bytes={}",
+            self.probation_hits,
+            self.protected_hits,
+            self.misses,
+            self.admissions,
+            self.sequential_admissions,
+            self.bypassed,
+            self.promotions,
+            self.demotions,
+            self.probation_bytes,
+            self.protected_bytes,
+        )?;
+        for cause in EvictionCause::ALL {
+            write!(f, " evicted_{cause}={}", self.evictions[cause as usize])?;
+        }
+        write!(f, " header_hits={}", self.header_hits)?;
+        if let Some(ratio) = self.prefetch_hit_ratio() {
+            write!(
+                f,
+                " prefetched={} prefetch_hits={} prefetch_hit_ratio={ratio:.2}",
+                self.prefetched, self.prefetch_hits
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A page as cached, see [PageCache::pages].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedPage {
+    pub offset: u64,
+    pub data: Vec<u8>,
+    /// Reads it served since it was admitted.
+    pub hits: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct PageKey {
+    db: String,
+    generation: u64,
+    offset: u64,
+    len: usize,
+}
+
+/// The header of a database as of a generation.
+#[derive(Debug)]
+struct HeaderPin {
+    generation: u64,
+    bytes: Vec<u8>,
+    /// Written by a flush of this instance whose commit hasn't moved it to its generation yet.
+    committing: bool,
+}
+
+#[derive(Debug)]
+struct Entry {
+    data: Vec<u8>,
+    /// What `data` is charged to the budget.
+    charge: Charge,
+    segment: Segment,
+    /// Position in the recency order of its segment, most recently used last.
+    tick: i64,
+    /// Prefetched and not read since.
+    prefetched: bool,
+    /// Reads served since it was admitted.
+    hits: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<PageKey, Entry>,
+    probation: BTreeMap<i64, PageKey>,
+    protected: BTreeMap<i64, PageKey>,
+    /// The next tick of the most recently used end.
+    next: i64,
+    /// The next tick of the least recently used end.
+    cold: i64,
+    /// The newest generation read per database.
+    newest: HashMap<String, u64>,
+    headers: HashMap<String, HeaderPin>,
+    stats: CacheStats,
+}
+
+impl State {
+    fn order(&mut self, segment: Segment) -> &mut BTreeMap<i64, PageKey> {
+        match segment {
+            Segment::Probation => &mut self.probation,
+            Segment::Protected => &mut self.protected,
+        }
+    }
+
+    fn bytes(&mut self, segment: Segment) -> &mut u64 {
+        match segment {
+            Segment::Probation => &mut self.stats.probation_bytes,
+            Segment::Protected => &mut self.stats.protected_bytes,
+        }
+    }
+
+    fn link(
+        &mut self,
+        key: PageKey,
+        data: Vec<u8>,
+        charge: Charge,
+        segment: Segment,
+        hot: bool,
+    ) -> &mut Entry {
+        let tick = match hot {
+            true => {
+                self.next += 1;
+                self.next
+            }
+            false => {
+                self.cold -= 1;
+                self.cold
+            }
+        };
+        *self.bytes(segment) += data.len() as u64;
+        self.order(segment).insert(tick, key.clone());
+        self.entries
+            .entry(key)
+            .insert_entry(Entry {
+                data,
+                charge,
+                segment,
+                tick,
+                prefetched: false,
+                hits: 0,
+            })
+            .into_mut()
+    }
+
+    fn unlink(&mut self, key: &PageKey) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.order(entry.segment).remove(&entry.tick);
+        *self.bytes(entry.segment) -= entry.data.len() as u64;
+        Some(entry)
+    }
+
+    fn evict(&mut self, key: &PageKey, cause: EvictionCause) {
+        if self.unlink(key).is_some() {
+            self.stats.evictions[cause as usize] += 1;
+        }
+    }
+
+    fn lru(&self, segment: Segment) -> Option<PageKey> {
+        let order = match segment {
+            Segment::Probation => &self.probation,
+            Segment::Protected => &self.protected,
+        };
+        order.values().next().cloned()
+    }
+}
+
+/// The page cache of an instance, see the [module documentation](self).
+#[derive(Debug)]
+pub struct PageCache {
+    config: CacheConfig,
+    memory: Arc<MemoryBudget>,
+    state: Mutex<State>,
+}
+
+impl PageCache {
+    /// A cache charging its pages to a budget of its own, without a cap.
+    pub fn new(config: CacheConfig) -> Self {
+        Self::with_budget(config, Arc::default())
+    }
+
+    pub fn with_budget(config: CacheConfig, memory: Arc<MemoryBudget>) -> Self {
+        Self {
+            config,
+            memory,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Register the segments of the cache as reliefs of its budget.
+    pub fn add_reliefs(self: &Arc<Self>) {
+        for (relief, segment) in [
+            (Relief::CacheProbation, Segment::Probation),
+            (Relief::CacheProtected, Segment::Protected),
+        ] {
+            let cache = Arc::downgrade(self);
+            self.memory.add_relief(relief, move |bytes| {
+                cache
+                    .upgrade()
+                    .map_or(0, |cache| cache.shed(segment, bytes))
+            });
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.capacity > 0
+    }
+
+    /// The page of `db` at `offset` as of `generation`, if cached.
+    pub fn get(&self, db: &str, generation: u64, offset: u64, len: usize) -> Option<Vec<u8>> {
+        let key = PageKey {
+            db: db.to_owned(),
+            generation,
+            offset,
+            len,
+        };
+        let mut state = self.state.lock().unwrap();
+        let Some(entry) = state.unlink(&key) else {
+            state.stats.misses += 1;
+            return None;
+        };
+        let data = entry.data.clone();
+        let hits = match entry.prefetched {
+            true => &mut state.stats.prefetch_hits,
+            false => match entry.segment {
+                Segment::Probation => &mut state.stats.probation_hits,
+                Segment::Protected => &mut state.stats.protected_hits,
+            },
+        };
+        *hits += 1;
+        match (self.config.policy, entry.segment) {
+            (CachePolicy::Segmented, Segment::Probation) => {
+                state.stats.promotions += 1;
+                state
+                    .link(key, entry.data, entry.charge, Segment::Protected, true)
+                    .hits = entry.hits + 1;
+                self.shrink_protected(&mut state);
+            }
+            (_, segment) => {
+                state
+                    .link(key, entry.data, entry.charge, segment, true)
+                    .hits = entry.hits + 1
+            }
+        }
+        Some(data)
+    }
+
+    /// Cache a page of `db` read at `generation`.
+    pub fn insert(&self, db: &str, generation: u64, offset: u64, data: Vec<u8>, usage: CacheUse) {
+        let sequential = usage == CacheUse::Sequential;
+        if !self.enabled()
+            || usage == CacheUse::Bypass
+            || (sequential && self.config.sequential == SequentialAdmission::Bypass)
+            || data.len() as u64 > self.config.capacity
+        {
+            self.bypass();
+            return;
+        }
+        // before locking the cache, which relieving pressure locks
+        let Ok(charge) = self.memory.charge(Component::Cache, data.len() as u64) else {
+            self.bypass();
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        let newest = state.newest.entry(db.to_owned()).or_default();
+        if generation > *newest {
+            *newest = generation;
+            let superseded: Vec<_> = state
+                .entries
+                .keys()
+                .filter(|key| key.db == db && key.generation < generation)
+                .cloned()
+                .collect();
+            for key in superseded {
+                state.evict(&key, EvictionCause::Superseded);
+            }
+        } else if generation < *newest {
+            // read before a commit that another read already saw
+            state.stats.bypassed += 1;
+            return;
+        }
+
+        let key = PageKey {
+            db: db.to_owned(),
+            generation,
+            offset,
+            len: data.len(),
+        };
+        state.unlink(&key);
+        state.stats.admissions += 1;
+        if usage == CacheUse::Prefetch {
+            state.stats.prefetched += 1;
+        }
+        let entry = match self.config.policy {
+            CachePolicy::Lru => state.link(key, data, charge, Segment::Protected, true),
+            CachePolicy::Segmented => {
+                if sequential {
+                    state.stats.sequential_admissions += 1;
+                }
+                state.link(key, data, charge, Segment::Probation, !sequential)
+            }
+        };
+        entry.prefetched = usage == CacheUse::Prefetch;
+        while state.stats.probation_bytes + state.stats.protected_bytes > self.config.capacity {
+            let Some(key) = state
+                .lru(Segment::Probation)
+                .or_else(|| state.lru(Segment::Protected))
+            else {
+                break;
+            };
+            state.evict(&key, EvictionCause::Capacity);
+        }
+    }
+
+    /// The page of `db` at `offset` as of `generation`, if cached, without counting it as a read.
+    pub fn peek(&self, db: &str, generation: u64, offset: u64, len: usize) -> Option<Vec<u8>> {
+        let key = PageKey {
+            db: db.to_owned(),
+            generation,
+            offset,
+            len,
+        };
+        let state = self.state.lock().unwrap();
+        state.entries.get(&key).map(|entry| entry.data.clone())
+    }
+
+    /// Whether the page of `db` at `offset` as of `generation` is cached, without reading it.
+    pub fn contains(&self, db: &str, generation: u64, offset: u64, len: usize) -> bool {
+        let key = PageKey {
+            db: db.to_owned(),
+            generation,
+            offset,
+            len,
+        };
+        self.state.lock().unwrap().entries.contains_key(&key)
+    }
+
+    /// The pages of `db` cached as of the newest generation read, most read first and most
+    /// recently used first among equally read ones, along with that generation.
+    pub fn pages(&self, db: &str) -> Option<(u64, Vec<CachedPage>)> {
+        let state = self.state.lock().unwrap();
+        let generation = *state.newest.get(db)?;
+        let mut pages: Vec<_> = state
+            .entries
+            .iter()
+            .filter(|(key, _)| key.db == db && key.generation == generation)
+            .collect();
+        pages.sort_by_key(|(_, entry)| {
+            let protected = entry.segment == Segment::Protected;
+            std::cmp::Reverse((entry.hits, protected, entry.tick))
+        });
+        let pages = pages
+            .into_iter()
+            .map(|(key, entry)| CachedPage {
+                offset: key.offset,
+                data: entry.data.clone(),
+                hits: entry.hits,
+            })
+            .collect();
+        Some((generation, pages))
+    }
+
+    /// `len` bytes at `offset` of `db` as of `generation`, if they lie within its pinned header.
+    pub fn header(&self, db: &str, generation: u64, offset: u64, len: usize) -> Option<Vec<u8>> {
+        let end = offset as usize + len;
+        let mut state = self.state.lock().unwrap();
+        let pin = state.headers.get(db)?;
+        if pin.generation != generation || pin.committing || end > HEADER_LEN {
+            return None;
+        }
+        let bytes = pin.bytes[offset as usize..end].to_vec();
+        state.stats.header_hits += 1;
+        Some(bytes)
+    }
+
+    /// Pin the header of `db` from `data`, read at `offset` as of `generation`, if it covers it.
+    pub fn pin_header(&self, db: &str, generation: u64, offset: u64, data: &[u8]) {
+        if offset != 0 || data.len() < HEADER_LEN {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state
+            .headers
+            .get(db)
+            .is_some_and(|pin| pin.generation > generation)
+        {
+            return;
+        }
+        state.headers.insert(
+            db.to_owned(),
+            HeaderPin {
+                generation,
+                bytes: data[..HEADER_LEN].to_vec(),
+                committing: false,
+            },
+        );
+    }
+
+    /// Drop the pages of `db` overlapping what this instance wrote at `offset`, and patch its
+    /// pinned header with it. The pin serves no reads until [PageCache::commit_header].
+    pub fn written(&self, db: &str, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        self.evict_overlapping(db, offset..end);
+        let mut state = self.state.lock().unwrap();
+        let Some(pin) = state.headers.get_mut(db) else {
+            return;
+        };
+        if offset < HEADER_LEN as u64 {
+            let to = (end as usize).min(HEADER_LEN);
+            pin.bytes[offset as usize..to].copy_from_slice(&data[..to - offset as usize]);
+        }
+        pin.committing = true;
+    }
+
+    /// Move the pinned header of `db` to `generation`, that of the commit of what was
+    /// [written](PageCache::written). The pin is dropped unless the commit was built on its
+    /// generation, i.e. `generation` immediately follows it.
+    pub fn commit_header(&self, db: &str, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(pin) = state.headers.get_mut(db) else {
+            return;
+        };
+        if !pin.committing {
+            return;
+        }
+        if pin.generation + 1 == generation {
+            pin.generation = generation;
+            pin.committing = false;
+        } else {
+            state.headers.remove(db);
+        }
+    }
+
+    /// Drop the pages of `db` overlapping `range`, and its pinned header if that does, since this
+    /// instance overwrote them.
+    pub fn invalidate(&self, db: &str, range: Range<u64>) {
+        if range.start < HEADER_LEN as u64 {
+            self.state.lock().unwrap().headers.remove(db);
+        }
+        self.evict_overlapping(db, range);
+    }
+
+    /// Move the pages of `db` cached as of `from` to `to`, the next generation, which changed
+    /// `changed` of it, see [crate::bus]. The pages overlapping `changed` are dropped and
+    /// returned, by offset and length, to be fetched again. Nothing moves unless `from` is the
+    /// newest generation cached.
+    pub fn advance(&self, db: &str, from: u64, to: u64, changed: &[Range<u64>]) -> Vec<(u64, u64)> {
+        let overlaps = |offset: u64, len: u64| {
+            changed
+                .iter()
+                .any(|r| offset < r.end && offset + len > r.start)
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.newest.get(db) != Some(&from) || to <= from {
+            return vec![];
+        }
+        state.newest.insert(db.to_owned(), to);
+        if let Some(pin) = state.headers.get_mut(db) {
+            if pin.generation == from && !pin.committing && !overlaps(0, HEADER_LEN as u64) {
+                pin.generation = to;
+            }
+        }
+        let keys: Vec<_> = state
+            .entries
+            .keys()
+            .filter(|key| key.db == db && key.generation == from)
+            .cloned()
+            .collect();
+        let mut refetch = vec![];
+        for key in keys {
+            if overlaps(key.offset, key.len as u64) {
+                refetch.push((key.offset, key.len as u64));
+                state.evict(&key, EvictionCause::Superseded);
+                continue;
+            }
+            // keeps its place in the recency order
+            let entry = state.entries.remove(&key).unwrap();
+            let moved = PageKey {
+                generation: to,
+                ..key
+            };
+            state.order(entry.segment).insert(entry.tick, moved.clone());
+            state.entries.insert(moved, entry);
+        }
+        refetch.sort_unstable();
+        refetch
+    }
+
+    /// Drop the pinned headers of all databases, to be read again at the generation of the next
+    /// read, see [crate::clock].
+    pub fn unpin_headers(&self) {
+        self.state.lock().unwrap().headers.clear();
+    }
+
+    fn evict_overlapping(&self, db: &str, range: Range<u64>) {
+        let mut state = self.state.lock().unwrap();
+        let written: Vec<_> = state
+            .entries
+            .keys()
+            .filter(|key| {
+                key.db == db && key.offset < range.end && key.offset + key.len as u64 > range.start
+            })
+            .cloned()
+            .collect();
+        for key in written {
+            state.evict(&key, EvictionCause::Written);
+        }
+    }
+
+    /// Count a read that neither was served from nor admitted to the cache.
+    pub fn bypass(&self) {
+        self.state.lock().unwrap().stats.bypassed += 1;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// Evict least recently used pages of `segment` until `bytes` are freed or it is empty.
+    /// Returns the bytes freed.
+    fn shed(&self, segment: Segment, bytes: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let mut freed = 0;
+        while freed < bytes {
+            let Some(key) = state.lru(segment) else {
+                break;
+            };
+            freed += key.len as u64;
+            state.evict(&key, EvictionCause::Pressure);
+        }
+        freed
+    }
+
+    fn shrink_protected(&self, state: &mut State) {
+        let share = (self.config.capacity as f64 * self.config.protected_share) as u64;
+        while state.stats.protected_bytes > share {
+            let Some(key) = state.lru(Segment::Protected) else {
+                break;
+            };
+            let entry = state.unlink(&key).unwrap();
+            state.stats.demotions += 1;
+            state
+                .link(key, entry.data, entry.charge, Segment::Probation, true)
+                .hits = entry.hits;
+        }
+    }
+}
+
+/// Tags the reads of a handle as sequential once they follow each other, see
+/// [CacheConfig::sequential_after].
+#[derive(Clone, Debug, Default)]
+pub struct ScanDetector {
+    /// Where the last read ended.
+    next: Option<u64>,
+    run: u32,
+}
+
+impl ScanDetector {
+    /// How the read of `len` bytes at `offset` uses the cache.
+    pub fn observe(&mut self, offset: u64, len: usize, after: u32) -> CacheUse {
+        self.run = match self.next == Some(offset) {
+            true => self.run.saturating_add(1),
+            false => 0,
+        };
+        self.next = Some(offset + len as u64);
+        match self.run >= after {
+            true => CacheUse::Sequential,
+            false => CacheUse::Admit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: usize = 4096;
+
+    /// Reads of a hot working set of 64 pages, a scan of 2,048 other pages, and the working set
+    /// again. Returns the hit ratio of the working set after the scan.
+    fn hot_set_after_scan(config: CacheConfig) -> (f64, CacheStats) {
+        let cache = PageCache::new(config.clone());
+        let mut scan = ScanDetector::default();
+        let mut read = |page: u64| {
+            let offset = page * PAGE as u64;
+            let usage = scan.observe(offset, PAGE, config.sequential_after);
+            match cache.get("test.db", 1, offset, PAGE) {
+                Some(_) => true,
+                None => {
+                    cache.insert("test.db", 1, offset, vec![0; PAGE], usage);
+                    false
+                }
+            }
+        };
+        // the working set is spread out, every page read a few times
+        let hot: Vec<u64> = (0..64).map(|i| i * 97 % 4000 + 10_000).collect();
+        for _ in 0..3 {
+            for page in &hot {
+                read(*page);
+            }
+        }
+        for page in 0..2048 {
+            read(page);
+        }
+        let hits = hot.iter().filter(|page| read(**page)).count();
+        (hits as f64 / hot.len() as f64, cache.stats())
+    }
+
+    fn config(policy: CachePolicy) -> CacheConfig {
+        CacheConfig {
+            capacity: 256 * PAGE as u64,
+            policy,
+            ..CacheConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        // the scan flushes a plain LRU cache
+        let (lru, _) = hot_set_after_scan(config(CachePolicy::Lru));
+        assert_eq!(lru, 0.0);
+
+        let (segmented, stats) = hot_set_after_scan(config(CachePolicy::Segmented));
+        assert!(segmented >= 0.95, "{segmented}");
+        assert_eq!(stats.promotions, 64);
+        assert!(stats.sequential_admissions >= 2000, "{stats}");
+        assert!(stats.evictions[EvictionCause::Capacity as usize] > 1500);
+        assert_eq!(stats.protected_bytes, 64 * PAGE as u64);
+        assert!(stats.probation_bytes + stats.protected_bytes <= 256 * PAGE as u64);
+
+        let (bypass, stats) = hot_set_after_scan(CacheConfig {
+            sequential: SequentialAdmission::Bypass,
+            ..config(CachePolicy::Segmented)
+        });
+        assert_eq!(bypass, 1.0);
+        assert_eq!(stats.sequential_admissions, 0);
+        assert!(stats.bypassed >= 2000);
+    }
+
+    #[test]
+    fn test_protected_share() {
+        let cache = PageCache::new(CacheConfig {
+            capacity: 10 * PAGE as u64,
+            protected_share: 0.5,
+            ..CacheConfig::default()
+        });
+        for page in 0..8 {
+            let offset = page * PAGE as u64;
+            cache.insert("test.db", 1, offset, vec![0; PAGE], CacheUse::Admit);
+            cache.get("test.db", 1, offset, PAGE).unwrap();
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.protected_bytes, 5 * PAGE as u64);
+        assert_eq!(stats.probation_bytes, 3 * PAGE as u64);
+        assert_eq!(stats.demotions, 3);
+        // demoted pages are promoted again on their next read
+        assert!(cache.get("test.db", 1, 0, PAGE).is_some());
+        assert_eq!(cache.stats().probation_hits, 9);
+    }
+
+    #[test]
+    fn test_prefetch_hits() {
+        let cache = PageCache::new(config(CachePolicy::Segmented));
+        for page in 0..4 {
+            let offset = page * PAGE as u64;
+            cache.insert("test.db", 1, offset, vec![0; PAGE], CacheUse::Prefetch);
+        }
+        assert!(cache.contains("test.db", 1, 0, PAGE));
+        assert!(!cache.contains("test.db", 2, 0, PAGE));
+        // only the first read of a prefetched page is a prefetch hit
+        cache.get("test.db", 1, 0, PAGE).unwrap();
+        cache.get("test.db", 1, 0, PAGE).unwrap();
+        cache.get("test.db", 1, 4096, PAGE).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.prefetched, stats.prefetch_hits), (4, 2));
+        assert_eq!((stats.probation_hits, stats.protected_hits), (0, 1));
+        assert_eq!(stats.hits(), 3);
+        assert_eq!(stats.prefetch_hit_ratio(), Some(0.5));
+        assert!(stats
+            .to_string()
+            .ends_with(" prefetched=4 prefetch_hits=2 prefetch_hit_ratio=0.50"));
+    }
+
+    #[test]
+    fn test_generations_and_writes() {
+        let cache = PageCache::new(config(CachePolicy::Segmented));
+        cache.insert("test.db", 1, 0, vec![1; PAGE], CacheUse::Admit);
+        cache.insert("test.db", 1, 4096, vec![1; PAGE], CacheUse::Admit);
+        cache.insert("other.db", 1, 0, vec![1; PAGE], CacheUse::Admit);
+
+        // written by this instance
+        cache.invalidate("test.db", 100..200);
+        assert_eq!(cache.get("test.db", 1, 0, PAGE), None);
+        assert!(cache.get("test.db", 1, 4096, PAGE).is_some());
+
+        // committed by any instance
+        assert_eq!(cache.get("test.db", 2, 4096, PAGE), None);
+        cache.insert("test.db", 2, 0, vec![2; PAGE], CacheUse::Admit);
+        assert_eq!(cache.get("test.db", 1, 4096, PAGE), None);
+        assert_eq!(cache.get("test.db", 2, 0, PAGE), Some(vec![2; PAGE]));
+        assert!(cache.get("other.db", 1, 0, PAGE).is_some());
+        // a read that started before the commit doesn't bring the old generation back
+        cache.insert("test.db", 1, 8192, vec![1; PAGE], CacheUse::Admit);
+        assert_eq!(cache.get("test.db", 1, 8192, PAGE), None);
+
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, [0, 1, 1, 0]);
+        assert_eq!(
+            stats.to_string(),
+            "probation_hits=3 protected_hits=0 misses=4 admissions=4 sequential_admissions=0 \
+             bypassed=1 promotions=3 demotions=0 probation_bytes=0 protected_bytes=8192 \
+             evicted_capacity=0 evicted_written=1 evicted_superseded=1 evicted_pressure=0 \
+             header_hits=0"
+        );
+    }
+
+    #[test]
+    fn test_advance() {
+        let cache = PageCache::new(config(CachePolicy::Segmented));
+        for page in 0..4 {
+            cache.insert("test.db", 1, page * 4096, vec![1; PAGE], CacheUse::Admit);
+        }
+        cache.insert("other.db", 1, 0, vec![1; PAGE], CacheUse::Admit);
+
+        // not the newest generation cached, or not the next one
+        assert_eq!(cache.advance("test.db", 2, 3, &[]), vec![]);
+        let changed = [100..200, 8192..8193];
+        assert_eq!(
+            cache.advance("test.db", 1, 2, &changed),
+            vec![(0, 4096), (8192, 4096)]
+        );
+        assert_eq!(cache.get("test.db", 2, 0, PAGE), None);
+        assert_eq!(cache.get("test.db", 2, 4096, PAGE), Some(vec![1; PAGE]));
+        assert_eq!(cache.get("test.db", 2, 12288, PAGE), Some(vec![1; PAGE]));
+        assert_eq!(cache.get("test.db", 1, 4096, PAGE), None);
+        assert!(cache.get("other.db", 1, 0, PAGE).is_some());
+        assert_eq!(cache.stats().evictions, [0, 0, 2, 0]);
+    }
+
+    #[test]
+    fn test_header_pin() {
+        let cache = PageCache::new(config(CachePolicy::Segmented));
+        let mut page = vec![0; PAGE];
+        page[..16].copy_from_slice(b"SQLite format 3\0");
+        cache.pin_header("test.db", 1, 0, &page[..50]);
+        assert_eq!(cache.header("test.db", 1, 0, 16), None);
+        cache.pin_header("test.db", 1, 0, &page);
+        assert_eq!(cache.header("test.db", 1, 0, 16), Some(page[..16].to_vec()));
+        assert_eq!(cache.header("test.db", 1, 96, 8), None);
+        assert_eq!(cache.header("test.db", 2, 24, 4), None);
+
+        // a counter-only workload never leaves the pin
+        for counter in 1u32..=10 {
+            let generation = counter as u64;
+            let bytes = cache.header("test.db", generation, 24, 4).unwrap();
+            assert_eq!(u32::from_be_bytes(bytes.try_into().unwrap()), counter - 1);
+            cache.written("test.db", 24, &counter.to_be_bytes());
+            assert_eq!(cache.header("test.db", generation, 24, 4), None);
+            cache.commit_header("test.db", generation + 1);
+        }
+        assert_eq!(cache.header("test.db", 11, 24, 4), Some(vec![0, 0, 0, 10]));
+        assert_eq!(cache.stats().header_hits, 12);
+
+        // an older read doesn't replace it
+        cache.pin_header("test.db", 3, 0, &page);
+        assert_eq!(cache.header("test.db", 11, 24, 4), Some(vec![0, 0, 0, 10]));
+
+        // another instance committed in between
+        cache.written("test.db", 24, &11u32.to_be_bytes());
+        cache.commit_header("test.db", 13);
+        assert_eq!(cache.header("test.db", 13, 24, 4), None);
+        cache.pin_header("test.db", 13, 0, &page);
+        cache.invalidate("test.db", 0..PAGE as u64);
+        assert_eq!(cache.header("test.db", 13, 0, 16), None);
+    }
+
+    #[test]
+    fn test_scan_detector() {
+        let mut scan = ScanDetector::default();
+        let uses: Vec<_> = [0, 4096, 8192, 12288, 16384, 0, 4096]
+            .into_iter()
+            .map(|offset| scan.observe(offset, 4096, 3))
+            .collect();
+        assert_eq!(
+            uses,
+            [
+                CacheUse::Admit,
+                CacheUse::Admit,
+                CacheUse::Admit,
+                CacheUse::Sequential,
+                CacheUse::Sequential,
+                CacheUse::Admit,
+                CacheUse::Admit,
+            ]
+        );
+    }
+}
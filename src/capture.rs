@@ -0,0 +1,671 @@
+//! Capturing what SQLite does through the VFS, to replay it elsewhere.
+//!
+//! With [Config::capture](crate::config::Config::capture), the instance records the callbacks
+//! SQLite makes through every registration in a [Workload]: opens, reads and writes with their
+//! offsets and lengths, syncs, truncations, lock transitions and deletes, when each started and
+//! how long it took. [ThreeQLite::save_capture], or `PRAGMA threeqlite_capture = 'path'`, writes
+//! it to a file of a few bytes per operation, which [crate::replay] replays against another
+//! configuration.
+//!
+//! Captures are meant to leave the user's hands, so they hold nothing of the data. Files are
+//! numbered in the order first seen and only known by their kind, never by name. Neither the bytes
+//! read and written are recorded nor a digest of them, which could be guessed for a page holding
+//! little: contents are numbered instead, in the order first seen, so that a read numbered like
+//! an earlier write is known to have read what it wrote, which is all a replay verifies. The
+//! digests telling contents apart stay in memory.
+
+use std::{
+    collections::HashMap,
+    ffi::c_int,
+    sync::{Arc, Mutex},
+    thread::ThreadId,
+    time::Instant,
+};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlite_vfs::instrument::{CallbackDetails, CallbackKind, Instrumentation, Phase};
+
+use crate::{error::Error, vfs::ThreeQLite};
+
+/// Marks a file holding a [Workload].
+pub const MAGIC: [u8; 4] = *b"3QWL";
+
+/// The version of the [Workload] format this crate writes and reads.
+pub const VERSION: u32 = 1;
+
+/// The longest [Workload] file [Workload::decode] reads.
+const MAX_LEN: u64 = 1 << 30;
+
+const SQLITE_OPEN_MAIN_DB: c_int = 0x100;
+const SQLITE_OPEN_MAIN_JOURNAL: c_int = 0x800;
+const SQLITE_OPEN_SUPER_JOURNAL: c_int = 0x4000;
+const SQLITE_OPEN_WAL: c_int = 0x80000;
+
+/// The callbacks a [Workload] records. The others, e.g. file controls or sleeps, don't reach the
+/// store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OpKind {
+    Open,
+    Close,
+    Delete,
+    Access,
+    Read,
+    Write,
+    Truncate,
+    Sync,
+    FileSize,
+    Lock,
+    Unlock,
+}
+
+impl OpKind {
+    fn of(kind: CallbackKind) -> Option<Self> {
+        Some(match kind {
+            CallbackKind::Open => Self::Open,
+            CallbackKind::Close => Self::Close,
+            CallbackKind::Delete => Self::Delete,
+            CallbackKind::Access => Self::Access,
+            CallbackKind::Read => Self::Read,
+            CallbackKind::Write => Self::Write,
+            CallbackKind::Truncate => Self::Truncate,
+            CallbackKind::Sync => Self::Sync,
+            CallbackKind::FileSize => Self::FileSize,
+            CallbackKind::Lock => Self::Lock,
+            CallbackKind::Unlock => Self::Unlock,
+            _ => return None,
+        })
+    }
+
+    pub fn callback(self) -> CallbackKind {
+        match self {
+            Self::Open => CallbackKind::Open,
+            Self::Close => CallbackKind::Close,
+            Self::Delete => CallbackKind::Delete,
+            Self::Access => CallbackKind::Access,
+            Self::Read => CallbackKind::Read,
+            Self::Write => CallbackKind::Write,
+            Self::Truncate => CallbackKind::Truncate,
+            Self::Sync => CallbackKind::Sync,
+            Self::FileSize => CallbackKind::FileSize,
+            Self::Lock => CallbackKind::Lock,
+            Self::Unlock => CallbackKind::Unlock,
+        }
+    }
+}
+
+impl std::fmt::Display for OpKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.callback().name())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileKind {
+    MainDb,
+    MainJournal,
+    SuperJournal,
+    Wal,
+    /// Temporary files and anything else SQLite opens.
+    Other,
+}
+
+impl FileKind {
+    fn of_flags(flags: c_int) -> Self {
+        match flags {
+            _ if flags & SQLITE_OPEN_MAIN_DB != 0 => Self::MainDb,
+            _ if flags & SQLITE_OPEN_MAIN_JOURNAL != 0 => Self::MainJournal,
+            _ if flags & SQLITE_OPEN_SUPER_JOURNAL != 0 => Self::SuperJournal,
+            _ if flags & SQLITE_OPEN_WAL != 0 => Self::Wal,
+            _ => Self::Other,
+        }
+    }
+
+    /// The kind of a file named `name` that wasn't opened yet, e.g. a journal SQLite checks for,
+    /// and the database it belongs to.
+    fn of_name(name: &str) -> (Self, Option<&str>) {
+        if let Some(db) = name.strip_suffix("-journal") {
+            return (Self::MainJournal, Some(db));
+        }
+        if let Some(db) = name.strip_suffix("-wal") {
+            return (Self::Wal, Some(db));
+        }
+        match name.is_empty() {
+            true => (Self::Other, None),
+            false => (Self::MainDb, None),
+        }
+    }
+}
+
+/// A file of a [Workload].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct File {
+    pub kind: FileKind,
+    /// The database a journal or WAL belongs to, if it was seen.
+    pub of: Option<u16>,
+}
+
+/// An operation without its timing and outcome, as a replay repeats it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    pub kind: OpKind,
+    /// The file, numbered as in [Workload::files].
+    pub file: u16,
+    /// The offset of a read or write, or the size a file is truncated to.
+    pub offset: Option<u64>,
+    /// The length of a read or write.
+    pub len: Option<u32>,
+    /// The lock level of a lock or unlock, or the flags of an open, sync or access.
+    pub arg: Option<i32>,
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}(#{}", self.kind, self.file)?;
+        if let Some(offset) = self.offset {
+            write!(f, " @{offset}")?;
+        }
+        if let Some(len) = self.len {
+            write!(f, " +{len}")?;
+        }
+        if let Some(arg) = self.arg {
+            write!(f, " {arg:#x}")?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// An operation SQLite made through the VFS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Op {
+    pub step: Step,
+    /// The content read or written, numbered in the order first seen, if read in full.
+    pub content: Option<u32>,
+    /// When the callback was entered, in microseconds since the capture started.
+    pub at_us: u64,
+    pub elapsed_us: u64,
+    /// The result code returned to SQLite.
+    pub rc: i32,
+}
+
+/// The operations of a capture, see the [module documentation](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Workload {
+    pub files: Vec<File>,
+    pub ops: Vec<Op>,
+}
+
+impl Workload {
+    /// The operations without their timing and outcome.
+    pub fn steps(&self) -> Vec<Step> {
+        self.ops.iter().map(|op| op.step).collect()
+    }
+
+    /// How large `file` was as far as the operations tell: the end of the furthest read, write
+    /// or truncation.
+    pub fn size(&self, file: u16) -> u64 {
+        let ends = self.ops.iter().filter(|op| op.step.file == file);
+        ends.filter_map(|op| match op.step.kind {
+            OpKind::Read | OpKind::Write => Some(op.step.offset? + op.step.len? as u64),
+            OpKind::Truncate => op.step.offset,
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+    }
+
+    /// The page size of the database `file`: the most frequent length of its reads and writes
+    /// that is a valid page size, 4096 if there is none.
+    pub fn page_size(&self, file: u16) -> u32 {
+        let mut lengths: HashMap<u32, usize> = HashMap::new();
+        let ops = self.ops.iter().filter(|op| op.step.file == file);
+        for op in ops.filter(|op| matches!(op.step.kind, OpKind::Read | OpKind::Write)) {
+            match op.step.len {
+                Some(len) if len.is_power_of_two() && (512..=65536).contains(&len) => {
+                    *lengths.entry(len).or_default() += 1
+                }
+                _ => {}
+            }
+        }
+        lengths
+            .into_iter()
+            .max_by_key(|(len, count)| (*count, *len))
+            .map_or(4096, |(len, _)| len)
+    }
+
+    /// The wall time the capture spans.
+    pub fn duration_us(&self) -> u64 {
+        let ends = self.ops.iter().map(|op| op.at_us + op.elapsed_us);
+        ends.max().unwrap_or(0)
+    }
+
+    /// The file format: [MAGIC], [VERSION] and the workload.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        let body = bincode::options().serialize(self);
+        bytes.extend(body.expect("a workload always serializes"));
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = |message: String| Error::Whatever {
+            message,
+            source: None,
+        };
+        let Some(rest) = bytes.strip_prefix(&MAGIC) else {
+            return Err(invalid("not a captured workload".to_owned()));
+        };
+        let Some((version, body)) = rest.split_first_chunk::<4>() else {
+            return Err(invalid("truncated workload".to_owned()));
+        };
+        let version = u32::from_le_bytes(*version);
+        if version != VERSION {
+            return Err(Error::IncompatibleFormat {
+                need: version,
+                have: VERSION,
+            });
+        }
+        bincode::options()
+            .with_limit(MAX_LEN)
+            .deserialize(body)
+            .map_err(|err| invalid(format!("invalid workload: {err}")))
+    }
+}
+
+impl std::fmt::Display for Workload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut counts: Vec<(OpKind, usize)> = vec![];
+        for op in &self.ops {
+            match counts.iter_mut().find(|(kind, _)| *kind == op.step.kind) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((op.step.kind, 1)),
+            }
+        }
+        write!(
+            f,
+            "{} operations on {} files over {}ms",
+            self.ops.len(),
+            self.files.len(),
+            self.duration_us() / 1000
+        )?;
+        for (kind, count) in counts {
+            write!(f, " {kind}={count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Recording {
+    workload: Workload,
+    /// The files by name, which never leaves memory.
+    files: HashMap<String, u16>,
+    /// The number of each content by its digest, which never leaves memory either.
+    contents: HashMap<[u8; 32], u32>,
+    /// The content of the read or write running on a thread, until its exit.
+    running: HashMap<(String, ThreadId), u32>,
+}
+
+impl Recording {
+    fn file(&mut self, name: &str, kind: Option<FileKind>) -> u16 {
+        if let Some(file) = self.files.get(name) {
+            return *file;
+        }
+        let (guessed, db) = FileKind::of_name(name);
+        let of = db.and_then(|db| self.files.get(db).copied());
+        let file = self.workload.files.len() as u16;
+        self.workload.files.push(File {
+            kind: kind.unwrap_or(guessed),
+            of,
+        });
+        self.files.insert(name.to_owned(), file);
+        file
+    }
+}
+
+/// Records the [Workload] of an instance, see the [module documentation](self).
+#[derive(Debug)]
+pub struct Capture {
+    start: Instant,
+    recording: Mutex<Recording>,
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            recording: Mutex::default(),
+        }
+    }
+}
+
+impl Capture {
+    /// The operations recorded so far.
+    pub fn workload(&self) -> Workload {
+        self.recording.lock().unwrap().workload.clone()
+    }
+}
+
+impl Instrumentation for Capture {
+    fn on_callback(&self, kind: CallbackKind, db: &str, details: CallbackDetails) {
+        let Phase::Exit { rc, elapsed } = details.phase else {
+            return;
+        };
+        let Some(kind) = OpKind::of(kind) else {
+            return;
+        };
+        let at = self.start.elapsed().saturating_sub(elapsed);
+        let mut recording = self.recording.lock().unwrap();
+        let opened = (kind == OpKind::Open).then(|| FileKind::of_flags(details.arg.unwrap_or(0)));
+        let file = recording.file(db, opened);
+        let content = match kind {
+            OpKind::Read | OpKind::Write => recording
+                .running
+                .remove(&(db.to_owned(), std::thread::current().id())),
+            _ => None,
+        };
+        recording.workload.ops.push(Op {
+            step: Step {
+                kind,
+                file,
+                offset: details.offset,
+                len: details.len.map(|len| len as u32),
+                arg: details.arg,
+            },
+            content,
+            at_us: at.as_micros() as u64,
+            elapsed_us: elapsed.as_micros() as u64,
+            rc,
+        });
+    }
+
+    fn on_data(&self, _kind: CallbackKind, db: &str, _offset: u64, data: &[u8]) {
+        let digest: [u8; 32] = Sha256::digest(data).into();
+        let mut recording = self.recording.lock().unwrap();
+        let next = recording.contents.len() as u32;
+        let content = *recording.contents.entry(digest).or_insert(next);
+        let running = (db.to_owned(), std::thread::current().id());
+        recording.running.insert(running, content);
+    }
+}
+
+/// Passes every callback on to several instrumentations.
+pub struct Instruments(pub Vec<Arc<dyn Instrumentation>>);
+
+impl Instrumentation for Instruments {
+    fn on_callback(&self, kind: CallbackKind, db: &str, details: CallbackDetails) {
+        for instrumentation in &self.0 {
+            instrumentation.on_callback(kind, db, details);
+        }
+    }
+
+    fn on_data(&self, kind: CallbackKind, db: &str, offset: u64, data: &[u8]) {
+        for instrumentation in &self.0 {
+            instrumentation.on_data(kind, db, offset, data);
+        }
+    }
+}
+
+impl ThreeQLite {
+    /// What to register along with the VFS: the capture, the file control coverage, both or
+    /// neither.
+    pub(crate) fn instrumentation(&self) -> Option<Arc<dyn Instrumentation>> {
+        let capture = self.capture.clone().map(|c| c as Arc<dyn Instrumentation>);
+        let coverage = self
+            .file_controls
+            .clone()
+            .map(|c| c as Arc<dyn Instrumentation>);
+        match (capture, coverage) {
+            (Some(capture), Some(coverage)) => Some(Arc::new(Instruments(vec![capture, coverage]))),
+            (capture, coverage) => capture.or(coverage),
+        }
+    }
+
+    /// The operations captured so far, `None` without
+    /// [Config::capture](crate::config::Config::capture).
+    pub fn captured(&self) -> Option<Workload> {
+        self.capture.as_ref().map(|capture| capture.workload())
+    }
+
+    /// Write the operations captured so far to `path`, see the [module documentation](self).
+    /// Returns how many there are.
+    pub fn save_capture(&self, path: &std::path::Path) -> Result<usize, Error> {
+        let Some(workload) = self.captured() else {
+            return Err(Error::Whatever {
+                message: "capturing is off, see Config::capture".to_owned(),
+                source: None,
+            });
+        };
+        std::fs::write(path, workload.encode()).map_err(|err| Error::Whatever {
+            message: format!("writing the capture to {} failed: {err}", path.display()),
+            source: Some(Box::new(err)),
+        })?;
+        Ok(workload.ops.len())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const SQLITE_OPEN_CREATE: c_int = 0x4;
+    const SQLITE_OPEN_READWRITE: c_int = 0x2;
+    const SHARED: c_int = 1;
+    const RESERVED: c_int = 2;
+    const EXCLUSIVE: c_int = 4;
+
+    pub const PAGE_SIZE: usize = 4096;
+    pub const PAGES: u64 = 64;
+    /// What every page of the user's database holds, which must not leak into a capture.
+    pub const SECRET: &[u8] = b"card 4111-1111-1111-1111 of jane.doe@example.com";
+    pub const NAME: &str = "customers-of-acme.db";
+
+    /// A page of the user's database.
+    fn page(n: u64) -> Vec<u8> {
+        let mut page = SECRET.repeat(PAGE_SIZE / SECRET.len() + 1);
+        page.truncate(PAGE_SIZE - 8);
+        page.extend(n.to_be_bytes());
+        page
+    }
+
+    /// Calls `instrumentation` as SQLite does with its callbacks.
+    struct Session<'a> {
+        instrumentation: &'a dyn Instrumentation,
+    }
+
+    impl Session<'_> {
+        fn call(
+            &self,
+            kind: CallbackKind,
+            db: &str,
+            details: CallbackDetails,
+            data: Option<&[u8]>,
+        ) {
+            self.instrumentation.on_callback(kind, db, details);
+            if let Some(data) = data {
+                let offset = details.offset.unwrap_or(0);
+                self.instrumentation.on_data(kind, db, offset, data);
+            }
+            let exit = CallbackDetails {
+                phase: Phase::Exit {
+                    rc: 0,
+                    elapsed: Duration::from_micros(50),
+                },
+                ..details
+            };
+            self.instrumentation.on_callback(kind, db, exit);
+        }
+
+        fn arg(&self, kind: CallbackKind, db: &str, arg: c_int) {
+            let details = CallbackDetails {
+                arg: Some(arg),
+                ..none()
+            };
+            self.call(kind, db, details, None);
+        }
+
+        fn io(&self, kind: CallbackKind, db: &str, offset: u64, data: &[u8]) {
+            let details = CallbackDetails {
+                offset: Some(offset),
+                len: Some(data.len()),
+                ..none()
+            };
+            self.call(kind, db, details, Some(data));
+        }
+    }
+
+    fn none() -> CallbackDetails {
+        CallbackDetails {
+            phase: Phase::Enter,
+            offset: None,
+            len: None,
+            arg: None,
+        }
+    }
+
+    /// The callbacks SQLite makes for point lookups in a database of [PAGES] pages, each looking
+    /// up the same pages twice, and a transaction updating some of them in a rollback journal.
+    pub fn canonical(instrumentation: &dyn Instrumentation) {
+        use CallbackKind::*;
+        let session = Session { instrumentation };
+        let journal = format!("{NAME}-journal");
+        session.arg(Open, NAME, SQLITE_OPEN_MAIN_DB | SQLITE_OPEN_READWRITE);
+        for lookup in [7u64, 31, 7, 31, 12, 7] {
+            session.arg(Lock, NAME, SHARED);
+            session.arg(Access, &journal, 0);
+            session.io(Read, NAME, 24, &page(0)[24..40]);
+            session.call(FileSize, NAME, none(), None);
+            session.io(Read, NAME, 0, &page(0));
+            for n in [1, lookup, lookup + 1] {
+                session.io(Read, NAME, n * PAGE_SIZE as u64, &page(n));
+            }
+            session.arg(Unlock, NAME, 0);
+        }
+
+        session.arg(Lock, NAME, SHARED);
+        session.io(Read, NAME, 0, &page(0));
+        session.arg(Lock, NAME, RESERVED);
+        let flags = SQLITE_OPEN_MAIN_JOURNAL | SQLITE_OPEN_CREATE | SQLITE_OPEN_READWRITE;
+        session.arg(Open, &journal, flags);
+        session.io(Write, &journal, 0, &[0; 28]);
+        let mut at = 512;
+        for n in [0, 3, 4] {
+            session.io(Write, &journal, at, &(n as u32 + 1).to_be_bytes());
+            session.io(Write, &journal, at + 4, &page(n));
+            at += 8 + PAGE_SIZE as u64;
+        }
+        session.arg(Sync, &journal, 2);
+        session.arg(Lock, NAME, EXCLUSIVE);
+        for n in [0, 3, 4, PAGES] {
+            let mut updated = page(n);
+            updated[..8].copy_from_slice(b"updated!");
+            session.io(Write, NAME, n * PAGE_SIZE as u64, &updated);
+        }
+        session.arg(Sync, NAME, 2);
+        session.call(Close, &journal, none(), None);
+        session.call(Delete, &journal, none(), None);
+        session.arg(Unlock, NAME, SHARED);
+        // reads back a page it wrote
+        let mut updated = page(3);
+        updated[..8].copy_from_slice(b"updated!");
+        session.io(Read, NAME, 3 * PAGE_SIZE as u64, &updated);
+        session.arg(Unlock, NAME, 0);
+        session.call(Close, NAME, none(), None);
+    }
+
+    #[test]
+    fn test_capture() {
+        let capture = Capture::default();
+        canonical(&capture);
+        let workload = capture.workload();
+
+        assert_eq!(
+            workload.files,
+            vec![
+                File {
+                    kind: FileKind::MainDb,
+                    of: None
+                },
+                File {
+                    kind: FileKind::MainJournal,
+                    of: Some(0)
+                },
+            ]
+        );
+        assert_eq!(workload.size(0), (PAGES + 1) * PAGE_SIZE as u64);
+        assert_eq!(workload.page_size(0), PAGE_SIZE as u32);
+        let reads: Vec<_> = workload
+            .ops
+            .iter()
+            .filter(|op| op.step.kind == OpKind::Read && op.step.offset == Some(7 * 4096))
+            .collect();
+        assert_eq!(reads.len(), 3);
+        assert!(reads.iter().all(|op| op.content == reads[0].content));
+        // the read of the page written has the content of the write
+        let content = |kind, offset| {
+            let mut ops = workload.ops.iter().rev();
+            let op = ops.find(|op| op.step.kind == kind && op.step.offset == Some(offset));
+            op.unwrap().content
+        };
+        assert_eq!(
+            content(OpKind::Read, 3 * 4096),
+            content(OpKind::Write, 3 * 4096)
+        );
+        assert_ne!(
+            content(OpKind::Read, 3 * 4096),
+            content(OpKind::Read, 7 * 4096)
+        );
+        let times: Vec<_> = workload.ops.iter().map(|op| op.at_us).collect();
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(workload
+            .to_string()
+            .starts_with(&format!("{} operations on 2 files", workload.ops.len())));
+
+        let decoded = Workload::decode(&workload.encode()).unwrap();
+        assert_eq!(decoded, workload);
+    }
+
+    #[test]
+    fn test_nothing_of_the_data_captured() {
+        let capture = Capture::default();
+        canonical(&capture);
+        let encoded = capture.workload().encode();
+        // a few bytes per operation, far less than the data
+        assert!(
+            encoded.len() < capture.workload().ops.len() * 24,
+            "{}",
+            encoded.len()
+        );
+
+        let leaks = |needle: &[u8]| encoded.windows(needle.len()).any(|w| w == needle);
+        assert!(!leaks(NAME.as_bytes()));
+        assert!(!leaks(b"customers"));
+        assert!(!leaks(b"-journal"));
+        for window in SECRET.chunks(6) {
+            assert!(!leaks(window), "{}", String::from_utf8_lossy(window));
+        }
+        assert!(!leaks(b"updated!"));
+        // nor a digest of a page
+        for n in [0, 1, 3, 7] {
+            let digest = Sha256::digest(page(n));
+            assert!(!leaks(&digest[..8]));
+            assert!(!leaks(&md5::compute(page(n)).0[..8]));
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects() {
+        let mut encoded = Workload::default().encode();
+        assert!(Workload::decode(b"SQLite format 3\0").is_err());
+        assert!(Workload::decode(&encoded[..6]).is_err());
+        encoded[4] = 2;
+        assert!(matches!(
+            Workload::decode(&encoded),
+            Err(Error::IncompatibleFormat { need: 2, have: 1 })
+        ));
+    }
+}
@@ -0,0 +1,305 @@
+//! Serializing updates of the metadata object with conditional writes of the lock object.
+//!
+//! The [S3FileLock] sets a legal hold on the lock object, which needs Object Lock enabled on the
+//! bucket, and most S3-compatible stores don't offer it by default, e.g. MinIO, R2 or the S3
+//! interoperability of GCS. [CasLock], selected with [LockBackend::Conditional], only relies on
+//! conditional writes:
+//!
+//! 1. Acquiring writes the lock object with `If-None-Match: *`. Of instances acquiring at once,
+//!    one write succeeds and the others fail with `412 Precondition Failed` and wait.
+//! 2. Releasing deletes the lock object with `If-Match` on the ETag written, so that a release
+//!    never deletes the lock of another holder.
+//! 3. A lock object that is there without being held is taken over with `If-Match` on its ETag,
+//!    a compare-and-swap only one of the instances taking it over wins. An empty one, as the
+//!    [S3FileLock] leaves it when released, is taken over right away. One a holder never released,
+//!    e.g. because it crashed, is taken over once the same ETag was observed for
+//!    [LockConfig::write_request_lease] on the monotonic clock, see [crate::clock].
+//!
+//! A holder whose lock was taken over finds out when it releases it, which fails with
+//! [Error::LockLost]. The lock is held for a read-modify-write of the metadata object only, far
+//! shorter than the lease.
+//!
+//! A legal hold and a conditional write don't exclude each other, so all instances of a database
+//! must use the same [LockBackend].
+//!
+//! [S3FileLock]: crate::vfs::S3FileLock
+//! [LockBackend]: crate::config::LockBackend
+//! [LockBackend::Conditional]: crate::config::LockBackend::Conditional
+
+use std::time::Duration;
+
+use crate::{
+    clock::Observation,
+    config::LockConfig,
+    error::Error,
+    key::ObjectKey,
+    vfs::{status, Lock, Precondition},
+};
+
+#[derive(Clone)]
+pub struct CasLock {
+    pub s3: aws_sdk_s3::Client,
+    pub bucket: String,
+    pub lock_file: ObjectKey,
+    pub current_lock: Option<Vec<u8>>,
+    /// The ETag the held lock was written with.
+    etag: Option<String>,
+    /// How long a lock object may stay unchanged before it is taken over.
+    lease: Duration,
+    /// How long to wait before looking at a held lock object again.
+    poll_interval: Duration,
+}
+
+impl CasLock {
+    pub fn new(
+        s3: aws_sdk_s3::Client,
+        bucket: String,
+        lock_file: ObjectKey,
+        config: &LockConfig,
+    ) -> Self {
+        Self {
+            s3,
+            bucket,
+            lock_file,
+            current_lock: None,
+            etag: None,
+            lease: config.write_request_lease,
+            poll_interval: config.poll_interval,
+        }
+    }
+
+    fn failed(&self, action: &'static str, reason: impl ToString) -> Error {
+        Error::LockObject {
+            key: self.lock_file.to_string(),
+            action,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Write `id` to the lock object on `condition`. Returns the ETag written, or `None` if the
+    /// condition failed.
+    async fn write(&self, id: &[u8], condition: Precondition) -> Result<Option<String>, Error> {
+        let put = self
+            .s3
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&self.lock_file)
+            .body(id.to_vec().into());
+        let put = match condition {
+            Precondition::None => put,
+            Precondition::Absent => put.if_none_match("*"),
+            Precondition::Matches(etag) => put.if_match(etag),
+        };
+        match put.send().await {
+            Ok(out) => Ok(Some(out.e_tag.unwrap_or_default())),
+            // S3 answers 409 to a conditional write racing another one
+            Err(err) if matches!(status(&err), Some(412 | 409)) => Ok(None),
+            Err(err) => Err(self.failed("writing", err)),
+        }
+    }
+
+    /// The length and ETag of the lock object, `None` if there is none.
+    async fn head(&self) -> Result<Option<(i64, String)>, Error> {
+        let head = self
+            .s3
+            .head_object()
+            .bucket(&self.bucket)
+            .key(&self.lock_file)
+            .send()
+            .await;
+        match head {
+            Ok(head) => Ok(Some((
+                head.content_length().unwrap_or(0),
+                head.e_tag.unwrap_or_default(),
+            ))),
+            Err(err) if status(&err) == Some(404) => Ok(None),
+            Err(err) => Err(self.failed("reading", err)),
+        }
+    }
+}
+
+impl Lock for CasLock {
+    async fn request_lock(&mut self) -> Result<Vec<u8>, Error> {
+        let lock_uuid = uuid::Uuid::new_v4().to_bytes_le().to_vec();
+        let mut observed = None;
+        let mut condition = Some(Precondition::Absent);
+        loop {
+            if let Some(condition) = condition.take() {
+                if let Some(etag) = self.write(&lock_uuid, condition).await? {
+                    self.etag = Some(etag);
+                    self.current_lock = Some(lock_uuid.clone());
+                    return Ok(lock_uuid);
+                }
+            }
+            condition = match self.head().await? {
+                None => Some(Precondition::Absent),
+                Some((0, etag)) => Some(Precondition::Matches(etag)),
+                Some((_, etag)) if Observation::observe(&mut observed, &etag) >= self.lease => {
+                    tracing::warn!(
+                        target: "threeqlite::lock_protocol",
+                        key = %self.lock_file,
+                        %etag,
+                        lease = ?self.lease,
+                        "lock object unchanged for a whole lease, taking it over"
+                    );
+                    Some(Precondition::Matches(etag))
+                }
+                Some(_) => {
+                    tokio::time::sleep(self.poll_interval).await;
+                    None
+                }
+            };
+        }
+    }
+
+    async fn release_lock(&mut self) -> Result<(), Error> {
+        self.current_lock = None;
+        let Some(etag) = self.etag.take() else {
+            return Ok(());
+        };
+        let res = self
+            .s3
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(&self.lock_file)
+            .if_match(etag)
+            .send()
+            .await;
+        match res {
+            Ok(_) => Ok(()),
+            Err(err) if matches!(status(&err), Some(412 | 404)) => Err(Error::LockLost {
+                key: self.lock_file.to_string(),
+            }),
+            Err(err) => Err(self.failed("deleting", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::mock::MockS3;
+
+    fn lock(mock: &MockS3, lease: Duration) -> CasLock {
+        let config = LockConfig {
+            write_request_lease: lease,
+            poll_interval: Duration::from_millis(5),
+            ..LockConfig::default()
+        };
+        let key = ObjectKey::new("lockfile").unwrap();
+        CasLock::new(mock.client(), "threeqlite".to_owned(), key, &config)
+    }
+
+    #[tokio::test]
+    async fn test_excludes_concurrent_holders() {
+        let mock = MockS3::start();
+        let inside = Arc::new(AtomicUsize::new(0));
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..4 {
+            let (mut lock, inside) = (lock(&mock, Duration::from_secs(30)), inside.clone());
+            tasks.spawn(async move {
+                for _ in 0..3 {
+                    lock.request_lock().await.unwrap();
+                    assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                    lock.release_lock().await.unwrap();
+                }
+            });
+        }
+        while let Some(res) = tasks.join_next().await {
+            res.unwrap();
+        }
+        assert_eq!(mock.get("lockfile"), None);
+    }
+
+    #[tokio::test]
+    async fn test_takes_over_released_legal_hold() {
+        let mock = MockS3::start();
+        // what S3FileLock leaves behind
+        mock.put("lockfile", []);
+        let mut lock = lock(&mock, Duration::from_secs(30));
+        let id = tokio::time::timeout(Duration::from_secs(5), lock.request_lock())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(mock.get("lockfile"), Some(id));
+        lock.release_lock().await.unwrap();
+        assert_eq!(mock.get("lockfile"), None);
+    }
+
+    #[tokio::test]
+    async fn test_takes_over_stale_lock() {
+        let mock = MockS3::start();
+        let mut crashed = lock(&mock, Duration::from_secs(30));
+        crashed.request_lock().await.unwrap();
+
+        let mut other = lock(&mock, Duration::from_millis(50));
+        let id = other.request_lock().await.unwrap();
+        assert_eq!(mock.get("lockfile"), Some(id));
+
+        // the release can't delete the lock of the new holder
+        assert!(matches!(
+            crashed.release_lock().await,
+            Err(Error::LockLost { key }) if key == "lockfile"
+        ));
+        assert!(mock.get("lockfile").is_some());
+        other.release_lock().await.unwrap();
+        assert_eq!(mock.get("lockfile"), None);
+    }
+}
+
+#[cfg(all(test, feature = "rusqlite"))]
+mod sqlite_tests {
+    use crate::{
+        blocking::BlockingClient,
+        config::{Config, LockBackend, LockConfig},
+        mock::MockS3,
+    };
+
+    #[test]
+    fn test_writes_without_legal_hold() {
+        let mock = MockS3::start();
+        let config = Config {
+            lock: LockConfig {
+                backend: LockBackend::Conditional,
+                ..LockConfig::default()
+            },
+            ..Config::default()
+        };
+        let client = BlockingClient::with_client(config, mock.client()).unwrap();
+        client.register("conditional-lock", false).unwrap();
+        let conn = client.connect("test.db").unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (x);
+             BEGIN;
+             INSERT INTO t VALUES (1), (2);
+             COMMIT;",
+        )
+        .unwrap();
+        let count: i64 = conn
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+        drop(conn);
+
+        // the lock object is written and deleted, never read for a legal hold
+        let requests = mock.requests();
+        let lock: Vec<_> = requests
+            .iter()
+            .filter(|(_, key)| key == "lockfile")
+            .map(|(method, _)| method.as_str())
+            .collect();
+        assert!(
+            lock.contains(&"PUT") && lock.contains(&"DELETE"),
+            "{lock:?}"
+        );
+        assert!(!lock.contains(&"GET"), "{lock:?}");
+        assert_eq!(mock.get("lockfile"), None);
+    }
+}
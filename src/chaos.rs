@@ -0,0 +1,679 @@
+//! Fault injection into the requests of an instance, for chaos testing against a real store.
+//!
+//! [crate::mock] scripts failures for unit tests. Staging environments running against S3 need
+//! faults too, to check alerting, busy handling and recovery with real latencies and a real SQLite:
+//! cutting the writer off for 10 s in the middle of a commit, or delaying the uploads of block
+//! manifests by 2 s. With the `chaos` feature, the requests of every instance go through a
+//! [ChaosStorage], which applies the [ChaosPolicy] of the instance's [Chaos]. Without the feature,
+//! none of this is compiled, so a production build can't inject anything.
+//!
+//! A policy is a list of [ChaosRule]s, each matching requests by method, [OpClass] and key pattern,
+//! optionally within a window of time after the policy was set, and with a probability drawn from
+//! the seed of the policy, so that a run injects the same faults given the same requests. Its
+//! [ChaosAction] delays the request, fails it without sending it, loses its response after the
+//! store handled it, or sends it twice. The policy is set with [Chaos::set], or from a connection
+//! with `PRAGMA threeqlite_chaos = '<policy as JSON>'`, and cleared with
+//! `PRAGMA threeqlite_chaos = 'off'`:
+//!
+//! ```sql
+//! PRAGMA threeqlite_chaos = '{"seed": 7, "rules": [
+//!     {"methods": ["PUT"], "key": "*.blocks", "action": {"kind": "latency", "min_ms": 2000, "max_ms": 2000}},
+//!     {"class": "write", "window": {"after_ms": 0, "for_ms": 10000}, "action": {"kind": "fail", "error": "io"}}
+//! ]}';
+//! ```
+//!
+//! Requests pass straight through while no policy is set. Every fault injected is logged under
+//! `threeqlite::chaos` and counted in [ChaosStats], and in [Stats::injected_faults] next to the
+//! failures of the instance, so that tests and dashboards tell injected failures from organic ones.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use aws_sdk_s3::config::{
+    http::{HttpRequest, HttpResponse},
+    HttpClient, RuntimeComponents, SharedHttpClient,
+};
+use aws_smithy_runtime_api::{
+    client::{
+        http::{HttpConnector, HttpConnectorFuture, HttpConnectorSettings, SharedHttpConnector},
+        result::ConnectorError,
+    },
+    http::StatusCode,
+};
+use aws_smithy_types::body::SdkBody;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{circuit::OpClass, stats::Stats};
+
+/// The faults to inject, see the [module documentation](self).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChaosPolicy {
+    /// Seeds the draws of [ChaosRule::probability] and [ChaosAction::Latency].
+    pub seed: u64,
+    pub rules: Vec<ChaosRule>,
+}
+
+impl std::str::FromStr for ChaosPolicy {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+/// Requests a [ChaosAction] applies to.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChaosRule {
+    /// Only requests of these methods, e.g. `PUT`, or of any method if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Only requests of this class: `GET` and `HEAD` read, anything else writes.
+    #[serde(default)]
+    pub class: Option<OpClass>,
+    /// Only requests whose path matches this pattern, where `*` matches any run of characters.
+    /// The path starts with the bucket with path-style addressing, so most patterns start with
+    /// `*`.
+    #[serde(default = "any_key")]
+    pub key: String,
+    /// Only within this window after the policy was set.
+    #[serde(default)]
+    pub window: Option<ChaosWindow>,
+    /// The share of the matching requests to inject into.
+    #[serde(default = "always")]
+    pub probability: f64,
+    pub action: ChaosAction,
+}
+
+fn any_key() -> String {
+    "*".to_owned()
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+impl ChaosRule {
+    /// A rule applying `action` to every request.
+    pub fn new(action: ChaosAction) -> Self {
+        Self {
+            methods: vec![],
+            class: None,
+            key: any_key(),
+            window: None,
+            probability: always(),
+            action,
+        }
+    }
+
+    fn matches(&self, method: &str, path: &str, elapsed: Duration) -> bool {
+        let class = match method {
+            "GET" | "HEAD" => OpClass::Read,
+            _ => OpClass::Write,
+        };
+        (self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+            && self.class.is_none_or(|c| c == class)
+            && self.window.is_none_or(|window| window.contains(elapsed))
+            && glob(&self.key, path)
+    }
+}
+
+/// `after_ms..after_ms + for_ms` after the policy was set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChaosWindow {
+    pub after_ms: u64,
+    pub for_ms: u64,
+}
+
+impl ChaosWindow {
+    fn contains(&self, elapsed: Duration) -> bool {
+        let elapsed = elapsed.as_millis() as u64;
+        elapsed >= self.after_ms && elapsed - self.after_ms < self.for_ms
+    }
+}
+
+/// Whether `pattern`, where `*` matches any run of characters, matches all of `text`.
+fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<_> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChaosAction {
+    /// Delay the request by a duration drawn from `min_ms..=max_ms`.
+    Latency { min_ms: u64, max_ms: u64 },
+    /// Fail the request without sending it.
+    Fail { error: InjectedError },
+    /// Send the request, then lose its response, as if the connection dropped after the store
+    /// handled it.
+    DropResponse,
+    /// Send the request twice, as a retry after a lost response would, answering with the second
+    /// response.
+    Duplicate,
+}
+
+impl ChaosAction {
+    fn name(&self) -> &'static str {
+        match self {
+            ChaosAction::Latency { .. } => "latency",
+            ChaosAction::Fail { .. } => "fail",
+            ChaosAction::DropResponse => "drop_response",
+            ChaosAction::Duplicate => "duplicate",
+        }
+    }
+}
+
+/// The failure a [ChaosAction::Fail] answers with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectedError {
+    /// `503 SlowDown`.
+    Throttled,
+    /// `500 InternalError`.
+    Internal,
+    /// `403 AccessDenied`.
+    Denied,
+    /// The connection timed out.
+    Timeout,
+    /// The connection failed, as if the network was down.
+    Io,
+}
+
+impl InjectedError {
+    fn answer(self) -> Result<HttpResponse, ConnectorError> {
+        let (status, code) = match self {
+            InjectedError::Throttled => (503, "SlowDown"),
+            InjectedError::Internal => (500, "InternalError"),
+            InjectedError::Denied => (403, "AccessDenied"),
+            InjectedError::Timeout => {
+                return Err(ConnectorError::timeout("injected timeout".into()))
+            }
+            InjectedError::Io => return Err(ConnectorError::io("injected network failure".into())),
+        };
+        let body = format!(
+            "<Error><Code>{code}</Code><Message>injected by threeqlite chaos</Message></Error>"
+        );
+        let mut response = HttpResponse::new(
+            StatusCode::try_from(status).expect("valid status"),
+            SdkBody::from(body),
+        );
+        response
+            .headers_mut()
+            .insert("content-type", "application/xml");
+        Ok(response)
+    }
+}
+
+/// The faults injected by a [Chaos], by action.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChaosStats {
+    pub latency: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+}
+
+impl ChaosStats {
+    pub fn total(&self) -> u64 {
+        self.latency + self.failed + self.dropped + self.duplicated
+    }
+}
+
+impl std::fmt::Display for ChaosStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "latency={} failed={} dropped={} duplicated={}",
+            self.latency, self.failed, self.dropped, self.duplicated
+        )
+    }
+}
+
+struct Active {
+    policy: ChaosPolicy,
+    since: Instant,
+    rng: StdRng,
+}
+
+/// The policy of an instance and the faults it injected, see the [module documentation](self).
+pub struct Chaos {
+    /// Whether a policy with rules is set, checked before anything else.
+    active: AtomicBool,
+    policy: Mutex<Option<Active>>,
+    /// Indexed like the fields of [ChaosStats].
+    injected: [AtomicU64; 4],
+    stats: Arc<Stats>,
+}
+
+impl Chaos {
+    /// Injecting nothing until [Chaos::set], counting what it injects in `stats` too.
+    pub fn new(stats: Arc<Stats>) -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            policy: Mutex::new(None),
+            injected: Default::default(),
+            stats,
+        }
+    }
+
+    /// Inject the faults of `policy` from now on, its windows starting now.
+    pub fn set(&self, policy: ChaosPolicy) {
+        tracing::warn!(target: "threeqlite::chaos", ?policy, "policy set");
+        let mut current = self.policy.lock().unwrap();
+        self.active.store(!policy.rules.is_empty(), Relaxed);
+        *current = Some(Active {
+            rng: StdRng::seed_from_u64(policy.seed),
+            policy,
+            since: Instant::now(),
+        });
+    }
+
+    /// Stop injecting faults.
+    pub fn clear(&self) {
+        let mut current = self.policy.lock().unwrap();
+        self.active.store(false, Relaxed);
+        if current.take().is_some() {
+            tracing::warn!(target: "threeqlite::chaos", "policy cleared");
+        }
+    }
+
+    /// The policy set, if any.
+    pub fn policy(&self) -> Option<ChaosPolicy> {
+        let current = self.policy.lock().unwrap();
+        current.as_ref().map(|active| active.policy.clone())
+    }
+
+    pub fn stats(&self) -> ChaosStats {
+        let [latency, failed, dropped, duplicated] =
+            self.injected.each_ref().map(|n| n.load(Relaxed));
+        ChaosStats {
+            latency,
+            failed,
+            dropped,
+            duplicated,
+        }
+    }
+
+    /// The faults to inject into a request, with the delays drawn, counting and logging them.
+    fn decide(&self, method: &str, path: &str) -> Vec<(ChaosAction, Duration)> {
+        let mut current = self.policy.lock().unwrap();
+        let Some(active) = current.as_mut() else {
+            return vec![];
+        };
+        let elapsed = active.since.elapsed();
+        let mut faults = vec![];
+        for rule in &active.policy.rules {
+            if !rule.matches(method, path, elapsed)
+                || !active.rng.gen_bool(rule.probability.clamp(0.0, 1.0))
+            {
+                continue;
+            }
+            let delay = match rule.action {
+                ChaosAction::Latency { min_ms, max_ms } => {
+                    Duration::from_millis(active.rng.gen_range(min_ms..=max_ms.max(min_ms)))
+                }
+                _ => Duration::ZERO,
+            };
+            let counter = match rule.action {
+                ChaosAction::Latency { .. } => 0,
+                ChaosAction::Fail { .. } => 1,
+                ChaosAction::DropResponse => 2,
+                ChaosAction::Duplicate => 3,
+            };
+            self.injected[counter].fetch_add(1, Relaxed);
+            Stats::incr(&self.stats.injected_faults);
+            tracing::warn!(
+                target: "threeqlite::chaos",
+                method,
+                path,
+                action = rule.action.name(),
+                ?delay,
+                "injecting fault"
+            );
+            faults.push((rule.action, delay));
+        }
+        faults
+    }
+}
+
+impl std::fmt::Debug for Chaos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Chaos")
+            .field("policy", &self.policy())
+            .field("injected", &self.stats())
+            .finish()
+    }
+}
+
+/// The HTTP client of an S3 client, injecting the faults of a [Chaos] into its requests.
+#[derive(Debug)]
+pub struct ChaosStorage {
+    client: SharedHttpClient,
+    chaos: Arc<Chaos>,
+}
+
+impl ChaosStorage {
+    /// `s3`, sending its requests through `chaos`.
+    pub fn wrap(s3: &aws_sdk_s3::Client, chaos: Arc<Chaos>) -> aws_sdk_s3::Client {
+        let Some(client) = s3
+            .config()
+            .http_client()
+            .or_else(aws_smithy_runtime::client::http::hyper_014::default_client)
+        else {
+            tracing::error!(target: "threeqlite::chaos", "no HTTP client to wrap, not injecting faults");
+            return s3.clone();
+        };
+        aws_sdk_s3::Client::from_conf(
+            s3.config()
+                .to_builder()
+                .http_client(ChaosStorage { client, chaos })
+                .build(),
+        )
+    }
+}
+
+impl HttpClient for ChaosStorage {
+    fn http_connector(
+        &self,
+        settings: &HttpConnectorSettings,
+        components: &RuntimeComponents,
+    ) -> SharedHttpConnector {
+        SharedHttpConnector::new(ChaosConnector {
+            connector: self.client.http_connector(settings, components),
+            chaos: self.chaos.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ChaosConnector {
+    connector: SharedHttpConnector,
+    chaos: Arc<Chaos>,
+}
+
+impl HttpConnector for ChaosConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        if !self.chaos.active.load(Relaxed) {
+            return self.connector.call(request);
+        }
+        let path = request.uri().split('?').next().unwrap_or_default();
+        let path = path.split_once("://").map_or(path, |(_, rest)| {
+            rest.find('/').map_or("", |at| &rest[at..])
+        });
+        let faults = self
+            .chaos
+            .decide(request.method(), path.trim_start_matches('/'));
+        if faults.is_empty() {
+            return self.connector.call(request);
+        }
+        let connector = self.connector.clone();
+        HttpConnectorFuture::new(async move {
+            let mut drop_response = false;
+            for (action, delay) in faults {
+                match action {
+                    ChaosAction::Latency { .. } => tokio::time::sleep(delay).await,
+                    ChaosAction::Fail { error } => return error.answer(),
+                    ChaosAction::DropResponse => drop_response = true,
+                    ChaosAction::Duplicate => {
+                        if let Some(copy) = request.try_clone() {
+                            let _ = connector.call(copy).await;
+                        }
+                    }
+                }
+            }
+            let response = connector.call(request).await?;
+            match drop_response {
+                true => Err(ConnectorError::io("injected loss of the response".into())),
+                false => Ok(response),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        circuit::CircuitConfig,
+        config::Config,
+        mock::{self, MockS3},
+        vfs::ThreeQLite,
+    };
+
+    async fn instance(mock: &MockS3) -> (ThreeQLite, Arc<Chaos>) {
+        // injected failures open circuits like any other
+        let config = Config {
+            circuit: CircuitConfig {
+                failure_threshold: 100,
+                ..CircuitConfig::default()
+            },
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let chaos = tq.chaos().await;
+        (tq, chaos)
+    }
+
+    fn rule(methods: &[&str], key: &str, action: ChaosAction) -> ChaosRule {
+        ChaosRule {
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            key: key.to_owned(),
+            ..ChaosRule::new(action)
+        }
+    }
+
+    fn policy(rules: Vec<ChaosRule>) -> ChaosPolicy {
+        ChaosPolicy { seed: 7, rules }
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("*", "bucket/test.db"));
+        assert!(glob("*.blocks", "bucket/test.db.blocks"));
+        assert!(!glob("*.blocks", "bucket/test.db"));
+        assert!(glob("bucket/*/main.db", "bucket/tenants/a/main.db"));
+        assert!(glob("*test*", "bucket/test.db-journal"));
+        assert!(glob("bucket/test.db", "bucket/test.db"));
+        assert!(!glob("bucket/test.db", "bucket/test.db-journal"));
+    }
+
+    #[test]
+    fn test_policy_from_json() {
+        let policy: ChaosPolicy = r#"{"seed": 7, "rules": [
+            {"methods": ["PUT"], "key": "*.blocks", "action": {"kind": "latency", "min_ms": 2000, "max_ms": 2000}},
+            {"class": "write", "window": {"after_ms": 0, "for_ms": 10000}, "action": {"kind": "fail", "error": "io"}}
+        ]}"#
+        .parse()
+        .unwrap();
+        assert_eq!(policy.rules.len(), 2);
+        assert_eq!(policy.rules[0].probability, 1.0);
+        assert_eq!(policy.rules[1].class, Some(OpClass::Write));
+        assert_eq!(policy.rules[1].key, "*");
+        assert_eq!(
+            policy.rules[1].action,
+            ChaosAction::Fail {
+                error: InjectedError::Io
+            }
+        );
+        assert!("{\"rules\": [{}]}".parse::<ChaosPolicy>().is_err());
+    }
+
+    /// The same seed injects into the same requests.
+    #[test]
+    fn test_seeded() {
+        let chaos = Chaos::new(Arc::default());
+        let drawn = |seed| {
+            chaos.set(ChaosPolicy {
+                seed,
+                rules: vec![ChaosRule {
+                    probability: 0.5,
+                    ..ChaosRule::new(ChaosAction::Latency {
+                        min_ms: 1,
+                        max_ms: 1000,
+                    })
+                }],
+            });
+            (0..32)
+                .map(|_| {
+                    chaos
+                        .decide("GET", "bucket/test.db")
+                        .first()
+                        .map(|(_, d)| *d)
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = drawn(1);
+        assert_eq!(first, drawn(1));
+        assert_ne!(first, drawn(2));
+        assert!(first.iter().any(Option::is_none) && first.iter().any(Option::is_some));
+    }
+
+    #[tokio::test]
+    async fn test_passthrough() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let (tq, chaos) = instance(&mock).await;
+        chaos.set(ChaosPolicy::default());
+        tq.quick_header("test.db").await.unwrap();
+        chaos.set(policy(vec![rule(&["PUT"], "*", ChaosAction::DropResponse)]));
+        tq.quick_header("test.db").await.unwrap();
+        assert_eq!(chaos.stats(), ChaosStats::default());
+        assert_eq!(tq.stats().await.injected_faults, 0);
+    }
+
+    #[tokio::test]
+    async fn test_latency() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let (tq, chaos) = instance(&mock).await;
+        let delay = ChaosAction::Latency {
+            min_ms: 200,
+            max_ms: 200,
+        };
+        chaos.set(policy(vec![rule(&["GET"], "*test.db", delay)]));
+        let start = Instant::now();
+        tq.quick_header("test.db").await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(chaos.stats().latency, 1);
+    }
+
+    #[tokio::test]
+    async fn test_fail() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let (tq, chaos) = instance(&mock).await;
+        for error in [
+            InjectedError::Throttled,
+            InjectedError::Internal,
+            InjectedError::Timeout,
+            InjectedError::Io,
+        ] {
+            chaos.set(policy(vec![rule(
+                &[],
+                "*test.db",
+                ChaosAction::Fail { error },
+            )]));
+            assert!(tq.quick_header("test.db").await.is_err(), "{error:?}");
+        }
+        // a 403 reads as an object these credentials may not read
+        let denied = ChaosAction::Fail {
+            error: InjectedError::Denied,
+        };
+        chaos.set(policy(vec![rule(&[], "*test.db", denied)]));
+        assert!(matches!(
+            tq.quick_header("test.db").await,
+            Err(crate::error::Error::ObjectNotFound)
+        ));
+        // never sent
+        assert!(mock.requests().is_empty());
+
+        // injected failures are told apart from organic ones
+        let failures = tq.stats().await.failures;
+        mock.reject("GET", 500);
+        chaos.clear();
+        assert!(tq.quick_header("test.db").await.is_err());
+        let stats = tq.stats().await;
+        assert_eq!(chaos.stats().failed, 5);
+        assert_eq!((stats.failures - failures, stats.injected_faults), (1, 5));
+    }
+
+    #[tokio::test]
+    async fn test_drop_response() {
+        let mock = MockS3::start();
+        let (tq, chaos) = instance(&mock).await;
+        chaos.set(policy(vec![rule(
+            &["PUT"],
+            "*metadata",
+            ChaosAction::DropResponse,
+        )]));
+        let inner = tq.inner.read().await;
+        let record = crate::vfs::MetadataRecord::default();
+        assert!(inner.write_metadata_record(record).await.is_err());
+        // the store took the write
+        assert!(mock.get("metadata").is_some());
+        assert_eq!(chaos.stats().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let (tq, chaos) = instance(&mock).await;
+        chaos.set(policy(vec![rule(
+            &["GET"],
+            "*test.db",
+            ChaosAction::Duplicate,
+        )]));
+        tq.quick_header("test.db").await.unwrap();
+        let get = ("GET".to_owned(), "test.db".to_owned());
+        let requests = mock.requests();
+        assert_eq!(
+            requests.iter().filter(|request| **request == get).count(),
+            2
+        );
+        assert_eq!(chaos.stats().duplicated, 1);
+    }
+
+    #[tokio::test]
+    async fn test_window() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let (tq, chaos) = instance(&mock).await;
+        let fail = ChaosAction::Fail {
+            error: InjectedError::Io,
+        };
+        chaos.set(policy(vec![ChaosRule {
+            class: Some(OpClass::Read),
+            window: Some(ChaosWindow {
+                after_ms: 0,
+                for_ms: 100,
+            }),
+            ..ChaosRule::new(fail)
+        }]));
+        assert!(tq.quick_header("test.db").await.is_err());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        tq.quick_header("test.db").await.unwrap();
+        assert_eq!(chaos.stats().total(), 1);
+    }
+}
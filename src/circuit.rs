@@ -0,0 +1,480 @@
+//! Failing fast against an unhealthy backend.
+//!
+//! Requests are counted per circuit, one per operation class of a database: a database whose
+//! prefix is misconfigured or throttled opens its own circuits, and the requests of an instance
+//! for other databases, e.g. an integrity check of one next to the database it serves, or the
+//! journals of an attached one, go on as before. [CircuitIsolation::Bucket] shares the circuits of
+//! a bucket between its databases instead, and [CircuitBreaker::with_overrides] gives databases
+//! thresholds or an isolation of their own.
+//!
+//! Read permits are shared the same way, see [crate::priority]. The page cache only holds pages of
+//! the database the instance serves, so there is nothing to partition between databases.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::error::Error;
+
+/// The class of a storage operation. Circuits are tracked separately per class so that a bucket
+/// rejecting writes does not take reads down with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "s3",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum OpClass {
+    Read,
+    Write,
+}
+
+impl OpClass {
+    pub const ALL: [OpClass; 2] = [OpClass::Read, OpClass::Write];
+}
+
+impl fmt::Display for OpClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OpClass::Read => "read",
+            OpClass::Write => "write",
+        })
+    }
+}
+
+/// The state of a single circuit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests flow normally.
+    Closed,
+    /// Requests fail immediately with [Error::CircuitOpen].
+    Open,
+    /// A single probe request is allowed through to test whether the backend recovered.
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half-open",
+        })
+    }
+}
+
+/// Which requests share a circuit, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CircuitIsolation {
+    /// A circuit per database and class.
+    #[default]
+    Database,
+    /// A circuit per bucket and class, shared by the databases in the bucket.
+    Bucket,
+}
+
+#[derive(Clone, Debug)]
+pub struct CircuitConfig {
+    /// Number of failures within `window` after which the circuit opens.
+    pub failure_threshold: usize,
+    /// The sliding window over which failures are counted.
+    pub window: Duration,
+    /// How long an open circuit waits before letting a single probe request through.
+    pub probe_interval: Duration,
+    /// Keep serving reads while the write circuit is open. Reads skip reader registration in the
+    /// metadata object (which is itself a write) and go straight to the database object.
+    pub degraded_reads: bool,
+    pub isolation: CircuitIsolation,
+}
+
+impl Default for CircuitConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            window: Duration::from_secs(10),
+            probe_interval: Duration::from_secs(5),
+            degraded_reads: false,
+            isolation: CircuitIsolation::default(),
+        }
+    }
+}
+
+struct Circuit {
+    state: CircuitState,
+    failures: VecDeque<Instant>,
+    opened_at: Option<Instant>,
+    probe_in_flight: bool,
+}
+
+impl Circuit {
+    fn new() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            failures: VecDeque::new(),
+            opened_at: None,
+            probe_in_flight: false,
+        }
+    }
+}
+
+/// A circuit: the bucket, the database unless shared by the bucket, see [CircuitIsolation], and
+/// the class of its requests.
+type CircuitKey = (String, Option<String>, OpClass);
+
+/// A circuit that isn't closed, see [CircuitBreaker::unhealthy].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CircuitStatus {
+    pub bucket: String,
+    /// `None` for the circuit of a bucket, see [CircuitIsolation::Bucket].
+    pub db: Option<String>,
+    pub class: OpClass,
+    pub state: CircuitState,
+}
+
+impl fmt::Display for CircuitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.db {
+            Some(db) => write!(f, "{}/{db}.{}={}", self.bucket, self.class, self.state),
+            None => write!(f, "{}.{}={}", self.bucket, self.class, self.state),
+        }
+    }
+}
+
+/// Tracks recent failures per circuit, see the [module documentation](self), and fails requests
+/// fast once a backend is known to be unhealthy.
+pub struct CircuitBreaker {
+    config: CircuitConfig,
+    /// The configuration of databases that differ from `config`, by the key of their object.
+    overrides: HashMap<String, CircuitConfig>,
+    circuits: Mutex<HashMap<CircuitKey, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitConfig) -> Self {
+        Self::with_overrides(config, HashMap::new())
+    }
+
+    /// A breaker configured with `config`, except for the databases in `overrides`, by the key of
+    /// their object. A database isolated with [CircuitIsolation::Bucket] shares the circuits of
+    /// its bucket, which follow `config`.
+    pub fn with_overrides(
+        config: CircuitConfig,
+        overrides: HashMap<String, CircuitConfig>,
+    ) -> Self {
+        Self {
+            config,
+            overrides,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &CircuitConfig {
+        &self.config
+    }
+
+    /// The circuit of requests of `class` for `db` in `bucket`, and its configuration.
+    fn circuit(&self, bucket: &str, db: &str, class: OpClass) -> (CircuitKey, &CircuitConfig) {
+        let config = self.overrides.get(db).unwrap_or(&self.config);
+        match config.isolation {
+            CircuitIsolation::Database => ((bucket.to_owned(), Some(db.to_owned()), class), config),
+            CircuitIsolation::Bucket => ((bucket.to_owned(), None, class), &self.config),
+        }
+    }
+
+    /// Check whether a request of `class` for `db` in `bucket` may be sent.
+    pub fn check(&self, bucket: &str, db: &str, class: OpClass) -> Result<(), Error> {
+        self.check_at(bucket, db, class, Instant::now())
+    }
+
+    /// Record the outcome of a request previously allowed by [CircuitBreaker::check].
+    pub fn record(&self, bucket: &str, db: &str, class: OpClass, success: bool) {
+        self.record_at(bucket, db, class, success, Instant::now())
+    }
+
+    /// The current state of the circuit of requests of `class` for `db` in `bucket`.
+    pub fn state(&self, bucket: &str, db: &str, class: OpClass) -> CircuitState {
+        let (key, _) = self.circuit(bucket, db, class);
+        self.circuits
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map(|c| c.state)
+            .unwrap_or(CircuitState::Closed)
+    }
+
+    /// Whether reads of `db` should bypass metadata writes because its write circuit is not
+    /// closed.
+    pub fn degraded(&self, bucket: &str, db: &str) -> bool {
+        let (_, config) = self.circuit(bucket, db, OpClass::Write);
+        config.degraded_reads && self.state(bucket, db, OpClass::Write) != CircuitState::Closed
+    }
+
+    /// The circuits that aren't closed, of every database and bucket.
+    pub fn unhealthy(&self) -> Vec<CircuitStatus> {
+        let circuits = self.circuits.lock().unwrap();
+        let mut unhealthy: Vec<_> = circuits
+            .iter()
+            .filter(|(_, circuit)| circuit.state != CircuitState::Closed)
+            .map(|((bucket, db, class), circuit)| CircuitStatus {
+                bucket: bucket.clone(),
+                db: db.clone(),
+                class: *class,
+                state: circuit.state,
+            })
+            .collect();
+        unhealthy.sort_by(|a, b| {
+            (&a.bucket, &a.db, a.class as u8).cmp(&(&b.bucket, &b.db, b.class as u8))
+        });
+        unhealthy
+    }
+
+    fn check_at(&self, bucket: &str, db: &str, class: OpClass, now: Instant) -> Result<(), Error> {
+        let (key, config) = self.circuit(bucket, db, class);
+        let open = || Error::CircuitOpen {
+            bucket: bucket.to_owned(),
+            db: key.1.clone(),
+            class,
+        };
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(key.clone()).or_insert_with(Circuit::new);
+
+        match circuit.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open => {
+                let opened_at = circuit.opened_at.unwrap_or(now);
+                if now.duration_since(opened_at) >= config.probe_interval {
+                    transition(&key, circuit, CircuitState::HalfOpen);
+                    circuit.probe_in_flight = true;
+                    Ok(())
+                } else {
+                    Err(open())
+                }
+            }
+            CircuitState::HalfOpen => {
+                if circuit.probe_in_flight {
+                    Err(open())
+                } else {
+                    circuit.probe_in_flight = true;
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn record_at(&self, bucket: &str, db: &str, class: OpClass, success: bool, now: Instant) {
+        let (key, config) = self.circuit(bucket, db, class);
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(key.clone()).or_insert_with(Circuit::new);
+
+        while circuit
+            .failures
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > config.window)
+        {
+            circuit.failures.pop_front();
+        }
+
+        match (circuit.state, success) {
+            (CircuitState::HalfOpen, true) => {
+                circuit.failures.clear();
+                circuit.opened_at = None;
+                circuit.probe_in_flight = false;
+                transition(&key, circuit, CircuitState::Closed);
+            }
+            (CircuitState::HalfOpen, false) => {
+                circuit.opened_at = Some(now);
+                circuit.probe_in_flight = false;
+                transition(&key, circuit, CircuitState::Open);
+            }
+            (CircuitState::Closed, false) => {
+                circuit.failures.push_back(now);
+                if circuit.failures.len() >= config.failure_threshold {
+                    circuit.opened_at = Some(now);
+                    transition(&key, circuit, CircuitState::Open);
+                }
+            }
+            (CircuitState::Closed, true) | (CircuitState::Open, _) => {}
+        }
+    }
+}
+
+fn transition(key: &CircuitKey, circuit: &mut Circuit, to: CircuitState) {
+    let (bucket, db, class) = key;
+    tracing::warn!(
+        target: "threeqlite::s3",
+        bucket,
+        db = db.as_deref(),
+        ?class,
+        from = %circuit.state,
+        to = %to,
+        "circuit state changed"
+    );
+    circuit.state = to;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(CircuitConfig {
+            failure_threshold: 3,
+            window: Duration::from_secs(10),
+            probe_interval: Duration::from_secs(5),
+            degraded_reads: true,
+            isolation: CircuitIsolation::Database,
+        })
+    }
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let cb = breaker();
+        let now = Instant::now();
+        for _ in 0..3 {
+            cb.check_at("b", "x.db", OpClass::Write, now).unwrap();
+            cb.record_at("b", "x.db", OpClass::Write, false, now);
+        }
+        assert_eq!(cb.state("b", "x.db", OpClass::Write), CircuitState::Open);
+        assert!(matches!(
+            cb.check_at("b", "x.db", OpClass::Write, now),
+            Err(Error::CircuitOpen { .. })
+        ));
+        // reads are tracked independently
+        assert!(cb.check_at("b", "x.db", OpClass::Read, now).is_ok());
+        assert!(cb.degraded("b", "x.db"));
+    }
+
+    #[test]
+    fn test_failures_outside_window_are_forgotten() {
+        let cb = breaker();
+        let now = Instant::now();
+        cb.record_at("b", "x.db", OpClass::Write, false, now);
+        cb.record_at("b", "x.db", OpClass::Write, false, now);
+        cb.record_at(
+            "b",
+            "x.db",
+            OpClass::Write,
+            false,
+            now + Duration::from_secs(11),
+        );
+        assert_eq!(cb.state("b", "x.db", OpClass::Write), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_probe_closes_circuit() {
+        let cb = breaker();
+        let now = Instant::now();
+        for _ in 0..3 {
+            cb.record_at("b", "x.db", OpClass::Write, false, now);
+        }
+
+        let later = now + Duration::from_secs(5);
+        cb.check_at("b", "x.db", OpClass::Write, later).unwrap();
+        assert_eq!(
+            cb.state("b", "x.db", OpClass::Write),
+            CircuitState::HalfOpen
+        );
+        // only a single probe at a time
+        assert!(cb.check_at("b", "x.db", OpClass::Write, later).is_err());
+
+        cb.record_at("b", "x.db", OpClass::Write, true, later);
+        assert_eq!(cb.state("b", "x.db", OpClass::Write), CircuitState::Closed);
+        assert!(!cb.degraded("b", "x.db"));
+    }
+
+    #[test]
+    fn test_failed_probe_reopens_circuit() {
+        let cb = breaker();
+        let now = Instant::now();
+        for _ in 0..3 {
+            cb.record_at("b", "x.db", OpClass::Write, false, now);
+        }
+
+        let later = now + Duration::from_secs(5);
+        cb.check_at("b", "x.db", OpClass::Write, later).unwrap();
+        cb.record_at("b", "x.db", OpClass::Write, false, later);
+        assert_eq!(cb.state("b", "x.db", OpClass::Write), CircuitState::Open);
+        assert!(cb
+            .check_at("b", "x.db", OpClass::Write, later + Duration::from_secs(1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_requests_bounded_during_storm() {
+        let cb = breaker();
+        let start = Instant::now();
+        let mut sent = 0;
+        // one attempt every 100ms for 60s against a backend that always fails
+        for i in 0..600 {
+            let now = start + Duration::from_millis(i * 100);
+            if cb.check_at("b", "x.db", OpClass::Write, now).is_ok() {
+                sent += 1;
+                cb.record_at("b", "x.db", OpClass::Write, false, now);
+            }
+        }
+        // threshold to open, then one probe per probe interval
+        assert!(sent <= 3 + 60 / 5, "sent {sent} requests");
+    }
+
+    #[test]
+    fn test_isolation() {
+        let now = Instant::now();
+        let fail = |cb: &CircuitBreaker, db| {
+            for _ in 0..3 {
+                cb.record_at("b", db, OpClass::Read, false, now);
+            }
+        };
+
+        // a failing database opens its own circuits only
+        let cb = breaker();
+        fail(&cb, "bad.db");
+        assert!(cb.check_at("b", "bad.db", OpClass::Read, now).is_err());
+        assert!(cb.check_at("b", "good.db", OpClass::Read, now).is_ok());
+        assert_eq!(
+            cb.unhealthy(),
+            vec![CircuitStatus {
+                bucket: "b".to_owned(),
+                db: Some("bad.db".to_owned()),
+                class: OpClass::Read,
+                state: CircuitState::Open,
+            }]
+        );
+        assert_eq!(cb.unhealthy()[0].to_string(), "b/bad.db.read=open");
+
+        // shared by the bucket, it takes the others down with it
+        let shared = CircuitConfig {
+            isolation: CircuitIsolation::Bucket,
+            ..breaker().config().clone()
+        };
+        let cb = CircuitBreaker::new(shared.clone());
+        fail(&cb, "bad.db");
+        let err = cb.check_at("b", "good.db", OpClass::Read, now).unwrap_err();
+        assert!(matches!(err, Error::CircuitOpen { db: None, .. }), "{err}");
+
+        // databases opted into a looser isolation or stricter thresholds
+        let strict = CircuitConfig {
+            failure_threshold: 1,
+            ..breaker().config().clone()
+        };
+        let overrides = [
+            ("shared.db".to_owned(), shared),
+            ("strict.db".to_owned(), strict),
+        ];
+        let cb = CircuitBreaker::with_overrides(breaker().config().clone(), overrides.into());
+        cb.record_at("b", "strict.db", OpClass::Read, false, now);
+        assert_eq!(
+            cb.state("b", "strict.db", OpClass::Read),
+            CircuitState::Open
+        );
+        fail(&cb, "shared.db");
+        assert_eq!(
+            cb.state("b", "good.db", OpClass::Read),
+            CircuitState::Closed
+        );
+        assert_eq!(cb.unhealthy().len(), 2);
+        assert!(cb.unhealthy().iter().any(|status| status.db.is_none()));
+    }
+}
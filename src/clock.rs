@@ -0,0 +1,247 @@
+//! Telling a suspend or a step of the wall clock from time passing.
+//!
+//! A laptop resuming from suspend, or a VM whose clock NTP stepped, sees the wall clock jump while
+//! the monotonic clock, that of [tokio::time::Instant], didn't move or moved by less. Each side
+//! alone misleads: lease expiries compared against the wall clock fire all at once or not at all,
+//! and monotonic timers frozen through a suspend make a lease look current that ran out hours ago.
+//!
+//! So time is used in two ways only. Waits and intervals are measured on the monotonic clock, and
+//! the leases of other instances are judged by how long they were observed unchanged on it, see
+//! [Observation]: a lease is expired once its holder didn't renew it for its length as this
+//! instance counts, whatever the clocks of either instance read. The wall clock only goes into
+//! what is stored for other instances and people to read, e.g. [crate::role::Lease::expires].
+//!
+//! [Clock::check] compares the time passed on both clocks since its last call, and reports a
+//! [Jump] once they diverge by more than [ClockConfig::jump_threshold]. The background tasks and
+//! lock requests check on every wakeup, and [crate::vfs::Inner::check_clock] then revalidates
+//! conservatively: the role leases of the instance count as lost and are read before they are
+//! renewed, observations of other leases start over, and the pinned headers of the cache, see
+//! [crate::cache], are dropped.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::protocol;
+
+#[derive(Clone, Debug)]
+pub struct ClockConfig {
+    /// How far the wall clock may run ahead of or behind the monotonic clock between two checks
+    /// before it counts as a [Jump].
+    pub jump_threshold: Duration,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            jump_threshold: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Where the wall clock is read from.
+pub trait WallClock: fmt::Debug + Send + Sync {
+    /// Milliseconds since the Unix epoch.
+    fn now_ms(&self) -> u64;
+}
+
+/// The clock of the system.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl WallClock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        protocol::now_ms()
+    }
+}
+
+/// The clock of the system, stepped by an offset, for tests simulating steps and suspends.
+#[derive(Debug, Default)]
+pub struct SteppedClock {
+    offset_ms: AtomicI64,
+}
+
+impl SteppedClock {
+    /// Step the clock by `ms`, back if negative.
+    pub fn step(&self, ms: i64) {
+        self.offset_ms.fetch_add(ms, Relaxed);
+    }
+}
+
+impl WallClock for SteppedClock {
+    fn now_ms(&self) -> u64 {
+        protocol::now_ms().saturating_add_signed(self.offset_ms.load(Relaxed))
+    }
+}
+
+/// The clocks diverging between two checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Jump {
+    /// Time passed on the monotonic clock.
+    pub monotonic: Duration,
+    /// Time passed on the wall clock, in milliseconds, negative if it was stepped back.
+    pub wall_ms: i64,
+}
+
+impl Jump {
+    /// How far the wall clock ran ahead of the monotonic clock, in milliseconds.
+    pub fn divergence_ms(&self) -> i64 {
+        self.wall_ms - self.monotonic.as_millis() as i64
+    }
+}
+
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "wall clock moved {}ms while {}ms passed",
+            self.wall_ms,
+            self.monotonic.as_millis()
+        )
+    }
+}
+
+/// The wall clock of an instance, and the detector of its jumps, see the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct Clock {
+    config: ClockConfig,
+    wall: Arc<dyn WallClock>,
+    /// Both clocks as of the last check.
+    last: Mutex<(Instant, u64)>,
+    jumps: AtomicU64,
+}
+
+impl Clock {
+    pub fn new(config: ClockConfig) -> Self {
+        Self::with_wall(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_wall(config: ClockConfig, wall: Arc<dyn WallClock>) -> Self {
+        let last = Mutex::new((Instant::now(), wall.now_ms()));
+        Self {
+            config,
+            wall,
+            last,
+            jumps: AtomicU64::new(0),
+        }
+    }
+
+    /// Milliseconds since the Unix epoch, for what is stored rather than for deciding.
+    pub fn now_ms(&self) -> u64 {
+        self.wall.now_ms()
+    }
+
+    /// The jump of the clocks since the last check, if any.
+    pub fn check(&self) -> Option<Jump> {
+        let (now, now_ms) = (Instant::now(), self.wall.now_ms());
+        let (then, then_ms) = std::mem::replace(&mut *self.last.lock().unwrap(), (now, now_ms));
+        let jump = Jump {
+            monotonic: now.saturating_duration_since(then),
+            wall_ms: now_ms as i64 - then_ms as i64,
+        };
+        if jump.divergence_ms().unsigned_abs() <= self.config.jump_threshold.as_millis() as u64 {
+            return None;
+        }
+        self.jumps.fetch_add(1, Relaxed);
+        Some(jump)
+    }
+
+    /// The jumps detected so far.
+    pub fn jumps(&self) -> u64 {
+        self.jumps.load(Relaxed)
+    }
+}
+
+/// A lease of another instance as first seen in a version, e.g. its ETag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Observation {
+    version: String,
+    since: Instant,
+}
+
+impl Observation {
+    /// Observe `version` in `slot`, returning how long it has been observed unchanged. A new
+    /// version restarts the observation.
+    pub fn observe(slot: &mut Option<Observation>, version: &str) -> Duration {
+        let now = Instant::now();
+        match slot {
+            Some(observed) if observed.version == version => now - observed.since,
+            _ => {
+                *slot = Some(Observation {
+                    version: version.to_owned(),
+                    since: now,
+                });
+                Duration::ZERO
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock() -> (Clock, Arc<SteppedClock>) {
+        let wall = Arc::new(SteppedClock::default());
+        (Clock::with_wall(ClockConfig::default(), wall.clone()), wall)
+    }
+
+    const HOUR_MS: i64 = 3_600_000;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_time_passing() {
+        let (clock, wall) = clock();
+        for _ in 0..10 {
+            tokio::time::advance(Duration::from_secs(60)).await;
+            wall.step(60_000);
+            assert_eq!(clock.check(), None);
+        }
+        // NTP slewing, or a step within the threshold
+        tokio::time::advance(Duration::from_secs(60)).await;
+        wall.step(63_000);
+        assert_eq!(clock.check(), None);
+        assert_eq!(clock.jumps(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_suspend_and_steps() {
+        let (clock, wall) = clock();
+        // a suspend: the monotonic clock stood still while the wall clock went on
+        tokio::time::advance(Duration::from_secs(1)).await;
+        wall.step(2 * HOUR_MS);
+        let jump = clock.check().unwrap();
+        assert!(jump.divergence_ms() > 2 * HOUR_MS - 10_000, "{jump}");
+        assert_eq!(clock.check(), None);
+
+        // a step back
+        tokio::time::advance(Duration::from_secs(1)).await;
+        wall.step(-HOUR_MS);
+        assert!(clock.check().unwrap().divergence_ms() < -HOUR_MS + 10_000);
+
+        // the monotonic clock running on while the wall clock was stepped back by as much
+        tokio::time::advance(Duration::from_secs(60)).await;
+        wall.step(-60_000);
+        assert!(clock.check().is_some());
+        assert_eq!(clock.jumps(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_observation() {
+        let mut slot = None;
+        assert_eq!(Observation::observe(&mut slot, "a"), Duration::ZERO);
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(Observation::observe(&mut slot, "a"), Duration::from_secs(2));
+        // a renewal restarts it
+        assert_eq!(Observation::observe(&mut slot, "b"), Duration::ZERO);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(Observation::observe(&mut slot, "b"), Duration::from_secs(1));
+    }
+}
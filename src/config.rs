@@ -0,0 +1,320 @@
+use std::{collections::HashMap, time::Duration};
+
+#[cfg(feature = "s3")]
+use crate::{
+    burst::BurstConfig, clock::ClockConfig, degraded::DegradedReadPolicy, extent::ManifestConfig,
+    fetch::FetchConfig, flush::DeltaConfig, limits::TransactionLimits, prefetch::PrefetchConfig,
+    reconcile::ReconcileConfig, region::RegionConfig, role::RoleConfig, shutdown::ShutdownConfig,
+    spend::SpendBudget, verify::VerifyWrites, watch::WatchConfig,
+};
+use crate::{
+    cache::CacheConfig, circuit::CircuitConfig, durability::SyncPolicy, priority::PriorityConfig,
+    probe::ProbeConfig, timeouts::TimeoutConfig,
+};
+
+/// Configuration for a [crate::vfs::ThreeQLite] instance.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The bucket holding the database, lock and metadata objects.
+    pub bucket: String,
+    /// Key of the database object.
+    pub db_filename: String,
+    /// Key of the object used as the metadata lock.
+    pub lock_file: String,
+    /// Key of the metadata object.
+    pub metadata_filename: String,
+    /// Circuit breaker settings for storage operations.
+    pub circuit: CircuitConfig,
+    /// Circuit breaker settings of the databases that differ from [Config::circuit], by the key
+    /// of their object, see [crate::circuit].
+    pub database_circuits: HashMap<String, CircuitConfig>,
+    /// Write-permission probe settings.
+    pub write_probe: ProbeConfig,
+    /// Check every upload with a HEAD request on top of comparing the ETag of the response, see
+    /// [crate::verify].
+    pub paranoid_commit: bool,
+    /// Which uploads to verify with checksums the store returns, or by reading them back, see
+    /// [crate::verify]. Off by default.
+    #[cfg(feature = "s3")]
+    pub verify_writes: VerifyWrites,
+    /// Serve reads of a database object that was modified out-of-band rather than failing them
+    /// with [crate::error::Error::ExternalModification]. Writes stay refused, see [crate::heal].
+    pub read_externally_modified: bool,
+    /// Reader/writer protocol settings.
+    pub lock: LockConfig,
+    /// Priority classes of reads, see [crate::priority].
+    pub priority: PriorityConfig,
+    /// Caching of pages, see [crate::cache].
+    pub cache: CacheConfig,
+    /// Timeouts per class of storage operation, see [crate::timeouts]. Derived from
+    /// [crate::fetch::FetchConfig::attempt_timeout] if `None`. Replaces the timeout config of the client.
+    pub timeouts: Option<TimeoutConfig>,
+    /// The most memory the caches and journals of the instance may use together, see
+    /// [crate::memory]. Unbounded if `None`.
+    pub memory_cap: Option<u64>,
+    /// Settings of [crate::watch::GenerationWatcher].
+    #[cfg(feature = "s3")]
+    pub watch: WatchConfig,
+    /// Settings of parallel ranged reads, see [crate::fetch].
+    #[cfg(feature = "s3")]
+    pub fetch: FetchConfig,
+    /// Prefetching the pages transactions start with, see [crate::prefetch].
+    #[cfg(feature = "s3")]
+    pub prefetch: PrefetchConfig,
+    /// Size limits of a single transaction, see [crate::limits].
+    #[cfg(feature = "s3")]
+    pub limits: TransactionLimits,
+    /// Caps on the requests and bytes of the whole instance, see [crate::spend].
+    #[cfg(feature = "s3")]
+    pub spend: SpendBudget,
+    /// Uploading only the bytes of a page that changed, see [crate::flush::DeltaConfig].
+    #[cfg(feature = "s3")]
+    pub delta: DeltaConfig,
+    /// Settings of listing the sidecars of a database, see [crate::reconcile].
+    #[cfg(feature = "s3")]
+    pub reconcile: ReconcileConfig,
+    /// Which background roles this instance runs, see [crate::role].
+    #[cfg(feature = "s3")]
+    pub roles: RoleConfig,
+    /// When a jump of the wall clock forces leases to be revalidated, see [crate::clock].
+    #[cfg(feature = "s3")]
+    pub clock: ClockConfig,
+    /// Whether to find the region of the bucket on the first open, see [crate::region].
+    #[cfg(feature = "s3")]
+    pub region: RegionConfig,
+    /// How long opens share the outcomes of their checks, see [crate::burst].
+    #[cfg(feature = "s3")]
+    pub open_burst: BurstConfig,
+    /// How long [crate::vfs::ThreeQLite::shutdown] waits, see [crate::shutdown].
+    #[cfg(feature = "s3")]
+    pub shutdown: ShutdownConfig,
+    /// When block manifests are split into extents, see [crate::extent].
+    #[cfg(feature = "s3")]
+    pub manifest: ManifestConfig,
+    /// Serve reads from the offline mirror while the metadata object is unreadable, see
+    /// [crate::degraded]. Such reads fail if `None`.
+    #[cfg(feature = "s3")]
+    pub degraded_reads: Option<DegradedReadPolicy>,
+    /// Count the file controls SQLite sends by opcode and report them in the stats, warning once
+    /// about each opcode acknowledged without being handled, see [sqlite_vfs::fcntl].
+    pub strict_file_control: bool,
+    /// Record the operations SQLite makes through the registrations of the instance, to replay
+    /// them elsewhere, see [crate::capture].
+    #[cfg(feature = "s3")]
+    pub capture: bool,
+    /// What happens to commits SQLite completes without syncing the database, e.g. with
+    /// `PRAGMA synchronous=OFF`, see [crate::durability].
+    pub synchronous: SyncPolicy,
+    /// PRAGMAs set on the connections threeqlite opens.
+    pub connection: ConnectionDefaults,
+}
+
+/// PRAGMAs set on connections opened by [crate::blocking::BlockingClient::connect] and
+/// `AsyncConnection::open`, before they are handed out. `None` leaves SQLite's default; a
+/// connection can still override them.
+#[derive(Clone, Debug)]
+pub struct ConnectionDefaults {
+    /// `PRAGMA cache_spill`. A spill writes dirty pages to the database object mid-transaction,
+    /// only to write most of them again before the commit, so it is off by default.
+    pub cache_spill: Option<bool>,
+    /// `PRAGMA cache_size`, in pages or negative in KiB. Defaults to 64 MiB, so that large
+    /// transactions stay in the page cache.
+    pub cache_size: Option<i64>,
+    /// The sector size database handles answer to `xSectorSize`, capped at
+    /// [crate::sector::MAX_SECTOR_SIZE]. Anything above 512 only grows journals, see
+    /// [crate::sector].
+    pub sector_size: u32,
+    /// `PRAGMA threeqlite_budget`, the budget of each connection on top of that of the instance,
+    /// see [crate::spend].
+    #[cfg(feature = "s3")]
+    pub budget: Option<SpendBudget>,
+    /// `PRAGMA temp_store=MEMORY`, keeping temporary tables and indices in the memory of SQLite
+    /// rather than in [temporary files](crate::temp), which are in memory as well but go through
+    /// the VFS.
+    pub temp_store_memory: bool,
+}
+
+impl Default for ConnectionDefaults {
+    fn default() -> Self {
+        Self {
+            cache_spill: Some(false),
+            cache_size: Some(-64 * 1024),
+            sector_size: sqlite_vfs::capability::DEFAULT_SECTOR_SIZE,
+            #[cfg(feature = "s3")]
+            budget: None,
+            temp_store_memory: true,
+        }
+    }
+}
+
+impl ConnectionDefaults {
+    /// The PRAGMAs to run on a new connection.
+    pub fn sql(&self) -> String {
+        let spill = self
+            .cache_spill
+            .map(|spill| format!("PRAGMA cache_spill={spill};"));
+        let size = self
+            .cache_size
+            .map(|size| format!("PRAGMA cache_size={size};"));
+        #[cfg(feature = "s3")]
+        let budget = self
+            .budget
+            .map(|budget| format!("PRAGMA threeqlite_budget='{budget}';"));
+        #[cfg(not(feature = "s3"))]
+        let budget = None;
+        let temp_store = self
+            .temp_store_memory
+            .then(|| "PRAGMA temp_store=MEMORY;".to_owned());
+        [spill, size, budget, temp_store]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// The URI to open `db` with, passing `psow=0` if [Self::sector_size] is larger than 512, since
+    /// SQLite ignores the sector size of files advertising powersafe overwrites. `None` if the
+    /// name will do.
+    pub fn uri(&self, db: &str) -> Option<String> {
+        if crate::sector::advertised(self.sector_size)
+            == sqlite_vfs::capability::DEFAULT_SECTOR_SIZE
+        {
+            return None;
+        }
+        let path = db
+            .replace('%', "%25")
+            .replace('?', "%3f")
+            .replace('#', "%23");
+        Some(format!("file:{path}?psow=0"))
+    }
+}
+
+/// Settings of the reader/writer protocol, see [crate::protocol].
+#[derive(Clone, Debug)]
+pub struct LockConfig {
+    /// How long a write request keeps new readers out without being refreshed. A waiting writer
+    /// refreshes it once half of the lease is left; the request of a crashed writer stops blocking
+    /// readers after this long.
+    pub write_request_lease: Duration,
+    /// How long to wait before looking at the metadata object again after the lock changed hands,
+    /// see [crate::wait].
+    pub poll_interval: Duration,
+    /// How long to wait at most before looking again while the same holder keeps the lock.
+    pub max_poll_interval: Duration,
+    /// Give up waiting for the lock after this long, failing with a diagnosis of who holds it,
+    /// see [crate::busy]. Waits indefinitely if `None`.
+    pub busy_timeout: Option<Duration>,
+    /// How this instance names itself to instances waiting for its lock. Defaults to
+    /// `<hostname>:<pid>`.
+    pub identity: String,
+    /// Take the write lock over from a writer on this machine whose process exited without
+    /// releasing it, see [crate::busy].
+    pub take_over_dead_writers: bool,
+    /// How updates of the metadata object are serialized. All instances of a database must use
+    /// the same backend.
+    pub backend: LockBackend,
+}
+
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self {
+            write_request_lease: Duration::from_secs(30),
+            poll_interval: Duration::from_millis(10),
+            max_poll_interval: Duration::from_secs(2),
+            busy_timeout: None,
+            identity: format!("{}:{}", hostname(), std::process::id()),
+            take_over_dead_writers: true,
+            backend: LockBackend::default(),
+        }
+    }
+}
+
+/// How the lock object serializes updates of the metadata object.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockBackend {
+    /// A legal hold on the lock object, which needs Object Lock enabled on the bucket, see
+    /// [crate::vfs::S3FileLock].
+    #[default]
+    LegalHold,
+    /// Conditional writes of the lock object, for stores without Object Lock, see [crate::cas].
+    Conditional,
+}
+
+impl std::str::FromStr for LockBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legal-hold" => Ok(Self::LegalHold),
+            "conditional" => Ok(Self::Conditional),
+            _ => Err(format!(
+                "{s:?} is not a lock backend, expected legal-hold or conditional"
+            )),
+        }
+    }
+}
+
+/// The name of this host, or `unknown`.
+pub fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bucket: "threeqlite".to_owned(),
+            db_filename: "test.db".to_owned(),
+            lock_file: "lockfile".to_owned(),
+            metadata_filename: "metadata".to_owned(),
+            circuit: CircuitConfig::default(),
+            database_circuits: HashMap::new(),
+            write_probe: ProbeConfig::default(),
+            paranoid_commit: false,
+            #[cfg(feature = "s3")]
+            verify_writes: VerifyWrites::default(),
+            read_externally_modified: false,
+            lock: LockConfig::default(),
+            priority: PriorityConfig::default(),
+            cache: CacheConfig::default(),
+            timeouts: None,
+            memory_cap: None,
+            #[cfg(feature = "s3")]
+            watch: WatchConfig::default(),
+            #[cfg(feature = "s3")]
+            fetch: FetchConfig::default(),
+            #[cfg(feature = "s3")]
+            prefetch: PrefetchConfig::default(),
+            #[cfg(feature = "s3")]
+            limits: TransactionLimits::default(),
+            #[cfg(feature = "s3")]
+            spend: SpendBudget::default(),
+            #[cfg(feature = "s3")]
+            delta: DeltaConfig::default(),
+            #[cfg(feature = "s3")]
+            reconcile: ReconcileConfig::default(),
+            #[cfg(feature = "s3")]
+            roles: RoleConfig::default(),
+            #[cfg(feature = "s3")]
+            clock: ClockConfig::default(),
+            #[cfg(feature = "s3")]
+            region: RegionConfig::default(),
+            #[cfg(feature = "s3")]
+            open_burst: BurstConfig::default(),
+            #[cfg(feature = "s3")]
+            shutdown: ShutdownConfig::default(),
+            #[cfg(feature = "s3")]
+            manifest: ManifestConfig::default(),
+            #[cfg(feature = "s3")]
+            degraded_reads: None,
+            strict_file_control: false,
+            #[cfg(feature = "s3")]
+            capture: false,
+            synchronous: SyncPolicy::Allow,
+            connection: ConnectionDefaults::default(),
+        }
+    }
+}
@@ -0,0 +1,1088 @@
+//! Copying and renaming databases within the bucket.
+//!
+//! [ThreeQLite::copy_database] copies the database object and its sidecars (see [Sidecar]) with
+//! server-side `CopyObject` requests, so no data passes through the client. Objects larger than
+//! [CopyOptions::multipart_threshold] are copied in parts, since `CopyObject` is limited to 5 GiB.
+//! Generations aren't copied: the destination starts a lineage of its own once an instance opens
+//! it. [ThreeQLite::rename_database] copies, verifies the copies, and deletes the source.
+//!
+//! While either operation runs, both names carry a progress record ([KeyLayout::copy_progress]),
+//! created in key order with `If-None-Match: *`, which keeps other copies and renames of the same
+//! names out. The record of the destination lists the objects copied so far, so that an
+//! interrupted operation resumes where it stopped when called again with the same names. The
+//! sidecars are copied before the database object, and the block manifest last, so the destination
+//! doesn't exist as a database before it is complete. [Overwrite] decides what happens to an
+//! existing destination.
+//!
+//! Both run as maintenance operations, see [crate::operation]. A cancelled copy or rename stops
+//! after the object it is copying and leaves its progress records, like a crash would.
+//! [ThreeQLite::estimate_copy_database] and [ThreeQLite::estimate_rename_database] plan either
+//! without running it, see [crate::cost]: the checks before the first write, the listing of the
+//! objects left to copy and the parts they are copied in are those of running it.
+//!
+//! Progress records don't keep SQLite out. For the database of this instance both operations
+//! refuse to run while anyone holds its lock; stop other instances serving the databases before
+//! copying or renaming them.
+
+use std::ops::Range;
+
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    circuit::OpClass,
+    cost::{self, CostEstimate},
+    error::Error,
+    format::{self, Bounded},
+    key::{KeyLayout, ObjectKey},
+    operation::{Operation, OperationHandle, OperationKind},
+    reconcile::Sidecar,
+    vfs::{status, Inner, ThreeQLite},
+};
+
+/// What to do if the destination of a copy already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Fail with [Error::DatabaseExists].
+    #[default]
+    Fail,
+    /// Delete the destination and its sidecars first.
+    Replace,
+}
+
+#[derive(Clone, Debug)]
+pub struct CopyOptions {
+    pub overwrite: Overwrite,
+    /// Objects larger than this are copied in parts.
+    pub multipart_threshold: u64,
+    pub part_size: u64,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            overwrite: Overwrite::Fail,
+            multipart_threshold: 5 << 30,
+            part_size: 512 << 20,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CopyReport {
+    /// Objects copied by this call.
+    pub objects: u64,
+    pub bytes: u64,
+    /// Objects an interrupted call had copied already.
+    pub resumed: u64,
+    /// Objects of the source deleted by a rename.
+    pub deleted: u64,
+    /// What the plan of this call estimated it costs, see [crate::cost].
+    pub estimate: Option<CostEstimate>,
+    pub cost: CostEstimate,
+}
+
+/// The progress record of a copy, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Progress {
+    src: String,
+    dst: String,
+    rename: bool,
+    /// The ETag of the source database object as of the objects copied so far.
+    etag: String,
+    /// Whether an existing destination was deleted, see [Overwrite::Replace].
+    cleared: bool,
+    /// Keys of the source copied so far.
+    copied: Vec<String>,
+    /// Set once a rename verified the copies and started deleting the source.
+    deleting: bool,
+}
+
+impl Progress {
+    fn is(&self, src: &ObjectKey, dst: &ObjectKey, rename: bool) -> bool {
+        self.src == src.as_str() && self.dst == dst.as_str() && self.rename == rename
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        bincode::serialize(self).map_err(|err| Error::Whatever {
+            message: format!("failed to encode copy progress: {err}"),
+            source: Some(err),
+        })
+    }
+}
+
+impl Bounded for Progress {
+    const MAX_LEN: u64 = 64 << 20;
+
+    fn validate(&self) -> Result<(), String> {
+        format::check_count("keys", &self.copied, 1 << 16)
+    }
+}
+
+/// Percent-encode `key` of `bucket` for `x-amz-copy-source`.
+fn copy_source(bucket: &str, key: &str) -> String {
+    let mut source = String::new();
+    for b in format!("{bucket}/{key}").bytes() {
+        match b.is_ascii_alphanumeric() || b"-_.~/".contains(&b) {
+            true => source.push(b as char),
+            false => source += &format!("%{b:02X}"),
+        }
+    }
+    source
+}
+
+/// The ranges an object of `size` bytes is copied in with `UploadPartCopy`, `None` if a single
+/// `CopyObject` copies it.
+fn copy_parts(size: u64, opts: &CopyOptions) -> Option<Vec<Range<u64>>> {
+    if size <= opts.multipart_threshold {
+        return None;
+    }
+    let part_size = opts.part_size.max(1);
+    let parts = (0..size)
+        .step_by(part_size as usize)
+        .map(|start| start..(start + part_size).min(size));
+    Some(parts.collect())
+}
+
+/// The order objects are copied in: sidecars, then the database object, then the manifest.
+fn rank(db: &ObjectKey, key: &str) -> u8 {
+    match Sidecar::of(db, key) {
+        Some(Sidecar::Manifest) => 2,
+        None => 1,
+        Some(_) => 0,
+    }
+}
+
+/// The database object `db` and its sidecars, other than its progress record and role leases,
+/// with their sizes.
+async fn objects(inner: &Inner, db: &ObjectKey) -> Result<Vec<(String, u64)>, Error> {
+    let mut objects = vec![];
+    let mut token = None;
+    loop {
+        let page = inner
+            .list(
+                inner
+                    .s3
+                    .list_objects_v2()
+                    .prefix(db.as_str())
+                    .set_continuation_token(token.take()),
+            )
+            .await?;
+        for obj in page.contents() {
+            let Some(key) = obj.key() else { continue };
+            let sidecar = Sidecar::of(db, key);
+            // progress records and role leases belong to the name, not to the database
+            let copied = |s| !matches!(s, Sidecar::CopyProgress | Sidecar::RoleLease);
+            if key == db.as_str() || sidecar.is_some_and(copied) {
+                objects.push((key.to_owned(), obj.size().unwrap_or(0) as u64));
+            }
+        }
+        match page.next_continuation_token() {
+            Some(next) if page.is_truncated() == Some(true) => token = Some(next.to_owned()),
+            _ => break,
+        }
+    }
+    objects.sort_by_key(|(key, _)| rank(db, key));
+    Ok(objects)
+}
+
+/// The ETag of `key`, `None` if it doesn't exist.
+async fn head_etag(inner: &Inner, key: &ObjectKey) -> Result<Option<String>, Error> {
+    let head = inner
+        .s3
+        .head_object()
+        .bucket(&inner.bucket)
+        .key(key)
+        .send()
+        .await;
+    // a missing object is an answer, not a failure of the store
+    let missing = matches!(&head, Err(err) if status(err) == Some(404));
+    inner.record_for(key, OpClass::Read, head.is_ok() || missing);
+    match head {
+        Ok(head) => Ok(Some(head.e_tag.unwrap_or_default())),
+        Err(err) if status(&err) == Some(404) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn read_progress(inner: &Inner, db: &ObjectKey) -> Result<Option<Progress>, Error> {
+    let key = KeyLayout::copy_progress(db);
+    let obj = inner
+        .s3
+        .get_object()
+        .bucket(&inner.bucket)
+        .key(&key)
+        .send()
+        .await;
+    let missing = matches!(&obj, Err(err) if status(err) == Some(404));
+    inner.record_for(&key, OpClass::Read, obj.is_ok() || missing);
+    let obj = match obj {
+        Ok(obj) => obj,
+        Err(err) if status(&err) == Some(404) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let corrupt = |reason| Error::CorruptManifest {
+        key: key.to_string(),
+        reason,
+    };
+    format::check_len::<Progress>(obj.content_length().unwrap_or(0) as u64).map_err(corrupt)?;
+    let bytes = obj.body.collect().await.map_err(|err| Error::Whatever {
+        message: format!("failed to read object body: {err}"),
+        source: None,
+    })?;
+    let bytes = bytes.into_bytes();
+    cost::transferred(bytes.len() as u64, 0);
+    format::parse(&bytes).map(Some).map_err(corrupt)
+}
+
+/// Write the progress record of `db`. Returns `false` if `only_if_absent` is set and a record
+/// exists already.
+async fn write_progress(
+    inner: &Inner,
+    db: &ObjectKey,
+    progress: &Progress,
+    only_if_absent: bool,
+) -> Result<bool, Error> {
+    let bytes = progress.encode()?;
+    cost::transferred(0, bytes.len() as u64);
+    let mut put = inner
+        .s3
+        .put_object()
+        .bucket(&inner.bucket)
+        .key(KeyLayout::copy_progress(db))
+        .body(bytes.into());
+    if only_if_absent {
+        put = put.if_none_match("*");
+    }
+    let res = put.send().await;
+    inner.record_for(db, OpClass::Write, res.is_ok());
+    match res {
+        Ok(_) => Ok(true),
+        Err(err) if only_if_absent && status(&err) == Some(412) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn delete(inner: &Inner, key: &str) -> Result<(), Error> {
+    let res = inner
+        .s3
+        .delete_object()
+        .bucket(&inner.bucket)
+        .key(key)
+        .send()
+        .await;
+    inner.record_for(key, OpClass::Write, res.is_ok());
+    res?;
+    Ok(())
+}
+
+/// Copy `src` of `size` bytes to `dst` on the server.
+async fn copy_object(
+    inner: &Inner,
+    src: &str,
+    dst: &str,
+    size: u64,
+    opts: &CopyOptions,
+) -> Result<(), Error> {
+    let source = copy_source(&inner.bucket, src);
+    let Some(ranges) = copy_parts(size, opts) else {
+        let res = inner
+            .s3
+            .copy_object()
+            .bucket(&inner.bucket)
+            .key(dst)
+            .copy_source(source)
+            .send()
+            .await;
+        inner.record_for(dst, OpClass::Write, res.is_ok());
+        res?;
+        return Ok(());
+    };
+
+    let head = inner
+        .s3
+        .head_object()
+        .bucket(&inner.bucket)
+        .key(src)
+        .send()
+        .await;
+    inner.record_for(src, OpClass::Read, head.is_ok());
+    let upload = inner
+        .s3
+        .create_multipart_upload()
+        .bucket(&inner.bucket)
+        .key(dst)
+        .set_metadata(head?.metadata)
+        .send()
+        .await;
+    inner.record_for(dst, OpClass::Write, upload.is_ok());
+    let upload_id = upload?.upload_id.unwrap_or_default();
+    let parts = async {
+        let mut parts = vec![];
+        for (n, range) in ranges.into_iter().enumerate() {
+            let part = inner
+                .s3
+                .upload_part_copy()
+                .bucket(&inner.bucket)
+                .key(dst)
+                .upload_id(&upload_id)
+                .part_number(n as i32 + 1)
+                .copy_source(&source)
+                .copy_source_range(format!("bytes={}-{}", range.start, range.end - 1))
+                .send()
+                .await;
+            inner.record_for(dst, OpClass::Write, part.is_ok());
+            let etag = part?.copy_part_result.and_then(|result| result.e_tag);
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(n as i32 + 1)
+                    .set_e_tag(etag)
+                    .build(),
+            );
+        }
+        let res = inner
+            .s3
+            .complete_multipart_upload()
+            .bucket(&inner.bucket)
+            .key(dst)
+            .upload_id(&upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await;
+        inner.record_for(dst, OpClass::Write, res.is_ok());
+        res?;
+        Ok::<_, Error>(())
+    }
+    .await;
+    if parts.is_err() {
+        let _ = inner
+            .s3
+            .abort_multipart_upload()
+            .bucket(&inner.bucket)
+            .key(dst)
+            .upload_id(&upload_id)
+            .send()
+            .await;
+    }
+    parts
+}
+
+fn names(src: &str, dst: &str) -> Result<(ObjectKey, ObjectKey), Error> {
+    let (src, dst) = (KeyLayout::db(src)?, KeyLayout::db(dst)?);
+    if src == dst {
+        snafu::whatever!("cannot copy {src} onto itself");
+    }
+    Ok((src, dst))
+}
+
+/// Where a copy starts, after the checks before its first write.
+struct Start {
+    progress: Progress,
+    /// Whether the destination had a progress record, of an interrupted run of the copy.
+    resumed: bool,
+    /// The metadata objects read to check that nobody holds the lock.
+    busy_checks: u64,
+}
+
+/// Check that the copy of `src` to `dst` can run, and read where it starts: from the progress
+/// record of an interrupted run, or from scratch.
+async fn start(
+    inner: &Inner,
+    src: &ObjectKey,
+    dst: &ObjectKey,
+    opts: &CopyOptions,
+    rename: bool,
+) -> Result<Start, Error> {
+    let mut busy_checks = 0;
+    for db in [src, dst] {
+        if *db == inner.db_filename {
+            let record = inner.read_metadata_record().await?;
+            busy_checks += 1;
+            if let Some(diagnosis) = inner.diagnose(&record) {
+                return Err(Error::Busy {
+                    diagnosis: Box::new(diagnosis),
+                });
+            }
+        }
+    }
+
+    let progress = match read_progress(inner, dst).await? {
+        Some(progress) if progress.is(src, dst, rename) => {
+            return Ok(Start {
+                progress,
+                resumed: true,
+                busy_checks,
+            })
+        }
+        Some(progress) => {
+            return Err(Error::CopyInProgress {
+                db: dst.to_string(),
+                src: progress.src,
+                dst: progress.dst,
+            })
+        }
+        None => {
+            let Some(etag) = head_etag(inner, src).await? else {
+                return Err(Error::ObjectNotFound);
+            };
+            let exists = head_etag(inner, dst).await?.is_some();
+            if exists && opts.overwrite == Overwrite::Fail {
+                return Err(Error::DatabaseExists {
+                    db: dst.to_string(),
+                });
+            }
+            Progress {
+                src: src.to_string(),
+                dst: dst.to_string(),
+                rename,
+                etag,
+                cleared: !exists,
+                copied: vec![],
+                deleting: false,
+            }
+        }
+    };
+    Ok(Start {
+        progress,
+        resumed: false,
+        busy_checks,
+    })
+}
+
+/// What a copy costs from `start`, deleting the objects `cleared` of the destination first unless
+/// it did already, and copying the `objects` of the source it didn't yet, see [crate::cost].
+fn plan_cost(
+    start: &Start,
+    cleared: &[(String, u64)],
+    objects: &[(String, u64)],
+    opts: &CopyOptions,
+) -> Result<CostEstimate, Error> {
+    let len = |progress: &Progress| progress.encode().map(|bytes| bytes.len() as u64);
+    let pages = |keys: usize| (keys as u64).div_ceil(1000).max(1);
+    let mut progress = start.progress.clone();
+    let mut cost = CostEstimate {
+        get_requests: start.busy_checks + 1,
+        ..CostEstimate::default()
+    };
+    // the HEAD requests of both names, then their progress records, see [take_records]
+    cost.get_requests += 2;
+    cost.put_requests += 2;
+    cost.bytes_up += 2 * len(&progress)?;
+    if start.resumed {
+        // taken already, so read instead of the HEAD requests: the record of the destination
+        // once more, and the one of the source as it was taken
+        let taken = Progress {
+            copied: vec![],
+            deleting: false,
+            ..progress.clone()
+        };
+        cost.bytes_down += 2 * len(&progress)? + len(&taken)?;
+    }
+
+    if !progress.cleared {
+        cost.get_requests += pages(cleared.len());
+        cost.put_requests += cleared.len() as u64 + 1;
+        progress.cleared = true;
+        cost.bytes_up += len(&progress)?;
+    }
+    if !progress.deleting {
+        cost.get_requests += 1 + pages(objects.len());
+        for (key, size) in objects {
+            if progress.copied.contains(key) {
+                continue;
+            }
+            match copy_parts(*size, opts) {
+                Some(parts) => {
+                    cost.get_requests += 1;
+                    cost.put_requests += 2;
+                    cost.copy_requests += parts.len() as u64;
+                }
+                None => cost.copy_requests += 1,
+            }
+            progress.copied.push(key.clone());
+            cost.put_requests += 1;
+            cost.bytes_up += len(&progress)?;
+        }
+        if progress.rename {
+            cost.get_requests += objects.len() as u64;
+            progress.deleting = true;
+            cost.put_requests += 1;
+            cost.bytes_up += len(&progress)?;
+        }
+    }
+    if progress.deleting {
+        cost.get_requests += pages(objects.len());
+        cost.put_requests += objects.len() as u64;
+    }
+    cost.put_requests += 2;
+    Ok(cost)
+}
+
+/// Take the progress records of both names in key order, see the [module documentation](self).
+/// A record left by an interrupted run of the same operation counts as taken; on conflict, the
+/// records taken so far are released.
+async fn take_records(
+    inner: &Inner,
+    src: &ObjectKey,
+    dst: &ObjectKey,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let mut names = [src, dst];
+    names.sort_by_key(|db| db.as_str());
+    let mut taken = vec![];
+    for db in names {
+        if write_progress(inner, db, progress, true).await? {
+            taken.push(db);
+            continue;
+        }
+        match read_progress(inner, db).await? {
+            Some(held) if held.is(src, dst, progress.rename) => {}
+            held => {
+                for db in taken {
+                    delete(inner, KeyLayout::copy_progress(db).as_str()).await?;
+                }
+                let held = held.unwrap_or_else(|| progress.clone());
+                return Err(Error::CopyInProgress {
+                    db: db.to_string(),
+                    src: held.src,
+                    dst: held.dst,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+impl ThreeQLite {
+    /// Copy `src` to `dst` within the bucket, or resume an interrupted copy, see the
+    /// [module documentation](self).
+    pub async fn copy_database(
+        &self,
+        src: &str,
+        dst: &str,
+        opts: CopyOptions,
+    ) -> Result<CopyReport, Error> {
+        let target = format!("{src} -> {dst}");
+        self.operations
+            .run(OperationKind::Copy, target, |op| async move {
+                self.copy(src, dst, opts, false, &op).await
+            })
+            .await
+    }
+
+    /// [ThreeQLite::copy_database] as an operation of its own, see [crate::operation].
+    pub fn start_copy_database(
+        &self,
+        src: &str,
+        dst: &str,
+        opts: CopyOptions,
+    ) -> OperationHandle<CopyReport> {
+        self.start_copy(src, dst, opts, false)
+    }
+
+    /// Move `src` to `dst` within the bucket, or resume an interrupted rename, see the
+    /// [module documentation](self).
+    pub async fn rename_database(
+        &self,
+        src: &str,
+        dst: &str,
+        opts: CopyOptions,
+    ) -> Result<CopyReport, Error> {
+        let target = format!("{src} -> {dst}");
+        self.operations
+            .run(OperationKind::Rename, target, |op| async move {
+                self.copy(src, dst, opts, true, &op).await
+            })
+            .await
+    }
+
+    /// [ThreeQLite::rename_database] as an operation of its own, see [crate::operation].
+    pub fn start_rename_database(
+        &self,
+        src: &str,
+        dst: &str,
+        opts: CopyOptions,
+    ) -> OperationHandle<CopyReport> {
+        self.start_copy(src, dst, opts, true)
+    }
+
+    /// What [ThreeQLite::copy_database] costs, planned without writing anything, see
+    /// [crate::cost].
+    pub async fn estimate_copy_database(
+        &self,
+        src: &str,
+        dst: &str,
+        opts: CopyOptions,
+    ) -> Result<CostEstimate, Error> {
+        self.estimate_copy(src, dst, opts, false).await
+    }
+
+    /// What [ThreeQLite::rename_database] costs, planned without writing anything, see
+    /// [crate::cost].
+    pub async fn estimate_rename_database(
+        &self,
+        src: &str,
+        dst: &str,
+        opts: CopyOptions,
+    ) -> Result<CostEstimate, Error> {
+        self.estimate_copy(src, dst, opts, true).await
+    }
+
+    async fn estimate_copy(
+        &self,
+        src: &str,
+        dst: &str,
+        opts: CopyOptions,
+        rename: bool,
+    ) -> Result<CostEstimate, Error> {
+        let (src, dst) = names(src, dst)?;
+        let inner = self.inner.read().await;
+        inner.guard_for(&src, OpClass::Read)?;
+        let start = start(&inner, &src, &dst, &opts, rename).await?;
+        let cleared = match start.progress.cleared {
+            true => vec![],
+            false => objects(&inner, &dst).await?,
+        };
+        let objects = objects(&inner, &src).await?;
+        plan_cost(&start, &cleared, &objects, &opts)
+    }
+
+    fn start_copy(
+        &self,
+        src: &str,
+        dst: &str,
+        opts: CopyOptions,
+        rename: bool,
+    ) -> OperationHandle<CopyReport> {
+        let kind = match rename {
+            true => OperationKind::Rename,
+            false => OperationKind::Copy,
+        };
+        let (tq, src, dst) = (self.clone(), src.to_owned(), dst.to_owned());
+        self.operations
+            .spawn(kind, format!("{src} -> {dst}"), move |op| async move {
+                tq.copy(&src, &dst, opts, rename, &op).await
+            })
+    }
+
+    async fn copy(
+        &self,
+        src: &str,
+        dst: &str,
+        opts: CopyOptions,
+        rename: bool,
+        op: &Operation,
+    ) -> Result<CopyReport, Error> {
+        let (src, dst) = names(src, dst)?;
+        let inner = self.inner.read().await;
+        inner.guard_for(&dst, OpClass::Write)?;
+        let start = start(&inner, &src, &dst, &opts, rename).await?;
+        let mut progress = start.progress.clone();
+        let mut report = CopyReport::default();
+        op.checkpoint()?;
+        take_records(&inner, &src, &dst, &progress).await?;
+
+        let mut cleared = vec![];
+        if !progress.cleared {
+            op.phase("clearing", None);
+            cleared = objects(&inner, &dst).await?;
+            for (key, _) in &cleared {
+                delete(&inner, key).await?;
+            }
+            progress.cleared = true;
+            write_progress(&inner, &dst, &progress, false).await?;
+        }
+
+        if !progress.deleting {
+            match head_etag(&inner, &src).await? {
+                Some(etag) if etag == progress.etag => {}
+                Some(etag) => {
+                    // committed to since, the objects copied so far may be of another generation
+                    tracing::info!(target: "threeqlite::s3", %src, "source changed, copying from the start");
+                    progress.etag = etag;
+                    progress.copied.clear();
+                }
+                None => return Err(Error::ObjectNotFound),
+            }
+            report.resumed = progress.copied.len() as u64;
+            let objects = objects(&inner, &src).await?;
+            let estimate = plan_cost(&start, &cleared, &objects, &opts)?;
+            op.estimate(estimate);
+            report.estimate = Some(estimate);
+            op.phase("copying", Some(objects.len() as u64));
+            op.advance(report.resumed, 0);
+            for (key, size) in &objects {
+                if progress.copied.contains(key) {
+                    continue;
+                }
+                let target = format!("{dst}{}", &key[src.as_str().len()..]);
+                copy_object(&inner, key, &target, *size, &opts).await?;
+                progress.copied.push(key.clone());
+                write_progress(&inner, &dst, &progress, false).await?;
+                report.objects += 1;
+                report.bytes += size;
+                op.advance(1, *size);
+                op.checkpoint()?;
+            }
+
+            if rename {
+                op.phase("verifying", Some(objects.len() as u64));
+                for (key, size) in &objects {
+                    let target = ObjectKey::new(format!("{dst}{}", &key[src.as_str().len()..]))?;
+                    let head = inner
+                        .s3
+                        .head_object()
+                        .bucket(&inner.bucket)
+                        .key(&target)
+                        .send()
+                        .await;
+                    inner.record_for(&target, OpClass::Read, head.is_ok());
+                    let len = head?.content_length.unwrap_or(0) as u64;
+                    if len != *size {
+                        snafu::whatever!("copy {target} is {len} bytes long, but {key} is {size}");
+                    }
+                    op.advance(1, 0);
+                }
+                op.checkpoint()?;
+                progress.deleting = true;
+                write_progress(&inner, &dst, &progress, false).await?;
+            }
+        }
+
+        if progress.deleting {
+            // the database object first, so that the source is gone at once
+            let mut objects = objects(&inner, &src).await?;
+            objects.sort_by_key(|(key, _)| *key != src.as_str());
+            op.phase("deleting", Some(objects.len() as u64));
+            for (key, _) in objects {
+                delete(&inner, &key).await?;
+                report.deleted += 1;
+                op.advance(1, 0);
+            }
+        }
+        delete(&inner, KeyLayout::copy_progress(&src).as_str()).await?;
+        delete(&inner, KeyLayout::copy_progress(&dst).as_str()).await?;
+        report.cost = op.cost();
+        tracing::info!(
+            target: "threeqlite::s3",
+            %src,
+            %dst,
+            rename,
+            objects = report.objects,
+            resumed = report.resumed,
+            "copied database"
+        );
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlite_vfs::DatabaseHandle;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        handle::Handle,
+        mirror::BlockManifest,
+        mock::{self, MockS3},
+        operation::OperationState,
+    };
+
+    /// A database with 3 chunks, a journal and a block manifest, next to a database whose name
+    /// starts with the same characters.
+    fn setup() -> (MockS3, ThreeQLite, Vec<(String, Vec<u8>)>) {
+        let mock = MockS3::start();
+        let db = mock::database(4096, 8, 1);
+        let objects = vec![
+            ("test.db".to_owned(), db.clone()),
+            ("test.db.chunks/0000000000".to_owned(), vec![1; 5000]),
+            ("test.db.chunks/0000000001".to_owned(), vec![2; 5000]),
+            ("test.db.chunks/0000000002".to_owned(), vec![3; 100]),
+            ("test.db-journal".to_owned(), vec![4; 512]),
+            (
+                "test.db.blocks".to_owned(),
+                bincode::serialize(&BlockManifest::new(&db)).unwrap(),
+            ),
+        ];
+        for (key, data) in &objects {
+            mock.put(key, data.clone());
+        }
+        mock.put("test.db2", mock::database(4096, 2, 1));
+        // no lock holders
+        mock.put("metadata", vec![]);
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        (mock, tq, objects)
+    }
+
+    fn assert_copied(mock: &MockS3, objects: &[(String, Vec<u8>)], dst: &str) {
+        for (key, data) in objects {
+            let target = format!("{dst}{}", &key["test.db".len()..]);
+            assert_eq!(mock.get(&target).as_ref(), Some(data), "{target}");
+        }
+        assert_eq!(mock.get(&format!("{dst}.copy")), None);
+        assert_eq!(mock.get(&format!("{dst}2")), None);
+    }
+
+    #[tokio::test]
+    async fn test_copy_is_server_side() {
+        let (mock, tq, objects) = setup();
+        let report = tq
+            .copy_database("test.db", "copy.db", CopyOptions::default())
+            .await
+            .unwrap();
+        assert_eq!((report.objects, report.resumed, report.deleted), (6, 0, 0));
+        assert_copied(&mock, &objects, "copy.db");
+        assert_eq!(mock.get("test.db.copy"), None);
+
+        // no data flowed through the client: only copies wrote the objects of the destination,
+        // and nothing of the source but its progress record was read
+        let copies = mock.copies();
+        assert_eq!(copies.len(), 6);
+        for (method, key) in mock.requests() {
+            match method.as_str() {
+                "GET" => assert!(
+                    !key.starts_with("test.db") || key.ends_with(".copy"),
+                    "{key}"
+                ),
+                "PUT" if !key.ends_with(".copy") => {
+                    assert!(copies.iter().any(|(_, dst)| *dst == key), "{key}")
+                }
+                _ => {}
+            }
+        }
+        // the sidecars first, then the database object, then the manifest
+        let order: Vec<_> = copies.iter().map(|(src, _)| src.as_str()).collect();
+        assert_eq!(order[4..], ["test.db", "test.db.blocks"]);
+
+        // objects above the threshold are copied in parts
+        let opts = CopyOptions {
+            multipart_threshold: 10_000,
+            part_size: 8192,
+            ..CopyOptions::default()
+        };
+        tq.copy_database("test.db", "parts.db", opts).await.unwrap();
+        assert_copied(&mock, &objects, "parts.db");
+        let parts = mock.copies()[6..]
+            .iter()
+            .filter(|(src, _)| src == "test.db")
+            .count();
+        assert_eq!(parts, 4);
+    }
+
+    #[tokio::test]
+    async fn test_rename_resumes_after_crash() {
+        let (mock, tq, objects) = setup();
+        mock.fail_copies_after(Some(2));
+        let err = tq
+            .rename_database("test.db", "moved.db", CopyOptions::default())
+            .await
+            .unwrap_err();
+        assert!(!matches!(err, Error::CopyInProgress { .. }), "{err}");
+        // the source is intact, and the destination doesn't exist as a database yet
+        assert_eq!(mock.get("test.db").as_ref(), Some(&objects[0].1));
+        assert_eq!(mock.get("moved.db"), None);
+        assert!(mock.get("moved.db.copy").is_some());
+        // other operations on either name wait for this one
+        let err = tq
+            .copy_database("test.db", "other.db", CopyOptions::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::CopyInProgress { dst, .. } if dst == "moved.db"),
+            "{err}"
+        );
+        assert_eq!(mock.get("other.db.copy"), None);
+        let err = tq
+            .copy_database("test.db2", "moved.db", CopyOptions::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::CopyInProgress { .. }), "{err}");
+
+        mock.fail_copies_after(None);
+        let report = tq
+            .rename_database("test.db", "moved.db", CopyOptions::default())
+            .await
+            .unwrap();
+        assert_eq!((report.resumed, report.objects, report.deleted), (2, 4, 6));
+        assert_copied(&mock, &objects, "moved.db");
+        for (key, _) in &objects {
+            assert_eq!(mock.get(key), None, "{key}");
+        }
+        assert_eq!(mock.get("test.db.copy"), None);
+        assert!(mock.get("test.db2").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_and_resume() {
+        let (mock, tq, objects) = setup();
+        // cancelled before its first checkpoint, it takes no progress records
+        let op = tq.start_copy_database("test.db", "copy.db", CopyOptions::default());
+        assert!(op.cancel());
+        let err = op.await_done().await.unwrap_err();
+        assert!(matches!(err, Error::Cancelled { .. }), "{err}");
+        assert!(mock.copies().is_empty());
+        assert_eq!(mock.get("test.db.copy"), None);
+
+        // cancelled while copying, it leaves what a crash would
+        mock.delay("PUT", std::time::Duration::from_millis(20));
+        let op = tq.start_rename_database("test.db", "moved.db", CopyOptions::default());
+        while op.progress().phase != "copying" || op.progress().done < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        assert!(tq.cancel_operation(op.id()));
+        let mut handle = Handle::new(tq.clone(), KeyLayout::db("test.db").unwrap(), false);
+        let stats = handle.pragma("threeqlite_stats", None).await.unwrap();
+        assert!(
+            stats.unwrap().contains(" operation=(id=2 kind=rename target=test.db -> moved.db state=cancelling phase=copying done="),
+        );
+        let id = op.id();
+        assert!(matches!(
+            op.await_done().await,
+            Err(Error::Cancelled { .. })
+        ));
+        let copied = mock.copies().len() as u64;
+        assert!((2..=3).contains(&copied), "{copied}");
+        assert_eq!(mock.get("test.db").as_ref(), Some(&objects[0].1));
+        assert_eq!(mock.get("moved.db"), None);
+        assert!(mock.get("moved.db.copy").is_some());
+        let operations = handle.pragma("threeqlite_operations", None).await.unwrap();
+        let operations = operations.unwrap();
+        let states: Vec<_> = operations
+            .lines()
+            .map(|line| line.split(" target=").next().unwrap())
+            .collect();
+        assert_eq!(
+            states,
+            ["id=2 kind=rename", "id=1 kind=copy"],
+            "{operations}"
+        );
+        assert!(operations.contains("state=cancelled"), "{operations}");
+        let cancel = handle.pragma("threeqlite_cancel", Some("2")).await.unwrap();
+        assert_eq!(cancel.as_deref(), Some("not running"));
+
+        // resumed by calling it again
+        mock.delay("PUT", std::time::Duration::ZERO);
+        let report = tq
+            .rename_database("test.db", "moved.db", CopyOptions::default())
+            .await
+            .unwrap();
+        assert_eq!((report.resumed, report.objects), (copied, 6 - copied));
+        assert_copied(&mock, &objects, "moved.db");
+        assert_eq!(mock.get("test.db"), None);
+        let finished = tq.operations();
+        assert_eq!(finished[0].state, OperationState::Succeeded);
+        assert_eq!((finished[0].phase, finished[0].done), ("deleting", 6));
+        assert_eq!(tq.operation(id).unwrap().state, OperationState::Cancelled);
+    }
+
+    /// Run `copy` after estimating it with `estimate`, checking that the estimate sends no writes
+    /// and is within the tolerance of the cost of the run, which the mock accounts for.
+    async fn estimated<E, R>(mock: &MockS3, estimate: E, copy: R) -> CopyReport
+    where
+        E: std::future::Future<Output = Result<CostEstimate, Error>>,
+        R: std::future::Future<Output = Result<CopyReport, Error>>,
+    {
+        let before = mock.request_classes();
+        let estimate = estimate.await.unwrap();
+        let after = mock.request_classes();
+        assert_eq!(after[1..], before[1..], "the estimate wrote");
+        let report = Box::pin(copy).await.unwrap();
+        let accounted = mock.request_classes();
+        let metered = [
+            report.cost.get_requests,
+            report.cost.put_requests,
+            report.cost.copy_requests,
+        ];
+        assert_eq!(metered, [0, 1, 2].map(|i| accounted[i] - after[i]));
+        assert_eq!(report.estimate, Some(estimate));
+        assert!(
+            estimate.matches(&report.cost),
+            "estimated {estimate}, cost {}",
+            report.cost
+        );
+        report
+    }
+
+    #[tokio::test]
+    async fn test_estimates_match_costs() {
+        let (mock, tq, objects) = setup();
+        let opts = CopyOptions::default;
+        let report = estimated(
+            &mock,
+            tq.estimate_copy_database("test.db", "copy.db", opts()),
+            tq.copy_database("test.db", "copy.db", opts()),
+        )
+        .await;
+        assert_eq!(report.cost.copy_requests, 6);
+        assert!(report.cost.bytes_up > 0);
+
+        let parts = || CopyOptions {
+            multipart_threshold: 10_000,
+            part_size: 8192,
+            overwrite: Overwrite::Replace,
+        };
+        let report = estimated(
+            &mock,
+            tq.estimate_copy_database("test.db", "copy.db", parts()),
+            tq.copy_database("test.db", "copy.db", parts()),
+        )
+        .await;
+        assert_eq!(report.cost.copy_requests, 5 + 4);
+        assert_copied(&mock, &objects, "copy.db");
+
+        // resumed after a crash
+        mock.fail_copies_after(Some(2));
+        let err = tq.rename_database("test.db", "moved.db", opts()).await;
+        assert!(err.is_err());
+        mock.fail_copies_after(None);
+        let report = estimated(
+            &mock,
+            tq.estimate_rename_database("test.db", "moved.db", opts()),
+            tq.rename_database("test.db", "moved.db", opts()),
+        )
+        .await;
+        assert_eq!((report.resumed, report.deleted), (2, 6));
+        assert_eq!(mock.get("test.db"), None);
+        let progress = tq.operations()[0].clone();
+        assert_eq!(progress.estimate, report.estimate);
+        assert_eq!(progress.cost, report.cost);
+        assert!(progress.to_string().contains(" estimate=(get_requests="));
+    }
+
+    #[tokio::test]
+    async fn test_overwrite_policy() {
+        let (mock, tq, objects) = setup();
+        mock.put("copy.db", mock::database(4096, 1, 9));
+        mock.put("copy.db-wal", vec![7; 10]);
+        let err = tq
+            .copy_database("test.db", "copy.db", CopyOptions::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::DatabaseExists { db } if db == "copy.db"),
+            "{err}"
+        );
+        assert_eq!(mock.get("copy.db"), Some(mock::database(4096, 1, 9)));
+        assert!(mock.copies().is_empty());
+        assert_eq!(mock.get("test.db.copy"), None);
+
+        let opts = CopyOptions {
+            overwrite: Overwrite::Replace,
+            ..CopyOptions::default()
+        };
+        tq.copy_database("test.db", "copy.db", opts).await.unwrap();
+        assert_copied(&mock, &objects, "copy.db");
+        // sidecars of the replaced database are gone
+        assert_eq!(mock.get("copy.db-wal"), None);
+        assert!(matches!(
+            tq.copy_database("missing.db", "new.db", CopyOptions::default())
+                .await,
+            Err(Error::ObjectNotFound)
+        ));
+    }
+}
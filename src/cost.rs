@@ -0,0 +1,298 @@
+//! What maintenance operations cost, estimated before running them and metered while they run.
+//!
+//! Copying a large database or checking its integrity takes requests and bandwidth an operator may
+//! want to know about before running it during business hours. [ThreeQLite::estimate_copy_database],
+//! [ThreeQLite::estimate_rename_database] and [ThreeQLite::estimate_integrity_check] plan the
+//! operation with the code running it does, e.g. listing the objects to copy and splitting them
+//! into parts, or splitting the database into the ranges to fetch, and return a [CostEstimate]
+//! instead of running it. Planning only sends the requests running the operation sends before its
+//! first write, and nothing that writes. [CostEstimate::estimated_duration_at] turns an estimate
+//! into time at a latency per request, e.g. the one [ThreeQLite::observed_latency] measured.
+//! `--dry-run` on the `check`, `copy` and `rename` commands of the CLI prints the estimate.
+//!
+//! While an operation runs, its requests are metered as they are sent by the [Metering]
+//! interceptor of the client of the instance, and its transfers and local scratch space by the
+//! operation itself. Both the cost and the estimate the plan of the operation made are reported
+//! in its [ProgressSnapshot], and in the report it returns, so that the accuracy of estimates can
+//! be followed over time.
+//!
+//! An estimate assumes the operation runs uncontended: waiting for the lock, retrying a request or
+//! the source changing during a copy takes requests it doesn't count. Bytes count the data of the
+//! operation, i.e. the ranges and logs fetched and the progress records of a copy, but not the
+//! records of the lock protocol. Uncontended, an estimate is within [TOLERANCE] of each count,
+//! or [BYTES_SLACK] of each number of bytes, of what the operation costs, see
+//! [CostEstimate::matches].
+//!
+//! [ProgressSnapshot]: crate::operation::ProgressSnapshot
+
+use std::{
+    fmt,
+    future::Future,
+    ops::Add,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use aws_sdk_s3::config::{
+    interceptors::{BeforeTransmitInterceptorContextRef, FinalizerInterceptorContextRef},
+    ConfigBag, Intercept, RuntimeComponents,
+};
+use aws_sdk_s3::error::BoxError;
+use aws_smithy_types::config_bag::{Storable, StoreReplace};
+
+use crate::{stats::Stats, vfs::ThreeQLite};
+
+/// How far each count of an estimate may be off, relative to the actual cost.
+pub const TOLERANCE: f64 = 0.1;
+
+/// How far each number of bytes of an estimate may be off, for the records of the lock protocol
+/// it leaves out.
+pub const BYTES_SLACK: u64 = 4096;
+
+/// What registering as a reader and leaving again takes when uncontended, see
+/// [Inner::request_read_lock](crate::vfs::Inner::request_read_lock): each takes the legal hold on
+/// the lock object, checking, setting and reading it back, reads and writes the metadata object,
+/// and lifts the legal hold.
+pub const READ_LOCK: CostEstimate = CostEstimate {
+    get_requests: 6,
+    put_requests: 6,
+    copy_requests: 0,
+    bytes_down: 0,
+    bytes_up: 0,
+    peak_scratch_bytes: 0,
+};
+
+/// The requests and bytes an operation takes, estimated or metered, see the
+/// [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CostEstimate {
+    /// `GET`, `HEAD` and `ListObjectsV2` requests.
+    pub get_requests: u64,
+    /// `PUT`, `POST` and `DELETE` requests, other than copies.
+    pub put_requests: u64,
+    /// `CopyObject` and `UploadPartCopy` requests.
+    pub copy_requests: u64,
+    pub bytes_down: u64,
+    pub bytes_up: u64,
+    /// The most local disk space the operation takes at once, e.g. for the snapshot an integrity
+    /// check runs against.
+    pub peak_scratch_bytes: u64,
+}
+
+impl CostEstimate {
+    pub fn requests(&self) -> u64 {
+        self.get_requests + self.put_requests + self.copy_requests
+    }
+
+    /// How long the requests take one after the other at `latency` each. Maintenance operations
+    /// send their requests one at a time, so a latency measured over requests of similar sizes,
+    /// like [ThreeQLite::observed_latency], accounts for the transfers as well.
+    pub fn estimated_duration_at(&self, latency: Duration) -> Duration {
+        latency.saturating_mul(self.requests().min(u32::MAX as u64) as u32)
+    }
+
+    /// Whether the estimate is within [TOLERANCE] and [BYTES_SLACK] of the `actual` cost.
+    pub fn matches(&self, actual: &CostEstimate) -> bool {
+        let count = |estimate: u64, actual: u64| {
+            estimate.abs_diff(actual) as f64 <= actual as f64 * TOLERANCE
+        };
+        let bytes = |estimate: u64, actual: u64| {
+            estimate.abs_diff(actual) <= BYTES_SLACK || count(estimate, actual)
+        };
+        count(self.get_requests, actual.get_requests)
+            && count(self.put_requests, actual.put_requests)
+            && count(self.copy_requests, actual.copy_requests)
+            && bytes(self.bytes_down, actual.bytes_down)
+            && bytes(self.bytes_up, actual.bytes_up)
+            && bytes(self.peak_scratch_bytes, actual.peak_scratch_bytes)
+    }
+}
+
+impl Add for CostEstimate {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            get_requests: self.get_requests + other.get_requests,
+            put_requests: self.put_requests + other.put_requests,
+            copy_requests: self.copy_requests + other.copy_requests,
+            bytes_down: self.bytes_down + other.bytes_down,
+            bytes_up: self.bytes_up + other.bytes_up,
+            peak_scratch_bytes: self.peak_scratch_bytes.max(other.peak_scratch_bytes),
+        }
+    }
+}
+
+impl fmt::Display for CostEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "get_requests={} put_requests={} copy_requests={} bytes_down={} bytes_up={} \
+             peak_scratch_bytes={}",
+            self.get_requests,
+            self.put_requests,
+            self.copy_requests,
+            self.bytes_down,
+            self.bytes_up,
+            self.peak_scratch_bytes
+        )
+    }
+}
+
+tokio::task_local! {
+    /// The meter of the operation running the current task, see [scope].
+    static METER: Arc<Meter>;
+}
+
+/// What an operation has cost so far.
+#[derive(Debug, Default)]
+pub struct Meter {
+    get_requests: AtomicU64,
+    put_requests: AtomicU64,
+    copy_requests: AtomicU64,
+    bytes_down: AtomicU64,
+    bytes_up: AtomicU64,
+    peak_scratch_bytes: AtomicU64,
+}
+
+impl Meter {
+    pub fn cost(&self) -> CostEstimate {
+        CostEstimate {
+            get_requests: self.get_requests.load(Relaxed),
+            put_requests: self.put_requests.load(Relaxed),
+            copy_requests: self.copy_requests.load(Relaxed),
+            bytes_down: self.bytes_down.load(Relaxed),
+            bytes_up: self.bytes_up.load(Relaxed),
+            peak_scratch_bytes: self.peak_scratch_bytes.load(Relaxed),
+        }
+    }
+}
+
+/// Run `fut` charging the requests it sends and what it reports to `meter`. `fut` is boxed like
+/// the one of [crate::busy::with_handler].
+pub async fn scope<F: Future>(meter: Arc<Meter>, fut: F) -> F::Output {
+    METER.scope(meter, Box::pin(fut)).await
+}
+
+/// Count `down` bytes received and `up` bytes sent by the operation running the current task.
+pub fn transferred(down: u64, up: u64) {
+    let _ = METER.try_with(|meter| {
+        meter.bytes_down.fetch_add(down, Relaxed);
+        meter.bytes_up.fetch_add(up, Relaxed);
+    });
+}
+
+/// Note that the operation running the current task takes `bytes` of local disk space.
+pub fn scratch(bytes: u64) {
+    let _ = METER.try_with(|meter| Stats::max(&meter.peak_scratch_bytes, bytes));
+}
+
+/// Counts every attempt of a request to the [Meter] of the operation sending it, if any, and the
+/// time it took in [Stats::request_latency].
+#[derive(Debug)]
+pub struct Metering {
+    pub stats: Arc<Stats>,
+}
+
+/// When the current attempt of a request was sent.
+#[derive(Debug, Clone)]
+struct Sent(Instant);
+
+impl Storable for Sent {
+    type Storer = StoreReplace<Self>;
+}
+
+impl Intercept for Metering {
+    fn name(&self) -> &'static str {
+        "Metering"
+    }
+
+    fn read_before_transmit(
+        &self,
+        context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        cfg.interceptor_state().store_put(Sent(Instant::now()));
+        let request = context.request();
+        let _ = METER.try_with(|meter| {
+            let counter = match request.method() {
+                _ if request.headers().contains_key("x-amz-copy-source") => &meter.copy_requests,
+                "GET" | "HEAD" => &meter.get_requests,
+                _ => &meter.put_requests,
+            };
+            counter.fetch_add(1, Relaxed);
+        });
+        Ok(())
+    }
+
+    fn read_after_attempt(
+        &self,
+        _context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if let Some(Sent(sent)) = cfg.load::<Sent>() {
+            self.stats.request_latency.record(sent.elapsed());
+        }
+        Ok(())
+    }
+}
+
+impl ThreeQLite {
+    /// The median time the last requests of the instance took, `None` before the first one.
+    pub async fn observed_latency(&self) -> Option<Duration> {
+        let summary = self.inner.read().await.stats.request_latency.summary();
+        (summary.count > 0).then_some(summary.p50)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let actual = CostEstimate {
+            get_requests: 100,
+            put_requests: 10,
+            copy_requests: 0,
+            bytes_down: 1 << 20,
+            bytes_up: 500,
+            peak_scratch_bytes: 1 << 20,
+        };
+        assert!(actual.matches(&actual));
+        let close = CostEstimate {
+            get_requests: 91,
+            put_requests: 11,
+            bytes_down: (1 << 20) + 100_000,
+            bytes_up: 0,
+            ..actual
+        };
+        assert!(close.matches(&actual));
+        for off in [
+            CostEstimate {
+                get_requests: 89,
+                ..actual
+            },
+            CostEstimate {
+                copy_requests: 1,
+                ..actual
+            },
+            CostEstimate {
+                bytes_up: 500 + BYTES_SLACK + 1,
+                ..actual
+            },
+        ] {
+            assert!(!off.matches(&actual), "{off}");
+        }
+        assert_eq!(
+            actual.estimated_duration_at(Duration::from_millis(20)),
+            Duration::from_millis(2200)
+        );
+        assert_eq!((actual + READ_LOCK).requests(), 122);
+    }
+}
@@ -0,0 +1,555 @@
+//! Creating databases.
+//!
+//! SQLite creates a database as an empty file and writes page 1 with the first transaction that
+//! commits. The database object alone decides which [DatabaseState] a database is in:
+//!
+//! | State                        | Database object    | Metadata object                      |
+//! |------------------------------|--------------------|--------------------------------------|
+//! | [DatabaseState::Missing]     | none               | none, or left behind, see below      |
+//! | [DatabaseState::Empty]       | zero bytes         | none, or at generation 0             |
+//! | [DatabaseState::Initialized] | starts with page 1 | at the generation of the last commit |
+//!
+//! Opening a missing database with [OpenAccess::Create] or [OpenAccess::CreateNew] makes it empty
+//! with a single `PUT` of an empty object with `If-None-Match: *`, so a create that crashes left
+//! the database either missing or empty, and of two racing creates exactly one writes the object.
+//! [OpenAccess::CreateNew] fails with [Error::DatabaseExists] unless it wrote the object itself.
+//! Opening a missing database with [OpenAccess::Read] or [OpenAccess::Write] fails with
+//! `DbNotFound`, which SQLite reports as `SQLITE_CANTOPEN`, and [Vfs::exists] is only false for
+//! missing databases.
+//!
+//! Only a `404` makes a database or journal missing. When the store can't be reached, the state is
+//! unknown rather than missing: opening fails with the error of the store instead of creating a
+//! database over one that exists, and [Vfs::exists] fails instead of letting SQLite skip the
+//! recovery of a hot journal.
+//!
+//! The metadata object of an empty database is written with its first lock, like that of any
+//! database without one, and one left behind by a database deleted out-of-band is kept, so
+//! generations never go back. Neither is taken for a sign of a lost metadata object, see
+//! [Inner::check_metadata].
+//!
+//! An empty database reads as an empty file: its size is zero, and a read fills the buffer with
+//! zeros and fails with `UnexpectedEof`, which SQLite takes for a new database. The first commit
+//! writes page 1 on, contiguous from offset 0, so its pages are uploaded as a single extent like
+//! those of any commit, see [crate::flush], and its journal truncates the database back to empty
+//! should it be rolled back.
+//!
+//! [Vfs::exists]: sqlite_vfs::Vfs::exists
+//! [Inner::check_metadata]: crate::vfs::Inner::check_metadata
+
+use sqlite_vfs::OpenAccess;
+
+use crate::{
+    circuit::OpClass,
+    error::Error,
+    key::ObjectKey,
+    vfs::{status, Inner},
+};
+
+/// The state of a database, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DatabaseState {
+    /// Never created, or deleted.
+    Missing,
+    /// Created, and no transaction committed yet.
+    Empty,
+    /// At least one transaction committed.
+    Initialized,
+}
+
+impl std::fmt::Display for DatabaseState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Missing => "missing",
+            Self::Empty => "empty",
+            Self::Initialized => "initialized",
+        })
+    }
+}
+
+/// The state of the database `db`.
+pub async fn state(inner: &Inner, db: &ObjectKey) -> Result<DatabaseState, Error> {
+    inner.guard_for(db, OpClass::Read)?;
+    let head = inner
+        .s3
+        .head_object()
+        .bucket(&inner.bucket)
+        .key(db)
+        .send()
+        .await;
+    // a missing object is an answer, not a failure of the store
+    let missing = matches!(&head, Err(err) if status(err) == Some(404));
+    inner.record_for(db, OpClass::Read, head.is_ok() || missing);
+    match head {
+        Ok(head) => match head.content_length().unwrap_or(0) {
+            0 => Ok(DatabaseState::Empty),
+            _ => Ok(DatabaseState::Initialized),
+        },
+        Err(err) if status(&err) == Some(404) => Ok(DatabaseState::Missing),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Make the missing database `db` empty. Returns whether this wrote the object, rather than
+/// someone else before.
+async fn create(inner: &Inner, db: &ObjectKey) -> Result<bool, Error> {
+    inner.guard_for(db, OpClass::Write)?;
+    let put = inner
+        .s3
+        .put_object()
+        .bucket(&inner.bucket)
+        .key(db)
+        .if_none_match("*")
+        .body(Vec::new().into())
+        .send()
+        .await;
+    let exists = matches!(&put, Err(err) if status(err) == Some(412));
+    inner.record_for(db, OpClass::Write, put.is_ok() || exists);
+    // missing no longer, for the opens of a burst too, see [crate::burst]
+    inner.forget_state(db);
+    match put {
+        Ok(_) => {
+            tracing::info!(target: "threeqlite::s3", %db, "created database");
+            Ok(true)
+        }
+        Err(_) if exists => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The state `db` is in once opened with `access`, creating it if `access` asks for it. `None` if
+/// the database is missing and `access` doesn't create it.
+pub async fn open(
+    inner: &Inner,
+    db: &ObjectKey,
+    access: OpenAccess,
+) -> Result<Option<DatabaseState>, Error> {
+    let state = inner.shared_state(db).await?;
+    match (state, access) {
+        (DatabaseState::Missing, OpenAccess::Read | OpenAccess::Write) => Ok(None),
+        (DatabaseState::Missing, OpenAccess::Create) => {
+            create(inner, db).await?;
+            // a racing create may have committed already
+            self::state(inner, db).await.map(Some)
+        }
+        (DatabaseState::Missing, OpenAccess::CreateNew) => match create(inner, db).await? {
+            true => Ok(Some(DatabaseState::Empty)),
+            false => Err(Error::DatabaseExists { db: db.to_string() }),
+        },
+        (_, OpenAccess::CreateNew) => Err(Error::DatabaseExists { db: db.to_string() }),
+        (state, _) => Ok(Some(state)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{seq::SliceRandom, Rng};
+    use sqlite_vfs::{DatabaseHandle, OpenKind, OpenOptions, Vfs};
+
+    use super::*;
+    use crate::{
+        burst::BurstConfig,
+        circuit::{CircuitConfig, CircuitIsolation, CircuitState},
+        config::Config,
+        flush::PendingWrites,
+        handle::Handle,
+        key::KeyLayout,
+        mock,
+        vfs::ThreeQLite,
+    };
+
+    /// Opens seeing every change of the database at once, rather than sharing checks with the
+    /// opens before, see [crate::burst].
+    fn unshared() -> Config {
+        Config {
+            open_burst: BurstConfig {
+                ttl: std::time::Duration::ZERO,
+            },
+            ..Config::default()
+        }
+    }
+
+    async fn open_db(
+        tq: &ThreeQLite,
+        access: OpenAccess,
+    ) -> Result<Handle, sqlite_vfs::error::Error<Error>> {
+        Box::pin(tq.open("test.db", OpenOptions::new(OpenKind::MainDb, access))).await
+    }
+
+    async fn state_of(tq: &ThreeQLite) -> DatabaseState {
+        let inner = tq.inner.read().await;
+        state(&inner, &inner.db_filename).await.unwrap()
+    }
+
+    /// Commit the first transaction of the database, as SQLite writes it with the metadata
+    /// object the write lock left.
+    async fn commit_first(tq: &ThreeQLite) {
+        let mut pending = PendingWrites::default();
+        pending.write(0, &mock::database(4096, 2, 1));
+        let inner = tq.inner.read().await;
+        let report = inner
+            .page_flush(&inner.db_filename, &pending)
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(report.round_trips(), 1);
+        inner
+            .write_metadata_record(Default::default())
+            .await
+            .unwrap();
+    }
+
+    /// Check that a handle reads the database as expected in `state`.
+    async fn check_reads(tq: &ThreeQLite, state: DatabaseState) {
+        let mut page = [0xaa; 100];
+        match state {
+            DatabaseState::Missing => {
+                let err = open_db(tq, OpenAccess::Read).await.err().unwrap();
+                assert!(
+                    matches!(err, sqlite_vfs::error::Error::DbNotFound { .. }),
+                    "{err:?}"
+                );
+            }
+            DatabaseState::Empty => {
+                let mut handle = open_db(tq, OpenAccess::Read).await.unwrap();
+                let err = Box::pin(handle.read_exact_at(&mut page, 0))
+                    .await
+                    .unwrap_err();
+                assert!(matches!(err, sqlite_vfs::error::Error::UnexpectedEof));
+                assert_eq!(page, [0; 100]);
+            }
+            DatabaseState::Initialized => {
+                let mut handle = open_db(tq, OpenAccess::Read).await.unwrap();
+                handle.read_exact_at(&mut page, 0).await.unwrap();
+                assert_eq!(&page[..16], b"SQLite format 3\0");
+                // past the end of the database
+                let err = Box::pin(handle.read_exact_at(&mut page, 8192))
+                    .await
+                    .unwrap_err();
+                assert!(matches!(err, sqlite_vfs::error::Error::UnexpectedEof));
+                assert_eq!(page, [0; 100]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transitions() {
+        let mock = mock::MockS3::start();
+        let tq = ThreeQLite::with_client(unshared(), mock.client()).unwrap();
+        let metadata = tq.inner.read().await.metadata_filename.to_string();
+        // the write probe is cached before PUTs start failing
+        assert!(tq.writable("test.db").await.unwrap());
+
+        // missing
+        assert_eq!(state_of(&tq).await, DatabaseState::Missing);
+        assert!(!tq.exists("test.db").await.unwrap());
+        for access in [OpenAccess::Read, OpenAccess::Write] {
+            assert!(open_db(&tq, access).await.is_err());
+        }
+        check_reads(&tq, DatabaseState::Missing).await;
+
+        // a create crashing before its PUT leaves it missing
+        mock.reject("PUT", 503);
+        assert!(open_db(&tq, OpenAccess::Create).await.is_err());
+        mock.restore("PUT");
+        assert_eq!(state_of(&tq).await, DatabaseState::Missing);
+
+        // missing -> empty
+        open_db(&tq, OpenAccess::CreateNew).await.unwrap();
+        assert_eq!(state_of(&tq).await, DatabaseState::Empty);
+        assert_eq!(mock.get("test.db"), Some(vec![]));
+        assert!(tq.exists("test.db").await.unwrap());
+        check_reads(&tq, DatabaseState::Empty).await;
+        // a create crashing after its PUT, before any lock, is empty without metadata, which isn't
+        // mistaken for a lost metadata object
+        for access in [OpenAccess::Write, OpenAccess::Create] {
+            open_db(&tq, access).await.unwrap();
+        }
+        assert_eq!(mock.get(&metadata), None);
+        let err = open_db(&tq, OpenAccess::CreateNew).await.err().unwrap();
+        assert!(
+            matches!(
+                err,
+                sqlite_vfs::error::Error::External {
+                    cause: Error::DatabaseExists { .. }
+                }
+            ),
+            "{err:?}"
+        );
+
+        // a first commit failing leaves it empty
+        mock.reject("PUT", 503);
+        {
+            let mut pending = PendingWrites::default();
+            pending.write(0, &mock::database(4096, 2, 1));
+            let inner = tq.inner.read().await;
+            let flush = inner.page_flush(&inner.db_filename, &pending);
+            assert!(flush.run().await.is_err());
+        }
+        mock.restore("PUT");
+        assert_eq!(state_of(&tq).await, DatabaseState::Empty);
+
+        // empty -> initialized
+        commit_first(&tq).await;
+        assert_eq!(state_of(&tq).await, DatabaseState::Initialized);
+        check_reads(&tq, DatabaseState::Initialized).await;
+        for access in [OpenAccess::Read, OpenAccess::Write, OpenAccess::Create] {
+            open_db(&tq, access).await.unwrap();
+        }
+        assert!(open_db(&tq, OpenAccess::CreateNew).await.is_err());
+
+        // deleted, with its metadata object left behind
+        mock.delete("test.db");
+        assert_eq!(state_of(&tq).await, DatabaseState::Missing);
+        check_reads(&tq, DatabaseState::Missing).await;
+        open_db(&tq, OpenAccess::Create).await.unwrap();
+        assert_eq!(state_of(&tq).await, DatabaseState::Empty);
+        assert!(mock.get(&metadata).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_existence_unknown() {
+        let mock = mock::MockS3::start();
+        let data = mock::database(4096, 2, 1);
+        mock.put("test.db", data.clone());
+        // each failure is seen as such, rather than the circuit opening
+        let config = Config {
+            circuit: CircuitConfig {
+                failure_threshold: usize::MAX,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let metadata = tq.inner.read().await.metadata_filename.to_string();
+        assert!(tq.writable("test.db").await.unwrap());
+        let db_puts = || {
+            mock.requests()
+                .iter()
+                .filter(|(method, key)| method == "PUT" && key == "test.db")
+                .count()
+        };
+
+        // the metadata object is missing, and whether the database is can't be told: the metadata
+        // object isn't reconstructed
+        mock.lose_response("HEAD", "test.db");
+        assert!(open_db(&tq, OpenAccess::Write).await.is_err());
+        assert_eq!(mock.get(&metadata), None);
+        tq.inner
+            .read()
+            .await
+            .write_metadata_record(Default::default())
+            .await
+            .unwrap();
+
+        // nor is the database taken for missing, or created over
+        for access in [
+            OpenAccess::Read,
+            OpenAccess::Write,
+            OpenAccess::Create,
+            OpenAccess::CreateNew,
+        ] {
+            mock.lose_response("HEAD", "test.db");
+            let err = open_db(&tq, access).await.err().unwrap();
+            assert!(
+                !matches!(
+                    err,
+                    sqlite_vfs::error::Error::DbNotFound { .. }
+                        | sqlite_vfs::error::Error::External {
+                            cause: Error::DatabaseExists { .. }
+                        }
+                ),
+                "{access:?}: {err:?}"
+            );
+        }
+        assert_eq!(db_puts(), 0);
+        assert_eq!(mock.get("test.db"), Some(data));
+
+        // nor is a hot journal, which SQLite would then skip recovering
+        mock.put("test.db-journal", b"journal".to_vec());
+        for name in ["test.db", "test.db-journal"] {
+            mock.lose_response("HEAD", name);
+            assert!(tq.exists(name).await.is_err(), "{name}");
+            assert!(tq.exists(name).await.unwrap(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_racing_creates() {
+        let mock = mock::MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        assert!(tq.writable("test.db").await.unwrap());
+        let mut creates = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let tq = tq.clone();
+            creates.spawn(async move { open_db(&tq, OpenAccess::CreateNew).await.is_ok() });
+        }
+        let created = creates.join_all().await;
+        assert_eq!(created.iter().filter(|created| **created).count(), 1);
+        assert_eq!(state_of(&tq).await, DatabaseState::Empty);
+    }
+
+    /// Kinds the backend doesn't declare are refused rather than created, and temporary files are
+    /// never created in the object store.
+    #[tokio::test]
+    async fn test_undeclared_kinds() {
+        let mock = mock::MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let capabilities = tq.capabilities();
+        assert!(capabilities.supports_temp_files, "{capabilities}");
+        assert!(!capabilities.supports_wal && !capabilities.supports_shm);
+        let refused = Box::pin(tq.open(
+            "test.db",
+            OpenOptions::new(OpenKind::Wal, OpenAccess::Create),
+        ))
+        .await
+        .err();
+        assert!(
+            matches!(
+                refused,
+                Some(sqlite_vfs::error::Error::UnsupportedKind {
+                    kind: OpenKind::Wal
+                })
+            ),
+            "{refused:?}"
+        );
+        let name = tq.temporary_name().await;
+        for kind in [OpenKind::TempDb, OpenKind::TransientDb] {
+            let handle = Box::pin(tq.open(&name, OpenOptions::new(kind, OpenAccess::Create))).await;
+            assert!(handle.unwrap().temp.is_some(), "{kind:?}");
+        }
+        assert!(mock.requests().is_empty());
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    enum Op {
+        Open(OpenAccess),
+        /// Open with [OpenAccess::Create], crashing before its PUT.
+        CrashCreate,
+        CommitFirst,
+        /// Leave a metadata object behind.
+        Metadata,
+        Delete,
+    }
+
+    /// Random sequences of operations only ever lead to the state a model of the state machine
+    /// predicts.
+    #[tokio::test]
+    async fn test_random_sequences() {
+        use DatabaseState::*;
+
+        let ops = [
+            Op::Open(OpenAccess::Read),
+            Op::Open(OpenAccess::Write),
+            Op::Open(OpenAccess::Create),
+            Op::Open(OpenAccess::CreateNew),
+            Op::CrashCreate,
+            Op::CommitFirst,
+            Op::Metadata,
+            Op::Delete,
+        ];
+        let mut rng = rand::thread_rng();
+        for _ in 0..4 {
+            let mock = mock::MockS3::start();
+            let tq = ThreeQLite::with_client(unshared(), mock.client()).unwrap();
+            assert!(tq.writable("test.db").await.unwrap());
+            let mut model = Missing;
+            let mut history = vec![];
+            for _ in 0..rng.gen_range(8..24) {
+                let op = *ops.choose(&mut rng).unwrap();
+                history.push(op);
+                match op {
+                    Op::Open(access) => {
+                        let opened = open_db(&tq, access).await.is_ok();
+                        let expected = match (model, access) {
+                            (Missing, OpenAccess::Read | OpenAccess::Write) => false,
+                            (Missing, _) => {
+                                model = Empty;
+                                true
+                            }
+                            (_, OpenAccess::CreateNew) => false,
+                            _ => true,
+                        };
+                        assert_eq!(opened, expected, "{history:?}");
+                    }
+                    Op::CrashCreate => {
+                        mock.reject("PUT", 503);
+                        let opened = open_db(&tq, OpenAccess::Create).await.is_ok();
+                        mock.restore("PUT");
+                        assert_eq!(opened, model != Missing, "{history:?}");
+                    }
+                    Op::CommitFirst if model == Empty => {
+                        commit_first(&tq).await;
+                        model = Initialized;
+                    }
+                    // only SQLite's first commit writes an empty database
+                    Op::CommitFirst => {}
+                    Op::Metadata => {
+                        let inner = tq.inner.read().await;
+                        inner
+                            .write_metadata_record(Default::default())
+                            .await
+                            .unwrap();
+                    }
+                    Op::Delete => {
+                        mock.delete("test.db");
+                        model = Missing;
+                    }
+                }
+                assert_eq!(state_of(&tq).await, model, "{history:?}");
+                assert_eq!(
+                    tq.exists("test.db").await.unwrap(),
+                    model != Missing,
+                    "{history:?}"
+                );
+            }
+            check_reads(&tq, model).await;
+        }
+    }
+
+    /// A database failing every request opens its own circuits, not those of the database the
+    /// instance serves, see [crate::circuit].
+    #[tokio::test]
+    async fn test_failing_database_isolated() {
+        let mock = mock::MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        mock.reject_prefix("bad.db", 503);
+        let bad = KeyLayout::db("bad.db").unwrap();
+        for isolation in [CircuitIsolation::Database, CircuitIsolation::Bucket] {
+            let config = Config {
+                circuit: CircuitConfig {
+                    failure_threshold: 3,
+                    isolation,
+                    ..Default::default()
+                },
+                ..unshared()
+            };
+            let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+            let inner = tq.inner.read().await;
+            for _ in 0..3 {
+                assert!(state(&inner, &bad).await.is_err());
+            }
+            assert!(matches!(
+                state(&inner, &bad).await,
+                Err(Error::CircuitOpen { .. })
+            ));
+            let served = state(&inner, &inner.db_filename).await;
+            let unhealthy = inner.circuit.unhealthy();
+            match isolation {
+                CircuitIsolation::Database => {
+                    assert_eq!(served.unwrap(), DatabaseState::Initialized);
+                    assert_eq!(unhealthy.len(), 1);
+                    assert_eq!(unhealthy[0].to_string(), "threeqlite/bad.db.read=open");
+                    let stats = inner.stats();
+                    assert_eq!(stats.read_circuit, CircuitState::Closed);
+                    assert!(stats
+                        .to_string()
+                        .contains("unhealthy_circuits=threeqlite/bad.db.read=open"));
+                }
+                CircuitIsolation::Bucket => {
+                    assert!(matches!(served, Err(Error::CircuitOpen { .. })));
+                    assert_eq!(unhealthy[0].to_string(), "threeqlite.read=open");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,535 @@
+//! Credentials that are refreshed as soon as the object store rejects them.
+//!
+//! Instances live for days, while the credentials of the client (STS sessions, IRSA tokens) last
+//! an hour. The SDK caches credentials until the expiry their provider reports, so once the store
+//! rejects them earlier (a revoked session, a refresh that went wrong) every request fails with
+//! `ExpiredToken` until the cache expires, and retrying the request doesn't help.
+//! [ThreeQLite::with_credentials](crate::vfs::ThreeQLite::with_credentials), which
+//! [with_config](crate::vfs::ThreeQLite::with_config) uses for the credentials of the environment,
+//! thus loads them through a `RefreshingCredentials` of its own:
+//!
+//! - a request rejected for its credentials, or failing to load them, is classified as a
+//!   [CredentialFailure] rather than retried as it is;
+//! - the first rejection of a set of credentials makes the next request reload them from the
+//!   provider;
+//! - page reads and journals are sent once more with the reloaded credentials, and only fail with
+//!   [Error::CredentialsExpired](crate::error::Error::CredentialsExpired), reported to SQLite as
+//!   `SQLITE_AUTH`, if reloading fails as well. Other requests fail with it right away, and the
+//!   next one uses the reloaded credentials.
+//!
+//! [CredentialHealth] is part of the [stats](crate::stats::StatsSnapshot::credentials) and
+//! checked by [preflight](crate::vfs::ThreeQLite::preflight).
+
+use std::time::Duration;
+
+#[cfg(feature = "s3")]
+pub use sdk::*;
+
+/// Why the object store didn't accept the credentials of a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CredentialFailure {
+    /// The session of the credentials expired, `ExpiredToken`.
+    ExpiredToken,
+    /// The store doesn't know the credentials, e.g. `InvalidToken` or `InvalidAccessKeyId`.
+    InvalidToken,
+    /// The credentials provider failed to load credentials.
+    Provider,
+}
+
+impl CredentialFailure {
+    /// The failure an S3 error `code` stands for, if any.
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "ExpiredToken" | "ExpiredTokenException" | "TokenRefreshRequired" => {
+                Some(Self::ExpiredToken)
+            }
+            "InvalidToken" | "InvalidAccessKeyId" => Some(Self::InvalidToken),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CredentialFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CredentialFailure::ExpiredToken => "expired token",
+            CredentialFailure::InvalidToken => "invalid token",
+            CredentialFailure::Provider => "credentials provider failed",
+        })
+    }
+}
+
+/// The state of the credentials of an instance.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CredentialHealth {
+    /// Since the credentials in use were loaded.
+    pub age: Option<Duration>,
+    /// Until they are reloaded, if they expire.
+    pub next_refresh: Option<Duration>,
+    /// Loads that failed since the last one that succeeded.
+    pub consecutive_failures: u64,
+    /// Loads that succeeded.
+    pub refreshes: u64,
+    /// Loads forced by the store rejecting the credentials.
+    pub forced_refreshes: u64,
+}
+
+impl CredentialHealth {
+    pub fn healthy(&self) -> bool {
+        self.consecutive_failures == 0
+    }
+}
+
+impl std::fmt::Display for CredentialHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = |d: Option<Duration>| match d {
+            Some(d) => format!("{}s", d.as_secs()),
+            None => "none".to_owned(),
+        };
+        write!(
+            f,
+            "age={} next_refresh={} consecutive_failures={} refreshes={} forced_refreshes={}",
+            secs(self.age),
+            secs(self.next_refresh),
+            self.consecutive_failures,
+            self.refreshes,
+            self.forced_refreshes,
+        )
+    }
+}
+
+#[cfg(feature = "s3")]
+mod sdk {
+    use std::{
+        error::Error as _,
+        future::Future,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use aws_credential_types::provider::{error::CredentialsError, future};
+    use aws_sdk_s3::config::{
+        interceptors::{BeforeTransmitInterceptorContextRef, FinalizerInterceptorContextRef},
+        ConfigBag, Credentials, Intercept, ProvideCredentials, RuntimeComponents,
+        SharedCredentialsProvider,
+    };
+    use aws_sdk_s3::error::{BoxError, ProvideErrorMetadata, SdkError};
+    use aws_smithy_types::config_bag::{Storable, StoreReplace};
+
+    use super::*;
+    use crate::stats::Stats;
+
+    /// Credentials expiring within this long are reloaded before they are used.
+    const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+    /// The credential failure behind `err`, if any.
+    pub fn classify<E, R>(err: &SdkError<E, R>) -> Option<CredentialFailure>
+    where
+        E: ProvideErrorMetadata + std::error::Error + 'static,
+        R: std::fmt::Debug,
+    {
+        if let Some(failure) = err.code().and_then(CredentialFailure::from_code) {
+            return Some(failure);
+        }
+        let mut source = err.source();
+        while let Some(err) = source {
+            if err.is::<CredentialsError>() {
+                return Some(CredentialFailure::Provider);
+            }
+            source = err.source();
+        }
+        None
+    }
+
+    struct Loaded {
+        credentials: Credentials,
+        at: Instant,
+    }
+
+    /// Caches the credentials of `provider`, reloading them once the store rejects them, see the
+    /// [module documentation](self).
+    #[derive(Debug)]
+    pub struct RefreshingCredentials {
+        provider: SharedCredentialsProvider,
+        loaded: Mutex<Option<Loaded>>,
+        /// Rejected by the store since they were loaded.
+        stale: AtomicBool,
+        /// Held while loading, so that concurrent requests load once.
+        loading: tokio::sync::Mutex<()>,
+        consecutive_failures: AtomicU64,
+        refreshes: AtomicU64,
+        forced_refreshes: AtomicU64,
+    }
+
+    impl std::fmt::Debug for Loaded {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Loaded").field("at", &self.at).finish()
+        }
+    }
+
+    impl RefreshingCredentials {
+        pub fn new(provider: SharedCredentialsProvider) -> Self {
+            Self {
+                provider,
+                loaded: Mutex::new(None),
+                stale: AtomicBool::new(false),
+                loading: tokio::sync::Mutex::new(()),
+                consecutive_failures: AtomicU64::new(0),
+                refreshes: AtomicU64::new(0),
+                forced_refreshes: AtomicU64::new(0),
+            }
+        }
+
+        /// The loaded credentials, unless they were rejected or are about to expire.
+        fn fresh(&self) -> Option<Credentials> {
+            if self.stale.load(Relaxed) {
+                return None;
+            }
+            let loaded = self.loaded.lock().unwrap();
+            let credentials = &loaded.as_ref()?.credentials;
+            let expiring = credentials
+                .expiry()
+                .is_some_and(|expiry| expiry <= SystemTime::now() + EXPIRY_MARGIN);
+            (!expiring).then(|| credentials.clone())
+        }
+
+        async fn load(&self) -> Result<Credentials, CredentialsError> {
+            let forced = self.stale.load(Relaxed);
+            match self.provider.provide_credentials().await {
+                Ok(credentials) => {
+                    *self.loaded.lock().unwrap() = Some(Loaded {
+                        credentials: credentials.clone(),
+                        at: Instant::now(),
+                    });
+                    self.stale.store(false, Relaxed);
+                    self.consecutive_failures.store(0, Relaxed);
+                    Stats::incr(&self.refreshes);
+                    if forced {
+                        Stats::incr(&self.forced_refreshes);
+                    }
+                    tracing::debug!(target: "threeqlite::s3", forced, "loaded credentials");
+                    Ok(credentials)
+                }
+                Err(err) => {
+                    Stats::incr(&self.consecutive_failures);
+                    tracing::warn!(target: "threeqlite::s3", forced, %err, "failed to load credentials");
+                    Err(err)
+                }
+            }
+        }
+
+        async fn get(&self) -> Result<Credentials, CredentialsError> {
+            if let Some(credentials) = self.fresh() {
+                return Ok(credentials);
+            }
+            let _loading = self.loading.lock().await;
+            // loaded by a concurrent request meanwhile
+            if let Some(credentials) = self.fresh() {
+                return Ok(credentials);
+            }
+            self.load().await
+        }
+
+        /// Mark the credentials with `access_key_id` as rejected, unless they were replaced
+        /// already.
+        pub fn reject(&self, access_key_id: &str) {
+            let loaded = self.loaded.lock().unwrap();
+            if loaded
+                .as_ref()
+                .is_some_and(|loaded| loaded.credentials.access_key_id() == access_key_id)
+            {
+                self.stale.store(true, Relaxed);
+            }
+        }
+
+        /// Reload the credentials if they were rejected or never loaded. Returns whether usable
+        /// credentials are loaded.
+        pub async fn refresh(&self) -> bool {
+            self.get().await.is_ok()
+        }
+
+        pub fn health(&self) -> CredentialHealth {
+            let loaded = self.loaded.lock().unwrap();
+            CredentialHealth {
+                age: loaded.as_ref().map(|loaded| loaded.at.elapsed()),
+                next_refresh: loaded
+                    .as_ref()
+                    .and_then(|loaded| loaded.credentials.expiry())
+                    .map(|expiry| {
+                        (expiry - EXPIRY_MARGIN)
+                            .duration_since(SystemTime::now())
+                            .unwrap_or_default()
+                    }),
+                consecutive_failures: self.consecutive_failures.load(Relaxed),
+                refreshes: self.refreshes.load(Relaxed),
+                forced_refreshes: self.forced_refreshes.load(Relaxed),
+            }
+        }
+    }
+
+    impl ProvideCredentials for RefreshingCredentials {
+        fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+        where
+            Self: 'a,
+        {
+            future::ProvideCredentials::new(self.get())
+        }
+    }
+
+    /// Marks the credentials of requests the store rejected for them, see
+    /// [RefreshingCredentials::reject].
+    #[derive(Debug)]
+    pub struct CredentialWatch(pub Arc<RefreshingCredentials>);
+
+    /// The access key ID the last attempt of a request was signed with; the request is gone by
+    /// the time its response is read.
+    #[derive(Debug, Clone)]
+    struct SignedWith(String);
+
+    impl Storable for SignedWith {
+        type Storer = StoreReplace<Self>;
+    }
+
+    impl Intercept for CredentialWatch {
+        fn name(&self) -> &'static str {
+            "CredentialWatch"
+        }
+
+        fn read_after_signing(
+            &self,
+            context: &BeforeTransmitInterceptorContextRef<'_>,
+            _runtime_components: &RuntimeComponents,
+            cfg: &mut ConfigBag,
+        ) -> Result<(), BoxError> {
+            let access_key_id = context
+                .request()
+                .headers()
+                .get("authorization")
+                .and_then(|auth| auth.split_once("Credential=")?.1.split_once('/'))
+                .map(|(access_key_id, _)| access_key_id.to_owned());
+            if let Some(access_key_id) = access_key_id {
+                cfg.interceptor_state().store_put(SignedWith(access_key_id));
+            }
+            Ok(())
+        }
+
+        fn read_after_execution(
+            &self,
+            context: &FinalizerInterceptorContextRef<'_>,
+            _runtime_components: &RuntimeComponents,
+            cfg: &mut ConfigBag,
+        ) -> Result<(), BoxError> {
+            let (Some(response), Some(SignedWith(access_key_id))) =
+                (context.response(), cfg.load::<SignedWith>())
+            else {
+                return Ok(());
+            };
+            if response.status().is_success() {
+                return Ok(());
+            }
+            // the error code is only in the body, which is read by now
+            let rejected = response
+                .body()
+                .bytes()
+                .and_then(|body| std::str::from_utf8(body).ok())
+                .and_then(|body| body.split_once("<Code>")?.1.split_once("</Code>"))
+                .and_then(|(code, _)| CredentialFailure::from_code(code));
+            if let Some(failure) = rejected {
+                tracing::info!(target: "threeqlite::s3", %failure, "credentials rejected, reloading");
+                self.0.reject(access_key_id);
+            }
+            Ok(())
+        }
+    }
+
+    /// Send a request with `send`, and once more if its credentials were rejected and could be
+    /// reloaded.
+    pub async fn send<T, E, F, Fut>(
+        credentials: Option<&RefreshingCredentials>,
+        mut send: F,
+    ) -> Result<T, SdkError<E, aws_sdk_s3::config::http::HttpResponse>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, SdkError<E, aws_sdk_s3::config::http::HttpResponse>>>,
+        E: ProvideErrorMetadata + std::error::Error + 'static,
+    {
+        let result = send().await;
+        match (&result, credentials) {
+            (Err(err), Some(credentials)) if classify(err).is_some() => {
+                if credentials.refresh().await {
+                    return send().await;
+                }
+                result
+            }
+            _ => result,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "s3"))]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+        Arc,
+    };
+    use std::time::{Duration, Instant, SystemTime};
+
+    use aws_credential_types::provider::{error::CredentialsError, future};
+    use aws_sdk_s3::config::{Credentials, ProvideCredentials, SharedCredentialsProvider};
+    use sqlite_vfs::DatabaseHandle;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        error::Error,
+        fetch::{chunks, fetch},
+        handle::Handle,
+        key::ObjectKey,
+        mock::MockS3,
+        priority::IoClass,
+        vfs::ThreeQLite,
+    };
+
+    /// Hands out `key-1`, `key-2`, ... valid for an hour, until broken.
+    #[derive(Debug, Default)]
+    struct Rotating {
+        issued: AtomicU64,
+        broken: AtomicBool,
+    }
+
+    impl ProvideCredentials for Rotating {
+        fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+        where
+            Self: 'a,
+        {
+            if self.broken.load(Relaxed) {
+                return future::ProvideCredentials::ready(Err(CredentialsError::provider_error(
+                    "sts unavailable",
+                )));
+            }
+            let n = self.issued.fetch_add(1, Relaxed) + 1;
+            future::ProvideCredentials::ready(Ok(Credentials::new(
+                format!("key-{n}"),
+                "secret",
+                Some("session".to_owned()),
+                Some(SystemTime::now() + Duration::from_secs(3600)),
+                "rotating",
+            )))
+        }
+    }
+
+    fn setup() -> (MockS3, Arc<Rotating>, ThreeQLite, ObjectKey) {
+        let mock = MockS3::start();
+        mock.put("test.db", vec![7; 8192]);
+        let rotating = Arc::new(Rotating::default());
+        let tq = ThreeQLite::with_credentials(
+            Config::default(),
+            mock.client(),
+            SharedCredentialsProvider::new(rotating.clone() as Arc<dyn ProvideCredentials>),
+        )
+        .unwrap();
+        (mock, rotating, tq, ObjectKey::new("test.db").unwrap())
+    }
+
+    #[test]
+    fn test_from_code() {
+        assert_eq!(
+            CredentialFailure::from_code("ExpiredToken"),
+            Some(CredentialFailure::ExpiredToken)
+        );
+        assert_eq!(
+            CredentialFailure::from_code("InvalidAccessKeyId"),
+            Some(CredentialFailure::InvalidToken)
+        );
+        assert_eq!(CredentialFailure::from_code("SlowDown"), None);
+        assert_eq!(CredentialFailure::from_code("AccessDenied"), None);
+    }
+
+    #[tokio::test]
+    async fn test_expired_token_is_reloaded_once() {
+        let (mock, rotating, tq, key) = setup();
+        let ranges = chunks(0, 8192, 8192);
+        let inner = tq.inner.read().await;
+        fetch(&inner, &key, None, &ranges, IoClass::Critical)
+            .await
+            .unwrap();
+        assert_eq!(rotating.issued.load(Relaxed), 1);
+
+        mock.expire_credentials("key-1");
+        let (chunks, report) = fetch(&inner, &key, None, &ranges, IoClass::Critical)
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), [7; 8192]);
+        // sent once more with the reloaded credentials, not retried by the fetch
+        assert_eq!(report.retries, [0]);
+        assert_eq!(rotating.issued.load(Relaxed), 2);
+        assert_eq!(mock.requests().len(), 3);
+
+        for _ in 0..3 {
+            fetch(&inner, &key, None, &ranges, IoClass::Critical)
+                .await
+                .unwrap();
+        }
+        assert_eq!(rotating.issued.load(Relaxed), 2);
+        assert_eq!(mock.requests().len(), 6);
+        drop(inner);
+
+        let health = tq.credential_health().await.unwrap();
+        assert!(health.healthy());
+        assert_eq!((health.refreshes, health.forced_refreshes), (2, 1));
+        assert!(health.next_refresh.unwrap() > Duration::from_secs(3000));
+        let stats = tq.stats().await;
+        assert_eq!(stats.credentials.as_ref().unwrap().forced_refreshes, 1);
+        assert!(stats.to_string().contains(" credentials=(age="));
+    }
+
+    #[tokio::test]
+    async fn test_broken_provider_fails_promptly() {
+        let (mock, rotating, tq, key) = setup();
+        let ranges = chunks(0, 8192, 8192);
+        let inner = tq.inner.read().await;
+        fetch(&inner, &key, None, &ranges, IoClass::Critical)
+            .await
+            .unwrap();
+
+        rotating.broken.store(true, Relaxed);
+        mock.expire_credentials("key-1");
+        let start = Instant::now();
+        let err = fetch(&inner, &key, None, &ranges, IoClass::Critical)
+            .await
+            .unwrap_err();
+        assert!(start.elapsed() < inner.fetch_config.deadline / 2);
+        assert!(
+            matches!(
+                err,
+                Error::CredentialsExpired {
+                    failure: CredentialFailure::ExpiredToken,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        drop(inner);
+        let health = tq.credential_health().await.unwrap();
+        assert!(!health.healthy());
+        assert_eq!(health.consecutive_failures, 1);
+
+        // reported to SQLite as SQLITE_AUTH
+        let mut handle = Handle::new(tq.clone(), key.clone(), true);
+        let mut page = vec![0; 4096];
+        let err = handle.read_exact_at(&mut page, 0).await.unwrap_err();
+        assert!(
+            matches!(err, sqlite_vfs::error::Error::Auth { .. }),
+            "{err:?}"
+        );
+
+        // and recovers once the provider does
+        rotating.broken.store(false, Relaxed);
+        handle.read_exact_at(&mut page, 0).await.unwrap();
+        assert_eq!(page, [7; 4096]);
+        assert!(tq.credential_health().await.unwrap().healthy());
+    }
+}
@@ -0,0 +1,147 @@
+//! Reading through a metadata object that is temporarily unreadable.
+//!
+//! A reader registers in the metadata object before reading, so failing to read it fails the
+//! read. With a [DegradedReadPolicy], such a read is served from the offline mirror of the
+//! database instead (see [crate::mirror]), provided the mirror holds a complete copy that was
+//! validated recently enough: no longer than [DegradedReadPolicy::max_age] ago and no more than
+//! [DegradedReadPolicy::max_generation_lag] generations behind the newest generation this
+//! instance has seen.
+//!
+//! The rest of the transaction is served from the mirror as well, pinned to the generation it
+//! started at, so that it reads a single snapshot. Writes fail with [Error::DegradedReadOnly]
+//! until it ends. The budget is measured from the last validation of the mirror rather than from
+//! the first degraded read, so consecutive degraded transactions don't extend it: once it is
+//! spent, reads fail with the original error again.
+//!
+//! [Error::DegradedReadOnly]: crate::error::Error::DegradedReadOnly
+
+use std::time::Duration;
+
+use crate::mirror::OfflineStatus;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DegradedReadPolicy {
+    /// Serve reads from a mirror synced at most this long ago.
+    pub max_age: Duration,
+    /// Serve reads from a mirror at most this many generations behind the newest one seen.
+    /// Unbounded if `None`.
+    pub max_generation_lag: Option<u64>,
+}
+
+impl Default for DegradedReadPolicy {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(30),
+            max_generation_lag: Some(0),
+        }
+    }
+}
+
+/// A transaction served from the mirror.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DegradedRead {
+    /// The generation the transaction reads.
+    pub generation: u64,
+    /// Time since the mirror was validated, when the transaction started.
+    pub age: Duration,
+}
+
+impl std::fmt::Display for DegradedRead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "generation={} age={:?}", self.generation, self.age)
+    }
+}
+
+/// Whether `policy` allows serving a transaction from a mirror in state `mirror`, `newest` being
+/// the newest generation seen. Returns why not otherwise.
+pub fn admit(
+    policy: &DegradedReadPolicy,
+    mirror: &OfflineStatus,
+    newest: u64,
+) -> Result<DegradedRead, String> {
+    if !mirror.complete {
+        return Err("the mirror is incomplete".to_owned());
+    }
+    let (Some(generation), Some(age)) = (mirror.generation, mirror.staleness) else {
+        return Err("the generation of the mirror is unknown".to_owned());
+    };
+    if age > policy.max_age {
+        return Err(format!(
+            "the mirror was validated {age:?} ago, more than {:?}",
+            policy.max_age
+        ));
+    }
+    let lag = newest.saturating_sub(generation);
+    if let Some(max) = policy.max_generation_lag.filter(|max| lag > *max) {
+        return Err(format!(
+            "the mirror is {lag} generations behind, more than {max}"
+        ));
+    }
+    Ok(DegradedRead { generation, age })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admit() {
+        let policy = DegradedReadPolicy {
+            max_age: Duration::from_secs(10),
+            max_generation_lag: Some(1),
+        };
+        let mirror = OfflineStatus {
+            generation: Some(7),
+            staleness: Some(Duration::from_secs(4)),
+            complete: true,
+            offline: false,
+        };
+        assert_eq!(
+            admit(&policy, &mirror, 8),
+            Ok(DegradedRead {
+                generation: 7,
+                age: Duration::from_secs(4)
+            })
+        );
+
+        for (mirror, newest, reason) in [
+            (
+                OfflineStatus {
+                    complete: false,
+                    ..mirror.clone()
+                },
+                7,
+                "the mirror is incomplete",
+            ),
+            (
+                OfflineStatus {
+                    generation: None,
+                    ..mirror.clone()
+                },
+                7,
+                "the generation of the mirror is unknown",
+            ),
+            (
+                OfflineStatus {
+                    staleness: Some(Duration::from_secs(11)),
+                    ..mirror.clone()
+                },
+                7,
+                "the mirror was validated 11s ago, more than 10s",
+            ),
+            (
+                mirror.clone(),
+                9,
+                "the mirror is 2 generations behind, more than 1",
+            ),
+        ] {
+            assert_eq!(admit(&policy, &mirror, newest), Err(reason.to_owned()));
+        }
+
+        let unbounded = DegradedReadPolicy {
+            max_generation_lag: None,
+            ..policy
+        };
+        assert!(admit(&unbounded, &mirror, 100).is_ok());
+    }
+}
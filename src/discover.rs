@@ -0,0 +1,647 @@
+//! Discovery of the databases stored in a bucket.
+//!
+//! [ThreeQLite::list_databases] pages through the keys under a prefix and reads the first bytes of
+//! every object, keeping those [format::describe] identifies as SQLite databases. Anything else,
+//! such as objects of other applications interleaved with the databases, is skipped. The sidecars
+//! of a database (its block manifest, journal and WAL) are counted towards its physical size
+//! without being read. Keys are decoded to the names the databases are opened with, see
+//! [crate::key]; keys that aren't the encoding of a name are skipped.
+//!
+//! Generation and lock state live in the metadata object, which an instance only knows for its
+//! own database, so they are reported for that one only. SQLite's change counter is reported for
+//! every database.
+//!
+//! [ThreeQLite::quick_header] reads the same first bytes of a single database, outside of any
+//! transaction, for the header fields a fleet inventory asks for, such as `user_version`.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+};
+
+use aws_sdk_s3::primitives::DateTime;
+use snafu::whatever;
+use tokio::task::JoinSet;
+
+use crate::{
+    circuit::OpClass,
+    error::Error,
+    format::{self, DatabaseHeader, ObjectKind},
+    key::{KeyLayout, ObjectKey},
+    mirror::BLOCK_SIZE,
+    priority::IoClass,
+    protocol,
+    vfs::{status, Inner, Metadata, MetadataRecord, ThreeQLite},
+};
+
+#[derive(Clone, Debug)]
+pub struct ListOptions {
+    /// Stop after finding this many databases.
+    pub limit: usize,
+    /// Stop listing after this many keys.
+    pub max_keys: usize,
+    /// Keys per `ListObjectsV2` request.
+    pub page_size: i32,
+    /// Database headers read at once.
+    pub concurrency: usize,
+    /// Fill in [DatabaseInfo::header], at no extra request.
+    pub headers: bool,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        Self {
+            limit: 100,
+            max_keys: 10_000,
+            page_size: 1000,
+            concurrency: 8,
+            headers: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// The database object alone.
+    Object,
+    /// The database object with a block manifest, see [crate::mirror].
+    Blocks,
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Layout::Object => "object",
+            Layout::Blocks => "blocks",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockStatus {
+    Idle,
+    Reading {
+        readers: usize,
+    },
+    /// Readers are draining for a waiting writer.
+    WriteRequested {
+        readers: usize,
+    },
+    Writing,
+}
+
+impl LockStatus {
+    /// The state of the lock in `record` at `now`, in milliseconds since the Unix epoch.
+    pub fn of(record: &MetadataRecord, now: u64) -> Self {
+        let live = protocol::reader_decision(record, now) == protocol::ReaderDecision::Defer;
+        match &record.metadata {
+            Metadata::Writer(_) => LockStatus::Writing,
+            Metadata::Reader(reader) if live => LockStatus::WriteRequested {
+                readers: reader.readers.len(),
+            },
+            Metadata::Reader(reader) if !reader.readers.is_empty() => LockStatus::Reading {
+                readers: reader.readers.len(),
+            },
+            Metadata::None | Metadata::Reader(_) => LockStatus::Idle,
+        }
+    }
+}
+
+impl std::fmt::Display for LockStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockStatus::Idle => write!(f, "idle"),
+            LockStatus::Reading { readers } => write!(f, "reading({readers})"),
+            LockStatus::WriteRequested { readers } => write!(f, "write-requested({readers})"),
+            LockStatus::Writing => write!(f, "writing"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseInfo {
+    /// The name to open the database with, decoded from its key, see [crate::key].
+    pub name: String,
+    pub key: ObjectKey,
+    pub layout: Layout,
+    pub page_size: u32,
+    /// The logical size according to the database header, `None` if its page count is stale.
+    pub size: Option<u64>,
+    /// The number of blocks, for [Layout::Blocks].
+    pub blocks: Option<u64>,
+    pub change_counter: u32,
+    /// Only known for the database of this instance.
+    pub generation: Option<u64>,
+    /// Only known for the database of this instance.
+    pub lock: Option<LockStatus>,
+    /// When the database object was last written, i.e. the time of the last commit.
+    pub last_modified: Option<DateTime>,
+    /// The database object and its sidecars.
+    pub physical_bytes: u64,
+    /// With [ListOptions::headers], see [ThreeQLite::quick_header].
+    pub header: Option<QuickHeader>,
+}
+
+/// The fields of a database header that identify its schema and application, and its generation,
+/// see [ThreeQLite::quick_header].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuickHeader {
+    /// `PRAGMA user_version`.
+    pub user_version: u32,
+    /// `PRAGMA application_id`.
+    pub application_id: u32,
+    /// `PRAGMA schema_version`, incremented by SQLite whenever the schema changes.
+    pub schema_cookie: u32,
+    pub page_size: u32,
+    /// `None` if the header's page count is stale, e.g. after a legacy writer.
+    pub page_count: Option<u32>,
+    /// Only known for the database of this instance.
+    pub generation: Option<u64>,
+}
+
+impl QuickHeader {
+    fn new(header: &DatabaseHeader, generation: Option<u64>) -> Self {
+        Self {
+            user_version: header.user_version,
+            application_id: header.application_id,
+            schema_cookie: header.schema_cookie,
+            page_size: header.page_size,
+            page_count: header.page_count,
+            generation,
+        }
+    }
+}
+
+/// The kind of the object `db` judging by its first bytes, `None` if it is missing or these
+/// credentials may not read it.
+async fn read_header(
+    inner: &Inner,
+    db: &ObjectKey,
+    class: IoClass,
+) -> Result<Option<ObjectKind>, Error> {
+    inner.guard_for(db, OpClass::Read)?;
+    let permit = inner.permit_for(db, class).await;
+    let obj = inner
+        .s3
+        .get_object()
+        .bucket(&inner.bucket)
+        .key(db)
+        .range(format!("bytes=0-{}", format::DESCRIBE_LEN - 1))
+        .send()
+        .await;
+    inner.record_for(db, OpClass::Read, obj.is_ok());
+    let obj = match obj {
+        Ok(obj) => obj,
+        // e.g. an object of another application these credentials may not read
+        Err(err) if status(&err).is_some_and(|status| status < 500) => {
+            tracing::debug!(target: "threeqlite::s3", key = %db, %err, "skipping unreadable object");
+            return Ok(None);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let bytes = obj.body.collect().await.map_err(|err| Error::Whatever {
+        message: format!("failed to read object body: {err}"),
+        source: None,
+    })?;
+    drop(permit);
+    Ok(Some(format::describe(&bytes.into_bytes())))
+}
+
+impl ThreeQLite {
+    /// The header fields of `db` that identify its schema and application, as a probe across many
+    /// databases would read `PRAGMA user_version` and `application_id`, without a transaction.
+    ///
+    /// This is a dirty read: a single ranged GET of the header of the database object, plus a
+    /// HEAD of the metadata object for the generation if `db` is the database of this instance.
+    /// It takes no lock and writes nothing, so it may see the header of a commit in progress,
+    /// and the generation of the commit before or after it. Fails with [Error::ObjectNotFound] if
+    /// `db` is missing or unreadable.
+    pub async fn quick_header(&self, db: &str) -> Result<QuickHeader, Error> {
+        let key = KeyLayout::db(db)?;
+        let inner = self.inner.read().await;
+        let header = match read_header(&inner, &key, IoClass::Critical).await? {
+            Some(ObjectKind::Database(header)) => header,
+            Some(_) => whatever!("{db} is not a SQLite database"),
+            None => return Err(Error::ObjectNotFound),
+        };
+        let generation = match key == inner.db_filename {
+            true => inner.read_generation().await?,
+            false => None,
+        };
+        Ok(QuickHeader::new(&header, generation))
+    }
+
+    /// List the databases under `prefix_filter`, or in the whole bucket.
+    pub async fn list_databases(
+        &self,
+        prefix_filter: Option<&str>,
+        opts: ListOptions,
+    ) -> Result<Vec<DatabaseInfo>, Error> {
+        let inner = self.inner.read().await;
+        inner.guard(OpClass::Read)?;
+
+        let mut objects = BTreeMap::new();
+        let mut token = None;
+        loop {
+            let page = inner
+                .list(
+                    inner
+                        .s3
+                        .list_objects_v2()
+                        .set_prefix(prefix_filter.map(str::to_owned))
+                        .max_keys(opts.page_size)
+                        .set_continuation_token(token.take()),
+                )
+                .await?;
+            for obj in page.contents() {
+                if let Some(key) = obj.key() {
+                    objects.insert(
+                        key.to_owned(),
+                        (obj.size().unwrap_or(0) as u64, obj.last_modified().copied()),
+                    );
+                }
+            }
+            if objects.len() >= opts.max_keys {
+                tracing::warn!(
+                    target: "threeqlite::s3",
+                    prefix = prefix_filter,
+                    max_keys = opts.max_keys,
+                    "stopped listing databases at the key limit"
+                );
+                break;
+            }
+            match page.next_continuation_token() {
+                Some(next) if page.is_truncated() == Some(true) => token = Some(next.to_owned()),
+                _ => break,
+            }
+        }
+
+        let own = [
+            inner.metadata_lock.lock_file().as_str(),
+            inner.metadata_filename.as_str(),
+        ];
+        // the sidecars of any key, so that they are never read even if listed before it
+        let mut sidecars = HashSet::new();
+        for key in objects.keys() {
+            let Ok(db) = ObjectKey::new(key.as_str()) else {
+                continue;
+            };
+            for sidecar in [
+                KeyLayout::manifest(&db),
+                KeyLayout::journal(&db),
+                KeyLayout::wal(&db),
+            ] {
+                if objects.contains_key(sidecar.as_str()) {
+                    sidecars.insert(String::from(sidecar));
+                }
+            }
+        }
+        let mut candidates = vec![];
+        for (key, &(len, _)) in &objects {
+            if len < format::DESCRIBE_LEN as u64
+                || own.contains(&key.as_str())
+                || sidecars.contains(key.as_str())
+            {
+                continue;
+            }
+            // foreign objects may use any key, including ones this crate doesn't encode
+            let Some(name) = KeyLayout::decode_db_name(key) else {
+                continue;
+            };
+            // decoding checked that `key` is the encoding of `name`
+            candidates.push((name.clone(), KeyLayout::db(&name)?));
+        }
+
+        // the headers of up to `concurrency` candidates at once, in key order
+        let shared = Arc::new(inner.clone());
+        let mut databases = vec![];
+        for batch in candidates.chunks(opts.concurrency.max(1)) {
+            if databases.len() >= opts.limit {
+                break;
+            }
+            let mut reads = JoinSet::new();
+            for (i, (_, db)) in batch.iter().enumerate() {
+                let (inner, db) = (shared.clone(), db.clone());
+                reads.spawn(async move { (i, read_header(&inner, &db, IoClass::Bulk).await) });
+            }
+            let mut headers = reads.join_all().await;
+            headers.sort_by_key(|(i, _)| *i);
+
+            for ((name, db), (_, header)) in batch.iter().zip(headers) {
+                if databases.len() >= opts.limit {
+                    break;
+                }
+                let Some(ObjectKind::Database(header)) = header? else {
+                    continue;
+                };
+                let (len, last_modified) = objects[db.as_str()];
+
+                let manifest = KeyLayout::manifest(db);
+                let mut physical_bytes = len;
+                for sidecar in [manifest.clone(), KeyLayout::journal(db), KeyLayout::wal(db)] {
+                    if let Some((len, _)) = objects.get(sidecar.as_str()) {
+                        physical_bytes += len;
+                    }
+                }
+                let layout = match sidecars.contains(manifest.as_str()) {
+                    true => Layout::Blocks,
+                    false => Layout::Object,
+                };
+
+                let (generation, lock) = if *db == inner.db_filename {
+                    match inner.read_metadata_record().await {
+                        Ok(record) => {
+                            let lock = LockStatus::of(&record, protocol::now_ms());
+                            (record.stamp.map(|stamp| stamp.generation), Some(lock))
+                        }
+                        Err(err) => {
+                            tracing::debug!(target: "threeqlite::lock_protocol", %err, "reading metadata failed");
+                            (None, None)
+                        }
+                    }
+                } else {
+                    (None, None)
+                };
+
+                databases.push(DatabaseInfo {
+                    layout,
+                    page_size: header.page_size,
+                    size: header.size(),
+                    blocks: (layout == Layout::Blocks).then(|| len.div_ceil(BLOCK_SIZE)),
+                    change_counter: header.change_counter,
+                    generation,
+                    lock,
+                    last_modified,
+                    physical_bytes,
+                    header: opts.headers.then(|| QuickHeader::new(&header, generation)),
+                    name: name.clone(),
+                    key: db.clone(),
+                });
+            }
+        }
+        Ok(databases)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        heal::Stamp,
+        mirror::BlockManifest,
+        mock::{self, MockS3},
+        vfs::{MetadataRecord, ReaderMetadata},
+    };
+
+    #[tokio::test]
+    async fn test_list_databases() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+
+        // the database of this instance, with a block manifest and active readers
+        let own = mock::database(4096, 20, 9);
+        mock.put("test.db", own.clone());
+        let key = KeyLayout::db("test.db").unwrap();
+        let manifest = BlockManifest::new(&own);
+        {
+            let inner = tq.inner.read().await;
+            inner.publish_blocks(&key, &manifest).await.unwrap();
+            inner
+                .write_metadata_record(MetadataRecord {
+                    metadata: Metadata::Reader(ReaderMetadata {
+                        readers: vec![vec![1], vec![2]],
+                        write_request: None,
+                    }),
+                    stamp: Some(Stamp::new(12)),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        // a plain database with a leftover journal
+        mock.put("tenants/a/main.db", mock::database(1024, 4, 3));
+        mock.put("tenants/a/main.db-journal", vec![0; 512]);
+        // a database with a stale page count, next to its WAL
+        let mut legacy = mock::database(512, 2, 5);
+        legacy[92..96].copy_from_slice(&4u32.to_be_bytes());
+        mock.put("tenants/b/legacy.sqlite", legacy);
+        mock.put("tenants/b/legacy.sqlite-wal", vec![0; 100]);
+
+        // junk
+        mock.put("notes.txt", "hello");
+        mock.put("tenants/a/.write-probe", "");
+        mock.put("tenants/a/report.csv", vec![b'x'; 300]);
+        mock.put("tenants//weird", vec![0; 200]);
+        mock.put("ghost.db.blocks", vec![0; 200]);
+        mock.put("lockfile", "");
+
+        let opts = ListOptions {
+            page_size: 3,
+            ..ListOptions::default()
+        };
+        let dbs = tq.list_databases(None, opts.clone()).await.unwrap();
+        let names: Vec<_> = dbs.iter().map(|db| db.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["tenants/a/main.db", "tenants/b/legacy.sqlite", "test.db"]
+        );
+
+        let main = &dbs[0];
+        assert_eq!(main.layout, Layout::Object);
+        assert_eq!(main.page_size, 1024);
+        assert_eq!(main.size, Some(4096));
+        assert_eq!(main.change_counter, 3);
+        assert_eq!(main.physical_bytes, 4096 + 512);
+        assert_eq!((main.generation, main.lock), (None, None));
+        assert!(main.last_modified.is_some());
+
+        let legacy = &dbs[1];
+        assert_eq!(legacy.size, None);
+        assert_eq!(legacy.physical_bytes, 1024 + 100);
+
+        let own = &dbs[2];
+        assert_eq!(own.layout, Layout::Blocks);
+        assert_eq!(own.size, Some(20 * 4096));
+        assert_eq!(own.blocks, Some(2));
+        assert_eq!(own.generation, Some(12));
+        assert_eq!(own.lock, Some(LockStatus::Reading { readers: 2 }));
+        let manifest_len = mock.get("test.db.blocks").unwrap().len() as u64;
+        assert_eq!(own.physical_bytes, 20 * 4096 + manifest_len);
+
+        // sidecars of a database are never read
+        assert!(!mock
+            .requests()
+            .iter()
+            .any(|(method, key)| method == "GET" && key.ends_with("-journal")));
+
+        let dbs = tq.list_databases(Some("tenants/a/"), opts).await.unwrap();
+        assert_eq!(dbs.len(), 1);
+        assert_eq!(dbs[0].name.as_str(), "tenants/a/main.db");
+
+        let dbs = tq
+            .list_databases(
+                None,
+                ListOptions {
+                    limit: 1,
+                    ..ListOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(dbs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_encoded_names() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let names = ["tenants/acme corp/日報.db", "tenants/t#1/100%.db"];
+        for name in names {
+            let key = KeyLayout::db(name).unwrap();
+            mock.put(key.as_str(), mock::database(512, 2, 1));
+        }
+        // written verbatim by another tool, unreachable under the encoding
+        mock.put("tenants/raw name.db", mock::database(512, 2, 1));
+
+        let dbs = tq
+            .list_databases(Some("tenants/"), ListOptions::default())
+            .await
+            .unwrap();
+        let listed: Vec<_> = dbs.iter().map(|db| db.name.as_str()).collect();
+        assert_eq!(listed, names);
+        assert_eq!(
+            dbs[0].key.as_str(),
+            "tenants/acme%20corp/%E6%97%A5%E5%A0%B1.db"
+        );
+        for db in &dbs {
+            assert_eq!(KeyLayout::db(&db.name).unwrap(), db.key);
+        }
+    }
+
+    /// A database with the given `user_version`, `application_id` and schema cookie.
+    fn fixture(change_counter: u32, user_version: u32, application_id: u32) -> Vec<u8> {
+        let mut db = mock::database(4096, 3, change_counter);
+        db[40..44].copy_from_slice(&7u32.to_be_bytes());
+        db[60..64].copy_from_slice(&user_version.to_be_bytes());
+        db[68..72].copy_from_slice(&application_id.to_be_bytes());
+        db
+    }
+
+    #[tokio::test]
+    async fn test_quick_header() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        mock.put("test.db", fixture(9, 42, 0x5AFE));
+        mock.put("tenants/a/main.db", fixture(3, 1, 0));
+        mock.put("notes.txt", vec![b'x'; 200]);
+        tq.inner
+            .read()
+            .await
+            .write_metadata_record(MetadataRecord {
+                stamp: Some(Stamp::new(12)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let before = mock.requests().len();
+        let header = tq.quick_header("test.db").await.unwrap();
+        assert_eq!(
+            header,
+            QuickHeader {
+                user_version: 42,
+                application_id: 0x5AFE,
+                schema_cookie: 7,
+                page_size: 4096,
+                page_count: Some(3),
+                generation: Some(12),
+            }
+        );
+        // one GET of the header, and the generation from the metadata object's user metadata
+        assert_eq!(
+            mock.requests()[before..],
+            [
+                ("GET".to_owned(), "test.db".to_owned()),
+                ("HEAD".to_owned(), "metadata".to_owned()),
+            ]
+        );
+        assert_eq!(mock.ranges().last().unwrap(), "bytes=0-99");
+
+        // other databases have no generation the instance knows of, and take a single GET
+        let before = mock.requests().len();
+        let header = tq.quick_header("tenants/a/main.db").await.unwrap();
+        assert_eq!((header.user_version, header.generation), (1, None));
+        assert_eq!(
+            mock.requests()[before..],
+            [("GET".to_owned(), "tenants/a/main.db".to_owned())]
+        );
+
+        assert!(matches!(
+            tq.quick_header("missing.db").await,
+            Err(Error::ObjectNotFound)
+        ));
+        let err = tq.quick_header("notes.txt").await.unwrap_err();
+        assert!(err.to_string().contains("not a SQLite database"), "{err}");
+
+        // listed with the same fields, without another request
+        let opts = ListOptions {
+            headers: true,
+            concurrency: 2,
+            ..ListOptions::default()
+        };
+        let dbs = tq.list_databases(None, opts).await.unwrap();
+        let headers: Vec<_> = dbs
+            .iter()
+            .map(|db| {
+                (
+                    db.name.as_str(),
+                    db.header.map(|h| (h.user_version, h.generation)),
+                )
+            })
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                ("tenants/a/main.db", Some((1, None))),
+                ("test.db", Some((42, Some(12)))),
+            ]
+        );
+        let dbs = tq
+            .list_databases(None, ListOptions::default())
+            .await
+            .unwrap();
+        assert!(dbs.iter().all(|db| db.header.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_quick_header_during_commits() {
+        let mock = Arc::new(MockS3::start());
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        mock.put("test.db", fixture(1, 1, 0x5AFE));
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = {
+            let (mock, done) = (mock.clone(), done.clone());
+            std::thread::spawn(move || {
+                let mut generation = 1;
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    generation += 1;
+                    mock.put("test.db", fixture(generation, generation, 0x5AFE));
+                }
+                generation
+            })
+        };
+        let mut seen = vec![];
+        for _ in 0..50 {
+            let header = tq.quick_header("test.db").await.unwrap();
+            assert_eq!(header.application_id, 0x5AFE);
+            seen.push(header.user_version);
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        let last = writer.join().unwrap();
+        // each probe sees a whole header of some commit, never going back
+        assert!(seen.windows(2).all(|pair| pair[0] <= pair[1]), "{seen:?}");
+        assert!(seen.iter().all(|version| (1..=last).contains(version)));
+    }
+}
@@ -0,0 +1,1025 @@
+//! Recovery drills.
+//!
+//! [ThreeQLite::drill] rehearses recovering a database from failures before they happen for real.
+//! Each [Scenario] runs against a copy of its own, made with [ThreeQLite::copy_database] under a
+//! scratch prefix and served by scratch instances. The drill plays the part of SQLite: it writes
+//! journals and pages through the [Vfs] and [DatabaseHandle] interfaces of the instance, crashes
+//! where the scenario says by dropping the instance, and recovers with a fresh instance the way a
+//! restarted process would, see [crate::recovery]. The outcome is checked against the one documented for the failure:
+//! the copy matches a checksum taken before the scenario (or of the committed transaction), or
+//! the database is quarantined, see [crate::heal]. The scratch prefix is deleted afterwards,
+//! whatever the outcomes, and also when the drill is cancelled between scenarios, see
+//! [crate::operation].
+//!
+//! Failures that can't be caused from the outside, such as a page upload failing mid-commit, are
+//! injected through [Faults]. Only the scratch instances of a drill carry them, and they only fire
+//! for keys under the scratch prefix, so other instances never fail because of them.
+//!
+//! A scratch copy is private to its drill, so the drill takes and releases its write lock by
+//! writing the metadata object directly, without the metadata lock serializing instances. A
+//! writer that crashes keeps the write lock, see [crate::busy]; the drill hands it to the instance
+//! recovering, as an operator releasing it would.
+
+use std::{
+    io::Write,
+    ops::Range,
+    sync::{atomic::Ordering::Relaxed, Mutex},
+    time::{Duration, Instant},
+};
+
+use sqlite_vfs::{DatabaseHandle, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+use crate::{
+    busy::{Holder, LocalProcess},
+    cache::CacheUse,
+    circuit::OpClass,
+    config::Config,
+    copy::{CopyOptions, CopyReport},
+    error::Error,
+    flush::CommitStep,
+    format::{self, ObjectKind},
+    handle::{storage_cause, Handle},
+    heal::MetadataHealth,
+    journal,
+    key::{KeyLayout, ObjectKey},
+    operation::{Operation, OperationHandle, OperationKind},
+    protocol::{self, WriterDecision},
+    recovery,
+    vfs::{Metadata, MetadataRecord, ThreeQLite},
+};
+
+/// The length of the chunk [Scenario::CorruptChunk] appends, a sector.
+const SECTOR: usize = 512;
+
+/// A failure a drill rehearses, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scenario {
+    /// A writer crashes once its commit reached `step`. Recovery rolls the hot journal back, unless
+    /// the journal was deleted and the transaction thus committed.
+    InterruptedCommit(CommitStep),
+    /// A writer whose write request lapsed, e.g. in a long pause, resumes after another writer took
+    /// over the request. It waits rather than writing, see [crate::protocol].
+    StaleWriter,
+    /// A transaction across databases crashes after deleting its super-journal, leaving the journal
+    /// hot. Recovery discards the journal rather than rolling it back, see [crate::journal].
+    HotJournal,
+    /// The metadata object is deleted, e.g. by a lifecycle rule. A new instance reconstructs it,
+    /// and an instance that saw a newer generation quarantines the database.
+    MetadataDeleted,
+    /// A chunk of bytes is appended to the database object out-of-band. The next registered read
+    /// fails and quarantines the database.
+    CorruptChunk,
+}
+
+impl Scenario {
+    /// Every scenario, in the order the CLI runs them.
+    pub const ALL: [Scenario; 9] = [
+        Scenario::InterruptedCommit(CommitStep::JournalWritten),
+        Scenario::InterruptedCommit(CommitStep::JournalSynced),
+        Scenario::InterruptedCommit(CommitStep::UploadStarted),
+        Scenario::InterruptedCommit(CommitStep::PagesDurable),
+        Scenario::InterruptedCommit(CommitStep::JournalDeleted),
+        Scenario::StaleWriter,
+        Scenario::HotJournal,
+        Scenario::MetadataDeleted,
+        Scenario::CorruptChunk,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scenario::InterruptedCommit(CommitStep::JournalWritten) => "commit-journal-written",
+            Scenario::InterruptedCommit(CommitStep::JournalSynced) => "commit-journal-synced",
+            Scenario::InterruptedCommit(CommitStep::UploadStarted) => "commit-upload-started",
+            Scenario::InterruptedCommit(CommitStep::UploadFinished) => "commit-upload-finished",
+            Scenario::InterruptedCommit(CommitStep::PagesDurable) => "commit-pages-durable",
+            Scenario::InterruptedCommit(CommitStep::JournalDeleted) => "commit-journal-deleted",
+            Scenario::StaleWriter => "stale-writer",
+            Scenario::HotJournal => "hot-journal",
+            Scenario::MetadataDeleted => "metadata-deleted",
+            Scenario::CorruptChunk => "corrupt-chunk",
+        }
+    }
+
+    /// The scenario called `name`, see [Scenario::name].
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|scenario| scenario.name() == name)
+    }
+
+    /// The outcome documented for the failure.
+    pub fn expected(&self) -> Outcome {
+        match self {
+            Scenario::MetadataDeleted | Scenario::CorruptChunk => Outcome::Quarantined,
+            _ => Outcome::Recovered,
+        }
+    }
+}
+
+impl std::fmt::Display for Scenario {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How a [Scenario] ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The copy matches the checksum expected after recovery.
+    Recovered,
+    /// The database was quarantined, and writes are refused.
+    Quarantined,
+    /// Recovery didn't end as documented.
+    Failed { reason: String },
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Recovered => write!(f, "recovered"),
+            Outcome::Quarantined => write!(f, "quarantined"),
+            Outcome::Failed { reason } => write!(f, "failed: {reason}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DrillOptions {
+    /// The copies are made under `<prefix>/<uuid>/`. Must be a key that needs no encoding, see
+    /// [KeyLayout::db].
+    pub prefix: String,
+}
+
+impl Default for DrillOptions {
+    fn default() -> Self {
+        Self {
+            prefix: "threeqlite-drill".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScenarioReport {
+    pub scenario: Scenario,
+    pub outcome: Outcome,
+    /// Including the copy.
+    pub duration: Duration,
+    /// Requests of the scratch instances. The copy is in `copied`.
+    pub requests: u64,
+    pub copied: CopyReport,
+}
+
+impl ScenarioReport {
+    pub fn passed(&self) -> bool {
+        self.outcome == self.scenario.expected()
+    }
+}
+
+impl std::fmt::Display for ScenarioReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} ({}) in {:?}, {} requests, {} objects copied",
+            self.scenario,
+            self.outcome,
+            match self.passed() {
+                true => "as documented",
+                false => "NOT as documented",
+            },
+            self.duration,
+            self.requests,
+            self.copied.objects
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DrillReport {
+    /// The scratch prefix, deleted by now.
+    pub scratch: String,
+    pub scenarios: Vec<ScenarioReport>,
+    /// Objects deleted under the scratch prefix.
+    pub deleted: u64,
+    pub duration: Duration,
+}
+
+impl DrillReport {
+    /// Whether every scenario ended as documented.
+    pub fn is_ok(&self) -> bool {
+        self.scenarios.iter().all(ScenarioReport::passed)
+    }
+}
+
+/// Failures injected into the scratch instances of a drill, see the
+/// [module documentation](self). Only [ThreeQLite::drill] creates them.
+#[derive(Debug)]
+pub struct Faults {
+    /// Faults only fire for keys starting with this.
+    prefix: String,
+    /// Fail the page upload covering this offset of the database object.
+    fail_upload_at: Mutex<Option<u64>>,
+}
+
+impl Faults {
+    fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_owned(),
+            fail_upload_at: Mutex::new(None),
+        }
+    }
+
+    /// Called before uploading `range` of the database object `key`.
+    pub(crate) fn upload(&self, key: &ObjectKey, range: Range<u64>) -> Result<(), Error> {
+        if !key.as_str().starts_with(&self.prefix) {
+            return Ok(());
+        }
+        match *self.fail_upload_at.lock().unwrap() {
+            Some(offset) if range.contains(&offset) => {
+                snafu::whatever!("drill: injected failure uploading {range:?} of {key}")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn failed(reason: impl Into<String>) -> Outcome {
+    Outcome::Failed {
+        reason: reason.into(),
+    }
+}
+
+/// Feeds a database object through MD5 with `overlay` written over it.
+struct Checksum<'a> {
+    md5: md5::Context,
+    offset: u64,
+    overlay: &'a [(u64, Vec<u8>)],
+}
+
+impl Write for Checksum<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut chunk = buf.to_vec();
+        let end = self.offset + buf.len() as u64;
+        for (at, data) in self.overlay {
+            let start = (*at).max(self.offset);
+            let stop = (at + data.len() as u64).min(end);
+            if start < stop {
+                chunk[(start - self.offset) as usize..(stop - self.offset) as usize]
+                    .copy_from_slice(&data[(start - at) as usize..(stop - at) as usize]);
+            }
+        }
+        self.md5.consume(&chunk);
+        self.offset = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The MD5 of `db` as `tq` reads it, with `overlay` written over it.
+async fn checksum(tq: &ThreeQLite, db: &str, overlay: &[(u64, Vec<u8>)]) -> Result<String, Error> {
+    let mut out = Checksum {
+        md5: md5::Context::new(),
+        offset: 0,
+        overlay,
+    };
+    tq.snapshot_to(db, &mut out).await?;
+    Ok(format!("{:x}", out.md5.compute()))
+}
+
+/// The transaction of a scenario: it inverts the bytes of the second and the last page.
+struct Transaction {
+    page_size: u64,
+    /// The size of the database in pages before the transaction.
+    pages: u64,
+    /// The pages as written by the transaction, by offset.
+    writes: Vec<(u64, Vec<u8>)>,
+    /// The pages as they were, by page number.
+    originals: Vec<(u32, Vec<u8>)>,
+}
+
+impl Transaction {
+    async fn plan(tq: &ThreeQLite, db: &str) -> Result<Self, Error> {
+        let inner = tq.inner.read().await;
+        let header = inner
+            .read_at(0, format::DESCRIBE_LEN, None, CacheUse::Bypass)
+            .await?;
+        let ObjectKind::Database(header) = format::describe(&header) else {
+            snafu::whatever!("{db} is not a SQLite database");
+        };
+        inner.guard(OpClass::Read)?;
+        let head = inner
+            .s3
+            .head_object()
+            .bucket(&inner.bucket)
+            .key(&inner.db_filename)
+            .send()
+            .await;
+        inner.record(OpClass::Read, head.is_ok());
+        let len = head.map_err(Error::from_aws)?.content_length().unwrap_or(0) as u64;
+        let page_size = header.page_size as u64;
+        let pages = len / page_size;
+        if pages < 2 {
+            snafu::whatever!("{db} has fewer than the two pages a drill rewrites");
+        }
+
+        let mut tx = Self {
+            page_size,
+            pages,
+            writes: vec![],
+            originals: vec![],
+        };
+        let mut numbers = vec![2, pages];
+        numbers.dedup();
+        for pgno in numbers {
+            let offset = (pgno - 1) * page_size;
+            let page = inner
+                .read_at(offset as usize, page_size as usize, None, CacheUse::Bypass)
+                .await?;
+            tx.writes.push((offset, page.iter().map(|b| !b).collect()));
+            tx.originals.push((pgno as u32, page));
+        }
+        Ok(tx)
+    }
+
+    /// The rollback journal of the transaction, see [recovery::journal_of].
+    fn journal(&self) -> Vec<u8> {
+        recovery::journal_of(self.page_size, self.pages, &self.originals)
+    }
+}
+
+/// Open `name` through the [Vfs] interface of `tq`. Boxed, like the other calls into the
+/// interfaces below, since their futures are large.
+async fn open(
+    tq: &ThreeQLite,
+    name: &str,
+    kind: OpenKind,
+    access: OpenAccess,
+) -> Result<Handle, sqlite_vfs::error::Error<Error>> {
+    Box::pin(tq.open(name, OpenOptions::new(kind, access))).await
+}
+
+async fn sync(handle: &mut Handle) -> Result<(), Error> {
+    Box::pin(handle.sync(false)).await.map_err(storage_cause)
+}
+
+/// The copy a scenario runs against, and the instances it created so far.
+struct Scratch {
+    db: String,
+    key: ObjectKey,
+    config: Config,
+    s3: aws_sdk_s3::Client,
+    faults: std::sync::Arc<Faults>,
+    instances: Vec<ThreeQLite>,
+}
+
+impl Scratch {
+    /// A new instance serving the copy, as a process (re)started would.
+    async fn instance(&mut self) -> Result<ThreeQLite, Error> {
+        let tq = ThreeQLite::with_client(self.config.clone(), self.s3.clone())?;
+        {
+            let mut inner = tq.inner.write().await;
+            inner.faults = Some(self.faults.clone());
+            if self.instances.is_empty() {
+                // the copy has no metadata object until it is first opened
+                inner.check_metadata(&self.key).await?;
+            }
+        }
+        self.instances.push(tq.clone());
+        Ok(tq)
+    }
+
+    async fn requests(&self) -> u64 {
+        let mut requests = 0;
+        for tq in &self.instances {
+            requests += tq.inner.read().await.stats.requests.load(Relaxed);
+        }
+        requests
+    }
+}
+
+/// Take the write lock of the copy `tq` serves, returning its ID, see the
+/// [module documentation](self).
+async fn begin(tq: &ThreeQLite) -> Result<Vec<u8>, Error> {
+    let lock = uuid::Uuid::new_v4().to_bytes_le().to_vec();
+    let mut inner = tq.inner.write().await;
+    let record = inner.read_metadata_record().await?;
+    let now = protocol::now_ms();
+    if protocol::writer_decision(&record, &lock, now, &inner.lock_config) != WriterDecision::Acquire
+    {
+        snafu::whatever!("the copy {} is locked", inner.db_filename);
+    }
+    let holder = Holder {
+        identity: format!("{}/drill", inner.lock_config.identity),
+        hostname: crate::config::hostname(),
+        since: now,
+        epoch: record.stamp.map_or(0, |stamp| stamp.generation),
+        expected_release: None,
+        process: LocalProcess::current(),
+    };
+    inner
+        .write_metadata_record(MetadataRecord {
+            holder: Some(holder),
+            ..protocol::acquire(record, &lock)
+        })
+        .await?;
+    inner.current_lock = Some(lock.clone());
+    Ok(lock)
+}
+
+/// Commit the next generation and release the write lock, like
+/// [crate::vfs::Inner::release_write_lock].
+async fn commit(tq: &ThreeQLite) -> Result<(), Error> {
+    let mut inner = tq.inner.write().await;
+    let record = inner.read_metadata_record().await?;
+    let stamp = inner.commit_stamp(record.stamp).await.stamp;
+    inner.write_metadata(Metadata::None, Some(stamp)).await?;
+    inner.generation_seen.fetch_max(stamp.generation, Relaxed);
+    inner.current_lock = None;
+    Ok(())
+}
+
+/// Crash a commit once it reached `step`, and recover with a fresh instance.
+async fn interrupted_commit(scratch: &mut Scratch, step: CommitStep) -> Result<Outcome, Error> {
+    let db = scratch.db.clone();
+    let tq = scratch.instance().await?;
+    let lock = begin(&tq).await?;
+    let tx = Transaction::plan(&tq, &db).await?;
+    let before = checksum(&tq, &db, &[]).await?;
+    let committed = checksum(&tq, &db, &tx.writes).await?;
+
+    let journal_name = format!("{db}-journal");
+    let mut journal = open(
+        &tq,
+        &journal_name,
+        OpenKind::MainJournal,
+        OpenAccess::Create,
+    )
+    .await
+    .map_err(storage_cause)?;
+    journal
+        .write_all_at(&tx.journal(), 0)
+        .await
+        .map_err(storage_cause)?;
+    if step != CommitStep::JournalWritten {
+        sync(&mut journal).await?;
+    }
+    if !matches!(step, CommitStep::JournalWritten | CommitStep::JournalSynced) {
+        let mut handle = open(&tq, &db, OpenKind::MainDb, OpenAccess::Write)
+            .await
+            .map_err(storage_cause)?;
+        for (offset, page) in &tx.writes {
+            handle
+                .write_all_at(page, *offset)
+                .await
+                .map_err(storage_cause)?;
+        }
+        if step == CommitStep::UploadStarted {
+            let (last, _) = tx.writes.last().unwrap();
+            *scratch.faults.fail_upload_at.lock().unwrap() = Some(*last);
+        }
+        let synced = sync(&mut handle).await;
+        scratch.faults.fail_upload_at.lock().unwrap().take();
+        match (step, synced) {
+            (CommitStep::UploadStarted, Ok(())) => {
+                return Ok(failed("the injected upload failure didn't fail the commit"))
+            }
+            (CommitStep::UploadStarted, Err(_)) => {}
+            (_, synced) => synced?,
+        }
+    }
+    if step == CommitStep::JournalDeleted {
+        drop(journal);
+        Box::pin(tq.delete(&journal_name))
+            .await
+            .map_err(storage_cause)?;
+        commit(&tq).await?;
+    }
+    // the writer crashed
+    drop(tq);
+
+    let tq = scratch.instance().await?;
+    if step != CommitStep::JournalDeleted {
+        tq.inner.write().await.current_lock = Some(lock);
+        Box::pin(tq.recover(&db)).await?;
+    }
+    let expected = match step {
+        CommitStep::JournalDeleted => committed,
+        _ => before,
+    };
+    Ok(match checksum(&tq, &db, &[]).await? == expected {
+        true => Outcome::Recovered,
+        false => failed("the copy doesn't match its expected checksum after recovery"),
+    })
+}
+
+/// Resume a writer whose lapsed write request another writer took over.
+async fn stale_writer(scratch: &mut Scratch) -> Result<Outcome, Error> {
+    let db = scratch.db.clone();
+    let tq = scratch.instance().await?;
+    let before = checksum(&tq, &db, &[]).await?;
+    let inner = tq.inner.read().await;
+    let config = &inner.lock_config;
+    let lease = config.write_request_lease.as_millis() as u64;
+    let id = || uuid::Uuid::new_v4().to_bytes_le().to_vec();
+    let (reader, stale, writer) = (id(), id(), id());
+    let now = protocol::now_ms();
+
+    let record = inner.read_metadata_record().await?;
+    inner
+        .write_metadata_record(protocol::join(record, &reader))
+        .await?;
+    // the stale writer requested the lock more than a lease ago, and paused since
+    let record = inner.read_metadata_record().await?;
+    let then = now.saturating_sub(lease + 1);
+    let WriterDecision::Request(request) = protocol::writer_decision(&record, &stale, then, config)
+    else {
+        return Ok(failed("the stale writer couldn't request the lock"));
+    };
+    inner
+        .write_metadata_record(protocol::request(record, request))
+        .await?;
+    let record = inner.read_metadata_record().await?;
+    let WriterDecision::Request(request) = protocol::writer_decision(&record, &writer, now, config)
+    else {
+        return Ok(failed("the lapsed write request wasn't taken over"));
+    };
+    inner
+        .write_metadata_record(protocol::request(record, request))
+        .await?;
+
+    let record = inner.read_metadata_record().await?;
+    if protocol::writer_decision(&record, &stale, now, config) != WriterDecision::Wait {
+        return Ok(failed(
+            "the stale writer went ahead of the request taking over its own",
+        ));
+    }
+    let Some(record) = protocol::leave(record, &reader) else {
+        return Ok(failed("the reader wasn't registered"));
+    };
+    inner.write_metadata_record(record).await?;
+    let record = inner.read_metadata_record().await?;
+    if protocol::writer_decision(&record, &stale, now, config) != WriterDecision::Wait {
+        return Ok(failed("the stale writer acquired once the reader left"));
+    }
+    if protocol::writer_decision(&record, &writer, now, config) != WriterDecision::Acquire {
+        return Ok(failed(
+            "the writer taking over didn't acquire once the reader left",
+        ));
+    }
+    inner
+        .write_metadata_record(protocol::acquire(record, &writer))
+        .await?;
+    let record = inner.read_metadata_record().await?;
+    if protocol::writer_decision(&record, &stale, now, config) != WriterDecision::Wait {
+        return Ok(failed("the stale writer acquired a held lock"));
+    }
+    inner.write_metadata(Metadata::None, record.stamp).await?;
+    drop(inner);
+
+    Ok(match checksum(&tq, &db, &[]).await? == before {
+        true => Outcome::Recovered,
+        false => failed("the copy changed although no writer committed"),
+    })
+}
+
+/// Crash a transaction across databases between deleting its super-journal and its journal.
+async fn hot_journal(scratch: &mut Scratch) -> Result<Outcome, Error> {
+    let db = scratch.db.clone();
+    let tq = scratch.instance().await?;
+    let lock = begin(&tq).await?;
+    let tx = Transaction::plan(&tq, &db).await?;
+    let committed = checksum(&tq, &db, &tx.writes).await?;
+
+    let journal_name = format!("{db}-journal");
+    let super_name = format!("{db}-mj{:09X}", rand::random::<u32>());
+    let mut super_journal = open(&tq, &super_name, OpenKind::SuperJournal, OpenAccess::Create)
+        .await
+        .map_err(storage_cause)?;
+    let children = format!("{journal_name}\0");
+    super_journal
+        .write_all_at(children.as_bytes(), 0)
+        .await
+        .map_err(storage_cause)?;
+    sync(&mut super_journal).await?;
+    drop(super_journal);
+
+    let mut journal = open(
+        &tq,
+        &journal_name,
+        OpenKind::MainJournal,
+        OpenAccess::Create,
+    )
+    .await
+    .map_err(storage_cause)?;
+    let mut content = tx.journal();
+    content.extend(journal::super_journal_pointer(&super_name));
+    journal
+        .write_all_at(&content, 0)
+        .await
+        .map_err(storage_cause)?;
+    sync(&mut journal).await?;
+    let mut handle = open(&tq, &db, OpenKind::MainDb, OpenAccess::Write)
+        .await
+        .map_err(storage_cause)?;
+    for (offset, page) in &tx.writes {
+        handle
+            .write_all_at(page, *offset)
+            .await
+            .map_err(storage_cause)?;
+    }
+    sync(&mut handle).await?;
+    // the commit point
+    Box::pin(tq.delete(&super_name))
+        .await
+        .map_err(storage_cause)?;
+    // the writer crashed before deleting the journal
+    drop((journal, handle, tq));
+
+    let tq = scratch.instance().await?;
+    tq.inner.write().await.current_lock = Some(lock);
+    Box::pin(tq.recover(&db)).await?;
+    Ok(match checksum(&tq, &db, &[]).await? == committed {
+        true => Outcome::Recovered,
+        false => failed("the copy doesn't match the committed transaction after recovery"),
+    })
+}
+
+/// Delete the metadata object after a commit.
+async fn metadata_deleted(scratch: &mut Scratch) -> Result<Outcome, Error> {
+    let db = scratch.db.clone();
+    let tq = scratch.instance().await?;
+    begin(&tq).await?;
+    commit(&tq).await?;
+    let before = checksum(&tq, &db, &[]).await?;
+    {
+        let inner = tq.inner.read().await;
+        inner.guard(OpClass::Write)?;
+        let res = inner
+            .s3
+            .delete_object()
+            .bucket(&inner.bucket)
+            .key(&inner.metadata_filename)
+            .send()
+            .await;
+        inner.record(OpClass::Write, res.is_ok());
+        res.map_err(Error::from_aws)?;
+    }
+
+    let fresh = scratch.instance().await?;
+    let health = fresh
+        .inner
+        .read()
+        .await
+        .check_metadata(&scratch.key)
+        .await?;
+    if health != MetadataHealth::Reconstructed {
+        return Ok(failed(format!(
+            "a new instance found the metadata {health:?}"
+        )));
+    }
+    let health = tq.inner.read().await.check_metadata(&scratch.key).await?;
+    if health != MetadataHealth::Quarantined {
+        return Ok(failed(format!(
+            "an instance that saw the lost generation found the metadata {health:?}"
+        )));
+    }
+    if !matches!(
+        open(&tq, &db, OpenKind::MainDb, OpenAccess::Write).await,
+        Err(sqlite_vfs::error::Error::PermissionDenied)
+    ) {
+        return Ok(failed("the quarantined copy still opens for writing"));
+    }
+    Ok(match checksum(&tq, &db, &[]).await? == before {
+        true => Outcome::Quarantined,
+        false => failed("the copy changed while its metadata was lost"),
+    })
+}
+
+/// Append a chunk to the database object out-of-band after a commit.
+async fn corrupt_chunk(scratch: &mut Scratch) -> Result<Outcome, Error> {
+    let db = scratch.db.clone();
+    let tq = scratch.instance().await?;
+    begin(&tq).await?;
+    let tx = Transaction::plan(&tq, &db).await?;
+    let mut handle = open(&tq, &db, OpenKind::MainDb, OpenAccess::Write)
+        .await
+        .map_err(storage_cause)?;
+    for (offset, page) in &tx.writes {
+        handle
+            .write_all_at(page, *offset)
+            .await
+            .map_err(storage_cause)?;
+    }
+    sync(&mut handle).await?;
+    drop(handle);
+    commit(&tq).await?;
+    {
+        let inner = tq.inner.read().await;
+        inner.guard(OpClass::Write)?;
+        let res = inner
+            .s3
+            .put_object()
+            .bucket(&inner.bucket)
+            .key(&inner.db_filename)
+            .write_offset_bytes((tx.pages * tx.page_size) as i64)
+            .body(vec![0xa5; SECTOR].into())
+            .send()
+            .await;
+        inner.record(OpClass::Write, res.is_ok());
+        res.map_err(Error::from_aws)?;
+    }
+
+    let reader = scratch.instance().await?;
+    {
+        let mut inner = reader.inner.write().await;
+        let record = inner.read_metadata_record().await?;
+        let generation = inner.join_stamp(record.stamp);
+        let read = inner
+            .read_at(0, tx.page_size as usize, generation, CacheUse::Bypass)
+            .await;
+        if !matches!(read, Err(Error::ExternalModification { .. })) {
+            return Ok(failed("a registered read served the modified object"));
+        }
+    }
+    if !matches!(
+        open(&reader, &db, OpenKind::MainDb, OpenAccess::Write).await,
+        Err(sqlite_vfs::error::Error::PermissionDenied)
+    ) {
+        return Ok(failed("the quarantined copy still opens for writing"));
+    }
+    Ok(Outcome::Quarantined)
+}
+
+impl ThreeQLite {
+    /// Rehearse recovering `db` from each of `scenarios`, against copies of it under
+    /// [DrillOptions::prefix], see the [module documentation](self). A scenario that doesn't end
+    /// as documented is reported as such; an error means the drill couldn't run at all.
+    pub async fn drill(
+        &self,
+        db: &str,
+        scenarios: &[Scenario],
+        opts: DrillOptions,
+    ) -> Result<DrillReport, Error> {
+        self.operations
+            .run(OperationKind::Drill, db.to_owned(), |op| async move {
+                self.run_drill(db, scenarios, opts, &op).await
+            })
+            .await
+    }
+
+    /// [ThreeQLite::drill] as an operation of its own, see [crate::operation].
+    pub fn start_drill(
+        &self,
+        db: &str,
+        scenarios: &[Scenario],
+        opts: DrillOptions,
+    ) -> OperationHandle<DrillReport> {
+        let (tq, db, scenarios) = (self.clone(), db.to_owned(), scenarios.to_vec());
+        self.operations
+            .spawn(OperationKind::Drill, db.clone(), move |op| async move {
+                tq.run_drill(&db, &scenarios, opts, &op).await
+            })
+    }
+
+    async fn run_drill(
+        &self,
+        db: &str,
+        scenarios: &[Scenario],
+        opts: DrillOptions,
+        op: &Operation,
+    ) -> Result<DrillReport, Error> {
+        let start = Instant::now();
+        KeyLayout::db(db)?;
+        let prefix = opts.prefix.trim_end_matches('/');
+        if KeyLayout::db(prefix)?.as_str() != prefix {
+            snafu::whatever!("drill prefix {prefix} must be a key that needs no encoding");
+        }
+        let scratch = format!("{prefix}/{}", uuid::Uuid::new_v4());
+        let (config, s3) = {
+            let inner = self.inner.read().await;
+            let config = Config {
+                bucket: inner.bucket.clone(),
+                lock: inner.lock_config.clone(),
+                timeouts: Some(inner.timeouts.clone()),
+                fetch: inner.fetch_config.clone(),
+                limits: inner.transaction_limits.clone(),
+                ..Config::default()
+            };
+            (config, inner.s3.clone())
+        };
+
+        let mut reports = vec![];
+        op.phase("scenarios", Some(scenarios.len() as u64));
+        for (i, scenario) in scenarios.iter().enumerate() {
+            if let Err(err) = op.checkpoint() {
+                self.clean_up(&scratch).await?;
+                return Err(err);
+            }
+            let start = Instant::now();
+            let copy = format!("{scratch}/{i}/{db}");
+            let copied = match self.copy_database(db, &copy, CopyOptions::default()).await {
+                Ok(copied) => copied,
+                Err(err) => {
+                    let _ = self.clean_up(&scratch).await;
+                    return Err(err);
+                }
+            };
+            let mut scratch = Scratch {
+                key: KeyLayout::db(&copy)?,
+                config: Config {
+                    db_filename: copy.clone(),
+                    lock_file: format!("{scratch}/{i}.lock"),
+                    metadata_filename: format!("{scratch}/{i}.metadata"),
+                    ..config.clone()
+                },
+                db: copy,
+                s3: s3.clone(),
+                faults: std::sync::Arc::new(Faults::new(&format!("{scratch}/"))),
+                instances: vec![],
+            };
+            // boxed, the futures of the scenarios are large
+            let outcome = match *scenario {
+                Scenario::InterruptedCommit(step) => {
+                    Box::pin(interrupted_commit(&mut scratch, step)).await
+                }
+                Scenario::StaleWriter => Box::pin(stale_writer(&mut scratch)).await,
+                Scenario::HotJournal => Box::pin(hot_journal(&mut scratch)).await,
+                Scenario::MetadataDeleted => Box::pin(metadata_deleted(&mut scratch)).await,
+                Scenario::CorruptChunk => Box::pin(corrupt_chunk(&mut scratch)).await,
+            };
+            let outcome = outcome.unwrap_or_else(|err| failed(err.to_string()));
+            let report = ScenarioReport {
+                scenario: *scenario,
+                outcome,
+                duration: start.elapsed(),
+                requests: scratch.requests().await,
+                copied,
+            };
+            tracing::info!(target: "threeqlite::s3", %db, %report, "drill scenario finished");
+            op.advance(1, report.copied.bytes);
+            reports.push(report);
+        }
+
+        let deleted = self.clean_up(&scratch).await?;
+        Ok(DrillReport {
+            scratch,
+            scenarios: reports,
+            deleted,
+            duration: start.elapsed(),
+        })
+    }
+
+    /// Delete every object under the scratch prefix `scratch`, returning how many.
+    async fn clean_up(&self, scratch: &str) -> Result<u64, Error> {
+        let inner = self.inner.read().await;
+        let mut deleted = 0;
+        let mut token = None;
+        loop {
+            let page = inner
+                .list(
+                    inner
+                        .s3
+                        .list_objects_v2()
+                        .prefix(format!("{scratch}/"))
+                        .set_continuation_token(token),
+                )
+                .await?;
+            for key in page.contents().iter().filter_map(|object| object.key()) {
+                inner.guard(OpClass::Write)?;
+                let res = inner
+                    .s3
+                    .delete_object()
+                    .bucket(&inner.bucket)
+                    .key(key)
+                    .send()
+                    .await;
+                inner.record(OpClass::Write, res.is_ok());
+                res.map_err(Error::from_aws)?;
+                deleted += 1;
+            }
+            match page.next_continuation_token() {
+                Some(next) => token = Some(next.to_owned()),
+                None => return Ok(deleted),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlite_vfs::LockKind;
+
+    use super::*;
+    use crate::{flush::PendingWrites, mock};
+
+    #[tokio::test]
+    async fn test_drill_suite() {
+        let mock = mock::MockS3::start();
+        mock.put("test.db", mock::database(4096, 6, 1));
+        mock.put("metadata", vec![]);
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+
+        let report = tq
+            .drill("test.db", &Scenario::ALL, DrillOptions::default())
+            .await
+            .unwrap();
+        for scenario in &report.scenarios {
+            assert!(scenario.passed(), "{scenario}");
+            assert!(scenario.requests > 0, "{scenario}");
+            assert_eq!(scenario.copied.objects, 1, "{scenario}");
+        }
+        assert_eq!(report.scenarios.len(), Scenario::ALL.len());
+        assert!(report.is_ok());
+        assert!(report.deleted > 0);
+
+        // nothing is left of the copies, and the database itself was only read
+        for (method, key) in mock.requests() {
+            match method.as_str() {
+                "GET" | "HEAD" => {}
+                _ => assert!(
+                    key.starts_with("threeqlite-drill/") || key.ends_with(".copy"),
+                    "{method} {key}"
+                ),
+            }
+        }
+        let lists = tq.inner.read().await.stats.list_requests.load(Relaxed);
+        assert!(lists > 0);
+        assert_eq!(mock.get("test.db"), Some(mock::database(4096, 6, 1)));
+        assert!(mock
+            .requests()
+            .iter()
+            .filter(|(_, key)| key.starts_with(&report.scratch))
+            .all(|(_, key)| mock.get(key).is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_drill_cleans_up() {
+        let mock = mock::MockS3::start();
+        mock.put("test.db", mock::database(4096, 6, 1));
+        mock.put("metadata", vec![]);
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+
+        let op = tq.start_drill("test.db", &Scenario::ALL, DrillOptions::default());
+        while op.progress().done < 1 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(op.cancel());
+        let err = op.await_done().await.unwrap_err();
+        assert!(matches!(err, Error::Cancelled { .. }), "{err}");
+
+        // the scenarios run before the cancel left nothing behind
+        let scratch: Vec<_> = mock
+            .requests()
+            .into_iter()
+            .filter(|(_, key)| key.starts_with("threeqlite-drill/"))
+            .collect();
+        assert!(!scratch.is_empty());
+        assert!(scratch.iter().all(|(_, key)| mock.get(key).is_none()));
+        // along with the copies it made, each an operation of its own
+        let operations = tq.operations();
+        let drill = &operations[0];
+        assert_eq!(drill.kind, OperationKind::Drill);
+        assert_eq!(drill.state, crate::operation::OperationState::Cancelled);
+        assert!(operations[1..]
+            .iter()
+            .all(|op| op.kind == OperationKind::Copy));
+        assert!(drill.done < Scenario::ALL.len() as u64, "{drill}");
+    }
+
+    #[test]
+    fn test_scenario_names() {
+        for scenario in Scenario::ALL {
+            assert_eq!(Scenario::parse(scenario.name()), Some(scenario));
+        }
+        assert_eq!(Scenario::parse("commit-upload-finished"), None);
+    }
+
+    #[tokio::test]
+    async fn test_faults_inert_outside_drill() {
+        let faults = Faults::new("threeqlite-drill/x/");
+        *faults.fail_upload_at.lock().unwrap() = Some(0);
+        let drilled = ObjectKey::new("threeqlite-drill/x/0/test.db").unwrap();
+        assert!(faults.upload(&drilled, 0..4096).is_err());
+        assert!(faults.upload(&drilled, 4096..8192).is_ok());
+        assert!(faults
+            .upload(&KeyLayout::db("test.db").unwrap(), 0..4096)
+            .is_ok());
+
+        // instances other than the scratch instances of a drill never carry faults
+        let mock = mock::MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        assert!(tq.inner.read().await.faults.is_none());
+        let db = KeyLayout::db("test.db").unwrap();
+        let mut handle = Handle::new(tq.clone(), db.clone(), false);
+        assert!(handle.lock(LockKind::Exclusive).await.unwrap());
+        let mut pending = PendingWrites::default();
+        pending.write(0, &mock::database(4096, 2, 2));
+        tq.flush_pages(&db, &std::sync::Arc::new(pending))
+            .await
+            .unwrap();
+        assert_eq!(mock.get("test.db"), Some(mock::database(4096, 2, 2)));
+    }
+}
@@ -0,0 +1,352 @@
+//! What `PRAGMA synchronous` means for a database on the object store.
+//!
+//! The durability point of a database is the flush of a sync, see [crate::flush]: a sync of the
+//! journal uploads it, and a sync of the database uploads the pages buffered since the last one
+//! and waits for all of them. Which syncs SQLite issues depends on the level of
+//! `PRAGMA synchronous`, with these guarantees in rollback-journal mode:
+//!
+//! - `FULL` (the default of SQLite) and `EXTRA`: the journal is uploaded before any page, and
+//!   every page before the journal is deleted. A commit is durable once SQLite deletes the
+//!   journal, and a crash at any point leaves either the old database with a hot journal to roll
+//!   back, or the new database. `EXTRA` also syncs the directory after deleting the journal, which
+//!   has no counterpart here: the DELETE of the journal is only acknowledged once the object is
+//!   gone.
+//! - `NORMAL`: the same as `FULL`. SQLite only skips syncing the journal a second time after
+//!   writing its header, and every sync uploads the whole journal anyway.
+//! - `OFF`: nothing is synced. The journal is never uploaded, and the pages are uploaded when
+//!   SQLite releases its lock, after it deleted the journal. A commit is thus acknowledged before
+//!   anything was uploaded, with `locking_mode=EXCLUSIVE` not until the connection closes, and a
+//!   crash while the pages upload leaves a torn database without a journal to roll it back.
+//!
+//! Without a journal (`journal_mode=MEMORY` or `OFF`), the pages are uploaded on the sync or,
+//! without one, when SQLite releases its lock, and a crash while they upload tears the database
+//! at any level. WAL mode isn't supported yet.
+//!
+//! A commit reaches its commit point when SQLite deletes the journal or, without one, releases
+//! its lock. Pages still buffered there were never synced: such a commit counts as unflushed in
+//! the stats, which also report the level last set with `PRAGMA synchronous` (`off` if SQLite
+//! skipped the sync without any level set). [SyncPolicy] decides what else happens to them.
+
+use std::fmt::Display;
+
+/// A level of `PRAGMA synchronous`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    /// The level `PRAGMA synchronous = value` sets, `None` if SQLite would ignore the value.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().trim_matches(|c| c == '\'' || c == '"');
+        match value.to_ascii_lowercase().as_str() {
+            "0" | "off" | "no" | "false" => Some(Self::Off),
+            "1" | "normal" => Some(Self::Normal),
+            "2" | "full" | "on" | "yes" | "true" => Some(Self::Full),
+            "3" | "extra" => Some(Self::Extra),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Synchronous {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Normal => "normal",
+            Self::Full => "full",
+            Self::Extra => "extra",
+        })
+    }
+}
+
+/// What happens to a commit that reaches its commit point unflushed, see the
+/// [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Nothing but counting it.
+    #[default]
+    Allow,
+    /// Log a warning the first time it happens on a connection.
+    Warn,
+    /// Upload the journal if SQLite closed it without syncing it, then the pages, before letting
+    /// the journal be deleted or the lock be released. This restores the guarantees of `FULL`,
+    /// along with the round trips `OFF` was meant to save.
+    EnforceDurability,
+}
+
+#[cfg(feature = "s3")]
+pub use barrier::{Barriers, Buffered};
+
+#[cfg(feature = "s3")]
+mod barrier {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex, Weak},
+    };
+
+    use super::{SyncPolicy, Synchronous};
+    use crate::{
+        error::Error,
+        flush::PendingWrites,
+        journal::{Journal, JournalKind},
+        key::{KeyLayout, ObjectKey},
+        registration,
+        stats::Stats,
+        vfs::ThreeQLite,
+    };
+
+    /// What a handle of a database buffered, shared with [Barriers].
+    #[derive(Debug, Default)]
+    pub struct Buffered {
+        /// Pages written since the last flush, see [crate::flush].
+        pub pages: PendingWrites,
+        /// The commit point of these pages was counted already.
+        pub counted: bool,
+        /// An unflushed commit was logged for this connection, see [SyncPolicy::Warn].
+        pub warned: bool,
+        /// The level set with `PRAGMA synchronous` on this connection.
+        pub synchronous: Option<Synchronous>,
+    }
+
+    /// The buffered pages and unsynced journals of the databases of an instance, for their commit
+    /// points.
+    #[derive(Debug, Default)]
+    pub struct Barriers {
+        pub policy: SyncPolicy,
+        state: Mutex<BarrierState>,
+    }
+
+    #[derive(Debug, Default)]
+    struct BarrierState {
+        handles: HashMap<ObjectKey, Vec<Weak<Mutex<Buffered>>>>,
+        /// Journals SQLite closed without syncing, by key.
+        journals: HashMap<ObjectKey, Journal>,
+    }
+
+    impl Barriers {
+        pub fn new(policy: SyncPolicy) -> Self {
+            Self {
+                policy,
+                ..Self::default()
+            }
+        }
+
+        /// Note that a handle of `db` buffers pages in `buffered`.
+        pub fn register(&self, db: &ObjectKey, buffered: &Arc<Mutex<Buffered>>) {
+            let mut state = self.state.lock().unwrap();
+            let handles = state.handles.entry(db.clone()).or_default();
+            handles.retain(|handle| handle.strong_count() > 0);
+            let weak = Arc::downgrade(buffered);
+            if !handles.iter().any(|handle| handle.ptr_eq(&weak)) {
+                handles.push(weak);
+            }
+        }
+
+        /// Keep `journal`, closed by SQLite, for the commit point of its database if it was
+        /// written since its last sync and [SyncPolicy::EnforceDurability] applies.
+        pub fn closed(&self, journal: Journal) {
+            if self.policy == SyncPolicy::EnforceDurability
+                && journal.kind == JournalKind::Main
+                && journal.is_dirty()
+            {
+                let mut state = self.state.lock().unwrap();
+                state.journals.insert(journal.key.clone(), journal);
+            }
+        }
+
+        /// The handles of `db` with buffered pages.
+        fn unflushed(&self, db: &ObjectKey) -> Vec<Arc<Mutex<Buffered>>> {
+            let state = self.state.lock().unwrap();
+            let Some(handles) = state.handles.get(db) else {
+                return vec![];
+            };
+            handles
+                .iter()
+                .filter_map(Weak::upgrade)
+                .filter(|buffered| !buffered.lock().unwrap().pages.is_empty())
+                .collect()
+        }
+    }
+
+    impl ThreeQLite {
+        /// Count the pages of `db` still buffered when a commit reaches its commit point, and
+        /// apply the [SyncPolicy] to them, see the [module documentation](super).
+        pub async fn commit_point(&self, db: &ObjectKey) -> Result<(), Error> {
+            crate::burst::assert_fresh();
+            let journal = self
+                .barriers
+                .state
+                .lock()
+                .unwrap()
+                .journals
+                .remove(&KeyLayout::journal(db));
+            let unflushed = self.barriers.unflushed(db);
+            if journal.is_none() && unflushed.is_empty() {
+                return Ok(());
+            }
+            let stats = self.inner.read().await.stats.clone();
+            for buffered in &unflushed {
+                let mut buffered = buffered.lock().unwrap();
+                if std::mem::replace(&mut buffered.counted, true) {
+                    continue;
+                }
+                Stats::incr(&stats.unflushed_commits);
+                let level = *buffered.synchronous.get_or_insert(Synchronous::Off);
+                stats.observe_synchronous(level);
+                if self.barriers.policy == SyncPolicy::Warn
+                    && !std::mem::replace(&mut buffered.warned, true)
+                {
+                    tracing::warn!(
+                        target: "threeqlite::s3",
+                        key = %db,
+                        synchronous = %level,
+                        bytes = buffered.pages.bytes(),
+                        "commit completed without syncing the database, its pages aren't durable yet"
+                    );
+                }
+            }
+            if self.barriers.policy != SyncPolicy::EnforceDurability {
+                return Ok(());
+            }
+
+            if let Some(mut journal) = journal {
+                // the journal must be durable before any page is overwritten
+                let inner = self.inner.write().await;
+                if let Err(err) = journal.sync(&inner).await {
+                    self.barriers.closed(journal);
+                    return Err(err);
+                }
+            }
+            for buffered in unflushed {
+                let pages = Arc::new(std::mem::take(&mut buffered.lock().unwrap().pages));
+                let flushed =
+                    registration::scope(self.registration.clone(), self.flush_pages(db, &pages))
+                        .await;
+                if let Err(err) = flushed {
+                    buffered.lock().unwrap().pages = Arc::unwrap_or_clone(pages);
+                    return Err(err);
+                }
+                buffered.lock().unwrap().counted = false;
+                Stats::incr(&stats.enforced_flushes);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_synchronous() {
+        assert_eq!(Synchronous::parse("OFF"), Some(Synchronous::Off));
+        assert_eq!(Synchronous::parse("0"), Some(Synchronous::Off));
+        assert_eq!(Synchronous::parse("normal"), Some(Synchronous::Normal));
+        assert_eq!(Synchronous::parse("'full'"), Some(Synchronous::Full));
+        assert_eq!(Synchronous::parse(" 3 "), Some(Synchronous::Extra));
+        assert_eq!(Synchronous::parse("4"), None);
+        assert_eq!(Synchronous::parse("fast"), None);
+        assert_eq!(Synchronous::Normal.to_string(), "normal");
+    }
+
+    #[cfg(feature = "s3")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_under_each_level_and_policy() {
+        use sqlite_vfs::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+        use crate::{config::Config, handle::Handle, key::KeyLayout, mock, vfs::ThreeQLite};
+
+        const LEVELS: [Synchronous; 4] = [
+            Synchronous::Off,
+            Synchronous::Normal,
+            Synchronous::Full,
+            Synchronous::Extra,
+        ];
+        let policies = [
+            SyncPolicy::Allow,
+            SyncPolicy::Warn,
+            SyncPolicy::EnforceDurability,
+        ];
+        for policy in policies {
+            for level in LEVELS {
+                let case = format!("{level} under {policy:?}");
+                let mock = mock::MockS3::start();
+                let before = mock::database(4096, 4, 1);
+                mock.put("test.db", before.clone());
+                let config = Config {
+                    synchronous: policy,
+                    ..Config::default()
+                };
+                let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+
+                // a rollback-journal commit of page 2, issuing the syncs of `level`
+                let mut db = Handle::new(tq.clone(), KeyLayout::db("test.db").unwrap(), false);
+                assert!(db.lock(LockKind::Exclusive).await.unwrap(), "{case}");
+                let answer = db.pragma("synchronous", Some(&level.to_string())).await;
+                assert_eq!(answer.unwrap(), None, "{case}");
+                let mut journal = tq
+                    .open(
+                        "test.db-journal",
+                        OpenOptions::new(OpenKind::MainJournal, OpenAccess::Create),
+                    )
+                    .await
+                    .unwrap();
+                journal.write_all_at(&before[4096..8192], 0).await.unwrap();
+                if level != Synchronous::Off {
+                    journal.sync(false).await.unwrap();
+                }
+                drop(journal);
+                db.write_all_at(&[9; 4096], 4096).await.unwrap();
+                if level != Synchronous::Off {
+                    db.sync(false).await.unwrap();
+                }
+                tq.delete("test.db-journal").await.unwrap();
+
+                // crash right after the commit point, before the lock is released
+                let warned = db.buffered.lock().unwrap().warned;
+                drop(db);
+                let enforced = policy == SyncPolicy::EnforceDurability;
+                let durable = level != Synchronous::Off || enforced;
+                let page = mock.get("test.db").unwrap()[4096..8192].to_vec();
+                assert_eq!(page == [9; 4096], durable, "{case}");
+                assert_eq!(page == before[4096..8192], !durable, "{case}");
+                assert_eq!(mock.get("test.db-journal"), None, "{case}");
+                // the journal, then the pages, then the delete of the journal, apart from the
+                // requests of the lock
+                let writes: Vec<_> = mock
+                    .requests()
+                    .into_iter()
+                    .filter(|(method, key)| {
+                        (method == "PUT" || method == "DELETE") && key.starts_with("test.db")
+                    })
+                    .collect();
+                let journal_put = ("PUT".to_owned(), "test.db-journal".to_owned());
+                let expected = match durable {
+                    true => vec![
+                        journal_put,
+                        ("PUT".to_owned(), "test.db".to_owned()),
+                        ("DELETE".to_owned(), "test.db-journal".to_owned()),
+                    ],
+                    false => vec![("DELETE".to_owned(), "test.db-journal".to_owned())],
+                };
+                assert_eq!(writes, expected, "{case}");
+
+                let unflushed = level == Synchronous::Off;
+                let stats = tq.stats().await;
+                assert_eq!(stats.synchronous, Some(level), "{case}");
+                assert_eq!(stats.unflushed_commits, unflushed as u64, "{case}");
+                assert_eq!(
+                    stats.enforced_flushes,
+                    (unflushed && enforced) as u64,
+                    "{case}"
+                );
+                assert_eq!(warned, unflushed && policy == SyncPolicy::Warn, "{case}");
+            }
+        }
+    }
+}
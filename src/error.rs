@@ -1,21 +1,303 @@
 use snafu::Snafu;
 
+use crate::{circuit::OpClass, credentials::CredentialFailure, memory::Component};
+
 #[derive(Debug, Snafu)]
+#[non_exhaustive]
 pub enum Error {
     ObjectNotFound,
 
     #[snafu(whatever, display("{message}"))]
     Whatever {
         message: String,
-        #[snafu(source(from(Box<dyn std::error::Error>, Some)))]
-        source: Option<Box<dyn std::error::Error>>,
+        #[snafu(source(from(Box<dyn std::error::Error + Send + Sync>, Some)))]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
     },
 
     FailedToGetDatabaseSize {
         msg: String,
     },
+
+    #[snafu(display("the VFS has not been registered with SQLite"))]
+    NotRegistered,
+
+    #[snafu(display("the connection has been closed"))]
+    ConnectionClosed,
+
+    /// See [crate::shutdown].
+    #[snafu(display("the instance is shutting down"))]
+    ShuttingDown,
+
+    #[snafu(display("{} handles still open: {}", handles.len(), handles.join(", ")))]
+    HandlesOpen {
+        handles: Vec<String>,
+    },
+
+    #[snafu(
+        display("unregistering the VFS {name} failed: {source}"),
+        visibility(pub(crate))
+    )]
+    Unregister {
+        name: String,
+        source: sqlite_vfs::RegisterError,
+    },
+
+    #[snafu(display(
+        "the blocking client was called from within an async runtime, where it would block a \
+         worker; use ThreeQLite directly instead"
+    ))]
+    InAsyncContext,
+
+    #[cfg(feature = "rusqlite")]
+    #[snafu(display("sqlite error: {source}"), visibility(pub(crate)))]
+    Sqlite {
+        source: rusqlite::Error,
+    },
+
+    /// See [crate::lite].
+    #[cfg(feature = "lite-s3")]
+    #[snafu(display("{method} {key} failed with {status} {code}"))]
+    LiteRequest {
+        method: String,
+        key: String,
+        status: u16,
+        code: String,
+    },
+
+    /// See [crate::lite].
+    #[cfg(feature = "lite-s3")]
+    #[snafu(display("request to {authority} failed: {source}"))]
+    LiteHttp {
+        authority: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(
+        "object requires format version {need}, but this build reads up to version {have}"
+    ))]
+    IncompatibleFormat {
+        need: u32,
+        have: u32,
+    },
+
+    #[snafu(display(
+        "database is quarantined because the generation in {key} went backwards; see \
+         ThreeQLite::clear_quarantine"
+    ))]
+    Quarantined {
+        key: String,
+    },
+
+    #[snafu(display(
+        "database object {key} is {actual} bytes long, but {expected} bytes were committed; it \
+         was modified out-of-band and is quarantined, see ThreeQLite::accept_external_state"
+    ))]
+    ExternalModification {
+        key: String,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[snafu(display("database {db} already exists"))]
+    DatabaseExists {
+        db: String,
+    },
+
+    #[snafu(display("{db} is being copied from {src} to {dst}; resume or finish that first"))]
+    CopyInProgress {
+        db: String,
+        src: String,
+        dst: String,
+    },
+
+    #[snafu(display("upload of {key} does not match what was sent: {reason}"))]
+    UploadMismatch {
+        key: String,
+        reason: String,
+    },
+
+    /// See [crate::verify::VerifyWrites].
+    #[cfg(feature = "s3")]
+    #[snafu(display("write of {key} failed verification by {method}: {reason}"))]
+    WriteVerificationFailed {
+        key: String,
+        method: crate::verify::VerifyMethod,
+        reason: String,
+    },
+
+    #[snafu(display("{db} is unreachable; its offline mirror only serves reads"))]
+    Offline {
+        db: String,
+    },
+
+    #[snafu(display("offline mirror of {db} cannot be served: {reason}"))]
+    MirrorUnavailable {
+        db: String,
+        reason: String,
+    },
+
+    #[snafu(display("invalid object key {key:?}: {reason}"))]
+    InvalidKey {
+        key: String,
+        reason: &'static str,
+    },
+
+    #[snafu(display(
+        "database name {name:?} is too long: its object key would exceed {max} bytes"
+    ))]
+    NameTooLong {
+        name: String,
+        max: usize,
+    },
+
+    #[snafu(display(
+        "transaction too large: {attempted} {resource} exceed the limit of {limit}; commit in \
+         smaller batches or use a larger page size"
+    ))]
+    TransactionTooLarge {
+        resource: &'static str,
+        limit: u64,
+        attempted: u64,
+    },
+
+    #[snafu(display(
+        "{scope} budget exhausted: spent {spent} of {limit} {dimension}; pages in the cache stay \
+         readable, reset the budget to read or write others"
+    ))]
+    BudgetExhausted {
+        scope: &'static str,
+        dimension: &'static str,
+        limit: u64,
+        spent: u64,
+    },
+
+    #[snafu(display(
+        "out of memory: {requested} bytes of {component} would exceed the cap of {cap} with \
+         {used} in use"
+    ))]
+    OutOfMemory {
+        component: Component,
+        requested: u64,
+        used: u64,
+        cap: u64,
+    },
+
+    #[snafu(display("object store rejected the credentials ({failure}): {message}"))]
+    CredentialsExpired {
+        failure: CredentialFailure,
+        message: String,
+    },
+
+    #[snafu(display("metadata object is unreadable: {source}"))]
+    MetadataUnavailable {
+        source: Box<Error>,
+    },
+
+    #[snafu(display(
+        "{db} is read-only while its metadata object is unreadable and reads are served from \
+         its mirror at generation {generation}"
+    ))]
+    DegradedReadOnly {
+        db: String,
+        generation: u64,
+    },
+
+    #[snafu(display("invalid {var}: {reason}"))]
+    InvalidEnv {
+        var: &'static str,
+        reason: String,
+    },
+
+    #[snafu(display("invalid configuration: {reason}"))]
+    InvalidConfig {
+        reason: String,
+    },
+
+    #[snafu(display("metadata object {key} is corrupt: {reason}"))]
+    CorruptMetadata {
+        key: String,
+        reason: String,
+    },
+
+    /// A block manifest, hot set or warm set, see [crate::format::Bounded].
+    #[snafu(display("manifest {key} is corrupt: {reason}"))]
+    CorruptManifest {
+        key: String,
+        reason: String,
+    },
+
+    #[snafu(display("{db} has no valid database header after a commit rewrote it"))]
+    CorruptHeader {
+        db: String,
+    },
+
+    /// See [crate::idempotency].
+    #[snafu(display(
+        "commit of generation {generation} of {db} conflicts with another write of its metadata \
+         object"
+    ))]
+    CommitConflict {
+        db: String,
+        generation: u64,
+    },
+
+    /// See [crate::recovery].
+    #[snafu(display(
+        "the recovery of {db} lost the write lock it took at generation {epoch} to another writer"
+    ))]
+    Fenced {
+        db: String,
+        epoch: u64,
+    },
+
+    #[cfg(feature = "s3")]
+    #[snafu(display("database is locked: {diagnosis}"))]
+    Busy {
+        diagnosis: Box<crate::busy::BusyDiagnosis>,
+    },
+
+    /// See [crate::vfs::S3FileLock].
+    #[snafu(display(
+        "lock object {key} has no legal hold ({reason}); enable Object Lock on the bucket or use \
+         LockBackend::Conditional"
+    ))]
+    LegalHoldUnavailable {
+        key: String,
+        reason: String,
+    },
+
+    #[snafu(display("{action} of lock object {key} failed: {reason}"))]
+    LockObject {
+        key: String,
+        action: &'static str,
+        reason: String,
+    },
+
+    /// See [crate::cas].
+    #[snafu(display("lock object {key} was taken over while held"))]
+    LockLost {
+        key: String,
+    },
+
+    #[snafu(display(
+        "circuit for {class:?} requests to {}bucket {bucket} is open",
+        db.as_ref().map_or(String::new(), |db| format!("{db} in "))
+    ))]
+    CircuitOpen {
+        bucket: String,
+        /// `None` for the circuit of the bucket, see [crate::circuit::CircuitIsolation].
+        db: Option<String>,
+        class: OpClass,
+    },
+
+    /// See [crate::operation].
+    #[snafu(display("{operation} was cancelled"))]
+    Cancelled {
+        operation: String,
+    },
 }
 
+#[cfg(feature = "s3")]
 impl Error {
     pub fn from_aws<E, R>(err: aws_sdk_s3::error::SdkError<E, R>) -> Self {
         Self::Whatever {
@@ -25,8 +307,28 @@ impl Error {
     }
 }
 
-impl<E, R> From<aws_sdk_s3::error::SdkError<E, R>> for Error {
+impl From<snafu::Whatever> for Error {
+    fn from(source: snafu::Whatever) -> Self {
+        Self::Whatever {
+            message: source.to_string(),
+            source: None,
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+impl<E, R> From<aws_sdk_s3::error::SdkError<E, R>> for Error
+where
+    E: aws_sdk_s3::error::ProvideErrorMetadata + std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
     fn from(source: aws_sdk_s3::error::SdkError<E, R>) -> Self {
+        if let Some(failure) = crate::credentials::classify(&source) {
+            return Self::CredentialsExpired {
+                failure,
+                message: source.to_string(),
+            };
+        }
         Self::Whatever {
             message: source.to_string(),
             source: None,
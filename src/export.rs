@@ -0,0 +1,1004 @@
+//! Stats in the OpenMetrics text format, for scraping by Prometheus.
+//!
+//! [ThreeQLite::render_prometheus] serializes the [StatsSnapshot] and the latency windows of an
+//! instance, to be served from an HTTP endpoint of the application with the content type
+//! [CONTENT_TYPE]. It reads the same counters [ThreeQLite::stats] does and keeps none of its own,
+//! so nothing is counted twice.
+//!
+//! The metric families are listed in [FAMILIES], with their type, unit and labels. Their names
+//! and labels are part of the interface of the crate: dashboards built on them keep working
+//! across releases. Every series has the label `db`, the database of the instance, and its other
+//! labels only take values of a fixed set, e.g. `class` those of [OpClass]. The keys of objects,
+//! e.g. of journals or chunks, are never labels.
+//!
+//! Latencies are kept as rolling windows of the last [LATENCY_WINDOW] samples rather than
+//! cumulative histograms, so they are exported as gauges per `quantile`: `0.5`, `0.99` and `1`,
+//! the maximum.
+//!
+//! [ThreeQLite::render_prometheus]: crate::vfs::ThreeQLite::render_prometheus
+//! [ThreeQLite::stats]: crate::vfs::ThreeQLite::stats
+//! [LATENCY_WINDOW]: crate::stats::LATENCY_WINDOW
+
+use std::fmt::Write as _;
+
+use crate::{
+    cache::EvictionCause,
+    circuit::{CircuitState, OpClass},
+    latency::Phase,
+    memory::{Component, Relief},
+    operation::{OperationKind, OperationState, ProgressSnapshot},
+    priority::IoClass,
+    stats::{LatencySummary, Stats, StatsSnapshot},
+    timeouts::{TimeoutCause, TimeoutClass},
+};
+
+/// The content type of the rendered stats.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// The prefix of every metric name.
+pub const PREFIX: &str = "threeqlite_";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Samples are named `<family>_total`.
+    Counter,
+    Gauge,
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        })
+    }
+}
+
+/// A metric family, named [PREFIX] followed by `name`.
+#[derive(Clone, Copy, Debug)]
+pub struct Family {
+    pub name: &'static str,
+    pub kind: Kind,
+    /// The unit the name ends with, if any.
+    pub unit: Option<&'static str>,
+    /// Labels besides `db`.
+    pub labels: &'static [&'static str],
+    pub help: &'static str,
+}
+
+const fn family(
+    name: &'static str,
+    kind: Kind,
+    labels: &'static [&'static str],
+    help: &'static str,
+) -> Family {
+    let unit = if ends_with(name, "_bytes") {
+        Some("bytes")
+    } else if ends_with(name, "_seconds") {
+        Some("seconds")
+    } else {
+        None
+    };
+    Family {
+        name,
+        kind,
+        unit,
+        labels,
+        help,
+    }
+}
+
+const fn ends_with(name: &str, suffix: &str) -> bool {
+    let (name, suffix) = (name.as_bytes(), suffix.as_bytes());
+    if name.len() < suffix.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < suffix.len() {
+        if name[name.len() - suffix.len() + i] != suffix[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+use Kind::{Counter, Gauge};
+
+/// Every metric family, in the order they are rendered.
+pub const FAMILIES: &[Family] = &[
+    family(
+        "requests",
+        Counter,
+        &["class", "outcome"],
+        "Requests sent to the object store, by class and outcome (ok, error).",
+    ),
+    family(
+        "circuit_rejections",
+        Counter,
+        &[],
+        "Requests rejected without being sent because a circuit was open.",
+    ),
+    family(
+        "circuit_state",
+        Gauge,
+        &["class", "state"],
+        "1 for the state of the circuit of a class (closed, open, half-open), 0 for the others.",
+    ),
+    family(
+        "degraded_reads",
+        Counter,
+        &[],
+        "Reads served without registering as a reader because the write circuit was open.",
+    ),
+    family(
+        "reader_defers",
+        Counter,
+        &[],
+        "Times a reader backed off because a writer held or requested the lock.",
+    ),
+    family(
+        "busy",
+        Counter,
+        &["holder"],
+        "Lock waits given up, by whether this instance (local) or another (remote) held the lock.",
+    ),
+    family(
+        "lock_polls",
+        Counter,
+        &[],
+        "Looks at the metadata object while waiting for the lock, after the first.",
+    ),
+    family(
+        "wasted_lock_polls",
+        Counter,
+        &[],
+        "Lock polls that found the lock held as at the look before.",
+    ),
+    family(
+        "max_lock_polls",
+        Gauge,
+        &[],
+        "The most polls a single wait for the lock took.",
+    ),
+    family(
+        "writer_wait_seconds",
+        Gauge,
+        &["quantile"],
+        "Time from the first attempt of a writer to acquiring the lock, of recent writers.",
+    ),
+    family(
+        "transaction_phase_seconds",
+        Gauge,
+        &["phase", "quantile"],
+        "Time spent in each phase by recent transactions.",
+    ),
+    family(
+        "queue_wait_seconds",
+        Gauge,
+        &["class", "quantile"],
+        "Time recent reads waited for a permit, by I/O class (critical, bulk).",
+    ),
+    family(
+        "list_requests",
+        Counter,
+        &[],
+        "ListObjectsV2 requests sent.",
+    ),
+    family(
+        "keys_listed",
+        Counter,
+        &[],
+        "Keys returned by ListObjectsV2 requests.",
+    ),
+    family(
+        "notifications_received",
+        Counter,
+        &[],
+        "Events for the metadata object received, not counting duplicates.",
+    ),
+    family(
+        "notification_batches",
+        Counter,
+        &["result"],
+        "Batches of events after which the generation had changed (confirmed) or not (spurious).",
+    ),
+    family(
+        "chunk_retries",
+        Counter,
+        &[],
+        "Retries of single chunks of a parallel read.",
+    ),
+    family(
+        "hedges",
+        Counter,
+        &[],
+        "Duplicate GETs issued for slow chunks.",
+    ),
+    family(
+        "hedge_wins",
+        Counter,
+        &[],
+        "Chunks served by their duplicate GET.",
+    ),
+    family(
+        "soft_limit_warnings",
+        Counter,
+        &[],
+        "Transactions that crossed a soft limit.",
+    ),
+    family(
+        "degraded_transactions",
+        Counter,
+        &[],
+        "Transactions served from the offline mirror because the metadata object was unreadable.",
+    ),
+    family(
+        "page_size_changes",
+        Counter,
+        &[],
+        "Commits that changed the page size of a database.",
+    ),
+    family(
+        "schema_changes",
+        Counter,
+        &[],
+        "Schema changes committed by any writer.",
+    ),
+    family(
+        "written_bytes",
+        Counter,
+        &[],
+        "Bytes SQLite wrote to databases.",
+    ),
+    family(
+        "uploaded_bytes",
+        Counter,
+        &[],
+        "Bytes of database objects uploaded.",
+    ),
+    family(
+        "copied_bytes",
+        Counter,
+        &[],
+        "Bytes copied into the write buffer for the writes flushed.",
+    ),
+    family(
+        "absorbed_bytes",
+        Counter,
+        &[],
+        "Bytes SQLite wrote again while still buffered.",
+    ),
+    family(
+        "max_block_rewrites",
+        Gauge,
+        &[],
+        "The most times one block was written again within a transaction.",
+    ),
+    family(
+        "patched_pages",
+        Counter,
+        &[],
+        "Pages uploaded as the byte ranges that changed.",
+    ),
+    family(
+        "unflushed_commits",
+        Counter,
+        &[],
+        "Commits whose pages were still buffered at their commit point.",
+    ),
+    family(
+        "enforced_flushes",
+        Counter,
+        &[],
+        "Flushes of such pages at the commit point.",
+    ),
+    family(
+        "adopted_writes",
+        Counter,
+        &[],
+        "Conditional writes found to have landed although their response was lost.",
+    ),
+    family(
+        "write_verifications",
+        Counter,
+        &["method"],
+        "Uploads verified after the store acknowledged them, by method (checksum, ranged_get).",
+    ),
+    family(
+        "verification_failures",
+        Counter,
+        &[],
+        "Verifications that found an upload not stored as sent.",
+    ),
+    family(
+        "budget_warnings",
+        Counter,
+        &[],
+        "Soft limits of a session budget crossed.",
+    ),
+    family(
+        "budget_refusals",
+        Counter,
+        &[],
+        "Reads and writes refused because a session budget was exhausted.",
+    ),
+    family(
+        "cache_hits",
+        Counter,
+        &["segment"],
+        "Reads served by the page cache, by segment (probation, protected, prefetch, header).",
+    ),
+    family(
+        "cache_misses",
+        Counter,
+        &[],
+        "Reads of pages not in the page cache.",
+    ),
+    family(
+        "cache_bypassed",
+        Counter,
+        &[],
+        "Reads that bypassed the page cache.",
+    ),
+    family(
+        "cache_admissions",
+        Counter,
+        &["access"],
+        "Pages admitted to the page cache, by access (random, sequential, prefetch).",
+    ),
+    family(
+        "cache_promotions",
+        Counter,
+        &[],
+        "Pages moved to the protected segment on being read again.",
+    ),
+    family(
+        "cache_demotions",
+        Counter,
+        &[],
+        "Pages moved back to the probationary segment.",
+    ),
+    family(
+        "cache_evictions",
+        Counter,
+        &["cause"],
+        "Pages evicted from the page cache, by cause.",
+    ),
+    family(
+        "cache_size_bytes",
+        Gauge,
+        &["segment"],
+        "Bytes of pages in each segment of the page cache (probation, protected).",
+    ),
+    family(
+        "memory_used_bytes",
+        Gauge,
+        &["component"],
+        "Memory in use, by component.",
+    ),
+    family("memory_cap_bytes", Gauge, &[], "The memory cap, if any."),
+    family(
+        "memory_high_water_bytes",
+        Gauge,
+        &[],
+        "The most memory in use at once.",
+    ),
+    family(
+        "memory_denied",
+        Counter,
+        &[],
+        "Charges that failed because the memory cap was reached.",
+    ),
+    family(
+        "memory_reliefs",
+        Counter,
+        &["relief"],
+        "Times each way of freeing memory ran.",
+    ),
+    family(
+        "memory_relieved_bytes",
+        Counter,
+        &["relief"],
+        "Bytes each way of freeing memory freed.",
+    ),
+    family(
+        "timeouts",
+        Counter,
+        &["class", "cause"],
+        "Requests timed out, by timeout class and cause.",
+    ),
+    family("warm_pages", Counter, &[], "Pages of warm sets imported."),
+    family(
+        "warm_valid_pages",
+        Counter,
+        &[],
+        "Pages of warm sets still valid and admitted to the cache.",
+    ),
+    family(
+        "time_to_warm_seconds",
+        Gauge,
+        &["quantile"],
+        "Time recent imports of warm sets took.",
+    ),
+    family(
+        "credential_refreshes",
+        Counter,
+        &["cause"],
+        "Loads of credentials that succeeded, by cause (scheduled, forced).",
+    ),
+    family(
+        "credential_failures",
+        Gauge,
+        &[],
+        "Loads of credentials that failed since the last one that succeeded.",
+    ),
+    family(
+        "file_controls",
+        Counter,
+        &["op"],
+        "File controls sent by SQLite, by opcode, with strict file control.",
+    ),
+    family(
+        "operations",
+        Gauge,
+        &["kind", "state"],
+        "Maintenance operations running or finished recently, by kind and state.",
+    ),
+];
+
+/// The stats of the instance serving the database `db` in the OpenMetrics text format, see the
+/// [module documentation](self). `stats` is that `snapshot` was taken of.
+pub fn render(
+    db: &str,
+    snapshot: &StatsSnapshot,
+    stats: &Stats,
+    operations: &[ProgressSnapshot],
+) -> String {
+    let mut out = Exposition {
+        out: String::new(),
+        db: escape(db),
+    };
+    let ok = |success: bool| if success { "ok" } else { "error" };
+    out.family("requests");
+    for class in OpClass::ALL {
+        for success in [true, false] {
+            let labels = [
+                ("class", class.to_string()),
+                ("outcome", ok(success).into()),
+            ];
+            out.sample("requests", &labels, snapshot.outcomes(class, success));
+        }
+    }
+    out.counter("circuit_rejections", snapshot.circuit_rejections);
+    out.family("circuit_state");
+    let circuits = [
+        (OpClass::Read, snapshot.read_circuit),
+        (OpClass::Write, snapshot.write_circuit),
+    ];
+    for (class, current) in circuits {
+        for state in [
+            CircuitState::Closed,
+            CircuitState::Open,
+            CircuitState::HalfOpen,
+        ] {
+            let labels = [("class", class.to_string()), ("state", state.to_string())];
+            out.sample("circuit_state", &labels, (state == current) as u8);
+        }
+    }
+    out.counter("degraded_reads", snapshot.degraded_reads);
+    out.counter("reader_defers", snapshot.reader_defers);
+    out.family("busy");
+    out.sample("busy", &[("holder", "local".into())], snapshot.busy_local);
+    out.sample("busy", &[("holder", "remote".into())], snapshot.busy_remote);
+    out.counter("lock_polls", snapshot.lock_polls);
+    out.counter("wasted_lock_polls", snapshot.wasted_lock_polls);
+    out.gauge("max_lock_polls", snapshot.max_lock_polls);
+    out.family("writer_wait_seconds");
+    out.latency("writer_wait_seconds", &[], stats.writer_wait.summary());
+    out.family("transaction_phase_seconds");
+    for phase in Phase::ALL {
+        let labels = [("phase", phase.name().to_owned())];
+        out.latency(
+            "transaction_phase_seconds",
+            &labels,
+            stats.phase_latency(phase),
+        );
+    }
+    out.family("queue_wait_seconds");
+    for class in IoClass::ALL {
+        let labels = [("class", class.to_string())];
+        out.latency("queue_wait_seconds", &labels, stats.queue_wait(class));
+    }
+    out.counter("list_requests", snapshot.list_requests);
+    out.counter("keys_listed", snapshot.keys_listed);
+    out.counter("notifications_received", snapshot.notifications_received);
+    out.family("notification_batches");
+    let batches = [
+        ("confirmed", snapshot.notifications_confirmed),
+        ("spurious", snapshot.notifications_spurious),
+    ];
+    for (result, count) in batches {
+        out.sample("notification_batches", &[("result", result.into())], count);
+    }
+    out.counter("chunk_retries", snapshot.chunk_retries);
+    out.counter(
+        "hedges",
+        stats.hedges.load(std::sync::atomic::Ordering::Relaxed),
+    );
+    out.counter("hedge_wins", snapshot.hedge_wins);
+    out.counter("soft_limit_warnings", snapshot.soft_limit_warnings);
+    out.counter("degraded_transactions", snapshot.degraded_transactions);
+    out.counter("page_size_changes", snapshot.page_size_changes);
+    out.counter("schema_changes", snapshot.schema_changes);
+    out.counter("written_bytes", snapshot.bytes_written);
+    out.counter("uploaded_bytes", snapshot.bytes_uploaded);
+    out.counter("copied_bytes", snapshot.bytes_copied);
+    out.counter("absorbed_bytes", snapshot.bytes_absorbed);
+    out.gauge("max_block_rewrites", snapshot.max_block_rewrites);
+    out.counter("patched_pages", snapshot.patched_pages);
+    out.counter("unflushed_commits", snapshot.unflushed_commits);
+    out.counter("enforced_flushes", snapshot.enforced_flushes);
+    out.counter("adopted_writes", snapshot.adopted_writes);
+    out.family("write_verifications");
+    let verifications = [
+        ("checksum", snapshot.checksum_verifications),
+        ("ranged_get", snapshot.ranged_get_verifications),
+    ];
+    for (method, count) in verifications {
+        out.sample("write_verifications", &[("method", method.into())], count);
+    }
+    out.counter("verification_failures", snapshot.verification_failures);
+    out.counter("budget_warnings", snapshot.budget_warnings);
+    out.counter("budget_refusals", snapshot.budget_refusals);
+
+    let cache = &snapshot.cache;
+    out.family("cache_hits");
+    let hits = [
+        ("probation", cache.probation_hits),
+        ("protected", cache.protected_hits),
+        ("prefetch", cache.prefetch_hits),
+        ("header", cache.header_hits),
+    ];
+    for (segment, count) in hits {
+        out.sample("cache_hits", &[("segment", segment.into())], count);
+    }
+    out.counter("cache_misses", cache.misses);
+    out.counter("cache_bypassed", cache.bypassed);
+    out.family("cache_admissions");
+    // `admissions` counts sequential ones too
+    let admissions = [
+        ("random", cache.admissions - cache.sequential_admissions),
+        ("sequential", cache.sequential_admissions),
+        ("prefetch", cache.prefetched),
+    ];
+    for (access, count) in admissions {
+        out.sample("cache_admissions", &[("access", access.into())], count);
+    }
+    out.counter("cache_promotions", cache.promotions);
+    out.counter("cache_demotions", cache.demotions);
+    out.family("cache_evictions");
+    for cause in EvictionCause::ALL {
+        let labels = [("cause", cause.to_string())];
+        out.sample("cache_evictions", &labels, cache.evictions[cause as usize]);
+    }
+    out.family("cache_size_bytes");
+    let sizes = [
+        ("probation", cache.probation_bytes),
+        ("protected", cache.protected_bytes),
+    ];
+    for (segment, bytes) in sizes {
+        out.sample("cache_size_bytes", &[("segment", segment.into())], bytes);
+    }
+
+    let memory = &snapshot.memory;
+    out.family("memory_used_bytes");
+    for component in Component::ALL {
+        let labels = [("component", component.to_string())];
+        out.sample(
+            "memory_used_bytes",
+            &labels,
+            memory.used[component as usize],
+        );
+    }
+    out.family("memory_cap_bytes");
+    if let Some(cap) = memory.cap {
+        out.sample("memory_cap_bytes", &[], cap);
+    }
+    out.gauge("memory_high_water_bytes", memory.high_water);
+    out.counter("memory_denied", memory.denied);
+    out.family("memory_reliefs");
+    for relief in Relief::ALL {
+        let labels = [("relief", relief.to_string())];
+        out.sample("memory_reliefs", &labels, memory.reliefs[relief as usize].0);
+    }
+    out.family("memory_relieved_bytes");
+    for relief in Relief::ALL {
+        let labels = [("relief", relief.to_string())];
+        out.sample(
+            "memory_relieved_bytes",
+            &labels,
+            memory.reliefs[relief as usize].1,
+        );
+    }
+
+    out.family("timeouts");
+    for class in TimeoutClass::ALL {
+        for cause in TimeoutCause::ALL {
+            let labels = [("class", class.to_string()), ("cause", cause.to_string())];
+            out.sample("timeouts", &labels, snapshot.timeouts(class, cause));
+        }
+    }
+    out.counter("warm_pages", snapshot.warm_pages);
+    out.counter("warm_valid_pages", snapshot.warm_valid);
+    out.family("time_to_warm_seconds");
+    out.latency("time_to_warm_seconds", &[], snapshot.time_to_warm);
+
+    // only with a credentials provider
+    out.family("credential_refreshes");
+    out.family("credential_failures");
+    if let Some(credentials) = &snapshot.credentials {
+        let refreshes = [
+            (
+                "scheduled",
+                credentials.refreshes - credentials.forced_refreshes,
+            ),
+            ("forced", credentials.forced_refreshes),
+        ];
+        for (cause, count) in refreshes {
+            out.sample("credential_refreshes", &[("cause", cause.into())], count);
+        }
+        out.sample("credential_failures", &[], credentials.consecutive_failures);
+    }
+    out.family("file_controls");
+    for (op, count) in &snapshot.file_controls {
+        out.sample("file_controls", &[("op", op.clone())], *count);
+    }
+    out.family("operations");
+    let states = [
+        OperationState::Running,
+        OperationState::Cancelling,
+        OperationState::Cancelled,
+        OperationState::Succeeded,
+        OperationState::Failed,
+    ];
+    for kind in OperationKind::ALL {
+        for state in states {
+            let count = operations
+                .iter()
+                .filter(|op| op.kind == kind && op.state == state)
+                .count();
+            let labels = [("kind", kind.to_string()), ("state", state.to_string())];
+            out.sample("operations", &labels, count);
+        }
+    }
+    out.out.push_str("# EOF\n");
+    out.out
+}
+
+struct Exposition {
+    out: String,
+    /// Escaped.
+    db: String,
+}
+
+impl Exposition {
+    /// Start the family `name` of [FAMILIES].
+    fn family(&mut self, name: &str) {
+        let family = FAMILIES
+            .iter()
+            .find(|family| family.name == name)
+            .expect("listed in FAMILIES");
+        let name = format!("{PREFIX}{name}");
+        writeln!(self.out, "# TYPE {name} {}", family.kind).unwrap();
+        if let Some(unit) = family.unit {
+            writeln!(self.out, "# UNIT {name} {unit}").unwrap();
+        }
+        writeln!(self.out, "# HELP {name} {}", family.help).unwrap();
+    }
+
+    /// A sample of the family `name` started last.
+    fn sample(&mut self, name: &str, labels: &[(&str, String)], value: impl std::fmt::Display) {
+        let suffix = match FAMILIES.iter().find(|family| family.name == name) {
+            Some(family) if family.kind == Kind::Counter => "_total",
+            _ => "",
+        };
+        write!(self.out, "{PREFIX}{name}{suffix}{{db=\"{}\"", self.db).unwrap();
+        for (label, value) in labels {
+            write!(self.out, ",{label}=\"{}\"", escape(value)).unwrap();
+        }
+        writeln!(self.out, "}} {value}").unwrap();
+    }
+
+    fn counter(&mut self, name: &str, value: u64) {
+        self.family(name);
+        self.sample(name, &[], value);
+    }
+
+    fn gauge(&mut self, name: &str, value: u64) {
+        self.family(name);
+        self.sample(name, &[], value);
+    }
+
+    fn latency(&mut self, name: &str, labels: &[(&str, String)], summary: LatencySummary) {
+        let quantiles = [
+            ("0.5", summary.p50),
+            ("0.99", summary.p99),
+            ("1", summary.max),
+        ];
+        for (quantile, value) in quantiles {
+            let mut labels = labels.to_vec();
+            labels.push(("quantile", quantile.to_owned()));
+            self.sample(name, &labels, value.as_secs_f64());
+        }
+    }
+}
+
+/// `value` as a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        cache::{CacheConfig, CacheUse},
+        config::Config,
+        mock,
+        vfs::ThreeQLite,
+    };
+
+    /// Renaming a metric or changing its labels breaks dashboards.
+    #[test]
+    fn test_golden_families() {
+        let catalog = FAMILIES
+            .iter()
+            .map(|family| {
+                let labels = ["db"].iter().chain(family.labels).copied();
+                format!(
+                    "{PREFIX}{} {} {} {}",
+                    family.name,
+                    family.kind,
+                    family.unit.unwrap_or("-"),
+                    labels.collect::<Vec<_>>().join(",")
+                )
+            })
+            .collect::<Vec<_>>();
+        let golden = "
+        threeqlite_requests counter - db,class,outcome
+        threeqlite_circuit_rejections counter - db
+        threeqlite_circuit_state gauge - db,class,state
+        threeqlite_degraded_reads counter - db
+        threeqlite_reader_defers counter - db
+        threeqlite_busy counter - db,holder
+        threeqlite_lock_polls counter - db
+        threeqlite_wasted_lock_polls counter - db
+        threeqlite_max_lock_polls gauge - db
+        threeqlite_writer_wait_seconds gauge seconds db,quantile
+        threeqlite_transaction_phase_seconds gauge seconds db,phase,quantile
+        threeqlite_queue_wait_seconds gauge seconds db,class,quantile
+        threeqlite_list_requests counter - db
+        threeqlite_keys_listed counter - db
+        threeqlite_notifications_received counter - db
+        threeqlite_notification_batches counter - db,result
+        threeqlite_chunk_retries counter - db
+        threeqlite_hedges counter - db
+        threeqlite_hedge_wins counter - db
+        threeqlite_soft_limit_warnings counter - db
+        threeqlite_degraded_transactions counter - db
+        threeqlite_page_size_changes counter - db
+        threeqlite_schema_changes counter - db
+        threeqlite_written_bytes counter bytes db
+        threeqlite_uploaded_bytes counter bytes db
+        threeqlite_copied_bytes counter bytes db
+        threeqlite_absorbed_bytes counter bytes db
+        threeqlite_max_block_rewrites gauge - db
+        threeqlite_patched_pages counter - db
+        threeqlite_unflushed_commits counter - db
+        threeqlite_enforced_flushes counter - db
+        threeqlite_adopted_writes counter - db
+        threeqlite_write_verifications counter - db,method
+        threeqlite_verification_failures counter - db
+        threeqlite_budget_warnings counter - db
+        threeqlite_budget_refusals counter - db
+        threeqlite_cache_hits counter - db,segment
+        threeqlite_cache_misses counter - db
+        threeqlite_cache_bypassed counter - db
+        threeqlite_cache_admissions counter - db,access
+        threeqlite_cache_promotions counter - db
+        threeqlite_cache_demotions counter - db
+        threeqlite_cache_evictions counter - db,cause
+        threeqlite_cache_size_bytes gauge bytes db,segment
+        threeqlite_memory_used_bytes gauge bytes db,component
+        threeqlite_memory_cap_bytes gauge bytes db
+        threeqlite_memory_high_water_bytes gauge bytes db
+        threeqlite_memory_denied counter - db
+        threeqlite_memory_reliefs counter - db,relief
+        threeqlite_memory_relieved_bytes counter bytes db,relief
+        threeqlite_timeouts counter - db,class,cause
+        threeqlite_warm_pages counter - db
+        threeqlite_warm_valid_pages counter - db
+        threeqlite_time_to_warm_seconds gauge seconds db,quantile
+        threeqlite_credential_refreshes counter - db,cause
+        threeqlite_credential_failures gauge - db
+        threeqlite_file_controls counter - db,op
+        threeqlite_operations gauge - db,kind,state
+        ";
+        let golden = golden
+            .split('\n')
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
+        assert_eq!(catalog, golden.collect::<Vec<_>>());
+    }
+
+    type Series = (String, Vec<(String, String)>);
+
+    /// The samples of `text`, checking that it is valid OpenMetrics.
+    fn parse(text: &str) -> HashMap<Series, f64> {
+        let valid_name = |name: &str| {
+            name.chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        let (body, rest) = text.split_once("# EOF\n").expect("ends with # EOF");
+        assert_eq!(rest, "");
+        let mut families = HashSet::new();
+        let mut family: Option<(String, String)> = None;
+        let mut samples = HashMap::new();
+        for line in body.lines() {
+            if let Some(meta) = line.strip_prefix("# ") {
+                let mut parts = meta.splitn(3, ' ');
+                let (keyword, name, value) = (
+                    parts.next().unwrap(),
+                    parts.next().unwrap(),
+                    parts.next().unwrap(),
+                );
+                assert!(valid_name(name), "{line}");
+                match keyword {
+                    "TYPE" => {
+                        assert!(families.insert(name.to_owned()), "{name} twice");
+                        assert!(["counter", "gauge"].contains(&value), "{line}");
+                        family = Some((name.to_owned(), value.to_owned()));
+                    }
+                    "UNIT" | "HELP" => {
+                        let (current, _) = family.as_ref().expect("TYPE first");
+                        assert_eq!(current, name, "{line}");
+                        if keyword == "UNIT" {
+                            assert!(name.ends_with(&format!("_{value}")), "{line}");
+                        }
+                    }
+                    _ => panic!("{line}"),
+                }
+                continue;
+            }
+            let (name, rest) = line.split_once('{').expect("labelled");
+            let (labels, value) = rest.rsplit_once("} ").expect("a value");
+            let (current, kind) = family.as_ref().expect("a family first");
+            match kind.as_str() {
+                "counter" => assert_eq!(name, format!("{current}_total")),
+                _ => assert_eq!(name, current),
+            }
+            let mut parsed = vec![];
+            let mut labels = labels;
+            while !labels.is_empty() {
+                let (label, rest) = labels.split_once("=\"").expect("label=\"value\"");
+                assert!(valid_name(label), "{line}");
+                // the closing quote is the first one not escaped
+                let mut end = None;
+                let mut escaped = false;
+                for (i, c) in rest.char_indices() {
+                    match (escaped, c) {
+                        (false, '\\') => escaped = true,
+                        (false, '"') => {
+                            end = Some(i);
+                            break;
+                        }
+                        _ => escaped = false,
+                    }
+                }
+                let end = end.expect("closed");
+                assert!(parsed.iter().all(|(seen, _)| seen != label), "{line}");
+                parsed.push((label.to_owned(), rest[..end].to_owned()));
+                labels = rest[end + 1..]
+                    .strip_prefix(',')
+                    .unwrap_or(&rest[end + 1..]);
+            }
+            let value: f64 = value.parse().expect("a number");
+            assert!(value >= 0.0, "{line}");
+            let series = (name.to_owned(), parsed);
+            assert!(samples.insert(series, value).is_none(), "{line} twice");
+        }
+        samples
+    }
+
+    #[tokio::test]
+    async fn test_canonical_workload() {
+        let mock = mock::MockS3::start();
+        mock.put("test.db", mock::database(4096, 4, 1));
+        let config = Config {
+            cache: CacheConfig {
+                capacity: 1024 * 1024,
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        {
+            let inner = tq.inner.read().await;
+            for _ in 0..2 {
+                inner
+                    .read_at(4096, 4096, Some(1), CacheUse::Admit)
+                    .await
+                    .unwrap();
+            }
+            mock.reject("GET", 503);
+            let failed = inner.read_at(8192, 4096, Some(1), CacheUse::Admit).await;
+            assert!(failed.is_err());
+            mock.restore("GET");
+            inner.stats.writer_wait.record(Duration::from_millis(250));
+        }
+
+        let text = tq.render_prometheus().await;
+        let samples = parse(&text);
+        let value = |name: &str, labels: &[(&str, &str)]| {
+            let mut series = vec![("db".to_owned(), "test.db".to_owned())];
+            series.extend(labels.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+            *samples
+                .get(&(name.to_owned(), series))
+                .unwrap_or_else(|| panic!("{name} {labels:?} missing from\n{text}"))
+        };
+        let requests = |class, outcome| {
+            value(
+                "threeqlite_requests_total",
+                &[("class", class), ("outcome", outcome)],
+            )
+        };
+        assert_eq!(requests("read", "ok"), 1.0);
+        assert_eq!(requests("read", "error"), 1.0);
+        assert_eq!(requests("write", "ok"), 0.0);
+        let hits = value("threeqlite_cache_hits_total", &[("segment", "probation")]);
+        assert_eq!(hits, 1.0);
+        assert_eq!(value("threeqlite_cache_misses_total", &[]), 2.0);
+        // read again, the page moved to the protected segment
+        let size = value("threeqlite_cache_size_bytes", &[("segment", "protected")]);
+        assert_eq!(size, 4096.0);
+        let circuit = |state| {
+            value(
+                "threeqlite_circuit_state",
+                &[("class", "read"), ("state", state)],
+            )
+        };
+        assert_eq!((circuit("closed"), circuit("open")), (1.0, 0.0));
+        let wait = value("threeqlite_writer_wait_seconds", &[("quantile", "0.99")]);
+        assert_eq!(wait, 0.25);
+        let running = value(
+            "threeqlite_operations",
+            &[("kind", "copy"), ("state", "running")],
+        );
+        assert_eq!(running, 0.0);
+        // every family is rendered, whether it has samples or not
+        for family in FAMILIES {
+            assert!(text.contains(&format!("# TYPE {PREFIX}{} ", family.name)));
+        }
+        // no object keys as labels
+        assert!(samples
+            .keys()
+            .flat_map(|(_, labels)| labels)
+            .all(|(label, value)| label == "db" || !value.contains(".db")));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
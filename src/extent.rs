@@ -0,0 +1,604 @@
+//! Block manifests of large databases, split into extents.
+//!
+//! The [BlockManifest] of a database of many GiB holds tens of thousands of checksums, so
+//! rewriting and validating it as a whole gets expensive. Beyond
+//! [ManifestConfig::inline_blocks] blocks, [publish] stores the checksums in extent objects of
+//! [ManifestConfig::extent_blocks] blocks each at [KeyLayout::manifest_extent], referenced by a
+//! small [ManifestRoot] at [KeyLayout::manifest] holding the length and MD5 of the database object
+//! and the MD5 of each extent:
+//! - a publish only rewrites the extents whose checksums changed, then the root, conditional on
+//!   the root it replaces, so that the root stays the single point a publish commits at. The
+//!   extents no longer referenced are deleted afterwards,
+//! - readers validate the root alone, and fetch extents as they need them, verified against the
+//!   root and kept in an [ExtentCache] by their MD5.
+//!
+//! A manifest switches between the inline and extent forms whenever a publish crosses the
+//! threshold. The root carries a [format header](crate::format), the inline form doesn't, as
+//! before. Readers predating extents fail to parse a root and fall back to fetching every block,
+//! like without a manifest. A reader holding an older root may find an extent rewritten by a
+//! newer publish, which fails its MD5 check and costs the same fallback.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    circuit::OpClass,
+    error::Error,
+    format::{self, Bounded},
+    idempotency,
+    key::{KeyLayout, ObjectKey},
+    mirror::BlockManifest,
+    priority::IoClass,
+    verify::{Upload, WriteClass},
+    vfs::{status, Inner},
+};
+
+/// The most blocks an extent may cover.
+pub const MAX_EXTENT_BLOCKS: usize = 1 << 16;
+
+/// Extents an [ExtentCache] keeps.
+const CACHED_EXTENTS: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ManifestConfig {
+    /// Manifests of more blocks are split into extents.
+    pub inline_blocks: usize,
+    /// Blocks per extent, at most [MAX_EXTENT_BLOCKS].
+    pub extent_blocks: usize,
+}
+
+impl Default for ManifestConfig {
+    fn default() -> Self {
+        // 256 MiB of 64 KiB blocks, about 150 KiB of checksums
+        Self {
+            inline_blocks: 4096,
+            extent_blocks: 1024,
+        }
+    }
+}
+
+/// A manifest split into extents, stored at [KeyLayout::manifest].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestRoot {
+    pub block_size: u64,
+    pub len: u64,
+    /// Hex-encoded MD5 of the whole object, see [BlockManifest::md5].
+    pub md5: String,
+    pub extent_blocks: u64,
+    /// Hex-encoded MD5 of the encoding of each extent.
+    pub extents: Vec<String>,
+}
+
+impl ManifestRoot {
+    pub fn blocks(&self) -> u64 {
+        self.len.div_ceil(self.block_size)
+    }
+
+    /// The key of extent `n` of the manifest of `db`.
+    pub fn extent_key(&self, db: &ObjectKey, n: usize) -> ObjectKey {
+        let first = n as u64 * self.extent_blocks;
+        let end = (first + self.extent_blocks).min(self.blocks());
+        KeyLayout::manifest_extent(db, first, end)
+    }
+}
+
+/// 1 MiB of extent checksums cover databases of about 1.5 TiB at the default extent size.
+impl Bounded for ManifestRoot {
+    const MAX_LEN: u64 = 1 << 20;
+
+    fn validate(&self) -> Result<(), String> {
+        if self.block_size == 0 {
+            return Err("block size of 0".to_owned());
+        }
+        if !(1..=MAX_EXTENT_BLOCKS as u64).contains(&self.extent_blocks) {
+            return Err(format!("extents of {} blocks", self.extent_blocks));
+        }
+        let expected = self.blocks().div_ceil(self.extent_blocks);
+        if self.extents.len() as u64 != expected {
+            return Err(format!(
+                "{} extents, but {expected} cover {} bytes",
+                self.extents.len(),
+                self.len
+            ));
+        }
+        format::check_md5(&self.md5)?;
+        self.extents
+            .iter()
+            .try_for_each(|md5| format::check_md5(md5))
+    }
+}
+
+/// The checksums of a range of blocks, stored at [KeyLayout::manifest_extent].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestExtent {
+    /// The index of the first block.
+    pub first: u64,
+    /// Hex-encoded MD5 of each block.
+    pub blocks: Vec<String>,
+}
+
+impl Bounded for ManifestExtent {
+    const MAX_LEN: u64 = 4 << 20;
+
+    fn validate(&self) -> Result<(), String> {
+        format::check_count("blocks", &self.blocks, MAX_EXTENT_BLOCKS)?;
+        self.blocks
+            .iter()
+            .try_for_each(|md5| format::check_md5(md5))
+    }
+}
+
+/// A block manifest in either form.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Manifest {
+    Inline(BlockManifest),
+    Extents(ManifestRoot),
+}
+
+impl Manifest {
+    /// Parse the object at [KeyLayout::manifest]. Returns why it is invalid otherwise.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.starts_with(&format::MAGIC) {
+            return format::parse(bytes).map(Manifest::Inline);
+        }
+        let (_, body) = format::decode_header(bytes).map_err(|err| err.to_string())?;
+        format::parse(body).map(Manifest::Extents)
+    }
+
+    pub fn block_size(&self) -> u64 {
+        match self {
+            Manifest::Inline(manifest) => manifest.block_size,
+            Manifest::Extents(root) => root.block_size,
+        }
+    }
+
+    pub fn object_len(&self) -> u64 {
+        match self {
+            Manifest::Inline(manifest) => manifest.len,
+            Manifest::Extents(root) => root.len,
+        }
+    }
+
+    pub fn md5(&self) -> &str {
+        match self {
+            Manifest::Inline(manifest) => &manifest.md5,
+            Manifest::Extents(root) => &root.md5,
+        }
+    }
+
+    /// The checksum of block `i` of the database object `db`, fetching its extent unless
+    /// cached.
+    pub async fn block(&self, inner: &Inner, db: &ObjectKey, i: usize) -> Result<String, Error> {
+        match self {
+            Manifest::Inline(manifest) => Ok(manifest.blocks[i].clone()),
+            Manifest::Extents(root) => {
+                let extent = extent(inner, db, root, i / root.extent_blocks as usize).await?;
+                Ok(extent.blocks[i - extent.first as usize].clone())
+            }
+        }
+    }
+}
+
+/// Extents fetched by any reader of an instance, by their MD5. Keeps the [CACHED_EXTENTS] last
+/// fetched.
+#[derive(Debug, Default)]
+pub struct ExtentCache {
+    cached: Mutex<Cached>,
+}
+
+#[derive(Debug, Default)]
+struct Cached {
+    extents: HashMap<String, Arc<ManifestExtent>>,
+    /// The MD5s of `extents`, oldest first.
+    order: VecDeque<String>,
+}
+
+impl ExtentCache {
+    pub fn get(&self, md5: &str) -> Option<Arc<ManifestExtent>> {
+        self.cached.lock().unwrap().extents.get(md5).cloned()
+    }
+
+    pub fn insert(&self, md5: String, extent: Arc<ManifestExtent>) {
+        let mut cached = self.cached.lock().unwrap();
+        if cached.extents.insert(md5.clone(), extent).is_none() {
+            cached.order.push_back(md5);
+        }
+        while cached.order.len() > CACHED_EXTENTS {
+            let oldest = cached.order.pop_front().unwrap();
+            cached.extents.remove(&oldest);
+        }
+    }
+}
+
+/// Extent `n` of `root`, the manifest of `db`.
+async fn extent(
+    inner: &Inner,
+    db: &ObjectKey,
+    root: &ManifestRoot,
+    n: usize,
+) -> Result<Arc<ManifestExtent>, Error> {
+    let md5 = &root.extents[n];
+    if let Some(extent) = inner.extents.get(md5) {
+        return Ok(extent);
+    }
+    let key = root.extent_key(db, n);
+    let corrupt = |reason| Error::CorruptManifest {
+        key: key.to_string(),
+        reason,
+    };
+    let _permit = inner.permit(IoClass::Bulk).await;
+    let obj = inner
+        .s3
+        .get_object()
+        .bucket(&inner.bucket)
+        .key(&key)
+        .send()
+        .await;
+    inner.record(OpClass::Read, obj.is_ok());
+    let obj = obj?;
+    format::check_len::<ManifestExtent>(obj.content_length().unwrap_or(0) as u64)
+        .map_err(corrupt)?;
+    let bytes = obj
+        .body
+        .collect()
+        .await
+        .map_err(|err| Error::Whatever {
+            message: format!("failed to read object body: {err}"),
+            source: None,
+        })?
+        .into_bytes();
+    // rewritten since the root was read, or damaged
+    if format!("{:x}", md5::compute(&bytes)) != *md5 {
+        return Err(corrupt("MD5 differs from the root".to_owned()));
+    }
+    let extent: ManifestExtent = format::parse(&bytes).map_err(corrupt)?;
+    let first = n as u64 * root.extent_blocks;
+    let expected = (root.blocks() - first).min(root.extent_blocks);
+    if extent.first != first || extent.blocks.len() as u64 != expected {
+        return Err(corrupt(format!(
+            "blocks {}+{} instead of {first}+{expected}",
+            extent.first,
+            extent.blocks.len()
+        )));
+    }
+    let extent = Arc::new(extent);
+    inner.extents.insert(md5.clone(), extent.clone());
+    Ok(extent)
+}
+
+/// The manifest of `db`, `None` if it doesn't exist or is unreadable, and the ETag of the object
+/// if it exists.
+pub async fn read(
+    inner: &Inner,
+    db: &ObjectKey,
+) -> Result<(Option<Manifest>, Option<String>), Error> {
+    let key = KeyLayout::manifest(db);
+    let _permit = inner.permit(IoClass::Bulk).await;
+    let obj = inner
+        .s3
+        .get_object()
+        .bucket(&inner.bucket)
+        .key(&key)
+        .send()
+        .await;
+    inner.record(OpClass::Read, obj.is_ok());
+    let obj = match obj {
+        Ok(obj) => obj,
+        Err(err) if status(&err) == Some(404) => return Ok((None, None)),
+        Err(err) => return Err(err.into()),
+    };
+    let etag = obj.e_tag.clone();
+    let parsed = match format::check_len::<BlockManifest>(obj.content_length().unwrap_or(0) as u64)
+    {
+        Ok(()) => {
+            let bytes = obj.body.collect().await.map_err(|err| Error::Whatever {
+                message: format!("failed to read object body: {err}"),
+                source: None,
+            })?;
+            Manifest::decode(&bytes.into_bytes())
+        }
+        Err(reason) => Err(reason),
+    };
+    // an unreadable manifest only costs a full fetch
+    let manifest = parsed
+        .inspect_err(|reason| {
+            let err = Error::CorruptManifest {
+                key: key.to_string(),
+                reason: reason.clone(),
+            };
+            tracing::warn!(target: "threeqlite::s3", %err, "ignoring manifest");
+        })
+        .ok();
+    Ok((manifest, etag))
+}
+
+async fn put(inner: &Inner, key: &ObjectKey, bytes: Vec<u8>) -> Result<(), Error> {
+    let upload = Upload::verified(key, &bytes, WriteClass::Manifests, &inner.verify_writes);
+    let put = inner
+        .s3
+        .put_object()
+        .bucket(&inner.bucket)
+        .key(key)
+        .body(bytes.into());
+    let res = upload.send_checksum(put).send().await;
+    inner.record(OpClass::Write, res.is_ok());
+    inner.verify_put(&upload, &res?).await
+}
+
+/// Publish `manifest` as the manifest of `db`, in the form its size calls for, see the
+/// [module documentation](self).
+pub async fn publish(inner: &Inner, db: &ObjectKey, manifest: &BlockManifest) -> Result<(), Error> {
+    let config = inner.manifest_config;
+    let key = KeyLayout::manifest(db);
+    let (previous, etag) = read(inner, db).await?;
+    let previous = match previous {
+        Some(Manifest::Extents(root)) => Some(root),
+        _ => None,
+    };
+    let old_keys: Vec<_> = previous
+        .iter()
+        .flat_map(|root| (0..root.extents.len()).map(|n| root.extent_key(db, n)))
+        .collect();
+
+    let encode_err = |err: bincode::Error| Error::Whatever {
+        message: format!("failed to encode block manifest: {err}"),
+        source: Some(err),
+    };
+    let (bytes, new_keys) = match manifest.blocks.len() > config.inline_blocks {
+        false => (bincode::serialize(manifest).map_err(encode_err)?, vec![]),
+        true => {
+            let mut root = ManifestRoot {
+                block_size: manifest.block_size,
+                len: manifest.len,
+                md5: manifest.md5.clone(),
+                extent_blocks: config.extent_blocks.clamp(1, MAX_EXTENT_BLOCKS) as u64,
+                extents: vec![],
+            };
+            let mut new_keys = vec![];
+            for (n, blocks) in manifest
+                .blocks
+                .chunks(root.extent_blocks as usize)
+                .enumerate()
+            {
+                let extent = ManifestExtent {
+                    first: n as u64 * root.extent_blocks,
+                    blocks: blocks.to_vec(),
+                };
+                let bytes = bincode::serialize(&extent).map_err(encode_err)?;
+                let md5 = format!("{:x}", md5::compute(&bytes));
+                let key = root.extent_key(db, n);
+                let unchanged = previous.as_ref().is_some_and(|old| {
+                    old.extent_blocks == root.extent_blocks
+                        && old.extents.get(n) == Some(&md5)
+                        && old.extent_key(db, n) == key
+                });
+                if !unchanged {
+                    put(inner, &key, bytes).await?;
+                }
+                root.extents.push(md5);
+                new_keys.push(key);
+            }
+            let version = *format::WRITE_VERSIONS.end();
+            (format::encode(version, &root)?, new_keys)
+        }
+    };
+
+    format::check_single_put(key.as_str(), &bytes)?;
+    let upload = Upload::verified(&key, &bytes, WriteClass::Manifests, &inner.verify_writes);
+    let put = inner
+        .s3
+        .put_object()
+        .bucket(&inner.bucket)
+        .key(&key)
+        .body(bytes.into());
+    let put = match etag {
+        Some(etag) => put.if_match(etag),
+        None => put.if_none_match("*"),
+    };
+    let res = upload.send_checksum(put).send().await;
+    inner.record(OpClass::Write, res.is_ok());
+    match res {
+        Ok(out) => inner.verify_put(&upload, &out).await?,
+        // the response to this or an earlier attempt may have been lost, see [crate::idempotency]
+        Err(err) if !idempotency::adopt_upload(inner, &upload).await? => {
+            if status(&err) == Some(412) {
+                return Err(Error::Whatever {
+                    message: format!("block manifest of {db} was replaced concurrently"),
+                    source: None,
+                });
+            }
+            return Err(err.into());
+        }
+        Err(_) => {}
+    }
+
+    for key in old_keys.iter().filter(|key| !new_keys.contains(key)) {
+        let res = inner
+            .s3
+            .delete_object()
+            .bucket(&inner.bucket)
+            .key(key)
+            .send()
+            .await;
+        inner.record(OpClass::Write, res.is_ok());
+        if let Err(err) = res {
+            let err = Error::from(err);
+            tracing::warn!(target: "threeqlite::s3", %key, %err, "deleting manifest extent failed");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, mock::MockS3, vfs::ThreeQLite};
+
+    const BLOCK: u64 = crate::mirror::BLOCK_SIZE;
+
+    fn test_db() -> ObjectKey {
+        KeyLayout::db("test.db").unwrap()
+    }
+
+    /// A manifest of `blocks` blocks with synthetic checksums, `seed` varying their content.
+    fn synthetic(blocks: u64, seed: u64) -> BlockManifest {
+        BlockManifest {
+            block_size: BLOCK,
+            len: blocks * BLOCK,
+            md5: format!("{:x}", md5::compute(format!("{blocks}/{seed}"))),
+            blocks: (0..blocks)
+                .map(|i| format!("{:x}", md5::compute(format!("{i}/{}", seed + i))))
+                .collect(),
+        }
+    }
+
+    fn puts(mock: &MockS3) -> Vec<String> {
+        mock.requests()
+            .into_iter()
+            .filter(|(method, _)| method == "PUT")
+            .map(|(_, key)| key)
+            .collect()
+    }
+
+    async fn resolve(tq: &ThreeQLite) -> (Manifest, Vec<String>) {
+        let inner = tq.inner.read().await;
+        let manifest = read(&inner, &test_db()).await.unwrap().0.unwrap();
+        let mut blocks = vec![];
+        for i in 0..manifest.object_len().div_ceil(manifest.block_size()) as usize {
+            blocks.push(manifest.block(&inner, &test_db(), i).await.unwrap());
+        }
+        (manifest, blocks)
+    }
+
+    /// Changing this encoding makes roots unreadable to the readers of earlier versions.
+    #[test]
+    fn test_golden_root() {
+        let root = ManifestRoot {
+            block_size: 65536,
+            len: 65537,
+            md5: "0".repeat(32),
+            extent_blocks: 1,
+            extents: vec!["1".repeat(32), "2".repeat(32)],
+        };
+        let bytes = format::encode(2, &root).unwrap();
+        let string = |s: String| [&(s.len() as u64).to_le_bytes()[..], s.as_bytes()].concat();
+        let expected = [
+            &format::MAGIC[..],
+            // the format header, version 2
+            &2u32.to_le_bytes(),
+            &2u32.to_le_bytes(),
+            &65536u64.to_le_bytes(),
+            &65537u64.to_le_bytes(),
+            &string("0".repeat(32)),
+            &1u64.to_le_bytes(),
+            &2u64.to_le_bytes(),
+            &string("1".repeat(32)),
+            &string("2".repeat(32)),
+        ]
+        .concat();
+        assert_eq!(bytes, expected);
+        assert_eq!(Manifest::decode(&bytes), Ok(Manifest::Extents(root)));
+        // and inline manifests stay without a header
+        let inline = BlockManifest::new(&[7; 10]);
+        assert_eq!(
+            Manifest::decode(&bincode::serialize(&inline).unwrap()),
+            Ok(Manifest::Inline(inline))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extents() {
+        let mock = MockS3::start();
+        let config = Config {
+            manifest: ManifestConfig {
+                inline_blocks: 100,
+                extent_blocks: 32,
+            },
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config.clone(), mock.client()).unwrap();
+        let publish = |manifest: BlockManifest| {
+            let tq = tq.clone();
+            async move {
+                let inner = tq.inner.read().await;
+                publish(&inner, &test_db(), &manifest).await.unwrap();
+            }
+        };
+
+        // inline up to the threshold
+        let small = synthetic(100, 0);
+        publish(small.clone()).await;
+        assert_eq!(puts(&mock), ["test.db.blocks"]);
+        let (manifest, blocks) = resolve(&tq).await;
+        assert_eq!(manifest, Manifest::Inline(small.clone()));
+        assert_eq!(blocks, small.blocks);
+
+        // past it, 4 extents of 32 blocks and one of 2
+        let mut large = synthetic(130, 0);
+        publish(large.clone()).await;
+        let extent = |first, end| format!("test.db.blocks/extents/{first:010}-{end:010}");
+        assert_eq!(puts(&mock).len(), 1 + 5 + 1);
+        assert!(puts(&mock).contains(&extent(128, 130)));
+        let (manifest, blocks) = resolve(&tq).await;
+        let Manifest::Extents(root) = &manifest else {
+            panic!("{manifest:?}")
+        };
+        assert_eq!((root.len, &root.md5), (large.len, &large.md5));
+        assert_eq!(blocks, large.blocks);
+
+        // a commit changing blocks 40 and 41 rewrites their extent and the root
+        let before = puts(&mock).len();
+        large.blocks[40] = format!("{:x}", md5::compute("changed"));
+        large.blocks[41] = format!("{:x}", md5::compute("changed too"));
+        large.md5 = format!("{:x}", md5::compute("130 changed"));
+        publish(large.clone()).await;
+        assert_eq!(
+            puts(&mock)[before..],
+            [extent(32, 64), "test.db.blocks".to_owned()]
+        );
+
+        // reads resolve through the cache, fetching only the rewritten extent again
+        let gets = |mock: &MockS3| {
+            mock.requests()
+                .into_iter()
+                .filter(|(method, key)| method == "GET" && key.contains("/extents/"))
+                .count()
+        };
+        let before = gets(&mock);
+        let (_, blocks) = resolve(&tq).await;
+        assert_eq!(blocks, large.blocks);
+        assert_eq!(gets(&mock), before + 1);
+        resolve(&tq).await;
+        assert_eq!(gets(&mock), before + 1);
+
+        // an extent rewritten behind the root fails its check rather than serving wrong
+        // checksums to an instance that didn't cache it
+        mock.put(&extent(96, 128), mock.get(&extent(0, 32)).unwrap());
+        let other = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let inner = other.inner.read().await;
+        let manifest = read(&inner, &test_db()).await.unwrap().0.unwrap();
+        let err = manifest.block(&inner, &test_db(), 100).await.unwrap_err();
+        assert!(matches!(err, Error::CorruptManifest { .. }), "{err}");
+        assert_eq!(
+            manifest.block(&inner, &test_db(), 129).await.unwrap(),
+            large.blocks[129]
+        );
+        drop(inner);
+
+        // shrinking below the threshold goes back inline, deleting the extents
+        let small = synthetic(60, 7);
+        publish(small.clone()).await;
+        let (manifest, blocks) = resolve(&tq).await;
+        assert_eq!(manifest, Manifest::Inline(small.clone()));
+        assert_eq!(blocks, small.blocks);
+        assert!((0..5).all(|n| mock.get(&extent(n * 32, (n * 32 + 32).min(130))).is_none()));
+
+        // and past it again, with every entry preserved
+        publish(large.clone()).await;
+        assert_eq!(resolve(&tq).await.1, large.blocks);
+    }
+}
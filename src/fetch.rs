@@ -0,0 +1,613 @@
+//! Parallel ranged reads that survive throttling.
+//!
+//! A read spanning several chunks issues one ranged GET per chunk, [FetchConfig::concurrency] at
+//! a time. Under a throttling storm (`503 SlowDown`) a single chunk failing must not fail the
+//! whole read: every chunk has its own retry budget and per-attempt timeout, failed chunks are
+//! retried with escalating backoff while completed ones are kept, and the read only fails once a
+//! chunk runs out of attempts, an error can't be retried, or [FetchConfig::deadline] passes.
+//!
+//! With [FetchConfig::hedge] set, the slowest outstanding chunk gets a duplicate GET once it has
+//! taken longer than most completed chunks did. Whichever attempt finishes first is used and the
+//! other one is cancelled.
+//!
+//! Every attempt waits for a permit of the [IoClass] of the read, see [crate::priority]. The
+//! ranges of a bulk read are split into parts of at most `bulk_part_size` first. Attempts are
+//! bounded by the total timeout of the [TimeoutClass](crate::timeouts::TimeoutClass) of the read and sent with a client of its
+//! timeouts, see [crate::timeouts].
+
+use std::{ops::Range, sync::Arc, time::Duration};
+
+use tokio::{
+    task::{AbortHandle, JoinSet},
+    time::Instant,
+};
+
+use crate::{
+    circuit::OpClass,
+    credentials,
+    error::Error,
+    key::ObjectKey,
+    priority::IoClass,
+    spend::Dimension,
+    stats::Stats,
+    timeouts::TimeoutCause,
+    vfs::{status, Inner},
+};
+
+#[derive(Clone, Debug)]
+pub struct FetchConfig {
+    /// Reads larger than this are split into chunks of this size. Unrelated to the growth quantum
+    /// of `SQLITE_FCNTL_CHUNK_SIZE`, see [sqlite_vfs::DatabaseHandle::set_chunk_size].
+    pub chunk_size: u64,
+    /// Chunks in flight at once.
+    pub concurrency: usize,
+    /// Attempts per chunk, including the first.
+    pub chunk_attempts: u32,
+    /// Give up on an attempt after this long and retry the chunk. The total timeout of page reads
+    /// unless [crate::config::Config::timeouts] is set, see [crate::timeouts].
+    pub attempt_timeout: Duration,
+    /// Backoff before the first retry of a chunk, doubled with every further retry.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Fail the read after this long.
+    pub deadline: Duration,
+    /// Hedge slow chunks, off by default.
+    pub hedge: Option<HedgeConfig>,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1024 * 1024,
+            concurrency: 16,
+            chunk_attempts: 8,
+            attempt_timeout: Duration::from_secs(10),
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            deadline: Duration::from_secs(30),
+            hedge: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HedgeConfig {
+    /// Hedge the slowest outstanding chunk once it has taken longer than this percentile of the
+    /// completed chunks of the same read.
+    pub percentile: f64,
+    /// Never hedge before this long.
+    pub min_delay: Duration,
+}
+
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        Self {
+            percentile: 0.95,
+            min_delay: Duration::from_millis(20),
+        }
+    }
+}
+
+/// How a read went.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FetchReport {
+    /// Retries per chunk.
+    pub retries: Vec<u32>,
+    /// Duplicate GETs issued.
+    pub hedges: u64,
+    /// Chunks served by their duplicate GET.
+    pub hedge_wins: u64,
+}
+
+/// Split `len` bytes at `offset` into chunks of at most `chunk_size` bytes.
+pub fn chunks(offset: u64, len: u64, chunk_size: u64) -> Vec<Range<u64>> {
+    (offset..offset + len)
+        .step_by(chunk_size as usize)
+        .map(|start| start..(start + chunk_size).min(offset + len))
+        .collect()
+}
+
+/// The backoff before `retry` (starting at 1).
+fn backoff(config: &FetchConfig, retry: u32) -> Duration {
+    config
+        .backoff
+        .saturating_mul(1 << (retry - 1).min(16))
+        .min(config.max_backoff)
+}
+
+/// The delay after which to hedge, given the durations of the completed chunks.
+fn hedge_delay(hedge: &HedgeConfig, completed: &[Duration]) -> Option<Duration> {
+    let mut completed = completed.to_vec();
+    completed.sort();
+    let at = ((completed.len().checked_sub(1)?) as f64 * hedge.percentile).round() as usize;
+    Some(completed[at].max(hedge.min_delay))
+}
+
+struct Chunk {
+    data: Option<Vec<u8>>,
+    attempts: u32,
+    retries: u32,
+    in_flight: Vec<AbortHandle>,
+    /// When the oldest attempt in flight started.
+    started: Option<Instant>,
+    hedged: bool,
+    /// When the next retry may start, while backing off.
+    retry_at: Option<Instant>,
+}
+
+struct Attempt {
+    chunk: usize,
+    hedge: bool,
+    elapsed: Duration,
+    result: Result<Vec<u8>, (Error, bool)>,
+}
+
+/// Read `ranges` of `key` with the priority of `class`, failing if the object no longer matches
+/// `if_match`.
+pub async fn fetch(
+    inner: &Inner,
+    key: &ObjectKey,
+    if_match: Option<&str>,
+    ranges: &[Range<u64>],
+    class: IoClass,
+) -> Result<(Vec<Vec<u8>>, FetchReport), Error> {
+    if class == IoClass::Bulk {
+        let part_size = inner.limiter.config().bulk_part_size.max(1);
+        if ranges.iter().any(|r| r.end - r.start > part_size) {
+            let split: Vec<_> = ranges
+                .iter()
+                .map(|r| chunks(r.start, r.end - r.start, part_size))
+                .collect();
+            let parts = split.concat();
+            let (mut data, mut report) = fetch_ranges(inner, key, if_match, &parts, class).await?;
+            let mut joined = Vec::with_capacity(ranges.len());
+            let mut retries = Vec::with_capacity(ranges.len());
+            for split in split.iter().rev() {
+                let at = data.len() - split.len();
+                joined.push(data.split_off(at).concat());
+                retries.push(report.retries.split_off(at).into_iter().sum());
+            }
+            joined.reverse();
+            retries.reverse();
+            report.retries = retries;
+            return Ok((joined, report));
+        }
+    }
+    fetch_ranges(inner, key, if_match, ranges, class).await
+}
+
+async fn fetch_ranges(
+    inner: &Inner,
+    key: &ObjectKey,
+    if_match: Option<&str>,
+    ranges: &[Range<u64>],
+    class: IoClass,
+) -> Result<(Vec<Vec<u8>>, FetchReport), Error> {
+    let config = inner.fetch_config.clone();
+    let deadline = Instant::now() + config.deadline;
+    let shared = Arc::new((inner.clone(), key.clone(), if_match.map(str::to_owned)));
+    let mut chunks: Vec<_> = ranges
+        .iter()
+        .map(|_| Chunk {
+            data: None,
+            attempts: 0,
+            retries: 0,
+            in_flight: vec![],
+            started: None,
+            hedged: false,
+            retry_at: Some(Instant::now()),
+        })
+        .collect();
+    let mut attempts = JoinSet::new();
+    let mut completed = vec![];
+    let mut report = FetchReport::default();
+    let mut remaining = ranges.len();
+
+    let spawn = |attempts: &mut JoinSet<Attempt>, chunk: usize, hedge: bool| {
+        let shared = shared.clone();
+        let range = ranges[chunk].clone();
+        let timeout = inner.timeouts.profile(class.into()).total;
+        attempts.spawn(async move {
+            let (inner, key, if_match) = &*shared;
+            let start = Instant::now();
+            let result = match tokio::time::timeout(
+                timeout,
+                read_range(inner, key, if_match.as_deref(), range, class),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => {
+                    inner
+                        .stats
+                        .record_timeout(class.into(), TimeoutCause::Total);
+                    Err((
+                        Error::Whatever {
+                            message: format!("ranged GET of {key} timed out after {timeout:?}"),
+                            source: None,
+                        },
+                        true,
+                    ))
+                }
+            };
+            Attempt {
+                chunk,
+                hedge,
+                elapsed: start.elapsed(),
+                result,
+            }
+        })
+    };
+
+    while remaining > 0 {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Whatever {
+                message: format!(
+                    "read of {key} missed its deadline of {:?} with {remaining} of {} chunks \
+                     outstanding",
+                    config.deadline,
+                    ranges.len()
+                ),
+                source: None,
+            });
+        }
+        let mut in_flight = chunks.iter().filter(|c| !c.in_flight.is_empty()).count();
+        for (i, chunk) in chunks.iter_mut().enumerate() {
+            if in_flight >= config.concurrency {
+                break;
+            }
+            if chunk.retry_at.is_some_and(|at| at <= now) {
+                chunk.retry_at = None;
+                chunk.attempts += 1;
+                chunk.started = Some(now);
+                chunk.in_flight.push(spawn(&mut attempts, i, false));
+                in_flight += 1;
+            }
+        }
+
+        // the slowest chunk that hasn't been hedged yet, and when to hedge it
+        let hedge = config
+            .hedge
+            .as_ref()
+            .and_then(|hedge| hedge_delay(hedge, &completed))
+            .and_then(|delay| {
+                chunks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| !c.hedged && !c.in_flight.is_empty())
+                    .filter_map(|(i, c)| Some((i, c.started? + delay)))
+                    .min_by_key(|(_, at)| *at)
+            });
+        let mut wake = deadline;
+        for chunk in &chunks {
+            wake = wake.min(chunk.retry_at.unwrap_or(deadline));
+        }
+        if let Some((_, at)) = hedge {
+            wake = wake.min(at);
+        }
+
+        let attempt = match tokio::time::timeout_at(wake, attempts.join_next()).await {
+            Ok(Some(Ok(attempt))) => attempt,
+            // cancelled as the loser of a hedge
+            Ok(Some(Err(_))) => continue,
+            // every chunk is backing off
+            Ok(None) => {
+                tokio::time::sleep_until(wake).await;
+                continue;
+            }
+            Err(_) => {
+                let now = Instant::now();
+                if let Some((i, at)) = hedge.filter(|(_, at)| *at <= now) {
+                    chunks[i].hedged = true;
+                    chunks[i].in_flight.push(spawn(&mut attempts, i, true));
+                    report.hedges += 1;
+                    Stats::incr(&inner.stats.hedges);
+                    tracing::debug!(target: "threeqlite::s3", %key, chunk = i, waited = ?(now - at), "hedging slow chunk");
+                }
+                continue;
+            }
+        };
+
+        let chunk = &mut chunks[attempt.chunk];
+        chunk.in_flight.retain(|handle| !handle.is_finished());
+        if chunk.data.is_some() {
+            continue;
+        }
+        match attempt.result {
+            Ok(data) => {
+                for handle in chunk.in_flight.drain(..) {
+                    handle.abort();
+                }
+                if attempt.hedge {
+                    report.hedge_wins += 1;
+                    Stats::incr(&inner.stats.hedge_wins);
+                }
+                chunk.data = Some(data);
+                completed.push(attempt.elapsed);
+                remaining -= 1;
+            }
+            // the other attempt may still succeed
+            Err(_) if !chunk.in_flight.is_empty() => {}
+            Err((err, retryable)) if retryable && chunk.attempts < config.chunk_attempts => {
+                chunk.retries += 1;
+                Stats::incr(&inner.stats.chunk_retries);
+                let delay = backoff(&config, chunk.retries);
+                tracing::debug!(target: "threeqlite::s3", %key, chunk = attempt.chunk, retry = chunk.retries, ?delay, %err, "retrying chunk");
+                chunk.retry_at = Some(Instant::now() + delay);
+                chunk.started = None;
+                chunk.hedged = false;
+            }
+            // reported as such to SQLite
+            Err((err @ Error::CredentialsExpired { .. }, _)) => return Err(err),
+            Err((err, _)) => {
+                return Err(Error::Whatever {
+                    message: format!(
+                        "chunk {} of {key} failed after {} attempts: {err}",
+                        attempt.chunk, chunk.attempts
+                    ),
+                    source: Some(Box::new(err)),
+                })
+            }
+        }
+    }
+
+    report.retries = chunks.iter().map(|c| c.retries).collect();
+    let data = chunks
+        .into_iter()
+        .map(|c| c.data.unwrap_or_default())
+        .collect();
+    Ok((data, report))
+}
+
+/// A single ranged GET, with whether a failure may be retried.
+async fn read_range(
+    inner: &Inner,
+    key: &ObjectKey,
+    if_match: Option<&str>,
+    range: Range<u64>,
+    class: IoClass,
+) -> Result<Vec<u8>, (Error, bool)> {
+    inner.guard(OpClass::Read).map_err(|err| (err, false))?;
+    inner
+        .check_budget(OpClass::Read)
+        .map_err(|err| (err, false))?;
+    let _permit = inner.permit(class).await;
+    let client = match class {
+        IoClass::Critical => &inner.page_client,
+        IoClass::Bulk => &inner.bulk_client,
+    };
+    let mut get = client
+        .get_object()
+        .bucket(&inner.bucket)
+        .key(key)
+        .range(format!("bytes={}-{}", range.start, range.end - 1));
+    if let Some(etag) = if_match {
+        get = get.if_match(format!("\"{etag}\""));
+    }
+    let obj = credentials::send(inner.credentials.as_deref(), || get.clone().send()).await;
+    inner.record(OpClass::Read, obj.is_ok());
+    let obj = obj.map_err(|err| {
+        // reloading the credentials didn't help, neither would retrying
+        if credentials::classify(&err).is_some() {
+            return (err.into(), false);
+        }
+        let status = status(&err);
+        // throttling, server errors and dropped connections pass
+        let retryable = status.is_none_or(|status| status == 429 || status >= 500);
+        let message = match status {
+            Some(status) => format!("ranged GET of {key} failed with status {status}"),
+            None => format!("ranged GET of {key} failed: {err}"),
+        };
+        let err = Error::Whatever {
+            message,
+            source: Some(Box::new(err)),
+        };
+        (err, retryable)
+    })?;
+    let bytes = obj.body.collect().await.map_err(|err| {
+        let err = Error::Whatever {
+            message: format!("failed to read object body: {err}"),
+            source: Some(Box::new(err)),
+        };
+        (err, true)
+    })?;
+    let bytes = bytes.to_vec();
+    inner.charge(Dimension::GetBytes, bytes.len() as u64);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::CacheUse, config::Config, mock::MockS3, priority::PriorityConfig, vfs::ThreeQLite,
+    };
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(chunks(0, 0, 4), vec![]);
+        assert_eq!(chunks(2, 9, 4), vec![2..6, 6..10, 10..11]);
+        assert_eq!(chunks(0, 8, 4), vec![0..4, 4..8]);
+    }
+
+    #[test]
+    fn test_backoff_and_hedge_delay() {
+        let config = FetchConfig::default();
+        assert_eq!(backoff(&config, 1), Duration::from_millis(50));
+        assert_eq!(backoff(&config, 3), Duration::from_millis(200));
+        assert_eq!(backoff(&config, 40), config.max_backoff);
+
+        let hedge = HedgeConfig::default();
+        assert_eq!(hedge_delay(&hedge, &[]), None);
+        let completed: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(
+            hedge_delay(&hedge, &completed),
+            Some(Duration::from_millis(95))
+        );
+        assert_eq!(
+            hedge_delay(&hedge, &[Duration::from_millis(1)]),
+            Some(hedge.min_delay)
+        );
+    }
+
+    fn setup(fetch: FetchConfig) -> (MockS3, ThreeQLite, ObjectKey, Vec<u8>) {
+        let mock = MockS3::start();
+        let data: Vec<u8> = (0..16 * 1024).map(|i| (i % 251) as u8).collect();
+        mock.put("test.db", data.clone());
+        let config = Config {
+            fetch,
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        (mock, tq, ObjectKey::new("test.db").unwrap(), data)
+    }
+
+    #[tokio::test]
+    async fn test_throttled_chunk_is_retried_alone() {
+        let (mock, tq, key, data) = setup(FetchConfig {
+            backoff: Duration::from_millis(10),
+            ..FetchConfig::default()
+        });
+        let ranges = chunks(0, data.len() as u64, 1024);
+        mock.throttle_range("bytes=5120-6143", 3);
+
+        let inner = tq.inner.read().await;
+        let start = Instant::now();
+        let (chunks, report) = fetch(&inner, &key, None, &ranges, IoClass::Critical)
+            .await
+            .unwrap();
+        assert!(start.elapsed() < inner.fetch_config.deadline);
+        assert_eq!(chunks.concat(), data);
+        let mut retries = vec![0; 16];
+        retries[5] = 3;
+        assert_eq!(report.retries, retries);
+        assert_eq!(tq.stats().await.chunk_retries, 3);
+
+        // every other chunk was fetched exactly once
+        let gets = mock.ranges();
+        assert_eq!(gets.len(), 16 + 3);
+        for range in &ranges {
+            let header = format!("bytes={}-{}", range.start, range.end - 1);
+            let expected = if range.start == 5120 { 4 } else { 1 };
+            assert_eq!(gets.iter().filter(|r| **r == header).count(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_fails_when_chunk_exhausts_attempts() {
+        let (mock, tq, key, data) = setup(FetchConfig {
+            backoff: Duration::from_millis(1),
+            chunk_attempts: 3,
+            ..FetchConfig::default()
+        });
+        let ranges = chunks(0, data.len() as u64, 4096);
+        mock.throttle_range("bytes=0-4095", 10);
+        let inner = tq.inner.read().await;
+        let err = fetch(&inner, &key, None, &ranges, IoClass::Critical)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("503"), "{err}");
+
+        // a missed deadline fails the read as well
+        let inner = Inner {
+            fetch_config: FetchConfig {
+                deadline: Duration::from_millis(100),
+                backoff: Duration::from_millis(500),
+                ..FetchConfig::default()
+            },
+            ..inner.clone()
+        };
+        mock.throttle_range("bytes=0-4095", 10);
+        let err = fetch(&inner, &key, None, &ranges, IoClass::Critical)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("deadline"), "{err}");
+
+        // errors that retrying doesn't fix fail immediately
+        mock.throttle_range("bytes=0-4095", 0);
+        let err = fetch(&inner, &key, Some("nope"), &ranges, IoClass::Critical)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("412"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_hedge_beats_stuck_request() {
+        let (mock, tq, key, data) = setup(FetchConfig {
+            hedge: Some(HedgeConfig::default()),
+            ..FetchConfig::default()
+        });
+        let ranges = chunks(0, data.len() as u64, 2048);
+        // only the first GET of this chunk is stuck
+        mock.stall_range("bytes=4096-6143", Duration::from_secs(5), 1);
+
+        let inner = tq.inner.read().await;
+        let start = Instant::now();
+        let (chunks, report) = fetch(&inner, &key, None, &ranges, IoClass::Critical)
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(chunks.concat(), data);
+        assert_eq!(report.hedge_wins, 1);
+        assert!(report.hedges >= 1);
+        assert_eq!(report.retries, vec![0; 8]);
+        let stats = tq.stats().await;
+        assert_eq!(stats.hedge_wins, 1);
+        assert_eq!(stats.chunk_retries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_page_reads_overtake_bulk_fetch() {
+        let mock = MockS3::start();
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        mock.put("test.db", data.clone());
+        // the whole object takes 125ms to send, a part 8ms
+        mock.limit_bandwidth(32 * 1024 * 1024);
+        let config = Config {
+            priority: PriorityConfig {
+                permits: 3,
+                reserved: 1,
+                bulk_part_size: 256 * 1024,
+                ..PriorityConfig::default()
+            },
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let key = ObjectKey::new("test.db").unwrap();
+        let inner = tq.inner.read().await.clone();
+
+        let snapshot = tokio::spawn({
+            let (inner, key) = (inner.clone(), key.clone());
+            let len = data.len() as u64;
+            async move { fetch(&inner, &key, None, &chunks(0, len, len), IoClass::Bulk).await }
+        });
+        let mut page_reads = Vec::new();
+        while !snapshot.is_finished() {
+            let mut inner = inner.clone();
+            let start = Instant::now();
+            let page = inner
+                .read_exact_at(8192, 4096, false, CacheUse::Admit, None)
+                .await
+                .unwrap();
+            page_reads.push(start.elapsed());
+            assert_eq!(page, data[8192..12288]);
+        }
+        let (chunks, report) = snapshot.await.unwrap().unwrap();
+        assert_eq!(chunks, vec![data]);
+        assert_eq!(report.retries, vec![0]);
+
+        // a page read waits for the parts already on the wire at most, not for the snapshot
+        assert!(page_reads.len() >= 3, "{page_reads:?}");
+        let slowest = page_reads.iter().max().unwrap();
+        assert!(*slowest < Duration::from_millis(60), "{page_reads:?}");
+        let parts = mock.ranges();
+        assert_eq!(parts.iter().filter(|r| r.ends_with("-262143")).count(), 1);
+        assert_eq!(parts.len(), 16 + page_reads.len());
+        assert_eq!(tq.queue_wait(IoClass::Bulk).await.count, 16);
+        assert_eq!(
+            tq.queue_wait(IoClass::Critical).await.count,
+            page_reads.len()
+        );
+    }
+}
@@ -0,0 +1,863 @@
+//! The commit pipeline of a database.
+//!
+//! In rollback-journal mode, SQLite commits by writing and syncing the journal, writing and
+//! syncing the database, and deleting the journal, each a round trip to the object store. Only two
+//! orderings matter for durability:
+//!
+//! 1. The journal is durable before any page of the database is overwritten.
+//! 2. Every page is durable before the journal is deleted.
+//!
+//! SQLite issues its callbacks in that order, one at a time, so a [Handle](crate::handle::Handle)
+//! buffers the pages written to a database in [PendingWrites], which reads and the size of the
+//! file see, and uploads them on the next sync or unlock as a [FlushGraph]: a barrier standing for
+//! the durable journal, a stage node per extent computing its checksum, an upload node per extent
+//! after its stage, and a barrier after all uploads, which is all the sync waits for. Extents thus
+//! upload concurrently, and a commit has the journal upload, one round of page uploads and the
+//! journal delete on its critical path, however many pages it wrote.
+//!
+//! An in-place update often changes a few bytes of a page, e.g. a counter and a cell, yet uploads
+//! the whole page. With [DeltaConfig] enabled, a page this instance read at the generation it
+//! builds on is compared with what SQLite wrote, and uploaded as the byte ranges that changed when
+//! few did, each an upload node of its own; a page written unchanged isn't uploaded at all. The
+//! database object is overwritten in place, so there is no chain of deltas to resolve on reads.
+//!
+//! Every instance keeps a [CommitLog] of these steps. In debug builds, recording a step that
+//! violates either ordering panics; in release builds, it logs an error.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    future::Future,
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use tokio::task::JoinSet;
+
+use crate::{error::Error, key::ObjectKey};
+
+/// The work of a node.
+pub type Work = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    /// Preparing a body, e.g. checksumming it, without any request.
+    Stage,
+    /// A request to the object store, i.e. a round trip.
+    Upload,
+    /// Waits for its dependencies without doing anything itself.
+    Barrier,
+}
+
+/// A node of a [FlushGraph]. Nodes can only depend on nodes added before them, so a graph has no
+/// cycles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+struct Node {
+    kind: NodeKind,
+    label: String,
+    after: Vec<NodeId>,
+    work: Option<Work>,
+}
+
+/// Steps of a flush and what each waits for, see the [module documentation](self).
+#[derive(Default)]
+pub struct FlushGraph {
+    nodes: Vec<Node>,
+}
+
+impl FlushGraph {
+    pub fn stage(
+        &mut self,
+        label: impl Into<String>,
+        after: &[NodeId],
+        work: impl Future<Output = Result<(), Error>> + Send + 'static,
+    ) -> NodeId {
+        self.add(NodeKind::Stage, label.into(), after, Some(Box::pin(work)))
+    }
+
+    pub fn upload(
+        &mut self,
+        label: impl Into<String>,
+        after: &[NodeId],
+        work: impl Future<Output = Result<(), Error>> + Send + 'static,
+    ) -> NodeId {
+        self.add(NodeKind::Upload, label.into(), after, Some(Box::pin(work)))
+    }
+
+    pub fn barrier(&mut self, label: impl Into<String>, after: &[NodeId]) -> NodeId {
+        self.add(NodeKind::Barrier, label.into(), after, None)
+    }
+
+    fn add(
+        &mut self,
+        kind: NodeKind,
+        label: String,
+        after: &[NodeId],
+        work: Option<Work>,
+    ) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Node {
+            kind,
+            label,
+            after: after.to_vec(),
+            work,
+        });
+        id
+    }
+
+    /// The most uploads on any chain of dependencies, i.e. the round trips the graph takes at
+    /// least.
+    pub fn critical_path(&self) -> usize {
+        let mut depth = vec![0; self.nodes.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            let before = node.after.iter().map(|dep| depth[dep.0]).max();
+            depth[i] = before.unwrap_or(0) + (node.kind == NodeKind::Upload) as usize;
+        }
+        depth.into_iter().max().unwrap_or(0)
+    }
+
+    /// Run every node as soon as its dependencies finished. Fails with the first error of a node,
+    /// abandoning the nodes still running.
+    pub async fn run(mut self) -> Result<FlushReport, Error> {
+        let start = Instant::now();
+        let mut waiting: Vec<_> = self.nodes.iter().map(|node| node.after.len()).collect();
+        let mut dependents = vec![Vec::new(); self.nodes.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            for dep in &node.after {
+                dependents[dep.0].push(i);
+            }
+        }
+        let mut started = vec![Duration::ZERO; self.nodes.len()];
+        let mut ready: Vec<_> = (0..self.nodes.len()).filter(|i| waiting[*i] == 0).collect();
+        let mut done = Vec::new();
+        let mut events = Vec::with_capacity(self.nodes.len());
+        let mut tasks = JoinSet::new();
+        loop {
+            while let Some(i) = done.pop() {
+                let node = &self.nodes[i];
+                events.push(FlushEvent {
+                    node: NodeId(i),
+                    kind: node.kind,
+                    label: node.label.clone(),
+                    started: started[i],
+                    finished: start.elapsed(),
+                });
+                for &next in &dependents[i] {
+                    waiting[next] -= 1;
+                    if waiting[next] == 0 {
+                        ready.push(next);
+                    }
+                }
+            }
+            while let Some(i) = ready.pop() {
+                started[i] = start.elapsed();
+                match self.nodes[i].work.take() {
+                    Some(work) => {
+                        tasks.spawn(async move { (i, work.await) });
+                    }
+                    None => done.push(i),
+                }
+            }
+            if !done.is_empty() {
+                continue;
+            }
+            let Some(joined) = tasks.join_next().await else {
+                break;
+            };
+            let (i, res) = joined.map_err(|err| Error::Whatever {
+                message: format!("flush step failed: {err}"),
+                source: None,
+            })?;
+            res?;
+            done.push(i);
+        }
+
+        let report = FlushReport {
+            events,
+            elapsed: start.elapsed(),
+            after: self.nodes.into_iter().map(|node| node.after).collect(),
+        };
+        if let Err(violation) = report.check_order() {
+            debug_assert!(false, "{violation}");
+            tracing::error!(target: "threeqlite::s3", violation, "flush ran out of order");
+        }
+        Ok(report)
+    }
+}
+
+/// A node that ran, with its times relative to the start of the flush.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlushEvent {
+    pub node: NodeId,
+    pub kind: NodeKind,
+    pub label: String,
+    pub started: Duration,
+    pub finished: Duration,
+}
+
+/// What a [FlushGraph] did.
+#[derive(Clone, Debug)]
+pub struct FlushReport {
+    /// In the order the nodes finished.
+    pub events: Vec<FlushEvent>,
+    pub elapsed: Duration,
+    /// The dependencies of each node.
+    after: Vec<Vec<NodeId>>,
+}
+
+impl FlushReport {
+    pub fn round_trips(&self) -> usize {
+        self.events
+            .iter()
+            .filter(|event| event.kind == NodeKind::Upload)
+            .count()
+    }
+
+    /// Check that every node started after all of its dependencies finished.
+    pub fn check_order(&self) -> Result<(), String> {
+        let mut finished = vec![None; self.after.len()];
+        for event in &self.events {
+            finished[event.node.0] = Some(event);
+        }
+        for event in &self.events {
+            for dep in &self.after[event.node.0] {
+                match finished[dep.0] {
+                    Some(dep) if dep.finished <= event.started => {}
+                    Some(dep) => {
+                        return Err(format!(
+                            "{} started before {} finished",
+                            event.label, dep.label
+                        ))
+                    }
+                    None => return Err(format!("{} ran, but not its dependencies", event.label)),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pages written to a database since its last flush, as disjoint extents by offset.
+///
+/// What SQLite writes is copied into an extent once. The stage and upload of an extent share its
+/// buffer rather than copying it again, and so do the ranges of a delta.
+#[derive(Clone, Debug, Default)]
+pub struct PendingWrites {
+    extents: BTreeMap<u64, Bytes>,
+    copied: u64,
+}
+
+impl PendingWrites {
+    pub fn is_empty(&self) -> bool {
+        self.extents.is_empty()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.extents.values().map(|data| data.len() as u64).sum()
+    }
+
+    /// The end of the last extent.
+    pub fn end(&self) -> Option<u64> {
+        let (offset, data) = self.extents.last_key_value()?;
+        Some(offset + data.len() as u64)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.extents
+            .iter()
+            .map(|(offset, data)| (*offset, data.as_ref()))
+    }
+
+    /// Bytes copied into the extents so far: those written, and those of the extents a write
+    /// overlapping them was merged with.
+    pub fn copied(&self) -> u64 {
+        self.copied
+    }
+
+    /// Buffer `buf` at `offset`, merging it with the extents it overlaps. Returns how many of its
+    /// bytes were buffered already, e.g. written again after a cache spill.
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> u64 {
+        let end = offset + buf.len() as u64;
+        self.copied += buf.len() as u64;
+        // a page written again lands in its extent, unless a flush still holds it
+        let within = self
+            .extents
+            .range(..=offset)
+            .next_back()
+            .filter(|(at, data)| *at + data.len() as u64 >= end)
+            .map(|(at, _)| *at);
+        if let Some(at) = within {
+            let data = self.extents.remove(&at).unwrap();
+            match data.try_into_mut() {
+                Ok(mut data) => {
+                    let from = (offset - at) as usize;
+                    data[from..from + buf.len()].copy_from_slice(buf);
+                    self.extents.insert(at, data.freeze());
+                    return buf.len() as u64;
+                }
+                Err(data) => {
+                    self.extents.insert(at, data);
+                }
+            }
+        }
+        let overlapping: Vec<_> = self
+            .extents
+            .range(..end)
+            .rev()
+            .take_while(|(at, data)| *at + data.len() as u64 > offset)
+            .map(|(at, _)| *at)
+            .collect();
+        let mut start = offset;
+        let mut merged_end = end;
+        let mut old = Vec::with_capacity(overlapping.len());
+        let mut rewritten = 0;
+        for at in overlapping {
+            let data = self.extents.remove(&at).unwrap();
+            let data_end = at + data.len() as u64;
+            rewritten += data_end.min(end) - at.max(offset);
+            start = start.min(at);
+            merged_end = merged_end.max(data_end);
+            old.push((at, data));
+        }
+        let mut merged = BytesMut::zeroed((merged_end - start) as usize);
+        for (at, data) in old {
+            let from = (at - start) as usize;
+            merged[from..from + data.len()].copy_from_slice(&data);
+            self.copied += data.len() as u64;
+        }
+        let from = (offset - start) as usize;
+        merged[from..from + buf.len()].copy_from_slice(buf);
+        self.extents.insert(start, merged.freeze());
+        rewritten
+    }
+
+    /// Copy what is buffered of `offset..offset + buf.len()` into `buf`. Returns whether all of
+    /// it is buffered.
+    pub fn overlay(&self, offset: u64, buf: &mut [u8]) -> bool {
+        let end = offset + buf.len() as u64;
+        let mut covered = 0;
+        for (at, data) in self.extents.range(..end).rev() {
+            let data_end = at + data.len() as u64;
+            if data_end <= offset {
+                break;
+            }
+            let (from, to) = ((*at).max(offset), data_end.min(end));
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&data[(from - at) as usize..(to - at) as usize]);
+            covered += to - from;
+        }
+        covered == buf.len() as u64
+    }
+
+    /// Drop what is buffered past `size`.
+    pub fn truncate(&mut self, size: u64) {
+        self.extents.retain(|at, _| *at < size);
+        if let Some((at, data)) = self.extents.last_key_value() {
+            if at + data.len() as u64 > size {
+                let at = *at;
+                self.extents
+                    .get_mut(&at)
+                    .unwrap()
+                    .truncate((size - at) as usize);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.extents.clear();
+    }
+
+    /// The writes uploading these extents, each as the ranges in which it differs from `base`,
+    /// what the database object holds at an offset and length if known, when [DeltaConfig]
+    /// allows it. Returns them with the number of extents uploaded as such ranges.
+    pub fn delta<B>(&self, config: &DeltaConfig, base: B) -> (PendingWrites, u64)
+    where
+        B: Fn(u64, usize) -> Option<Vec<u8>>,
+    {
+        let mut delta = PendingWrites::default();
+        let mut patched = 0;
+        for (&offset, data) in &self.extents {
+            let ranges = base(offset, data.len())
+                .filter(|base| base.len() == data.len())
+                .map(|base| changes(&base, data, config.merge_gap))
+                .filter(|ranges| config.allows(ranges, data.len()));
+            let Some(ranges) = ranges else {
+                delta.extents.insert(offset, data.clone());
+                continue;
+            };
+            for range in ranges {
+                delta
+                    .extents
+                    .insert(offset + range.start as u64, data.slice(range));
+            }
+            patched += 1;
+        }
+        (delta, patched)
+    }
+}
+
+/// When a page is uploaded as the byte ranges that changed, see the
+/// [module documentation](self). Every range is a request of its own, so this trades requests for
+/// upload bytes.
+#[derive(Clone, Debug)]
+pub struct DeltaConfig {
+    /// The largest share of a page that may have changed for it to be uploaded as its changes.
+    /// Disabled if 0.
+    pub max_dirty_ratio: f64,
+    /// The most ranges one page is uploaded as.
+    pub max_ranges: usize,
+    /// Two changes at most this many unchanged bytes apart are uploaded as one range.
+    pub merge_gap: usize,
+}
+
+impl Default for DeltaConfig {
+    fn default() -> Self {
+        Self {
+            max_dirty_ratio: 0.0,
+            max_ranges: 4,
+            merge_gap: 64,
+        }
+    }
+}
+
+impl DeltaConfig {
+    pub fn enabled(&self) -> bool {
+        self.max_dirty_ratio > 0.0
+    }
+
+    fn allows(&self, ranges: &[Range<usize>], len: usize) -> bool {
+        let dirty: usize = ranges.iter().map(|range| range.len()).sum();
+        ranges.len() <= self.max_ranges && dirty as f64 <= self.max_dirty_ratio * len as f64
+    }
+}
+
+/// The ranges in which `data` differs from `base`, joining those at most `merge_gap` bytes apart.
+fn changes(base: &[u8], data: &[u8], merge_gap: usize) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let changed = base
+        .iter()
+        .zip(data)
+        .enumerate()
+        .filter(|(_, (a, b))| a != b);
+    for (at, _) in changed {
+        match ranges.last_mut() {
+            Some(last) if at - last.end <= merge_gap => last.end = at + 1,
+            _ => ranges.push(at..at + 1),
+        }
+    }
+    ranges
+}
+
+/// A body prepared for upload by a stage node.
+#[derive(Debug)]
+pub struct Staged {
+    /// Shares the buffer of the extent.
+    pub body: Bytes,
+    /// The base64-encoded MD5 of the body, for `Content-MD5`.
+    pub md5: String,
+}
+
+/// Where a stage node leaves its [Staged] body for the upload after it.
+pub type Slot = Arc<Mutex<Option<Staged>>>;
+
+/// The flush of `pending`, see the [module documentation](self). `upload` sends the extent at an
+/// offset, taking its body from the slot once the upload node runs.
+pub fn page_graph<U, F>(pending: &PendingWrites, upload: U) -> FlushGraph
+where
+    U: Fn(u64, Slot) -> F,
+    F: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    let mut graph = FlushGraph::default();
+    let journal = graph.barrier("journal durable", &[]);
+    let mut uploads = Vec::new();
+    for (&offset, data) in &pending.extents {
+        let slot = Slot::default();
+        let stage = graph.stage(format!("stage {offset}"), &[journal], {
+            let (slot, body) = (slot.clone(), data.clone());
+            async move {
+                let md5 = base64::prelude::BASE64_STANDARD.encode(md5::compute(&body).as_ref());
+                *slot.lock().unwrap() = Some(Staged { body, md5 });
+                Ok(())
+            }
+        });
+        uploads.push(graph.upload(format!("upload {offset}"), &[stage], upload(offset, slot)));
+    }
+    graph.barrier("pages durable", &uploads);
+    graph
+}
+
+/// A step of a commit, see [CommitLog].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommitStep {
+    /// The journal was written after it was last uploaded.
+    JournalWritten,
+    /// The journal was uploaded.
+    JournalSynced,
+    /// An upload of pages of the database started.
+    UploadStarted,
+    UploadFinished,
+    /// The barrier after the uploads of a flush passed.
+    PagesDurable,
+    JournalDeleted,
+}
+
+/// A [CommitStep] of the database with the journal `journal`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitEvent {
+    pub journal: ObjectKey,
+    pub step: CommitStep,
+}
+
+/// How many events a [CommitLog] keeps.
+const RECENT: usize = 256;
+
+/// The commit steps of the databases of an instance, checking that they preserve the orderings
+/// durability depends on, see the [module documentation](self).
+#[derive(Debug, Default)]
+pub struct CommitLog {
+    state: Mutex<LogState>,
+}
+
+#[derive(Debug, Default)]
+struct LogState {
+    recent: VecDeque<CommitEvent>,
+    journals: HashMap<ObjectKey, JournalState>,
+}
+
+#[derive(Debug, Default)]
+struct JournalState {
+    /// SQLite syncs the journal, i.e. the database isn't on `synchronous=OFF`, which promises no
+    /// ordering at all.
+    synced: bool,
+    /// Written since it was last uploaded.
+    dirty: bool,
+    uploads: usize,
+    /// Pages were uploaded since the last barrier.
+    undurable: bool,
+}
+
+impl JournalState {
+    fn apply(&mut self, step: CommitStep) -> Option<&'static str> {
+        let mut violation = None;
+        match step {
+            CommitStep::JournalWritten => self.dirty = true,
+            CommitStep::JournalSynced => (self.synced, self.dirty) = (true, false),
+            CommitStep::UploadStarted => {
+                if self.synced && self.dirty {
+                    violation = Some("page upload started before the journal was durable");
+                }
+                self.uploads += 1;
+                self.undurable = true;
+            }
+            CommitStep::UploadFinished => self.uploads = self.uploads.saturating_sub(1),
+            CommitStep::PagesDurable => {
+                if self.uploads > 0 {
+                    violation = Some("barrier passed with page uploads in flight");
+                }
+                self.undurable = false;
+            }
+            CommitStep::JournalDeleted => {
+                if self.uploads > 0 || self.undurable {
+                    violation = Some("journal deleted before the pages were durable");
+                }
+                *self = Self::default();
+            }
+        }
+        violation
+    }
+}
+
+impl CommitLog {
+    pub fn record(&self, journal: &ObjectKey, step: CommitStep) {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.journals.entry(journal.clone()).or_default();
+        let violation = entry.apply(step);
+        if step == CommitStep::JournalDeleted {
+            state.journals.remove(journal);
+        }
+        if state.recent.len() == RECENT {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(CommitEvent {
+            journal: journal.clone(),
+            step,
+        });
+        if let Some(violation) = violation {
+            debug_assert!(false, "{journal}: {violation}");
+            tracing::error!(target: "threeqlite::s3", %journal, violation, "commit ran out of order");
+        }
+    }
+
+    /// The last events recorded, oldest first.
+    pub fn recent(&self) -> Vec<CommitEvent> {
+        self.state.lock().unwrap().recent.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        journal::{self, Journal, JournalKind},
+        key::KeyLayout,
+        mock::MockS3,
+        vfs::ThreeQLite,
+    };
+
+    #[test]
+    fn test_pending_writes() {
+        let mut pending = PendingWrites::default();
+        pending.write(4096, &[1; 4096]);
+        pending.write(0, &[2; 4096]);
+        assert_eq!(pending.end(), Some(8192));
+        // adjacent extents stay apart, to upload concurrently
+        assert_eq!(pending.iter().count(), 2);
+
+        // an overlapping write merges
+        assert_eq!(pending.write(4000, &[3; 200]), 200);
+        let extents: Vec<_> = pending.iter().map(|(at, data)| (at, data.len())).collect();
+        assert_eq!(extents, [(0, 8192)]);
+        assert_eq!(pending.bytes(), 8192);
+
+        let mut buf = [0; 300];
+        assert!(pending.overlay(3950, &mut buf));
+        assert_eq!(buf[..50], [2; 50]);
+        assert_eq!(buf[50..250], [3; 200]);
+        assert_eq!(buf[250..], [1; 50]);
+        // partly buffered reads only overlay what is buffered
+        let mut buf = [9; 16];
+        assert!(!pending.overlay(8184, &mut buf));
+        assert_eq!(buf, [1, 1, 1, 1, 1, 1, 1, 1, 9, 9, 9, 9, 9, 9, 9, 9]);
+
+        pending.write(16384, &[4; 4096]);
+        pending.truncate(6000);
+        assert_eq!(pending.end(), Some(6000));
+        pending.truncate(0);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_copies() {
+        let mut pending = PendingWrites::default();
+        for page in 0..4 {
+            pending.write(page * 4096, &[1; 4096]);
+        }
+        // written again in place
+        pending.write(4096 + 100, &[2; 100]);
+        assert_eq!(pending.copied(), 4 * 4096 + 100);
+
+        // the stages share the extents
+        let graph = page_graph(&pending, |_, _| async { Ok(()) });
+        let before = pending
+            .iter()
+            .map(|(_, data)| data.as_ptr())
+            .collect::<Vec<_>>();
+        let staged = &pending.extents[&4096];
+        assert!(!staged.clone().is_unique());
+        // so a write while they do copies the extent rather than changing what they upload
+        let shared = staged.clone();
+        pending.write(4096, &[3; 10]);
+        assert_eq!(pending.copied(), 5 * 4096 + 110);
+        assert_eq!(shared[..10], [1; 10]);
+        assert_ne!(pending.extents[&4096].as_ptr(), before[1]);
+        drop(graph);
+    }
+
+    #[test]
+    fn test_delta() {
+        let config = DeltaConfig {
+            max_dirty_ratio: 0.25,
+            max_ranges: 2,
+            merge_gap: 8,
+        };
+        let base = vec![0u8; 256];
+        let mut pending = PendingWrites::default();
+        // two changes 8 bytes apart upload as one range
+        let mut page = base.clone();
+        page[10] = 1;
+        page[19..22].copy_from_slice(&[2; 3]);
+        pending.write(0, &page);
+        // changes too many to upload apart
+        let mut page = base.clone();
+        for at in [0, 50, 100] {
+            page[at] = 3;
+        }
+        pending.write(256, &page);
+        // more than a quarter changed
+        pending.write(512, &[4; 256]);
+        // written unchanged
+        pending.write(768, &base);
+        // not read before
+        pending.write(1024, &[5; 16]);
+
+        let (delta, patched) =
+            pending.delta(&config, |offset, len| (offset < 1024).then(|| vec![0; len]));
+        assert_eq!(patched, 2);
+        let extents: Vec<_> = delta.iter().map(|(at, data)| (at, data.len())).collect();
+        assert_eq!(extents, [(10, 12), (256, 256), (512, 256), (1024, 16)]);
+        let mut buf = [0; 12];
+        assert!(delta.overlay(10, &mut buf));
+        assert_eq!(buf, [1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_delta_upload() {
+        let mock = MockS3::start();
+        mock.put("test.db", crate::mock::database(4096, 4, 1));
+        let config = Config {
+            cache: crate::cache::CacheConfig {
+                capacity: 1024 * 1024,
+                ..Default::default()
+            },
+            delta: DeltaConfig {
+                max_dirty_ratio: 0.1,
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let inner = tq.inner.read().await;
+        let db = KeyLayout::db("test.db").unwrap();
+        // pages 1 and 2 as read at the generation the commit builds on
+        let object = mock.get("test.db").unwrap();
+        for page in 1..3 {
+            let data = object[page * 4096..(page + 1) * 4096].to_vec();
+            inner.cache.insert(
+                "test.db",
+                0,
+                page as u64 * 4096,
+                data,
+                crate::cache::CacheUse::Admit,
+            );
+        }
+
+        // an in-place update of a few bytes of each page, and a new page
+        let mut pending = PendingWrites::default();
+        for page in 1..4u8 {
+            let mut data = vec![0; 4096];
+            data[100..104].copy_from_slice(&[page; 4]);
+            data[2000] = page;
+            pending.write(page as u64 * 4096, &data);
+        }
+        inner.page_flush(&db, &pending).run().await.unwrap();
+
+        let mut expected = object.clone();
+        for (offset, data) in pending.iter() {
+            expected[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        }
+        assert_eq!(mock.get("test.db").unwrap(), expected);
+        let stats = tq.stats().await;
+        assert_eq!(stats.patched_pages, 2);
+        assert_eq!(stats.bytes_uploaded, 2 * (4 + 1) + 4096);
+        assert!(stats.to_string().contains(" patched_pages=2"), "{stats}");
+        let puts = mock.requests().iter().filter(|(m, _)| m == "PUT").count();
+        assert_eq!(puts, 2 * 2 + 1);
+    }
+
+    #[tokio::test]
+    async fn test_commit_critical_path() {
+        const RTT: Duration = Duration::from_millis(50);
+        const PAGES: u64 = 8;
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let inner = tq.inner.read().await;
+        let db = KeyLayout::db("test.db").unwrap();
+        let key = KeyLayout::journal(&db);
+        mock.delay("PUT", RTT);
+        mock.delay("DELETE", RTT);
+
+        // the order SQLite calls in
+        let mut journal = Journal::open(&inner, key.clone(), JournalKind::Main, true)
+            .await
+            .unwrap();
+        journal.write_at(&[1; 512], 0).unwrap();
+        journal.sync(&inner).await.unwrap();
+        let mut pending = PendingWrites::default();
+        for page in 0..PAGES {
+            pending.write(page * 4096, &[page as u8; 4096]);
+        }
+        let graph = inner.page_flush(&db, &pending);
+        assert_eq!(graph.critical_path(), 1);
+        let report = graph.run().await.unwrap();
+        inner.commit_log.record(&key, CommitStep::PagesDurable);
+        journal::delete(&inner, &key, JournalKind::Main)
+            .await
+            .unwrap();
+
+        report.check_order().unwrap();
+        assert_eq!(report.round_trips(), PAGES as usize);
+
+        // the journal upload, one round of pages and the delete, instead of a round trip per page
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2 + PAGES as usize, "{requests:?}");
+        assert_eq!(requests[0], ("PUT".to_owned(), key.to_string()));
+        assert!(requests[1..=PAGES as usize]
+            .iter()
+            .all(|(method, page)| method == "PUT" && *page == db.to_string()));
+        assert_eq!(
+            requests.last(),
+            Some(&("DELETE".to_owned(), key.to_string()))
+        );
+
+        let steps: Vec<_> = inner
+            .commit_log
+            .recent()
+            .into_iter()
+            .filter(|event| event.journal == key)
+            .map(|event| event.step)
+            .collect();
+        assert_eq!(
+            steps[..2],
+            [CommitStep::JournalWritten, CommitStep::JournalSynced]
+        );
+        assert_eq!(
+            steps[steps.len() - 2..],
+            [CommitStep::PagesDurable, CommitStep::JournalDeleted]
+        );
+        let in_flight = steps.iter().scan(0, |uploads, step| {
+            match step {
+                CommitStep::UploadStarted => *uploads += 1,
+                CommitStep::UploadFinished => *uploads -= 1,
+                _ => {}
+            }
+            Some(*uploads)
+        });
+        assert!(in_flight.max().unwrap() > 1);
+    }
+
+    #[test]
+    fn test_commit_log_without_sync() {
+        // `synchronous=OFF` never syncs the journal and promises no order
+        let log = CommitLog::default();
+        let journal = ObjectKey::new("test.db-journal").unwrap();
+        for step in [
+            CommitStep::JournalWritten,
+            CommitStep::UploadStarted,
+            CommitStep::UploadFinished,
+            CommitStep::PagesDurable,
+            CommitStep::JournalDeleted,
+        ] {
+            log.record(&journal, step);
+        }
+        assert_eq!(log.recent().len(), 5);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "page upload started before the journal was durable")]
+    fn test_upload_before_journal_sync() {
+        let log = CommitLog::default();
+        let journal = ObjectKey::new("test.db-journal").unwrap();
+        log.record(&journal, CommitStep::JournalWritten);
+        log.record(&journal, CommitStep::JournalSynced);
+        // a cache spill appends to the journal, which SQLite syncs again before writing pages
+        log.record(&journal, CommitStep::JournalWritten);
+        log.record(&journal, CommitStep::UploadStarted);
+    }
+}
@@ -0,0 +1,749 @@
+//! Versioning of the objects this crate stores next to the database.
+//!
+//! Every object written in format version 2 or later starts with [MAGIC] followed by a
+//! [FormatHeader]. Version 1 objects predate the header and consist of the body only. From
+//! version 3, a CRC32C of everything before it trails the body, so that an object cut short, e.g.
+//! by a store keeping part of an upload that timed out, fails its checksum rather than parsing
+//! into a valid-looking state. A writer never emits a version newer than the oldest version
+//! understood by any active reader (see [negotiate]), so that rolling deployments can mix binaries
+//! of different crate versions.
+//!
+//! The metadata object and the manifest root are the points the lock protocol and a publish
+//! commit at, and must be written whole or not at all. They are written with a single `PutObject`
+//! each, which the SDK never splits into parts, and [check_single_put] refuses to write one
+//! larger than [MAX_PROTOCOL_OBJECT], well below the 5 MiB parts of a multipart upload start at.
+//! The lock object is a fixed 16 bytes, sent with their `Content-MD5`.
+//!
+//! In a shared bucket, anyone with write access may have written these objects. They are parsed
+//! with [parse], which rejects objects longer than their type allows ([Bounded::MAX_LEN]) before
+//! looking at them and never reads past it, rejects trailing bytes, and checks the sizes of the
+//! collections it parsed ([Bounded::validate]). A length prefix claiming more elements than the
+//! input holds fails at the end of the input rather than allocating what it claims.
+
+use std::ops::RangeInclusive;
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Format versions this crate is able to read.
+pub const READ_VERSIONS: RangeInclusive<u32> = 1..=3;
+
+/// Format versions this crate is able to write.
+pub const WRITE_VERSIONS: RangeInclusive<u32> = 1..=3;
+
+/// The first format version ending in a checksum.
+const CHECKSUM_VERSION: u32 = 3;
+
+/// The largest metadata object or manifest root written, see the [module documentation](self).
+pub const MAX_PROTOCOL_OBJECT: u64 = 1 << 20;
+
+/// Marks an object carrying a [FormatHeader].
+pub const MAGIC: [u8; 4] = *b"3QLF";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormatHeader {
+    /// The oldest format version a reader must understand to read the object.
+    pub min_reader_version: u32,
+    /// The format version the object was written in.
+    pub writer_version: u32,
+}
+
+impl FormatHeader {
+    /// The header of objects written before headers were introduced.
+    pub const V1: FormatHeader = FormatHeader {
+        min_reader_version: 1,
+        writer_version: 1,
+    };
+}
+
+/// The newest version that may be written while readers understanding at most the given versions
+/// are active.
+pub fn negotiate(reader_versions: impl IntoIterator<Item = u32>) -> u32 {
+    reader_versions
+        .into_iter()
+        .fold(*WRITE_VERSIONS.end(), u32::min)
+        .max(*WRITE_VERSIONS.start())
+}
+
+/// Serialize `body` in format `version`.
+pub fn encode<T: Serialize>(version: u32, body: &T) -> Result<Vec<u8>, Error> {
+    let body = bincode::serialize(body).map_err(|err| Error::Whatever {
+        message: format!("failed to serialize: {err}"),
+        source: None,
+    })?;
+    if version == 1 {
+        return Ok(body);
+    }
+
+    let header = FormatHeader {
+        min_reader_version: version,
+        writer_version: version,
+    };
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(bincode::serialize(&header).expect("header always serializes"));
+    bytes.extend(body);
+    if version >= CHECKSUM_VERSION {
+        bytes.extend(crc32c::crc32c(&bytes).to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+/// Fails if `bytes`, to be written to `key`, is too large for a metadata object or manifest root,
+/// see the [module documentation](self).
+pub fn check_single_put(key: &str, bytes: &[u8]) -> Result<(), Error> {
+    match bytes.len() as u64 > MAX_PROTOCOL_OBJECT {
+        true => Err(Error::Whatever {
+            message: format!(
+                "{key} would take {} bytes, more than the {MAX_PROTOCOL_OBJECT} written with a single PUT",
+                bytes.len()
+            ),
+            source: None,
+        }),
+        false => Ok(()),
+    }
+}
+
+/// Split `bytes` into its header and body, failing if this crate cannot read the format, or if
+/// the checksum of a version 3 object or later doesn't match.
+pub fn decode_header(bytes: &[u8]) -> Result<(FormatHeader, &[u8]), Error> {
+    let (header, body) = split_header(bytes)?;
+    if header.writer_version < CHECKSUM_VERSION {
+        return Ok((header, body));
+    }
+    let mismatch = || Error::Whatever {
+        message: "checksum mismatch, the object is truncated or corrupt".to_owned(),
+        source: None,
+    };
+    let Some((body, checksum)) = body.split_last_chunk::<4>() else {
+        return Err(mismatch());
+    };
+    let covered = &bytes[..bytes.len() - checksum.len()];
+    match crc32c::crc32c(covered) == u32::from_le_bytes(*checksum) {
+        true => Ok((header, body)),
+        false => Err(mismatch()),
+    }
+}
+
+/// [decode_header] without checking the checksum, for a prefix of the object.
+fn split_header(bytes: &[u8]) -> Result<(FormatHeader, &[u8]), Error> {
+    let Some(rest) = bytes.strip_prefix(&MAGIC) else {
+        return Ok((FormatHeader::V1, bytes));
+    };
+
+    let header: FormatHeader = bincode::deserialize(rest).map_err(|err| Error::Whatever {
+        message: format!("invalid format header: {err}"),
+        source: None,
+    })?;
+    if header.min_reader_version > *READ_VERSIONS.end() {
+        return Err(Error::IncompatibleFormat {
+            need: header.min_reader_version,
+            have: *READ_VERSIONS.end(),
+        });
+    }
+
+    let header_len = bincode::serialized_size(&header).expect("header always serializes");
+    Ok((header, &rest[header_len as usize..]))
+}
+
+/// How many leading bytes [describe] looks at.
+pub const DESCRIBE_LEN: usize = 100;
+
+/// The first bytes of every SQLite database file.
+const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// What an object is, judging by its first [DESCRIBE_LEN] bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObjectKind {
+    Database(DatabaseHeader),
+    /// An object written by this crate with a [FormatHeader], such as the metadata object.
+    Versioned(FormatHeader),
+    Unknown,
+}
+
+/// The parts of the SQLite database header describing the file as a whole.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DatabaseHeader {
+    pub page_size: u32,
+    /// Incremented by SQLite on every commit in rollback-journal mode.
+    pub change_counter: u32,
+    /// `None` if the header's page count is stale, e.g. after a legacy writer.
+    pub page_count: Option<u32>,
+    /// Incremented by SQLite whenever the schema changes.
+    pub schema_cookie: u32,
+    /// `PRAGMA user_version`.
+    pub user_version: u32,
+    /// `PRAGMA application_id`.
+    pub application_id: u32,
+}
+
+impl DatabaseHeader {
+    /// The logical size of the database, if the header knows it.
+    pub fn size(&self) -> Option<u64> {
+        self.page_count
+            .map(|pages| pages as u64 * self.page_size as u64)
+    }
+}
+
+pub fn describe(bytes: &[u8]) -> ObjectKind {
+    let be32 = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+    if bytes.len() >= DESCRIBE_LEN && bytes.starts_with(SQLITE_MAGIC) {
+        let page_size = match u16::from_be_bytes([bytes[16], bytes[17]]) {
+            1 => 65536,
+            size => size as u32,
+        };
+        if page_size.is_power_of_two() && (512..=65536).contains(&page_size) {
+            let change_counter = be32(24);
+            // only valid if written by the same commit as the change counter
+            let page_count = (be32(92) == change_counter).then(|| be32(28));
+            return ObjectKind::Database(DatabaseHeader {
+                page_size,
+                change_counter,
+                page_count,
+                schema_cookie: be32(40),
+                user_version: be32(60),
+                application_id: be32(68),
+            });
+        }
+    }
+    match split_header(bytes) {
+        Ok((header, _)) if bytes.starts_with(&MAGIC) => ObjectKind::Versioned(header),
+        _ => ObjectKind::Unknown,
+    }
+}
+
+/// Deserialize a body previously split off by [decode_header].
+pub fn decode_body<T: Bounded>(body: &[u8]) -> Result<T, Error> {
+    parse(body).map_err(|reason| Error::Whatever {
+        message: format!("failed to deserialize: {reason}"),
+        source: None,
+    })
+}
+
+/// An object parsed from bytes of the bucket, see the [module documentation](self).
+pub trait Bounded: DeserializeOwned {
+    /// The longest encoding accepted.
+    const MAX_LEN: u64;
+
+    /// Check what was parsed, e.g. the number and size of the elements of its collections.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Fails if an encoding of `len` bytes is too long to be parsed as a `T`. Lets callers give up
+/// before downloading such an object.
+pub fn check_len<T: Bounded>(len: u64) -> Result<(), String> {
+    match len > T::MAX_LEN {
+        true => Err(format!("{len} bytes exceed the limit of {}", T::MAX_LEN)),
+        false => Ok(()),
+    }
+}
+
+/// Parse `bytes`, see the [module documentation](self). Returns why they are invalid otherwise.
+pub fn parse<T: Bounded>(bytes: &[u8]) -> Result<T, String> {
+    check_len::<T>(bytes.len() as u64)?;
+    let value: T = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(T::MAX_LEN)
+        .reject_trailing_bytes()
+        .deserialize(bytes)
+        .map_err(|err| err.to_string())?;
+    value.validate()?;
+    Ok(value)
+}
+
+/// Fails if `items` holds more than `max` elements.
+pub fn check_count<T>(what: &str, items: &[T], max: usize) -> Result<(), String> {
+    match items.len() > max {
+        true => Err(format!("{} {what} exceed the limit of {max}", items.len())),
+        false => Ok(()),
+    }
+}
+
+/// Fails unless `md5` is a hex-encoded MD5.
+pub fn check_md5(md5: &str) -> Result<(), String> {
+    match md5.len() == 32 && md5.bytes().all(|b| b.is_ascii_hexdigit()) {
+        true => Ok(()),
+        false => Err(format!("invalid MD5 {md5:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
+
+    use super::*;
+    use crate::{
+        extent::{ManifestExtent, ManifestRoot},
+        mirror::BlockManifest,
+        prefetch::HotSet,
+        vfs::{Metadata, MetadataRecord, ReaderMetadata},
+        warm::{WarmPage, WarmSet},
+    };
+
+    /// Tracks the largest allocation of each thread, see [largest_allocation].
+    struct Counting;
+
+    thread_local! {
+        static LARGEST: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn track(size: usize) {
+        let _ = LARGEST.try_with(|largest| largest.set(largest.get().max(size)));
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            track(layout.size());
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            track(new_size);
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// The largest allocation `f` made on this thread.
+    fn largest_allocation<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        LARGEST.with(|largest| largest.set(0));
+        let out = f();
+        (out, LARGEST.with(Cell::get))
+    }
+
+    /// Parses `bytes` as a `T`, asserting that no allocation exceeds a small multiple of the
+    /// input, or the 1 MiB serde preallocates for collections at most. Collections of small
+    /// elements take more memory than their encoding, and grow by doubling.
+    fn parse_bounded<T: Bounded>(bytes: &[u8]) -> Result<T, String> {
+        let (parsed, largest) = largest_allocation(|| parse::<T>(bytes));
+        assert!(
+            largest <= (8 * bytes.len()).max(1 << 20),
+            "allocated {largest} bytes parsing {}",
+            bytes.len()
+        );
+        parsed
+    }
+
+    /// A parser of an encoding, checking it without keeping what it parsed.
+    type Check = fn(&[u8]) -> Result<(), String>;
+
+    /// The name of a format, a valid encoding, the offset of a length prefix in it, and its parser.
+    type Sample = (&'static str, Vec<u8>, usize, Check);
+
+    /// Valid encodings of each format.
+    fn samples() -> Vec<Sample> {
+        fn check<T: Bounded>(bytes: &[u8]) -> Result<(), String> {
+            parse_bounded::<T>(bytes).map(|_| ())
+        }
+        let manifest = BlockManifest::new(&[7; 100_000]);
+        let root = ManifestRoot {
+            block_size: manifest.block_size,
+            len: manifest.len,
+            md5: manifest.md5.clone(),
+            extent_blocks: 1,
+            extents: manifest.blocks.clone(),
+        };
+        let extent = ManifestExtent {
+            first: 1,
+            blocks: manifest.blocks[1..].to_vec(),
+        };
+        let hot = HotSet {
+            pages: vec![(0, 4096), (4096, 4096)],
+        };
+        let warm = WarmSet {
+            db: "test.db".to_owned(),
+            generation: 3,
+            pages: vec![WarmPage {
+                offset: 4096,
+                len: 4096,
+                md5: manifest.md5.clone(),
+                hits: 9,
+            }],
+        };
+        vec![
+            // the tag of the variant, then the readers
+            (
+                "metadata",
+                bincode::serialize(&record().metadata).unwrap(),
+                4,
+                check::<Metadata>,
+            ),
+            (
+                "metadata record",
+                bincode::serialize(&record()).unwrap(),
+                4,
+                check::<MetadataRecord>,
+            ),
+            // the block size and length, then the MD5
+            (
+                "block manifest",
+                bincode::serialize(&manifest).unwrap(),
+                16,
+                check::<BlockManifest>,
+            ),
+            // the body of a manifest root, with the MD5 at the same offset
+            (
+                "manifest root",
+                bincode::serialize(&root).unwrap(),
+                16,
+                check::<ManifestRoot>,
+            ),
+            // the first block, then the checksums
+            (
+                "manifest extent",
+                bincode::serialize(&extent).unwrap(),
+                8,
+                check::<ManifestExtent>,
+            ),
+            (
+                "hot set",
+                bincode::serialize(&hot).unwrap(),
+                0,
+                check::<HotSet>,
+            ),
+            // the name of the database, then the generation and the pages
+            (
+                "warm set",
+                bincode::serialize(&warm).unwrap(),
+                8 + 7 + 8,
+                check::<WarmSet>,
+            ),
+        ]
+    }
+
+    fn record() -> MetadataRecord {
+        MetadataRecord {
+            metadata: Metadata::Reader(ReaderMetadata {
+                readers: vec![vec![1], vec![2]],
+                write_request: None,
+            }),
+            reader_versions: vec![(vec![2], 2)],
+            pending_upgrade: None,
+            stamp: None,
+            write_request: None,
+            holder: None,
+            etag: None,
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate([]), 3);
+        assert_eq!(negotiate([3, 3]), 3);
+        // an old reader holds writers back
+        assert_eq!(negotiate([3, 2]), 2);
+        assert_eq!(negotiate([2, 1]), 1);
+        // readers from the future don't push writers past what they can write
+        assert_eq!(negotiate([7]), 3);
+    }
+
+    #[test]
+    fn test_old_reader_new_writer() {
+        // a new writer that had to fall back to version 1 must produce bytes old readers parse
+        let record = record();
+        let bytes = encode(1, &record.metadata).unwrap();
+        let old: Metadata = bincode::deserialize(&bytes).unwrap();
+        assert!(matches!(old, Metadata::Reader(r) if r.readers.len() == 2));
+    }
+
+    #[test]
+    fn test_new_reader_old_writer() {
+        let bytes = bincode::serialize(&Metadata::Writer(vec![3])).unwrap();
+        let (header, body) = decode_header(&bytes).unwrap();
+        assert_eq!(header, FormatHeader::V1);
+        assert!(matches!(decode_body(body).unwrap(), Metadata::Writer(id) if id == vec![3]));
+    }
+
+    #[test]
+    fn test_roundtrip_current() {
+        for version in 2..=3 {
+            let bytes = encode(version, &record()).unwrap();
+            let (header, body) = decode_header(&bytes).unwrap();
+            assert_eq!(header.writer_version, version);
+            let decoded: MetadataRecord = decode_body(body).unwrap();
+            assert_eq!(decoded.reader_versions, vec![(vec![2], 2)]);
+        }
+    }
+
+    /// The objects written with a single PUT, in the current format.
+    fn protocol_objects() -> Vec<(&'static str, Vec<u8>, Check)> {
+        fn check<T: Bounded>(bytes: &[u8]) -> Result<(), String> {
+            let (_, body) = decode_header(bytes).map_err(|err| err.to_string())?;
+            parse::<T>(body).map(|_| ())
+        }
+        let manifest = BlockManifest::new(&[7; 100_000]);
+        let root = ManifestRoot {
+            block_size: manifest.block_size,
+            len: manifest.len,
+            md5: manifest.md5.clone(),
+            extent_blocks: 1,
+            extents: manifest.blocks.clone(),
+        };
+        let version = *WRITE_VERSIONS.end();
+        vec![
+            (
+                "metadata record",
+                encode(version, &record()).unwrap(),
+                check::<MetadataRecord>,
+            ),
+            (
+                "metadata held back",
+                encode(version, &record().metadata).unwrap(),
+                check::<Metadata>,
+            ),
+            ("manifest root", encode(version, &root).unwrap(), |bytes| {
+                crate::extent::Manifest::decode(bytes).map(|_| ())
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_truncated_at_every_byte() {
+        for (what, bytes, check) in protocol_objects() {
+            check(&bytes).unwrap_or_else(|err| panic!("{what}: {err}"));
+            for len in 1..bytes.len() {
+                assert!(check(&bytes[..len]).is_err(), "{what} cut at {len}");
+            }
+            // a byte flipped anywhere fails the checksum, even where the body would still parse
+            for at in MAGIC.len()..bytes.len() {
+                let mut flipped = bytes.clone();
+                flipped[at] ^= 0x20;
+                assert!(check(&flipped).is_err(), "{what} flipped at {at}");
+            }
+        }
+        let bytes = encode(3, &record()).unwrap();
+        let err = decode_header(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{err}");
+        // only a prefix is described, which the checksum doesn't cover
+        assert!(matches!(
+            describe(&bytes[..12]),
+            ObjectKind::Versioned(FormatHeader {
+                writer_version: 3,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_protocol_objects_below_cap() {
+        let id = |n: usize| uuid::Uuid::from_u128(n as u128).to_bytes_le().to_vec();
+        let ids: Vec<_> = (0..crate::vfs::MAX_READERS).map(id).collect();
+        let crowded = MetadataRecord {
+            metadata: Metadata::Reader(ReaderMetadata {
+                readers: ids.clone(),
+                write_request: Some(id(0)),
+            }),
+            reader_versions: ids.iter().map(|id| (id.clone(), 3)).collect(),
+            pending_upgrade: Some(3),
+            ..record()
+        };
+        // the manifest root of the largest database a single PUT uploads, at the default extents
+        let config = crate::extent::ManifestConfig::default();
+        let len = crate::limits::MAX_PUT_BYTES;
+        let blocks = len.div_ceil(crate::mirror::BLOCK_SIZE);
+        let root = ManifestRoot {
+            block_size: crate::mirror::BLOCK_SIZE,
+            len,
+            md5: format!("{:x}", md5::compute(b"")),
+            extent_blocks: config.extent_blocks as u64,
+            extents: vec![
+                format!("{:x}", md5::compute(b""));
+                blocks.div_ceil(config.extent_blocks as u64) as usize
+            ],
+        };
+        let sizes = [
+            ("metadata record", encode(3, &crowded).unwrap().len()),
+            ("metadata", encode(3, &crowded.metadata).unwrap().len()),
+            ("manifest root", encode(3, &root).unwrap().len()),
+            ("lock object", 16),
+        ];
+        for (what, len) in sizes {
+            // leaves room for the formats to grow before anything gets near a multipart upload
+            assert!(
+                (len as u64) < MAX_PROTOCOL_OBJECT / 2,
+                "{what} takes {len} bytes, close to the cap of {MAX_PROTOCOL_OBJECT}"
+            );
+            assert!(check_single_put(what, &vec![0; len]).is_ok());
+        }
+        let err = check_single_put("metadata", &vec![0; MAX_PROTOCOL_OBJECT as usize + 1]);
+        assert!(err.unwrap_err().to_string().contains("single PUT"));
+    }
+
+    #[test]
+    fn test_describe() {
+        let db = crate::mock::database(4096, 3, 7);
+        assert_eq!(
+            describe(&db[..DESCRIBE_LEN]),
+            ObjectKind::Database(DatabaseHeader {
+                page_size: 4096,
+                change_counter: 7,
+                page_count: Some(3),
+                schema_cookie: 0,
+                user_version: 0,
+                application_id: 0,
+            })
+        );
+        let mut stale = db.clone();
+        stale[95] = 6;
+        assert!(matches!(
+            describe(&stale[..DESCRIBE_LEN]),
+            ObjectKind::Database(DatabaseHeader {
+                page_count: None,
+                ..
+            })
+        ));
+
+        let metadata = encode(2, &record()).unwrap();
+        assert!(matches!(
+            describe(&metadata),
+            ObjectKind::Versioned(FormatHeader {
+                writer_version: 2,
+                ..
+            })
+        ));
+        assert_eq!(describe(b"hello"), ObjectKind::Unknown);
+        assert_eq!(describe(&db[..50]), ObjectKind::Unknown);
+        assert_eq!(describe(&[0; DESCRIBE_LEN]), ObjectKind::Unknown);
+    }
+
+    #[test]
+    fn test_hostile_payloads() {
+        for (what, bytes, prefix, check) in samples() {
+            check(&bytes).unwrap_or_else(|err| panic!("{what}: {err}"));
+
+            let mut huge = bytes.clone();
+            huge[prefix..prefix + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+            assert!(check(&huge).is_err(), "{what}");
+            let truncated = &bytes[..bytes.len() / 2];
+            assert!(check(truncated).is_err(), "{what}");
+            let mut trailing = bytes.clone();
+            trailing.push(0);
+            assert!(check(&trailing).is_err(), "{what}");
+            // too many elements, each of them valid
+            let mut many = bytes[..prefix].to_vec();
+            many.extend(5_000_000u64.to_le_bytes());
+            many.resize(64 << 20, 0);
+            assert!(check(&many).is_err(), "{what}");
+        }
+
+        // each format declares its maximum
+        let err = parse::<HotSet>(&vec![0; 1 << 20]).unwrap_err();
+        assert!(err.contains("exceed the limit"), "{err}");
+        // and the sizes of the collections it holds
+        let mut manifest = BlockManifest::new(&[7; 100_000]);
+        manifest.blocks.pop();
+        let err = parse::<BlockManifest>(&bincode::serialize(&manifest).unwrap()).unwrap_err();
+        assert_eq!(err, "1 blocks, but 2 cover 100000 bytes");
+        let readers = Metadata::Reader(ReaderMetadata {
+            readers: vec![vec![1; 16]; 5000],
+            write_request: None,
+        });
+        let err = parse::<Metadata>(&bincode::serialize(&readers).unwrap())
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err, "5000 readers exceed the limit of 4096");
+        let hot = HotSet {
+            pages: vec![(u64::MAX, 4096)],
+        };
+        assert!(parse::<HotSet>(&bincode::serialize(&hot).unwrap()).is_err());
+        assert!(matches!(
+            WarmSet::decode(&[0xff; 64]),
+            Err(Error::CorruptManifest { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_metadata_object() {
+        let mock = crate::mock::MockS3::start();
+        let tq = crate::vfs::ThreeQLite::with_client(Default::default(), mock.client()).unwrap();
+        let mut bytes = encode(2, &record()).unwrap();
+        bytes[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        mock.put("metadata", bytes);
+        let err = tq.inner.read().await.read_metadata_record().await.err();
+        assert!(
+            matches!(&err, Some(Error::CorruptMetadata { key, .. }) if key == "metadata"),
+            "{err:?}"
+        );
+        mock.put("metadata", vec![7; 2 << 20]);
+        let err = tq.inner.read().await.read_metadata_record().await.err();
+        assert!(
+            matches!(&err, Some(Error::CorruptMetadata { reason, .. }) if reason.contains("limit")),
+            "{err:?}"
+        );
+        // cut short by an upload that timed out
+        let bytes = encode(3, &record()).unwrap();
+        mock.put("metadata", bytes[..bytes.len() - 6].to_vec());
+        let err = tq.inner.read().await.read_metadata_record().await.err();
+        assert!(
+            matches!(&err, Some(Error::CorruptMetadata { reason, .. }) if reason.contains("checksum")),
+            "{err:?}"
+        );
+    }
+
+    /// Mutates valid encodings of each format at random, asserting that parsing them neither
+    /// panics nor allocates more than it is bounded to.
+    #[test]
+    fn test_mutated_payloads() {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for (_, bytes, _, check) in samples() {
+            for _ in 0..2000 {
+                let mut mutated = bytes.clone();
+                for _ in 0..1 + next() % 4 {
+                    let at = next() as usize % mutated.len();
+                    match next() % 4 {
+                        0 => mutated[at] = next() as u8,
+                        1 => mutated.truncate(at),
+                        2 => {
+                            let end = (at + 8).min(mutated.len());
+                            mutated[at..end].fill(0xff);
+                        }
+                        _ => mutated.insert(at, next() as u8),
+                    }
+                    if mutated.is_empty() {
+                        break;
+                    }
+                }
+                let _ = check(&mutated);
+            }
+        }
+    }
+
+    #[test]
+    fn test_too_new_format() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(
+            bincode::serialize(&FormatHeader {
+                min_reader_version: 4,
+                writer_version: 5,
+            })
+            .unwrap(),
+        );
+        let err = decode_header(&bytes).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::IncompatibleFormat { need: 4, have: 3 }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "object requires format version 4, but this build reads up to version 3"
+        );
+    }
+}
@@ -0,0 +1,546 @@
+//! What the configuration of an instance guarantees.
+//!
+//! Whether a setup is safe for a workload depends on several settings at once: the
+//! [SyncPolicy] decides when commits are durable, [Config::degraded_reads] and read-only
+//! credentials weaken what readers see, and the legal hold of the lock only excludes other writers
+//! on a bucket with Object Lock enabled, unlike [conditional writes](crate::cas). [ThreeQLite::guarantees] sums them up as [Guarantees], computed by
+//! [Guarantees::of] from a [Setup]: the settings of the [Config] that matter, whether an offline
+//! mirror is enabled, and what the last [ThreeQLite::preflight] found about the bucket. A
+//! finding can downgrade a guarantee, e.g. a bucket without Object Lock leaves writes without a
+//! lock to take. The rendering is what the `guarantees` command of the CLI prints, and what an
+//! instance logs when it is registered.
+//!
+//! [ThreeQLite::guarantees]: crate::vfs::ThreeQLite::guarantees
+//! [ThreeQLite::preflight]: crate::vfs::ThreeQLite::preflight
+
+use std::{fmt::Display, time::Duration};
+
+use crate::{
+    config::{Config, LockBackend},
+    degraded::DegradedReadPolicy,
+    durability::SyncPolicy,
+};
+
+/// What [ThreeQLite::preflight](crate::vfs::ThreeQLite::preflight) found about the bucket.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Preflight {
+    /// Whether Object Lock is enabled on the bucket, which the legal hold on the lock object
+    /// needs. `None` if it couldn't be checked.
+    pub object_lock: Option<bool>,
+    /// The IDs of the lifecycle rules expiring objects of the database.
+    pub expiring_rules: Vec<String>,
+    /// The region of the bucket, see [crate::region]. `None` if it couldn't be resolved.
+    pub region: Option<String>,
+}
+
+/// What [Guarantees] are computed from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Setup {
+    pub synchronous: SyncPolicy,
+    pub paranoid_commit: bool,
+    pub write_request_lease: Duration,
+    pub lock_backend: LockBackend,
+    pub degraded_reads: Option<DegradedReadPolicy>,
+    pub read_externally_modified: bool,
+    /// The credentials are configured as unable to write, see
+    /// [ProbeConfig::assume_writable](crate::probe::ProbeConfig::assume_writable).
+    pub read_only: bool,
+    /// An offline mirror serves read-only opens while the object store is unreachable, see
+    /// [crate::mirror].
+    pub offline_mirror: bool,
+    /// `None` until [ThreeQLite::preflight](crate::vfs::ThreeQLite::preflight) ran.
+    pub preflight: Option<Preflight>,
+}
+
+impl Setup {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            synchronous: config.synchronous,
+            paranoid_commit: config.paranoid_commit,
+            write_request_lease: config.lock.write_request_lease,
+            lock_backend: config.lock.backend,
+            degraded_reads: config.degraded_reads.clone(),
+            read_externally_modified: config.read_externally_modified,
+            read_only: config.write_probe.assume_writable == Some(false),
+            offline_mirror: false,
+            preflight: None,
+        }
+    }
+}
+
+/// When a commit SQLite acknowledged is durable, see [crate::durability].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DurabilityPoint {
+    /// Once SQLite deletes the journal of a connection that syncs. A connection with `PRAGMA
+    /// synchronous=OFF` has its commits acknowledged before their pages upload.
+    OnSync,
+    /// Before SQLite deletes the journal or releases its lock, at any level of `PRAGMA
+    /// synchronous`, see [SyncPolicy::EnforceDurability].
+    Always,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Durability {
+    pub point: DurabilityPoint,
+    /// Each upload is checked with a HEAD request, see [crate::verify].
+    pub verified_uploads: bool,
+}
+
+/// What the transactions of readers see.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Isolation {
+    /// A transaction registers as a reader and sees the generation it joined at throughout,
+    /// while writers wait for it to leave.
+    Snapshot,
+    /// Like [Isolation::Snapshot], but while the metadata object is unreadable, a transaction
+    /// reads a generation of the offline mirror validated at most `max_age` ago and at most
+    /// `max_generation_lag` generations behind, see [crate::degraded].
+    SnapshotOrMirror {
+        max_age: Duration,
+        max_generation_lag: Option<u64>,
+    },
+    /// Without write access, transactions don't register as readers: a commit made while one
+    /// runs may show up in the pages it reads next.
+    Unregistered,
+}
+
+/// How a single writer is enforced, and what that assumes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriterExclusion {
+    /// A legal hold on the lock object serializes the updates of the metadata object, in which
+    /// the writer takes the lock; a writer waiting for readers keeps new ones out with a request
+    /// leased for `lease`. Assumes Object Lock on the bucket, and wall clocks agreeing to within a
+    /// fraction of the lease. An instance crashing while it holds the legal hold leaves it set.
+    LegalHold { lease: Duration },
+    /// Like [WriterExclusion::LegalHold], but conditional writes of the lock object serialize the
+    /// updates of the metadata object, see [crate::cas]. Needs no Object Lock, and a lock object
+    /// left by a crash is taken over after `lease`.
+    Conditional { lease: Duration },
+    /// The bucket has no Object Lock, so the legal hold can't be set: writes fail rather than go
+    /// unserialized.
+    Unavailable,
+    /// The instance can't write.
+    ReadOnly,
+}
+
+/// What a crash in the middle of a commit leaves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// A hot journal, which the next writer rolls back.
+    Journal,
+    /// A hot journal, except for a connection that skipped the sync: its pages upload after the
+    /// journal was deleted, and a crash then tears the database.
+    JournalIfSynced,
+    /// Nothing to recover, since the instance doesn't write.
+    ReadOnly,
+}
+
+/// A known way the guarantees fall short.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Caveat {
+    /// Commits without a sync are acknowledged before they are durable, logged once per
+    /// connection if `warned`.
+    UnsyncedCommits { warned: bool },
+    /// Lifecycle rules expire objects of the database, which loses the lock state once the
+    /// metadata object expires.
+    ExpiringObjects { rules: Vec<String> },
+    /// Object Lock wasn't checked, so the lock may not hold.
+    ObjectLockUnchecked,
+    /// Reads of a database object modified out-of-band are served, see [crate::heal].
+    ExternallyModifiedReads,
+    /// While the object store is unreachable, read-only opens read the offline mirror, as of the
+    /// generation it was last synced at.
+    OfflineReads,
+}
+
+/// What a configuration guarantees, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Guarantees {
+    pub durability: Durability,
+    pub isolation: Isolation,
+    pub writers: WriterExclusion,
+    pub recovery: Recovery,
+    pub caveats: Vec<Caveat>,
+}
+
+impl Guarantees {
+    pub fn of(setup: &Setup) -> Self {
+        let point = match setup.synchronous {
+            SyncPolicy::EnforceDurability => DurabilityPoint::Always,
+            SyncPolicy::Allow | SyncPolicy::Warn => DurabilityPoint::OnSync,
+        };
+        let isolation = match &setup.degraded_reads {
+            _ if setup.read_only => Isolation::Unregistered,
+            Some(policy) => Isolation::SnapshotOrMirror {
+                max_age: policy.max_age,
+                max_generation_lag: policy.max_generation_lag,
+            },
+            None => Isolation::Snapshot,
+        };
+        let preflight = setup.preflight.as_ref();
+        let object_lock = preflight.and_then(|preflight| preflight.object_lock);
+        let lease = setup.write_request_lease;
+        let writers = match (setup.lock_backend, object_lock) {
+            _ if setup.read_only => WriterExclusion::ReadOnly,
+            (LockBackend::Conditional, _) => WriterExclusion::Conditional { lease },
+            (LockBackend::LegalHold, Some(false)) => WriterExclusion::Unavailable,
+            (LockBackend::LegalHold, Some(true) | None) => WriterExclusion::LegalHold { lease },
+        };
+        let recovery = match (writers, point) {
+            (WriterExclusion::ReadOnly | WriterExclusion::Unavailable, _) => Recovery::ReadOnly,
+            (_, DurabilityPoint::Always) => Recovery::Journal,
+            (_, DurabilityPoint::OnSync) => Recovery::JournalIfSynced,
+        };
+
+        let mut caveats = Vec::new();
+        let writes = recovery != Recovery::ReadOnly;
+        if writes && point == DurabilityPoint::OnSync {
+            caveats.push(Caveat::UnsyncedCommits {
+                warned: setup.synchronous == SyncPolicy::Warn,
+            });
+        }
+        if let Some(preflight) = preflight.filter(|p| !p.expiring_rules.is_empty()) {
+            caveats.push(Caveat::ExpiringObjects {
+                rules: preflight.expiring_rules.clone(),
+            });
+        }
+        if matches!(writers, WriterExclusion::LegalHold { .. }) && object_lock.is_none() {
+            caveats.push(Caveat::ObjectLockUnchecked);
+        }
+        if setup.read_externally_modified {
+            caveats.push(Caveat::ExternallyModifiedReads);
+        }
+        if setup.offline_mirror {
+            caveats.push(Caveat::OfflineReads);
+        }
+        Self {
+            durability: Durability {
+                point,
+                verified_uploads: setup.paranoid_commit,
+            },
+            isolation,
+            writers,
+            recovery,
+            caveats,
+        }
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.point {
+            DurabilityPoint::OnSync => f.write_str(
+                "commits are durable once SQLite deletes the journal, unless the connection \
+                 skips the sync (PRAGMA synchronous=OFF)",
+            )?,
+            DurabilityPoint::Always => f.write_str(
+                "commits are durable before SQLite deletes the journal or releases its lock, at \
+                 any level of PRAGMA synchronous",
+            )?,
+        }
+        if self.verified_uploads {
+            f.write_str(", each upload checked with a HEAD request")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Isolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Snapshot => f.write_str(
+                "snapshot: a transaction reads the generation it registered at, writers wait for it",
+            ),
+            Self::SnapshotOrMirror {
+                max_age,
+                max_generation_lag,
+            } => {
+                write!(
+                    f,
+                    "snapshot, or while the metadata object is unreadable a generation of the \
+                     offline mirror validated within {max_age:?}"
+                )?;
+                match max_generation_lag {
+                    Some(lag) => write!(f, " and at most {lag} generations behind"),
+                    None => f.write_str(" and any number of generations behind"),
+                }
+            }
+            Self::Unregistered => f.write_str(
+                "unregistered: without write access, transactions may see pages of commits made \
+                 while they run",
+            ),
+        }
+    }
+}
+
+impl Display for WriterExclusion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LegalHold { lease } => write!(
+                f,
+                "legal hold on the lock object, write requests leased for {lease:?}; assumes \
+                 Object Lock and clocks agreeing within a fraction of the lease, and a crash \
+                 while holding it leaves it set"
+            ),
+            Self::Conditional { lease } => write!(
+                f,
+                "conditional writes of the lock object, write requests leased for {lease:?}; \
+                 assumes clocks agreeing within a fraction of the lease"
+            ),
+            Self::Unavailable => {
+                f.write_str("none: the bucket has no Object Lock, so writes can't take the lock")
+            }
+            Self::ReadOnly => f.write_str("none needed: the instance doesn't write"),
+        }
+    }
+}
+
+impl Display for Recovery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Journal => "a crash mid-commit leaves a hot journal the next writer rolls back",
+            Self::JournalIfSynced => {
+                "a crash mid-commit leaves a hot journal the next writer rolls back, except for \
+                 a connection that skipped the sync, which leaves the database torn"
+            }
+            Self::ReadOnly => "nothing to recover, the instance doesn't write",
+        })
+    }
+}
+
+impl Display for Caveat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsyncedCommits { warned } => {
+                f.write_str("commits without a sync are acknowledged before they are durable")?;
+                if *warned {
+                    f.write_str(" (logged once per connection)")?;
+                }
+                Ok(())
+            }
+            Self::ExpiringObjects { rules } => write!(
+                f,
+                "lifecycle rules {} expire objects of the database, losing the lock state",
+                rules.join(", ")
+            ),
+            Self::ObjectLockUnchecked => {
+                f.write_str("Object Lock wasn't checked, run preflight to confirm the lock holds")
+            }
+            Self::ExternallyModifiedReads => {
+                f.write_str("reads of a database object modified out-of-band are served")
+            }
+            Self::OfflineReads => f.write_str(
+                "while the object store is unreachable, read-only opens read the offline mirror \
+                 as of its last sync",
+            ),
+        }
+    }
+}
+
+impl Display for Guarantees {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "durability: {}", self.durability)?;
+        writeln!(f, "isolation: {}", self.isolation)?;
+        writeln!(f, "writers: {}", self.writers)?;
+        write!(f, "recovery: {}", self.recovery)?;
+        for caveat in &self.caveats {
+            write!(f, "\ncaveat: {caveat}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::LockConfig, mock::MockS3, probe::ProbeConfig, vfs::ThreeQLite};
+
+    fn checked(object_lock: bool) -> Option<Preflight> {
+        Some(Preflight {
+            object_lock: Some(object_lock),
+            expiring_rules: vec![],
+            region: None,
+        })
+    }
+
+    #[test]
+    fn test_guarantees_of_setups() {
+        let defaults = Setup {
+            preflight: checked(true),
+            ..Setup::new(&Config::default())
+        };
+        let lease = LockConfig::default().write_request_lease;
+        let legal_hold = WriterExclusion::LegalHold { lease };
+        let on_sync = Durability {
+            point: DurabilityPoint::OnSync,
+            verified_uploads: false,
+        };
+        let unsynced = Caveat::UnsyncedCommits { warned: false };
+        let cases = [
+            (
+                "defaults",
+                defaults.clone(),
+                (on_sync, Isolation::Snapshot, legal_hold),
+                Recovery::JournalIfSynced,
+                vec![unsynced.clone()],
+            ),
+            (
+                "enforced durability, verified uploads",
+                Setup {
+                    synchronous: SyncPolicy::EnforceDurability,
+                    paranoid_commit: true,
+                    ..defaults.clone()
+                },
+                (
+                    Durability {
+                        point: DurabilityPoint::Always,
+                        verified_uploads: true,
+                    },
+                    Isolation::Snapshot,
+                    legal_hold,
+                ),
+                Recovery::Journal,
+                vec![],
+            ),
+            (
+                "warned, degraded reads",
+                Setup {
+                    synchronous: SyncPolicy::Warn,
+                    degraded_reads: Some(DegradedReadPolicy::default()),
+                    offline_mirror: true,
+                    ..defaults.clone()
+                },
+                (
+                    on_sync,
+                    Isolation::SnapshotOrMirror {
+                        max_age: Duration::from_secs(30),
+                        max_generation_lag: Some(0),
+                    },
+                    legal_hold,
+                ),
+                Recovery::JournalIfSynced,
+                vec![
+                    Caveat::UnsyncedCommits { warned: true },
+                    Caveat::OfflineReads,
+                ],
+            ),
+            (
+                "preflight not run",
+                Setup {
+                    preflight: None,
+                    ..defaults.clone()
+                },
+                (on_sync, Isolation::Snapshot, legal_hold),
+                Recovery::JournalIfSynced,
+                vec![unsynced.clone(), Caveat::ObjectLockUnchecked],
+            ),
+            (
+                "no Object Lock, expiring objects",
+                Setup {
+                    preflight: Some(Preflight {
+                        object_lock: Some(false),
+                        expiring_rules: vec!["expire-30d".to_owned()],
+                        region: None,
+                    }),
+                    ..defaults.clone()
+                },
+                (on_sync, Isolation::Snapshot, WriterExclusion::Unavailable),
+                Recovery::ReadOnly,
+                vec![Caveat::ExpiringObjects {
+                    rules: vec!["expire-30d".to_owned()],
+                }],
+            ),
+            (
+                "conditional writes, no Object Lock",
+                Setup {
+                    lock_backend: LockBackend::Conditional,
+                    preflight: checked(false),
+                    ..defaults.clone()
+                },
+                (
+                    on_sync,
+                    Isolation::Snapshot,
+                    WriterExclusion::Conditional { lease },
+                ),
+                Recovery::JournalIfSynced,
+                vec![unsynced.clone()],
+            ),
+            (
+                "read-only credentials",
+                Setup::new(&Config {
+                    write_probe: ProbeConfig {
+                        assume_writable: Some(false),
+                        ..ProbeConfig::default()
+                    },
+                    read_externally_modified: true,
+                    ..Config::default()
+                }),
+                (on_sync, Isolation::Unregistered, WriterExclusion::ReadOnly),
+                Recovery::ReadOnly,
+                vec![Caveat::ExternallyModifiedReads],
+            ),
+        ];
+        for (name, setup, (durability, isolation, writers), recovery, caveats) in cases {
+            assert_eq!(
+                Guarantees::of(&setup),
+                Guarantees {
+                    durability,
+                    isolation,
+                    writers,
+                    recovery,
+                    caveats
+                },
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preflight_downgrades() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        assert!(matches!(
+            tq.guarantees().writers,
+            WriterExclusion::LegalHold { .. }
+        ));
+        assert!(tq
+            .guarantees()
+            .caveats
+            .contains(&Caveat::ObjectLockUnchecked));
+
+        tq.preflight().await.unwrap();
+        assert_eq!(tq.guarantees().writers, WriterExclusion::Unavailable);
+        assert_eq!(tq.guarantees().recovery, Recovery::ReadOnly);
+
+        mock.set_object_lock(true);
+        tq.preflight().await.unwrap();
+        let guarantees = tq.guarantees();
+        assert!(matches!(
+            guarantees.writers,
+            WriterExclusion::LegalHold { .. }
+        ));
+        assert!(!guarantees.caveats.contains(&Caveat::ObjectLockUnchecked));
+    }
+
+    #[test]
+    fn test_display() {
+        let setup = Setup {
+            preflight: Some(Preflight {
+                object_lock: Some(false),
+                expiring_rules: vec!["a".to_owned(), "b".to_owned()],
+                region: None,
+            }),
+            ..Setup::new(&Config::default())
+        };
+        assert_eq!(
+            Guarantees::of(&setup).to_string(),
+            "durability: commits are durable once SQLite deletes the journal, unless the \
+             connection skips the sync (PRAGMA synchronous=OFF)\n\
+             isolation: snapshot: a transaction reads the generation it registered at, writers \
+             wait for it\n\
+             writers: none: the bucket has no Object Lock, so writes can't take the lock\n\
+             recovery: nothing to recover, the instance doesn't write\n\
+             caveat: lifecycle rules a, b expire objects of the database, losing the lock state"
+        );
+    }
+}
@@ -1,11 +1,500 @@
-use sqlite_vfs::DatabaseHandle;
+use std::sync::{atomic::Ordering::Relaxed, Arc, Mutex};
 
-use crate::{error::Error, vfs::ThreeQLite, wal::WalIndex};
+use sqlite_vfs::{busy::BusyHandlerRef, DatabaseHandle, LockKind};
 
-#[derive(Clone)]
+use crate::{
+    busy,
+    cache::{CacheUse, ScanDetector},
+    circuit::OpClass,
+    degraded::{self, DegradedRead},
+    durability::{Buffered, Synchronous},
+    error::Error,
+    format::{self, DatabaseHeader, ObjectKind},
+    journal::Journal,
+    key::ObjectKey,
+    latency::{self, Phase, Timings, TransactionBreakdown},
+    limits::{self, TransactionBudget},
+    mirror::{BlockManifest, Mirror},
+    prefetch::{self, HotSet, Learner},
+    registration, sector,
+    session::Participant,
+    shutdown::{Listed, OpenHandle},
+    spend::{self, Dimension, Spend, SpendBudget},
+    stats::Stats,
+    temp::TempFile,
+    verify::{Upload, WriteClass},
+    vfs::{status, Metadata, ThreeQLite},
+    wal::WalIndex,
+};
+
+/// Map a storage error to the error reported to SQLite. An open circuit is reported as busy so
+/// that SQLite's busy handler gets a chance to retry once the backend recovered, just like a lock
+/// that could not be acquired in time. A transaction
+/// exceeding its size limit is reported as a full disk.
+fn storage_error(err: Error) -> sqlite_vfs::error::Error<Error> {
+    match err {
+        err @ (Error::CircuitOpen { .. } | Error::Busy { .. }) => {
+            sqlite_vfs::error::Error::Busy { cause: err }
+        }
+        err @ Error::TransactionTooLarge { .. } => sqlite_vfs::error::Error::Full { cause: err },
+        err @ Error::OutOfMemory { .. } => sqlite_vfs::error::Error::NoMem { cause: err },
+        err @ Error::CredentialsExpired { .. } => sqlite_vfs::error::Error::Auth { cause: err },
+        err => sqlite_vfs::error::Error::External { cause: err },
+    }
+}
+
+/// The storage error behind an error of the [sqlite_vfs] interface, the inverse of
+/// [storage_error].
+pub(crate) fn storage_cause(err: sqlite_vfs::error::Error<Error>) -> Error {
+    use sqlite_vfs::error::Error as Vfs;
+    match err {
+        Vfs::External { cause }
+        | Vfs::Busy { cause }
+        | Vfs::Full { cause }
+        | Vfs::NoMem { cause }
+        | Vfs::Auth { cause } => cause,
+        err => Error::Whatever {
+            message: err.to_string(),
+            source: None,
+        },
+    }
+}
+
+/// A database file opened by SQLite through [ThreeQLite].
+///
+/// A handle owns the lock SQLite acquired through it, so it is deliberately not `Clone`: two
+/// copies would each believe they hold the lock and release it twice. Dropping a handle that
+/// still holds a lock releases the remote lock.
+///
+/// ```compile_fail
+/// fn copy(handle: &threeqlite::handle::Handle) -> threeqlite::handle::Handle {
+///     handle.clone()
+/// }
+/// ```
 pub struct Handle {
     pub storage: ThreeQLite,
-    pub obj_key: String,
+    pub obj_key: ObjectKey,
+    /// Opened without write permission. Reads don't register as a reader, since that is a write.
+    pub readonly: bool,
+    /// Serving reads from this offline mirror, since the object store was unreachable on open.
+    pub mirror: Option<Arc<Mirror>>,
+    /// A journal rather than a database, see [crate::journal].
+    pub journal: Option<Journal>,
+    /// A temporary file rather than a database, see [crate::temp].
+    pub temp: Option<TempFile>,
+    lock: LockKind,
+    /// Shares the remote lock with the other handles of the object, see [crate::session].
+    session: Participant,
+    /// Where the time of the running transaction went so far.
+    timings: Arc<Mutex<Timings>>,
+    last_transaction: Option<TransactionBreakdown>,
+    /// What the running transaction wrote so far, see [crate::limits].
+    budget: Option<TransactionBudget>,
+    /// The database header as of the last read of page 1 or commit that rewrote it.
+    header: Option<DatabaseHeader>,
+    /// Serving the running transaction from the mirror, see [crate::degraded].
+    degraded: Option<(DegradedRead, Arc<Mirror>)>,
+    /// Tags scans for the page cache, see [crate::cache].
+    scan: ScanDetector,
+    /// Asked while waiting for the lock, see [crate::busy].
+    busy_handler: Option<BusyHandlerRef>,
+    /// Pages written since the last flush, see [crate::flush], shared with the commit points of
+    /// [crate::durability].
+    pub buffered: Arc<Mutex<Buffered>>,
+    /// Learns the hot set, created with it on the first registered read, see [crate::prefetch].
+    learner: Option<Learner>,
+    hot_set: HotSet,
+    /// What the connection spent against its budget, set by `PRAGMA threeqlite_budget`, see
+    /// [crate::spend].
+    spend: Option<Arc<Spend>>,
+    /// Listed among the handles open until dropped, see [crate::shutdown].
+    _listed: Listed,
+}
+
+impl Handle {
+    pub fn new(storage: ThreeQLite, obj_key: ObjectKey, readonly: bool) -> Self {
+        let listed = storage.handles.list(OpenHandle {
+            db: obj_key.to_string(),
+            vfs: (storage.registration.as_ref())
+                .map(|registration| registration.config.name.clone()),
+            readonly,
+        });
+        let session = storage.sessions.join(&obj_key);
+        Self {
+            storage,
+            obj_key,
+            readonly,
+            mirror: None,
+            journal: None,
+            temp: None,
+            lock: LockKind::None,
+            session,
+            timings: Arc::default(),
+            last_transaction: None,
+            budget: None,
+            header: None,
+            degraded: None,
+            scan: ScanDetector::default(),
+            busy_handler: None,
+            buffered: Arc::default(),
+            learner: None,
+            hot_set: HotSet::default(),
+            spend: None,
+            _listed: listed,
+        }
+    }
+
+    /// A read-only handle served from `mirror`, see [crate::mirror].
+    pub fn offline(storage: ThreeQLite, obj_key: ObjectKey, mirror: Arc<Mirror>) -> Self {
+        let mut handle = Self::new(storage, obj_key, true);
+        handle.mirror = Some(mirror);
+        handle
+    }
+
+    /// A handle to `journal`.
+    pub fn journal(storage: ThreeQLite, journal: Journal) -> Self {
+        let mut handle = Self::new(storage, journal.key.clone(), false);
+        handle.journal = Some(journal);
+        handle
+    }
+
+    /// A handle to the temporary file `file`, which SQLite named `key`.
+    pub fn temp(storage: ThreeQLite, key: ObjectKey, file: TempFile) -> Self {
+        let mut handle = Self::new(storage, key, false);
+        handle.temp = Some(file);
+        handle
+    }
+
+    fn reject_offline(&self) -> Result<(), sqlite_vfs::error::Error<Error>> {
+        match self.mirror {
+            Some(_) => Err(sqlite_vfs::error::Error::External {
+                cause: Error::Offline {
+                    db: self.obj_key.to_string(),
+                },
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// The database header, once page 1 has been read or written through this handle.
+    pub fn header(&self) -> Option<DatabaseHeader> {
+        self.header
+    }
+
+    /// Keep the header in `bytes`, read from the start of the database, noting a change of the
+    /// page size.
+    fn update_header(&mut self, bytes: &[u8], stats: &Stats) -> Result<(), Error> {
+        let ObjectKind::Database(header) = format::describe(bytes) else {
+            return Err(Error::CorruptHeader {
+                db: self.obj_key.to_string(),
+            });
+        };
+        if let Some(old) = self.header.filter(|old| old.page_size != header.page_size) {
+            tracing::info!(
+                target: "threeqlite::s3",
+                key = %self.obj_key,
+                from = old.page_size,
+                to = header.page_size,
+                "page size changed"
+            );
+            Stats::incr(&stats.page_size_changes);
+        }
+        self.header = Some(header);
+        Ok(())
+    }
+
+    /// Read back and validate the header after a transaction that rewrote it. SQLite changes the
+    /// page size by rewriting the whole database, e.g. on `VACUUM` after `PRAGMA page_size`, so
+    /// anything derived from the old header is stale.
+    async fn refresh_header(&mut self) -> Result<(), Error> {
+        let (bytes, stats) = {
+            let mut inner = self.storage.inner.write().await;
+            let bytes = inner
+                .read_exact_at(0, format::DESCRIBE_LEN, false, CacheUse::Bypass, None)
+                .await?;
+            (bytes, inner.stats.clone())
+        };
+        self.update_header(&bytes, &stats)
+    }
+
+    /// Fail writes while the transaction is served from the mirror.
+    fn reject_degraded(&self) -> Result<(), sqlite_vfs::error::Error<Error>> {
+        match &self.degraded {
+            Some((read, _)) => Err(sqlite_vfs::error::Error::External {
+                cause: Error::DegradedReadOnly {
+                    db: self.obj_key.to_string(),
+                    generation: read.generation,
+                },
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Serve a read that failed with `err` because the metadata object was unreadable from the
+    /// mirror, if the policy allows it, see [crate::degraded]. Fails with `err` otherwise.
+    async fn read_degraded(
+        &mut self,
+        err: Error,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> Result<(), Error> {
+        let (policy, mirror, newest, stats) = {
+            let inner = self.storage.inner.read().await;
+            (
+                inner.degraded_reads.clone(),
+                inner.mirror(self.obj_key.as_str()),
+                inner
+                    .generation_seen
+                    .load(std::sync::atomic::Ordering::Relaxed),
+                inner.stats.clone(),
+            )
+        };
+        let original = |err| match err {
+            Error::MetadataUnavailable { source } => *source,
+            err => err,
+        };
+        let (Some(policy), Some(mirror)) = (policy, mirror) else {
+            return Err(original(err));
+        };
+        let read = match degraded::admit(&policy, &mirror.status().await, newest) {
+            Ok(read) => read,
+            Err(reason) => {
+                tracing::debug!(target: "threeqlite::lock_protocol", key = %self.obj_key, reason, "not serving degraded read");
+                return Err(original(err));
+            }
+        };
+        tracing::warn!(
+            target: "threeqlite::lock_protocol",
+            key = %self.obj_key,
+            %err,
+            %read,
+            "serving transaction from the mirror"
+        );
+        Stats::incr(&stats.degraded_transactions);
+        self.degraded = Some((read, mirror));
+        self.read_pinned(buf, offset).await
+    }
+
+    /// Read from the mirror serving the transaction, failing if it moved on from the generation
+    /// the transaction started at.
+    async fn read_pinned(&self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        let Some((read, mirror)) = &self.degraded else {
+            unreachable!("only called while degraded");
+        };
+        latency::scope(
+            self.timings.clone(),
+            latency::timed(Phase::StorageRead, async {
+                if mirror.status().await.generation != Some(read.generation) {
+                    return Err(Error::MirrorUnavailable {
+                        db: self.obj_key.to_string(),
+                        reason: format!("the mirror moved on from generation {}", read.generation),
+                    });
+                }
+                mirror.read_exact_at(buf, offset).await
+            }),
+        )
+        .await
+    }
+
+    /// Upload the pages written since the last flush, see [crate::flush]. They stay buffered if
+    /// the flush fails.
+    async fn flush(&mut self) -> Result<(), Error> {
+        let pending = std::mem::take(&mut self.buffered.lock().unwrap().pages);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        // shared with the reads of the instance while it uploads, see [crate::inflight]
+        let pending = Arc::new(pending);
+        let report = latency::scope(
+            self.timings.clone(),
+            busy::with_handler(
+                self.busy_handler.clone(),
+                registration::scope(
+                    self.storage.registration.clone(),
+                    spend::scope(
+                        self.spend.clone(),
+                        self.storage.flush_pages(&self.obj_key, &pending),
+                    ),
+                ),
+            ),
+        )
+        .await;
+        let report = match report {
+            Ok(report) => report,
+            Err(err) => {
+                self.buffered.lock().unwrap().pages = Arc::unwrap_or_clone(pending);
+                return Err(err);
+            }
+        };
+        tracing::debug!(
+            target: "threeqlite::s3",
+            key = %self.obj_key,
+            bytes = pending.bytes(),
+            uploads = report.round_trips(),
+            elapsed = ?report.elapsed,
+            "flushed pages"
+        );
+        self.buffered.lock().unwrap().counted = false;
+        Ok(())
+    }
+
+    /// End the running transaction and report its latency breakdown.
+    async fn finish_transaction(&mut self) {
+        let wrote = self.budget.take().is_some();
+        if self
+            .learner
+            .as_mut()
+            .is_some_and(|learner| learner.finish(wrote))
+        {
+            let set = self.learner.as_ref().unwrap().hot_set();
+            let inner = self.storage.inner.read().await;
+            if let Err(err) = prefetch::publish(&inner, &self.obj_key, &set).await {
+                tracing::warn!(target: "threeqlite::s3", key = %self.obj_key, %err, "publishing hot set failed");
+            }
+            self.hot_set = set;
+        }
+        let degraded = self.degraded.take();
+        let Some(mut breakdown) = self.timings.lock().unwrap().finish() else {
+            return;
+        };
+        breakdown.degraded = degraded.map(|(read, _)| read.generation);
+        let (stats, transactions) = {
+            let inner = self.storage.inner.read().await;
+            (inner.stats.clone(), inner.transactions.clone())
+        };
+        stats.record_transaction(&breakdown);
+        let observer = self.storage.registration.as_ref().and_then(|registration| {
+            Stats::incr(&registration.stats.transactions);
+            registration.config.observer.clone()
+        });
+        transactions.finished(self.obj_key.as_str(), &breakdown, observer);
+        self.last_transaction = Some(breakdown);
+    }
+
+    /// Whether this handle takes the remote lock. Journals are covered by the lock of their
+    /// database, temporary files are never shared, mirrors are read offline, and reading without
+    /// write permission doesn't register.
+    fn locks_remotely(&self) -> bool {
+        self.journal.is_none() && self.temp.is_none() && self.mirror.is_none() && !self.readonly
+    }
+
+    /// Release the remote lock backing the lock held by this handle, if any, unless another
+    /// handle of the object still holds it, see [crate::session].
+    async fn release(&mut self) -> Result<(), Error> {
+        self.lock = LockKind::None;
+        let change = self.session.lock(LockKind::None);
+        self.move_remote(change.before, change.after).await
+    }
+
+    /// Move the remote lock of the instance as the lock of its handles moved from `before` to
+    /// `after`, see [crate::session]. Remotely, [LockKind::Shared] registers as a reader and
+    /// anything above takes the write lock, which keeps new readers out already.
+    ///
+    /// A reader upgrades in place, see [Inner::request_write_lock]. A writer going back to
+    /// [LockKind::Shared] commits, then registers as a reader again: another writer may commit in
+    /// between, as SQLite never keeps reading across the end of a write transaction.
+    ///
+    /// [Inner::request_write_lock]: crate::vfs::Inner::request_write_lock
+    async fn move_remote(&mut self, before: LockKind, after: LockKind) -> Result<(), Error> {
+        let remote = |lock| match lock {
+            LockKind::None | LockKind::Shared => lock,
+            LockKind::Reserved | LockKind::Pending | LockKind::Exclusive => LockKind::Exclusive,
+        };
+        let (before, after) = (remote(before), remote(after));
+        if before == after {
+            return Ok(());
+        }
+        let storage = &self.storage;
+        let moved = busy::with_handler(
+            self.busy_handler.clone(),
+            registration::scope(storage.registration.clone(), async {
+                let mut inner = storage.inner.write().await;
+                if after == LockKind::None && inner.current_lock.is_none() {
+                    // already released, e.g. by a failed request
+                    return Ok(());
+                }
+                // boxed, like reads, keeping the future of a lock small enough for the stack
+                latency::timed(Phase::LockWait, async {
+                    match (before, after) {
+                        (_, LockKind::Exclusive) => Box::pin(inner.request_write_lock()).await,
+                        (LockKind::Shared, LockKind::None) => {
+                            Box::pin(inner.release_read_lock()).await
+                        }
+                        (_, LockKind::None) => Box::pin(inner.release_write_lock()).await,
+                        (_, _) => {
+                            if before == LockKind::Exclusive {
+                                Box::pin(inner.release_write_lock()).await?;
+                            }
+                            inner.held_generation = Box::pin(inner.request_read_lock()).await?;
+                            Ok(())
+                        }
+                    }
+                })
+                .await
+            }),
+        );
+        // releasing doesn't start a transaction
+        match after != LockKind::None || self.timings.lock().unwrap().running() {
+            true => latency::scope(self.timings.clone(), moved).await,
+            false => moved.await,
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        if let Some(journal) = self.journal.take() {
+            // SQLite closes the journal before deleting it, see [crate::durability]
+            self.storage.barriers.closed(journal);
+        }
+        let pending = self.buffered.lock().unwrap().pages.bytes();
+        if pending > 0 {
+            tracing::warn!(
+                target: "threeqlite::s3",
+                key = %self.obj_key,
+                bytes = pending,
+                "handle dropped with pages that were never synced, discarding them"
+            );
+        }
+        if self.lock == LockKind::None {
+            return;
+        }
+        tracing::debug!(
+            target: "threeqlite::lock_protocol",
+            key = %self.obj_key,
+            lock = ?self.lock,
+            "handle dropped while holding a lock, releasing"
+        );
+
+        // Drop may run inside or outside of a runtime, so drive the release on a thread of its own.
+        let res = std::thread::scope(|s| {
+            s.spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .map_err(|err| Error::Whatever {
+                        message: format!("failed to start runtime: {err}"),
+                        source: None,
+                    })?
+                    .block_on(self.release())
+            })
+            .join()
+        });
+        match res {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::warn!(
+                target: "threeqlite::lock_protocol",
+                key = %self.obj_key,
+                %err,
+                "releasing lock of dropped handle failed"
+            ),
+            Err(_) => tracing::error!(
+                target: "threeqlite::lock_protocol",
+                key = %self.obj_key,
+                "releasing lock of dropped handle panicked"
+            ),
+        }
+    }
 }
 
 impl DatabaseHandle for Handle {
@@ -13,9 +502,34 @@ impl DatabaseHandle for Handle {
     type Error = crate::error::Error;
 
     async fn size(&mut self) -> Result<u64, sqlite_vfs::error::Error<Self::Error>> {
-        let size = self.storage.inner.write().await.get_database_size().await;
+        if let Some(mirror) = &self.mirror {
+            return Ok(mirror.size().await);
+        }
+        if let Some(journal) = &self.journal {
+            return Ok(journal.size());
+        }
+        if let Some(temp) = &self.temp {
+            return Ok(temp.size());
+        }
+        if let Some((_, mirror)) = &self.degraded {
+            return Ok(mirror.size().await);
+        }
+        let size = latency::scope(
+            self.timings.clone(),
+            busy::with_handler(
+                self.busy_handler.clone(),
+                spend::scope(self.spend.clone(), async {
+                    // boxed, like a read
+                    Box::pin(self.storage.inner.write().await.get_database_size()).await
+                }),
+            ),
+        )
+        .await;
         match size {
-            Ok(size) => Ok(size as u64),
+            Ok(size) => {
+                let end = self.buffered.lock().unwrap().pages.end();
+                Ok((size as u64).max(end.unwrap_or(0)))
+            }
             Err(e) => Err(sqlite_vfs::error::Error::External {
                 cause: crate::error::Error::FailedToGetDatabaseSize { msg: e.to_string() },
             }),
@@ -27,24 +541,95 @@ impl DatabaseHandle for Handle {
         buf: &mut [u8],
         offset: u64,
     ) -> Result<(), sqlite_vfs::error::Error<Self::Error>> {
-        let data = self
-            .storage
-            .inner
-            .write()
-            .await
-            .read_exact_at(offset as usize, buf.len())
-            .await;
+        if let Some(mirror) = &self.mirror {
+            return mirror
+                .read_exact_at(buf, offset)
+                .await
+                .map_err(storage_error);
+        }
+        if let Some(journal) = &self.journal {
+            return match journal.read_at(buf, offset) {
+                true => Ok(()),
+                false => Err(sqlite_vfs::error::Error::UnexpectedEof),
+            };
+        }
+        if let Some(temp) = &self.temp {
+            return match temp.read_at(buf, offset) {
+                true => Ok(()),
+                false => Err(sqlite_vfs::error::Error::UnexpectedEof),
+            };
+        }
+        if self.degraded.is_some() {
+            return self.read_pinned(buf, offset).await.map_err(storage_error);
+        }
+        if self.buffered.lock().unwrap().pages.overlay(offset, buf) {
+            return Ok(());
+        }
+        let (storage, register, scan) = (&self.storage, !self.readonly, &mut self.scan);
+        let (obj_key, learner, hot_set) = (&self.obj_key, &mut self.learner, &mut self.hot_set);
+        let data = latency::scope(
+            self.timings.clone(),
+            busy::with_handler(
+                self.busy_handler.clone(),
+                spend::scope(self.spend.clone(), async {
+                    let mut inner = storage.inner.write().await;
+                    let usage =
+                        scan.observe(offset, buf.len(), inner.cache.config().sequential_after);
+                    if register && learner.is_none() {
+                        *hot_set = prefetch::load(&inner, obj_key).await;
+                        let mut loaded = Learner::new(inner.prefetch_config.clone());
+                        loaded.seed(hot_set);
+                        *learner = Some(loaded);
+                    }
+                    // the hot set is fetched at the first read of a transaction
+                    let hot = learner
+                        .as_ref()
+                        .filter(|learner| learner.starting())
+                        .map(|_| &*hot_set);
+                    // boxed, keeping the future of a read small enough for the stack
+                    Box::pin(inner.read_exact_at(offset as usize, buf.len(), register, usage, hot))
+                        .await
+                }),
+            ),
+        )
+        .await;
         match data {
+            // past the end, e.g. of a database created and never written, see [crate::create]
+            Ok(data) if data.len() < buf.len() => {
+                buf[..data.len()].copy_from_slice(&data);
+                buf[data.len()..].fill(0);
+                // unless written there and not synced yet
+                let buffered = self.buffered.lock().unwrap();
+                buffered.pages.overlay(offset, buf);
+                match buffered.pages.end() >= Some(offset + buf.len() as u64) {
+                    true => Ok(()),
+                    false => Err(sqlite_vfs::error::Error::UnexpectedEof),
+                }
+            }
             Ok(data) => {
+                if let Some(learner) = &mut self.learner {
+                    learner.observe(offset, buf.len() as u64);
+                }
                 buf.copy_from_slice(&data);
+                if let Some(registration) = &self.storage.registration {
+                    registration
+                        .stats
+                        .bytes_read
+                        .fetch_add(buf.len() as u64, Relaxed);
+                }
+                self.buffered.lock().unwrap().pages.overlay(offset, buf);
+                if offset == 0 && buf.len() >= format::DESCRIBE_LEN {
+                    let stats = self.storage.inner.read().await.stats.clone();
+                    // page 1 of a database being created is still empty
+                    let _ = self.update_header(buf, &stats);
+                }
                 Ok(())
             }
-            Err(e) => Err(sqlite_vfs::error::Error::External {
-                cause: crate::error::Error::Whatever {
-                    message: e.to_string(),
-                    source: None,
-                },
-            }),
+            Err(err @ Error::MetadataUnavailable { .. }) => self
+                .read_degraded(err, buf, offset)
+                .await
+                .map_err(storage_error),
+            Err(e) => Err(storage_error(e)),
         }
     }
 
@@ -53,69 +638,381 @@ impl DatabaseHandle for Handle {
         buf: &[u8],
         offset: u64,
     ) -> Result<(), sqlite_vfs::error::Error<Self::Error>> {
-        let data = self
-            .storage
+        self.reject_offline()?;
+        if let Some(journal) = &mut self.journal {
+            return journal.write_at(buf, offset).map_err(storage_error);
+        }
+        if let Some(temp) = &mut self.temp {
+            return temp.write_at(buf, offset).map_err(storage_error);
+        }
+        self.reject_degraded()?;
+        {
+            // before any upload, so that an oversized transaction fails cleanly
+            let inner = self.storage.inner.read().await;
+            self.budget
+                .get_or_insert_with(|| TransactionBudget::new(inner.transaction_limits.clone()))
+                .record_write(offset, buf.len() as u64, &inner.stats)
+                .map_err(storage_error)?;
+            inner
+                .stats
+                .bytes_written
+                .fetch_add(buf.len() as u64, Relaxed);
+            if let Some(registration) = &self.storage.registration {
+                registration
+                    .stats
+                    .bytes_written
+                    .fetch_add(buf.len() as u64, Relaxed);
+            }
+        }
+        // uploaded on the next sync or unlock, pages written again until then (e.g. by cache
+        // spills) are uploaded once
+        let absorbed = self.buffered.lock().unwrap().pages.write(offset, buf);
+        self.storage
             .inner
-            .write()
+            .read()
             .await
-            .write_at(offset as usize, buf)
-            .await;
-        match data {
-            Ok(_) => Ok(()),
-            Err(e) => Err(sqlite_vfs::error::Error::External {
-                cause: crate::error::Error::Whatever {
-                    message: e.to_string(),
-                    source: None,
-                },
-            }),
-        }
+            .stats
+            .bytes_absorbed
+            .fetch_add(absorbed, Relaxed);
+        self.storage
+            .barriers
+            .register(&self.obj_key, &self.buffered);
+        Ok(())
     }
 
     async fn sync(
         &mut self,
         _data_only: bool,
     ) -> Result<(), sqlite_vfs::error::Error<Self::Error>> {
+        if let Some(journal) = &mut self.journal {
+            let inner = self.storage.inner.read().await;
+            return journal.sync(&inner).await.map_err(storage_error);
+        }
+        if self.temp.is_some() {
+            return Ok(());
+        }
+        self.flush().await.map_err(storage_error)?;
+        if self
+            .budget
+            .as_ref()
+            .is_some_and(TransactionBudget::rewrote_header)
+        {
+            self.refresh_header().await.map_err(storage_error)?;
+        }
+        if !self.timings.lock().unwrap().running() {
+            return Ok(());
+        }
+        // the flush passed the commit barrier, so there is nothing left to wait for
+        latency::scope(self.timings.clone(), latency::timed(Phase::Flush, async {})).await;
+        self.finish_transaction().await;
         Ok(())
     }
 
     async fn set_len(&mut self, size: u64) -> Result<(), sqlite_vfs::error::Error<Self::Error>> {
+        self.reject_offline()?;
+        if let Some(journal) = &mut self.journal {
+            return journal.set_len(size).map_err(storage_error);
+        }
+        if let Some(temp) = &mut self.temp {
+            return temp.set_len(size).map_err(storage_error);
+        }
+        self.reject_degraded()?;
+        // the whole object is uploaded with a single PUT
+        let limits = self.storage.inner.read().await.transaction_limits.clone();
+        limits::check_upload(&limits, size).map_err(storage_error)?;
+        // growing buffers the zeros appended, uploaded with the pages written next rather than
+        // rewriting the object here. The length recorded is the one SQLite asked for, never
+        // rounded up to the growth quantum of SQLITE_FCNTL_CHUNK_SIZE, which the object store has
+        // no use for
+        let buffered = self.buffered.lock().unwrap().pages.end().unwrap_or(0);
+        if size >= buffered {
+            let len = {
+                let inner = self.storage.inner.read().await;
+                inner
+                    .guard_for(&self.obj_key, OpClass::Read)
+                    .map_err(storage_error)?;
+                let head = inner
+                    .s3
+                    .head_object()
+                    .bucket(&inner.bucket)
+                    .key(&self.obj_key)
+                    .send()
+                    .await;
+                // a database not created yet is empty, see [crate::create]
+                let missing = matches!(&head, Err(err) if status(err) == Some(404));
+                inner.record_for(&self.obj_key, OpClass::Read, head.is_ok() || missing);
+                match head {
+                    Err(_) if missing => 0,
+                    head => head.map_err(Error::from_aws)?.content_length().unwrap_or(0) as u64,
+                }
+            };
+            if size >= len {
+                let end = len.max(buffered);
+                if size > end {
+                    let zeros = vec![0; (size - end) as usize];
+                    self.buffered.lock().unwrap().pages.write(end, &zeros);
+                    self.storage
+                        .barriers
+                        .register(&self.obj_key, &self.buffered);
+                }
+                return Ok(());
+            }
+        }
+        // the truncation rewrites the object as stored
+        self.flush().await.map_err(storage_error)?;
         let mut inner = self.storage.inner.write().await;
 
-        inner.request_write_lock().await.unwrap();
+        inner
+            .guard_for(&self.obj_key, OpClass::Write)
+            .map_err(storage_error)?;
+        inner.check_budget(OpClass::Read).map_err(storage_error)?;
+        inner.check_budget(OpClass::Write).map_err(storage_error)?;
 
-        let obj = inner
-            .s3
-            .get_object()
-            .bucket(&inner.bucket)
-            .key(&self.obj_key)
-            .send()
+        // SQLite truncates under its EXCLUSIVE lock, which holds the write lock already, see
+        // [Self::move_remote]
+        let took_lock = inner.current_lock.is_none();
+        if took_lock {
+            registration::scope(
+                self.storage.registration.clone(),
+                inner.request_write_lock(),
+            )
             .await
-            .map_err(|e| Error::from_aws(e))?;
+            .map_err(storage_error)?;
+        }
+        inner.written = true;
+        inner.changed = None;
 
-        let bytes = obj.body.collect().await.unwrap();
+        let res = async {
+            let obj = inner
+                .s3
+                .get_object()
+                .bucket(&inner.bucket)
+                .key(&self.obj_key)
+                .send()
+                .await
+                .map_err(Error::from_aws)?;
 
-        let mut bytes = bytes.to_vec();
+            let bytes = obj.body.collect().await.map_err(|err| Error::Whatever {
+                message: format!("failed to read object body: {err}"),
+                source: None,
+            })?;
+
+            let mut bytes = bytes.to_vec();
+            inner.charge(Dimension::GetBytes, bytes.len() as u64);
 
-        if bytes.len() < size as usize {
-            bytes.extend(std::iter::repeat(0).take(bytes.len() - size as usize));
-        } else {
             bytes.truncate(size as usize);
-        }
 
-        let res = inner
-            .s3
-            .put_object()
-            .bucket(&inner.bucket)
-            .key(&self.obj_key)
-            .body(bytes.into())
-            .send()
-            .await;
+            let upload = Upload::verified(
+                &self.obj_key,
+                &bytes,
+                WriteClass::Pages,
+                &inner.verify_writes,
+            );
+            let manifest = BlockManifest::new(&bytes);
+            let len = bytes.len() as u64;
+            let put = inner
+                .s3
+                .put_object()
+                .bucket(&inner.bucket)
+                .key(&self.obj_key)
+                .body(bytes.into());
+            let res = upload.send_checksum(put).send().await;
+            inner.record_for(&self.obj_key, OpClass::Write, res.is_ok());
+            if res.is_ok() {
+                inner.stats.bytes_uploaded.fetch_add(len, Relaxed);
+                inner.charge(Dimension::PutBytes, len);
+            }
+            inner
+                .cache
+                .invalidate(self.obj_key.as_str(), size..u64::MAX);
+            let res = match res {
+                Ok(out) => inner.verify_put(&upload, &out).await,
+                Err(e) => Err(Error::from_aws(e)),
+            };
+            if res.is_ok() {
+                // without a current manifest, offline mirrors fall back to fetching everything
+                if let Err(err) = inner.publish_blocks(&self.obj_key, &manifest).await {
+                    tracing::warn!(target: "threeqlite::s3", key = %self.obj_key, %err, "publishing block manifest failed");
+                }
+            }
+            res
+        }
+        .await;
 
-        inner.release_write_lock().await.unwrap();
+        // released whether the truncation succeeded or not, its error reported over the release's
+        let released = match took_lock {
+            true => inner.release_write_lock().await,
+            false => Ok(()),
+        };
+        res.and(released).map_err(storage_error)
+    }
 
-        match res {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::from_aws(e).into()),
+    async fn pragma(
+        &mut self,
+        name: &str,
+        value: Option<&str>,
+    ) -> Result<Option<String>, sqlite_vfs::error::Error<Self::Error>> {
+        match name.to_ascii_lowercase().as_str() {
+            "synchronous" => {
+                // observed on its way to SQLite, which applies it
+                if let Some(level) = value.and_then(Synchronous::parse) {
+                    self.buffered.lock().unwrap().synchronous = Some(level);
+                    let stats = self.storage.inner.read().await.stats.clone();
+                    stats.observe_synchronous(level);
+                }
+                Ok(None)
+            }
+            "threeqlite_stats" => {
+                let stats = self.storage.stats().await;
+                let mut out = stats.to_string();
+                if let Some(header) = &self.header {
+                    out += &format!(" page_size={}", header.page_size);
+                }
+                if let Some(budget) = &self.budget {
+                    out += &format!(" transaction=({budget})");
+                }
+                if let Some(last) = &self.last_transaction {
+                    out += &format!(" last_transaction=({last})");
+                }
+                if let Some(registration) = &self.storage.registration {
+                    out += &format!(" registration=({})", registration.snapshot());
+                }
+                let instance = self.storage.inner.read().await.spend.clone();
+                if !instance.budget().is_unlimited() {
+                    out += &format!(" budget=({instance})");
+                }
+                if let Some(spend) = &self.spend {
+                    out += &format!(" connection_budget=({spend})");
+                }
+                let operations = self.storage.operations();
+                let running = operations.iter().filter(|op| !op.state.is_finished());
+                for operation in running {
+                    out += &format!(" operation=({operation})");
+                }
+                for role in self.storage.roles().await {
+                    out += &format!(" role=({role})");
+                }
+                Ok(Some(out))
+            }
+            "threeqlite_budget" => {
+                match value.map(str::trim) {
+                    Some("reset") => {
+                        if let Some(spend) = &self.spend {
+                            spend.reset();
+                        }
+                        return Ok(Some("reset".to_owned()));
+                    }
+                    Some(value) => {
+                        let Ok(budget) = value.parse::<SpendBudget>() else {
+                            return Err(sqlite_vfs::error::Error::ExpectedArg { name: "budget" });
+                        };
+                        match &self.spend {
+                            Some(spend) => spend.set_budget(budget),
+                            None => self.spend = Some(Arc::new(Spend::new(budget))),
+                        }
+                    }
+                    None => {}
+                }
+                let instance = self.storage.inner.read().await.spend.clone();
+                let connection = match &self.spend {
+                    Some(spend) => spend.to_string(),
+                    None => "none".to_owned(),
+                };
+                Ok(Some(format!(
+                    "connection=({connection}) instance=({instance})"
+                )))
+            }
+            "threeqlite_operations" => {
+                let operations: Vec<_> = self
+                    .storage
+                    .operations()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect();
+                Ok(Some(match operations.is_empty() {
+                    true => "none".to_owned(),
+                    false => operations.join("\n"),
+                }))
+            }
+            "threeqlite_cancel" => {
+                let Some(id) = value.and_then(|value| value.trim().parse().ok()) else {
+                    return Err(sqlite_vfs::error::Error::ExpectedArg {
+                        name: "operation id",
+                    });
+                };
+                Ok(Some(
+                    match self.storage.cancel_operation(id) {
+                        true => "cancelling",
+                        false => "not running",
+                    }
+                    .to_owned(),
+                ))
+            }
+            "threeqlite_receipt" => Ok(Some(
+                match self
+                    .storage
+                    .last_commit_receipt(self.obj_key.as_str())
+                    .await
+                {
+                    Some(receipt) => receipt.to_string(),
+                    None => "none".to_owned(),
+                },
+            )),
+            "threeqlite_verify_receipt" => {
+                let Some(receipt) = value.and_then(|value| value.trim().parse().ok()) else {
+                    return Err(sqlite_vfs::error::Error::ExpectedArg { name: "receipt" });
+                };
+                let status = self
+                    .storage
+                    .verify_receipt(self.obj_key.as_str(), &receipt)
+                    .await
+                    .map_err(storage_error)?;
+                Ok(Some(status.to_string()))
+            }
+            "threeqlite_capture" => {
+                let Some(workload) = self.storage.captured() else {
+                    return Ok(Some("off".to_owned()));
+                };
+                match value.map(|path| path.trim().trim_matches('\'')) {
+                    Some(path) => {
+                        let ops = self
+                            .storage
+                            .save_capture(std::path::Path::new(path))
+                            .map_err(storage_error)?;
+                        Ok(Some(format!("{ops} operations written to {path}")))
+                    }
+                    None => Ok(Some(workload.to_string())),
+                }
+            }
+            #[cfg(feature = "chaos")]
+            "threeqlite_chaos" => {
+                let chaos = self.storage.chaos().await;
+                match value.map(|value| value.trim().trim_matches('\'')) {
+                    Some("off") => chaos.clear(),
+                    Some(policy) => {
+                        let Ok(policy) = policy.parse() else {
+                            return Err(sqlite_vfs::error::Error::ExpectedArg {
+                                name: "chaos policy",
+                            });
+                        };
+                        chaos.set(policy);
+                    }
+                    None => {}
+                }
+                Ok(Some(chaos.stats().to_string()))
+            }
+            "threeqlite_memory" => Ok(Some(self.storage.stats().await.memory.to_string())),
+            "threeqlite_degraded" => Ok(Some(match &self.degraded {
+                Some((read, _)) => read.to_string(),
+                None => "no".to_owned(),
+            })),
+            "threeqlite_offline" => Ok(Some(
+                match self.storage.offline_status(self.obj_key.as_str()).await {
+                    Some(status) => status.to_string(),
+                    None => "disabled".to_owned(),
+                },
+            )),
+            _ => Ok(None),
         }
     }
 
@@ -123,23 +1020,87 @@ impl DatabaseHandle for Handle {
         &mut self,
         lock: sqlite_vfs::LockKind,
     ) -> Result<bool, sqlite_vfs::error::Error<Self::Error>> {
-        match lock {
-            sqlite_vfs::LockKind::None => todo!(),
-            sqlite_vfs::LockKind::Shared => todo!(),
-            sqlite_vfs::LockKind::Reserved => todo!(),
-            sqlite_vfs::LockKind::Pending => todo!(),
-            sqlite_vfs::LockKind::Exclusive => todo!(),
+        if !self.locks_remotely() {
+            self.lock = lock;
+            return Ok(true);
+        }
+        let previous = self.lock;
+        let change = self.session.lock(lock);
+        self.lock = self.session.lock_kind();
+        if let Err(err) = self.move_remote(change.before, change.after).await {
+            self.session.lock(previous);
+            self.lock = previous;
+            return match err {
+                // SQLite asks its busy handler and tries again, like for a conflict between
+                // handles of the instance
+                Error::Busy { .. } => Ok(false),
+                err => Err(storage_error(err)),
+            };
         }
+        if self.lock == LockKind::None {
+            self.finish_transaction().await;
+        }
+        Ok(change.granted)
+    }
+
+    async fn unlock(
+        &mut self,
+        lock: sqlite_vfs::LockKind,
+    ) -> Result<bool, sqlite_vfs::error::Error<Self::Error>> {
+        if self.temp.is_some() {
+            return self.lock(lock).await;
+        }
+        // without `synchronous`, SQLite never syncs, but the pages must be uploaded before
+        // another connection may read them
+        self.storage
+            .commit_point(&self.obj_key)
+            .await
+            .map_err(storage_error)?;
+        self.flush().await.map_err(storage_error)?;
+        self.lock(lock).await
     }
 
     async fn reserved(&mut self) -> Result<bool, sqlite_vfs::error::Error<Self::Error>> {
-        todo!()
+        if !self.locks_remotely() {
+            return Ok(false);
+        }
+        if matches!(
+            self.session.others(),
+            LockKind::Reserved | LockKind::Pending | LockKind::Exclusive
+        ) {
+            return Ok(true);
+        }
+        let inner = self.storage.inner.read().await;
+        let record = inner.read_metadata_record().await.map_err(storage_error)?;
+        // this instance holds or requested it for this handle
+        let own = inner.current_lock.as_deref();
+        let writer = match &record.metadata {
+            Metadata::Writer(id) => Some(id.as_slice()) != own,
+            Metadata::None | Metadata::Reader(_) => false,
+        };
+        let now = inner.clock.now_ms();
+        let requested = (record.write_request.as_ref())
+            .is_some_and(|request| request.live(now) && Some(request.id.as_slice()) != own);
+        Ok(writer || requested)
     }
 
     async fn current_lock(
         &self,
     ) -> Result<sqlite_vfs::LockKind, sqlite_vfs::error::Error<Self::Error>> {
-        todo!()
+        Ok(self.lock)
+    }
+
+    fn sector_size(&self) -> u32 {
+        // SQLite only asks databases, and those served from a mirror or kept in memory never
+        // journal to the object store, see [crate::sector]
+        match (&self.journal, &self.temp, &self.mirror) {
+            (None, None, None) => sector::advertised(self.storage.connection_defaults.sector_size),
+            _ => sqlite_vfs::capability::DEFAULT_SECTOR_SIZE,
+        }
+    }
+
+    fn set_busy_handler(&mut self, handler: Option<BusyHandlerRef>) {
+        self.busy_handler = handler;
     }
 
     async fn wal_index(
@@ -149,3 +1110,949 @@ impl DatabaseHandle for Handle {
         todo!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_s3::config::{BehaviorVersion, Region};
+    use sqlite_vfs::Vfs;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        key::KeyLayout,
+        mock::MockS3,
+        vfs::{Metadata, MetadataRecord},
+    };
+
+    /// An instance whose object store is unreachable, so that any request shows up as a failure.
+    fn test_db() -> ObjectKey {
+        KeyLayout::db("test.db").unwrap()
+    }
+
+    fn storage() -> ThreeQLite {
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url("http://127.0.0.1:1")
+            .build();
+        ThreeQLite::with_client(Config::default(), aws_sdk_s3::Client::from_conf(config)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_new_handle_unlocked() {
+        let handle = Handle::new(storage(), test_db(), false);
+        assert_eq!(handle.current_lock().await.unwrap(), LockKind::None);
+    }
+
+    #[tokio::test]
+    async fn test_drop_unlocked() {
+        let storage = storage();
+        drop(Handle::new(storage.clone(), test_db(), false));
+        assert_eq!(storage.stats().await.requests, 0);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_drop_after_remote_release() {
+        let storage = storage();
+        let mut a = Handle::new(storage.clone(), test_db(), false);
+        let mut b = Handle::new(storage.clone(), test_db(), false);
+        a.lock = LockKind::Shared;
+        b.lock = LockKind::Exclusive;
+
+        // the remote lock is gone already, so neither handle may release it again
+        drop(a);
+        drop(b);
+        assert_eq!(storage.stats().await.requests, 0);
+        assert!(storage.inner.read().await.current_lock.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_aliases_share_remote_lock() {
+        let mock = MockS3::start();
+        let storage = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let readers = || async {
+            let record = storage.inner.read().await.read_metadata_record().await;
+            match record.unwrap().metadata {
+                Metadata::Reader(reader) => reader.readers.len(),
+                _ => 0,
+            }
+        };
+        storage
+            .inner
+            .read()
+            .await
+            .write_metadata_record(MetadataRecord::default())
+            .await
+            .unwrap();
+        // the main database and the same database attached
+        let mut main = Handle::new(storage.clone(), test_db(), false);
+        let mut attached = Handle::new(storage.clone(), test_db(), false);
+        assert_eq!(storage.sessions.participants(&test_db()), 2);
+        storage
+            .inner
+            .write()
+            .await
+            .request_read_lock()
+            .await
+            .unwrap();
+        for handle in [&mut main, &mut attached] {
+            handle.session.lock(LockKind::Shared);
+            handle.lock = LockKind::Shared;
+        }
+        assert_eq!(readers().await, 1);
+
+        // the other alias still reads
+        drop(main);
+        assert_eq!(readers().await, 1);
+        assert_eq!(storage.sessions.held(&test_db()), LockKind::Shared);
+        drop(attached);
+        assert_eq!(readers().await, 0);
+        assert!(storage.sessions.is_empty());
+    }
+
+    #[test]
+    fn test_drop_outside_runtime() {
+        let mut handle = Handle::new(storage(), test_db(), false);
+        handle.lock = LockKind::Shared;
+        drop(handle);
+    }
+
+    #[derive(Default)]
+    struct Observed(Mutex<Vec<(String, TransactionBreakdown)>>);
+
+    impl latency::TransactionObserver for Observed {
+        fn on_transaction(&self, db: &str, breakdown: &TransactionBreakdown) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((db.to_owned(), breakdown.clone()));
+        }
+    }
+
+    fn assert_near(actual: std::time::Duration, expected_ms: u64) {
+        let expected = std::time::Duration::from_millis(expected_ms);
+        assert!(
+            actual >= expected && actual < expected + std::time::Duration::from_millis(60),
+            "{actual:?} is not close to {expected:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transaction_breakdown() {
+        let mock = MockS3::start();
+        mock.put("test.db", vec![7; 8192]);
+        mock.delay("GET", std::time::Duration::from_millis(40));
+        let storage = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let observed = Arc::new(Observed::default());
+        storage.observe_transactions(observed.clone()).await;
+
+        // read-only, so that reads don't take part in the lock protocol
+        let mut handle = Handle::new(storage.clone(), test_db(), true);
+        let mut page = vec![0; 4096];
+        for offset in [0, 4096, 0] {
+            handle.read_exact_at(&mut page, offset).await.unwrap();
+            assert_eq!(page, vec![7; 4096]);
+            // stands in for SQLite working between calls
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        handle.sync(false).await.unwrap();
+
+        let breakdown = storage.explain_last_transaction("test.db").await.unwrap();
+        assert_near(breakdown.get(Phase::StorageRead), 120);
+        assert_near(breakdown.get(Phase::Sqlite), 40);
+        assert_eq!(breakdown.get(Phase::LockWait), std::time::Duration::ZERO);
+        assert_eq!(
+            breakdown.get(Phase::StorageWrite),
+            std::time::Duration::ZERO
+        );
+        assert_eq!(breakdown.keys, ["test.db"]);
+        assert_eq!(
+            observed.0.lock().unwrap().clone(),
+            vec![("test.db".to_owned(), breakdown.clone())]
+        );
+
+        let read = storage.phase_latency(Phase::StorageRead).await;
+        assert_eq!(read.count, 1);
+        assert_eq!(read.max, breakdown.get(Phase::StorageRead));
+
+        let stats = handle
+            .pragma("threeqlite_stats", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            stats.ends_with(&format!("last_transaction=({breakdown})")),
+            "{stats}"
+        );
+
+        // nothing happened since, so there is no transaction to report
+        handle.sync(false).await.unwrap();
+        assert_eq!(observed.0.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unregistered_reads_bypass_cache() {
+        let mock = MockS3::start();
+        mock.put("test.db", vec![7; 8192]);
+        let config = Config {
+            cache: crate::cache::CacheConfig {
+                capacity: 1024 * 1024,
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let storage = ThreeQLite::with_client(config, mock.client()).unwrap();
+        // without registering, the generation read is unknown
+        let mut handle = Handle::new(storage.clone(), test_db(), true);
+        let mut page = vec![0; 4096];
+        for _ in 0..2 {
+            handle.read_exact_at(&mut page, 4096).await.unwrap();
+        }
+        assert_eq!(page, [7; 4096]);
+        let stats = storage.stats().await;
+        assert_eq!(stats.cache.bypassed, 2);
+        assert_eq!(stats.cache.hits() + stats.cache.admissions, 0);
+        let pragma = handle
+            .pragma("threeqlite_stats", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(pragma.contains(" cache=(probation_hits=0 "), "{pragma}");
+    }
+
+    async fn metadata(tq: &ThreeQLite) -> Metadata {
+        let inner = tq.inner.read().await;
+        inner.read_metadata_record().await.unwrap().metadata
+    }
+
+    fn readers(metadata: Metadata) -> usize {
+        match metadata {
+            Metadata::Reader(reader) => reader.readers.len(),
+            _ => panic!("not read"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lock_states() {
+        let mock = MockS3::start();
+        let config = Config {
+            lock: crate::config::LockConfig {
+                busy_timeout: Some(std::time::Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let storage = ThreeQLite::with_client(config.clone(), mock.client()).unwrap();
+        let other = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let mut handle = Handle::new(storage.clone(), test_db(), false);
+        let mut remote = Handle::new(other.clone(), test_db(), false);
+        let metadata_puts = || {
+            let requests = mock.requests();
+            requests
+                .iter()
+                .filter(|(method, key)| method == "PUT" && key == "metadata")
+                .count()
+        };
+
+        assert!(handle.lock(LockKind::Shared).await.unwrap());
+        assert_eq!(handle.current_lock().await.unwrap(), LockKind::Shared);
+        assert_eq!(readers(metadata(&storage).await), 1);
+        assert!(!remote.reserved().await.unwrap());
+
+        // the reader turns into the writer with a single write, never leaving the lock
+        let puts = metadata_puts();
+        assert!(handle.lock(LockKind::Reserved).await.unwrap());
+        assert_eq!(metadata_puts(), puts + 1);
+        assert!(matches!(metadata(&storage).await, Metadata::Writer(_)));
+        assert!(remote.reserved().await.unwrap());
+        assert!(!handle.reserved().await.unwrap());
+        assert!(handle.lock(LockKind::Exclusive).await.unwrap());
+
+        // busy rather than waiting on
+        assert!(!remote.lock(LockKind::Shared).await.unwrap());
+        assert_eq!(remote.current_lock().await.unwrap(), LockKind::None);
+
+        handle.unlock(LockKind::Shared).await.unwrap();
+        assert_eq!(readers(metadata(&storage).await), 1);
+        assert!(remote.lock(LockKind::Shared).await.unwrap());
+        assert_eq!(readers(metadata(&storage).await), 2);
+
+        // waiting for the other reader gives up, still reading
+        assert!(!handle.lock(LockKind::Reserved).await.unwrap());
+        assert_eq!(handle.current_lock().await.unwrap(), LockKind::Shared);
+        assert_eq!(readers(metadata(&storage).await), 2);
+        assert!(remote.reserved().await.unwrap());
+
+        remote.unlock(LockKind::None).await.unwrap();
+        assert!(handle.lock(LockKind::Reserved).await.unwrap());
+        handle.unlock(LockKind::None).await.unwrap();
+        assert!(matches!(metadata(&storage).await, Metadata::None));
+        assert!(!remote.reserved().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_lock_released_on_error() {
+        let mock = MockS3::start();
+        let config = Config {
+            lock: crate::config::LockConfig {
+                backend: crate::config::LockBackend::Conditional,
+                busy_timeout: Some(std::time::Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let storage = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let mut handle = Handle::new(storage.clone(), test_db(), false);
+
+        // the metadata object can't be read under its lock, which is released all the same
+        mock.reject("GET", 500);
+        assert!(handle.lock(LockKind::Shared).await.is_err());
+        assert_eq!(mock.get("lockfile"), None);
+        mock.restore("GET");
+
+        // a reader that failed to leave is still registered, and can leave again
+        assert!(handle.lock(LockKind::Shared).await.unwrap());
+        mock.reject("GET", 500);
+        assert!(handle.unlock(LockKind::None).await.is_err());
+        assert_eq!(mock.get("lockfile"), None);
+        mock.restore("GET");
+        assert_eq!(readers(metadata(&storage).await), 1);
+        handle.unlock(LockKind::None).await.unwrap();
+        assert_eq!(readers(metadata(&storage).await), 0);
+        assert_eq!(mock.get("lockfile"), None);
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn test_chaos_pragma() {
+        let mock = MockS3::start();
+        mock.put("test.db", vec![7; 8192]);
+        let storage = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let mut handle = Handle::new(storage.clone(), test_db(), true);
+        let policy =
+            r#"'{"rules": [{"methods": ["GET"], "action": {"kind": "fail", "error": "io"}}]}'"#;
+        handle
+            .pragma("threeqlite_chaos", Some(policy))
+            .await
+            .unwrap();
+        let mut page = vec![0; 4096];
+        assert!(handle.read_exact_at(&mut page, 4096).await.is_err());
+        assert!(mock.requests().is_empty());
+        let stats = handle.pragma("threeqlite_chaos", None).await.unwrap();
+        assert_eq!(
+            stats.as_deref(),
+            Some("latency=0 failed=1 dropped=0 duplicated=0")
+        );
+
+        handle
+            .pragma("threeqlite_chaos", Some("off"))
+            .await
+            .unwrap();
+        handle.read_exact_at(&mut page, 4096).await.unwrap();
+        assert!(handle
+            .pragma("threeqlite_chaos", Some("{not json"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_open_rejects_long_name() {
+        let mock = MockS3::start();
+        let storage = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        // every character encodes to 9 bytes
+        let name = "日".repeat(crate::key::MAX_DB_KEY_LEN / 9 + 1);
+        let Err(err) = storage
+            .open(
+                &name,
+                sqlite_vfs::OpenOptions::new(
+                    sqlite_vfs::OpenKind::MainDb,
+                    sqlite_vfs::OpenAccess::Read,
+                ),
+            )
+            .await
+        else {
+            panic!("opened {name}");
+        };
+        assert!(
+            matches!(
+                err,
+                sqlite_vfs::error::Error::External {
+                    cause: Error::NameTooLong { .. }
+                }
+            ),
+            "{err}"
+        );
+        // before any request
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_journal_out_of_memory() {
+        let mock = MockS3::start();
+        let config = Config {
+            memory_cap: Some(8192),
+            ..Config::default()
+        };
+        let storage = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let mut journal = storage
+            .open(
+                "test.db-journal",
+                sqlite_vfs::OpenOptions::new(
+                    sqlite_vfs::OpenKind::MainJournal,
+                    sqlite_vfs::OpenAccess::Create,
+                ),
+            )
+            .await
+            .unwrap();
+        journal.write_all_at(&[1; 4096], 0).await.unwrap();
+        let err = journal.write_all_at(&[1; 4096], 8192).await.unwrap_err();
+        assert!(
+            matches!(err, sqlite_vfs::error::Error::NoMem { .. }),
+            "{err}"
+        );
+        assert_eq!(journal.size().await.unwrap(), 4096);
+
+        let memory = journal
+            .pragma("threeqlite_memory", None)
+            .await
+            .unwrap()
+            .unwrap();
+        // the empty cache was asked to make room first
+        assert_eq!(
+            memory,
+            "cap=8192 used=4096 cache=0 journal=4096 temp=0 high_water=4096 \
+             cache_probation=1/0 cache_protected=1/0 denied=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_control_report() {
+        let mock = MockS3::start();
+        let strict = Config {
+            strict_file_control: true,
+            ..Config::default()
+        };
+        let storage = ThreeQLite::with_client(strict, mock.client()).unwrap();
+        let coverage = storage.file_controls.clone().unwrap();
+        // as reported by SQLite: two SQLITE_FCNTL_SYNC, a SQLITE_FCNTL_PRAGMA and an opcode
+        // newer than this build
+        for op in [21, 14, 21, 99] {
+            coverage.record(op);
+        }
+        let mut handle = Handle::new(storage.clone(), test_db(), false);
+        let stats = handle
+            .pragma("threeqlite_stats", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            stats.contains(" file_controls=PRAGMA:1,SYNC:2,unknown(99):1"),
+            "{stats}"
+        );
+
+        // not counted by default
+        let storage = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        assert!(storage.file_controls.is_none());
+        assert!(storage.stats().await.file_controls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_degraded_reads_within_budget() {
+        let mock = MockS3::start();
+        let data: Vec<u8> = (0..8192).map(|i| (i / 4096) as u8 + 1).collect();
+        mock.put("test.db", data.clone());
+        let config = Config {
+            degraded_reads: Some(crate::degraded::DegradedReadPolicy {
+                max_age: std::time::Duration::from_millis(300),
+                max_generation_lag: Some(1),
+            }),
+            ..Config::default()
+        };
+        let storage = ThreeQLite::with_client(config, mock.client()).unwrap();
+        storage
+            .inner
+            .read()
+            .await
+            .write_metadata_record(crate::vfs::MetadataRecord {
+                stamp: Some(crate::heal::Stamp::new(4)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("threeqlite-mirror-{}", uuid::Uuid::new_v4()));
+        storage
+            .enable_offline_mirror("test.db", &path, crate::mirror::MirrorPolicy::default())
+            .await
+            .unwrap();
+        let observed = Arc::new(Observed::default());
+        storage.observe_transactions(observed.clone()).await;
+
+        // registering as a reader needs the lock protocol, which the mock doesn't speak, so the
+        // failure to read the metadata object is handed to the fallback directly
+        let unreadable = || Error::MetadataUnavailable {
+            source: Box::new(Error::Whatever {
+                message: "metadata GET failed with 503".to_owned(),
+                source: None,
+            }),
+        };
+        let mut handle = Handle::new(storage.clone(), test_db(), false);
+        let mut page = vec![0; 4096];
+        handle
+            .read_degraded(unreadable(), &mut page, 4096)
+            .await
+            .unwrap();
+        assert_eq!(page, data[4096..]);
+        // the rest of the transaction reads the same generation
+        mock.offline(true);
+        handle.read_exact_at(&mut page, 0).await.unwrap();
+        assert_eq!(page, data[..4096]);
+        assert_eq!(handle.size().await.unwrap(), 8192);
+        let pragma = handle
+            .pragma("threeqlite_degraded", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(pragma.starts_with("generation=4 age="), "{pragma}");
+
+        // writes are rejected
+        let err = handle.write_all_at(&[0; 4096], 0).await.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                sqlite_vfs::error::Error::External {
+                    cause: Error::DegradedReadOnly { generation: 4, .. }
+                }
+            ),
+            "{err:?}"
+        );
+        assert!(err.describe().contains("read-only"), "{err:?}");
+        assert!(handle.set_len(4096).await.is_err());
+
+        handle.sync(false).await.unwrap();
+        let (_, breakdown) = observed.0.lock().unwrap().pop().unwrap();
+        assert_eq!(breakdown.degraded, Some(4));
+        assert_eq!(
+            handle
+                .pragma("threeqlite_degraded", None)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("no")
+        );
+        assert_eq!(storage.stats().await.degraded_transactions, 1);
+
+        // too many generations behind
+        storage
+            .inner
+            .read()
+            .await
+            .generation_seen
+            .store(6, std::sync::atomic::Ordering::Relaxed);
+        let err = handle
+            .read_degraded(unreadable(), &mut page, 0)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("503"), "{err}");
+        storage
+            .inner
+            .read()
+            .await
+            .generation_seen
+            .store(5, std::sync::atomic::Ordering::Relaxed);
+
+        // another degraded transaction doesn't extend the budget, which runs out
+        handle
+            .read_degraded(unreadable(), &mut page, 0)
+            .await
+            .unwrap();
+        handle.sync(false).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let err = handle
+            .read_degraded(unreadable(), &mut page, 0)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::Whatever { message, .. } if message.contains("503")),
+            "{err:?}"
+        );
+        assert_eq!(storage.stats().await.degraded_transactions, 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_header_only_commits() {
+        let mock = MockS3::start();
+        mock.put("test.db", crate::mock::database(4096, 4, 1));
+        let storage = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let mut handle = Handle::new(storage.clone(), test_db(), false);
+        for counter in 1u32..=3 {
+            handle
+                .write_all_at(&counter.to_be_bytes(), 24)
+                .await
+                .unwrap();
+            handle
+                .write_all_at(&counter.to_be_bytes(), 92)
+                .await
+                .unwrap();
+            // the flush of `sync`, without the requests of a lock
+            let pending = std::mem::take(&mut handle.buffered.lock().unwrap().pages);
+            let inner = storage.inner.read().await;
+            inner
+                .page_flush(&handle.obj_key, &pending)
+                .run()
+                .await
+                .unwrap();
+        }
+        assert!(mock.requests().iter().all(|(method, _)| method == "PUT"));
+        assert_eq!(mock.requests().len(), 6);
+
+        let stats = storage.stats().await;
+        assert_eq!((stats.bytes_written, stats.bytes_uploaded), (24, 24));
+        assert_eq!(stats.write_amplification(), Some(1.0));
+        assert!(
+            stats.to_string().contains(" write_amplification=1.00"),
+            "{stats}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_len() {
+        let mock = MockS3::start();
+        let storage = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let mut handle = Handle::new(storage.clone(), test_db(), false);
+        let puts = || {
+            let requests = mock.requests();
+            requests
+                .iter()
+                .filter(|(method, key)| method == "PUT" && key == "test.db")
+                .count()
+        };
+        // SQLite truncates under its EXCLUSIVE lock
+        assert!(handle.lock(LockKind::Exclusive).await.unwrap());
+
+        // a database not created yet is empty, and is created with the length asked for; boxed,
+        // the futures of a handle are large
+        assert_eq!(Box::pin(handle.size()).await.unwrap(), 0);
+        Box::pin(handle.set_len(2 * 4096)).await.unwrap();
+        assert_eq!((puts(), mock.get("test.db")), (0, None));
+        assert_eq!(Box::pin(handle.size()).await.unwrap(), 2 * 4096);
+        Box::pin(handle.sync(false)).await.unwrap();
+        assert_eq!(mock.get("test.db").unwrap(), vec![0; 2 * 4096]);
+
+        // growing appends zeros, uploaded with the pages written next
+        let db = crate::mock::database(4096, 2, 1);
+        mock.put("test.db", db.clone());
+        let before = puts();
+        Box::pin(handle.set_len(4 * 4096)).await.unwrap();
+        assert_eq!(puts(), before);
+        assert_eq!(Box::pin(handle.size()).await.unwrap(), 4 * 4096);
+        handle.write_all_at(&[4; 4096], 3 * 4096).await.unwrap();
+        assert_eq!(Box::pin(handle.size()).await.unwrap(), 4 * 4096);
+        Box::pin(handle.sync(false)).await.unwrap();
+        let grown = mock.get("test.db").unwrap();
+        assert_eq!(grown.len(), 4 * 4096);
+        assert_eq!(grown[..2 * 4096], db[..]);
+        assert!(grown[2 * 4096..3 * 4096].iter().all(|byte| *byte == 0));
+        assert!(grown[3 * 4096..].iter().all(|byte| *byte == 4));
+
+        // truncating keeps the first bytes
+        Box::pin(handle.set_len(4096 + 100)).await.unwrap();
+        assert_eq!(mock.get("test.db").unwrap(), db[..4096 + 100]);
+        assert_eq!(Box::pin(handle.size()).await.unwrap(), 4096 + 100);
+        Box::pin(handle.set_len(0)).await.unwrap();
+        assert_eq!(mock.get("test.db").unwrap(), Vec::<u8>::new());
+        assert_eq!(Box::pin(handle.size()).await.unwrap(), 0);
+        handle.unlock(LockKind::None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_truncate_under_lock() {
+        let mock = MockS3::start();
+        mock.put("test.db", crate::mock::database(4096, 4, 1));
+        let config = Config {
+            lock: crate::config::LockConfig {
+                busy_timeout: Some(std::time::Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let storage = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let mut handle = Handle::new(storage.clone(), test_db(), false);
+
+        // keeps the write lock it is called under rather than waiting for itself
+        assert!(handle.lock(LockKind::Exclusive).await.unwrap());
+        handle.set_len(2 * 4096).await.unwrap();
+        assert_eq!(mock.get("test.db").unwrap().len(), 2 * 4096);
+        assert!(matches!(metadata(&storage).await, Metadata::Writer(_)));
+        handle.unlock(LockKind::None).await.unwrap();
+        assert!(matches!(metadata(&storage).await, Metadata::None));
+    }
+
+    #[tokio::test]
+    async fn test_cache_spills_absorbed() {
+        let mock = MockS3::start();
+        mock.put("test.db", crate::mock::database(4096, 4, 1));
+        let storage = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let mut handle = Handle::new(storage.clone(), test_db(), false);
+        assert!(handle.lock(LockKind::Exclusive).await.unwrap());
+
+        // grown to the size of the transaction ahead of it
+        handle.set_len(8 * 4096).await.unwrap();
+        handle.sync(false).await.unwrap();
+        assert_eq!(mock.get("test.db").unwrap().len(), 8 * 4096);
+
+        // a transaction of 8 pages through a page cache of 2, which spills each page 3 times and
+        // hints at the size it grows to ahead of the spills
+        let grown = mock.requests().len();
+        for spill in 1u8..=3 {
+            handle.set_len(8 * 4096).await.unwrap();
+            for page in 1..8 {
+                handle
+                    .write_all_at(&[spill; 4096], page * 4096)
+                    .await
+                    .unwrap();
+            }
+        }
+        let uploads = || {
+            mock.requests()[grown..]
+                .iter()
+                .filter(|(method, key)| method != "GET" && method != "HEAD" && key == "test.db")
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(uploads(), vec![]);
+        handle.sync(false).await.unwrap();
+
+        // each page is uploaded once, with the contents of the last spill
+        let puts = uploads();
+        assert_eq!(puts, vec![("PUT".to_owned(), "test.db".to_owned()); 7]);
+        let db = mock.get("test.db").unwrap();
+        assert_eq!(db.len(), 8 * 4096);
+        assert!(db[4096..].iter().all(|byte| *byte == 3));
+
+        let stats = storage.stats().await;
+        assert_eq!(stats.bytes_written, 3 * 7 * 4096);
+        assert_eq!(stats.bytes_absorbed, 2 * 7 * 4096);
+        // and the zeros grown by
+        assert_eq!(stats.bytes_uploaded, (7 + 4) * 4096);
+        assert_eq!(stats.spill_amplification(), Some(3.0));
+        // pages 1..8 are in the first block of the budget
+        assert_eq!(stats.max_block_rewrites, 20);
+        assert!(
+            stats.to_string().contains(" spill_amplification=3.00"),
+            "{stats}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reads_during_flush() {
+        let mock = MockS3::start();
+        mock.put("test.db", vec![1; 16 * 4096]);
+        let storage = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let mut writer = Handle::new(storage.clone(), test_db(), false);
+        assert!(writer.lock(LockKind::Exclusive).await.unwrap());
+        // the PUTs of the lock aren't those of the flush
+        let count_puts = |methods: Vec<String>| methods.iter().filter(|m| *m == "PUT").count();
+        let lock_puts = (
+            count_puts(mock.requests().into_iter().map(|(m, _)| m).collect()),
+            count_puts(mock.exchanges().into_iter().map(|e| e.method).collect()),
+        );
+        mock.delay("PUT", std::time::Duration::from_millis(500));
+        for page in 1..9 {
+            writer.write_all_at(&[2; 4096], page * 4096).await.unwrap();
+        }
+        let flush = tokio::spawn(Box::pin(async move { writer.sync(false).await }));
+
+        // read-only, so that reads don't take part in the lock protocol. Without a SHARED lock,
+        // two reads may see different commits, but each page is one commit or the next.
+        let mut reader = Handle::new(storage.clone(), test_db(), true);
+        let mut page = vec![0; 4096];
+        let mut seen = [Vec::new(), Vec::new()];
+        // reads done while the PUT of the flush was outstanding, i.e. not waiting for it
+        let mut overlapping = 0;
+        let put_outstanding = || {
+            let received = count_puts(mock.requests().into_iter().map(|(m, _)| m).collect());
+            let answered = count_puts(mock.exchanges().into_iter().map(|e| e.method).collect());
+            received > lock_puts.0 && answered == lock_puts.1
+        };
+        while !flush.is_finished() {
+            let before = put_outstanding();
+            for (seen, offset) in seen.iter_mut().zip([4096, 8 * 4096]) {
+                reader.read_exact_at(&mut page, offset).await.unwrap();
+                assert!(page.iter().all(|byte| *byte == page[0]), "torn page");
+                seen.push(page[0]);
+            }
+            if before && put_outstanding() {
+                overlapping += 1;
+            }
+        }
+        flush.await.unwrap().unwrap();
+        assert!(overlapping > 0, "reads waited for the flush");
+        for seen in seen {
+            // the pages of the commit from the start of the flush on
+            assert!(seen.windows(2).all(|pair| pair[0] <= pair[1]), "{seen:?}");
+            assert_eq!(seen.last(), Some(&2));
+        }
+
+        let mut first = vec![0; 4096];
+        reader.read_exact_at(&mut first, 4096).await.unwrap();
+        assert_eq!(first, vec![2; 4096]);
+        assert!(storage.inner.read().await.in_flight.is_empty());
+        assert_eq!(mock.get("test.db").unwrap()[4096..9 * 4096], [2; 8 * 4096]);
+    }
+
+    #[tokio::test]
+    async fn test_header_refreshed_after_page_size_change() {
+        let mock = MockS3::start();
+        mock.put("test.db", crate::mock::database(4096, 4, 1));
+        let storage = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let mut handle = Handle::new(storage.clone(), test_db(), true);
+        let mut page = vec![0; 4096];
+        handle.read_exact_at(&mut page, 0).await.unwrap();
+        assert_eq!(handle.header().unwrap().page_size, 4096);
+
+        // a commit that leaves page 1 alone doesn't read the header again
+        let limits = storage.inner.read().await.transaction_limits.clone();
+        let stats = Stats::default();
+        let mut budget = TransactionBudget::new(limits.clone());
+        budget.record_write(4096, 4096, &stats).unwrap();
+        handle.budget = Some(budget);
+        let requests = mock.requests().len();
+        handle.sync(false).await.unwrap();
+        assert_eq!(mock.requests().len(), requests);
+
+        // as if `PRAGMA page_size = 8192; VACUUM` had rewritten the database
+        mock.put("test.db", crate::mock::database(8192, 2, 2));
+        let mut budget = TransactionBudget::new(limits.clone());
+        budget.record_write(0, 8192, &stats).unwrap();
+        handle.budget = Some(budget);
+        handle.sync(false).await.unwrap();
+        let header = handle.header().unwrap();
+        assert_eq!((header.page_size, header.size()), (8192, Some(16384)));
+        assert_eq!(storage.stats().await.page_size_changes, 1);
+        let out = handle
+            .pragma("threeqlite_stats", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(out.contains(" page_size=8192"), "{out}");
+
+        // a commit that leaves no valid header behind fails
+        mock.put("test.db", vec![0; 8192]);
+        let mut budget = TransactionBudget::new(limits);
+        budget.record_write(0, 100, &stats).unwrap();
+        handle.budget = Some(budget);
+        let err = handle.sync(false).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                sqlite_vfs::error::Error::External {
+                    cause: Error::CorruptHeader { .. }
+                }
+            ),
+            "{err:?}"
+        );
+        assert_eq!(handle.header(), Some(header));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_transaction_fails_before_upload() {
+        let mock = MockS3::start();
+        let config = Config {
+            limits: limits::TransactionLimits {
+                max_dirty_blocks: Some(2),
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let storage = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let mut handle = Handle::new(storage.clone(), test_db(), false);
+        // as if the first pages had been written already
+        handle.budget = Some(TransactionBudget::new(
+            storage.inner.read().await.transaction_limits.clone(),
+        ));
+        let stats = storage.stats().await;
+        handle
+            .budget
+            .as_mut()
+            .unwrap()
+            .record_write(0, 2 * crate::mirror::BLOCK_SIZE, &Default::default())
+            .unwrap();
+
+        let out = handle
+            .pragma("threeqlite_stats", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            out.ends_with(&format!(
+                "transaction=(dirty_blocks=2/2 pending_bytes={}/{})",
+                2 * crate::mirror::BLOCK_SIZE,
+                limits::MAX_PUT_BYTES
+            )),
+            "{out}"
+        );
+
+        let err = handle
+            .write_all_at(&[1; 4096], 2 * crate::mirror::BLOCK_SIZE)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                sqlite_vfs::error::Error::Full {
+                    cause: Error::TransactionTooLarge { attempted: 3, .. }
+                }
+            ),
+            "{err:?}"
+        );
+        assert!(err.describe().contains("smaller batches"), "{err:?}");
+        assert_eq!(storage.stats().await, stats);
+        assert!(mock.requests().is_empty());
+
+        // the whole-object upload of a truncation is checked up front as well
+        let storage = ThreeQLite::with_client(
+            Config {
+                limits: limits::TransactionLimits {
+                    max_pending_bytes: Some(4096),
+                    ..Default::default()
+                },
+                ..Config::default()
+            },
+            mock.client(),
+        )
+        .unwrap();
+        let mut handle = Handle::new(storage, test_db(), false);
+        let err = handle.set_len(8192).await.unwrap_err();
+        assert!(
+            matches!(err, sqlite_vfs::error::Error::Full { .. }),
+            "{err:?}"
+        );
+        assert!(mock.requests().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_sleeps() {
+        // sleeping yields to the runtime, so busy handles waiting together don't queue up. Through
+        // the `xSleep` SQLite calls, from a thread of each connection
+        storage().register("concurrent-sleeps", false).unwrap();
+        let start = std::time::Instant::now();
+        let sleeps: Vec<_> = (0..10)
+            .map(|_| {
+                std::thread::spawn(|| unsafe {
+                    let vfs = libsqlite3_sys::sqlite3_vfs_find(c"concurrent-sleeps".as_ptr());
+                    assert!(!vfs.is_null());
+                    ((*vfs).xSleep.unwrap())(vfs, 100_000)
+                })
+            })
+            .collect();
+        for sleep in sleeps {
+            assert!(sleep.join().unwrap() >= 100_000);
+        }
+        assert!(
+            start.elapsed() < std::time::Duration::from_millis(500),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+}
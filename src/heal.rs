@@ -0,0 +1,564 @@
+//! Detection and repair of a lost or reset metadata object.
+//!
+//! Bucket lifecycle rules that expire objects by age eventually delete the metadata object of a
+//! database that is mostly read, since only writers rewrite it. Without it, the lock state and the
+//! generation counter silently start over. To notice, the metadata object carries a [Stamp] as
+//! user metadata, and [crate::vfs::Inner::check_metadata] compares it against the newest
+//! generation this instance has seen whenever a database is opened. A missing object is
+//! reconstructed without any lock holders; a generation that went backwards quarantines the
+//! database, which then only opens read-only until [crate::vfs::ThreeQLite::clear_quarantine].
+//!
+//! The stamp also records the length of the database object as of each commit. Anything appending
+//! to or replacing the object out-of-band, e.g. a backup tool or a manual copy, changes its length
+//! behind SQLite's back, which would surface as corruption far from the cause. A registered read
+//! that fetches from the object compares the length it reports against the recorded one for free.
+//! On a mismatch, the database is quarantined as well and the read fails with
+//! [Error::ExternalModification](crate::error::Error::ExternalModification), unless
+//! [crate::config::Config::read_externally_modified] acknowledges reading it anyway. Once the
+//! operator confirmed that the object as it is now is the desired state,
+//! [crate::vfs::ThreeQLite::accept_external_state] records its length, rebuilds its block
+//! manifest and lifts the quarantine.
+//!
+//! Each stamp links its generation to the one before, which makes receipts of commits
+//! verifiable, see [crate::receipt].
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aws_sdk_s3::types::{ExpirationStatus, LifecycleRule};
+use uuid::Uuid;
+
+use crate::receipt::{Link, HISTORY};
+
+const CREATED: &str = "threeqlite-created";
+const GENERATION: &str = "threeqlite-generation";
+const QUARANTINED: &str = "threeqlite-quarantined";
+const LENGTH: &str = "threeqlite-length";
+const EXTERNAL_LENGTH: &str = "threeqlite-external-length";
+const LINK: &str = "threeqlite-link";
+const COMMIT: &str = "threeqlite-commit";
+const ANCESTORS: &str = "threeqlite-ancestors";
+
+/// Stored as user metadata on every write of the metadata object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stamp {
+    /// When the metadata object was first written, in milliseconds since the Unix epoch.
+    pub created: u64,
+    /// The number of write transactions committed since then.
+    pub generation: u64,
+    /// Set once the generation went backwards or the database object was modified out-of-band.
+    /// Writes are refused until it is cleared.
+    pub quarantined: bool,
+    /// The length of the database object as of `generation`, `None` if unknown.
+    pub len: Option<u64>,
+    /// The length found instead of `len`, once the database object was modified out-of-band.
+    pub external_len: Option<u64>,
+    /// The link of `generation` to its parent, `None` if it was committed before links were
+    /// recorded.
+    pub link: Option<Link>,
+    /// The links of the generations before, newest first.
+    pub ancestors: [Option<Link>; HISTORY],
+    /// The ID the writer of `generation` picked for its commit, which makes recording it
+    /// idempotent, see [crate::idempotency]. `None` before IDs were recorded.
+    pub commit: Option<Uuid>,
+}
+
+impl Stamp {
+    pub fn new(generation: u64) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self {
+            created,
+            generation,
+            quarantined: false,
+            len: None,
+            external_len: None,
+            link: None,
+            ancestors: [None; HISTORY],
+            commit: None,
+        }
+    }
+
+    /// Move on to the next generation, committed at `committed_at` with the database object at
+    /// the ETag `content`, and link it to this one. The commit ID is left for the writer to pick.
+    pub fn advance(&mut self, committed_at: u64, content: Option<&str>) {
+        self.commit = None;
+        self.ancestors.rotate_right(1);
+        self.ancestors[0] = self.link;
+        self.generation += 1;
+        self.link = Some(Link::next(
+            self.link,
+            self.generation,
+            committed_at,
+            content,
+        ));
+    }
+
+    /// The stamp in an object's user metadata, `None` for objects written before stamps existed.
+    pub fn from_metadata(metadata: Option<&HashMap<String, String>>) -> Option<Self> {
+        let metadata = metadata?;
+        Some(Self {
+            created: metadata.get(CREATED)?.parse().ok()?,
+            generation: metadata.get(GENERATION)?.parse().ok()?,
+            quarantined: metadata.get(QUARANTINED).is_some_and(|q| q == "true"),
+            len: metadata.get(LENGTH).and_then(|len| len.parse().ok()),
+            external_len: metadata
+                .get(EXTERNAL_LENGTH)
+                .and_then(|len| len.parse().ok()),
+            link: metadata.get(LINK).and_then(|link| link.parse().ok()),
+            ancestors: {
+                let mut ancestors = [None; HISTORY];
+                let links = metadata.get(ANCESTORS).map_or("", String::as_str);
+                for (ancestor, link) in ancestors.iter_mut().zip(links.split(',')) {
+                    *ancestor = link.parse().ok();
+                }
+                ancestors
+            },
+            commit: metadata.get(COMMIT).and_then(|id| id.parse().ok()),
+        })
+    }
+
+    pub fn to_metadata(self) -> HashMap<String, String> {
+        let mut metadata = HashMap::from([
+            (CREATED.to_owned(), self.created.to_string()),
+            (GENERATION.to_owned(), self.generation.to_string()),
+            (QUARANTINED.to_owned(), self.quarantined.to_string()),
+        ]);
+        if let Some(len) = self.len {
+            metadata.insert(LENGTH.to_owned(), len.to_string());
+        }
+        if let Some(len) = self.external_len {
+            metadata.insert(EXTERNAL_LENGTH.to_owned(), len.to_string());
+        }
+        if let Some(link) = self.link {
+            metadata.insert(LINK.to_owned(), link.to_string());
+        }
+        if let Some(commit) = self.commit {
+            metadata.insert(COMMIT.to_owned(), commit.to_string());
+        }
+        // unknown links in between are kept as empty entries
+        let known = HISTORY
+            - self
+                .ancestors
+                .iter()
+                .rev()
+                .take_while(|a| a.is_none())
+                .count();
+        if known > 0 {
+            let ancestors: Vec<_> = self.ancestors[..known]
+                .iter()
+                .map(|link| link.map(|link| link.to_string()).unwrap_or_default())
+                .collect();
+            metadata.insert(ANCESTORS.to_owned(), ancestors.join(","));
+        }
+        metadata
+    }
+}
+
+/// What [crate::vfs::Inner::check_metadata] found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataHealth {
+    /// The metadata object is present and its generation is plausible, or the database doesn't
+    /// exist yet.
+    Healthy,
+    /// The metadata object was missing next to an existing database and has been recreated.
+    Reconstructed,
+    /// The generation went backwards, now or earlier. Writes are refused.
+    Quarantined,
+}
+
+/// IDs of the enabled `rules` that expire any of `keys`. Rules without an ID are reported by
+/// their prefix.
+pub fn expiring_rules(rules: &[LifecycleRule], keys: &[&str]) -> Vec<String> {
+    rules
+        .iter()
+        .filter(|rule| *rule.status() == ExpirationStatus::Enabled && rule.expiration().is_some())
+        .filter_map(|rule| {
+            let filter = rule.filter();
+            // the crate doesn't tag its objects, so rules limited to tags never apply
+            if filter.is_some_and(|f| f.tag().is_some())
+                || filter
+                    .and_then(|f| f.and())
+                    .is_some_and(|and| !and.tags().is_empty())
+            {
+                return None;
+            }
+            #[allow(deprecated)]
+            let prefix = filter
+                .and_then(|f| f.prefix().or(f.and().and_then(|and| and.prefix())))
+                .or(rule.prefix())
+                .unwrap_or_default();
+            keys.iter()
+                .any(|key| key.starts_with(prefix))
+                .then(|| rule.id().unwrap_or(prefix).to_owned())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use aws_sdk_s3::types::{LifecycleExpiration, LifecycleRuleFilter, Tag};
+
+    use super::*;
+    use crate::{
+        cache::{CacheConfig, CacheUse},
+        config::Config,
+        error::Error,
+        key::{KeyLayout, ObjectKey},
+        mirror::BlockManifest,
+        mock::{self, MockS3},
+        vfs::{Inner, Metadata, MetadataRecord, ThreeQLite},
+    };
+
+    fn test_db() -> ObjectKey {
+        KeyLayout::db("test.db").unwrap()
+    }
+
+    fn rule(id: &str, filter: LifecycleRuleFilter) -> LifecycleRule {
+        LifecycleRule::builder()
+            .id(id)
+            .status(ExpirationStatus::Enabled)
+            .expiration(LifecycleExpiration::builder().days(30).build())
+            .filter(filter)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_stamp_roundtrip() {
+        let stamp = Stamp {
+            created: 1700000000000,
+            generation: 42,
+            quarantined: true,
+            len: Some(8192),
+            external_len: Some(12288),
+            commit: Some(Uuid::from_u128(0x5eed)),
+            ..Stamp::new(42)
+        };
+        assert_eq!(
+            Stamp::from_metadata(Some(&stamp.to_metadata())),
+            Some(stamp)
+        );
+        assert_eq!(Stamp::from_metadata(Some(&HashMap::new())), None);
+        assert_eq!(Stamp::from_metadata(None), None);
+
+        // linked, with the link of a generation unknown in between
+        let mut linked = stamp;
+        linked.advance(1700000000001, Some("\"a\""));
+        linked.advance(1700000000002, None);
+        linked.advance(1700000000003, Some("\"b\""));
+        assert_eq!(linked.ancestors.iter().filter(|a| a.is_some()).count(), 2);
+        linked.ancestors[0] = None;
+        assert_eq!(
+            Stamp::from_metadata(Some(&linked.to_metadata())),
+            Some(linked)
+        );
+    }
+
+    #[test]
+    fn test_expiring_rules() {
+        let keys = ["tenants/a/main.db", "tenants/a/lockfile"];
+        let rules = [
+            rule("all", LifecycleRuleFilter::builder().build()),
+            rule(
+                "tenants",
+                LifecycleRuleFilter::builder().prefix("tenants/").build(),
+            ),
+            rule(
+                "logs",
+                LifecycleRuleFilter::builder().prefix("logs/").build(),
+            ),
+            rule(
+                "tagged",
+                LifecycleRuleFilter::builder()
+                    .tag(Tag::builder().key("tmp").value("1").build().unwrap())
+                    .build(),
+            ),
+        ];
+        assert_eq!(expiring_rules(&rules, &keys), ["all", "tenants"]);
+
+        let disabled = LifecycleRule::builder()
+            .status(ExpirationStatus::Disabled)
+            .expiration(LifecycleExpiration::builder().days(30).build())
+            .build()
+            .unwrap();
+        assert!(expiring_rules(&[disabled], &keys).is_empty());
+    }
+
+    async fn write_stamped(tq: &ThreeQLite, generation: u64) {
+        let inner = tq.inner.read().await;
+        inner
+            .write_metadata_record(MetadataRecord {
+                stamp: Some(Stamp::new(generation)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_missing_metadata_reconstructed() {
+        let mock = MockS3::start();
+        mock.put("test.db", vec![0; 4096]);
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        write_stamped(&tq, 7).await;
+        let inner = tq.inner.read().await;
+        assert_eq!(
+            inner
+                .read_metadata_record()
+                .await
+                .unwrap()
+                .stamp
+                .unwrap()
+                .generation,
+            7
+        );
+
+        mock.delete("metadata");
+        assert_eq!(
+            inner.check_metadata(&test_db()).await.unwrap(),
+            MetadataHealth::Reconstructed
+        );
+        let record = inner.read_metadata_record().await.unwrap();
+        assert!(matches!(record.metadata, Metadata::None));
+        let stamp = record.stamp.unwrap();
+        assert_eq!(stamp.generation, 7);
+        assert!(!stamp.quarantined);
+        assert_eq!(
+            inner.check_metadata(&test_db()).await.unwrap(),
+            MetadataHealth::Healthy
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_database_untouched() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let inner = tq.inner.read().await;
+        assert_eq!(
+            inner.check_metadata(&test_db()).await.unwrap(),
+            MetadataHealth::Healthy
+        );
+        assert_eq!(mock.get("metadata"), None);
+    }
+
+    #[tokio::test]
+    async fn test_generation_regression_quarantines() {
+        let mock = MockS3::start();
+        mock.put("test.db", vec![0; 4096]);
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        write_stamped(&tq, 5).await;
+        assert_eq!(
+            tq.inner
+                .read()
+                .await
+                .check_metadata(&test_db())
+                .await
+                .unwrap(),
+            MetadataHealth::Healthy
+        );
+
+        // another instance that never saw the database recreates the metadata object
+        let other = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        write_stamped(&other, 1).await;
+
+        let inner = tq.inner.read().await;
+        assert_eq!(
+            inner.check_metadata(&test_db()).await.unwrap(),
+            MetadataHealth::Quarantined
+        );
+        assert_eq!(
+            mock.user_metadata("metadata")["threeqlite-quarantined"],
+            "true"
+        );
+        assert_eq!(mock.user_metadata("metadata")["threeqlite-generation"], "5");
+        // the quarantine is visible to instances without any history, too
+        assert_eq!(
+            other
+                .inner
+                .read()
+                .await
+                .check_metadata(&test_db())
+                .await
+                .unwrap(),
+            MetadataHealth::Quarantined
+        );
+        drop(inner);
+
+        tq.clear_quarantine().await.unwrap();
+        assert_eq!(
+            tq.inner
+                .read()
+                .await
+                .check_metadata(&test_db())
+                .await
+                .unwrap(),
+            MetadataHealth::Healthy
+        );
+        assert_eq!(
+            tq.inner
+                .read()
+                .await
+                .generation_seen
+                .load(Ordering::Relaxed),
+            5
+        );
+    }
+
+    /// Register as a reader as of the stamp in the metadata object, as a transaction start does.
+    async fn join(inner: &mut Inner) -> u64 {
+        let stamp = inner.read_metadata_record().await.unwrap().stamp;
+        inner.join_stamp(stamp).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_external_modification_quarantines() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 4, 1));
+        let config = Config {
+            cache: CacheConfig {
+                capacity: 1024 * 1024,
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config.clone(), mock.client()).unwrap();
+        let mut inner = tq.inner.write().await;
+        // a commit records the length it left the object at
+        inner.written = true;
+        let stamp = inner.commit_stamp(Some(Stamp::new(3))).await.stamp;
+        assert_eq!((stamp.generation, stamp.len), (4, Some(4 * 4096)));
+        inner
+            .write_metadata(Metadata::None, Some(stamp))
+            .await
+            .unwrap();
+        assert_eq!(mock.user_metadata("metadata")["threeqlite-length"], "16384");
+        let generation = join(&mut inner).await;
+        inner
+            .read_at(4096, 4096, Some(generation), CacheUse::Admit)
+            .await
+            .unwrap();
+
+        // appended to out-of-band
+        mock.put("test.db", mock::database(4096, 5, 1));
+        let err = inner
+            .read_at(8192, 4096, Some(generation), CacheUse::Admit)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::ExternalModification {
+                    expected: 16384,
+                    actual: 20480,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        let stamp = inner.read_metadata_record().await.unwrap().stamp.unwrap();
+        assert!(stamp.quarantined);
+        assert_eq!((stamp.len, stamp.external_len), (Some(16384), Some(20480)));
+        assert_eq!(
+            inner.check_metadata(&test_db()).await.unwrap(),
+            MetadataHealth::Quarantined
+        );
+        // the pages cached before are no longer served
+        assert!(!inner.cache.contains("test.db", generation, 4096, 4096));
+
+        // reading it anyway has to be acknowledged
+        let acknowledged = ThreeQLite::with_client(
+            Config {
+                read_externally_modified: true,
+                ..config
+            },
+            mock.client(),
+        )
+        .unwrap();
+        let mut other = acknowledged.inner.write().await;
+        let generation = join(&mut other).await;
+        let page = other
+            .read_at(16384, 4096, Some(generation), CacheUse::Admit)
+            .await
+            .unwrap();
+        assert_eq!(page, vec![0; 4096]);
+        assert!(
+            other
+                .read_metadata_record()
+                .await
+                .unwrap()
+                .stamp
+                .unwrap()
+                .quarantined
+        );
+        drop((inner, other));
+
+        // fsck accepts the object as it is now
+        assert_eq!(tq.accept_external_state("test.db").await.unwrap(), 20480);
+        let mut inner = tq.inner.write().await;
+        let stamp = inner.read_metadata_record().await.unwrap().stamp.unwrap();
+        assert!(!stamp.quarantined);
+        assert_eq!(
+            (stamp.generation, stamp.len, stamp.external_len),
+            (5, Some(20480), None)
+        );
+        let manifest: BlockManifest =
+            bincode::deserialize(&mock.get("test.db.blocks").unwrap()).unwrap();
+        assert_eq!(manifest.len, 20480);
+        assert_eq!(
+            inner.check_metadata(&test_db()).await.unwrap(),
+            MetadataHealth::Healthy
+        );
+        let generation = join(&mut inner).await;
+        assert_eq!(generation, 5);
+        inner
+            .read_at(8192, 4096, Some(generation), CacheUse::Admit)
+            .await
+            .unwrap();
+
+        // truncated out-of-band
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let err = inner
+            .read_at(4096, 4096, Some(generation), CacheUse::Admit)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::ExternalModification {
+                    expected: 20480,
+                    actual: 8192,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(
+            inner.check_metadata(&test_db()).await.unwrap(),
+            MetadataHealth::Quarantined
+        );
+    }
+
+    #[tokio::test]
+    async fn test_preflight_lifecycle() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        assert!(tq.preflight().await.unwrap().is_empty());
+
+        mock.set_lifecycle(
+            "<LifecycleConfiguration>\
+               <Rule><ID>expire-30d</ID><Filter><Prefix></Prefix></Filter><Status>Enabled</Status>\
+                 <Expiration><Days>30</Days></Expiration></Rule>\
+               <Rule><ID>logs</ID><Filter><Prefix>logs/</Prefix></Filter><Status>Enabled</Status>\
+                 <Expiration><Days>1</Days></Expiration></Rule>\
+             </LifecycleConfiguration>",
+        );
+        assert_eq!(tq.preflight().await.unwrap(), ["expire-30d"]);
+    }
+}
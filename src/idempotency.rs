@@ -0,0 +1,292 @@
+//! Idempotent conditional writes of commit points.
+//!
+//! A conditional PUT can succeed on the object store while its response is lost, e.g. to a
+//! timeout or a dropped connection. Retrying it, whether the SDK or this crate does, then fails its
+//! precondition, since the ETag moved on because of the very write being retried. Reporting that
+//! as a conflict fails a commit that happened, and retrying it as a new commit would record a
+//! generation twice. The conditional writes that are commit points thus carry an idempotency key,
+//! and one that failed, or whose precondition did, reads the object back before deciding: if it
+//! holds the key, the write landed and its ETag is adopted as the result.
+//!
+//! | Write | Precondition | Idempotency key |
+//! |---|---|---|
+//! | commit record, releasing the write lock | `If-Match` the record the release read | a UUID per commit, the `threeqlite-commit` user metadata, see [Stamp::commit] |
+//! | block manifest, see [crate::extent] | `If-Match` the manifest replaced, `If-None-Match: *` for the first | the MD5 of the body |
+//!
+//! The idempotency keys are part of the format: a writer finds its commit by the UUID alone, so a
+//! record without one, written before UUIDs were recorded, is never mistaken for its own.
+//!
+//! A commit record whose precondition failed while the metadata object still shows the write lock
+//! of the writer, e.g. after a write request of a waiting writer, is retried against the new ETag.
+//! Only a record holding another commit or another lock holder is a conflict, reported as
+//! [Error::CommitConflict]. Adopted writes are counted as `adopted_writes` in the stats.
+
+use crate::{
+    circuit::OpClass,
+    error::Error,
+    heal::Stamp,
+    stats::Stats,
+    verify::Upload,
+    vfs::{status, Inner, Metadata, MetadataRecord, Precondition},
+};
+
+/// How many times a commit record is written before giving up, when its responses keep getting
+/// lost or waiting writers keep moving its ETag.
+const ATTEMPTS: usize = 4;
+
+/// Record the commit `stamp` of the writer holding the lock `lock_uuid` in the metadata object,
+/// if it is still at `etag`. Returns the ETag of the record written or adopted.
+pub async fn record_commit(
+    inner: &Inner,
+    stamp: Stamp,
+    mut etag: Option<String>,
+    lock_uuid: &[u8],
+) -> Result<Option<String>, Error> {
+    let conflict = || Error::CommitConflict {
+        db: inner.db_filename.to_string(),
+        generation: stamp.generation,
+    };
+    let mut failure = None;
+    for _ in 0..ATTEMPTS {
+        let record = MetadataRecord {
+            metadata: Metadata::None,
+            stamp: Some(stamp),
+            ..Default::default()
+        };
+        let precondition = etag
+            .clone()
+            .map_or(Precondition::None, Precondition::Matches);
+        failure = match inner.put_metadata_record(record, precondition).await {
+            Ok(Some(out)) => return Ok(out.e_tag),
+            Ok(None) => None,
+            // it may have landed anyway
+            Err(err) => Some(err),
+        };
+
+        let current = inner.read_metadata_record().await?;
+        let current_commit = current.stamp.and_then(|stamp| stamp.commit);
+        if stamp.commit.is_some() && current_commit == stamp.commit {
+            Stats::incr(&inner.stats.adopted_writes);
+            tracing::info!(
+                target: "threeqlite::lock_protocol",
+                key = %inner.metadata_filename,
+                generation = stamp.generation,
+                "commit record was written although the response was lost, adopting it"
+            );
+            return Ok(current.etag);
+        }
+        match &current.metadata {
+            Metadata::Writer(holder) if holder == lock_uuid => etag = current.etag,
+            _ => return Err(conflict()),
+        }
+    }
+    Err(failure.unwrap_or_else(conflict))
+}
+
+/// Whether the object `upload` was sent to holds its body, i.e. whether a PUT of it landed
+/// although it failed or its precondition did. Counted as adopted if so.
+pub async fn adopt_upload(inner: &Inner, upload: &Upload) -> Result<bool, Error> {
+    let obj = inner
+        .s3
+        .get_object()
+        .bucket(&inner.bucket)
+        .key(&upload.key)
+        .send()
+        .await;
+    let missing = matches!(&obj, Err(err) if status(err) == Some(404));
+    inner.record(OpClass::Read, obj.is_ok() || missing);
+    let obj = match obj {
+        Ok(obj) => obj,
+        Err(_) if missing => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    if obj.content_length() != Some(upload.len as i64) {
+        return Ok(false);
+    }
+    let bytes = obj.body.collect().await.map_err(|err| Error::Whatever {
+        message: format!("failed to read object body: {err}"),
+        source: None,
+    })?;
+    if format!("{:x}", md5::compute(bytes.into_bytes())) != upload.md5 {
+        return Ok(false);
+    }
+    Stats::incr(&inner.stats.adopted_writes);
+    tracing::info!(
+        target: "threeqlite::s3",
+        key = %upload.key,
+        "upload was written although the response was lost, adopting it"
+    );
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering::Relaxed;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        config::Config, extent, key::KeyLayout, mirror::BlockManifest, mock::MockS3,
+        vfs::ThreeQLite,
+    };
+
+    const LOCK: &[u8] = b"writer";
+
+    /// The metadata object as the writer holding [LOCK] at `generation` finds it on release.
+    async fn locked(tq: &ThreeQLite, generation: u64) -> MetadataRecord {
+        let inner = tq.inner.read().await;
+        inner
+            .write_metadata_record(MetadataRecord {
+                metadata: Metadata::Writer(LOCK.to_vec()),
+                stamp: Some(Stamp::new(generation)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        inner.read_metadata_record().await.unwrap()
+    }
+
+    fn metadata_puts(mock: &MockS3) -> usize {
+        mock.requests()
+            .iter()
+            .filter(|(method, key)| method == "PUT" && key == "metadata")
+            .count()
+    }
+
+    fn generation(mock: &MockS3) -> u64 {
+        mock.user_metadata("metadata")["threeqlite-generation"]
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lost_commit_response() {
+        // the SDK retries the commit, which fails its precondition, or this crate sees the error
+        for retrying in [true, false] {
+            let mock = MockS3::start();
+            let client = match retrying {
+                true => mock.retrying_client(3),
+                false => mock.client(),
+            };
+            let tq = ThreeQLite::with_client(Config::default(), client).unwrap();
+            let read = locked(&tq, 5).await;
+            let puts = metadata_puts(&mock);
+
+            mock.lose_response("PUT", "metadata");
+            let mut inner = tq.inner.write().await;
+            let receipt = inner.record_commit(read, LOCK).await.unwrap();
+            assert_eq!(receipt.generation, 6);
+            assert_eq!(generation(&mock), 6, "retrying={retrying}");
+            assert_eq!(
+                metadata_puts(&mock) - puts,
+                if retrying { 2 } else { 1 },
+                "the commit isn't written again once found"
+            );
+            assert_eq!(inner.stats.adopted_writes.load(Relaxed), 1);
+            let current = inner.read_metadata_record().await.unwrap();
+            assert_eq!(receipt.record_etag, current.etag);
+            assert!(matches!(current.metadata, Metadata::None));
+            drop(inner);
+
+            // the next commit follows on, rather than a duplicate of the first
+            let read = locked(&tq, 6).await;
+            let receipt = tq
+                .inner
+                .write()
+                .await
+                .record_commit(read, LOCK)
+                .await
+                .unwrap();
+            assert_eq!(receipt.generation, 7);
+            assert_eq!(generation(&mock), 7);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conflicts() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let inner = tq.inner.read().await;
+
+        // another writer committed meanwhile
+        let read = locked(&tq, 5).await;
+        inner
+            .write_metadata_record(MetadataRecord {
+                stamp: Some(Stamp {
+                    commit: Some(Uuid::new_v4()),
+                    ..Stamp::new(6)
+                }),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let stamp = Stamp {
+            commit: Some(Uuid::new_v4()),
+            ..Stamp::new(6)
+        };
+        let err = record_commit(&inner, stamp, read.etag, LOCK)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::CommitConflict { generation: 6, .. }),
+            "{err}"
+        );
+        assert_eq!(inner.stats.adopted_writes.load(Relaxed), 0);
+
+        // a record without commit ID is someone else's, even at the same generation
+        let read = locked(&tq, 5).await;
+        inner
+            .write_metadata_record(MetadataRecord {
+                stamp: Some(Stamp::new(6)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let err = record_commit(&inner, stamp, read.etag, LOCK)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::CommitConflict { .. }), "{err}");
+
+        // the lock is still held, only the ETag moved on
+        let read = locked(&tq, 5).await;
+        inner
+            .write_metadata_record(MetadataRecord {
+                metadata: Metadata::Writer(LOCK.to_vec()),
+                reader_versions: vec![(b"reader".to_vec(), 2)],
+                stamp: read.stamp,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let etag = record_commit(&inner, stamp, read.etag.clone(), LOCK)
+            .await
+            .unwrap();
+        assert_ne!(etag, read.etag);
+        assert_eq!(generation(&mock), 6);
+    }
+
+    #[tokio::test]
+    async fn test_lost_manifest_response() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let db = KeyLayout::db("test.db").unwrap();
+        let inner = tq.inner.read().await;
+
+        mock.lose_response("PUT", KeyLayout::manifest(&db).as_str());
+        extent::publish(&inner, &db, &BlockManifest::new(b"first"))
+            .await
+            .unwrap();
+        assert_eq!(inner.stats.adopted_writes.load(Relaxed), 1);
+
+        // replaced conditionally on the ETag the adopted write left
+        extent::publish(&inner, &db, &BlockManifest::new(b"second"))
+            .await
+            .unwrap();
+        assert_eq!(inner.stats.adopted_writes.load(Relaxed), 1);
+        assert!(matches!(
+            extent::read(&inner, &db).await.unwrap().0,
+            Some(extent::Manifest::Inline(manifest)) if manifest == BlockManifest::new(b"second")
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+//! Reads of a database while a flush of it uploads.
+//!
+//! A flush uploads the pages of a commit in rounds of requests, see [crate::flush], which takes
+//! seconds for a large transaction on a slow object store. The state of the database is only held
+//! for the steps around the uploads: the start of the flush, which takes the write lock unless it
+//! is held already, and the commit barrier after the uploads, which records the pages in the page
+//! cache and releases the lock it took. In between, the pages are [InFlight]: every read of the
+//! instance sees them over the database object and the page cache, so that the other connections
+//! of the process neither wait for the flush nor read a page halfway uploaded. They read the
+//! database as of the commit throughout, as the page cache has it once the barrier passed. The
+//! pages in flight share the buffers SQLite's writes were copied into, see [PendingWrites].
+//!
+//! A flush that fails withdraws its pages and drops them from the page cache, and the connection
+//! that wrote them keeps them buffered to retry, like before.
+//!
+//! The steps hold the state as an [Exclusive] section, and the uploads between them run through
+//! [unlocked], which panics in debug builds when the task still holds a section: a request to the
+//! object store under it would block every read of the instance again.
+
+use std::{
+    cell::Cell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{RwLock, RwLockWriteGuard};
+
+use crate::{flush::PendingWrites, vfs::Inner};
+
+tokio::task_local! {
+    /// How many [Exclusive] sections the current task holds, see [scope].
+    static HELD: Cell<usize>;
+}
+
+/// The pages of the flushes of a database that are uploading, oldest first.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    flights: Mutex<Vec<Arc<PendingWrites>>>,
+}
+
+impl InFlight {
+    /// Let reads see `pages` until the returned [Flight] is dropped.
+    pub fn start(self: &Arc<Self>, pages: Arc<PendingWrites>) -> Flight {
+        self.flights.lock().unwrap().push(pages.clone());
+        Flight {
+            in_flight: self.clone(),
+            pages,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flights.lock().unwrap().is_empty()
+    }
+
+    /// The end of the last page in flight.
+    pub fn end(&self) -> Option<u64> {
+        let flights = self.flights.lock().unwrap();
+        flights.iter().filter_map(|pages| pages.end()).max()
+    }
+
+    /// Copy what is in flight of `offset..offset + buf.len()` into `buf`, the pages of later
+    /// flushes over those of earlier ones. Returns whether a single flush has all of it.
+    pub fn overlay(&self, offset: u64, buf: &mut [u8]) -> bool {
+        let mut covered = false;
+        for pages in self.flights.lock().unwrap().iter() {
+            covered |= pages.overlay(offset, buf);
+        }
+        covered
+    }
+}
+
+/// The pages of a flush, withdrawn from [InFlight] when dropped.
+pub struct Flight {
+    in_flight: Arc<InFlight>,
+    pages: Arc<PendingWrites>,
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        self.in_flight
+            .flights
+            .lock()
+            .unwrap()
+            .retain(|pages| !Arc::ptr_eq(pages, &self.pages));
+    }
+}
+
+/// The state of a database, held by the current task for a step of a flush, see the
+/// [module documentation](self).
+pub struct Exclusive<'a> {
+    guard: RwLockWriteGuard<'a, Inner>,
+}
+
+impl<'a> Exclusive<'a> {
+    pub async fn acquire(inner: &'a RwLock<Inner>) -> Self {
+        let guard = inner.write().await;
+        let _ = HELD.try_with(|held| held.set(held.get() + 1));
+        Self { guard }
+    }
+}
+
+impl Deref for Exclusive<'_> {
+    type Target = Inner;
+
+    fn deref(&self) -> &Inner {
+        &self.guard
+    }
+}
+
+impl DerefMut for Exclusive<'_> {
+    fn deref_mut(&mut self) -> &mut Inner {
+        &mut self.guard
+    }
+}
+
+impl Drop for Exclusive<'_> {
+    fn drop(&mut self) {
+        let _ = HELD.try_with(|held| held.set(held.get() - 1));
+    }
+}
+
+/// Run `fut` counting the [Exclusive] sections it holds, for [unlocked] to check. `fut` is boxed
+/// like the one of [crate::busy::with_handler].
+pub async fn scope<F: Future>(fut: F) -> F::Output {
+    HELD.scope(Cell::new(0), Box::pin(fut)).await
+}
+
+/// How many [Exclusive] sections the current task holds, 0 outside of [scope].
+pub fn held() -> usize {
+    HELD.try_with(Cell::get).unwrap_or(0)
+}
+
+/// Await `fut`, which makes requests to the object store, checking in debug builds that the
+/// current task holds no [Exclusive] section.
+pub async fn unlocked<F: Future>(fut: F) -> F::Output {
+    debug_assert_eq!(
+        held(),
+        0,
+        "the state of the database is held across a request to the object store"
+    );
+    fut.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, mock::MockS3, vfs::ThreeQLite};
+
+    fn pages(extents: &[(u64, u8)]) -> Arc<PendingWrites> {
+        let mut pages = PendingWrites::default();
+        for &(offset, fill) in extents {
+            pages.write(offset, &[fill; 4]);
+        }
+        Arc::new(pages)
+    }
+
+    #[test]
+    fn test_overlay_later_flushes_win() {
+        let in_flight = Arc::new(InFlight::default());
+        let first = in_flight.start(pages(&[(0, 1), (4, 1)]));
+        let second = in_flight.start(pages(&[(4, 2)]));
+
+        let mut buf = [0; 8];
+        assert!(in_flight.overlay(0, &mut buf));
+        assert_eq!(buf, [1, 1, 1, 1, 2, 2, 2, 2]);
+        let mut buf = [0; 8];
+        assert!(!in_flight.overlay(6, &mut buf));
+        assert_eq!(buf, [2, 2, 0, 0, 0, 0, 0, 0]);
+
+        drop(second);
+        let mut buf = [0; 4];
+        assert!(in_flight.overlay(4, &mut buf));
+        assert_eq!(buf, [1; 4]);
+        drop(first);
+        assert!(in_flight.is_empty());
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "held across a request")]
+    async fn test_unlocked_while_held() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        scope(async {
+            let inner = Exclusive::acquire(&tq.inner).await;
+            unlocked(async {}).await;
+            drop(inner);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_sections_counted() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        scope(async {
+            assert_eq!(held(), 0);
+            let inner = Exclusive::acquire(&tq.inner).await;
+            assert_eq!(held(), 1);
+            drop(inner);
+            unlocked(async {}).await;
+        })
+        .await;
+        // outside of a scope, nothing is counted
+        let _inner = Exclusive::acquire(&tq.inner).await;
+        assert_eq!(held(), 0);
+    }
+}
@@ -0,0 +1,642 @@
+//! Integrity verification of hosted databases.
+//!
+//! Running `PRAGMA integrity_check` through the VFS on a cold database reads every page with its
+//! own ranged GET. Instead, the database is fetched with a few large sequential GETs into a local
+//! snapshot, and the check runs against that snapshot with the default VFS.
+//!
+//! Databases aren't run in WAL mode through this VFS, but one uploaded in WAL mode may come with
+//! a write-ahead log object (`<db>-wal`, see [KeyLayout::wal]) holding commits that were never
+//! checkpointed. Checking the database object alone would then miss them, or report a database
+//! torn between the two. What a check does about it is [IntegrityOptions::wal], see
+//! [WalHandling]: by default, if the header of the database says WAL mode, the log is fetched
+//! under the same read lock, and replayed into the local snapshot by a local connection before the
+//! check. A log next to a database whose header doesn't say WAL mode is ignored by SQLite too, so
+//! it is never fetched.
+//!
+//! Checkpointing the log into the database object before the check isn't offered: no connection
+//! runs the database in WAL mode through this VFS, so there is nothing to ask for a checkpoint,
+//! and the log object is left as uploaded either way.
+//!
+//! A check runs as a maintenance operation, see [crate::operation]. Cancelling it stops the
+//! fetch after the range in flight; the local snapshot is deleted either way.
+//! [ThreeQLite::estimate_integrity_check] plans a check without running it, see [crate::cost]:
+//! the ranges to fetch are those of running it, and whether there is a log to fetch as well is
+//! read from the header of the database. The estimate a check records for itself leaves the log
+//! out, since it only learns about it from the first range.
+
+use std::{
+    io::Write as _,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use rusqlite::{Connection, OpenFlags};
+use snafu::ResultExt;
+
+use crate::{
+    circuit::OpClass,
+    cost::{self, CostEstimate, READ_LOCK},
+    error::{Error, SqliteSnafu},
+    key::{KeyLayout, ObjectKey},
+    operation::{Operation, OperationHandle, OperationKind},
+    priority::IoClass,
+    vfs::{status, Inner, ThreeQLite},
+};
+
+#[derive(Clone, Debug)]
+pub struct IntegrityOptions {
+    /// Run `PRAGMA quick_check` instead of the full `PRAGMA integrity_check`.
+    pub quick: bool,
+    /// Stop after this many findings.
+    pub max_errors: u32,
+    /// Size of each ranged GET, capped at the `bulk_part_size` of the instance, see
+    /// [crate::priority].
+    pub fetch_size: u64,
+    /// Abort with a partial report once this many bytes were fetched.
+    pub max_bytes: Option<u64>,
+    /// Whether to replay the write-ahead log object of a database in WAL mode.
+    pub wal: WalHandling,
+}
+
+/// What a check does with the write-ahead log object of a database in WAL mode, see the
+/// [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WalHandling {
+    /// Fetch the log under the same read lock as the database, and replay it into the snapshot.
+    /// One more GET and the bytes of the log, and the check sees every commit.
+    #[default]
+    Replay,
+    /// Check the database object alone. Commits only in the log are missed, and a database torn
+    /// between the two may be reported as corrupt.
+    Ignore,
+}
+
+impl Default for IntegrityOptions {
+    fn default() -> Self {
+        Self {
+            quick: false,
+            max_errors: 100,
+            fetch_size: 8 * 1024 * 1024,
+            max_bytes: None,
+            wal: WalHandling::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IntegrityReport {
+    /// The rows returned by the check. A healthy database reports a single `"ok"`.
+    pub findings: Vec<String>,
+    /// Whether the whole database was fetched and checked. `false` if the byte budget ran out.
+    pub complete: bool,
+    pub bytes_transferred: u64,
+    pub requests: u64,
+    pub duration: Duration,
+    /// What the plan of the check estimated it costs, see [crate::cost].
+    pub estimate: Option<CostEstimate>,
+    pub cost: CostEstimate,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.complete && self.findings == ["ok"]
+    }
+}
+
+/// A temporary file removed on drop, along with the log and shared memory file of a replayed
+/// write-ahead log.
+struct Snapshot(PathBuf);
+
+impl Snapshot {
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut path = self.0.clone().into_os_string();
+        path.push(suffix);
+        path.into()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        for path in [self.0.clone(), self.sibling("-wal"), self.sibling("-shm")] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Whether the database starting with `header` is in WAL mode: its file format read and write
+/// versions are 2 rather than 1.
+fn is_wal_mode(header: &[u8]) -> bool {
+    header.get(18..20) == Some(&[2, 2])
+}
+
+/// Checkpoint the write-ahead log next to the local database at `path` into it, and leave WAL mode
+/// so that the database can be opened read-only without a log.
+fn replay_wal(path: &Path) -> Result<(), Error> {
+    let conn = Connection::open(path).context(SqliteSnafu)?;
+    conn.query_row("PRAGMA journal_mode = DELETE", [], |_| Ok(()))
+        .context(SqliteSnafu)
+}
+
+/// Split `size` bytes into sequential ranges of at most `fetch_size` bytes.
+fn plan_ranges(size: u64, fetch_size: u64) -> Vec<Range<u64>> {
+    (0..size)
+        .step_by(fetch_size.max(1) as usize)
+        .map(|start| start..(start + fetch_size).min(size))
+        .collect()
+}
+
+/// The ranges a check fetches of a database of `size` bytes, and whether they are all of it
+/// within the byte budget of `opts`.
+fn plan_fetch(size: u64, fetch_size: u64, opts: &IntegrityOptions) -> (Vec<Range<u64>>, bool) {
+    let mut ranges = plan_ranges(size, fetch_size);
+    let within = match opts.max_bytes {
+        Some(max) => ranges.iter().take_while(|range| range.end <= max).count(),
+        None => ranges.len(),
+    };
+    let complete = within == ranges.len();
+    ranges.truncate(within);
+    (ranges, complete)
+}
+
+/// What a check fetching `ranges` costs, along with a write-ahead log of `wal` bytes if any, see
+/// [crate::cost].
+fn fetch_cost(ranges: &[Range<u64>], wal: Option<u64>) -> CostEstimate {
+    let bytes = ranges
+        .iter()
+        .map(|range| range.end - range.start)
+        .sum::<u64>();
+    let bytes = bytes + wal.unwrap_or(0);
+    let fetch = CostEstimate {
+        get_requests: 1 + ranges.len() as u64 + wal.is_some() as u64,
+        bytes_down: bytes,
+        peak_scratch_bytes: bytes,
+        ..CostEstimate::default()
+    };
+    fetch + READ_LOCK
+}
+
+/// The length of `key`, with the HEAD request checks start with.
+async fn head_len(inner: &Inner, key: &ObjectKey) -> Result<u64, Error> {
+    let head = inner
+        .s3
+        .head_object()
+        .bucket(&inner.bucket)
+        .key(key)
+        .send()
+        .await;
+    inner.record_for(key, OpClass::Read, head.is_ok());
+    Ok(head?.content_length.unwrap_or(0) as u64)
+}
+
+/// Run the check against a local database file.
+fn check_local(path: &Path, opts: &IntegrityOptions) -> Result<Vec<String>, Error> {
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).context(SqliteSnafu)?;
+    let pragma = if opts.quick {
+        "quick_check"
+    } else {
+        "integrity_check"
+    };
+    let findings = conn
+        .prepare(&format!("PRAGMA {pragma}({})", opts.max_errors))
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        });
+    match findings {
+        Ok(findings) => Ok(findings),
+        // damage bad enough to abort the check is a finding, not a failure to check
+        Err(rusqlite::Error::SqliteFailure(err, msg))
+            if matches!(
+                err.code,
+                rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase
+            ) =>
+        {
+            Ok(vec![msg.unwrap_or_else(|| err.to_string())])
+        }
+        Err(source) => Err(Error::Sqlite { source }),
+    }
+}
+
+impl ThreeQLite {
+    /// Check the integrity of the database stored at `db` without going through the
+    /// page-granular read path.
+    pub async fn integrity_check(
+        &self,
+        db: &str,
+        opts: IntegrityOptions,
+    ) -> Result<IntegrityReport, Error> {
+        self.operations
+            .run(
+                OperationKind::IntegrityCheck,
+                db.to_owned(),
+                |op| async move { self.check_integrity(db, opts, &op).await },
+            )
+            .await
+    }
+
+    /// [ThreeQLite::integrity_check] as an operation of its own, see [crate::operation].
+    pub fn start_integrity_check(
+        &self,
+        db: &str,
+        opts: IntegrityOptions,
+    ) -> OperationHandle<IntegrityReport> {
+        let (tq, db) = (self.clone(), db.to_owned());
+        self.operations.spawn(
+            OperationKind::IntegrityCheck,
+            db.clone(),
+            move |op| async move { tq.check_integrity(&db, opts, &op).await },
+        )
+    }
+
+    /// What [ThreeQLite::integrity_check] costs, planned without taking the lock or fetching the
+    /// database, see [crate::cost].
+    pub async fn estimate_integrity_check(
+        &self,
+        db: &str,
+        opts: IntegrityOptions,
+    ) -> Result<CostEstimate, Error> {
+        let db = KeyLayout::db(db)?;
+        let inner = self.inner.read().await;
+        inner.guard_for(&db, OpClass::Read)?;
+        let size = head_len(&inner, &db).await?;
+        let fetch_size = opts.fetch_size.min(inner.limiter.config().bulk_part_size);
+        let (ranges, complete) = plan_fetch(size, fetch_size, &opts);
+        if !complete || ranges.is_empty() || opts.wal == WalHandling::Ignore {
+            return Ok(fetch_cost(&ranges, None));
+        }
+
+        let obj = inner
+            .s3
+            .get_object()
+            .bucket(&inner.bucket)
+            .key(&db)
+            .range("bytes=0-99")
+            .send()
+            .await;
+        inner.record_for(&db, OpClass::Read, obj.is_ok());
+        let header = obj?.body.collect().await.map_err(|err| Error::Whatever {
+            message: format!("failed to read object body: {err}"),
+            source: None,
+        })?;
+        if !is_wal_mode(&header.into_bytes()) {
+            return Ok(fetch_cost(&ranges, None));
+        }
+        let head = inner
+            .s3
+            .head_object()
+            .bucket(&inner.bucket)
+            .key(KeyLayout::wal(&db))
+            .send()
+            .await;
+        let missing = matches!(&head, Err(err) if status(err) == Some(404));
+        inner.record_for(&db, OpClass::Read, head.is_ok() || missing);
+        let wal = match head {
+            Ok(head) => head.content_length.unwrap_or(0) as u64,
+            // asked for all the same
+            Err(err) if status(&err) == Some(404) => 0,
+            Err(err) => return Err(err.into()),
+        };
+        Ok(fetch_cost(&ranges, Some(wal)))
+    }
+
+    async fn check_integrity(
+        &self,
+        db: &str,
+        opts: IntegrityOptions,
+        op: &Operation,
+    ) -> Result<IntegrityReport, Error> {
+        let db = KeyLayout::db(db)?;
+        let start = Instant::now();
+        let path = std::env::temp_dir().join(format!("threeqlite-check-{}", uuid::Uuid::new_v4()));
+        let snapshot = Snapshot(path);
+        let mut file = std::fs::File::create(&snapshot.0).map_err(|err| Error::Whatever {
+            message: format!("failed to create snapshot file: {err}"),
+            source: None,
+        })?;
+
+        let mut bytes_transferred = 0;
+        let mut requests = 0;
+        let mut complete = true;
+        // whether the snapshot is of a database in WAL mode, to leave locally before the check
+        let mut wal = false;
+        {
+            let mut inner = self.inner.write().await;
+            inner.guard_for(&db, OpClass::Read)?;
+
+            // Hold a read lock while fetching so that the snapshot is consistent.
+            inner.request_read_lock().await?;
+            let fetched: Result<(), Error> = async {
+                requests += 1;
+                let size = head_len(&inner, &db).await?;
+
+                let fetch_size = opts.fetch_size.min(inner.limiter.config().bulk_part_size);
+                let ranges;
+                (ranges, complete) = plan_fetch(size, fetch_size, &opts);
+                op.estimate(fetch_cost(&ranges, None));
+                op.phase("fetching", Some(ranges.len() as u64));
+                for range in ranges {
+                    op.checkpoint()?;
+                    let permit = inner.permit_for(&db, IoClass::Bulk).await;
+                    let obj = inner
+                        .s3
+                        .get_object()
+                        .bucket(&inner.bucket)
+                        .key(&db)
+                        .range(format!("bytes={}-{}", range.start, range.end - 1))
+                        .send()
+                        .await;
+                    inner.record_for(&db, OpClass::Read, obj.is_ok());
+                    requests += 1;
+                    let data = obj?.body.collect().await.map_err(|err| Error::Whatever {
+                        message: format!("failed to read object body: {err}"),
+                        source: None,
+                    })?;
+                    drop(permit);
+                    let data = data.into_bytes();
+                    bytes_transferred += data.len() as u64;
+                    cost::transferred(data.len() as u64, 0);
+                    op.advance(1, data.len() as u64);
+                    if range.start == 0 {
+                        wal = is_wal_mode(&data);
+                    }
+                    file.write_all(&data).map_err(|err| Error::Whatever {
+                        message: format!("failed to write snapshot file: {err}"),
+                        source: None,
+                    })?;
+                    cost::scratch(bytes_transferred);
+                }
+                if !(complete && wal && opts.wal == WalHandling::Replay) {
+                    return Ok(());
+                }
+
+                // fetched under the same read lock, so that the log matches the database
+                op.checkpoint()?;
+                let key = KeyLayout::wal(&db);
+                let obj = inner
+                    .s3
+                    .get_object()
+                    .bucket(&inner.bucket)
+                    .key(&key)
+                    .send()
+                    .await;
+                let missing = matches!(&obj, Err(err) if status(err) == Some(404));
+                inner.record_for(&key, OpClass::Read, obj.is_ok() || missing);
+                requests += 1;
+                let obj = match obj {
+                    Ok(obj) => obj,
+                    // checkpointed and deleted before the upload
+                    Err(err) if status(&err) == Some(404) => return Ok(()),
+                    Err(err) => return Err(err.into()),
+                };
+                let data = obj.body.collect().await.map_err(|err| Error::Whatever {
+                    message: format!("failed to read object body: {err}"),
+                    source: None,
+                })?;
+                let data = data.into_bytes();
+                bytes_transferred += data.len() as u64;
+                cost::transferred(data.len() as u64, 0);
+                op.advance(0, data.len() as u64);
+                std::fs::write(snapshot.sibling("-wal"), &data).map_err(|err| Error::Whatever {
+                    message: format!("failed to write snapshot file: {err}"),
+                    source: None,
+                })?;
+                cost::scratch(bytes_transferred);
+                Ok(())
+            }
+            .await;
+            inner.release_read_lock().await?;
+            fetched?;
+        }
+        drop(file);
+
+        let findings = if complete {
+            op.phase("checking", None);
+            let path = snapshot.0.clone();
+            tokio::task::spawn_blocking(move || {
+                if wal {
+                    // without a log if it was ignored, only leaving WAL mode
+                    replay_wal(&path)?;
+                }
+                check_local(&path, &opts)
+            })
+            .await
+            .map_err(|err| Error::Whatever {
+                message: format!("integrity check panicked: {err}"),
+                source: None,
+            })??
+        } else {
+            vec![]
+        };
+
+        Ok(IntegrityReport {
+            findings,
+            complete,
+            bytes_transferred,
+            requests,
+            duration: start.elapsed(),
+            estimate: op.snapshot().estimate,
+            cost: op.cost(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+
+    use super::*;
+    use crate::{config::Config, mock::MockS3};
+
+    fn fixture() -> Snapshot {
+        let path =
+            std::env::temp_dir().join(format!("threeqlite-fixture-{}", uuid::Uuid::new_v4()));
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "PRAGMA page_size = 4096;
+             CREATE TABLE t (n INTEGER PRIMARY KEY, s TEXT);
+             CREATE INDEX t_s ON t (s);",
+        )
+        .unwrap();
+        for n in 0..2000 {
+            conn.execute("INSERT INTO t VALUES (?1, ?2)", (n, format!("row {n}")))
+                .unwrap();
+        }
+        Snapshot(path)
+    }
+
+    /// The GETs of the log of `wal.db` so far.
+    fn wal_gets(mock: &MockS3) -> usize {
+        let requests = mock.requests();
+        let gets = requests
+            .iter()
+            .filter(|(method, key)| method == "GET" && key == "wal.db-wal");
+        gets.count()
+    }
+
+    #[test]
+    fn test_plan_ranges() {
+        assert_eq!(plan_ranges(0, 10), vec![]);
+        assert_eq!(plan_ranges(25, 10), vec![0..10, 10..20, 20..25]);
+        // a 64MiB database needs 8 requests, not 16384 page reads
+        assert_eq!(plan_ranges(64 << 20, 8 << 20).len(), 8);
+    }
+
+    #[test]
+    fn test_plan_fetch() {
+        let opts = |max_bytes| IntegrityOptions {
+            max_bytes,
+            ..IntegrityOptions::default()
+        };
+        assert_eq!(
+            plan_fetch(25, 10, &opts(None)),
+            (vec![0..10, 10..20, 20..25], true)
+        );
+        assert!(plan_fetch(25, 10, &opts(Some(25))).1);
+        assert_eq!(
+            plan_fetch(25, 10, &opts(Some(24))),
+            (vec![0..10, 10..20], false)
+        );
+        assert_eq!(plan_fetch(25, 10, &opts(Some(5))), (vec![], false));
+    }
+
+    #[tokio::test]
+    async fn test_estimate_matches_cost() {
+        let db = fixture();
+        let data = std::fs::read(&db.0).unwrap();
+        let mock = MockS3::start();
+        mock.put("test.db", data.clone());
+        mock.put("metadata", vec![]);
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let opts = |max_bytes, wal| IntegrityOptions {
+            fetch_size: 16 << 10,
+            max_bytes,
+            wal,
+            ..IntegrityOptions::default()
+        };
+
+        let mut wal = fixture();
+        let conn = Connection::open(&wal.0).unwrap();
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA wal_autocheckpoint = 0;")
+            .unwrap();
+        conn.execute("DELETE FROM t WHERE n % 2 = 0", []).unwrap();
+        mock.put("wal.db", std::fs::read(&wal.0).unwrap());
+        mock.put("wal.db-wal", std::fs::read(wal.sibling("-wal")).unwrap());
+        drop(conn);
+        wal.0 = wal.sibling("-gone");
+
+        for (name, max_bytes, wal, complete) in [
+            ("test.db", None, WalHandling::Replay, true),
+            (
+                "test.db",
+                Some(data.len() as u64 / 2),
+                WalHandling::Replay,
+                false,
+            ),
+            ("wal.db", None, WalHandling::Replay, true),
+            ("wal.db", None, WalHandling::Ignore, true),
+        ] {
+            let case = format!("{name} within {max_bytes:?} with {wal:?}");
+            let estimate = tq
+                .estimate_integrity_check(name, opts(max_bytes, wal))
+                .await;
+            let estimate = estimate.unwrap();
+            let before = mock.request_classes();
+            let logs = wal_gets(&mock);
+            let report = tq
+                .integrity_check(name, opts(max_bytes, wal))
+                .await
+                .unwrap();
+            let accounted = mock.request_classes();
+            assert_eq!(report.complete, complete, "{case}");
+            assert!(!complete || report.is_ok(), "{case}: {:?}", report.findings);
+
+            let cost = report.cost;
+            let metered = [cost.get_requests, cost.put_requests, cost.copy_requests];
+            assert_eq!(
+                metered,
+                [0, 1, 2].map(|i| accounted[i] - before[i]),
+                "{case}"
+            );
+            assert_eq!(
+                (cost.bytes_down, cost.peak_scratch_bytes),
+                (report.bytes_transferred, report.bytes_transferred),
+                "{case}"
+            );
+            assert!(
+                estimate.matches(&cost),
+                "{case}: estimated {estimate}, cost {cost}"
+            );
+            // the check only learns of the log from the header
+            let recorded = report.estimate.unwrap();
+            let replayed = name == "wal.db" && wal == WalHandling::Replay;
+            assert_eq!(recorded != estimate, replayed, "{case}");
+            assert_eq!(wal_gets(&mock) - logs, replayed as usize, "{case}");
+        }
+    }
+
+    #[test]
+    fn test_healthy() {
+        let db = fixture();
+        let findings = check_local(&db.0, &IntegrityOptions::default()).unwrap();
+        assert_eq!(findings, vec!["ok"]);
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let db = fixture();
+        // clobber the b-tree header of a page past the database header page
+        let file = std::fs::OpenOptions::new().write(true).open(&db.0).unwrap();
+        file.write_all_at(&[0xff; 8], 4096 * 3).unwrap();
+
+        let findings = check_local(&db.0, &IntegrityOptions::default()).unwrap();
+        assert_ne!(findings, vec!["ok"]);
+    }
+
+    #[test]
+    fn test_wal_replayed() {
+        let db = fixture();
+        let conn = Connection::open(&db.0).unwrap();
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA wal_autocheckpoint = 0;",
+        )
+        .unwrap();
+        for n in 2000..2500 {
+            conn.execute("INSERT INTO t VALUES (?1, ?2)", (n, format!("row {n}")))
+                .unwrap();
+        }
+        // copies taken while the commits are only in the log, as uploaded
+        let snapshot = |wal: bool| {
+            let copy = Snapshot(db.sibling(&format!("-copy-{wal}")));
+            std::fs::copy(&db.0, &copy.0).unwrap();
+            if wal {
+                std::fs::copy(db.sibling("-wal"), copy.sibling("-wal")).unwrap();
+            }
+            copy
+        };
+        let (with_wal, without_wal) = (snapshot(true), snapshot(false));
+        let mut header = [0; 20];
+        std::fs::File::open(&with_wal.0)
+            .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut header))
+            .unwrap();
+        assert!(is_wal_mode(&header));
+        let rows = |copy: &Snapshot| {
+            Connection::open_with_flags(&copy.0, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .unwrap()
+                .query_row("SELECT count(*) FROM t", [], |row| row.get::<_, u64>(0))
+                .unwrap()
+        };
+
+        // the database object alone misses every commit since switching to WAL mode
+        replay_wal(&without_wal.0).unwrap();
+        assert_eq!(rows(&without_wal), 2000);
+
+        replay_wal(&with_wal.0).unwrap();
+        assert_eq!(rows(&with_wal), 2500);
+        let findings = check_local(&with_wal.0, &IntegrityOptions::default()).unwrap();
+        assert_eq!(findings, vec!["ok"]);
+        assert!(!with_wal.sibling("-wal").exists());
+    }
+}
@@ -0,0 +1,575 @@
+//! Rollback journals and super-journals.
+//!
+//! SQLite commits a transaction spanning several attached databases through a super-journal:
+//!
+//! 1. It writes the names of the journals of all databases into a new super-journal.
+//! 2. It appends the name of the super-journal to each journal and writes each database.
+//! 3. It deletes the super-journal. This is the commit point.
+//! 4. It deletes the journals.
+//!
+//! After a crash, a hot journal naming a super-journal that still exists is rolled back, while
+//! one naming a super-journal that is gone belongs to a committed transaction and is discarded.
+//! [ThreeQLite](crate::vfs::ThreeQLite) thus answers `exists` for journals with a HEAD and only
+//! acknowledges a delete once the object is gone.
+//!
+//! A journal is stored as a single object next to its database. It is read on open, kept in
+//! memory, charged to the [memory budget](crate::memory) of the instance, and uploaded on sync. On top of the journals of the databases, a cross-database commit
+//! costs a PUT and a DELETE of the super-journal and a HEAD of it per journal deleted. The cost is
+//! logged with the commit point under the `threeqlite::s3` target.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    circuit::OpClass,
+    credentials,
+    error::Error,
+    flush::{CommitLog, CommitStep},
+    key::{KeyLayout, ObjectKey},
+    memory::{Charge, Component},
+    priority::IoClass,
+    spend::Dimension,
+    vfs::{status, Inner},
+};
+
+/// The magic SQLite starts a journal header and ends a super-journal pointer with.
+pub const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalKind {
+    /// The rollback journal of a database, `<db>-journal`.
+    Main,
+    /// The super-journal of a cross-database transaction, `<db>-mj<id>`.
+    Super,
+}
+
+impl JournalKind {
+    /// The kind of journal SQLite names `name`, if it names a journal.
+    pub fn of(name: &str) -> Option<Self> {
+        if name.ends_with("-journal") {
+            return Some(Self::Main);
+        }
+        let (_, id) = name.rsplit_once("-mj")?;
+        (id.len() == 9 && id.bytes().all(|b| b.is_ascii_hexdigit())).then_some(Self::Super)
+    }
+
+    /// The key of the journal SQLite names `name`.
+    pub fn key(self, name: &str) -> Result<ObjectKey, Error> {
+        match self {
+            Self::Main => Ok(KeyLayout::journal(&KeyLayout::db(
+                name.strip_suffix("-journal").unwrap_or(name),
+            )?)),
+            Self::Super => {
+                let (db, id) = name.rsplit_once("-mj").unwrap_or((name, ""));
+                Ok(KeyLayout::super_journal(&KeyLayout::db(db)?, id))
+            }
+        }
+    }
+}
+
+/// The super-journal named at the end of `journal`, following `readSuperJournal` of SQLite: the
+/// name, its length, the checksum of the name and [JOURNAL_MAGIC]. A damaged pointer counts as
+/// no pointer.
+pub fn super_journal_of(journal: &[u8]) -> Option<&str> {
+    let trailer = journal.len().checked_sub(16)?;
+    let u32_at = |at: usize| u32::from_be_bytes(journal[at..at + 4].try_into().unwrap());
+    let len = u32_at(trailer) as usize;
+    if len == 0 || len > trailer || journal[trailer + 8..] != JOURNAL_MAGIC {
+        return None;
+    }
+    let name = &journal[trailer - len..trailer];
+    if checksum(name) != u32_at(trailer + 4) {
+        return None;
+    }
+    // SQLite stops at the first NUL
+    let name = name.split(|b| *b == 0).next()?;
+    std::str::from_utf8(name)
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+/// The super-journal pointer SQLite appends to a journal, see `writeSuperJournal`.
+pub fn super_journal_pointer(name: &str) -> Vec<u8> {
+    let mut record = 1u32.to_be_bytes().to_vec();
+    record.extend_from_slice(name.as_bytes());
+    record.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    record.extend_from_slice(&checksum(name.as_bytes()).to_be_bytes());
+    record.extend_from_slice(&JOURNAL_MAGIC);
+    record
+}
+
+/// The checksum of a super-journal name, summing its bytes as C `char`s.
+fn checksum(name: &[u8]) -> u32 {
+    name.iter()
+        .fold(0u32, |sum, b| sum.wrapping_add(*b as i8 as u32))
+}
+
+/// The journals listed in `super_journal`, each terminated by a NUL.
+pub fn children(super_journal: &[u8]) -> Vec<&str> {
+    super_journal
+        .split(|b| *b == 0)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| std::str::from_utf8(name).ok())
+        .collect()
+}
+
+/// What is known about the super-journals of an instance, to verify journal deletions and log
+/// the cost of cross-database commits.
+#[derive(Debug, Default)]
+pub struct SuperJournals {
+    state: Mutex<SuperState>,
+}
+
+#[derive(Debug, Default)]
+struct SuperState {
+    /// The super-journal named by each journal seen.
+    pointers: HashMap<ObjectKey, ObjectKey>,
+    /// When each super-journal was written, and how many journals it lists.
+    written: HashMap<ObjectKey, (Instant, usize)>,
+}
+
+impl SuperJournals {
+    fn seen(&self, journal: &Journal) {
+        let mut state = self.state.lock().unwrap();
+        match journal.kind {
+            JournalKind::Main => {
+                let pointer = super_journal_of(&journal.data)
+                    .and_then(|name| JournalKind::Super.key(name).ok());
+                match pointer {
+                    Some(pointer) => state.pointers.insert(journal.key.clone(), pointer),
+                    None => state.pointers.remove(&journal.key),
+                };
+            }
+            JournalKind::Super => {
+                let children = children(&journal.data).len();
+                state
+                    .written
+                    .insert(journal.key.clone(), (Instant::now(), children));
+            }
+        }
+    }
+}
+
+/// A journal opened by SQLite, see the [module documentation](self).
+#[derive(Debug)]
+pub struct Journal {
+    pub key: ObjectKey,
+    pub kind: JournalKind,
+    data: Vec<u8>,
+    /// What `data` is charged to the budget.
+    charge: Charge,
+    /// Written since the last upload.
+    dirty: bool,
+    /// Where the writes and uploads of a main journal are recorded, see [crate::flush].
+    log: Arc<CommitLog>,
+}
+
+impl Journal {
+    /// Open the journal at `key`. Unless `create` is set, the journal is read from the object
+    /// store, e.g. to roll back a hot journal.
+    pub async fn open(
+        inner: &Inner,
+        key: ObjectKey,
+        kind: JournalKind,
+        create: bool,
+    ) -> Result<Self, Error> {
+        let mut journal = Self {
+            key,
+            kind,
+            data: Vec::new(),
+            charge: inner.memory.charge(Component::Journal, 0)?,
+            dirty: false,
+            log: inner.commit_log.clone(),
+        };
+        if create {
+            return Ok(journal);
+        }
+        inner.guard_for(&journal.key, OpClass::Read)?;
+        inner.check_budget(OpClass::Read)?;
+        let _permit = inner.permit_for(&journal.key, IoClass::Critical).await;
+        let obj = credentials::send(inner.credentials.as_deref(), || {
+            inner
+                .s3
+                .get_object()
+                .bucket(&inner.bucket)
+                .key(&journal.key)
+                .send()
+        })
+        .await;
+        inner.record_for(&journal.key, OpClass::Read, obj.is_ok());
+        let obj = match obj {
+            Ok(obj) => obj,
+            Err(err) if status(&err) == Some(404) => return Ok(journal),
+            Err(err) => return Err(err.into()),
+        };
+        let bytes = obj.body.collect().await.map_err(|err| Error::Whatever {
+            message: format!("failed to read object body: {err}"),
+            source: None,
+        })?;
+        let bytes = bytes.into_bytes();
+        inner.charge(Dimension::GetBytes, bytes.len() as u64);
+        journal.charge.resize(bytes.len() as u64)?;
+        journal.data = bytes.to_vec();
+        inner.super_journals.seen(&journal);
+        Ok(journal)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Written since it was last uploaded.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Fill `buf` from `offset`, zeroing what lies past the end. Returns whether `buf` was filled
+    /// completely.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> bool {
+        let start = (offset as usize).min(self.data.len());
+        let available = &self.data[start..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        buf[n..].fill(0);
+        n == buf.len()
+    }
+
+    /// Fails if growing the journal would exceed the memory cap.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        let end = offset as usize + buf.len();
+        if self.data.len() < end {
+            self.charge.resize(end as u64)?;
+            self.data.resize(end, 0);
+        }
+        self.data[offset as usize..end].copy_from_slice(buf);
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Fails if growing the journal would exceed the memory cap.
+    pub fn set_len(&mut self, size: u64) -> Result<(), Error> {
+        self.charge.resize(size)?;
+        self.data.resize(size as usize, 0);
+        self.mark_dirty();
+        Ok(())
+    }
+
+    fn mark_dirty(&mut self) {
+        if !self.dirty && self.kind == JournalKind::Main {
+            self.log.record(&self.key, CommitStep::JournalWritten);
+        }
+        self.dirty = true;
+    }
+
+    /// Upload the journal if it was written since the last upload.
+    pub async fn sync(&mut self, inner: &Inner) -> Result<(), Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        inner.guard_for(&self.key, OpClass::Write)?;
+        inner.check_budget(OpClass::Write)?;
+        let res = credentials::send(inner.credentials.as_deref(), || {
+            inner
+                .s3
+                .put_object()
+                .bucket(&inner.bucket)
+                .key(&self.key)
+                .body(self.data.clone().into())
+                .send()
+        })
+        .await;
+        inner.record_for(&self.key, OpClass::Write, res.is_ok());
+        res?;
+        inner.charge(Dimension::PutBytes, self.data.len() as u64);
+        self.dirty = false;
+        if self.kind == JournalKind::Main {
+            self.log.record(&self.key, CommitStep::JournalSynced);
+        }
+        inner.super_journals.seen(self);
+        Ok(())
+    }
+}
+
+/// Whether the journal at `key` exists.
+pub async fn exists(inner: &Inner, key: &ObjectKey) -> Result<bool, Error> {
+    inner.guard_for(key, OpClass::Read)?;
+    let head = inner
+        .s3
+        .head_object()
+        .bucket(&inner.bucket)
+        .key(key)
+        .send()
+        .await;
+    match head {
+        Ok(_) => {
+            inner.record_for(key, OpClass::Read, true);
+            Ok(true)
+        }
+        Err(err) if status(&err) == Some(404) => {
+            inner.record_for(key, OpClass::Read, true);
+            Ok(false)
+        }
+        Err(err) => {
+            inner.record_for(key, OpClass::Read, false);
+            Err(err.into())
+        }
+    }
+}
+
+/// Delete the journal at `key`. Deleting a super-journal commits the transaction it belongs to,
+/// so an error is returned unless the object is gone.
+pub async fn delete(inner: &Inner, key: &ObjectKey, kind: JournalKind) -> Result<(), Error> {
+    let start = Instant::now();
+    match kind {
+        JournalKind::Main => {
+            let pointer = inner
+                .super_journals
+                .state
+                .lock()
+                .unwrap()
+                .pointers
+                .remove(key);
+            if let Some(pointer) = pointer {
+                // SQLite only rolls back a journal whose super-journal exists, and only deletes
+                // the journals of a committed transaction after its super-journal
+                let outcome = match exists(inner, &pointer).await? {
+                    true => "rolled back",
+                    false => "committed",
+                };
+                tracing::debug!(
+                    target: "threeqlite::s3",
+                    journal = %key,
+                    super_journal = %pointer,
+                    outcome,
+                    "deleting journal of a cross-database transaction"
+                );
+            }
+        }
+        JournalKind::Super => {}
+    }
+
+    inner.guard_for(key, OpClass::Write)?;
+    let res = inner
+        .s3
+        .delete_object()
+        .bucket(&inner.bucket)
+        .key(key)
+        .send()
+        .await;
+    inner.record_for(key, OpClass::Write, res.is_ok());
+    res?;
+    if kind == JournalKind::Main {
+        inner.commit_log.record(key, CommitStep::JournalDeleted);
+    }
+
+    if kind == JournalKind::Super {
+        let written = inner
+            .super_journals
+            .state
+            .lock()
+            .unwrap()
+            .written
+            .remove(key);
+        let (since_written, journals) = written
+            .map(|(at, journals)| (at.elapsed(), journals))
+            .unwrap_or((Duration::ZERO, 0));
+        tracing::debug!(
+            target: "threeqlite::s3",
+            super_journal = %key,
+            journals,
+            // the PUT and DELETE of the super-journal, and a HEAD of it per journal deleted
+            extra_round_trips = 2 + journals,
+            ?since_written,
+            delete = ?start.elapsed(),
+            "cross-database commit point"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlite_vfs::{DatabaseHandle, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+    use super::*;
+    use crate::{config::Config, mock::MockS3, vfs::ThreeQLite};
+
+    #[test]
+    fn test_kind_and_key() {
+        assert_eq!(JournalKind::of("a/main.db"), None);
+        assert_eq!(JournalKind::of("a/main.db-wal"), None);
+        assert_eq!(
+            JournalKind::of("a/main.db-journal"),
+            Some(JournalKind::Main)
+        );
+        assert_eq!(
+            JournalKind::of("a/main.db-mj0A1B2C9D3"),
+            Some(JournalKind::Super)
+        );
+        assert_eq!(JournalKind::of("a/main.db-mj0A1B2C"), None);
+        assert_eq!(
+            JournalKind::Main.key("a/main.db-journal").unwrap().as_str(),
+            "a/main.db-journal"
+        );
+        assert_eq!(
+            JournalKind::Super
+                .key("a/main.db-mj0A1B2C9D3")
+                .unwrap()
+                .as_str(),
+            "a/main.db-mj0A1B2C9D3"
+        );
+        assert_eq!(
+            JournalKind::Main
+                .key("a/my db#2.db-journal")
+                .unwrap()
+                .as_str(),
+            "a/my%20db%232.db-journal"
+        );
+        assert!(JournalKind::Main.key("a//main.db-journal").is_err());
+    }
+
+    #[test]
+    fn test_super_journal_pointer() {
+        let mut journal = vec![7; 512];
+        assert_eq!(super_journal_of(&journal), None);
+        assert_eq!(super_journal_of(&[]), None);
+        journal.extend(super_journal_pointer("main.db-mj0A1B2C9D3"));
+        assert_eq!(super_journal_of(&journal), Some("main.db-mj0A1B2C9D3"));
+
+        // a damaged checksum or magic is no pointer
+        let mut damaged = journal.clone();
+        let at = damaged.len() - 20;
+        damaged[at] ^= 1;
+        assert_eq!(super_journal_of(&damaged), None);
+        let mut damaged = journal.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert_eq!(super_journal_of(&damaged), None);
+        // nor is a length past the start of the journal
+        assert_eq!(super_journal_of(&journal[512 + 5..]), None);
+
+        assert_eq!(
+            children(b"a.db-journal\0b.db-journal\0"),
+            vec!["a.db-journal", "b.db-journal"]
+        );
+    }
+
+    const DBS: [&str; 3] = ["a.db", "b.db", "c.db"];
+    const SUPER: &str = "a.db-mj0A1B2C9D3";
+
+    /// Commit a transaction across [DBS] the way SQLite does, stopping after the databases in
+    /// `crash_after` were written.
+    async fn commit(tq: &ThreeQLite, mock: &MockS3, crash_after: Option<usize>) {
+        let names: Vec<_> = DBS.iter().map(|db| format!("{db}-journal")).collect();
+        let mut sj = tq
+            .open(
+                SUPER,
+                OpenOptions::new(OpenKind::SuperJournal, OpenAccess::CreateNew),
+            )
+            .await
+            .unwrap();
+        sj.write_all_at(format!("{}\0", names.join("\0")).as_bytes(), 0)
+            .await
+            .unwrap();
+        sj.sync(false).await.unwrap();
+        drop(sj);
+
+        for (i, (db, name)) in DBS.iter().zip(&names).enumerate() {
+            if crash_after == Some(i) {
+                return;
+            }
+            // the journal holds the original content, followed by the super-journal pointer
+            let mut journal = tq
+                .open(
+                    name,
+                    OpenOptions::new(OpenKind::MainJournal, OpenAccess::Create),
+                )
+                .await
+                .unwrap();
+            let mut content = mock.get(db).unwrap();
+            content.extend(super_journal_pointer(SUPER));
+            journal.write_all_at(&content, 0).await.unwrap();
+            journal.sync(false).await.unwrap();
+            mock.put(db, format!("{db} after"));
+        }
+
+        tq.delete(SUPER).await.unwrap();
+        for name in &names {
+            tq.delete(name).await.unwrap();
+        }
+    }
+
+    /// Recover [DBS] after a crash the way SQLite does on the next open.
+    async fn recover(tq: &ThreeQLite, mock: &MockS3) {
+        for db in DBS {
+            let name = format!("{db}-journal");
+            if !tq.exists(&name).await.unwrap() {
+                continue;
+            }
+            let mut journal = tq
+                .open(
+                    &name,
+                    OpenOptions::new(OpenKind::MainJournal, OpenAccess::Write),
+                )
+                .await
+                .unwrap();
+            let mut content = vec![0; journal.size().await.unwrap() as usize];
+            journal.read_exact_at(&mut content, 0).await.unwrap();
+            let super_journal = super_journal_of(&content).unwrap();
+            if tq.exists(super_journal).await.unwrap() {
+                content.truncate(content.len() - super_journal_pointer(super_journal).len());
+                mock.put(db, content);
+            }
+            tq.delete(&name).await.unwrap();
+        }
+        // no journal refers to the super-journal anymore
+        if tq.exists(SUPER).await.unwrap() {
+            tq.delete(SUPER).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cross_database_commit_is_atomic() {
+        let mock = MockS3::start();
+        for db in DBS {
+            mock.put(db, format!("{db} before"));
+        }
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+
+        // crash after two of the three databases were written
+        commit(&tq, &mock, Some(2)).await;
+        assert_eq!(mock.get("a.db").unwrap(), b"a.db after");
+        assert!(tq.exists(SUPER).await.unwrap());
+        recover(&tq, &mock).await;
+        for db in DBS {
+            assert_eq!(mock.get(db).unwrap(), format!("{db} before").as_bytes());
+            assert!(!tq.exists(&format!("{db}-journal")).await.unwrap());
+        }
+        assert_eq!(mock.get(SUPER), None);
+
+        commit(&tq, &mock, None).await;
+        recover(&tq, &mock).await;
+        for db in DBS {
+            assert_eq!(mock.get(db).unwrap(), format!("{db} after").as_bytes());
+            assert_eq!(mock.get(&format!("{db}-journal")), None);
+        }
+        assert_eq!(mock.get(SUPER), None);
+    }
+
+    #[tokio::test]
+    async fn test_committed_journal_is_discarded() {
+        let mock = MockS3::start();
+        mock.put("a.db", "a.db after");
+        // the super-journal is gone, so this hot journal belongs to a committed transaction
+        let mut journal = b"a.db before".to_vec();
+        journal.extend(super_journal_pointer(SUPER));
+        mock.put("a.db-journal", journal);
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+
+        recover(&tq, &mock).await;
+        assert_eq!(mock.get("a.db").unwrap(), b"a.db after");
+        assert_eq!(mock.get("a.db-journal"), None);
+    }
+}
@@ -0,0 +1,511 @@
+//! Object keys and how they are derived.
+//!
+//! Every key this crate sends to the object store is an [ObjectKey], which can only be created by
+//! validation or by one of the derivations of [KeyLayout]. A missing or doubled slash thus fails
+//! loudly instead of silently writing to a parallel namespace.
+//!
+//! Database names come from applications (tenant IDs, paths with spaces, unicode) and are
+//! encoded by [KeyLayout::db] rather than used verbatim: slashes separate segments as before, and
+//! each byte outside the characters S3 documents as safe (`0-9a-zA-Z!-_.*'()`) is written as
+//! `%XX`, as is every dot of a segment consisting of dots only. `%` itself is encoded, so distinct
+//! names never share a key and [KeyLayout::decode_db_name] recovers the name. The encoding is part of the on-bucket
+//! format: changing it moves existing databases out of reach. Encoded database keys are limited
+//! to [MAX_DB_KEY_LEN] bytes, so that even the longest key derived from them, that of the last
+//! chunk, stays within [MAX_KEY_LEN]; a longer name fails when it is opened.
+
+use std::{borrow::Borrow, fmt::Write};
+
+use crate::{config::Config, error::Error, probe, role::Role};
+
+/// The longest key S3 accepts, in bytes.
+pub const MAX_KEY_LEN: usize = 1024;
+
+/// The longest suffix of a key derived from a database key, see [KeyLayout::chunk].
+const LONGEST_SUFFIX: &str = ".chunks/18446744073709551615";
+
+/// The longest database key, leaving room for the suffixes of derived keys.
+pub const MAX_DB_KEY_LEN: usize = MAX_KEY_LEN - LONGEST_SUFFIX.len();
+
+/// A validated object key: non-empty, at most [MAX_KEY_LEN] bytes of UTF-8, without a leading
+/// slash or empty segments.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectKey(String);
+
+impl ObjectKey {
+    pub fn new(key: impl Into<String>) -> Result<Self, Error> {
+        let key = key.into();
+        match validate(&key, MAX_KEY_LEN) {
+            Ok(()) => Ok(Self(key)),
+            Err(reason) => Err(Error::InvalidKey { key, reason }),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Build a key from parts that are valid by construction.
+    fn derived(key: String) -> Self {
+        debug_assert_eq!(validate(&key, MAX_KEY_LEN), Ok(()), "{key}");
+        Self(key)
+    }
+}
+
+fn validate(key: &str, max_len: usize) -> Result<(), &'static str> {
+    if key.is_empty() {
+        Err("key is empty")
+    } else if key.len() > max_len {
+        Err("key is too long")
+    } else if key.starts_with('/') {
+        Err("key starts with a slash")
+    } else if key.split('/').any(str::is_empty) {
+        Err("key has an empty segment")
+    } else {
+        Ok(())
+    }
+}
+
+/// The characters S3 documents as safe in keys, other than the `/` delimiter.
+fn is_safe(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!-_.*'()".contains(&b)
+}
+
+/// Append the encoding of the database name `segment` to `key`, see the
+/// [module documentation](self).
+fn encode_segment(segment: &str, key: &mut String) {
+    // `.` and `..` would be resolved by tools treating keys as paths
+    let dots = segment.bytes().all(|b| b == b'.');
+    for b in segment.bytes() {
+        match is_safe(b) && !dots {
+            true => key.push(b as char),
+            false => write!(key, "%{b:02X}").unwrap(),
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for ObjectKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for ObjectKey {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&ObjectKey> for String {
+    fn from(key: &ObjectKey) -> Self {
+        key.0.clone()
+    }
+}
+
+impl From<ObjectKey> for String {
+    fn from(key: ObjectKey) -> Self {
+        key.0
+    }
+}
+
+/// Where the objects of a database are stored.
+///
+/// The lock and metadata objects are named by the [Config] rather than derived from a database,
+/// since an instance serves a single database.
+pub struct KeyLayout;
+
+impl KeyLayout {
+    /// The key of the database object named `db` by SQLite, encoded as described in the
+    /// [module documentation](self).
+    pub fn db(db: &str) -> Result<ObjectKey, Error> {
+        if let Err(reason) = validate(db, usize::MAX) {
+            return Err(Error::InvalidKey {
+                key: db.to_owned(),
+                reason,
+            });
+        }
+        let mut key = String::with_capacity(db.len());
+        for (i, segment) in db.split('/').enumerate() {
+            if i > 0 {
+                key.push('/');
+            }
+            encode_segment(segment, &mut key);
+        }
+        if key.len() > MAX_DB_KEY_LEN {
+            return Err(Error::NameTooLong {
+                name: db.to_owned(),
+                max: MAX_DB_KEY_LEN,
+            });
+        }
+        Ok(ObjectKey::derived(key))
+    }
+
+    /// The database name `key` was encoded from by [KeyLayout::db], `None` if it wasn't.
+    pub fn decode_db_name(key: &str) -> Option<String> {
+        let mut bytes = Vec::with_capacity(key.len());
+        let mut rest = key.as_bytes();
+        while let Some((&b, tail)) = rest.split_first() {
+            rest = tail;
+            if b != b'%' {
+                bytes.push(b);
+                continue;
+            }
+            let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &rest[2..];
+        }
+        let name = String::from_utf8(bytes).ok()?;
+        // each name has a single encoding, anything else wasn't written by this crate
+        let encoded = Self::db(&name).ok()?;
+        (encoded.as_str() == key).then_some(name)
+    }
+
+    pub fn lock(config: &Config) -> Result<ObjectKey, Error> {
+        ObjectKey::new(config.lock_file.as_str())
+    }
+
+    pub fn metadata(config: &Config) -> Result<ObjectKey, Error> {
+        ObjectKey::new(config.metadata_filename.as_str())
+    }
+
+    /// The block checksums of `db`, see [crate::mirror].
+    pub fn manifest(db: &ObjectKey) -> ObjectKey {
+        ObjectKey::derived(format!("{db}.blocks"))
+    }
+
+    /// The checksums of blocks `first..end` of `db`, once its manifest is split into extents, see
+    /// [crate::extent].
+    pub fn manifest_extent(db: &ObjectKey, first: u64, end: u64) -> ObjectKey {
+        ObjectKey::derived(format!("{db}.blocks/extents/{first:010}-{end:010}"))
+    }
+
+    /// The pages transactions on `db` start with, see [crate::prefetch].
+    pub fn hot_set(db: &ObjectKey) -> ObjectKey {
+        ObjectKey::derived(format!("{db}.hot"))
+    }
+
+    /// The pages an instance cached of `db`, see [crate::warm].
+    pub fn warm_set(db: &ObjectKey) -> ObjectKey {
+        ObjectKey::derived(format!("{db}.warm"))
+    }
+
+    /// The progress of a copy or rename to or from `db`, see [crate::copy].
+    pub fn copy_progress(db: &ObjectKey) -> ObjectKey {
+        ObjectKey::derived(format!("{db}.copy"))
+    }
+
+    /// The lease of `role` of `db`, see [crate::role].
+    pub fn role_lease(db: &ObjectKey, role: Role) -> ObjectKey {
+        ObjectKey::derived(format!("{db}.roles/{role}"))
+    }
+
+    /// Chunk `idx` of `db`. Zero-padded, so that listing returns chunks in order.
+    pub fn chunk(db: &ObjectKey, idx: u64) -> ObjectKey {
+        ObjectKey::derived(format!("{db}.chunks/{idx:010}"))
+    }
+
+    /// WAL segment `n` of `db`. Zero-padded, so that listing returns segments in order.
+    pub fn wal_segment(db: &ObjectKey, n: u64) -> ObjectKey {
+        ObjectKey::derived(format!("{db}.wal/{n:010}"))
+    }
+
+    /// The rollback journal of `db`, as named by SQLite.
+    pub fn journal(db: &ObjectKey) -> ObjectKey {
+        ObjectKey::derived(format!("{db}-journal"))
+    }
+
+    /// A super-journal of a transaction across `db` and other databases, as named by SQLite with
+    /// the random `id`, see [crate::journal].
+    pub fn super_journal(db: &ObjectKey, id: &str) -> ObjectKey {
+        ObjectKey::derived(format!("{db}-mj{id}"))
+    }
+
+    /// The write-ahead log of `db`, as named by SQLite.
+    pub fn wal(db: &ObjectKey) -> ObjectKey {
+        ObjectKey::derived(format!("{db}-wal"))
+    }
+
+    /// The key of the database `key` belongs to, for the keys derived above from a database, or
+    /// `key` itself, e.g. for the database, lock and temporary files.
+    pub fn database_of(key: &str) -> &str {
+        let db = ["-journal", "-wal", ".blocks", ".hot", ".warm", ".copy"]
+            .iter()
+            .find_map(|suffix| key.strip_suffix(suffix))
+            .or_else(|| {
+                [".blocks/extents/", ".roles/", ".chunks/", ".wal/"]
+                    .iter()
+                    .filter_map(|sep| key.rfind(sep))
+                    .min()
+                    .map(|end| &key[..end])
+            })
+            .or_else(|| {
+                let end = key.rfind("-mj")?;
+                (!key[end..].contains('/')).then(|| &key[..end])
+            });
+        db.unwrap_or(key)
+    }
+
+    /// A temporary file named by SQLite.
+    pub fn temp(uuid: &uuid::Uuid) -> ObjectKey {
+        ObjectKey::derived(uuid.to_string())
+    }
+
+    /// The write-permission probe next to `db`, see [crate::probe].
+    pub fn probe(db: &ObjectKey) -> ObjectKey {
+        match probe::prefix(db.as_str()) {
+            "" => ObjectKey::derived(probe::PROBE_OBJECT.to_owned()),
+            prefix => ObjectKey::derived(format!("{prefix}/{}", probe::PROBE_OBJECT)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{seq::SliceRandom, Rng};
+
+    use super::*;
+
+    #[test]
+    fn test_validation() {
+        for key in ["test.db", "tenants/a/main.db", "a", "ü/ß.db", "a/.b"] {
+            assert_eq!(ObjectKey::new(key).unwrap().as_str(), key);
+        }
+        for (key, reason) in [
+            ("", "key is empty"),
+            ("/lockfile", "key starts with a slash"),
+            ("//lockfile", "key starts with a slash"),
+            ("tenants//main.db", "key has an empty segment"),
+            ("tenants/", "key has an empty segment"),
+        ] {
+            assert!(
+                matches!(ObjectKey::new(key), Err(Error::InvalidKey { reason: r, .. }) if r == reason),
+                "{key}"
+            );
+        }
+        assert!(ObjectKey::new("a".repeat(MAX_KEY_LEN)).is_ok());
+        assert!(ObjectKey::new("a".repeat(MAX_KEY_LEN + 1)).is_err());
+        assert!(KeyLayout::db("tenants//main.db").is_err());
+    }
+
+    #[test]
+    fn test_length_boundary() {
+        let longest = KeyLayout::db(&"a".repeat(MAX_DB_KEY_LEN)).unwrap();
+        assert_eq!(
+            KeyLayout::chunk(&longest, u64::MAX).as_str().len(),
+            MAX_KEY_LEN
+        );
+        for name in [
+            "a".repeat(MAX_DB_KEY_LEN + 1),
+            " ".repeat(MAX_DB_KEY_LEN / 3 + 1),
+        ] {
+            let err = KeyLayout::db(&name).unwrap_err();
+            assert!(
+                matches!(err, Error::NameTooLong { max, .. } if max == MAX_DB_KEY_LEN),
+                "{err}"
+            );
+        }
+        // the limit applies to the encoded name
+        assert!(KeyLayout::db(&" ".repeat(MAX_DB_KEY_LEN / 3)).is_ok());
+    }
+
+    /// Changing any of these moves databases with such names out of reach.
+    #[test]
+    fn test_golden_encoding() {
+        for (name, key) in [
+            ("tenants/a/main.db", "tenants/a/main.db"),
+            ("Tenant_1/(draft)!*'.db", "Tenant_1/(draft)!*'.db"),
+            ("my db.sqlite", "my%20db.sqlite"),
+            ("a#1?x=2&y.db", "a%231%3Fx%3D2%26y.db"),
+            ("100%.db", "100%25.db"),
+            (
+                "tenants/acme corp/eu/main.db",
+                "tenants/acme%20corp/eu/main.db",
+            ),
+            ("ü/ß.db", "%C3%BC/%C3%9F.db"),
+            ("租户/数据.db", "%E7%A7%9F%E6%88%B7/%E6%95%B0%E6%8D%AE.db"),
+            ("../x.db", "%2E%2E/x.db"),
+            ("a/./..b", "a/%2E/..b"),
+            ("back\\slash~+.db", "back%5Cslash%7E%2B.db"),
+        ] {
+            let db = KeyLayout::db(name).unwrap();
+            assert_eq!(db.as_str(), key, "{name}");
+            assert_eq!(KeyLayout::decode_db_name(key).as_deref(), Some(name));
+        }
+        for key in [
+            "my db.sqlite",
+            "100%.db",
+            "100%2.db",
+            "100%zz.db",
+            "%41.db",
+            "%c3%bc.db",
+            "%+5.db",
+            "%FF.db",
+            "a//b.db",
+        ] {
+            assert_eq!(KeyLayout::decode_db_name(key), None, "{key}");
+        }
+    }
+
+    /// Distinct names never share a key, and every key decodes to its name.
+    #[test]
+    fn test_encoding_is_injective() {
+        let alphabet = [
+            'a', 'A', '0', '.', '%', ' ', '#', '?', '/', '2', '5', 'é', '日', '~',
+        ];
+        let mut rng = rand::thread_rng();
+        let mut keys = std::collections::HashMap::new();
+        for _ in 0..20_000 {
+            let len = rng.gen_range(1..8);
+            let name: String = (0..len)
+                .map(|_| *alphabet.choose(&mut rng).unwrap())
+                .collect();
+            let Ok(db) = KeyLayout::db(&name) else {
+                assert!(validate(&name, usize::MAX).is_err(), "{name}");
+                continue;
+            };
+            assert_eq!(ObjectKey::new(db.as_str()).unwrap(), db);
+            assert_eq!(KeyLayout::decode_db_name(db.as_str()).as_ref(), Some(&name));
+            if let Some(other) = keys.insert(db.clone(), name.clone()) {
+                assert_eq!(other, name, "{db}");
+            }
+        }
+    }
+
+    /// Changing any of these moves existing objects out of reach.
+    #[test]
+    fn test_golden_layout() {
+        let db = KeyLayout::db("tenants/a/main.db").unwrap();
+        let top = KeyLayout::db("test.db").unwrap();
+        assert_eq!(db.as_str(), "tenants/a/main.db");
+        assert_eq!(
+            KeyLayout::manifest(&db).as_str(),
+            "tenants/a/main.db.blocks"
+        );
+        assert_eq!(
+            KeyLayout::manifest_extent(&db, 1024, 2048).as_str(),
+            "tenants/a/main.db.blocks/extents/0000001024-0000002048"
+        );
+        assert_eq!(
+            KeyLayout::chunk(&db, 1).as_str(),
+            "tenants/a/main.db.chunks/0000000001"
+        );
+        assert_eq!(
+            KeyLayout::chunk(&db, u64::MAX).as_str(),
+            "tenants/a/main.db.chunks/18446744073709551615"
+        );
+        assert_eq!(
+            KeyLayout::wal_segment(&db, 42).as_str(),
+            "tenants/a/main.db.wal/0000000042"
+        );
+        assert_eq!(
+            KeyLayout::journal(&db).as_str(),
+            "tenants/a/main.db-journal"
+        );
+        assert_eq!(
+            KeyLayout::super_journal(&db, "0A1B2C9D3").as_str(),
+            "tenants/a/main.db-mj0A1B2C9D3"
+        );
+        assert_eq!(KeyLayout::wal(&db).as_str(), "tenants/a/main.db-wal");
+        assert_eq!(
+            KeyLayout::role_lease(&db, Role::Reconcile).as_str(),
+            "tenants/a/main.db.roles/reconcile"
+        );
+        assert_eq!(
+            KeyLayout::temp(&uuid::Uuid::from_u128(0x0123456789abcdef0123456789abcdef)).as_str(),
+            "01234567-89ab-cdef-0123-456789abcdef"
+        );
+        assert_eq!(KeyLayout::probe(&db).as_str(), "tenants/a/.write-probe");
+        assert_eq!(KeyLayout::probe(&top).as_str(), ".write-probe");
+
+        let config = Config::default();
+        assert_eq!(KeyLayout::lock(&config).unwrap().as_str(), "lockfile");
+        assert_eq!(KeyLayout::metadata(&config).unwrap().as_str(), "metadata");
+        let config = Config {
+            lock_file: "/lockfile".to_owned(),
+            ..Config::default()
+        };
+        assert!(KeyLayout::lock(&config).is_err());
+    }
+
+    #[test]
+    fn test_database_of() {
+        let db = KeyLayout::db("tenants/a/main.db").unwrap();
+        for key in [
+            db.clone(),
+            KeyLayout::manifest(&db),
+            KeyLayout::manifest_extent(&db, 1024, 2048),
+            KeyLayout::hot_set(&db),
+            KeyLayout::warm_set(&db),
+            KeyLayout::copy_progress(&db),
+            KeyLayout::role_lease(&db, Role::Reconcile),
+            KeyLayout::chunk(&db, 7),
+            KeyLayout::wal_segment(&db, 7),
+            KeyLayout::journal(&db),
+            KeyLayout::super_journal(&db, "0A1B2C9D3"),
+            KeyLayout::wal(&db),
+        ] {
+            assert_eq!(KeyLayout::database_of(key.as_str()), db.as_str(), "{key}");
+        }
+        let other = KeyLayout::db("a-mj/x.db").unwrap();
+        assert_eq!(KeyLayout::database_of(other.as_str()), other.as_str());
+        let lock = KeyLayout::lock(&Config::default()).unwrap();
+        assert_eq!(KeyLayout::database_of(lock.as_str()), lock.as_str());
+    }
+
+    #[test]
+    fn test_derived_keys_validate() {
+        let alphabet = ['a', 'Z', '0', '.', '-', '_', '/', 'é', '日'];
+        let mut rng = rand::thread_rng();
+        for _ in 0..2000 {
+            let len = match rng.gen_bool(0.9) {
+                true => rng.gen_range(0..16),
+                false => rng.gen_range(MAX_DB_KEY_LEN - 8..MAX_DB_KEY_LEN + 8),
+            };
+            let name: String = (0..len)
+                .map(|_| *alphabet.choose(&mut rng).unwrap())
+                .collect();
+            let Ok(db) = KeyLayout::db(&name) else {
+                assert!(ObjectKey::new(name.as_str()).is_err() || name.len() > MAX_DB_KEY_LEN / 3);
+                continue;
+            };
+            let n = rng.gen();
+            for key in [
+                db.clone(),
+                KeyLayout::manifest(&db),
+                KeyLayout::manifest_extent(&db, n, n),
+                KeyLayout::chunk(&db, n),
+                KeyLayout::wal_segment(&db, n),
+                KeyLayout::journal(&db),
+                KeyLayout::wal(&db),
+                KeyLayout::role_lease(&db, Role::Reconcile),
+                KeyLayout::probe(&db),
+                KeyLayout::temp(&uuid::Uuid::new_v4()),
+            ] {
+                assert_eq!(ObjectKey::new(key.as_str()).unwrap(), key);
+            }
+        }
+    }
+
+    #[cfg(feature = "s3")]
+    #[tokio::test]
+    async fn test_instance_rejects_invalid_keys() {
+        let mock = crate::mock::MockS3::start();
+        let config = Config {
+            lock_file: "/lockfile".to_owned(),
+            ..Config::default()
+        };
+        let result = crate::vfs::ThreeQLite::with_client(config, mock.client());
+        assert!(matches!(result, Err(Error::InvalidKey { .. })));
+        let config = Config {
+            metadata_filename: String::new(),
+            ..Config::default()
+        };
+        let result = crate::vfs::ThreeQLite::with_client(config, mock.client());
+        assert!(matches!(result, Err(Error::InvalidKey { .. })));
+    }
+}
@@ -0,0 +1,317 @@
+//! Per-transaction latency breakdown.
+//!
+//! Every [crate::handle::Handle] accumulates the time its current transaction spends in each
+//! [Phase]. The storage code marks its phases with [timed]. Phases don't nest: an operation
+//! started while another phase is running is attributed to the outer one, so a read issued while
+//! flushing counts as [Phase::Flush]. Whatever remains of the transaction's wall time is
+//! attributed to [Phase::Sqlite].
+//!
+//! Timing costs two [Instant::now] calls and an uncontended mutex per phase, and nothing at all
+//! outside of a handle.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::schema::SchemaChange;
+#[cfg(feature = "s3")]
+use crate::{receipt::CommitReceipt, spend::BudgetWarning};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Acquiring and releasing locks, including waiting for other holders.
+    LockWait,
+    /// Reading the database from the object store.
+    StorageRead,
+    /// Writing the database to the object store.
+    StorageWrite,
+    /// Serializing, checksumming and compressing objects.
+    Encode,
+    /// Waiting for written data to become durable at the commit barrier.
+    Flush,
+    /// Everything else, i.e. SQLite's own work and the application between calls.
+    Sqlite,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 6] = [
+        Phase::LockWait,
+        Phase::StorageRead,
+        Phase::StorageWrite,
+        Phase::Encode,
+        Phase::Flush,
+        Phase::Sqlite,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::LockWait => "lock_wait",
+            Phase::StorageRead => "storage_read",
+            Phase::StorageWrite => "storage_write",
+            Phase::Encode => "encode",
+            Phase::Flush => "flush",
+            Phase::Sqlite => "sqlite",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Where the time of one transaction went.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransactionBreakdown {
+    /// Wall time from the first call of the transaction to its end.
+    pub total: Duration,
+    phases: [Duration; Phase::ALL.len()],
+    /// Keys of the database objects read or written, sorted.
+    pub keys: Vec<String>,
+    /// The generation the transaction read from the offline mirror, because the metadata object
+    /// was unreadable, see [crate::degraded].
+    pub degraded: Option<u64>,
+}
+
+impl TransactionBreakdown {
+    pub fn get(&self, phase: Phase) -> Duration {
+        self.phases[phase.index()]
+    }
+}
+
+impl std::fmt::Display for TransactionBreakdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "total={:?}", self.total)?;
+        for phase in Phase::ALL {
+            write!(f, " {}={:?}", phase.name(), self.get(phase))?;
+        }
+        write!(f, " keys={}", self.keys.join(","))?;
+        if let Some(generation) = self.degraded {
+            write!(f, " degraded={generation}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Notified about every finished transaction of a [crate::vfs::ThreeQLite] instance.
+pub trait TransactionObserver: Send + Sync {
+    fn on_transaction(&self, db: &str, breakdown: &TransactionBreakdown);
+
+    /// A generation of a database changed its schema, see [crate::schema].
+    fn on_schema_change(&self, _change: &SchemaChange) {}
+
+    /// A write transaction committed, see [crate::receipt].
+    #[cfg(feature = "s3")]
+    fn on_commit(&self, _receipt: &CommitReceipt) {}
+
+    /// A session crossed a soft limit of its budget, see [crate::spend].
+    #[cfg(feature = "s3")]
+    fn on_budget_warning(&self, _warning: &BudgetWarning) {}
+}
+
+/// The running transaction of a handle.
+#[derive(Debug, Default)]
+pub struct Timings {
+    started: Option<Instant>,
+    active: Option<Phase>,
+    phases: [Duration; Phase::ALL.len()],
+    keys: BTreeSet<String>,
+}
+
+impl Timings {
+    /// Mark the start of the transaction, unless it already started.
+    pub fn begin(&mut self) {
+        self.started.get_or_insert_with(Instant::now);
+    }
+
+    pub fn running(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// End the transaction and start over. `None` if it never started.
+    pub fn finish(&mut self) -> Option<TransactionBreakdown> {
+        let timings = std::mem::take(self);
+        let total = timings.started?.elapsed();
+        let mut phases = timings.phases;
+        let measured = phases.iter().sum::<Duration>();
+        phases[Phase::Sqlite.index()] = total.saturating_sub(measured);
+        Some(TransactionBreakdown {
+            total,
+            phases,
+            keys: timings.keys.into_iter().collect(),
+            degraded: None,
+        })
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: Arc<Mutex<Timings>>;
+}
+
+/// Run `fut` with `timings` as the transaction [timed] and [touch] report to.
+pub async fn scope<F: Future>(timings: Arc<Mutex<Timings>>, fut: F) -> F::Output {
+    timings.lock().unwrap().begin();
+    CURRENT.scope(timings, fut).await
+}
+
+/// Run `fut`, attributing its time to `phase` unless another phase is already running.
+pub async fn timed<F: Future>(phase: Phase, fut: F) -> F::Output {
+    let Ok(timings) = CURRENT.try_with(Arc::clone) else {
+        return fut.await;
+    };
+    let nested = {
+        let mut timings = timings.lock().unwrap();
+        let nested = timings.active.is_some();
+        if !nested {
+            timings.active = Some(phase);
+        }
+        nested
+    };
+    if nested {
+        return fut.await;
+    }
+
+    let start = Instant::now();
+    let out = fut.await;
+    let elapsed = start.elapsed();
+
+    let mut timings = timings.lock().unwrap();
+    timings.phases[phase.index()] += elapsed;
+    timings.active = None;
+    out
+}
+
+/// Record that the running transaction accessed the database object `key`.
+pub fn touch(key: &str) {
+    let _ = CURRENT.try_with(|timings| {
+        let mut timings = timings.lock().unwrap();
+        if !timings.keys.contains(key) {
+            timings.keys.insert(key.to_owned());
+        }
+    });
+}
+
+/// Finished transactions of all handles of an instance.
+#[derive(Default)]
+pub struct Transactions {
+    observers: Mutex<Vec<Arc<dyn TransactionObserver>>>,
+    last: Mutex<HashMap<String, TransactionBreakdown>>,
+    #[cfg(feature = "s3")]
+    receipts: Mutex<HashMap<String, CommitReceipt>>,
+}
+
+impl Transactions {
+    pub fn observe(&self, observer: Arc<dyn TransactionObserver>) {
+        self.observers.lock().unwrap().push(observer);
+    }
+
+    pub fn last(&self, db: &str) -> Option<TransactionBreakdown> {
+        self.last.lock().unwrap().get(db).cloned()
+    }
+
+    /// Record the transaction `breakdown` on `db`, reporting it to `observer` instead of the
+    /// observers of the instance if one is given.
+    pub fn finished(
+        &self,
+        db: &str,
+        breakdown: &TransactionBreakdown,
+        observer: Option<Arc<dyn TransactionObserver>>,
+    ) {
+        self.last
+            .lock()
+            .unwrap()
+            .insert(db.to_owned(), breakdown.clone());
+        if let Some(observer) = observer {
+            return observer.on_transaction(db, breakdown);
+        }
+        let observers = self.observers.lock().unwrap().clone();
+        for observer in observers {
+            observer.on_transaction(db, breakdown);
+        }
+    }
+
+    /// Report `change` to the observers of the instance.
+    pub fn schema_changed(&self, change: &SchemaChange) {
+        let observers = self.observers.lock().unwrap().clone();
+        for observer in observers {
+            observer.on_schema_change(change);
+        }
+    }
+
+    /// Report `warning` to the observers of the instance.
+    #[cfg(feature = "s3")]
+    pub fn budget_warning(&self, warning: &BudgetWarning) {
+        let observers = self.observers.lock().unwrap().clone();
+        for observer in observers {
+            observer.on_budget_warning(warning);
+        }
+    }
+
+    /// Record `receipt` as the last commit on its database and report it to the observers of the
+    /// instance.
+    #[cfg(feature = "s3")]
+    pub fn committed(&self, receipt: CommitReceipt) {
+        let observers = self.observers.lock().unwrap().clone();
+        for observer in observers {
+            observer.on_commit(&receipt);
+        }
+        self.receipts
+            .lock()
+            .unwrap()
+            .insert(receipt.db.clone(), receipt);
+    }
+
+    #[cfg(feature = "s3")]
+    pub fn last_receipt(&self, db: &str) -> Option<CommitReceipt> {
+        self.receipts.lock().unwrap().get(db).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sleep(ms: u64) -> tokio::time::Sleep {
+        tokio::time::sleep(Duration::from_millis(ms))
+    }
+
+    fn assert_near(actual: Duration, expected_ms: u64) {
+        let expected = Duration::from_millis(expected_ms);
+        assert!(
+            actual >= expected && actual < expected + Duration::from_millis(40),
+            "{actual:?} is not close to {expected:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nested_phases_count_as_outer() {
+        let timings = Arc::new(Mutex::new(Timings::default()));
+        scope(timings.clone(), async {
+            timed(Phase::StorageRead, sleep(20)).await;
+            timed(Phase::Flush, async {
+                timed(Phase::StorageRead, sleep(30)).await;
+                touch("test.db");
+            })
+            .await;
+            sleep(25).await;
+        })
+        .await;
+
+        let breakdown = timings.lock().unwrap().finish().unwrap();
+        assert_near(breakdown.get(Phase::StorageRead), 20);
+        assert_near(breakdown.get(Phase::Flush), 30);
+        assert_near(breakdown.get(Phase::Sqlite), 25);
+        assert_near(breakdown.total, 75);
+        assert_eq!(breakdown.keys, ["test.db"]);
+        // finishing starts over
+        assert_eq!(timings.lock().unwrap().finish(), None);
+    }
+
+    #[tokio::test]
+    async fn test_untimed_outside_scope() {
+        timed(Phase::StorageWrite, sleep(1)).await;
+        touch("test.db");
+    }
+}
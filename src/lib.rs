@@ -0,0 +1,135 @@
+#![allow(async_fn_in_trait)]
+
+//! SQLite on S3. See README.md for the available features.
+//!
+//! Events are emitted under the targets `threeqlite::s3` (object store requests and circuit
+//! breaker transitions), `threeqlite::lock_protocol` (lock and metadata objects) and
+//! `threeqlite::asyncdb`, following the level policy of [sqlite_vfs].
+
+#[cfg(feature = "asyncdb")]
+pub mod asyncdb;
+#[cfg(feature = "auto-register")]
+pub mod auto;
+#[cfg(all(test, feature = "s3"))]
+mod bench;
+#[cfg(all(feature = "s3", feature = "rusqlite"))]
+pub mod blocking;
+#[cfg(feature = "s3")]
+pub mod burst;
+#[cfg(feature = "s3")]
+pub mod bus;
+#[cfg(feature = "s3")]
+pub mod busy;
+pub mod cache;
+#[cfg(feature = "s3")]
+pub mod capture;
+#[cfg(feature = "s3")]
+pub mod cas;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod circuit;
+#[cfg(feature = "s3")]
+pub mod clock;
+pub mod config;
+#[cfg(feature = "s3")]
+pub mod copy;
+#[cfg(feature = "s3")]
+pub mod cost;
+#[cfg(feature = "s3")]
+pub mod create;
+pub mod credentials;
+#[cfg(feature = "s3")]
+pub mod degraded;
+#[cfg(feature = "s3")]
+pub mod discover;
+#[cfg(feature = "s3")]
+pub mod drill;
+pub mod durability;
+pub mod error;
+#[cfg(feature = "metrics-export")]
+pub mod export;
+#[cfg(feature = "s3")]
+pub mod extent;
+#[cfg(feature = "s3")]
+pub mod fetch;
+#[cfg(feature = "s3")]
+pub mod flush;
+#[cfg(feature = "s3")]
+pub mod format;
+#[cfg(feature = "s3")]
+pub mod guarantees;
+#[cfg(feature = "s3")]
+pub mod handle;
+#[cfg(feature = "s3")]
+pub mod heal;
+#[cfg(feature = "s3")]
+pub mod idempotency;
+#[cfg(feature = "s3")]
+pub mod inflight;
+#[cfg(all(feature = "s3", feature = "rusqlite"))]
+pub mod integrity;
+#[cfg(feature = "s3")]
+pub mod journal;
+#[cfg(feature = "s3")]
+pub mod key;
+pub mod latency;
+#[cfg(feature = "s3")]
+pub mod limits;
+#[cfg(feature = "lite-s3")]
+pub mod lite;
+pub mod memory;
+#[cfg(feature = "s3")]
+pub mod mirror;
+#[cfg(all(test, feature = "s3"))]
+mod mock;
+pub mod msrv;
+#[cfg(feature = "s3")]
+pub mod operation;
+#[cfg(feature = "asyncdb")]
+pub mod pool;
+#[cfg(feature = "s3")]
+pub mod prefetch;
+pub mod priority;
+pub mod probe;
+#[cfg(all(test, feature = "s3"))]
+mod processes;
+#[cfg(feature = "s3")]
+pub mod protocol;
+#[cfg(feature = "s3")]
+pub mod receipt;
+#[cfg(feature = "s3")]
+pub mod reconcile;
+#[cfg(feature = "s3")]
+pub mod recovery;
+#[cfg(feature = "s3")]
+pub mod region;
+#[cfg(feature = "s3")]
+pub mod registration;
+#[cfg(feature = "s3")]
+pub mod replay;
+#[cfg(feature = "s3")]
+pub mod role;
+pub mod schema;
+pub mod sector;
+#[cfg(feature = "s3")]
+pub mod session;
+#[cfg(feature = "s3")]
+pub mod shutdown;
+#[cfg(feature = "s3")]
+pub mod spend;
+pub mod stats;
+#[cfg(feature = "s3")]
+pub mod temp;
+pub mod timeouts;
+#[cfg(feature = "s3")]
+pub mod verify;
+#[cfg(feature = "s3")]
+pub mod vfs;
+#[cfg(feature = "s3")]
+pub mod wait;
+#[cfg(feature = "s3")]
+pub mod wal;
+#[cfg(feature = "s3")]
+pub mod warm;
+#[cfg(feature = "s3")]
+pub mod watch;
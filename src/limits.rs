@@ -0,0 +1,284 @@
+//! Size limits of a single transaction.
+//!
+//! Every block a transaction dirties ends up in the block manifest and, once uploads are split,
+//! in a part of a multipart upload, of which S3 allows at most [MAX_PARTS]. A single PUT carries
+//! at most [MAX_PUT_BYTES]. Rather than failing deep in the flush after uploading gigabytes, a
+//! [TransactionBudget] accounts every write as it happens and rejects the write that would cross
+//! a hard limit with [Error::TransactionTooLarge], before anything is sent. SQLite sees
+//! `SQLITE_FULL` and rolls back. Crossing a configurable soft limit only logs a warning.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{error::Error, format, mirror::BLOCK_SIZE, stats::Stats};
+
+/// The most parts of a multipart upload.
+pub const MAX_PARTS: u64 = 10_000;
+
+/// The largest object a single PUT can upload.
+pub const MAX_PUT_BYTES: u64 = 5 * 1024 * 1024 * 1024;
+
+#[derive(Clone, Debug, Default)]
+pub struct TransactionLimits {
+    /// Warn once a transaction dirties more blocks than this.
+    pub soft_dirty_blocks: Option<u64>,
+    /// Warn once a transaction has written more bytes than this.
+    pub soft_pending_bytes: Option<u64>,
+    /// Lowers the hard limit of [MAX_PARTS] dirty blocks.
+    pub max_dirty_blocks: Option<u64>,
+    /// Lowers the hard limit of [MAX_PUT_BYTES] written bytes.
+    pub max_pending_bytes: Option<u64>,
+}
+
+impl TransactionLimits {
+    pub fn hard_dirty_blocks(&self) -> u64 {
+        self.max_dirty_blocks
+            .map_or(MAX_PARTS, |max| max.min(MAX_PARTS))
+    }
+
+    pub fn hard_pending_bytes(&self) -> u64 {
+        self.max_pending_bytes
+            .map_or(MAX_PUT_BYTES, |max| max.min(MAX_PUT_BYTES))
+    }
+}
+
+/// What the running transaction has written so far.
+#[derive(Debug, Default)]
+pub struct TransactionBudget {
+    limits: TransactionLimits,
+    dirty: HashSet<u64>,
+    /// How often each dirty block was written again, e.g. when SQLite spills its page cache.
+    rewrites: HashMap<u64, u64>,
+    pending_bytes: u64,
+    /// Whether a write touched the database header on page 1.
+    rewrote_header: bool,
+    warned_blocks: bool,
+    warned_bytes: bool,
+}
+
+impl TransactionBudget {
+    pub fn new(limits: TransactionLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    pub fn dirty_blocks(&self) -> u64 {
+        self.dirty.len() as u64
+    }
+
+    pub fn pending_bytes(&self) -> u64 {
+        self.pending_bytes
+    }
+
+    /// The most times any one block was written again after it was dirtied.
+    pub fn max_block_rewrites(&self) -> u64 {
+        self.rewrites.values().copied().max().unwrap_or(0)
+    }
+
+    /// Whether the transaction wrote the database header, e.g. to change the page size.
+    pub fn rewrote_header(&self) -> bool {
+        self.rewrote_header
+    }
+
+    /// Account a write of `len` bytes at `offset`. A write crossing a hard limit fails and is not
+    /// accounted.
+    pub fn record_write(&mut self, offset: u64, len: u64, stats: &Stats) -> Result<(), Error> {
+        let (rewritten, blocks) = (offset / BLOCK_SIZE..(offset + len).div_ceil(BLOCK_SIZE))
+            .partition::<Vec<_>, _>(|block| self.dirty.contains(block));
+        let dirty = self.dirty_blocks() + blocks.len() as u64;
+        let pending = self.pending_bytes + len;
+
+        check("dirty blocks", dirty, self.limits.hard_dirty_blocks())?;
+        check("pending bytes", pending, self.limits.hard_pending_bytes())?;
+
+        self.dirty.extend(blocks);
+        for block in rewritten {
+            *self.rewrites.entry(block).or_default() += 1;
+        }
+        Stats::max(&stats.max_block_rewrites, self.max_block_rewrites());
+        self.pending_bytes = pending;
+        self.rewrote_header |= offset < format::DESCRIBE_LEN as u64;
+        if !self.warned_blocks
+            && self
+                .limits
+                .soft_dirty_blocks
+                .is_some_and(|soft| dirty > soft)
+        {
+            self.warned_blocks = true;
+            soft_limit_crossed("dirty blocks", dirty, self.limits.soft_dirty_blocks, stats);
+        }
+        if !self.warned_bytes
+            && self
+                .limits
+                .soft_pending_bytes
+                .is_some_and(|soft| pending > soft)
+        {
+            self.warned_bytes = true;
+            soft_limit_crossed(
+                "pending bytes",
+                pending,
+                self.limits.soft_pending_bytes,
+                stats,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Fail if a single upload of `len` bytes would exceed the hard limit.
+pub fn check_upload(limits: &TransactionLimits, len: u64) -> Result<(), Error> {
+    check("pending bytes", len, limits.hard_pending_bytes())
+}
+
+fn check(resource: &'static str, attempted: u64, limit: u64) -> Result<(), Error> {
+    match attempted > limit {
+        true => Err(Error::TransactionTooLarge {
+            resource,
+            limit,
+            attempted,
+        }),
+        false => Ok(()),
+    }
+}
+
+fn soft_limit_crossed(resource: &str, attempted: u64, limit: Option<u64>, stats: &Stats) {
+    Stats::incr(&stats.soft_limit_warnings);
+    tracing::warn!(
+        target: "threeqlite::limits",
+        resource,
+        attempted,
+        limit,
+        "transaction crossed its soft limit; consider committing in smaller batches"
+    );
+}
+
+impl std::fmt::Display for TransactionBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "dirty_blocks={}/{} pending_bytes={}/{}",
+            self.dirty_blocks(),
+            self.limits.hard_dirty_blocks(),
+            self.pending_bytes,
+            self.limits.hard_pending_bytes()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> TransactionLimits {
+        TransactionLimits {
+            soft_dirty_blocks: Some(2),
+            soft_pending_bytes: None,
+            max_dirty_blocks: Some(4),
+            max_pending_bytes: Some(10 * BLOCK_SIZE),
+        }
+    }
+
+    #[test]
+    fn test_hard_limits() {
+        let limits = TransactionLimits::default();
+        assert_eq!(limits.hard_dirty_blocks(), MAX_PARTS);
+        assert_eq!(limits.hard_pending_bytes(), MAX_PUT_BYTES);
+        // configured limits can only lower them
+        let limits = TransactionLimits {
+            max_dirty_blocks: Some(MAX_PARTS + 1),
+            max_pending_bytes: Some(1),
+            ..TransactionLimits::default()
+        };
+        assert_eq!(limits.hard_dirty_blocks(), MAX_PARTS);
+        assert_eq!(limits.hard_pending_bytes(), 1);
+    }
+
+    #[test]
+    fn test_rewrote_header() {
+        let stats = Stats::default();
+        let mut budget = TransactionBudget::new(limits());
+        budget.record_write(4096, 4096, &stats).unwrap();
+        assert!(!budget.rewrote_header());
+        // the header is the first 100 bytes of page 1
+        budget.record_write(96, 4, &stats).unwrap();
+        assert!(budget.rewrote_header());
+    }
+
+    #[test]
+    fn test_budget() {
+        let stats = Stats::default();
+        let mut budget = TransactionBudget::new(limits());
+
+        // rewriting a dirty block doesn't dirty it again, a write across a boundary dirties two
+        budget.record_write(0, 4096, &stats).unwrap();
+        budget.record_write(4096, 4096, &stats).unwrap();
+        budget.record_write(BLOCK_SIZE - 10, 20, &stats).unwrap();
+        assert_eq!(budget.dirty_blocks(), 2);
+        assert_eq!(
+            stats
+                .soft_limit_warnings
+                .load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+
+        // the soft limit warns once
+        budget.record_write(2 * BLOCK_SIZE, 10, &stats).unwrap();
+        budget.record_write(3 * BLOCK_SIZE, 10, &stats).unwrap();
+        assert_eq!(budget.dirty_blocks(), 4);
+        assert_eq!(
+            stats
+                .soft_limit_warnings
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+
+        // just under the limit is fine, crossing it fails without being accounted
+        let err = budget.record_write(4 * BLOCK_SIZE, 10, &stats).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::TransactionTooLarge {
+                    resource: "dirty blocks",
+                    limit: 4,
+                    attempted: 5
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(budget.dirty_blocks(), 4);
+        assert_eq!(
+            budget.to_string(),
+            format!(
+                "dirty_blocks=4/4 pending_bytes={}/{}",
+                4096 * 2 + 40,
+                10 * BLOCK_SIZE
+            )
+        );
+
+        // rewrites count towards the written bytes
+        let mut budget = TransactionBudget::new(limits());
+        assert_eq!(budget.max_block_rewrites(), 0);
+        budget.record_write(0, 4 * BLOCK_SIZE, &stats).unwrap();
+        budget.record_write(0, 4 * BLOCK_SIZE, &stats).unwrap();
+        budget.record_write(0, 2 * BLOCK_SIZE, &stats).unwrap();
+        assert_eq!(budget.max_block_rewrites(), 2);
+        assert_eq!(
+            stats
+                .max_block_rewrites
+                .load(std::sync::atomic::Ordering::Relaxed),
+            2
+        );
+        let err = budget.record_write(0, 1, &stats).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::TransactionTooLarge {
+                    resource: "pending bytes",
+                    ..
+                }
+            ),
+            "{err}"
+        );
+    }
+}
@@ -1,22 +1,455 @@
-#![allow(async_fn_in_trait)]
-
+use clap::{Parser, Subcommand};
 use rusqlite::{Connection, OpenFlags};
-use vfs::ThreeQLite;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+use threeqlite::{
+    capture::Workload,
+    config::{Config, LockBackend, LockConfig},
+    copy::{CopyOptions, Overwrite},
+    cost::CostEstimate,
+    discover::ListOptions,
+    drill::{DrillOptions, Scenario},
+    error::Error,
+    integrity::IntegrityOptions,
+    operation::OperationHandle,
+    replay::ReplayOptions,
+    vfs::ThreeQLite,
+};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Give up after waiting this many seconds for a lock, naming its holder.
+    #[arg(long, global = true, default_value_t = 30)]
+    busy_timeout: u64,
+    /// How updates are serialized: `legal-hold`, which needs Object Lock on the bucket, or
+    /// `conditional`, which needs conditional writes. All instances of a database must agree.
+    #[arg(long, global = true, default_value = "legal-hold")]
+    lock_backend: LockBackend,
+}
 
-pub mod error;
-pub mod handle;
-pub mod vfs;
-pub mod wal;
+#[derive(Subcommand)]
+enum Command {
+    /// Check the integrity of a hosted database.
+    Check {
+        /// Key of the database object.
+        #[arg(default_value = "test.db")]
+        db: String,
+        /// Run `PRAGMA quick_check` instead of the full `PRAGMA integrity_check`.
+        #[arg(long)]
+        quick: bool,
+        /// Give up with a partial report after fetching this many bytes.
+        #[arg(long)]
+        max_bytes: Option<u64>,
+        /// Before checking, accept the database object as it is after an out-of-band
+        /// modification: record its length, rebuild its block manifest and lift the quarantine.
+        /// Only pass this once you confirmed that the object holds the desired state.
+        #[arg(long)]
+        accept_external: bool,
+        /// Only print what it would cost, without running it.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List the databases in the bucket.
+    List {
+        /// Only list keys starting with this prefix.
+        prefix: Option<String>,
+        /// Stop after this many databases.
+        #[arg(long, default_value_t = ListOptions::default().limit)]
+        limit: usize,
+        /// Also print the user version, application ID and schema cookie of each database.
+        #[arg(long)]
+        verbose: bool,
+    },
+    /// Show who holds the lock of a hosted database and its background roles.
+    Busy {
+        /// Key of the database object.
+        #[arg(default_value = "test.db")]
+        db: String,
+    },
+    /// List all sidecars of a hosted database and report orphaned super-journals.
+    Reconcile {
+        /// Key of the database object.
+        #[arg(default_value = "test.db")]
+        db: String,
+    },
+    /// Copy a hosted database and its sidecars within the bucket, or resume an interrupted copy.
+    Copy {
+        src: String,
+        dst: String,
+        /// Replace the destination if it exists.
+        #[arg(long)]
+        replace: bool,
+        /// Only print what it would cost, without running it.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Move a hosted database and its sidecars within the bucket, or resume an interrupted move.
+    Rename {
+        src: String,
+        dst: String,
+        /// Replace the destination if it exists.
+        #[arg(long)]
+        replace: bool,
+        /// Only print what it would cost, without running it.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Rehearse recovering a hosted database from failures, against copies of it under a scratch
+    /// prefix that is deleted afterwards.
+    Drill {
+        /// Key of the database object.
+        #[arg(default_value = "test.db")]
+        db: String,
+        /// Only run this scenario, may be repeated. Runs all of them by default.
+        #[arg(long = "scenario", value_parser = parse_scenario)]
+        scenarios: Vec<Scenario>,
+        /// Make the copies under this prefix.
+        #[arg(long, default_value_t = DrillOptions::default().prefix)]
+        prefix: String,
+    },
+    /// Show what the configuration guarantees, after checking the bucket for lifecycle rules and
+    /// Object Lock.
+    Guarantees,
+    /// Replay a captured workload against a synthetic database, and report the requests it took.
+    Replay {
+        /// The file written by `PRAGMA threeqlite_capture`.
+        capture: std::path::PathBuf,
+        /// Key of the synthetic database, which must not exist.
+        #[arg(long, default_value = "replay.db")]
+        db: String,
+        /// Keep the time between operations as captured.
+        #[arg(long)]
+        pace: bool,
+        /// Keep the synthetic database afterwards.
+        #[arg(long)]
+        keep: bool,
+    },
+}
+
+fn parse_scenario(name: &str) -> Result<Scenario, String> {
+    Scenario::parse(name).ok_or_else(|| {
+        let names: Vec<_> = Scenario::ALL.iter().map(Scenario::name).collect();
+        format!("expected one of {}", names.join(", "))
+    })
+}
+
+/// Wait for `op`, reporting its progress every 10 seconds. The first Ctrl-C cancels it at its next
+/// checkpoint, the second exits right away.
+fn wait<T>(rt: &Runtime, tq: &ThreeQLite, op: OperationHandle<T>) -> Result<T, Error> {
+    let id = op.id();
+    let progress = rt.spawn({
+        let tq = tq.clone();
+        async move {
+            let mut ticks = tokio::time::interval(Duration::from_secs(10));
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                if let Some(progress) = tq.operation(id) {
+                    eprintln!("{progress}");
+                }
+            }
+        }
+    });
+    let interrupt = rt.spawn({
+        let tq = tq.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() && tq.cancel_operation(id) {
+                eprintln!("cancelling at the next checkpoint, press Ctrl-C again to exit now");
+            }
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        }
+    });
+    let res = rt.block_on(op.await_done());
+    progress.abort();
+    interrupt.abort();
+    if let Err(err @ Error::Cancelled { .. }) = &res {
+        eprintln!("{err}");
+        std::process::exit(130);
+    }
+    res
+}
+
+/// The latency per request assumed before the first request measured one.
+const NOMINAL_LATENCY: Duration = Duration::from_millis(50);
+
+/// Print `estimate` and how long it takes at the latency measured so far.
+fn print_estimate(rt: &Runtime, tq: &ThreeQLite, estimate: CostEstimate) {
+    let latency = rt.block_on(tq.observed_latency());
+    println!("estimate: {estimate}");
+    println!(
+        "estimated duration: {:?} at {:?} per request{}",
+        estimate.estimated_duration_at(latency.unwrap_or(NOMINAL_LATENCY)),
+        latency.unwrap_or(NOMINAL_LATENCY),
+        if latency.is_some() { "" } else { " (nominal)" }
+    );
+}
+
+fn main() -> Result<(), Error> {
+    let cli = Cli::parse();
 
-fn main() -> Result<(), crate::error::Error> {
     dotenvy::dotenv().unwrap();
 
     tracing_subscriber::fmt::init();
 
     let rt = tokio::runtime::Runtime::new().unwrap();
 
-    let tq = rt.block_on(ThreeQLite::new());
-    sqlite_vfs::register("bruhfs", tq, true).unwrap();
+    let config = Config {
+        lock: LockConfig {
+            busy_timeout: Some(Duration::from_secs(cli.busy_timeout)),
+            backend: cli.lock_backend,
+            ..LockConfig::default()
+        },
+        ..Config::default()
+    };
+    let tq = rt.block_on(ThreeQLite::with_config(config.clone()))?;
+
+    match cli.command {
+        Some(Command::Check {
+            db,
+            quick,
+            max_bytes,
+            accept_external,
+            dry_run,
+        }) => {
+            if accept_external {
+                let len = rt.block_on(tq.accept_external_state(&db))?;
+                println!("accepted {db} as modified out-of-band, {len} bytes");
+            }
+            let opts = IntegrityOptions {
+                quick,
+                max_bytes,
+                ..IntegrityOptions::default()
+            };
+            if dry_run {
+                print_estimate(
+                    &rt,
+                    &tq,
+                    rt.block_on(tq.estimate_integrity_check(&db, opts))?,
+                );
+                return Ok(());
+            }
+            let report = match wait(&rt, &tq, tq.start_integrity_check(&db, opts)) {
+                Err(err @ Error::Busy { .. }) => {
+                    eprintln!("{err}");
+                    std::process::exit(2);
+                }
+                res => res?,
+            };
+            for finding in &report.findings {
+                println!("{finding}");
+            }
+            if let Some(estimate) = report.estimate {
+                println!("estimate: {estimate}");
+            }
+            println!("cost: {}", report.cost);
+            println!(
+                "checked {} bytes in {} requests ({:?}){}",
+                report.bytes_transferred,
+                report.requests,
+                report.duration,
+                if report.complete { "" } else { ", incomplete" }
+            );
+            std::process::exit(if report.is_ok() { 0 } else { 1 });
+        }
+        Some(Command::List {
+            prefix,
+            limit,
+            verbose,
+        }) => {
+            let opts = ListOptions {
+                limit,
+                headers: verbose,
+                ..ListOptions::default()
+            };
+            let dbs = rt.block_on(tq.list_databases(prefix.as_deref(), opts))?;
+            print!(
+                "{:<40} {:>6} {:>6} {:>12} {:>12} {:>8} {:>10} {:<18} ",
+                "name", "layout", "page", "size", "physical", "changes", "generation", "lock"
+            );
+            match verbose {
+                true => println!(
+                    "{:>12} {:>12} {:>8} last commit",
+                    "user_version", "app_id", "schema"
+                ),
+                false => println!("last commit"),
+            }
+            for db in dbs {
+                let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_owned());
+                print!(
+                    "{:<40} {:>6} {:>6} {:>12} {:>12} {:>8} {:>10} {:<18} ",
+                    db.name,
+                    db.layout,
+                    db.page_size,
+                    or_dash(db.size.map(|size| size.to_string())),
+                    db.physical_bytes,
+                    db.change_counter,
+                    or_dash(db.generation.map(|generation| generation.to_string())),
+                    or_dash(db.lock.map(|lock| lock.to_string())),
+                );
+                if let Some(header) = db.header {
+                    print!(
+                        "{:>12} {:>#12x} {:>8} ",
+                        header.user_version, header.application_id, header.schema_cookie
+                    );
+                }
+                println!("{}", or_dash(db.last_modified.map(|time| time.to_string())));
+            }
+            return Ok(());
+        }
+        Some(Command::Busy { db }) => {
+            match rt.block_on(tq.why_busy(&db))? {
+                Some(diagnosis) => println!("{db}: {diagnosis}"),
+                None => println!("{db}: not locked"),
+            }
+            let now = threeqlite::protocol::now_ms();
+            for (role, lease) in rt.block_on(tq.role_leases(&db))? {
+                match &lease {
+                    Some(lease) if lease.expires > now => println!(
+                        "{role}: held by {} for {}ms",
+                        lease.holder,
+                        lease.expires - now
+                    ),
+                    Some(lease) => println!("{role}: lease of {} expired", lease.holder),
+                    None => println!("{role}: never claimed"),
+                }
+                if let Some(run) = lease.as_ref().and_then(|lease| lease.last_run.as_ref()) {
+                    println!("  last run: {run}");
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Reconcile { db }) => {
+            let (mut keys, mut sidecars) = (0, 0);
+            loop {
+                let report = rt.block_on(tq.reconcile(&db))?;
+                keys += report.keys_scanned;
+                sidecars += report.sidecars;
+                for orphan in &report.orphans {
+                    println!("orphaned super-journal: {orphan}");
+                }
+                if report.complete {
+                    break;
+                }
+            }
+            println!("{db}: {sidecars} sidecars in {keys} keys");
+            return Ok(());
+        }
+        Some(Command::Copy {
+            ref src,
+            ref dst,
+            replace,
+            dry_run,
+        })
+        | Some(Command::Rename {
+            ref src,
+            ref dst,
+            replace,
+            dry_run,
+        }) => {
+            let rename = matches!(cli.command, Some(Command::Rename { .. }));
+            let opts = CopyOptions {
+                overwrite: match replace {
+                    true => Overwrite::Replace,
+                    false => Overwrite::Fail,
+                },
+                ..CopyOptions::default()
+            };
+            if dry_run {
+                let estimate = match rename {
+                    true => rt.block_on(tq.estimate_rename_database(src, dst, opts))?,
+                    false => rt.block_on(tq.estimate_copy_database(src, dst, opts))?,
+                };
+                print_estimate(&rt, &tq, estimate);
+                return Ok(());
+            }
+            // a cancelled copy resumes where it stopped when run again
+            let report = match rename {
+                true => wait(&rt, &tq, tq.start_rename_database(src, dst, opts))?,
+                false => wait(&rt, &tq, tq.start_copy_database(src, dst, opts))?,
+            };
+            println!(
+                "{src} -> {dst}: {} objects, {} bytes copied, {} resumed, {} deleted",
+                report.objects, report.bytes, report.resumed, report.deleted
+            );
+            if let Some(estimate) = report.estimate {
+                println!("estimate: {estimate}");
+            }
+            println!("cost: {}", report.cost);
+            return Ok(());
+        }
+        Some(Command::Drill {
+            db,
+            scenarios,
+            prefix,
+        }) => {
+            let scenarios = match scenarios.is_empty() {
+                true => Scenario::ALL.to_vec(),
+                false => scenarios,
+            };
+            let report = wait(
+                &rt,
+                &tq,
+                tq.start_drill(&db, &scenarios, DrillOptions { prefix }),
+            )?;
+            for scenario in &report.scenarios {
+                println!("{scenario}");
+            }
+            println!(
+                "{db}: {} scenarios in {:?}, {} objects deleted under {}",
+                report.scenarios.len(),
+                report.duration,
+                report.deleted,
+                report.scratch
+            );
+            std::process::exit(if report.is_ok() { 0 } else { 1 });
+        }
+        Some(Command::Guarantees) => {
+            // without the findings, the guarantees say what wasn't checked
+            if let Err(err) = rt.block_on(tq.preflight()) {
+                eprintln!("preflight failed: {err}");
+            }
+            println!("{}", tq.guarantees());
+            return Ok(());
+        }
+        Some(Command::Replay {
+            capture,
+            db,
+            pace,
+            keep,
+        }) => {
+            let bytes = std::fs::read(&capture).map_err(|err| Error::Whatever {
+                message: format!("reading {} failed: {err}", capture.display()),
+                source: Some(Box::new(err)),
+            })?;
+            let workload = Workload::decode(&bytes)?;
+            println!("{}: {workload}", capture.display());
+            // an instance of the synthetic database
+            let config = Config {
+                db_filename: db,
+                ..config
+            };
+            let tq = rt.block_on(ThreeQLite::with_config(config))?;
+            let report = rt.block_on(tq.replay(&workload, &ReplayOptions { pace, keep }))?;
+            for (i, failure) in &report.failures {
+                println!("operation {i} failed: {failure}");
+            }
+            println!("{report}");
+            std::process::exit(if report.failures.is_empty() && report.mismatched == 0 {
+                0
+            } else {
+                1
+            });
+        }
+        None => {}
+    }
+
+    tq.register("bruhfs", true).unwrap();
 
     let conn = Connection::open_with_flags_and_vfs(
         "main.db3",
@@ -0,0 +1,418 @@
+//! Memory accounting across the buffers of an instance.
+//!
+//! Every buffer that grows with the workload, i.e. the [page cache](crate::cache), the
+//! journals kept in memory until they are uploaded and [temporary files](crate::temp), charges
+//! what it holds to the [MemoryBudget] of its instance through a [Charge], which credits the
+//! budget back when dropped. With [Config::memory_cap](crate::config::Config::memory_cap) set, a
+//! charge that would exceed the cap first runs the reliefs of the instance, cheapest first:
+//!
+//! 1. [Relief::CacheProbation] evicts pages read once.
+//! 2. [Relief::CacheProtected] evicts pages read repeatedly.
+//!
+//! If the cap would still be exceeded, the charge fails with [Error::OutOfMemory], which a
+//! journal write reports to SQLite as `SQLITE_NOMEM` and a page read skips the cache for. Journals
+//! and temporary files can't be relieved, as they must stay in memory until the transaction ends or
+//! they are closed.
+//!
+//! Usage by component is part of the [stats](crate::stats::StatsSnapshot::memory) and of the
+//! `threeqlite_memory` pragma.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering::Relaxed},
+    Arc, Mutex,
+};
+
+use crate::{error::Error, stats::Stats};
+
+/// A consumer of memory accounted for by a [MemoryBudget].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Component {
+    /// Pages of the [page cache](crate::cache).
+    Cache,
+    /// Journals held until they are uploaded, see [crate::journal].
+    Journal,
+    /// Temporary files, held until closed, see [crate::temp].
+    Temp,
+}
+
+impl Component {
+    pub const ALL: [Component; 3] = [Component::Cache, Component::Journal, Component::Temp];
+}
+
+impl std::fmt::Display for Component {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Component::Cache => "cache",
+            Component::Journal => "journal",
+            Component::Temp => "temp",
+        })
+    }
+}
+
+/// A way to free memory under pressure, in the order they are tried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Relief {
+    /// Evict pages from the probationary segment of the cache.
+    CacheProbation,
+    /// Evict pages from the protected segment of the cache.
+    CacheProtected,
+}
+
+impl Relief {
+    pub const ALL: [Relief; 2] = [Relief::CacheProbation, Relief::CacheProtected];
+}
+
+impl std::fmt::Display for Relief {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Relief::CacheProbation => "cache_probation",
+            Relief::CacheProtected => "cache_protected",
+        })
+    }
+}
+
+/// Frees up to the given number of bytes and returns how many it freed.
+type ReliefFn = Arc<dyn Fn(u64) -> u64 + Send + Sync>;
+
+/// The memory used by an instance, see the [module documentation](self).
+#[derive(Default)]
+pub struct MemoryBudget {
+    cap: Option<u64>,
+    used: [AtomicU64; Component::ALL.len()],
+    total: AtomicU64,
+    high_water: AtomicU64,
+    reliefs: Mutex<Vec<(Relief, ReliefFn)>>,
+    /// Times each relief ran, and the bytes it freed.
+    relieved: [(AtomicU64, AtomicU64); Relief::ALL.len()],
+    denied: AtomicU64,
+}
+
+impl std::fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryBudget")
+            .field("cap", &self.cap)
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MemoryBudget {
+    /// A budget capped at `cap` bytes, unbounded if `None`.
+    pub fn new(cap: Option<u64>) -> Self {
+        Self {
+            cap,
+            ..Self::default()
+        }
+    }
+
+    pub fn cap(&self) -> Option<u64> {
+        self.cap
+    }
+
+    /// Run `relieve` to free memory under pressure, in the order of [Relief]. `relieve` is
+    /// called without any lock of the budget held and may drop charges.
+    pub fn add_relief(&self, relief: Relief, relieve: impl Fn(u64) -> u64 + Send + Sync + 'static) {
+        let mut reliefs = self.reliefs.lock().unwrap();
+        reliefs.push((relief, Arc::new(relieve)));
+        reliefs.sort_by_key(|(relief, _)| *relief);
+    }
+
+    /// Charge `bytes` used by `component`, relieving pressure if the cap would be exceeded.
+    pub fn charge(self: &Arc<Self>, component: Component, bytes: u64) -> Result<Charge, Error> {
+        self.reserve(component, bytes)?;
+        Ok(Charge {
+            budget: self.clone(),
+            component,
+            bytes,
+        })
+    }
+
+    fn reserve(&self, component: Component, bytes: u64) -> Result<(), Error> {
+        let mut reliefs = None;
+        let mut next = 0;
+        let mut total = self.total.load(Relaxed);
+        loop {
+            let over = match self.cap {
+                Some(cap) => (total + bytes).saturating_sub(cap),
+                None => 0,
+            };
+            if over == 0 {
+                match self
+                    .total
+                    .compare_exchange_weak(total, total + bytes, Relaxed, Relaxed)
+                {
+                    Ok(_) => break,
+                    Err(current) => {
+                        total = current;
+                        continue;
+                    }
+                }
+            }
+            // cloned so that reliefs can charge and credit the budget themselves
+            let reliefs = reliefs.get_or_insert_with(|| self.reliefs.lock().unwrap().clone());
+            let Some((relief, relieve)) = reliefs.get(next) else {
+                Stats::incr(&self.denied);
+                return Err(Error::OutOfMemory {
+                    component,
+                    requested: bytes,
+                    used: total,
+                    cap: self.cap.unwrap_or_default(),
+                });
+            };
+            next += 1;
+            let freed = relieve(over);
+            let (runs, relieved) = &self.relieved[*relief as usize];
+            Stats::incr(runs);
+            relieved.fetch_add(freed, Relaxed);
+            tracing::debug!(
+                target: "threeqlite::memory",
+                %component,
+                requested = bytes,
+                %relief,
+                freed,
+                "relieved memory pressure"
+            );
+            total = self.total.load(Relaxed);
+        }
+        self.used[component as usize].fetch_add(bytes, Relaxed);
+        self.high_water.fetch_max(total + bytes, Relaxed);
+        Ok(())
+    }
+
+    fn release(&self, component: Component, bytes: u64) {
+        self.used[component as usize].fetch_sub(bytes, Relaxed);
+        self.total.fetch_sub(bytes, Relaxed);
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            cap: self.cap,
+            used: self.used.each_ref().map(|used| used.load(Relaxed)),
+            high_water: self.high_water.load(Relaxed),
+            reliefs: self
+                .relieved
+                .each_ref()
+                .map(|(runs, freed)| (runs.load(Relaxed), freed.load(Relaxed))),
+            denied: self.denied.load(Relaxed),
+        }
+    }
+}
+
+/// Memory charged to a [MemoryBudget], credited back when dropped.
+#[derive(Debug)]
+pub struct Charge {
+    budget: Arc<MemoryBudget>,
+    component: Component,
+    bytes: u64,
+}
+
+impl Charge {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Charge `bytes` in total instead. Shrinking always succeeds, growing charges the
+    /// difference and leaves the charge as it was on failure.
+    pub fn resize(&mut self, bytes: u64) -> Result<(), Error> {
+        match bytes.checked_sub(self.bytes) {
+            Some(grown) => self.budget.reserve(self.component, grown)?,
+            None => self.budget.release(self.component, self.bytes - bytes),
+        }
+        self.bytes = bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budget.release(self.component, self.bytes);
+    }
+}
+
+/// A point-in-time copy of the counters of a [MemoryBudget].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub cap: Option<u64>,
+    /// Bytes in use per [Component].
+    pub used: [u64; Component::ALL.len()],
+    /// The most bytes in use at once.
+    pub high_water: u64,
+    /// Times each [Relief] ran, and the bytes it freed.
+    pub reliefs: [(u64, u64); Relief::ALL.len()],
+    /// Charges that failed because the cap was reached.
+    pub denied: u64,
+}
+
+impl MemoryStats {
+    pub fn used(&self) -> u64 {
+        self.used.iter().sum()
+    }
+}
+
+impl std::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.cap {
+            Some(cap) => write!(f, "cap={cap}")?,
+            None => f.write_str("cap=none")?,
+        }
+        write!(f, " used={}", self.used())?;
+        for component in Component::ALL {
+            write!(f, " {component}={}", self.used[component as usize])?;
+        }
+        write!(f, " high_water={}", self.high_water)?;
+        for relief in Relief::ALL {
+            let (runs, freed) = self.reliefs[relief as usize];
+            write!(f, " {relief}={runs}/{freed}")?;
+        }
+        write!(f, " denied={}", self.denied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheConfig, CacheUse, EvictionCause, PageCache};
+
+    const PAGE: usize = 4096;
+
+    #[test]
+    fn test_charges() {
+        let budget = Arc::new(MemoryBudget::new(Some(10 * PAGE as u64)));
+        let cache = budget.charge(Component::Cache, 6 * PAGE as u64).unwrap();
+        let mut journal = budget.charge(Component::Journal, 0).unwrap();
+        journal.resize(4 * PAGE as u64).unwrap();
+
+        // nothing to relieve
+        let err = journal.resize(5 * PAGE as u64).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::OutOfMemory {
+                    component: Component::Journal,
+                    requested: 4096,
+                    used: 40960,
+                    cap: 40960,
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(journal.bytes(), 4 * PAGE as u64);
+
+        drop(cache);
+        journal.resize(5 * PAGE as u64).unwrap();
+        journal.resize(PAGE as u64).unwrap();
+        let stats = budget.stats();
+        assert_eq!(stats.used, [0, PAGE as u64, 0]);
+        assert_eq!(stats.high_water, 10 * PAGE as u64);
+        assert_eq!(stats.denied, 1);
+        assert_eq!(
+            stats.to_string(),
+            "cap=40960 used=4096 cache=0 journal=4096 temp=0 high_water=40960 \
+             cache_probation=0/0 cache_protected=0/0 denied=1"
+        );
+
+        drop(journal);
+        assert_eq!(budget.stats().used(), 0);
+    }
+
+    #[test]
+    fn test_reliefs_run_cheapest_first() {
+        let budget = Arc::new(MemoryBudget::new(Some(100)));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        // registered out of order
+        for (relief, frees) in [(Relief::CacheProtected, 30), (Relief::CacheProbation, 10)] {
+            let order = order.clone();
+            budget.add_relief(relief, move |_| {
+                order.lock().unwrap().push(relief);
+                frees
+            });
+        }
+        let _held = budget.charge(Component::Cache, 100).unwrap();
+        // the reliefs only report what they freed, without crediting the budget
+        assert!(budget.charge(Component::Journal, 1).is_err());
+        assert_eq!(
+            *order.lock().unwrap(),
+            [Relief::CacheProbation, Relief::CacheProtected]
+        );
+        assert_eq!(budget.stats().reliefs, [(1, 10), (1, 30)]);
+    }
+
+    /// Several databases share the cache and journals of an instance capped well below what
+    /// they would use.
+    #[test]
+    fn test_workload_under_cap() {
+        const CAP: u64 = 32 * PAGE as u64;
+        let budget = Arc::new(MemoryBudget::new(Some(CAP)));
+        let cache = Arc::new(PageCache::with_budget(
+            CacheConfig {
+                capacity: 1024 * PAGE as u64,
+                ..CacheConfig::default()
+            },
+            budget.clone(),
+        ));
+        cache.add_reliefs();
+        let page = |db: usize, page: u64| vec![(db as u64 * 31 + page) as u8; PAGE];
+        let dbs = ["a.db", "b.db", "c.db"];
+
+        // a hot set of 8 pages per database, read twice to protect them, then a scan
+        for (i, db) in dbs.iter().enumerate() {
+            for n in 0..8 {
+                let offset = n * PAGE as u64;
+                cache.insert(db, 1, offset, page(i, n), CacheUse::Admit);
+                assert_eq!(cache.get(db, 1, offset, PAGE), Some(page(i, n)));
+            }
+            for n in 100..120 {
+                cache.insert(db, 1, n * PAGE as u64, page(i, n), CacheUse::Sequential);
+            }
+        }
+        let stats = budget.stats();
+        assert!(stats.high_water <= CAP, "{stats}");
+        assert_eq!(stats.used[Component::Cache as usize], CAP);
+        // the hot sets survive the scans
+        assert!(
+            stats.reliefs[Relief::CacheProbation as usize].0 > 0,
+            "{stats}"
+        );
+        assert_eq!(
+            stats.reliefs[Relief::CacheProtected as usize].0,
+            0,
+            "{stats}"
+        );
+        assert_eq!(cache.stats().protected_bytes, 24 * PAGE as u64);
+
+        // each transaction journals 6 pages; the cache makes room, down to its hot pages
+        let mut journals: Vec<_> = dbs
+            .iter()
+            .map(|_| budget.charge(Component::Journal, 0).unwrap())
+            .collect();
+        for journal in &mut journals {
+            journal.resize(6 * PAGE as u64).unwrap();
+        }
+        let stats = budget.stats();
+        assert!(
+            stats.reliefs[Relief::CacheProtected as usize].0 > 0,
+            "{stats}"
+        );
+        assert_eq!(stats.used, [14 * PAGE as u64, 18 * PAGE as u64, 0]);
+        assert!(cache.stats().evictions[EvictionCause::Pressure as usize] > 0);
+
+        // a journal larger than the cap fails without the cache serving wrong pages
+        let err = journals[0].resize(CAP + 1).unwrap_err();
+        assert!(matches!(err, Error::OutOfMemory { .. }), "{err}");
+        for (i, db) in dbs.iter().enumerate() {
+            for n in (0..8).chain(100..120) {
+                if let Some(cached) = cache.get(db, 1, n * PAGE as u64, PAGE) {
+                    assert_eq!(cached, page(i, n));
+                }
+            }
+        }
+        let stats = budget.stats();
+        assert!(stats.high_water <= CAP, "{stats}");
+        assert_eq!(stats.denied, 1);
+        assert_eq!(stats.used[Component::Cache as usize], 0);
+
+        drop(journals);
+        drop(cache);
+        assert_eq!(budget.stats().used(), 0);
+    }
+}
@@ -0,0 +1,547 @@
+//! Local mirrors for reading a database through connectivity gaps.
+//!
+//! [crate::vfs::ThreeQLite::enable_offline_mirror] keeps a full copy of a database in a local
+//! file. Whenever the database is opened while the object store is reachable, the mirror catches
+//! up with it, fetching only the blocks whose checksums differ from the [BlockManifest] published
+//! next to the database object, split into extents for large databases (see [crate::extent]).
+//! Without a manifest matching the current object, e.g. for databases last written by another
+//! tool, every block is fetched.
+//!
+//! When the connectivity probe fails, read-only opens are served from the mirror, which reports
+//! how stale it is through `PRAGMA threeqlite_offline`, and opens for writing fail with
+//! [Error::Offline].
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    circuit::OpClass,
+    error::Error,
+    extent::{self, Manifest},
+    fetch,
+    format::{self, Bounded},
+    key::ObjectKey,
+    priority::IoClass,
+    vfs::{status, Inner},
+};
+
+/// Granularity of block manifests and mirror updates.
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Checksums of the blocks of a database object, stored at [crate::key::KeyLayout::manifest]
+/// unless split into extents.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockManifest {
+    pub block_size: u64,
+    pub len: u64,
+    /// Hex-encoded MD5 of the whole object, i.e. its ETag. A manifest whose MD5 doesn't match the
+    /// ETag of the object is outdated.
+    pub md5: String,
+    /// Hex-encoded MD5 of each block.
+    pub blocks: Vec<String>,
+}
+
+impl BlockManifest {
+    pub fn new(data: &[u8]) -> Self {
+        Self {
+            block_size: BLOCK_SIZE,
+            len: data.len() as u64,
+            md5: format!("{:x}", md5::compute(data)),
+            blocks: data
+                .chunks(BLOCK_SIZE as usize)
+                .map(|block| format!("{:x}", md5::compute(block)))
+                .collect(),
+        }
+    }
+}
+
+/// 64 MiB of checksums cover databases of about 100 GiB.
+impl Bounded for BlockManifest {
+    const MAX_LEN: u64 = 64 << 20;
+
+    fn validate(&self) -> Result<(), String> {
+        if self.block_size == 0 {
+            return Err("block size of 0".to_owned());
+        }
+        let expected = self.len.div_ceil(self.block_size);
+        if self.blocks.len() as u64 != expected {
+            return Err(format!(
+                "{} blocks, but {expected} cover {} bytes",
+                self.blocks.len(),
+                self.len
+            ));
+        }
+        format::check_md5(&self.md5)?;
+        self.blocks
+            .iter()
+            .try_for_each(|md5| format::check_md5(md5))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MirrorPolicy {
+    /// How long the connectivity probe waits for the object store before giving up.
+    pub probe_timeout: Duration,
+    /// Refuse to serve a mirror last synced longer ago than this.
+    pub max_staleness: Option<Duration>,
+}
+
+impl Default for MirrorPolicy {
+    fn default() -> Self {
+        Self {
+            probe_timeout: Duration::from_secs(2),
+            max_staleness: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OfflineStatus {
+    /// The generation of the database at the last sync, if its metadata object was stamped.
+    pub generation: Option<u64>,
+    /// Time since the mirror was last known to match the database. `None` before the first sync.
+    pub staleness: Option<Duration>,
+    /// Whether the mirror holds a full copy of the database.
+    pub complete: bool,
+    /// Whether the last connectivity probe failed.
+    pub offline: bool,
+}
+
+impl std::fmt::Display for OfflineStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "offline={}", self.offline)?;
+        match self.generation {
+            Some(generation) => write!(f, " generation={generation}")?,
+            None => write!(f, " generation=unknown")?,
+        }
+        match self.staleness {
+            Some(staleness) => write!(f, " staleness={staleness:?}")?,
+            None => write!(f, " staleness=unknown")?,
+        }
+        write!(f, " complete={}", self.complete)
+    }
+}
+
+/// What a [Mirror::sync] transferred.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub blocks_fetched: u64,
+    pub bytes_transferred: u64,
+}
+
+struct State {
+    file: File,
+    len: u64,
+    /// Hex-encoded MD5 of each block of the local copy.
+    blocks: Vec<String>,
+    /// The ETag of the database object the local copy matches.
+    etag: Option<String>,
+    generation: Option<u64>,
+    /// When the last sync completed, on both clocks: the monotonic one stands still through a
+    /// suspend and the wall clock may be stepped back, so the mirror is as stale as the larger
+    /// says, see [crate::clock].
+    synced: Option<(Instant, SystemTime)>,
+    complete: bool,
+    offline: bool,
+}
+
+/// The local copy of one database.
+pub struct Mirror {
+    pub db: ObjectKey,
+    pub path: PathBuf,
+    pub policy: MirrorPolicy,
+    state: Mutex<State>,
+}
+
+fn io_error(context: &str, err: std::io::Error) -> Error {
+    Error::Whatever {
+        message: format!("{context}: {err}"),
+        source: Some(Box::new(err)),
+    }
+}
+
+impl Mirror {
+    /// Open the mirror of `db` at `path`. An existing file is reused, so that the first sync only
+    /// fetches what changed since, but it is only served once a sync completed.
+    pub fn open(db: ObjectKey, path: &Path, policy: MirrorPolicy) -> Result<Self, Error> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|err| io_error("failed to open mirror file", err))?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)
+            .map_err(|err| io_error("failed to read mirror file", err))?;
+        let BlockManifest { len, blocks, .. } = BlockManifest::new(&data);
+
+        Ok(Self {
+            db,
+            path: path.to_owned(),
+            policy,
+            state: Mutex::new(State {
+                file,
+                len,
+                blocks,
+                etag: None,
+                generation: None,
+                synced: None,
+                complete: false,
+                offline: false,
+            }),
+        })
+    }
+
+    /// Check whether the object store answers within the probe timeout. Any answer other than a
+    /// server error counts, e.g. a database that doesn't exist yet.
+    pub async fn probe(&self, inner: &Inner) -> bool {
+        let head = tokio::time::timeout(
+            self.policy.probe_timeout,
+            inner
+                .s3
+                .head_object()
+                .bucket(&inner.bucket)
+                .key(&self.db)
+                .send(),
+        )
+        .await;
+        let online = match head {
+            Ok(Ok(_)) => true,
+            Ok(Err(err)) => status(&err).is_some_and(|status| status < 500),
+            Err(_) => false,
+        };
+
+        let mut state = self.state.lock().await;
+        if state.offline == online {
+            match online {
+                true => {
+                    tracing::info!(target: "threeqlite::s3", db = %self.db, "object store reachable again")
+                }
+                false => tracing::warn!(
+                    target: "threeqlite::s3",
+                    db = %self.db,
+                    "object store unreachable, serving reads from the offline mirror"
+                ),
+            }
+        }
+        state.offline = !online;
+        online
+    }
+
+    /// Bring the local copy up to date with the database object.
+    pub async fn sync(&self, inner: &Inner) -> Result<SyncReport, Error> {
+        let mut state = self.state.lock().await;
+        let generation = inner
+            .read_metadata_record()
+            .await
+            .ok()
+            .and_then(|record| record.stamp)
+            .map(|stamp| stamp.generation);
+
+        let head = inner
+            .s3
+            .head_object()
+            .bucket(&inner.bucket)
+            .key(&self.db)
+            .send()
+            .await;
+        inner.record(OpClass::Read, head.is_ok());
+        let head = head?;
+        let etag = head.e_tag().map(|etag| etag.trim_matches('"').to_owned());
+        let len = head.content_length.unwrap_or(0) as u64;
+
+        let mut report = SyncReport::default();
+        if !(state.complete && etag.is_some() && state.etag == etag) {
+            let manifest = self.manifest(inner).await?.filter(|m| {
+                m.block_size() == BLOCK_SIZE
+                    && m.object_len() == len
+                    && Some(m.md5()) == etag.as_deref()
+            });
+            // the copy is a mix of generations until every changed block is in
+            state.complete = false;
+            state.etag = None;
+
+            let blocks = len.div_ceil(BLOCK_SIZE) as usize;
+            let mut expected = Vec::with_capacity(blocks);
+            for i in (0..blocks).take_while(|_| manifest.is_some()) {
+                match manifest.as_ref().unwrap().block(inner, &self.db, i).await {
+                    Ok(md5) => expected.push(md5),
+                    // as without a manifest
+                    Err(err) => {
+                        tracing::warn!(target: "threeqlite::s3", %err, "ignoring manifest");
+                        expected.clear();
+                        break;
+                    }
+                }
+            }
+            let changed: Vec<_> = (0..blocks)
+                .filter(|&i| {
+                    let expected = expected.get(i);
+                    expected.is_none() || expected != state.blocks.get(i)
+                })
+                .collect();
+            // bounded, so that a full fetch of a large database isn't held in memory at once
+            for batch in changed.chunks(inner.fetch_config.concurrency.max(1) * 4) {
+                let ranges: Vec<_> = batch
+                    .iter()
+                    .map(|&i| i as u64 * BLOCK_SIZE..((i as u64 + 1) * BLOCK_SIZE).min(len))
+                    .collect();
+                // fail rather than mix in blocks of a newer commit
+                let (blocks, _) =
+                    fetch::fetch(inner, &self.db, etag.as_deref(), &ranges, IoClass::Bulk).await?;
+
+                for ((&i, range), data) in batch.iter().zip(ranges).zip(blocks) {
+                    let md5 = format!("{:x}", md5::compute(&data));
+                    if expected.get(i).is_some_and(|expected| *expected != md5) {
+                        return Err(Error::Whatever {
+                            message: format!(
+                                "block {i} of {} does not match its manifest",
+                                self.db
+                            ),
+                            source: None,
+                        });
+                    }
+
+                    state
+                        .file
+                        .seek(SeekFrom::Start(range.start))
+                        .and_then(|_| state.file.write_all(&data))
+                        .map_err(|err| io_error("failed to write mirror file", err))?;
+                    if state.blocks.len() <= i {
+                        state.blocks.resize(i + 1, String::new());
+                    }
+                    state.blocks[i] = md5;
+                    report.blocks_fetched += 1;
+                    report.bytes_transferred += data.len() as u64;
+                }
+            }
+
+            state
+                .file
+                .set_len(len)
+                .and_then(|_| state.file.sync_data())
+                .map_err(|err| io_error("failed to write mirror file", err))?;
+            state.blocks.truncate(len.div_ceil(BLOCK_SIZE) as usize);
+            state.len = len;
+            state.etag = etag;
+            state.complete = true;
+            tracing::debug!(
+                target: "threeqlite::s3",
+                db = %self.db,
+                blocks = report.blocks_fetched,
+                bytes = report.bytes_transferred,
+                "offline mirror synced"
+            );
+        }
+
+        state.generation = generation;
+        state.synced = Some((Instant::now(), SystemTime::now()));
+        state.offline = false;
+        Ok(report)
+    }
+
+    async fn manifest(&self, inner: &Inner) -> Result<Option<Manifest>, Error> {
+        Ok(extent::read(inner, &self.db).await?.0)
+    }
+
+    pub async fn status(&self) -> OfflineStatus {
+        let state = self.state.lock().await;
+        OfflineStatus {
+            generation: state.generation,
+            staleness: state
+                .synced
+                .map(|(at, wall)| at.elapsed().max(wall.elapsed().unwrap_or_default())),
+            complete: state.complete,
+            offline: state.offline,
+        }
+    }
+
+    /// Fail unless the mirror may be served in place of the database.
+    pub async fn check_servable(&self) -> Result<(), Error> {
+        let status = self.status().await;
+        let reason = if !status.complete {
+            "the mirror is incomplete".to_owned()
+        } else if let Some((staleness, max)) = status.staleness.zip(self.policy.max_staleness) {
+            if staleness <= max {
+                return Ok(());
+            }
+            format!("the mirror was last synced {staleness:?} ago")
+        } else {
+            return Ok(());
+        };
+        Err(Error::MirrorUnavailable {
+            db: self.db.to_string(),
+            reason,
+        })
+    }
+
+    pub async fn size(&self) -> u64 {
+        self.state.lock().await.len
+    }
+
+    pub async fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        state
+            .file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| state.file.read_exact(buf))
+            .map_err(|err| io_error("failed to read mirror file", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlite_vfs::{DatabaseHandle, OpenAccess};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        heal::Stamp,
+        key::KeyLayout,
+        mock::MockS3,
+        vfs::{MetadataRecord, ThreeQLite},
+    };
+
+    #[test]
+    fn test_block_manifest() {
+        let data = vec![7; BLOCK_SIZE as usize + 10];
+        let manifest = BlockManifest::new(&data);
+        assert_eq!(manifest.len, BLOCK_SIZE + 10);
+        assert_eq!(manifest.md5, format!("{:x}", md5::compute(&data)));
+        assert_eq!(manifest.blocks.len(), 2);
+        assert_eq!(manifest.blocks[1], format!("{:x}", md5::compute([7; 10])));
+        assert!(BlockManifest::new(&[]).blocks.is_empty());
+    }
+
+    fn test_db() -> ObjectKey {
+        KeyLayout::db("test.db").unwrap()
+    }
+
+    /// Commit `data` as generation `generation` the way a writer of this crate does.
+    async fn commit(mock: &MockS3, tq: &ThreeQLite, data: &[u8], generation: u64) {
+        mock.put("test.db", data);
+        let inner = tq.inner.read().await;
+        inner
+            .publish_blocks(&test_db(), &BlockManifest::new(data))
+            .await
+            .unwrap();
+        inner
+            .write_metadata_record(MetadataRecord {
+                stamp: Some(Stamp::new(generation)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    fn block_reads(mock: &MockS3) -> usize {
+        mock.requests()
+            .iter()
+            .filter(|(method, key)| method == "GET" && key == "test.db")
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_offline_reads_and_incremental_resync() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let mut data: Vec<u8> = (0..4 * BLOCK_SIZE).map(|i| (i / 4096) as u8).collect();
+        commit(&mock, &tq, &data, 3).await;
+
+        let path = std::env::temp_dir().join(format!("threeqlite-mirror-{}", uuid::Uuid::new_v4()));
+        let status = tq
+            .enable_offline_mirror("test.db", &path, MirrorPolicy::default())
+            .await
+            .unwrap();
+        assert!(status.complete);
+        assert_eq!(status.generation, Some(3));
+        assert_eq!(block_reads(&mock), 4);
+
+        mock.offline(true);
+        let mut handle = tq
+            .open_offline(&test_db(), OpenAccess::Read)
+            .await
+            .unwrap()
+            .expect("served from the mirror");
+        assert_eq!(handle.size().await.unwrap(), 4 * BLOCK_SIZE);
+        let mut buf = vec![0; 4096];
+        handle
+            .read_exact_at(&mut buf, 2 * BLOCK_SIZE)
+            .await
+            .unwrap();
+        assert_eq!(buf, data[2 * BLOCK_SIZE as usize..][..4096]);
+        let pragma = handle
+            .pragma("threeqlite_offline", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            pragma.starts_with("offline=true generation=3 staleness="),
+            "{pragma}"
+        );
+        let status = tq.offline_status("test.db").await.unwrap();
+        assert!(status.offline);
+        assert!(status.staleness.is_some());
+
+        assert!(matches!(
+            handle.write_all_at(&[1], 0).await,
+            Err(sqlite_vfs::error::Error::External {
+                cause: Error::Offline { .. }
+            })
+        ));
+        assert!(matches!(
+            tq.open_offline(&test_db(), OpenAccess::Write).await,
+            Err(Error::Offline { .. })
+        ));
+
+        // back online, another instance commits a change to one block
+        mock.offline(false);
+        let other = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        data[BLOCK_SIZE as usize + 100] ^= 0xff;
+        commit(&mock, &other, &data, 4).await;
+
+        assert!(tq
+            .open_offline(&test_db(), OpenAccess::Read)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(block_reads(&mock), 5);
+        let status = tq.offline_status("test.db").await.unwrap();
+        assert_eq!(status.generation, Some(4));
+        assert!(!status.offline);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        // nothing changed since
+        let report = tq.sync_offline_mirror("test.db").await.unwrap();
+        assert_eq!(report, SyncReport::default());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_mirror_not_served() {
+        let mock = MockS3::start();
+        mock.offline(true);
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let path = std::env::temp_dir().join(format!("threeqlite-mirror-{}", uuid::Uuid::new_v4()));
+        let status = tq
+            .enable_offline_mirror("test.db", &path, MirrorPolicy::default())
+            .await
+            .unwrap();
+        assert!(!status.complete);
+        assert!(status.offline);
+        assert!(matches!(
+            tq.open_offline(&test_db(), OpenAccess::Read).await,
+            Err(Error::MirrorUnavailable { .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1035 @@
+//! A minimal in-memory S3 endpoint for tests.
+//!
+//! Speaks just enough path-style HTTP/1.1 for the requests this crate sends: `GET` (optionally
+//! ranged and with `If-Match`), `HEAD`, `PUT` (optionally with `If-None-Match: *` or `If-Match`,
+//! or at an offset with `x-amz-write-offset-bytes`) and `DELETE` (optionally with `If-Match`),
+//! keeping user metadata (`x-amz-meta-*`) and MD5 ETags, checking and returning SHA-256 and
+//! CRC32C checksums sent along, plus `GET ?lifecycle`,
+//! `GET ?object-lock` and `ListObjectsV2` on the bucket, and legal holds set by `PUT` and read by
+//! `GET ?legal-hold`. `CopyObject` and multipart uploads of copied parts are served
+//! without data passing through the client.
+//! Requests of a method or of keys with a prefix can be rejected with a fixed status to simulate
+//! missing permissions or outages, or delayed to simulate latency. Ranged GETs can also be throttled or stalled per
+//! range. Response bodies can share a link of limited bandwidth, or trickle in small pieces after
+//! the headers. Taking the endpoint offline simulates a network partition. Requests signed with
+//! expired credentials are rejected with `400 ExpiredToken`. Buckets can be placed in a region,
+//! answering requests signed for another one with `301 PermanentRedirect`, and `HeadBucket` and
+//! `GetBucketLocation` with the region.
+//! Every request answered is accounted for as an [Exchange], from which [crate::replay::critical_path] derives
+//! the round trips that had to happen one after the other.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+
+use crate::{replay::Exchange, verify::ChecksumAlgorithm};
+
+#[derive(Default)]
+struct State {
+    objects: HashMap<String, Vec<u8>>,
+    user_metadata: HashMap<String, HashMap<String, String>>,
+    lifecycle: Option<String>,
+    object_lock: bool,
+    /// Keys with a legal hold, set with `x-amz-object-lock-legal-hold: ON`.
+    legal_holds: HashSet<String>,
+    etags: HashMap<String, String>,
+    wrong_etag: bool,
+    /// Acknowledge PUTs without the checksums sent along.
+    omit_checksums: bool,
+    /// Keys whose next PUT has a byte of its body flipped on the way.
+    corrupt_puts: HashSet<String>,
+    /// The regions of buckets, us-east-1 for any other.
+    regions: HashMap<String, String>,
+    /// Leave `x-amz-bucket-region` out of responses, as some stores do.
+    hide_bucket_region: bool,
+    offline: bool,
+    truncate_puts: Option<usize>,
+    rejections: HashMap<String, u16>,
+    /// Statuses that requests of keys starting with a prefix are answered with.
+    prefix_rejections: Vec<(String, u16)>,
+    /// Access key IDs rejected as expired.
+    expired: Vec<String>,
+    delays: HashMap<String, Duration>,
+    /// Remaining `503 SlowDown` answers per `Range` header.
+    throttled: HashMap<String, usize>,
+    /// Delay and remaining count per `Range` header.
+    stalls: HashMap<String, (Duration, usize)>,
+    /// Bytes per second of the link all response bodies are sent over, one after the other.
+    bandwidth: Option<u64>,
+    /// When the link is done sending the bodies queued so far.
+    link_free: Option<Instant>,
+    /// Send bodies in pieces of this many bytes, pausing between them.
+    trickle: Option<(usize, Duration)>,
+    requests: Vec<(String, String)>,
+    ranges: Vec<String>,
+    lists: Vec<String>,
+    /// Source and destination of every copy, of whole objects or parts.
+    copies: Vec<(String, String)>,
+    /// Copies to serve before failing the rest with `500 InternalError`.
+    copies_left: Option<usize>,
+    /// Method and key of requests to serve without answering, once each.
+    lost: Vec<(String, String)>,
+    exchanges: Vec<Exchange>,
+    /// Key, user metadata and parts of each multipart upload in progress.
+    uploads: HashMap<String, Upload>,
+    next_upload: u64,
+}
+
+type Upload = (String, HashMap<String, String>, BTreeMap<u32, Vec<u8>>);
+
+pub struct MockS3 {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+struct Request {
+    method: String,
+    bucket: String,
+    key: String,
+    query: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    extra_headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            extra_headers: vec![],
+            body: vec![],
+        }
+    }
+
+    fn error(status: u16, code: &str) -> Self {
+        let mut res = Self::new(status);
+        res.headers
+            .push(("content-type", "application/xml".to_owned()));
+        res.body = format!("<Error><Code>{code}</Code><Message>{code}</Message></Error>").into();
+        res
+    }
+}
+
+impl MockS3 {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let accept_state = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { return };
+                let state = accept_state.clone();
+                std::thread::spawn(move || serve(stream, state));
+            }
+        });
+
+        Self { addr, state }
+    }
+
+    /// The URL of this endpoint, for clients of other processes.
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// A client talking to this endpoint, with retries disabled.
+    pub fn client(&self) -> aws_sdk_s3::Client {
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url(self.endpoint())
+            .force_path_style(true)
+            .credentials_provider(Credentials::new("test", "test", None, None, "mock"))
+            .retry_config(aws_sdk_s3::config::retry::RetryConfig::disabled())
+            .build();
+        aws_sdk_s3::Client::from_conf(config)
+    }
+
+    /// A client like [MockS3::client] that retries each request up to `max_attempts` times.
+    pub fn retrying_client(&self, max_attempts: u32) -> aws_sdk_s3::Client {
+        let config = self
+            .client()
+            .config()
+            .to_builder()
+            .retry_config(
+                aws_sdk_s3::config::retry::RetryConfig::standard()
+                    .with_max_attempts(max_attempts)
+                    .with_initial_backoff(Duration::from_millis(1)),
+            )
+            .build();
+        aws_sdk_s3::Client::from_conf(config)
+    }
+
+    /// Serve the next `method` request of `key`, then close the connection without answering, as
+    /// if the response was lost.
+    pub fn lose_response(&self, method: &str, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.lost.push((method.to_owned(), key.to_owned()));
+    }
+
+    /// Answer every request of `method` with `status`.
+    pub fn reject(&self, method: &str, status: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.rejections.insert(method.to_owned(), status);
+    }
+
+    /// Answer every request of a key starting with `prefix` with `status`, whatever its method.
+    pub fn reject_prefix(&self, prefix: &str, status: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.prefix_rejections.push((prefix.to_owned(), status));
+    }
+
+    /// Serve requests of `method` again after [Self::reject].
+    pub fn restore(&self, method: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.rejections.remove(method);
+    }
+
+    /// Reject every request signed with `access_key_id` as `400 ExpiredToken`.
+    pub fn expire_credentials(&self, access_key_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.expired.push(access_key_id.to_owned());
+    }
+
+    /// Delay the response to every request of `method` by `delay`.
+    pub fn delay(&self, method: &str, delay: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.delays.insert(method.to_owned(), delay);
+    }
+
+    /// Answer the next `times` GETs with this `Range` header with `503 SlowDown`.
+    pub fn throttle_range(&self, range: &str, times: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.throttled.insert(range.to_owned(), times);
+    }
+
+    /// Delay the responses to the next `times` GETs with this `Range` header by `delay`.
+    pub fn stall_range(&self, range: &str, delay: Duration, times: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.stalls.insert(range.to_owned(), (delay, times));
+    }
+
+    /// Send response bodies over a shared link of `bytes_per_sec`, queueing each body behind the
+    /// ones still being sent.
+    pub fn limit_bandwidth(&self, bytes_per_sec: u64) {
+        self.state.lock().unwrap().bandwidth = Some(bytes_per_sec);
+    }
+
+    /// Send the headers of every response at once, and its body in pieces of `piece` bytes with
+    /// `pause` in between.
+    pub fn trickle(&self, piece: usize, pause: Duration) {
+        self.state.lock().unwrap().trickle = Some((piece.max(1), pause));
+    }
+
+    pub fn put(&self, key: &str, data: impl Into<Vec<u8>>) {
+        let data = data.into();
+        let mut state = self.state.lock().unwrap();
+        state
+            .etags
+            .insert(key.to_owned(), format!("\"{:x}\"", md5::compute(&data)));
+        state.objects.insert(key.to_owned(), data);
+    }
+
+    pub fn delete(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.objects.remove(key);
+        state.user_metadata.remove(key);
+        state.etags.remove(key);
+    }
+
+    /// The user metadata of `key`, without the `x-amz-meta-` prefix.
+    pub fn user_metadata(&self, key: &str) -> HashMap<String, String> {
+        let state = self.state.lock().unwrap();
+        state.user_metadata.get(key).cloned().unwrap_or_default()
+    }
+
+    /// Drop every connection without answering, as if the endpoint were unreachable.
+    pub fn offline(&self, offline: bool) {
+        self.state.lock().unwrap().offline = offline;
+    }
+
+    /// Acknowledge PUTs with an ETag that doesn't match the body.
+    pub fn wrong_etag(&self, wrong: bool) {
+        self.state.lock().unwrap().wrong_etag = wrong;
+    }
+
+    /// Acknowledge PUTs without the checksums they were sent with, as stores that don't support
+    /// them do.
+    pub fn omit_checksums(&self, omit: bool) {
+        self.state.lock().unwrap().omit_checksums = omit;
+    }
+
+    /// Place `bucket` in `region`: requests signed for another region are redirected.
+    pub fn require_region(&self, bucket: &str, region: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.regions.insert(bucket.to_owned(), region.to_owned());
+    }
+
+    /// Leave `x-amz-bucket-region` out of responses, so that the region can only be found with
+    /// `GetBucketLocation`.
+    pub fn hide_bucket_region(&self, hide: bool) {
+        self.state.lock().unwrap().hide_bucket_region = hide;
+    }
+
+    /// Flip the first byte of the body of the next PUT of `key` on its way, as a misbehaving
+    /// proxy would, rewriting its checksums to match. The PUT is acknowledged as usual.
+    pub fn corrupt_next_put(&self, key: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .corrupt_puts
+            .insert(key.to_owned());
+    }
+
+    /// Store only the first `len` bytes of every PUT, while acknowledging the full body.
+    pub fn truncate_puts(&self, len: Option<usize>) {
+        self.state.lock().unwrap().truncate_puts = len;
+    }
+
+    /// Answer `GET ?lifecycle` with the given `<LifecycleConfiguration>` document.
+    pub fn set_lifecycle(&self, xml: &str) {
+        self.state.lock().unwrap().lifecycle = Some(xml.to_owned());
+    }
+
+    /// Answer `GET ?object-lock` with Object Lock enabled, rather than not configured.
+    pub fn set_object_lock(&self, enabled: bool) {
+        self.state.lock().unwrap().object_lock = enabled;
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().objects.get(key).cloned()
+    }
+
+    /// The method and key of every request received so far.
+    pub fn requests(&self) -> Vec<(String, String)> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Every request answered so far, see [crate::replay::critical_path].
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.state.lock().unwrap().exchanges.clone()
+    }
+
+    /// The prefix of every `ListObjectsV2` received so far.
+    pub fn lists(&self) -> Vec<String> {
+        self.state.lock().unwrap().lists.clone()
+    }
+
+    /// The `Range` header of every ranged GET received so far.
+    pub fn ranges(&self) -> Vec<String> {
+        self.state.lock().unwrap().ranges.clone()
+    }
+
+    /// Source and destination of every copy received so far.
+    pub fn copies(&self) -> Vec<(String, String)> {
+        self.state.lock().unwrap().copies.clone()
+    }
+
+    /// The requests received so far as [CostEstimate](crate::cost::CostEstimate) counts them:
+    /// reads, writes other than copies, and copies.
+    pub fn request_classes(&self) -> [u64; 3] {
+        let state = self.state.lock().unwrap();
+        let reads = state.requests.iter();
+        let reads = reads.filter(|(method, _)| method == "GET" || method == "HEAD");
+        let (reads, copies) = (reads.count(), state.copies.len());
+        [reads, state.requests.len() - reads - copies, copies].map(|n| n as u64)
+    }
+
+    /// Serve `n` more copies and fail the rest, simulating a crash of the client, or serve all
+    /// copies again.
+    pub fn fail_copies_after(&self, n: Option<usize>) {
+        self.state.lock().unwrap().copies_left = n;
+    }
+}
+
+/// The value of `name` in a query string, percent-decoded.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))?;
+    percent_decode(value, true)
+}
+
+/// `value` percent-decoded, with `+` standing for a space in query strings only.
+fn percent_decode(value: &str, plus_is_space: bool) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' && tail.len() >= 2 {
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&tail[..2]).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(if plus_is_space && b == b'+' { b' ' } else { b });
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn xml(root: &str, fields: &[(&str, &str)]) -> Response {
+    let mut body = format!("<{root}>");
+    for (name, value) in fields {
+        body += &format!("<{name}>{}</{name}>", escape_xml(value));
+    }
+    body += &format!("</{root}>");
+    let mut res = Response::new(200);
+    res.headers
+        .push(("content-type", "application/xml".to_owned()));
+    res.body = body.into();
+    res
+}
+
+const LAST_MODIFIED: &str = "2024-01-01T00:00:00.000Z";
+
+/// `CopyObject`, or `UploadPartCopy` given `partNumber` and `uploadId`, from `source`, a
+/// percent-encoded `<bucket>/<key>`.
+fn copy(req: &Request, state: &mut State, source: &str) -> Response {
+    let source = percent_decode(source.trim_start_matches('/'), false)
+        .and_then(|source| Some(source.split_once('/')?.1.to_owned()))
+        .unwrap_or_default();
+    if let Some(left) = &mut state.copies_left {
+        if *left == 0 {
+            return Response::error(500, "InternalError");
+        }
+        *left -= 1;
+    }
+    let Some(data) = state.objects.get(&source).cloned() else {
+        return Response::error(404, "NoSuchKey");
+    };
+    state.copies.push((source.clone(), req.key.clone()));
+
+    let Some(upload) = query_param(&req.query, "uploadId") else {
+        let etag = state.etags[&source].clone();
+        let user_metadata = state
+            .user_metadata
+            .get(&source)
+            .cloned()
+            .unwrap_or_default();
+        state.objects.insert(req.key.clone(), data);
+        state.etags.insert(req.key.clone(), etag.clone());
+        state.user_metadata.insert(req.key.clone(), user_metadata);
+        return xml(
+            "CopyObjectResult",
+            &[("ETag", &etag), ("LastModified", LAST_MODIFIED)],
+        );
+    };
+    let part = query_param(&req.query, "partNumber").and_then(|n| n.parse().ok());
+    let range = req
+        .headers
+        .get("x-amz-copy-source-range")
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, end)| Some(start.parse::<usize>().ok()?..=end.parse().ok()?))
+        .unwrap_or(0..=data.len().saturating_sub(1));
+    let (Some(part), Some((_, _, parts))) = (part, state.uploads.get_mut(&upload)) else {
+        return Response::error(404, "NoSuchUpload");
+    };
+    let Some(data) = data.get(range) else {
+        return Response::error(416, "InvalidRange");
+    };
+    let etag = format!("\"{:x}\"", md5::compute(data));
+    parts.insert(part, data.to_vec());
+    xml(
+        "CopyPartResult",
+        &[("ETag", &etag), ("LastModified", LAST_MODIFIED)],
+    )
+}
+
+/// `CreateMultipartUpload` and `CompleteMultipartUpload`, completing with the parts in order.
+fn multipart(req: &Request, state: &mut State) -> Response {
+    if req
+        .query
+        .split('&')
+        .any(|param| param == "uploads" || param == "uploads=")
+    {
+        state.next_upload += 1;
+        let upload = format!("upload-{}", state.next_upload);
+        let user_metadata = req
+            .headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((name.strip_prefix("x-amz-meta-")?.to_owned(), value.clone()))
+            })
+            .collect();
+        state.uploads.insert(
+            upload.clone(),
+            (req.key.clone(), user_metadata, BTreeMap::new()),
+        );
+        return xml(
+            "InitiateMultipartUploadResult",
+            &[
+                ("Bucket", "threeqlite"),
+                ("Key", &req.key),
+                ("UploadId", &upload),
+            ],
+        );
+    }
+    let Some((key, user_metadata, parts)) =
+        query_param(&req.query, "uploadId").and_then(|upload| state.uploads.remove(&upload))
+    else {
+        return Response::error(404, "NoSuchUpload");
+    };
+    let digests: Vec<u8> = parts
+        .values()
+        .flat_map(|part| md5::compute(part).0)
+        .collect();
+    let etag = format!("\"{:x}-{}\"", md5::compute(digests), parts.len());
+    state
+        .objects
+        .insert(key.clone(), parts.into_values().flatten().collect());
+    state.etags.insert(key.clone(), etag.clone());
+    state.user_metadata.insert(key.clone(), user_metadata);
+    xml(
+        "CompleteMultipartUploadResult",
+        &[("Bucket", "threeqlite"), ("Key", &key), ("ETag", &etag)],
+    )
+}
+
+/// `ListObjectsV2`, using the last key of a page as the continuation token.
+fn list(req: &Request, state: &mut State) -> Response {
+    let prefix = query_param(&req.query, "prefix").unwrap_or_default();
+    state.lists.push(prefix.clone());
+    let after = query_param(&req.query, "continuation-token")
+        .or_else(|| query_param(&req.query, "start-after"))
+        .unwrap_or_default();
+    let max_keys = query_param(&req.query, "max-keys")
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(1000);
+
+    let mut keys: Vec<_> = state
+        .objects
+        .keys()
+        .filter(|key| key.starts_with(&prefix) && **key > after)
+        .collect();
+    keys.sort();
+    let truncated = keys.len() > max_keys;
+    keys.truncate(max_keys);
+
+    let mut xml = format!(
+        "<ListBucketResult><Name>threeqlite</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount>\
+         <MaxKeys>{max_keys}</MaxKeys><IsTruncated>{truncated}</IsTruncated>",
+        escape_xml(&prefix),
+        keys.len()
+    );
+    if let (true, Some(last)) = (truncated, keys.last()) {
+        xml += &format!(
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            escape_xml(last)
+        );
+    }
+    for key in keys {
+        xml += &format!(
+            "<Contents><Key>{}</Key><Size>{}</Size>\
+             <LastModified>2024-01-01T00:00:00.000Z</LastModified></Contents>",
+            escape_xml(key),
+            state.objects[key].len()
+        );
+    }
+    xml += "</ListBucketResult>";
+
+    let mut res = Response::new(200);
+    res.headers
+        .push(("content-type", "application/xml".to_owned()));
+    res.body = xml.into();
+    res
+}
+
+/// A database file of `pages` empty pages with a valid header.
+pub fn database(page_size: u32, pages: u32, change_counter: u32) -> Vec<u8> {
+    let mut db = vec![0; (page_size * pages) as usize];
+    db[..16].copy_from_slice(b"SQLite format 3\0");
+    db[16..18].copy_from_slice(&(page_size as u16).to_be_bytes());
+    db[24..28].copy_from_slice(&change_counter.to_be_bytes());
+    db[28..32].copy_from_slice(&pages.to_be_bytes());
+    db[92..96].copy_from_slice(&change_counter.to_be_bytes());
+    db
+}
+
+fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    while let Some(req) = read_request(&mut reader) {
+        let received = Instant::now();
+        if state.lock().unwrap().offline {
+            return;
+        }
+        let (res, delay) = {
+            let mut state = state.lock().unwrap();
+            let stall = req
+                .headers
+                .get("range")
+                .and_then(|range| state.stalls.get_mut(range))
+                .filter(|(_, times)| *times > 0)
+                .map(|(delay, times)| {
+                    *times -= 1;
+                    *delay
+                });
+            let res = handle(&req, &mut state);
+            let lost = state
+                .lost
+                .iter()
+                .position(|(method, key)| *method == req.method && *key == req.key);
+            if let Some(at) = lost {
+                state.lost.remove(at);
+                return;
+            }
+            (
+                res,
+                stall.or_else(|| state.delays.get(&req.method).copied()),
+            )
+        };
+        if let Some(delay) = delay {
+            std::thread::sleep(delay);
+        }
+        let sent = {
+            let mut state = state.lock().unwrap();
+            match state.bandwidth {
+                Some(bandwidth) if req.method != "HEAD" && !res.body.is_empty() => {
+                    let now = Instant::now();
+                    let start = state.link_free.map_or(now, |free| free.max(now));
+                    let sent =
+                        start + Duration::from_secs_f64(res.body.len() as f64 / bandwidth as f64);
+                    state.link_free = Some(sent);
+                    Some(sent)
+                }
+                _ => None,
+            }
+        };
+        if let Some(sent) = sent {
+            std::thread::sleep(sent.saturating_duration_since(Instant::now()));
+        }
+        let reason = match res.status {
+            200 => "OK",
+            204 => "No Content",
+            206 => "Partial Content",
+            _ => "Error",
+        };
+        let mut head = format!("HTTP/1.1 {} {reason}\r\n", res.status);
+        let len = match req.method.as_str() {
+            "HEAD" => res
+                .headers
+                .iter()
+                .find(|(name, _)| *name == "content-length")
+                .map(|(_, len)| len.clone())
+                .unwrap_or_else(|| "0".to_owned()),
+            _ => res.body.len().to_string(),
+        };
+        head += &format!("content-length: {len}\r\n");
+        for (name, value) in &res.headers {
+            if *name != "content-length" {
+                head += &format!("{name}: {value}\r\n");
+            }
+        }
+        for (name, value) in &res.extra_headers {
+            head += &format!("{name}: {value}\r\n");
+        }
+        head += "\r\n";
+        let mut bytes = head.into_bytes();
+        // accounted for before the client can act on the response
+        let bytes_out = match req.method.as_str() {
+            "HEAD" => 0,
+            _ => res.body.len(),
+        };
+        state.lock().unwrap().exchanges.push(Exchange {
+            method: req.method.clone(),
+            received,
+            answered: Instant::now(),
+            bytes_in: req.body.len(),
+            bytes_out,
+        });
+        let trickle = state.lock().unwrap().trickle;
+        match trickle {
+            Some((piece, pause)) if req.method != "HEAD" => {
+                if writer.write_all(&bytes).is_err() {
+                    return;
+                }
+                for piece in res.body.chunks(piece) {
+                    std::thread::sleep(pause);
+                    if writer.write_all(piece).is_err() {
+                        return;
+                    }
+                }
+            }
+            _ => {
+                if req.method != "HEAD" {
+                    bytes.extend(&res.body);
+                }
+                if writer.write_all(&bytes).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let target = parts.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    // path-style: /<bucket>/<key>
+    let path = path.trim_start_matches('/');
+    let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+    let bucket = bucket.to_owned();
+    let key = percent_decode(key, false).unwrap_or_default();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.to_ascii_lowercase(), value.trim().to_owned());
+    }
+
+    let mut body = if headers
+        .get("transfer-encoding")
+        .is_some_and(|te| te.contains("chunked"))
+    {
+        read_chunked(reader)?
+    } else {
+        let len = headers
+            .get("content-length")
+            .and_then(|len| len.parse().ok())
+            .unwrap_or(0);
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).ok()?;
+        body
+    };
+    if headers
+        .get("content-encoding")
+        .is_some_and(|ce| ce.contains("aws-chunked"))
+    {
+        body = read_chunked(&mut body.as_slice())?;
+    }
+
+    Some(Request {
+        method,
+        bucket,
+        key,
+        query: query.to_owned(),
+        headers,
+        body,
+    })
+}
+
+/// Decode a chunked body, discarding chunk extensions and trailers.
+fn read_chunked(reader: &mut impl BufRead) -> Option<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let size = line.trim_end().split(';').next()?;
+        let size = usize::from_str_radix(size, 16).ok()?;
+        if size == 0 {
+            // trailers
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).ok()?;
+                if line.trim_end().is_empty() {
+                    return Some(body);
+                }
+            }
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).ok()?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).ok()?;
+    }
+}
+
+fn handle(req: &Request, state: &mut State) -> Response {
+    state.requests.push((req.method.clone(), req.key.clone()));
+    let signed_with = req
+        .headers
+        .get("authorization")
+        .and_then(|auth| auth.split_once("Credential=")?.1.split_once('/'))
+        .map(|(access_key_id, _)| access_key_id);
+    if signed_with.is_some_and(|id| state.expired.iter().any(|expired| expired == id)) {
+        return Response::error(400, "ExpiredToken");
+    }
+    // Credential=<access key ID>/<date>/<region>/s3/aws4_request
+    let signed_for = req
+        .headers
+        .get("authorization")
+        .and_then(|auth| auth.split_once("Credential=")?.1.split('/').nth(2));
+    let region = state
+        .regions
+        .get(&req.bucket)
+        .map_or("us-east-1", String::as_str)
+        .to_owned();
+    let region_header = |mut res: Response| {
+        if !state.hide_bucket_region {
+            res.extra_headers
+                .push(("x-amz-bucket-region".to_owned(), region.clone()));
+        }
+        res
+    };
+    // answered in any region
+    if req.key.is_empty() && req.query.split('&').any(|param| param == "location") {
+        let mut res = Response::new(200);
+        res.headers
+            .push(("content-type", "application/xml".to_owned()));
+        res.body = format!("<LocationConstraint>{region}</LocationConstraint>").into();
+        return res;
+    }
+    if signed_for.is_some_and(|signed_for| signed_for != region) {
+        return region_header(match req.method.as_str() {
+            "HEAD" => Response::new(301),
+            _ => Response::error(301, "PermanentRedirect"),
+        });
+    }
+    if req.method == "HEAD" && req.key.is_empty() {
+        return region_header(Response::new(200));
+    }
+    if let (true, Some(range)) = (req.method == "GET", req.headers.get("range")) {
+        state.ranges.push(range.clone());
+        if let Some(times) = state.throttled.get_mut(range).filter(|times| **times > 0) {
+            *times -= 1;
+            return Response::error(503, "SlowDown");
+        }
+    }
+    let by_prefix = state
+        .prefix_rejections
+        .iter()
+        .find(|(prefix, _)| req.key.starts_with(prefix.as_str()))
+        .map(|(_, status)| status);
+    if let Some(status) = state.rejections.get(&req.method).or(by_prefix) {
+        let code = match status {
+            403 => "AccessDenied",
+            503 => "SlowDown",
+            _ => "InternalError",
+        };
+        return Response::error(*status, code);
+    }
+
+    if req.key.is_empty() && req.query.split('&').any(|param| param == "lifecycle") {
+        return match &state.lifecycle {
+            Some(xml) => {
+                let mut res = Response::new(200);
+                res.headers
+                    .push(("content-type", "application/xml".to_owned()));
+                res.body = xml.clone().into();
+                res
+            }
+            None => Response::error(404, "NoSuchLifecycleConfiguration"),
+        };
+    }
+    if req.key.is_empty() && req.query.split('&').any(|param| param == "object-lock") {
+        if !state.object_lock {
+            return Response::error(404, "ObjectLockConfigurationNotFoundError");
+        }
+        let mut res = Response::new(200);
+        res.headers
+            .push(("content-type", "application/xml".to_owned()));
+        res.body = "<ObjectLockConfiguration><ObjectLockEnabled>Enabled</ObjectLockEnabled>\
+                    </ObjectLockConfiguration>"
+            .into();
+        return res;
+    }
+
+    if req.key.is_empty() && query_param(&req.query, "list-type").as_deref() == Some("2") {
+        return list(req, state);
+    }
+    if let (true, Some(source)) = (req.method == "PUT", req.headers.get("x-amz-copy-source")) {
+        return copy(req, state, source);
+    }
+    if req.method == "POST" {
+        return multipart(req, state);
+    }
+
+    match req.method.as_str() {
+        "GET" | "HEAD" => {
+            let Some(data) = state.objects.get(&req.key) else {
+                return Response::error(404, "NoSuchKey");
+            };
+            let hold = match state.legal_holds.contains(&req.key) {
+                true => "ON",
+                false => "OFF",
+            };
+            if req.query.split('&').any(|param| param == "legal-hold") {
+                let mut res = Response::new(200);
+                res.headers
+                    .push(("content-type", "application/xml".to_owned()));
+                res.body = format!("<LegalHold><Status>{hold}</Status></LegalHold>").into();
+                return res;
+            }
+            if req
+                .headers
+                .get("if-match")
+                .is_some_and(|etag| state.etags.get(&req.key) != Some(etag))
+            {
+                return Response::error(412, "PreconditionFailed");
+            }
+            let user_metadata = state
+                .user_metadata
+                .get(&req.key)
+                .into_iter()
+                .flatten()
+                .map(|(name, value)| (format!("x-amz-meta-{name}"), value.clone()))
+                .chain(
+                    state
+                        .etags
+                        .get(&req.key)
+                        .map(|etag| ("etag".to_owned(), etag.clone())),
+                )
+                .chain([("x-amz-object-lock-legal-hold".to_owned(), hold.to_owned())]);
+            let range = req
+                .headers
+                .get("range")
+                .and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.split_once('-'))
+                .and_then(|(start, end)| Some((start.parse::<usize>().ok()?, end.parse().ok())));
+            match range {
+                Some((start, end)) => {
+                    if start >= data.len() {
+                        return Response::error(416, "InvalidRange");
+                    }
+                    let end = end.unwrap_or(usize::MAX).min(data.len() - 1);
+                    let mut res = Response::new(206);
+                    res.headers.push((
+                        "content-range",
+                        format!("bytes {start}-{end}/{}", data.len()),
+                    ));
+                    res.headers
+                        .push(("content-length", (end + 1 - start).to_string()));
+                    res.body = data[start..=end].to_vec();
+                    res.extra_headers.extend(user_metadata);
+                    res
+                }
+                None => {
+                    let mut res = Response::new(200);
+                    res.headers.push(("content-length", data.len().to_string()));
+                    res.body = data.clone();
+                    res.extra_headers.extend(user_metadata);
+                    res
+                }
+            }
+        }
+        "PUT" => {
+            if req.headers.get("if-none-match").is_some_and(|v| v == "*")
+                && state.objects.contains_key(&req.key)
+            {
+                return Response::error(412, "PreconditionFailed");
+            }
+            if req
+                .headers
+                .get("if-match")
+                .is_some_and(|etag| state.etags.get(&req.key) != Some(etag))
+            {
+                return Response::error(412, "PreconditionFailed");
+            }
+            let mut body = req.body.clone();
+            let corrupt = state.corrupt_puts.remove(&req.key);
+            if let (true, Some(first)) = (corrupt, body.first_mut()) {
+                *first ^= 0xff;
+            }
+            let mut checksums = vec![];
+            for (header, algorithm) in [
+                ("x-amz-checksum-sha256", ChecksumAlgorithm::Sha256),
+                ("x-amz-checksum-crc32c", ChecksumAlgorithm::Crc32c),
+            ] {
+                let Some(sent) = req.headers.get(header) else {
+                    continue;
+                };
+                let checksum = algorithm.compute(&body);
+                if !corrupt && *sent != checksum {
+                    return Response::error(400, "BadDigest");
+                }
+                checksums.push((header.to_owned(), checksum));
+            }
+            let mut etag = format!("\"{:x}\"", md5::compute(&body));
+            if let Some(len) = state.truncate_puts {
+                body.truncate(len);
+            }
+            let offset = req.headers.get("x-amz-write-offset-bytes");
+            if let Some(offset) = offset.and_then(|offset| offset.parse::<usize>().ok()) {
+                let mut data = state.objects.get(&req.key).cloned().unwrap_or_default();
+                let end = offset + body.len();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[offset..end].copy_from_slice(&body);
+                etag = format!("\"{:x}\"", md5::compute(&data));
+                body = data;
+            }
+            state.objects.insert(req.key.clone(), body);
+            state.etags.insert(req.key.clone(), etag.clone());
+            match req
+                .headers
+                .get("x-amz-object-lock-legal-hold")
+                .map(String::as_str)
+            {
+                Some("ON") => state.legal_holds.insert(req.key.clone()),
+                Some(_) => state.legal_holds.remove(&req.key),
+                None => false,
+            };
+            let user_metadata = req
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.strip_prefix("x-amz-meta-")?.to_owned(), value.clone()))
+                })
+                .collect();
+            state.user_metadata.insert(req.key.clone(), user_metadata);
+            let mut res = Response::new(200);
+            let etag = match state.wrong_etag {
+                true => format!("\"{:x}\"", md5::compute(b"something else")),
+                false => etag,
+            };
+            res.extra_headers.push(("etag".to_owned(), etag));
+            if !state.omit_checksums {
+                res.extra_headers.extend(checksums);
+            }
+            res
+        }
+        "DELETE" if query_param(&req.query, "uploadId").is_some() => {
+            state
+                .uploads
+                .remove(&query_param(&req.query, "uploadId").unwrap());
+            Response::new(204)
+        }
+        "DELETE" => {
+            if req
+                .headers
+                .get("if-match")
+                .is_some_and(|etag| state.etags.get(&req.key) != Some(etag))
+            {
+                return Response::error(412, "PreconditionFailed");
+            }
+            state.objects.remove(&req.key);
+            state.user_metadata.remove(&req.key);
+            state.etags.remove(&req.key);
+            Response::new(204)
+        }
+        _ => Response::error(405, "MethodNotAllowed"),
+    }
+}
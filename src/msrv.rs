@@ -0,0 +1,272 @@
+//! The minimum supported Rust version (MSRV), by feature.
+//!
+//! Without any feature, the crate needs Rust 1.83, e.g. for `btree_map::Entry::insert_entry` in
+//! the page cache: the `rust-version` of its manifest, which clippy's `incompatible_msrv` lint
+//! checks the code of the crate against. [sqlite_vfs] needs 1.77, for C string literals and async
+//! functions in traits. Features pull in
+//! dependencies with minimum versions of their own, which raise it as listed in [FEATURES] for the
+//! versions in `Cargo.lock`; a feature implying one of them, e.g. `asyncdb` implying `s3`, raises
+//! it as well. The build script checks the compiler against what the enabled features need, and
+//! fails the build with a single message naming the version instead of an error deep in the
+//! dependency tree.
+//!
+//! A test compares the table with the `rust-version` the dependencies of each feature declare, so
+//! that a dependency bump raising the version fails it rather than the builds of downstream users.
+//! With `THREEQLITE_MSRV_CHECK=1`, another test runs `scripts/check-msrv.sh` for the main
+//! combinations of features, which checks the crate with the toolchain of the version the table
+//! documents for them.
+//!
+//! This file is also the `msrv` module of the build script, so it only uses `std`.
+
+use std::fmt::Display;
+
+/// A Rust version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl Version {
+    /// Parse a version like `1.77` or `1.77.2`.
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.trim().split('.').map(|part| part.parse().ok());
+        let major = parts.next()??;
+        let minor = parts.next()??;
+        let patch = parts.next().unwrap_or(Some(0))?;
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Self(major, minor, patch)),
+        }
+    }
+
+    /// The version `rustc --version` printed, like `rustc 1.77.2 (25ef9e3d8 2024-04-09)` or
+    /// `rustc 1.80.0-nightly (...)`.
+    pub fn of_rustc(output: &str) -> Option<Self> {
+        let version = output.strip_prefix("rustc ")?.split_whitespace().next()?;
+        Self::parse(version.split('-').next()?)
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.2 {
+            0 => write!(f, "{}.{}", self.0, self.1),
+            patch => write!(f, "{}.{}.{}", self.0, self.1, patch),
+        }
+    }
+}
+
+/// The version the crate needs without any feature.
+pub const CORE: Version = Version(1, 83, 0);
+
+/// The features raising the version the crate needs, to which version, and what raises it.
+pub const FEATURES: &[(&str, Version, &str)] = &[(
+    "s3",
+    Version(1, 94, 1),
+    "the AWS SDK (aws-smithy-types, aws-smithy-async)",
+)];
+
+/// The version needed with the features `enabled` says are enabled, and the feature raising it
+/// the most, if any.
+pub fn required(enabled: impl Fn(&str) -> bool) -> (Version, Option<&'static str>) {
+    let mut required = (CORE, None);
+    for &(feature, version, _) in FEATURES {
+        if enabled(feature) && version > required.0 {
+            required = (version, Some(feature));
+        }
+    }
+    required
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Version::parse("1.77"), Some(Version(1, 77, 0)));
+        assert_eq!(Version::parse("1.94.1"), Some(Version(1, 94, 1)));
+        assert_eq!(Version::parse("1"), None);
+        assert_eq!(Version::parse("1.77.0.1"), None);
+        assert_eq!(Version::parse("1.x"), None);
+        assert_eq!(
+            Version::of_rustc("rustc 1.77.2 (25ef9e3d8 2024-04-09)"),
+            Some(Version(1, 77, 2))
+        );
+        assert_eq!(
+            Version::of_rustc("rustc 1.80.0-nightly (bdbbb6c6a 2024-05-26)"),
+            Some(Version(1, 80, 0))
+        );
+        assert_eq!(Version::of_rustc("cargo 1.77.2"), None);
+        assert_eq!(Version(1, 77, 0).to_string(), "1.77");
+        assert_eq!(Version(1, 94, 1).to_string(), "1.94.1");
+    }
+
+    #[test]
+    fn test_required() {
+        assert_eq!(required(|_| false), (CORE, None));
+        assert_eq!(required(|feature| feature == "rusqlite"), (CORE, None));
+        assert_eq!(
+            required(|feature| feature == "s3"),
+            (Version(1, 94, 1), Some("s3"))
+        );
+        assert_eq!(CORE.to_string(), env!("CARGO_PKG_RUST_VERSION"));
+    }
+
+    #[cfg(feature = "s3")]
+    mod manifest {
+        use std::{
+            collections::{BTreeMap, BTreeSet, HashMap},
+            process::Command,
+        };
+
+        use super::super::*;
+
+        /// What `cargo metadata` says about the packages built with `args`.
+        fn metadata(args: &[&str]) -> serde_json::Value {
+            let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+            let host = Command::new(rustc).arg("-vV").output().unwrap();
+            let host = String::from_utf8(host.stdout).unwrap();
+            let host = host
+                .lines()
+                .find_map(|line| line.strip_prefix("host: "))
+                .unwrap();
+            let output = Command::new(env!("CARGO"))
+                .args(["metadata", "--format-version", "1", "--locked"])
+                .args(["--filter-platform", host])
+                .args(args)
+                .current_dir(env!("CARGO_MANIFEST_DIR"))
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            serde_json::from_slice(&output.stdout).unwrap()
+        }
+
+        fn package<'a>(metadata: &'a serde_json::Value, name: &str) -> &'a serde_json::Value {
+            let packages = metadata["packages"].as_array().unwrap();
+            packages
+                .iter()
+                .find(|package| package["name"] == name)
+                .unwrap()
+        }
+
+        /// The features of the crate, with those each implies, itself included.
+        fn features() -> BTreeMap<String, BTreeSet<String>> {
+            let metadata = metadata(&[]);
+            let declared = package(&metadata, "threeqlite")["features"]
+                .as_object()
+                .unwrap();
+            let mut features = BTreeMap::new();
+            for feature in declared.keys() {
+                let mut implied = BTreeSet::new();
+                let mut next = vec![feature.as_str()];
+                while let Some(feature) = next.pop() {
+                    if !implied.insert(feature.to_owned()) {
+                        continue;
+                    }
+                    // `dep:` and `crate/feature` enable dependencies rather than features
+                    next.extend(
+                        declared[feature]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .filter_map(|value| value.as_str())
+                            .filter(|value| declared.contains_key(*value)),
+                    );
+                }
+                features.insert(feature.clone(), implied);
+            }
+            features
+        }
+
+        /// The highest `rust-version` declared by the packages `threeqlite` is built from with
+        /// `features`, and the package declaring it.
+        fn dependency_floor(features: &str) -> (Version, String) {
+            let metadata = metadata(&["--no-default-features", "--features", features]);
+            let packages: HashMap<_, _> = metadata["packages"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|package| (package["id"].as_str().unwrap(), package))
+                .collect();
+            let nodes: HashMap<_, _> = metadata["resolve"]["nodes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|node| (node["id"].as_str().unwrap(), node))
+                .collect();
+            let root = package(&metadata, "threeqlite")["id"].as_str().unwrap();
+            let mut floor = (CORE, "threeqlite".to_owned());
+            let (mut seen, mut next) = (BTreeSet::new(), vec![root]);
+            while let Some(id) = next.pop() {
+                if !seen.insert(id) {
+                    continue;
+                }
+                let package = packages[id];
+                let declared = package["rust_version"].as_str().and_then(Version::parse);
+                if let Some(version) = declared.filter(|version| *version > floor.0) {
+                    floor = (version, package["name"].as_str().unwrap().to_owned());
+                }
+                // normal and build dependencies, not dev-dependencies
+                for dep in nodes[id]["deps"].as_array().unwrap() {
+                    let kinds = dep["dep_kinds"].as_array().unwrap();
+                    if kinds.iter().any(|kind| kind["kind"] != "dev") {
+                        next.push(dep["pkg"].as_str().unwrap());
+                    }
+                }
+            }
+            floor
+        }
+
+        #[test]
+        fn test_table_covers_dependencies() {
+            let metadata = metadata(&[]);
+            let vfs = package(&metadata, "sqlite-vfs")["rust_version"].as_str();
+            assert!(vfs.and_then(Version::parse).is_some_and(|vfs| vfs <= CORE));
+
+            let mut features: Vec<_> = features().into_iter().collect();
+            features.push((String::new(), BTreeSet::new()));
+            for (feature, implied) in features {
+                let (documented, _) = required(|feature| implied.contains(feature));
+                let (floor, package) = dependency_floor(&feature);
+                assert!(
+                    floor <= documented,
+                    "with features [{feature}], {package} needs Rust {floor}, but src/msrv.rs \
+                     documents {documented}"
+                );
+            }
+        }
+
+        /// The main combinations of features, built with the toolchain of their documented
+        /// version by `scripts/check-msrv.sh`. Needs `rustup` and a network connection to
+        /// install the toolchains, so it only runs with `THREEQLITE_MSRV_CHECK=1`.
+        #[test]
+        fn test_msrv_toolchains() {
+            if std::env::var_os("THREEQLITE_MSRV_CHECK").is_none() {
+                return;
+            }
+            let features = features();
+            for combination in ["", "rusqlite", "s3", "asyncdb", "cli", "auto-register"] {
+                let implied: BTreeSet<_> = combination
+                    .split(',')
+                    .filter(|feature| !feature.is_empty())
+                    .flat_map(|feature| features[feature].iter().cloned())
+                    .collect();
+                let (version, _) = required(|feature| implied.contains(feature));
+                let status = Command::new(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/scripts/check-msrv.sh"
+                ))
+                .args([version.to_string().as_str(), combination])
+                .status()
+                .unwrap();
+                assert!(
+                    status.success(),
+                    "features [{combination}] don't build with Rust {version}"
+                );
+            }
+        }
+    }
+}
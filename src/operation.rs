@@ -0,0 +1,509 @@
+//! Tracking and cancelling long-running maintenance operations.
+//!
+//! Copies, renames, integrity checks and recovery drills run as an [Operation] registered with the
+//! [Operations] of their instance, which [ThreeQLite::operations] lists, running ones first, along
+//! with the last [RETAINED] to finish. The `start_*` methods spawn an operation and return an
+//! [OperationHandle] to follow and cancel it by; the plain methods run it in the calling task,
+//! registered all the same, so that [ThreeQLite::cancel_operation] reaches maintenance started
+//! anywhere in the process.
+//!
+//! Cancelling is cooperative: an operation checks for it at checkpoints between units of work and
+//! fails with [Error::Cancelled] at the first one after [Operation::cancel]. What it leaves behind
+//! depends on the operation:
+//!
+//! - A copy or rename checkpoints after each object it copied, once its progress record lists it,
+//!   and leaves the same state a crash at that point would: progress records on both names, which
+//!   keep other copies out until it is resumed by calling it again with the same names, see
+//!   [crate::copy]. Once a rename started deleting the source, it runs to the end.
+//! - An integrity check checkpoints between the ranges it fetches, releases its read lock and
+//!   deletes its local snapshot.
+//! - A drill checkpoints between scenarios and deletes its scratch copies, see [crate::drill].
+//!
+//! Each operation meters the requests it sends and the bytes it transfers, and reports them next
+//! to what its plan estimated they would be, if it made one, see [crate::cost].
+//!
+//! [ThreeQLite::operations]: crate::vfs::ThreeQLite::operations
+//! [ThreeQLite::cancel_operation]: crate::vfs::ThreeQLite::cancel_operation
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cost::{self, CostEstimate, Meter},
+    error::Error,
+};
+
+/// How many finished operations [Operations::list] keeps listing.
+pub const RETAINED: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperationKind {
+    Copy,
+    Rename,
+    IntegrityCheck,
+    Drill,
+}
+
+impl OperationKind {
+    pub const ALL: [OperationKind; 4] = [
+        OperationKind::Copy,
+        OperationKind::Rename,
+        OperationKind::IntegrityCheck,
+        OperationKind::Drill,
+    ];
+}
+
+impl std::fmt::Display for OperationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OperationKind::Copy => "copy",
+            OperationKind::Rename => "rename",
+            OperationKind::IntegrityCheck => "integrity-check",
+            OperationKind::Drill => "drill",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperationState {
+    Running,
+    /// Cancelled, but not at a checkpoint since.
+    Cancelling,
+    Cancelled,
+    Succeeded,
+    Failed,
+}
+
+impl OperationState {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, OperationState::Running | OperationState::Cancelling)
+    }
+}
+
+impl std::fmt::Display for OperationState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OperationState::Running => "running",
+            OperationState::Cancelling => "cancelling",
+            OperationState::Cancelled => "cancelled",
+            OperationState::Succeeded => "succeeded",
+            OperationState::Failed => "failed",
+        })
+    }
+}
+
+/// A point-in-time copy of the progress of an [Operation].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProgressSnapshot {
+    pub id: u64,
+    pub kind: OperationKind,
+    /// What the operation works on, e.g. `src -> dst` of a copy.
+    pub target: String,
+    pub state: OperationState,
+    pub phase: &'static str,
+    /// Items of the phase done, e.g. objects copied or ranges fetched.
+    pub done: u64,
+    /// Items of the phase, if known.
+    pub total: Option<u64>,
+    /// Bytes the operation transferred so far.
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// What the plan of the operation estimated it costs, see [Operation::estimate].
+    pub estimate: Option<CostEstimate>,
+    /// What the operation cost so far.
+    pub cost: CostEstimate,
+}
+
+impl ProgressSnapshot {
+    /// The time left in the phase if the items left take as long as those done, `None` if nothing
+    /// is done yet or the total is unknown.
+    pub fn eta(&self) -> Option<Duration> {
+        let total = self.total?;
+        if self.done == 0 || self.state.is_finished() {
+            return None;
+        }
+        let left = total.saturating_sub(self.done) as u32;
+        Some(self.elapsed / self.done as u32 * left)
+    }
+}
+
+impl std::fmt::Display for ProgressSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "id={} kind={} target={} state={} phase={} done={}",
+            self.id, self.kind, self.target, self.state, self.phase, self.done
+        )?;
+        if let Some(total) = self.total {
+            write!(f, "/{total}")?;
+        }
+        write!(f, " bytes={} elapsed={:?}", self.bytes, self.elapsed)?;
+        if let Some(eta) = self.eta() {
+            write!(f, " eta={eta:?}")?;
+        }
+        write!(f, " cost=({})", self.cost)?;
+        if let Some(estimate) = &self.estimate {
+            write!(f, " estimate=({estimate})")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Progress {
+    state: OperationState,
+    phase: &'static str,
+    done: u64,
+    total: Option<u64>,
+    bytes: u64,
+    estimate: Option<CostEstimate>,
+    /// When the operation finished.
+    finished: Option<Instant>,
+}
+
+/// A maintenance operation in flight, see the [module documentation](self).
+#[derive(Debug)]
+pub struct Operation {
+    id: u64,
+    kind: OperationKind,
+    target: String,
+    start: Instant,
+    progress: Mutex<Progress>,
+    meter: Arc<Meter>,
+}
+
+impl Operation {
+    fn new(id: u64, kind: OperationKind, target: String) -> Self {
+        Self {
+            id,
+            kind,
+            target,
+            start: Instant::now(),
+            progress: Mutex::new(Progress {
+                state: OperationState::Running,
+                phase: "starting",
+                done: 0,
+                total: None,
+                bytes: 0,
+                estimate: None,
+                finished: None,
+            }),
+            meter: Arc::default(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Enter `phase` of `total` items, if known.
+    pub fn phase(&self, phase: &'static str, total: Option<u64>) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.phase = phase;
+        progress.done = 0;
+        progress.total = total;
+    }
+
+    /// Count `items` of the phase done, having transferred `bytes`.
+    pub fn advance(&self, items: u64, bytes: u64) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.done += items;
+        progress.bytes += bytes;
+    }
+
+    /// Record what the plan of the operation estimates it costs, see [crate::cost].
+    pub fn estimate(&self, estimate: CostEstimate) {
+        self.progress.lock().unwrap().estimate = Some(estimate);
+    }
+
+    /// What the operation cost so far.
+    pub fn cost(&self) -> CostEstimate {
+        self.meter.cost()
+    }
+
+    /// Fail with [Error::Cancelled] if the operation was cancelled.
+    pub fn checkpoint(&self) -> Result<(), Error> {
+        match self.progress.lock().unwrap().state {
+            OperationState::Cancelling => Err(Error::Cancelled {
+                operation: self.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Ask the operation to stop at its next checkpoint. Returns whether it was running.
+    pub fn cancel(&self) -> bool {
+        let mut progress = self.progress.lock().unwrap();
+        match progress.state {
+            OperationState::Running => {
+                progress.state = OperationState::Cancelling;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let progress = self.progress.lock().unwrap();
+        ProgressSnapshot {
+            id: self.id,
+            kind: self.kind,
+            target: self.target.clone(),
+            state: progress.state,
+            phase: progress.phase,
+            done: progress.done,
+            total: progress.total,
+            bytes: progress.bytes,
+            elapsed: progress.finished.unwrap_or_else(Instant::now) - self.start,
+            estimate: progress.estimate,
+            cost: self.meter.cost(),
+        }
+    }
+
+    fn finish<T>(&self, res: &Result<T, Error>) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.state = match res {
+            Ok(_) => OperationState::Succeeded,
+            Err(Error::Cancelled { .. }) => OperationState::Cancelled,
+            Err(_) => OperationState::Failed,
+        };
+        progress.finished = Some(Instant::now());
+    }
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} (operation {})",
+            self.kind, self.target, self.id
+        )
+    }
+}
+
+/// The operations of an instance, see the [module documentation](self).
+#[derive(Debug, Default)]
+pub struct Operations {
+    next: AtomicU64,
+    running: Mutex<BTreeMap<u64, Arc<Operation>>>,
+    finished: Mutex<VecDeque<Arc<Operation>>>,
+}
+
+impl Operations {
+    /// Register an operation of `kind` on `target`.
+    pub fn begin(&self, kind: OperationKind, target: String) -> Arc<Operation> {
+        let id = self.next.fetch_add(1, Relaxed) + 1;
+        let operation = Arc::new(Operation::new(id, kind, target));
+        self.running.lock().unwrap().insert(id, operation.clone());
+        operation
+    }
+
+    /// Record the outcome of `operation`, moving it to the finished ones.
+    pub fn finish<T>(&self, operation: &Arc<Operation>, res: Result<T, Error>) -> Result<T, Error> {
+        operation.finish(&res);
+        self.running.lock().unwrap().remove(&operation.id);
+        let mut finished = self.finished.lock().unwrap();
+        if finished.len() == RETAINED {
+            finished.pop_front();
+        }
+        finished.push_back(operation.clone());
+        tracing::info!(
+            target: "threeqlite::s3",
+            progress = %operation.snapshot(),
+            "maintenance operation finished"
+        );
+        res
+    }
+
+    /// Run `work` in the calling task as an operation of `kind` on `target`.
+    pub async fn run<T, W, F>(
+        &self,
+        kind: OperationKind,
+        target: String,
+        work: W,
+    ) -> Result<T, Error>
+    where
+        W: FnOnce(Arc<Operation>) -> F,
+        F: Future<Output = Result<T, Error>>,
+    {
+        let operation = self.begin(kind, target);
+        // boxed by the scope, the futures of maintenance operations are large
+        let res = cost::scope(operation.meter.clone(), work(operation.clone())).await;
+        self.finish(&operation, res)
+    }
+
+    /// Spawn `work` as an operation of `kind` on `target`.
+    pub fn spawn<T, W, F>(
+        self: &Arc<Self>,
+        kind: OperationKind,
+        target: String,
+        work: W,
+    ) -> OperationHandle<T>
+    where
+        T: Send + 'static,
+        W: FnOnce(Arc<Operation>) -> F,
+        F: Future<Output = Result<T, Error>> + Send + 'static,
+    {
+        let operation = self.begin(kind, target);
+        let fut = cost::scope(operation.meter.clone(), work(operation.clone()));
+        let task = tokio::spawn({
+            let (operations, operation) = (self.clone(), operation.clone());
+            async move { operations.finish(&operation, fut.await) }
+        });
+        OperationHandle { operation, task }
+    }
+
+    /// Running operations by id, then those finished most recently.
+    pub fn list(&self) -> Vec<ProgressSnapshot> {
+        let running: Vec<_> = self.running.lock().unwrap().values().cloned().collect();
+        let finished: Vec<_> = self.finished.lock().unwrap().iter().cloned().collect();
+        running
+            .iter()
+            .chain(finished.iter().rev())
+            .map(|operation| operation.snapshot())
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<ProgressSnapshot> {
+        self.list().into_iter().find(|snapshot| snapshot.id == id)
+    }
+
+    /// Cancel the running operation `id`. Returns whether there was one.
+    pub fn cancel(&self, id: u64) -> bool {
+        let operation = self.running.lock().unwrap().get(&id).cloned();
+        operation.is_some_and(|operation| operation.cancel())
+    }
+}
+
+/// An operation spawned by one of the `start_*` methods, see the [module documentation](self).
+pub struct OperationHandle<T> {
+    operation: Arc<Operation>,
+    task: tokio::task::JoinHandle<Result<T, Error>>,
+}
+
+impl<T> OperationHandle<T> {
+    pub fn id(&self) -> u64 {
+        self.operation.id
+    }
+
+    pub fn progress(&self) -> ProgressSnapshot {
+        self.operation.snapshot()
+    }
+
+    /// See [Operation::cancel].
+    pub fn cancel(&self) -> bool {
+        self.operation.cancel()
+    }
+
+    /// The outcome of the operation, once it finished.
+    pub async fn await_done(self) -> Result<T, Error> {
+        self.task.await.map_err(|err| Error::Whatever {
+            message: format!("{} panicked: {err}", self.operation),
+            source: None,
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lifecycle() {
+        let operations = Arc::new(Operations::default());
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let handle = operations.spawn(
+            OperationKind::Copy,
+            "a.db -> b.db".to_owned(),
+            |op| async move {
+                op.phase("copying", Some(4));
+                op.advance(1, 100);
+                rx.await.unwrap();
+                op.checkpoint()?;
+                Ok(1)
+            },
+        );
+        tokio::task::yield_now().await;
+        let progress = handle.progress();
+        assert_eq!(progress.state, OperationState::Running);
+        assert_eq!(
+            (progress.phase, progress.done, progress.total),
+            ("copying", 1, Some(4))
+        );
+        assert!(progress.to_string().starts_with(
+            "id=1 kind=copy target=a.db -> b.db state=running phase=copying done=1/4 bytes=100 "
+        ));
+        let listed = operations.list();
+        assert_eq!((listed.len(), listed[0].id), (1, handle.id()));
+
+        assert!(operations.cancel(1));
+        assert!(!operations.cancel(1));
+        assert_eq!(handle.progress().state, OperationState::Cancelling);
+        tx.send(()).unwrap();
+        let err = handle.await_done().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "copy of a.db -> b.db (operation 1) was cancelled"
+        );
+        let cancelled = operations.get(1).unwrap();
+        assert_eq!(cancelled.state, OperationState::Cancelled);
+        assert_eq!(cancelled.eta(), None);
+        assert!(!operations.cancel(1));
+
+        let done = operations
+            .run(OperationKind::Drill, "test.db".to_owned(), |_| async {
+                Ok(())
+            })
+            .await;
+        assert!(done.is_ok());
+        let failed = operations
+            .run(
+                OperationKind::IntegrityCheck,
+                "test.db".to_owned(),
+                |_| async { Err::<(), _>(Error::ObjectNotFound) },
+            )
+            .await;
+        assert!(failed.is_err());
+        let states: Vec<_> = operations
+            .list()
+            .iter()
+            .map(|op| (op.id, op.state))
+            .collect();
+        assert_eq!(
+            states,
+            [
+                (3, OperationState::Failed),
+                (2, OperationState::Succeeded),
+                (1, OperationState::Cancelled)
+            ]
+        );
+
+        for _ in 0..RETAINED {
+            let _ = operations
+                .run(OperationKind::Drill, "test.db".to_owned(), |_| async {
+                    Ok(())
+                })
+                .await;
+        }
+        assert_eq!(operations.list().len(), RETAINED);
+        assert_eq!(operations.get(1), None);
+    }
+
+    #[test]
+    fn test_eta() {
+        let mut progress = Operation::new(1, OperationKind::Copy, "a -> b".to_owned()).snapshot();
+        progress.elapsed = Duration::from_secs(10);
+        assert_eq!(progress.eta(), None);
+        progress.total = Some(5);
+        assert_eq!(progress.eta(), None);
+        progress.done = 2;
+        assert_eq!(progress.eta(), Some(Duration::from_secs(15)));
+    }
+}
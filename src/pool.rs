@@ -0,0 +1,512 @@
+//! A pool of read-only connections to a database, for services running each request on a
+//! connection of their own.
+//!
+//! Opening a connection through the VFS and warming its page cache takes round trips, so a
+//! [ReadPool] keeps up to [PoolConfig::size] connections open and hands them out with
+//! [ReadPool::get], which waits while all of them are checked out. [PooledConnection::checkin], or
+//! dropping the connection, returns it after a health check: a connection whose thread is gone,
+//! e.g. after a panic, or that was left inside a transaction is closed instead of reused.
+//!
+//! An idle connection stays at the generation it last read. When commits moved the database more
+//! than [PoolConfig::max_lag] generations past it, the connection runs a new read transaction
+//! before it is handed out, or is reopened if that fails. The generation is the newest one the
+//! instance has seen, see [crate::heal]. A connection flushes its cached statements before its
+//! first statement after a schema change, see [crate::schema].
+//!
+//! Pooled connections run on the connection threads of the instance, so [ThreeQLite::shutdown]
+//! closes them, after which [ReadPool::get] fails with [Error::ConnectionClosed]. Statements
+//! failing on a locked database report [Error::Busy] like those of any [AsyncConnection].
+
+use std::{
+    future::Future,
+    ops::Deref,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rusqlite::OpenFlags;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+
+use crate::{asyncdb::AsyncConnection, error::Error, vfs::ThreeQLite};
+
+type OpenFuture = Pin<Box<dyn Future<Output = Result<AsyncConnection, Error>> + Send>>;
+type Opener = Box<dyn Fn() -> OpenFuture + Send + Sync>;
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// Connections open at most, i.e. checked out at once.
+    pub size: usize,
+    /// Generations an idle connection may lag behind before it is refreshed on checkout.
+    pub max_lag: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            size: 4,
+            max_lag: 0,
+        }
+    }
+}
+
+/// Upper bounds of the buckets of [PoolStats::staleness], the last one being unbounded.
+const STALENESS: [u64; 3] = [0, 1, 7];
+
+/// Counters of a [ReadPool] since it was created.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub checkouts: u64,
+    /// Checkouts that waited for a connection to be checked in.
+    pub waits: u64,
+    pub wait_time: Duration,
+    pub opens: u64,
+    /// Connections refreshed on checkout since they lagged too far behind.
+    pub recycles: u64,
+    /// Connections closed on checkin since they were unhealthy.
+    pub evictions: u64,
+    /// Generations idle connections lagged behind when checked out: none, 1, up to 7, and more.
+    pub staleness: [u64; STALENESS.len() + 1],
+}
+
+impl std::fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "checkouts={} waits={} wait_ms={} opens={} recycles={} evictions={} staleness={:?}",
+            self.checkouts,
+            self.waits,
+            self.wait_time.as_millis(),
+            self.opens,
+            self.recycles,
+            self.evictions,
+            self.staleness,
+        )
+    }
+}
+
+struct Idle {
+    conn: AsyncConnection,
+    /// The generation as of the last read transaction of the connection.
+    generation: u64,
+}
+
+struct Shared {
+    config: PoolConfig,
+    open: Opener,
+    generation: Arc<AtomicU64>,
+    permits: Arc<Semaphore>,
+    idle: Mutex<Vec<Idle>>,
+    stats: Mutex<PoolStats>,
+}
+
+/// See the [module documentation](self). Cloning is cheap; clones share the pool.
+#[derive(Clone)]
+pub struct ReadPool {
+    shared: Arc<Shared>,
+}
+
+impl ReadPool {
+    /// A pool of read-only connections to `db` through the VFS `vfs` was registered as. Opens the
+    /// first connection, so that a database that can't be opened fails here.
+    pub async fn open(vfs: &ThreeQLite, db: &str, config: PoolConfig) -> Result<Self, Error> {
+        let generation = vfs.inner.read().await.generation_seen.clone();
+        let (vfs, db) = (vfs.clone(), db.to_owned());
+        let pool = Self::with_opener(config, generation, move || {
+            let (vfs, db) = (vfs.clone(), db.clone());
+            Box::pin(async move {
+                AsyncConnection::open_with_flags(&vfs, &db, OpenFlags::SQLITE_OPEN_READ_ONLY).await
+            })
+        });
+        pool.get().await?.checkin().await;
+        Ok(pool)
+    }
+
+    fn with_opener(
+        config: PoolConfig,
+        generation: Arc<AtomicU64>,
+        open: impl Fn() -> OpenFuture + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                permits: Arc::new(Semaphore::new(config.size)),
+                config,
+                open: Box::new(open),
+                generation,
+                idle: Mutex::default(),
+                stats: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Check out a connection, waiting for one to be checked in if all of them are in use.
+    pub async fn get(&self) -> Result<PooledConnection, Error> {
+        let shared = &self.shared;
+        let permit = match shared.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::NoPermits) => {
+                let start = Instant::now();
+                let permit = shared
+                    .permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| Error::ConnectionClosed)?;
+                let mut stats = shared.stats.lock().unwrap();
+                stats.waits += 1;
+                stats.wait_time += start.elapsed();
+                permit
+            }
+            Err(TryAcquireError::Closed) => return Err(Error::ConnectionClosed),
+        };
+
+        // before any read, so that the connection never appears newer than it is
+        let current = shared.generation.load(Ordering::Relaxed);
+        let idle = {
+            let mut idle = shared.idle.lock().unwrap();
+            // closed by a shutdown
+            idle.retain(|idle| !idle.conn.is_closed());
+            idle.pop()
+        };
+        let idle = match idle {
+            Some(idle) => {
+                let lag = current.saturating_sub(idle.generation);
+                let bucket = STALENESS.iter().take_while(|&&bound| lag > bound).count();
+                shared.stats.lock().unwrap().staleness[bucket] += 1;
+                if lag > shared.config.max_lag {
+                    Idle {
+                        conn: shared.refresh(idle.conn).await?,
+                        generation: current,
+                    }
+                } else {
+                    idle
+                }
+            }
+            None => Idle {
+                conn: shared.open().await?,
+                generation: current,
+            },
+        };
+        shared.stats.lock().unwrap().checkouts += 1;
+        Ok(PooledConnection {
+            idle: Some(idle),
+            permit: Some(permit),
+            shared: shared.clone(),
+        })
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats.lock().unwrap().clone()
+    }
+}
+
+impl Shared {
+    async fn open(&self) -> Result<AsyncConnection, Error> {
+        let conn = (self.open)().await?;
+        self.stats.lock().unwrap().opens += 1;
+        Ok(conn)
+    }
+
+    /// Bring `conn` up to the current generation with a read transaction, or replace it.
+    async fn refresh(&self, conn: AsyncConnection) -> Result<AsyncConnection, Error> {
+        self.stats.lock().unwrap().recycles += 1;
+        let res = conn
+            .call(|conn| conn.query_row("PRAGMA schema_version", [], |_| Ok(())))
+            .await;
+        match res {
+            Ok(Ok(())) => Ok(conn),
+            Ok(Err(err)) => {
+                tracing::debug!(target: "threeqlite::asyncdb", %err, "reopening pooled connection");
+                self.open().await
+            }
+            Err(_) => self.open().await,
+        }
+    }
+
+    async fn checkin(&self, idle: Idle) {
+        match idle.conn.call(|conn| conn.is_autocommit()).await {
+            Ok(true) => self.idle.lock().unwrap().push(idle),
+            healthy => {
+                tracing::debug!(
+                    target: "threeqlite::asyncdb",
+                    in_transaction = healthy.is_ok(),
+                    "closing unhealthy pooled connection"
+                );
+                self.stats.lock().unwrap().evictions += 1;
+            }
+        }
+    }
+}
+
+/// A connection checked out of a [ReadPool], returned to it by [PooledConnection::checkin] or when
+/// dropped within a runtime.
+pub struct PooledConnection {
+    idle: Option<Idle>,
+    permit: Option<OwnedSemaphorePermit>,
+    shared: Arc<Shared>,
+}
+
+impl PooledConnection {
+    /// Return the connection to the pool, or close it if it is unhealthy.
+    pub async fn checkin(mut self) {
+        if let Some(idle) = self.idle.take() {
+            self.shared.checkin(idle).await;
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = AsyncConnection;
+
+    fn deref(&self) -> &AsyncConnection {
+        &self.idle.as_ref().unwrap().conn
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let (Some(idle), Some(permit)) = (self.idle.take(), self.permit.take()) else {
+            return;
+        };
+        // outside of a runtime the connection is closed, and the pool opens another one
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let shared = self.shared.clone();
+            runtime.spawn(async move {
+                shared.checkin(idle).await;
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::atomic::AtomicUsize};
+
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::asyncdb::Workers;
+
+    struct Fixture {
+        path: PathBuf,
+        workers: Arc<Workers>,
+        generation: Arc<AtomicU64>,
+        schema_changes: Arc<AtomicU64>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let path =
+                std::env::temp_dir().join(format!("threeqlite-pool-{}", uuid::Uuid::new_v4()));
+            Connection::open(&path)
+                .unwrap()
+                .execute_batch("CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1);")
+                .unwrap();
+            Self {
+                path,
+                workers: Arc::default(),
+                generation: Arc::default(),
+                schema_changes: Arc::default(),
+            }
+        }
+
+        /// A pool of local connections, whose generation moves with [Fixture::commit].
+        fn pool(&self, size: usize, max_lag: u64) -> ReadPool {
+            let (workers, path) = (self.workers.clone(), self.path.clone());
+            let changes = self.schema_changes.clone();
+            ReadPool::with_opener(
+                PoolConfig { size, max_lag },
+                self.generation.clone(),
+                move || {
+                    let (workers, path) = (workers.clone(), path.clone());
+                    let changes = changes.clone();
+                    Box::pin(async move {
+                        let conn = AsyncConnection::spawn(&workers, move || {
+                            Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                        })
+                        .await?;
+                        Ok(conn.following_schema(changes))
+                    })
+                },
+            )
+        }
+
+        /// Commit `n`, moving the database `generations` ahead.
+        fn commit(&self, n: i64, generations: u64) {
+            Connection::open(&self.path)
+                .unwrap()
+                .execute("UPDATE t SET n = ?1", [n])
+                .unwrap();
+            self.generation.fetch_add(generations, Ordering::Relaxed);
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    async fn read(conn: &AsyncConnection) -> i64 {
+        conn.query_map("SELECT n FROM t", [], |row| row.get(0))
+            .await
+            .unwrap()[0]
+    }
+
+    /// The columns of the first row of a cached statement selecting all of them.
+    async fn columns(conn: &AsyncConnection) -> usize {
+        conn.call(|conn| {
+            let mut stmt = conn.prepare_cached("SELECT * FROM t")?;
+            let mut rows = stmt.query([])?;
+            rows.next().map(|row| row.unwrap().as_ref().column_count())
+        })
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_checkouts_wait_for_checkins() {
+        let fixture = Fixture::new();
+        let pool = fixture.pool(2, 0);
+        let active = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..6)
+            .map(|_| {
+                let (pool, active, most) = (pool.clone(), active.clone(), most.clone());
+                tokio::spawn(async move {
+                    let conn = pool.get().await.unwrap();
+                    most.fetch_max(
+                        active.fetch_add(1, Ordering::Relaxed) + 1,
+                        Ordering::Relaxed,
+                    );
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    let n = read(&conn).await;
+                    active.fetch_sub(1, Ordering::Relaxed);
+                    conn.checkin().await;
+                    n
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), 1);
+        }
+
+        assert_eq!(most.load(Ordering::Relaxed), 2);
+        let stats = pool.stats();
+        assert_eq!((stats.checkouts, stats.waits, stats.opens), (6, 4, 2));
+        assert!(stats.wait_time >= Duration::from_millis(20), "{stats}");
+        assert_eq!(stats.staleness, [4, 0, 0, 0]);
+        fixture.workers.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_lagging_connections_recycled() {
+        let fixture = Fixture::new();
+        let pool = fixture.pool(1, 1);
+        let conn = pool.get().await.unwrap();
+        assert_eq!(read(&conn).await, 1);
+        conn.checkin().await;
+
+        // within the bound
+        fixture.commit(2, 1);
+        let conn = pool.get().await.unwrap();
+        assert_eq!(read(&conn).await, 2);
+        conn.checkin().await;
+        assert_eq!(pool.stats().recycles, 0);
+
+        // past it
+        fixture.commit(3, 2);
+        let conn = pool.get().await.unwrap();
+        assert_eq!(pool.stats().recycles, 1);
+        assert_eq!(read(&conn).await, 3);
+        conn.checkin().await;
+
+        // the recycled connection is current again
+        let conn = pool.get().await.unwrap();
+        conn.checkin().await;
+        let stats = pool.stats();
+        assert_eq!((stats.opens, stats.recycles), (1, 1));
+        assert_eq!(stats.staleness, [1, 1, 1, 0]);
+        assert_eq!(
+            stats.to_string(),
+            "checkouts=4 waits=0 wait_ms=0 opens=1 recycles=1 evictions=0 staleness=[1, 1, 1, 0]"
+        );
+        fixture.workers.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_unhealthy_connections_evicted() {
+        let fixture = Fixture::new();
+        let pool = fixture.pool(1, 0);
+
+        // left inside a transaction
+        let conn = pool.get().await.unwrap();
+        conn.call(|conn| conn.execute_batch("BEGIN; SELECT * FROM t;"))
+            .await
+            .unwrap()
+            .unwrap();
+        conn.checkin().await;
+        assert_eq!(pool.stats().evictions, 1);
+
+        // poisoned, and dropped instead of checked in
+        let conn = pool.get().await.unwrap();
+        assert!(conn.call(|conn| conn.is_autocommit()).await.unwrap());
+        let res = conn.call(|_| panic!("forced error")).await;
+        assert!(matches!(res, Err::<(), _>(Error::ConnectionClosed)));
+        drop(conn);
+
+        let conn = pool.get().await.unwrap();
+        assert_eq!(read(&conn).await, 1);
+        conn.checkin().await;
+        let stats = pool.stats();
+        assert_eq!((stats.opens, stats.evictions), (3, 2));
+        fixture.workers.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_statements_flushed_after_schema_change() {
+        let fixture = Fixture::new();
+        let pool = fixture.pool(1, 0);
+        let conn = pool.get().await.unwrap();
+        assert_eq!(columns(&conn).await, 1);
+        conn.checkin().await;
+
+        // data-only commits keep the statements
+        fixture.commit(2, 1);
+        let conn = pool.get().await.unwrap();
+        assert_eq!(columns(&conn).await, 1);
+        assert_eq!(conn.statement_flushes(), 0);
+        conn.checkin().await;
+
+        // migrated by another writer
+        Connection::open(&fixture.path)
+            .unwrap()
+            .execute_batch("ALTER TABLE t ADD COLUMN s TEXT")
+            .unwrap();
+        fixture.generation.fetch_add(1, Ordering::Relaxed);
+        fixture.schema_changes.fetch_add(1, Ordering::Relaxed);
+        let conn = pool.get().await.unwrap();
+        assert_eq!(columns(&conn).await, 2);
+        assert_eq!(read(&conn).await, 2);
+        assert_eq!(conn.statement_flushes(), 1);
+        conn.checkin().await;
+        fixture.workers.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_pool() {
+        let fixture = Fixture::new();
+        let pool = fixture.pool(2, 0);
+        pool.get().await.unwrap().checkin().await;
+
+        fixture.workers.shutdown().await;
+        assert!(matches!(pool.get().await, Err(Error::ConnectionClosed)));
+        assert_eq!(pool.stats().opens, 1);
+    }
+}
@@ -0,0 +1,435 @@
+//! Fetching the pages every transaction starts with before SQLite asks for them.
+//!
+//! A point lookup descends the B-tree from its root, one page at a time, and every page it
+//! doesn't find in the [page cache](crate::cache) costs a serial round trip to the object store.
+//! The root and interior pages are shared by most lookups, so they are what transactions read
+//! first. A [Learner] counts the pages each transaction of a handle reads among its first
+//! [PrefetchConfig::learn_reads], and the most frequent ones, at most [PrefetchConfig::max_pages],
+//! form the [HotSet] of the database.
+//!
+//! Writers publish the hot set next to the database (see [KeyLayout::hot_set]) every
+//! [PrefetchConfig::publish_every] transactions, and handles load it once, seeding their learner
+//! with it. At the first read of a transaction, once registering as a reader has validated the
+//! generation, the pages of the hot set missing from the cache are fetched in parallel and
+//! admitted as [CacheUse::Prefetch], so a cold lookup waits for a single round trip before
+//! reaching the leaf. [CacheStats::prefetch_hit_ratio](crate::cache::CacheStats) tells how many
+//! of them were needed. A failed prefetch only costs the round trips it was meant to save.
+//!
+//! Prefetching requires the page cache; reads that don't register bypass both.
+
+use std::{collections::HashMap, ops::Range};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{CacheUse, HEADER_LEN},
+    circuit::OpClass,
+    error::Error,
+    fetch,
+    format::{self, Bounded},
+    key::{KeyLayout, ObjectKey},
+    priority::IoClass,
+    verify::{Upload, WriteClass},
+    vfs::{status, Inner},
+};
+
+#[derive(Clone, Debug)]
+pub struct PrefetchConfig {
+    pub enabled: bool,
+    /// Reads at the start of each transaction whose pages are learned.
+    pub learn_reads: usize,
+    /// Pages in the hot set.
+    pub max_pages: usize,
+    /// Transactions a writer learns from between publishing the hot set.
+    pub publish_every: u32,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            learn_reads: 8,
+            max_pages: 64,
+            publish_every: 16,
+        }
+    }
+}
+
+/// The pages transactions of a database start with, by offset and length.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HotSet {
+    pub pages: Vec<(u64, u64)>,
+}
+
+impl HotSet {
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+}
+
+/// Pages in a hot set as loaded, before [PrefetchConfig::max_pages] applies.
+const MAX_HOT_PAGES: usize = 4096;
+
+/// The longest page SQLite supports.
+pub const MAX_PAGE_LEN: u64 = 65536;
+
+/// Fails unless `len` bytes at `offset` are at most a page and within the bounds of `u64`.
+pub fn check_page(offset: u64, len: u64) -> Result<(), String> {
+    match len <= MAX_PAGE_LEN && offset.checked_add(len).is_some() {
+        true => Ok(()),
+        false => Err(format!("invalid page of {len} bytes at {offset}")),
+    }
+}
+
+impl Bounded for HotSet {
+    const MAX_LEN: u64 = 8 + 16 * MAX_HOT_PAGES as u64;
+
+    fn validate(&self) -> Result<(), String> {
+        format::check_count("pages", &self.pages, MAX_HOT_PAGES)?;
+        self.pages
+            .iter()
+            .try_for_each(|(offset, len)| check_page(*offset, *len))
+    }
+}
+
+/// Counts the pages read at the start of the transactions of a handle, see the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct Learner {
+    config: PrefetchConfig,
+    /// Reads of the running transaction so far.
+    reads: usize,
+    counts: HashMap<(u64, u64), u64>,
+    /// Transactions learned from since the hot set was last published.
+    unpublished: u32,
+}
+
+impl Learner {
+    pub fn new(config: PrefetchConfig) -> Self {
+        Self {
+            config,
+            reads: 0,
+            counts: HashMap::new(),
+            unpublished: 0,
+        }
+    }
+
+    /// Whether the next read is the first of a transaction, i.e. the one to prefetch at.
+    pub fn starting(&self) -> bool {
+        self.config.enabled && self.reads == 0
+    }
+
+    /// Count the pages of `set` as read once, e.g. when it was loaded.
+    pub fn seed(&mut self, set: &HotSet) {
+        for page in &set.pages {
+            *self.counts.entry(*page).or_default() += 1;
+        }
+    }
+
+    /// Record a read of `len` bytes at `offset` by the running transaction.
+    pub fn observe(&mut self, offset: u64, len: u64) {
+        if !self.config.enabled || self.reads >= self.config.learn_reads {
+            return;
+        }
+        self.reads += 1;
+        // served by the pinned header anyway
+        if offset + len <= HEADER_LEN as u64 {
+            return;
+        }
+        *self.counts.entry((offset, len)).or_default() += 1;
+        // keep the candidates bounded, dropping the rarest
+        if self.counts.len() > 4 * self.config.max_pages.max(1) {
+            let keep: HashMap<_, _> = self.ranked().into_iter().collect();
+            self.counts = keep;
+        }
+    }
+
+    /// End the running transaction. Returns whether a writer should publish the hot set.
+    pub fn finish(&mut self, wrote: bool) -> bool {
+        if self.reads > 0 {
+            self.unpublished += 1;
+        }
+        self.reads = 0;
+        if wrote && self.unpublished >= self.config.publish_every {
+            self.unpublished = 0;
+            return true;
+        }
+        false
+    }
+
+    /// The hot set learned so far.
+    pub fn hot_set(&self) -> HotSet {
+        let mut pages: Vec<_> = self.ranked().into_iter().map(|(page, _)| page).collect();
+        pages.sort();
+        HotSet { pages }
+    }
+
+    /// The [PrefetchConfig::max_pages] most frequent pages with their counts, lower offsets first
+    /// among equally frequent ones.
+    fn ranked(&self) -> Vec<((u64, u64), u64)> {
+        let mut ranked: Vec<_> = self.counts.iter().map(|(page, n)| (*page, *n)).collect();
+        ranked.sort_by_key(|(page, n)| (std::cmp::Reverse(*n), *page));
+        ranked.truncate(self.config.max_pages);
+        ranked
+    }
+}
+
+/// Load the hot set of `db`, empty if it has none or it can't be read. Sets published with a
+/// larger [PrefetchConfig::max_pages] are cut to this one's.
+pub async fn load(inner: &Inner, db: &ObjectKey) -> HotSet {
+    if !inner.prefetch_config.enabled || !inner.cache.enabled() {
+        return HotSet::default();
+    }
+    let obj = inner
+        .s3
+        .get_object()
+        .bucket(&inner.bucket)
+        .key(KeyLayout::hot_set(db))
+        .send()
+        .await;
+    inner.record(OpClass::Read, obj.is_ok());
+    let obj = match obj {
+        Ok(obj) => obj,
+        Err(err) if status(&err) == Some(404) => return HotSet::default(),
+        Err(err) => {
+            tracing::debug!(target: "threeqlite::s3", key = %db, %err, "loading hot set failed");
+            return HotSet::default();
+        }
+    };
+    if format::check_len::<HotSet>(obj.content_length().unwrap_or(0) as u64).is_err() {
+        return HotSet::default();
+    }
+    let Ok(bytes) = obj.body.collect().await else {
+        return HotSet::default();
+    };
+    match format::parse::<HotSet>(&bytes.into_bytes()) {
+        Ok(mut set) => {
+            set.pages.truncate(inner.prefetch_config.max_pages);
+            set
+        }
+        Err(reason) => {
+            let err = Error::CorruptManifest {
+                key: KeyLayout::hot_set(db).to_string(),
+                reason,
+            };
+            tracing::debug!(target: "threeqlite::s3", %err, "ignoring hot set");
+            HotSet::default()
+        }
+    }
+}
+
+/// Publish `set` as the hot set of `db`.
+pub async fn publish(inner: &Inner, db: &ObjectKey, set: &HotSet) -> Result<(), Error> {
+    let bytes = bincode::serialize(set).map_err(|err| Error::Whatever {
+        message: format!("failed to encode hot set: {err}"),
+        source: Some(err),
+    })?;
+    let key = KeyLayout::hot_set(db);
+    let upload = Upload::verified(&key, &bytes, WriteClass::Manifests, &inner.verify_writes);
+    let put = inner
+        .s3
+        .put_object()
+        .bucket(&inner.bucket)
+        .key(&key)
+        .body(bytes.into());
+    let res = upload.send_checksum(put).send().await;
+    inner.record(OpClass::Write, res.is_ok());
+    inner.verify_put(&upload, &res?).await
+}
+
+/// Fetch the pages of `set` missing from the cache in parallel and admit them as of
+/// `generation`. Returns how many were fetched.
+pub async fn prefetch(
+    inner: &Inner,
+    db: &ObjectKey,
+    generation: u64,
+    set: &HotSet,
+) -> Result<usize, Error> {
+    let ranges: Vec<Range<u64>> = set
+        .pages
+        .iter()
+        .filter(|(offset, len)| {
+            !inner
+                .cache
+                .contains(db.as_str(), generation, *offset, *len as usize)
+        })
+        .map(|(offset, len)| *offset..offset + len)
+        .collect();
+    if ranges.is_empty() {
+        return Ok(0);
+    }
+    let (pages, _) = fetch::fetch(inner, db, None, &ranges, IoClass::Critical).await?;
+    for (range, data) in ranges.iter().zip(pages) {
+        inner.cache.insert(
+            db.as_str(),
+            generation,
+            range.start,
+            data,
+            CacheUse::Prefetch,
+        );
+    }
+    Ok(ranges.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::CacheConfig,
+        config::Config,
+        mock::{self, MockS3},
+        vfs::ThreeQLite,
+    };
+
+    const PAGE: u64 = 4096;
+    const LEAVES: u64 = 64;
+
+    /// The pages a point lookup of `key` reads: page 1 for the schema, then the root, one of 4
+    /// interior pages and one of [LEAVES] leaves of a table.
+    fn lookup_path(key: u64) -> [u64; 4] {
+        [1, 2, 3 + key % 4, 7 + key % LEAVES]
+    }
+
+    /// Run point lookups, each at a generation of its own so that it starts with a cold cache.
+    /// Returns the serial round trips per lookup and the learner.
+    async fn lookups(mock: &MockS3, config: PrefetchConfig) -> (Vec<usize>, Learner, ThreeQLite) {
+        let tq = ThreeQLite::with_client(
+            Config {
+                cache: CacheConfig {
+                    capacity: 1024 * PAGE,
+                    ..CacheConfig::default()
+                },
+                prefetch: config.clone(),
+                ..Config::default()
+            },
+            mock.client(),
+        )
+        .unwrap();
+        let key = ObjectKey::new("test.db").unwrap();
+        let inner = tq.inner.read().await.clone();
+        let mut learner = Learner::new(config);
+        learner.seed(&load(&inner, &key).await);
+        let mut hot = learner.hot_set();
+        let mut round_trips = vec![];
+        for generation in 1..=40 {
+            let mut rounds = 0;
+            let mut sent = |mock: &MockS3, before: usize| {
+                if mock.requests().len() > before {
+                    rounds += 1;
+                }
+            };
+            for (i, page) in lookup_path(generation * 7).into_iter().enumerate() {
+                let offset = (page - 1) * PAGE;
+                if i == 0 && learner.starting() {
+                    let before = mock.requests().len();
+                    prefetch(&inner, &key, generation, &hot).await.unwrap();
+                    sent(mock, before);
+                }
+                let before = mock.requests().len();
+                let data = inner
+                    .read_at(
+                        offset as usize,
+                        PAGE as usize,
+                        Some(generation),
+                        CacheUse::Admit,
+                    )
+                    .await
+                    .unwrap();
+                sent(mock, before);
+                assert_eq!(data.len(), PAGE as usize);
+                learner.observe(offset, PAGE);
+            }
+            if learner.finish(true) {
+                hot = learner.hot_set();
+                publish(&inner, &key, &hot).await.unwrap();
+            }
+            round_trips.push(rounds);
+        }
+        (round_trips, learner, tq)
+    }
+
+    fn setup() -> MockS3 {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(PAGE as u32, 7 + LEAVES as u32, 1));
+        mock
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_cuts_round_trips_of_lookups() {
+        let mock = setup();
+        let (without, _, tq) = lookups(
+            &mock,
+            PrefetchConfig {
+                enabled: false,
+                ..PrefetchConfig::default()
+            },
+        )
+        .await;
+        assert!(without.iter().all(|rounds| *rounds == 4), "{without:?}");
+        assert_eq!(tq.stats().await.cache.prefetch_hit_ratio(), None);
+
+        let mock = setup();
+        let config = PrefetchConfig {
+            learn_reads: 3,
+            max_pages: 6,
+            publish_every: 4,
+            ..PrefetchConfig::default()
+        };
+        let (with, learner, tq) = lookups(&mock, config.clone()).await;
+        // once learned, one round trip fetches page 1, the root and the interior pages, and one
+        // the leaf
+        assert!(with[8..].iter().all(|rounds| *rounds == 2), "{with:?}");
+        let set = learner.hot_set();
+        assert_eq!(set.pages.len(), 6);
+        assert!(set.pages.iter().all(|(offset, _)| *offset < 6 * PAGE));
+        let stats = tq.stats().await.cache;
+        assert!(stats.prefetch_hit_ratio().unwrap() > 0.4, "{stats}");
+
+        // the published set serves the next handle from its first transaction on
+        let (next, _, _) = lookups(&mock, config).await;
+        assert!(next.iter().all(|rounds| *rounds == 2), "{next:?}");
+    }
+
+    #[test]
+    fn test_hot_set_stays_within_cap() {
+        let mut learner = Learner::new(PrefetchConfig {
+            learn_reads: 4,
+            max_pages: 3,
+            ..PrefetchConfig::default()
+        });
+        assert!(learner.starting());
+        for lookup in 0..100 {
+            for page in lookup_path(lookup) {
+                learner.observe((page - 1) * PAGE, PAGE);
+            }
+            // reads past the first ones of a transaction aren't learned
+            learner.observe(1000 * PAGE, PAGE);
+            assert!(!learner.starting());
+            learner.finish(false);
+            assert!(learner.counts.len() <= 12);
+            assert!(learner.hot_set().pages.len() <= 3);
+        }
+        // page 1 and the root, then one of the interior pages
+        let pages: Vec<_> = learner
+            .hot_set()
+            .pages
+            .iter()
+            .map(|(o, _)| o / PAGE)
+            .collect();
+        assert_eq!(pages[..2], [0, 1]);
+        assert!((2..6).contains(&pages[2]), "{pages:?}");
+
+        // the header alone isn't learned
+        let mut learner = Learner::new(PrefetchConfig::default());
+        learner.observe(0, 100);
+        assert!(learner.hot_set().is_empty());
+        // nor anything once disabled
+        let mut learner = Learner::new(PrefetchConfig {
+            enabled: false,
+            ..PrefetchConfig::default()
+        });
+        learner.observe(0, PAGE);
+        assert!(!learner.starting());
+        assert!(learner.hot_set().is_empty());
+    }
+}
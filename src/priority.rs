@@ -0,0 +1,322 @@
+//! Priority classes of storage reads.
+//!
+//! SQLite blocks on every page read, while bulk work (integrity snapshots, mirror syncs, listing)
+//! only cares about throughput. Both share one client, so without coordination a page read queues
+//! behind multi-megabyte transfers. Every GET therefore takes a permit from the [Limiter] of its
+//! instance first:
+//!
+//! - [IoClass::Critical] reads may take any permit, including the [PriorityConfig::reserved] ones.
+//! - [IoClass::Bulk] reads only take unreserved permits, and none at all while a critical read is
+//!   waiting.
+//!
+//! Bulk transfers are split into parts of at most [PriorityConfig::bulk_part_size], so that a
+//! permit is never held for long and a critical read waits for one part at most.
+//!
+//! The reads of an instance may be for several databases, e.g. a journal or the source of a copy.
+//! While reads of another database wait, a database holds at most its
+//! [PriorityConfig::database_share] of the permits, so that a slow one can't take them all.
+
+use std::{
+    collections::HashMap,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use tokio::sync::Notify;
+
+use crate::stats::Stats;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IoClass {
+    /// Reads SQLite is blocked on: pages, headers and metadata on the transaction path.
+    Critical,
+    /// Snapshots, mirror syncs and other background transfers.
+    Bulk,
+}
+
+impl IoClass {
+    pub const ALL: [IoClass; 2] = [IoClass::Critical, IoClass::Bulk];
+}
+
+impl std::fmt::Display for IoClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IoClass::Critical => "critical",
+            IoClass::Bulk => "bulk",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PriorityConfig {
+    /// Reads in flight at once.
+    pub permits: usize,
+    /// Permits only critical reads may take. Bulk reads get at least one permit regardless.
+    pub reserved: usize,
+    /// Bulk transfers are split into ranged GETs of at most this size.
+    pub bulk_part_size: u64,
+    /// The fraction of the permits the reads of a database may hold while reads of another
+    /// database wait. A database gets at least one permit regardless.
+    pub database_share: f64,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            permits: 64,
+            reserved: 8,
+            bulk_part_size: 1024 * 1024,
+            database_share: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    in_use: usize,
+    critical_waiting: usize,
+    /// Permits held, by database.
+    held: HashMap<String, usize>,
+    /// Reads waiting, by database.
+    waiting: HashMap<String, usize>,
+}
+
+impl State {
+    fn others_waiting(&self, db: &str) -> bool {
+        self.waiting.keys().any(|other| other != db)
+    }
+}
+
+/// Hands out the read permits of an instance.
+#[derive(Debug)]
+pub struct Limiter {
+    config: PriorityConfig,
+    state: Mutex<State>,
+    released: Notify,
+}
+
+/// A permit to send a read, returned on drop.
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    db: String,
+}
+
+/// Counts a waiting read until it gets its permit or gives up.
+struct Waiter<'a> {
+    limiter: &'a Limiter,
+    db: &'a str,
+    class: IoClass,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if self.class == IoClass::Critical {
+            state.critical_waiting -= 1;
+        }
+        decrement(&mut state.waiting, self.db);
+        drop(state);
+        // reads of this class or database may have waited for this one
+        self.limiter.released.notify_waiters();
+    }
+}
+
+fn decrement(counts: &mut HashMap<String, usize>, db: &str) {
+    if let Some(count) = counts.get_mut(db) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(db);
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(config: PriorityConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::default(),
+            released: Notify::new(),
+        }
+    }
+
+    pub fn config(&self) -> &PriorityConfig {
+        &self.config
+    }
+
+    /// The permits a database may hold while another waits.
+    fn database_share(&self) -> usize {
+        ((self.config.permits as f64 * self.config.database_share) as usize).max(1)
+    }
+
+    fn admits(&self, state: &State, db: &str, class: IoClass) -> bool {
+        let held = state.held.get(db).copied().unwrap_or(0);
+        if held >= self.database_share() && state.others_waiting(db) {
+            return false;
+        }
+        match class {
+            IoClass::Critical => state.in_use < self.config.permits.max(1),
+            IoClass::Bulk => {
+                let bulk = self.config.permits.saturating_sub(self.config.reserved);
+                state.critical_waiting == 0 && state.in_use < bulk.max(1)
+            }
+        }
+    }
+
+    /// Wait for a permit of `class` for a read of the objects of `db`, recording the wait in
+    /// `stats`.
+    pub async fn acquire(self: &Arc<Self>, db: &str, class: IoClass, stats: &Stats) -> Permit {
+        let start = Instant::now();
+        let mut waiter = None;
+        loop {
+            let mut released = pin!(self.released.notified());
+            released.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                if self.admits(&state, db, class) {
+                    state.in_use += 1;
+                    *state.held.entry(db.to_owned()).or_default() += 1;
+                    break;
+                }
+                if waiter.is_none() {
+                    if class == IoClass::Critical {
+                        state.critical_waiting += 1;
+                    }
+                    *state.waiting.entry(db.to_owned()).or_default() += 1;
+                    waiter = Some(Waiter {
+                        limiter: self,
+                        db,
+                        class,
+                    });
+                }
+            }
+            released.await;
+        }
+        drop(waiter);
+        stats.queue_wait[class as usize].record(start.elapsed());
+        Permit {
+            limiter: self.clone(),
+            db: db.to_owned(),
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.in_use -= 1;
+        decrement(&mut state.held, &self.db);
+        drop(state);
+        self.limiter.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bulk_yields_to_critical() {
+        let limiter = Arc::new(Limiter::new(PriorityConfig {
+            permits: 3,
+            reserved: 1,
+            ..PriorityConfig::default()
+        }));
+        let stats = Arc::new(Stats::default());
+
+        // bulk reads can't take the reserved permit
+        let a = limiter.acquire("x.db", IoClass::Bulk, &stats).await;
+        let b = limiter.acquire("x.db", IoClass::Bulk, &stats).await;
+        let bulk = tokio::spawn({
+            let (limiter, stats) = (limiter.clone(), stats.clone());
+            async move { limiter.acquire("x.db", IoClass::Bulk, &stats).await }
+        });
+        let critical = limiter.acquire("x.db", IoClass::Critical, &stats).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!bulk.is_finished());
+
+        // with all permits taken, a waiting critical read goes before the waiting bulk read
+        let waiting = tokio::spawn({
+            let (limiter, stats) = (limiter.clone(), stats.clone());
+            async move { limiter.acquire("x.db", IoClass::Critical, &stats).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(a);
+        let second = waiting.await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!bulk.is_finished());
+
+        drop((b, critical, second));
+        let _bulk = bulk.await.unwrap();
+        assert_eq!(limiter.state.lock().unwrap().in_use, 1);
+
+        assert_eq!(stats.queue_wait(IoClass::Critical).count, 2);
+        assert!(stats.queue_wait(IoClass::Critical).max >= Duration::from_millis(20));
+        assert!(stats.queue_wait(IoClass::Bulk).max >= Duration::from_millis(60));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_critical_read_unblocks_bulk() {
+        let limiter = Arc::new(Limiter::new(PriorityConfig {
+            permits: 1,
+            reserved: 0,
+            ..PriorityConfig::default()
+        }));
+        let stats = Stats::default();
+        let held = limiter.acquire("x.db", IoClass::Bulk, &stats).await;
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(10),
+            limiter.acquire("x.db", IoClass::Critical, &stats),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        assert_eq!(limiter.state.lock().unwrap().critical_waiting, 0);
+        drop(held);
+        let _bulk = limiter.acquire("x.db", IoClass::Bulk, &stats).await;
+    }
+
+    #[tokio::test]
+    async fn test_database_share() {
+        let limiter = Arc::new(Limiter::new(PriorityConfig {
+            permits: 4,
+            reserved: 0,
+            database_share: 0.5,
+            ..PriorityConfig::default()
+        }));
+        let stats = Arc::new(Stats::default());
+
+        // alone, a database may take every permit
+        let mut slow: Vec<_> = Vec::new();
+        for _ in 0..4 {
+            slow.push(limiter.acquire("slow.db", IoClass::Critical, &stats).await);
+        }
+        let spawn = |db: &'static str| {
+            let (limiter, stats) = (limiter.clone(), stats.clone());
+            tokio::spawn(async move { limiter.acquire(db, IoClass::Critical, &stats).await })
+        };
+        let others = [spawn("other.db"), spawn("other.db")];
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let more = spawn("slow.db");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // the permits slow.db returns go to other.db until slow.db is down to its share
+        slow.truncate(2);
+        let mut held = vec![];
+        for other in others {
+            held.push(other.await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!more.is_finished());
+        slow.pop();
+        let _more = more.await.unwrap();
+
+        // with nothing else waiting, slow.db may go beyond its share again
+        drop(held);
+        let _again = limiter.acquire("slow.db", IoClass::Critical, &stats).await;
+        assert_eq!(limiter.state.lock().unwrap().held["slow.db"], 3);
+        assert!(limiter.state.lock().unwrap().waiting.is_empty());
+    }
+}
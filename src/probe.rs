@@ -0,0 +1,184 @@
+//! Write-permission probes.
+//!
+//! A HEAD request proves that an object exists but not that the credentials may write next to it.
+//! To find out, a tiny conditional PUT is sent to `<prefix>/.write-probe` and deleted again right
+//! away. The answer is cached per bucket and prefix. Since every [crate::vfs::ThreeQLite] instance
+//! has its own client, the cache is also per set of credentials.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Name of the probe object within a prefix.
+pub const PROBE_OBJECT: &str = ".write-probe";
+
+#[derive(Clone, Debug)]
+pub struct ProbeConfig {
+    /// How long the outcome of a probe is trusted.
+    pub ttl: Duration,
+    /// Answer write-permission checks with this value instead of probing. For deployments that
+    /// forbid any write, including probes.
+    pub assume_writable: Option<bool>,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(300),
+            assume_writable: None,
+        }
+    }
+}
+
+/// The "directory" of `db`, i.e. everything up to its last `/`.
+pub fn prefix(db: &str) -> &str {
+    db.rsplit_once('/').map_or("", |(prefix, _)| prefix)
+}
+
+/// Cached probe outcomes per (bucket, prefix).
+pub struct WriteProbes {
+    config: ProbeConfig,
+    cache: Mutex<HashMap<(String, String), (bool, Instant)>>,
+}
+
+impl WriteProbes {
+    pub fn new(config: ProbeConfig) -> Self {
+        Self {
+            config,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether writes to `prefix` are known to be possible, without probing.
+    pub fn cached(&self, bucket: &str, prefix: &str) -> Option<bool> {
+        self.cached_at(bucket, prefix, Instant::now())
+    }
+
+    pub fn store(&self, bucket: &str, prefix: &str, writable: bool) {
+        self.store_at(bucket, prefix, writable, Instant::now())
+    }
+
+    fn cached_at(&self, bucket: &str, prefix: &str, now: Instant) -> Option<bool> {
+        if let Some(writable) = self.config.assume_writable {
+            return Some(writable);
+        }
+        let cache = self.cache.lock().unwrap();
+        let (writable, at) = cache.get(&(bucket.to_owned(), prefix.to_owned()))?;
+        (now.duration_since(*at) < self.config.ttl).then_some(*writable)
+    }
+
+    fn store_at(&self, bucket: &str, prefix: &str, writable: bool, now: Instant) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert((bucket.to_owned(), prefix.to_owned()), (writable, now));
+    }
+}
+
+#[cfg(all(test, feature = "s3"))]
+mod tests {
+    use sqlite_vfs::Vfs;
+
+    use super::*;
+    use crate::{config::Config, mock::MockS3, vfs::ThreeQLite};
+
+    fn instance(mock: &MockS3, probe: ProbeConfig) -> ThreeQLite {
+        let config = Config {
+            write_probe: probe,
+            ..Config::default()
+        };
+        ThreeQLite::with_client(config, mock.client()).unwrap()
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(prefix("test.db"), "");
+        assert_eq!(prefix("tenants/a/main.db"), "tenants/a");
+    }
+
+    #[test]
+    fn test_ttl() {
+        let probes = WriteProbes::new(ProbeConfig::default());
+        let start = Instant::now();
+        assert_eq!(probes.cached_at("b", "", start), None);
+
+        probes.store_at("b", "", false, start);
+        assert_eq!(
+            probes.cached_at("b", "", start + Duration::from_secs(299)),
+            Some(false)
+        );
+        assert_eq!(probes.cached_at("b", "other", start), None);
+        assert_eq!(
+            probes.cached_at("b", "", start + Duration::from_secs(300)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_denied() {
+        let mock = MockS3::start();
+        mock.reject("PUT", 403);
+        let tq = instance(&mock, ProbeConfig::default());
+
+        assert!(!tq.access("test.db", true).await.unwrap());
+        assert!(!tq.access("test.db", true).await.unwrap());
+        // the second check is answered from the cache
+        assert_eq!(mock.requests().len(), 1);
+        // reads don't need a probe
+        assert!(tq.access("test.db", false).await.unwrap());
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_allowed() {
+        let mock = MockS3::start();
+        let tq = instance(&mock, ProbeConfig::default());
+
+        assert!(tq.access("tenants/a/main.db", true).await.unwrap());
+        assert_eq!(
+            mock.requests(),
+            vec![
+                ("PUT".to_owned(), "tenants/a/.write-probe".to_owned()),
+                ("DELETE".to_owned(), "tenants/a/.write-probe".to_owned()),
+            ]
+        );
+        assert_eq!(mock.get("tenants/a/.write-probe"), None);
+    }
+
+    #[tokio::test]
+    async fn test_probe_in_progress_elsewhere() {
+        let mock = MockS3::start();
+        mock.put(".write-probe", "someone else's");
+        let tq = instance(&mock, ProbeConfig::default());
+
+        // a failed precondition still proves write permission; the object isn't ours to delete
+        assert!(tq.access("test.db", true).await.unwrap());
+        assert_eq!(mock.get(".write-probe").unwrap(), b"someone else's");
+    }
+
+    #[tokio::test]
+    async fn test_assume_writable() {
+        let mock = MockS3::start();
+        let tq = instance(
+            &mock,
+            ProbeConfig {
+                assume_writable: Some(false),
+                ..ProbeConfig::default()
+            },
+        );
+
+        assert!(!tq.access("test.db", true).await.unwrap());
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_outage_not_cached() {
+        let mock = MockS3::start();
+        mock.reject("PUT", 503);
+        let tq = instance(&mock, ProbeConfig::default());
+
+        assert!(tq.access("test.db", true).await.is_err());
+        assert!(tq.access("test.db", true).await.is_err());
+        assert_eq!(mock.requests().len(), 2);
+    }
+}
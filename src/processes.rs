@@ -0,0 +1,754 @@
+//! Lock contention across real processes.
+//!
+//! The other tests run all their instances in the test process. The holders they record all name
+//! that process, which is alive, and a handle dropped in the middle of a transaction still
+//! releases its lock on the way out, so none of them can leave a lock behind the way a process
+//! killed while holding it does. Here the test binary runs itself again as client processes, each
+//! playing a [Role] against the same store, with connection pools, PIDs and identities of their
+//! own. The orchestrating tests collect what the clients did and check it against each other.
+//!
+//! | Role      | Does                                                                        |
+//! |-----------|-----------------------------------------------------------------------------|
+//! | `writer`  | takes the write lock, increments the counter in the database object, commits |
+//! | `reader`  | registers as a reader and reads the counter twice, which must agree          |
+//! | `crasher` | takes the write lock and exits without releasing it                          |
+//! | `torn`    | takes the write lock, uploads its journal and the new counter, and exits      |
+//! | `stealer` | claims the reconcile role of the database, working while it holds it         |
+//!
+//! Every client waits for the file `go` in the directory of its run, so that they contend from
+//! the start, and appends an [Event] per iteration to `<name>.jsonl` there as a JSON line, along
+//! with its output in `<name>.log`. The tests check that writers never overlapped each other or a
+//! reader, that every increment is there and the generations recorded with them grow with the
+//! counter, that reads saw no writes, and that a role is only ever worked in one process at a
+//! time.
+//!
+//! The store is a [MockS3] served by the orchestrating test over localhost. With
+//! `THREEQLITE_PROCESS_ENDPOINT` set, e.g. to `http://localhost:9000` for a MinIO, the clients use
+//! that with the credentials of the environment instead, in the bucket
+//! `THREEQLITE_PROCESS_BUCKET`, which needs Object Lock.
+//!
+//! ```sh
+//! cargo test --lib processes
+//! ```
+//!
+//! The suite found two bugs, both fixed since:
+//!
+//! * A writer that died holding the write lock left it held for good: writers waited until their
+//!   busy timeout and readers deferred to it forever. This takes a process exiting without
+//!   unwinding, which the tests in the test process can't do, and a holder whose process is gone,
+//!   which they can't record since they share one that is running. Waiters now take over the lock
+//!   of a writer on their machine whose process exited, see [crate::busy];
+//!   [tests::test_crashed_writer_taken_over] shows the lock left behind without that. A writer
+//!   that left a journal may have uploaded part of its pages, so its lock is only taken over once
+//!   the journal was rolled back, see [tests::test_torn_writer_kept_until_rolled_back].
+//! * Instances checking the legal hold of the lock object at the same time both went on, and one
+//!   of them panicked reading the lock object back. Both then wrote the metadata object, so two
+//!   writers could take the lock at once. The lock protocol now writes it on the ETag it read, see
+//!   [Inner::swap_metadata_record](crate::vfs::Inner::swap_metadata_record).
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+use aws_sdk_s3::config::BehaviorVersion;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::CacheUse,
+    config::{Config, LockConfig},
+    error::Error,
+    key::KeyLayout,
+    mock::MockS3,
+    protocol::now_ms,
+    role::Role as LeasedRole,
+    vfs::{Inner, MetadataRecord, ThreeQLite},
+};
+
+const ROLE: &str = "THREEQLITE_PROCESS_ROLE";
+const NAME: &str = "THREEQLITE_PROCESS_NAME";
+const RUN: &str = "THREEQLITE_PROCESS_RUN";
+const ENDPOINT: &str = "THREEQLITE_PROCESS_ENDPOINT";
+const BUCKET: &str = "THREEQLITE_PROCESS_BUCKET";
+const DB: &str = "THREEQLITE_PROCESS_DB";
+const ITERATIONS: &str = "THREEQLITE_PROCESS_ITERATIONS";
+const BUSY_TIMEOUT_MS: &str = "THREEQLITE_PROCESS_BUSY_TIMEOUT_MS";
+const TAKEOVER: &str = "THREEQLITE_PROCESS_TAKEOVER";
+
+/// The exit code of a `crasher`.
+const CRASHED: i32 = 3;
+/// The interval of the task a `stealer` claims the role for, a third of its lease.
+const ROLE_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a `stealer` works once it holds the role.
+const ROLE_WORK: Duration = Duration::from_millis(20);
+/// How long a client waits for the lock at most, unless its run says otherwise.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long a run may take at most before its clients are killed.
+const DEADLINE: Duration = Duration::from_secs(120);
+
+/// What a client process does, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Writer,
+    Reader,
+    Crasher,
+    Torn,
+    Stealer,
+}
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Writer => "writer",
+            Role::Reader => "reader",
+            Role::Crasher => "crasher",
+            Role::Torn => "torn",
+            Role::Stealer => "stealer",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        [
+            Role::Writer,
+            Role::Reader,
+            Role::Crasher,
+            Role::Torn,
+            Role::Stealer,
+        ]
+        .into_iter()
+        .find(|role| role.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A commit incrementing the counter to `value`, at `generation`.
+    Write,
+    /// A registered read of the counter at `value`, twice.
+    Read,
+    /// The write lock was taken and won't be released.
+    Hold,
+    /// A run of the leased role.
+    Role,
+    /// The iteration failed with `error`, ending the client.
+    Error,
+}
+
+/// What a client did in an iteration, from `start` to `end` in milliseconds since the Unix epoch.
+/// For reads and writes, that is while it held the lock.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub process: String,
+    pub pid: u32,
+    pub kind: EventKind,
+    pub start: u64,
+    pub end: u64,
+    pub value: u64,
+    pub generation: u64,
+    pub error: Option<String>,
+}
+
+impl Event {
+    fn overlaps(&self, other: &Event) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// A client process, configured by the environment its orchestrating test passed.
+struct Client {
+    role: Role,
+    name: String,
+    run: PathBuf,
+    iterations: u32,
+    tq: ThreeQLite,
+    log: File,
+}
+
+/// A client of the store at `endpoint`, with the credentials and region of the environment.
+async fn client(endpoint: &str) -> aws_sdk_s3::Client {
+    let sdk = aws_config::defaults(BehaviorVersion::latest())
+        .endpoint_url(endpoint)
+        .load()
+        .await;
+    let config = aws_sdk_s3::config::Builder::from(&sdk)
+        .force_path_style(true)
+        .build();
+    aws_sdk_s3::Client::from_conf(config)
+}
+
+/// The configuration of every instance of a run over `db` in `bucket`.
+fn config(bucket: &str, db: &str, lock: LockConfig) -> Config {
+    Config {
+        bucket: bucket.to_owned(),
+        db_filename: db.to_owned(),
+        lock_file: format!("{db}-lock"),
+        metadata_filename: format!("{db}-metadata"),
+        lock,
+        ..Config::default()
+    }
+}
+
+/// The counter in the database object, 0 while it is shorter.
+async fn counter(inner: &Inner) -> Result<u64, Error> {
+    let bytes = inner.read_at(0, 8, None, CacheUse::Bypass).await?;
+    Ok(bytes.try_into().map(u64::from_be_bytes).unwrap_or_default())
+}
+
+impl Client {
+    async fn from_env() -> Self {
+        let var = |name| std::env::var(name).unwrap_or_else(|_| panic!("{name} not set"));
+        let role = Role::parse(&var(ROLE)).expect("unknown role");
+        let name = var(NAME);
+        let run = PathBuf::from(var(RUN));
+        let busy_timeout = std::env::var(BUSY_TIMEOUT_MS)
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .map_or(BUSY_TIMEOUT, Duration::from_millis);
+        let lock = LockConfig {
+            busy_timeout: Some(busy_timeout),
+            take_over_dead_writers: std::env::var(TAKEOVER).as_deref() != Ok("0"),
+            ..LockConfig::default()
+        };
+        let config = config(&var(BUCKET), &var(DB), lock);
+        let tq = ThreeQLite::with_client(config, client(&var(ENDPOINT)).await).unwrap();
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(run.join(format!("{name}.jsonl")))
+            .unwrap();
+        Self {
+            role,
+            iterations: var(ITERATIONS).parse().unwrap(),
+            name,
+            run,
+            tq,
+            log,
+        }
+    }
+
+    fn record(&mut self, kind: EventKind, start: u64, value: u64, generation: u64) {
+        let event = Event {
+            process: self.name.clone(),
+            pid: std::process::id(),
+            kind,
+            start,
+            end: now_ms(),
+            value,
+            generation,
+            error: None,
+        };
+        self.append(&event);
+    }
+
+    fn append(&mut self, event: &Event) {
+        let mut line = serde_json::to_vec(event).unwrap();
+        line.push(b'\n');
+        self.log.write_all(&line).unwrap();
+    }
+
+    /// Run the iterations of the role once the run says go, returning whether all of them
+    /// succeeded.
+    async fn run(mut self) -> bool {
+        let go = self.run.join("go");
+        let waiting = Instant::now();
+        while !go.exists() {
+            assert!(waiting.elapsed() < DEADLINE, "never told to go");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        for _ in 0..self.iterations {
+            // boxed, the futures of the lock protocol are too large for the stack of a test
+            let res = Box::pin(self.iteration()).await;
+            if let Err(err) = res {
+                let event = Event {
+                    process: self.name.clone(),
+                    pid: std::process::id(),
+                    kind: EventKind::Error,
+                    start: now_ms(),
+                    end: now_ms(),
+                    value: 0,
+                    generation: 0,
+                    error: Some(err.to_string()),
+                };
+                self.append(&event);
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn iteration(&mut self) -> Result<(), Error> {
+        let tq = self.tq.clone();
+        match self.role {
+            Role::Writer => {
+                let mut inner = tq.inner.write().await;
+                inner.request_write_lock().await?;
+                let start = now_ms();
+                let value = counter(&inner).await? + 1;
+                let put = inner
+                    .s3
+                    .put_object()
+                    .bucket(&inner.bucket)
+                    .key(&inner.db_filename)
+                    .body(value.to_be_bytes().to_vec().into())
+                    .send()
+                    .await;
+                inner.written = put.is_ok();
+                inner.changed = None;
+                if let Err(err) = put {
+                    inner.release_write_lock().await?;
+                    return Err(err.into());
+                }
+                inner.release_write_lock().await?;
+                let generation = inner
+                    .generation_seen
+                    .load(std::sync::atomic::Ordering::Relaxed);
+                self.record(EventKind::Write, start, value, generation);
+            }
+            Role::Reader => {
+                let mut inner = tq.inner.write().await;
+                let generation = inner.request_read_lock().await?.unwrap_or_default();
+                let start = now_ms();
+                let first = counter(&inner).await?;
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                let second = counter(&inner).await?;
+                inner.release_read_lock().await?;
+                if first != second {
+                    snafu::whatever!("the counter changed from {first} to {second} under a read");
+                }
+                self.record(EventKind::Read, start, first, generation);
+            }
+            Role::Crasher => {
+                let mut inner = tq.inner.write().await;
+                inner.request_write_lock().await?;
+                let value = counter(&inner).await?;
+                self.record(EventKind::Hold, now_ms(), value, 0);
+                // as if killed: no destructor runs, and the lock stays taken
+                std::process::exit(CRASHED);
+            }
+            Role::Torn => {
+                let mut inner = tq.inner.write().await;
+                inner.request_write_lock().await?;
+                let value = counter(&inner).await?;
+                // the journal holds the counter before, as SQLite's holds the original pages
+                for (key, value) in [
+                    (KeyLayout::journal(&inner.db_filename), value),
+                    (inner.db_filename.clone(), value + 1),
+                ] {
+                    inner
+                        .s3
+                        .put_object()
+                        .bucket(&inner.bucket)
+                        .key(&key)
+                        .body(value.to_be_bytes().to_vec().into())
+                        .send()
+                        .await?;
+                }
+                self.record(EventKind::Hold, now_ms(), value + 1, 0);
+                // killed before the rest of its pages and its commit
+                std::process::exit(CRASHED);
+            }
+            Role::Stealer => {
+                let db = tq.inner.read().await.db_filename.to_string();
+                let work = async {
+                    let start = now_ms();
+                    tokio::time::sleep(ROLE_WORK).await;
+                    Ok(start)
+                };
+                let ran = tq
+                    .run_role(&db, LeasedRole::Reconcile, ROLE_INTERVAL, work)
+                    .await?;
+                if let Some(start) = ran {
+                    self.record(EventKind::Role, start, 0, 0);
+                }
+                tokio::time::sleep(ROLE_INTERVAL).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Client processes over one database, and the store they share, see the
+/// [module documentation](self).
+pub struct Run {
+    dir: PathBuf,
+    endpoint: String,
+    bucket: String,
+    db: String,
+    /// Serving the store unless the run is against an external one.
+    mock: Option<MockS3>,
+    tq: ThreeQLite,
+    children: Vec<(String, Child)>,
+}
+
+impl Run {
+    /// A run over a fresh database, with the counter at 0 and the lock free.
+    pub async fn start() -> Self {
+        let id = uuid::Uuid::new_v4();
+        let dir = std::env::temp_dir().join(format!("threeqlite-processes-{id}"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = format!("processes-{id}.db");
+        let (mock, endpoint, bucket, s3) = match std::env::var(ENDPOINT) {
+            Ok(endpoint) => {
+                let bucket = std::env::var(BUCKET).expect("the bucket to run in");
+                let s3 = client(&endpoint).await;
+                (None, endpoint, bucket, s3)
+            }
+            Err(_) => {
+                let mock = MockS3::start();
+                let endpoint = mock.endpoint();
+                let s3 = mock.client();
+                (Some(mock), endpoint, Config::default().bucket, s3)
+            }
+        };
+        let tq = ThreeQLite::with_client(config(&bucket, &db, LockConfig::default()), s3).unwrap();
+        {
+            let inner = tq.inner.read().await;
+            inner
+                .write_metadata_record(MetadataRecord::default())
+                .await
+                .unwrap();
+            inner
+                .s3
+                .put_object()
+                .bucket(&bucket)
+                .key(&db)
+                .body(0u64.to_be_bytes().to_vec().into())
+                .send()
+                .await
+                .unwrap();
+        }
+        Self {
+            dir,
+            endpoint,
+            bucket,
+            db,
+            mock,
+            tq,
+            children: vec![],
+        }
+    }
+
+    /// Start a client process `name` playing `role` for `iterations`, with the extra environment
+    /// `env`.
+    pub fn spawn(&mut self, name: &str, role: Role, iterations: u32, env: &[(&str, &str)]) {
+        let output = File::create(self.dir.join(format!("{name}.log"))).unwrap();
+        let child = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "processes::tests::role",
+                "--exact",
+                "--nocapture",
+                "--quiet",
+            ])
+            .env(ROLE, role.name())
+            .env(NAME, name)
+            .env(RUN, &self.dir)
+            .env(ENDPOINT, &self.endpoint)
+            .env(BUCKET, &self.bucket)
+            .env(DB, &self.db)
+            .env(ITERATIONS, iterations.to_string())
+            .envs(self.credentials())
+            .envs(env.iter().copied())
+            .stdin(Stdio::null())
+            .stdout(output.try_clone().unwrap())
+            .stderr(output)
+            .spawn()
+            .unwrap();
+        self.children.push((name.to_owned(), child));
+    }
+
+    /// The credentials of the mock, unless the run is against an external store.
+    fn credentials(&self) -> Vec<(&'static str, &'static str)> {
+        match self.mock {
+            Some(_) => vec![
+                ("AWS_ACCESS_KEY_ID", "test"),
+                ("AWS_SECRET_ACCESS_KEY", "test"),
+                ("AWS_REGION", "us-east-1"),
+            ],
+            None => vec![],
+        }
+    }
+
+    /// Let the clients started so far begin.
+    pub fn go(&self) {
+        File::create(self.dir.join("go")).unwrap();
+    }
+
+    /// Wait for every client started so far to exit, returning their exit status by name.
+    pub fn wait(&mut self) -> Vec<(String, ExitStatus)> {
+        let start = Instant::now();
+        let mut exited = vec![];
+        while !self.children.is_empty() {
+            let mut running = vec![];
+            for (name, mut child) in self.children.drain(..) {
+                match child.try_wait().unwrap() {
+                    Some(status) => exited.push((name, status)),
+                    None if start.elapsed() > DEADLINE => {
+                        let _ = child.kill();
+                        exited.push((name, child.wait().unwrap()));
+                    }
+                    None => running.push((name, child)),
+                }
+            }
+            self.children = running;
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        exited
+    }
+
+    /// Everything the clients recorded so far.
+    pub fn events(&self) -> Vec<Event> {
+        let mut events = vec![];
+        for entry in std::fs::read_dir(&self.dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "jsonl") {
+                let lines = std::fs::read_to_string(&path).unwrap();
+                events.extend(
+                    lines
+                        .lines()
+                        .map(|line| serde_json::from_str(line).unwrap()),
+                );
+            }
+        }
+        events.sort_by_key(|event: &Event| event.start);
+        events
+    }
+
+    /// What client `name` printed, for failure messages.
+    pub fn output(&self, name: &str) -> String {
+        std::fs::read_to_string(self.dir.join(format!("{name}.log"))).unwrap_or_default()
+    }
+
+    /// The counter as the store has it now.
+    pub async fn counter(&self) -> u64 {
+        counter(&*self.tq.inner.read().await).await.unwrap()
+    }
+
+    /// Roll back the journal a `torn` client left, as SQLite does with a hot journal: restore the
+    /// counter from it, then delete it.
+    pub async fn roll_back(&self) {
+        let inner = self.tq.inner.read().await;
+        let journal = KeyLayout::journal(&inner.db_filename);
+        let obj = inner.s3.get_object().bucket(&self.bucket).key(&journal);
+        let saved = obj.send().await.unwrap().body.collect().await.unwrap();
+        let put = inner.s3.put_object().bucket(&self.bucket).key(&self.db);
+        put.body(saved.into_bytes().to_vec().into())
+            .send()
+            .await
+            .unwrap();
+        let delete = inner.s3.delete_object().bucket(&self.bucket).key(&journal);
+        delete.send().await.unwrap();
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        for (_, child) in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        // kept for a look at what failed
+        if !std::thread::panicking() {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Check the invariants of the lock protocol on `events`: writers never overlapped each other or
+/// a reader, the increments of the counter are `1..=writes`, each seen by exactly one writer, and
+/// the generations recorded with them grow with it.
+pub fn check_exclusion(events: &[Event], writes: u64) {
+    let writers: Vec<_> = events
+        .iter()
+        .filter(|event| event.kind == EventKind::Write)
+        .collect();
+    let readers: Vec<_> = events
+        .iter()
+        .filter(|event| event.kind == EventKind::Read)
+        .collect();
+    for (i, write) in writers.iter().enumerate() {
+        for other in writers[i + 1..].iter().chain(&readers) {
+            assert!(
+                !write.overlaps(other),
+                "{write:?} overlaps {other:?}, both holding the lock"
+            );
+        }
+    }
+
+    let mut values: Vec<_> = writers.iter().map(|write| write.value).collect();
+    values.sort_unstable();
+    assert_eq!(values, (1..=writes).collect::<Vec<_>>(), "lost updates");
+    let mut by_value = writers.clone();
+    by_value.sort_by_key(|write| write.value);
+    for pair in by_value.windows(2) {
+        assert!(
+            pair[0].generation < pair[1].generation,
+            "generation didn't grow with the counter: {:?} then {:?}",
+            pair[0],
+            pair[1]
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The entry point of the client processes, see [Run::spawn]. Does nothing when the test
+    /// binary runs the tests.
+    #[test]
+    fn role() {
+        if std::env::var(ROLE).is_err() {
+            return;
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let ok = runtime.block_on(async { Client::from_env().await.run().await });
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    fn assert_exited(run: &Run, statuses: &[(String, ExitStatus)], code: i32) {
+        for (name, status) in statuses {
+            assert_eq!(
+                status.code(),
+                Some(code),
+                "{name} exited with {status}:\n{}",
+                run.output(name)
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_writers_and_readers() {
+        let mut run = Run::start().await;
+        for n in 0..3 {
+            run.spawn(&format!("writer-{n}"), Role::Writer, 8, &[]);
+        }
+        for n in 0..2 {
+            run.spawn(&format!("reader-{n}"), Role::Reader, 8, &[]);
+        }
+        run.go();
+        let statuses = tokio::task::block_in_place(|| run.wait());
+        assert_exited(&run, &statuses, 0);
+
+        let events = run.events();
+        check_exclusion(&events, 24);
+        let reads = events.iter().filter(|e| e.kind == EventKind::Read).count();
+        assert_eq!(reads, 16);
+        let pids: std::collections::HashSet<_> = events.iter().map(|e| e.pid).collect();
+        assert_eq!(pids.len(), 5);
+        assert_eq!(run.counter().await, 24);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_crashed_writer_taken_over() {
+        // without taking over, the lock of the crashed writer is never released
+        let mut run = Run::start().await;
+        run.spawn("crasher", Role::Crasher, 1, &[]);
+        run.go();
+        let statuses = tokio::task::block_in_place(|| run.wait());
+        assert_exited(&run, &statuses, CRASHED);
+        let stuck = [(TAKEOVER, "0"), (BUSY_TIMEOUT_MS, "1000")];
+        run.spawn("writer", Role::Writer, 1, &stuck);
+        let statuses = tokio::task::block_in_place(|| run.wait());
+        assert_exited(&run, &statuses, 1);
+        let error = run.events().pop().unwrap();
+        assert_eq!(error.kind, EventKind::Error);
+        assert!(
+            error.error.unwrap().contains("held by"),
+            "{}",
+            run.output("writer")
+        );
+
+        // taken over once the writer is gone, by writers and readers alike
+        let mut run = Run::start().await;
+        run.spawn("crasher", Role::Crasher, 1, &[]);
+        run.go();
+        let statuses = tokio::task::block_in_place(|| run.wait());
+        assert_exited(&run, &statuses, CRASHED);
+        run.spawn("reader", Role::Reader, 3, &[]);
+        for n in 0..2 {
+            run.spawn(&format!("writer-{n}"), Role::Writer, 4, &[]);
+        }
+        let statuses = tokio::task::block_in_place(|| run.wait());
+        assert_exited(&run, &statuses, 0);
+        let events = run.events();
+        check_exclusion(&events, 8);
+        assert_eq!(run.counter().await, 8);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_torn_writer_kept_until_rolled_back() {
+        let mut run = Run::start().await;
+        run.spawn("torn", Role::Torn, 1, &[]);
+        run.go();
+        let statuses = tokio::task::block_in_place(|| run.wait());
+        assert_exited(&run, &statuses, CRASHED);
+        assert_eq!(run.counter().await, 1);
+
+        // its journal keeps the lock held, for writers and readers alike
+        let waiting = [(BUSY_TIMEOUT_MS, "1000")];
+        run.spawn("reader", Role::Reader, 1, &waiting);
+        run.spawn("writer", Role::Writer, 1, &waiting);
+        let statuses = tokio::task::block_in_place(|| run.wait());
+        assert_exited(&run, &statuses, 1);
+        let errors: Vec<_> = run
+            .events()
+            .into_iter()
+            .filter(|event| event.kind == EventKind::Error)
+            .collect();
+        assert_eq!(errors.len(), 2);
+        for error in errors {
+            let output = run.output(&error.process);
+            assert!(error.error.unwrap().contains("held by"), "{output}");
+        }
+
+        // taken over once the journal was rolled back, without the torn write
+        run.roll_back().await;
+        assert_eq!(run.counter().await, 0);
+        run.spawn("reader", Role::Reader, 2, &[]);
+        run.spawn("writer", Role::Writer, 3, &[]);
+        let statuses = tokio::task::block_in_place(|| run.wait());
+        assert_exited(&run, &statuses, 0);
+        assert_eq!(run.counter().await, 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_role_worked_in_one_process_at_a_time() {
+        let mut run = Run::start().await;
+        // the first holder stops early, leaving its lease to run out
+        run.spawn("stealer-0", Role::Stealer, 3, &[]);
+        run.go();
+        let start = Instant::now();
+        while run.events().is_empty() {
+            assert!(start.elapsed() < DEADLINE, "{}", run.output("stealer-0"));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for n in 1..3 {
+            run.spawn(&format!("stealer-{n}"), Role::Stealer, 15, &[]);
+        }
+        let statuses = tokio::task::block_in_place(|| run.wait());
+        assert_exited(&run, &statuses, 0);
+
+        let runs: Vec<_> = run
+            .events()
+            .into_iter()
+            .filter(|event| event.kind == EventKind::Role)
+            .collect();
+        for (i, one) in runs.iter().enumerate() {
+            for other in &runs[i + 1..] {
+                assert!(
+                    one.process == other.process || !one.overlaps(other),
+                    "{one:?} overlaps {other:?}"
+                );
+            }
+        }
+        let holders: std::collections::HashSet<_> = runs.iter().map(|e| &e.process).collect();
+        assert!(holders.len() >= 2, "never taken over: {runs:?}");
+    }
+}
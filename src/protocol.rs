@@ -0,0 +1,414 @@
+//! The reader/writer protocol on the metadata object.
+//!
+//! Readers register in [Metadata::Reader] and writers take [Metadata::Writer], both while holding
+//! the metadata lock. A writer that finds active readers leaves a [WriteRequest] instead, which
+//! keeps new readers out until the active ones are gone. Requests carry a lease: a writer that
+//! crashed stops refreshing its request, and once the lease ran out readers ignore it. A writer
+//! refreshes its request whenever less than half of the lease is left, so a live writer keeps
+//! new readers out until it acquired.
+//!
+//! The request is stored as user metadata of the metadata object, next to the [Stamp], so that it
+//! is honored in every metadata state. It is mirrored into [ReaderMetadata::write_request] for
+//! readers that predate leases. Leases are compared against the wall clock, so the clocks of all
+//! instances must agree to within a fraction of [LockConfig::write_request_lease].
+//!
+//! The functions here are pure; [crate::vfs::Inner] applies them under the metadata lock.
+//!
+//! [Stamp]: crate::heal::Stamp
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::LockConfig,
+    format,
+    vfs::{Metadata, MetadataRecord, ReaderMetadata},
+};
+
+const WRITE_REQUEST: &str = "threeqlite-write-request";
+const WRITE_REQUEST_EXPIRES: &str = "threeqlite-write-request-expires";
+
+/// Milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// A writer waiting for the active readers to leave.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteRequest {
+    /// The lock ID of the writer.
+    pub id: Vec<u8>,
+    /// End of the lease, in milliseconds since the Unix epoch.
+    pub expires: u64,
+}
+
+impl WriteRequest {
+    pub fn live(&self, now: u64) -> bool {
+        now < self.expires
+    }
+
+    pub fn from_metadata(metadata: Option<&HashMap<String, String>>) -> Option<Self> {
+        let metadata = metadata?;
+        let id = metadata.get(WRITE_REQUEST)?;
+        let id = (0..id.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(id.get(i..i + 2)?, 16).ok())
+            .collect::<Option<_>>()?;
+        Some(Self {
+            id,
+            expires: metadata.get(WRITE_REQUEST_EXPIRES)?.parse().ok()?,
+        })
+    }
+
+    pub fn to_metadata(&self) -> HashMap<String, String> {
+        let id = self.id.iter().map(|b| format!("{b:02x}")).collect();
+        HashMap::from([
+            (WRITE_REQUEST.to_owned(), id),
+            (WRITE_REQUEST_EXPIRES.to_owned(), self.expires.to_string()),
+        ])
+    }
+}
+
+/// What a reader does with the metadata object it found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReaderDecision {
+    Join,
+    /// A writer holds the lock or waits for it.
+    Defer,
+}
+
+/// What a writer does with the metadata object it found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WriterDecision {
+    Acquire,
+    /// Store this request, which is new or a refresh of the writer's request.
+    Request(WriteRequest),
+    Wait,
+}
+
+fn live_request(record: &MetadataRecord, now: u64) -> Option<&WriteRequest> {
+    record.write_request.as_ref().filter(|req| req.live(now))
+}
+
+pub fn reader_decision(record: &MetadataRecord, now: u64) -> ReaderDecision {
+    match (&record.metadata, live_request(record, now)) {
+        (Metadata::Writer(_), _) | (_, Some(_)) => ReaderDecision::Defer,
+        (Metadata::None | Metadata::Reader(_), None) => ReaderDecision::Join,
+    }
+}
+
+pub fn writer_decision(
+    record: &MetadataRecord,
+    id: &[u8],
+    now: u64,
+    config: &LockConfig,
+) -> WriterDecision {
+    let request = live_request(record, now);
+    if request.is_some_and(|req| req.id != id) {
+        return WriterDecision::Wait;
+    }
+    match &record.metadata {
+        Metadata::Writer(_) => WriterDecision::Wait,
+        Metadata::Reader(reader) if !reader.readers.is_empty() => {
+            let lease = config.write_request_lease.as_millis() as u64;
+            match request {
+                Some(req) if req.expires - now > lease / 2 => WriterDecision::Wait,
+                _ => WriterDecision::Request(WriteRequest {
+                    id: id.to_vec(),
+                    expires: now + lease,
+                }),
+            }
+        }
+        Metadata::None | Metadata::Reader(_) => WriterDecision::Acquire,
+    }
+}
+
+/// Register reader `id`.
+pub fn join(record: MetadataRecord, id: &[u8]) -> MetadataRecord {
+    let (mut readers, mut reader_versions) = match record.metadata {
+        Metadata::Reader(reader) => (reader.readers, record.reader_versions),
+        Metadata::None | Metadata::Writer(_) => (vec![], vec![]),
+    };
+    readers.push(id.to_vec());
+    // advertise the newest format we can read
+    reader_versions.push((id.to_vec(), *format::READ_VERSIONS.end()));
+    MetadataRecord {
+        metadata: Metadata::Reader(ReaderMetadata {
+            readers,
+            write_request: None,
+        }),
+        reader_versions,
+        // an expired request is dropped by the first reader that ignores it
+        write_request: None,
+        holder: None,
+        ..record
+    }
+}
+
+/// Unregister reader `id`. `None` if it isn't registered.
+pub fn leave(record: MetadataRecord, id: &[u8]) -> Option<MetadataRecord> {
+    let Metadata::Reader(reader) = record.metadata else {
+        return None;
+    };
+    Some(MetadataRecord {
+        metadata: Metadata::Reader(ReaderMetadata {
+            readers: reader.readers.into_iter().filter(|r| r != id).collect(),
+            write_request: reader.write_request,
+        }),
+        reader_versions: record
+            .reader_versions
+            .into_iter()
+            .filter(|(r, _)| r != id)
+            .collect(),
+        ..record
+    })
+}
+
+/// Store `request`, keeping the active readers.
+pub fn request(record: MetadataRecord, request: WriteRequest) -> MetadataRecord {
+    let metadata = match record.metadata {
+        Metadata::Reader(reader) => Metadata::Reader(ReaderMetadata {
+            readers: reader.readers,
+            write_request: Some(request.id.clone()),
+        }),
+        metadata => metadata,
+    };
+    MetadataRecord {
+        metadata,
+        write_request: Some(request),
+        ..record
+    }
+}
+
+/// Hand the lock to writer `id`.
+pub fn acquire(record: MetadataRecord, id: &[u8]) -> MetadataRecord {
+    MetadataRecord {
+        metadata: Metadata::Writer(id.to_vec()),
+        reader_versions: vec![],
+        write_request: None,
+        ..record
+    }
+}
+
+/// Drop the write lock of a writer that went away without releasing it, see [crate::busy].
+pub fn abandon(record: MetadataRecord) -> MetadataRecord {
+    MetadataRecord {
+        metadata: Metadata::None,
+        holder: None,
+        ..record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn config() -> LockConfig {
+        LockConfig {
+            write_request_lease: Duration::from_millis(1000),
+            ..LockConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_write_request_roundtrip() {
+        let req = WriteRequest {
+            id: vec![0, 1, 0xab, 0xff],
+            expires: 1700000000000,
+        };
+        assert_eq!(
+            WriteRequest::from_metadata(Some(&req.to_metadata())),
+            Some(req)
+        );
+        assert_eq!(WriteRequest::from_metadata(Some(&HashMap::new())), None);
+        let odd = HashMap::from([
+            (WRITE_REQUEST.to_owned(), "abc".to_owned()),
+            (WRITE_REQUEST_EXPIRES.to_owned(), "1".to_owned()),
+        ]);
+        assert_eq!(WriteRequest::from_metadata(Some(&odd)), None);
+    }
+
+    #[test]
+    fn test_request_honored_in_every_state() {
+        let req = WriteRequest {
+            id: vec![9],
+            expires: 100,
+        };
+        // a request left behind after the last reader left
+        let idle = request(MetadataRecord::default(), req.clone());
+        assert_eq!(reader_decision(&idle, 50), ReaderDecision::Defer);
+        assert_eq!(reader_decision(&idle, 100), ReaderDecision::Join);
+        assert_eq!(
+            writer_decision(&idle, &[9], 50, &config()),
+            WriterDecision::Acquire
+        );
+        assert_eq!(
+            writer_decision(&idle, &[8], 50, &config()),
+            WriterDecision::Wait
+        );
+        assert_eq!(
+            writer_decision(&idle, &[8], 100, &config()),
+            WriterDecision::Acquire
+        );
+
+        let reading = request(join(MetadataRecord::default(), &[1]), req);
+        assert_eq!(reader_decision(&reading, 50), ReaderDecision::Defer);
+        let Metadata::Reader(reader) = &reading.metadata else {
+            unreachable!()
+        };
+        // mirrored for readers without lease support
+        assert_eq!(reader.write_request, Some(vec![9]));
+    }
+
+    /// A deterministic schedule: one tick is 10 ms. Readers arrive every `arrival` ticks and hold
+    /// their lock for `hold` ticks. The writer polls every 5 ticks, unless it `crashed`.
+    struct Model {
+        record: MetadataRecord,
+        now: u64,
+        active: Vec<(Vec<u8>, u64)>,
+        next_reader: u8,
+        defers: u64,
+    }
+
+    impl Model {
+        fn new() -> Self {
+            Self {
+                record: MetadataRecord::default(),
+                now: 1_000_000,
+                active: vec![],
+                next_reader: 0,
+                defers: 0,
+            }
+        }
+
+        fn tick(&mut self, tick: u64, arrival: u64, hold: u64) {
+            self.now += 10;
+            let leaving: Vec<_> = self
+                .active
+                .iter()
+                .filter(|(_, until)| *until <= self.now)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in leaving {
+                self.record = leave(self.record.clone(), &id).unwrap();
+                self.active.retain(|(r, _)| r != &id);
+            }
+            if tick.is_multiple_of(arrival) {
+                let id = vec![self.next_reader];
+                self.next_reader = self.next_reader.wrapping_add(1);
+                match reader_decision(&self.record, self.now) {
+                    ReaderDecision::Join => {
+                        self.record = join(self.record.clone(), &id);
+                        self.active.push((id, self.now + hold * 10));
+                    }
+                    ReaderDecision::Defer => self.defers += 1,
+                }
+            }
+        }
+
+        /// Returns whether the writer acquired.
+        fn poll_writer(&mut self, id: &[u8]) -> bool {
+            match writer_decision(&self.record, id, self.now, &config()) {
+                WriterDecision::Acquire => {
+                    self.record = acquire(self.record.clone(), id);
+                    true
+                }
+                WriterDecision::Request(req) => {
+                    self.record = request(self.record.clone(), req);
+                    false
+                }
+                WriterDecision::Wait => false,
+            }
+        }
+    }
+
+    #[test]
+    fn test_live_writer_acquires_under_continuous_readers() {
+        // readers overlap all the time, so there is never a moment without one
+        for (arrival, hold) in [(1, 3), (2, 7), (3, 20)] {
+            let mut model = Model::new();
+            for tick in 0..50 {
+                model.tick(tick, arrival, hold);
+            }
+            assert!(!model.active.is_empty());
+
+            let mut acquired_at = None;
+            for tick in 50..1000 {
+                model.tick(tick, arrival, hold);
+                if tick.is_multiple_of(5) && model.poll_writer(&[200]) {
+                    acquired_at = Some(tick);
+                    break;
+                }
+            }
+            let acquired_at = acquired_at.expect("writer starved");
+            // bounded by the longest read plus one poll
+            assert!(
+                acquired_at - 50 <= hold + 5,
+                "{arrival} {hold} {acquired_at}"
+            );
+            assert!(model.active.is_empty());
+            assert!(model.defers > 0);
+        }
+    }
+
+    #[test]
+    fn test_request_refreshed_while_readers_drain() {
+        let mut model = Model::new();
+        // a reader that holds its lock for longer than the lease
+        model.record = join(model.record.clone(), &[1]);
+        assert!(!model.poll_writer(&[200]));
+        let first = model.record.write_request.clone().unwrap();
+        for _ in 0..150 {
+            model.now += 10;
+            assert!(!model.poll_writer(&[200]));
+            assert_eq!(
+                reader_decision(&model.record, model.now),
+                ReaderDecision::Defer
+            );
+        }
+        assert!(model.record.write_request.as_ref().unwrap().expires > first.expires);
+
+        model.record = leave(model.record.clone(), &[1]).unwrap();
+        assert!(model.poll_writer(&[200]));
+        assert!(model.record.write_request.is_none());
+    }
+
+    #[test]
+    fn test_expired_request_does_not_block_readers() {
+        let mut model = Model::new();
+        for tick in 0..10 {
+            model.tick(tick, 1, 3);
+        }
+        // the writer requests once and crashes
+        assert!(!model.poll_writer(&[200]));
+        assert!(model.record.write_request.is_some());
+
+        let lease_ticks = config().write_request_lease.as_millis() as u64 / 10;
+        let mut joined = 0;
+        for tick in 10..10 + lease_ticks + 20 {
+            let before = model.defers;
+            model.tick(tick, 1, 3);
+            if tick < 10 + lease_ticks - 1 {
+                assert_eq!(model.defers, before + 1, "{tick}");
+            } else if model.defers == before {
+                joined += 1;
+            }
+        }
+        assert!(joined >= 19, "{joined}");
+        // the first reader past the lease cleared the request
+        assert!(model.record.write_request.is_none());
+
+        // another writer isn't blocked by the dead request either
+        assert!(matches!(
+            writer_decision(&model.record, &[201], model.now, &config()),
+            WriterDecision::Request(_)
+        ));
+    }
+}
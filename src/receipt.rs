@@ -0,0 +1,414 @@
+//! Receipts proving that a commit reached a generation.
+//!
+//! Applications recording that a business operation is durable as of a commit, e.g. audit
+//! pipelines, can keep the [CommitReceipt] of that commit and later ask
+//! [ThreeQLite::verify_receipt] whether the database still descends from it. The receipt of the
+//! last commit of an instance is passed to [TransactionObserver::on_commit], returned by
+//! [ThreeQLite::last_commit_receipt], and by `PRAGMA threeqlite_receipt` on any connection;
+//! `PRAGMA threeqlite_verify_receipt='<receipt>'` verifies one.
+//!
+//! Every commit links the [Stamp] of its generation to that of its parent: the [Link] hashes the
+//! link of the parent, the generation, the commit time and the ETag of the database object as
+//! committed. The stamp also keeps the links of the [HISTORY] generations before it. The links
+//! are hashes, not signatures, so they detect accidents, not a forger with write access.
+//!
+//! Verification reads the metadata object once and reports
+//! - [ReceiptStatus::Present] if the database is at the generation of the receipt, with its link,
+//! - [ReceiptStatus::Ancestor] if it moved on from there, with the link among the ancestors,
+//! - [ReceiptStatus::Orphaned] if its lineage no longer contains the commit: it is behind the
+//!   generation of the receipt, typically after a restore of an older snapshot, or the link at
+//!   that generation differs, since the restored database committed again,
+//! - [ReceiptStatus::Unverifiable] if the generation of the receipt is further back than the
+//!   history kept, or was committed before links were recorded.
+//!
+//! A receipt only speaks for the commit records. Replacing the database object out-of-band leaves
+//! them intact, but is caught by the length check of [crate::heal], and the next commit links the
+//! ETag of the replaced object.
+//!
+//! [ThreeQLite::verify_receipt]: crate::vfs::ThreeQLite::verify_receipt
+//! [ThreeQLite::last_commit_receipt]: crate::vfs::ThreeQLite::last_commit_receipt
+//! [TransactionObserver::on_commit]: crate::latency::TransactionObserver::on_commit
+
+use std::{fmt, str::FromStr};
+
+use crate::heal::Stamp;
+
+/// The generations before the current one whose links a [Stamp] keeps.
+pub const HISTORY: usize = 16;
+
+/// The hash linking a generation to its parent, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Link(pub [u8; 16]);
+
+impl Link {
+    /// The link of `generation`, committed at `committed_at` with the database object at
+    /// `content`, whose parent is linked by `parent`.
+    pub fn next(
+        parent: Option<Link>,
+        generation: u64,
+        committed_at: u64,
+        content: Option<&str>,
+    ) -> Self {
+        let mut md5 = md5::Context::new();
+        md5.consume(parent.map_or([0; 16], |parent| parent.0));
+        md5.consume(generation.to_be_bytes());
+        md5.consume(committed_at.to_be_bytes());
+        md5.consume(content.unwrap_or_default());
+        Self(md5.compute().0)
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl FromStr for Link {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid link {s:?}");
+        if s.len() != 32 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+/// Proof that a commit reached a generation, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitReceipt {
+    pub db: String,
+    pub generation: u64,
+    pub link: Link,
+    /// When the commit was recorded, in milliseconds since the Unix epoch.
+    pub committed_at: u64,
+    /// The ETag of the metadata object recording the commit.
+    pub record_etag: Option<String>,
+    /// The ETag of the database object as committed, `None` if the commit didn't write it.
+    pub content_etag: Option<String>,
+}
+
+/// Formatted as `generation=.. link=.. committed_at=.. [record=..] [content=..] db=..`, which
+/// [CommitReceipt::from_str] parses back.
+impl fmt::Display for CommitReceipt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "generation={} link={} committed_at={}",
+            self.generation, self.link, self.committed_at
+        )?;
+        if let Some(etag) = &self.record_etag {
+            write!(f, " record={etag}")?;
+        }
+        if let Some(etag) = &self.content_etag {
+            write!(f, " content={etag}")?;
+        }
+        // last, as the only field that may contain spaces
+        write!(f, " db={}", self.db)
+    }
+}
+
+impl FromStr for CommitReceipt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (fields, db) = s
+            .split_once(" db=")
+            .ok_or_else(|| format!("receipt without db: {s:?}"))?;
+        let (mut generation, mut link, mut committed_at) = (None, None, None);
+        let (mut record_etag, mut content_etag) = (None, None);
+        for field in fields.split_whitespace() {
+            let invalid = || format!("invalid receipt field {field:?}");
+            match field.split_once('=').ok_or_else(invalid)? {
+                ("generation", value) => generation = Some(value.parse().map_err(|_| invalid())?),
+                ("link", value) => link = Some(value.parse()?),
+                ("committed_at", value) => {
+                    committed_at = Some(value.parse().map_err(|_| invalid())?)
+                }
+                ("record", value) => record_etag = Some(value.to_owned()),
+                ("content", value) => content_etag = Some(value.to_owned()),
+                _ => return Err(invalid()),
+            }
+        }
+        let missing = |name| format!("receipt without {name}: {s:?}");
+        Ok(Self {
+            db: db.to_owned(),
+            generation: generation.ok_or_else(|| missing("generation"))?,
+            link: link.ok_or_else(|| missing("link"))?,
+            committed_at: committed_at.ok_or_else(|| missing("committed_at"))?,
+            record_etag,
+            content_etag,
+        })
+    }
+}
+
+/// A commit about to be recorded, see [crate::vfs::Inner::commit_stamp].
+#[derive(Clone, Debug)]
+pub struct Commit {
+    pub stamp: Stamp,
+    pub committed_at: u64,
+    pub content_etag: Option<String>,
+}
+
+impl Commit {
+    /// The receipt of the commit once recorded in the metadata object with the ETag
+    /// `record_etag`.
+    pub fn receipt(self, db: &str, record_etag: Option<String>) -> CommitReceipt {
+        CommitReceipt {
+            db: db.to_owned(),
+            generation: self.stamp.generation,
+            // set by Stamp::advance
+            link: self.stamp.link.unwrap(),
+            committed_at: self.committed_at,
+            record_etag,
+            content_etag: self.content_etag,
+        }
+    }
+}
+
+/// Whether the lineage of a database contains the commit of a receipt, see the
+/// [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReceiptStatus {
+    Present,
+    Ancestor,
+    Orphaned,
+    Unverifiable,
+}
+
+impl ReceiptStatus {
+    /// The status of `receipt` in the lineage ending in `stamp`.
+    pub fn of(receipt: &CommitReceipt, stamp: &Stamp) -> Self {
+        let Some(back) = stamp.generation.checked_sub(receipt.generation) else {
+            return Self::Orphaned;
+        };
+        let link = match back {
+            0 => stamp.link,
+            back => match stamp.ancestors.get(back as usize - 1) {
+                Some(link) => *link,
+                None => return Self::Unverifiable,
+            },
+        };
+        match link {
+            Some(link) if link != receipt.link => Self::Orphaned,
+            Some(_) if back == 0 => Self::Present,
+            Some(_) => Self::Ancestor,
+            None => Self::Unverifiable,
+        }
+    }
+}
+
+impl fmt::Display for ReceiptStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Present => "present",
+            Self::Ancestor => "ancestor",
+            Self::Orphaned => "orphaned",
+            Self::Unverifiable => "unverifiable",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use sqlite_vfs::DatabaseHandle;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        handle::Handle,
+        key::KeyLayout,
+        latency::{TransactionBreakdown, TransactionObserver},
+        mock::{self, MockS3},
+        vfs::{Metadata, ThreeQLite},
+    };
+
+    fn receipt(stamp: &Stamp) -> CommitReceipt {
+        CommitReceipt {
+            db: "my db.db".to_owned(),
+            generation: stamp.generation,
+            link: stamp.link.unwrap(),
+            committed_at: 1700000000000,
+            record_etag: Some("\"abc\"".to_owned()),
+            content_etag: None,
+        }
+    }
+
+    #[test]
+    fn test_receipt_roundtrip() {
+        let link = Link::next(None, 1, 1700000000000, Some("\"abc\""));
+        assert_eq!(link.to_string().parse(), Ok(link));
+        assert!("xyz".parse::<Link>().is_err());
+        let mut stamp = Stamp::new(0);
+        stamp.advance(1, None);
+        let receipt = receipt(&stamp);
+        assert_eq!(receipt.to_string().parse(), Ok(receipt.clone()));
+        assert!(receipt
+            .to_string()
+            .replace("generation=1 ", "")
+            .parse::<CommitReceipt>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_status() {
+        // committed before links were recorded
+        let mut stamp = Stamp::new(5);
+        stamp.advance(1, Some("\"a\""));
+        let first = receipt(&stamp);
+        assert_eq!(ReceiptStatus::of(&first, &stamp), ReceiptStatus::Present);
+
+        let restore_point = stamp;
+        stamp.advance(2, Some("\"b\""));
+        let second = receipt(&stamp);
+        for n in 0..HISTORY as u64 {
+            stamp.advance(3 + n, None);
+        }
+        assert_eq!(ReceiptStatus::of(&second, &stamp), ReceiptStatus::Ancestor);
+        // out of history
+        assert_eq!(
+            ReceiptStatus::of(&first, &stamp),
+            ReceiptStatus::Unverifiable
+        );
+        let before_links = CommitReceipt {
+            generation: 5,
+            ..first.clone()
+        };
+        assert_eq!(
+            ReceiptStatus::of(&before_links, &restore_point),
+            ReceiptStatus::Unverifiable
+        );
+
+        // restored to the generation of the first receipt, then committed again
+        let mut restored = restore_point;
+        assert_eq!(ReceiptStatus::of(&first, &restored), ReceiptStatus::Present);
+        assert_eq!(
+            ReceiptStatus::of(&second, &restored),
+            ReceiptStatus::Orphaned
+        );
+        restored.advance(100, Some("\"c\""));
+        assert_eq!(
+            ReceiptStatus::of(&first, &restored),
+            ReceiptStatus::Ancestor
+        );
+        assert_eq!(
+            ReceiptStatus::of(&second, &restored),
+            ReceiptStatus::Orphaned
+        );
+    }
+
+    /// Commit the database object as it is in `mock` after writing `counter` to it.
+    async fn commit(tq: &ThreeQLite, mock: &MockS3, counter: u32) -> CommitReceipt {
+        mock.put("test.db", mock::database(4096, 4, counter));
+        let mut inner = tq.inner.write().await;
+        let record = inner.read_metadata_record().await.unwrap();
+        inner.written = true;
+        let receipt = inner.record_commit(record, b"writer").await.unwrap();
+        // as release_write_lock does once the metadata object is unlocked
+        inner.transactions.committed(receipt.clone());
+        receipt
+    }
+
+    #[tokio::test]
+    async fn test_restore_orphans_later_receipts() {
+        #[derive(Default)]
+        struct Observed(Mutex<Vec<CommitReceipt>>);
+
+        impl TransactionObserver for Observed {
+            fn on_transaction(&self, _: &str, _: &TransactionBreakdown) {}
+
+            fn on_commit(&self, receipt: &CommitReceipt) {
+                self.0.lock().unwrap().push(receipt.clone());
+            }
+        }
+
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let observer = Arc::new(Observed::default());
+        tq.observe_transactions(observer.clone()).await;
+        tq.inner
+            .read()
+            .await
+            .write_metadata(Metadata::None, Some(Stamp::new(0)))
+            .await
+            .unwrap();
+        let status = |receipt: &CommitReceipt| {
+            let (tq, receipt) = (tq.clone(), receipt.clone());
+            async move { tq.verify_receipt("test.db", &receipt).await.unwrap() }
+        };
+
+        let first = commit(&tq, &mock, 1).await;
+        assert_eq!(first.generation, 1);
+        assert_eq!(
+            first.content_etag.as_deref(),
+            Some(format!("\"{:x}\"", md5::compute(mock.get("test.db").unwrap())).as_str())
+        );
+        assert!(first.record_etag.is_some());
+        assert_eq!(status(&first).await, ReceiptStatus::Present);
+        // a snapshot of both objects
+        let snapshot = (
+            tq.inner.read().await.read_metadata_record().await.unwrap(),
+            mock.get("test.db").unwrap(),
+        );
+
+        let second = commit(&tq, &mock, 2).await;
+        let third = commit(&tq, &mock, 3).await;
+        assert_eq!(third.generation, 3);
+        assert_eq!(tq.last_commit_receipt("test.db").await, Some(third.clone()));
+        assert_eq!(
+            *observer.0.lock().unwrap(),
+            [&first, &second, &third].map(Clone::clone)
+        );
+        assert_eq!(status(&third).await, ReceiptStatus::Present);
+        assert_eq!(status(&second).await, ReceiptStatus::Ancestor);
+        assert_eq!(status(&first).await, ReceiptStatus::Ancestor);
+
+        // from a plain connection
+        let mut handle = Handle::new(tq.clone(), KeyLayout::db("test.db").unwrap(), false);
+        let pragma = handle.pragma("threeqlite_receipt", None).await.unwrap();
+        assert_eq!(pragma, Some(third.to_string()));
+        let pragma = handle
+            .pragma("threeqlite_verify_receipt", Some(&second.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(pragma.as_deref(), Some("ancestor"));
+        assert!(handle
+            .pragma("threeqlite_verify_receipt", Some("generation=2"))
+            .await
+            .is_err());
+
+        // restored to the snapshot
+        mock.put("test.db", snapshot.1);
+        tq.inner
+            .read()
+            .await
+            .write_metadata_record(snapshot.0)
+            .await
+            .unwrap();
+        assert_eq!(status(&first).await, ReceiptStatus::Present);
+        assert_eq!(status(&second).await, ReceiptStatus::Orphaned);
+        assert_eq!(status(&third).await, ReceiptStatus::Orphaned);
+
+        // and committed to again, reaching the generation of the second receipt anew
+        let again = commit(&tq, &mock, 2).await;
+        assert_eq!(again.generation, second.generation);
+        assert_eq!(status(&again).await, ReceiptStatus::Present);
+        assert_eq!(status(&second).await, ReceiptStatus::Orphaned);
+        assert_eq!(status(&first).await, ReceiptStatus::Ancestor);
+
+        // receipts name their database
+        let elsewhere = CommitReceipt {
+            db: "other.db".to_owned(),
+            ..again
+        };
+        assert_eq!(status(&elsewhere).await, ReceiptStatus::Unverifiable);
+    }
+}
@@ -0,0 +1,443 @@
+//! Finding the sidecar objects of a database without listing on open.
+//!
+//! Opening a database reads the metadata object and the database object only, so its cost does
+//! not grow with the number of objects next to it. What only a `ListObjectsV2` can find is left
+//! to two bounded operations:
+//!
+//! - [ThreeQLite::reconcile] pages through the sidecars of a database in runs of at most
+//!   [ReconcileConfig::max_keys_per_run] keys, each run resuming where the previous one stopped,
+//!   and reports super-journals that no journal refers to any longer. Call it from a background
+//!   task, e.g. [ThreeQLite::spawn_reconcile], or run it to completion like `threeqlite
+//!   reconcile` does. Of the instances sharing a database, only the one holding its
+//!   [Role::Reconcile] runs the task, see [crate::role].
+//! - [ThreeQLite::wal_segments] lists the WAL segment prefix alone, for crash recovery, in at
+//!   most [ReconcileConfig::max_wal_pages] pages per call.
+//!
+//! Every LIST request and the keys it returned are counted in [Stats::list_requests] and
+//! [Stats::keys_listed].
+//!
+//! [Stats::list_requests]: crate::stats::Stats::list_requests
+//! [Stats::keys_listed]: crate::stats::Stats::keys_listed
+
+use std::{collections::HashMap, fmt, sync::Mutex, time::Duration};
+
+use crate::{
+    error::Error,
+    journal::{self, JournalKind},
+    key::{KeyLayout, ObjectKey},
+    role::Role,
+    vfs::{Inner, ThreeQLite},
+};
+
+#[derive(Clone, Debug)]
+pub struct ReconcileConfig {
+    /// Keys a single [ThreeQLite::reconcile] run lists at most.
+    pub max_keys_per_run: usize,
+    /// Pages of 1000 keys a single [ThreeQLite::wal_segments] call lists at most.
+    pub max_wal_pages: usize,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        Self {
+            max_keys_per_run: 1000,
+            max_wal_pages: 10,
+        }
+    }
+}
+
+/// An object stored next to a database, see [KeyLayout].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sidecar {
+    Manifest,
+    /// An extent of the manifest, by its first block, see [crate::extent].
+    ManifestExtent(u64),
+    HotSet,
+    WarmSet,
+    CopyProgress,
+    Chunk(u64),
+    WalSegment(u64),
+    Journal,
+    SuperJournal,
+    Wal,
+    /// The lease of a background role, see [crate::role].
+    RoleLease,
+}
+
+impl Sidecar {
+    /// What `key` is to `db`, `None` if it isn't one of its sidecars, e.g. the database itself
+    /// or another database whose name starts with the same characters.
+    pub fn of(db: &ObjectKey, key: &str) -> Option<Self> {
+        let rest = key.strip_prefix(db.as_str())?;
+        let index = |n: &str| match n.len() == 10 {
+            true => n.parse().ok(),
+            false => None,
+        };
+        if let Some(n) = rest.strip_prefix(".chunks/") {
+            return index(n).map(Sidecar::Chunk);
+        }
+        if let Some(n) = rest.strip_prefix(".wal/") {
+            return index(n).map(Sidecar::WalSegment);
+        }
+        if let Some(role) = rest.strip_prefix(".roles/") {
+            return Role::parse(role).map(|_| Sidecar::RoleLease);
+        }
+        if let Some(range) = rest.strip_prefix(".blocks/extents/") {
+            let (first, end) = range.split_once('-')?;
+            index(end)?;
+            return index(first).map(Sidecar::ManifestExtent);
+        }
+        match rest {
+            ".blocks" => Some(Sidecar::Manifest),
+            ".hot" => Some(Sidecar::HotSet),
+            ".warm" => Some(Sidecar::WarmSet),
+            ".copy" => Some(Sidecar::CopyProgress),
+            "-journal" => Some(Sidecar::Journal),
+            "-wal" => Some(Sidecar::Wal),
+            _ if rest.starts_with("-mj") && JournalKind::of(key) == Some(JournalKind::Super) => {
+                Some(Sidecar::SuperJournal)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Where the last [ThreeQLite::reconcile] and [ThreeQLite::wal_segments] of each database
+/// stopped, as the last key listed.
+#[derive(Debug, Default)]
+pub struct Cursors {
+    sidecars: Mutex<HashMap<ObjectKey, String>>,
+    wal: Mutex<HashMap<ObjectKey, String>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    /// Keys listed by this run.
+    pub keys_scanned: usize,
+    /// Sidecars among them.
+    pub sidecars: usize,
+    /// Super-journals none of whose journals exist any longer. SQLite never reads them again.
+    pub orphans: Vec<ObjectKey>,
+    /// Whether this run reached the last sidecar. The next run starts over.
+    pub complete: bool,
+}
+
+impl fmt::Display for ReconcileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "keys_scanned={} sidecars={} orphans={} complete={}",
+            self.keys_scanned,
+            self.sidecars,
+            self.orphans.len(),
+            self.complete
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WalSegments {
+    /// Segment numbers found by this call, in order.
+    pub segments: Vec<u64>,
+    /// Whether this call reached the last segment. Otherwise the next call continues after the
+    /// last segment returned.
+    pub complete: bool,
+}
+
+/// List up to `max_keys` keys under `prefix` after `cursor`, advancing it. Returns the keys and
+/// whether the prefix is exhausted, in which case the cursor is reset.
+async fn list_from(
+    inner: &Inner,
+    prefix: &str,
+    cursor: &Mutex<HashMap<ObjectKey, String>>,
+    db: &ObjectKey,
+    max_keys: usize,
+) -> Result<(Vec<String>, bool), Error> {
+    let mut after = cursor.lock().unwrap().get(db).cloned();
+    let mut keys = vec![];
+    let complete = loop {
+        let page_size = (max_keys - keys.len()).min(1000) as i32;
+        let page = inner
+            .list(
+                inner
+                    .s3
+                    .list_objects_v2()
+                    .prefix(prefix)
+                    .max_keys(page_size)
+                    .set_start_after(after.clone()),
+            )
+            .await?;
+        keys.extend(
+            page.contents()
+                .iter()
+                .filter_map(|obj| obj.key())
+                .map(str::to_owned),
+        );
+        after = keys.last().cloned().or(after);
+        if page.is_truncated() != Some(true) {
+            break true;
+        }
+        if keys.len() >= max_keys {
+            break false;
+        }
+    };
+    let mut cursor = cursor.lock().unwrap();
+    match (complete, after) {
+        (false, Some(after)) => cursor.insert(db.clone(), after),
+        _ => cursor.remove(db),
+    };
+    Ok((keys, complete))
+}
+
+/// Whether no journal named in the super-journal at `key` exists.
+async fn is_orphan(inner: &Inner, key: ObjectKey) -> Result<bool, Error> {
+    let super_journal = journal::Journal::open(inner, key, JournalKind::Super, false).await?;
+    let mut data = vec![0; super_journal.size() as usize];
+    super_journal.read_at(&mut data, 0);
+    for child in journal::children(&data) {
+        let Ok(child) = JournalKind::Main.key(child) else {
+            continue;
+        };
+        if journal::exists(inner, &child).await? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+impl ThreeQLite {
+    /// List the next [ReconcileConfig::max_keys_per_run] keys of the sidecars of `db`, see the
+    /// [module documentation](self).
+    pub async fn reconcile(&self, db: &str) -> Result<ReconcileReport, Error> {
+        let inner = self.inner.read().await;
+        let db = KeyLayout::db(db)?;
+        let max_keys = inner.reconcile_config.max_keys_per_run.max(1);
+        let (keys, complete) =
+            list_from(&inner, db.as_str(), &inner.cursors.sidecars, &db, max_keys).await?;
+
+        let mut report = ReconcileReport {
+            keys_scanned: keys.len(),
+            complete,
+            ..ReconcileReport::default()
+        };
+        for key in keys {
+            match Sidecar::of(&db, &key) {
+                Some(Sidecar::SuperJournal) => {
+                    report.sidecars += 1;
+                    let key = ObjectKey::new(key)?;
+                    if is_orphan(&inner, key.clone()).await? {
+                        report.orphans.push(key);
+                    }
+                }
+                Some(_) => report.sidecars += 1,
+                None => {}
+            }
+        }
+        tracing::debug!(
+            target: "threeqlite::s3",
+            %db,
+            keys = report.keys_scanned,
+            sidecars = report.sidecars,
+            orphans = report.orphans.len(),
+            complete,
+            "reconciled sidecars"
+        );
+        Ok(report)
+    }
+
+    /// List the WAL segments of `db` for crash recovery, continuing after the segments the
+    /// previous call returned unless it was complete.
+    pub async fn wal_segments(&self, db: &str) -> Result<WalSegments, Error> {
+        let inner = self.inner.read().await;
+        let db = KeyLayout::db(db)?;
+        let prefix = format!("{db}.wal/");
+        let max_keys = inner.reconcile_config.max_wal_pages.max(1) * 1000;
+        let (keys, complete) =
+            list_from(&inner, &prefix, &inner.cursors.wal, &db, max_keys).await?;
+        let segments = keys
+            .iter()
+            .filter_map(|key| match Sidecar::of(&db, key) {
+                Some(Sidecar::WalSegment(n)) => Some(n),
+                _ => None,
+            })
+            .collect();
+        Ok(WalSegments { segments, complete })
+    }
+
+    /// Spawn a task calling [ThreeQLite::reconcile] for `db` every `interval` while this instance
+    /// holds [Role::Reconcile] of `db`, logging what it finds.
+    pub fn spawn_reconcile(&self, db: &str, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let (tq, db) = (self.clone(), db.to_owned());
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let cycle = tq.run_role(&db, Role::Reconcile, interval, tq.reconcile(&db));
+                match cycle.await {
+                    Ok(Some(report)) if !report.orphans.is_empty() => tracing::warn!(
+                        target: "threeqlite::s3",
+                        db,
+                        orphans = ?report.orphans,
+                        "found orphaned super-journals"
+                    ),
+                    Ok(_) => {}
+                    Err(err) => {
+                        tracing::warn!(target: "threeqlite::s3", db, %err, "reconcile failed")
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlite_vfs::{OpenAccess, OpenKind, OpenOptions, Vfs};
+
+    use super::*;
+    use crate::{config::Config, mock::MockS3};
+
+    fn db() -> ObjectKey {
+        KeyLayout::db("test.db").unwrap()
+    }
+
+    #[test]
+    fn test_sidecar_of() {
+        let db = db();
+        for (key, sidecar) in [
+            ("test.db.blocks", Some(Sidecar::Manifest)),
+            ("test.db.chunks/0000000012", Some(Sidecar::Chunk(12))),
+            ("test.db.wal/0000000003", Some(Sidecar::WalSegment(3))),
+            ("test.db-journal", Some(Sidecar::Journal)),
+            ("test.db-mj0123abcde", Some(Sidecar::SuperJournal)),
+            ("test.db-wal", Some(Sidecar::Wal)),
+            ("test.db.hot", Some(Sidecar::HotSet)),
+            ("test.db.copy", Some(Sidecar::CopyProgress)),
+            ("test.db.roles/reconcile", Some(Sidecar::RoleLease)),
+            ("test.db.roles/unknown", None),
+            (
+                "test.db.blocks/extents/0000001024-0000002048",
+                Some(Sidecar::ManifestExtent(1024)),
+            ),
+            ("test.db.blocks/extents/0000001024", None),
+            ("test.db", None),
+            ("test.db2", None),
+            ("test.db2-journal", None),
+            ("test.db.wal/tmp", None),
+            ("other.db-journal", None),
+        ] {
+            assert_eq!(Sidecar::of(&db, key), sidecar, "{key}");
+        }
+    }
+
+    /// A database with 5,000 sidecars: 3,000 chunks, 1,900 WAL segments and 100 super-journals
+    /// of transactions whose journals are gone.
+    fn crowded(mock: &MockS3) {
+        mock.put("test.db", crate::mock::database(4096, 2, 1));
+        let db = db();
+        for idx in 0..3000 {
+            mock.put(KeyLayout::chunk(&db, idx).as_str(), vec![0; 16]);
+        }
+        for n in 0..1900 {
+            mock.put(KeyLayout::wal_segment(&db, n).as_str(), vec![0; 16]);
+        }
+        for id in 0..100 {
+            let key = KeyLayout::super_journal(&db, &format!("{id:09x}"));
+            mock.put(key.as_str(), b"test.db-journal\0".to_vec());
+        }
+    }
+
+    fn instance(mock: &MockS3, reconcile: ReconcileConfig) -> ThreeQLite {
+        let config = Config {
+            reconcile,
+            ..Config::default()
+        };
+        ThreeQLite::with_client(config, mock.client()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_open_does_not_list() {
+        let mock = MockS3::start();
+        crowded(&mock);
+        let tq = instance(&mock, ReconcileConfig::default());
+        let opts = OpenOptions::new(OpenKind::MainDb, OpenAccess::Write);
+        tq.open("test.db", opts).await.unwrap();
+        assert!(mock.lists().is_empty());
+        let stats = tq.stats().await;
+        assert_eq!((stats.list_requests, stats.keys_listed), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_wal_recovery_lists_wal_prefix_only() {
+        let mock = MockS3::start();
+        crowded(&mock);
+        let tq = instance(
+            &mock,
+            ReconcileConfig {
+                max_wal_pages: 1,
+                ..ReconcileConfig::default()
+            },
+        );
+
+        // the first call stops at its page cap, the second one continues
+        let first = tq.wal_segments("test.db").await.unwrap();
+        assert_eq!(first.segments, (0..1000).collect::<Vec<_>>());
+        assert!(!first.complete);
+        let second = tq.wal_segments("test.db").await.unwrap();
+        assert_eq!(second.segments, (1000..1900).collect::<Vec<_>>());
+        assert!(second.complete);
+
+        assert_eq!(mock.lists(), vec!["test.db.wal/"; 2]);
+        let stats = tq.stats().await;
+        assert_eq!((stats.list_requests, stats.keys_listed), (2, 1900));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_respects_budget() {
+        let mock = MockS3::start();
+        crowded(&mock);
+        // a journal still refers to the first super-journal
+        mock.put("test.db-journal", vec![0; 512]);
+        let tq = instance(
+            &mock,
+            ReconcileConfig {
+                max_keys_per_run: 1500,
+                ..ReconcileConfig::default()
+            },
+        );
+
+        let mut runs = vec![];
+        loop {
+            let before = tq.stats().await.keys_listed;
+            let report = tq.reconcile("test.db").await.unwrap();
+            assert!(report.keys_scanned <= 1500);
+            assert_eq!(
+                tq.stats().await.keys_listed - before,
+                report.keys_scanned as u64
+            );
+            let complete = report.complete;
+            runs.push(report);
+            if complete {
+                break;
+            }
+            assert!(runs.len() < 10, "reconcile does not finish");
+        }
+        // the database, its journal and the 5,000 sidecars
+        assert_eq!(runs.len(), 4);
+        assert_eq!(runs.iter().map(|run| run.keys_scanned).sum::<usize>(), 5002);
+        assert_eq!(runs.iter().map(|run| run.sidecars).sum::<usize>(), 5001);
+        assert!(runs.iter().all(|run| run.orphans.is_empty()));
+
+        // the journal is gone, so are its transactions
+        mock.delete("test.db-journal");
+        let mut orphans = vec![];
+        loop {
+            let report = tq.reconcile("test.db").await.unwrap();
+            orphans.extend(report.orphans);
+            if report.complete {
+                break;
+            }
+        }
+        assert_eq!(orphans.len(), 100);
+        assert!(mock.lists().iter().all(|prefix| prefix == "test.db"));
+    }
+}
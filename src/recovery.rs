@@ -0,0 +1,687 @@
+//! Rolling back hot journals, exactly once.
+//!
+//! A writer that dies in the middle of a transaction leaves its journal behind, and with it the
+//! write lock, which isn't taken over while the journal exists, see [crate::busy]. Its pages may
+//! be partly uploaded. [ThreeQLite::recover] rolls the journal back the way SQLite does with a hot
+//! journal, as a write session of its own, in the [RecoveryStep]s:
+//!
+//! 1. Take the write lock, from the dead writer despite its journal. The ID of the lock and the
+//!    generation it was taken at are the [Fence] of the session.
+//! 2. Read the journal. One naming a super-journal that is gone belongs to a committed
+//!    transaction, and one without a valid header to none at all: both are deleted as they are,
+//!    see [crate::journal].
+//! 3. Write the journaled pages back through a handle of the database, and truncate it to its
+//!    size before the transaction. They go through the flush and commit barrier of any other
+//!    commit, see [crate::flush].
+//! 4. Delete the journal, at the commit point of the rollback, once its pages are durable, see
+//!    [crate::durability].
+//! 5. Commit the next generation and release the lock.
+//!
+//! Before each step writing to the object store, the session checks its fence against the
+//! metadata object, and fails with [Error::Fenced] once another writer took the lock over, e.g.
+//! because this process was taken for dead. Its writes thus never interleave with those of a
+//! writer after it.
+//!
+//! Every step can be repeated: the pages written back are those of the journal, whatever the
+//! database holds, and the journal stays until they are durable. A recovery crashing at any step
+//! leaves a dead writer and its journal, as the writer it recovered did, and the next recovery
+//! starts over. One that fails keeps the lock, so that no reader sees the database half rolled
+//! back, and resumes when called again.
+
+use sqlite_vfs::DatabaseHandle;
+
+use crate::{
+    error::Error,
+    handle::{storage_cause, Handle},
+    journal::{self, JournalKind, JOURNAL_MAGIC},
+    key::{KeyLayout, ObjectKey},
+    registration,
+    vfs::{Metadata, ThreeQLite},
+};
+
+/// The sector size of the journals [journal_of] writes.
+const SECTOR: usize = 512;
+
+/// The checksum of a journaled page, following `pager_cksum` of SQLite.
+pub fn page_checksum(nonce: u32, page: &[u8]) -> u32 {
+    let mut sum = nonce;
+    let mut i = page.len() - 200;
+    while i > 0 {
+        sum = sum.wrapping_add(page[i] as u32);
+        i = i.saturating_sub(200);
+    }
+    sum
+}
+
+/// The rollback journal of a transaction on a database of `pages` pages of `page_size` that
+/// overwrote `originals`, by page number, as SQLite writes it before overwriting a page: a header
+/// padded to a sector, then each page with its number and checksum.
+pub fn journal_of(page_size: u64, pages: u64, originals: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let nonce: u32 = rand::random();
+    let mut journal = vec![0; SECTOR];
+    journal[..8].copy_from_slice(&JOURNAL_MAGIC);
+    journal[8..12].copy_from_slice(&(originals.len() as u32).to_be_bytes());
+    journal[12..16].copy_from_slice(&nonce.to_be_bytes());
+    journal[16..20].copy_from_slice(&(pages as u32).to_be_bytes());
+    journal[20..24].copy_from_slice(&(SECTOR as u32).to_be_bytes());
+    journal[24..28].copy_from_slice(&(page_size as u32).to_be_bytes());
+    for (pgno, page) in originals {
+        journal.extend_from_slice(&pgno.to_be_bytes());
+        journal.extend_from_slice(page);
+        journal.extend_from_slice(&page_checksum(nonce, page).to_be_bytes());
+    }
+    journal
+}
+
+/// A page number and the content of the page before the transaction.
+pub type JournaledPage<'a> = (u32, &'a [u8]);
+
+/// What rolling back a journal restores, see [playback].
+#[derive(Debug, PartialEq, Eq)]
+pub struct Playback<'a> {
+    pub page_size: u64,
+    /// The size of the database in pages before the transaction.
+    pub pages: u64,
+    /// The pages to write back, in the order of the journal.
+    pub records: Vec<JournaledPage<'a>>,
+}
+
+/// The pages recorded in the rollback journal `journal`, following `pager_playback` of SQLite:
+/// each header starts a segment of records at the next sector, and playback stops at the first
+/// record with a wrong checksum. Pages past the size before the transaction are truncated away,
+/// and so left out. `None` without a valid first header, which SQLite doesn't take for hot.
+pub fn playback(journal: &[u8]) -> Option<Playback<'_>> {
+    let u32_at = |at: usize| u32::from_be_bytes(journal[at..at + 4].try_into().unwrap());
+    let header = |at: usize| {
+        journal
+            .get(at..at + 28)
+            .is_some_and(|h| h[..8] == JOURNAL_MAGIC)
+    };
+    if !header(0) {
+        return None;
+    }
+    let (pages, sector, page_size) = (u32_at(16), u32_at(20) as usize, u32_at(24) as usize);
+    if sector < 28 || page_size < 512 {
+        return None;
+    }
+    // never written to, see PENDING_BYTE in SQLite
+    let lock_page = (0x4000_0000 / page_size) as u32 + 1;
+    let record_len = page_size + 8;
+    let mut records = vec![];
+    let mut at = 0;
+    while header(at) {
+        let (count, nonce) = (u32_at(at + 8), u32_at(at + 12));
+        at += sector;
+        let count = match count {
+            // written without syncing, the records reach to the end
+            u32::MAX => (journal.len().saturating_sub(at) / record_len) as u32,
+            count => count,
+        };
+        for _ in 0..count {
+            let Some(record) = journal.get(at..at + record_len) else {
+                return Some(Playback::new(page_size, pages, records));
+            };
+            let pgno = u32::from_be_bytes(record[..4].try_into().unwrap());
+            let page = &record[4..4 + page_size];
+            let sum = u32::from_be_bytes(record[4 + page_size..].try_into().unwrap());
+            if pgno == 0 || sum != page_checksum(nonce, page) {
+                return Some(Playback::new(page_size, pages, records));
+            }
+            if pgno <= pages && pgno != lock_page {
+                records.push((pgno, page));
+            }
+            at += record_len;
+        }
+        at = at.div_ceil(sector) * sector;
+    }
+    Some(Playback::new(page_size, pages, records))
+}
+
+impl<'a> Playback<'a> {
+    fn new(page_size: usize, pages: u32, records: Vec<JournaledPage<'a>>) -> Self {
+        Self {
+            page_size: page_size as u64,
+            pages: pages as u64,
+            records,
+        }
+    }
+}
+
+/// What a recovery fences its writes with, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fence {
+    /// The ID of the write lock of the recovery.
+    pub lock: Vec<u8>,
+    /// The generation the lock was taken at. Only a commit advances it, and only the holder of
+    /// the lock commits.
+    pub epoch: u64,
+}
+
+impl Fence {
+    /// Fail with [Error::Fenced] unless the metadata object of `tq` still has the write lock held
+    /// as it was taken.
+    pub async fn check(&self, tq: &ThreeQLite) -> Result<(), Error> {
+        let inner = tq.inner.read().await;
+        let record = inner.read_metadata_record().await?;
+        let generation = record.stamp.map_or(0, |stamp| stamp.generation);
+        match record.metadata {
+            Metadata::Writer(lock) if lock == self.lock && generation == self.epoch => Ok(()),
+            _ => Err(Error::Fenced {
+                db: inner.db_filename.to_string(),
+                epoch: self.epoch,
+            }),
+        }
+    }
+}
+
+/// A step of a [Recovery], in the order they run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RecoveryStep {
+    /// The write lock was taken.
+    Locked,
+    JournalRead,
+    /// The journaled pages were written to a handle of the database, which buffers them.
+    PagesWritten,
+    /// The pages were uploaded, past the commit barrier of their flush.
+    PagesDurable,
+    /// The database was truncated to its size before the transaction.
+    Truncated,
+    JournalDeleted,
+    /// The next generation was committed, and the lock released.
+    Committed,
+}
+
+impl RecoveryStep {
+    /// Every step, in order.
+    pub const ALL: [RecoveryStep; 7] = [
+        RecoveryStep::Locked,
+        RecoveryStep::JournalRead,
+        RecoveryStep::PagesWritten,
+        RecoveryStep::PagesDurable,
+        RecoveryStep::Truncated,
+        RecoveryStep::JournalDeleted,
+        RecoveryStep::Committed,
+    ];
+
+    fn next(self) -> Option<Self> {
+        Self::ALL.get(self as usize + 1).copied()
+    }
+}
+
+/// How a recovery ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecoveryOutcome {
+    /// There was no journal.
+    Clean,
+    /// The journal was rolled back, writing back `pages` pages and truncating the database to
+    /// `len`.
+    RolledBack { pages: usize, len: u64 },
+    /// The journal belonged to a committed transaction, or had no valid header, and was deleted
+    /// as it was.
+    Discarded,
+}
+
+impl std::fmt::Display for RecoveryOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecoveryOutcome::Clean => write!(f, "clean"),
+            RecoveryOutcome::RolledBack { pages, len } => {
+                write!(f, "rolled back {pages} pages, {len} bytes")
+            }
+            RecoveryOutcome::Discarded => write!(f, "discarded"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub outcome: RecoveryOutcome,
+    /// The epoch of the [Fence] of the recovery.
+    pub epoch: u64,
+    /// The generation the recovery committed.
+    pub generation: u64,
+}
+
+/// A recovery in progress, see the [module documentation](self). Dropping it in the middle leaves
+/// the lock held, as a crash would.
+pub struct Recovery {
+    tq: ThreeQLite,
+    key: ObjectKey,
+    fence: Fence,
+    done: RecoveryStep,
+    /// The journal as read, empty if there was none.
+    journal: Vec<u8>,
+    outcome: RecoveryOutcome,
+    /// Writing the pages back, while rolling back.
+    handle: Option<Handle>,
+}
+
+impl Recovery {
+    pub fn fence(&self) -> &Fence {
+        &self.fence
+    }
+
+    /// The last step done.
+    pub fn done(&self) -> RecoveryStep {
+        self.done
+    }
+
+    /// Run the next step, returning it, or `None` once the recovery is over. A step that fails
+    /// runs again on the next call.
+    pub async fn step(&mut self) -> Result<Option<RecoveryStep>, Error> {
+        let Some(step) = self.done.next() else {
+            return Ok(None);
+        };
+        // boxed, the futures of the handle are large
+        Box::pin(self.run_step(step)).await?;
+        self.done = step;
+        Ok(Some(step))
+    }
+
+    async fn run_step(&mut self, step: RecoveryStep) -> Result<(), Error> {
+        let playback = match self.outcome {
+            RecoveryOutcome::RolledBack { .. } => playback(&self.journal),
+            _ => None,
+        };
+        match step {
+            RecoveryStep::Locked => {}
+            RecoveryStep::JournalRead => self.read_journal().await?,
+            RecoveryStep::PagesWritten => {
+                let Some(playback) = playback else {
+                    return Ok(());
+                };
+                let handle =
+                    self.handle
+                        .insert(Handle::new(self.tq.clone(), self.key.clone(), false));
+                for (pgno, page) in playback.records {
+                    let offset = (pgno as u64 - 1) * playback.page_size;
+                    handle
+                        .write_all_at(page, offset)
+                        .await
+                        .map_err(storage_cause)?;
+                }
+            }
+            RecoveryStep::PagesDurable => {
+                if let Some(handle) = &mut self.handle {
+                    self.fence.check(&self.tq).await?;
+                    handle.sync(false).await.map_err(storage_cause)?;
+                }
+            }
+            RecoveryStep::Truncated => {
+                if let (Some(handle), Some(playback)) = (&mut self.handle, playback) {
+                    self.fence.check(&self.tq).await?;
+                    let len = playback.pages * playback.page_size;
+                    handle.set_len(len).await.map_err(storage_cause)?;
+                }
+            }
+            RecoveryStep::JournalDeleted => {
+                if self.outcome != RecoveryOutcome::Clean {
+                    self.fence.check(&self.tq).await?;
+                    // the commit point of the rollback, see [crate::durability]
+                    self.tq.commit_point(&self.key).await?;
+                    let inner = self.tq.inner.read().await;
+                    journal::delete(&inner, &KeyLayout::journal(&self.key), JournalKind::Main)
+                        .await?;
+                }
+            }
+            RecoveryStep::Committed => {
+                let mut inner = self.tq.inner.write().await;
+                Box::pin(inner.release_write_lock()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_journal(&mut self) -> Result<(), Error> {
+        let key = KeyLayout::journal(&self.key);
+        let inner = self.tq.inner.read().await;
+        if !journal::exists(&inner, &key).await? {
+            self.outcome = RecoveryOutcome::Clean;
+            return Ok(());
+        }
+        let read = journal::Journal::open(&inner, key, JournalKind::Main, false).await?;
+        self.journal = vec![0; read.size() as usize];
+        read.read_at(&mut self.journal, 0);
+        let committed = match journal::super_journal_of(&self.journal) {
+            Some(super_journal) => {
+                let key = JournalKind::Super.key(super_journal)?;
+                !journal::exists(&inner, &key).await?
+            }
+            None => false,
+        };
+        self.outcome = match playback(&self.journal).filter(|_| !committed) {
+            Some(playback) => RecoveryOutcome::RolledBack {
+                pages: playback.records.len(),
+                len: playback.pages * playback.page_size,
+            },
+            None => RecoveryOutcome::Discarded,
+        };
+        Ok(())
+    }
+
+    /// Run the remaining steps.
+    pub async fn finish(mut self) -> Result<RecoveryReport, Error> {
+        while self.step().await?.is_some() {}
+        let inner = self.tq.inner.read().await;
+        let generation = inner
+            .generation_seen
+            .load(std::sync::atomic::Ordering::Relaxed);
+        tracing::info!(
+            target: "threeqlite::lock_protocol",
+            key = %self.key,
+            outcome = %self.outcome,
+            epoch = self.fence.epoch,
+            generation,
+            "recovered database"
+        );
+        Ok(RecoveryReport {
+            outcome: self.outcome,
+            epoch: self.fence.epoch,
+            generation,
+        })
+    }
+}
+
+impl ThreeQLite {
+    /// Roll back the hot journal of `db` left by a writer that died, see the
+    /// [module documentation](self). Waits for a writer that is alive like any other writer, up
+    /// to the busy timeout.
+    pub async fn recover(&self, db: &str) -> Result<RecoveryReport, Error> {
+        Box::pin(self.begin_recovery(db)).await?.finish().await
+    }
+
+    /// Take the write lock to recover `db`, see [ThreeQLite::recover]. A write lock this instance
+    /// holds already is kept.
+    pub async fn begin_recovery(&self, db: &str) -> Result<Recovery, Error> {
+        let key = KeyLayout::db(db)?;
+        let mut inner = self.inner.write().await;
+        let record = inner.read_metadata_record().await?;
+        let held = matches!(
+            (&record.metadata, &inner.current_lock),
+            (Metadata::Writer(lock), Some(current)) if lock == current
+        );
+        let record = match held {
+            true => record,
+            false => {
+                inner.recovering = true;
+                let locked = registration::scope(
+                    self.registration.clone(),
+                    Box::pin(inner.request_write_lock()),
+                )
+                .await;
+                inner.recovering = false;
+                locked?;
+                inner.read_metadata_record().await?
+            }
+        };
+        let fence = Fence {
+            lock: inner.current_lock.clone().expect("taken"),
+            epoch: record.stamp.map_or(0, |stamp| stamp.generation),
+        };
+        Ok(Recovery {
+            tq: self.clone(),
+            key,
+            fence,
+            done: RecoveryStep::Locked,
+            journal: vec![],
+            outcome: RecoveryOutcome::Clean,
+            handle: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        busy::{Holder, LocalProcess},
+        config::{Config, LockConfig},
+        mock::{self, MockS3},
+        vfs::MetadataRecord,
+    };
+
+    const PAGE: u64 = 4096;
+
+    fn instance(mock: &MockS3) -> ThreeQLite {
+        let config = Config {
+            lock: LockConfig {
+                busy_timeout: Some(Duration::from_millis(500)),
+                ..LockConfig::default()
+            },
+            ..Config::default()
+        };
+        ThreeQLite::with_client(config, mock.client()).unwrap()
+    }
+
+    /// A process of this machine that exited.
+    fn dead() -> LocalProcess {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        LocalProcess {
+            pid: child.id(),
+            ..LocalProcess::current().unwrap()
+        }
+    }
+
+    /// Have the holder of the write lock of `tq` die, as its process would.
+    async fn bury(tq: &ThreeQLite) {
+        let inner = tq.inner.read().await;
+        let record = inner.read_metadata_record().await.unwrap();
+        assert!(matches!(record.metadata, Metadata::Writer(_)));
+        let holder = record.holder.clone().unwrap();
+        let record = MetadataRecord {
+            holder: Some(Holder {
+                process: Some(dead()),
+                ..holder
+            }),
+            ..record
+        };
+        inner.write_metadata_record(record).await.unwrap();
+    }
+
+    /// A writer that died in the middle of a transaction growing a database of 6 pages to 8 and
+    /// rewriting its pages 2 and 6, with part of its pages uploaded. Returns the database as it
+    /// was before.
+    async fn torn(mock: &MockS3) -> Vec<u8> {
+        let before = mock::database(PAGE as u32, 6, 1);
+        mock.put("test.db", before.clone());
+        let originals: Vec<_> = [2u32, 6]
+            .iter()
+            .map(|&pgno| {
+                let offset = (pgno as u64 - 1) * PAGE;
+                (
+                    pgno,
+                    before[offset as usize..(offset + PAGE) as usize].to_vec(),
+                )
+            })
+            .collect();
+        mock.put("test.db-journal", journal_of(PAGE, 6, &originals));
+        let mut torn = before.clone();
+        torn[PAGE as usize..2 * PAGE as usize].fill(0xee);
+        torn.extend(vec![0xdd; 2 * PAGE as usize]);
+        mock.put("test.db", torn);
+
+        let writer = instance(mock);
+        let mut inner = writer.inner.write().await;
+        inner.request_write_lock().await.unwrap();
+        drop(inner);
+        bury(&writer).await;
+        before
+    }
+
+    async fn metadata(tq: &ThreeQLite) -> Metadata {
+        tq.inner.read().await.read_metadata().await.unwrap()
+    }
+
+    fn md5(data: &[u8]) -> String {
+        format!("{:x}", md5::compute(data))
+    }
+
+    #[test]
+    fn test_playback() {
+        let originals = [(2, vec![7; 512]), (3, vec![9; 512]), (5, vec![1; 512])];
+        let mut journal = journal_of(512, 4, &originals);
+        let playback = playback(&journal).unwrap();
+        assert_eq!((playback.page_size, playback.pages), (512, 4));
+        // page 5 is truncated away
+        assert_eq!(playback.records, [(2, &[7; 512][..]), (3, &[9; 512][..])]);
+
+        // a second segment, at the next sector, and a super-journal pointer after it
+        let second = journal_of(512, 4, &[(4, vec![3; 512])]);
+        journal.resize(journal.len().div_ceil(SECTOR) * SECTOR, 0);
+        journal.extend(second);
+        journal.extend(journal::super_journal_pointer("test.db-mj0000000A1"));
+        let records = super::playback(&journal).unwrap().records;
+        assert_eq!(
+            records.iter().map(|(pgno, _)| *pgno).collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+
+        // a torn record ends the playback, as far as the checksum samples it
+        journal[SECTOR + 4 + 512 + 4 + 4 + 112] ^= 1;
+        assert_eq!(super::playback(&journal).unwrap().records.len(), 1);
+
+        // written without syncing, the count is left for the length to tell
+        let mut unsynced = journal_of(512, 4, &originals[..2]);
+        unsynced[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(super::playback(&unsynced).unwrap().records.len(), 2);
+        assert_eq!(super::playback(&[0; 512]), None);
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let mock = MockS3::start();
+        let before = torn(&mock).await;
+        let tq = instance(&mock);
+
+        let report = tq.recover("test.db").await.unwrap();
+        assert_eq!(
+            report.outcome,
+            RecoveryOutcome::RolledBack {
+                pages: 2,
+                len: 6 * PAGE
+            }
+        );
+        assert_eq!(mock.get("test.db").unwrap(), before);
+        assert_eq!(mock.get("test.db-journal"), None);
+        assert!(matches!(metadata(&tq).await, Metadata::None));
+        assert_eq!(report.generation, report.epoch + 1);
+
+        // nothing left to do
+        let report = tq.recover("test.db").await.unwrap();
+        assert_eq!(report.outcome, RecoveryOutcome::Clean);
+        assert_eq!(mock.get("test.db").unwrap(), before);
+    }
+
+    #[tokio::test]
+    async fn test_crash_at_every_step() {
+        for crash in RecoveryStep::ALL {
+            let mock = MockS3::start();
+            let before = torn(&mock).await;
+            for attempt in 0..3 {
+                let tq = instance(&mock);
+                let mut recovery = tq.begin_recovery("test.db").await.unwrap();
+                while recovery.done() < crash {
+                    recovery.step().await.unwrap();
+                }
+                // the process crashed
+                drop((recovery, tq));
+                let journal = mock.get("test.db-journal").is_some();
+                assert_eq!(journal, crash < RecoveryStep::JournalDeleted, "{crash:?}");
+                if crash < RecoveryStep::Committed {
+                    bury(&instance(&mock)).await;
+                } else if attempt == 0 {
+                    assert_eq!(mock.get("test.db").unwrap(), before, "{crash:?}");
+                }
+            }
+
+            let tq = instance(&mock);
+            tq.recover("test.db").await.unwrap();
+            let db = mock.get("test.db").unwrap();
+            assert_eq!(md5(&db), md5(&before), "{crash:?}");
+            assert_eq!(db, before, "{crash:?}");
+            assert_eq!(mock.get("test.db-journal"), None, "{crash:?}");
+            assert!(matches!(metadata(&tq).await, Metadata::None), "{crash:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_crash_while_uploading() {
+        let mock = MockS3::start();
+        let before = torn(&mock).await;
+        let tq = instance(&mock);
+        let mut recovery = tq.begin_recovery("test.db").await.unwrap();
+        while recovery.done() < RecoveryStep::PagesWritten {
+            recovery.step().await.unwrap();
+        }
+        // one of the pages is stored, but its upload fails
+        mock.lose_response("PUT", "test.db");
+        assert!(recovery.step().await.is_err());
+        assert_eq!(recovery.done(), RecoveryStep::PagesWritten);
+        drop((recovery, tq));
+        assert!(mock.get("test.db-journal").is_some());
+        bury(&instance(&mock)).await;
+
+        instance(&mock).recover("test.db").await.unwrap();
+        assert_eq!(mock.get("test.db").unwrap(), before);
+        assert_eq!(mock.get("test.db-journal"), None);
+    }
+
+    #[tokio::test]
+    async fn test_fenced() {
+        let mock = MockS3::start();
+        let before = torn(&mock).await;
+        let stale = instance(&mock);
+        let mut first = stale.begin_recovery("test.db").await.unwrap();
+        first.step().await.unwrap();
+        first.step().await.unwrap();
+        // taken for dead, and taken over
+        bury(&stale).await;
+        let tq = instance(&mock);
+        let second = tq.begin_recovery("test.db").await.unwrap();
+        assert_ne!(second.fence().lock, first.fence().lock);
+
+        let err = first.step().await.unwrap_err();
+        assert!(matches!(err, Error::Fenced { .. }), "{err}");
+        assert_eq!(first.done(), RecoveryStep::PagesWritten);
+        assert_eq!(
+            mock.get("test.db-journal").map(|j| j.is_empty()),
+            Some(false)
+        );
+        second.finish().await.unwrap();
+        assert_eq!(mock.get("test.db").unwrap(), before);
+        drop(first);
+        assert_eq!(mock.get("test.db").unwrap(), before);
+    }
+
+    #[tokio::test]
+    async fn test_live_writer_kept() {
+        let mock = MockS3::start();
+        torn(&mock).await;
+        let writer = instance(&mock);
+        {
+            let inner = writer.inner.read().await;
+            let record = inner.read_metadata_record().await.unwrap();
+            let holder = Holder {
+                process: LocalProcess::current(),
+                ..record.holder.clone().unwrap()
+            };
+            let record = MetadataRecord {
+                holder: Some(holder),
+                ..record
+            };
+            inner.write_metadata_record(record).await.unwrap();
+        }
+
+        // a writer in the middle of its transaction rather than a dead one
+        let err = instance(&mock)
+            .begin_recovery("test.db")
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Busy { .. }), "{err}");
+        assert!(mock.get("test.db-journal").is_some());
+    }
+}
@@ -0,0 +1,355 @@
+//! Finding and pinning the region of the bucket.
+//!
+//! A client configured for another region than the bucket's gets `301 PermanentRedirect` for
+//! every request, which the SDK doesn't follow. With [RegionConfig::discover], the region is
+//! resolved on the first open of an instance, and [ThreeQLite::preflight] resolves it in any
+//! case: from the `x-amz-bucket-region` header of a `HeadBucket`, which S3 sends along with the
+//! redirect too, or else from `GetBucketLocation`. The clients of the instance are then rebuilt
+//! for that region; instances of other buckets keep their own.
+//!
+//! Every response is watched for redirects and `NoSuchBucket`. Once one comes in after the region
+//! was pinned, as when a bucket is recreated elsewhere, the next open resolves the region again,
+//! and an open failing on it resolves it once more before it fails.
+//!
+//! The round trip of the `HeadBucket` is kept as the baseline latency to the region, so that the
+//! cost of talking to a far region shows in the stats.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use aws_sdk_s3::{
+    config::{
+        interceptors::FinalizerInterceptorContextRef, ConfigBag, Intercept, Region,
+        RuntimeComponents,
+    },
+    error::BoxError,
+};
+
+use crate::{
+    circuit::OpClass,
+    error::Error,
+    stats::Stats,
+    vfs::{Inner, ThreeQLite},
+};
+
+/// Settings of region discovery, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RegionConfig {
+    /// Resolve the region of the bucket on the first open, rather than only in
+    /// [ThreeQLite::preflight]. Costs a `HeadBucket` per instance.
+    pub discover: bool,
+}
+
+/// The region a bucket was found in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BucketRegion {
+    pub region: String,
+    /// The round trip of the request it was found with.
+    pub baseline: Duration,
+}
+
+impl std::fmt::Display for BucketRegion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} baseline={:?}", self.region, self.baseline)
+    }
+}
+
+/// The region the clients of an instance are pinned to, shared with the [RegionWatch] of its
+/// clients.
+#[derive(Debug, Default)]
+pub struct RegionPin {
+    pinned: Mutex<Option<BucketRegion>>,
+    /// Whether a response said the bucket isn't where the clients are pinned to.
+    moved: AtomicBool,
+}
+
+impl RegionPin {
+    pub fn get(&self) -> Option<BucketRegion> {
+        self.pinned.lock().unwrap().clone()
+    }
+
+    /// Whether a response said the bucket moved since it was pinned, or since the last call.
+    pub fn take_moved(&self) -> bool {
+        self.moved.swap(false, Relaxed)
+    }
+}
+
+/// Notes responses saying that the bucket is in another region, see [RegionPin::take_moved].
+#[derive(Debug)]
+pub struct RegionWatch(pub std::sync::Arc<RegionPin>);
+
+impl Intercept for RegionWatch {
+    fn name(&self) -> &'static str {
+        "RegionWatch"
+    }
+
+    fn read_after_execution(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let Some(response) = context.response() else {
+            return Ok(());
+        };
+        let status = response.status().as_u16();
+        let no_such_bucket = status == 404
+            && response
+                .body()
+                .bytes()
+                .is_some_and(|body| body.windows(24).any(|w| w == b"<Code>NoSuchBucket</Code>"));
+        if matches!(status, 301 | 307) || no_such_bucket {
+            self.0.moved.store(true, Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Find the region of the bucket of `inner`.
+pub async fn discover(inner: &Inner) -> Result<BucketRegion, Error> {
+    let start = Instant::now();
+    let res = inner.s3.head_bucket().bucket(&inner.bucket).send().await;
+    let baseline = start.elapsed();
+    // sent with redirects as well
+    let header = match &res {
+        Ok(out) => out.bucket_region().map(str::to_owned),
+        Err(err) => err
+            .raw_response()
+            .and_then(|response| response.headers().get("x-amz-bucket-region"))
+            .map(str::to_owned),
+    };
+    inner.record(OpClass::Read, header.is_some());
+    if let Some(region) = header {
+        return Ok(BucketRegion { region, baseline });
+    }
+
+    // stores that don't send the header
+    let start = Instant::now();
+    let res = inner
+        .s3
+        .get_bucket_location()
+        .bucket(&inner.bucket)
+        .send()
+        .await;
+    let baseline = start.elapsed();
+    inner.record(OpClass::Read, res.is_ok());
+    let region = match res?.location_constraint.as_ref().map(|c| c.as_str()) {
+        // buckets in us-east-1 have no location constraint
+        None | Some("") => "us-east-1".to_owned(),
+        Some("EU") => "eu-west-1".to_owned(),
+        Some(region) => region.to_owned(),
+    };
+    Ok(BucketRegion { region, baseline })
+}
+
+impl Inner {
+    /// Rebuild the clients of the instance for `region`.
+    fn pin_region(&mut self, region: &str) {
+        let pin = |client: &aws_sdk_s3::Client| {
+            let config = client.config().to_builder();
+            aws_sdk_s3::Client::from_conf(config.region(Region::new(region.to_owned())).build())
+        };
+        self.s3 = pin(&self.s3);
+        self.page_client = pin(&self.page_client);
+        self.bulk_client = pin(&self.bulk_client);
+        *self.metadata_lock.client_mut() = pin(self.metadata_lock.client_mut());
+    }
+}
+
+impl ThreeQLite {
+    /// Resolve the region of the bucket and pin the clients of the instance to it, see
+    /// [crate::region].
+    pub async fn resolve_region(&self) -> Result<BucketRegion, Error> {
+        let found = {
+            let inner = self.inner.read().await;
+            discover(&inner).await?
+        };
+        let mut inner = self.inner.write().await;
+        let configured = inner.s3.config().region().map(|region| region.as_ref());
+        if configured != Some(found.region.as_str()) {
+            tracing::info!(
+                target: "threeqlite::s3",
+                bucket = inner.bucket,
+                region = found.region,
+                baseline = ?found.baseline,
+                "pinning clients to the region of the bucket"
+            );
+            inner.pin_region(&found.region);
+        }
+        inner.region.moved.store(false, Relaxed);
+        *inner.region.pinned.lock().unwrap() = Some(found.clone());
+        Stats::incr(&inner.stats.region_resolutions);
+        Ok(found)
+    }
+
+    /// Resolve the region of the bucket on the first call with [RegionConfig::discover], and on
+    /// the first call after a response said that the bucket moved.
+    pub async fn ensure_region(&self) -> Result<(), Error> {
+        let resolve = {
+            let inner = self.inner.read().await;
+            match inner.region.get() {
+                Some(_) => inner.region.take_moved(),
+                None => inner.region_config.discover,
+            }
+        };
+        if resolve {
+            self.resolve_region().await?;
+        }
+        Ok(())
+    }
+
+    /// Whether a response said that the bucket moved since its region was pinned. If so, the
+    /// region was resolved again.
+    pub async fn region_moved(&self) -> Result<bool, Error> {
+        let moved = {
+            let inner = self.inner.read().await;
+            inner.region.get().is_some() && inner.region.take_moved()
+        };
+        if moved {
+            self.resolve_region().await?;
+        }
+        Ok(moved)
+    }
+
+    /// The region the clients are pinned to, `None` until it was resolved.
+    pub async fn region(&self) -> Option<BucketRegion> {
+        self.inner.read().await.region.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlite_vfs::{OpenAccess, OpenKind, OpenOptions, Vfs};
+
+    use super::*;
+    use crate::{
+        burst::BurstConfig,
+        config::Config,
+        mock::{self, MockS3},
+        vfs::MetadataRecord,
+    };
+
+    fn instance(mock: &MockS3, bucket: &str, discover: bool) -> ThreeQLite {
+        let config = Config {
+            bucket: bucket.to_owned(),
+            region: RegionConfig { discover },
+            // every open asks the store, see [crate::burst]
+            open_burst: BurstConfig {
+                ttl: Duration::ZERO,
+            },
+            ..Config::default()
+        };
+        ThreeQLite::with_client(config, mock.client()).unwrap()
+    }
+
+    fn discoveries(mock: &MockS3) -> usize {
+        let requests = mock.requests().into_iter();
+        requests
+            .filter(|(method, key)| method == "HEAD" && key.is_empty())
+            .count()
+    }
+
+    async fn write_metadata(tq: &ThreeQLite) -> Result<(), Error> {
+        let inner = tq.inner.read().await;
+        inner.write_metadata_record(MetadataRecord::default()).await
+    }
+
+    #[tokio::test]
+    async fn test_discovery_pins_clients() {
+        let mock = MockS3::start();
+        mock.require_region("far", "eu-west-1");
+
+        // the client of the mock is configured for us-east-1
+        let tq = instance(&mock, "far", false);
+        tq.ensure_region().await.unwrap();
+        assert!(write_metadata(&tq).await.is_err());
+        assert_eq!(discoveries(&mock), 0);
+
+        let tq = instance(&mock, "far", true);
+        for _ in 0..3 {
+            tq.ensure_region().await.unwrap();
+        }
+        assert_eq!(discoveries(&mock), 1);
+        let region = tq.region().await.unwrap();
+        assert_eq!(region.region, "eu-west-1");
+        for _ in 0..3 {
+            write_metadata(&tq).await.unwrap();
+        }
+        let inner = tq.inner.read().await;
+        for client in [&inner.s3, &inner.page_client, &inner.bulk_client] {
+            assert_eq!(client.config().region().unwrap().as_ref(), "eu-west-1");
+        }
+        let stats = inner.stats();
+        assert_eq!(stats.region_resolutions, 1);
+        assert!(
+            stats.to_string().contains(&format!(
+                " region=eu-west-1 region_baseline={:?}",
+                region.baseline
+            )),
+            "{stats}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buckets_pinned_independently() {
+        let mock = MockS3::start();
+        mock.require_region("near", "us-east-1");
+        mock.require_region("far", "ap-southeast-2");
+        let near = instance(&mock, "near", true);
+        let far = instance(&mock, "far", true);
+        near.ensure_region().await.unwrap();
+        far.ensure_region().await.unwrap();
+        assert_eq!(discoveries(&mock), 2);
+
+        for _ in 0..2 {
+            write_metadata(&near).await.unwrap();
+            write_metadata(&far).await.unwrap();
+        }
+        let region = |tq: &ThreeQLite| {
+            let inner = tq.inner.try_read().unwrap();
+            inner.s3.config().region().unwrap().as_ref().to_owned()
+        };
+        assert_eq!(region(&near), "us-east-1");
+        assert_eq!(region(&far), "ap-southeast-2");
+    }
+
+    #[tokio::test]
+    async fn test_bucket_location_fallback() {
+        let mock = MockS3::start();
+        mock.require_region("far", "eu-west-1");
+        mock.hide_bucket_region(true);
+        let tq = instance(&mock, "far", true);
+        tq.ensure_region().await.unwrap();
+        assert_eq!(tq.region().await.unwrap().region, "eu-west-1");
+        write_metadata(&tq).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_moved_bucket_resolved_again() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        mock.require_region("far", "eu-west-1");
+        let tq = instance(&mock, "far", true);
+        let open = || async {
+            let opts = OpenOptions::new(OpenKind::MainDb, OpenAccess::Write);
+            Box::pin(tq.open("test.db", opts)).await.map(drop)
+        };
+        open().await.unwrap();
+        assert_eq!(discoveries(&mock), 1);
+
+        // recreated elsewhere: the open fails on the old region, then resolves it again once
+        mock.require_region("far", "eu-central-1");
+        open().await.unwrap();
+        assert_eq!(discoveries(&mock), 2);
+        assert_eq!(tq.region().await.unwrap().region, "eu-central-1");
+        open().await.unwrap();
+        assert_eq!(discoveries(&mock), 2);
+
+        assert_eq!(tq.stats().await.region_resolutions, 2);
+    }
+}
@@ -0,0 +1,276 @@
+//! Registering one instance under several VFS names.
+//!
+//! [ThreeQLite::register_as] registers an instance with a [RegistrationConfig] of its own, e.g. a
+//! read-write registration for the application next to a read-only alias for analytics. Every
+//! handle carries the [Registration] of the VFS that opened it.
+//!
+//! The object store client, the lock protocol, the page cache, the memory budget, the offline
+//! mirrors and the commit points are instance-wide: a page one registration read is a cache hit for
+//! the others, a transaction budget applies to each handle whichever registration opened it, and
+//! [ThreeQLite::stats] aggregates over all registrations. The settings of a [RegistrationConfig]
+//! and its [RegistrationStats] apply to its own handles only; a read-only alias refuses to open
+//! databases for writing, so SQLite opens them read-only and fails writes with `SQLITE_READONLY`,
+//! even though the instance itself writes through its other registrations.
+//!
+//! [ThreeQLite::register_as]: crate::vfs::ThreeQLite::register_as
+//! [ThreeQLite::stats]: crate::vfs::ThreeQLite::stats
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+};
+
+use crate::latency::TransactionObserver;
+
+tokio::task_local! {
+    /// The registration of the handle taking the write lock, see [scope].
+    static REGISTRATION: Option<Arc<Registration>>;
+}
+
+/// The settings of one registration of an instance.
+#[derive(Clone)]
+pub struct RegistrationConfig {
+    /// The name of the VFS.
+    pub name: String,
+    /// Open every database read-only, whatever the connection asks for.
+    pub readonly: bool,
+    /// Recorded as `<identity>/<suffix>` by the writers of this registration, where `identity` is
+    /// [LockConfig::identity](crate::config::LockConfig::identity), see [crate::busy].
+    pub identity_suffix: Option<String>,
+    /// Called with the transactions of its handles instead of the observers of the instance, see
+    /// [ThreeQLite::observe_transactions](crate::vfs::ThreeQLite::observe_transactions).
+    pub observer: Option<Arc<dyn TransactionObserver>>,
+}
+
+impl RegistrationConfig {
+    /// A read-write registration as `name`, inheriting everything else from the instance.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            readonly: false,
+            identity_suffix: None,
+            observer: None,
+        }
+    }
+}
+
+/// A registration of an instance, shared by the handles it opened.
+pub struct Registration {
+    pub config: RegistrationConfig,
+    pub stats: RegistrationStats,
+}
+
+/// Counters of the handles of one registration.
+#[derive(Default, Debug)]
+pub struct RegistrationStats {
+    /// Databases opened.
+    pub opens: AtomicU64,
+    /// Opens for writing refused because the registration is read-only.
+    pub rejected_writes: AtomicU64,
+    /// Transactions finished.
+    pub transactions: AtomicU64,
+    /// Bytes SQLite read from databases.
+    pub bytes_read: AtomicU64,
+    /// Bytes SQLite wrote to databases.
+    pub bytes_written: AtomicU64,
+}
+
+/// A point-in-time copy of the [RegistrationStats] of a registration.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegistrationSnapshot {
+    pub name: String,
+    pub readonly: bool,
+    pub opens: u64,
+    pub rejected_writes: u64,
+    pub transactions: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl Registration {
+    pub fn new(config: RegistrationConfig) -> Self {
+        Self {
+            config,
+            stats: RegistrationStats::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> RegistrationSnapshot {
+        RegistrationSnapshot {
+            name: self.config.name.clone(),
+            readonly: self.config.readonly,
+            opens: self.stats.opens.load(Relaxed),
+            rejected_writes: self.stats.rejected_writes.load(Relaxed),
+            transactions: self.stats.transactions.load(Relaxed),
+            bytes_read: self.stats.bytes_read.load(Relaxed),
+            bytes_written: self.stats.bytes_written.load(Relaxed),
+        }
+    }
+}
+
+impl std::fmt::Display for RegistrationSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "name={} readonly={} opens={} rejected_writes={} transactions={} bytes_read={} bytes_written={}",
+            self.name,
+            self.readonly,
+            self.opens,
+            self.rejected_writes,
+            self.transactions,
+            self.bytes_read,
+            self.bytes_written
+        )
+    }
+}
+
+/// Run `fut` on behalf of `registration`, so that the writer it records carries its identity, see
+/// [identity]. `fut` is boxed like the one of [crate::busy::with_handler].
+pub async fn scope<F: Future>(registration: Option<Arc<Registration>>, fut: F) -> F::Output {
+    REGISTRATION.scope(registration, Box::pin(fut)).await
+}
+
+/// `identity` qualified with the suffix of the registration running the current task, if any.
+pub fn identity(identity: &str) -> String {
+    let suffix = REGISTRATION
+        .try_with(|registration| {
+            registration
+                .as_ref()
+                .and_then(|registration| registration.config.identity_suffix.clone())
+        })
+        .ok()
+        .flatten();
+    match suffix {
+        Some(suffix) => format!("{identity}/{suffix}"),
+        None => identity.to_owned(),
+    }
+}
+
+/// Whether `holder` is `identity`, or `identity` qualified by any registration.
+pub fn is_own(holder: &str, identity: &str) -> bool {
+    holder
+        .strip_prefix(identity)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use sqlite_vfs::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+    use super::*;
+    use crate::{
+        cache::{CacheConfig, CacheUse},
+        config::Config,
+        latency::TransactionBreakdown,
+        mock,
+        vfs::ThreeQLite,
+    };
+
+    #[derive(Default)]
+    struct Observed(Mutex<Vec<String>>);
+
+    impl TransactionObserver for Observed {
+        fn on_transaction(&self, db: &str, _: &TransactionBreakdown) {
+            self.0.lock().unwrap().push(db.to_owned());
+        }
+    }
+
+    #[test]
+    fn test_is_own() {
+        assert!(is_own("host:12", "host:12"));
+        assert!(is_own("host:12/analytics", "host:12"));
+        assert!(!is_own("host:123", "host:12"));
+        assert!(!is_own("host:1", "host:12"));
+    }
+
+    #[tokio::test]
+    async fn test_registrations_share_the_instance() {
+        let mock = mock::MockS3::start();
+        mock.put("test.db", mock::database(4096, 4, 1));
+        let config = Config {
+            cache: CacheConfig {
+                capacity: 1024 * 1024,
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let instance = Arc::new(Observed::default());
+        let analytics = Arc::new(Observed::default());
+        tq.observe_transactions(instance.clone()).await;
+        let app = tq.registered(RegistrationConfig::new("app"));
+        let alias = tq.registered(RegistrationConfig {
+            readonly: true,
+            identity_suffix: Some("analytics".to_owned()),
+            observer: Some(analytics.clone()),
+            ..RegistrationConfig::new("analytics")
+        });
+
+        // the alias refuses to write, the instance and its other registration don't
+        let opts = |access| OpenOptions::new(OpenKind::MainDb, access);
+        assert!(matches!(
+            alias.open("test.db", opts(OpenAccess::Write)).await,
+            Err(sqlite_vfs::error::Error::PermissionDenied)
+        ));
+        assert!(!alias.access("test.db", true).await.unwrap());
+        assert!(app.access("test.db", true).await.unwrap());
+        let mut writer = app.open("test.db", opts(OpenAccess::Write)).await.unwrap();
+        let mut reader = alias.open("test.db", opts(OpenAccess::Read)).await.unwrap();
+
+        // a transaction through each, reported to the observers of its registration
+        assert!(writer.lock(LockKind::Exclusive).await.unwrap());
+        writer.write_all_at(&[1; 4096], 4096).await.unwrap();
+        writer.sync(false).await.unwrap();
+        let mut page = vec![0; 4096];
+        reader.read_exact_at(&mut page, 4096).await.unwrap();
+        assert_eq!(page, [1; 4096]);
+        reader.sync(false).await.unwrap();
+        assert_eq!(*instance.0.lock().unwrap(), ["test.db"]);
+        assert_eq!(*analytics.0.lock().unwrap(), ["test.db"]);
+
+        // a page one registration read at a generation is a cache hit for the other
+        let read = |tq: ThreeQLite| async move {
+            let inner = tq.inner.read().await;
+            inner
+                .read_at(8192, 4096, Some(1), CacheUse::Admit)
+                .await
+                .unwrap()
+        };
+        let page = read(app.clone()).await;
+        let requests = mock.requests().len();
+        assert_eq!(read(alias.clone()).await, page);
+        assert_eq!(mock.requests().len(), requests);
+        assert_eq!(tq.stats().await.cache.hits(), 1);
+
+        let app = tq.registration_stats("app").unwrap();
+        let alias = tq.registration_stats("analytics").unwrap();
+        assert_eq!((app.opens, app.rejected_writes), (1, 0));
+        assert_eq!((alias.opens, alias.rejected_writes), (1, 1));
+        assert_eq!((app.bytes_written, alias.bytes_written), (4096, 0));
+        assert_eq!((app.bytes_read, alias.bytes_read), (0, 4096));
+        assert_eq!((app.transactions, alias.transactions), (1, 1));
+        assert_eq!(tq.registration_stats("other"), None);
+        assert!(alias
+            .to_string()
+            .starts_with("name=analytics readonly=true opens=1 "));
+    }
+
+    #[tokio::test]
+    async fn test_identity() {
+        let suffixed = Registration::new(RegistrationConfig {
+            identity_suffix: Some("analytics".to_owned()),
+            ..RegistrationConfig::new("analytics")
+        });
+        assert_eq!(identity("host:12"), "host:12");
+        let qualified = scope(Some(Arc::new(suffixed)), async { identity("host:12") }).await;
+        assert_eq!(qualified, "host:12/analytics");
+        let plain = Registration::new(RegistrationConfig::new("app"));
+        let unqualified = scope(Some(Arc::new(plain)), async { identity("host:12") }).await;
+        assert_eq!(unqualified, "host:12");
+    }
+}
@@ -0,0 +1,768 @@
+//! Replaying a captured workload against any configuration.
+//!
+//! [replay] repeats the operations of a [Workload] captured by a user, see [crate::capture],
+//! through an instance configured as it likes, so that a configuration or a fix can be measured
+//! against the user's exact access pattern without their data. The database of the instance,
+//! [Config::db_filename](crate::config::Config::db_filename), stands in for the first database of
+//! the capture and is uploaded as a synthetic one of the same size and page size: a valid header,
+//! and bytes generated from a fixed seed. Writes write bytes generated from the number of their content,
+//! and reads of a content the replay wrote check that they read it back. Reads of contents the
+//! database held before aren't checked.
+//!
+//! Each operation goes through the VFS as SQLite would have called it, except locks, which the
+//! handles take as they need them: reads register as readers and flushes take the write lock, see
+//! [crate::protocol]. Lock transitions are only tracked, so that leaving a write transaction is
+//! its commit point as in [Handle::unlock](sqlite_vfs::DatabaseHandle::unlock). Opens and closes
+//! of the same file tell its connections apart, the operations in between go to the one opened
+//! last.
+//!
+//! The requests the replay sends are measured like the workloads of the benchmarks, see [Report].
+//!
+//! ```sh
+//! threeqlite replay capture.3qwl --db replay.db
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::c_int,
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use aws_sdk_s3::{
+    config::{
+        interceptors::{BeforeTransmitInterceptorContextRef, FinalizerInterceptorContextRef},
+        ConfigBag, Intercept, RuntimeComponents,
+    },
+    error::BoxError,
+};
+use aws_smithy_types::config_bag::{Storable, StoreReplace};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use sqlite_vfs::{DatabaseHandle, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+use crate::{
+    capture::{FileKind, Op, OpKind, Step, Workload},
+    circuit::OpClass,
+    create::{self, DatabaseState},
+    credentials,
+    error::Error,
+    handle::Handle,
+    key::{KeyLayout, ObjectKey},
+    vfs::{Inner, MetadataRecord, ThreeQLite},
+};
+
+/// The seed the synthetic databases and the contents written are generated from.
+const SEED: u64 = 0x3e91a7;
+
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+const SQLITE_OPEN_EXCLUSIVE: c_int = 0x10;
+const SQLITE_SYNC_DATAONLY: c_int = 0x10;
+const SQLITE_ACCESS_READWRITE: c_int = 1;
+const SQLITE_ACCESS_READ: c_int = 2;
+const NONE: c_int = 0;
+const RESERVED: c_int = 2;
+
+/// A request answered by the store.
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    pub method: String,
+    /// When the request was sent, or received in full by the mock store.
+    pub received: Instant,
+    /// When the response came in, or started being sent by the mock store.
+    pub answered: Instant,
+    /// Bytes of the request body.
+    pub bytes_in: usize,
+    /// Bytes of the response body.
+    pub bytes_out: usize,
+}
+
+/// The round trips on the critical path of `exchanges`: the longest chain of requests each
+/// received only after the previous one was answered, i.e. issued only after its response.
+pub fn critical_path(exchanges: &[Exchange]) -> usize {
+    let mut exchanges: Vec<_> = exchanges.iter().collect();
+    exchanges.sort_by_key(|exchange| exchange.received);
+    let mut depths: Vec<usize> = Vec::with_capacity(exchanges.len());
+    for (i, exchange) in exchanges.iter().enumerate() {
+        let after = (0..i)
+            .filter(|j| exchanges[*j].answered <= exchange.received)
+            .map(|j| depths[j])
+            .max()
+            .unwrap_or(0);
+        depths.push(after + 1);
+    }
+    depths.into_iter().max().unwrap_or(0)
+}
+
+/// What the store saw of a workload.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub wall_ms: u64,
+    /// Requests per method.
+    pub requests: BTreeMap<String, usize>,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub round_trips: usize,
+}
+
+impl Report {
+    pub fn new(exchanges: &[Exchange], wall: Duration) -> Self {
+        let mut requests = BTreeMap::new();
+        for exchange in exchanges {
+            *requests.entry(exchange.method.clone()).or_default() += 1;
+        }
+        Self {
+            wall_ms: wall.as_millis() as u64,
+            requests,
+            bytes_in: exchanges.iter().map(|exchange| exchange.bytes_in).sum(),
+            bytes_out: exchanges.iter().map(|exchange| exchange.bytes_out).sum(),
+            round_trips: critical_path(exchanges),
+        }
+    }
+
+    /// The requests of `method`.
+    pub fn count(&self, method: &str) -> usize {
+        self.requests.get(method).copied().unwrap_or(0)
+    }
+
+    /// The measures of this report that grew past `baseline` by more than `tolerance`, and the
+    /// wall time if it grew by more than `wall_tolerance`.
+    pub fn regressions(
+        &self,
+        baseline: &Report,
+        tolerance: f64,
+        wall_tolerance: Option<f64>,
+    ) -> Vec<String> {
+        let mut measures: Vec<(String, usize, usize)> = vec![
+            ("bytes_in".into(), baseline.bytes_in, self.bytes_in),
+            ("bytes_out".into(), baseline.bytes_out, self.bytes_out),
+            ("round_trips".into(), baseline.round_trips, self.round_trips),
+        ];
+        let methods = baseline.requests.keys().chain(self.requests.keys());
+        for method in methods.collect::<std::collections::BTreeSet<_>>() {
+            measures.push((
+                format!("{method} requests"),
+                baseline.count(method),
+                self.count(method),
+            ));
+        }
+        let mut regressions: Vec<String> = measures
+            .into_iter()
+            .filter(|(_, before, after)| *after as f64 > *before as f64 * (1.0 + tolerance))
+            .map(|(measure, before, after)| format!("{measure} {before} -> {after}"))
+            .collect();
+        if let Some(wall_tolerance) = wall_tolerance {
+            if self.wall_ms as f64 > baseline.wall_ms as f64 * (1.0 + wall_tolerance) {
+                regressions.push(format!("wall_ms {} -> {}", baseline.wall_ms, self.wall_ms));
+            }
+        }
+        regressions
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>5} ms {:>3} round trips",
+            self.wall_ms, self.round_trips
+        )?;
+        for (method, count) in &self.requests {
+            write!(f, " {method}={count}")?;
+        }
+        write!(f, " in={} out={}", self.bytes_in, self.bytes_out)
+    }
+}
+
+/// The requests of an instance, recorded while a replay measures them.
+#[derive(Debug, Default)]
+pub struct ExchangeLog {
+    recording: AtomicBool,
+    exchanges: Mutex<Vec<Exchange>>,
+}
+
+impl ExchangeLog {
+    fn start(&self) {
+        self.exchanges.lock().unwrap().clear();
+        self.recording.store(true, Relaxed);
+    }
+
+    fn stop(&self) -> Vec<Exchange> {
+        self.recording.store(false, Relaxed);
+        std::mem::take(&mut self.exchanges.lock().unwrap())
+    }
+}
+
+/// The attempt of a request being sent while an [ExchangeLog] records.
+#[derive(Debug, Clone)]
+struct Sending {
+    method: String,
+    at: Instant,
+    bytes_in: usize,
+}
+
+impl Storable for Sending {
+    type Storer = StoreReplace<Self>;
+}
+
+/// Records every attempt of a request in an [ExchangeLog] while it records.
+#[derive(Debug)]
+pub struct ExchangeWatch(pub Arc<ExchangeLog>);
+
+impl Intercept for ExchangeWatch {
+    fn name(&self) -> &'static str {
+        "ExchangeWatch"
+    }
+
+    fn read_before_transmit(
+        &self,
+        context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if !self.0.recording.load(Relaxed) {
+            return Ok(());
+        }
+        let request = context.request();
+        cfg.interceptor_state().store_put(Sending {
+            method: request.method().to_owned(),
+            at: Instant::now(),
+            bytes_in: request.body().content_length().unwrap_or(0) as usize,
+        });
+        Ok(())
+    }
+
+    fn read_after_attempt(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let Some(sending) = cfg.load::<Sending>() else {
+            return Ok(());
+        };
+        let bytes_out = match (sending.method.as_str(), context.response()) {
+            ("HEAD", _) | (_, None) => 0,
+            (_, Some(response)) => response
+                .headers()
+                .get("content-length")
+                .and_then(|len| len.parse().ok())
+                .unwrap_or(0),
+        };
+        self.0.exchanges.lock().unwrap().push(Exchange {
+            method: sending.method.clone(),
+            received: sending.at,
+            answered: Instant::now(),
+            bytes_in: sending.bytes_in,
+            bytes_out,
+        });
+        Ok(())
+    }
+}
+
+/// Settings of a [replay].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayOptions {
+    /// Keep the time between operations as captured, rather than replaying them one after the
+    /// other.
+    pub pace: bool,
+    /// Keep the synthetic databases afterwards, rather than deleting them.
+    pub keep: bool,
+}
+
+/// The outcome of a [replay].
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    /// The requests of the replay, not counting those setting it up.
+    pub report: Report,
+    /// The operations replayed, in order.
+    pub steps: Vec<Step>,
+    /// Reads of a content the replay wrote that read it back.
+    pub verified: usize,
+    /// Reads of a content the replay wrote that read something else.
+    pub mismatched: usize,
+    /// The operations that failed where the captured ones succeeded, by index.
+    pub failures: Vec<(usize, String)>,
+}
+
+impl std::fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} operations: {} reads verified, {} mismatched, {} failed; {}",
+            self.steps.len(),
+            self.verified,
+            self.mismatched,
+            self.failures.len(),
+            self.report
+        )
+    }
+}
+
+/// A database of `size` bytes in pages of `page_size`: a header SQLite accepts, then bytes
+/// generated from [SEED].
+pub fn synthetic(page_size: u32, size: u64) -> Vec<u8> {
+    let pages = size.div_ceil(page_size as u64).max(1);
+    let mut db = vec![0; (pages * page_size as u64) as usize];
+    StdRng::seed_from_u64(SEED).fill_bytes(&mut db[100..]);
+    db[..16].copy_from_slice(b"SQLite format 3\0");
+    let page_size_field = match page_size {
+        65536 => 1,
+        size => size as u16,
+    };
+    db[16..18].copy_from_slice(&page_size_field.to_be_bytes());
+    db[18..24].copy_from_slice(&[1, 1, 0, 64, 32, 32]);
+    db[24..28].copy_from_slice(&1u32.to_be_bytes());
+    db[28..32].copy_from_slice(&(pages as u32).to_be_bytes());
+    db[32..92].fill(0);
+    db[92..96].copy_from_slice(&1u32.to_be_bytes());
+    db
+}
+
+/// The bytes of a write of `content`, generated from its number.
+fn generate(content: Option<u32>, len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    let seed = SEED ^ content.map_or(u64::MAX, u64::from);
+    StdRng::seed_from_u64(seed).fill_bytes(&mut data);
+    data
+}
+
+/// Replays the operations of a workload, see [replay].
+struct Replayer<'a> {
+    tq: &'a ThreeQLite,
+    workload: &'a Workload,
+    /// The name each file of the workload is replayed as.
+    names: Vec<String>,
+    /// The handles of each file open, the last one opened at the end.
+    handles: HashMap<u16, Vec<Handle>>,
+    /// The lock level held on each file.
+    locks: HashMap<u16, c_int>,
+    /// The bytes written of each content.
+    written: HashMap<u32, Vec<u8>>,
+    /// The header of the synthetic databases, kept by writes so that they stay databases.
+    header: Vec<u8>,
+    report: ReplayReport,
+}
+
+impl<'a> Replayer<'a> {
+    fn new(tq: &'a ThreeQLite, workload: &'a Workload, db: &str) -> Self {
+        let mut dbs = 0;
+        let mut names: Vec<String> = vec![];
+        for (i, file) in workload.files.iter().enumerate() {
+            let of = |names: &[String]| match file.of {
+                Some(of) if (of as usize) < names.len() => names[of as usize].clone(),
+                _ => db.to_owned(),
+            };
+            let name = match file.kind {
+                FileKind::MainDb => {
+                    dbs += 1;
+                    match dbs {
+                        1 => db.to_owned(),
+                        n => format!("{db}.{n}"),
+                    }
+                }
+                FileKind::MainJournal => format!("{}-journal", of(&names)),
+                FileKind::SuperJournal => format!("{db}-mj{i:09X}"),
+                FileKind::Wal => format!("{}-wal", of(&names)),
+                FileKind::Other => format!("{db}.tmp{i}"),
+            };
+            names.push(name);
+        }
+        Self {
+            tq,
+            workload,
+            names,
+            handles: HashMap::new(),
+            locks: HashMap::new(),
+            written: HashMap::new(),
+            header: vec![],
+            report: ReplayReport::default(),
+        }
+    }
+
+    /// The databases of the workload, with their keys.
+    fn databases(&self) -> Result<Vec<(u16, ObjectKey)>, Error> {
+        let files = self.workload.files.iter().enumerate();
+        let dbs = files.filter(|(_, file)| file.kind == FileKind::MainDb);
+        dbs.map(|(i, _)| Ok((i as u16, KeyLayout::db(&self.names[i])?)))
+            .collect()
+    }
+
+    /// Upload a synthetic database for every database of the workload.
+    async fn set_up(&mut self) -> Result<(), Error> {
+        let inner = self.tq.inner.read().await;
+        for (_, key) in self.databases()? {
+            if create::state(&inner, &key).await? != DatabaseState::Missing {
+                return Err(Error::DatabaseExists { db: key.into() });
+            }
+        }
+        for (file, key) in self.databases()? {
+            let page_size = self.workload.page_size(file);
+            let db = synthetic(page_size, self.workload.size(file));
+            self.header = db[..100].to_vec();
+            put(&inner, &key, db).await?;
+        }
+        inner.write_metadata_record(MetadataRecord::default()).await
+    }
+
+    /// Delete the synthetic databases and any journal left.
+    async fn clean_up(&self) -> Result<(), Error> {
+        let inner = self.tq.inner.read().await;
+        for (_, key) in self.databases()? {
+            for key in [KeyLayout::journal(&key), key] {
+                inner.guard(OpClass::Write)?;
+                let res = credentials::send(inner.credentials.as_deref(), || {
+                    inner
+                        .s3
+                        .delete_object()
+                        .bucket(&inner.bucket)
+                        .key(&key)
+                        .send()
+                })
+                .await;
+                inner.record(OpClass::Write, res.is_ok());
+                res?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, file: u16) -> Result<&mut Handle, Error> {
+        let handles = self.handles.get_mut(&file);
+        handles
+            .and_then(|handles| handles.last_mut())
+            .ok_or_else(|| Error::Whatever {
+                message: "not open".to_owned(),
+                source: None,
+            })
+    }
+
+    async fn run(&mut self, op: &Op) -> Result<(), Error> {
+        let Step {
+            kind,
+            file,
+            offset,
+            len,
+            arg,
+        } = op.step;
+        let name = self.names[file as usize].clone();
+        let (offset, len, arg) = (
+            offset.unwrap_or(0),
+            len.unwrap_or(0) as usize,
+            arg.unwrap_or(0),
+        );
+        let external = |err: sqlite_vfs::error::Error<Error>| match err {
+            sqlite_vfs::error::Error::External { cause } => cause,
+            err => Error::Whatever {
+                message: err.to_string(),
+                source: None,
+            },
+        };
+        match kind {
+            OpKind::Open => {
+                let kind = match self.workload.files[file as usize].kind {
+                    FileKind::MainDb => OpenKind::MainDb,
+                    FileKind::MainJournal => OpenKind::MainJournal,
+                    FileKind::SuperJournal => OpenKind::SuperJournal,
+                    FileKind::Wal => OpenKind::Wal,
+                    FileKind::Other => OpenKind::TempDb,
+                };
+                let access = match arg {
+                    _ if arg & SQLITE_OPEN_EXCLUSIVE != 0 => OpenAccess::CreateNew,
+                    _ if arg & SQLITE_OPEN_CREATE != 0 => OpenAccess::Create,
+                    _ if arg & SQLITE_OPEN_READWRITE != 0 => OpenAccess::Write,
+                    _ => OpenAccess::Read,
+                };
+                // boxed, the future of an open is too large for the stack
+                let handle = Box::pin(self.tq.open(&name, OpenOptions::new(kind, access)))
+                    .await
+                    .map_err(external)?;
+                self.handles.entry(file).or_default().push(handle);
+            }
+            OpKind::Close => {
+                let handles = self.handles.get_mut(&file);
+                drop(handles.and_then(|handles| handles.pop()));
+            }
+            OpKind::Delete => self.tq.delete(&name).await.map_err(external)?,
+            OpKind::Access => {
+                let _ = match arg {
+                    SQLITE_ACCESS_READWRITE => self.tq.access(&name, true).await,
+                    SQLITE_ACCESS_READ => self.tq.access(&name, false).await,
+                    _ => self.tq.exists(&name).await,
+                }
+                .map_err(external)?;
+            }
+            OpKind::Read => {
+                let mut buf = vec![0; len];
+                let handle = self.handle(file)?;
+                match handle.read_exact_at(&mut buf, offset).await {
+                    Ok(()) | Err(sqlite_vfs::error::Error::UnexpectedEof) => {}
+                    Err(err) => return Err(external(err)),
+                }
+                let written = op.content.and_then(|content| self.written.get(&content));
+                match written {
+                    Some(written) if *written == buf => self.report.verified += 1,
+                    Some(_) => self.report.mismatched += 1,
+                    None => {}
+                }
+            }
+            OpKind::Write => {
+                let mut data = match op.content.and_then(|content| self.written.get(&content)) {
+                    Some(written) if written.len() == len => written.clone(),
+                    _ => generate(op.content, len),
+                };
+                let db = self.workload.files[file as usize].kind == FileKind::MainDb;
+                if db && offset < self.header.len() as u64 {
+                    let header = &self.header[offset as usize..];
+                    let n = header.len().min(len);
+                    data[..n].copy_from_slice(&header[..n]);
+                }
+                if let Some(content) = op.content {
+                    self.written.insert(content, data.clone());
+                }
+                let handle = self.handle(file)?;
+                handle.write_all_at(&data, offset).await.map_err(external)?;
+            }
+            OpKind::Truncate => self.handle(file)?.set_len(offset).await.map_err(external)?,
+            OpKind::Sync => {
+                let data_only = arg & SQLITE_SYNC_DATAONLY != 0;
+                let handle = self.handle(file)?;
+                handle.sync(data_only).await.map_err(external)?;
+            }
+            OpKind::FileSize => {
+                self.handle(file)?.size().await.map_err(external)?;
+            }
+            OpKind::Lock | OpKind::Unlock => self.lock(file, &name, arg).await?,
+        }
+        Ok(())
+    }
+
+    /// Move the lock of `file` to the level `to`, see the [module documentation](self).
+    async fn lock(&mut self, file: u16, name: &str, to: c_int) -> Result<(), Error> {
+        let held = self.locks.insert(file, to).unwrap_or(NONE);
+        if held >= RESERVED && to < RESERVED {
+            self.tq.commit_point(&KeyLayout::db(name)?).await?;
+        }
+        Ok(())
+    }
+
+    /// Leave the write transactions still running and close the handles still open.
+    async fn finish(&mut self) -> Result<(), Error> {
+        let files: Vec<_> = self.locks.keys().copied().collect();
+        for file in files {
+            let name = self.names[file as usize].clone();
+            self.lock(file, &name, NONE).await?;
+        }
+        self.handles.clear();
+        Ok(())
+    }
+}
+
+async fn put(inner: &Inner, key: &ObjectKey, data: Vec<u8>) -> Result<(), Error> {
+    inner.guard(OpClass::Write)?;
+    let res = credentials::send(inner.credentials.as_deref(), || {
+        inner
+            .s3
+            .put_object()
+            .bucket(&inner.bucket)
+            .key(key)
+            .body(data.clone().into())
+            .send()
+    })
+    .await;
+    inner.record(OpClass::Write, res.is_ok());
+    res?;
+    Ok(())
+}
+
+/// Replay the operations of `workload` through `tq`, see the [module documentation](self). Fails
+/// if the database of `tq` or another named like the synthetic ones exists, and if setting them up fails; operations
+/// failing are reported in [ReplayReport::failures].
+pub async fn replay(
+    tq: &ThreeQLite,
+    workload: &Workload,
+    options: &ReplayOptions,
+) -> Result<ReplayReport, Error> {
+    let db = tq.inner.read().await.db_filename.clone();
+    let mut replayer = Replayer::new(tq, workload, db.as_str());
+    Box::pin(replayer.set_up()).await?;
+    let log = tq.inner.read().await.exchanges.clone();
+
+    log.start();
+    let started = Instant::now();
+    for (i, op) in workload.ops.iter().enumerate() {
+        if options.pace {
+            let at = started + Duration::from_micros(op.at_us);
+            tokio::time::sleep_until(at.into()).await;
+        }
+        if let Err(err) = Box::pin(replayer.run(op)).await {
+            if op.rc == 0 {
+                replayer
+                    .report
+                    .failures
+                    .push((i, format!("{}: {err}", op.step)));
+            }
+        }
+        replayer.report.steps.push(op.step);
+    }
+    let finished = Box::pin(replayer.finish()).await;
+    let wall = started.elapsed();
+    replayer.report.report = Report::new(&log.stop(), wall);
+    finished?;
+
+    if !options.keep {
+        Box::pin(replayer.clean_up()).await?;
+    }
+    Ok(replayer.report)
+}
+
+impl ThreeQLite {
+    /// Replay the operations of `workload` through this instance, see [crate::replay].
+    pub async fn replay(
+        &self,
+        workload: &Workload,
+        options: &ReplayOptions,
+    ) -> Result<ReplayReport, Error> {
+        replay(self, workload, options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::CacheConfig,
+        capture::{self, Capture},
+        config::Config,
+        mock::MockS3,
+    };
+
+    #[test]
+    fn test_critical_path() {
+        let at = Instant::now();
+        let exchange = |received: u64, answered: u64| Exchange {
+            method: "GET".into(),
+            received: at + Duration::from_millis(received),
+            answered: at + Duration::from_millis(answered),
+            bytes_in: 0,
+            bytes_out: 0,
+        };
+        assert_eq!(critical_path(&[]), 0);
+        // two in parallel, then one after both
+        let exchanges = [exchange(0, 5), exchange(1, 6), exchange(6, 11)];
+        assert_eq!(critical_path(&exchanges), 2);
+        // one after the other, listed out of order
+        let exchanges = [exchange(10, 15), exchange(0, 5), exchange(5, 10)];
+        assert_eq!(critical_path(&exchanges), 3);
+    }
+
+    #[test]
+    fn test_regressions() {
+        let baseline = Report {
+            wall_ms: 100,
+            requests: [("GET".to_owned(), 10)].into(),
+            bytes_out: 1000,
+            round_trips: 10,
+            ..Default::default()
+        };
+        let mut report = baseline.clone();
+        report.requests.insert("GET".to_owned(), 11);
+        report.wall_ms = 500;
+        assert!(report.regressions(&baseline, 0.1, None).is_empty());
+        report.requests.insert("HEAD".to_owned(), 1);
+        report.round_trips = 5;
+        assert_eq!(
+            report.regressions(&baseline, 0.1, Some(1.0)),
+            ["HEAD requests 0 -> 1", "wall_ms 100 -> 500"]
+        );
+    }
+
+    #[test]
+    fn test_synthetic_database() {
+        let db = synthetic(4096, 10 * 4096 + 1);
+        assert_eq!(db.len(), 11 * 4096);
+        let crate::format::ObjectKind::Database(header) = crate::format::describe(&db) else {
+            panic!("not a database");
+        };
+        assert_eq!((header.page_size, header.page_count), (4096, Some(11)));
+        assert_eq!(db, synthetic(4096, 10 * 4096 + 1));
+        assert_eq!(synthetic(65536, 0).len(), 65536);
+    }
+
+    /// Replay `workload` through a fresh instance of `replay.db` configured with `config`, against
+    /// a store answering after 2ms.
+    async fn replay_with(workload: &Workload, config: Config) -> ReplayReport {
+        let config = Config {
+            db_filename: "replay.db".to_owned(),
+            ..config
+        };
+        let mock = MockS3::start();
+        for method in ["GET", "HEAD", "PUT", "DELETE"] {
+            mock.delay(method, Duration::from_millis(2));
+        }
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        // boxed, the future of a replay is too large for the stack of a test
+        let report = Box::pin(replay(&tq, workload, &ReplayOptions::default()))
+            .await
+            .unwrap();
+        // cleaned up
+        assert!(mock.get("replay.db").is_none());
+        report
+    }
+
+    #[tokio::test]
+    async fn test_replay_against_two_configs() {
+        let capture = Capture::default();
+        capture::tests::canonical(&capture);
+        let workload = Workload::decode(&capture.workload().encode()).unwrap();
+
+        let uncached = replay_with(&workload, Config::default()).await;
+        let cached = replay_with(
+            &workload,
+            Config {
+                cache: CacheConfig {
+                    capacity: 4 * 1024 * 1024,
+                    ..CacheConfig::default()
+                },
+                ..Config::default()
+            },
+        )
+        .await;
+
+        for report in [&uncached, &cached] {
+            assert_eq!(report.steps, workload.steps());
+            assert!(report.failures.is_empty(), "{:?}", report.failures);
+            assert_eq!((report.verified, report.mismatched), (1, 0));
+            // the pages and the journal uploaded
+            assert!(report.report.bytes_in > 4 * capture::tests::PAGE_SIZE);
+        }
+        // the pages looked up again are served from the cache
+        assert!(cached.report.count("GET") < uncached.report.count("GET"));
+        assert!(cached.report.bytes_out < uncached.report.bytes_out);
+        assert!(cached.report.round_trips < uncached.report.round_trips);
+        assert!(cached
+            .report
+            .regressions(&uncached.report, 0.0, None)
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replay_keeps_existing_database() {
+        let mock = MockS3::start();
+        mock.put("replay.db", crate::mock::database(4096, 2, 1));
+        let config = Config {
+            db_filename: "replay.db".to_owned(),
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let capture = Capture::default();
+        capture::tests::canonical(&capture);
+        let res = Box::pin(replay(&tq, &capture.workload(), &ReplayOptions::default())).await;
+        assert!(matches!(res, Err(Error::DatabaseExists { db }) if db == "replay.db"));
+        assert_eq!(mock.get("replay.db").unwrap().len(), 2 * 4096);
+    }
+}
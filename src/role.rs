@@ -0,0 +1,556 @@
+//! Running the background maintenance of a database in one instance at a time.
+//!
+//! Instances of a horizontally scaled service share their databases, and each would otherwise run
+//! the same background tasks against them, multiplying their requests and racing each other's
+//! writes. Each background task of a database runs a [Role], and only the instance holding the
+//! lease of the role runs it. The lease is an object next to the database
+//! ([KeyLayout::role_lease]) naming its holder, when it expires, and the outcome of the last run.
+//!
+//! Every cycle of a task first claims the role, see [ThreeQLite::run_role]. A claim reads the
+//! lease and, if it is absent or expired, writes a lease of its own with `If-None-Match: *` or
+//! `If-Match` on the ETag it read, so that of instances claiming at once only one wins. The holder
+//! renews its lease on the ETag of its last write without reading it first. The others stand by,
+//! at a GET per cycle, and take over once the holder stopped renewing, e.g. because it was
+//! dropped, and its lease ran out. A lease lasts [RoleConfig::lease_cycles] intervals of the task,
+//! so a holder late for a cycle keeps it. A standby doesn't compare [Lease::expires] against its
+//! wall clock, but takes over once it observed the same lease, by its ETag, for as long as a lease
+//! lasts on its monotonic clock, see [crate::clock]. After a jump of the clocks the holder reads
+//! its lease again before renewing it.
+//!
+//! Instances with [RoleConfig::volunteer] unset never claim a role, e.g. serving instances next to
+//! dedicated maintenance instances. [ThreeQLite::roles] lists what the instance knows of the roles
+//! it claimed, also reported by `PRAGMA threeqlite_stats`, and [ThreeQLite::role_leases] reads the
+//! leases of a database, as `threeqlite busy` prints them. The outcome of a run reaches the lease
+//! with the renewal of the next cycle.
+
+use std::{collections::BTreeMap, fmt, future::Future, sync::Mutex, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    circuit::OpClass,
+    clock::Observation,
+    error::Error,
+    format::{self, Bounded},
+    key::{KeyLayout, ObjectKey},
+    vfs::{status, Inner, ThreeQLite},
+};
+
+#[derive(Clone, Debug)]
+pub struct RoleConfig {
+    /// Whether this instance claims roles at all.
+    pub volunteer: bool,
+    /// How many intervals of its task a lease lasts, and thus how many cycles it takes a standby
+    /// to take over from a holder that went away.
+    pub lease_cycles: u32,
+}
+
+impl Default for RoleConfig {
+    fn default() -> Self {
+        Self {
+            volunteer: true,
+            lease_cycles: 3,
+        }
+    }
+}
+
+/// A background task of a database that only one instance runs at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    /// [ThreeQLite::reconcile], as run by [ThreeQLite::spawn_reconcile].
+    Reconcile,
+}
+
+impl Role {
+    pub const ALL: [Role; 1] = [Role::Reconcile];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Reconcile => "reconcile",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|role| role.name() == name)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The outcome of a run of a role.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoleRun {
+    /// When the run finished, in milliseconds since the Unix epoch.
+    pub finished: u64,
+    pub ok: bool,
+    /// What the run found, or why it failed.
+    pub summary: String,
+}
+
+impl fmt::Display for RoleRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "finished={} ok={} summary=({})",
+            self.finished, self.ok, self.summary
+        )
+    }
+}
+
+/// The lease object of a role.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    /// [LockConfig::identity](crate::config::LockConfig::identity) of the holder.
+    pub holder: String,
+    /// Random per instance, telling instances of the same identity apart.
+    pub instance: u64,
+    /// End of the lease, in milliseconds since the Unix epoch.
+    pub expires: u64,
+    /// The last run the holder finished before writing the lease.
+    pub last_run: Option<RoleRun>,
+}
+
+impl Bounded for Lease {
+    const MAX_LEN: u64 = 64 << 10;
+}
+
+/// What this instance knows of a role of a database, as of its last claim.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoleState {
+    pub db: String,
+    pub role: Role,
+    /// Whether this instance holds the lease.
+    pub held: bool,
+    /// The identity of the holder, if known.
+    pub holder: Option<String>,
+    /// Runs of the role by this instance.
+    pub runs: u64,
+    /// Claims this instance lost to another holder.
+    pub standbys: u64,
+    /// The last run by this instance.
+    pub last_run: Option<RoleRun>,
+    /// The ETag of the lease this instance wrote last, while it holds it.
+    etag: Option<String>,
+    /// The lease of another holder, while this instance stands by.
+    observed: Option<Observation>,
+}
+
+impl fmt::Display for RoleState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "db={} role={} held={} holder={} runs={} standbys={}",
+            self.db,
+            self.role,
+            self.held,
+            self.holder.as_deref().unwrap_or("-"),
+            self.runs,
+            self.standbys
+        )?;
+        if let Some(run) = &self.last_run {
+            write!(f, " last_run=({run})")?;
+        }
+        Ok(())
+    }
+}
+
+/// The roles an instance claimed, see the [module documentation](self).
+#[derive(Debug)]
+pub struct Roles {
+    pub config: RoleConfig,
+    /// See [Lease::instance].
+    instance: u64,
+    states: Mutex<BTreeMap<(ObjectKey, Role), RoleState>>,
+}
+
+impl Roles {
+    pub fn new(config: RoleConfig) -> Self {
+        Self {
+            config,
+            instance: rand::random(),
+            states: Mutex::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<RoleState> {
+        self.states.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Count the leases held as lost and start observing those of others over, after a jump of
+    /// the clocks.
+    pub fn forget(&self) {
+        for state in self.states.lock().unwrap().values_mut() {
+            state.held = false;
+            state.etag = None;
+            state.observed = None;
+        }
+    }
+
+    fn update<T>(&self, db: &ObjectKey, role: Role, f: impl FnOnce(&mut RoleState) -> T) -> T {
+        let mut states = self.states.lock().unwrap();
+        let state = states
+            .entry((db.clone(), role))
+            .or_insert_with(|| RoleState {
+                db: db.to_string(),
+                role,
+                held: false,
+                holder: None,
+                runs: 0,
+                standbys: 0,
+                last_run: None,
+                etag: None,
+                observed: None,
+            });
+        f(state)
+    }
+}
+
+/// The lease at `key` and its ETag, `None` if there is none.
+async fn read_lease(inner: &Inner, key: &ObjectKey) -> Result<Option<(Lease, String)>, Error> {
+    let obj = inner
+        .s3
+        .get_object()
+        .bucket(&inner.bucket)
+        .key(key)
+        .send()
+        .await;
+    let missing = matches!(&obj, Err(err) if status(err) == Some(404));
+    inner.record(OpClass::Read, obj.is_ok() || missing);
+    let obj = match obj {
+        Ok(obj) => obj,
+        Err(err) if status(&err) == Some(404) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let corrupt = |reason| Error::CorruptManifest {
+        key: key.to_string(),
+        reason,
+    };
+    format::check_len::<Lease>(obj.content_length().unwrap_or(0) as u64).map_err(corrupt)?;
+    let etag = obj.e_tag().unwrap_or_default().to_owned();
+    let bytes = obj.body.collect().await.map_err(|err| Error::Whatever {
+        message: format!("failed to read object body: {err}"),
+        source: None,
+    })?;
+    let lease = format::parse(&bytes.into_bytes()).map_err(corrupt)?;
+    Ok(Some((lease, etag)))
+}
+
+/// Write `lease` to `key` if it is at `etag`, or absent if `None`. Returns the ETag written, or
+/// `None` if another instance wrote the lease first.
+async fn write_lease(
+    inner: &Inner,
+    key: &ObjectKey,
+    lease: &Lease,
+    etag: Option<&str>,
+) -> Result<Option<String>, Error> {
+    let bytes = bincode::serialize(lease).map_err(|err| Error::Whatever {
+        message: format!("failed to encode role lease: {err}"),
+        source: Some(err),
+    })?;
+    let put = inner
+        .s3
+        .put_object()
+        .bucket(&inner.bucket)
+        .key(key)
+        .body(bytes.into());
+    let put = match etag {
+        Some(etag) => put.if_match(etag),
+        None => put.if_none_match("*"),
+    };
+    let res = put.send().await;
+    let lost = matches!(&res, Err(err) if status(err) == Some(412));
+    inner.record(OpClass::Write, res.is_ok() || lost);
+    match res {
+        Ok(out) => Ok(Some(out.e_tag.unwrap_or_default())),
+        Err(_) if lost => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Claim `role` of `db` for `lease`, see the [module documentation](self). Returns whether this
+/// instance holds it.
+async fn claim(inner: &Inner, db: &ObjectKey, role: Role, lease: Duration) -> Result<bool, Error> {
+    let roles = &inner.roles;
+    if !roles.config.volunteer {
+        return Ok(false);
+    }
+    inner.check_clock();
+    let key = KeyLayout::role_lease(db, role);
+    let (held, last_run) = roles.update(db, role, |state| {
+        (state.etag.clone(), state.last_run.clone())
+    });
+    let etag = match held {
+        Some(etag) => Some(etag),
+        None => match read_lease(inner, &key).await? {
+            None => None,
+            Some((current, etag)) if current.instance == roles.instance => Some(etag),
+            Some((current, etag)) => {
+                let expired = roles.update(db, role, |state| {
+                    let observed = Observation::observe(&mut state.observed, &etag);
+                    if observed < lease {
+                        state.held = false;
+                        state.holder = Some(current.holder);
+                        state.standbys += 1;
+                    }
+                    observed >= lease
+                });
+                if !expired {
+                    return Ok(false);
+                }
+                Some(etag)
+            }
+        },
+    };
+    let renewal = Lease {
+        holder: inner.lock_config.identity.clone(),
+        instance: roles.instance,
+        expires: inner.clock.now_ms() + lease.as_millis() as u64,
+        last_run,
+    };
+    let written = write_lease(inner, &key, &renewal, etag.as_deref()).await?;
+    let held = written.is_some();
+    if !held {
+        tracing::debug!(target: "threeqlite::s3", db = %db, %role, "role claimed by another instance");
+    }
+    roles.update(db, role, |state| {
+        if held && !state.held {
+            tracing::info!(target: "threeqlite::s3", db = %db, %role, "took over role");
+        }
+        state.held = held;
+        state.holder = held.then(|| renewal.holder.clone());
+        state.standbys += !held as u64;
+        state.etag = written;
+        state.observed = None;
+    });
+    Ok(held)
+}
+
+impl ThreeQLite {
+    /// Claim `role` of `db` for a task running every `interval`, and run `work` if this instance
+    /// holds it, recording its outcome. Returns `None` without running `work` if another instance
+    /// holds the role, or if this one doesn't volunteer, see [RoleConfig].
+    pub async fn run_role<T: fmt::Display>(
+        &self,
+        db: &str,
+        role: Role,
+        interval: Duration,
+        work: impl Future<Output = Result<T, Error>>,
+    ) -> Result<Option<T>, Error> {
+        let db = KeyLayout::db(db)?;
+        let (roles, clock) = {
+            let inner = self.inner.read().await;
+            let lease = interval.saturating_mul(inner.roles.config.lease_cycles);
+            if !claim(&inner, &db, role, lease).await? {
+                return Ok(None);
+            }
+            (inner.roles.clone(), inner.clock.clone())
+        };
+        let res = work.await;
+        let run = RoleRun {
+            finished: clock.now_ms(),
+            ok: res.is_ok(),
+            summary: match &res {
+                Ok(outcome) => outcome.to_string(),
+                Err(err) => err.to_string(),
+            },
+        };
+        roles.update(&db, role, |state| {
+            state.runs += 1;
+            state.last_run = Some(run);
+        });
+        res.map(Some)
+    }
+
+    /// The roles this instance claimed, with what it knows of them.
+    pub async fn roles(&self) -> Vec<RoleState> {
+        self.inner.read().await.roles.snapshot()
+    }
+
+    /// The lease of each role of `db`, `None` for roles nobody claimed yet.
+    pub async fn role_leases(&self, db: &str) -> Result<Vec<(Role, Option<Lease>)>, Error> {
+        let inner = self.inner.read().await;
+        let db = KeyLayout::db(db)?;
+        let mut leases = vec![];
+        for role in Role::ALL {
+            let lease = read_lease(&inner, &KeyLayout::role_lease(&db, role)).await?;
+            leases.push((role, lease.map(|(lease, _)| lease)));
+        }
+        Ok(leases)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, ClockConfig, SteppedClock},
+        config::{Config, LockConfig},
+        mock::{self, MockS3},
+        reconcile::ReconcileReport,
+    };
+
+    fn instance(mock: &MockS3, identity: &str, volunteer: bool) -> ThreeQLite {
+        let config = Config {
+            lock: LockConfig {
+                identity: identity.to_owned(),
+                ..LockConfig::default()
+            },
+            roles: RoleConfig {
+                volunteer,
+                ..RoleConfig::default()
+            },
+            ..Config::default()
+        };
+        ThreeQLite::with_client(config, mock.client()).unwrap()
+    }
+
+    /// Run a cycle of [Role::Reconcile] of `test.db` in all of `instances` at once, returning
+    /// which ran it.
+    async fn cycle(instances: &[&ThreeQLite], interval: Duration) -> Vec<bool> {
+        let mut ran = vec![];
+        let mut tasks = tokio::task::JoinSet::new();
+        for (i, tq) in instances.iter().enumerate() {
+            let tq = (*tq).clone();
+            tasks.spawn(async move {
+                let work = tq.reconcile("test.db");
+                let res = tq.run_role("test.db", Role::Reconcile, interval, work);
+                (i, Box::pin(res).await.unwrap())
+            });
+        }
+        ran.resize(instances.len(), false);
+        while let Some(res) = tasks.join_next().await {
+            let (i, report): (usize, Option<ReconcileReport>) = res.unwrap();
+            ran[i] = report.is_some();
+        }
+        ran
+    }
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let (a, b, c) = (
+            instance(&mock, "a", true),
+            instance(&mock, "b", true),
+            instance(&mock, "c", true),
+        );
+        let standby = instance(&mock, "standby", false);
+        let lease_key = "test.db.roles/reconcile";
+        let interval = Duration::from_millis(100);
+
+        let mut holder = 0;
+        for n in 0..5 {
+            let requests = mock.requests().len();
+            let lists = mock.lists().len();
+            let ran = cycle(&[&a, &b, &c, &standby], interval).await;
+            assert_eq!(
+                ran.iter().filter(|ran| **ran).count(),
+                1,
+                "cycle {n}: {ran:?}"
+            );
+            assert!(!ran[3]);
+            // the holder keeps the role
+            let ran_in = ran.iter().position(|ran| *ran).unwrap();
+            match n {
+                0 => holder = ran_in,
+                _ => assert_eq!(ran_in, holder, "cycle {n}"),
+            }
+            assert_eq!(mock.lists().len() - lists, 1, "cycle {n}");
+            // the only writes are the claims of the lease, of which one succeeded
+            let requests = mock.requests()[requests..].to_vec();
+            let writes: Vec<_> = requests
+                .iter()
+                .filter(|(method, _)| method != "GET" && method != "HEAD")
+                .collect();
+            assert!(writes
+                .iter()
+                .all(|(method, key)| method == "PUT" && key == lease_key));
+            match n {
+                // those that found the lease absent race for it
+                0 => assert!((1..=3).contains(&writes.len())),
+                // only the holder renews
+                _ => assert_eq!(writes.len(), 1, "cycle {n}"),
+            }
+        }
+
+        // visible in the lease and in the stats of the holder
+        let instances = [&a, &b, &c];
+        let holder = instances[holder];
+        let is_holder = |tq: &&ThreeQLite| Arc::ptr_eq(&tq.inner, &holder.inner);
+        let identity = holder.inner.read().await.lock_config.identity.clone();
+        let leases = standby.role_leases("test.db").await.unwrap();
+        let (role, Some(lease)) = &leases[0] else {
+            panic!("{leases:?}")
+        };
+        assert_eq!(
+            (*role, lease.holder.as_str()),
+            (Role::Reconcile, identity.as_str())
+        );
+        let last_run = lease.last_run.as_ref().unwrap();
+        assert!(last_run.ok && last_run.summary.contains("complete=true"));
+        let states = holder.roles().await;
+        assert_eq!((states[0].held, states[0].runs), (true, 5));
+        for other in instances.into_iter().filter(|tq| !is_holder(tq)) {
+            let states = other.roles().await;
+            assert_eq!((states[0].held, states[0].runs), (false, 0));
+            assert_eq!(states[0].holder.as_deref(), Some(identity.as_str()));
+        }
+        assert!(standby.roles().await.is_empty());
+
+        // a dropped holder stops renewing, and one of the others takes over once its lease ran out
+        let others: Vec<_> = instances.into_iter().filter(|tq| !is_holder(tq)).collect();
+        let ran = cycle(&others, interval).await;
+        assert_eq!(ran, [false, false]);
+        tokio::time::sleep(interval * RoleConfig::default().lease_cycles).await;
+        let ran = cycle(&others, interval).await;
+        assert_eq!(ran.iter().filter(|ran| **ran).count(), 1, "{ran:?}");
+        let ran = cycle(&others, interval).await;
+        assert_eq!(ran.iter().filter(|ran| **ran).count(), 1, "{ran:?}");
+    }
+
+    #[tokio::test]
+    async fn test_clock_jump() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let (a, b) = (instance(&mock, "a", true), instance(&mock, "b", true));
+        let wall = Arc::new(SteppedClock::default());
+        a.inner.write().await.clock =
+            Arc::new(Clock::with_wall(ClockConfig::default(), wall.clone()));
+        let lease_key = "test.db.roles/reconcile";
+        let interval = Duration::from_millis(100);
+        assert_eq!(cycle(&[&a], interval).await, [true]);
+        assert_eq!(cycle(&[&b], interval).await, [false]);
+        a.inner
+            .read()
+            .await
+            .cache
+            .pin_header("test.db", 1, 0, &[0; 100]);
+
+        // a is suspended, or its clock stepped ahead, while b takes over
+        wall.step(3_600_000);
+        tokio::time::sleep(interval * RoleConfig::default().lease_cycles).await;
+        assert_eq!(cycle(&[&b], interval).await, [true]);
+
+        // a reads its lease before renewing it, and finds the lease of b
+        let requests = mock.requests().len();
+        assert_eq!(cycle(&[&a], interval).await, [false]);
+        let requests = mock.requests()[requests..].to_vec();
+        assert_eq!(requests, [("GET".to_owned(), lease_key.to_owned())]);
+        let inner = a.inner.read().await;
+        assert_eq!(inner.clock.jumps(), 1);
+        assert_eq!(inner.cache.header("test.db", 1, 0, 16), None);
+        drop(inner);
+
+        // which has long expired by the clock of a, but a didn't see b stop renewing it
+        assert_eq!(cycle(&[&a], interval).await, [false]);
+        let states = a.roles().await;
+        assert!(!states[0].held);
+        assert_eq!(states[0].holder.as_deref(), Some("b"));
+    }
+}
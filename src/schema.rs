@@ -0,0 +1,285 @@
+//! Noticing schema changes committed by other writers.
+//!
+//! When one service migrates a shared database, the others keep their prepared statements for
+//! the old schema. SQLite bumps the schema cookie in the database header (offset 40) with every
+//! schema change, and the header is fetched anyway whenever page 1 is read at a new generation,
+//! so a [SchemaWatch] compares the cookie of each generation with the one before at no cost.
+//!
+//! A change is only reported for a generation newer than the last one observed. Pages SQLite
+//! rolls back are never committed as a generation of their own, so a rollback can't report one.
+//! A change is counted in [Stats::schema_changes], passed to the
+//! [TransactionObserver::on_schema_change] of the observers of the instance, and to the
+//! callbacks registered with [ThreeQLite::on_schema_change]. Connections opened with
+//! [AsyncConnection::open] drop their cached statements before their next statement, and so do
+//! the connections of a [ReadPool] on checkout.
+//!
+//! [Stats::schema_changes]: crate::stats::Stats::schema_changes
+//! [TransactionObserver::on_schema_change]: crate::latency::TransactionObserver::on_schema_change
+//! [ThreeQLite::on_schema_change]: crate::vfs::ThreeQLite::on_schema_change
+//! [AsyncConnection::open]: crate::asyncdb::AsyncConnection::open
+//! [ReadPool]: crate::pool::ReadPool
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// The offset of the schema cookie in the database header.
+pub const COOKIE_OFFSET: usize = 40;
+
+/// The schema cookie of the database starting with `header`, `None` if it is too short.
+pub fn cookie(header: &[u8]) -> Option<u32> {
+    let bytes = header.get(COOKIE_OFFSET..COOKIE_OFFSET + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// The schema cookie of a database changed between two generations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SchemaChange {
+    pub db: String,
+    pub old_cookie: u32,
+    pub new_cookie: u32,
+    /// The generation the new cookie was observed at.
+    pub generation: u64,
+}
+
+impl std::fmt::Display for SchemaChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "schema of {} changed from cookie {} to {} at generation {}",
+            self.db, self.old_cookie, self.new_cookie, self.generation
+        )
+    }
+}
+
+/// See [crate::vfs::ThreeQLite::on_schema_change].
+pub type SchemaCallback =
+    Arc<dyn Fn(SchemaChange) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// The schema cookies observed by an instance, see the [module documentation](self).
+#[derive(Default)]
+pub struct SchemaWatch {
+    /// The newest generation observed of each database, and its cookie.
+    cookies: Mutex<HashMap<String, (u64, u32)>>,
+    callbacks: Mutex<Vec<SchemaCallback>>,
+    /// Changes observed on any database.
+    changes: Arc<AtomicU64>,
+}
+
+impl SchemaWatch {
+    /// Record `cookie` as that of `db` at `generation`, unless a generation as new was observed
+    /// already. Returns the change if it differs from the cookie of the older generation.
+    pub fn observe(&self, db: &str, generation: u64, cookie: u32) -> Option<SchemaChange> {
+        let mut cookies = self.cookies.lock().unwrap();
+        let old = cookies.get(db).copied();
+        if old.is_some_and(|(seen, _)| seen >= generation) {
+            return None;
+        }
+        cookies.insert(db.to_owned(), (generation, cookie));
+        let (_, old_cookie) = old?;
+        if old_cookie == cookie {
+            return None;
+        }
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        Some(SchemaChange {
+            db: db.to_owned(),
+            old_cookie,
+            new_cookie: cookie,
+            generation,
+        })
+    }
+
+    pub fn subscribe(&self, callback: SchemaCallback) {
+        self.callbacks.lock().unwrap().push(callback);
+    }
+
+    /// Run the callbacks with `change`, each in a task of its own.
+    pub fn notify(&self, change: &SchemaChange) {
+        let callbacks = self.callbacks.lock().unwrap().clone();
+        for callback in callbacks {
+            tokio::spawn(callback(change.clone()));
+        }
+    }
+
+    /// The changes observed so far, shared with connections that compare it with the count they
+    /// last prepared statements at.
+    pub fn changes(&self) -> Arc<AtomicU64> {
+        self.changes.clone()
+    }
+}
+
+/// Whether the cached statements of a connection predate a schema change.
+#[derive(Debug)]
+pub struct Statements {
+    changes: Arc<AtomicU64>,
+    seen: AtomicU64,
+    flushes: AtomicU64,
+}
+
+impl Statements {
+    /// Statements prepared now, as of the changes counted by `changes`.
+    pub fn new(changes: Arc<AtomicU64>) -> Self {
+        let seen = AtomicU64::new(changes.load(Ordering::Relaxed));
+        Self {
+            changes,
+            seen,
+            flushes: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the schema changed since the last call, in which case the statements are to be
+    /// flushed, which this counts.
+    pub fn stale(&self) -> bool {
+        let changes = self.changes.load(Ordering::Relaxed);
+        let stale = self.seen.swap(changes, Ordering::Relaxed) != changes;
+        if stale {
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+        }
+        stale
+    }
+
+    /// How often the statements were flushed.
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(cookie: u32) -> Vec<u8> {
+        let mut header = vec![0; 100];
+        header[COOKIE_OFFSET..COOKIE_OFFSET + 4].copy_from_slice(&cookie.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn test_observe() {
+        let watch = SchemaWatch::default();
+        assert_eq!(cookie(&header(7)), Some(7));
+        assert_eq!(cookie(&[0; 43]), None);
+
+        // the first observation has nothing to compare with
+        assert_eq!(watch.observe("a.db", 1, 7), None);
+        // data-only commits keep the cookie
+        assert_eq!(watch.observe("a.db", 2, 7), None);
+        assert_eq!(
+            watch.observe("a.db", 3, 8),
+            Some(SchemaChange {
+                db: "a.db".to_owned(),
+                old_cookie: 7,
+                new_cookie: 8,
+                generation: 3,
+            })
+        );
+        // seen again, e.g. by another read of page 1
+        assert_eq!(watch.observe("a.db", 3, 8), None);
+        // an older generation read late, and pages written before the generation moved on
+        assert_eq!(watch.observe("a.db", 2, 7), None);
+        assert_eq!(watch.observe("a.db", 3, 9), None);
+        let change = watch.observe("a.db", 4, 9).unwrap();
+        assert_eq!((change.old_cookie, change.new_cookie), (8, 9));
+        // databases apart
+        assert_eq!(watch.observe("b.db", 4, 1), None);
+        assert_eq!(watch.changes().load(Ordering::Relaxed), 2);
+
+        let statements = Statements::new(watch.changes());
+        assert!(!statements.stale());
+        watch.observe("b.db", 5, 2).unwrap();
+        assert!(statements.stale());
+        assert!(!statements.stale());
+        assert_eq!(statements.flushes(), 1);
+    }
+
+    #[cfg(feature = "s3")]
+    #[tokio::test]
+    async fn test_migration_by_another_writer() {
+        use crate::{
+            cache::{CacheConfig, CacheUse},
+            config::Config,
+            latency::{TransactionBreakdown, TransactionObserver},
+            mock,
+            vfs::ThreeQLite,
+        };
+
+        #[derive(Default)]
+        struct Observed(Mutex<Vec<SchemaChange>>);
+
+        impl TransactionObserver for Observed {
+            fn on_transaction(&self, _: &str, _: &TransactionBreakdown) {}
+
+            fn on_schema_change(&self, change: &SchemaChange) {
+                self.0.lock().unwrap().push(change.clone());
+            }
+        }
+
+        let mock = mock::MockS3::start();
+        let commit = |generation: u32, cookie: u32| {
+            let mut db = mock::database(4096, 4, generation);
+            db[COOKIE_OFFSET..COOKIE_OFFSET + 4].copy_from_slice(&cookie.to_be_bytes());
+            mock.put("test.db", db);
+        };
+        commit(1, 1);
+        let config = Config {
+            cache: CacheConfig {
+                capacity: 1024 * 1024,
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let observer = Arc::new(Observed::default());
+        tq.observe_transactions(observer.clone()).await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tq.on_schema_change(move |change| {
+            let tx = tx.clone();
+            async move {
+                tx.send(change).unwrap();
+            }
+        })
+        .await;
+        // page 1 as read by SQLite at each generation
+        let read = |generation| {
+            let tq = tq.clone();
+            async move {
+                let inner = tq.inner.read().await;
+                inner
+                    .read_at(0, 4096, Some(generation), CacheUse::Admit)
+                    .await
+                    .unwrap();
+            }
+        };
+
+        read(1).await;
+        // data-only commits of the other writer
+        commit(2, 1);
+        read(2).await;
+        commit(3, 1);
+        read(3).await;
+        // its migration
+        commit(4, 2);
+        read(4).await;
+        read(4).await;
+        commit(5, 2);
+        read(5).await;
+
+        let change = rx.recv().await.unwrap();
+        assert_eq!(
+            (change.old_cookie, change.new_cookie, change.generation),
+            (1, 2, 4)
+        );
+        tokio::task::yield_now().await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(*observer.0.lock().unwrap(), [change]);
+        let stats = tq.stats().await;
+        assert_eq!(stats.schema_changes, 1);
+        assert!(stats.to_string().contains(" schema_changes=1"), "{stats}");
+    }
+}
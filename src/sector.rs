@@ -0,0 +1,201 @@
+//! The sector size database handles answer to `xSectorSize`.
+//!
+//! SQLite pads the header of each rollback journal to the sector size of the database. Unless the
+//! database advertises `SQLITE_IOCAP_POWERSAFE_OVERWRITE`, it also assumes a crash may garble
+//! every sector a write touches, so it journals all the pages sharing a sector with a page it
+//! writes. threeqlite advertises powersafe overwrites, like SQLite's own VFSes, which is honest:
+//! the database object only ever changes through PUTs that land whole or not at all, so a crash
+//! garbles no bytes next to those written. SQLite then uses 512 whatever the answer, and the
+//! handles answer 512 to match.
+//!
+//! | Handle | Sector size | Why |
+//! |---|---|---|
+//! | database, object layout | [ConnectionDefaults::sector_size], 512 by default | extents and changed ranges are uploaded by PUTs, which are atomic |
+//! | database, blocks layout | the same | blocks are the unit the [offline mirror](crate::mirror) catches up by, not a unit of writes |
+//! | served from the offline mirror | 512 | read-only, never journaled |
+//! | journal | 512 | SQLite only asks the database |
+//!
+//! A larger sector is only honored with `psow=0`, which the connections threeqlite opens pass
+//! then, see [ConnectionDefaults::uri], and [sqlite_vfs] asserts the two agree in debug builds.
+//! It buys nothing on an object store and costs journal space: the journal header grows from 512
+//! bytes to the sector size, and every page sharing a sector with a written page is journaled as
+//! well. [MAX_SECTOR_SIZE] caps it, as the block size of 64 KiB would add 64 KiB to every journal.
+//!
+//! [ConnectionDefaults::sector_size]: crate::config::ConnectionDefaults::sector_size
+//! [ConnectionDefaults::uri]: crate::config::ConnectionDefaults::uri
+
+use sqlite_vfs::capability::DEFAULT_SECTOR_SIZE;
+
+/// The largest sector size advertised, that of most disks and of the default page size.
+pub const MAX_SECTOR_SIZE: u32 = 4096;
+
+/// The sector size database handles advertise when `configured`: a power of two from 512 to
+/// [MAX_SECTOR_SIZE], rounded down.
+pub fn advertised(configured: u32) -> u32 {
+    let size = configured.clamp(DEFAULT_SECTOR_SIZE, MAX_SECTOR_SIZE);
+    1 << size.ilog2()
+}
+
+#[cfg(all(test, feature = "s3"))]
+mod tests {
+    use std::{collections::BTreeSet, sync::Arc};
+
+    use sqlite_vfs::{
+        capability::check_sector_size, DatabaseHandle, OpenAccess, OpenKind, OpenOptions, Vfs,
+    };
+
+    use super::*;
+    use crate::{
+        config::{Config, ConnectionDefaults},
+        journal::JOURNAL_MAGIC,
+        key::KeyLayout,
+        mirror::{BlockManifest, Mirror, MirrorPolicy},
+        mock::{self, MockS3},
+        vfs::ThreeQLite,
+    };
+
+    fn instance(mock: &MockS3, sector_size: u32) -> ThreeQLite {
+        let config = Config {
+            connection: ConnectionDefaults {
+                sector_size,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        ThreeQLite::with_client(config, mock.client()).unwrap()
+    }
+
+    /// The journal SQLite writes without powersafe overwrites for a transaction rewriting `pages`
+    /// of a database of `db_pages` pages: a header padded to a sector, then each page sharing a
+    /// sector with a rewritten one, with its number and checksum.
+    fn rollback_journal(sector_size: u32, page_size: u32, db_pages: u32, pages: &[u32]) -> Vec<u8> {
+        let per_sector = (sector_size / page_size).max(1);
+        let journaled: BTreeSet<u32> = pages
+            .iter()
+            .flat_map(|page| {
+                let first = (page - 1) / per_sector * per_sector + 1;
+                first..(first + per_sector).min(db_pages + 1)
+            })
+            .collect();
+        let mut journal = vec![0; sector_size as usize];
+        journal[..8].copy_from_slice(&JOURNAL_MAGIC);
+        journal[8..12].copy_from_slice(&(journaled.len() as u32).to_be_bytes());
+        journal[16..20].copy_from_slice(&db_pages.to_be_bytes());
+        journal[20..24].copy_from_slice(&sector_size.to_be_bytes());
+        journal[24..28].copy_from_slice(&page_size.to_be_bytes());
+        for page in journaled {
+            journal.extend(page.to_be_bytes());
+            journal.extend(vec![0xa5; page_size as usize]);
+            journal.extend(0u32.to_be_bytes());
+        }
+        journal
+    }
+
+    #[test]
+    fn test_advertised() {
+        assert_eq!(advertised(0), 512);
+        assert_eq!(advertised(512), 512);
+        assert_eq!(advertised(1000), 512);
+        assert_eq!(advertised(2048), 2048);
+        assert_eq!(advertised(4096), 4096);
+        assert_eq!(advertised(65536), MAX_SECTOR_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_handles_per_layout() {
+        // SQLITE_IOCAP_POWERSAFE_OVERWRITE
+        let psow = 0x1000;
+        let data = mock::database(4096, 16, 1);
+        for (configured, expected) in [(512, 512), (4096, 4096), (65536, MAX_SECTOR_SIZE)] {
+            let mock = MockS3::start();
+            mock.put("test.db", data.clone());
+            let tq = instance(&mock, configured);
+            let opts = OpenOptions::new(OpenKind::MainDb, OpenAccess::Write);
+
+            // object layout
+            let db = tq.open("test.db", opts.clone()).await.unwrap();
+            assert_eq!(db.sector_size(), expected);
+            // the flags the connections of the instance advertise agree with it
+            let uri = tq.connection_defaults.uri("test.db");
+            assert_eq!(uri.is_some(), expected > 512);
+            let flags = if uri.is_some() { 0 } else { psow };
+            assert_eq!(check_sector_size(db.sector_size(), flags), Ok(()));
+            assert_eq!(
+                check_sector_size(db.sector_size(), psow).is_ok(),
+                expected == 512
+            );
+            drop(db);
+
+            // blocks layout
+            let key = KeyLayout::db("test.db").unwrap();
+            tq.inner
+                .read()
+                .await
+                .publish_blocks(&key, &BlockManifest::new(&data))
+                .await
+                .unwrap();
+            let db = tq.open("test.db", opts).await.unwrap();
+            assert_eq!(db.sector_size(), expected);
+            drop(db);
+
+            let journal = tq
+                .open(
+                    "test.db-journal",
+                    OpenOptions::new(OpenKind::MainJournal, OpenAccess::Create),
+                )
+                .await
+                .unwrap();
+            assert_eq!(journal.sector_size(), 512);
+
+            let path =
+                std::env::temp_dir().join(format!("threeqlite-sector-{}", uuid::Uuid::new_v4()));
+            let mirror = Mirror::open(key.clone(), &path, MirrorPolicy::default()).unwrap();
+            let offline = crate::handle::Handle::offline(tq.clone(), key, Arc::new(mirror));
+            assert_eq!(offline.sector_size(), 512);
+            drop(offline);
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_journal_sizes() {
+        // a transaction updating a row on page 2, and the change counter on page 1
+        let record = |page_size: u32| page_size + 8;
+        for (configured, page_size, expected) in [
+            (512, 4096, 512 + 2 * record(4096)),
+            (4096, 4096, 4096 + 2 * record(4096)),
+            (65536, 4096, 4096 + 2 * record(4096)),
+            (512, 1024, 512 + 2 * record(1024)),
+            // pages 3 and 4 share the sector of pages 1 and 2
+            (4096, 1024, 4096 + 4 * record(1024)),
+        ] {
+            let mock = MockS3::start();
+            mock.put("test.db", mock::database(page_size, 16, 1));
+            let tq = instance(&mock, configured);
+            let db = tq
+                .open(
+                    "test.db",
+                    OpenOptions::new(OpenKind::MainDb, OpenAccess::Write),
+                )
+                .await
+                .unwrap();
+            let sector_size = db.sector_size();
+
+            let mut journal = tq
+                .open(
+                    "test.db-journal",
+                    OpenOptions::new(OpenKind::MainJournal, OpenAccess::Create),
+                )
+                .await
+                .unwrap();
+            let content = rollback_journal(sector_size, page_size, 16, &[1, 2]);
+            journal.write_all_at(&content, 0).await.unwrap();
+            journal.sync(false).await.unwrap();
+            assert_eq!(
+                mock.get("test.db-journal").unwrap().len() as u32,
+                expected,
+                "sector size {configured}, page size {page_size}"
+            );
+        }
+    }
+}
@@ -0,0 +1,361 @@
+//! The locks the handles of an instance hold on each database.
+//!
+//! SQLite may open a database more than once in a process: from two connections, or twice from
+//! one, when it `ATTACH`es the path of a database it already has open. The remote lock is the
+//! instance's, see [crate::protocol], so the handles of a database share it, just like the file
+//! descriptors of an inode share their POSIX locks in SQLite's unix VFS. [Sessions] keeps the lock
+//! of every handle open on a database, and reduces them to the one lock the instance needs
+//! remotely, see [Change]: the first handle to read registers the reader and the last one to
+//! leave unregisters it, no matter how many aliases read in between. Between the handles of the
+//! instance, a handle can't take the write lock while another holds it, nor the exclusive lock
+//! while another reads, and can't start reading while another is about to write.
+//!
+//! Connections in shared-cache mode share a single handle per database, so only their
+//! attachments alias. The WAL index is mapped per handle by [sqlite_vfs]; WAL mode isn't
+//! supported, see [crate::wal], so no index is ever mapped by two aliases.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+};
+
+use sqlite_vfs::LockKind;
+
+use crate::key::ObjectKey;
+
+fn rank(lock: LockKind) -> u8 {
+    match lock {
+        LockKind::None => 0,
+        LockKind::Shared => 1,
+        LockKind::Reserved => 2,
+        LockKind::Pending => 3,
+        LockKind::Exclusive => 4,
+    }
+}
+
+/// The lock of the instance on a database, given the locks of its handles: the lock of the
+/// writer, if a handle holds [LockKind::Reserved] or more, otherwise [LockKind::Shared] while any
+/// handle reads.
+fn combined<'a>(locks: impl IntoIterator<Item = &'a LockKind>) -> LockKind {
+    locks
+        .into_iter()
+        .copied()
+        .max_by_key(|lock| rank(*lock))
+        .unwrap_or(LockKind::None)
+}
+
+/// How moving the lock of a handle changed the lock of the instance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub before: LockKind,
+    pub after: LockKind,
+    /// The lock of the handle moved as requested. If not, another handle holds a conflicting lock,
+    /// and the handle is left where it was, or at [LockKind::Pending] if it asked for
+    /// [LockKind::Exclusive] while others read.
+    pub granted: bool,
+}
+
+/// The handles open on each database of an instance and their locks, see the
+/// [module documentation](self).
+#[derive(Debug, Default)]
+pub struct Sessions {
+    databases: Mutex<HashMap<ObjectKey, BTreeMap<u64, LockKind>>>,
+    next: AtomicU64,
+}
+
+impl Sessions {
+    /// Add a handle of `db`, holding no lock, until the returned [Participant] is dropped.
+    pub fn join(self: &Arc<Self>, db: &ObjectKey) -> Participant {
+        let id = self.next.fetch_add(1, Relaxed);
+        let mut databases = self.databases.lock().unwrap();
+        databases
+            .entry(db.clone())
+            .or_default()
+            .insert(id, LockKind::None);
+        Participant {
+            sessions: self.clone(),
+            db: db.clone(),
+            id,
+        }
+    }
+
+    /// The databases with handles open.
+    pub fn len(&self) -> usize {
+        self.databases.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The handles open on `db`.
+    pub fn participants(&self, db: &ObjectKey) -> usize {
+        let databases = self.databases.lock().unwrap();
+        databases.get(db).map_or(0, |handles| handles.len())
+    }
+
+    /// The lock of the instance on `db`, see [Change].
+    pub fn held(&self, db: &ObjectKey) -> LockKind {
+        let databases = self.databases.lock().unwrap();
+        databases
+            .get(db)
+            .map_or(LockKind::None, |handles| combined(handles.values()))
+    }
+}
+
+/// A handle open on a database, see [Sessions::join].
+#[derive(Debug)]
+pub struct Participant {
+    sessions: Arc<Sessions>,
+    db: ObjectKey,
+    id: u64,
+}
+
+impl Participant {
+    /// The lock this handle holds.
+    pub fn lock_kind(&self) -> LockKind {
+        let databases = self.sessions.databases.lock().unwrap();
+        databases[&self.db][&self.id]
+    }
+
+    /// The lock of the other handles of the database, see [Change].
+    pub fn others(&self) -> LockKind {
+        let databases = self.sessions.databases.lock().unwrap();
+        combined(
+            databases[&self.db]
+                .iter()
+                .filter(|(id, _)| **id != self.id)
+                .map(|(_, lock)| lock),
+        )
+    }
+
+    /// Move the lock of this handle to `lock`, up or down, unless the other handles of the
+    /// database hold a conflicting lock. The caller takes or releases the remote lock as
+    /// [Change] says, and moves this handle back if that fails.
+    pub fn lock(&self, lock: LockKind) -> Change {
+        let mut databases = self.sessions.databases.lock().unwrap();
+        let handles = databases.get_mut(&self.db).unwrap();
+        let before = combined(handles.values());
+        let others = combined(
+            handles
+                .iter()
+                .filter(|(id, _)| **id != self.id)
+                .map(|(_, lock)| lock),
+        );
+        let current = handles[&self.id];
+        let (to, granted) = match lock {
+            // dropping a lock never conflicts
+            _ if rank(lock) <= rank(current) => (lock, true),
+            // a writer about to commit waits for the readers, so no new ones start
+            LockKind::Shared => match rank(others) >= rank(LockKind::Pending) {
+                true => (current, false),
+                false => (lock, true),
+            },
+            _ if rank(others) >= rank(LockKind::Reserved) => (current, false),
+            LockKind::Exclusive if others == LockKind::Shared => (LockKind::Pending, false),
+            _ => (lock, true),
+        };
+        handles.insert(self.id, to);
+        Change {
+            before,
+            after: combined(handles.values()),
+            granted,
+        }
+    }
+}
+
+impl Drop for Participant {
+    fn drop(&mut self) {
+        let mut databases = self.sessions.databases.lock().unwrap();
+        let Some(handles) = databases.get_mut(&self.db) else {
+            return;
+        };
+        handles.remove(&self.id);
+        if handles.is_empty() {
+            databases.remove(&self.db);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::KeyLayout;
+
+    fn change(before: LockKind, after: LockKind, granted: bool) -> Change {
+        Change {
+            before,
+            after,
+            granted,
+        }
+    }
+
+    #[test]
+    fn test_aliases_share_the_lock() {
+        use LockKind::*;
+
+        let sessions = Arc::new(Sessions::default());
+        let db = KeyLayout::db("test.db").unwrap();
+        let main = sessions.join(&db);
+        let attached = sessions.join(&db);
+        let other = sessions.join(&KeyLayout::db("other.db").unwrap());
+        assert_eq!((sessions.len(), sessions.participants(&db)), (2, 2));
+
+        // only the first reader registers
+        assert_eq!(main.lock(Shared), change(None, Shared, true));
+        assert_eq!(attached.lock(Shared), change(Shared, Shared, true));
+        // taken again by the same handle, nothing is counted twice
+        assert_eq!(attached.lock(Shared), change(Shared, Shared, true));
+        assert_eq!(main.lock(None), change(Shared, Shared, true));
+        assert_eq!(main.lock(Shared), change(Shared, Shared, true));
+
+        // while one alias writes, the other can't
+        assert_eq!(main.lock(Reserved), change(Shared, Reserved, true));
+        assert_eq!(attached.lock(Reserved), change(Reserved, Reserved, false));
+        assert_eq!(attached.lock_kind(), Shared);
+        assert_eq!((attached.others(), main.others()), (Reserved, Shared));
+        assert_eq!(sessions.held(&db), Reserved);
+        // committing waits for the other alias to stop reading
+        assert_eq!(main.lock(Exclusive), change(Reserved, Pending, false));
+        assert_eq!(main.lock_kind(), Pending);
+        let reader = sessions.join(&db);
+        assert_eq!(reader.lock(Shared), change(Pending, Pending, false));
+        drop(reader);
+        assert_eq!(attached.lock(None), change(Pending, Pending, true));
+        assert_eq!(main.lock(Exclusive), change(Pending, Exclusive, true));
+        assert_eq!(main.lock(Shared), change(Exclusive, Shared, true));
+        assert_eq!(other.lock_kind(), None);
+
+        // closing one alias leaves the lock of the other
+        assert_eq!(attached.lock(Shared), change(Shared, Shared, true));
+        drop(main);
+        assert_eq!(sessions.held(&db), Shared);
+        assert_eq!(attached.lock(None), change(Shared, None, true));
+        drop(attached);
+        drop(other);
+        assert!(sessions.is_empty());
+    }
+}
+
+#[cfg(all(test, feature = "rusqlite"))]
+mod sqlite_tests {
+    use std::time::{Duration, Instant};
+
+    use rusqlite::{Connection, ErrorCode, OpenFlags};
+
+    use crate::{
+        blocking::BlockingClient,
+        config::Config,
+        mock::MockS3,
+        vfs::{Metadata, ThreeQLite},
+    };
+
+    fn client(mock: &MockS3, name: &str) -> BlockingClient {
+        let client = BlockingClient::with_client(Config::default(), mock.client()).unwrap();
+        client.register(name, false).unwrap();
+        client
+    }
+
+    /// The readers registered in the metadata object, `None` while a writer holds it.
+    fn readers(tq: &ThreeQLite) -> Option<usize> {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let record = runtime
+            .block_on(async { tq.inner.read().await.read_metadata_record().await })
+            .unwrap();
+        match record.metadata {
+            Metadata::None => Some(0),
+            Metadata::Reader(reader) => Some(reader.readers.len()),
+            Metadata::Writer(_) => None,
+        }
+    }
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_attached_alias() {
+        let mock = MockS3::start();
+        let client = client(&mock, "session-attach");
+        let conn = client.connect("test.db").unwrap();
+        conn.execute_batch("CREATE TABLE t (x); ATTACH 'test.db' AS alias;")
+            .unwrap();
+        assert_eq!(client.instance().sessions.participants(&db()), 2);
+
+        conn.execute("INSERT INTO main.t VALUES (1)", []).unwrap();
+        assert_eq!(count(&conn, "alias.t"), 1);
+
+        // both aliases read under the one registration of the instance
+        conn.execute_batch("BEGIN").unwrap();
+        assert_eq!(count(&conn, "main.t") + count(&conn, "alias.t"), 2);
+        assert_eq!(readers(client.instance()), Some(1));
+        conn.execute_batch("COMMIT").unwrap();
+        assert_eq!(readers(client.instance()), Some(0));
+
+        // committing waits for the alias to stop reading, as it would for a file
+        conn.execute_batch("BEGIN; SELECT * FROM alias.t; INSERT INTO main.t VALUES (2);")
+            .unwrap();
+        let err = conn.execute_batch("COMMIT").unwrap_err();
+        assert_eq!(err.sqlite_error_code(), Some(ErrorCode::DatabaseBusy));
+        conn.execute_batch("ROLLBACK").unwrap();
+        assert_eq!(count(&conn, "t"), 1);
+        assert_eq!(readers(client.instance()), Some(0));
+    }
+
+    #[test]
+    fn test_shared_cache() {
+        let mock = MockS3::start();
+        let client = client(&mock, "session-shared-cache");
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_SHARED_CACHE;
+        let first = client.connect_with_flags("test.db", flags).unwrap();
+        let second = client.connect_with_flags("test.db", flags).unwrap();
+        // a single handle for both
+        assert_eq!(client.instance().sessions.participants(&db()), 1);
+
+        first.execute_batch("CREATE TABLE t (x)").unwrap();
+        second.execute("INSERT INTO t VALUES (1)", []).unwrap();
+        first.execute("INSERT INTO t VALUES (2)", []).unwrap();
+        assert_eq!(count(&second, "t"), 2);
+        drop((first, second));
+        assert!(client.instance().sessions.is_empty());
+        assert_eq!(readers(client.instance()), Some(0));
+    }
+
+    /// A connection of another instance gets `SQLITE_BUSY` while the lock is held, once its busy
+    /// handler gave up, rather than waiting for it.
+    #[test]
+    fn test_busy_across_instances() {
+        let mock = MockS3::start();
+        let writer = client(&mock, "session-busy-writer");
+        let other = client(&mock, "session-busy-other");
+        let conn = writer.connect("test.db").unwrap();
+        conn.execute_batch("CREATE TABLE t (x)").unwrap();
+        // opening reads the header without a busy handler yet, so it would wait for the writer
+        let waiting = other.connect("test.db").unwrap();
+        waiting.busy_timeout(Duration::ZERO).unwrap();
+
+        conn.execute_batch("BEGIN IMMEDIATE; INSERT INTO t VALUES (1);")
+            .unwrap();
+        assert_eq!(readers(writer.instance()), None);
+        let start = Instant::now();
+        let err = waiting.execute("INSERT INTO t VALUES (2)", []).unwrap_err();
+        assert_eq!(err.sqlite_error_code(), Some(ErrorCode::DatabaseBusy));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        conn.execute_batch("COMMIT").unwrap();
+        waiting.execute("INSERT INTO t VALUES (2)", []).unwrap();
+        assert_eq!(count(&conn, "t"), 2);
+    }
+
+    fn db() -> crate::key::ObjectKey {
+        crate::key::KeyLayout::db("test.db").unwrap()
+    }
+}
@@ -0,0 +1,489 @@
+//! Shutting down.
+//!
+//! SQLite calls into the VFS of an instance for as long as connections opened through it are
+//! open, so an instance is torn down in this order, which [teardown] follows:
+//!
+//! 1. Stop accepting: opening a database fails with [Error::ShuttingDown], and opening an
+//!    [AsyncConnection] with [Error::ConnectionClosed]. Journals still open, so that transactions
+//!    running can commit.
+//! 2. Close the connections of [crate::asyncdb], and with them those of [crate::pool]. Statements
+//!    queued run first, so a transaction running commits or rolls back.
+//! 3. Wait for the handles still open, such as those of connections the application opened on
+//!    its own. Closing a connection flushes what it committed.
+//! 4. Wait for the imports of warm sets, see [crate::warm].
+//! 5. Unregister the VFS and free its state, see [VfsHandle::unregister].
+//!
+//! Steps 1 to 4 are [ThreeQLite::shutdown], which waits at most [ShutdownConfig::deadline] for
+//! the connections and handles and then fails with [Error::HandlesOpen], naming the handles still
+//! open. A connection in the middle of a transaction holds it up until the transaction ends and
+//! the connection closes. Opens stay refused after a failed shutdown.
+//!
+//! The other orders are refused or made safe:
+//!
+//! - Unregistering while handles opened through the VFS are open fails with [Error::HandlesOpen],
+//!   see [VfsGuard::unregister]. Dropping the guard then logs an error and keeps the VFS
+//!   registered.
+//! - Dropping the last clone of an instance the application has while it is registered logs an
+//!   error. SQLite keeps a clone of its own, so connections through the VFS keep working.
+//! - SQLite never loses the state it calls into: unregistering frees it only once the files of
+//!   the connections still open are closed.
+//!
+//! [AsyncConnection]: crate::asyncdb::AsyncConnection
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use snafu::ResultExt;
+use sqlite_vfs::{RegisterError, VfsHandle};
+use tokio::sync::watch;
+
+use crate::{
+    error::{Error, UnregisterSnafu},
+    registration::{Registration, RegistrationConfig},
+    vfs::ThreeQLite,
+};
+
+/// Settings of [ThreeQLite::shutdown].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShutdownConfig {
+    /// How long to wait for connections and handles to close.
+    pub deadline: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            deadline: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A handle open on an instance.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpenHandle {
+    /// The key of the database or journal.
+    pub db: String,
+    /// The registration it was opened through, `None` if opened on the instance directly.
+    pub vfs: Option<String>,
+    pub readonly: bool,
+}
+
+impl Display for OpenHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.db)?;
+        if let Some(vfs) = &self.vfs {
+            write!(f, " through {vfs}")?;
+        }
+        if self.readonly {
+            f.write_str(" (read-only)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The handles open on an instance, and whether it is shutting down.
+#[derive(Debug)]
+pub struct Handles {
+    open: watch::Sender<BTreeMap<u64, OpenHandle>>,
+    next: AtomicU64,
+    closing: AtomicBool,
+}
+
+impl Default for Handles {
+    fn default() -> Self {
+        Self {
+            open: watch::Sender::new(BTreeMap::new()),
+            next: AtomicU64::new(0),
+            closing: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Handles {
+    /// List `handle` as open until the returned [Listed] is dropped.
+    pub fn list(self: &Arc<Self>, handle: OpenHandle) -> Listed {
+        let id = self.next.fetch_add(1, Relaxed);
+        self.open.send_modify(|open| {
+            open.insert(id, handle);
+        });
+        Listed {
+            handles: self.clone(),
+            id,
+        }
+    }
+
+    /// The handles open, through the registration `vfs` if given.
+    pub fn open(&self, vfs: Option<&str>) -> Vec<OpenHandle> {
+        let open = self.open.borrow();
+        let through = |handle: &&OpenHandle| vfs.is_none() || handle.vfs.as_deref() == vfs;
+        open.values().filter(through).cloned().collect()
+    }
+
+    /// Whether the instance stopped accepting opens.
+    pub fn closing(&self) -> bool {
+        self.closing.load(Relaxed)
+    }
+
+    /// Resolves once no handle is open.
+    async fn closed(&self) {
+        let mut open = self.open.subscribe();
+        let _ = open.wait_for(BTreeMap::is_empty).await;
+    }
+}
+
+/// Lists a handle as open until dropped, see [Handles::list].
+#[derive(Debug)]
+pub struct Listed {
+    handles: Arc<Handles>,
+    id: u64,
+}
+
+impl Drop for Listed {
+    fn drop(&mut self) {
+        self.handles.open.send_modify(|open| {
+            open.remove(&self.id);
+        });
+    }
+}
+
+/// Held by the clones of an instance the application has, as opposed to the one SQLite has, to
+/// tell when the application dropped the instance while it is registered.
+pub struct Owner {
+    registrations: Arc<Mutex<HashMap<String, Arc<Registration>>>>,
+}
+
+impl Owner {
+    pub fn new(registrations: Arc<Mutex<HashMap<String, Arc<Registration>>>>) -> Self {
+        Self { registrations }
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        let registrations = self.registrations.lock().unwrap();
+        if registrations.is_empty() {
+            return;
+        }
+        let names: Vec<_> = registrations.keys().collect();
+        tracing::error!(
+            target: "threeqlite::s3",
+            ?names,
+            "instance dropped while registered with SQLite, which keeps it alive for the \
+             connections through it; shut it down and unregister it first, see threeqlite::shutdown"
+        );
+    }
+}
+
+/// Unregisters the VFS it was returned for by [ThreeQLite::register_guarded] when dropped, unless
+/// handles opened through it are open, see the [module documentation](self).
+#[must_use = "dropping the guard unregisters the VFS"]
+pub struct VfsGuard {
+    tq: ThreeQLite,
+    name: String,
+    /// `None` once unregistered.
+    handle: Option<VfsHandle>,
+}
+
+impl VfsGuard {
+    /// The name the VFS is registered as.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Unregister the VFS. Fails with [Error::HandlesOpen] while handles opened through it are
+    /// open.
+    pub fn unregister(&mut self) -> Result<(), Error> {
+        if self.handle.is_none() {
+            return Ok(());
+        }
+        let open = self.tq.handles.open(Some(&self.name));
+        if !open.is_empty() {
+            return Err(Error::HandlesOpen {
+                handles: open.iter().map(ToString::to_string).collect(),
+            });
+        }
+        let handle = self.handle.take().unwrap();
+        handle
+            .unregister()
+            .context(UnregisterSnafu { name: &self.name })?;
+        self.tq.registrations.lock().unwrap().remove(&self.name);
+        tracing::info!(target: "threeqlite::s3", name = self.name, "unregistered");
+        Ok(())
+    }
+}
+
+impl Drop for VfsGuard {
+    fn drop(&mut self) {
+        if let Err(err) = self.unregister() {
+            if let Some(handle) = self.handle.take() {
+                handle.leak();
+            }
+            tracing::error!(
+                target: "threeqlite::s3",
+                name = self.name,
+                %err,
+                "dropped the guard of a VFS that can't be unregistered, keeping it registered"
+            );
+        }
+    }
+}
+
+impl ThreeQLite {
+    /// Register this instance with SQLite as the VFS `name` until the returned guard is dropped,
+    /// see [ThreeQLite::register].
+    pub fn register_guarded(
+        &self,
+        name: &str,
+        as_default: bool,
+    ) -> Result<VfsGuard, RegisterError> {
+        let handle = self.register_handle(RegistrationConfig::new(name), as_default)?;
+        Ok(VfsGuard {
+            tq: self.clone(),
+            name: name.to_owned(),
+            handle: Some(handle),
+        })
+    }
+
+    /// Stop accepting opens, close all connections opened through
+    /// [crate::asyncdb::AsyncConnection] and wait for the handles still open and the imports of
+    /// warm sets, see the [module documentation](self). Fails with [Error::HandlesOpen] if
+    /// handles are still open after [ShutdownConfig::deadline].
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        self.shutdown_within(self.shutdown_config.deadline).await
+    }
+
+    /// [ThreeQLite::shutdown], waiting at most `deadline`.
+    pub async fn shutdown_within(&self, deadline: Duration) -> Result<(), Error> {
+        self.handles.closing.store(true, Relaxed);
+        let closed = tokio::time::timeout(deadline, async {
+            #[cfg(feature = "asyncdb")]
+            self.workers.shutdown().await;
+            self.handles.closed().await;
+            self.ready().await;
+        })
+        .await;
+        if closed.is_ok() {
+            return Ok(());
+        }
+
+        let open = self.handles.open(None);
+        if open.is_empty() {
+            tracing::warn!(target: "threeqlite::s3", ?deadline, "imports of warm sets still running");
+            return Ok(());
+        }
+        let handles: Vec<_> = open.iter().map(ToString::to_string).collect();
+        tracing::warn!(target: "threeqlite::s3", ?deadline, ?handles, "handles still open");
+        Err(Error::HandlesOpen { handles })
+    }
+}
+
+/// Shut `tq` down and then unregister the VFS of `guard`, waiting at most `deadline` for its
+/// connections and handles to close, see the [module documentation](self). The VFS stays
+/// registered if handles are still open then.
+pub async fn teardown(
+    mut guard: VfsGuard,
+    tq: ThreeQLite,
+    deadline: Duration,
+) -> Result<(), Error> {
+    tq.shutdown_within(deadline).await?;
+    guard.unregister()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use sqlite_vfs::{DatabaseHandle, LockKind, OpenAccess, OpenKind, OpenOptions, Vfs};
+
+    use super::*;
+    use crate::{
+        cache::CacheUse,
+        config::Config,
+        handle::Handle,
+        mock::{self, MockS3},
+    };
+
+    fn instance(mock: &MockS3) -> ThreeQLite {
+        mock.put("test.db", mock::database(4096, 2, 1));
+        ThreeQLite::with_client(Config::default(), mock.client()).unwrap()
+    }
+
+    async fn open(tq: &ThreeQLite, access: OpenAccess) -> Result<Handle, Error> {
+        let opts = OpenOptions::new(OpenKind::MainDb, access);
+        match Box::pin(tq.open("test.db", opts)).await {
+            Ok(handle) => Ok(handle),
+            Err(sqlite_vfs::error::Error::External { cause }) => Err(cause),
+            Err(err) => panic!("{err:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_transaction() {
+        let mock = MockS3::start();
+        let tq = instance(&mock);
+        let mut handle = open(&tq, OpenAccess::Write).await.unwrap();
+        assert!(handle.lock(LockKind::Exclusive).await.unwrap());
+        handle.write_all_at(&[1; 4096], 4096).await.unwrap();
+
+        let start = Instant::now();
+        let connection = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            handle.sync(false).await.unwrap();
+            drop(handle);
+        });
+        tq.shutdown_within(Duration::from_secs(10)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        connection.await.unwrap();
+
+        // committed before the shutdown returned
+        let page = tq
+            .inner
+            .read()
+            .await
+            .read_at(4096, 4096, None, CacheUse::Admit)
+            .await
+            .unwrap();
+        assert_eq!(page, [1; 4096]);
+        assert!(matches!(
+            open(&tq, OpenAccess::Read).await,
+            Err(Error::ShuttingDown)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_deadline() {
+        let mock = MockS3::start();
+        let tq = instance(&mock);
+        let handle = open(&tq, OpenAccess::Read).await.unwrap();
+
+        let err = tq
+            .shutdown_within(Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        let Error::HandlesOpen { handles } = &err else {
+            panic!("{err}");
+        };
+        assert_eq!(handles, &["test.db (read-only)"]);
+        assert!(matches!(
+            open(&tq, OpenAccess::Read).await,
+            Err(Error::ShuttingDown)
+        ));
+
+        drop(handle);
+        tq.shutdown_within(Duration::from_millis(100))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unregister_with_open_handles() {
+        let mock = MockS3::start();
+        let tq = instance(&mock);
+        let mut guard = tq.register_guarded("shutdown-unregister", false).unwrap();
+        let through = tq.registered(RegistrationConfig::new("shutdown-unregister"));
+        let handle = open(&through, OpenAccess::Read).await.unwrap();
+        // handles opened on the instance itself don't hold up the registration
+        let direct = open(&tq, OpenAccess::Read).await.unwrap();
+
+        let err = guard.unregister().unwrap_err();
+        assert!(
+            matches!(&err, Error::HandlesOpen { handles } if handles == &["test.db through shutdown-unregister (read-only)"]),
+            "{err}"
+        );
+        assert!(sqlite_vfs::vfs_stats("shutdown-unregister").is_some());
+
+        drop((handle, direct));
+        guard.unregister().unwrap();
+        assert!(sqlite_vfs::vfs_stats("shutdown-unregister").is_none());
+        assert!(tq.registrations.lock().unwrap().is_empty());
+        guard.unregister().unwrap();
+
+        // dropping the guard while handles are open keeps it registered
+        let guard = tq.register_guarded("shutdown-unregister", false).unwrap();
+        let through = tq.registered(RegistrationConfig::new("shutdown-unregister"));
+        let handle = open(&through, OpenAccess::Read).await.unwrap();
+        drop(guard);
+        assert!(sqlite_vfs::vfs_stats("shutdown-unregister").is_some());
+        assert!(tq
+            .registrations
+            .lock()
+            .unwrap()
+            .contains_key("shutdown-unregister"));
+        drop(handle);
+        assert!(sqlite_vfs::unregister("shutdown-unregister").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_while_registered() {
+        let mock = MockS3::start();
+        let tq = instance(&mock);
+        tq.register("shutdown-dropped", false).unwrap();
+        let inner = Arc::downgrade(&tq.inner);
+        drop(tq);
+        // SQLite's clone keeps the instance alive
+        assert!(inner.upgrade().is_some());
+        assert!(sqlite_vfs::vfs_stats("shutdown-dropped").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_teardown() {
+        let mock = MockS3::start();
+        let tq = instance(&mock);
+        let guard = tq.register_guarded("shutdown-teardown", false).unwrap();
+        let through = tq.registered(RegistrationConfig::new("shutdown-teardown"));
+        let handle = open(&through, OpenAccess::Read).await.unwrap();
+        let closing = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(handle);
+        });
+
+        teardown(guard, tq.clone(), Duration::from_secs(10))
+            .await
+            .unwrap();
+        closing.await.unwrap();
+        assert!(sqlite_vfs::vfs_stats("shutdown-teardown").is_none());
+        assert!(tq.handles.open(None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_teardown_past_deadline() {
+        let mock = MockS3::start();
+        let tq = instance(&mock);
+        let guard = tq.register_guarded("shutdown-stuck", false).unwrap();
+        let through = tq.registered(RegistrationConfig::new("shutdown-stuck"));
+        let handle = open(&through, OpenAccess::Read).await.unwrap();
+
+        let err = teardown(guard, tq.clone(), Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::HandlesOpen { .. }), "{err}");
+        // SQLite can still call into the VFS of the handle
+        assert!(sqlite_vfs::vfs_stats("shutdown-stuck").is_some());
+        drop(handle);
+    }
+
+    #[cfg(feature = "asyncdb")]
+    #[tokio::test]
+    async fn test_shutdown_closes_async_connections() {
+        use crate::asyncdb::AsyncConnection;
+
+        let mock = MockS3::start();
+        let tq = instance(&mock);
+        let conn = AsyncConnection::spawn(&tq.workers, rusqlite::Connection::open_in_memory)
+            .await
+            .unwrap();
+        tq.shutdown_within(Duration::from_secs(10)).await.unwrap();
+        assert!(conn.is_closed());
+        assert!(matches!(
+            AsyncConnection::spawn(&tq.workers, rusqlite::Connection::open_in_memory).await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+}
@@ -0,0 +1,711 @@
+//! Caps on what a session may spend on the object store.
+//!
+//! An exploratory query can cost far more than its author meant, e.g. a cross join reading every
+//! page of a large database over and over. A [SpendBudget] caps the requests and bytes of each
+//! [OpClass], as soft and hard limits per [Dimension]:
+//!
+//! | Dimension | Charged with |
+//! |---|---|
+//! | `get_requests` | every request of the read class: GETs, HEADs and LISTs |
+//! | `get_bytes` | the pages and journals received |
+//! | `put_requests` | every request of the write class: PUTs and DELETEs, every upload of a flush |
+//! | `put_bytes` | the pages and journals uploaded |
+//!
+//! Every charge goes to the [Spend] of the instance, see [Config::spend], and to that of the
+//! connection running the request, see [scope]. A connection gets one from
+//! [ConnectionDefaults::budget] or from `PRAGMA threeqlite_budget='soft_get_requests=800
+//! get_requests=1000'`, which replaces its limits but keeps what it spent. Both apply, so the
+//! stricter one wins. Journal handles and truncations charge the instance only.
+//!
+//! Crossing a soft limit warns once per dimension under the target `threeqlite::budget` and
+//! reports a [BudgetWarning] to [TransactionObserver::on_budget_warning]. Once a hard limit is
+//! reached, reading pages from the object store and writing to it fail with
+//! [Error::BudgetExhausted], which SQLite sees as `SQLITE_IOERR`. The operation reaching the limit
+//! completes, so the spend exceeds it by at most one operation. The lock protocol keeps running,
+//! counted but never refused, so that transactions end cleanly and pages in the cache stay readable
+//! at the current generation: a cache hit costs nothing but the requests validating it.
+//!
+//! `PRAGMA threeqlite_budget` reports the spend of the connection and the instance, as does
+//! `PRAGMA threeqlite_stats`. `PRAGMA threeqlite_budget=reset` starts the connection over,
+//! [ThreeQLite::reset_budget] the instance.
+//!
+//! [Config::spend]: crate::config::Config::spend
+//! [ConnectionDefaults::budget]: crate::config::ConnectionDefaults::budget
+//! [TransactionObserver::on_budget_warning]: crate::latency::TransactionObserver::on_budget_warning
+//! [ThreeQLite::reset_budget]: crate::vfs::ThreeQLite::reset_budget
+
+use std::{
+    fmt,
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+};
+
+use crate::{circuit::OpClass, error::Error, latency::Transactions, stats::Stats};
+
+tokio::task_local! {
+    /// The spend of the connection running the current task, see [scope].
+    static CONNECTION: Option<Arc<Spend>>;
+}
+
+/// What a [SpendBudget] limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Dimension {
+    GetRequests,
+    GetBytes,
+    PutRequests,
+    PutBytes,
+}
+
+impl Dimension {
+    pub const ALL: [Dimension; 4] = [
+        Dimension::GetRequests,
+        Dimension::GetBytes,
+        Dimension::PutRequests,
+        Dimension::PutBytes,
+    ];
+
+    pub fn requests(class: OpClass) -> Self {
+        match class {
+            OpClass::Read => Dimension::GetRequests,
+            OpClass::Write => Dimension::PutRequests,
+        }
+    }
+
+    pub fn bytes(class: OpClass) -> Self {
+        match class {
+            OpClass::Read => Dimension::GetBytes,
+            OpClass::Write => Dimension::PutBytes,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Dimension::GetRequests => "get_requests",
+            Dimension::GetBytes => "get_bytes",
+            Dimension::PutRequests => "put_requests",
+            Dimension::PutBytes => "put_bytes",
+        }
+    }
+
+    fn class(self) -> OpClass {
+        match self {
+            Dimension::GetRequests | Dimension::GetBytes => OpClass::Read,
+            Dimension::PutRequests | Dimension::PutBytes => OpClass::Write,
+        }
+    }
+}
+
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A limit per [Dimension]. `None` doesn't limit it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpendLimits {
+    pub get_requests: Option<u64>,
+    pub get_bytes: Option<u64>,
+    pub put_requests: Option<u64>,
+    pub put_bytes: Option<u64>,
+}
+
+impl SpendLimits {
+    pub fn get(&self, dimension: Dimension) -> Option<u64> {
+        *self.field(dimension)
+    }
+
+    fn field(&self, dimension: Dimension) -> &Option<u64> {
+        match dimension {
+            Dimension::GetRequests => &self.get_requests,
+            Dimension::GetBytes => &self.get_bytes,
+            Dimension::PutRequests => &self.put_requests,
+            Dimension::PutBytes => &self.put_bytes,
+        }
+    }
+
+    fn field_mut(&mut self, dimension: Dimension) -> &mut Option<u64> {
+        match dimension {
+            Dimension::GetRequests => &mut self.get_requests,
+            Dimension::GetBytes => &mut self.get_bytes,
+            Dimension::PutRequests => &mut self.put_requests,
+            Dimension::PutBytes => &mut self.put_bytes,
+        }
+    }
+}
+
+/// The limits of a session, parsed from and displayed as `soft_<dimension>=<n> <dimension>=<n>`
+/// for the soft and hard limits, or `none`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpendBudget {
+    /// Warn once the spend crosses these.
+    pub soft: SpendLimits,
+    /// Refuse to read pages from or write to the object store once the spend reaches these.
+    pub hard: SpendLimits,
+}
+
+impl SpendBudget {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for SpendBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_unlimited() {
+            return f.write_str("none");
+        }
+        let soft = Dimension::ALL
+            .iter()
+            .filter_map(|d| Some(format!("soft_{d}={}", self.soft.get(*d)?)));
+        let hard = Dimension::ALL
+            .iter()
+            .filter_map(|d| Some(format!("{d}={}", self.hard.get(*d)?)));
+        f.write_str(&soft.chain(hard).collect::<Vec<_>>().join(" "))
+    }
+}
+
+impl FromStr for SpendBudget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut budget = Self::default();
+        if s.trim() == "none" {
+            return Ok(budget);
+        }
+        for field in s.split(|c: char| c == ',' || c.is_whitespace()) {
+            if field.is_empty() {
+                continue;
+            }
+            let invalid = || format!("invalid budget field {field:?}");
+            let (name, value) = field.split_once('=').ok_or_else(invalid)?;
+            let (limits, name) = match name.strip_prefix("soft_") {
+                Some(name) => (&mut budget.soft, name),
+                None => (&mut budget.hard, name),
+            };
+            let dimension = Dimension::ALL
+                .into_iter()
+                .find(|d| d.name() == name)
+                .ok_or_else(invalid)?;
+            *limits.field_mut(dimension) = Some(value.parse().map_err(|_| invalid())?);
+        }
+        Ok(budget)
+    }
+}
+
+/// Whose [Spend] crossed a limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpendScope {
+    Instance,
+    Connection,
+}
+
+impl SpendScope {
+    pub fn name(self) -> &'static str {
+        match self {
+            SpendScope::Instance => "instance",
+            SpendScope::Connection => "connection",
+        }
+    }
+}
+
+impl fmt::Display for SpendScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A soft limit crossed, reported to [TransactionObserver::on_budget_warning].
+///
+/// [TransactionObserver::on_budget_warning]: crate::latency::TransactionObserver::on_budget_warning
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BudgetWarning {
+    pub scope: SpendScope,
+    pub dimension: Dimension,
+    pub spent: u64,
+    pub soft: u64,
+    pub hard: Option<u64>,
+}
+
+/// What an instance or a connection spent against its [SpendBudget].
+#[derive(Debug, Default)]
+pub struct Spend {
+    budget: Mutex<SpendBudget>,
+    spent: [AtomicU64; Dimension::ALL.len()],
+    warned: [AtomicBool; Dimension::ALL.len()],
+}
+
+impl Spend {
+    pub fn new(budget: SpendBudget) -> Self {
+        Self {
+            budget: Mutex::new(budget),
+            ..Self::default()
+        }
+    }
+
+    pub fn budget(&self) -> SpendBudget {
+        *self.budget.lock().unwrap()
+    }
+
+    /// Replace the limits, keeping what was spent. Soft limits crossed already warn again.
+    pub fn set_budget(&self, budget: SpendBudget) {
+        *self.budget.lock().unwrap() = budget;
+        self.warned
+            .iter()
+            .for_each(|warned| warned.store(false, Relaxed));
+    }
+
+    pub fn spent(&self, dimension: Dimension) -> u64 {
+        self.spent[dimension as usize].load(Relaxed)
+    }
+
+    /// Start over from nothing spent.
+    pub fn reset(&self) {
+        self.spent.iter().for_each(|spent| spent.store(0, Relaxed));
+        self.warned
+            .iter()
+            .for_each(|warned| warned.store(false, Relaxed));
+    }
+
+    /// Fail once a hard limit of `class` is reached.
+    fn check(&self, scope: SpendScope, class: OpClass) -> Result<(), Error> {
+        let budget = self.budget();
+        for dimension in Dimension::ALL.into_iter().filter(|d| d.class() == class) {
+            let spent = self.spent(dimension);
+            match budget.hard.get(dimension) {
+                Some(limit) if spent >= limit => {
+                    return Err(Error::BudgetExhausted {
+                        scope: scope.name(),
+                        dimension: dimension.name(),
+                        limit,
+                        spent,
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `amount` to `dimension`, returning the warning if that crossed its soft limit first.
+    fn charge(
+        &self,
+        scope: SpendScope,
+        dimension: Dimension,
+        amount: u64,
+    ) -> Option<BudgetWarning> {
+        let spent = self.spent[dimension as usize].fetch_add(amount, Relaxed) + amount;
+        let budget = self.budget();
+        let soft = budget.soft.get(dimension).filter(|soft| spent > *soft)?;
+        if self.warned[dimension as usize].swap(true, Relaxed) {
+            return None;
+        }
+        Some(BudgetWarning {
+            scope,
+            dimension,
+            spent,
+            soft,
+            hard: budget.hard.get(dimension),
+        })
+    }
+}
+
+impl fmt::Display for Spend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let budget = self.budget();
+        let dimensions = Dimension::ALL.iter().map(|d| match budget.hard.get(*d) {
+            Some(limit) => format!("{d}={}/{limit}", self.spent(*d)),
+            None => format!("{d}={}", self.spent(*d)),
+        });
+        f.write_str(&dimensions.collect::<Vec<_>>().join(" "))
+    }
+}
+
+/// Run `fut` on behalf of a connection spending against `spend`. `fut` is boxed like the one of
+/// [crate::busy::with_handler].
+pub async fn scope<F: Future>(spend: Option<Arc<Spend>>, fut: F) -> F::Output {
+    CONNECTION.scope(spend, Box::pin(fut)).await
+}
+
+/// The spend of the connection running the current task, if any.
+pub fn connection() -> Option<Arc<Spend>> {
+    CONNECTION.try_with(Clone::clone).ok().flatten()
+}
+
+/// The budgets requests are charged to, captured so that requests sent by tasks of their own,
+/// e.g. the uploads of a flush, are charged to the connection they are sent for.
+#[derive(Clone)]
+pub struct Payer {
+    pub instance: Arc<Spend>,
+    pub connection: Option<Arc<Spend>>,
+    pub stats: Arc<Stats>,
+    pub transactions: Arc<Transactions>,
+}
+
+impl Payer {
+    fn spends(&self) -> impl Iterator<Item = (SpendScope, &Spend)> {
+        let connection = self.connection.as_deref();
+        let connection = connection.map(|spend| (SpendScope::Connection, spend));
+        connection
+            .into_iter()
+            .chain([(SpendScope::Instance, &*self.instance)])
+    }
+
+    /// Fail with [Error::BudgetExhausted] if the connection or the instance reached a hard limit
+    /// of `class`.
+    pub fn check(&self, class: OpClass) -> Result<(), Error> {
+        self.spends()
+            .try_for_each(|(scope, spend)| spend.check(scope, class))
+            .inspect_err(|err| {
+                Stats::incr(&self.stats.budget_refusals);
+                tracing::debug!(target: "threeqlite::budget", %err, "refusing request");
+            })
+    }
+
+    /// Charge `amount` of `dimension` to the connection and the instance.
+    pub fn charge(&self, dimension: Dimension, amount: u64) {
+        if amount == 0 {
+            return;
+        }
+        let warnings: Vec<_> = self
+            .spends()
+            .filter_map(|(scope, spend)| spend.charge(scope, dimension, amount))
+            .collect();
+        for warning in warnings {
+            Stats::incr(&self.stats.budget_warnings);
+            tracing::warn!(
+                target: "threeqlite::budget",
+                scope = %warning.scope,
+                dimension = %warning.dimension,
+                spent = warning.spent,
+                soft = warning.soft,
+                hard = warning.hard,
+                "session crossed its soft budget"
+            );
+            self.transactions.budget_warning(&warning);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering::Relaxed;
+
+    use sqlite_vfs::DatabaseHandle;
+
+    use super::*;
+    use crate::{
+        cache::{CacheConfig, CacheUse},
+        config::Config,
+        handle::Handle,
+        journal::{Journal, JournalKind},
+        key::KeyLayout,
+        latency::{TransactionBreakdown, TransactionObserver},
+        mock::{self, MockS3},
+        vfs::ThreeQLite,
+    };
+
+    #[derive(Default)]
+    struct Warnings(Mutex<Vec<BudgetWarning>>);
+
+    impl TransactionObserver for Warnings {
+        fn on_transaction(&self, _db: &str, _breakdown: &TransactionBreakdown) {}
+
+        fn on_budget_warning(&self, warning: &BudgetWarning) {
+            self.0.lock().unwrap().push(warning.clone());
+        }
+    }
+
+    async fn budget(handle: &mut Handle, value: Option<&str>) -> String {
+        handle
+            .pragma("threeqlite_budget", value)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn instance(mock: &MockS3, budget: &str) -> ThreeQLite {
+        let config = Config {
+            spend: budget.parse().unwrap(),
+            ..Default::default()
+        };
+        ThreeQLite::with_client(config, mock.client()).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let budget: SpendBudget = "soft_get_requests=5, get_requests=10 put_bytes=4096"
+            .parse()
+            .unwrap();
+        assert_eq!(budget.soft.get_requests, Some(5));
+        assert_eq!(budget.hard.get_requests, Some(10));
+        assert_eq!(budget.hard.put_bytes, Some(4096));
+        assert_eq!(budget.hard.get_bytes, None);
+        assert_eq!(budget.to_string().parse(), Ok(budget));
+        assert_eq!(
+            budget.to_string(),
+            "soft_get_requests=5 get_requests=10 put_bytes=4096"
+        );
+        assert_eq!("none".parse(), Ok(SpendBudget::default()));
+        assert_eq!(SpendBudget::default().to_string(), "none");
+        assert!("get_pages=1".parse::<SpendBudget>().is_err());
+        assert!("get_requests=many".parse::<SpendBudget>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_soft_then_hard() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 16, 1));
+        let tq = instance(&mock, "soft_get_requests=2 get_requests=4");
+        let warnings = Arc::new(Warnings::default());
+        tq.observe_transactions(warnings.clone()).await;
+        let inner = tq.inner.read().await;
+
+        // unregistered reads, without the lock protocol: a GET each
+        for page in 0..4 {
+            let read = inner
+                .read_at(page * 4096, 4096, None, CacheUse::Admit)
+                .await;
+            assert!(read.is_ok(), "page {page}");
+            let expected = if page < 2 { 0 } else { 1 };
+            assert_eq!(warnings.0.lock().unwrap().len(), expected, "page {page}");
+        }
+        assert_eq!(
+            *warnings.0.lock().unwrap(),
+            [BudgetWarning {
+                scope: SpendScope::Instance,
+                dimension: Dimension::GetRequests,
+                spent: 3,
+                soft: 2,
+                hard: Some(4),
+            }]
+        );
+
+        // the fifth read is refused without being sent
+        let gets = mock.requests().len();
+        let err = inner
+            .read_at(4 * 4096, 4096, None, CacheUse::Admit)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::BudgetExhausted {
+                    scope: "instance",
+                    dimension: "get_requests",
+                    limit: 4,
+                    spent: 4
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(mock.requests().len(), gets);
+        assert_eq!(inner.stats.budget_warnings.load(Relaxed), 1);
+        assert_eq!(inner.stats.budget_refusals.load(Relaxed), 1);
+        // writes are not limited
+        assert_eq!(inner.spend.spent(Dimension::PutRequests), 0);
+        assert!(inner.check_budget(OpClass::Write).is_ok());
+        drop(inner);
+
+        // reset, everything is allowed again and warns again
+        tq.reset_budget().await;
+        let inner = tq.inner.read().await;
+        assert_eq!(
+            inner.spend.to_string(),
+            "get_requests=0/4 get_bytes=0 put_requests=0 put_bytes=0"
+        );
+        for page in 0..3 {
+            inner
+                .read_at(page * 4096, 4096, None, CacheUse::Admit)
+                .await
+                .unwrap();
+        }
+        assert_eq!(warnings.0.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_attribution() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 16, 1));
+        let tq = instance(&mock, "get_bytes=8192 put_bytes=100");
+        let inner = tq.inner.read().await;
+
+        let connection = Arc::new(Spend::new("get_requests=1".parse().unwrap()));
+        scope(Some(connection.clone()), async {
+            inner.read_at(0, 4096, None, CacheUse::Admit).await.unwrap();
+            // the connection ran out of requests, the instance has bytes left
+            let err = inner
+                .read_at(4096, 4096, None, CacheUse::Admit)
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(
+                    &err,
+                    Error::BudgetExhausted {
+                        scope: "connection",
+                        dimension: "get_requests",
+                        ..
+                    }
+                ),
+                "{err}"
+            );
+        })
+        .await;
+        assert_eq!(connection.spent(Dimension::GetRequests), 1);
+        assert_eq!(connection.spent(Dimension::GetBytes), 4096);
+        assert_eq!(inner.spend.spent(Dimension::GetBytes), 4096);
+
+        // other connections only spend against the instance, which runs out of bytes
+        inner
+            .read_at(4096, 4096, None, CacheUse::Admit)
+            .await
+            .unwrap();
+        let err = inner
+            .read_at(8192, 4096, None, CacheUse::Admit)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::BudgetExhausted {
+                    scope: "instance",
+                    dimension: "get_bytes",
+                    limit: 8192,
+                    spent: 8192
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(connection.spent(Dimension::GetBytes), 4096);
+        assert_eq!(inner.spend.spent(Dimension::GetRequests), 2);
+
+        // the upload reaching the byte limit completes, the next is refused before it is sent
+        let key = KeyLayout::db("test.db").unwrap();
+        let mut journal = Journal::open(&inner, KeyLayout::journal(&key), JournalKind::Main, true)
+            .await
+            .unwrap();
+        journal.write_at(&[1; 200], 0).unwrap();
+        journal.sync(&inner).await.unwrap();
+        assert_eq!(inner.spend.spent(Dimension::PutBytes), 200);
+        assert_eq!(inner.spend.spent(Dimension::PutRequests), 1);
+        journal.write_at(&[2; 10], 0).unwrap();
+        let err = journal.sync(&inner).await.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::BudgetExhausted {
+                    dimension: "put_bytes",
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(inner.spend.spent(Dimension::PutRequests), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cached_reads_after_exhaustion() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 16, 1));
+        let config = Config {
+            cache: CacheConfig {
+                capacity: 1024 * 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        // read-only, so that reads don't take part in the lock protocol
+        let mut handle = Handle::new(tq.clone(), KeyLayout::db("test.db").unwrap(), true);
+        assert_eq!(
+            budget(&mut handle, None).await,
+            "connection=(none) instance=(get_requests=0 get_bytes=0 put_requests=0 put_bytes=0)"
+        );
+        budget(&mut handle, Some("get_bytes=8192")).await;
+
+        let mut page = vec![0; 4096];
+        handle.read_exact_at(&mut page, 4096).await.unwrap();
+        handle.read_exact_at(&mut page, 8192).await.unwrap();
+        let err = handle.read_exact_at(&mut page, 12288).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                sqlite_vfs::error::Error::External {
+                    cause: Error::BudgetExhausted {
+                        scope: "connection",
+                        dimension: "get_bytes",
+                        ..
+                    }
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(
+            budget(&mut handle, None).await,
+            "connection=(get_requests=2 get_bytes=8192/8192 put_requests=0 put_bytes=0) \
+             instance=(get_requests=2 get_bytes=8192 put_requests=0 put_bytes=0)"
+        );
+
+        // pages cached at a generation stay readable once the instance ran out, and cost nothing
+        let inner = tq.inner.read().await;
+        inner.spend.set_budget("get_requests=4".parse().unwrap());
+        for offset in [4096, 8192] {
+            inner
+                .read_at(offset, 4096, Some(1), CacheUse::Admit)
+                .await
+                .unwrap();
+        }
+        let err = inner
+            .read_at(12288, 4096, Some(1), CacheUse::Admit)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::BudgetExhausted {
+                    scope: "instance",
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        let requests = mock.requests().len();
+        for offset in [4096, 8192] {
+            let page = inner
+                .read_at(offset, 4096, Some(1), CacheUse::Admit)
+                .await
+                .unwrap();
+            assert_eq!(page.len(), 4096);
+        }
+        assert_eq!(mock.requests().len(), requests);
+        assert_eq!(
+            inner.spend.to_string(),
+            "get_requests=4/4 get_bytes=16384 put_requests=0 put_bytes=0"
+        );
+        drop(inner);
+
+        let stats = handle
+            .pragma("threeqlite_stats", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            stats.contains(" budget_warnings=0 budget_refusals=2"),
+            "{stats}"
+        );
+        assert!(stats.contains(" budget=(get_requests=4/4 "), "{stats}");
+        assert!(
+            stats.contains(" connection_budget=(get_requests=2 "),
+            "{stats}"
+        );
+
+        // starting over
+        assert_eq!(budget(&mut handle, Some("reset")).await, "reset");
+        tq.reset_budget().await;
+        handle.read_exact_at(&mut page, 12288).await.unwrap();
+    }
+}
@@ -0,0 +1,515 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use sqlite_vfs::fcntl::FileControlCoverage;
+
+use crate::{
+    cache::CacheStats,
+    circuit::{CircuitState, CircuitStatus, OpClass},
+    credentials::CredentialHealth,
+    durability::Synchronous,
+    latency::{Phase, TransactionBreakdown},
+    memory::MemoryStats,
+    priority::IoClass,
+    timeouts::{TimeoutCause, TimeoutClass},
+};
+
+/// How many transactions the latency histograms cover.
+pub const LATENCY_WINDOW: usize = 1024;
+
+/// Counters shared by all handles of a [crate::vfs::ThreeQLite] instance.
+#[derive(Default, Debug)]
+pub struct Stats {
+    /// Requests sent to the object store.
+    pub requests: AtomicU64,
+    /// Requests that failed.
+    pub failures: AtomicU64,
+    /// Requests per [OpClass] that succeeded, and that failed.
+    pub outcomes: [[AtomicU64; 2]; OpClass::ALL.len()],
+    /// Requests rejected without being sent because a circuit was open.
+    pub circuit_rejections: AtomicU64,
+    /// Reads served without registering as a reader because the write circuit was open.
+    pub degraded_reads: AtomicU64,
+    /// Times a reader backed off because a writer held or requested the lock.
+    pub reader_defers: AtomicU64,
+    /// Lock waits given up because this instance held the lock, see [crate::busy].
+    pub busy_local: AtomicU64,
+    /// Lock waits given up because another instance held the lock.
+    pub busy_remote: AtomicU64,
+    /// Write locks taken over from writers whose process exited, see [crate::busy].
+    pub dead_writers: AtomicU64,
+    /// Faults injected into requests by [crate::chaos], counted in [Stats::failures] too when
+    /// they failed the request.
+    pub injected_faults: AtomicU64,
+    /// `ListObjectsV2` requests sent, see [crate::reconcile].
+    pub list_requests: AtomicU64,
+    /// Keys returned by them.
+    pub keys_listed: AtomicU64,
+    /// Time from the first attempt of a writer to acquiring the lock, of the last
+    /// [LATENCY_WINDOW] writers.
+    pub writer_wait: Histogram,
+    /// Events for the metadata object received by a [crate::watch::GenerationWatcher], not
+    /// counting duplicates.
+    pub notifications_received: AtomicU64,
+    /// Batches of events after which the generation had changed.
+    pub notifications_confirmed: AtomicU64,
+    /// Batches of events after which the generation had not changed.
+    pub notifications_spurious: AtomicU64,
+    /// Retries of single chunks of a parallel read, see [crate::fetch].
+    pub chunk_retries: AtomicU64,
+    /// Duplicate GETs issued for slow chunks.
+    pub hedges: AtomicU64,
+    /// Chunks served by their duplicate GET.
+    pub hedge_wins: AtomicU64,
+    /// Transactions that crossed a soft limit, see [crate::limits].
+    pub soft_limit_warnings: AtomicU64,
+    /// Transactions served from the offline mirror because the metadata object was unreadable,
+    /// see [crate::degraded].
+    pub degraded_transactions: AtomicU64,
+    /// Commits that changed the page size of a database, e.g. a `VACUUM` after
+    /// `PRAGMA page_size`.
+    pub page_size_changes: AtomicU64,
+    /// Bytes SQLite wrote to databases.
+    pub bytes_written: AtomicU64,
+    /// Bytes of database objects uploaded for them, see [StatsSnapshot::write_amplification].
+    pub bytes_uploaded: AtomicU64,
+    /// Bytes copied into the write buffer for the writes flushed, see [PendingWrites::copied].
+    /// Once per byte written, unless writes overlapped without covering each other.
+    ///
+    /// [PendingWrites::copied]: crate::flush::PendingWrites::copied
+    pub bytes_copied: AtomicU64,
+    /// Bytes SQLite wrote again while still buffered, e.g. after spilling its page cache, see
+    /// [StatsSnapshot::spill_amplification].
+    pub bytes_absorbed: AtomicU64,
+    /// The most times one block was written again within a transaction.
+    pub max_block_rewrites: AtomicU64,
+    /// Pages uploaded as the byte ranges that changed, see [crate::flush::DeltaConfig].
+    pub patched_pages: AtomicU64,
+    /// Looks at the metadata object while waiting for the lock, after the first, see
+    /// [crate::wait].
+    pub lock_polls: AtomicU64,
+    /// Polls that found the lock held as at the look before.
+    pub wasted_lock_polls: AtomicU64,
+    /// The most polls a single wait for the lock took.
+    pub max_lock_polls: AtomicU64,
+    /// Schema changes committed by any writer, see [crate::schema].
+    pub schema_changes: AtomicU64,
+    /// Time per [Phase] of the last [LATENCY_WINDOW] transactions.
+    pub phase_latency: [Histogram; Phase::ALL.len()],
+    /// Time the last [LATENCY_WINDOW] reads per [IoClass] waited for a permit, see
+    /// [crate::priority].
+    pub queue_wait: [Histogram; IoClass::ALL.len()],
+    /// File controls sent by SQLite, if [crate::config::Config::strict_file_control] is set.
+    pub file_controls: Option<Arc<FileControlCoverage>>,
+    /// Requests timed out per [TimeoutClass] and [TimeoutCause], see [crate::timeouts].
+    pub timeouts: [[AtomicU64; TimeoutCause::ALL.len()]; TimeoutClass::ALL.len()],
+    /// Pages of warm sets imported, see [crate::warm].
+    pub warm_pages: AtomicU64,
+    /// Those still valid and admitted to the cache.
+    pub warm_valid: AtomicU64,
+    /// Time the last [LATENCY_WINDOW] imports of warm sets took.
+    pub time_to_warm: Histogram,
+    /// Time the last [LATENCY_WINDOW] attempts of requests took until their response, see
+    /// [crate::cost].
+    pub request_latency: Histogram,
+    /// The level of `PRAGMA synchronous` last set on a connection, see [crate::durability].
+    pub synchronous: Mutex<Option<Synchronous>>,
+    /// Commits whose pages were still buffered at their commit point.
+    pub unflushed_commits: AtomicU64,
+    /// Flushes of such pages at the commit point, under
+    /// [crate::durability::SyncPolicy::EnforceDurability].
+    pub enforced_flushes: AtomicU64,
+    /// Conditional writes found to have landed although their response was lost, see
+    /// [crate::idempotency].
+    pub adopted_writes: AtomicU64,
+    /// Soft limits of a session budget crossed, see [crate::spend].
+    pub budget_warnings: AtomicU64,
+    /// Reads and writes refused because a session budget was exhausted.
+    pub budget_refusals: AtomicU64,
+    /// Uploads verified against the checksum the store returned, see [crate::verify].
+    pub checksum_verifications: AtomicU64,
+    /// Uploads verified by reading back what was written.
+    pub ranged_get_verifications: AtomicU64,
+    /// Verifications that found the upload not stored as sent.
+    pub verification_failures: AtomicU64,
+    /// Times the region of the bucket was resolved, see [crate::region].
+    pub region_resolutions: AtomicU64,
+    /// Checks of opens answered by a request of another open, see [crate::burst].
+    pub open_burst_hits: AtomicU64,
+}
+
+/// A rolling window of durations.
+#[derive(Default, Debug)]
+pub struct Histogram {
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+/// Percentiles of a [Histogram].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Histogram {
+    pub fn record(&self, sample: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == LATENCY_WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let mut samples = self
+            .samples
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        samples.sort();
+        let Some(&max) = samples.last() else {
+            return LatencySummary::default();
+        };
+        let at = |q: f64| samples[((samples.len() - 1) as f64 * q).round() as usize];
+        LatencySummary {
+            count: samples.len(),
+            p50: at(0.5),
+            p99: at(0.99),
+            max,
+        }
+    }
+}
+
+/// A point-in-time copy of [Stats].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub requests: u64,
+    pub failures: u64,
+    /// See [Stats::outcomes].
+    pub outcomes: [[u64; 2]; OpClass::ALL.len()],
+    pub circuit_rejections: u64,
+    pub degraded_reads: u64,
+    pub reader_defers: u64,
+    pub busy_local: u64,
+    pub busy_remote: u64,
+    pub dead_writers: u64,
+    pub injected_faults: u64,
+    pub list_requests: u64,
+    pub keys_listed: u64,
+    pub notifications_received: u64,
+    pub notifications_confirmed: u64,
+    pub notifications_spurious: u64,
+    pub chunk_retries: u64,
+    pub hedge_wins: u64,
+    pub soft_limit_warnings: u64,
+    pub page_size_changes: u64,
+    pub degraded_transactions: u64,
+    pub bytes_written: u64,
+    pub bytes_uploaded: u64,
+    pub bytes_copied: u64,
+    /// The circuits of the database of the instance, see [crate::circuit].
+    pub read_circuit: CircuitState,
+    pub write_circuit: CircuitState,
+    /// The circuits that aren't closed, of every database the instance sent requests for.
+    pub unhealthy_circuits: Vec<CircuitStatus>,
+    /// File controls sent by SQLite by opcode name, see [Stats::file_controls].
+    pub file_controls: Vec<(String, u64)>,
+    pub cache: CacheStats,
+    /// Memory in use by component, see [crate::memory].
+    pub memory: MemoryStats,
+    /// `None` if the client has no credentials provider, see [crate::credentials].
+    pub credentials: Option<CredentialHealth>,
+    /// Requests timed out by class and cause, see [Stats::timeouts].
+    pub timeouts: [[u64; TimeoutCause::ALL.len()]; TimeoutClass::ALL.len()],
+    pub warm_pages: u64,
+    pub warm_valid: u64,
+    /// See [Stats::time_to_warm].
+    pub time_to_warm: LatencySummary,
+    pub synchronous: Option<Synchronous>,
+    pub unflushed_commits: u64,
+    pub enforced_flushes: u64,
+    pub adopted_writes: u64,
+    pub budget_warnings: u64,
+    pub budget_refusals: u64,
+    pub bytes_absorbed: u64,
+    pub max_block_rewrites: u64,
+    pub patched_pages: u64,
+    pub lock_polls: u64,
+    pub wasted_lock_polls: u64,
+    pub max_lock_polls: u64,
+    pub schema_changes: u64,
+    pub checksum_verifications: u64,
+    pub ranged_get_verifications: u64,
+    pub verification_failures: u64,
+    /// The region the clients are pinned to, see [crate::region].
+    pub region: Option<String>,
+    /// The round trip to it when it was resolved.
+    pub region_baseline: Option<Duration>,
+    pub region_resolutions: u64,
+    pub open_burst_hits: u64,
+}
+
+impl Stats {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn max(counter: &AtomicU64, value: u64) {
+        counter.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn record_request(&self, class: OpClass, success: bool) {
+        Self::incr(&self.requests);
+        if !success {
+            Self::incr(&self.failures);
+        }
+        Self::incr(&self.outcomes[class as usize][!success as usize]);
+    }
+
+    pub fn outcomes(&self) -> [[u64; 2]; OpClass::ALL.len()] {
+        self.outcomes.each_ref().map(|outcomes| {
+            outcomes
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed))
+        })
+    }
+
+    pub fn record_transaction(&self, breakdown: &TransactionBreakdown) {
+        for phase in Phase::ALL {
+            self.phase_latency[phase as usize].record(breakdown.get(phase));
+        }
+    }
+
+    pub fn phase_latency(&self, phase: Phase) -> LatencySummary {
+        self.phase_latency[phase as usize].summary()
+    }
+
+    pub fn queue_wait(&self, class: IoClass) -> LatencySummary {
+        self.queue_wait[class as usize].summary()
+    }
+
+    /// Record an import of a warm set of `pages`, `valid` of which were admitted.
+    pub fn record_warm(&self, pages: u64, valid: u64, elapsed: Duration) {
+        self.warm_pages.fetch_add(pages, Ordering::Relaxed);
+        self.warm_valid.fetch_add(valid, Ordering::Relaxed);
+        self.time_to_warm.record(elapsed);
+    }
+
+    pub fn observe_synchronous(&self, level: Synchronous) {
+        *self.synchronous.lock().unwrap() = Some(level);
+    }
+
+    pub fn record_timeout(&self, class: TimeoutClass, cause: TimeoutCause) {
+        Self::incr(&self.timeouts[class as usize][cause as usize]);
+    }
+
+    pub fn timeouts(&self) -> [[u64; TimeoutCause::ALL.len()]; TimeoutClass::ALL.len()] {
+        self.timeouts
+            .each_ref()
+            .map(|causes| causes.each_ref().map(|count| count.load(Ordering::Relaxed)))
+    }
+}
+
+impl StatsSnapshot {
+    /// Bytes uploaded per byte SQLite wrote, `None` before any write. `1.0` when only what was
+    /// written is uploaded; a truncation rewrites the whole object.
+    pub fn write_amplification(&self) -> Option<f64> {
+        (self.bytes_written > 0).then(|| self.bytes_uploaded as f64 / self.bytes_written as f64)
+    }
+
+    /// Bytes SQLite wrote per distinct byte buffered, `None` until a write was absorbed. Without
+    /// the buffer each of these writes would have been uploaded.
+    pub fn spill_amplification(&self) -> Option<f64> {
+        (self.bytes_absorbed > 0).then(|| {
+            self.bytes_written as f64
+                / self.bytes_written.saturating_sub(self.bytes_absorbed) as f64
+        })
+    }
+
+    pub fn timeouts(&self, class: TimeoutClass, cause: TimeoutCause) -> u64 {
+        self.timeouts[class as usize][cause as usize]
+    }
+
+    /// Requests of `class` that succeeded, or failed.
+    pub fn outcomes(&self, class: OpClass, success: bool) -> u64 {
+        self.outcomes[class as usize][!success as usize]
+    }
+}
+
+impl std::fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "requests={} failures={} circuit_rejections={} degraded_reads={} reader_defers={} busy_local={} busy_remote={} list_requests={} keys_listed={} notifications_received={} notifications_confirmed={} notifications_spurious={} chunk_retries={} hedge_wins={} soft_limit_warnings={} page_size_changes={} degraded_transactions={} read_circuit={} write_circuit={}",
+            self.requests,
+            self.failures,
+            self.circuit_rejections,
+            self.degraded_reads,
+            self.reader_defers,
+            self.busy_local,
+            self.busy_remote,
+            self.list_requests,
+            self.keys_listed,
+            self.notifications_received,
+            self.notifications_confirmed,
+            self.notifications_spurious,
+            self.chunk_retries,
+            self.hedge_wins,
+            self.soft_limit_warnings,
+            self.page_size_changes,
+            self.degraded_transactions,
+            self.read_circuit,
+            self.write_circuit,
+        )?;
+        if let Some(amplification) = self.write_amplification() {
+            write!(
+                f,
+                " bytes_written={} bytes_uploaded={} write_amplification={amplification:.2}",
+                self.bytes_written, self.bytes_uploaded
+            )?;
+        }
+        if self.bytes_copied > 0 {
+            write!(f, " bytes_copied={}", self.bytes_copied)?;
+        }
+        if self.dead_writers > 0 {
+            write!(f, " dead_writers={}", self.dead_writers)?;
+        }
+        if self.injected_faults > 0 {
+            write!(f, " injected_faults={}", self.injected_faults)?;
+        }
+        if !self.unhealthy_circuits.is_empty() {
+            let circuits = self.unhealthy_circuits.iter().map(ToString::to_string);
+            write!(
+                f,
+                " unhealthy_circuits={}",
+                circuits.collect::<Vec<_>>().join(",")
+            )?;
+        }
+        if self.cache != CacheStats::default() {
+            write!(f, " cache=({})", self.cache)?;
+        }
+        if self.memory.cap.is_some() || self.memory.used() > 0 {
+            write!(f, " memory=({})", self.memory)?;
+        }
+        if let Some(credentials) = &self.credentials {
+            write!(f, " credentials=({credentials})")?;
+        }
+        if !self.file_controls.is_empty() {
+            let report = self
+                .file_controls
+                .iter()
+                .map(|(op, count)| format!("{op}:{count}"))
+                .collect::<Vec<_>>();
+            write!(f, " file_controls={}", report.join(","))?;
+        }
+        let timeouts = TimeoutClass::ALL
+            .iter()
+            .flat_map(|class| TimeoutCause::ALL.iter().map(move |cause| (class, cause)))
+            .filter(|(class, cause)| self.timeouts(**class, **cause) > 0)
+            .map(|(class, cause)| format!("{class}.{cause}:{}", self.timeouts(*class, *cause)))
+            .collect::<Vec<_>>();
+        if !timeouts.is_empty() {
+            write!(f, " timeouts={}", timeouts.join(","))?;
+        }
+        if self.warm_pages > 0 {
+            write!(
+                f,
+                " warm_pages={} warm_valid={} time_to_warm={:?}",
+                self.warm_pages, self.warm_valid, self.time_to_warm.p50
+            )?;
+        }
+        if let Some(level) = self.synchronous {
+            write!(f, " synchronous={level}")?;
+        }
+        if self.unflushed_commits > 0 {
+            write!(
+                f,
+                " unflushed_commits={} enforced_flushes={}",
+                self.unflushed_commits, self.enforced_flushes
+            )?;
+        }
+        if self.adopted_writes > 0 {
+            write!(f, " adopted_writes={}", self.adopted_writes)?;
+        }
+        if self.budget_warnings > 0 || self.budget_refusals > 0 {
+            write!(
+                f,
+                " budget_warnings={} budget_refusals={}",
+                self.budget_warnings, self.budget_refusals
+            )?;
+        }
+        if let Some(amplification) = self.spill_amplification() {
+            write!(
+                f,
+                " bytes_absorbed={} max_block_rewrites={} spill_amplification={amplification:.2}",
+                self.bytes_absorbed, self.max_block_rewrites
+            )?;
+        }
+        if self.patched_pages > 0 {
+            write!(f, " patched_pages={}", self.patched_pages)?;
+        }
+        if self.lock_polls > 0 {
+            write!(
+                f,
+                " lock_polls={} wasted_lock_polls={} max_lock_polls={}",
+                self.lock_polls, self.wasted_lock_polls, self.max_lock_polls
+            )?;
+        }
+        if self.schema_changes > 0 {
+            write!(f, " schema_changes={}", self.schema_changes)?;
+        }
+        if self.checksum_verifications > 0 || self.ranged_get_verifications > 0 {
+            write!(
+                f,
+                " write_verifications=checksum:{},ranged_get:{} verification_failures={}",
+                self.checksum_verifications,
+                self.ranged_get_verifications,
+                self.verification_failures
+            )?;
+        }
+        if let (Some(region), Some(baseline)) = (&self.region, self.region_baseline) {
+            write!(
+                f,
+                " region={region} region_baseline={baseline:?} region_resolutions={}",
+                self.region_resolutions
+            )?;
+        }
+        if self.open_burst_hits > 0 {
+            write!(f, " open_burst_hits={}", self.open_burst_hits)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_window() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.summary(), LatencySummary::default());
+
+        for ms in 0..LATENCY_WINDOW as u64 + 100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        let summary = histogram.summary();
+        assert_eq!(summary.count, LATENCY_WINDOW);
+        // the oldest 100 samples fell out of the window
+        assert_eq!(summary.p50, Duration::from_millis(100 + 512));
+        assert_eq!(
+            summary.max,
+            Duration::from_millis(LATENCY_WINDOW as u64 + 99)
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Temporary files: temporary databases, transient databases (e.g. for `ORDER BY` or
+//! `VACUUM`), and their journals and statement journals.
+//!
+//! SQLite never shares a temporary file with another connection, and opens most of them
+//! without a name, so the object store has no use for them. The [Handle](crate::handle::Handle)
+//! of one keeps it in a buffer of its own, charged to the [memory budget](crate::memory) of the
+//! instance, and frees it once closed. Deleting one is a no-op, and nothing about them is ever
+//! locked remotely, uploaded or cached.
+
+use std::sync::Arc;
+
+use crate::{
+    error::Error,
+    memory::{Charge, Component, MemoryBudget},
+};
+
+/// A temporary file of a single handle.
+pub struct TempFile {
+    data: Vec<u8>,
+    /// What `data` is charged to the budget.
+    charge: Charge,
+}
+
+impl TempFile {
+    pub fn new(memory: &Arc<MemoryBudget>) -> Result<Self, Error> {
+        Ok(Self {
+            data: Vec::new(),
+            charge: memory.charge(Component::Temp, 0)?,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Fill `buf` from `offset`, zeroing what lies past the end. Returns whether `buf` was filled
+    /// completely.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> bool {
+        let start = (offset as usize).min(self.data.len());
+        let available = &self.data[start..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        buf[n..].fill(0);
+        n == buf.len()
+    }
+
+    /// Fails if growing the file would exceed the memory cap.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        let end = offset as usize + buf.len();
+        if self.data.len() < end {
+            self.charge.resize(end as u64)?;
+            self.data.resize(end, 0);
+        }
+        self.data[offset as usize..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    /// Fails if growing the file would exceed the memory cap.
+    pub fn set_len(&mut self, size: u64) -> Result<(), Error> {
+        self.charge.resize(size)?;
+        self.data.resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_charged_while_open() {
+        let memory = Arc::new(MemoryBudget::new(Some(8192)));
+        let mut file = TempFile::new(&memory).unwrap();
+        file.write_at(b"page", 4096).unwrap();
+        assert_eq!(file.size(), 4100);
+        assert_eq!(memory.stats().used[Component::Temp as usize], 4100);
+
+        let mut buf = [1; 8];
+        assert!(!file.read_at(&mut buf, 4096));
+        assert_eq!(&buf, b"page\0\0\0\0");
+
+        // over the cap, the file is left as it was
+        assert!(matches!(
+            file.write_at(&[0; 8192], 4096),
+            Err(Error::OutOfMemory {
+                component: Component::Temp,
+                ..
+            })
+        ));
+        assert_eq!(file.size(), 4100);
+
+        file.set_len(10).unwrap();
+        assert_eq!(memory.stats().used(), 10);
+        drop(file);
+        assert_eq!(memory.stats().used(), 0);
+    }
+}
+
+#[cfg(all(test, feature = "rusqlite"))]
+mod sqlite_tests {
+    use crate::{blocking::BlockingClient, config::Config, memory::Component, mock::MockS3};
+
+    #[test]
+    fn test_temp_store_file() {
+        let mock = MockS3::start();
+        let client = BlockingClient::with_client(Config::default(), mock.client()).unwrap();
+        client.register("temp-store-file", false).unwrap();
+        let conn = client.connect("test.db").unwrap();
+        // the temporary database spills out of a page cache that small
+        conn.execute_batch(
+            "PRAGMA temp_store=FILE;
+             CREATE TABLE t (x);
+             WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 1000)
+             INSERT INTO t SELECT x FROM n;
+             CREATE TEMP TABLE scratch AS SELECT x * 2 AS y FROM t;
+             PRAGMA temp.cache_size=2;
+             CREATE INDEX temp.scratch_y ON scratch (y);",
+        )
+        .unwrap();
+        let sum: i64 = conn
+            .query_row("SELECT sum(y) FROM scratch", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(sum, 1000 * 1001);
+        let memory = || {
+            client.instance().inner.blocking_read().memory.stats().used[Component::Temp as usize]
+        };
+        assert!(memory() > 0);
+
+        drop(conn);
+        assert_eq!(memory(), 0);
+        // SQLite names temporary files with [crate::key::KeyLayout::temp]
+        let requests = mock.requests();
+        assert!(
+            requests
+                .iter()
+                .all(|(_, key)| uuid::Uuid::parse_str(key).is_err()),
+            "{requests:?}"
+        );
+    }
+}
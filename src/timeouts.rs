@@ -0,0 +1,446 @@
+//! Timeouts per class of storage operation.
+//!
+//! A page read SQLite is blocked on and a multi-megabyte bulk transfer can't share timeouts: a
+//! total timeout long enough for the transfer leaves a page read waiting on a dead connection for
+//! most of its budget before it is retried or hedged, see [crate::fetch]. Every
+//! [TimeoutClass] therefore gets a [TimeoutProfile] of its own, applied through the SDK's timeout
+//! config of a dedicated client:
+//!
+//! - [TimeoutClass::PageRead]: ranged GETs of [IoClass::Critical] reads, failing fast when a
+//!   connection is black-holed.
+//! - [TimeoutClass::Metadata]: everything else on the transaction path, e.g. the metadata object
+//!   and writes.
+//! - [TimeoutClass::Bulk]: ranged GETs of [IoClass::Bulk] reads, tolerating slow but steady
+//!   transfers.
+//!
+//! The SDK's first-byte timeout only covers waiting for the response, not streaming its body, so
+//! the total timeout of a ranged GET also bounds reading the body. Timeouts are counted by class
+//! and [TimeoutCause] in [crate::stats::Stats::timeouts].
+
+use std::time::Duration;
+
+use crate::priority::IoClass;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimeoutClass {
+    PageRead,
+    Metadata,
+    Bulk,
+}
+
+impl TimeoutClass {
+    pub const ALL: [TimeoutClass; 3] = [
+        TimeoutClass::PageRead,
+        TimeoutClass::Metadata,
+        TimeoutClass::Bulk,
+    ];
+}
+
+impl From<IoClass> for TimeoutClass {
+    fn from(class: IoClass) -> Self {
+        match class {
+            IoClass::Critical => TimeoutClass::PageRead,
+            IoClass::Bulk => TimeoutClass::Bulk,
+        }
+    }
+}
+
+impl std::fmt::Display for TimeoutClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TimeoutClass::PageRead => "page_read",
+            TimeoutClass::Metadata => "metadata",
+            TimeoutClass::Bulk => "bulk",
+        })
+    }
+}
+
+/// Which timeout of a [TimeoutProfile] expired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimeoutCause {
+    Connect,
+    FirstByte,
+    Total,
+}
+
+impl TimeoutCause {
+    pub const ALL: [TimeoutCause; 3] = [
+        TimeoutCause::Connect,
+        TimeoutCause::FirstByte,
+        TimeoutCause::Total,
+    ];
+}
+
+impl std::fmt::Display for TimeoutCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TimeoutCause::Connect => "connect",
+            TimeoutCause::FirstByte => "first_byte",
+            TimeoutCause::Total => "total",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeoutProfile {
+    /// Give up establishing a connection after this long.
+    pub connect: Duration,
+    /// Give up waiting for the response after this long, counted from sending the request.
+    pub first_byte: Duration,
+    /// Give up on an attempt after this long, including reading the body of a ranged GET.
+    pub total: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimeoutConfig {
+    pub page_read: TimeoutProfile,
+    pub metadata: TimeoutProfile,
+    pub bulk: TimeoutProfile,
+}
+
+impl TimeoutConfig {
+    /// Profiles derived from the timeout of a single attempt of a read, see
+    /// [crate::fetch::FetchConfig::attempt_timeout]: page reads and metadata operations get it as
+    /// their total timeout and a fraction of it to connect and to see the first byte, bulk
+    /// transfers get it to see the first byte and many times it in total.
+    pub fn derived(op_timeout: Duration) -> Self {
+        let fraction = |divisor: u32| {
+            (op_timeout / divisor)
+                .max(Duration::from_millis(50))
+                .min(op_timeout)
+        };
+        Self {
+            page_read: TimeoutProfile {
+                connect: fraction(40),
+                first_byte: fraction(20),
+                total: op_timeout,
+            },
+            metadata: TimeoutProfile {
+                connect: fraction(10),
+                first_byte: fraction(5),
+                total: op_timeout,
+            },
+            bulk: TimeoutProfile {
+                connect: fraction(5),
+                first_byte: op_timeout,
+                total: op_timeout * 30,
+            },
+        }
+    }
+
+    pub fn profile(&self, class: TimeoutClass) -> &TimeoutProfile {
+        match class {
+            TimeoutClass::PageRead => &self.page_read,
+            TimeoutClass::Metadata => &self.metadata,
+            TimeoutClass::Bulk => &self.bulk,
+        }
+    }
+
+    /// Check that every timeout is set and that no profile waits for the first byte longer than
+    /// in total, or to connect longer than for the first byte.
+    pub fn validate(&self) -> Result<(), String> {
+        for class in TimeoutClass::ALL {
+            let profile = self.profile(class);
+            if profile.connect.is_zero() {
+                return Err(format!("the {class} connect timeout is zero"));
+            }
+            if profile.connect > profile.first_byte {
+                return Err(format!(
+                    "the {class} connect timeout of {:?} exceeds its first-byte timeout of {:?}",
+                    profile.connect, profile.first_byte
+                ));
+            }
+            if profile.first_byte > profile.total {
+                return Err(format!(
+                    "the {class} first-byte timeout of {:?} exceeds its total timeout of {:?}",
+                    profile.first_byte, profile.total
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "s3")]
+pub use sdk::{client, TimeoutRecorder};
+
+#[cfg(feature = "s3")]
+mod sdk {
+    use std::{error::Error as _, sync::Arc};
+
+    use aws_sdk_s3::config::{
+        interceptors::FinalizerInterceptorContextRef, timeout::TimeoutConfig as SdkTimeouts,
+        ConfigBag, Intercept, RuntimeComponents,
+    };
+    use aws_sdk_s3::error::BoxError;
+
+    use super::*;
+    use crate::stats::Stats;
+
+    /// A client like `s3` with the timeouts of `profile`, counting its timeouts as `class`.
+    pub fn client(
+        s3: &aws_sdk_s3::Client,
+        class: TimeoutClass,
+        profile: &TimeoutProfile,
+        stats: &Arc<Stats>,
+    ) -> aws_sdk_s3::Client {
+        let config = s3
+            .config()
+            .to_builder()
+            .timeout_config(
+                SdkTimeouts::builder()
+                    .connect_timeout(profile.connect)
+                    .read_timeout(profile.first_byte)
+                    .operation_attempt_timeout(profile.total)
+                    .build(),
+            )
+            .interceptor(TimeoutRecorder {
+                class,
+                stats: stats.clone(),
+            })
+            .build();
+        aws_sdk_s3::Client::from_conf(config)
+    }
+
+    /// Counts the timeouts of the requests of a client in [Stats::timeouts].
+    #[derive(Debug)]
+    pub struct TimeoutRecorder {
+        class: TimeoutClass,
+        stats: Arc<Stats>,
+    }
+
+    impl TimeoutRecorder {
+        fn record(&self, cause: TimeoutCause) {
+            tracing::debug!(target: "threeqlite::s3", class = %self.class, %cause, "request timed out");
+            self.stats.record_timeout(self.class, cause);
+        }
+    }
+
+    impl Intercept for TimeoutRecorder {
+        fn name(&self) -> &'static str {
+            "TimeoutRecorder"
+        }
+
+        // timeouts of the connector, the attempt timeout skips this hook
+        fn read_after_attempt(
+            &self,
+            context: &FinalizerInterceptorContextRef<'_>,
+            _runtime_components: &RuntimeComponents,
+            _cfg: &mut ConfigBag,
+        ) -> Result<(), BoxError> {
+            let Some(Err(err)) = context.output_or_error() else {
+                return Ok(());
+            };
+            let Some(err) = err.as_connector_error().filter(|err| err.is_timeout()) else {
+                return Ok(());
+            };
+            // the connector only tells the two apart in its messages
+            let mut source = err.source();
+            while let Some(err) = source {
+                let message = err.to_string();
+                if message.contains("connect timeout") {
+                    self.record(TimeoutCause::Connect);
+                    return Ok(());
+                }
+                if message.contains("read timeout") {
+                    self.record(TimeoutCause::FirstByte);
+                    return Ok(());
+                }
+                source = err.source();
+            }
+            self.record(TimeoutCause::Total);
+            Ok(())
+        }
+
+        fn read_after_execution(
+            &self,
+            context: &FinalizerInterceptorContextRef<'_>,
+            _runtime_components: &RuntimeComponents,
+            _cfg: &mut ConfigBag,
+        ) -> Result<(), BoxError> {
+            if let Some(Err(err)) = context.output_or_error() {
+                if err.is_timeout_error() {
+                    self.record(TimeoutCause::Total);
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "s3")]
+    use crate::{
+        config::Config,
+        fetch::{chunks, fetch, FetchConfig},
+        key::ObjectKey,
+        mock::MockS3,
+        vfs::ThreeQLite,
+    };
+
+    #[test]
+    fn test_derived_profiles() {
+        let config = TimeoutConfig::derived(Duration::from_secs(10));
+        assert_eq!(
+            config.page_read,
+            TimeoutProfile {
+                connect: Duration::from_millis(250),
+                first_byte: Duration::from_millis(500),
+                total: Duration::from_secs(10),
+            }
+        );
+        assert_eq!(config.bulk.total, Duration::from_secs(300));
+        config.validate().unwrap();
+
+        // never below 50ms, unless the attempt is shorter
+        let config = TimeoutConfig::derived(Duration::from_millis(100));
+        assert_eq!(config.page_read.connect, Duration::from_millis(50));
+        config.validate().unwrap();
+        TimeoutConfig::derived(Duration::from_millis(10))
+            .validate()
+            .unwrap();
+
+        let mut config = TimeoutConfig::derived(Duration::from_secs(10));
+        config.metadata.first_byte = Duration::from_secs(20);
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "the metadata first-byte timeout of 20s exceeds its total timeout of 10s"
+        );
+        config.metadata.first_byte = Duration::from_secs(2);
+        config.bulk.connect = Duration::ZERO;
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "the bulk connect timeout is zero"
+        );
+    }
+
+    #[cfg(feature = "s3")]
+    fn setup(fetch: FetchConfig) -> (MockS3, ThreeQLite, ObjectKey, Vec<u8>) {
+        let mock = MockS3::start();
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        mock.put("test.db", data.clone());
+        let profile = |first_byte, total| TimeoutProfile {
+            connect: Duration::from_millis(100),
+            first_byte,
+            total,
+        };
+        let config = Config {
+            fetch,
+            timeouts: Some(TimeoutConfig {
+                page_read: profile(Duration::from_millis(200), Duration::from_millis(400)),
+                metadata: profile(Duration::from_millis(200), Duration::from_secs(1)),
+                bulk: profile(Duration::from_millis(200), Duration::from_secs(10)),
+            }),
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        (mock, tq, ObjectKey::new("test.db").unwrap(), data)
+    }
+
+    #[cfg(feature = "s3")]
+    #[tokio::test]
+    async fn test_instance_rejects_invalid_timeouts() {
+        let mock = MockS3::start();
+        let mut timeouts = TimeoutConfig::derived(Duration::from_secs(10));
+        timeouts.bulk.connect = Duration::ZERO;
+        let config = Config {
+            timeouts: Some(timeouts),
+            ..Config::default()
+        };
+        let Err(err) = ThreeQLite::with_client(config, mock.client()) else {
+            panic!("zero connect timeout accepted");
+        };
+        assert_eq!(
+            err.to_string(),
+            "invalid configuration: the bulk connect timeout is zero"
+        );
+    }
+
+    #[cfg(feature = "s3")]
+    #[tokio::test]
+    async fn test_black_holed_page_read_fails_fast() {
+        let (mock, tq, key, data) = setup(FetchConfig {
+            backoff: Duration::from_millis(10),
+            ..FetchConfig::default()
+        });
+        // accepted, but never answered
+        mock.stall_range("bytes=0-4095", Duration::from_secs(30), 1);
+
+        let inner = tq.inner.read().await;
+        let start = std::time::Instant::now();
+        let (parts, report) = fetch(
+            &inner,
+            &key,
+            None,
+            &chunks(0, 4096, 4096),
+            IoClass::Critical,
+        )
+        .await
+        .unwrap();
+        // failed after the first-byte timeout rather than the total one, and was retried
+        assert!(
+            start.elapsed() < Duration::from_millis(400),
+            "{:?}",
+            start.elapsed()
+        );
+        assert_eq!(parts, vec![data[..4096].to_vec()]);
+        assert_eq!(report.retries, vec![1]);
+        let stats = inner.stats();
+        assert_eq!(
+            stats.timeouts(TimeoutClass::PageRead, TimeoutCause::FirstByte),
+            1
+        );
+        assert_eq!(stats.timeouts.iter().flatten().sum::<u64>(), 1);
+        assert!(
+            stats
+                .to_string()
+                .ends_with(" timeouts=page_read.first_byte:1"),
+            "{stats}"
+        );
+    }
+
+    #[cfg(feature = "s3")]
+    #[tokio::test]
+    async fn test_steady_bulk_transfer_is_not_killed() {
+        let (mock, tq, key, data) = setup(FetchConfig {
+            chunk_size: 64 * 1024,
+            chunk_attempts: 1,
+            ..FetchConfig::default()
+        });
+        // 16 pieces over 800ms, longer than the total timeout of page reads
+        mock.trickle(4096, Duration::from_millis(50));
+
+        let inner = tq.inner.read().await;
+        let (parts, report) = fetch(
+            &inner,
+            &key,
+            None,
+            &chunks(0, data.len() as u64, data.len() as u64),
+            IoClass::Bulk,
+        )
+        .await
+        .unwrap();
+        assert_eq!(parts, vec![data.clone()]);
+        assert_eq!(report.retries, vec![0]);
+        assert_eq!(inner.stats().timeouts.iter().flatten().sum::<u64>(), 0);
+
+        // the same transfer as a page read runs out of time
+        fetch(
+            &inner,
+            &key,
+            None,
+            &chunks(0, data.len() as u64, data.len() as u64),
+            IoClass::Critical,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            inner
+                .stats()
+                .timeouts(TimeoutClass::PageRead, TimeoutCause::Total),
+            1
+        );
+    }
+}
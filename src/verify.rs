@@ -0,0 +1,596 @@
+//! Verification of uploads against what the object store acknowledged.
+//!
+//! For a single-part upload without KMS encryption, the ETag S3 returns is the MD5 of the stored
+//! bytes. Comparing it against the MD5 of the bytes sent catches corruption in transit and
+//! mixed-up responses without any extra request, before anything relies on the upload. With
+//! [crate::config::Config::paranoid_commit], the object is also looked at with a HEAD request.
+//!
+//! Deployments that want assurance for every upload, whatever its encryption, turn on
+//! [VerifyWrites] per [WriteClass]. A verified upload carries a SHA-256 or CRC32C checksum, which
+//! the store checks the body against and returns; comparing it against the one computed locally
+//! takes no extra request. Where the store returns no checksum, and for pages uploaded in place,
+//! whose checksum would cover the whole object, a ranged GET reads back up to [SPOT_CHECK_LEN]
+//! bytes of what was written instead. A mismatch fails the upload with
+//! [Error::WriteVerificationFailed]: a flush before its commit barrier, a metadata write before
+//! it is relied on. Verifications are counted per [VerifyMethod] in the stats.
+
+use aws_sdk_s3::{
+    operation::put_object::{builders::PutObjectFluentBuilder, PutObjectOutput},
+    types::ServerSideEncryption,
+};
+use base64::Engine;
+use sha2::Digest;
+
+use crate::{
+    circuit::OpClass,
+    error::Error,
+    key::ObjectKey,
+    spend::{Dimension, Payer},
+    stats::Stats,
+};
+
+/// The most bytes of an upload a ranged GET reads back, see [VerifyWrites].
+pub const SPOT_CHECK_LEN: usize = 64 * 1024;
+
+/// What an upload holds, for [VerifyWrites] to decide whether to verify it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteClass {
+    /// Pages of a database object, uploaded in place or as a whole.
+    Pages,
+    /// Block manifests and their extents, hot sets and warm sets.
+    Manifests,
+    /// The metadata object, which publishes commits.
+    Metadata,
+}
+
+/// The checksum verified uploads carry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Sha256,
+    Crc32c,
+}
+
+impl ChecksumAlgorithm {
+    /// The checksum of `body`, base64-encoded as S3 exchanges it.
+    pub fn compute(self, body: &[u8]) -> String {
+        let digest = match self {
+            ChecksumAlgorithm::Sha256 => sha2::Sha256::digest(body).to_vec(),
+            ChecksumAlgorithm::Crc32c => crc32c::crc32c(body).to_be_bytes().to_vec(),
+        };
+        base64::prelude::BASE64_STANDARD.encode(digest)
+    }
+}
+
+impl std::fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ChecksumAlgorithm::Sha256 => "SHA-256",
+            ChecksumAlgorithm::Crc32c => "CRC32C",
+        })
+    }
+}
+
+/// Which uploads to verify after the store acknowledged them, see the
+/// [module documentation](self). Off by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VerifyWrites {
+    pub pages: bool,
+    pub manifests: bool,
+    pub metadata: bool,
+    pub checksum: ChecksumAlgorithm,
+}
+
+impl VerifyWrites {
+    /// Verify uploads of every class.
+    pub fn all() -> Self {
+        Self {
+            pages: true,
+            manifests: true,
+            metadata: true,
+            checksum: ChecksumAlgorithm::default(),
+        }
+    }
+
+    pub fn enabled(&self, class: WriteClass) -> bool {
+        match class {
+            WriteClass::Pages => self.pages,
+            WriteClass::Manifests => self.manifests,
+            WriteClass::Metadata => self.metadata,
+        }
+    }
+}
+
+/// How an upload was verified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyMethod {
+    /// Against the checksum the store returned.
+    Checksum,
+    /// By reading back what was written.
+    RangedGet,
+}
+
+impl std::fmt::Display for VerifyMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            VerifyMethod::Checksum => "checksum",
+            VerifyMethod::RangedGet => "ranged_get",
+        })
+    }
+}
+
+/// What was sent in a PUT request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upload {
+    pub key: ObjectKey,
+    pub len: u64,
+    /// Hex-encoded MD5 of the body.
+    pub md5: String,
+    /// The checksum sent along, if the upload is verified, see [VerifyWrites].
+    pub checksum: Option<(ChecksumAlgorithm, String)>,
+    /// Where in the object the bytes read back to verify the upload are, and what they are, if
+    /// it is verified.
+    pub spot: Option<(u64, Vec<u8>)>,
+}
+
+impl Upload {
+    pub fn new(key: &ObjectKey, body: &[u8]) -> Self {
+        Self {
+            key: key.clone(),
+            len: body.len() as u64,
+            md5: format!("{:x}", md5::compute(body)),
+            checksum: None,
+            spot: None,
+        }
+    }
+
+    /// An upload of `body` as `class`, prepared for verification if `verify` asks for it.
+    pub fn verified(
+        key: &ObjectKey,
+        body: &[u8],
+        class: WriteClass,
+        verify: &VerifyWrites,
+    ) -> Self {
+        let mut upload = Self::new(key, body);
+        if verify.enabled(class) {
+            upload.checksum = Some((verify.checksum, verify.checksum.compute(body)));
+            upload.spot = spot(0, body);
+        }
+        upload
+    }
+
+    /// An upload of `body` at `offset` of an existing object. The checksum of the object would
+    /// cover all of it, so it is verified by reading back what was written.
+    pub fn in_place(key: &ObjectKey, offset: u64, body: &[u8]) -> Self {
+        let mut upload = Self::new(key, body);
+        upload.spot = spot(offset, body);
+        upload
+    }
+
+    /// Send the checksum of a verified upload with `put`, for the store to check the body against
+    /// and return it.
+    pub fn send_checksum(&self, put: PutObjectFluentBuilder) -> PutObjectFluentBuilder {
+        match &self.checksum {
+            Some((ChecksumAlgorithm::Sha256, checksum)) => put.checksum_sha256(checksum),
+            Some((ChecksumAlgorithm::Crc32c, checksum)) => put.checksum_crc32_c(checksum),
+            None => put,
+        }
+    }
+
+    /// The checksum of the algorithm sent that the store returned in `out`.
+    pub fn returned_checksum<'a>(&self, out: &'a PutObjectOutput) -> Option<&'a str> {
+        match self.checksum.as_ref()?.0 {
+            ChecksumAlgorithm::Sha256 => out.checksum_sha256(),
+            ChecksumAlgorithm::Crc32c => out.checksum_crc32_c(),
+        }
+    }
+
+    /// Compare the ETag of the stored object against the body sent. ETags that aren't an MD5 of
+    /// the content, i.e. those of KMS-encrypted and multipart objects, can't be checked.
+    pub fn check_etag(
+        &self,
+        etag: Option<&str>,
+        encryption: Option<&ServerSideEncryption>,
+    ) -> Result<(), Error> {
+        if matches!(
+            encryption,
+            Some(ServerSideEncryption::AwsKms | ServerSideEncryption::AwsKmsDsse)
+        ) {
+            return Ok(());
+        }
+        let Some(etag) = etag.map(|etag| etag.trim_matches('"')) else {
+            return Ok(());
+        };
+        if etag.contains('-') || etag.eq_ignore_ascii_case(&self.md5) {
+            return Ok(());
+        }
+        Err(self.mismatch(format!("sent MD5 {}, stored ETag {etag}", self.md5)))
+    }
+
+    /// Compare the length of the stored object against the body sent.
+    pub fn check_len(&self, len: Option<i64>) -> Result<(), Error> {
+        match len {
+            Some(len) if len as u64 != self.len => {
+                Err(self.mismatch(format!("sent {} bytes, stored {len} bytes", self.len)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn mismatch(&self, reason: String) -> Error {
+        Error::UploadMismatch {
+            key: self.key.to_string(),
+            reason,
+        }
+    }
+}
+
+/// Up to [SPOT_CHECK_LEN] bytes of `body` written at `offset`, from a random place in it.
+fn spot(offset: u64, body: &[u8]) -> Option<(u64, Vec<u8>)> {
+    if body.is_empty() {
+        return None;
+    }
+    let len = body.len().min(SPOT_CHECK_LEN);
+    let start = rand::random::<usize>() % (body.len() - len + 1);
+    Some((offset + start as u64, body[start..start + len].to_vec()))
+}
+
+/// Verify `upload` once the store acknowledged it, by `returned`, the checksum the store
+/// returned, or else by reading back part of what was written. Does nothing for uploads that
+/// aren't verified. Requests and verifications are accounted to `payer`.
+pub async fn verify_write(
+    s3: &aws_sdk_s3::Client,
+    bucket: &str,
+    payer: &Payer,
+    upload: &Upload,
+    returned: Option<&str>,
+) -> Result<(), Error> {
+    let (method, res) = match (&upload.checksum, returned) {
+        (Some((algorithm, sent)), Some(returned)) => {
+            let res = match returned == sent {
+                true => Ok(()),
+                false => Err(format!(
+                    "sent {algorithm} {sent}, the store computed {returned}"
+                )),
+            };
+            (VerifyMethod::Checksum, res)
+        }
+        _ => {
+            let Some((offset, expected)) = &upload.spot else {
+                return Ok(());
+            };
+            let res = read_back(s3, bucket, payer, &upload.key, *offset, expected).await?;
+            (VerifyMethod::RangedGet, res)
+        }
+    };
+
+    let stats = &payer.stats;
+    Stats::incr(match method {
+        VerifyMethod::Checksum => &stats.checksum_verifications,
+        VerifyMethod::RangedGet => &stats.ranged_get_verifications,
+    });
+    res.map_err(|reason| {
+        Stats::incr(&stats.verification_failures);
+        let err = Error::WriteVerificationFailed {
+            key: upload.key.to_string(),
+            method,
+            reason,
+        };
+        tracing::error!(target: "threeqlite::s3", %err, "write verification failed");
+        err
+    })
+}
+
+/// Compare the `expected` bytes at `offset` of `key` against those stored.
+async fn read_back(
+    s3: &aws_sdk_s3::Client,
+    bucket: &str,
+    payer: &Payer,
+    key: &ObjectKey,
+    offset: u64,
+    expected: &[u8],
+) -> Result<Result<(), String>, Error> {
+    let end = offset + expected.len() as u64 - 1;
+    let res = s3
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .range(format!("bytes={offset}-{end}"))
+        .send()
+        .await;
+    payer.stats.record_request(OpClass::Read, res.is_ok());
+    payer.charge(Dimension::GetRequests, 1);
+    let stored = res?.body.collect().await.map_err(|err| Error::Whatever {
+        message: format!("failed to read back {key}: {err}"),
+        source: Some(err.into()),
+    })?;
+    let stored = stored.into_bytes();
+    payer.charge(Dimension::GetBytes, stored.len() as u64);
+    if stored.len() != expected.len() {
+        let (sent, len) = (expected.len(), stored.len());
+        return Ok(Err(format!(
+            "read back {len} bytes at {offset} instead of the {sent} sent"
+        )));
+    }
+    Ok(
+        match stored.iter().zip(expected).position(|(a, b)| a != b) {
+            Some(at) => Err(format!(
+                "byte {} read back differs from the one sent",
+                offset + at as u64
+            )),
+            None => Ok(()),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlite_vfs::{DatabaseHandle, LockKind};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        flush::{CommitStep, PendingWrites},
+        handle::Handle,
+        key::KeyLayout,
+        mock::{self, MockS3},
+        vfs::{MetadataRecord, ThreeQLite},
+    };
+
+    fn verifying(verify_writes: VerifyWrites) -> Config {
+        Config {
+            verify_writes,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_check_etag() {
+        let upload = Upload::new(&ObjectKey::new("metadata").unwrap(), b"hello");
+        assert_eq!(upload.md5, "5d41402abc4b2a76b9719d911017c592");
+        upload
+            .check_etag(Some("\"5d41402abc4b2a76b9719d911017c592\""), None)
+            .unwrap();
+        upload.check_etag(None, None).unwrap();
+        // multipart and KMS ETags aren't content hashes
+        upload
+            .check_etag(Some("\"d41d8cd98f00b204e9800998ecf8427e-2\""), None)
+            .unwrap();
+        upload
+            .check_etag(
+                Some("\"d41d8cd98f00b204e9800998ecf8427e\""),
+                Some(&ServerSideEncryption::AwsKms),
+            )
+            .unwrap();
+
+        let err = upload
+            .check_etag(
+                Some("\"d41d8cd98f00b204e9800998ecf8427e\""),
+                Some(&ServerSideEncryption::Aes256),
+            )
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::UploadMismatch { key, .. } if key == "metadata"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrong_etag_fails_metadata_write() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        let inner = tq.inner.read().await;
+        inner
+            .write_metadata_record(MetadataRecord::default())
+            .await
+            .unwrap();
+
+        mock.wrong_etag(true);
+        let err = inner
+            .write_metadata_record(MetadataRecord::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("metadata"), "{err}");
+    }
+
+    #[test]
+    fn test_checksums() {
+        // as S3 documents them for this body
+        assert_eq!(
+            ChecksumAlgorithm::Sha256.compute(b"hello"),
+            "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+        assert_eq!(ChecksumAlgorithm::Crc32c.compute(b"hello"), "mnG7TA==");
+
+        let key = ObjectKey::new("metadata").unwrap();
+        let upload = Upload::verified(&key, b"hello", WriteClass::Pages, &VerifyWrites::all());
+        assert_eq!(upload.spot, Some((0, b"hello".to_vec())));
+        let upload = Upload::verified(&key, b"hello", WriteClass::Pages, &Default::default());
+        assert_eq!((upload.checksum, upload.spot), (None, None));
+
+        let body = vec![1; 3 * SPOT_CHECK_LEN];
+        let (offset, spot) = Upload::in_place(&key, 100, &body).spot.unwrap();
+        assert_eq!(spot.len(), SPOT_CHECK_LEN);
+        assert!((100..=100 + 2 * SPOT_CHECK_LEN as u64).contains(&offset));
+    }
+
+    #[tokio::test]
+    async fn test_checksum_verification_adds_no_requests() {
+        for checksum in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Crc32c] {
+            let mock = MockS3::start();
+            let tq = ThreeQLite::with_client(
+                verifying(VerifyWrites {
+                    metadata: true,
+                    checksum,
+                    ..Default::default()
+                }),
+                mock.client(),
+            )
+            .unwrap();
+            let inner = tq.inner.read().await;
+            inner
+                .write_metadata_record(MetadataRecord::default())
+                .await
+                .unwrap();
+            assert_eq!(mock.requests(), [("PUT".to_owned(), "metadata".to_owned())]);
+            let stats = inner.stats();
+            assert_eq!(
+                (stats.checksum_verifications, stats.ranged_get_verifications),
+                (1, 0)
+            );
+
+            mock.corrupt_next_put("metadata");
+            let err = inner
+                .write_metadata_record(MetadataRecord::default())
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(
+                    &err,
+                    Error::WriteVerificationFailed { key, method: VerifyMethod::Checksum, .. }
+                        if key == "metadata"
+                ),
+                "{err}"
+            );
+            assert_eq!(inner.stats().verification_failures, 1);
+            assert_eq!(mock.requests().len(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ranged_get_without_checksums() {
+        let mock = MockS3::start();
+        mock.omit_checksums(true);
+        let tq = ThreeQLite::with_client(
+            verifying(VerifyWrites {
+                metadata: true,
+                ..Default::default()
+            }),
+            mock.client(),
+        )
+        .unwrap();
+        let inner = tq.inner.read().await;
+        inner
+            .write_metadata_record(MetadataRecord::default())
+            .await
+            .unwrap();
+        let len = mock.get("metadata").unwrap().len();
+        assert_eq!(mock.ranges(), [format!("bytes=0-{}", len - 1)]);
+
+        mock.corrupt_next_put("metadata");
+        let err = inner
+            .write_metadata_record(MetadataRecord::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::WriteVerificationFailed { method: VerifyMethod::RangedGet, reason, .. }
+                    if reason.contains("byte 0")
+            ),
+            "{err}"
+        );
+        let stats = inner.stats();
+        assert_eq!(
+            (
+                stats.checksum_verifications,
+                stats.ranged_get_verifications,
+                stats.verification_failures
+            ),
+            (0, 2, 1)
+        );
+        assert!(
+            stats
+                .to_string()
+                .contains(" write_verifications=checksum:0,ranged_get:2 verification_failures=1"),
+            "{stats}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_page_upload_aborts_commit() {
+        let mock = MockS3::start();
+        mock.put("test.db", mock::database(4096, 2, 1));
+        let tq = ThreeQLite::with_client(
+            verifying(VerifyWrites {
+                pages: true,
+                ..Default::default()
+            }),
+            mock.client(),
+        )
+        .unwrap();
+        let db = KeyLayout::db("test.db").unwrap();
+        let mut handle = Handle::new(tq.clone(), db.clone(), false);
+        assert!(handle.lock(LockKind::Exclusive).await.unwrap());
+        let pages = |n| {
+            let mut pending = PendingWrites::default();
+            pending.write(0, &mock::database(4096, 2, n));
+            Arc::new(pending)
+        };
+        let durable = || async {
+            let inner = tq.inner.read().await;
+            let events = inner.commit_log.recent().into_iter();
+            events
+                .filter(|event| event.step == CommitStep::PagesDurable)
+                .count()
+        };
+
+        // read back, as the checksum of pages uploaded in place would cover the whole object
+        tq.flush_pages(&db, &pages(2)).await.unwrap();
+        assert_eq!(mock.ranges(), ["bytes=0-8191"]);
+        assert_eq!(durable().await, 1);
+
+        mock.corrupt_next_put("test.db");
+        let err = tq.flush_pages(&db, &pages(3)).await.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::WriteVerificationFailed { key, method: VerifyMethod::RangedGet, .. }
+                    if key == "test.db"
+            ),
+            "{err}"
+        );
+        // the commit barrier was never reached
+        assert_eq!(durable().await, 1);
+        let stats = tq.stats().await;
+        assert_eq!(
+            (stats.ranged_get_verifications, stats.verification_failures),
+            (2, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paranoid_commit_checks_stored_object() {
+        let mock = MockS3::start();
+        mock.truncate_puts(Some(3));
+        let record = MetadataRecord::default();
+
+        // the ETag matches what was sent, only the stored object is short
+        let tq = ThreeQLite::with_client(Config::default(), mock.client()).unwrap();
+        tq.inner
+            .read()
+            .await
+            .write_metadata_record(record.clone())
+            .await
+            .unwrap();
+
+        let config = Config {
+            paranoid_commit: true,
+            ..Config::default()
+        };
+        let tq = ThreeQLite::with_client(config, mock.client()).unwrap();
+        let err = tq
+            .inner
+            .read()
+            .await
+            .write_metadata_record(record)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::UploadMismatch { key, reason } if key == "metadata" && reason.contains("stored 3 bytes")),
+            "{err}"
+        );
+    }
+}
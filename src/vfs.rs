@@ -1,36 +1,198 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    future::Future,
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
 use aws_config::BehaviorVersion;
-use aws_sdk_s3::types::ObjectLockLegalHoldStatus;
+use aws_sdk_s3::types::{ObjectLockEnabled, ObjectLockLegalHoldStatus};
+use aws_sdk_s3::{
+    config::{http::HttpResponse, IdentityCache, ProvideCredentials, SharedCredentialsProvider},
+    error::SdkError,
+    operation::{
+        list_objects_v2::{builders::ListObjectsV2FluentBuilder, ListObjectsV2Output},
+        put_object::PutObjectOutput,
+    },
+};
 use base64::Engine;
 use rand::{Rng as _, RngCore};
 use serde::{Deserialize, Serialize};
 use snafu::whatever;
-use sqlite_vfs::{OpenAccess, OpenKind, Vfs};
+use sqlite_vfs::{
+    capability::VfsCapabilities, fcntl::FileControlCoverage, OpenAccess, OpenKind, RegisterError,
+    Vfs, VfsHandle, VfsRegister,
+};
 use tokio::sync::RwLock;
 
-use crate::{error::Error, handle::Handle};
+#[cfg(feature = "chaos")]
+use crate::chaos::{Chaos, ChaosStorage};
+use crate::{
+    burst::{self, OpenBurst},
+    bus::{GenerationUpdate, InvalidationBus},
+    busy::{self, BusyDiagnosis, Holder, LocalProcess},
+    cache::{CacheUse, PageCache},
+    capture::Capture,
+    cas::CasLock,
+    circuit::{CircuitBreaker, OpClass},
+    clock::Clock,
+    config::{Config, ConnectionDefaults, LockBackend, LockConfig},
+    cost::Metering,
+    create::{self, DatabaseState},
+    credentials::{self, CredentialHealth, CredentialWatch, RefreshingCredentials},
+    degraded::DegradedReadPolicy,
+    drill::Faults,
+    durability::Barriers,
+    error::Error,
+    extent::{self, ExtentCache, ManifestConfig},
+    fetch::{self, FetchConfig},
+    flush::{self, CommitLog, CommitStep, DeltaConfig, FlushGraph, FlushReport, PendingWrites},
+    format::{self, Bounded},
+    guarantees::{Guarantees, Preflight, Setup},
+    handle::Handle,
+    heal::{self, MetadataHealth, Stamp},
+    idempotency,
+    inflight::{self, Exclusive, InFlight},
+    journal::{self, Journal, JournalKind, SuperJournals},
+    key::{KeyLayout, ObjectKey},
+    latency::{self, Phase, TransactionBreakdown, TransactionObserver, Transactions},
+    limits::TransactionLimits,
+    memory::MemoryBudget,
+    mirror::{BlockManifest, Mirror, MirrorPolicy, OfflineStatus, SyncReport},
+    operation::{Operations, ProgressSnapshot},
+    prefetch::{self, HotSet, PrefetchConfig},
+    priority::{IoClass, Limiter, Permit},
+    probe::{self, WriteProbes},
+    protocol::{self, ReaderDecision, WriteRequest, WriterDecision},
+    receipt::{Commit, CommitReceipt, ReceiptStatus},
+    reconcile::{Cursors, ReconcileConfig},
+    region::{RegionConfig, RegionPin, RegionWatch},
+    registration::{self, Registration, RegistrationConfig, RegistrationSnapshot},
+    replay::{ExchangeLog, ExchangeWatch},
+    role::Roles,
+    schema::{self, SchemaCallback, SchemaChange, SchemaWatch},
+    session::Sessions,
+    shutdown::{Handles, Owner, ShutdownConfig},
+    spend::{self, Dimension, Payer, Spend},
+    stats::{LatencySummary, Stats, StatsSnapshot},
+    temp::TempFile,
+    timeouts::{self, TimeoutClass, TimeoutConfig},
+    verify::{self, Upload, VerifyWrites, WriteClass},
+    wait::{self, Poller},
+    watch::WatchConfig,
+};
 
 #[derive(Clone)]
 pub struct Inner {
+    /// The client of metadata operations and writes, see [crate::timeouts].
     pub s3: aws_sdk_s3::Client,
-    pub metadata_lock: S3FileLock,
-    pub metadata_filename: String,
+    /// The client of ranged GETs of page reads.
+    pub page_client: aws_sdk_s3::Client,
+    /// The client of ranged GETs of bulk transfers.
+    pub bulk_client: aws_sdk_s3::Client,
+    pub timeouts: TimeoutConfig,
+    /// The credentials of the clients, see [crate::credentials]. `None` unless created
+    /// [with credentials](ThreeQLite::with_credentials).
+    pub credentials: Option<Arc<RefreshingCredentials>>,
+    pub metadata_lock: MetadataLock,
+    pub metadata_filename: ObjectKey,
     pub current_lock: Option<Vec<u8>>,
+    /// The generation the handles of the instance hold the read lock at, see [crate::session].
+    /// Their reads don't register again while they do.
+    pub held_generation: Option<u64>,
+    /// Rolling back a hot journal, which takes the write lock over from a dead writer despite its
+    /// journal, see [crate::recovery].
+    pub recovering: bool,
     pub bucket: String,
-    pub db_filename: String,
+    pub db_filename: ObjectKey,
+    pub circuit: Arc<CircuitBreaker>,
+    pub stats: Arc<Stats>,
+    pub cache: Arc<PageCache>,
+    /// Memory used by the cache and journals, see [crate::memory].
+    pub memory: Arc<MemoryBudget>,
+    pub probes: Arc<WriteProbes>,
+    /// Checks shared between opens, see [crate::burst].
+    pub burst: Arc<OpenBurst>,
+    pub transactions: Arc<Transactions>,
+    /// The schema cookies seen, see [crate::schema].
+    pub schema: Arc<SchemaWatch>,
+    /// The newest generation seen in the metadata object, see [crate::heal].
+    pub generation_seen: Arc<AtomicU64>,
+    pub paranoid_commit: bool,
+    /// Which uploads to verify after the fact, see [crate::verify].
+    pub verify_writes: VerifyWrites,
+    /// The region the clients are pinned to, see [crate::region].
+    pub region: Arc<RegionPin>,
+    pub region_config: RegionConfig,
+    /// The requests of the instance while a replay measures them, see [crate::replay].
+    pub exchanges: Arc<ExchangeLog>,
+    pub lock_config: LockConfig,
+    pub watch_config: WatchConfig,
+    pub fetch_config: FetchConfig,
+    /// Settings of prefetching the hot set of each database, see [crate::prefetch].
+    pub prefetch_config: PrefetchConfig,
+    pub transaction_limits: TransactionLimits,
+    /// What the instance spent against its budget, see [crate::spend].
+    pub spend: Arc<Spend>,
+    /// Uploading the changes of pages rather than the pages, see [crate::flush].
+    pub delta_config: DeltaConfig,
+    /// Read permits by priority class, see [crate::priority].
+    pub limiter: Arc<Limiter>,
+    /// Offline mirrors by database, see [crate::mirror].
+    pub mirrors: Arc<std::sync::Mutex<HashMap<ObjectKey, Arc<Mirror>>>>,
+    /// Super-journals of cross-database transactions, see [crate::journal].
+    pub super_journals: Arc<SuperJournals>,
+    /// The steps of commits, see [crate::flush].
+    pub commit_log: Arc<CommitLog>,
+    /// The pages of the flushes uploading, which reads see, see [crate::inflight].
+    pub in_flight: Arc<InFlight>,
+    pub reconcile_config: ReconcileConfig,
+    pub manifest_config: ManifestConfig,
+    /// Extents of block manifests fetched, see [crate::extent].
+    pub extents: Arc<ExtentCache>,
+    pub degraded_reads: Option<DegradedReadPolicy>,
+    /// Serve reads of a database object modified out-of-band, see [crate::heal].
+    pub read_externally_modified: bool,
+    /// The length of the database object recorded at the generation the registered reader
+    /// joined at, see [crate::heal].
+    pub recorded_len: Option<u64>,
+    /// The database object was written under the held write lock, see [Inner::commit_stamp].
+    pub written: bool,
+    /// The ranges of the database object written under the held write lock, `None` if unknown,
+    /// published with the commit, see [crate::bus].
+    pub changed: Option<Vec<Range<u64>>>,
+    /// Where commits are published, see [crate::bus].
+    pub bus: Arc<std::sync::Mutex<Option<Arc<dyn InvalidationBus>>>>,
+    /// Where listing the sidecars of each database stopped, see [crate::reconcile].
+    pub cursors: Arc<Cursors>,
+    /// The background roles this instance claimed, see [crate::role].
+    pub roles: Arc<Roles>,
+    /// The wall clock, and the detector of its jumps, see [crate::clock].
+    pub clock: Arc<Clock>,
+    /// Failures injected by a recovery drill. Only ever set on the scratch instances of
+    /// [ThreeQLite::drill], see [crate::drill].
+    pub faults: Option<Arc<Faults>>,
+    /// The faults injected into the requests of the instance, see [crate::chaos].
+    #[cfg(feature = "chaos")]
+    pub chaos: Arc<Chaos>,
     // bucket: String,
     // lock_file: String,
     // current_lock: Arc<std::sync::Mutex<Option<Vec<u8>>>>,
 }
 
 pub trait Lock {
-    async fn request_lock(&mut self) -> Vec<u8>;
-    async fn release_lock(&mut self) -> Result<(), snafu::Whatever>;
+    async fn request_lock(&mut self) -> Result<Vec<u8>, Error>;
+    async fn release_lock(&mut self) -> Result<(), Error>;
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub enum Metadata {
+    #[default]
     None,
     Writer(Vec<u8>),
     Reader(ReaderMetadata),
@@ -38,24 +200,160 @@ pub enum Metadata {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ReaderMetadata {
-    readers: Vec<Vec<u8>>,
-    write_request: Option<Vec<u8>>,
+    pub readers: Vec<Vec<u8>>,
+    pub write_request: Option<Vec<u8>>,
+}
+
+/// The metadata object as stored from format version 2 on. Version 1 stores [Metadata] only.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct MetadataRecord {
+    pub metadata: Metadata,
+    /// The newest format version understood by each reader in `metadata`. Readers missing here
+    /// predate version 2.
+    pub reader_versions: Vec<(Vec<u8>, u32)>,
+    /// The version a writer held back because an active reader does not understand it yet. The
+    /// next writer that finds all readers capable applies it.
+    pub pending_upgrade: Option<u32>,
+    /// Kept in the object's user metadata rather than its body, so that readers of any format
+    /// version leave it alone.
+    #[serde(skip)]
+    pub stamp: Option<Stamp>,
+    /// Kept in the object's user metadata, see [crate::protocol].
+    #[serde(skip)]
+    pub write_request: Option<WriteRequest>,
+    /// The writer holding or requesting the lock. Kept in the object's user metadata, see
+    /// [crate::busy].
+    #[serde(skip)]
+    pub holder: Option<Holder>,
+    /// The ETag of the metadata object the record was read from, which the commit is conditional
+    /// on, see [crate::idempotency].
+    #[serde(skip)]
+    pub etag: Option<String>,
+}
+
+/// The condition a write of the metadata object is made on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Precondition {
+    None,
+    /// The object doesn't exist.
+    Absent,
+    /// The object is at this ETag.
+    Matches(String),
+}
+
+/// Readers a metadata object may list, see [Bounded].
+pub const MAX_READERS: usize = 4096;
+
+/// The longest ID of a reader or writer accepted, see [Bounded].
+const MAX_ID_LEN: usize = 64;
+
+fn check_id(id: &[u8]) -> Result<(), String> {
+    match id.len() > MAX_ID_LEN {
+        true => Err(format!(
+            "ID of {} bytes exceeds the limit of {MAX_ID_LEN}",
+            id.len()
+        )),
+        false => Ok(()),
+    }
+}
+
+impl Bounded for Metadata {
+    const MAX_LEN: u64 = 1 << 20;
+
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Metadata::None => Ok(()),
+            Metadata::Writer(id) => check_id(id),
+            Metadata::Reader(reader) => {
+                format::check_count("readers", &reader.readers, MAX_READERS)?;
+                reader.readers.iter().try_for_each(|id| check_id(id))?;
+                reader.write_request.as_deref().map_or(Ok(()), check_id)
+            }
+        }
+    }
+}
+
+impl Bounded for MetadataRecord {
+    const MAX_LEN: u64 = Metadata::MAX_LEN;
+
+    fn validate(&self) -> Result<(), String> {
+        self.metadata.validate()?;
+        format::check_count("reader versions", &self.reader_versions, MAX_READERS)?;
+        self.reader_versions
+            .iter()
+            .try_for_each(|(id, _)| check_id(id))
+    }
+}
+
+impl MetadataRecord {
+    fn active_reader_versions(&self) -> Vec<u32> {
+        match &self.metadata {
+            Metadata::Reader(read_metadata) => read_metadata
+                .readers
+                .iter()
+                .map(|id| {
+                    self.reader_versions
+                        .iter()
+                        .find(|(reader, _)| reader == id)
+                        .map(|(_, version)| *version)
+                        .unwrap_or(1)
+                })
+                .collect(),
+            Metadata::None | Metadata::Writer(_) => vec![],
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct S3FileLock {
     pub s3: aws_sdk_s3::Client,
     pub bucket: String,
-    pub lock_file: String,
+    pub lock_file: ObjectKey,
     pub current_lock: Option<Vec<u8>>,
 }
 
+/// The HTTP status of a failed request, if a response was received.
+pub(crate) fn status<E>(err: &SdkError<E, HttpResponse>) -> Option<u16> {
+    err.raw_response().map(|res| res.status().as_u16())
+}
+
 fn prepare_md5(uuid: &[u8; 16]) -> String {
     base64::prelude::BASE64_STANDARD.encode(md5::compute(uuid).as_ref())
 }
 
+impl S3FileLock {
+    fn failed(&self, action: &'static str, reason: impl ToString) -> Error {
+        Error::LockObject {
+            key: self.lock_file.to_string(),
+            action,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Whether the lock object holds `lock_uuid` under a legal hold, i.e. this instance holds it.
+    async fn holds(&self, lock_uuid: &[u8]) -> Result<bool, Error> {
+        let obj = self
+            .s3
+            .get_object()
+            .bucket(self.bucket.clone())
+            .key(self.lock_file.clone())
+            .send()
+            .await
+            .map_err(|err| self.failed("reading", err))?;
+        let hold = obj.object_lock_legal_hold_status.clone();
+        // the body streams, it is only in memory once collected
+        let bytes = obj
+            .body
+            .collect()
+            .await
+            .map_err(|err| self.failed("reading", err))?
+            .into_bytes();
+        Ok(bytes == lock_uuid && hold == Some(ObjectLockLegalHoldStatus::On))
+    }
+}
+
 impl Lock for S3FileLock {
-    async fn request_lock(&mut self) -> Vec<u8> {
+    async fn request_lock(&mut self) -> Result<Vec<u8>, Error> {
         let lock_uuid = uuid::Uuid::new_v4().to_bytes_le();
         let mut i = 0;
         loop {
@@ -68,100 +366,55 @@ impl Lock for S3FileLock {
                 .await
             {
                 Ok(lock_status) => {
-                    tracing::info!("Requesting lock ({i}): {lock_status:?}");
+                    tracing::trace!(target: "threeqlite::lock_protocol", attempt = i, ?lock_status, "requesting lock");
                     i += 1;
 
-                    match lock_status.legal_hold.and_then(|status| status.status) {
-                        Some(status) => {
-                            if status == ObjectLockLegalHoldStatus::Off {
-                                match self
-                                    .s3
-                                    .put_object()
-                                    .bucket(&self.bucket)
-                                    .key(&self.lock_file)
-                                    .body(lock_uuid.to_vec().into())
-                                    .content_md5(prepare_md5(&lock_uuid))
-                                    .object_lock_legal_hold_status(ObjectLockLegalHoldStatus::On)
-                                    .send()
-                                    .await
-                                {
-                                    Ok(_) => {
-                                        tracing::info!("put ok");
-                                        // this might be unnecessary, but double checking for now
-                                        {
-                                            let val = self
-                                                .s3
-                                                .get_object()
-                                                .bucket(self.bucket.clone())
-                                                .key(self.lock_file.clone())
-                                                .send()
-                                                .await;
-                                            if let Ok(obj) = val {
-                                                if let Some(bytes) = obj.body.bytes() {
-                                                    if bytes == lock_uuid.to_vec()
-                                                        && obj.object_lock_legal_hold_status
-                                                            == Some(ObjectLockLegalHoldStatus::On)
-                                                    {
-                                                        let vect = lock_uuid.to_vec();
-                                                        self.current_lock = Some(vect.clone());
-                                                        return vect;
-                                                    } else {
-                                                        panic!("1")
-                                                    }
-                                                } else {
-                                                    panic!("2")
-                                                }
-                                            } else {
-                                                panic!("3")
-                                            }
-                                        }
-
-                                        // let vect = lock_uuid.to_vec();
-                                        // self.current_lock = Some(vect.clone());
-                                        // return vect;
-                                    }
-                                    Err(e) => {
-                                        tracing::info!("put err: {e:?}");
-                                    }
-                                }
-                            } else {
-                                let val = self
-                                    .s3
-                                    .get_object()
-                                    .bucket(self.bucket.clone())
-                                    .key(self.lock_file.clone())
-                                    .send()
-                                    .await;
-                                if let Ok(obj) = val {
-                                    if let Some(bytes) = obj.body.bytes() {
-                                        if bytes == lock_uuid.to_vec()
-                                            && obj.object_lock_legal_hold_status
-                                                == Some(ObjectLockLegalHoldStatus::On)
-                                        {
-                                            let vect = lock_uuid.to_vec();
-                                            self.current_lock = Some(vect.clone());
-                                            return vect;
-                                        }
-                                    }
+                    let Some(status) = lock_status.legal_hold.and_then(|status| status.status)
+                    else {
+                        return Err(Error::LegalHoldUnavailable {
+                            key: self.lock_file.to_string(),
+                            reason: "no legal hold status".to_owned(),
+                        });
+                    };
+                    if status == ObjectLockLegalHoldStatus::Off {
+                        match self
+                            .s3
+                            .put_object()
+                            .bucket(&self.bucket)
+                            .key(&self.lock_file)
+                            .body(lock_uuid.to_vec().into())
+                            .content_md5(prepare_md5(&lock_uuid))
+                            .object_lock_legal_hold_status(ObjectLockLegalHoldStatus::On)
+                            .send()
+                            .await
+                        {
+                            Ok(_) => {
+                                tracing::debug!(target: "threeqlite::lock_protocol", "lock object written");
+                                // another instance may have checked the legal hold at the same time
+                                if self.holds(&lock_uuid).await? {
+                                    self.current_lock = Some(lock_uuid.to_vec());
+                                    return Ok(lock_uuid.to_vec());
                                 }
+                                tracing::debug!(target: "threeqlite::lock_protocol", "lock object overwritten");
+                            }
+                            Err(e) => {
+                                tracing::debug!(target: "threeqlite::lock_protocol", err = ?e, "writing lock object failed");
                             }
                         }
-                        None => {
-                            panic!("bruhx skehfal.ksehfkaehfk");
-                        }
+                    } else if self.holds(&lock_uuid).await.unwrap_or(false) {
+                        self.current_lock = Some(lock_uuid.to_vec());
+                        return Ok(lock_uuid.to_vec());
                     }
-
-                    // if lock_status
-                    //     .legal_hold
-                    //     .and_then(|status| status.status)
-                    //     .is_some_and(|status| status == ObjectLockLegalHoldStatus::Off)
-                    // {
-                    // } else {
-                    //     panic!("bruhx skehfal.ksehfkaehfk");
-                    // }
+                }
+                // a bucket without Object Lock refuses to tell
+                Err(err) if matches!(status(&err), Some(400 | 501)) => {
+                    return Err(Error::LegalHoldUnavailable {
+                        key: self.lock_file.to_string(),
+                        reason: err.to_string(),
+                    });
                 }
                 Err(legal_status_error) => {
-                    tracing::info!("err bruh");
+                    tracing::debug!(target: "threeqlite::lock_protocol", "no legal hold status, creating lock object");
                     // check if file exists
                     match self
                         .s3
@@ -171,9 +424,8 @@ impl Lock for S3FileLock {
                         .send()
                         .await
                     {
-                        Err(get_obj_err) => {
-                            match self
-                                .s3
+                        Err(_) => {
+                            self.s3
                                 .put_object()
                                 .bucket(&self.bucket)
                                 .key(&self.lock_file)
@@ -181,31 +433,23 @@ impl Lock for S3FileLock {
                                 .object_lock_legal_hold_status(ObjectLockLegalHoldStatus::On)
                                 .send()
                                 .await
-                            {
-                                Ok(_) => {
-                                    let vect = lock_uuid.to_vec();
-                                    self.current_lock = Some(vect.clone());
-                                    return vect;
-                                }
-                                Err(_) => {
-                                    panic!("Error creating lock file: {:?}", get_obj_err)
-                                }
-                            }
+                                .map_err(|err| self.failed("creating", err))?;
+                            self.current_lock = Some(lock_uuid.to_vec());
+                            return Ok(lock_uuid.to_vec());
                         }
+                        // created by another instance in the meantime
                         _ => {
-                            panic!("Error getting legal hold status / but also it seems to exist ok: {:?}", legal_status_error)
+                            tracing::debug!(target: "threeqlite::lock_protocol", err = ?legal_status_error, "lock object created concurrently");
                         }
                     }
                 }
             }
-            // for debugging, remove this later
             tokio::time::sleep(Duration::from_millis(10)).await;
         }
     }
 
-    async fn release_lock(&mut self) -> Result<(), snafu::Whatever> {
-        match self
-            .s3
+    async fn release_lock(&mut self) -> Result<(), Error> {
+        self.s3
             .put_object()
             .bucket(&self.bucket)
             .key(&self.lock_file)
@@ -213,359 +457,2424 @@ impl Lock for S3FileLock {
             .object_lock_legal_hold_status(ObjectLockLegalHoldStatus::Off)
             .send()
             .await
-        {
-            Ok(_) => {
-                self.current_lock = None;
-                Ok(())
-            }
-            Err(e) => whatever!("Error releasing lock: {}", e),
-        }
+            .map_err(|err| self.failed("releasing", err))?;
+        self.current_lock = None;
+        Ok(())
     }
 }
 
-impl Inner {
-    pub async fn read_exact_at(
-        &mut self,
-        offset: usize,
-        len: usize,
-    ) -> Result<Vec<u8>, snafu::Whatever> {
-        let _ = self.request_read_lock().await;
-        let data = self
-            .s3
-            .get_object()
-            .bucket(&self.bucket)
-            .key(&self.db_filename)
-            .range(format!("bytes={}-{}", offset, offset + len))
-            .send()
-            .await;
-        let _ = self.release_read_lock().await;
+/// The lock serializing updates of the metadata object, of the [LockBackend] configured.
+#[derive(Clone)]
+pub enum MetadataLock {
+    LegalHold(S3FileLock),
+    Conditional(CasLock),
+}
 
-        match data {
-            Ok(obj) => {
-                if let Some(bytes) = obj.body.bytes() {
-                    Ok(bytes.to_vec())
-                } else {
-                    whatever!("Error reading data: no bytes")
-                }
+impl MetadataLock {
+    pub fn new(
+        s3: aws_sdk_s3::Client,
+        bucket: String,
+        lock_file: ObjectKey,
+        config: &LockConfig,
+    ) -> Self {
+        match config.backend {
+            LockBackend::LegalHold => Self::LegalHold(S3FileLock {
+                s3,
+                bucket,
+                lock_file,
+                current_lock: None,
+            }),
+            LockBackend::Conditional => {
+                Self::Conditional(CasLock::new(s3, bucket, lock_file, config))
             }
-            Err(e) => whatever!("Error reading data: {}", e),
         }
     }
 
-    pub async fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), snafu::Whatever> {
-        let _ = self.request_write_lock().await;
-        match self
-            .s3
-            .put_object()
-            .bucket(&self.metadata_lock.bucket)
-            .write_offset_bytes(offset as i64)
-            .key(&self.db_filename)
-            .body(data.to_vec().into())
-            .send()
-            .await
-        {
-            Ok(_) => {
-                let _ = self.release_write_lock().await;
-                Ok(())
-            }
-            Err(e) => {
-                let _ = self.release_write_lock().await;
-                whatever!("Error writing data: {}", e)
-            }
+    pub fn lock_file(&self) -> &ObjectKey {
+        match self {
+            Self::LegalHold(lock) => &lock.lock_file,
+            Self::Conditional(lock) => &lock.lock_file,
         }
     }
 
-    pub async fn get_database_size(&mut self) -> Result<i64, snafu::Whatever> {
-        let _ = self.request_read_lock().await;
-        let size = self
-            .s3
-            .get_object()
-            .bucket(&self.metadata_lock.bucket)
-            .key(&self.metadata_lock.lock_file)
-            .send()
-            .await;
-        let _ = self.release_read_lock().await;
-
-        match size {
-            Ok(obj) => {
-                if let Some(size) = obj.content_length {
-                    Ok(size)
-                } else {
-                    whatever!("Error getting database size: no content length")
-                }
-            }
-            Err(e) => whatever!("Error getting database size: {}", e),
+    /// The client the lock object is written with.
+    pub fn client_mut(&mut self) -> &mut aws_sdk_s3::Client {
+        match self {
+            Self::LegalHold(lock) => &mut lock.s3,
+            Self::Conditional(lock) => &mut lock.s3,
         }
     }
+}
 
-    pub async fn write_metadata(&self, meta: Metadata) -> Result<(), snafu::Whatever> {
-        let bytes = bincode::serialize(&meta).unwrap();
-        match self
-            .s3
-            .put_object()
-            .bucket(&self.metadata_lock.bucket)
-            .key(&self.metadata_lock.lock_file)
-            .body(bytes.into())
-            .send()
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(e) => whatever!("Error writing metadata: {}", e),
+impl Lock for MetadataLock {
+    async fn request_lock(&mut self) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::LegalHold(lock) => lock.request_lock().await,
+            Self::Conditional(lock) => lock.request_lock().await,
         }
     }
 
-    pub async fn read_metadata(&self) -> Result<Metadata, snafu::Whatever> {
-        match self
-            .s3
-            .get_object()
-            .bucket(&self.metadata_lock.bucket)
-            .key(&self.metadata_lock.lock_file)
-            .send()
-            .await
-        {
-            Ok(obj) => {
-                if let Some(bytes) = obj.body.bytes() {
-                    Ok(bincode::deserialize(bytes).unwrap_or(Metadata::None))
-                } else {
-                    Ok(Metadata::None)
-                }
-            }
-            Err(e) => whatever!("Error reading metadata: {}", e),
+    async fn release_lock(&mut self) -> Result<(), Error> {
+        match self {
+            Self::LegalHold(lock) => lock.release_lock().await,
+            Self::Conditional(lock) => lock.release_lock().await,
         }
     }
+}
 
-    pub async fn read_metadata_until(
-        &self,
-        until: Box<dyn Fn(Metadata) -> bool>,
-        poll_delay: std::time::Duration,
-    ) -> Result<Metadata, snafu::Whatever> {
-        loop {
-            let metadata = self.read_metadata().await?;
-            if until(metadata.clone()) {
-                return Ok(metadata);
-            }
-            tokio::time::sleep(poll_delay).await;
-        }
+impl Inner {
+    /// Fail fast if the circuit for `class` of the database of the instance is open.
+    pub fn guard(&self, class: OpClass) -> Result<(), Error> {
+        self.guard_for(&self.db_filename, class)
     }
 
-    pub async fn release_read_lock(&mut self) -> Result<(), snafu::Whatever> {
-        let _ = self.metadata_lock.request_lock().await;
-        if let Metadata::Reader(read_metadata) = self.read_metadata().await? {
-            let lock_uuid = self.current_lock.clone().unwrap();
-            let new_readers = read_metadata
-                .readers
-                .into_iter()
-                .filter(|v| v != &lock_uuid)
-                .collect();
-
-            self.write_metadata(Metadata::Reader(ReaderMetadata {
-                readers: new_readers,
-                write_request: read_metadata.write_request,
-            }))
-            .await?;
-            self.metadata_lock.release_lock().await?;
-            self.current_lock = None;
-            // Ok(())
-            Ok(())
-        } else {
-            self.metadata_lock.release_lock().await?;
-            self.current_lock = None;
-            whatever!("Error releasing read lock, no reader metadata found")
-        }
+    /// Record the outcome of a request that passed [Inner::guard].
+    pub fn record(&self, class: OpClass, success: bool) {
+        self.record_for(&self.db_filename, class, success)
     }
 
-    pub async fn release_write_lock(&mut self) -> Result<(), snafu::Whatever> {
-        let _ = self.metadata_lock.request_lock().await;
-        if let Metadata::Writer(lock_uuid) = self.read_metadata().await? {
-            if let Some(current_lock) = self.current_lock.clone() {
-                if current_lock == lock_uuid {
-                    self.write_metadata(Metadata::None).await?;
-                    self.metadata_lock.release_lock().await?;
-                    self.current_lock = None;
-                    return Ok(());
-                }
-            }
-        }
-        self.metadata_lock.release_lock().await?;
-        self.current_lock = None;
-        whatever!("Error releasing write lock, no writer metadata found")
+    /// [Inner::guard] for a request for the object `key`, which may belong to another database
+    /// than that of the instance, e.g. a journal or the target of a copy, see [crate::circuit].
+    pub fn guard_for(&self, key: impl AsRef<str>, class: OpClass) -> Result<(), Error> {
+        let db = KeyLayout::database_of(key.as_ref());
+        self.circuit
+            .check(&self.bucket, db, class)
+            .inspect_err(|_| {
+                Stats::incr(&self.stats.circuit_rejections);
+            })
     }
 
-    pub async fn request_read_lock(&mut self) -> Result<(), snafu::Whatever> {
-        let lock_uuid = uuid::Uuid::new_v4().to_bytes_le();
-
-        loop {
-            let _ = self.metadata_lock.request_lock().await;
-
-            let ready_for_reader = |meta: &Metadata| match meta {
-                Metadata::None => true,
-                Metadata::Writer(_) => false,
-                Metadata::Reader(read_metadata) => read_metadata.write_request.is_none(),
-            };
+    /// Record the outcome of a request that passed [Inner::guard_for].
+    pub fn record_for(&self, key: impl AsRef<str>, class: OpClass, success: bool) {
+        let db = KeyLayout::database_of(key.as_ref());
+        tracing::trace!(target: "threeqlite::s3", bucket = %self.bucket, %db, ?class, success, "request");
+        self.stats.record_request(class, success);
+        self.circuit.record(&self.bucket, db, class, success);
+        self.charge(Dimension::requests(class), 1);
+    }
 
-            let meta = self.read_metadata().await?;
-            if ready_for_reader(&meta) {
-                let mut current_readers = match meta {
-                    Metadata::None => vec![],
-                    Metadata::Writer(_) => vec![],
-                    Metadata::Reader(read_metadata) => read_metadata.readers,
-                };
-                current_readers.push(lock_uuid.to_vec());
-                self.write_metadata(Metadata::Reader(ReaderMetadata {
-                    readers: current_readers,
-                    write_request: None,
-                }))
-                .await?;
-                break;
-            }
+    /// The budgets of the instance and of the connection running the current task, see
+    /// [crate::spend].
+    pub fn payer(&self) -> Payer {
+        Payer {
+            instance: self.spend.clone(),
+            connection: spend::connection(),
+            stats: self.stats.clone(),
+            transactions: self.transactions.clone(),
         }
-        self.current_lock = Some(lock_uuid.to_vec());
-        Ok(())
     }
 
-    pub async fn request_write_lock(&mut self) -> Result<(), snafu::Whatever> {
-        let lock_uuid = uuid::Uuid::new_v4().to_bytes_le();
+    /// Charge `amount` of `dimension` to the budgets, see [crate::spend].
+    pub fn charge(&self, dimension: Dimension, amount: u64) {
+        self.payer().charge(dimension, amount);
+    }
+
+    /// Fail with [Error::BudgetExhausted] before reading pages from or writing to the object
+    /// store once a budget ran out, see [crate::spend].
+    pub fn check_budget(&self, class: OpClass) -> Result<(), Error> {
+        self.payer().check(class)
+    }
 
-        let ready_for_writer = |meta: Metadata| match meta {
-            Metadata::None => true,
-            Metadata::Writer(_) => false,
-            Metadata::Reader(read_metadata) => read_metadata.write_request.is_none(),
+    /// Fail with a diagnosis of `record` once the lock was waited for longer than
+    /// [LockConfig::busy_timeout], or the busy handler of the connection refuses to wait any
+    /// longer, see [crate::busy].
+    pub(crate) fn check_busy(&self, record: &MetadataRecord, start: Instant) -> Result<(), Error> {
+        let timed_out = self
+            .lock_config
+            .busy_timeout
+            .is_some_and(|timeout| start.elapsed() >= timeout);
+        if !timed_out && busy::keep_waiting() {
+            return Ok(());
+        }
+        let Some(diagnosis) = self.diagnose(record) else {
+            return Ok(());
         };
+        Stats::incr(match diagnosis.contention {
+            busy::Contention::Local => &self.stats.busy_local,
+            busy::Contention::Remote => &self.stats.busy_remote,
+        });
+        tracing::info!(
+            target: "threeqlite::lock_protocol",
+            waited = ?start.elapsed(),
+            timed_out,
+            %diagnosis,
+            "giving up on busy lock"
+        );
+        Err(Error::Busy {
+            diagnosis: Box::new(diagnosis),
+        })
+    }
 
-        loop {
-            let _ = self.metadata_lock.request_lock().await;
+    /// Check the clocks for a jump since the last check, see [crate::clock]. After one, the role
+    /// leases of the instance count as lost, observations of the leases of others start over, and
+    /// the pinned headers are dropped. Returns whether the clocks jumped.
+    pub fn check_clock(&self) -> bool {
+        let Some(jump) = self.clock.check() else {
+            return false;
+        };
+        tracing::warn!(
+            target: "threeqlite::lock_protocol",
+            %jump,
+            "clock jumped, revalidating leases"
+        );
+        self.roles.forget();
+        self.cache.unpin_headers();
+        true
+    }
 
-            let current_metadata = self.read_metadata().await?;
+    pub fn diagnose(&self, record: &MetadataRecord) -> Option<BusyDiagnosis> {
+        busy::diagnose(
+            record,
+            &self.lock_config.identity,
+            self.current_lock.as_deref(),
+            self.clock.now_ms(),
+        )
+    }
 
-            if ready_for_writer(current_metadata.clone()) {
-                self.write_metadata(Metadata::Writer(lock_uuid.to_vec()))
-                    .await?;
-                break;
-            } else if let Metadata::Reader(read_metadata) = current_metadata {
-                if read_metadata.readers.is_empty()
-                    && read_metadata
-                        .write_request
-                        .clone()
-                        .is_none_or(|v| v == lock_uuid.to_vec())
-                {
-                    self.write_metadata(Metadata::Writer(lock_uuid.to_vec()))
-                        .await?;
-                    break;
-                } else if read_metadata.write_request.is_none() {
-                    self.write_metadata(Metadata::Reader(ReaderMetadata {
-                        readers: read_metadata.readers,
-                        write_request: Some(lock_uuid.to_vec()),
-                    }))
-                    .await?;
-                    break;
-                    // return Ok(lock_uuid.to_vec());
-                }
-            }
+    /// Send the `ListObjectsV2` request `req` for the bucket, counting it and the keys it
+    /// returned, see [crate::reconcile].
+    pub async fn list(
+        &self,
+        req: ListObjectsV2FluentBuilder,
+    ) -> Result<ListObjectsV2Output, Error> {
+        self.guard(OpClass::Read)?;
+        let page = req.bucket(&self.bucket).send().await;
+        self.record(OpClass::Read, page.is_ok());
+        Stats::incr(&self.stats.list_requests);
+        let page = page?;
+        self.stats.keys_listed.fetch_add(
+            page.contents().len() as u64,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+        Ok(page)
+    }
 
-            self.metadata_lock.release_lock().await?;
+    /// Wait for a permit to read with the priority of `class`, see [crate::priority].
+    pub async fn permit(&self, class: IoClass) -> Permit {
+        self.permit_for(&self.db_filename, class).await
+    }
 
-            tokio::time::sleep(Duration::from_millis(50)).await;
-        }
-        self.current_lock = Some(lock_uuid.to_vec());
-        Ok(())
+    /// [Inner::permit] for a read of the object `key`, which may belong to another database than
+    /// that of the instance, see [crate::priority].
+    pub async fn permit_for(&self, key: impl AsRef<str>, class: IoClass) -> Permit {
+        let db = KeyLayout::database_of(key.as_ref());
+        self.limiter.acquire(db, class, &self.stats).await
     }
-}
 
-#[derive(Clone)]
-pub struct ThreeQLite {
-    pub inner: Arc<RwLock<Inner>>,
-}
+    pub fn stats(&self) -> StatsSnapshot {
+        use std::sync::atomic::Ordering::Relaxed;
 
-impl ThreeQLite {
-    pub async fn new() -> Self {
-        let sdk_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-        let s3 = aws_sdk_s3::Client::new(&sdk_config);
+        let region = self.region.get();
+        let db = self.db_filename.as_str();
+        StatsSnapshot {
+            requests: self.stats.requests.load(Relaxed),
+            failures: self.stats.failures.load(Relaxed),
+            outcomes: self.stats.outcomes(),
+            circuit_rejections: self.stats.circuit_rejections.load(Relaxed),
+            degraded_reads: self.stats.degraded_reads.load(Relaxed),
+            reader_defers: self.stats.reader_defers.load(Relaxed),
+            busy_local: self.stats.busy_local.load(Relaxed),
+            busy_remote: self.stats.busy_remote.load(Relaxed),
+            dead_writers: self.stats.dead_writers.load(Relaxed),
+            injected_faults: self.stats.injected_faults.load(Relaxed),
+            list_requests: self.stats.list_requests.load(Relaxed),
+            keys_listed: self.stats.keys_listed.load(Relaxed),
+            notifications_received: self.stats.notifications_received.load(Relaxed),
+            notifications_confirmed: self.stats.notifications_confirmed.load(Relaxed),
+            notifications_spurious: self.stats.notifications_spurious.load(Relaxed),
+            chunk_retries: self.stats.chunk_retries.load(Relaxed),
+            hedge_wins: self.stats.hedge_wins.load(Relaxed),
+            soft_limit_warnings: self.stats.soft_limit_warnings.load(Relaxed),
+            page_size_changes: self.stats.page_size_changes.load(Relaxed),
+            degraded_transactions: self.stats.degraded_transactions.load(Relaxed),
+            bytes_written: self.stats.bytes_written.load(Relaxed),
+            bytes_uploaded: self.stats.bytes_uploaded.load(Relaxed),
+            bytes_copied: self.stats.bytes_copied.load(Relaxed),
+            read_circuit: self.circuit.state(&self.bucket, db, OpClass::Read),
+            write_circuit: self.circuit.state(&self.bucket, db, OpClass::Write),
+            unhealthy_circuits: self.circuit.unhealthy(),
+            file_controls: self
+                .stats
+                .file_controls
+                .as_ref()
+                .map(|coverage| coverage.report())
+                .unwrap_or_default(),
+            cache: self.cache.stats(),
+            memory: self.memory.stats(),
+            credentials: self.credentials.as_ref().map(|c| c.health()),
+            timeouts: self.stats.timeouts(),
+            warm_pages: self.stats.warm_pages.load(Relaxed),
+            warm_valid: self.stats.warm_valid.load(Relaxed),
+            time_to_warm: self.stats.time_to_warm.summary(),
+            synchronous: *self.stats.synchronous.lock().unwrap(),
+            unflushed_commits: self.stats.unflushed_commits.load(Relaxed),
+            enforced_flushes: self.stats.enforced_flushes.load(Relaxed),
+            adopted_writes: self.stats.adopted_writes.load(Relaxed),
+            budget_warnings: self.stats.budget_warnings.load(Relaxed),
+            budget_refusals: self.stats.budget_refusals.load(Relaxed),
+            bytes_absorbed: self.stats.bytes_absorbed.load(Relaxed),
+            max_block_rewrites: self.stats.max_block_rewrites.load(Relaxed),
+            patched_pages: self.stats.patched_pages.load(Relaxed),
+            lock_polls: self.stats.lock_polls.load(Relaxed),
+            wasted_lock_polls: self.stats.wasted_lock_polls.load(Relaxed),
+            max_lock_polls: self.stats.max_lock_polls.load(Relaxed),
+            schema_changes: self.stats.schema_changes.load(Relaxed),
+            checksum_verifications: self.stats.checksum_verifications.load(Relaxed),
+            ranged_get_verifications: self.stats.ranged_get_verifications.load(Relaxed),
+            verification_failures: self.stats.verification_failures.load(Relaxed),
+            region: region.as_ref().map(|pinned| pinned.region.clone()),
+            region_baseline: region.map(|pinned| pinned.baseline),
+            region_resolutions: self.stats.region_resolutions.load(Relaxed),
+            open_burst_hits: self.stats.open_burst_hits.load(Relaxed),
+        }
+    }
 
-        let bucket = "threeqlite".to_owned();
+    /// Read `len` bytes at `offset` of the database. Unless `register` is set, the read doesn't
+    /// register as a reader, which requires write access to the metadata object. `usage` decides
+    /// whether the read is served from and admitted to the page cache, see [crate::cache]. The
+    /// pages of `hot` are fetched along with it once registering has validated the generation,
+    /// see [crate::prefetch].
+    pub async fn read_exact_at(
+        &mut self,
+        offset: usize,
+        len: usize,
+        register: bool,
+        usage: CacheUse,
+        hot: Option<&HotSet>,
+    ) -> Result<Vec<u8>, Error> {
+        self.guard(OpClass::Read)?;
 
-        Self {
-            inner: Arc::new(RwLock::new(Inner {
-                s3: s3.clone(),
-                metadata_lock: S3FileLock {
-                    s3,
-                    bucket: bucket.clone(),
-                    lock_file: "lockfile".to_owned(),
-                    current_lock: None,
-                },
-                metadata_filename: "metadata".to_owned(),
-                current_lock: None,
-                bucket: bucket.clone(),
-                db_filename: "test.db".to_owned(),
-            })),
+        // Registering as a reader writes the metadata object, which fails while writes are
+        // rejected. In degraded mode, read the database object directly instead.
+        let degraded = self
+            .circuit
+            .degraded(&self.bucket, self.db_filename.as_str());
+        if degraded {
+            Stats::incr(&self.stats.degraded_reads);
+        }
+        // under a lock of a handle, see [crate::session]
+        let held = self.current_lock.is_some();
+        let register = register && !degraded && !held;
+        let mut generation = self.held_generation.filter(|_| held);
+        if register {
+            match latency::timed(Phase::LockWait, self.request_read_lock()).await {
+                Ok(joined) => generation = joined,
+                // giving up on a busy lock fails the read with its diagnosis
+                Err(err @ Error::Busy { .. }) => return Err(err),
+                // may be served from the mirror, see [crate::degraded]
+                Err(err) => {
+                    return Err(Error::MetadataUnavailable {
+                        source: Box::new(err),
+                    })
+                }
+            }
+        }
+        latency::touch(self.db_filename.as_str());
+        let cached = generation.filter(|_| self.cache.enabled() && usage != CacheUse::Bypass);
+        if let (Some(generation), Some(hot)) = (cached, hot.filter(|hot| !hot.is_empty())) {
+            let res = latency::timed(
+                Phase::StorageRead,
+                prefetch::prefetch(self, &self.db_filename, generation, hot),
+            )
+            .await;
+            if let Err(err) = res {
+                tracing::debug!(target: "threeqlite::s3", key = %self.db_filename, %err, "prefetching hot set failed");
+            }
         }
+        let data = self.read_at(offset, len, generation, usage).await;
+        if register {
+            let _ = latency::timed(Phase::LockWait, self.release_read_lock()).await;
+        }
+        data
     }
-}
-
-impl Vfs for ThreeQLite {
-    type Handle = Handle;
-    type Error = crate::error::Error;
 
-    async fn open(
+    /// Read `len` bytes at `offset` of the database as of `generation`, through the page cache
+    /// unless `generation` is unknown or `usage` bypasses it, with the pages of the flushes
+    /// uploading over them, see [crate::inflight]. Fewer bytes are returned past the end of the
+    /// database.
+    pub async fn read_at(
         &self,
-        db: &str,
-        opts: sqlite_vfs::OpenOptions,
-    ) -> Result<Self::Handle, sqlite_vfs::error::Error<Self::Error>> {
-        let sqlite_vfs::OpenOptions { kind, access, .. } = opts;
-
-        match kind {
-            OpenKind::MainDb => {}
-            OpenKind::MainJournal => unimplemented!(),
-            OpenKind::TempDb => unimplemented!(),
-            OpenKind::TempJournal => unimplemented!(),
-            OpenKind::TransientDb => unimplemented!(),
-            OpenKind::SubJournal => unimplemented!(),
-            OpenKind::SuperJournal => unimplemented!(),
-            OpenKind::Wal => unimplemented!(),
+        offset: usize,
+        len: usize,
+        generation: Option<u64>,
+        usage: CacheUse,
+    ) -> Result<Vec<u8>, Error> {
+        if self.in_flight.is_empty() {
+            return self.read_stored(offset, len, generation, usage).await;
         }
+        let mut buf = vec![0; len];
+        if self.in_flight.overlay(offset as u64, &mut buf) {
+            return Ok(buf);
+        }
+        let stored = self.read_stored(offset, len, generation, usage).await?;
+        // the flushes may extend the database
+        let flushed = self
+            .in_flight
+            .end()
+            .map_or(0, |end| end.saturating_sub(offset as u64));
+        buf.truncate(stored.len().max(flushed.min(len as u64) as usize));
+        buf[..stored.len()].copy_from_slice(&stored);
+        self.in_flight.overlay(offset as u64, &mut buf);
+        Ok(buf)
+    }
 
-        match access {
-            OpenAccess::Read => {
-                // TODO: Throw if doesn't exist
-            }
-            OpenAccess::Write => {
-                // TODO: Throw if doesn't exist
+    /// [Inner::read_at] without the pages in flight.
+    async fn read_stored(
+        &self,
+        offset: usize,
+        len: usize,
+        generation: Option<u64>,
+        usage: CacheUse,
+    ) -> Result<Vec<u8>, Error> {
+        // the header is pinned even with the cache disabled
+        let pinned = generation.filter(|_| usage != CacheUse::Bypass);
+        if let Some(generation) = pinned {
+            let db = self.db_filename.as_str();
+            if let Some(header) = self.cache.header(db, generation, offset as u64, len) {
+                return Ok(header);
             }
-            OpenAccess::Create => {
-                // TODO: Ensure created
+        }
+        // only pages of a known generation are cached
+        let cached = generation.filter(|_| self.cache.enabled() && usage != CacheUse::Bypass);
+        if let Some(generation) = cached {
+            let db = self.db_filename.as_str();
+            if let Some(page) = self.cache.get(db, generation, offset as u64, len) {
+                return Ok(page);
             }
-            OpenAccess::CreateNew => {
-                // TODO: Ensure created, throw if already exists
+        } else if self.cache.enabled() {
+            self.cache.bypass();
+        }
+        // pages in the cache stay readable once the budget ran out
+        self.check_budget(OpClass::Read)?;
+        // large reads are split into chunks fetched in parallel, see [crate::fetch]
+        let chunked = len as u64 > self.fetch_config.chunk_size;
+        // the body is received within the timed read, so that the read accounts for all of it
+        let data = latency::timed(Phase::StorageRead, async {
+            if chunked {
+                let ranges = fetch::chunks(offset as u64, len as u64, self.fetch_config.chunk_size);
+                let (chunks, _) =
+                    fetch::fetch(self, &self.db_filename, None, &ranges, IoClass::Critical).await?;
+                return Ok((chunks.concat(), None));
             }
+            let _permit = self.permit(IoClass::Critical).await;
+            let obj = credentials::send(self.credentials.as_deref(), || {
+                self.s3
+                    .get_object()
+                    .bucket(&self.bucket)
+                    .key(&self.db_filename)
+                    .range(format!("bytes={}-{}", offset, offset + len - 1))
+                    .send()
+            })
+            .await;
+            let obj = match obj {
+                Ok(obj) => obj,
+                // starts past the end, e.g. of an empty database, see [crate::create]
+                Err(err) if status(&err) == Some(416) => return Ok((Vec::new(), None)),
+                Err(err) => return Err(err.into()),
+            };
+            // `bytes <first>-<last>/<length>`
+            let total = obj
+                .content_range()
+                .and_then(|range| range.rsplit_once('/'))
+                .and_then(|(_, total)| total.parse().ok());
+            let bytes = obj
+                .body
+                .collect()
+                .await
+                .map_err(|err| Error::Whatever {
+                    message: err.to_string(),
+                    source: Some(Box::new(err)),
+                })?
+                .to_vec();
+            self.charge(Dimension::GetBytes, bytes.len() as u64);
+            Ok::<_, Error>((bytes, total))
+        })
+        .await;
+        if !chunked {
+            self.record(OpClass::Read, data.is_ok());
         }
 
-        Ok(Handle {
-            storage: self.clone(),
-            obj_key: db.to_owned(),
-        })
+        match data {
+            Ok((bytes, total)) => {
+                // a flush uploading may have extended the object already
+                if let Some(actual) = total.filter(|_| self.in_flight.is_empty()) {
+                    self.check_len(actual).await?;
+                }
+                // a short read is left to the caller, and not cached
+                if bytes.len() < len {
+                    return Ok(bytes);
+                }
+                let db = self.db_filename.as_str();
+                if let Some(generation) = pinned {
+                    self.cache.pin_header(db, generation, offset as u64, &bytes);
+                    if let Some(cookie) = schema::cookie(&bytes).filter(|_| offset == 0) {
+                        self.observe_schema(generation, cookie);
+                    }
+                }
+                if let Some(generation) = cached {
+                    self.cache
+                        .insert(db, generation, offset as u64, bytes.clone(), usage);
+                }
+                Ok(bytes)
+            }
+            // reported as such to SQLite
+            Err(err @ Error::CredentialsExpired { .. }) => Err(err),
+            Err(e) => whatever!("Error reading data: {}", e),
+        }
     }
 
-    async fn delete(&self, db: &str) -> Result<(), sqlite_vfs::error::Error<Self::Error>> {
-        // if let Err(e) = self.inner.read().await.s3.get_object().send().await {
-        //     return Err(Error::from_aws(e).into());
-        // }
+    pub async fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.guard(OpClass::Write)?;
+        self.check_budget(OpClass::Write)?;
 
-        Ok(())
-    }
+        if let Err(err @ Error::Busy { .. }) =
+            latency::timed(Phase::LockWait, self.request_write_lock()).await
+        {
+            return Err(err);
+        }
+        latency::touch(self.db_filename.as_str());
+        self.written = true;
+        let res = latency::timed(
+            Phase::StorageWrite,
+            self.s3
+                .put_object()
+                .bucket(&self.bucket)
+                .write_offset_bytes(offset as i64)
+                .key(&self.db_filename)
+                .body(data.to_vec().into())
+                .send(),
+        )
+        .await;
+        self.record(OpClass::Write, res.is_ok());
+        if res.is_ok() {
+            self.charge(Dimension::PutBytes, data.len() as u64);
+        }
+        let written = offset as u64..(offset + data.len()) as u64;
+        self.note_changed(written.clone());
+        self.cache.invalidate(self.db_filename.as_str(), written);
+        let _ = latency::timed(Phase::LockWait, self.release_write_lock()).await;
 
-    async fn exists(&self, db: &str) -> Result<bool, sqlite_vfs::error::Error<Self::Error>> {
-        Ok(true)
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => whatever!("Error writing data: {}", e),
+        }
     }
 
-    async fn temporary_name(&self) -> String {
-        uuid::Uuid::new_v4().to_string()
-    }
+    /// Start a flush of pages, taking the write lock unless this instance holds it already.
+    /// Returns whether it did, for [Inner::finish_flush] to release it again.
+    pub async fn begin_flush(&mut self) -> Result<bool, Error> {
+        self.guard(OpClass::Write)?;
+        self.check_budget(OpClass::Write)?;
 
-    async fn random(&self, buffer: &mut [i8]) {
-        rand::thread_rng().fill(buffer);
+        let held = self.current_lock.is_some();
+        if !held {
+            if let Err(err @ Error::Busy { .. }) =
+                latency::timed(Phase::LockWait, self.request_write_lock()).await
+            {
+                return Err(err);
+            }
+        }
+        latency::touch(self.db_filename.as_str());
+        self.written = true;
+        Ok(!held)
     }
 
-    fn sleep(&self, duration: std::time::Duration) -> std::time::Duration {
-        std::thread::sleep(duration.clone());
-        duration
+    /// The commit barrier of a flush of `pending` to `db` started with [Inner::begin_flush],
+    /// which ended with `res`: record the pages in the page cache, or drop them from it if the
+    /// flush failed, and release the write lock if the flush took it.
+    pub async fn finish_flush(
+        &mut self,
+        db: &ObjectKey,
+        pending: &PendingWrites,
+        res: Result<FlushReport, Error>,
+        took_lock: bool,
+    ) -> Result<FlushReport, Error> {
+        self.record(OpClass::Write, res.is_ok());
+        if let Ok(report) = &res {
+            self.stats
+                .bytes_copied
+                .fetch_add(pending.copied(), Ordering::Relaxed);
+            // recorded as a single request, while each upload is charged
+            let uploads = report.round_trips() as u64;
+            self.charge(Dimension::PutRequests, uploads.saturating_sub(1));
+        }
+        // also if the flush failed, some of the pages may have been written
+        for (offset, data) in pending.iter() {
+            self.note_changed(offset..offset + data.len() as u64);
+        }
+        let key = self.db_filename.as_str();
+        for (offset, data) in pending.iter() {
+            match res {
+                Ok(_) => self.cache.written(key, offset, data),
+                Err(_) => self
+                    .cache
+                    .invalidate(key, offset..offset + data.len() as u64),
+            }
+        }
+        if res.is_ok() {
+            let journal = KeyLayout::journal(db);
+            self.commit_log.record(&journal, CommitStep::PagesDurable);
+        }
+        if took_lock {
+            let _ = latency::timed(Phase::LockWait, self.release_write_lock()).await;
+        }
+        // the pinned header now matches the generation of this commit
+        let generation = self.generation_seen.load(Ordering::Relaxed);
+        self.cache
+            .commit_header(self.db_filename.as_str(), generation);
+        let mut cookie = [0; 4];
+        if res.is_ok() && pending.overlay(schema::COOKIE_OFFSET as u64, &mut cookie) {
+            self.observe_schema(generation, u32::from_be_bytes(cookie));
+        }
+        res
+    }
+
+    /// Record the schema cookie of the database at `generation`, and report a change of it, see
+    /// [crate::schema].
+    fn observe_schema(&self, generation: u64, cookie: u32) {
+        let db = self.db_filename.as_str();
+        let Some(change) = self.schema.observe(db, generation, cookie) else {
+            return;
+        };
+        Stats::incr(&self.stats.schema_changes);
+        tracing::info!(
+            target: "threeqlite::s3",
+            %db,
+            old_cookie = change.old_cookie,
+            new_cookie = change.new_cookie,
+            generation,
+            "schema changed"
+        );
+        self.transactions.schema_changed(&change);
+        self.schema.notify(&change);
+    }
+
+    /// The graph uploading `pending` to the database object, see [crate::flush].
+    pub fn page_flush(&self, db: &ObjectKey, pending: &PendingWrites) -> FlushGraph {
+        let journal = KeyLayout::journal(db);
+        let delta;
+        let pending = match self.delta_config.enabled() {
+            true => {
+                // the write lock is held, so the pages cached at the generation seen are those of
+                // the database object
+                let generation = self.generation_seen.load(Ordering::Relaxed);
+                let key = self.db_filename.as_str();
+                let patched;
+                (delta, patched) = pending.delta(&self.delta_config, |offset, len| {
+                    self.cache.peek(key, generation, offset, len)
+                });
+                self.stats
+                    .patched_pages
+                    .fetch_add(patched, Ordering::Relaxed);
+                &delta
+            }
+            false => pending,
+        };
+        flush::page_graph(pending, |offset, slot| {
+            let (s3, log, journal) = (self.s3.clone(), self.commit_log.clone(), journal.clone());
+            let (stats, faults, payer) = (self.stats.clone(), self.faults.clone(), self.payer());
+            let (bucket, key) = (self.bucket.clone(), self.db_filename.clone());
+            let verify = self.verify_writes.enabled(WriteClass::Pages);
+            async move {
+                let staged = slot
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .expect("staged before the upload");
+                let len = staged.body.len() as u64;
+                let upload = verify.then(|| Upload::in_place(&key, offset, &staged.body));
+                if let Some(faults) = &faults {
+                    faults.upload(&key, offset..offset + len)?;
+                }
+                log.record(&journal, CommitStep::UploadStarted);
+                let res = s3
+                    .put_object()
+                    .bucket(&bucket)
+                    .write_offset_bytes(offset as i64)
+                    .key(&key)
+                    .content_md5(staged.md5)
+                    .body(staged.body.into())
+                    .send()
+                    .await;
+                if let (Ok(_), Some(upload)) = (&res, &upload) {
+                    // before the upload counts as finished, so before the commit barrier
+                    verify::verify_write(&s3, &bucket, &payer, upload, None).await?;
+                }
+                log.record(&journal, CommitStep::UploadFinished);
+                match res {
+                    Ok(_) => {
+                        stats.bytes_uploaded.fetch_add(len, Ordering::Relaxed);
+                        payer.charge(Dimension::PutBytes, len);
+                        Ok(())
+                    }
+                    Err(e) => whatever!("Error writing data: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Find out whether the credentials may write to `key` by creating it, see [crate::probe].
+    pub async fn probe_write(&self, key: &ObjectKey) -> Result<bool, Error> {
+        self.guard(OpClass::Write)?;
+        let res = self
+            .s3
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .if_none_match("*")
+            .body(Vec::new().into())
+            .send()
+            .await;
+        let writable = match &res {
+            Ok(_) => Some(true),
+            Err(err) => match status(err) {
+                Some(403) => Some(false),
+                // only checked after authorization, so someone else's probe proves permission too
+                Some(412) => Some(true),
+                _ => None,
+            },
+        };
+        self.record(OpClass::Write, writable.is_some());
+
+        if res.is_ok() {
+            let deleted = self
+                .s3
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+                .await;
+            if let Err(err) = deleted {
+                tracing::warn!(target: "threeqlite::s3", %key, %err, "failed to delete write probe");
+            }
+        }
+
+        match writable {
+            Some(writable) => {
+                tracing::debug!(target: "threeqlite::s3", %key, writable, "probed write permission");
+                Ok(writable)
+            }
+            None => Err(res.unwrap_err().into()),
+        }
+    }
+
+    /// Check that the object stored by a PUT is what was sent, see [crate::verify].
+    pub async fn verify_put(&self, upload: &Upload, out: &PutObjectOutput) -> Result<(), Error> {
+        let returned = upload.returned_checksum(out);
+        verify::verify_write(&self.s3, &self.bucket, &self.payer(), upload, returned).await?;
+        upload.check_etag(out.e_tag(), out.server_side_encryption())?;
+        if !self.paranoid_commit {
+            return Ok(());
+        }
+
+        let head = self
+            .s3
+            .head_object()
+            .bucket(&self.bucket)
+            .key(&upload.key)
+            .send()
+            .await;
+        self.record(OpClass::Read, head.is_ok());
+        let head = head?;
+        upload.check_len(head.content_length())?;
+        upload.check_etag(head.e_tag(), head.server_side_encryption())
+    }
+
+    /// Detect a missing or reset metadata object next to `db` and repair it, see [crate::heal].
+    pub async fn check_metadata(&self, db: &ObjectKey) -> Result<MetadataHealth, Error> {
+        self.guard(OpClass::Read)?;
+        let head = self
+            .s3
+            .head_object()
+            .bucket(&self.bucket)
+            .key(&self.metadata_filename)
+            .send()
+            .await;
+        let missing = matches!(&head, Err(err) if status(err) == Some(404));
+        self.record(OpClass::Read, head.is_ok() || missing);
+        let seen = self.generation_seen.load(Ordering::Relaxed);
+
+        if missing {
+            let exists = self
+                .s3
+                .head_object()
+                .bucket(&self.bucket)
+                .key(db)
+                .send()
+                .await;
+            match exists {
+                // created and never written, see [crate::create]
+                Ok(head) if head.content_length().unwrap_or(0) == 0 => {
+                    return Ok(MetadataHealth::Healthy)
+                }
+                Ok(_) => {}
+                // a new database, its metadata object is written with the first lock
+                Err(err) if status(&err) == Some(404) => return Ok(MetadataHealth::Healthy),
+                Err(err) => return Err(err.into()),
+            }
+
+            let record = MetadataRecord {
+                stamp: Some(Stamp::new(seen)),
+                ..Default::default()
+            };
+            if self
+                .put_metadata_record(record, Precondition::Absent)
+                .await?
+                .is_none()
+            {
+                // someone else was faster
+                return Ok(MetadataHealth::Healthy);
+            }
+            tracing::warn!(
+                target: "threeqlite::lock_protocol",
+                key = %self.metadata_filename,
+                %db,
+                generation = seen,
+                "metadata object was missing and has been reconstructed without lock holders. \
+                 A bucket lifecycle rule may have expired it: run ThreeQLite::preflight and \
+                 exclude the database's keys from expiration"
+            );
+            return Ok(MetadataHealth::Reconstructed);
+        }
+
+        let Some(mut stamp) = Stamp::from_metadata(head?.metadata()) else {
+            // written before stamps existed
+            return Ok(MetadataHealth::Healthy);
+        };
+        if stamp.quarantined {
+            return Ok(MetadataHealth::Quarantined);
+        }
+        if stamp.generation >= seen {
+            self.generation_seen
+                .fetch_max(stamp.generation, Ordering::Relaxed);
+            return Ok(MetadataHealth::Healthy);
+        }
+
+        tracing::error!(
+            target: "threeqlite::lock_protocol",
+            key = %self.metadata_filename,
+            %db,
+            generation = stamp.generation,
+            seen,
+            "metadata generation went backwards, the object was probably deleted and recreated. \
+             Writes are refused until the database has been verified and \
+             ThreeQLite::clear_quarantine is called"
+        );
+        let record = self.read_metadata_record().await?;
+        stamp.generation = seen;
+        stamp.quarantined = true;
+        self.write_metadata_record(MetadataRecord {
+            stamp: Some(stamp),
+            ..record
+        })
+        .await?;
+        Ok(MetadataHealth::Quarantined)
+    }
+
+    /// Publish the block checksums of the database object `key` for offline mirrors, see
+    /// [crate::mirror]. Large manifests are split into extents, see [crate::extent].
+    pub async fn publish_blocks(
+        &self,
+        db: &ObjectKey,
+        manifest: &BlockManifest,
+    ) -> Result<(), Error> {
+        extent::publish(self, db, manifest).await
+    }
+
+    pub fn mirror(&self, db: &str) -> Option<Arc<Mirror>> {
+        self.mirrors.lock().unwrap().get(db).cloned()
+    }
+
+    pub async fn get_database_size(&mut self) -> Result<i64, snafu::Whatever> {
+        // under a lock of a handle, see [crate::session]
+        let register = self.current_lock.is_none();
+        if register {
+            let _ = latency::timed(Phase::LockWait, self.request_read_lock()).await;
+        }
+        let size = latency::timed(
+            Phase::StorageRead,
+            self.s3
+                .head_object()
+                .bucket(&self.bucket)
+                .key(&self.db_filename)
+                .send(),
+        )
+        .await;
+        if register {
+            let _ = latency::timed(Phase::LockWait, self.release_read_lock()).await;
+        }
+
+        match size {
+            // a missing database reads as an empty file, see [crate::create]
+            Err(e) if status(&e) == Some(404) => Ok(0),
+            Ok(obj) => {
+                if let Some(size) = obj.content_length {
+                    Ok(size)
+                } else {
+                    whatever!("Error getting database size: no content length")
+                }
+            }
+            Err(e) => whatever!("Error getting database size: {}", e),
+        }
+    }
+
+    pub async fn write_metadata(&self, meta: Metadata, stamp: Option<Stamp>) -> Result<(), Error> {
+        self.write_metadata_record(MetadataRecord {
+            metadata: meta,
+            stamp,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Write the metadata object in the newest format all active readers understand.
+    pub async fn write_metadata_record(&self, record: MetadataRecord) -> Result<(), Error> {
+        self.put_metadata_record(record, Precondition::None)
+            .await
+            .map(|_| ())
+    }
+
+    /// Write the metadata object on `precondition`. Returns the response if it was written,
+    /// `None` if the precondition failed.
+    pub async fn put_metadata_record(
+        &self,
+        mut record: MetadataRecord,
+        precondition: Precondition,
+    ) -> Result<Option<PutObjectOutput>, Error> {
+        let stamp = record.stamp.unwrap_or_else(|| {
+            // first write, or the object predates stamps
+            Stamp::new(self.generation_seen.load(Ordering::Relaxed))
+        });
+        let version = format::negotiate(record.active_reader_versions());
+        let current = *format::WRITE_VERSIONS.end();
+        let bytes = if version < current {
+            if record.pending_upgrade.is_none() {
+                tracing::info!(
+                    target: "threeqlite::lock_protocol",
+                    version,
+                    current,
+                    "active readers hold back metadata format upgrade"
+                );
+            }
+            record.pending_upgrade = Some(current);
+            latency::timed(Phase::Encode, async {
+                format::encode(version, &record.metadata)
+            })
+            .await?
+        } else {
+            if record.pending_upgrade.take().is_some() {
+                tracing::info!(
+                    target: "threeqlite::lock_protocol",
+                    version,
+                    "applying pending metadata format upgrade"
+                );
+            }
+            latency::timed(Phase::Encode, async { format::encode(version, &record) }).await?
+        };
+        format::check_single_put(self.metadata_filename.as_str(), &bytes)?;
+
+        let mut user_metadata = stamp.to_metadata();
+        if let Some(request) = &record.write_request {
+            user_metadata.extend(request.to_metadata());
+        }
+        if let Some(holder) = &record.holder {
+            user_metadata.extend(holder.to_metadata());
+        }
+        let upload = Upload::verified(
+            &self.metadata_filename,
+            &bytes,
+            WriteClass::Metadata,
+            &self.verify_writes,
+        );
+        let put = self
+            .s3
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&self.metadata_filename)
+            .set_metadata(Some(user_metadata))
+            .body(bytes.into());
+        let mut put = upload.send_checksum(put);
+        put = match &precondition {
+            Precondition::None => put,
+            Precondition::Absent => put.if_none_match("*"),
+            Precondition::Matches(etag) => put.if_match(etag),
+        };
+        match put.send().await {
+            Ok(out) => {
+                self.verify_put(&upload, &out).await?;
+                Ok(Some(out))
+            }
+            Err(e) if precondition != Precondition::None && status(&e) == Some(412) => Ok(None),
+            Err(e) => whatever!("Error writing metadata: {}", e),
+        }
+    }
+
+    /// Write `record` unless the metadata object changed since `record` was read from it, i.e.
+    /// on its ETag. Returns whether it was written. The lock protocol reads the object again if
+    /// not, since the legal hold on the lock object doesn't keep out an instance that checked it
+    /// at the same time.
+    pub async fn swap_metadata_record(&self, record: MetadataRecord) -> Result<bool, Error> {
+        let precondition = record
+            .etag
+            .clone()
+            .map_or(Precondition::None, Precondition::Matches);
+        let written = self.put_metadata_record(record, precondition).await?;
+        Ok(written.is_some())
+    }
+
+    /// `record`, without its write lock if the writer holding it ran on this machine, its process
+    /// exited, and it left no journal behind, see [crate::busy]. With a journal, the writer may
+    /// have uploaded part of its pages, so the lock stays held rather than let readers see them.
+    async fn without_dead_writer(&self, record: MetadataRecord) -> Result<MetadataRecord, Error> {
+        let process = record.holder.as_ref().and_then(|h| h.process.as_ref());
+        let dead = matches!(record.metadata, Metadata::Writer(_))
+            && self.lock_config.take_over_dead_writers
+            && process.is_some_and(LocalProcess::exited);
+        if !dead {
+            return Ok(record);
+        }
+        let journal = KeyLayout::journal(&self.db_filename);
+        // a recovery rolls the journal back itself
+        if !self.recovering && journal::exists(self, &journal).await? {
+            tracing::warn!(
+                target: "threeqlite::lock_protocol",
+                key = %self.metadata_filename,
+                %journal,
+                process = %process.unwrap(),
+                "the process holding the write lock exited and left a journal, not taking the lock over"
+            );
+            return Ok(record);
+        }
+        tracing::warn!(
+            target: "threeqlite::lock_protocol",
+            key = %self.metadata_filename,
+            holder = record.holder.as_ref().map(|h| h.identity.as_str()),
+            process = %process.unwrap(),
+            "the process holding the write lock exited, taking the lock over"
+        );
+        Stats::incr(&self.stats.dead_writers);
+        Ok(protocol::abandon(record))
+    }
+
+    /// The generation in the metadata object, `None` if it has none yet. Only reads the user
+    /// metadata of the object, not the lock state in its body.
+    pub async fn read_generation(&self) -> Result<Option<u64>, Error> {
+        self.guard(OpClass::Read)?;
+        let head = self
+            .s3
+            .head_object()
+            .bucket(&self.bucket)
+            .key(&self.metadata_filename)
+            .send()
+            .await;
+        let missing = matches!(&head, Err(err) if status(err) == Some(404));
+        self.record(OpClass::Read, head.is_ok() || missing);
+        if missing {
+            return Ok(None);
+        }
+        Ok(Stamp::from_metadata(head?.metadata()).map(|stamp| stamp.generation))
+    }
+
+    pub async fn read_metadata(&self) -> Result<Metadata, Error> {
+        Ok(self.read_metadata_record().await?.metadata)
+    }
+
+    /// The metadata object, empty if there is none yet.
+    pub async fn read_metadata_record(&self) -> Result<MetadataRecord, Error> {
+        let _permit = self.permit(IoClass::Critical).await;
+        match self
+            .s3
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.metadata_filename)
+            .send()
+            .await
+        {
+            Ok(obj) => {
+                let stamp = Stamp::from_metadata(obj.metadata());
+                let write_request = WriteRequest::from_metadata(obj.metadata());
+                let holder = Holder::from_metadata(obj.metadata());
+                let etag = obj.e_tag().map(str::to_owned);
+                if let Some(stamp) = stamp {
+                    self.generation_seen
+                        .fetch_max(stamp.generation, Ordering::Relaxed);
+                }
+                let corrupt = |reason| Error::CorruptMetadata {
+                    key: self.metadata_filename.to_string(),
+                    reason,
+                };
+                format::check_len::<MetadataRecord>(obj.content_length().unwrap_or(0) as u64)
+                    .map_err(corrupt)?;
+                let bytes = obj.body.collect().await.map_err(|err| Error::Whatever {
+                    message: format!("Error reading metadata: {err}"),
+                    source: Some(Box::new(err)),
+                })?;
+                let bytes = bytes.to_vec();
+                if bytes.is_empty() {
+                    return Ok(MetadataRecord {
+                        stamp,
+                        write_request,
+                        holder,
+                        etag,
+                        ..Default::default()
+                    });
+                }
+                let (header, body) = match format::decode_header(&bytes) {
+                    Ok(split) => split,
+                    Err(err @ Error::IncompatibleFormat { .. }) => return Err(err),
+                    Err(err) => return Err(corrupt(err.to_string())),
+                };
+                let record = if header.writer_version == 1 {
+                    MetadataRecord {
+                        metadata: format::parse(body).map_err(corrupt)?,
+                        ..Default::default()
+                    }
+                } else {
+                    format::parse(body).map_err(corrupt)?
+                };
+                Ok(MetadataRecord {
+                    stamp,
+                    write_request,
+                    holder,
+                    etag,
+                    ..record
+                })
+            }
+            // a new database, written with its first lock, see [crate::create]
+            Err(err) if status(&err) == Some(404) => Ok(MetadataRecord::default()),
+            Err(e) => whatever!("Error reading metadata: {}", e),
+        }
+    }
+
+    pub async fn read_metadata_until(
+        &self,
+        until: Box<dyn Fn(Metadata) -> bool>,
+        poll_delay: std::time::Duration,
+    ) -> Result<Metadata, Error> {
+        loop {
+            let metadata = self.read_metadata().await?;
+            if until(metadata.clone()) {
+                return Ok(metadata);
+            }
+            tokio::time::sleep(poll_delay).await;
+        }
+    }
+
+    /// Release the lock of the metadata object taken for `held`, the result of what ran while
+    /// holding it, whether that succeeded or not. An error of `held` is returned over one of the
+    /// release.
+    async fn release_metadata_lock<T>(&mut self, held: Result<T, Error>) -> Result<T, Error> {
+        let released = self.metadata_lock.release_lock().await;
+        let held = held?;
+        released?;
+        Ok(held)
+    }
+
+    pub async fn release_read_lock(&mut self) -> Result<(), Error> {
+        let lock_uuid = self.current_lock.clone();
+        let left = loop {
+            self.metadata_lock.request_lock().await?;
+            let held = async {
+                let record = self.read_metadata_record().await?;
+                Ok(match &lock_uuid {
+                    Some(id) => match protocol::leave(record, id) {
+                        Some(record) => self.swap_metadata_record(record).await?.then_some(true),
+                        None => Some(false),
+                    },
+                    None => Some(false),
+                })
+            }
+            .await;
+            // `None` if the metadata object changed since it was read
+            if let Some(left) = self.release_metadata_lock(held).await? {
+                break left;
+            }
+        };
+        // kept until the reader is gone from the metadata object, so that a failed release can be
+        // retried
+        self.current_lock = None;
+        self.held_generation = None;
+        self.recorded_len = None;
+        if !left {
+            whatever!("Error releasing read lock, no reader metadata found")
+        }
+        Ok(())
+    }
+
+    /// Take note of the stamp of the generation a reader joins at, returning the generation.
+    pub fn join_stamp(&mut self, stamp: Option<Stamp>) -> Option<u64> {
+        self.recorded_len = stamp.and_then(|stamp| stamp.len);
+        stamp.map(|stamp| stamp.generation)
+    }
+
+    /// The commit following `stamp`, recording the length and ETag of the database object if it
+    /// was written, see [crate::heal] and [crate::receipt].
+    pub async fn commit_stamp(&mut self, stamp: Option<Stamp>) -> Commit {
+        let mut stamp =
+            stamp.unwrap_or_else(|| Stamp::new(self.generation_seen.load(Ordering::Relaxed)));
+        let mut content_etag = None;
+        if std::mem::take(&mut self.written) {
+            let head = self
+                .s3
+                .head_object()
+                .bucket(&self.bucket)
+                .key(&self.db_filename)
+                .send()
+                .await;
+            self.record(OpClass::Read, head.is_ok());
+            // an unknown length only skips the next checks
+            stamp.len = head
+                .as_ref()
+                .ok()
+                .and_then(|head| head.content_length())
+                .map(|len| len as u64);
+            content_etag = head.ok().and_then(|head| head.e_tag);
+        }
+        let committed_at = self.clock.now_ms();
+        stamp.advance(committed_at, content_etag.as_deref());
+        stamp.commit = Some(uuid::Uuid::new_v4());
+        Commit {
+            stamp,
+            committed_at,
+            content_etag,
+        }
+    }
+
+    /// Compare `actual`, the length the database object reported to a read, against the one
+    /// recorded at the generation the reader joined at. A mismatch quarantines the database, see
+    /// [crate::heal]. Writes of the running transaction change the length themselves.
+    pub async fn check_len(&self, actual: u64) -> Result<(), Error> {
+        let Some(expected) = (self.recorded_len).filter(|len| *len != actual && !self.written)
+        else {
+            return Ok(());
+        };
+        // the cached pages are of the same generation, but no longer of the same object
+        self.cache
+            .invalidate(self.db_filename.as_str(), 0..u64::MAX);
+        let record = self.read_metadata_record().await?;
+        let mut stamp = record
+            .stamp
+            .unwrap_or_else(|| Stamp::new(self.generation_seen.load(Ordering::Relaxed)));
+        if stamp.external_len != Some(actual) {
+            tracing::error!(
+                target: "threeqlite::lock_protocol",
+                key = %self.db_filename,
+                expected,
+                actual,
+                "database object was modified out-of-band. Writes are refused until the object \
+                 has been restored or ThreeQLite::accept_external_state is called"
+            );
+            stamp.quarantined = true;
+            stamp.external_len = Some(actual);
+            self.write_metadata_record(MetadataRecord {
+                stamp: Some(stamp),
+                ..record
+            })
+            .await?;
+        }
+        if self.read_externally_modified {
+            return Ok(());
+        }
+        Err(Error::ExternalModification {
+            key: self.db_filename.to_string(),
+            expected,
+            actual,
+        })
+    }
+
+    /// Record the commit following `read`, the record holding the write lock `lock_uuid`, in the
+    /// metadata object, leaving it unlocked. The write is conditional on the ETag `read` was read
+    /// at and idempotent, see [crate::idempotency]. Returns the receipt of the commit, see
+    /// [crate::receipt].
+    pub async fn record_commit(
+        &mut self,
+        read: MetadataRecord,
+        lock_uuid: &[u8],
+    ) -> Result<CommitReceipt, Error> {
+        let commit = self.commit_stamp(read.stamp).await;
+        let record_etag =
+            idempotency::record_commit(self, commit.stamp, read.etag, lock_uuid).await?;
+        self.generation_seen
+            .fetch_max(commit.stamp.generation, Ordering::Relaxed);
+        Ok(commit.receipt(self.db_filename.as_str(), record_etag))
+    }
+
+    /// Note that the database object changed at `range` under the held write lock.
+    pub fn note_changed(&mut self, range: Range<u64>) {
+        if let Some(changed) = &mut self.changed {
+            changed.push(range);
+        }
+    }
+
+    /// Publish the commit of `generation` to the [InvalidationBus] of the instance, if it has
+    /// one, with the ranges written since the last.
+    fn publish_commit(&mut self, generation: u64) {
+        let changed = self.changed.replace(vec![]);
+        let Some(bus) = self.bus.lock().unwrap().clone() else {
+            return;
+        };
+        bus.publish(GenerationUpdate {
+            bucket: self.bucket.clone(),
+            db: self.db_filename.to_string(),
+            generation,
+            changed,
+        });
+    }
+
+    pub async fn release_write_lock(&mut self) -> Result<(), Error> {
+        self.held_generation = None;
+        self.metadata_lock.request_lock().await?;
+        let held = async {
+            let record = self.read_metadata_record().await?;
+            match (&record.metadata, self.current_lock.clone()) {
+                (Metadata::Writer(lock_uuid), Some(current_lock)) if current_lock == *lock_uuid => {
+                    // the write transaction is over
+                    self.record_commit(record, &current_lock).await.map(Some)
+                }
+                _ => Ok(None),
+            }
+        }
+        .await;
+        let receipt = self.release_metadata_lock(held).await?;
+        self.current_lock = None;
+        let Some(receipt) = receipt else {
+            whatever!("Error releasing write lock, no writer metadata found")
+        };
+        let generation = receipt.generation;
+        self.transactions.committed(receipt);
+        self.publish_commit(generation);
+        Ok(())
+    }
+
+    /// Register as a reader, waiting while a writer holds or requested the lock, see
+    /// [crate::protocol]. Returns the generation registered at, if the metadata object has one.
+    pub async fn request_read_lock(&mut self) -> Result<Option<u64>, Error> {
+        let lock_uuid = uuid::Uuid::new_v4().to_bytes_le();
+        let start = Instant::now();
+        let mut poller = Poller::new(&self.lock_config, start, self.stats.clone());
+
+        let generation = loop {
+            self.check_clock();
+            self.metadata_lock.request_lock().await?;
+            let held = async {
+                let record = self
+                    .without_dead_writer(self.read_metadata_record().await?)
+                    .await?;
+                let decision = protocol::reader_decision(&record, self.clock.now_ms());
+                if decision != ReaderDecision::Join {
+                    return Ok((record, None));
+                }
+                let stamp = record.stamp;
+                let joined = self
+                    .swap_metadata_record(protocol::join(record.clone(), &lock_uuid))
+                    .await?;
+                Ok((record, Some((joined, stamp))))
+            }
+            .await;
+            let (record, joined) = self.release_metadata_lock(held).await?;
+            match joined {
+                // another instance changed the metadata object since it was read
+                Some((false, _)) => continue,
+                Some((true, stamp)) => break self.join_stamp(stamp),
+                None => {}
+            }
+
+            Stats::incr(&self.stats.reader_defers);
+            self.check_busy(&record, start)?;
+            let wait = poller.next(&record, Instant::now(), self.clock.now_ms());
+            tokio::time::sleep(wait).await;
+        };
+        self.current_lock = Some(lock_uuid.to_vec());
+        Ok(generation)
+    }
+
+    /// Take the write lock, requesting it while readers are active, see [crate::protocol]. A
+    /// reader of this instance upgrades: it doesn't wait for itself, and stays registered until
+    /// the write that takes the lock replaces it.
+    pub async fn request_write_lock(&mut self) -> Result<(), Error> {
+        burst::assert_fresh();
+        let reading = self.current_lock.clone();
+        // an upgrade keeps its ID, so that the request of an attempt that was busy is its own
+        let lock_uuid = reading
+            .clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_bytes_le().to_vec());
+        let start = Instant::now();
+        let since = self.clock.now_ms();
+        let mut poller = Poller::new(&self.lock_config, start, self.stats.clone());
+        let process = LocalProcess::current();
+
+        loop {
+            // a request of this writer that ran out during a jump is renewed by the decision
+            self.check_clock();
+            self.metadata_lock.request_lock().await?;
+            let held = async {
+                let record = self
+                    .without_dead_writer(self.read_metadata_record().await?)
+                    .await?;
+
+                if let Some(stamp) = record.stamp.filter(|stamp| stamp.quarantined) {
+                    if let Some(actual) = stamp.external_len {
+                        return Err(Error::ExternalModification {
+                            key: self.db_filename.to_string(),
+                            expected: stamp.len.unwrap_or_default(),
+                            actual,
+                        });
+                    }
+                    return Err(Error::Quarantined {
+                        key: self.metadata_filename.to_string(),
+                    });
+                }
+
+                let others = reading
+                    .as_deref()
+                    .and_then(|id| protocol::leave(record.clone(), id));
+                let decision = protocol::writer_decision(
+                    others.as_ref().unwrap_or(&record),
+                    &lock_uuid,
+                    self.clock.now_ms(),
+                    &self.lock_config,
+                );
+                let acquired = decision == WriterDecision::Acquire;
+                let holder = Holder {
+                    identity: registration::identity(&self.lock_config.identity),
+                    hostname: crate::config::hostname(),
+                    since,
+                    epoch: record.stamp.map_or(0, |stamp| stamp.generation),
+                    // only once acquired, so that the holder of a waiting writer stays the same
+                    expected_release: acquired
+                        .then(|| wait::expected_release(self.clock.now_ms()))
+                        .flatten(),
+                    process: process.clone(),
+                };
+                let waiting_for = record.clone();
+                let written = match decision {
+                    WriterDecision::Acquire => Some(MetadataRecord {
+                        holder: Some(holder),
+                        ..protocol::acquire(record, &lock_uuid)
+                    }),
+                    WriterDecision::Request(request) => {
+                        tracing::debug!(
+                            target: "threeqlite::lock_protocol",
+                            expires = request.expires,
+                            "requesting write lock"
+                        );
+                        Some(MetadataRecord {
+                            holder: Some(holder),
+                            ..protocol::request(record, request)
+                        })
+                    }
+                    WriterDecision::Wait => None,
+                };
+                let written = match written {
+                    Some(record) => self.swap_metadata_record(record).await?,
+                    None => true,
+                };
+                Ok((waiting_for, acquired, written))
+            }
+            .await;
+            let (waiting_for, acquired, written) = self.release_metadata_lock(held).await?;
+            // another instance changed the metadata object since it was read
+            if !written {
+                continue;
+            }
+            if acquired {
+                break;
+            }
+
+            // an abandoned request expires with its lease
+            self.check_busy(&waiting_for, start)?;
+            let wait = poller.next(&waiting_for, Instant::now(), self.clock.now_ms());
+            tokio::time::sleep(wait).await;
+        }
+        self.stats.writer_wait.record(start.elapsed());
+        self.current_lock = Some(lock_uuid.to_vec());
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct ThreeQLite {
+    pub inner: Arc<RwLock<Inner>>,
+    /// The name this instance was registered with SQLite as.
+    pub name: Arc<OnceLock<String>>,
+    /// See [Config::strict_file_control].
+    pub file_controls: Option<Arc<FileControlCoverage>>,
+    /// See [Config::capture].
+    pub capture: Option<Arc<Capture>>,
+    #[cfg(feature = "asyncdb")]
+    pub workers: Arc<crate::asyncdb::Workers>,
+    /// Imports of warm sets running, see [crate::warm].
+    pub warming: Arc<tokio::sync::watch::Sender<usize>>,
+    /// Buffered pages and unsynced journals, for the commit points of the databases, see
+    /// [crate::durability].
+    pub barriers: Arc<Barriers>,
+    /// See [Config::connection].
+    pub connection_defaults: ConnectionDefaults,
+    /// The registration this clone was registered as, `None` for an instance not registered, see
+    /// [crate::registration].
+    pub registration: Option<Arc<Registration>>,
+    /// Every registration of the instance, by name.
+    pub registrations: Arc<std::sync::Mutex<HashMap<String, Arc<Registration>>>>,
+    /// Maintenance operations running and recently finished, see [crate::operation].
+    pub operations: Arc<Operations>,
+    /// What the guarantees of the instance are computed from, see [crate::guarantees].
+    pub setup: Arc<std::sync::Mutex<Setup>>,
+    /// The handles open, see [crate::shutdown].
+    pub handles: Arc<Handles>,
+    /// The locks of the handles open on each database, see [crate::session].
+    pub sessions: Arc<Sessions>,
+    /// See [Config::shutdown].
+    pub shutdown_config: ShutdownConfig,
+    /// Logs dropping the instance while registered, `None` for the clone SQLite has, see
+    /// [crate::shutdown].
+    pub owner: Option<Arc<Owner>>,
+}
+
+impl ThreeQLite {
+    pub async fn new() -> Result<Self, Error> {
+        Self::with_config(Config::default()).await
+    }
+
+    pub async fn with_config(config: Config) -> Result<Self, Error> {
+        let sdk_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+        let s3 = aws_sdk_s3::Client::new(&sdk_config);
+        match sdk_config.credentials_provider() {
+            Some(provider) => Self::with_credentials(config, s3, provider),
+            None => Self::with_client(config, s3),
+        }
+    }
+
+    /// Create an instance talking to the object store through `s3`. Fails if the configuration
+    /// names an invalid object key, see [crate::key], or sets inconsistent timeouts, see
+    /// [TimeoutConfig::validate].
+    pub fn with_client(config: Config, s3: aws_sdk_s3::Client) -> Result<Self, Error> {
+        Self::build(config, s3, None)
+    }
+
+    /// Like [ThreeQLite::with_client], with `s3` signing its requests with the credentials of
+    /// `provider`, reloaded as soon as the store rejects them, see [crate::credentials].
+    pub fn with_credentials(
+        config: Config,
+        s3: aws_sdk_s3::Client,
+        provider: SharedCredentialsProvider,
+    ) -> Result<Self, Error> {
+        Self::build(config, s3, Some(provider))
+    }
+
+    fn build(
+        config: Config,
+        s3: aws_sdk_s3::Client,
+        provider: Option<SharedCredentialsProvider>,
+    ) -> Result<Self, Error> {
+        let setup = Setup::new(&config);
+        let registrations: Arc<std::sync::Mutex<HashMap<_, _>>> = Arc::default();
+        let lock_file = KeyLayout::lock(&config)?;
+        let metadata_filename = KeyLayout::metadata(&config)?;
+        let db_filename = KeyLayout::db(&config.db_filename)?;
+        let file_controls = config
+            .strict_file_control
+            .then(|| Arc::new(FileControlCoverage::default()));
+        let timeouts = config
+            .timeouts
+            .unwrap_or_else(|| TimeoutConfig::derived(config.fetch.attempt_timeout));
+        if let Err(reason) = timeouts.validate() {
+            return Err(Error::InvalidConfig { reason });
+        }
+        let stats = Arc::new(Stats {
+            file_controls: file_controls.clone(),
+            ..Stats::default()
+        });
+        let credentials = provider.map(|provider| Arc::new(RefreshingCredentials::new(provider)));
+        let region = Arc::new(RegionPin::default());
+        let exchanges = Arc::new(ExchangeLog::default());
+        let s3 = match &credentials {
+            Some(credentials) => aws_sdk_s3::Client::from_conf(
+                s3.config()
+                    .to_builder()
+                    .credentials_provider(SharedCredentialsProvider::new(
+                        credentials.clone() as Arc<dyn ProvideCredentials>
+                    ))
+                    // cached by `credentials` instead
+                    .identity_cache(IdentityCache::no_cache())
+                    .interceptor(CredentialWatch(credentials.clone()))
+                    .build(),
+            ),
+            None => s3,
+        };
+        #[cfg(feature = "chaos")]
+        let chaos = Arc::new(Chaos::new(stats.clone()));
+        #[cfg(feature = "chaos")]
+        let s3 = ChaosStorage::wrap(&s3, chaos.clone());
+        let s3 = aws_sdk_s3::Client::from_conf(
+            s3.config()
+                .to_builder()
+                .interceptor(Metering {
+                    stats: stats.clone(),
+                })
+                .interceptor(RegionWatch(region.clone()))
+                .interceptor(ExchangeWatch(exchanges.clone()))
+                .build(),
+        );
+        let client = |class| timeouts::client(&s3, class, timeouts.profile(class), &stats);
+        let (page_client, bulk_client) =
+            (client(TimeoutClass::PageRead), client(TimeoutClass::Bulk));
+        let s3 = client(TimeoutClass::Metadata);
+        let memory = Arc::new(MemoryBudget::new(config.memory_cap));
+        let cache = Arc::new(PageCache::with_budget(config.cache, memory.clone()));
+        cache.add_reliefs();
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Inner {
+                s3: s3.clone(),
+                page_client,
+                bulk_client,
+                timeouts,
+                credentials,
+                metadata_lock: MetadataLock::new(
+                    s3,
+                    config.bucket.clone(),
+                    lock_file,
+                    &config.lock,
+                ),
+                metadata_filename,
+                current_lock: None,
+                held_generation: None,
+                recovering: false,
+                bucket: config.bucket,
+                db_filename,
+                circuit: Arc::new(CircuitBreaker::with_overrides(
+                    config.circuit,
+                    config.database_circuits,
+                )),
+                stats,
+                cache,
+                memory,
+                probes: Arc::new(WriteProbes::new(config.write_probe)),
+                burst: Arc::new(OpenBurst::new(config.open_burst)),
+                transactions: Arc::new(Transactions::default()),
+                schema: Arc::new(SchemaWatch::default()),
+                generation_seen: Arc::new(AtomicU64::new(0)),
+                paranoid_commit: config.paranoid_commit,
+                verify_writes: config.verify_writes,
+                region,
+                region_config: config.region,
+                exchanges,
+                lock_config: config.lock,
+                watch_config: config.watch,
+                fetch_config: config.fetch,
+                prefetch_config: config.prefetch,
+                transaction_limits: config.limits,
+                spend: Arc::new(Spend::new(config.spend)),
+                delta_config: config.delta,
+                limiter: Arc::new(Limiter::new(config.priority)),
+                mirrors: Arc::default(),
+                super_journals: Arc::default(),
+                commit_log: Arc::default(),
+                in_flight: Arc::default(),
+                reconcile_config: config.reconcile,
+                manifest_config: config.manifest,
+                extents: Arc::default(),
+                degraded_reads: config.degraded_reads,
+                read_externally_modified: config.read_externally_modified,
+                recorded_len: None,
+                written: false,
+                changed: Some(vec![]),
+                bus: Arc::default(),
+                cursors: Arc::default(),
+                roles: Arc::new(Roles::new(config.roles)),
+                clock: Arc::new(Clock::new(config.clock)),
+                faults: None,
+                #[cfg(feature = "chaos")]
+                chaos,
+            })),
+            name: Arc::new(OnceLock::new()),
+            file_controls,
+            capture: config.capture.then(|| Arc::new(Capture::default())),
+            #[cfg(feature = "asyncdb")]
+            workers: Default::default(),
+            warming: Arc::new(tokio::sync::watch::Sender::new(0)),
+            barriers: Arc::new(Barriers::new(config.synchronous)),
+            connection_defaults: config.connection,
+            registration: None,
+            registrations: registrations.clone(),
+            operations: Arc::default(),
+            setup: Arc::new(std::sync::Mutex::new(setup)),
+            handles: Arc::default(),
+            sessions: Arc::default(),
+            shutdown_config: config.shutdown,
+            owner: Some(Arc::new(Owner::new(registrations))),
+        })
+    }
+
+    /// What the configuration of this instance guarantees, as of the last [Self::preflight].
+    pub fn guarantees(&self) -> Guarantees {
+        Guarantees::of(&self.setup.lock().unwrap())
+    }
+
+    /// Register this instance with SQLite as the VFS `name`.
+    pub fn register(&self, name: &str, as_default: bool) -> Result<(), RegisterError> {
+        self.register_as(RegistrationConfig::new(name), as_default)
+    }
+
+    /// Register this instance with SQLite under the name and with the settings of `config`. An
+    /// instance can be registered under several names, see [crate::registration].
+    pub fn register_as(
+        &self,
+        config: RegistrationConfig,
+        as_default: bool,
+    ) -> Result<(), RegisterError> {
+        self.register_handle(config, as_default)
+            .map(VfsHandle::leak)
+    }
+
+    /// [Self::register_as], returning the handle that unregisters it.
+    pub(crate) fn register_handle(
+        &self,
+        config: RegistrationConfig,
+        as_default: bool,
+    ) -> Result<VfsHandle, RegisterError> {
+        let name = config.name.clone();
+        let vfs = self.registered(config);
+        let handle = match self.instrumentation() {
+            Some(instrumentation) => {
+                sqlite_vfs::register_instrumented(&name, vfs, as_default, instrumentation)?
+            }
+            None => sqlite_vfs::register(&name, vfs, as_default)?,
+        };
+        let _ = self.name.set(name);
+        Ok(handle)
+    }
+
+    /// A clone of this instance carrying a new registration as `config`.
+    pub(crate) fn registered(&self, config: RegistrationConfig) -> Self {
+        let registration = Arc::new(Registration::new(config));
+        tracing::info!(
+            target: "threeqlite::s3",
+            name = registration.config.name,
+            guarantees = %self.guarantees(),
+            "registered"
+        );
+        self.registrations
+            .lock()
+            .unwrap()
+            .insert(registration.config.name.clone(), registration.clone());
+        Self {
+            registration: Some(registration),
+            owner: None,
+            ..self.clone()
+        }
+    }
+
+    /// Register this instance as the VFS `name` through `vfs_register`, see
+    /// [sqlite_vfs::register_with].
+    pub fn register_with(
+        &self,
+        name: &str,
+        as_default: bool,
+        vfs_register: VfsRegister,
+    ) -> Result<(), RegisterError> {
+        sqlite_vfs::register_with(
+            name,
+            self.registered(RegistrationConfig::new(name)),
+            as_default,
+            self.instrumentation(),
+            vfs_register,
+        )?;
+        let _ = self.name.set(name.to_owned());
+        Ok(())
+    }
+
+    /// Upload the pages `pending` written through a handle of `db` under a single write lock, as
+    /// concurrently as their order allows, see [crate::flush]. A write lock this instance holds
+    /// already is kept. The state of the database is only held to start the flush and at its
+    /// commit barrier, while reads see the pages uploading, see [crate::inflight].
+    pub async fn flush_pages(
+        &self,
+        db: &ObjectKey,
+        pending: &Arc<PendingWrites>,
+    ) -> Result<FlushReport, Error> {
+        inflight::scope(async {
+            let (graph, took_lock, flight) = {
+                let mut inner = Exclusive::acquire(&self.inner).await;
+                // boxed, keeping the future of a flush small enough for the stack
+                let took_lock = Box::pin(inner.begin_flush()).await?;
+                let flight = inner.in_flight.start(pending.clone());
+                (inner.page_flush(db, pending), took_lock, flight)
+            };
+            let res = inflight::unlocked(latency::timed(Phase::StorageWrite, graph.run())).await;
+            let mut inner = Exclusive::acquire(&self.inner).await;
+            let res = Box::pin(inner.finish_flush(db, pending, res, took_lock)).await;
+            // withdrawn once the page cache has the pages, or dropped them
+            drop(flight);
+            res
+        })
+        .await
+    }
+
+    /// The stats of the instance, over all of its registrations.
+    pub async fn stats(&self) -> StatsSnapshot {
+        self.inner.read().await.stats()
+    }
+
+    /// The faults injected into the requests of the instance, see [crate::chaos].
+    #[cfg(feature = "chaos")]
+    pub async fn chaos(&self) -> Arc<Chaos> {
+        self.inner.read().await.chaos.clone()
+    }
+
+    /// What the instance spent against its budget, see [crate::spend].
+    pub async fn budget(&self) -> Arc<Spend> {
+        self.inner.read().await.spend.clone()
+    }
+
+    /// Start the budget of the instance over from nothing spent, see [crate::spend].
+    pub async fn reset_budget(&self) {
+        self.inner.read().await.spend.reset();
+        tracing::info!(target: "threeqlite::budget", "budget reset");
+    }
+
+    /// The stats of this instance in the OpenMetrics text format, for scraping by Prometheus, see
+    /// [crate::export].
+    #[cfg(feature = "metrics-export")]
+    pub async fn render_prometheus(&self) -> String {
+        let inner = self.inner.read().await;
+        let snapshot = inner.stats();
+        let operations = self.operations.list();
+        crate::export::render(
+            inner.db_filename.as_str(),
+            &snapshot,
+            &inner.stats,
+            &operations,
+        )
+    }
+
+    /// The stats of the handles opened through the registration `name`.
+    pub fn registration_stats(&self, name: &str) -> Option<RegistrationSnapshot> {
+        let registrations = self.registrations.lock().unwrap();
+        registrations
+            .get(name)
+            .map(|registration| registration.snapshot())
+    }
+
+    /// Maintenance operations running, then those finished most recently, see
+    /// [crate::operation].
+    pub fn operations(&self) -> Vec<ProgressSnapshot> {
+        self.operations.list()
+    }
+
+    /// The maintenance operation `id`, if running or finished recently.
+    pub fn operation(&self, id: u64) -> Option<ProgressSnapshot> {
+        self.operations.get(id)
+    }
+
+    /// Cancel the maintenance operation `id`, see [crate::operation]. Returns whether it was
+    /// running.
+    pub fn cancel_operation(&self, id: u64) -> bool {
+        self.operations.cancel(id)
+    }
+
+    /// The state of the credentials of the client, see [crate::credentials].
+    pub async fn credential_health(&self) -> Option<CredentialHealth> {
+        let inner = self.inner.read().await;
+        inner.credentials.as_ref().map(|c| c.health())
+    }
+
+    /// Time recent writers waited for the write lock, see [crate::protocol].
+    pub async fn writer_wait(&self) -> LatencySummary {
+        self.inner.read().await.stats.writer_wait.summary()
+    }
+
+    /// Time spent in `phase` by recent transactions on any database of this instance.
+    pub async fn phase_latency(&self, phase: Phase) -> LatencySummary {
+        self.inner.read().await.stats.phase_latency(phase)
+    }
+
+    /// Time recent reads of `class` waited for a permit, see [crate::priority].
+    pub async fn queue_wait(&self, class: IoClass) -> LatencySummary {
+        self.inner.read().await.stats.queue_wait(class)
+    }
+
+    /// Publish the commits of this instance to `bus`, see [crate::bus]. Watchers created from
+    /// now on also subscribe to it.
+    pub async fn publish_commits(&self, bus: Arc<dyn InvalidationBus>) {
+        *self.inner.read().await.bus.lock().unwrap() = Some(bus);
+    }
+
+    /// Call `observer` with the latency breakdown of every finished transaction.
+    pub async fn observe_transactions(&self, observer: Arc<dyn TransactionObserver>) {
+        self.inner.read().await.transactions.observe(observer);
+    }
+
+    /// Call `callback` in a task of its own whenever a generation of a database changed its
+    /// schema, see [crate::schema].
+    pub async fn on_schema_change<F, Fut>(&self, callback: F)
+    where
+        F: Fn(SchemaChange) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let callback: SchemaCallback = Arc::new(move |change| Box::pin(callback(change)));
+        self.inner.read().await.schema.subscribe(callback);
+    }
+
+    /// The latency breakdown and the objects touched by the last finished transaction on `db`. A
+    /// debugging aid for questions like "why did this commit take 900 ms".
+    pub async fn explain_last_transaction(&self, db: &str) -> Option<TransactionBreakdown> {
+        self.inner.read().await.transactions.last(db)
+    }
+
+    /// The receipt of the last commit of this instance on `db`, see [crate::receipt].
+    pub async fn last_commit_receipt(&self, db: &str) -> Option<CommitReceipt> {
+        self.inner.read().await.transactions.last_receipt(db)
+    }
+
+    /// Whether the lineage of `db` still contains the commit of `receipt`, see [crate::receipt].
+    pub async fn verify_receipt(
+        &self,
+        db: &str,
+        receipt: &CommitReceipt,
+    ) -> Result<ReceiptStatus, Error> {
+        let db = KeyLayout::db(db)?;
+        let inner = self.inner.read().await;
+        if db != inner.db_filename || receipt.db != db.as_str() {
+            return Ok(ReceiptStatus::Unverifiable);
+        }
+        let record = inner.read_metadata_record().await?;
+        Ok(match record.stamp {
+            Some(stamp) => ReceiptStatus::of(receipt, &stamp),
+            // lost, and not reconstructed yet
+            None => ReceiptStatus::Unverifiable,
+        })
+    }
+
+    /// Warn about bucket lifecycle rules that would expire the objects of this instance, which
+    /// silently resets the lock state once they hit the metadata object, and about a bucket
+    /// without the Object Lock the lock object needs. Returns the IDs of the offending rules. The
+    /// findings downgrade [Self::guarantees].
+    pub async fn preflight(&self) -> Result<Vec<String>, Error> {
+        // before anything else, which would fail in the wrong region
+        let region = match self.resolve_region().await {
+            Ok(found) => {
+                tracing::info!(target: "threeqlite::s3", region = %found, "bucket region");
+                Some(found.region)
+            }
+            Err(err) => {
+                tracing::debug!(target: "threeqlite::s3", %err, "couldn't resolve the bucket region");
+                None
+            }
+        };
+        let inner = self.inner.read().await;
+        if let Some(health) = inner.credentials.as_ref().map(|c| c.health()) {
+            match health.healthy() {
+                true => tracing::info!(target: "threeqlite::s3", %health, "credentials"),
+                false => tracing::warn!(
+                    target: "threeqlite::s3",
+                    %health,
+                    "credentials failed to load; requests fail with SQLITE_AUTH until they do"
+                ),
+            }
+        }
+        let res = inner
+            .s3
+            .get_object_lock_configuration()
+            .bucket(&inner.bucket)
+            .send()
+            .await;
+        let object_lock = match res {
+            Ok(config) => Some(
+                config
+                    .object_lock_configuration
+                    .and_then(|config| config.object_lock_enabled)
+                    == Some(ObjectLockEnabled::Enabled),
+            ),
+            // never enabled
+            Err(err) if status(&err) == Some(404) => Some(false),
+            Err(err) => {
+                tracing::debug!(target: "threeqlite::s3", %err, "couldn't check Object Lock");
+                None
+            }
+        };
+        if object_lock == Some(false) && inner.lock_config.backend == LockBackend::LegalHold {
+            tracing::warn!(
+                target: "threeqlite::lock_protocol",
+                bucket = inner.bucket,
+                "bucket has no Object Lock; writes fail, as the lock object can't be held, unless \
+                 the lock backend is conditional"
+            );
+        }
+        let mut findings = Preflight {
+            object_lock,
+            expiring_rules: vec![],
+            region,
+        };
+
+        let res = inner
+            .s3
+            .get_bucket_lifecycle_configuration()
+            .bucket(&inner.bucket)
+            .send()
+            .await;
+        let rules = match res {
+            Ok(config) => config.rules.unwrap_or_default(),
+            // no lifecycle configuration at all
+            Err(err) if status(&err) == Some(404) => vec![],
+            Err(err) => return Err(err.into()),
+        };
+
+        let keys = [
+            inner.db_filename.as_str(),
+            inner.metadata_lock.lock_file().as_str(),
+            inner.metadata_filename.as_str(),
+        ];
+        let matching = heal::expiring_rules(&rules, &keys);
+        for rule in &matching {
+            tracing::warn!(
+                target: "threeqlite::lock_protocol",
+                bucket = inner.bucket,
+                rule,
+                "bucket lifecycle rule expires objects of this database; exclude its keys from \
+                 the rule, or the lock state is lost once the metadata object expires"
+            );
+        }
+        findings.expiring_rules = matching.clone();
+        self.setup.lock().unwrap().preflight = Some(findings);
+        Ok(matching)
+    }
+
+    /// Allow writes to a database quarantined by [Inner::check_metadata] again. Only call this
+    /// once the database has been verified, e.g. with [crate::integrity].
+    pub async fn clear_quarantine(&self) -> Result<(), Error> {
+        let inner = self.inner.read().await;
+        let record = inner.read_metadata_record().await?;
+        let Some(mut stamp) = record.stamp.filter(|stamp| stamp.quarantined) else {
+            return Ok(());
+        };
+        stamp.quarantined = false;
+        stamp.external_len = None;
+        inner
+            .write_metadata_record(MetadataRecord {
+                stamp: Some(stamp),
+                ..record
+            })
+            .await?;
+        tracing::info!(
+            target: "threeqlite::lock_protocol",
+            key = %inner.metadata_filename,
+            generation = stamp.generation,
+            "quarantine cleared"
+        );
+        Ok(())
+    }
+
+    /// Accept the database object `db` as it is after an out-of-band modification: record its
+    /// length, rebuild its block manifest and lift the quarantine, see [crate::heal]. Commits the
+    /// next generation, so that no instance serves pages it cached before. Only call this once
+    /// the operator confirmed that the object holds the desired state. Returns its length.
+    pub async fn accept_external_state(&self, db: &str) -> Result<u64, Error> {
+        let key = KeyLayout::db(db)?;
+        let inner = self.inner.read().await;
+        let obj = inner
+            .s3
+            .get_object()
+            .bucket(&inner.bucket)
+            .key(&key)
+            .send()
+            .await;
+        inner.record(OpClass::Read, obj.is_ok());
+        let obj = obj?;
+        let etag = obj.e_tag.clone();
+        let bytes = obj
+            .body
+            .collect()
+            .await
+            .map_err(|err| Error::Whatever {
+                message: format!("failed to read object body: {err}"),
+                source: None,
+            })?
+            .into_bytes();
+        let len = bytes.len() as u64;
+        inner
+            .publish_blocks(&key, &BlockManifest::new(&bytes))
+            .await?;
+
+        let record = inner.read_metadata_record().await?;
+        let mut stamp = record
+            .stamp
+            .unwrap_or_else(|| Stamp::new(inner.generation_seen.load(Ordering::Relaxed)));
+        stamp.advance(inner.clock.now_ms(), etag.as_deref());
+        stamp.len = Some(len);
+        stamp.external_len = None;
+        stamp.quarantined = false;
+        inner
+            .write_metadata_record(MetadataRecord {
+                stamp: Some(stamp),
+                ..record
+            })
+            .await?;
+        inner
+            .generation_seen
+            .fetch_max(stamp.generation, Ordering::Relaxed);
+        tracing::info!(
+            target: "threeqlite::lock_protocol",
+            %key,
+            len,
+            generation = stamp.generation,
+            "accepted external state of database object"
+        );
+        Ok(len)
+    }
+
+    /// Copy the database object of `db` to `out` as it streams in, returning its length. The
+    /// object is read with a single GET, so the copy is of one generation. `out` is written to
+    /// from the runtime, like the file of an offline mirror, so it should not block for long.
+    pub async fn snapshot_to(&self, db: &str, out: &mut impl std::io::Write) -> Result<u64, Error> {
+        let key = KeyLayout::db(db)?;
+        let inner = self.inner.read().await;
+        inner.guard(OpClass::Read)?;
+        let _permit = inner.permit(IoClass::Bulk).await;
+        let obj = inner
+            .s3
+            .get_object()
+            .bucket(&inner.bucket)
+            .key(&key)
+            .send()
+            .await;
+        inner.record(OpClass::Read, obj.is_ok());
+        let mut body = obj?.body;
+        let mut len = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|err| Error::Whatever {
+                message: format!("failed to read object body: {err}"),
+                source: Some(Box::new(err)),
+            })?;
+            out.write_all(&chunk).map_err(|err| Error::Whatever {
+                message: format!("failed to write snapshot of {key}: {err}"),
+                source: Some(Box::new(err)),
+            })?;
+            len += chunk.len() as u64;
+        }
+        out.flush().map_err(|err| Error::Whatever {
+            message: format!("failed to write snapshot of {key}: {err}"),
+            source: Some(Box::new(err)),
+        })?;
+        Ok(len)
+    }
+
+    /// Keep a full copy of `db` in the local file at `path` to read from while the object store
+    /// is unreachable, see [crate::mirror]. The copy is bootstrapped right away if the object
+    /// store is reachable, and otherwise on the next open that finds it reachable.
+    pub async fn enable_offline_mirror(
+        &self,
+        db: &str,
+        path: impl AsRef<Path>,
+        policy: MirrorPolicy,
+    ) -> Result<OfflineStatus, Error> {
+        let db = KeyLayout::db(db)?;
+        let mirror = Arc::new(Mirror::open(db.clone(), path.as_ref(), policy)?);
+        let inner = self.inner.read().await;
+        inner.mirrors.lock().unwrap().insert(db, mirror.clone());
+        self.setup.lock().unwrap().offline_mirror = true;
+        if mirror.probe(&inner).await {
+            mirror.sync(&inner).await?;
+        }
+        Ok(mirror.status().await)
+    }
+
+    /// The state of the offline mirror of `db`, if enabled.
+    pub async fn offline_status(&self, db: &str) -> Option<OfflineStatus> {
+        let mirror = self.inner.read().await.mirror(db)?;
+        Some(mirror.status().await)
+    }
+
+    /// Bring the offline mirror of `db` up to date, e.g. periodically for databases that stay
+    /// open for long.
+    pub async fn sync_offline_mirror(&self, db: &str) -> Result<SyncReport, Error> {
+        let inner = self.inner.read().await;
+        let Some(mirror) = inner.mirror(db) else {
+            return Err(Error::MirrorUnavailable {
+                db: db.to_owned(),
+                reason: "no offline mirror is enabled".to_owned(),
+            });
+        };
+        mirror.sync(&inner).await
+    }
+
+    /// Serve a read-only open of `db` from its offline mirror if the object store is
+    /// unreachable. While it is reachable, the mirror catches up with the database instead and
+    /// `None` is returned.
+    pub async fn open_offline(
+        &self,
+        db: &ObjectKey,
+        access: OpenAccess,
+    ) -> Result<Option<Handle>, Error> {
+        let inner = self.inner.read().await;
+        let Some(mirror) = inner.mirror(db.as_str()) else {
+            return Ok(None);
+        };
+        if mirror.probe(&inner).await {
+            // the mirror stays at its last generation until the next open
+            if let Err(err) = mirror.sync(&inner).await {
+                tracing::warn!(target: "threeqlite::s3", %db, %err, "syncing offline mirror failed");
+            }
+            return Ok(None);
+        }
+        if access != OpenAccess::Read {
+            return Err(Error::Offline { db: db.to_string() });
+        }
+        mirror.check_servable().await?;
+        Ok(Some(Handle::offline(self.clone(), db.clone(), mirror)))
+    }
+
+    /// Who holds the lock of `db`, if anyone, see [crate::busy]. `None` for databases other than
+    /// the one of this instance, whose metadata object it doesn't know.
+    pub async fn why_busy(&self, db: &str) -> Result<Option<BusyDiagnosis>, Error> {
+        let db = KeyLayout::db(db)?;
+        let inner = self.inner.read().await;
+        if db != inner.db_filename {
+            return Ok(None);
+        }
+        let record = inner.read_metadata_record().await?;
+        Ok(inner.diagnose(&record))
+    }
+
+    /// Whether the credentials may write next to `db`. Probes on the first call per prefix, see
+    /// [crate::probe].
+    pub async fn writable(&self, db: &str) -> Result<bool, Error> {
+        let db = KeyLayout::db(db)?;
+        let prefix = probe::prefix(db.as_str());
+        let inner = self.inner.read().await;
+        if let Some(writable) = inner.probes.cached(&inner.bucket, prefix) {
+            return Ok(writable);
+        }
+        let writable = inner.shared_probe(prefix, &KeyLayout::probe(&db)).await?;
+        inner.probes.store(&inner.bucket, prefix, writable);
+        Ok(writable)
+    }
+
+    /// The checks of an open of `db` with `access`: the state the database is in once opened, see
+    /// [create::open], `None` if it is missing.
+    async fn open_checks(
+        &self,
+        db: &str,
+        key: &ObjectKey,
+        access: OpenAccess,
+    ) -> Result<Option<DatabaseState>, sqlite_vfs::error::Error<Error>> {
+        let mut health = self.inner.read().await.shared_metadata_health(key).await;
+        // the bucket moved since its region was pinned, see [crate::region]
+        if health.is_err()
+            && self
+                .region_moved()
+                .await
+                .map_err(|cause| sqlite_vfs::error::Error::External { cause })?
+        {
+            health = self.inner.read().await.shared_metadata_health(key).await;
+        }
+        match health {
+            Ok(MetadataHealth::Quarantined) if access != OpenAccess::Read => {
+                return Err(sqlite_vfs::error::Error::PermissionDenied);
+            }
+            Ok(_) => {}
+            // reading doesn't depend on the metadata object
+            Err(err) if access == OpenAccess::Read => {
+                tracing::warn!(target: "threeqlite::lock_protocol", db, %err, "checking metadata failed");
+            }
+            Err(cause) => return Err(sqlite_vfs::error::Error::External { cause }),
+        }
+
+        // Refuse to open for writing without write permission, so that SQLite retries read-only
+        // right away instead of failing halfway through the first transaction.
+        if access != OpenAccess::Read
+            && !self
+                .writable(db)
+                .await
+                .map_err(|cause| sqlite_vfs::error::Error::External { cause })?
+        {
+            return Err(sqlite_vfs::error::Error::PermissionDenied);
+        }
+
+        // boxed, keeping the future of an open small
+        let inner = self.inner.read().await;
+        Box::pin(create::open(&inner, key, access))
+            .await
+            .map_err(|cause| sqlite_vfs::error::Error::External { cause })
+    }
+}
+
+impl Vfs for ThreeQLite {
+    type Handle = Handle;
+    type Error = crate::error::Error;
+
+    async fn open(
+        &self,
+        db: &str,
+        opts: sqlite_vfs::OpenOptions,
+    ) -> Result<Self::Handle, sqlite_vfs::error::Error<Self::Error>> {
+        let sqlite_vfs::OpenOptions { kind, access, .. } = opts;
+
+        match kind {
+            OpenKind::MainDb => {}
+            OpenKind::MainJournal | OpenKind::SuperJournal => {
+                let kind = match kind {
+                    OpenKind::MainJournal => JournalKind::Main,
+                    _ => JournalKind::Super,
+                };
+                let create = matches!(access, OpenAccess::Create | OpenAccess::CreateNew);
+                let journal = async {
+                    let inner = self.inner.read().await;
+                    Journal::open(&inner, kind.key(db)?, kind, create).await
+                }
+                .await
+                .map_err(|cause| sqlite_vfs::error::Error::External { cause })?;
+                return Ok(Handle::journal(self.clone(), journal));
+            }
+            OpenKind::TempDb
+            | OpenKind::TempJournal
+            | OpenKind::TransientDb
+            | OpenKind::SubJournal => {
+                let key = KeyLayout::db(db)
+                    .map_err(|cause| sqlite_vfs::error::Error::External { cause })?;
+                let file = TempFile::new(&self.inner.read().await.memory)
+                    .map_err(|cause| sqlite_vfs::error::Error::External { cause })?;
+                return Ok(Handle::temp(self.clone(), key, file));
+            }
+            // not declared in [Self::capabilities], so only reached when called directly
+            OpenKind::Wal => {
+                return Err(sqlite_vfs::error::Error::UnsupportedKind { kind });
+            }
+        }
+        // journals still open, for transactions running to commit, see [crate::shutdown]
+        if self.handles.closing() {
+            return Err(sqlite_vfs::error::Error::External {
+                cause: Error::ShuttingDown,
+            });
+        }
+
+        if let Some(registration) = &self.registration {
+            // SQLite retries read-only, see [crate::registration]
+            if registration.config.readonly && access != OpenAccess::Read {
+                Stats::incr(&registration.stats.rejected_writes);
+                return Err(sqlite_vfs::error::Error::PermissionDenied);
+            }
+            Stats::incr(&registration.stats.opens);
+        }
+        let key =
+            KeyLayout::db(db).map_err(|cause| sqlite_vfs::error::Error::External { cause })?;
+        let offline = self
+            .open_offline(&key, access)
+            .await
+            .map_err(|cause| sqlite_vfs::error::Error::External { cause })?;
+        if let Some(handle) = offline {
+            return Ok(handle);
+        }
+
+        self.ensure_region()
+            .await
+            .map_err(|cause| sqlite_vfs::error::Error::External { cause })?;
+        // shared with the other opens of a burst, see [crate::burst]
+        let state = burst::scope(self.open_checks(db, &key, access)).await?;
+        if state.is_none() {
+            return Err(sqlite_vfs::error::Error::DbNotFound {
+                name: db.to_owned(),
+            });
+        }
+
+        Ok(Handle::new(self.clone(), key, access == OpenAccess::Read))
+    }
+
+    /// Everything but the WAL, which isn't implemented, see [crate::wal]. Temporary files are kept
+    /// in memory by their handles, see [crate::temp].
+    fn capabilities(&self) -> VfsCapabilities {
+        VfsCapabilities::of::<Handle>()
+    }
+
+    async fn delete(&self, db: &str) -> Result<(), sqlite_vfs::error::Error<Self::Error>> {
+        // SQLite only deletes journals, see [crate::journal]
+        let Some(kind) = JournalKind::of(db) else {
+            return Ok(());
+        };
+        async {
+            let key = kind.key(db)?;
+            if kind == JournalKind::Main {
+                // deleting the journal commits the transaction, see [crate::durability]
+                self.commit_point(&KeyLayout::db(db.strip_suffix("-journal").unwrap_or(db))?)
+                    .await?;
+            }
+            journal::delete(&*self.inner.read().await, &key, kind).await
+        }
+        .await
+        .map_err(|cause| sqlite_vfs::error::Error::External { cause })
+    }
+
+    async fn exists(&self, db: &str) -> Result<bool, sqlite_vfs::error::Error<Self::Error>> {
+        async {
+            let inner = self.inner.read().await;
+            match JournalKind::of(db) {
+                Some(kind) => journal::exists(&inner, &kind.key(db)?).await,
+                // created, if never written, see [crate::create]
+                None => {
+                    let state = inner.shared_state(&KeyLayout::db(db)?).await?;
+                    Ok(state != DatabaseState::Missing)
+                }
+            }
+        }
+        .await
+        .map_err(|cause| sqlite_vfs::error::Error::External { cause })
+    }
+
+    async fn access(
+        &self,
+        db: &str,
+        write: bool,
+    ) -> Result<bool, sqlite_vfs::error::Error<Self::Error>> {
+        if !write {
+            return Ok(true);
+        }
+        if self
+            .registration
+            .as_ref()
+            .is_some_and(|registration| registration.config.readonly)
+        {
+            return Ok(false);
+        }
+        self.writable(db)
+            .await
+            .map_err(|cause| sqlite_vfs::error::Error::External { cause })
+    }
+
+    async fn temporary_name(&self) -> String {
+        KeyLayout::temp(&uuid::Uuid::new_v4()).into()
+    }
+
+    async fn random(&self, buffer: &mut [u8]) {
+        rand::thread_rng().fill(buffer);
+    }
+
+    async fn sleep(&self, duration: Duration) -> Duration {
+        let start = Instant::now();
+        tokio::time::sleep(duration).await;
+        start.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlite_vfs::conformance::{run_all, Category, ConformanceOptions};
+
+    use super::*;
+    use crate::mock::MockS3;
+
+    #[test]
+    fn test_conformance() {
+        // the scenarios open `main.db`, the database of the instance
+        let config = Config {
+            db_filename: "main.db".to_owned(),
+            ..Config::default()
+        };
+        let report = run_all(
+            || ThreeQLite::with_client(config.clone(), MockS3::start().client()).unwrap(),
+            &ConformanceOptions {
+                skip: vec![Category::Wal],
+            },
+        );
+        // deleting a database is left to the operator, only journals are deleted
+        assert_eq!(report.failed(), vec!["files.delete"], "{report}");
     }
 }
@@ -0,0 +1,261 @@
+//! Pacing the wait for the lock.
+//!
+//! An instance that finds the lock taken looks at the metadata object again after a while. A fixed
+//! interval is both too short for a lock held for minutes, e.g. by a bulk import, and too long for
+//! a short critical section. A [Poller] starts at [LockConfig::poll_interval] and doubles the
+//! interval up to [LockConfig::max_poll_interval] for as long as the same [Blocker] holds the lock,
+//! going back to the shortest interval as soon as it changes or leaves.
+//!
+//! A writer that knows it will hold the lock for long can say so with [expecting_hold]: its
+//! [Holder] then records when it expects to release the lock, and waiters look again just after
+//! that rather than polling through it. Neither ever waits past [LockConfig::busy_timeout]: the
+//! last look happens at the deadline, when the wait gives up, see [crate::busy]. The busy handler
+//! of the connection is asked once per look.
+//!
+//! Each look after the first counts as a poll in the stats, and as a wasted one if it found the
+//! same blocker as the look before, along with the most polls a single wait took.
+//!
+//! [LockConfig::poll_interval]: crate::config::LockConfig::poll_interval
+//! [LockConfig::max_poll_interval]: crate::config::LockConfig::max_poll_interval
+//! [LockConfig::busy_timeout]: crate::config::LockConfig::busy_timeout
+//! [Holder]: crate::busy::Holder
+
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    busy::Holder,
+    config::LockConfig,
+    stats::Stats,
+    vfs::{Metadata, MetadataRecord},
+};
+
+tokio::task_local! {
+    /// How long the writer running the current task expects to hold the lock, see
+    /// [expecting_hold].
+    static EXPECTED_HOLD: Duration;
+}
+
+/// Run `fut`, recording in the write locks it takes that they will be held for about `hold`, see
+/// the [module documentation](self). `fut` is boxed like the one of [crate::busy::with_handler].
+pub async fn expecting_hold<F: Future>(hold: Duration, fut: F) -> F::Output {
+    EXPECTED_HOLD.scope(hold, Box::pin(fut)).await
+}
+
+/// When a write lock taken by the current task at `now`, in milliseconds since the Unix epoch,
+/// is expected to be released, if the task said so with [expecting_hold].
+pub fn expected_release(now: u64) -> Option<u64> {
+    EXPECTED_HOLD
+        .try_with(|hold| now + hold.as_millis() as u64)
+        .ok()
+}
+
+/// Whatever keeps a waiter from the lock, as it appears in the metadata object. A waiter backs
+/// off while it stays the same.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Blocker {
+    Writer {
+        lock: Vec<u8>,
+        holder: Option<Holder>,
+    },
+    Readers {
+        readers: Vec<Vec<u8>>,
+        holder: Option<Holder>,
+    },
+    None,
+}
+
+impl Blocker {
+    pub fn of(record: &MetadataRecord) -> Self {
+        let holder = record.holder.clone();
+        match &record.metadata {
+            Metadata::Writer(lock) => Blocker::Writer {
+                lock: lock.clone(),
+                holder,
+            },
+            Metadata::Reader(reader) => Blocker::Readers {
+                readers: reader.readers.clone(),
+                holder,
+            },
+            Metadata::None => Blocker::None,
+        }
+    }
+}
+
+/// The intervals between the looks of one wait for the lock, see the
+/// [module documentation](self).
+pub struct Poller {
+    min: Duration,
+    max: Duration,
+    deadline: Option<Instant>,
+    interval: Duration,
+    seen: Option<Blocker>,
+    stats: Arc<Stats>,
+    /// Looks after the first.
+    pub polls: u64,
+    /// Looks that found the blocker of the look before.
+    pub wasted: u64,
+}
+
+impl Poller {
+    /// A wait that started at `start`.
+    pub fn new(config: &LockConfig, start: Instant, stats: Arc<Stats>) -> Self {
+        Self {
+            min: config.poll_interval,
+            max: config.max_poll_interval.max(config.poll_interval),
+            deadline: config.busy_timeout.map(|timeout| start + timeout),
+            interval: config.poll_interval,
+            seen: None,
+            stats,
+            polls: 0,
+            wasted: 0,
+        }
+    }
+
+    /// How long to wait before looking again, after a look at `now` found `record`, at `now_ms`
+    /// milliseconds since the Unix epoch.
+    pub fn next(&mut self, record: &MetadataRecord, now: Instant, now_ms: u64) -> Duration {
+        let blocker = Blocker::of(record);
+        self.polls += 1;
+        Stats::incr(&self.stats.lock_polls);
+        Stats::max(&self.stats.max_lock_polls, self.polls);
+        self.interval = match self.seen.as_ref() == Some(&blocker) {
+            true => {
+                self.wasted += 1;
+                Stats::incr(&self.stats.wasted_lock_polls);
+                (self.interval * 2).min(self.max)
+            }
+            false => self.min,
+        };
+        let release = match &blocker {
+            Blocker::Writer {
+                holder: Some(holder),
+                ..
+            } => holder
+                .expected_release
+                .filter(|release| *release > now_ms)
+                .map(|release| Duration::from_millis(release - now_ms) + self.min),
+            _ => None,
+        };
+        self.seen = Some(blocker);
+        let wait = release.unwrap_or(self.interval);
+        match self.deadline {
+            Some(deadline) => wait.min(deadline.saturating_duration_since(now)),
+            None => wait,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+
+    const HOLD: Duration = Duration::from_secs(60);
+
+    fn config() -> LockConfig {
+        LockConfig {
+            poll_interval: Duration::from_millis(10),
+            max_poll_interval: Duration::from_secs(2),
+            ..LockConfig::default()
+        }
+    }
+
+    fn writing(lock: u8, expected_release: Option<u64>) -> MetadataRecord {
+        MetadataRecord {
+            holder: Some(Holder {
+                identity: format!("writer-{lock}"),
+                hostname: "host-b".to_owned(),
+                since: 0,
+                epoch: 1,
+                expected_release,
+                process: None,
+            }),
+            ..protocol::acquire(MetadataRecord::default(), &[lock])
+        }
+    }
+
+    /// Wait on a lock held from 0 until `hold` by `record`, returning the polls, the wasted ones
+    /// and how long after the release the wait ended, or when it gave up.
+    fn simulate(config: LockConfig, record: MetadataRecord, hold: Duration) -> (Poller, Duration) {
+        let start = Instant::now();
+        let mut poller = Poller::new(&config, start, Arc::default());
+        let mut elapsed = Duration::ZERO;
+        loop {
+            if elapsed >= hold {
+                return (poller, elapsed - hold);
+            }
+            if config
+                .busy_timeout
+                .is_some_and(|timeout| elapsed >= timeout)
+            {
+                return (poller, elapsed);
+            }
+            elapsed += poller.next(&record, start + elapsed, elapsed.as_millis() as u64);
+        }
+    }
+
+    #[test]
+    fn test_long_hold_backs_off() {
+        let (poller, late) = simulate(config(), writing(1, None), HOLD);
+        // instead of 6000 at a fixed 10 ms
+        assert!(poller.polls < 50, "{}", poller.polls);
+        assert_eq!(poller.wasted, poller.polls - 1);
+        assert!(late <= config().max_poll_interval, "{late:?}");
+
+        // a holder expecting to hold the lock is looked at again just after it releases it
+        let (poller, late) = simulate(config(), writing(1, Some(HOLD.as_millis() as u64)), HOLD);
+        assert_eq!((poller.polls, poller.wasted), (1, 0));
+        assert_eq!(late, config().poll_interval);
+
+        // a short hold is noticed within a short interval
+        let hold = Duration::from_millis(25);
+        let (poller, late) = simulate(config(), writing(1, None), hold);
+        assert_eq!((poller.polls, poller.wasted), (2, 1));
+        assert!(late <= config().poll_interval, "{late:?}");
+    }
+
+    #[test]
+    fn test_new_holder_resets() {
+        let start = Instant::now();
+        let stats = Arc::new(Stats::default());
+        let mut poller = Poller::new(&config(), start, stats.clone());
+        let min = config().poll_interval;
+        assert_eq!(poller.next(&writing(1, None), start, 0), min);
+        assert_eq!(poller.next(&writing(1, None), start, 0), 2 * min);
+        assert_eq!(poller.next(&writing(1, None), start, 0), 4 * min);
+        assert_eq!(poller.next(&writing(2, None), start, 0), min);
+        assert_eq!(poller.next(&MetadataRecord::default(), start, 0), min);
+        assert_eq!((poller.polls, poller.wasted), (5, 2));
+        let polls = |counter: &std::sync::atomic::AtomicU64| {
+            counter.load(std::sync::atomic::Ordering::Relaxed)
+        };
+        assert_eq!(polls(&stats.lock_polls), 5);
+        assert_eq!(polls(&stats.wasted_lock_polls), 2);
+        assert_eq!(polls(&stats.max_lock_polls), 5);
+    }
+
+    #[test]
+    fn test_deadline_honored() {
+        let timeout = Duration::from_millis(1_234);
+        let config = LockConfig {
+            busy_timeout: Some(timeout),
+            ..config()
+        };
+        // neither backing off nor a later expected release wait past the deadline
+        for release in [None, Some(HOLD.as_millis() as u64)] {
+            let (_, gave_up) = simulate(config.clone(), writing(1, release), HOLD);
+            assert_eq!(gave_up, timeout);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expecting_hold() {
+        assert_eq!(expected_release(1_000), None);
+        let release = expecting_hold(Duration::from_secs(5), async { expected_release(1_000) });
+        assert_eq!(release.await, Some(6_000));
+    }
+}
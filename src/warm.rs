@@ -0,0 +1,408 @@
+//! Warming the page cache of a fresh instance with the working set of another.
+//!
+//! A fleet-wide deploy restarts every instance at once, and each of them starts with a cold
+//! [page cache](crate::cache), fetching the same pages as all the others. An instance that has
+//! been serving for a while can [export](ThreeQLite::export_warm_set) which pages of a database it
+//! caches, ranked by how often they were read: a [WarmSet] of offsets, checksums and the
+//! generation they were read at, without their contents. It is small enough to bake into the
+//! deploy artifact ([WarmSet::encode]) or to [publish](ThreeQLite::publish_warm_set) next to the
+//! database.
+//!
+//! On startup, [ThreeQLite::import_warm_set] fetches the pages of a warm set with bulk priority
+//! and admits them as of the current generation, skipping entries that went stale: pages past the
+//! recorded end of the database object and pages whose checksum no longer matches, since a commit
+//! changed them after the export. [ThreeQLite::ready] resolves once every import started so far
+//! is done, for the application to await before reporting ready. How much of the warm sets was
+//! still valid and how long importing took are part of the stats.
+
+use std::{
+    future::Future,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{
+    cache::CacheUse,
+    circuit::OpClass,
+    error::Error,
+    fetch,
+    format::{self, Bounded},
+    key::KeyLayout,
+    prefetch::check_page,
+    priority::IoClass,
+    verify::{Upload, WriteClass},
+    vfs::{status, ThreeQLite},
+};
+
+/// A page of a [WarmSet].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarmPage {
+    pub offset: u64,
+    pub len: u64,
+    /// Hex-encoded MD5 of the page as exported.
+    pub md5: String,
+    /// Reads the page served on the exporting instance.
+    pub hits: u64,
+}
+
+/// The pages an instance cached of a database, most read first, see the
+/// [module documentation](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarmSet {
+    pub db: String,
+    /// The generation the pages were read at.
+    pub generation: u64,
+    pub pages: Vec<WarmPage>,
+}
+
+impl WarmSet {
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        bincode::serialize(self).map_err(|err| Error::Whatever {
+            message: format!("failed to encode warm set: {err}"),
+            source: Some(err),
+        })
+    }
+
+    /// Decode a warm set, e.g. shipped with a deploy. Fails with [Error::CorruptManifest] if it
+    /// exceeds the limits of [Bounded].
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        Self::decode_at("warm set", bytes)
+    }
+
+    fn decode_at(key: &str, bytes: &[u8]) -> Result<Self, Error> {
+        format::parse(bytes).map_err(|reason| Error::CorruptManifest {
+            key: key.to_owned(),
+            reason,
+        })
+    }
+}
+
+/// Pages in a warm set, enough for 4 GiB of pages of 4 KiB.
+const MAX_WARM_PAGES: usize = 1 << 20;
+
+impl Bounded for WarmSet {
+    const MAX_LEN: u64 = 64 * MAX_WARM_PAGES as u64;
+
+    fn validate(&self) -> Result<(), String> {
+        KeyLayout::db(&self.db).map_err(|err| err.to_string())?;
+        format::check_count("pages", &self.pages, MAX_WARM_PAGES)?;
+        self.pages.iter().try_for_each(|page| {
+            check_page(page.offset, page.len)?;
+            format::check_md5(&page.md5)
+        })
+    }
+}
+
+/// What [ThreeQLite::import_warm_set] did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WarmReport {
+    /// Pages in the warm set.
+    pub pages: u64,
+    /// Pages admitted to the cache.
+    pub valid: u64,
+    /// Pages skipped since they changed or no longer exist.
+    pub stale: u64,
+    /// Bytes fetched.
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl std::fmt::Display for WarmReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pages={} valid={} stale={} bytes={} elapsed={:?}",
+            self.pages, self.valid, self.stale, self.bytes, self.elapsed
+        )
+    }
+}
+
+/// Counts an import as running until dropped, see [ThreeQLite::ready].
+struct Warming(Arc<watch::Sender<usize>>);
+
+impl Warming {
+    fn start(imports: &Arc<watch::Sender<usize>>) -> Self {
+        imports.send_modify(|running| *running += 1);
+        Self(imports.clone())
+    }
+}
+
+impl Drop for Warming {
+    fn drop(&mut self) {
+        self.0.send_modify(|running| *running -= 1);
+    }
+}
+
+impl ThreeQLite {
+    /// The pages of `db` in the cache of this instance, see the [module documentation](self).
+    /// Empty if none were read at a known generation.
+    pub async fn export_warm_set(&self, db: &str) -> Result<WarmSet, Error> {
+        let key = KeyLayout::db(db)?;
+        let inner = self.inner.read().await;
+        let Some((generation, pages)) = inner.cache.pages(key.as_str()) else {
+            return Ok(WarmSet {
+                db: db.to_owned(),
+                ..WarmSet::default()
+            });
+        };
+        let pages = pages
+            .into_iter()
+            .map(|page| WarmPage {
+                offset: page.offset,
+                len: page.data.len() as u64,
+                md5: format!("{:x}", md5::compute(&page.data)),
+                hits: page.hits,
+            })
+            .collect();
+        Ok(WarmSet {
+            db: db.to_owned(),
+            generation,
+            pages,
+        })
+    }
+
+    /// Fetch the pages of `set` still valid into the cache, see the [module documentation](self).
+    /// The import counts towards [ThreeQLite::ready] from this call on, not only once the
+    /// returned future is polled.
+    pub fn import_warm_set(
+        &self,
+        db: &str,
+        set: WarmSet,
+    ) -> impl Future<Output = Result<WarmReport, Error>> + Send + 'static {
+        let warming = Warming::start(&self.warming);
+        let (tq, db) = (self.clone(), db.to_owned());
+        async move {
+            let _warming = warming;
+            let start = Instant::now();
+            let key = KeyLayout::db(&db)?;
+            // not holding the lock of the instance while fetching
+            let inner = tq.inner.read().await.clone();
+            let mut report = WarmReport {
+                pages: set.pages.len() as u64,
+                ..WarmReport::default()
+            };
+            // pages are cached by generation, which is only known of the database of this instance
+            let stamp = match key == inner.db_filename && inner.cache.enabled() {
+                true => inner.read_metadata_record().await?.stamp,
+                false => None,
+            };
+            if let Some(stamp) = stamp {
+                let end = stamp.len.unwrap_or(u64::MAX);
+                let (pages, past_end): (Vec<_>, Vec<_>) = set
+                    .pages
+                    .iter()
+                    .partition(|page| page.offset + page.len <= end);
+                report.stale += past_end.len() as u64;
+                let ranges: Vec<Range<u64>> = pages
+                    .iter()
+                    .map(|page| page.offset..page.offset + page.len)
+                    .collect();
+                let (data, _) = fetch::fetch(&inner, &key, None, &ranges, IoClass::Bulk).await?;
+                for (page, data) in pages.iter().zip(data) {
+                    report.bytes += data.len() as u64;
+                    if format!("{:x}", md5::compute(&data)) != page.md5 {
+                        report.stale += 1;
+                        continue;
+                    }
+                    report.valid += 1;
+                    inner.cache.insert(
+                        key.as_str(),
+                        stamp.generation,
+                        page.offset,
+                        data,
+                        CacheUse::Prefetch,
+                    );
+                }
+            } else {
+                report.stale = report.pages;
+            }
+            report.elapsed = start.elapsed();
+            inner
+                .stats
+                .record_warm(report.pages, report.valid, report.elapsed);
+            tracing::info!(target: "threeqlite::s3", %key, %report, "imported warm set");
+            Ok(report)
+        }
+    }
+
+    /// Resolves once every import of a warm set started so far is done, see
+    /// [ThreeQLite::import_warm_set].
+    pub fn ready(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut imports = self.warming.subscribe();
+        async move {
+            let _ = imports.wait_for(|running| *running == 0).await;
+        }
+    }
+
+    /// Publish `set` next to its database, see [KeyLayout::warm_set].
+    pub async fn publish_warm_set(&self, set: &WarmSet) -> Result<(), Error> {
+        let key = KeyLayout::warm_set(&KeyLayout::db(&set.db)?);
+        let bytes = set.encode()?;
+        let inner = self.inner.read().await;
+        let upload = Upload::verified(&key, &bytes, WriteClass::Manifests, &inner.verify_writes);
+        let put = inner
+            .s3
+            .put_object()
+            .bucket(&inner.bucket)
+            .key(&key)
+            .body(bytes.into());
+        let res = upload.send_checksum(put).send().await;
+        inner.record(OpClass::Write, res.is_ok());
+        inner.verify_put(&upload, &res?).await
+    }
+
+    /// The warm set published next to `db`, if any.
+    pub async fn load_warm_set(&self, db: &str) -> Result<Option<WarmSet>, Error> {
+        let key = KeyLayout::warm_set(&KeyLayout::db(db)?);
+        let inner = self.inner.read().await;
+        let obj = inner
+            .s3
+            .get_object()
+            .bucket(&inner.bucket)
+            .key(&key)
+            .send()
+            .await;
+        inner.record(OpClass::Read, obj.is_ok());
+        let obj = match obj {
+            Ok(obj) => obj,
+            Err(err) if status(&err) == Some(404) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if let Err(reason) = format::check_len::<WarmSet>(obj.content_length().unwrap_or(0) as u64)
+        {
+            return Err(Error::CorruptManifest {
+                key: key.to_string(),
+                reason,
+            });
+        }
+        let bytes = obj.body.collect().await.map_err(|err| Error::Whatever {
+            message: format!("failed to read object body: {err}"),
+            source: None,
+        })?;
+        WarmSet::decode_at(key.as_str(), &bytes.into_bytes()).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::CacheConfig,
+        config::Config,
+        heal::Stamp,
+        mock::{self, MockS3},
+        vfs::MetadataRecord,
+    };
+
+    const PAGE: u64 = 4096;
+
+    /// Reads of a skewed workload: pages 1 to 16 are read often, the rest of the 64 rarely.
+    fn workload() -> Vec<u64> {
+        (0..400u64)
+            .map(|i| match i % 5 {
+                0 => 16 + i * 7 % 48,
+                _ => i * 13 % 16,
+            })
+            .collect()
+    }
+
+    /// Run [workload] at `generation`, returning its cache hit ratio.
+    async fn run(tq: &ThreeQLite, generation: u64) -> f64 {
+        let inner = tq.inner.read().await;
+        let before = inner.cache.stats().hits();
+        let reads = workload();
+        for page in &reads {
+            inner
+                .read_at(
+                    (page * PAGE) as usize,
+                    PAGE as usize,
+                    Some(generation),
+                    CacheUse::Admit,
+                )
+                .await
+                .unwrap();
+        }
+        (inner.cache.stats().hits() - before) as f64 / reads.len() as f64
+    }
+
+    fn instance(mock: &MockS3) -> ThreeQLite {
+        let config = Config {
+            cache: CacheConfig {
+                capacity: 24 * PAGE,
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        ThreeQLite::with_client(config, mock.client()).unwrap()
+    }
+
+    async fn commit(tq: &ThreeQLite, generation: u64) {
+        let stamp = Stamp {
+            len: Some(64 * PAGE),
+            ..Stamp::new(generation)
+        };
+        tq.inner
+            .read()
+            .await
+            .write_metadata_record(MetadataRecord {
+                stamp: Some(stamp),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restart_with_warm_set() {
+        let mock = MockS3::start();
+        let mut db = mock::database(PAGE as u32, 64, 1);
+        mock.put("test.db", db.clone());
+        let warm = instance(&mock);
+        commit(&warm, 3).await;
+        run(&warm, 3).await;
+        let warm_ratio = run(&warm, 3).await;
+        assert!(warm_ratio > 0.7, "{warm_ratio}");
+
+        let set = warm.export_warm_set("test.db").await.unwrap();
+        assert_eq!(set.generation, 3);
+        assert_eq!(set.pages.len(), 24);
+        // the hot pages rank first
+        assert!(set.pages[..16].iter().all(|page| page.offset < 16 * PAGE));
+        assert!(set.pages.windows(2).all(|w| w[0].hits >= w[1].hits));
+        let set = WarmSet::decode(&set.encode().unwrap()).unwrap();
+        warm.publish_warm_set(&set).await.unwrap();
+        // without the contents of the pages
+        assert!(mock.get("test.db.warm").unwrap().len() < 24 * 128);
+
+        // a commit after the export changes one of the pages
+        db[5 * PAGE as usize] = 1;
+        mock.put("test.db", db);
+        commit(&warm, 4).await;
+
+        // the restarted instance
+        let fresh = instance(&mock);
+        let set = fresh.load_warm_set("test.db").await.unwrap().unwrap();
+        let import = tokio::spawn(fresh.import_warm_set("test.db", set));
+        fresh.ready().await;
+        assert!(import.is_finished());
+        let report = import.await.unwrap().unwrap();
+        assert_eq!((report.pages, report.valid, report.stale), (24, 23, 1));
+        assert_eq!(report.bytes, 24 * PAGE);
+
+        let fresh_ratio = run(&fresh, 4).await;
+        assert!(
+            (warm_ratio - fresh_ratio).abs() < 0.05,
+            "warm {warm_ratio}, fresh {fresh_ratio}"
+        );
+        let stats = fresh.stats().await;
+        assert_eq!((stats.warm_pages, stats.warm_valid), (24, 23));
+        assert_eq!(stats.time_to_warm.count, 1);
+        // without any import running, the instance is ready right away
+        fresh.ready().await;
+        assert_eq!(fresh.load_warm_set("other.db").await.unwrap(), None);
+    }
+}
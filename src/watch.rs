@@ -0,0 +1,656 @@
+//! Watching a database for generations committed by other instances.
+//!
+//! Polling the metadata object on a fixed interval is either too slow or too costly, depending on
+//! how often the database is written. Where S3 already publishes `s3:ObjectCreated` events of the
+//! metadata object, for example to an SQS queue, a [GenerationWatcher] learns about new
+//! generations from an [EventSource] instead and only reads the metadata object when an event
+//! arrives. Events are never trusted alone: they are deduplicated by their sequencer, filtered
+//! down to this database, and confirmed by reading the generation before anyone is notified.
+//!
+//! Events can be dropped, so the watcher falls back to polling every
+//! [WatchConfig::poll_interval] while the source fails or has been silent for longer than
+//! [WatchConfig::watchdog], and returns to events once one arrives.
+//!
+//! Independently of its source, a watcher subscribes to the [InvalidationBus](crate::bus) of the
+//! instance, if it has one, and handles the updates of other instances like events, see
+//! [crate::bus].
+
+use std::{cmp::Ordering, future::Future, sync::atomic::Ordering::Relaxed, time::Duration};
+
+use serde::Deserialize;
+use snafu::whatever;
+use tokio::{sync::mpsc::UnboundedReceiver, time::Instant};
+
+use crate::{
+    bus::{self, GenerationUpdate},
+    error::Error,
+    prefetch::{self, HotSet},
+    stats::Stats,
+    vfs::{Inner, ThreeQLite},
+};
+
+#[derive(Clone, Debug)]
+pub struct WatchConfig {
+    /// How often to read the metadata object while polling.
+    pub poll_interval: Duration,
+    /// How long a single receive from the [EventSource] waits for events.
+    pub wait: Duration,
+    /// Fall back to polling after this long without an event for the database. Should be well
+    /// above the time between writes.
+    pub watchdog: Duration,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(5),
+            wait: Duration::from_secs(20),
+            watchdog: Duration::from_secs(300),
+        }
+    }
+}
+
+/// An `s3:ObjectCreated` event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectEvent {
+    pub bucket: String,
+    pub key: String,
+    /// Orders the events of a key, see [sequencer_cmp].
+    pub sequencer: Option<String>,
+}
+
+/// A source of [ObjectEvent]s, such as an SQS queue S3 publishes to.
+pub trait EventSource: Send + Sync {
+    /// Wait up to `wait` for the next batch of events, like a long-polled receive.
+    fn receive(
+        &self,
+        wait: Duration,
+    ) -> impl Future<Output = Result<Vec<ObjectEvent>, Error>> + Send;
+}
+
+/// The [EventSource] of a watcher that only polls.
+pub enum NoEvents {}
+
+impl EventSource for NoEvents {
+    async fn receive(&self, _wait: Duration) -> Result<Vec<ObjectEvent>, Error> {
+        match *self {}
+    }
+}
+
+#[derive(Deserialize)]
+struct Notification {
+    #[serde(rename = "Records", default)]
+    records: Vec<Record>,
+    /// Set when the notification was delivered through SNS.
+    #[serde(rename = "Message")]
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    #[serde(rename = "eventName")]
+    event_name: String,
+    s3: Entity,
+}
+
+#[derive(Deserialize)]
+struct Entity {
+    bucket: BucketEntity,
+    object: ObjectEntity,
+}
+
+#[derive(Deserialize)]
+struct BucketEntity {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ObjectEntity {
+    key: String,
+    sequencer: Option<String>,
+}
+
+/// The longest message SQS delivers.
+pub const MAX_NOTIFICATION_LEN: usize = 256 * 1024;
+
+/// Parse the body of an S3 event notification, as delivered to SQS directly or through SNS, into
+/// its `s3:ObjectCreated` events. Test events have none.
+pub fn parse_events(body: &str) -> Result<Vec<ObjectEvent>, Error> {
+    parse_notification(body, true)
+}
+
+fn parse_notification(body: &str, envelope: bool) -> Result<Vec<ObjectEvent>, Error> {
+    if body.len() > MAX_NOTIFICATION_LEN {
+        whatever!(
+            "invalid S3 event notification: {} bytes exceed the limit of {MAX_NOTIFICATION_LEN}",
+            body.len()
+        );
+    }
+    let invalid = |err: serde_json::Error| Error::Whatever {
+        message: format!("invalid S3 event notification: {err}"),
+        source: Some(Box::new(err)),
+    };
+    let notification: Notification = serde_json::from_str(body).map_err(invalid)?;
+    if let Some(message) = notification.message {
+        // SNS wraps the notification once
+        if !envelope {
+            whatever!("invalid S3 event notification: nested SNS envelopes");
+        }
+        return parse_notification(&message, false);
+    }
+    Ok(notification
+        .records
+        .into_iter()
+        .filter(|record| record.event_name.starts_with("ObjectCreated:"))
+        .map(|record| ObjectEvent {
+            bucket: record.s3.bucket.name,
+            key: decode_key(&record.s3.object.key),
+            sequencer: record.s3.object.sequencer,
+        })
+        .collect())
+}
+
+/// Keys in event notifications are URL-encoded, with `+` for spaces.
+fn decode_key(key: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = key.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (b, hex) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(if b == b'+' { b' ' } else { b });
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Order two sequencers of the same key: hex strings of varying length, compared after padding
+/// the shorter one with leading zeros.
+pub fn sequencer_cmp(a: &str, b: &str) -> Ordering {
+    let width = a.len().max(b.len());
+    format!("{a:0>width$}").cmp(&format!("{b:0>width$}"))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchMode {
+    /// Waiting for events, reading the metadata object only to confirm them.
+    Events,
+    /// Reading the metadata object every [WatchConfig::poll_interval].
+    Polling,
+}
+
+pub struct GenerationWatcher<S> {
+    inner: Inner,
+    source: Option<S>,
+    config: WatchConfig,
+    /// The last generation read, `None` if none could be read yet.
+    generation: Option<u64>,
+    sequencer: Option<String>,
+    last_event: Instant,
+    source_failed: bool,
+    /// The updates of the [InvalidationBus](crate::bus::InvalidationBus) of the instance.
+    bus: Option<UnboundedReceiver<GenerationUpdate>>,
+}
+
+/// Wait for `fut`, unless an update arrives on `bus` first.
+async fn or_update<T>(
+    bus: &mut Option<UnboundedReceiver<GenerationUpdate>>,
+    fut: impl Future<Output = T>,
+) -> Result<T, GenerationUpdate> {
+    let update = async {
+        match bus {
+            Some(bus) => match bus.recv().await {
+                Some(update) => Err(update),
+                // the bus went away, leaving the source and polling
+                None => std::future::pending().await,
+            },
+            None => std::future::pending().await,
+        }
+    };
+    bus::first(async { Ok(fut.await) }, update).await
+}
+
+impl<S: EventSource> GenerationWatcher<S> {
+    pub async fn new(inner: Inner, source: Option<S>) -> Self {
+        let bus = inner.bus.lock().unwrap().clone();
+        let mut watcher = Self {
+            bus: bus.map(|bus| bus.subscribe()),
+            config: inner.watch_config.clone(),
+            inner,
+            source,
+            generation: None,
+            sequencer: None,
+            last_event: Instant::now(),
+            source_failed: false,
+        };
+        watcher.generation = watcher.inner.read_generation().await.ok().flatten();
+        watcher
+    }
+
+    pub fn mode(&self) -> WatchMode {
+        match self.source {
+            Some(_) if !self.source_failed && self.last_event.elapsed() < self.config.watchdog => {
+                WatchMode::Events
+            }
+            _ => WatchMode::Polling,
+        }
+    }
+
+    /// The last generation read.
+    pub fn generation(&self) -> Option<u64> {
+        self.generation
+    }
+
+    /// Watch forever, calling `on_change` with every new generation.
+    pub async fn run(mut self, mut on_change: impl FnMut(u64)) {
+        loop {
+            self.step(&mut on_change).await;
+        }
+    }
+
+    /// Wait for one batch of events, one poll interval, or one update on the bus, and call
+    /// `on_change` if the generation changed.
+    pub async fn step(&mut self, on_change: &mut impl FnMut(u64)) {
+        let Some(source) = &self.source else {
+            let sleep = tokio::time::sleep(self.config.poll_interval);
+            match or_update(&mut self.bus, sleep).await {
+                Ok(()) => {
+                    self.confirm(on_change).await;
+                }
+                Err(update) => self.on_update(update, on_change).await,
+            }
+            return;
+        };
+
+        let mode = self.mode();
+        let wait = match mode {
+            WatchMode::Events => self.config.wait,
+            WatchMode::Polling => self.config.poll_interval,
+        };
+        // a source that doesn't return in time is as good as silent
+        let receive = tokio::time::timeout(wait * 2, source.receive(wait));
+        let received = match or_update(&mut self.bus, receive).await {
+            Ok(received) => received,
+            Err(update) => return self.on_update(update, on_change).await,
+        };
+        let events = match received {
+            Ok(Ok(events)) => {
+                self.source_failed = false;
+                events
+            }
+            Ok(Err(err)) => {
+                if !self.source_failed {
+                    tracing::warn!(target: "threeqlite::watch", %err, "event source failed, polling instead");
+                }
+                self.source_failed = true;
+                let sleep = tokio::time::sleep(self.config.poll_interval);
+                if let Err(update) = or_update(&mut self.bus, sleep).await {
+                    return self.on_update(update, on_change).await;
+                }
+                vec![]
+            }
+            Err(_) => vec![],
+        };
+
+        let received = self.accept(events);
+        let jumped = self.inner.check_clock();
+        let stats = self.inner.stats.clone();
+        if received > 0 {
+            stats.notifications_received.fetch_add(received, Relaxed);
+            self.last_event = Instant::now();
+            match self.confirm(on_change).await {
+                Some(true) => Stats::incr(&stats.notifications_confirmed),
+                Some(false) => Stats::incr(&stats.notifications_spurious),
+                None => {}
+            }
+        } else if mode == WatchMode::Polling || self.mode() == WatchMode::Polling || jumped {
+            // also once after recovering, or after a suspend, events may have been lost in the
+            // meantime
+            self.confirm(on_change).await;
+        }
+    }
+
+    /// Count the events of `events` that are about the metadata object and newer than the last.
+    fn accept(&mut self, events: Vec<ObjectEvent>) -> u64 {
+        let mut received = 0;
+        for event in events {
+            if event.bucket != self.inner.bucket
+                || event.key != self.inner.metadata_filename.as_str()
+            {
+                continue;
+            }
+            if let Some(sequencer) = event.sequencer {
+                if let Some(last) = &self.sequencer {
+                    if sequencer_cmp(&sequencer, last) != Ordering::Greater {
+                        continue;
+                    }
+                }
+                self.sequencer = Some(sequencer);
+            }
+            received += 1;
+        }
+        received
+    }
+
+    /// Confirm `update` from the bus like an event. If it is for the generation right after the
+    /// last one read, move the cached pages it didn't change to it and fetch the changed ones.
+    async fn on_update(&mut self, update: GenerationUpdate, on_change: &mut impl FnMut(u64)) {
+        let inner = &self.inner;
+        if update.bucket != inner.bucket || update.db != inner.db_filename.as_str() {
+            return;
+        }
+        // delivered again, or behind what polling found
+        let previous = self.generation;
+        if previous.is_some_and(|last| update.generation <= last) {
+            return;
+        }
+        let stats = inner.stats.clone();
+        Stats::incr(&stats.notifications_received);
+        match self.confirm(on_change).await {
+            Some(true) => Stats::incr(&stats.notifications_confirmed),
+            Some(false) => Stats::incr(&stats.notifications_spurious),
+            None => return,
+        }
+        let (Some(previous), Some(changed)) = (previous, update.changed) else {
+            return;
+        };
+        if self.generation != Some(update.generation) || previous + 1 != update.generation {
+            return;
+        }
+        let db = self.inner.db_filename.clone();
+        let pages = self
+            .inner
+            .cache
+            .advance(db.as_str(), previous, update.generation, &changed);
+        if pages.is_empty() {
+            return;
+        }
+        // fetched as readers do, registered at the generation
+        let mut inner = self.inner.clone();
+        if let Ok(Some(generation)) = inner.request_read_lock().await {
+            if generation == update.generation {
+                let set = HotSet { pages };
+                if let Err(err) = prefetch::prefetch(&inner, &db, generation, &set).await {
+                    tracing::debug!(target: "threeqlite::watch", %err, "fetching the changed pages failed");
+                }
+            }
+            let _ = inner.release_read_lock().await;
+        }
+    }
+
+    /// Read the generation and call `on_change` if it moved. `None` if it couldn't be read.
+    async fn confirm(&mut self, on_change: &mut impl FnMut(u64)) -> Option<bool> {
+        let generation = match self.inner.read_generation().await {
+            Ok(generation) => generation?,
+            Err(err) => {
+                tracing::debug!(target: "threeqlite::watch", %err, "reading the generation failed");
+                return None;
+            }
+        };
+        if self.generation.is_some_and(|last| generation <= last) {
+            return Some(false);
+        }
+        self.generation = Some(generation);
+        self.inner.generation_seen.fetch_max(generation, Relaxed);
+        on_change(generation);
+        Some(true)
+    }
+}
+
+impl ThreeQLite {
+    /// A watcher for new generations of this instance's database, polling without a `source`.
+    pub async fn generation_watcher<S: EventSource>(
+        &self,
+        source: Option<S>,
+    ) -> GenerationWatcher<S> {
+        GenerationWatcher::new(self.inner.read().await.clone(), source).await
+    }
+
+    /// Spawn a [GenerationWatcher] calling `on_change` with every new generation.
+    pub async fn watch_generations<S: EventSource + 'static>(
+        &self,
+        source: Option<S>,
+        on_change: impl FnMut(u64) + Send + 'static,
+    ) -> tokio::task::JoinHandle<()> {
+        let watcher = self.generation_watcher(source).await;
+        tokio::spawn(watcher.run(on_change))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use super::*;
+    use crate::{config::Config, heal::Stamp, mock::MockS3, vfs::MetadataRecord};
+
+    /// Hands out queued batches, or nothing after `wait`. Once stalled, never returns.
+    #[derive(Default)]
+    struct MockEvents {
+        batches: Mutex<VecDeque<Result<Vec<ObjectEvent>, Error>>>,
+        stalled: std::sync::atomic::AtomicBool,
+    }
+
+    impl MockEvents {
+        fn push(&self, batch: Vec<ObjectEvent>) {
+            self.batches.lock().unwrap().push_back(Ok(batch));
+        }
+    }
+
+    impl EventSource for &MockEvents {
+        async fn receive(&self, wait: Duration) -> Result<Vec<ObjectEvent>, Error> {
+            if self.stalled.load(Relaxed) {
+                return std::future::pending().await;
+            }
+            let batch = self.batches.lock().unwrap().pop_front();
+            match batch {
+                Some(batch) => batch,
+                None => {
+                    tokio::time::sleep(wait).await;
+                    Ok(vec![])
+                }
+            }
+        }
+    }
+
+    fn event(bucket: &str, key: &str, sequencer: &str) -> ObjectEvent {
+        ObjectEvent {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            sequencer: Some(sequencer.to_owned()),
+        }
+    }
+
+    async fn commit(tq: &ThreeQLite, generation: u64) {
+        tq.inner
+            .read()
+            .await
+            .write_metadata_record(MetadataRecord {
+                stamp: Some(Stamp::new(generation)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    fn config() -> Config {
+        Config {
+            watch: WatchConfig {
+                poll_interval: Duration::from_millis(20),
+                wait: Duration::from_millis(50),
+                watchdog: Duration::from_millis(300),
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let body = r#"{"Records":[
+            {"eventName":"ObjectCreated:Put","s3":{"bucket":{"name":"threeqlite"},
+             "object":{"key":"tenants/my+db%C3%BC/metadata","size":3,"sequencer":"0A1B"}}},
+            {"eventName":"ObjectRemoved:Delete","s3":{"bucket":{"name":"threeqlite"},
+             "object":{"key":"metadata","sequencer":"0A1C"}}},
+            {"eventName":"ObjectCreated:CompleteMultipartUpload","s3":{"bucket":{"name":"b"},
+             "object":{"key":"100%25"}}}
+        ]}"#;
+        assert_eq!(
+            parse_events(body).unwrap(),
+            [
+                event("threeqlite", "tenants/my dbü/metadata", "0A1B"),
+                ObjectEvent {
+                    bucket: "b".to_owned(),
+                    key: "100%".to_owned(),
+                    sequencer: None,
+                },
+            ]
+        );
+
+        let sns = serde_json::json!({ "Type": "Notification", "Message": body }).to_string();
+        assert_eq!(parse_events(&sns).unwrap(), parse_events(body).unwrap());
+        let test_event = r#"{"Service":"Amazon S3","Event":"s3:TestEvent","Bucket":"threeqlite"}"#;
+        assert_eq!(parse_events(test_event).unwrap(), []);
+        assert!(parse_events("not json").is_err());
+        let nested = serde_json::json!({ "Message": sns }).to_string();
+        assert!(parse_events(&nested).is_err());
+        let deep = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+        assert!(parse_events(&deep).is_err());
+        let long = format!(r#"{{"Message":"{}"}}"#, " ".repeat(MAX_NOTIFICATION_LEN));
+        assert!(parse_events(&long).is_err());
+    }
+
+    #[test]
+    fn test_sequencer_cmp() {
+        assert_eq!(sequencer_cmp("0A1B", "0A1B"), Ordering::Equal);
+        assert_eq!(sequencer_cmp("0A1B", "0A1C"), Ordering::Less);
+        assert_eq!(sequencer_cmp("FF", "0100"), Ordering::Less);
+        assert_eq!(sequencer_cmp("0100", "FF"), Ordering::Greater);
+    }
+
+    #[tokio::test]
+    async fn test_events_invalidate_once_per_generation() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(config(), mock.client()).unwrap();
+        commit(&tq, 1).await;
+        let source = MockEvents::default();
+        let mut watcher = tq.generation_watcher(Some(&source)).await;
+        assert_eq!(watcher.generation(), Some(1));
+        assert_eq!(watcher.mode(), WatchMode::Events);
+
+        let mut invalidations = vec![];
+        let mut on_change = |generation| invalidations.push(generation);
+
+        // a commit writes the metadata object twice, and events may be delivered more than once
+        commit(&tq, 2).await;
+        source.push(vec![
+            event("threeqlite", "metadata", "10"),
+            event("threeqlite", "tenants/b/metadata", "11"),
+            event("threeqlite", "metadata", "10"),
+            event("threeqlite", "test.db", "12"),
+            event("other", "metadata", "13"),
+            event("threeqlite", "metadata", "14"),
+        ]);
+        watcher.step(&mut on_change).await;
+        // a redelivery of the same batch
+        source.push(vec![
+            event("threeqlite", "metadata", "10"),
+            event("threeqlite", "metadata", "14"),
+        ]);
+        watcher.step(&mut on_change).await;
+        assert_eq!(tq.stats().await.notifications_received, 2);
+        assert_eq!(tq.stats().await.notifications_confirmed, 1);
+
+        // an event without a new generation, e.g. a reader registering
+        source.push(vec![event("threeqlite", "metadata", "15")]);
+        watcher.step(&mut on_change).await;
+        assert_eq!(tq.stats().await.notifications_spurious, 1);
+
+        // split across batches
+        commit(&tq, 3).await;
+        source.push(vec![event("threeqlite", "metadata", "0100")]);
+        watcher.step(&mut on_change).await;
+        source.push(vec![event("threeqlite", "metadata", "0101")]);
+        watcher.step(&mut on_change).await;
+        // nothing arrives
+        watcher.step(&mut on_change).await;
+
+        assert_eq!(invalidations, [2, 3]);
+        assert_eq!(tq.stats().await.notifications_received, 5);
+        assert_eq!(tq.stats().await.notifications_confirmed, 2);
+        assert_eq!(tq.stats().await.notifications_spurious, 2);
+        assert_eq!(tq.inner.read().await.generation_seen.load(Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_when_source_stalls() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(config(), mock.client()).unwrap();
+        commit(&tq, 1).await;
+        let source = MockEvents::default();
+        let mut watcher = tq.generation_watcher(Some(&source)).await;
+        let mut invalidations = vec![];
+        let mut on_change = |generation| invalidations.push(generation);
+
+        // the event of this commit is dropped and the source stops responding
+        source.stalled.store(true, Relaxed);
+        commit(&tq, 2).await;
+        let start = Instant::now();
+        while watcher.mode() == WatchMode::Events {
+            watcher.step(&mut on_change).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        watcher.step(&mut on_change).await;
+        assert_eq!(watcher.generation(), Some(2));
+
+        // polling keeps going
+        commit(&tq, 3).await;
+        watcher.step(&mut on_change).await;
+        assert_eq!(watcher.mode(), WatchMode::Polling);
+
+        // events resume
+        source.stalled.store(false, Relaxed);
+        commit(&tq, 4).await;
+        source.push(vec![event("threeqlite", "metadata", "20")]);
+        watcher.step(&mut on_change).await;
+        assert_eq!(watcher.mode(), WatchMode::Events);
+        assert_eq!(invalidations, [2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_failing_or_missing_source_polls() {
+        let mock = MockS3::start();
+        let tq = ThreeQLite::with_client(config(), mock.client()).unwrap();
+        let source = MockEvents::default();
+        source
+            .batches
+            .lock()
+            .unwrap()
+            .push_back(Err(Error::Whatever {
+                message: "queue does not exist".to_owned(),
+                source: None,
+            }));
+        let mut watcher = tq.generation_watcher(Some(&source)).await;
+        assert_eq!(watcher.generation(), None);
+        let mut invalidations = vec![];
+        let mut on_change = |generation| invalidations.push(generation);
+        watcher.step(&mut on_change).await;
+        assert_eq!(watcher.mode(), WatchMode::Polling);
+        commit(&tq, 1).await;
+        watcher.step(&mut on_change).await;
+
+        let mut poller = tq.generation_watcher(None::<NoEvents>).await;
+        assert_eq!(poller.mode(), WatchMode::Polling);
+        commit(&tq, 2).await;
+        poller.step(&mut on_change).await;
+        assert_eq!(invalidations, [1, 2]);
+    }
+}
@@ -0,0 +1,82 @@
+//! Allocations of the write path, counted by a global allocator of this test binary alone.
+//!
+//! Buffering and flushing a transaction used to copy every written byte into the write buffer,
+//! every page written again a second time along with the buffered one, and all of it once more
+//! into the bodies of the uploads. Now a page written again is overwritten in place, and the
+//! uploads share the buffer. Bytes copied per MiB committed:
+//!
+//! | pages            | before | after |
+//! |------------------|--------|-------|
+//! | written once     | 2 MiB  | 1 MiB |
+//! | written twice    | 4 MiB  | 2 MiB |
+#![cfg(feature = "s3")]
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use threeqlite::flush::{self, PendingWrites};
+
+struct Counting;
+
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[tokio::test]
+async fn test_one_copy_per_byte_written() {
+    const PAGE: usize = 4096;
+    const PAGES: u64 = 256;
+    let page = vec![7; PAGE];
+    let start = ALLOCATED.load(Ordering::Relaxed);
+
+    let mut pending = PendingWrites::default();
+    for n in 0..PAGES {
+        pending.write(n * 2 * PAGE as u64, &page);
+    }
+    // pages rewritten within the transaction, e.g. after a cache spill
+    for n in 0..PAGES {
+        pending.write(n * 2 * PAGE as u64, &page);
+    }
+    let buffered = ALLOCATED.load(Ordering::Relaxed) - start;
+
+    let start = ALLOCATED.load(Ordering::Relaxed);
+    let uploaded = std::sync::Arc::new(AtomicU64::new(0));
+    let graph = flush::page_graph(&pending, |_, slot| {
+        let uploaded = uploaded.clone();
+        async move {
+            let staged = slot.lock().unwrap().take().unwrap();
+            uploaded.fetch_add(staged.body.len() as u64, Ordering::Relaxed);
+            Ok(())
+        }
+    });
+    graph.run().await.unwrap();
+
+    let written = 2 * PAGES * PAGE as u64;
+    assert_eq!(uploaded.load(Ordering::Relaxed), written / 2);
+    assert_eq!(pending.copied(), written);
+    // the buffer itself, and its map
+    assert!(
+        buffered < written / 2 + written / 16,
+        "{buffered} bytes allocated buffering {written} bytes"
+    );
+    // nodes and tasks, but no copy of the pages
+    let flushed = ALLOCATED.load(Ordering::Relaxed) - start;
+    assert!(
+        flushed < written / 2,
+        "{flushed} bytes allocated flushing {written} bytes"
+    );
+}
@@ -0,0 +1,64 @@
+use std::{path::Path, process::Command};
+
+fn cargo(args: &[&str]) -> String {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+    let output = Command::new(env!("CARGO"))
+        .args(args)
+        .arg("--manifest-path")
+        .arg(manifest)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "cargo {args:?} failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_http_readonly_without_aws() {
+    let tree = cargo(&[
+        "tree",
+        "--package",
+        "threeqlite",
+        "--edges",
+        "normal",
+        "--prefix",
+        "none",
+        "--no-default-features",
+        "--features",
+        "http-readonly",
+    ]);
+    let aws = tree
+        .lines()
+        .filter(|line| line.starts_with("aws-"))
+        .collect::<Vec<_>>();
+    assert!(
+        aws.is_empty(),
+        "AWS crates in the dependency graph: {aws:?}"
+    );
+}
+
+#[test]
+fn test_default_includes_s3() {
+    let tree = cargo(&[
+        "tree",
+        "--package",
+        "threeqlite",
+        "--edges",
+        "normal",
+        "--prefix",
+        "none",
+    ]);
+    assert!(tree.lines().any(|line| line.starts_with("aws-sdk-s3 ")));
+}
+
+/// Builds every feature combination, which takes a while. Run with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn test_feature_powerset() {
+    let script = Path::new(env!("CARGO_MANIFEST_DIR")).join("scripts/check-features.sh");
+    let status = Command::new(script).status().unwrap();
+    assert!(status.success());
+}